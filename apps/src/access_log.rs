@@ -0,0 +1,203 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Logging of the requests served by the server.
+//!
+//! Each completed request is written as one line, either in the Combined Log
+//! Format followed by QUIC-specific fields, e.g.:
+//!
+//! ```text
+//! 127.0.0.1 - - [14/Oct/2026:08:49:37 +0000] "GET /index.html HTTP/3" 200 1024 "-" "curl/7.80" cid=4d5e6f duration_ms=12 early_data=0
+//! ```
+//!
+//! or as a JSON object with the same fields.
+
+use std::io::prelude::*;
+
+use std::net::SocketAddr;
+
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// A request whose response was sent completely.
+pub struct CompletedRequest {
+    pub method: String,
+
+    pub path: String,
+
+    pub status: u16,
+
+    pub referer: Option<String>,
+
+    pub user_agent: Option<String>,
+
+    /// The number of response body bytes sent.
+    pub bytes: u64,
+
+    /// When the request was received.
+    pub received: SystemTime,
+
+    /// How long it took to send the response.
+    pub duration: Duration,
+
+    /// Whether the request was received as early data.
+    pub early_data: bool,
+}
+
+/// Writes completed requests to a file.
+pub struct AccessLog {
+    writer: std::io::BufWriter<std::fs::File>,
+
+    json: bool,
+}
+
+impl AccessLog {
+    /// Opens the access log at `path` for appending, creating it if needed.
+    pub fn open(path: &str, json: bool) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(AccessLog {
+            writer: std::io::BufWriter::new(file),
+
+            json,
+        })
+    }
+
+    /// Logs a request received from `peer` on the connection `cid`.
+    pub fn log(
+        &mut self, peer: Option<SocketAddr>, cid: &str, req: &CompletedRequest,
+    ) -> std::io::Result<()> {
+        let line = if self.json {
+            json_line(peer, cid, req)
+        } else {
+            combined_line(peer, cid, req)
+        };
+
+        writeln!(self.writer, "{}", line)
+    }
+
+    /// Writes the buffered lines to the file.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn combined_line(
+    peer: Option<SocketAddr>, cid: &str, req: &CompletedRequest,
+) -> String {
+    let peer = peer.map_or("-".to_string(), |v| v.ip().to_string());
+
+    format!(
+        "{} - - [{}] \"{} {} HTTP/3\" {} {} \"{}\" \"{}\" cid={} duration_ms={} early_data={}",
+        peer,
+        clf_date(req.received),
+        escape(&req.method),
+        escape(&req.path),
+        req.status,
+        req.bytes,
+        req.referer.as_deref().map_or("-".to_string(), escape),
+        req.user_agent.as_deref().map_or("-".to_string(), escape),
+        cid,
+        req.duration.as_millis(),
+        req.early_data as u8
+    )
+}
+
+fn json_line(
+    peer: Option<SocketAddr>, cid: &str, req: &CompletedRequest,
+) -> String {
+    let peer = peer.map_or("null".to_string(), |v| format!("\"{}\"", v));
+
+    let optional = |v: &Option<String>| {
+        v.as_deref()
+            .map_or("null".to_string(), |v| format!("\"{}\"", escape(v)))
+    };
+
+    let received = req
+        .received
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    format!(
+        "{{\"time\":{}.{:03},\"peer\":{},\"cid\":\"{}\",\"method\":\"{}\",\"path\":\"{}\",\"status\":{},\"bytes\":{},\"referer\":{},\"user_agent\":{},\"duration_ms\":{},\"early_data\":{}}}",
+        received.as_secs(),
+        received.subsec_millis(),
+        peer,
+        cid,
+        escape(&req.method),
+        escape(&req.path),
+        req.status,
+        req.bytes,
+        optional(&req.referer),
+        optional(&req.user_agent),
+        req.duration.as_millis(),
+        req.early_data
+    )
+}
+
+/// Formats `time` as in the Common Log Format, e.g.
+/// `14/Oct/2026:08:49:37 +0000`.
+fn clf_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let (year, month, day) = crate::cache::civil_from_days(secs / 86400);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        crate::cache::MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Escapes the quotes, backslashes and control characters in `v`, so that
+/// it can be used inside a quoted string of both formats.
+fn escape(v: &str) -> String {
+    let mut out = String::with_capacity(v.len());
+
+    for c in v.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+
+            '\\' => out.push_str("\\\\"),
+
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+
+            c => out.push(c),
+        }
+    }
+
+    out
+}
@@ -24,7 +24,12 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use super::bind::AddressFamily;
+use super::bind::BindOptions;
+use super::client::MigrateAfter;
 use super::common::alpns;
+use super::common::RequestBody;
+use super::impair::ImpairmentConfig;
 
 pub trait Args {
     fn with_docopt(docopt: &docopt::Docopt) -> Self;
@@ -48,6 +53,9 @@ pub struct CommonArgs {
     pub dgrams_enabled: bool,
     pub dgram_count: u64,
     pub dgram_data: String,
+    pub impairment: ImpairmentConfig,
+    pub bind: BindOptions,
+    pub dscp: u8,
 }
 
 /// Creates a new `CommonArgs` structure using the provided [`Docopt`].
@@ -68,6 +76,12 @@ pub struct CommonArgs {
 /// --dgram-proto PROTO         DATAGRAM application protocol.
 /// --dgram-count COUNT         Number of DATAGRAMs to send.
 /// --dgram-data DATA           DATAGRAM data to send.
+/// --impair SPEC               Drop, reorder and delay datagrams.
+/// --bind-address ADDR         Local IP address to bind to.
+/// --interface NAME            Network interface to bind to.
+/// -4 --ipv4                   Only use IPv4.
+/// -6 --ipv6                   Only use IPv6.
+/// --dscp VALUE                DSCP value to mark outgoing packets with.
 ///
 /// [`Docopt`]: https://docs.rs/docopt/1.1.0/docopt/
 impl Args for CommonArgs {
@@ -143,6 +157,42 @@ impl Args for CommonArgs {
 
         let disable_hystart = args.get_bool("--disable-hystart");
 
+        let impairment = ImpairmentConfig::parse(args.get_str("--impair"));
+
+        let address = if args.get_bool("--bind-address") {
+            Some(args.get_str("--bind-address").parse().unwrap())
+        } else {
+            None
+        };
+
+        let interface = if args.get_bool("--interface") {
+            Some(args.get_str("--interface").to_string())
+        } else {
+            None
+        };
+
+        let family = match (args.get_bool("--ipv4"), args.get_bool("--ipv6")) {
+            (true, false) => Some(AddressFamily::V4),
+
+            (false, true) => Some(AddressFamily::V6),
+
+            (false, false) => None,
+
+            (true, true) => panic!("Only one of -4 and -6 can be used."),
+        };
+
+        let bind = BindOptions {
+            address,
+            interface,
+            family,
+        };
+
+        let dscp = if !args.get_str("--dscp").is_empty() {
+            args.get_str("--dscp").parse::<u8>().unwrap()
+        } else {
+            0
+        };
+
         CommonArgs {
             alpns,
             max_data,
@@ -160,6 +210,9 @@ impl Args for CommonArgs {
             dgrams_enabled,
             dgram_count,
             dgram_data,
+            impairment,
+            bind,
+            dscp,
         }
     }
 }
@@ -183,6 +236,9 @@ impl Default for CommonArgs {
             dgrams_enabled: false,
             dgram_count: 0,
             dgram_data: "quack".to_string(),
+            impairment: ImpairmentConfig::default(),
+            bind: BindOptions::default(),
+            dscp: 0,
         }
     }
 }
@@ -193,7 +249,8 @@ pub const CLIENT_USAGE: &str = "Usage:
 
 Options:
   --method METHOD          Use the given HTTP request method [default: GET].
-  --body FILE              Send the given file as request body.
+  --body FILE              Send the given file as request body, or stdin if FILE is -.
+  --content-type TYPE      Set the content-type of the request body.
   --max-data BYTES         Connection-wide flow control limit [default: 10000000].
   --max-window BYTES       Connection-wide max receiver window [default: 25165824].
   --max-stream-data BYTES  Per-stream flow control limit [default: 1000000].
@@ -208,17 +265,32 @@ Options:
   --dgram-count COUNT      Number of DATAGRAMs to send [default: 0].
   --dgram-data DATA        Data to send for certain types of DATAGRAM application protocol [default: quack].
   --dump-packets PATH      Dump the incoming packets as files in the given directory.
+  --impair SPEC            Drop, reorder and delay datagrams in both directions, e.g. loss=1%,reorder=0.5%,delay=20ms.
   --dump-responses PATH    Dump response payload as files in the given directory.
   --dump-json              Dump response headers and payload to stdout in JSON format.
+  --write-out FORMAT       Print FORMAT after each request, expanding curl-style %{variable} references.
+  --json-stats             Print the timing and transfer metrics of each request in JSON format.
   --max-json-payload BYTES  Per-response payload limit when dumping JSON [default: 10000].
   --connect-to ADDRESS     Override ther server's address.
+  --bind-address ADDR      Bind the UDP socket to the given local IP address.
+  --interface NAME         Bind the UDP socket to the given network interface.
+  -4 --ipv4                Only connect to IPv4 addresses.
+  -6 --ipv6                Only connect to IPv6 addresses.
+  --dscp VALUE             Mark outgoing packets with the given DSCP value.
+  --migrate-after WHEN     Rebind to a new UDP port after receiving WHEN bytes, or after WHEN seconds if suffixed with s (e.g. 1.5s).
   --no-verify              Don't verify server's certificate.
   --no-grease              Don't send GREASE.
   --cc-algorithm NAME      Specify which congestion control algorithm to use [default: cubic].
   --disable-hystart        Disable HyStart++.
   -H --header HEADER ...   Add a request header.
   -n --requests REQUESTS   Send the given number of identical requests [default: 1].
+  --max-concurrent-requests N  Don't have more than N requests in flight at once.
   --session-file PATH      File used to cache a TLS session for resumption.
+  --session-dir PATH       Directory used to cache TLS sessions for resumption, keyed by origin.
+  --benchmark              Generate load on the server and report statistics, instead of printing responses.
+  --connections N          Number of parallel connections in benchmark mode [default: 1].
+  --requests-per-connection M  Number of requests sent on each connection in benchmark mode [default: 100].
+  --duration S             Run the benchmark for S seconds, replacing connections as they finish.
   -h --help                Show this screen.
 ";
 
@@ -227,14 +299,24 @@ pub struct ClientArgs {
     pub version: u32,
     pub dump_response_path: Option<String>,
     pub dump_json: Option<usize>,
+    pub write_out: Option<String>,
+    pub json_stats: bool,
     pub urls: Vec<url::Url>,
     pub reqs_cardinal: u64,
+    pub max_concurrent_reqs: Option<usize>,
     pub req_headers: Vec<String>,
     pub no_verify: bool,
-    pub body: Option<Vec<u8>>,
+    pub body: Option<RequestBody>,
+    pub content_type: Option<String>,
     pub method: String,
     pub connect_to: Option<String>,
+    pub migrate_after: Option<MigrateAfter>,
     pub session_file: Option<String>,
+    pub session_dir: Option<String>,
+    pub benchmark: bool,
+    pub connections: usize,
+    pub reqs_per_conn: u64,
+    pub duration: Option<std::time::Duration>,
 }
 
 impl Args for ClientArgs {
@@ -259,6 +341,14 @@ impl Args for ClientArgs {
             None
         };
 
+        let write_out = if args.get_bool("--write-out") {
+            Some(args.get_str("--write-out").to_string())
+        } else {
+            None
+        };
+
+        let json_stats = args.get_bool("--json-stats");
+
         // URLs (can be multiple).
         let urls: Vec<url::Url> = args
             .get_vec("URL")
@@ -276,10 +366,23 @@ impl Args for ClientArgs {
         let reqs_cardinal = args.get_str("--requests");
         let reqs_cardinal = reqs_cardinal.parse::<u64>().unwrap();
 
+        let max_concurrent_reqs = if args.get_bool("--max-concurrent-requests") {
+            let max = args.get_str("--max-concurrent-requests");
+            Some(max.parse::<usize>().unwrap())
+        } else {
+            None
+        };
+
         let no_verify = args.get_bool("--no-verify");
 
         let body = if args.get_bool("--body") {
-            std::fs::read(args.get_str("--body")).ok()
+            Some(RequestBody::parse(args.get_str("--body")))
+        } else {
+            None
+        };
+
+        let content_type = if args.get_bool("--content-type") {
+            Some(args.get_str("--content-type").to_string())
         } else {
             None
         };
@@ -292,24 +395,62 @@ impl Args for ClientArgs {
             None
         };
 
+        let migrate_after = if args.get_bool("--migrate-after") {
+            Some(MigrateAfter::parse(args.get_str("--migrate-after")))
+        } else {
+            None
+        };
+
         let session_file = if args.get_bool("--session-file") {
             Some(args.get_str("--session-file").to_string())
         } else {
             None
         };
 
+        let session_dir = if args.get_bool("--session-dir") {
+            Some(args.get_str("--session-dir").to_string())
+        } else {
+            None
+        };
+
+        let benchmark = args.get_bool("--benchmark");
+
+        let connections = args.get_str("--connections");
+        let connections = connections.parse::<usize>().unwrap();
+
+        let reqs_per_conn = args.get_str("--requests-per-connection");
+        let reqs_per_conn = reqs_per_conn.parse::<u64>().unwrap();
+
+        let duration = if args.get_bool("--duration") {
+            let duration = args.get_str("--duration");
+            let duration = duration.parse::<u64>().unwrap();
+            Some(std::time::Duration::from_secs(duration))
+        } else {
+            None
+        };
+
         ClientArgs {
             version,
             dump_response_path,
             dump_json,
+            write_out,
+            json_stats,
             urls,
             reqs_cardinal,
+            max_concurrent_reqs,
             req_headers,
             no_verify,
             body,
+            content_type,
             method,
             connect_to,
+            migrate_after,
             session_file,
+            session_dir,
+            benchmark,
+            connections,
+            reqs_per_conn,
+            duration,
         }
     }
 }
@@ -320,14 +461,24 @@ impl Default for ClientArgs {
             version: 0xbabababa,
             dump_response_path: None,
             dump_json: None,
+            write_out: None,
+            json_stats: false,
             urls: vec![],
             req_headers: vec![],
             reqs_cardinal: 1,
+            max_concurrent_reqs: None,
             no_verify: false,
             body: None,
+            content_type: None,
             method: "GET".to_string(),
             connect_to: None,
+            migrate_after: None,
             session_file: None,
+            session_dir: None,
+            benchmark: false,
+            connections: 1,
+            reqs_per_conn: 100,
+            duration: None,
         }
     }
 }
@@ -338,10 +489,16 @@ pub const SERVER_USAGE: &str = "Usage:
 
 Options:
   --listen <addr>             Listen on the given IP:port [default: 127.0.0.1:4433]
+  --bind-address ADDR         Listen on the given local IP address instead of the one of --listen.
+  --interface NAME            Bind the UDP socket to the given network interface.
+  -4 --ipv4                   Only listen on an IPv4 address.
+  -6 --ipv6                   Only listen on an IPv6 address.
+  --dscp VALUE                Mark outgoing packets with the given DSCP value.
   --cert <file>               TLS certificate path [default: src/bin/cert.crt]
   --key <file>                TLS certificate key path [default: src/bin/cert.key]
   --root <dir>                Root directory [default: src/bin/root/]
   --index <name>              The file that will be used as index [default: index.html].
+  --cache-size BYTES          Keep up to BYTES of recently served files in memory.
   --name <str>                Name of the server [default: quic.tech]
   --max-data BYTES            Connection-wide flow control limit [default: 10000000].
   --max-window BYTES          Connection-wide max receiver window [default: 25165824].
@@ -351,8 +508,16 @@ Options:
   --max-streams-uni STREAMS   Number of allowed concurrent streams [default: 100].
   --idle-timeout TIMEOUT      Idle timeout in milliseconds [default: 30000].
   --dump-packets PATH         Dump the incoming packets as files in the given directory.
+  --impair SPEC               Drop, reorder and delay datagrams in both directions, e.g. loss=1%,reorder=0.5%,delay=20ms.
   --early-data                Enable receiving early data.
   --no-retry                  Disable stateless retry.
+  --retry-key-file <file>     Read the keys protecting address validation tokens from the given file, one hex-encoded 256-bit key per line, the first one being used for new tokens.
+  --new-token                 Send NEW_TOKEN frames, so that clients can skip stateless retry on later connections.
+  --token-lifetime SECS       How long the tokens sent in NEW_TOKEN frames remain valid [default: 86400].
+  --max-connections N         Refuse new connections while N connections are open.
+  --max-handshakes-per-second N  Accept at most N new connections per second, answering the others with a stateless retry, or refusing them once their address is validated.
+  --max-handshakes-per-ip N   Accept at most N new connections per second from a single IP address, answering the others like --max-handshakes-per-second.
+  --max-rate-per-conn BYTES   Limit the rate at which response bodies are written on each connection to BYTES per second.
   --no-grease                 Don't send GREASE.
   --http-version VERSION      HTTP version to use [default: all].
   --dgram-proto PROTO         DATAGRAM application protocol to use [default: none].
@@ -360,6 +525,11 @@ Options:
   --dgram-data DATA           Data to send for certain types of DATAGRAM application protocol [default: brrr].
   --cc-algorithm NAME         Specify which congestion control algorithm to use [default: cubic].
   --disable-hystart           Disable HyStart++.
+  --masque-proxy              Proxy CONNECT-UDP requests to their targets.
+  --proxy-to URL              Forward HTTP/3 requests to the given HTTP/1.1 upstream instead of serving files.
+  --metrics-listen <addr>     Serve Prometheus metrics over TCP on the given IP:port.
+  --access-log PATH           Append a line in the Combined Log Format to PATH for every completed HTTP/3 request.
+  --access-log-json           Write the access log lines as JSON objects instead.
   -h --help                   Show this screen.
 ";
 
@@ -367,10 +537,23 @@ Options:
 pub struct ServerArgs {
     pub listen: String,
     pub no_retry: bool,
+    pub retry_key_file: Option<String>,
+    pub new_token: bool,
+    pub token_lifetime: std::time::Duration,
+    pub max_connections: Option<usize>,
+    pub max_handshakes_per_second: Option<u64>,
+    pub max_handshakes_per_ip: Option<u64>,
+    pub max_rate_per_conn: Option<u64>,
     pub root: String,
     pub index: String,
+    pub cache_size: Option<usize>,
     pub cert: String,
     pub key: String,
+    pub masque_proxy: bool,
+    pub proxy_to: Option<url::Url>,
+    pub metrics_listen: Option<String>,
+    pub access_log: Option<String>,
+    pub access_log_json: bool,
 }
 
 impl Args for ServerArgs {
@@ -379,18 +562,184 @@ impl Args for ServerArgs {
 
         let listen = args.get_str("--listen").to_string();
         let no_retry = args.get_bool("--no-retry");
+
+        let retry_key_file = if !args.get_str("--retry-key-file").is_empty() {
+            Some(args.get_str("--retry-key-file").to_string())
+        } else {
+            None
+        };
+
+        let new_token = args.get_bool("--new-token");
+
+        let token_lifetime = args.get_str("--token-lifetime");
+        let token_lifetime = token_lifetime.parse::<u64>().unwrap();
+        let token_lifetime = std::time::Duration::from_secs(token_lifetime);
+
+        let max_connections = if !args.get_str("--max-connections").is_empty() {
+            Some(args.get_str("--max-connections").parse::<usize>().unwrap())
+        } else {
+            None
+        };
+
+        let max_handshakes_per_second =
+            if !args.get_str("--max-handshakes-per-second").is_empty() {
+                let v = args.get_str("--max-handshakes-per-second");
+                Some(v.parse::<u64>().unwrap())
+            } else {
+                None
+            };
+
+        let max_handshakes_per_ip =
+            if !args.get_str("--max-handshakes-per-ip").is_empty() {
+                let v = args.get_str("--max-handshakes-per-ip");
+                Some(v.parse::<u64>().unwrap())
+            } else {
+                None
+            };
+
+        let max_rate_per_conn = if !args.get_str("--max-rate-per-conn").is_empty()
+        {
+            let v = args.get_str("--max-rate-per-conn");
+            let v = v.parse::<u64>().unwrap();
+
+            if v == 0 {
+                panic!("--max-rate-per-conn must be greater than 0");
+            }
+
+            Some(v)
+        } else {
+            None
+        };
+
         let root = args.get_str("--root").to_string();
         let index = args.get_str("--index").to_string();
+
+        let cache_size = if !args.get_str("--cache-size").is_empty() {
+            Some(args.get_str("--cache-size").parse::<usize>().unwrap())
+        } else {
+            None
+        };
+
         let cert = args.get_str("--cert").to_string();
         let key = args.get_str("--key").to_string();
+        let masque_proxy = args.get_bool("--masque-proxy");
+
+        let proxy_to = if !args.get_str("--proxy-to").is_empty() {
+            let url = url::Url::parse(args.get_str("--proxy-to")).unwrap();
+
+            if url.scheme() != "http" {
+                panic!("only http:// upstreams are supported");
+            }
+
+            Some(url)
+        } else {
+            None
+        };
+
+        let metrics_listen = if !args.get_str("--metrics-listen").is_empty() {
+            Some(args.get_str("--metrics-listen").to_string())
+        } else {
+            None
+        };
+
+        let access_log = if !args.get_str("--access-log").is_empty() {
+            Some(args.get_str("--access-log").to_string())
+        } else {
+            None
+        };
+
+        let access_log_json = args.get_bool("--access-log-json");
 
         ServerArgs {
             listen,
             no_retry,
+            retry_key_file,
+            new_token,
+            token_lifetime,
+            max_connections,
+            max_handshakes_per_second,
+            max_handshakes_per_ip,
+            max_rate_per_conn,
             root,
             index,
+            cache_size,
             cert,
             key,
+            masque_proxy,
+            proxy_to,
+            metrics_listen,
+            access_log,
+            access_log_json,
+        }
+    }
+}
+
+pub const PROXY_USAGE: &str = "Usage:
+  quiche-proxy [options] ORIGIN
+  quiche-proxy -h | --help
+
+Options:
+  --listen <addr>             Accept HTTP/1.1 connections on the given IP:port [default: 127.0.0.1:8080].
+  --max-data BYTES            Connection-wide flow control limit [default: 10000000].
+  --max-window BYTES          Connection-wide max receiver window [default: 25165824].
+  --max-stream-data BYTES     Per-stream flow control limit [default: 1000000].
+  --max-stream-window BYTES   Per-stream max receiver window [default: 16777216].
+  --max-streams-bidi STREAMS  Number of allowed concurrent streams [default: 100].
+  --max-streams-uni STREAMS   Number of allowed concurrent streams [default: 100].
+  --idle-timeout TIMEOUT      Idle timeout in milliseconds [default: 30000].
+  --wire-version VERSION      The version number to send to the origin [default: babababa].
+  --http-version VERSION      HTTP version to use [default: HTTP/3].
+  --early-data                Enable sending early data.
+  --dgram-proto PROTO         DATAGRAM application protocol to use [default: none].
+  --dgram-count COUNT         Number of DATAGRAMs to send [default: 0].
+  --dgram-data DATA           Data to send for certain types of DATAGRAM application protocol [default: quack].
+  --dump-packets PATH         Dump the incoming packets as files in the given directory.
+  --connect-to ADDRESS        Override the origin's address.
+  --no-verify                 Don't verify the origin's certificate.
+  --no-grease                 Don't send GREASE.
+  --cc-algorithm NAME         Specify which congestion control algorithm to use [default: cubic].
+  --disable-hystart           Disable HyStart++.
+  -h --help                   Show this screen.
+";
+
+/// Application-specific arguments that compliment the `CommonArgs`.
+pub struct ProxyArgs {
+    pub version: u32,
+    pub listen: String,
+    pub origin: url::Url,
+    pub connect_to: Option<String>,
+    pub no_verify: bool,
+}
+
+impl Args for ProxyArgs {
+    fn with_docopt(docopt: &docopt::Docopt) -> Self {
+        let args = docopt.parse().unwrap_or_else(|e| e.exit());
+
+        let version = args.get_str("--wire-version");
+        let version = u32::from_str_radix(version, 16).unwrap();
+
+        let listen = args.get_str("--listen").to_string();
+
+        let origin = url::Url::parse(args.get_str("ORIGIN")).unwrap();
+
+        if origin.scheme() != "https" {
+            panic!("the origin must be an https:// URL");
+        }
+
+        let connect_to = if !args.get_str("--connect-to").is_empty() {
+            Some(args.get_str("--connect-to").to_string())
+        } else {
+            None
+        };
+
+        let no_verify = args.get_bool("--no-verify");
+
+        ProxyArgs {
+            version,
+            listen,
+            origin,
+            connect_to,
+            no_verify,
         }
     }
 }
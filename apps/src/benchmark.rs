@@ -0,0 +1,471 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! HTTP/3 load generation, in the style of h2load.
+//!
+//! In benchmark mode the client opens `--connections` parallel connections to
+//! the server and sends `--requests-per-connection` requests on each of them,
+//! cycling through the given URLs, with at most `--max-concurrent-requests`
+//! requests in flight per connection. When `--duration` is given, connections
+//! that sent all their requests are replaced by new ones until the time is
+//! up. The request rate, throughput and latency percentiles are reported at
+//! the end.
+
+use std::collections::HashMap;
+
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::args::*;
+use crate::bind::BindOptions;
+use crate::client::*;
+use crate::common::*;
+
+use quiche::h3::NameValue;
+
+/// A benchmark connection, and the requests in flight on it.
+struct BenchConn {
+    socket: mio::net::UdpSocket,
+    conn: std::pin::Pin<Box<quiche::Connection>>,
+    h3_conn: Option<quiche::h3::Connection>,
+    reqs_sent: u64,
+    in_flight: HashMap<u64, Instant>,
+
+    /// Set when the socket failed, so the connection can't be used anymore.
+    failed: bool,
+}
+
+impl BenchConn {
+    fn new(
+        poll: &mio::Poll, token: usize, peer_addr: SocketAddr, url: &url::Url,
+        config: &mut quiche::Config, bind: &BindOptions,
+    ) -> Result<BenchConn, ClientError> {
+        let (socket, conn) = open_connection(
+            poll,
+            mio::Token(token),
+            peer_addr,
+            url,
+            config,
+            bind,
+        )?;
+
+        let mut c = BenchConn {
+            socket,
+            conn,
+            h3_conn: None,
+            reqs_sent: 0,
+            in_flight: HashMap::new(),
+            failed: false,
+        };
+
+        c.flush()?;
+
+        Ok(c)
+    }
+
+    /// Processes the responses received on the connection.
+    fn handle_responses(&mut self, report: &mut BenchmarkReport, buf: &mut [u8]) {
+        let h3_conn = self.h3_conn.as_mut().unwrap();
+
+        loop {
+            match h3_conn.poll(&mut self.conn) {
+                Ok((_, quiche::h3::Event::Headers { list, .. })) => {
+                    let status = list
+                        .iter()
+                        .find(|h| h.name() == b":status")
+                        .and_then(|h| std::str::from_utf8(h.value()).ok())
+                        .and_then(|v| v.parse::<usize>().ok())
+                        .unwrap_or(0);
+
+                    if let Some(v) = report.statuses.get_mut(status / 100) {
+                        *v += 1;
+                    }
+                },
+
+                Ok((stream_id, quiche::h3::Event::Data)) => {
+                    while let Ok(read) =
+                        h3_conn.recv_body(&mut self.conn, stream_id, buf)
+                    {
+                        report.bytes_recv += read as u64;
+                    }
+                },
+
+                Ok((stream_id, quiche::h3::Event::Finished)) => {
+                    if let Some(t) = self.in_flight.remove(&stream_id) {
+                        let now = Instant::now();
+
+                        report.latencies.push(now - t);
+                        report.reqs_complete += 1;
+                        report.last_response = Some(now);
+                    }
+                },
+
+                Ok((stream_id, quiche::h3::Event::Reset(_))) => {
+                    if self.in_flight.remove(&stream_id).is_some() {
+                        report.reqs_failed += 1;
+                    }
+                },
+
+                Ok(_) => (),
+
+                Err(quiche::h3::Error::Done) => break,
+
+                Err(e) => {
+                    error!("HTTP/3 processing failed: {:?}", e);
+                    break;
+                },
+            }
+        }
+    }
+
+    /// Sends new requests, as long as the concurrency and stream limits
+    /// allow.
+    fn send_requests(
+        &mut self, args: &ClientArgs, max_concurrent: usize, next_url: &mut usize,
+    ) {
+        let h3_conn = self.h3_conn.as_mut().unwrap();
+
+        while self.in_flight.len() < max_concurrent &&
+            self.reqs_sent < args.reqs_per_conn
+        {
+            let url = &args.urls[*next_url % args.urls.len()];
+            let hdrs =
+                make_h3_request_headers(url, &args.method, &args.req_headers);
+
+            match h3_conn.send_request(&mut self.conn, &hdrs, true) {
+                Ok(stream_id) => {
+                    self.in_flight.insert(stream_id, Instant::now());
+                    self.reqs_sent += 1;
+
+                    *next_url += 1;
+                },
+
+                Err(quiche::h3::Error::TransportError(
+                    quiche::Error::StreamLimit(_),
+                )) |
+                Err(quiche::h3::Error::StreamBlocked) => break,
+
+                Err(e) => {
+                    error!("failed to send request {:?}", e);
+                    break;
+                },
+            }
+        }
+    }
+
+    /// Feeds the packets waiting on the socket to the connection.
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(), ClientError> {
+        recv_packets(&self.socket, &mut self.conn, buf)
+    }
+
+    /// Sends the packets generated by the connection.
+    fn flush(&mut self) -> Result<(), ClientError> {
+        send_packets(&self.socket, &mut self.conn)
+    }
+}
+
+/// The results of a benchmark.
+#[derive(Default)]
+pub struct BenchmarkReport {
+    elapsed: Duration,
+    connections: u64,
+    failed_connections: u64,
+    reqs_complete: u64,
+    reqs_failed: u64,
+    statuses: [u64; 6],
+    bytes_recv: u64,
+    latencies: Vec<Duration>,
+    last_response: Option<Instant>,
+}
+
+impl BenchmarkReport {
+    /// Returns the given percentile of the request latencies, which must be
+    /// sorted.
+    fn latency(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::default();
+        }
+
+        let i = ((self.latencies.len() - 1) as f64 * p).round() as usize;
+
+        self.latencies[i]
+    }
+}
+
+impl std::fmt::Display for BenchmarkReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+
+        let (rps, mbps) = if secs > 0.0 {
+            (
+                self.reqs_complete as f64 / secs,
+                self.bytes_recv as f64 / secs / 1_000_000.0,
+            )
+        } else {
+            (0.0, 0.0)
+        };
+
+        writeln!(
+            f,
+            "finished in {:.2}s, {:.2} req/s, {:.2} MB/s",
+            secs, rps, mbps
+        )?;
+
+        writeln!(
+            f,
+            "requests: {} succeeded, {} failed",
+            self.reqs_complete, self.reqs_failed
+        )?;
+
+        writeln!(
+            f,
+            "status codes: {} 2xx, {} 3xx, {} 4xx, {} 5xx",
+            self.statuses[2],
+            self.statuses[3],
+            self.statuses[4],
+            self.statuses[5]
+        )?;
+
+        writeln!(f, "traffic: {} bytes received", self.bytes_recv)?;
+
+        writeln!(
+            f,
+            "connections: {} opened, {} failed",
+            self.connections, self.failed_connections
+        )?;
+
+        writeln!(
+            f,
+            "latency: min {:?}, p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.latency(0.0),
+            self.latency(0.5),
+            self.latency(0.9),
+            self.latency(0.99),
+            self.latency(1.0)
+        )
+    }
+}
+
+/// Runs the benchmark described by the client arguments, and passes the
+/// report to `output_sink` once done.
+pub fn run(
+    args: ClientArgs, conn_args: CommonArgs,
+    mut output_sink: impl FnMut(String) + 'static,
+) -> Result<(), ClientError> {
+    let mut buf = [0; 65535];
+
+    let poll = mio::Poll::new().unwrap();
+    let mut events = mio::Events::with_capacity(1024);
+
+    let connect_url = &args.urls[0];
+
+    let peer_addr = if let Some(addr) = &args.connect_to {
+        addr.parse().unwrap()
+    } else {
+        connect_url
+            .to_socket_addrs()
+            .unwrap()
+            .find(|addr| conn_args.bind.accepts(addr))
+            .ok_or_else(|| {
+                ClientError::Other(format!(
+                    "no address of {} matches the requested address family",
+                    connect_url
+                ))
+            })?
+    };
+
+    let mut config = make_config(args.version, args.no_verify, &conn_args);
+
+    let h3_config = quiche::h3::Config::new().unwrap();
+
+    let max_concurrent = args.max_concurrent_reqs.unwrap_or(1);
+
+    let mut report = BenchmarkReport::default();
+
+    let start = Instant::now();
+    let deadline = args.duration.map(|d| start + d);
+
+    let mut next_url = 0;
+
+    let mut conns = Vec::with_capacity(args.connections);
+
+    for token in 0..args.connections {
+        let c = BenchConn::new(
+            &poll,
+            token,
+            peer_addr,
+            connect_url,
+            &mut config,
+            &conn_args.bind,
+        )?;
+
+        conns.push(Some(c));
+        report.connections += 1;
+    }
+
+    while conns.iter().any(|c| c.is_some()) {
+        let timeout = conns
+            .iter()
+            .flatten()
+            .filter_map(|c| c.conn.timeout())
+            .min();
+
+        let timeout = match deadline {
+            Some(d) if Instant::now() < d => {
+                let left = d - Instant::now();
+                Some(timeout.map_or(left, |t| std::cmp::min(t, left)))
+            },
+
+            _ => timeout,
+        };
+
+        poll.poll(&mut events, timeout).unwrap();
+
+        for event in &events {
+            if let Some(c) = conns[event.token().0].as_mut() {
+                if let Err(e) = c.recv(&mut buf) {
+                    error!(
+                        "connection {} recv failed: {:?}",
+                        c.conn.trace_id(),
+                        e
+                    );
+
+                    c.failed = true;
+                }
+            }
+        }
+
+        let expired = matches!(deadline, Some(d) if Instant::now() >= d);
+
+        for (token, slot) in conns.iter_mut().enumerate() {
+            let c = match slot {
+                Some(v) => v,
+
+                None => continue,
+            };
+
+            // With many connections the event loop rarely times out, so
+            // timers are checked on every iteration instead.
+            c.conn.on_timeout();
+
+            if c.conn.is_established() && c.h3_conn.is_none() {
+                let app_proto = c.conn.application_proto();
+                let app_proto = std::str::from_utf8(app_proto).unwrap_or("");
+
+                if !alpns::HTTP_3.contains(&app_proto) {
+                    return Err(ClientError::Other(format!(
+                        "benchmark mode requires HTTP/3, got {:?}",
+                        app_proto
+                    )));
+                }
+
+                match quiche::h3::Connection::with_transport(
+                    &mut c.conn,
+                    &h3_config,
+                ) {
+                    Ok(v) => c.h3_conn = Some(v),
+
+                    Err(e) => {
+                        error!("failed to create HTTP/3 connection: {:?}", e);
+
+                        c.conn.close(false, 0x1, b"fail").ok();
+                    },
+                }
+            }
+
+            if c.h3_conn.is_some() {
+                c.handle_responses(&mut report, &mut buf);
+
+                if !expired {
+                    c.send_requests(&args, max_concurrent, &mut next_url);
+                }
+
+                if c.in_flight.is_empty() && c.reqs_sent == args.reqs_per_conn {
+                    c.conn.close(true, 0x00, b"kthxbye").ok();
+                }
+            }
+
+            // Requests still in flight when the time is up are not counted.
+            if expired {
+                c.in_flight.clear();
+                c.conn.close(true, 0x00, b"kthxbye").ok();
+            }
+
+            if let Err(e) = c.flush() {
+                error!("connection {} send failed: {:?}", c.conn.trace_id(), e);
+
+                c.failed = true;
+            }
+
+            if !c.conn.is_closed() && !c.failed {
+                continue;
+            }
+
+            if !c.conn.is_established() || c.failed {
+                error!("connection {} failed", c.conn.trace_id());
+
+                report.failed_connections += 1;
+            }
+
+            report.reqs_failed += c.in_flight.len() as u64;
+
+            *slot = None;
+
+            if args.duration.is_some() && !expired {
+                let c = BenchConn::new(
+                    &poll,
+                    token,
+                    peer_addr,
+                    connect_url,
+                    &mut config,
+                    &conn_args.bind,
+                )?;
+
+                *slot = Some(c);
+                report.connections += 1;
+            }
+        }
+    }
+
+    report.elapsed = match deadline {
+        Some(d) => std::cmp::min(d, Instant::now()) - start,
+
+        None => report.last_response.unwrap_or(start) - start,
+    };
+
+    report.latencies.sort();
+
+    output_sink(report.to_string());
+
+    if report.reqs_complete == 0 {
+        return Err(ClientError::HttpFail);
+    }
+
+    Ok(())
+}
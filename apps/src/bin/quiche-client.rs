@@ -30,6 +30,8 @@ use quiche_apps::common::*;
 
 use quiche_apps::client::*;
 
+use quiche_apps::benchmark;
+
 fn main() {
     env_logger::builder()
         .default_format_timestamp_nanos(true)
@@ -40,7 +42,13 @@ fn main() {
     let conn_args = CommonArgs::with_docopt(&docopt);
     let args = ClientArgs::with_docopt(&docopt);
 
-    match connect(args, conn_args, stdout_sink) {
+    let res = if args.benchmark {
+        benchmark::run(args, conn_args, stdout_sink)
+    } else {
+        connect(args, conn_args, stdout_sink)
+    };
+
+    match res {
         Err(ClientError::HandshakeFail) => std::process::exit(-1),
 
         Err(ClientError::HttpFail) => std::process::exit(-2),
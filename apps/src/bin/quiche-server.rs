@@ -41,21 +41,59 @@ use std::cell::RefCell;
 
 use ring::rand::*;
 
+use quiche_apps::access_log::*;
+
 use quiche_apps::args::*;
 
+use quiche_apps::cache::*;
+
 use quiche_apps::common::*;
 
+use quiche_apps::impair::*;
+
+use quiche_apps::limits::*;
+
+use quiche_apps::metrics::*;
+
+use quiche_apps::proxy::*;
+
+use quiche_apps::reload::*;
+
 use quiche_apps::sendto::*;
 
+use quiche_apps::token::*;
+
 const MAX_BUF_SIZE: usize = 65536;
 
 const MAX_DATAGRAM_SIZE: usize = 1350;
 
 const MAX_SEND_BURST_PACKETS: usize = 10;
 
+// The bursts of all connections are sent together, as long as they fit in a
+// buffer of this size.
+const MAX_SEND_BATCH_SIZE: usize = 16 * MAX_BUF_SIZE;
+
+const METRICS_TOKEN: mio::Token = mio::Token(1);
+
+// The sockets of all proxied CONNECT-UDP flows share the same token, as
+// readiness is handled by relaying the payloads of every flow.
+const MASQUE_TOKEN: mio::Token = mio::Token(2);
+
+// Likewise, the connections of all requests forwarded to the upstream share
+// the same token.
+const UPSTREAM_TOKEN: mio::Token = mio::Token(3);
+
+// The CONNECTION_REFUSED transport error code.
+const CONNECTION_REFUSED: u64 = 0x2;
+
 fn main() {
     let mut buf = [0; MAX_BUF_SIZE];
     let mut out = [0; MAX_BUF_SIZE];
+    let mut batch_buf = vec![0; MAX_SEND_BATCH_SIZE];
+
+    // The packets in `batch_buf` that are still to be sent.
+    let mut bursts = Vec::new();
+    let mut write_interest = false;
 
     env_logger::builder()
         .default_format_timestamp_nanos(true)
@@ -71,11 +109,24 @@ fn main() {
     let mut events = mio::Events::with_capacity(1024);
 
     // Create the UDP listening socket, and register it with the event loop.
-    let socket = net::UdpSocket::bind(args.listen).unwrap();
+    let listen = net::ToSocketAddrs::to_socket_addrs(&args.listen)
+        .unwrap()
+        .find(|addr| conn_args.bind.accepts(addr))
+        .expect("no listen address matches the requested address family");
+
+    let socket = conn_args.bind.bind(listen).unwrap();
 
     info!("listening on {:}", socket.local_addr().unwrap());
 
     let socket = mio::net::UdpSocket::from_socket(socket).unwrap();
+
+    if conn_args.dscp != 0 {
+        set_dscp(&socket, conn_args.dscp).unwrap();
+    }
+
+    // All connections share the listening socket, so its marking is changed
+    // whenever packets need a different one.
+    let mut dscp_marker = DscpMarker::new(conn_args.dscp);
     poll.register(
         &socket,
         mio::Token(0),
@@ -84,6 +135,32 @@ fn main() {
     )
     .unwrap();
 
+    // Serve metrics if the user asked for it.
+    let metrics_listener = args.metrics_listen.as_ref().map(|addr| {
+        let listener = MetricsListener::bind(addr, &poll, METRICS_TOKEN).unwrap();
+
+        info!("serving metrics on {}", addr);
+
+        listener
+    });
+
+    let mut metrics = Metrics::default();
+
+    let mut access_log = args.access_log.as_ref().map(|path| {
+        AccessLog::open(path, args.access_log_json)
+            .expect("failed to open the access log")
+    });
+
+    // Resolve the upstream once, as resolving blocks the event loop.
+    let upstream = args.proxy_to.clone().map(|url| {
+        Upstream::resolve(url).expect("failed to resolve the upstream")
+    });
+
+    // The cache is shared by all connections.
+    let response_cache = args
+        .cache_size
+        .map(|size| Rc::new(RefCell::new(ResponseCache::new(size))));
+
     let max_datagram_size = MAX_DATAGRAM_SIZE;
     let enable_gso = detect_gso(&socket, max_datagram_size);
     let enable_sendmmsg = detect_sendmmsg();
@@ -97,6 +174,9 @@ fn main() {
     config.load_cert_chain_from_pem_file(&args.cert).unwrap();
     config.load_priv_key_from_pem_file(&args.key).unwrap();
 
+    // Pick up renewed certificates without restarting the server.
+    let mut cert_reloader = CertReloader::new(&args.cert, &args.key);
+
     config.set_application_protos(&conn_args.alpns).unwrap();
 
     config.set_max_idle_timeout(conn_args.idle_timeout);
@@ -113,6 +193,8 @@ fn main() {
     config.set_max_connection_window(conn_args.max_window);
     config.set_max_stream_window(conn_args.max_stream_window);
 
+    config.set_dscp(conn_args.dscp);
+
     let mut keylog = None;
 
     if let Some(keylog_path) = std::env::var_os("SSLKEYLOGFILE") {
@@ -143,7 +225,8 @@ fn main() {
         config.enable_hystart(false);
     }
 
-    if conn_args.dgrams_enabled {
+    // CONNECT-UDP payloads are carried in HTTP Datagrams.
+    if conn_args.dgrams_enabled || args.masque_proxy {
         config.enable_dgram(true, 1000, 1000);
     }
 
@@ -151,6 +234,26 @@ fn main() {
     let conn_id_seed =
         ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &rng).unwrap();
 
+    // Keys protecting the address validation tokens sent in Retry packets and
+    // NEW_TOKEN frames.
+    let mut address_tokens = match &args.retry_key_file {
+        Some(path) => AddressTokens::from_file(path).unwrap_or_else(|e| {
+            panic!("failed to read token keys from {}: {:?}", path, e)
+        }),
+
+        None => AddressTokens::with_random_key(),
+    };
+
+    address_tokens.set_new_token_lifetime(args.token_lifetime);
+
+    let mut limits = ConnectionLimits::new(
+        args.max_connections,
+        args.max_handshakes_per_second,
+        args.max_handshakes_per_ip,
+    );
+
+    let mut impairment = Impairment::new(conn_args.impairment);
+
     let mut clients = ClientMap::new();
 
     let mut pkt_count = 0;
@@ -167,15 +270,56 @@ fn main() {
             false => clients.values().filter_map(|c| c.conn.timeout()).min(),
         };
 
+        // Wake up when forwarded requests time out waiting for the upstream.
+        let upstreams = clients
+            .values()
+            .filter_map(|c| c.http_conn.as_ref()?.upstreams_timeout())
+            .min();
+
+        let timeout = match (timeout, upstreams) {
+            (Some(t), Some(upstreams)) => Some(cmp::min(t, upstreams)),
+
+            (t, upstreams) => t.or(upstreams),
+        };
+
+        // Wake up when throttled responses can be written again.
+        let throttled = clients
+            .values()
+            .filter(|c| !c.partial_responses.is_empty())
+            .filter_map(|c| c.http_conn.as_ref()?.throttle_timeout())
+            .min();
+
+        let timeout = match (timeout, throttled) {
+            (Some(t), Some(throttled)) => Some(cmp::min(t, throttled)),
+
+            (t, throttled) => t.or(throttled),
+        };
+
+        let timeout = match (timeout, impairment.timeout()) {
+            (Some(t), Some(impaired)) => Some(cmp::min(t, impaired)),
+
+            (t, impaired) => t.or(impaired),
+        };
+
         poll.poll(&mut events, timeout).unwrap();
 
+        cert_reloader.maybe_reload(&mut config);
+        address_tokens.maybe_reload();
+
+        if let Some(listener) = &metrics_listener {
+            if events.iter().any(|e| e.token() == METRICS_TOKEN) {
+                listener.serve(|| metrics.render(&clients));
+            }
+        }
+
         // Read incoming UDP packets from the socket and feed them to quiche,
         // until there are no more packets to read.
         'read: loop {
             // If the event loop reported no events, it means that the timeout
             // has expired, so handle it without attempting to read packets. We
-            // will then proceed with the send loop.
-            if events.is_empty() && !continue_write {
+            // will then proceed with the send loop. Datagrams held back by
+            // the impairment are still read once they are released.
+            if events.is_empty() && !continue_write && !impairment.has_rx_due() {
                 trace!("timed out");
 
                 clients.values_mut().for_each(|c| c.conn.on_timeout());
@@ -183,7 +327,7 @@ fn main() {
                 break 'read;
             }
 
-            let (len, from) = match socket.recv_from(&mut buf) {
+            let (len, from) = match impairment.recv_from(&socket, &mut buf) {
                 Ok(v) => v,
 
                 Err(e) => {
@@ -242,16 +386,17 @@ fn main() {
                     continue 'read;
                 }
 
-                if !quiche::version_is_supported(hdr.version) {
+                if !config.is_version_supported(hdr.version) {
                     warn!("Doing version negotiation");
 
-                    let len =
-                        quiche::negotiate_version(&hdr.scid, &hdr.dcid, &mut out)
-                            .unwrap();
+                    let len = quiche::negotiate_version_with_config(
+                        &hdr.scid, &hdr.dcid, &config, &mut out,
+                    )
+                    .unwrap();
 
                     let out = &out[..len];
 
-                    if let Err(e) = socket.send_to(out, &from) {
+                    if let Err(e) = impairment.send_to(&socket, out, &from) {
                         if e.kind() == std::io::ErrorKind::WouldBlock {
                             trace!("send() would block");
                             break;
@@ -267,49 +412,98 @@ fn main() {
 
                 let mut odcid = None;
 
-                if !args.no_retry {
-                    // Token is always present in Initial packets.
-                    let token = hdr.token.as_ref().unwrap();
-
-                    // Do stateless retry if the client didn't send a token.
-                    if token.is_empty() {
-                        warn!("Doing stateless retry");
-
-                        let scid = quiche::ConnectionId::from_ref(&scid);
-                        let new_token = mint_token(&hdr, &from);
-
-                        let len = quiche::retry(
-                            &hdr.scid,
-                            &hdr.dcid,
-                            &scid,
-                            &new_token,
-                            hdr.version,
-                            &mut out,
-                        )
-                        .unwrap();
+                // Token is always present in Initial packets.
+                let token = hdr.token.as_ref().unwrap();
+
+                let valid_token = if token.is_empty() {
+                    None
+                } else {
+                    let valid = address_tokens.validate(&from, token);
+
+                    if valid.is_none() {
+                        error!("Invalid address validation token");
+                    }
+
+                    valid
+                };
+
+                // Do stateless retry if the client didn't send a valid token.
+                // Tokens sent in NEW_TOKEN frames can expire, or be used from
+                // another address, so this isn't fatal. Clients exceeding the
+                // handshake rate limits also need to validate their address
+                // first, even when stateless retry is disabled.
+                let admission = if valid_token.is_none() && !args.no_retry {
+                    Admission::Retry
+                } else {
+                    limits.admit(from.ip(), clients.len(), valid_token.is_some())
+                };
+
+                if admission == Admission::Retry {
+                    warn!("Doing stateless retry");
+
+                    let scid = quiche::ConnectionId::from_ref(&scid);
+                    let new_token = address_tokens.mint_retry(&hdr, &from);
 
-                        let out = &out[..len];
+                    let len = quiche::retry(
+                        &hdr.scid,
+                        &hdr.dcid,
+                        &scid,
+                        &new_token,
+                        hdr.version,
+                        &mut out,
+                    )
+                    .unwrap();
 
-                        if let Err(e) = socket.send_to(out, &from) {
-                            if e.kind() == std::io::ErrorKind::WouldBlock {
-                                trace!("send() would block");
-                                break;
-                            }
+                    let out = &out[..len];
 
-                            panic!("send() failed: {:?}", e);
+                    if let Err(e) = impairment.send_to(&socket, out, &from) {
+                        if e.kind() == std::io::ErrorKind::WouldBlock {
+                            trace!("send() would block");
+                            break;
                         }
-                        continue 'read;
+
+                        panic!("send() failed: {:?}", e);
                     }
 
-                    odcid = validate_token(&from, token);
+                    metrics.on_retry();
 
-                    // The token was not valid, meaning the retry failed, so
-                    // drop the packet.
-                    if odcid.is_none() {
-                        error!("Invalid address validation token");
-                        continue;
+                    continue 'read;
+                }
+
+                if admission == Admission::Refuse {
+                    warn!("Refusing connection from {}", from);
+
+                    let scid = quiche::ConnectionId::from_ref(&scid);
+
+                    let len = quiche::refuse(
+                        &hdr.scid,
+                        &hdr.dcid,
+                        &scid,
+                        CONNECTION_REFUSED,
+                        hdr.version,
+                        &mut out,
+                    )
+                    .unwrap();
+
+                    let out = &out[..len];
+
+                    if let Err(e) = impairment.send_to(&socket, out, &from) {
+                        if e.kind() == std::io::ErrorKind::WouldBlock {
+                            trace!("send() would block");
+                            break;
+                        }
+
+                        panic!("send() failed: {:?}", e);
                     }
 
+                    metrics.on_refused();
+
+                    continue 'read;
+                }
+
+                // A token sent in a NEW_TOKEN frame on a previous connection
+                // already validated the client's address.
+                if let Some(ValidToken::Retry(v)) = valid_token {
                     if scid.len() != hdr.dcid.len() {
                         error!("Invalid destination connection ID");
                         continue 'read;
@@ -318,6 +512,8 @@ fn main() {
                     // Reuse the source connection ID we sent in the Retry
                     // packet, instead of changing it again.
                     scid.copy_from_slice(&hdr.dcid);
+
+                    odcid = Some(v);
                 }
 
                 let scid = quiche::ConnectionId::from_vec(scid.to_vec());
@@ -329,6 +525,13 @@ fn main() {
                     quiche::accept(&scid, odcid.as_ref(), from, &mut config)
                         .unwrap();
 
+                // Let the client skip stateless retry on its next connection.
+                if args.new_token {
+                    let token = address_tokens.mint_new_token(&from);
+
+                    conn.send_new_token(&token).unwrap();
+                }
+
                 if let Some(keylog) = &mut keylog {
                     if let Ok(keylog) = keylog.try_clone() {
                         conn.set_keylog(Box::new(keylog));
@@ -343,13 +546,15 @@ fn main() {
                         let writer = make_qlog_writer(&dir, "server", &id);
 
                         conn.set_qlog(
-                            std::boxed::Box::new(writer),
+                            writer,
                             "quiche-server qlog".to_string(),
                             format!("{} id={}", "quiche-server qlog", id),
                         );
                     }
                 }
 
+                metrics.on_connection();
+
                 let client = Client {
                     conn,
                     http_conn: None,
@@ -420,6 +625,8 @@ fn main() {
                         &mut client.conn,
                         dgram_sender,
                         Rc::new(RefCell::new(stdout_sink)),
+                        args.masque_proxy,
+                        upstream.clone(),
                     ));
 
                     client.app_proto_selected = true;
@@ -432,6 +639,24 @@ fn main() {
                     client.app_proto_selected = true;
                 }
 
+                if let (Some(http_conn), Some(rate)) =
+                    (client.http_conn.as_mut(), args.max_rate_per_conn)
+                {
+                    http_conn.set_max_send_rate(rate);
+                }
+
+                if let (Some(http_conn), Some(cache)) =
+                    (client.http_conn.as_mut(), &response_cache)
+                {
+                    http_conn.set_response_cache(cache.clone());
+                }
+
+                if let (Some(http_conn), Some(_)) =
+                    (client.http_conn.as_mut(), &access_log)
+                {
+                    http_conn.enable_access_log();
+                }
+
                 // Update max_datagram_size after connection established.
                 client.max_datagram_size =
                     client.conn.max_send_udp_payload_size();
@@ -460,6 +685,10 @@ fn main() {
                 {
                     continue 'read;
                 }
+
+                for status in http_conn.take_response_statuses() {
+                    metrics.on_h3_request(status);
+                }
             }
 
             // If we have a siduck connection, handle the quacks.
@@ -473,10 +702,93 @@ fn main() {
             }
         }
 
+        // Relay UDP payloads received from proxy targets, and responses from
+        // the upstream, back to clients.
+        for client in clients.values_mut() {
+            if let Some(http_conn) = client.http_conn.as_mut() {
+                http_conn.register_proxied_flows(&poll, MASQUE_TOKEN);
+                http_conn.relay_proxied_flows(&mut client.conn, &mut buf);
+                http_conn.register_upstreams(&poll, UPSTREAM_TOKEN);
+                http_conn.relay_upstreams(&mut client.conn, &mut buf);
+            }
+        }
+
+        // Resume the responses held back by the bandwidth throttle, as the
+        // client may have nothing left to acknowledge in the meantime.
+        if args.max_rate_per_conn.is_some() {
+            for client in clients.values_mut() {
+                let conn = &mut client.conn;
+                let partial_responses = &mut client.partial_responses;
+
+                if let Some(http_conn) = client.http_conn.as_mut() {
+                    for stream_id in conn.writable() {
+                        http_conn.handle_writable(
+                            conn,
+                            partial_responses,
+                            stream_id,
+                        );
+                    }
+                }
+            }
+        }
+
+        // Log the requests completed since the last iteration.
+        if let Some(access_log) = &mut access_log {
+            for client in clients.values_mut() {
+                let completed = match client.http_conn.as_mut() {
+                    Some(v) => v.take_completed_requests(),
+
+                    None => continue,
+                };
+
+                if completed.is_empty() {
+                    continue;
+                }
+
+                let peer = client
+                    .conn
+                    .path_stats()
+                    .find(|p| p.active)
+                    .map(|p| p.peer_addr);
+
+                for req in completed {
+                    if let Err(e) =
+                        access_log.log(peer, client.conn.trace_id(), &req)
+                    {
+                        error!("failed to write the access log: {:?}", e);
+                    }
+                }
+            }
+
+            if let Err(e) = access_log.flush() {
+                error!("failed to write the access log: {:?}", e);
+            }
+        }
+
+        if let Err(e) = impairment.flush(&socket) {
+            panic!("send_to() failed: {:?}", e);
+        }
+
         // Generate outgoing QUIC packets for all active connections and send
         // them on the UDP socket, until quiche reports that there are no more
         // packets to be sent.
         continue_write = false;
+
+        // Packets left over from the previous batch go first, and no new ones
+        // are generated until the socket takes them.
+        let mut blocked = !flush_batch(
+            &socket,
+            &batch_buf,
+            &mut bursts,
+            &mut dscp_marker,
+            &mut impairment,
+            enable_gso,
+            enable_sendmmsg,
+        )
+        .unwrap_or_else(|e| panic!("send_to() failed: {:?}", e));
+
+        let mut batch_len = 0;
+
         for client in clients.values_mut() {
             let max_send_burst = cmp::min(
                 MAX_BUF_SIZE,
@@ -485,6 +797,29 @@ fn main() {
             let mut total_write = 0;
             let mut dst_info = None;
 
+            // Send the bursts generated so far if this one might not fit.
+            if !blocked && batch_len + max_send_burst > batch_buf.len() {
+                blocked = !flush_batch(
+                    &socket,
+                    &batch_buf,
+                    &mut bursts,
+                    &mut dscp_marker,
+                    &mut impairment,
+                    enable_gso,
+                    enable_sendmmsg,
+                )
+                .unwrap_or_else(|e| panic!("send_to() failed: {:?}", e));
+
+                batch_len = 0;
+            }
+
+            if blocked {
+                trace!("send() would block");
+                break;
+            }
+
+            let out = &mut batch_buf[batch_len..batch_len + max_send_burst];
+
             while total_write < max_send_burst {
                 let (write, send_info) = match client
                     .conn
@@ -514,25 +849,20 @@ fn main() {
                 }
             }
 
-            if total_write == 0 || dst_info.is_none() {
-                break;
-            }
+            let dst_info = match dst_info {
+                Some(v) if total_write > 0 => v,
 
-            if let Err(e) = send_to(
-                &socket,
-                &out[..total_write],
-                &dst_info.unwrap().to,
+                _ => continue,
+            };
+
+            bursts.push((
+                batch_len..batch_len + total_write,
+                dst_info.to,
                 client.max_datagram_size,
-                enable_gso,
-                enable_sendmmsg,
-            ) {
-                if e.kind() == std::io::ErrorKind::WouldBlock {
-                    trace!("send() would block");
-                    break;
-                }
+                dst_info.dscp,
+            ));
 
-                panic!("send_to() failed: {:?}", e);
-            }
+            batch_len += total_write;
 
             trace!("{} written {} bytes", client.conn.trace_id(), total_write);
 
@@ -543,6 +873,38 @@ fn main() {
             }
         }
 
+        if !blocked {
+            blocked = !flush_batch(
+                &socket,
+                &batch_buf,
+                &mut bursts,
+                &mut dscp_marker,
+                &mut impairment,
+                enable_gso,
+                enable_sendmmsg,
+            )
+            .unwrap_or_else(|e| panic!("send_to() failed: {:?}", e));
+        }
+
+        // Wait for the socket to be writable again to send the rest.
+        if blocked != write_interest {
+            let interest = if blocked {
+                mio::Ready::readable() | mio::Ready::writable()
+            } else {
+                mio::Ready::readable()
+            };
+
+            poll.reregister(
+                &socket,
+                mio::Token(0),
+                interest,
+                mio::PollOpt::edge(),
+            )
+            .unwrap();
+
+            write_interest = blocked;
+        }
+
         // Garbage collect closed connections.
         clients.retain(|_, ref mut c| {
             trace!("Collecting garbage");
@@ -553,6 +915,8 @@ fn main() {
                     c.conn.trace_id(),
                     c.conn.stats()
                 );
+
+                metrics.on_connection_closed(&c.conn);
             }
 
             !c.conn.is_closed()
@@ -560,58 +924,132 @@ fn main() {
     }
 }
 
-/// Generate a stateless retry token.
-///
-/// The token includes the static string `"quiche"` followed by the IP address
-/// of the client and by the original destination connection ID generated by the
-/// client.
+/// A burst of packets in the batch buffer, with its destination, segment size
+/// and DSCP marking.
+type PendingBurst = (std::ops::Range<usize>, net::SocketAddr, usize, u8);
+
+/// Sends the bursts of packets coalesced in `buf`, and removes the packets
+/// that were sent from `bursts`.
 ///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn mint_token(hdr: &quiche::Header, src: &net::SocketAddr) -> Vec<u8> {
-    let mut token = Vec::new();
+/// Returns whether all of them were sent. Otherwise the socket's buffer is
+/// full, and the rest should be sent once it's writable again.
+fn flush_batch(
+    socket: &mio::net::UdpSocket, buf: &[u8], bursts: &mut Vec<PendingBurst>,
+    dscp_marker: &mut DscpMarker, impairment: &mut Impairment, enable_gso: bool,
+    enable_sendmmsg: bool,
+) -> std::io::Result<bool> {
+    let sent = send_batch(
+        socket,
+        buf,
+        bursts,
+        dscp_marker,
+        impairment,
+        enable_gso,
+        enable_sendmmsg,
+    );
+
+    let mut written = match sent {
+        Ok(v) => v,
+
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => 0,
+
+        Err(e) => return Err(e),
+    };
 
-    token.extend_from_slice(b"quiche");
+    // Only whole packets are sent, so the rest starts at a packet boundary.
+    while let Some((range, ..)) = bursts.first_mut() {
+        if written < range.len() {
+            range.start += written;
+            break;
+        }
 
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
+        written -= range.len();
 
-    token.extend_from_slice(&addr);
-    token.extend_from_slice(&hdr.dcid);
+        bursts.remove(0);
+    }
 
-    token
+    Ok(bursts.is_empty())
 }
 
-/// Validates a stateless retry token.
+/// Sends the bursts of packets coalesced in `buf`, in as few system calls as
+/// possible.
 ///
-/// This checks that the ticket includes the `"quiche"` static string, and that
-/// the client IP address matches the address stored in the ticket.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn validate_token<'a>(
-    src: &net::SocketAddr, token: &'a [u8],
-) -> Option<quiche::ConnectionId<'a>> {
-    if token.len() < 6 {
-        return None;
-    }
+/// The DSCP marking is a socket option, so bursts with different markings are
+/// sent separately.
+fn send_batch(
+    socket: &mio::net::UdpSocket, buf: &[u8], bursts: &[PendingBurst],
+    dscp_marker: &mut DscpMarker, impairment: &mut Impairment, enable_gso: bool,
+    enable_sendmmsg: bool,
+) -> std::io::Result<usize> {
+    // Impaired packets are handled one by one.
+    if !impairment.is_none() {
+        let mut written = 0;
+
+        for (range, to, segment_size, dscp) in bursts {
+            mark_dscp(socket, dscp_marker, *dscp);
+
+            written += impairment.send_segments(
+                socket,
+                &buf[range.clone()],
+                to,
+                *segment_size,
+            )?;
+        }
 
-    if &token[..6] != b"quiche" {
-        return None;
+        return Ok(written);
     }
 
-    let token = &token[6..];
+    let mut written = 0;
+    let mut start = 0;
 
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
+    while start < bursts.len() {
+        let dscp = bursts[start].3;
+
+        let end = bursts[start..]
+            .iter()
+            .position(|(.., v)| *v != dscp)
+            .map_or(bursts.len(), |i| start + i);
+
+        mark_dscp(socket, dscp_marker, dscp);
+
+        let run: Vec<Burst> = bursts[start..end]
+            .iter()
+            .map(|(range, to, segment_size, _)| Burst {
+                buf: &buf[range.clone()],
+
+                target: *to,
+
+                segment_size: *segment_size,
+            })
+            .collect();
 
-    if token.len() < addr.len() || &token[..addr.len()] != addr.as_slice() {
-        return None;
+        let run_len: usize = run.iter().map(|b| b.buf.len()).sum();
+
+        match send_bursts(socket, &run, enable_gso, enable_sendmmsg) {
+            Ok(v) => {
+                written += v;
+
+                // The socket is full, the rest is sent later.
+                if v < run_len {
+                    break;
+                }
+            },
+
+            // Report the bursts that were sent before the error.
+            Err(_) if written > 0 => break,
+
+            Err(e) => return Err(e),
+        }
+
+        start = end;
     }
 
-    Some(quiche::ConnectionId::from_ref(&token[addr.len()..]))
+    Ok(written)
+}
+
+/// Sets the socket's DSCP marking for the next packets, if it changed.
+fn mark_dscp(socket: &mio::net::UdpSocket, marker: &mut DscpMarker, dscp: u8) {
+    if let Err(e) = marker.mark(socket, dscp) {
+        warn!("failed to set DSCP {}: {:?}", dscp, e);
+    }
 }
@@ -0,0 +1,141 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Binding of UDP sockets to specific local addresses and interfaces.
+//!
+//! Multi-homed hosts can pin the traffic of the client and server to a given
+//! address family, local address or network interface, the latter using
+//! `SO_BINDTODEVICE` on Linux.
+
+use std::io;
+
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+
+/// The IP version sockets are restricted to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// Only use IPv4 addresses.
+    V4,
+
+    /// Only use IPv6 addresses.
+    V6,
+}
+
+/// Constraints on the local side of UDP sockets.
+#[derive(Clone, Debug, Default)]
+pub struct BindOptions {
+    /// The local address to bind to, instead of the unspecified one.
+    pub address: Option<IpAddr>,
+
+    /// The network interface to bind to.
+    pub interface: Option<String>,
+
+    /// The only address family to use.
+    pub family: Option<AddressFamily>,
+}
+
+impl BindOptions {
+    /// Returns whether sockets with these options can reach `peer`.
+    pub fn accepts(&self, peer: &SocketAddr) -> bool {
+        let family_ok = match self.family {
+            Some(AddressFamily::V4) => peer.is_ipv4(),
+
+            Some(AddressFamily::V6) => peer.is_ipv6(),
+
+            None => true,
+        };
+
+        let address_ok = match self.address {
+            Some(addr) => addr.is_ipv4() == peer.is_ipv4(),
+
+            None => true,
+        };
+
+        family_ok && address_ok
+    }
+
+    /// Creates a socket on an ephemeral port, suitable for sending to
+    /// `peer`.
+    pub fn bind_for(&self, peer: &SocketAddr) -> io::Result<UdpSocket> {
+        let ip = match (self.address, peer) {
+            (Some(addr), _) => addr,
+
+            (None, SocketAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+
+            (None, SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+
+        self.bind(SocketAddr::new(ip, 0))
+    }
+
+    /// Creates a socket listening on `addr`, whose IP is replaced by the
+    /// configured local address, if any.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        let addr = match self.address {
+            Some(ip) => SocketAddr::new(ip, addr.port()),
+
+            None => addr,
+        };
+
+        if !self.accepts(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} doesn't match the requested address family", addr),
+            ));
+        }
+
+        let socket = UdpSocket::bind(addr)?;
+
+        if let Some(interface) = &self.interface {
+            bind_to_device(&socket, interface)?;
+        }
+
+        Ok(socket)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &UdpSocket, interface: &str) -> io::Result<()> {
+    use nix::sys::socket::setsockopt;
+    use nix::sys::socket::sockopt::BindToDevice;
+    use std::ffi::OsString;
+    use std::os::unix::io::AsRawFd;
+
+    setsockopt(socket.as_raw_fd(), BindToDevice, &OsString::from(interface))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_socket: &UdpSocket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux",
+    ))
+}
@@ -0,0 +1,284 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! In-memory caching of static responses.
+//!
+//! A [`ResponseCache`] keeps the content of recently served files, keyed by
+//! their path, so that they don't need to be read from disk again. Entries are
+//! only used while the file's modification time and size are unchanged, and
+//! the least recently used ones are evicted once the total size of the cached
+//! files exceeds the configured capacity.
+//!
+//! This module also formats and parses the HTTP dates used by the
+//! `Last-Modified` and `If-Modified-Since` headers.
+//!
+//! [`ResponseCache`]: struct.ResponseCache.html
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use std::io::prelude::*;
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use std::rc::Rc;
+
+use std::time::Duration;
+use std::time::SystemTime;
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+pub(crate) const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov",
+    "Dec",
+];
+
+struct Entry {
+    body: Rc<[u8]>,
+
+    modified: SystemTime,
+
+    /// The position of the entry in the eviction order.
+    tick: u64,
+}
+
+/// A least recently used cache of file contents.
+pub struct ResponseCache {
+    capacity: usize,
+
+    size: usize,
+
+    entries: HashMap<PathBuf, Entry>,
+
+    /// The cached paths, from the least to the most recently used.
+    order: BTreeMap<u64, PathBuf>,
+
+    next_tick: u64,
+}
+
+impl ResponseCache {
+    /// Creates a cache holding up to `capacity` bytes of file contents.
+    pub fn new(capacity: usize) -> Self {
+        ResponseCache {
+            capacity,
+
+            size: 0,
+
+            entries: HashMap::new(),
+
+            order: BTreeMap::new(),
+
+            next_tick: 0,
+        }
+    }
+
+    /// Returns the content of the file at `path`, which was opened as `file`,
+    /// reading it from disk unless the cached copy is still current.
+    ///
+    /// Returns `None` if the file is too large to be cached, or can't be
+    /// read, in which case it should be read from disk as usual.
+    pub fn get(
+        &mut self, path: &Path, file: &mut std::fs::File,
+    ) -> Option<Rc<[u8]>> {
+        let metadata = file.metadata().ok()?;
+        let modified = metadata.modified().ok()?;
+        let len = metadata.len();
+
+        if len > self.capacity as u64 {
+            return None;
+        }
+
+        let tick = self.next_tick;
+        self.next_tick += 1;
+
+        if let Some(entry) = self.entries.get_mut(path) {
+            if entry.modified == modified && entry.body.len() as u64 == len {
+                self.order.remove(&entry.tick);
+                self.order.insert(tick, path.to_path_buf());
+
+                entry.tick = tick;
+
+                return Some(entry.body.clone());
+            }
+        }
+
+        self.remove(path);
+
+        let mut body = Vec::with_capacity(len as usize);
+
+        file.seek(std::io::SeekFrom::Start(0)).ok()?;
+        file.read_to_end(&mut body).ok()?;
+
+        let body: Rc<[u8]> = Rc::from(body);
+
+        while self.size + body.len() > self.capacity {
+            let oldest = match self.order.values().next() {
+                Some(v) => v.clone(),
+
+                None => break,
+            };
+
+            self.remove(&oldest);
+        }
+
+        self.size += body.len();
+
+        self.order.insert(tick, path.to_path_buf());
+
+        self.entries.insert(path.to_path_buf(), Entry {
+            body: body.clone(),
+
+            modified,
+
+            tick,
+        });
+
+        Some(body)
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.order.remove(&entry.tick);
+
+            self.size -= entry.body.len();
+        }
+    }
+}
+
+/// Formats `time` as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Parses an HTTP date in the IMF-fixdate format. Dates in the obsolete
+/// formats are not supported, and result in `None`.
+pub fn parse_http_date(v: &str) -> Option<SystemTime> {
+    let mut parts = v.split_whitespace();
+
+    let weekday = parts.next()?.strip_suffix(',')?;
+    let day = parts.next()?.parse::<u64>().ok()?;
+    let month = parts.next()?;
+    let year = parts.next()?.parse::<u64>().ok()?;
+    let time = parts.next()?;
+
+    if parts.next()? != "GMT" || parts.next().is_some() {
+        return None;
+    }
+
+    if !WEEKDAYS.contains(&weekday) || year < 1970 || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+
+    let mut time = time.split(':').map(|v| v.parse::<u64>().ok());
+
+    let hour = time.next()??;
+    let minute = time.next()??;
+    let second = time.next()??;
+
+    if time.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let secs = days_from_civil(year, month, day) * 86400 +
+        hour * 3600 +
+        minute * 60 +
+        second;
+
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Returns true if a resource modified at `modified` is unchanged since the
+/// `If-Modified-Since` header value `since`.
+pub fn not_modified_since(modified: SystemTime, since: &str) -> bool {
+    let since = match parse_http_date(since) {
+        Some(v) => v,
+
+        // Invalid dates are ignored.
+        None => return false,
+    };
+
+    // HTTP dates have a resolution of one second.
+    let modified = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let since = since
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    modified <= since
+}
+
+/// Converts a number of days since the Unix epoch to a (year, month, day)
+/// date in the proleptic Gregorian calendar.
+pub(crate) fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// Converts a date in the proleptic Gregorian calendar to a number of days
+/// since the Unix epoch. The year must not be before 1970.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
@@ -25,8 +25,15 @@
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use crate::args::*;
+use crate::bind::BindOptions;
 use crate::common::*;
+use crate::impair::Impairment;
+use crate::sendto::set_dscp;
+use crate::sendto::DscpMarker;
+use crate::session_store::*;
+use crate::timing::*;
 
+use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 
 use std::io::prelude::*;
@@ -37,7 +44,48 @@ use std::cell::RefCell;
 
 use ring::rand::*;
 
-const MAX_DATAGRAM_SIZE: usize = 1350;
+pub const MAX_DATAGRAM_SIZE: usize = 1350;
+
+/// How long to wait for a connection attempt before racing it with one to the
+/// next address of the server, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: std::time::Duration =
+    std::time::Duration::from_millis(250);
+
+/// A client connection along with the UDP socket it's using.
+pub type SocketConn =
+    (mio::net::UdpSocket, std::pin::Pin<Box<quiche::Connection>>);
+
+/// When the client moves to a new UDP socket, as asked with `--migrate-after`.
+#[derive(Clone, Debug)]
+pub enum MigrateAfter {
+    /// After receiving the given number of bytes from the server.
+    Bytes(u64),
+
+    /// After the given time since the client started.
+    Time(std::time::Duration),
+}
+
+impl MigrateAfter {
+    /// Parses the value of the `--migrate-after` option, a number of bytes or
+    /// a number of seconds followed by `s`.
+    pub fn parse(v: &str) -> MigrateAfter {
+        match v.strip_suffix('s') {
+            Some(secs) => MigrateAfter::Time(std::time::Duration::from_secs_f64(
+                secs.parse().unwrap(),
+            )),
+
+            None => MigrateAfter::Bytes(v.parse().unwrap()),
+        }
+    }
+
+    fn is_due(&self, recv_bytes: u64, start: &std::time::Instant) -> bool {
+        match self {
+            MigrateAfter::Bytes(v) => recv_bytes >= *v,
+
+            MigrateAfter::Time(v) => start.elapsed() >= *v,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum ClientError {
@@ -63,54 +111,35 @@ pub fn connect(
     // We'll only connect to the first server provided in URL list.
     let connect_url = &args.urls[0];
 
+    let start = std::time::Instant::now();
+
+    let mut conn_timings = ConnTimings::default();
+
     // Resolve server address.
-    let peer_addr = if let Some(addr) = &args.connect_to {
-        addr.parse().unwrap()
+    let peer_addrs = if let Some(addr) = &args.connect_to {
+        vec![addr.parse().unwrap()]
     } else {
-        connect_url.to_socket_addrs().unwrap().next().unwrap()
+        resolve(connect_url)?
     };
 
-    // Bind to INADDR_ANY or IN6ADDR_ANY depending on the IP family of the
-    // server address. This is needed on macOS and BSD variants that don't
-    // support binding to IN6ADDR_ANY for both v4 and v6.
-    let bind_addr = match peer_addr {
-        std::net::SocketAddr::V4(_) => "0.0.0.0:0",
-        std::net::SocketAddr::V6(_) => "[::]:0",
-    };
+    // Only keep the addresses reachable from the requested local address and
+    // family.
+    let peer_addrs: Vec<SocketAddr> = peer_addrs
+        .into_iter()
+        .filter(|addr| conn_args.bind.accepts(addr))
+        .collect();
+
+    if peer_addrs.is_empty() {
+        return Err(ClientError::Other(format!(
+            "no address of {} matches the requested address family",
+            connect_url
+        )));
+    }
 
-    // Create the UDP socket backing the QUIC connection, and register it with
-    // the event loop.
-    let socket = std::net::UdpSocket::bind(bind_addr).unwrap();
-
-    let socket = mio::net::UdpSocket::from_socket(socket).unwrap();
-    poll.register(
-        &socket,
-        mio::Token(0),
-        mio::Ready::readable(),
-        mio::PollOpt::edge(),
-    )
-    .unwrap();
+    conn_timings.namelookup = start.elapsed();
 
     // Create the configuration for the QUIC connection.
-    let mut config = quiche::Config::new(args.version).unwrap();
-
-    config.verify_peer(!args.no_verify);
-
-    config.set_application_protos(&conn_args.alpns).unwrap();
-
-    config.set_max_idle_timeout(conn_args.idle_timeout);
-    config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
-    config.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
-    config.set_initial_max_data(conn_args.max_data);
-    config.set_initial_max_stream_data_bidi_local(conn_args.max_stream_data);
-    config.set_initial_max_stream_data_bidi_remote(conn_args.max_stream_data);
-    config.set_initial_max_stream_data_uni(conn_args.max_stream_data);
-    config.set_initial_max_streams_bidi(conn_args.max_streams_bidi);
-    config.set_initial_max_streams_uni(conn_args.max_streams_uni);
-    config.set_disable_active_migration(true);
-
-    config.set_max_connection_window(conn_args.max_window);
-    config.set_max_stream_window(conn_args.max_stream_window);
+    let mut config = make_config(args.version, args.no_verify, &conn_args);
 
     let mut keylog = None;
 
@@ -126,96 +155,140 @@ pub fn connect(
         config.log_keys();
     }
 
-    if conn_args.no_grease {
-        config.grease(false);
-    }
+    let mut http_conn: Option<Box<dyn HttpConn>> = None;
+    let mut siduck_conn: Option<SiDuckConn> = None;
 
-    if conn_args.early_data {
-        config.enable_early_data();
-    }
+    let mut app_proto_selected = false;
 
-    config
-        .set_cc_algorithm_name(&conn_args.cc_algorithm)
-        .unwrap();
+    let origin = origin(connect_url);
+
+    let mut session_store = make_session_store(&args);
+
+    // Creates a QUIC connection to the given address, on a new UDP socket
+    // registered with the event loop.
+    let mut start_conn = |peer_addr: SocketAddr, token| {
+        let (socket, mut conn) = open_connection(
+            &poll,
+            token,
+            peer_addr,
+            connect_url,
+            &mut config,
+            &conn_args.bind,
+        )?;
+
+        if conn_args.dscp != 0 {
+            set_dscp(&socket, conn_args.dscp).map_err(|e| {
+                ClientError::Other(format!("setsockopt() failed: {:?}", e))
+            })?;
+        }
 
-    if conn_args.disable_hystart {
-        config.enable_hystart(false);
-    }
+        if let Some(keylog) = &keylog {
+            if let Ok(keylog) = keylog.try_clone() {
+                conn.set_keylog(Box::new(keylog));
+            }
+        }
 
-    if conn_args.dgrams_enabled {
-        config.enable_dgram(true, 1000, 1000);
-    }
+        // Only bother with qlog if the user specified it.
+        #[cfg(feature = "qlog")]
+        {
+            if let Some(dir) = std::env::var_os("QLOGDIR") {
+                let id = format!("{:?}", conn.source_id());
+                let writer = make_qlog_writer(&dir, "client", &id);
+
+                conn.set_qlog(
+                    writer,
+                    "quiche-client qlog".to_string(),
+                    format!("{} id={}", "quiche-client qlog", id),
+                );
+            }
+        }
 
-    let mut http_conn: Option<Box<dyn HttpConn>> = None;
-    let mut siduck_conn: Option<SiDuckConn> = None;
+        if let Some(store) = &session_store {
+            if let Some(session) = store.get(&origin, SessionEntryKind::Session) {
+                conn.set_session(&session).ok();
+            }
 
-    let mut app_proto_selected = false;
+            if let Some(token) = store.get(&origin, SessionEntryKind::Token) {
+                conn.set_token(&token).ok();
+            }
+        }
 
-    // Generate a random source connection ID for the connection.
-    let mut scid = [0; quiche::MAX_CONN_ID_LEN];
-    SystemRandom::new().fill(&mut scid[..]).unwrap();
+        info!(
+            "connecting to {:} from {:} with scid {:?}",
+            peer_addr,
+            socket.local_addr().unwrap(),
+            conn.source_id(),
+        );
 
-    let scid = quiche::ConnectionId::from_ref(&scid);
+        Ok((socket, conn))
+    };
 
-    // Create a QUIC connection and initiate handshake.
-    let mut conn =
-        quiche::connect(connect_url.domain(), &scid, peer_addr, &mut config)
-            .unwrap();
+    // With a single address the handshake is left to the main loop, so that
+    // 0-RTT requests can be sent right away. Otherwise the addresses are raced
+    // and the main loop starts with the connection that won.
+    let mut impairment = Impairment::new(conn_args.impairment);
 
-    if let Some(keylog) = &mut keylog {
-        if let Ok(keylog) = keylog.try_clone() {
-            conn.set_keylog(Box::new(keylog));
-        }
-    }
+    let (mut socket, mut conn, peer_addr) = if peer_addrs.len() == 1 {
+        let (socket, mut conn) = start_conn(peer_addrs[0], mio::Token(0))?;
 
-    // Only bother with qlog if the user specified it.
-    #[cfg(feature = "qlog")]
-    {
-        if let Some(dir) = std::env::var_os("QLOGDIR") {
-            let id = format!("{:?}", scid);
-            let writer = make_qlog_writer(&dir, "client", &id);
-
-            conn.set_qlog(
-                std::boxed::Box::new(writer),
-                "quiche-client qlog".to_string(),
-                format!("{} id={}", "quiche-client qlog", id),
-            );
-        }
-    }
+        let (write, send_info) =
+            conn.send(&mut out).expect("initial send failed");
+
+        while let Err(e) =
+            impairment.send_to(&socket, &out[..write], &send_info.to)
+        {
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                trace!("send() would block");
+                continue;
+            }
 
-    if let Some(session_file) = &args.session_file {
-        if let Ok(session) = std::fs::read(session_file) {
-            conn.set_session(&session).ok();
+            return Err(ClientError::Other(format!("send() failed: {:?}", e)));
         }
-    }
 
-    info!(
-        "connecting to {:} from {:} with scid {:?}",
-        peer_addr,
-        socket.local_addr().unwrap(),
-        scid,
-    );
+        trace!("written {}", write);
 
-    let (write, send_info) = conn.send(&mut out).expect("initial send failed");
+        conn_timings.connect = start.elapsed();
 
-    while let Err(e) = socket.send_to(&out[..write], &send_info.to) {
-        if e.kind() == std::io::ErrorKind::WouldBlock {
-            trace!("send() would block");
-            continue;
-        }
+        (socket, conn, peer_addrs[0])
+    } else {
+        let winner = race_connect(&poll, &peer_addrs, &mut buf, &mut start_conn)?;
 
-        return Err(ClientError::Other(format!("send() failed: {:?}", e)));
-    }
+        info!(
+            "{} won the connection race among {} addresses",
+            winner.peer_addr,
+            peer_addrs.len()
+        );
+
+        conn_timings.connect = winner.started.duration_since(start);
+
+        (winner.socket, winner.conn, winner.peer_addr)
+    };
+
+    conn_timings.remote_addr = Some(peer_addr);
 
-    trace!("written {}", write);
+    // The socket is marked with the configured value, and changed when the
+    // connection asks for a different one.
+    let mut dscp_marker = DscpMarker::new(conn_args.dscp);
 
     let app_data_start = std::time::Instant::now();
 
     let mut pkt_count = 0;
 
+    let mut recv_bytes = 0;
+    let mut migrated = false;
+
     loop {
-        if !conn.is_in_early_data() || app_proto_selected {
-            poll.poll(&mut events, conn.timeout()).unwrap();
+        // Don't wait for events if requests can already be sent.
+        if app_proto_selected ||
+            !(conn.is_in_early_data() || conn.is_established())
+        {
+            let timeout = match (conn.timeout(), impairment.timeout()) {
+                (Some(t), Some(impaired)) => Some(std::cmp::min(t, impaired)),
+
+                (t, impaired) => t.or(impaired),
+            };
+
+            poll.poll(&mut events, timeout).unwrap();
         }
 
         // Read incoming UDP packets from the socket and feed them to quiche,
@@ -223,8 +296,9 @@ pub fn connect(
         'read: loop {
             // If the event loop reported no events, it means that the timeout
             // has expired, so handle it without attempting to read packets. We
-            // will then proceed with the send loop.
-            if events.is_empty() {
+            // will then proceed with the send loop. Datagrams held back by
+            // the impairment are still read once they are released.
+            if events.is_empty() && !impairment.has_rx_due() {
                 trace!("timed out");
 
                 conn.on_timeout();
@@ -232,7 +306,7 @@ pub fn connect(
                 break 'read;
             }
 
-            let (len, from) = match socket.recv_from(&mut buf) {
+            let (len, from) = match impairment.recv_from(&socket, &mut buf) {
                 Ok(v) => v,
 
                 Err(e) => {
@@ -252,6 +326,8 @@ pub fn connect(
 
             trace!("got {} bytes", len);
 
+            recv_bytes += len as u64;
+
             if let Some(target_path) = conn_args.dump_packet_path.as_ref() {
                 let path = format!("{}/{}.pkt", target_path, pkt_count);
 
@@ -280,6 +356,47 @@ pub fn connect(
 
         trace!("done reading");
 
+        // Move to a new UDP socket, as a NAT rebinding would, to exercise the
+        // server's handling of the client's address change.
+        //
+        // TODO: use active migration once quiche supports it.
+        if let Some(migrate_after) = &args.migrate_after {
+            if !migrated &&
+                conn.is_established() &&
+                migrate_after.is_due(recv_bytes, &start)
+            {
+                let new_socket = rebind(&poll, &socket, &conn_args.bind)?;
+
+                if conn_args.dscp != 0 {
+                    set_dscp(&new_socket, conn_args.dscp).map_err(|e| {
+                        ClientError::Other(format!(
+                            "setsockopt() failed: {:?}",
+                            e
+                        ))
+                    })?;
+                }
+
+                info!(
+                    "migrating from {} to {} after receiving {} bytes",
+                    socket.local_addr().unwrap(),
+                    new_socket.local_addr().unwrap(),
+                    recv_bytes,
+                );
+
+                socket = new_socket;
+                dscp_marker = DscpMarker::new(conn_args.dscp);
+                migrated = true;
+            }
+        }
+
+        if conn_timings.handshake.is_none() && conn.is_established() {
+            conn_timings.handshake = Some(start.elapsed());
+
+            if session_store.is_some() {
+                report_resumption(&conn, conn_timings.early_data);
+            }
+        }
+
         if conn.is_closed() {
             info!("connection closed, {:?}", conn.stats());
 
@@ -292,10 +409,25 @@ pub fn connect(
                 return Err(ClientError::HandshakeFail);
             }
 
-            if let Some(session_file) = &args.session_file {
+            if let Some(store) = session_store.as_mut() {
                 if let Some(session) = conn.session() {
-                    std::fs::write(session_file, &session).ok();
+                    store.put(&origin, SessionEntryKind::Session, &session);
                 }
+
+                if let Some(token) = conn.new_token() {
+                    store.put(&origin, SessionEntryKind::Token, token);
+                }
+            }
+
+            if let Some(h_conn) = &http_conn {
+                print_timings(
+                    &args,
+                    &conn,
+                    &conn_timings,
+                    h_conn.as_ref(),
+                    &start,
+                    &output_sink,
+                );
             }
 
             if let Some(h_conn) = http_conn {
@@ -327,10 +459,13 @@ pub fn connect(
             let app_proto = conn.application_proto();
             let app_proto = &std::str::from_utf8(app_proto).unwrap();
 
+            conn_timings.early_data = conn.is_in_early_data();
+
             if alpns::HTTP_09.contains(app_proto) {
                 http_conn = Some(Http09Conn::with_urls(
                     &args.urls,
                     args.reqs_cardinal,
+                    args.max_concurrent_reqs,
                     Rc::clone(&output_sink),
                 ));
 
@@ -350,8 +485,10 @@ pub fn connect(
                     &mut conn,
                     &args.urls,
                     args.reqs_cardinal,
+                    args.max_concurrent_reqs,
                     &args.req_headers,
                     &args.body,
+                    &args.content_type,
                     &args.method,
                     args.dump_json,
                     dgram_sender,
@@ -370,10 +507,12 @@ pub fn connect(
         }
 
         // If we have an HTTP connection, first issue the requests then
-        // process received data.
+        // process received data. Requests are issued again afterwards, in
+        // place of the ones that completed in the meantime.
         if let Some(h_conn) = http_conn.as_mut() {
             h_conn.send_requests(&mut conn, &args.dump_response_path);
             h_conn.handle_responses(&mut conn, &mut buf, &app_data_start);
+            h_conn.send_requests(&mut conn, &args.dump_response_path);
         }
 
         // If we have a siduck connection, first issue the quacks then
@@ -383,6 +522,10 @@ pub fn connect(
             si_conn.handle_quack_acks(&mut conn, &mut buf, &app_data_start);
         }
 
+        if let Err(e) = impairment.flush(&socket) {
+            return Err(ClientError::Other(format!("send() failed: {:?}", e)));
+        }
+
         // Generate outgoing QUIC packets and send them on the UDP socket, until
         // quiche reports that there are no more packets to be sent.
         loop {
@@ -402,7 +545,13 @@ pub fn connect(
                 },
             };
 
-            if let Err(e) = socket.send_to(&out[..write], &send_info.to) {
+            if let Err(e) = dscp_marker.mark(&socket, send_info.dscp) {
+                warn!("failed to set DSCP {}: {:?}", send_info.dscp, e);
+            }
+
+            if let Err(e) =
+                impairment.send_to(&socket, &out[..write], &send_info.to)
+            {
                 if e.kind() == std::io::ErrorKind::WouldBlock {
                     trace!("send() would block");
                     break;
@@ -429,12 +578,27 @@ pub fn connect(
                 return Err(ClientError::HandshakeFail);
             }
 
-            if let Some(session_file) = &args.session_file {
+            if let Some(store) = session_store.as_mut() {
                 if let Some(session) = conn.session() {
-                    std::fs::write(session_file, &session).ok();
+                    store.put(&origin, SessionEntryKind::Session, &session);
+                }
+
+                if let Some(token) = conn.new_token() {
+                    store.put(&origin, SessionEntryKind::Token, token);
                 }
             }
 
+            if let Some(h_conn) = &http_conn {
+                print_timings(
+                    &args,
+                    &conn,
+                    &conn_timings,
+                    h_conn.as_ref(),
+                    &start,
+                    &output_sink,
+                );
+            }
+
             if let Some(h_conn) = http_conn {
                 if h_conn.report_incomplete(&app_data_start) {
                     return Err(ClientError::HttpFail);
@@ -451,3 +615,404 @@ pub fn connect(
 
     Ok(())
 }
+
+/// Creates the configuration of the client's QUIC connections.
+pub fn make_config(
+    version: u32, no_verify: bool, conn_args: &CommonArgs,
+) -> quiche::Config {
+    let mut config = quiche::Config::new(version).unwrap();
+
+    config.verify_peer(!no_verify);
+
+    config.set_dscp(conn_args.dscp);
+
+    config.set_application_protos(&conn_args.alpns).unwrap();
+
+    config.set_max_idle_timeout(conn_args.idle_timeout);
+    config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
+    config.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
+    config.set_initial_max_data(conn_args.max_data);
+    config.set_initial_max_stream_data_bidi_local(conn_args.max_stream_data);
+    config.set_initial_max_stream_data_bidi_remote(conn_args.max_stream_data);
+    config.set_initial_max_stream_data_uni(conn_args.max_stream_data);
+    config.set_initial_max_streams_bidi(conn_args.max_streams_bidi);
+    config.set_initial_max_streams_uni(conn_args.max_streams_uni);
+    config.set_disable_active_migration(true);
+
+    config.set_max_connection_window(conn_args.max_window);
+    config.set_max_stream_window(conn_args.max_stream_window);
+
+    if conn_args.no_grease {
+        config.grease(false);
+    }
+
+    if conn_args.early_data {
+        config.enable_early_data();
+    }
+
+    config
+        .set_cc_algorithm_name(&conn_args.cc_algorithm)
+        .unwrap();
+
+    if conn_args.disable_hystart {
+        config.enable_hystart(false);
+    }
+
+    if conn_args.dgrams_enabled {
+        config.enable_dgram(true, 1000, 1000);
+    }
+
+    config
+}
+
+/// Creates a UDP socket registered with the event loop using `token`, and
+/// starts a QUIC connection to `peer_addr` over it.
+pub fn open_connection(
+    poll: &mio::Poll, token: mio::Token, peer_addr: std::net::SocketAddr,
+    url: &url::Url, config: &mut quiche::Config, bind: &BindOptions,
+) -> Result<SocketConn, ClientError> {
+    let socket = bind
+        .bind_for(&peer_addr)
+        .map_err(|e| ClientError::Other(format!("bind() failed: {:?}", e)))?;
+
+    let socket = mio::net::UdpSocket::from_socket(socket).unwrap();
+    poll.register(&socket, token, mio::Ready::readable(), mio::PollOpt::edge())
+        .unwrap();
+
+    let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+    SystemRandom::new().fill(&mut scid[..]).unwrap();
+
+    let scid = quiche::ConnectionId::from_ref(&scid);
+
+    let conn = quiche::connect(url.domain(), &scid, peer_addr, config)
+        .map_err(|e| ClientError::Other(format!("connect() failed: {:?}", e)))?;
+
+    Ok((socket, conn))
+}
+
+/// Creates a UDP socket bound to a new port of the address of `socket`, and
+/// registers it with the event loop in place of `socket`.
+fn rebind(
+    poll: &mio::Poll, socket: &mio::net::UdpSocket, bind: &BindOptions,
+) -> Result<mio::net::UdpSocket, ClientError> {
+    let local_addr = socket.local_addr().unwrap();
+
+    let new_socket = bind
+        .bind(SocketAddr::new(local_addr.ip(), 0))
+        .map_err(|e| ClientError::Other(format!("bind() failed: {:?}", e)))?;
+
+    let new_socket = mio::net::UdpSocket::from_socket(new_socket).unwrap();
+
+    poll.deregister(socket).unwrap();
+    poll.register(
+        &new_socket,
+        mio::Token(0),
+        mio::Ready::readable(),
+        mio::PollOpt::edge(),
+    )
+    .unwrap();
+
+    Ok(new_socket)
+}
+
+/// Feeds the packets waiting on the socket to the connection.
+pub fn recv_packets(
+    socket: &mio::net::UdpSocket, conn: &mut quiche::Connection, buf: &mut [u8],
+) -> Result<(), ClientError> {
+    loop {
+        let (len, from) = match socket.recv_from(buf) {
+            Ok(v) => v,
+
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+
+                return Err(ClientError::Other(format!(
+                    "recv() failed: {:?}",
+                    e
+                )));
+            },
+        };
+
+        let recv_info = quiche::RecvInfo { from };
+
+        if let Err(e) = conn.recv(&mut buf[..len], recv_info) {
+            error!("{} recv failed: {:?}", conn.trace_id(), e);
+        }
+    }
+}
+
+/// Sends the packets generated by the connection.
+pub fn send_packets(
+    socket: &mio::net::UdpSocket, conn: &mut quiche::Connection,
+) -> Result<(), ClientError> {
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (write, send_info) = match conn.send(&mut out) {
+            Ok(v) => v,
+
+            Err(quiche::Error::Done) => return Ok(()),
+
+            Err(e) => {
+                error!("{} send failed: {:?}", conn.trace_id(), e);
+
+                conn.close(false, 0x1, b"fail").ok();
+                return Ok(());
+            },
+        };
+
+        if let Err(e) = socket.send_to(&out[..write], &send_info.to) {
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                trace!("send() would block");
+                return Ok(());
+            }
+
+            return Err(ClientError::Other(format!("send() failed: {:?}", e)));
+        }
+    }
+}
+
+/// Resolves the addresses of the server of `url`.
+///
+/// The address families are interleaved, starting with the family of the
+/// first address returned by the resolver, so that a broken IPv6 or IPv4 path
+/// only delays the connection by one attempt.
+fn resolve(url: &url::Url) -> Result<Vec<SocketAddr>, ClientError> {
+    let addrs: Vec<SocketAddr> = match url.to_socket_addrs() {
+        Ok(v) => v.collect(),
+
+        Err(e) =>
+            return Err(ClientError::Other(format!(
+                "failed to resolve {}: {:?}",
+                url, e
+            ))),
+    };
+
+    if addrs.is_empty() {
+        return Err(ClientError::Other(format!("failed to resolve {}", url)));
+    }
+
+    let (preferred, others): (Vec<_>, Vec<_>) = addrs
+        .iter()
+        .partition(|addr| addr.is_ipv6() == addrs[0].is_ipv6());
+
+    let mut others = others.into_iter();
+    let mut interleaved = Vec::with_capacity(addrs.len());
+
+    for addr in preferred {
+        interleaved.push(addr);
+        interleaved.extend(others.next());
+    }
+
+    interleaved.extend(others);
+
+    Ok(interleaved)
+}
+
+/// A connection attempt to one of the addresses of the server.
+struct Attempt {
+    peer_addr: SocketAddr,
+
+    socket: mio::net::UdpSocket,
+
+    conn: std::pin::Pin<Box<quiche::Connection>>,
+
+    started: std::time::Instant,
+}
+
+/// Connects to one of `peer_addrs`, happy eyeballs style.
+///
+/// A new attempt is started every `CONNECTION_ATTEMPT_DELAY`, or as soon as
+/// the previous one failed, and the first attempt to complete the handshake
+/// wins. The others are closed.
+fn race_connect(
+    poll: &mio::Poll, peer_addrs: &[SocketAddr], buf: &mut [u8],
+    start_conn: &mut dyn FnMut(
+        SocketAddr,
+        mio::Token,
+    ) -> Result<SocketConn, ClientError>,
+) -> Result<Attempt, ClientError> {
+    let mut events = mio::Events::with_capacity(1024);
+
+    let mut attempts: Vec<Attempt> = Vec::new();
+
+    let mut next = 0;
+    let mut next_start = std::time::Instant::now();
+
+    loop {
+        let now = std::time::Instant::now();
+
+        if next < peer_addrs.len() && now >= next_start {
+            let peer_addr = peer_addrs[next];
+
+            let res = start_conn(peer_addr, mio::Token(next)).and_then(
+                |(socket, mut conn)| {
+                    send_packets(&socket, &mut conn)?;
+
+                    Ok((socket, conn))
+                },
+            );
+
+            match res {
+                Ok((socket, conn)) => attempts.push(Attempt {
+                    peer_addr,
+
+                    socket,
+
+                    conn,
+
+                    started: now,
+                }),
+
+                Err(e) => {
+                    error!("failed to connect to {}: {:?}", peer_addr, e);
+
+                    // Move on to the next address right away.
+                    next += 1;
+                    continue;
+                },
+            }
+
+            next += 1;
+            next_start = now + CONNECTION_ATTEMPT_DELAY;
+        }
+
+        let mut failed = false;
+
+        attempts.retain(|attempt| {
+            if !attempt.conn.is_closed() {
+                return true;
+            }
+
+            error!("connection to {} failed", attempt.peer_addr);
+
+            failed = true;
+            false
+        });
+
+        // Move on to the next address right away when an attempt failed.
+        if failed {
+            next_start = now;
+        }
+
+        if let Some(i) = attempts.iter().position(|a| a.conn.is_established()) {
+            let winner = attempts.swap_remove(i);
+
+            for mut attempt in attempts {
+                attempt.conn.close(false, 0x0, b"").ok();
+                send_packets(&attempt.socket, &mut attempt.conn).ok();
+            }
+
+            return Ok(winner);
+        }
+
+        if attempts.is_empty() {
+            if next >= peer_addrs.len() {
+                error!("all connection attempts failed");
+
+                return Err(ClientError::HandshakeFail);
+            }
+
+            continue;
+        }
+
+        let mut timeout = attempts.iter().filter_map(|a| a.conn.timeout()).min();
+
+        if next < peer_addrs.len() {
+            let delay = next_start.saturating_duration_since(now);
+
+            timeout = Some(timeout.map_or(delay, |t| t.min(delay)));
+        }
+
+        poll.poll(&mut events, timeout).unwrap();
+
+        for attempt in attempts.iter_mut() {
+            let socket = &attempt.socket;
+            let conn = &mut attempt.conn;
+
+            if conn.timeout() == Some(std::time::Duration::from_secs(0)) {
+                conn.on_timeout();
+            }
+
+            // A socket error, e.g. because the network of the address is
+            // unreachable, fails the attempt.
+            let res = recv_packets(socket, conn, buf)
+                .and_then(|_| send_packets(socket, conn));
+
+            if let Err(ClientError::Other(e)) = res {
+                error!("connection to {}: {}", attempt.peer_addr, e);
+
+                conn.close(false, 0x1, b"fail").ok();
+            }
+        }
+    }
+}
+
+/// Prints the metrics of each request, as asked with `--write-out` and
+/// `--json-stats`.
+fn print_timings(
+    args: &ClientArgs, conn: &quiche::Connection, conn_timings: &ConnTimings,
+    h_conn: &dyn HttpConn, start: &std::time::Instant,
+    output_sink: &Rc<RefCell<dyn FnMut(String)>>,
+) {
+    if args.write_out.is_none() && !args.json_stats {
+        return;
+    }
+
+    let conn_timings = ConnTimings {
+        version: conn.peer_version().unwrap_or(args.version),
+        alpn: String::from_utf8_lossy(conn.application_proto()).into_owned(),
+        retry: conn.did_retry(),
+        resumed: conn.is_resumed(),
+        early_data_accepted: conn.is_early_data_accepted(),
+        ..conn_timings.clone()
+    };
+
+    for req in h_conn.request_timings(start) {
+        if let Some(format) = &args.write_out {
+            output_sink.borrow_mut()(write_out(format, &conn_timings, &req));
+        }
+
+        if args.json_stats {
+            let json = json_stats(&conn_timings, &req);
+            output_sink.borrow_mut()(format!("{}\n", json));
+        }
+    }
+}
+
+/// Logs whether the TLS session was resumed and, if requests were sent as
+/// 0-RTT data, whether the server accepted it.
+fn report_resumption(conn: &quiche::Connection, early_data: bool) {
+    let early_data = match (early_data, conn.is_early_data_accepted()) {
+        (false, _) => "not sent",
+
+        (true, true) => "accepted",
+
+        (true, false) => "rejected",
+    };
+
+    info!(
+        "session resumed: {}, 0-RTT: {}",
+        conn.is_resumed(),
+        early_data
+    );
+}
+
+/// Creates the session store requested by the client arguments, if any.
+fn make_session_store(args: &ClientArgs) -> Option<Box<dyn SessionStore>> {
+    if let Some(dir) = &args.session_dir {
+        match FileSessionStore::new(dir) {
+            Ok(v) => return Some(Box::new(v)),
+
+            Err(e) => error!("failed to open session directory {}: {:?}", dir, e),
+        }
+    }
+
+    if let Some(file) = &args.session_file {
+        return Some(Box::new(SingleFileSessionStore::new(file)));
+    }
+
+    None
+}
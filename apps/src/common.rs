@@ -45,6 +45,17 @@ use quiche::ConnectionId;
 
 use quiche::h3::NameValue;
 
+use crate::access_log::CompletedRequest;
+
+use crate::cache::ResponseCache;
+
+use crate::proxy::ProxiedRequest;
+use crate::proxy::Upstream;
+
+use crate::throttle::Throttle;
+
+use crate::timing::RequestTimings;
+
 pub fn stdout_sink(out: String) {
     print!("{}", out);
 }
@@ -79,7 +90,88 @@ pub struct PartialResponse {
 
     pub body: Vec<u8>,
 
+    /// A body served from the response cache, along with the range of it to
+    /// send. It is sent instead of `body`, so that it isn't copied.
+    pub cached: Option<(Rc<[u8]>, std::ops::Range<usize>)>,
+
     pub written: usize,
+
+    /// The rest of the body, still to be read from disk.
+    pub file: Option<FileBody>,
+}
+
+impl PartialResponse {
+    /// Returns the body buffered in memory.
+    fn buffered(&self) -> &[u8] {
+        match &self.cached {
+            Some((body, range)) => &body[range.clone()],
+
+            None => &self.body,
+        }
+    }
+
+    /// Returns true if the buffered body is the last part of the response.
+    fn is_last_chunk(&self) -> bool {
+        match self.file {
+            Some(ref file) => file.remaining == 0,
+
+            None => true,
+        }
+    }
+
+    /// Replaces the fully written body buffer with the next chunk of the
+    /// file, of at most `capacity` bytes.
+    fn refill(&mut self, capacity: usize) -> std::io::Result<()> {
+        let file = match self.file {
+            Some(ref mut file) => file,
+
+            None => return Ok(()),
+        };
+
+        let len = std::cmp::min(capacity as u64, file.remaining);
+
+        self.body.resize(len as usize, 0);
+
+        file.file.seek(std::io::SeekFrom::Start(file.offset))?;
+        file.file.read_exact(&mut self.body)?;
+
+        file.offset += len;
+        file.remaining -= len;
+
+        self.written = 0;
+
+        Ok(())
+    }
+}
+
+/// A response body that is read from disk as the stream can take it, rather
+/// than all at once.
+pub struct FileBody {
+    file: std::fs::File,
+
+    offset: u64,
+
+    remaining: u64,
+
+    modified: Option<std::time::SystemTime>,
+}
+
+impl FileBody {
+    /// Opens the file at `path`, to be sent whole.
+    fn open(path: &path::Path) -> std::io::Result<FileBody> {
+        let file = std::fs::File::open(path)?;
+        let metadata = file.metadata()?;
+
+        Ok(FileBody {
+            file,
+
+            offset: 0,
+
+            remaining: metadata.len(),
+
+            modified: metadata.modified().ok(),
+        })
+    }
 }
 
 pub struct Client {
@@ -100,6 +192,16 @@ pub struct Client {
 
 pub type ClientMap = HashMap<ConnectionId<'static>, Client>;
 
+/// Returns how many of the `len` bytes of a response body can be written under
+/// `throttle`.
+fn throttled_len(throttle: &mut Option<Throttle>, len: usize) -> usize {
+    match throttle {
+        Some(throttle) => std::cmp::min(len, throttle.available()),
+
+        None => len,
+    }
+}
+
 /// Makes a buffered writer for a resource with a target URL.
 ///
 /// The file will have the same name as the resource's last path segment value.
@@ -132,32 +234,179 @@ fn make_resource_writer(
     None
 }
 
+/// Maps a directory to the index file inside it.
 fn autoindex(path: path::PathBuf, index: &str) -> path::PathBuf {
-    if let Some(path_str) = path.to_str() {
-        if path_str.ends_with('/') {
-            let path_str = format!("{}{}", path_str, index);
-            return path::PathBuf::from(&path_str);
-        }
+    if path.is_dir() {
+        return path.join(index);
     }
 
     path
 }
 
+/// Guesses the media type of a file from its extension.
+fn content_type(path: &path::Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|v| v.to_str())
+        .map(|v| v.to_ascii_lowercase());
+
+    match ext.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+
+        Some("css") => "text/css; charset=utf-8",
+
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+
+        Some("txt") => "text/plain; charset=utf-8",
+
+        Some("json") => "application/json",
+
+        Some("xml") => "application/xml",
+
+        Some("wasm") => "application/wasm",
+
+        Some("pdf") => "application/pdf",
+
+        Some("png") => "image/png",
+
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+
+        Some("gif") => "image/gif",
+
+        Some("svg") => "image/svg+xml",
+
+        Some("webp") => "image/webp",
+
+        Some("ico") => "image/x-icon",
+
+        Some("mp4") => "video/mp4",
+
+        Some("webm") => "video/webm",
+
+        Some("mp3") => "audio/mpeg",
+
+        Some("ogg") => "audio/ogg",
+
+        _ => "application/octet-stream",
+    }
+}
+
+/// Compression applied to qlog files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QlogCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl QlogCompression {
+    /// Reads the compression from the `QLOGCOMPRESSION` environment variable,
+    /// which can be set to `gzip` or `zstd`.
+    pub fn from_env() -> QlogCompression {
+        let v = match std::env::var("QLOGCOMPRESSION") {
+            Ok(v) => v,
+
+            Err(_) => return QlogCompression::None,
+        };
+
+        match v.as_str() {
+            "gzip" => QlogCompression::Gzip,
+
+            "zstd" => QlogCompression::Zstd,
+
+            _ => {
+                error!("unknown qlog compression {:?}, ignoring", v);
+                QlogCompression::None
+            },
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            QlogCompression::None => "qlog",
+
+            QlogCompression::Gzip => "qlog.gz",
+
+            QlogCompression::Zstd => "qlog.zst",
+        }
+    }
+}
+
+type ZstdEncoder = zstd::Encoder<'static, std::io::BufWriter<std::fs::File>>;
+
+/// A zstd writer that finishes the compressed stream when dropped.
+///
+/// The encoder is wrapped in a `Mutex` as it isn't `Sync` otherwise, though
+/// the lock is never actually taken since the writer is mutably borrowed.
+struct ZstdWriter {
+    encoder: std::sync::Mutex<Option<ZstdEncoder>>,
+}
+
+impl std::io::Write for ZstdWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.encoder.get_mut() {
+            Ok(Some(v)) => v.write(buf),
+
+            _ => Ok(0),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.encoder.get_mut() {
+            Ok(Some(v)) => v.flush(),
+
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for ZstdWriter {
+    fn drop(&mut self) {
+        if let Ok(Some(encoder)) = self.encoder.get_mut().map(Option::take) {
+            if let Err(e) = encoder.finish().and_then(|mut w| w.flush()) {
+                error!("failed to finish qlog file: {:?}", e);
+            }
+        }
+    }
+}
+
 /// Makes a buffered writer for a qlog.
+///
+/// The output is compressed on the fly according to the `QLOGCOMPRESSION`
+/// environment variable (see [`QlogCompression::from_env()`]).
 pub fn make_qlog_writer(
     dir: &std::ffi::OsStr, role: &str, id: &str,
-) -> std::io::BufWriter<std::fs::File> {
+) -> Box<dyn std::io::Write + Send + Sync> {
+    let compression = QlogCompression::from_env();
+
     let mut path = std::path::PathBuf::from(dir);
-    let filename = format!("{}-{}.qlog", role, id);
+    let filename = format!("{}-{}.{}", role, id, compression.extension());
     path.push(filename);
 
-    match std::fs::File::create(&path) {
+    let writer = match std::fs::File::create(&path) {
         Ok(f) => std::io::BufWriter::new(f),
 
         Err(e) => panic!(
             "Error creating qlog file attempted path was {:?}: {}",
             path, e
         ),
+    };
+
+    match compression {
+        QlogCompression::None => Box::new(writer),
+
+        QlogCompression::Gzip => Box::new(flate2::write::GzEncoder::new(
+            writer,
+            flate2::Compression::default(),
+        )),
+
+        QlogCompression::Zstd => match zstd::Encoder::new(writer, 0) {
+            Ok(v) => Box::new(ZstdWriter {
+                encoder: std::sync::Mutex::new(Some(v)),
+            }),
+
+            Err(e) => panic!("Error creating qlog zstd encoder: {}", e),
+        },
     }
 }
 
@@ -265,6 +514,111 @@ pub trait HttpConn {
         &mut self, conn: &mut std::pin::Pin<Box<quiche::Connection>>,
         partial_responses: &mut HashMap<u64, PartialResponse>, stream_id: u64,
     );
+
+    /// Relays the UDP payloads received on proxied flows to the peer.
+    fn relay_proxied_flows(
+        &mut self, _conn: &mut std::pin::Pin<Box<quiche::Connection>>,
+        _buf: &mut [u8],
+    ) {
+    }
+
+    /// Registers the sockets of new proxied flows with the event loop, so
+    /// that it wakes up when UDP payloads are received from proxy targets.
+    fn register_proxied_flows(&mut self, _poll: &mio::Poll, _token: mio::Token) {}
+
+    /// Relays the responses of the requests forwarded to the upstream.
+    fn relay_upstreams(
+        &mut self, _conn: &mut std::pin::Pin<Box<quiche::Connection>>,
+        _buf: &mut [u8],
+    ) {
+    }
+
+    /// Registers the connections of new forwarded requests with the event
+    /// loop, so that it wakes up when the upstream can make progress.
+    fn register_upstreams(&mut self, _poll: &mio::Poll, _token: mio::Token) {}
+
+    /// Returns how long until the first forwarded request times out waiting
+    /// for the upstream.
+    fn upstreams_timeout(&self) -> Option<std::time::Duration> {
+        None
+    }
+
+    /// Returns the status codes of the responses sent since the last call.
+    fn take_response_statuses(&mut self) -> Vec<u16> {
+        Vec::new()
+    }
+
+    /// Limits the rate at which response bodies are written to `rate` bytes
+    /// per second.
+    fn set_max_send_rate(&mut self, _rate: u64) {}
+
+    /// Serves static responses from the given cache.
+    fn set_response_cache(&mut self, _cache: Rc<RefCell<ResponseCache>>) {}
+
+    /// Keeps track of the requests served, for the access log.
+    fn enable_access_log(&mut self) {}
+
+    /// Returns the requests whose response was completely sent since the last
+    /// call.
+    fn take_completed_requests(&mut self) -> Vec<CompletedRequest> {
+        Vec::new()
+    }
+
+    /// Returns how long until throttled response bodies can be written again.
+    fn throttle_timeout(&self) -> Option<std::time::Duration> {
+        None
+    }
+
+    /// Returns the timing metrics of the requests sent by the client,
+    /// relative to `start`.
+    fn request_timings(
+        &self, _start: &std::time::Instant,
+    ) -> Vec<RequestTimings> {
+        Vec::new()
+    }
+}
+
+/// When a request was sent and its response received, and how much data was
+/// transferred meanwhile.
+#[derive(Default)]
+struct RequestProgress {
+    sent_at: Option<std::time::Instant>,
+    first_byte_at: Option<std::time::Instant>,
+    finished_at: Option<std::time::Instant>,
+    bytes_sent: u64,
+    bytes_recv: u64,
+}
+
+impl RequestProgress {
+    fn on_sent(&mut self) {
+        self.sent_at = Some(std::time::Instant::now());
+    }
+
+    fn on_recv(&mut self, len: usize) {
+        if self.first_byte_at.is_none() {
+            self.first_byte_at = Some(std::time::Instant::now());
+        }
+
+        self.bytes_recv += len as u64;
+    }
+
+    fn on_finished(&mut self) {
+        self.finished_at = Some(std::time::Instant::now());
+    }
+
+    fn timings(
+        &self, start: &std::time::Instant, url: &url::Url, status: Option<u16>,
+    ) -> RequestTimings {
+        RequestTimings {
+            url: url.to_string(),
+            status,
+            start: crate::timing::since(start, self.sent_at),
+            first_byte: crate::timing::since(start, self.first_byte_at),
+            total: crate::timing::since(start, self.finished_at),
+            bytes_sent: self.bytes_sent,
+            bytes_recv: self.bytes_recv,
+        }
+    }
 }
 
 pub struct SiDuckConn {
@@ -429,6 +783,7 @@ pub struct Http09Request {
     request_line: String,
     stream_id: Option<u64>,
     response_writer: Option<std::io::BufWriter<std::fs::File>>,
+    progress: RequestProgress,
 }
 
 /// Represents an HTTP/3 formatted request.
@@ -441,14 +796,17 @@ struct Http3Request {
     response_body: Vec<u8>,
     response_body_max: usize,
     response_writer: Option<std::io::BufWriter<std::fs::File>>,
+    progress: RequestProgress,
 }
 
 pub struct Http09Conn {
     stream_id: u64,
     reqs_sent: usize,
     reqs_complete: usize,
+    max_concurrent_reqs: Option<usize>,
     reqs: Vec<Http09Request>,
     output_sink: Rc<RefCell<dyn FnMut(String)>>,
+    throttle: Option<Throttle>,
 }
 
 impl Default for Http09Conn {
@@ -457,8 +815,10 @@ impl Default for Http09Conn {
             stream_id: Default::default(),
             reqs_sent: Default::default(),
             reqs_complete: Default::default(),
+            max_concurrent_reqs: None,
             reqs: Default::default(),
             output_sink: Rc::new(RefCell::new(stdout_sink)),
+            throttle: None,
         }
     }
 }
@@ -466,6 +826,7 @@ impl Default for Http09Conn {
 impl Http09Conn {
     pub fn with_urls(
         urls: &[url::Url], reqs_cardinal: u64,
+        max_concurrent_reqs: Option<usize>,
         output_sink: Rc<RefCell<dyn FnMut(String)>>,
     ) -> Box<dyn HttpConn> {
         let mut reqs = Vec::new();
@@ -478,6 +839,7 @@ impl Http09Conn {
                     request_line,
                     stream_id: None,
                     response_writer: None,
+                    progress: RequestProgress::default(),
                 });
             }
         }
@@ -486,12 +848,71 @@ impl Http09Conn {
             stream_id: 0,
             reqs_sent: 0,
             reqs_complete: 0,
+            max_concurrent_reqs,
             reqs,
             output_sink,
+            throttle: None,
         };
 
         Box::new(h_conn)
     }
+
+    /// Sends as much of a response body as the stream can take, reading
+    /// further chunks from disk as capacity frees up. Returns true once the
+    /// response is finished.
+    fn send_partial_body(
+        &mut self, conn: &mut quiche::Connection, stream_id: u64,
+        resp: &mut PartialResponse,
+    ) -> quiche::Result<bool> {
+        loop {
+            let remaining = resp.buffered().len() - resp.written;
+            let len = throttled_len(&mut self.throttle, remaining);
+
+            // Wait for the throttle to refill, unless only the FIN is left.
+            if len == 0 && remaining > 0 {
+                return Ok(false);
+            }
+
+            let fin = resp.is_last_chunk() && len == remaining;
+            let body = &resp.buffered()[resp.written..resp.written + len];
+
+            let written = match conn.stream_send(stream_id, body, fin) {
+                Ok(v) => v,
+
+                Err(quiche::Error::Done) => 0,
+
+                Err(e) => return Err(e),
+            };
+
+            if let Some(throttle) = &mut self.throttle {
+                throttle.consume(written);
+            }
+
+            resp.written += written;
+
+            if resp.written < resp.buffered().len() {
+                return Ok(false);
+            }
+
+            if fin {
+                return Ok(true);
+            }
+
+            let cap = conn.stream_capacity(stream_id)?;
+
+            if cap == 0 {
+                return Ok(false);
+            }
+
+            if let Err(e) = resp.refill(cap) {
+                error!("{} failed to read body: {:?}", conn.trace_id(), e);
+
+                conn.stream_shutdown(stream_id, quiche::Shutdown::Write, 0)?;
+
+                return Ok(true);
+            }
+        }
+    }
 }
 
 impl HttpConn for Http09Conn {
@@ -500,7 +921,12 @@ impl HttpConn for Http09Conn {
     ) {
         let mut reqs_done = 0;
 
-        for req in self.reqs.iter_mut().skip(self.reqs_sent) {
+        let reqs_in_flight = self.reqs_sent - self.reqs_complete;
+        let reqs_allowed = self
+            .max_concurrent_reqs
+            .map_or(usize::MAX, |max| max.saturating_sub(reqs_in_flight));
+
+        for req in self.reqs.iter_mut().skip(self.reqs_sent).take(reqs_allowed) {
             match conn.stream_send(
                 self.stream_id,
                 req.request_line.as_bytes(),
@@ -508,7 +934,7 @@ impl HttpConn for Http09Conn {
             ) {
                 Ok(v) => v,
 
-                Err(quiche::Error::StreamLimit) => {
+                Err(quiche::Error::StreamLimit(_)) => {
                     debug!("not enough stream credits, retry later...");
                     break;
                 },
@@ -522,6 +948,7 @@ impl HttpConn for Http09Conn {
             debug!("sending HTTP request {:?}", req.request_line);
 
             req.stream_id = Some(self.stream_id);
+            req.progress.on_sent();
             req.response_writer =
                 make_resource_writer(&req.url, target_path, req.cardinal);
 
@@ -557,6 +984,12 @@ impl HttpConn for Http09Conn {
                     .find(|r| r.stream_id == Some(s))
                     .unwrap();
 
+                req.progress.on_recv(read);
+
+                if fin {
+                    req.progress.on_finished();
+                }
+
                 match &mut req.response_writer {
                     Some(rw) => {
                         rw.write_all(&buf[..read]).ok();
@@ -620,6 +1053,21 @@ impl HttpConn for Http09Conn {
         false
     }
 
+    fn request_timings(&self, start: &std::time::Instant) -> Vec<RequestTimings> {
+        self.reqs
+            .iter()
+            .map(|r| r.progress.timings(start, &r.url, None))
+            .collect()
+    }
+
+    fn set_max_send_rate(&mut self, rate: u64) {
+        self.throttle = Some(Throttle::new(rate));
+    }
+
+    fn throttle_timeout(&self) -> Option<std::time::Duration> {
+        self.throttle.as_ref().and_then(|t| t.timeout())
+    }
+
     fn handle_requests(
         &mut self, conn: &mut std::pin::Pin<Box<quiche::Connection>>,
         partial_requests: &mut HashMap<u64, PartialRequest>,
@@ -687,20 +1135,33 @@ impl HttpConn for Http09Conn {
                         s
                     );
 
-                    let body = std::fs::read(path.as_path())
-                        .unwrap_or_else(|_| b"Not Found!\r\n".to_vec());
+                    let (body, file) = match FileBody::open(&path) {
+                        Ok(file) => (Vec::new(), Some(file)),
+
+                        Err(_) => (b"Not Found!\r\n".to_vec(), None),
+                    };
 
                     info!(
                         "{} sending response of size {} on stream {}",
                         conn.trace_id(),
-                        body.len(),
+                        file.as_ref().map_or(body.len() as u64, |f| f.remaining),
                         s
                     );
 
-                    let written = match conn.stream_send(s, &body, true) {
-                        Ok(v) => v,
+                    let mut response = PartialResponse {
+                        headers: None,
+                        body,
+                        cached: None,
+                        written: 0,
+                        file,
+                    };
+
+                    match self.send_partial_body(conn, s, &mut response) {
+                        Ok(true) => (),
 
-                        Err(quiche::Error::Done) => 0,
+                        Ok(false) => {
+                            partial_responses.insert(s, response);
+                        },
 
                         Err(e) => {
                             error!(
@@ -710,16 +1171,6 @@ impl HttpConn for Http09Conn {
                             );
                             return Err(From::from(e));
                         },
-                    };
-
-                    if written < body.len() {
-                        let response = PartialResponse {
-                            headers: None,
-                            body,
-                            written,
-                        };
-
-                        partial_responses.insert(s, response);
                     }
                 }
             }
@@ -739,25 +1190,19 @@ impl HttpConn for Http09Conn {
         }
 
         let resp = partial_responses.get_mut(&stream_id).unwrap();
-        let body = &resp.body[resp.written..];
 
-        let written = match conn.stream_send(stream_id, body, true) {
-            Ok(v) => v,
+        match self.send_partial_body(conn, stream_id, resp) {
+            Ok(true) => {
+                partial_responses.remove(&stream_id);
+            },
 
-            Err(quiche::Error::Done) => 0,
+            Ok(false) => (),
 
             Err(e) => {
                 partial_responses.remove(&stream_id);
 
                 error!("{} stream send failed {:?}", conn.trace_id(), e);
-                return;
             },
-        };
-
-        resp.written += written;
-
-        if resp.written == resp.body.len() {
-            partial_responses.remove(&stream_id);
         }
     }
 }
@@ -780,106 +1225,317 @@ impl Http3DgramSender {
     }
 }
 
-pub struct Http3Conn {
-    h3_conn: quiche::h3::Connection,
-    reqs_hdrs_sent: usize,
-    reqs_complete: usize,
-    largest_processed_request: u64,
-    reqs: Vec<Http3Request>,
-    body: Option<Vec<u8>>,
-    sent_body_bytes: HashMap<u64, usize>,
-    dump_json: bool,
-    dgram_sender: Option<Http3DgramSender>,
-    output_sink: Rc<RefCell<dyn FnMut(String)>>,
+/// The outcome of evaluating a `Range` request header against a resource.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// The header can't be used, so the whole resource is served.
+    Ignored,
+
+    /// The first and last (inclusive) offsets of the bytes to serve.
+    Satisfiable(u64, u64),
+
+    /// None of the requested bytes exist.
+    Unsatisfiable,
 }
 
-impl Http3Conn {
-    #[allow(clippy::too_many_arguments)]
-    pub fn with_urls(
-        conn: &mut quiche::Connection, urls: &[url::Url], reqs_cardinal: u64,
-        req_headers: &[String], body: &Option<Vec<u8>>, method: &str,
-        dump_json: Option<usize>, dgram_sender: Option<Http3DgramSender>,
-        output_sink: Rc<RefCell<dyn FnMut(String)>>,
-    ) -> Box<dyn HttpConn> {
-        let mut reqs = Vec::new();
-        for url in urls {
-            for i in 1..=reqs_cardinal {
-                let authority = match url.port() {
-                    Some(port) => format!("{}:{}", url.host_str().unwrap(), port),
+impl ByteRange {
+    /// Evaluates the value of a `Range` header for a resource of `len` bytes.
+    ///
+    /// Only single byte ranges are supported. Other range units, multiple
+    /// ranges and malformed values are ignored, as allowed by RFC 9110.
+    fn parse(value: &str, len: u64) -> ByteRange {
+        let spec = match value.trim().strip_prefix("bytes=") {
+            Some(v) if !v.contains(',') => v.trim(),
 
-                    None => url.host_str().unwrap().to_string(),
-                };
+            _ => return ByteRange::Ignored,
+        };
 
-                let mut hdrs = vec![
-                    quiche::h3::Header::new(b":method", method.as_bytes()),
-                    quiche::h3::Header::new(b":scheme", url.scheme().as_bytes()),
-                    quiche::h3::Header::new(b":authority", authority.as_bytes()),
-                    quiche::h3::Header::new(
-                        b":path",
-                        url[url::Position::BeforePath..].as_bytes(),
-                    ),
-                    quiche::h3::Header::new(b"user-agent", b"quiche"),
-                ];
-
-                // Add custom headers to the request.
-                for header in req_headers {
-                    let header_split: Vec<&str> =
-                        header.splitn(2, ": ").collect();
-
-                    if header_split.len() != 2 {
-                        panic!("malformed header provided - \"{}\"", header);
-                    }
+        let (first, last) = match spec.split_once('-') {
+            Some((first, last)) => (first.trim(), last.trim()),
 
-                    hdrs.push(quiche::h3::Header::new(
-                        header_split[0].as_bytes(),
-                        header_split[1].as_bytes(),
-                    ));
-                }
+            None => return ByteRange::Ignored,
+        };
 
-                if body.is_some() {
-                    hdrs.push(quiche::h3::Header::new(
-                        b"content-length",
-                        body.as_ref().unwrap().len().to_string().as_bytes(),
-                    ));
-                }
+        // A suffix range, asking for the last bytes of the resource.
+        if first.is_empty() {
+            return match last.parse::<u64>() {
+                Ok(n) if n > 0 && len > 0 =>
+                    ByteRange::Satisfiable(len.saturating_sub(n), len - 1),
 
-                reqs.push(Http3Request {
-                    url: url.clone(),
-                    cardinal: i,
-                    hdrs,
-                    response_hdrs: Vec::new(),
-                    response_body: Vec::new(),
-                    response_body_max: dump_json.unwrap_or_default(),
-                    stream_id: None,
-                    response_writer: None,
-                });
-            }
+                Ok(_) => ByteRange::Unsatisfiable,
+
+                Err(_) => ByteRange::Ignored,
+            };
         }
 
-        let h_conn = Http3Conn {
-            h3_conn: quiche::h3::Connection::with_transport(
-                conn,
-                &quiche::h3::Config::new().unwrap(),
-            )
-            .unwrap(),
-            reqs_hdrs_sent: 0,
-            reqs_complete: 0,
-            largest_processed_request: 0,
-            reqs,
-            body: body.as_ref().map(|b| b.to_vec()),
-            sent_body_bytes: HashMap::new(),
-            dump_json: dump_json.is_some(),
-            dgram_sender,
-            output_sink,
+        let first = match first.parse::<u64>() {
+            Ok(v) => v,
+
+            Err(_) => return ByteRange::Ignored,
         };
 
-        Box::new(h_conn)
+        let last = if last.is_empty() {
+            u64::MAX
+        } else {
+            match last.parse::<u64>() {
+                Ok(v) if v >= first => v,
+
+                _ => return ByteRange::Ignored,
+            }
+        };
+
+        if first >= len {
+            return ByteRange::Unsatisfiable;
+        }
+
+        ByteRange::Satisfiable(first, std::cmp::min(last, len - 1))
     }
+}
 
-    pub fn with_conn(
-        conn: &mut quiche::Connection, dgram_sender: Option<Http3DgramSender>,
+/// Builds the headers of an HTTP/3 request for the given URL, including the
+/// custom headers given in `name: value` form.
+pub fn make_h3_request_headers(
+    url: &url::Url, method: &str, req_headers: &[String],
+) -> Vec<quiche::h3::Header> {
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap(), port),
+
+        None => url.host_str().unwrap().to_string(),
+    };
+
+    let mut hdrs = vec![
+        quiche::h3::Header::new(b":method", method.as_bytes()),
+        quiche::h3::Header::new(b":scheme", url.scheme().as_bytes()),
+        quiche::h3::Header::new(b":authority", authority.as_bytes()),
+        quiche::h3::Header::new(
+            b":path",
+            url[url::Position::BeforePath..].as_bytes(),
+        ),
+        quiche::h3::Header::new(b"user-agent", b"quiche"),
+    ];
+
+    // Add custom headers to the request.
+    for header in req_headers {
+        let header_split: Vec<&str> = header.splitn(2, ": ").collect();
+
+        if header_split.len() != 2 {
+            panic!("malformed header provided - \"{}\"", header);
+        }
+
+        hdrs.push(quiche::h3::Header::new(
+            header_split[0].as_bytes(),
+            header_split[1].as_bytes(),
+        ));
+    }
+
+    hdrs
+}
+
+/// The size of the chunks request bodies are read in.
+const BODY_CHUNK_LEN: usize = 65536;
+
+/// Where the body of client requests is read from.
+#[derive(Clone, Debug)]
+pub enum RequestBody {
+    /// The file at the given path, opened separately for each request.
+    File(String),
+
+    /// The standard input, which can only be used for a single request.
+    Stdin,
+}
+
+impl RequestBody {
+    /// Parses the value of the `--body` option. `-` stands for the standard
+    /// input, and a leading `@` is allowed before file paths.
+    pub fn parse(v: &str) -> RequestBody {
+        match v {
+            "-" => RequestBody::Stdin,
+
+            _ => RequestBody::File(v.trim_start_matches('@').to_string()),
+        }
+    }
+
+    /// Returns the length of the body, if known in advance.
+    fn len(&self) -> Option<u64> {
+        match self {
+            RequestBody::File(path) =>
+                std::fs::metadata(path).map(|m| m.len()).ok(),
+
+            RequestBody::Stdin => None,
+        }
+    }
+
+    fn open(&self) -> std::io::Result<Box<dyn Read>> {
+        match self {
+            RequestBody::File(path) => Ok(Box::new(std::fs::File::open(path)?)),
+
+            RequestBody::Stdin => Ok(Box::new(std::io::stdin())),
+        }
+    }
+}
+
+/// A request body being uploaded, one chunk at a time.
+struct BodyUpload {
+    reader: Box<dyn Read>,
+    buf: Vec<u8>,
+    off: usize,
+    eof: bool,
+    done: bool,
+}
+
+impl BodyUpload {
+    fn new(reader: Box<dyn Read>) -> Self {
+        BodyUpload {
+            reader,
+            buf: Vec::new(),
+            off: 0,
+            eof: false,
+            done: false,
+        }
+    }
+
+    /// Sends as much of the body as flow control allows, reading it from the
+    /// source as needed, and returns the number of bytes sent.
+    fn send(
+        &mut self, h3_conn: &mut quiche::h3::Connection,
+        conn: &mut quiche::Connection, stream_id: u64,
+    ) -> usize {
+        let mut sent = 0;
+
+        while !self.done {
+            if self.off == self.buf.len() && !self.eof {
+                self.buf.resize(BODY_CHUNK_LEN, 0);
+
+                match self.reader.read(&mut self.buf) {
+                    Ok(len) => {
+                        self.buf.truncate(len);
+                        self.off = 0;
+                        self.eof = len == 0;
+                    },
+
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                        self.buf.clear();
+                        self.off = 0;
+                        continue;
+                    },
+
+                    Err(e) => {
+                        error!("failed to read request body {:?}", e);
+
+                        conn.stream_shutdown(
+                            stream_id,
+                            quiche::Shutdown::Write,
+                            0,
+                        )
+                        .ok();
+
+                        self.done = true;
+                        break;
+                    },
+                }
+            }
+
+            let chunk = &self.buf[self.off..];
+
+            match h3_conn.send_body(conn, stream_id, chunk, self.eof) {
+                Ok(v) => {
+                    self.off += v;
+                    sent += v;
+
+                    if self.off < self.buf.len() {
+                        // The stream is blocked, retry later.
+                        break;
+                    }
+
+                    self.done = self.eof;
+                },
+
+                Err(quiche::h3::Error::Done) => break,
+
+                Err(e) => {
+                    error!("failed to send request body {:?}", e);
+
+                    self.done = true;
+                },
+            }
+        }
+
+        sent
+    }
+}
+
+pub struct Http3Conn {
+    h3_conn: quiche::h3::Connection,
+    reqs_hdrs_sent: usize,
+    reqs_complete: usize,
+    max_concurrent_reqs: Option<usize>,
+    largest_processed_request: u64,
+    reqs: Vec<Http3Request>,
+    body: Option<RequestBody>,
+    body_uploads: HashMap<u64, BodyUpload>,
+    dump_json: bool,
+    dgram_sender: Option<Http3DgramSender>,
+    output_sink: Rc<RefCell<dyn FnMut(String)>>,
+    masque_proxy: bool,
+    proxied_flows: HashMap<u64, ProxiedFlow>,
+    proxy_to: Option<Upstream>,
+    upstreams: HashMap<u64, ProxiedRequest>,
+    response_statuses: Vec<u16>,
+    throttle: Option<Throttle>,
+    response_cache: Option<Rc<RefCell<ResponseCache>>>,
+    access_log: bool,
+    pending_requests: HashMap<u64, (std::time::Instant, CompletedRequest)>,
+    completed_requests: Vec<CompletedRequest>,
+}
+
+impl Http3Conn {
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_urls(
+        conn: &mut quiche::Connection, urls: &[url::Url], reqs_cardinal: u64,
+        max_concurrent_reqs: Option<usize>, req_headers: &[String],
+        body: &Option<RequestBody>, content_type: &Option<String>, method: &str,
+        dump_json: Option<usize>, dgram_sender: Option<Http3DgramSender>,
         output_sink: Rc<RefCell<dyn FnMut(String)>>,
     ) -> Box<dyn HttpConn> {
+        let mut reqs = Vec::new();
+        if let Some(RequestBody::Stdin) = body {
+            if urls.len() as u64 * reqs_cardinal > 1 {
+                panic!("the body can only be read from stdin for one request");
+            }
+        }
+
+        for url in urls {
+            for i in 1..=reqs_cardinal {
+                let mut hdrs = make_h3_request_headers(url, method, req_headers);
+
+                if let Some(len) = body.as_ref().and_then(|b| b.len()) {
+                    hdrs.push(quiche::h3::Header::new(
+                        b"content-length",
+                        len.to_string().as_bytes(),
+                    ));
+                }
+
+                if let (Some(_), Some(ty)) = (body, content_type) {
+                    hdrs.push(quiche::h3::Header::new(
+                        b"content-type",
+                        ty.as_bytes(),
+                    ));
+                }
+
+                reqs.push(Http3Request {
+                    url: url.clone(),
+                    cardinal: i,
+                    hdrs,
+                    response_hdrs: Vec::new(),
+                    response_body: Vec::new(),
+                    response_body_max: dump_json.unwrap_or_default(),
+                    stream_id: None,
+                    response_writer: None,
+                    progress: RequestProgress::default(),
+                });
+            }
+        }
+
         let h_conn = Http3Conn {
             h3_conn: quiche::h3::Connection::with_transport(
                 conn,
@@ -888,28 +1544,134 @@ impl Http3Conn {
             .unwrap(),
             reqs_hdrs_sent: 0,
             reqs_complete: 0,
+            max_concurrent_reqs,
+            largest_processed_request: 0,
+            reqs,
+            body: body.clone(),
+            body_uploads: HashMap::new(),
+            dump_json: dump_json.is_some(),
+            dgram_sender,
+            output_sink,
+            masque_proxy: false,
+            proxied_flows: HashMap::new(),
+            proxy_to: None,
+            upstreams: HashMap::new(),
+            response_statuses: Vec::new(),
+            throttle: None,
+            response_cache: None,
+            access_log: false,
+            pending_requests: HashMap::new(),
+            completed_requests: Vec::new(),
+        };
+
+        Box::new(h_conn)
+    }
+
+    pub fn with_conn(
+        conn: &mut quiche::Connection, dgram_sender: Option<Http3DgramSender>,
+        output_sink: Rc<RefCell<dyn FnMut(String)>>, masque_proxy: bool,
+        proxy_to: Option<Upstream>,
+    ) -> Box<dyn HttpConn> {
+        let mut h3_config = quiche::h3::Config::new().unwrap();
+
+        // CONNECT-UDP requests use extended CONNECT.
+        h3_config.enable_extended_connect(masque_proxy);
+
+        let h_conn = Http3Conn {
+            h3_conn: quiche::h3::Connection::with_transport(conn, &h3_config)
+                .unwrap(),
+            reqs_hdrs_sent: 0,
+            reqs_complete: 0,
+            max_concurrent_reqs: None,
             largest_processed_request: 0,
             reqs: Vec::new(),
             body: None,
-            sent_body_bytes: HashMap::new(),
+            body_uploads: HashMap::new(),
             dump_json: false,
             dgram_sender,
             output_sink,
+            masque_proxy,
+            proxied_flows: HashMap::new(),
+            proxy_to,
+            upstreams: HashMap::new(),
+            response_statuses: Vec::new(),
+            throttle: None,
+            response_cache: None,
+            access_log: false,
+            pending_requests: HashMap::new(),
+            completed_requests: Vec::new(),
         };
 
         Box::new(h_conn)
     }
 
+    /// Opens a UDP flow towards the target of a CONNECT-UDP request, and
+    /// responds to the request accordingly.
+    fn handle_connect_udp(
+        &mut self, conn: &mut quiche::Connection, stream_id: u64,
+        request: &[quiche::h3::Header],
+    ) -> quiche::h3::Result<()> {
+        let path = request
+            .iter()
+            .find(|h| h.name() == b":path")
+            .and_then(|h| std::str::from_utf8(h.value()).ok())
+            .unwrap_or("");
+
+        let target = quiche::h3::connect_udp::parse_path(
+            quiche::h3::connect_udp::DEFAULT_URI_TEMPLATE,
+            path,
+        );
+
+        let status = match target.and_then(|(host, port)| {
+            ProxiedFlow::connect(&host, port)
+                .map_err(|e| {
+                    error!("failed to connect to {}:{}: {:?}", host, port, e);
+                })
+                .ok()
+        }) {
+            Some(flow) => {
+                info!(
+                    "{} proxying stream {} to {}",
+                    conn.trace_id(),
+                    stream_id,
+                    flow.target
+                );
+
+                self.proxied_flows.insert(stream_id, flow);
+
+                200
+            },
+
+            None => 400,
+        };
+
+        self.response_statuses.push(status);
+
+        let headers = vec![
+            quiche::h3::Header::new(b":status", status.to_string().as_bytes()),
+            quiche::h3::Header::new(b"capsule-protocol", b"?1"),
+            quiche::h3::Header::new(b"server", b"quiche"),
+        ];
+
+        // The request stream stays open for as long as the flow is proxied.
+        let fin = !self.proxied_flows.contains_key(&stream_id);
+
+        self.h3_conn.send_response(conn, stream_id, &headers, fin)
+    }
+
     /// Builds an HTTP/3 response given a request.
     fn build_h3_response(
         root: &str, index: &str, request: &[quiche::h3::Header],
-    ) -> (Vec<quiche::h3::Header>, Vec<u8>, String) {
+        cache: Option<&Rc<RefCell<ResponseCache>>>,
+    ) -> (PartialResponse, String) {
         let mut file_path = path::PathBuf::from(root);
         let mut scheme = "";
         let mut host = "";
         let mut path = "";
         let mut method = "";
         let mut priority = "";
+        let mut range = None;
+        let mut if_modified_since = None;
 
         // Parse some of the request headers.
         for hdr in request {
@@ -926,6 +1688,11 @@ impl Http3Conn {
                 b"priority" =>
                     priority = std::str::from_utf8(hdr.value()).unwrap(),
 
+                b"range" => range = std::str::from_utf8(hdr.value()).ok(),
+
+                b"if-modified-since" =>
+                    if_modified_since = std::str::from_utf8(hdr.value()).ok(),
+
                 _ => (),
             }
         }
@@ -936,14 +1703,21 @@ impl Http3Conn {
                 quiche::h3::Header::new(b"server", b"quiche"),
             ];
 
-            return (headers, b"Invalid scheme".to_vec(), priority.to_string());
+            let response = PartialResponse {
+                headers: Some(headers),
+                body: b"Invalid scheme".to_vec(),
+                cached: None,
+                written: 0,
+                file: None,
+            };
+
+            return (response, priority.to_string());
         }
 
         let url = format!("{}://{}{}", scheme, host, path);
         let url = url::Url::parse(&url).unwrap();
 
         let pathbuf = path::PathBuf::from(url.path());
-        let pathbuf = autoindex(pathbuf, index);
 
         // Priority query string takes precedence over the header.
         // So replace the header with one built here.
@@ -962,7 +1736,11 @@ impl Http3Conn {
             priority = &query_priority;
         }
 
-        let (status, body) = match method {
+        let mut extra_headers = Vec::new();
+
+        let mut cached_body = None;
+
+        let (status, body, file) = match method {
             "GET" => {
                 for c in pathbuf.components() {
                     if let path::Component::Normal(v) = c {
@@ -970,31 +1748,259 @@ impl Http3Conn {
                     }
                 }
 
-                match std::fs::read(file_path.as_path()) {
-                    Ok(data) => (200, data),
+                let file_path = autoindex(file_path, index);
+
+                match FileBody::open(&file_path) {
+                    Ok(mut file) => {
+                        let len = file.remaining;
+
+                        extra_headers.push(quiche::h3::Header::new(
+                            b"content-type",
+                            content_type(&file_path).as_bytes(),
+                        ));
 
-                    Err(_) => (404, b"Not Found!".to_vec()),
+                        extra_headers.push(quiche::h3::Header::new(
+                            b"accept-ranges",
+                            b"bytes",
+                        ));
+
+                        if let Some(modified) = file.modified {
+                            extra_headers.push(quiche::h3::Header::new(
+                                b"last-modified",
+                                crate::cache::format_http_date(modified)
+                                    .as_bytes(),
+                            ));
+                        }
+
+                        let not_modified =
+                            match (file.modified, if_modified_since) {
+                                (Some(modified), Some(since)) =>
+                                    crate::cache::not_modified_since(
+                                        modified, since,
+                                    ),
+
+                                _ => false,
+                            };
+
+                        // Serve the body from memory when it's cached.
+                        let cached = match cache {
+                            Some(cache) if !not_modified =>
+                                cache.borrow_mut().get(&file_path, &mut file.file),
+
+                            _ => None,
+                        };
+
+                        match range.map(|v| ByteRange::parse(v, len)) {
+                            _ if not_modified => (304, Vec::new(), None),
+
+                            Some(ByteRange::Satisfiable(first, last))
+                                if cached.is_some() =>
+                            {
+                                let content_range =
+                                    format!("bytes {}-{}/{}", first, last, len);
+
+                                extra_headers.push(quiche::h3::Header::new(
+                                    b"content-range",
+                                    content_range.as_bytes(),
+                                ));
+
+                                let body = cached.unwrap();
+                                let range = first as usize..last as usize + 1;
+
+                                cached_body = Some((body, range));
+
+                                (206, Vec::new(), None)
+                            },
+
+                            Some(ByteRange::Satisfiable(first, last)) => {
+                                let content_range =
+                                    format!("bytes {}-{}/{}", first, last, len);
+
+                                extra_headers.push(quiche::h3::Header::new(
+                                    b"content-range",
+                                    content_range.as_bytes(),
+                                ));
+
+                                file.offset = first;
+                                file.remaining = last - first + 1;
+
+                                (206, Vec::new(), Some(file))
+                            },
+
+                            Some(ByteRange::Unsatisfiable) => {
+                                let content_range = format!("bytes */{}", len);
+
+                                extra_headers.push(quiche::h3::Header::new(
+                                    b"content-range",
+                                    content_range.as_bytes(),
+                                ));
+
+                                (416, Vec::new(), None)
+                            },
+
+                            Some(ByteRange::Ignored) | None => match cached {
+                                Some(body) => {
+                                    let range = 0..body.len();
+
+                                    cached_body = Some((body, range));
+
+                                    (200, Vec::new(), None)
+                                },
+
+                                None => (200, Vec::new(), Some(file)),
+                            },
+                        }
+                    },
+
+                    Err(_) => (404, b"Not Found!".to_vec(), None),
                 }
             },
 
-            _ => (405, Vec::new()),
+            _ => (405, Vec::new(), None),
+        };
+
+        let content_length = match (&file, &cached_body) {
+            (Some(f), _) => f.remaining,
+
+            (None, Some((_, range))) => range.len() as u64,
+
+            (None, None) => body.len() as u64,
         };
 
         let mut headers = vec![
             quiche::h3::Header::new(b":status", status.to_string().as_bytes()),
             quiche::h3::Header::new(b"server", b"quiche"),
-            quiche::h3::Header::new(
-                b"content-length",
-                body.len().to_string().as_bytes(),
-            ),
         ];
 
+        // A 304 response has no body, and the content-length would otherwise
+        // describe the unchanged file.
+        if status != 304 {
+            headers.push(quiche::h3::Header::new(
+                b"content-length",
+                content_length.to_string().as_bytes(),
+            ));
+        }
+
+        headers.append(&mut extra_headers);
+
         if !priority.is_empty() {
             headers
                 .push(quiche::h3::Header::new(b"priority", priority.as_bytes()));
         }
 
-        (headers, body, priority.to_string())
+        let response = PartialResponse {
+            headers: Some(headers),
+            body,
+            cached: cached_body,
+            written: 0,
+            file,
+        };
+
+        (response, priority.to_string())
+    }
+
+    /// Sends as much of a response body as the stream can take, reading
+    /// further chunks from disk as capacity frees up. Returns true once the
+    /// response is finished.
+    fn send_partial_body(
+        &mut self, conn: &mut quiche::Connection, stream_id: u64,
+        resp: &mut PartialResponse,
+    ) -> quiche::h3::Result<bool> {
+        loop {
+            let remaining = resp.buffered().len() - resp.written;
+            let len = throttled_len(&mut self.throttle, remaining);
+
+            // Wait for the throttle to refill, unless only the FIN is left.
+            if len == 0 && remaining > 0 {
+                return Ok(false);
+            }
+
+            let fin = resp.is_last_chunk() && len == remaining;
+            let body = &resp.buffered()[resp.written..resp.written + len];
+
+            let written = match self.h3_conn.send_body(conn, stream_id, body, fin)
+            {
+                Ok(v) => v,
+
+                Err(quiche::h3::Error::Done) => 0,
+
+                Err(e) => return Err(e),
+            };
+
+            if let Some(throttle) = &mut self.throttle {
+                throttle.consume(written);
+            }
+
+            if let Some((_, req)) = self.pending_requests.get_mut(&stream_id) {
+                req.bytes += written as u64;
+            }
+
+            resp.written += written;
+
+            if resp.written < resp.buffered().len() {
+                return Ok(false);
+            }
+
+            if fin {
+                self.finish_request(stream_id);
+
+                return Ok(true);
+            }
+
+            let cap = conn.stream_capacity(stream_id)?;
+
+            if cap == 0 {
+                return Ok(false);
+            }
+
+            if let Err(e) = resp.refill(cap) {
+                error!("{} failed to read body: {:?}", conn.trace_id(), e);
+
+                // H3_INTERNAL_ERROR
+                conn.stream_shutdown(stream_id, quiche::Shutdown::Write, 0x102)?;
+
+                self.finish_request(stream_id);
+
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Starts tracking a request for the access log.
+    fn start_request(
+        &mut self, conn: &quiche::Connection, stream_id: u64,
+        request: &[quiche::h3::Header], status: u16,
+    ) {
+        let header = |name: &[u8]| {
+            request
+                .iter()
+                .find(|h| h.name() == name)
+                .map(|h| String::from_utf8_lossy(h.value()).into_owned())
+        };
+
+        let req = CompletedRequest {
+            method: header(b":method").unwrap_or_default(),
+            path: header(b":path").unwrap_or_default(),
+            status,
+            referer: header(b"referer"),
+            user_agent: header(b"user-agent"),
+            bytes: 0,
+            received: std::time::SystemTime::now(),
+            duration: std::time::Duration::default(),
+            early_data: conn.is_in_early_data(),
+        };
+
+        self.pending_requests
+            .insert(stream_id, (std::time::Instant::now(), req));
+    }
+
+    /// Moves a request to the completed ones, once its response was sent.
+    fn finish_request(&mut self, stream_id: u64) {
+        if let Some((start, mut req)) = self.pending_requests.remove(&stream_id) {
+            req.duration = start.elapsed();
+
+            self.completed_requests.push(req);
+        }
     }
 }
 
@@ -1004,8 +2010,18 @@ impl HttpConn for Http3Conn {
     ) {
         let mut reqs_done = 0;
 
+        let reqs_in_flight = self.reqs_hdrs_sent - self.reqs_complete;
+        let reqs_allowed = self
+            .max_concurrent_reqs
+            .map_or(usize::MAX, |max| max.saturating_sub(reqs_in_flight));
+
         // First send headers.
-        for req in self.reqs.iter_mut().skip(self.reqs_hdrs_sent) {
+        for req in self
+            .reqs
+            .iter_mut()
+            .skip(self.reqs_hdrs_sent)
+            .take(reqs_allowed)
+        {
             let s = match self.h3_conn.send_request(
                 conn,
                 &req.hdrs,
@@ -1014,7 +2030,7 @@ impl HttpConn for Http3Conn {
                 Ok(v) => v,
 
                 Err(quiche::h3::Error::TransportError(
-                    quiche::Error::StreamLimit,
+                    quiche::Error::StreamLimit(_),
                 )) => {
                     debug!("not enough stream credits, retry later...");
                     break;
@@ -1034,43 +2050,43 @@ impl HttpConn for Http3Conn {
             debug!("Sent HTTP request {:?}", req.hdrs);
 
             req.stream_id = Some(s);
+            req.progress.on_sent();
             req.response_writer =
                 make_resource_writer(&req.url, target_path, req.cardinal);
-            self.sent_body_bytes.insert(s, 0);
 
-            reqs_done += 1;
-        }
-        self.reqs_hdrs_sent += reqs_done;
+            if let Some(body) = &self.body {
+                match body.open() {
+                    Ok(reader) => {
+                        self.body_uploads.insert(s, BodyUpload::new(reader));
+                    },
 
-        // Then send any remaining body.
-        if let Some(body) = &self.body {
-            for (stream_id, sent_bytes) in self.sent_body_bytes.iter_mut() {
-                if *sent_bytes == body.len() {
-                    continue;
-                }
+                    Err(e) => {
+                        error!("failed to open request body {:?}", e);
 
-                // Always try to send all remaining bytes, so always set fin to
-                // true.
-                let sent = match self.h3_conn.send_body(
-                    conn,
-                    *stream_id,
-                    &body[*sent_bytes..],
-                    true,
-                ) {
-                    Ok(v) => v,
+                        conn.stream_shutdown(s, quiche::Shutdown::Write, 0).ok();
+                    },
+                }
+            }
 
-                    Err(quiche::h3::Error::Done) => 0,
+            reqs_done += 1;
+        }
+        self.reqs_hdrs_sent += reqs_done;
 
-                    Err(e) => {
-                        error!("failed to send request body {:?}", e);
-                        continue;
-                    },
-                };
+        // Then stream the remaining bodies.
+        for (stream_id, upload) in self.body_uploads.iter_mut() {
+            let sent = upload.send(&mut self.h3_conn, conn, *stream_id);
 
-                *sent_bytes += sent;
+            if let Some(req) = self
+                .reqs
+                .iter_mut()
+                .find(|r| r.stream_id == Some(*stream_id))
+            {
+                req.progress.bytes_sent += sent as u64;
             }
         }
 
+        self.body_uploads.retain(|_, upload| !upload.done);
+
         // And finally any DATAGRAMS.
         if let Some(ds) = self.dgram_sender.as_mut() {
             let mut dgrams_done = 0;
@@ -1120,6 +2136,7 @@ impl HttpConn for Http3Conn {
                         .find(|r| r.stream_id == Some(stream_id))
                         .unwrap();
 
+                    req.progress.on_recv(0);
                     req.response_hdrs = list;
                 },
 
@@ -1138,6 +2155,8 @@ impl HttpConn for Http3Conn {
                             .find(|r| r.stream_id == Some(stream_id))
                             .unwrap();
 
+                        req.progress.on_recv(read);
+
                         let len = std::cmp::min(
                             read,
                             req.response_body_max - req.response_body.len(),
@@ -1161,7 +2180,22 @@ impl HttpConn for Http3Conn {
                     }
                 },
 
-                Ok((_stream_id, quiche::h3::Event::Finished)) => {
+                Ok((stream_id, quiche::h3::Event::Trailers { list })) => {
+                    debug!(
+                        "got response trailers {:?} on stream id {}",
+                        list, stream_id
+                    );
+                },
+
+                Ok((stream_id, quiche::h3::Event::Finished)) => {
+                    if let Some(req) = self
+                        .reqs
+                        .iter_mut()
+                        .find(|r| r.stream_id == Some(stream_id))
+                    {
+                        req.progress.on_finished();
+                    }
+
                     self.reqs_complete += 1;
                     let reqs_count = self.reqs.len();
 
@@ -1230,6 +2264,18 @@ impl HttpConn for Http3Conn {
                     );
                 },
 
+                Ok((_, quiche::h3::Event::PriorityUpdate { .. })) => (),
+
+                Ok((_, quiche::h3::Event::ExtensionFrame { .. })) =>
+                    unreachable!(),
+
+                Ok((_, quiche::h3::Event::ExtensionStream { .. })) =>
+                    unreachable!(),
+
+                Ok((_, quiche::h3::Event::RawStream { .. })) => unreachable!(),
+
+                Ok((_, quiche::h3::Event::Settings)) => (),
+
                 Err(quiche::h3::Error::Done) => {
                     break;
                 },
@@ -1262,6 +2308,22 @@ impl HttpConn for Http3Conn {
         false
     }
 
+    fn request_timings(&self, start: &std::time::Instant) -> Vec<RequestTimings> {
+        self.reqs
+            .iter()
+            .map(|r| {
+                let status = r
+                    .response_hdrs
+                    .iter()
+                    .find(|h| h.name() == b":status")
+                    .and_then(|h| std::str::from_utf8(h.value()).ok())
+                    .and_then(|v| v.parse().ok());
+
+                r.progress.timings(start, &r.url, status)
+            })
+            .collect()
+    }
+
     fn handle_requests(
         &mut self, conn: &mut std::pin::Pin<Box<quiche::Connection>>,
         _partial_requests: &mut HashMap<u64, PartialRequest>,
@@ -1271,7 +2333,10 @@ impl HttpConn for Http3Conn {
         // Process HTTP events.
         loop {
             match self.h3_conn.poll(conn) {
-                Ok((stream_id, quiche::h3::Event::Headers { list, .. })) => {
+                Ok((
+                    stream_id,
+                    quiche::h3::Event::Headers { list, has_body },
+                )) => {
                     info!(
                         "{} got request {:?} on stream id {}",
                         conn.trace_id(),
@@ -1282,6 +2347,58 @@ impl HttpConn for Http3Conn {
                     self.largest_processed_request =
                         std::cmp::max(self.largest_processed_request, stream_id);
 
+                    if self.masque_proxy &&
+                        quiche::h3::connect_udp::is_request(&list)
+                    {
+                        if let Err(e) =
+                            self.handle_connect_udp(conn, stream_id, &list)
+                        {
+                            error!(
+                                "{} stream send failed {:?}",
+                                conn.trace_id(),
+                                e
+                            );
+                        }
+
+                        continue;
+                    }
+
+                    if let Some(upstream) = &self.proxy_to {
+                        match ProxiedRequest::new(upstream, &list, has_body) {
+                            Ok(req) => {
+                                self.upstreams.insert(stream_id, req);
+                            },
+
+                            Err(status) => {
+                                self.response_statuses.push(status);
+
+                                let headers = vec![
+                                    quiche::h3::Header::new(
+                                        b":status",
+                                        status.to_string().as_bytes(),
+                                    ),
+                                    quiche::h3::Header::new(b"server", b"quiche"),
+                                    quiche::h3::Header::new(
+                                        b"content-length",
+                                        b"0",
+                                    ),
+                                ];
+
+                                if let Err(e) = self.h3_conn.send_response(
+                                    conn, stream_id, &headers, true,
+                                ) {
+                                    error!(
+                                        "{} stream send failed {:?}",
+                                        conn.trace_id(),
+                                        e
+                                    );
+                                }
+                            },
+                        }
+
+                        continue;
+                    }
+
                     // We decide the response based on headers alone, so
                     // stop reading the request stream so that any body
                     // is ignored and pointless Data events are not
@@ -1289,21 +2406,38 @@ impl HttpConn for Http3Conn {
                     conn.stream_shutdown(stream_id, quiche::Shutdown::Read, 0)
                         .unwrap();
 
-                    let (headers, body, priority) =
-                        Http3Conn::build_h3_response(root, index, &list);
+                    let (mut response, priority) = Http3Conn::build_h3_response(
+                        root,
+                        index,
+                        &list,
+                        self.response_cache.as_ref(),
+                    );
+
+                    if let Some(status) = response
+                        .headers
+                        .iter()
+                        .flatten()
+                        .find(|h| h.name() == b":status")
+                        .and_then(|h| std::str::from_utf8(h.value()).ok())
+                        .and_then(|v| v.parse().ok())
+                    {
+                        self.response_statuses.push(status);
+
+                        if self.access_log {
+                            self.start_request(conn, stream_id, &list, status);
+                        }
+                    }
 
                     match self.h3_conn.send_response_with_priority(
-                        conn, stream_id, &headers, &priority, false,
+                        conn,
+                        stream_id,
+                        response.headers.as_deref().unwrap(),
+                        &priority,
+                        false,
                     ) {
                         Ok(v) => v,
 
                         Err(quiche::h3::Error::StreamBlocked) => {
-                            let response = PartialResponse {
-                                headers: Some(headers),
-                                body,
-                                written: 0,
-                            };
-
                             partial_responses.insert(stream_id, response);
                             continue;
                         },
@@ -1319,13 +2453,14 @@ impl HttpConn for Http3Conn {
                         },
                     }
 
-                    let written = match self
-                        .h3_conn
-                        .send_body(conn, stream_id, &body, true)
-                    {
-                        Ok(v) => v,
+                    response.headers = None;
 
-                        Err(quiche::h3::Error::Done) => 0,
+                    match self.send_partial_body(conn, stream_id, &mut response) {
+                        Ok(true) => (),
+
+                        Ok(false) => {
+                            partial_responses.insert(stream_id, response);
+                        },
 
                         Err(e) => {
                             error!(
@@ -1336,16 +2471,6 @@ impl HttpConn for Http3Conn {
 
                             break;
                         },
-                    };
-
-                    if written < body.len() {
-                        let response = PartialResponse {
-                            headers: None,
-                            body,
-                            written,
-                        };
-
-                        partial_responses.insert(stream_id, response);
                     }
                 },
 
@@ -1355,16 +2480,63 @@ impl HttpConn for Http3Conn {
                         conn.trace_id(),
                         stream_id
                     );
+
+                    if let Some(req) = self.upstreams.get_mut(&stream_id) {
+                        req.recv_request_body(
+                            &mut self.h3_conn,
+                            conn,
+                            stream_id,
+                            buf,
+                        );
+                    }
                 },
 
-                Ok((_stream_id, quiche::h3::Event::Finished)) => (),
+                Ok((stream_id, quiche::h3::Event::Trailers { list })) => {
+                    info!(
+                        "{} got trailers {:?} on stream id {}",
+                        conn.trace_id(),
+                        &list,
+                        stream_id
+                    );
+                },
 
-                Ok((_stream_id, quiche::h3::Event::Reset { .. })) => (),
+                Ok((stream_id, quiche::h3::Event::Finished)) => {
+                    self.proxied_flows.remove(&stream_id);
+
+                    if let Some(req) = self.upstreams.get_mut(&stream_id) {
+                        req.finish_request();
+                    }
+                },
+
+                Ok((stream_id, quiche::h3::Event::Reset { .. })) => {
+                    self.proxied_flows.remove(&stream_id);
+                    self.upstreams.remove(&stream_id);
+                    self.pending_requests.remove(&stream_id);
+                },
 
                 Ok((_, quiche::h3::Event::Datagram)) => {
                     while let Ok((len, flow_id, flow_id_len)) =
                         self.h3_conn.recv_dgram(conn, buf)
                     {
+                        // The flow ID is the Quarter Stream ID of the request.
+                        let stream_id = flow_id * 4;
+
+                        if let Some(flow) = self.proxied_flows.get(&stream_id) {
+                            // Datagrams with unknown Context IDs are dropped.
+                            if let Ok((
+                                quiche::h3::connect_udp::UDP_PAYLOAD_CONTEXT_ID,
+                                payload,
+                            )) = quiche::h3::connect_udp::decode_payload(
+                                &buf[flow_id_len..len],
+                            ) {
+                                if let Err(e) = flow.socket.send(payload) {
+                                    debug!("UDP send failed: {:?}", e);
+                                }
+                            }
+
+                            continue;
+                        }
+
                         info!(
                             "Received DATAGRAM flow_id={} len={} data={:?}",
                             flow_id,
@@ -1374,6 +2546,31 @@ impl HttpConn for Http3Conn {
                     }
                 },
 
+                Ok((
+                    _,
+                    quiche::h3::Event::PriorityUpdate {
+                        stream_id,
+                        priority,
+                    },
+                )) => {
+                    info!(
+                        "{} got PRIORITY_UPDATE for stream id {}: {:?}",
+                        conn.trace_id(),
+                        stream_id,
+                        priority
+                    );
+                },
+
+                Ok((_, quiche::h3::Event::ExtensionFrame { .. })) =>
+                    unreachable!(),
+
+                Ok((_, quiche::h3::Event::ExtensionStream { .. })) =>
+                    unreachable!(),
+
+                Ok((_, quiche::h3::Event::RawStream { .. })) => unreachable!(),
+
+                Ok((_, quiche::h3::Event::Settings)) => (),
+
                 Ok((goaway_id, quiche::h3::Event::GoAway)) => {
                     trace!(
                         "{} got GOAWAY with ID {} ",
@@ -1457,25 +2654,172 @@ impl HttpConn for Http3Conn {
 
         resp.headers = None;
 
-        let body = &resp.body[resp.written..];
-
-        let written = match self.h3_conn.send_body(conn, stream_id, body, true) {
-            Ok(v) => v,
+        match self.send_partial_body(conn, stream_id, resp) {
+            Ok(true) => {
+                partial_responses.remove(&stream_id);
+            },
 
-            Err(quiche::h3::Error::Done) => 0,
+            Ok(false) => (),
 
             Err(e) => {
                 partial_responses.remove(&stream_id);
 
                 error!("{} stream send failed {:?}", conn.trace_id(), e);
-                return;
             },
-        };
+        }
+    }
+
+    fn relay_proxied_flows(
+        &mut self, conn: &mut std::pin::Pin<Box<quiche::Connection>>,
+        buf: &mut [u8],
+    ) {
+        // Sockets are registered with edge-triggered readiness, so they are
+        // read until they would block.
+        for (stream_id, flow) in &self.proxied_flows {
+            loop {
+                let len = match flow.socket.recv(buf) {
+                    Ok(v) => v,
+
+                    Err(e) => {
+                        if e.kind() != std::io::ErrorKind::WouldBlock {
+                            debug!("UDP recv failed: {:?}", e);
+                        }
+
+                        break;
+                    },
+                };
+
+                let payload = match quiche::h3::connect_udp::encode_payload(
+                    quiche::h3::connect_udp::UDP_PAYLOAD_CONTEXT_ID,
+                    &buf[..len],
+                ) {
+                    Ok(v) => v,
+
+                    Err(_) => continue,
+                };
+
+                // Payloads that can't be sent right away are dropped, as
+                // UDP would.
+                if let Err(e) =
+                    self.h3_conn.send_dgram(conn, stream_id / 4, &payload)
+                {
+                    debug!(
+                        "{} failed to relay UDP payload on stream {}: {:?}",
+                        conn.trace_id(),
+                        stream_id,
+                        e
+                    );
+                }
+            }
+        }
+    }
 
-        resp.written += written;
+    fn register_proxied_flows(&mut self, poll: &mio::Poll, token: mio::Token) {
+        for flow in self.proxied_flows.values_mut().filter(|f| !f.registered) {
+            if let Err(e) = poll.register(
+                &flow.socket,
+                token,
+                mio::Ready::readable(),
+                mio::PollOpt::edge(),
+            ) {
+                error!("failed to register proxied flow: {:?}", e);
+            }
 
-        if resp.written == resp.body.len() {
-            partial_responses.remove(&stream_id);
+            flow.registered = true;
         }
     }
+
+    fn relay_upstreams(
+        &mut self, conn: &mut std::pin::Pin<Box<quiche::Connection>>,
+        buf: &mut [u8],
+    ) {
+        let h3_conn = &mut self.h3_conn;
+        let response_statuses = &mut self.response_statuses;
+
+        self.upstreams.retain(|stream_id, req| {
+            let done = req.relay(h3_conn, conn, *stream_id, buf);
+
+            if let Some(status) = req.take_status() {
+                response_statuses.push(status);
+            }
+
+            !done
+        });
+    }
+
+    fn register_upstreams(&mut self, poll: &mio::Poll, token: mio::Token) {
+        for req in self.upstreams.values_mut() {
+            if let Err(e) = req.register(poll, token) {
+                error!("failed to register upstream connection: {:?}", e);
+            }
+        }
+    }
+
+    fn upstreams_timeout(&self) -> Option<std::time::Duration> {
+        self.upstreams
+            .values()
+            .filter_map(ProxiedRequest::timeout)
+            .min()
+    }
+
+    fn take_response_statuses(&mut self) -> Vec<u16> {
+        std::mem::take(&mut self.response_statuses)
+    }
+
+    fn set_max_send_rate(&mut self, rate: u64) {
+        self.throttle = Some(Throttle::new(rate));
+    }
+
+    fn set_response_cache(&mut self, cache: Rc<RefCell<ResponseCache>>) {
+        self.response_cache = Some(cache);
+    }
+
+    fn enable_access_log(&mut self) {
+        self.access_log = true;
+    }
+
+    fn take_completed_requests(&mut self) -> Vec<CompletedRequest> {
+        std::mem::take(&mut self.completed_requests)
+    }
+
+    fn throttle_timeout(&self) -> Option<std::time::Duration> {
+        self.throttle.as_ref().and_then(|t| t.timeout())
+    }
+}
+
+/// A UDP flow proxied for a CONNECT-UDP request.
+struct ProxiedFlow {
+    socket: mio::net::UdpSocket,
+
+    target: std::net::SocketAddr,
+
+    /// Whether the socket was registered with the event loop yet.
+    registered: bool,
+}
+
+impl ProxiedFlow {
+    /// Creates a non-blocking UDP socket connected to the given target.
+    fn connect(host: &str, port: u16) -> std::io::Result<ProxiedFlow> {
+        use std::net::ToSocketAddrs;
+
+        let addr = (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no address found")
+        })?;
+
+        let bind_addr = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+
+        let socket = std::net::UdpSocket::bind(bind_addr)?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(ProxiedFlow {
+            socket: mio::net::UdpSocket::from_socket(socket)?,
+            target: addr,
+            registered: false,
+        })
+    }
 }
@@ -0,0 +1,1147 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Forwarding of HTTP/1.1 requests from local TCP clients to an HTTP/3
+//! origin.
+//!
+//! `quiche-proxy` accepts HTTP/1.1 connections, with requests in either the
+//! form sent to proxies or the one sent to origins, and forwards them on a
+//! single HTTP/3 connection to the origin given on the command line. That
+//! connection is opened when the first request arrives, and again whenever
+//! it was closed. CONNECT requests are forwarded as HTTP/3 CONNECT requests,
+//! and the tunnelled bytes carried on the request stream.
+
+use std::collections::HashMap;
+
+use std::io::prelude::*;
+
+use std::net::ToSocketAddrs;
+
+use quiche::h3::NameValue;
+
+use crate::args::*;
+use crate::bind::BindOptions;
+use crate::client::*;
+use crate::common::alpns;
+use crate::proxy::find;
+use crate::proxy::is_hop_by_hop;
+
+const LISTENER_TOKEN: mio::Token = mio::Token(0);
+
+const ORIGIN_TOKEN: mio::Token = mio::Token(1);
+
+/// The token of the first downstream connection, the following ones are
+/// numbered sequentially.
+const FIRST_DOWNSTREAM_TOKEN: usize = 2;
+
+/// How much data is buffered in either direction before reading from the
+/// sending side is paused.
+const MAX_BUFFERED_LEN: usize = 1 << 20;
+
+/// The largest request head accepted from clients.
+const MAX_HEAD_LEN: usize = 65536;
+
+/// H3_REQUEST_CANCELLED, used to abort requests whose client went away.
+const H3_REQUEST_CANCELLED: u64 = 0x10c;
+
+/// How the body of a request is read from the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RequestBody {
+    /// A body with a known length, holding the number of bytes left.
+    Length(u64),
+
+    ChunkSize,
+
+    /// A chunk of a chunked body, holding the number of bytes left.
+    ChunkData(u64),
+
+    /// The line break that ends each chunk.
+    ChunkEnd,
+
+    Trailers,
+
+    /// The bytes tunnelled by a CONNECT request, until the client closes
+    /// the connection.
+    Tunnel,
+
+    /// The body was read entirely, or there was none.
+    Done,
+}
+
+/// How the body of a response is written to the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ResponseBody {
+    /// The body is written as is, as its end is either signalled by a
+    /// content-length header or by closing the connection.
+    Raw,
+
+    Chunked,
+
+    /// The response has no body.
+    Empty,
+}
+
+/// A request received from a client, and its response.
+struct Exchange {
+    /// The request headers, until they are sent to the origin.
+    headers: Option<Vec<quiche::h3::Header>>,
+
+    stream_id: Option<u64>,
+
+    is_connect: bool,
+
+    is_head: bool,
+
+    request_body: RequestBody,
+
+    /// Bytes of the request body still to be sent to the origin.
+    body: Vec<u8>,
+
+    fin_sent: bool,
+
+    /// How the response is written, once its headers were received.
+    response_body: Option<ResponseBody>,
+
+    trailers: Vec<quiche::h3::Header>,
+
+    origin_finished: bool,
+}
+
+/// A TCP connection from a client.
+struct Downstream {
+    stream: mio::net::TcpStream,
+
+    /// Bytes read from the client and not processed yet.
+    incoming: Vec<u8>,
+
+    /// Bytes still to be written to the client.
+    outgoing: Vec<u8>,
+
+    client_closed: bool,
+
+    keep_alive: bool,
+
+    exchange: Option<Exchange>,
+
+    /// Set when the connection is closed once `outgoing` is written.
+    closing: bool,
+
+    /// The stream of an aborted request, until it's reset.
+    cancelled: Option<u64>,
+}
+
+/// The HTTP/3 connection to the origin, shared by all clients.
+struct Origin {
+    socket: mio::net::UdpSocket,
+
+    conn: std::pin::Pin<Box<quiche::Connection>>,
+
+    h3_conn: Option<quiche::h3::Connection>,
+}
+
+impl Downstream {
+    fn new(stream: mio::net::TcpStream) -> Downstream {
+        Downstream {
+            stream,
+
+            incoming: Vec::new(),
+
+            outgoing: Vec::new(),
+
+            client_closed: false,
+
+            keep_alive: true,
+
+            exchange: None,
+
+            closing: false,
+
+            cancelled: None,
+        }
+    }
+
+    /// Returns true if a request is waiting for a connection to the origin.
+    fn wants_origin(&self) -> bool {
+        matches!(&self.exchange, Some(ex) if ex.headers.is_some())
+    }
+
+    /// Responds with an error, unless a response was already started, and
+    /// closes the connection.
+    fn fail(&mut self, status: u16) {
+        let started = matches!(
+            &self.exchange,
+            Some(ex) if ex.response_body.is_some()
+        );
+
+        if !started {
+            let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason(status));
+            head.push_str("content-length: 0\r\nconnection: close\r\n\r\n");
+
+            self.outgoing.extend_from_slice(head.as_bytes());
+        }
+
+        self.abort();
+    }
+
+    /// Drops the current exchange, and closes the connection.
+    fn abort(&mut self) {
+        if let Some(ex) = self.exchange.take() {
+            self.cancelled = ex.stream_id;
+        }
+
+        self.closing = true;
+    }
+
+    /// Reads what the client sent, up to the buffering limit.
+    fn read(&mut self, buf: &mut [u8]) {
+        while !self.client_closed && self.incoming.len() < MAX_BUFFERED_LEN {
+            match self.stream.read(buf) {
+                Ok(0) => self.client_closed = true,
+
+                Ok(len) => self.incoming.extend_from_slice(&buf[..len]),
+
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+
+                Err(e) => {
+                    debug!("downstream read failed: {:?}", e);
+
+                    self.client_closed = true;
+                    self.closing = true;
+                },
+            }
+        }
+    }
+
+    /// Writes the buffered response bytes to the client.
+    fn write(&mut self) {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(len) => {
+                    self.outgoing.drain(..len);
+                },
+
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+
+                Err(e) => {
+                    debug!("downstream write failed: {:?}", e);
+
+                    self.outgoing.clear();
+                    self.abort();
+                },
+            }
+        }
+    }
+
+    /// Parses the next request, once its head was received entirely.
+    fn parse_request(&mut self, origin_url: &url::Url) {
+        let len = match find(&self.incoming, b"\r\n\r\n") {
+            Some(v) => v + 4,
+
+            None if self.incoming.len() > MAX_HEAD_LEN => return self.fail(431),
+
+            None => {
+                if self.client_closed {
+                    self.closing = true;
+                }
+
+                return;
+            },
+        };
+
+        let head: Vec<u8> = self.incoming.drain(..len).collect();
+
+        match parse_head(&head, origin_url) {
+            Ok((exchange, keep_alive)) => {
+                self.exchange = Some(exchange);
+                self.keep_alive = keep_alive;
+            },
+
+            Err(status) => self.fail(status),
+        }
+    }
+
+    /// Moves as much of the request body as possible from `incoming` to the
+    /// exchange's buffer, decoding it on the way.
+    fn decode_request_body(&mut self) {
+        let ex = match self.exchange.as_mut() {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        // Tunnelled bytes are only forwarded once the tunnel is established.
+        if ex.is_connect && ex.response_body.is_none() {
+            return;
+        }
+
+        while ex.body.len() < MAX_BUFFERED_LEN {
+            match ex.request_body {
+                RequestBody::Length(left) | RequestBody::ChunkData(left) => {
+                    if self.incoming.is_empty() {
+                        break;
+                    }
+
+                    let len = std::cmp::min(left, self.incoming.len() as u64);
+
+                    ex.body.extend(self.incoming.drain(..len as usize));
+
+                    ex.request_body = match (ex.request_body, left - len) {
+                        (RequestBody::Length(_), 0) => RequestBody::Done,
+
+                        (RequestBody::Length(_), left) =>
+                            RequestBody::Length(left),
+
+                        (_, 0) => RequestBody::ChunkEnd,
+
+                        (_, left) => RequestBody::ChunkData(left),
+                    };
+                },
+
+                RequestBody::ChunkSize => {
+                    let len = match find(&self.incoming, b"\r\n") {
+                        Some(v) => v,
+
+                        None => break,
+                    };
+
+                    let line: Vec<u8> = self.incoming.drain(..len + 2).collect();
+                    let line = String::from_utf8_lossy(&line[..len]);
+
+                    // Chunk extensions are ignored.
+                    let size = line.split(';').next().unwrap_or("").trim();
+
+                    ex.request_body = match u64::from_str_radix(size, 16) {
+                        Ok(0) => RequestBody::Trailers,
+
+                        Ok(v) => RequestBody::ChunkData(v),
+
+                        Err(_) => return self.fail(400),
+                    };
+                },
+
+                RequestBody::ChunkEnd => {
+                    if self.incoming.len() < 2 {
+                        break;
+                    }
+
+                    if !self.incoming.starts_with(b"\r\n") {
+                        return self.fail(400);
+                    }
+
+                    self.incoming.drain(..2);
+                    ex.request_body = RequestBody::ChunkSize;
+                },
+
+                // Request trailers are dropped.
+                RequestBody::Trailers => {
+                    let len = if self.incoming.starts_with(b"\r\n") {
+                        2
+                    } else {
+                        match find(&self.incoming, b"\r\n\r\n") {
+                            Some(v) => v + 4,
+
+                            None => break,
+                        }
+                    };
+
+                    self.incoming.drain(..len);
+                    ex.request_body = RequestBody::Done;
+                },
+
+                RequestBody::Tunnel => {
+                    let len =
+                        std::cmp::min(self.incoming.len(), MAX_BUFFERED_LEN);
+
+                    ex.body.extend(self.incoming.drain(..len));
+
+                    if self.client_closed && self.incoming.is_empty() {
+                        ex.request_body = RequestBody::Done;
+                    }
+
+                    break;
+                },
+
+                RequestBody::Done => break,
+            }
+        }
+
+        // The client went away before sending the whole request.
+        if self.client_closed &&
+            self.incoming.is_empty() &&
+            ex.request_body != RequestBody::Done
+        {
+            self.abort();
+        }
+    }
+
+    /// Forwards the current request to the origin, and relays the response
+    /// back.
+    fn relay(
+        &mut self, origin: &mut Origin, streams: &mut HashMap<u64, usize>,
+        token: usize, buf: &mut [u8],
+    ) {
+        let h3_conn = match origin.h3_conn.as_mut() {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let conn = &mut origin.conn;
+
+        let ex = match self.exchange.as_mut() {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        if let Some(headers) = &ex.headers {
+            let fin = ex.request_body == RequestBody::Done;
+
+            match h3_conn.send_request(conn, headers, fin) {
+                Ok(stream_id) => {
+                    ex.stream_id = Some(stream_id);
+                    ex.headers = None;
+                    ex.fin_sent = fin;
+
+                    streams.insert(stream_id, token);
+                },
+
+                Err(quiche::h3::Error::TransportError(
+                    quiche::Error::StreamLimit(_),
+                )) |
+                Err(quiche::h3::Error::StreamBlocked) => return,
+
+                Err(quiche::h3::Error::MessageError) => return self.fail(400),
+
+                Err(e) => {
+                    error!("failed to send request {:?}", e);
+
+                    return self.fail(502);
+                },
+            }
+        }
+
+        let stream_id = match ex.stream_id {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let fin = ex.request_body == RequestBody::Done;
+
+        if !ex.fin_sent && (fin || !ex.body.is_empty()) {
+            match h3_conn.send_body(conn, stream_id, &ex.body, fin) {
+                Ok(len) => {
+                    ex.body.drain(..len);
+                    ex.fin_sent = fin && ex.body.is_empty();
+                },
+
+                Err(quiche::h3::Error::Done) => (),
+
+                // The origin answered without reading the whole request, so
+                // the rest of the body is dropped.
+                Err(quiche::h3::Error::TransportError(
+                    quiche::Error::StreamStopped(_),
+                )) => {
+                    debug!("origin stopped reading request body");
+
+                    ex.body.clear();
+                    ex.fin_sent = true;
+                },
+
+                Err(e) => {
+                    error!("failed to send request body {:?}", e);
+
+                    return self.fail(502);
+                },
+            }
+        }
+
+        let response_body = match ex.response_body {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let mut drained = false;
+
+        while self.outgoing.len() < MAX_BUFFERED_LEN {
+            let len = match h3_conn.recv_body(conn, stream_id, buf) {
+                Ok(v) => v,
+
+                Err(_) => {
+                    drained = true;
+                    break;
+                },
+            };
+
+            match response_body {
+                ResponseBody::Chunked => {
+                    self.outgoing
+                        .extend_from_slice(format!("{:x}\r\n", len).as_bytes());
+                    self.outgoing.extend_from_slice(&buf[..len]);
+                    self.outgoing.extend_from_slice(b"\r\n");
+                },
+
+                ResponseBody::Raw => self.outgoing.extend_from_slice(&buf[..len]),
+
+                ResponseBody::Empty => (),
+            }
+        }
+
+        if !ex.origin_finished || !drained {
+            return;
+        }
+
+        if response_body == ResponseBody::Chunked {
+            self.outgoing.extend_from_slice(b"0\r\n");
+
+            for t in &ex.trailers {
+                self.outgoing.extend_from_slice(t.name());
+                self.outgoing.extend_from_slice(b": ");
+                self.outgoing.extend_from_slice(t.value());
+                self.outgoing.extend_from_slice(b"\r\n");
+            }
+
+            self.outgoing.extend_from_slice(b"\r\n");
+        }
+
+        // The rest of the request body is of no use anymore, and would have
+        // to be read before the connection can be reused.
+        if !ex.fin_sent {
+            conn.stream_shutdown(stream_id, quiche::Shutdown::Write, 0x100)
+                .ok();
+
+            self.closing = true;
+        }
+
+        if ex.is_connect || !self.keep_alive {
+            self.closing = true;
+        }
+
+        streams.remove(&stream_id);
+        self.exchange = None;
+    }
+
+    /// Handles the response headers received from the origin.
+    fn on_response_headers(&mut self, list: &[quiche::h3::Header]) {
+        let ex = match self.exchange.as_mut() {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        let status = list
+            .iter()
+            .find(|h| h.name() == b":status")
+            .and_then(|h| std::str::from_utf8(h.value()).ok())
+            .and_then(|v| v.parse::<u16>().ok())
+            .unwrap_or(0);
+
+        match status {
+            // Interim responses are dropped, a final one follows them.
+            100..=199 => return,
+
+            200..=599 => (),
+
+            _ => return self.fail(502),
+        }
+
+        if ex.is_connect && (200..300).contains(&status) {
+            self.outgoing.extend_from_slice(
+                b"HTTP/1.1 200 Connection Established\r\n\r\n",
+            );
+
+            ex.response_body = Some(ResponseBody::Raw);
+
+            return;
+        }
+
+        // The tunnel was refused, so nothing else may be sent on this
+        // connection.
+        if ex.is_connect {
+            ex.request_body = RequestBody::Done;
+            self.keep_alive = false;
+        }
+
+        let has_length = list.iter().any(|h| h.name() == b"content-length");
+
+        let response_body = if ex.is_head || status == 204 || status == 304 {
+            ResponseBody::Empty
+        } else if has_length {
+            ResponseBody::Raw
+        } else if self.keep_alive {
+            ResponseBody::Chunked
+        } else {
+            ResponseBody::Raw
+        };
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason(status));
+
+        for h in list {
+            if h.name().starts_with(b":") {
+                continue;
+            }
+
+            head.push_str(&String::from_utf8_lossy(h.name()));
+            head.push_str(": ");
+            head.push_str(&String::from_utf8_lossy(h.value()));
+            head.push_str("\r\n");
+        }
+
+        if response_body == ResponseBody::Chunked {
+            head.push_str("transfer-encoding: chunked\r\n");
+        }
+
+        if !self.keep_alive {
+            head.push_str("connection: close\r\n");
+        }
+
+        head.push_str("\r\n");
+
+        self.outgoing.extend_from_slice(head.as_bytes());
+
+        ex.response_body = Some(response_body);
+    }
+
+    /// Returns true once the connection can be dropped.
+    fn is_done(&self) -> bool {
+        let idle = self.client_closed &&
+            self.exchange.is_none() &&
+            self.incoming.is_empty();
+
+        (self.closing || idle) && self.outgoing.is_empty()
+    }
+}
+
+impl Origin {
+    fn connect(
+        poll: &mio::Poll, args: &ProxyArgs, config: &mut quiche::Config,
+    ) -> Result<Origin, ClientError> {
+        let peer_addr = match &args.connect_to {
+            Some(addr) => addr.parse().map_err(|e| {
+                ClientError::Other(format!("invalid address {}: {:?}", addr, e))
+            })?,
+
+            None => args
+                .origin
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut v| v.next())
+                .ok_or_else(|| {
+                    ClientError::Other(format!(
+                        "failed to resolve {}",
+                        args.origin
+                    ))
+                })?,
+        };
+
+        let (socket, conn) = open_connection(
+            poll,
+            ORIGIN_TOKEN,
+            peer_addr,
+            &args.origin,
+            config,
+            &BindOptions::default(),
+        )?;
+
+        info!("connecting to origin {} at {}", args.origin, peer_addr);
+
+        Ok(Origin {
+            socket,
+
+            conn,
+
+            h3_conn: None,
+        })
+    }
+}
+
+/// Runs the proxy, until a fatal error happens.
+pub fn run(args: ProxyArgs, conn_args: CommonArgs) -> Result<(), ClientError> {
+    let mut buf = [0; 65535];
+
+    let poll = mio::Poll::new().unwrap();
+    let mut events = mio::Events::with_capacity(1024);
+
+    let listen_addr = args.listen.parse().map_err(|e| {
+        ClientError::Other(format!("invalid address {}: {:?}", args.listen, e))
+    })?;
+
+    let listener = mio::net::TcpListener::bind(&listen_addr)
+        .map_err(|e| ClientError::Other(format!("bind() failed: {:?}", e)))?;
+
+    poll.register(
+        &listener,
+        LISTENER_TOKEN,
+        mio::Ready::readable(),
+        mio::PollOpt::edge(),
+    )
+    .unwrap();
+
+    info!(
+        "listening on {}, forwarding to {}",
+        listen_addr, args.origin
+    );
+
+    let mut config = make_config(args.version, args.no_verify, &conn_args);
+
+    let h3_config = quiche::h3::Config::new().unwrap();
+
+    let mut origin: Option<Origin> = None;
+
+    let mut downstreams = HashMap::new();
+
+    // The downstream connection of each request stream.
+    let mut streams: HashMap<u64, usize> = HashMap::new();
+
+    let mut next_token = FIRST_DOWNSTREAM_TOKEN;
+
+    loop {
+        let timeout = origin.as_ref().and_then(|o| o.conn.timeout());
+
+        poll.poll(&mut events, timeout).unwrap();
+
+        loop {
+            let (stream, from) = match listener.accept() {
+                Ok(v) => v,
+
+                Err(e) => {
+                    if e.kind() != std::io::ErrorKind::WouldBlock {
+                        error!("accept() failed: {:?}", e);
+                    }
+
+                    break;
+                },
+            };
+
+            debug!("accepted connection from {}", from);
+
+            stream.set_nodelay(true).ok();
+
+            poll.register(
+                &stream,
+                mio::Token(next_token),
+                mio::Ready::readable() | mio::Ready::writable(),
+                mio::PollOpt::edge(),
+            )
+            .unwrap();
+
+            downstreams.insert(next_token, Downstream::new(stream));
+
+            next_token += 1;
+        }
+
+        for d in downstreams.values_mut() {
+            d.read(&mut buf);
+
+            if d.exchange.is_none() && !d.closing {
+                d.parse_request(&args.origin);
+            }
+
+            d.decode_request_body();
+        }
+
+        // A connection is attempted again for the next request if this one
+        // fails.
+        if origin.is_none() && downstreams.values().any(|d| d.wants_origin()) {
+            match Origin::connect(&poll, &args, &mut config) {
+                Ok(v) => origin = Some(v),
+
+                Err(e) => {
+                    error!("failed to connect to origin: {:?}", e);
+
+                    for d in downstreams.values_mut() {
+                        if d.wants_origin() {
+                            d.fail(502);
+                            d.write();
+                        }
+                    }
+                },
+            }
+        }
+
+        // Set when the connection to the origin can't be used anymore, and
+        // is dropped without waiting for it to be closed.
+        let mut broken = false;
+
+        if let Some(o) = origin.as_mut() {
+            if let Err(e) = recv_packets(&o.socket, &mut o.conn, &mut buf) {
+                error!("failed to receive from origin: {:?}", e);
+
+                broken = true;
+            }
+
+            o.conn.on_timeout();
+
+            // The connection is closed if the origin doesn't speak HTTP/3,
+            // and the requests waiting for it fail once it is.
+            if o.conn.is_established() && o.h3_conn.is_none() && !broken {
+                let app_proto = o.conn.application_proto();
+                let app_proto = std::str::from_utf8(app_proto).unwrap_or("");
+
+                if !alpns::HTTP_3.contains(&app_proto) {
+                    error!("the origin must speak HTTP/3, got {:?}", app_proto);
+
+                    o.conn.close(false, 0x1, b"fail").ok();
+                } else {
+                    match quiche::h3::Connection::with_transport(
+                        &mut o.conn,
+                        &h3_config,
+                    ) {
+                        Ok(v) => o.h3_conn = Some(v),
+
+                        Err(e) => {
+                            error!("failed to create HTTP/3 connection: {:?}", e);
+
+                            o.conn.close(false, 0x1, b"fail").ok();
+                        },
+                    }
+                }
+            }
+
+            if let Some(h3_conn) = o.h3_conn.as_mut() {
+                poll_origin(h3_conn, &mut o.conn, &mut downstreams, &streams);
+            }
+        }
+
+        for (token, d) in downstreams.iter_mut() {
+            if let Some(o) = origin.as_mut() {
+                d.relay(o, &mut streams, *token, &mut buf);
+            }
+
+            d.write();
+
+            if d.is_done() {
+                d.abort();
+            }
+
+            if let Some(stream_id) = d.cancelled.take() {
+                streams.remove(&stream_id);
+
+                if let Some(o) = origin.as_mut() {
+                    cancel_stream(&mut o.conn, stream_id);
+                }
+            }
+        }
+
+        downstreams.retain(|_, d| !d.is_done());
+
+        if let Some(o) = origin.as_mut() {
+            if !broken {
+                if let Err(e) = send_packets(&o.socket, &mut o.conn) {
+                    error!("failed to send to origin: {:?}", e);
+
+                    broken = true;
+                }
+            }
+
+            if o.conn.is_closed() || broken {
+                info!("connection to origin closed, {:?}", o.conn.stats());
+
+                let established = o.h3_conn.is_some();
+
+                for d in downstreams.values_mut() {
+                    let in_flight = matches!(
+                        &d.exchange,
+                        Some(ex) if ex.stream_id.is_some()
+                    );
+
+                    // Requests waiting for the connection get another chance
+                    // with a new one, unless the origin couldn't be reached.
+                    if in_flight || !established && d.wants_origin() {
+                        d.fail(502);
+                        d.write();
+                    }
+                }
+
+                streams.clear();
+                origin = None;
+            }
+        }
+    }
+}
+
+/// Stops both directions of the stream of an aborted request.
+fn cancel_stream(conn: &mut quiche::Connection, stream_id: u64) {
+    conn.stream_shutdown(stream_id, quiche::Shutdown::Read, H3_REQUEST_CANCELLED)
+        .ok();
+
+    conn.stream_shutdown(
+        stream_id,
+        quiche::Shutdown::Write,
+        H3_REQUEST_CANCELLED,
+    )
+    .ok();
+}
+
+/// Processes the HTTP/3 events received from the origin.
+fn poll_origin(
+    h3_conn: &mut quiche::h3::Connection, conn: &mut quiche::Connection,
+    downstreams: &mut HashMap<usize, Downstream>, streams: &HashMap<u64, usize>,
+) {
+    loop {
+        let (stream_id, event) = match h3_conn.poll(conn) {
+            Ok(v) => v,
+
+            Err(quiche::h3::Error::Done) => break,
+
+            Err(e) => {
+                error!("HTTP/3 processing failed: {:?}", e);
+                break;
+            },
+        };
+
+        let d = match streams.get(&stream_id).and_then(|t| downstreams.get_mut(t))
+        {
+            Some(v) => v,
+
+            None => continue,
+        };
+
+        match event {
+            quiche::h3::Event::Headers { list, .. } =>
+                d.on_response_headers(&list),
+
+            quiche::h3::Event::Trailers { list } =>
+                if let Some(ex) = d.exchange.as_mut() {
+                    ex.trailers = list;
+                },
+
+            quiche::h3::Event::Finished =>
+                if let Some(ex) = d.exchange.as_mut() {
+                    ex.origin_finished = true;
+                },
+
+            quiche::h3::Event::Reset(e) => {
+                debug!("request on stream {} was reset: {}", stream_id, e);
+
+                d.fail(502);
+            },
+
+            // Response bodies are read as the clients can take them.
+            _ => (),
+        }
+    }
+}
+
+/// Parses the head of a request into the exchange that forwards it, and
+/// whether the connection should be kept alive after it. On failure, the
+/// status of the response to send instead is returned.
+fn parse_head(
+    head: &[u8], origin_url: &url::Url,
+) -> Result<(Exchange, bool), u16> {
+    let head = std::str::from_utf8(head).map_err(|_| 400_u16)?;
+    let mut lines = head.split("\r\n");
+
+    let request_line: Vec<&str> = lines.next().unwrap_or("").split(' ').collect();
+
+    let (method, target, version) = match request_line.as_slice() {
+        [m, t, v] => (*m, *t, *v),
+
+        _ => return Err(400),
+    };
+
+    let http_10 = match version {
+        "HTTP/1.1" => false,
+
+        "HTTP/1.0" => true,
+
+        _ => return Err(505),
+    };
+
+    let is_connect = method == "CONNECT";
+
+    let path = if is_connect {
+        None
+    } else if target.starts_with('/') || target == "*" {
+        Some(target.to_string())
+    } else {
+        // Absolute-form targets, as sent to proxies, only keep their path.
+        let url = url::Url::parse(target).map_err(|_| 400_u16)?;
+
+        match url.query() {
+            Some(q) => Some(format!("{}?{}", url.path(), q)),
+
+            None => Some(url.path().to_string()),
+        }
+    };
+
+    let mut fields = Vec::new();
+    let mut length = None;
+    let mut chunked = false;
+    let mut connection = String::new();
+
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+
+        let (name, value) = line.split_once(':').ok_or(400_u16)?;
+
+        let name = name.trim().to_ascii_lowercase();
+        let value = value.trim();
+
+        match name.as_str() {
+            "content-length" =>
+                length = Some(value.parse::<u64>().map_err(|_| 400_u16)?),
+
+            "transfer-encoding" =>
+                chunked = value.eq_ignore_ascii_case("chunked"),
+
+            "connection" => connection = value.to_ascii_lowercase(),
+
+            _ => (),
+        }
+
+        fields.push((name, value));
+    }
+
+    // Requests with both are a request smuggling vector.
+    if chunked && length.is_some() {
+        return Err(400);
+    }
+
+    let keep_alive = if http_10 {
+        connection.split(',').any(|v| v.trim() == "keep-alive")
+    } else {
+        !connection.split(',').any(|v| v.trim() == "close")
+    };
+
+    let authority = match origin_url.port() {
+        Some(port) => format!("{}:{}", origin_url.host_str().unwrap_or(""), port),
+
+        None => origin_url.host_str().unwrap_or("").to_string(),
+    };
+
+    let mut headers =
+        vec![quiche::h3::Header::new(b":method", method.as_bytes())];
+
+    match &path {
+        Some(path) => {
+            headers.push(quiche::h3::Header::new(b":scheme", b"https"));
+            headers.push(quiche::h3::Header::new(
+                b":authority",
+                authority.as_bytes(),
+            ));
+            headers.push(quiche::h3::Header::new(b":path", path.as_bytes()));
+        },
+
+        // CONNECT requests only carry the target's authority.
+        None => headers
+            .push(quiche::h3::Header::new(b":authority", target.as_bytes())),
+    }
+
+    for (name, value) in &fields {
+        // Fields named by the connection header are hop-by-hop as well.
+        if is_hop_by_hop(name) ||
+            name == "host" ||
+            name == "proxy-authorization" ||
+            connection.split(',').any(|v| v.trim() == name)
+        {
+            continue;
+        }
+
+        headers.push(quiche::h3::Header::new(name.as_bytes(), value.as_bytes()));
+    }
+
+    let request_body = match (is_connect, chunked, length) {
+        (true, ..) => RequestBody::Tunnel,
+
+        (false, true, _) => RequestBody::ChunkSize,
+
+        (false, false, Some(len)) if len > 0 => RequestBody::Length(len),
+
+        (false, false, _) => RequestBody::Done,
+    };
+
+    let exchange = Exchange {
+        headers: Some(headers),
+
+        stream_id: None,
+
+        is_connect,
+
+        is_head: method == "HEAD",
+
+        request_body,
+
+        body: Vec::new(),
+
+        fin_sent: false,
+
+        response_body: None,
+
+        trailers: Vec::new(),
+
+        origin_finished: false,
+    };
+
+    Ok((exchange, keep_alive))
+}
+
+/// Returns the reason phrase of common status codes.
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+
+        201 => "Created",
+
+        204 => "No Content",
+
+        206 => "Partial Content",
+
+        301 => "Moved Permanently",
+
+        302 => "Found",
+
+        304 => "Not Modified",
+
+        400 => "Bad Request",
+
+        403 => "Forbidden",
+
+        404 => "Not Found",
+
+        405 => "Method Not Allowed",
+
+        416 => "Range Not Satisfiable",
+
+        431 => "Request Header Fields Too Large",
+
+        500 => "Internal Server Error",
+
+        502 => "Bad Gateway",
+
+        503 => "Service Unavailable",
+
+        504 => "Gateway Timeout",
+
+        505 => "HTTP Version Not Supported",
+
+        _ => "",
+    }
+}
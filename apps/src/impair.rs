@@ -0,0 +1,328 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Network impairment emulation.
+//!
+//! An [`Impairment`] sits between the UDP socket and the connection, and
+//! drops, reorders and delays datagrams in both directions, to reproduce
+//! loss recovery issues without a dedicated network emulation setup. Each
+//! direction is impaired independently, so with `loss=1%` about 1% of the
+//! received datagrams, and 1% of the sent ones, are dropped.
+//!
+//! [`Impairment`]: struct.Impairment.html
+
+use std::io;
+
+use std::net::SocketAddr;
+
+use std::time::Duration;
+use std::time::Instant;
+
+use ring::rand::SecureRandom;
+
+/// How much longer than the others reordered datagrams are held back.
+const REORDER_DELAY: Duration = Duration::from_millis(10);
+
+/// The drop, reorder and delay settings of an impairment.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ImpairmentConfig {
+    /// The probability a datagram is dropped, between 0 and 1.
+    pub loss: f64,
+
+    /// The probability a datagram is held back so that later ones overtake
+    /// it, between 0 and 1.
+    pub reorder: f64,
+
+    /// How long every datagram is delayed.
+    pub delay: Duration,
+}
+
+impl ImpairmentConfig {
+    /// Parses the value of the `--impair` option, a comma-separated list of
+    /// `loss=PERCENT`, `reorder=PERCENT` and `delay=MILLIS` settings, such as
+    /// `loss=1%,reorder=0.5%,delay=20ms`.
+    pub fn parse(v: &str) -> ImpairmentConfig {
+        let mut config = ImpairmentConfig::default();
+
+        for setting in v.split(',').filter(|s| !s.is_empty()) {
+            let (name, value) = match setting.split_once('=') {
+                Some(v) => v,
+
+                None => panic!("invalid impairment {:?}", setting),
+            };
+
+            match name {
+                "loss" => config.loss = parse_percent(value),
+
+                "reorder" => config.reorder = parse_percent(value),
+
+                "delay" => {
+                    let v = value.strip_suffix("ms").unwrap_or(value);
+                    let v = v.parse::<u64>().unwrap_or_else(|_| {
+                        panic!("invalid impairment delay {:?}", value)
+                    });
+
+                    config.delay = Duration::from_millis(v);
+                },
+
+                _ => panic!("unknown impairment {:?}", name),
+            }
+        }
+
+        config
+    }
+
+    /// Returns true if datagrams are neither dropped, reordered nor delayed.
+    pub fn is_none(&self) -> bool {
+        *self == ImpairmentConfig::default()
+    }
+}
+
+fn parse_percent(v: &str) -> f64 {
+    let p = v.strip_suffix('%').unwrap_or(v).parse::<f64>();
+
+    match p {
+        Ok(p) if (0.0..=100.0).contains(&p) => p / 100.0,
+
+        _ => panic!("invalid impairment percentage {:?}", v),
+    }
+}
+
+// A datagram held back until `release`.
+struct Datagram {
+    release: Instant,
+
+    data: Vec<u8>,
+
+    addr: SocketAddr,
+}
+
+/// The datagrams held back in one direction, ordered by release time.
+#[derive(Default)]
+struct Queue {
+    datagrams: Vec<Datagram>,
+}
+
+impl Queue {
+    fn push(&mut self, data: &[u8], addr: SocketAddr, release: Instant) {
+        // Datagrams released at the same time keep their order.
+        let pos = self.datagrams.partition_point(|d| d.release <= release);
+
+        let datagram = Datagram {
+            release,
+
+            data: data.to_vec(),
+
+            addr,
+        };
+
+        self.datagrams.insert(pos, datagram);
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<Datagram> {
+        match self.datagrams.first() {
+            Some(d) if d.release <= now => Some(self.datagrams.remove(0)),
+
+            _ => None,
+        }
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        match self.datagrams.first() {
+            Some(d) => d.release <= now,
+
+            None => false,
+        }
+    }
+
+    fn next_release(&self) -> Option<Instant> {
+        self.datagrams.first().map(|d| d.release)
+    }
+}
+
+/// Impairs the datagrams received from, and sent to, a UDP socket.
+///
+/// Without impairments, datagrams go straight to the socket.
+pub struct Impairment {
+    config: ImpairmentConfig,
+
+    rx: Queue,
+
+    tx: Queue,
+
+    rng: ring::rand::SystemRandom,
+}
+
+impl Impairment {
+    /// Creates an impairment with the given settings.
+    pub fn new(config: ImpairmentConfig) -> Self {
+        Impairment {
+            config,
+
+            rx: Queue::default(),
+
+            tx: Queue::default(),
+
+            rng: ring::rand::SystemRandom::new(),
+        }
+    }
+
+    /// Returns true if datagrams go straight to the socket.
+    pub fn is_none(&self) -> bool {
+        self.config.is_none()
+    }
+
+    /// Receives a datagram from `socket`, returning `WouldBlock` until one is
+    /// released.
+    pub fn recv_from(
+        &mut self, socket: &mio::net::UdpSocket, buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        if self.config.is_none() {
+            return socket.recv_from(buf);
+        }
+
+        // Take everything that was received, so that it can be held back.
+        loop {
+            match socket.recv_from(buf) {
+                Ok((len, from)) =>
+                    if let Some(release) = self.release_time() {
+                        self.rx.push(&buf[..len], from, release);
+                    },
+
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+
+                Err(e) => return Err(e),
+            }
+        }
+
+        match self.rx.pop_due(Instant::now()) {
+            Some(d) => {
+                buf[..d.data.len()].copy_from_slice(&d.data);
+
+                Ok((d.data.len(), d.addr))
+            },
+
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    /// Sends a datagram to `target` on `socket`, once it is released.
+    ///
+    /// Datagrams that are dropped or held back are reported as sent.
+    pub fn send_to(
+        &mut self, socket: &mio::net::UdpSocket, buf: &[u8], target: &SocketAddr,
+    ) -> io::Result<usize> {
+        if self.config.is_none() {
+            return socket.send_to(buf, target);
+        }
+
+        if let Some(release) = self.release_time() {
+            self.tx.push(buf, *target, release);
+        }
+
+        self.flush(socket)?;
+
+        Ok(buf.len())
+    }
+
+    /// Sends the datagrams of `segment_size` bytes coalesced in `buf` to
+    /// `target` on `socket`, impairing each of them separately.
+    pub fn send_segments(
+        &mut self, socket: &mio::net::UdpSocket, buf: &[u8], target: &SocketAddr,
+        segment_size: usize,
+    ) -> io::Result<usize> {
+        let mut written = 0;
+
+        for datagram in buf.chunks(segment_size) {
+            written += self.send_to(socket, datagram, target)?;
+        }
+
+        Ok(written)
+    }
+
+    /// Sends the datagrams that were released on `socket`.
+    pub fn flush(&mut self, socket: &mio::net::UdpSocket) -> io::Result<()> {
+        let now = Instant::now();
+
+        while let Some(d) = self.tx.pop_due(now) {
+            if let Err(e) = socket.send_to(&d.data, &d.addr) {
+                // Try again later.
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.tx.datagrams.insert(0, d);
+                    break;
+                }
+
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if received datagrams were released.
+    pub fn has_rx_due(&self) -> bool {
+        self.rx.is_due(Instant::now())
+    }
+
+    /// Returns how long until the next datagram is released, in either
+    /// direction.
+    pub fn timeout(&self) -> Option<Duration> {
+        let next = match (self.rx.next_release(), self.tx.next_release()) {
+            (Some(rx), Some(tx)) => std::cmp::min(rx, tx),
+
+            (rx, tx) => rx.or(tx)?,
+        };
+
+        Some(next.saturating_duration_since(Instant::now()))
+    }
+
+    // Returns when a new datagram is to be released, or `None` if it's
+    // dropped.
+    fn release_time(&self) -> Option<Instant> {
+        if self.chance(self.config.loss) {
+            return None;
+        }
+
+        let mut release = Instant::now() + self.config.delay;
+
+        if self.chance(self.config.reorder) {
+            release += REORDER_DELAY;
+        }
+
+        Some(release)
+    }
+
+    fn chance(&self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+
+        let mut v = [0; 4];
+        self.rng.fill(&mut v).unwrap();
+
+        (u32::from_be_bytes(v) as f64) < p * u32::MAX as f64
+    }
+}
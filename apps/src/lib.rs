@@ -27,7 +27,21 @@
 #[macro_use]
 extern crate log;
 
+pub mod access_log;
 pub mod args;
+pub mod benchmark;
+pub mod bind;
+pub mod cache;
 pub mod client;
 pub mod common;
+pub mod forward;
+pub mod impair;
+pub mod limits;
+pub mod metrics;
+pub mod proxy;
+pub mod reload;
 pub mod sendto;
+pub mod session_store;
+pub mod throttle;
+pub mod timing;
+pub mod token;
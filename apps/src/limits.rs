@@ -0,0 +1,203 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Limits on the connections accepted by the server.
+//!
+//! The number of open connections can be capped, and new connections can be
+//! rate limited, both globally and per source IP address, using token buckets
+//! that allow bursts of up to one second worth of handshakes.
+//!
+//! When a new connection exceeds a rate limit and the client's address wasn't
+//! validated yet, the server should answer with a stateless retry, so that
+//! spoofed floods don't consume any state. Otherwise the connection should be
+//! refused.
+
+use std::collections::HashMap;
+
+use std::net::IpAddr;
+
+use std::time::Duration;
+use std::time::Instant;
+
+/// How often idle per-address buckets are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How a new connection should be handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    /// The connection can be accepted.
+    Accept,
+
+    /// The client's address should be validated with a stateless retry first.
+    Retry,
+
+    /// The connection should be closed with CONNECTION_REFUSED.
+    Refuse,
+}
+
+/// A token bucket refilled with `rate` tokens per second, holding at most
+/// `rate` tokens.
+struct Bucket {
+    tokens: f64,
+
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Bucket {
+            tokens: rate,
+
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        self.last_refill = now;
+    }
+}
+
+/// Connection limits of the server.
+#[derive(Default)]
+pub struct ConnectionLimits {
+    max_connections: Option<usize>,
+
+    handshake_rate: Option<f64>,
+    handshakes: Option<Bucket>,
+
+    per_ip_rate: Option<f64>,
+    per_ip: HashMap<IpAddr, Bucket>,
+
+    last_prune: Option<Instant>,
+}
+
+impl ConnectionLimits {
+    /// Creates limits allowing at most `max_connections` open connections,
+    /// `max_handshakes` new connections per second, and `max_per_ip` new
+    /// connections per second from a single IP address. `None` disables the
+    /// corresponding limit.
+    pub fn new(
+        max_connections: Option<usize>, max_handshakes: Option<u64>,
+        max_per_ip: Option<u64>,
+    ) -> Self {
+        ConnectionLimits {
+            max_connections,
+
+            handshake_rate: max_handshakes.map(|v| v as f64),
+
+            per_ip_rate: max_per_ip.map(|v| v as f64),
+
+            ..Default::default()
+        }
+    }
+
+    /// Decides how to handle a new connection from `ip`, given the number of
+    /// currently open connections, and whether the client's address was
+    /// already validated.
+    ///
+    /// The connection counts against the rate limits only when it's accepted.
+    pub fn admit(
+        &mut self, ip: IpAddr, connections: usize, validated: bool,
+    ) -> Admission {
+        if let Some(max) = self.max_connections {
+            if connections >= max {
+                return Admission::Refuse;
+            }
+        }
+
+        let now = Instant::now();
+
+        self.prune(now);
+
+        let mut allowed = true;
+
+        if let Some(rate) = self.handshake_rate {
+            let bucket = self
+                .handshakes
+                .get_or_insert_with(|| Bucket::new(rate, now));
+
+            bucket.refill(rate, now);
+
+            allowed &= bucket.tokens >= 1.0;
+        }
+
+        if let Some(rate) = self.per_ip_rate {
+            let bucket = self
+                .per_ip
+                .entry(ip)
+                .or_insert_with(|| Bucket::new(rate, now));
+
+            bucket.refill(rate, now);
+
+            allowed &= bucket.tokens >= 1.0;
+        }
+
+        if !allowed {
+            return if validated {
+                Admission::Refuse
+            } else {
+                Admission::Retry
+            };
+        }
+
+        if let Some(bucket) = &mut self.handshakes {
+            bucket.tokens -= 1.0;
+        }
+
+        if let Some(bucket) = self.per_ip.get_mut(&ip) {
+            bucket.tokens -= 1.0;
+        }
+
+        Admission::Accept
+    }
+
+    // Forgets the addresses whose buckets are full again, as they're no
+    // different from new ones.
+    fn prune(&mut self, now: Instant) {
+        let rate = match self.per_ip_rate {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        match self.last_prune {
+            Some(last)
+                if now.saturating_duration_since(last) < PRUNE_INTERVAL =>
+                return,
+
+            _ => self.last_prune = Some(now),
+        }
+
+        self.per_ip.retain(|_, bucket| {
+            bucket.refill(rate, now);
+
+            bucket.tokens < rate
+        });
+    }
+}
@@ -0,0 +1,339 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Aggregate server metrics, exposed in the Prometheus text format.
+//!
+//! The [`Metrics`] counters are updated by the server's event loop, and are
+//! served over plain TCP by a [`MetricsListener`], so that they can be
+//! scraped by Prometheus or any compatible collector.
+//!
+//! [`Metrics`]: struct.Metrics.html
+//! [`MetricsListener`]: struct.MetricsListener.html
+
+use std::collections::BTreeMap;
+
+use std::fmt::Write as _;
+
+use std::io::prelude::*;
+
+use crate::common::ClientMap;
+
+/// Counters accumulated over the lifetime of the server.
+#[derive(Default)]
+pub struct Metrics {
+    connections: u64,
+
+    retries: u64,
+
+    refused: u64,
+
+    handshakes: u64,
+
+    handshake_failures: BTreeMap<String, u64>,
+
+    h3_requests: BTreeMap<u16, u64>,
+
+    // Packet and byte counts of the connections that were already collected.
+    recv: u64,
+    sent: u64,
+    recv_bytes: u64,
+    sent_bytes: u64,
+}
+
+impl Metrics {
+    /// Records a newly accepted connection.
+    pub fn on_connection(&mut self) {
+        self.connections += 1;
+    }
+
+    /// Records a stateless retry sent to a client.
+    pub fn on_retry(&mut self) {
+        self.retries += 1;
+    }
+
+    /// Records a new connection refused because of the connection limits.
+    pub fn on_refused(&mut self) {
+        self.refused += 1;
+    }
+
+    /// Records the response sent to an HTTP/3 request.
+    pub fn on_h3_request(&mut self, status: u16) {
+        *self.h3_requests.entry(status).or_insert(0) += 1;
+    }
+
+    /// Records the statistics of a closed connection, before it's collected.
+    pub fn on_connection_closed(&mut self, conn: &quiche::Connection) {
+        let stats = conn.stats();
+
+        self.recv += stats.recv as u64;
+        self.sent += stats.sent as u64;
+        self.recv_bytes += stats.recv_bytes;
+        self.sent_bytes += stats.sent_bytes;
+
+        if conn.is_established() {
+            self.handshakes += 1;
+        } else {
+            let reason = handshake_failure_reason(conn);
+
+            *self.handshake_failures.entry(reason).or_insert(0) += 1;
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    ///
+    /// Connections that are still in `clients` are accounted for in addition
+    /// to the ones that were already collected.
+    pub fn render(&self, clients: &ClientMap) -> String {
+        let mut handshakes = self.handshakes;
+        let mut recv = self.recv;
+        let mut sent = self.sent;
+        let mut recv_bytes = self.recv_bytes;
+        let mut sent_bytes = self.sent_bytes;
+
+        for client in clients.values() {
+            let stats = client.conn.stats();
+
+            recv += stats.recv as u64;
+            sent += stats.sent as u64;
+            recv_bytes += stats.recv_bytes;
+            sent_bytes += stats.sent_bytes;
+
+            if client.conn.is_established() {
+                handshakes += 1;
+            }
+        }
+
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "quiche_server_connections_total",
+            "counter",
+            "Number of accepted connections.",
+            &[("", self.connections)],
+        );
+
+        write_metric(
+            &mut out,
+            "quiche_server_connections_active",
+            "gauge",
+            "Number of connections currently open.",
+            &[("", clients.len() as u64)],
+        );
+
+        write_metric(
+            &mut out,
+            "quiche_server_handshakes_total",
+            "counter",
+            "Number of completed handshakes.",
+            &[("", handshakes)],
+        );
+
+        let failures: Vec<(String, u64)> = self
+            .handshake_failures
+            .iter()
+            .map(|(reason, v)| (format!("reason=\"{}\"", reason), *v))
+            .collect();
+
+        write_metric(
+            &mut out,
+            "quiche_server_handshake_failures_total",
+            "counter",
+            "Number of connections closed before completing the handshake.",
+            &failures,
+        );
+
+        write_metric(
+            &mut out,
+            "quiche_server_retries_total",
+            "counter",
+            "Number of stateless retries sent.",
+            &[("", self.retries)],
+        );
+
+        write_metric(
+            &mut out,
+            "quiche_server_connections_refused_total",
+            "counter",
+            "Number of new connections refused because of connection limits.",
+            &[("", self.refused)],
+        );
+
+        write_metric(
+            &mut out,
+            "quiche_server_packets_total",
+            "counter",
+            "Number of QUIC packets received and sent.",
+            &[("direction=\"rx\"", recv), ("direction=\"tx\"", sent)],
+        );
+
+        write_metric(
+            &mut out,
+            "quiche_server_bytes_total",
+            "counter",
+            "Number of QUIC bytes received and sent.",
+            &[
+                ("direction=\"rx\"", recv_bytes),
+                ("direction=\"tx\"", sent_bytes),
+            ],
+        );
+
+        let requests: Vec<(String, u64)> = self
+            .h3_requests
+            .iter()
+            .map(|(status, v)| (format!("status=\"{}\"", status), *v))
+            .collect();
+
+        write_metric(
+            &mut out,
+            "quiche_server_h3_requests_total",
+            "counter",
+            "Number of HTTP/3 requests, by response status.",
+            &requests,
+        );
+
+        out
+    }
+}
+
+/// Writes a single metric family, with one sample per set of labels.
+fn write_metric<L: AsRef<str>>(
+    out: &mut String, name: &str, ty: &str, help: &str, samples: &[(L, u64)],
+) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, ty).unwrap();
+
+    for (labels, v) in samples {
+        let labels = labels.as_ref();
+
+        if labels.is_empty() {
+            writeln!(out, "{} {}", name, v).unwrap();
+        } else {
+            writeln!(out, "{}{{{}}} {}", name, labels, v).unwrap();
+        }
+    }
+}
+
+/// Returns a short description of why a connection failed to complete the
+/// handshake.
+fn handshake_failure_reason(conn: &quiche::Connection) -> String {
+    if conn.is_timed_out() {
+        return "timeout".to_string();
+    }
+
+    if let Some(e) = conn.local_error() {
+        return format!("local_0x{:x}", e.error_code);
+    }
+
+    if let Some(e) = conn.peer_error() {
+        return format!("peer_0x{:x}", e.error_code);
+    }
+
+    "unknown".to_string()
+}
+
+/// A plain TCP listener serving the rendered metrics to any client that
+/// connects to it.
+pub struct MetricsListener {
+    listener: mio::net::TcpListener,
+}
+
+impl MetricsListener {
+    /// Creates a listener bound to `addr`, and registers it with the event
+    /// loop using the given token.
+    pub fn bind(
+        addr: &str, poll: &mio::Poll, token: mio::Token,
+    ) -> std::io::Result<Self> {
+        let addr = addr.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid metrics address",
+            )
+        })?;
+
+        let listener = mio::net::TcpListener::bind(&addr)?;
+
+        poll.register(
+            &listener,
+            token,
+            mio::Ready::readable(),
+            mio::PollOpt::edge(),
+        )?;
+
+        Ok(MetricsListener { listener })
+    }
+
+    /// Accepts all pending connections, and responds to each of them with
+    /// the metrics returned by `render`.
+    ///
+    /// Responses are written with a short timeout, so that slow scrapers
+    /// don't stall the event loop for long.
+    pub fn serve<F: FnMut() -> String>(&self, mut render: F) {
+        loop {
+            let (mut stream, from) = match self.listener.accept_std() {
+                Ok(v) => v,
+
+                Err(e) => {
+                    if e.kind() != std::io::ErrorKind::WouldBlock {
+                        error!("metrics accept() failed: {:?}", e);
+                    }
+
+                    break;
+                },
+            };
+
+            trace!("metrics request from {}", from);
+
+            let timeout = Some(std::time::Duration::from_millis(100));
+
+            stream.set_nonblocking(false).ok();
+            stream.set_read_timeout(timeout).ok();
+            stream.set_write_timeout(timeout).ok();
+
+            // The request itself is ignored, but it needs to be read so the
+            // connection isn't reset while the response is sent.
+            let mut req = [0; 1024];
+            if let Ok(len) = stream.read(&mut req) {
+                trace!("metrics request of {} bytes", len);
+            }
+
+            let body = render();
+
+            let rsp = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+
+            if let Err(e) = stream.write_all(rsp.as_bytes()) {
+                error!("metrics write() failed: {:?}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,871 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Reverse proxying of HTTP/3 requests to an HTTP/1.1 upstream.
+//!
+//! Each request is forwarded over its own TCP connection, which is closed
+//! once the response is complete. Like the sockets of proxied CONNECT-UDP
+//! flows, upstream connections are registered with the event loop, which
+//! calls [`ProxiedRequest::relay()`] when they become ready.
+//!
+//! [`ProxiedRequest::relay()`]: struct.ProxiedRequest.html#method.relay
+
+use std::io::prelude::*;
+
+use std::net::SocketAddr;
+
+use std::net::ToSocketAddrs;
+
+use quiche::h3::NameValue;
+
+/// How long an upstream can go without making progress before the request
+/// fails with a 504 response.
+const UPSTREAM_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// The largest response head or trailer section accepted from the upstream.
+const MAX_HEAD_LEN: usize = 65536;
+
+/// How much of the request body is buffered before reading from the client
+/// is paused.
+const MAX_OUTGOING_LEN: usize = 1 << 20;
+
+/// Headers that only apply to a single HTTP/1.1 connection, which must not
+/// be forwarded in either direction.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// H3_INTERNAL_ERROR, used to reset streams whose response can't be
+/// completed.
+const H3_INTERNAL_ERROR: u64 = 0x102;
+
+/// A reason for failing a proxied request.
+#[derive(Debug)]
+enum Failure {
+    /// The upstream couldn't be reached, or didn't send a valid response.
+    BadGateway(String),
+
+    /// The upstream stopped making progress.
+    Timeout,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Failure::BadGateway(reason) => write!(f, "{}", reason),
+
+            Failure::Timeout => write!(f, "upstream timed out"),
+        }
+    }
+}
+
+/// Where parsing of the upstream response is at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ResponseState {
+    Head,
+
+    /// A body with a known length, holding the number of bytes left.
+    Body(u64),
+
+    ChunkSize,
+
+    /// A chunk of a chunked body, holding the number of bytes left.
+    ChunkData(u64),
+
+    /// The line break that ends each chunk.
+    ChunkEnd,
+
+    Trailers,
+
+    /// A body delimited by the upstream closing the connection.
+    UntilClose,
+
+    Done,
+}
+
+/// The HTTP/1.1 server requests are forwarded to.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    url: url::Url,
+
+    addr: SocketAddr,
+}
+
+impl Upstream {
+    /// Resolves the address of the upstream.
+    ///
+    /// This blocks, so it is meant to be called once on startup rather than
+    /// from the event loop. The resolved address is used for the lifetime of
+    /// the server.
+    pub fn resolve(url: url::Url) -> std::io::Result<Upstream> {
+        let host = url.host_str().unwrap_or("");
+        let port = url.port_or_known_default().unwrap_or(80);
+
+        let addr = (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no address found")
+        })?;
+
+        Ok(Upstream { url, addr })
+    }
+}
+
+/// An HTTP/3 request being forwarded to the upstream.
+pub struct ProxiedRequest {
+    stream: mio::net::TcpStream,
+
+    /// Whether the upstream connection was registered with the event loop
+    /// yet.
+    registered: bool,
+
+    /// Bytes of the request still to be written to the upstream.
+    outgoing: Vec<u8>,
+
+    /// Whether the request body is sent using the chunked transfer coding.
+    chunked: bool,
+
+    request_finished: bool,
+
+    head_request: bool,
+
+    /// Bytes read from the upstream and not parsed yet.
+    incoming: Vec<u8>,
+
+    upstream_closed: bool,
+
+    state: ResponseState,
+
+    /// The response headers, once parsed and until they are sent.
+    headers: Option<Vec<quiche::h3::Header>>,
+
+    response_started: bool,
+
+    status: Option<u16>,
+
+    body: Vec<u8>,
+
+    written: usize,
+
+    /// The trailers of a complete response, until the stream is finished.
+    end: Option<Vec<quiche::h3::Header>>,
+
+    last_progress: std::time::Instant,
+}
+
+impl ProxiedRequest {
+    /// Starts forwarding an HTTP/3 request to `upstream`.
+    ///
+    /// On failure, the status of the response to send to the client is
+    /// returned instead.
+    pub fn new(
+        upstream: &Upstream, request: &[quiche::h3::Header], has_body: bool,
+    ) -> Result<ProxiedRequest, u16> {
+        let mut method = None;
+        let mut path = None;
+        let mut authority = None;
+        let mut has_length = false;
+
+        // Line breaks would allow smuggling extra requests to the upstream.
+        if request
+            .iter()
+            .any(|h| h.value().iter().any(|&b| b == b'\r' || b == b'\n'))
+        {
+            return Err(400);
+        }
+
+        for hdr in request {
+            match hdr.name() {
+                b":method" => method = std::str::from_utf8(hdr.value()).ok(),
+
+                b":path" => path = std::str::from_utf8(hdr.value()).ok(),
+
+                b":authority" =>
+                    authority = std::str::from_utf8(hdr.value()).ok(),
+
+                b"content-length" => has_length = true,
+
+                _ => (),
+            }
+        }
+
+        let (method, path) = match (method, path) {
+            (Some("CONNECT"), _) => return Err(405),
+
+            (Some(method), Some(path))
+                if !method.contains(' ') && !path.contains(' ') =>
+                (method, path),
+
+            _ => return Err(400),
+        };
+
+        let host = upstream.url.host_str().unwrap_or("");
+        let port = upstream.url.port_or_known_default().unwrap_or(80);
+
+        let prefix = upstream.url.path().trim_end_matches('/');
+        let target = format!("{}{}", prefix, path);
+
+        let mut head = format!("{} {} HTTP/1.1\r\n", method, target);
+        head.push_str(&format!("host: {}:{}\r\n", host, port));
+        head.push_str("connection: close\r\n");
+        head.push_str("x-forwarded-proto: https\r\n");
+
+        if let Some(authority) = authority {
+            head.push_str(&format!("x-forwarded-host: {}\r\n", authority));
+        }
+
+        for hdr in request {
+            let name = match std::str::from_utf8(hdr.name()) {
+                Ok(v) if !v.starts_with(':') && !is_hop_by_hop(v) => v,
+
+                _ => continue,
+            };
+
+            let value = match std::str::from_utf8(hdr.value()) {
+                Ok(v) => v,
+
+                Err(_) => return Err(400),
+            };
+
+            if name == "host" {
+                continue;
+            }
+
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        let chunked = has_body && !has_length;
+
+        if chunked {
+            head.push_str("transfer-encoding: chunked\r\n");
+        }
+
+        head.push_str("\r\n");
+
+        let stream = match connect(&upstream.addr) {
+            Ok(v) => v,
+
+            Err(e) => {
+                error!("failed to connect to {}: {:?}", upstream.addr, e);
+
+                return Err(502);
+            },
+        };
+
+        let mut req = ProxiedRequest {
+            stream,
+
+            registered: false,
+
+            outgoing: head.into_bytes(),
+
+            chunked,
+
+            request_finished: false,
+
+            head_request: method == "HEAD",
+
+            incoming: Vec::new(),
+
+            upstream_closed: false,
+
+            state: ResponseState::Head,
+
+            headers: None,
+
+            response_started: false,
+
+            status: None,
+
+            body: Vec::new(),
+
+            written: 0,
+
+            end: None,
+
+            last_progress: std::time::Instant::now(),
+        };
+
+        if !has_body {
+            req.finish_request();
+        }
+
+        Ok(req)
+    }
+
+    /// Reads as much of the request body from the client as can be
+    /// buffered.
+    pub fn recv_request_body(
+        &mut self, h3_conn: &mut quiche::h3::Connection,
+        conn: &mut quiche::Connection, stream_id: u64, buf: &mut [u8],
+    ) {
+        while !self.request_finished && self.outgoing.len() < MAX_OUTGOING_LEN {
+            let len = match h3_conn.recv_body(conn, stream_id, buf) {
+                Ok(v) => v,
+
+                Err(_) => break,
+            };
+
+            if self.chunked {
+                self.outgoing
+                    .extend_from_slice(format!("{:x}\r\n", len).as_bytes());
+                self.outgoing.extend_from_slice(&buf[..len]);
+                self.outgoing.extend_from_slice(b"\r\n");
+            } else {
+                self.outgoing.extend_from_slice(&buf[..len]);
+            }
+        }
+    }
+
+    /// Marks the request as complete, once the client finished sending its
+    /// body.
+    pub fn finish_request(&mut self) {
+        if self.request_finished {
+            return;
+        }
+
+        if self.chunked {
+            self.outgoing.extend_from_slice(b"0\r\n\r\n");
+        }
+
+        self.request_finished = true;
+    }
+
+    /// Returns the status of the response sent to the client, if it was sent
+    /// since the last call.
+    pub fn take_status(&mut self) -> Option<u16> {
+        self.status.take()
+    }
+
+    /// Registers the upstream connection with the event loop, unless it
+    /// already was.
+    pub fn register(
+        &mut self, poll: &mio::Poll, token: mio::Token,
+    ) -> std::io::Result<()> {
+        if self.registered {
+            return Ok(());
+        }
+
+        // The connection is registered with edge-triggered readiness, so it
+        // is read and written until it would block.
+        poll.register(
+            &self.stream,
+            token,
+            mio::Ready::readable() | mio::Ready::writable(),
+            mio::PollOpt::edge(),
+        )?;
+
+        self.registered = true;
+
+        Ok(())
+    }
+
+    /// Returns how long until the upstream times out, if the response is
+    /// waiting for more data from it.
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        let waiting = self.headers.is_none() &&
+            self.written >= self.body.len() &&
+            self.end.is_none() &&
+            self.state != ResponseState::Done;
+
+        if !waiting {
+            return None;
+        }
+
+        let elapsed = self.last_progress.elapsed();
+
+        Some(UPSTREAM_TIMEOUT.checked_sub(elapsed).unwrap_or_default())
+    }
+
+    /// Exchanges data with the upstream, and relays as much of its response
+    /// as the stream can take. Returns true once the request is done with,
+    /// whether it succeeded or not.
+    pub fn relay(
+        &mut self, h3_conn: &mut quiche::h3::Connection,
+        conn: &mut quiche::Connection, stream_id: u64, buf: &mut [u8],
+    ) -> bool {
+        self.recv_request_body(h3_conn, conn, stream_id, buf);
+
+        if let Err(e) = self.flush_request() {
+            return self.fail(h3_conn, conn, stream_id, e);
+        }
+
+        loop {
+            match self.send_response(h3_conn, conn, stream_id) {
+                Ok(true) => return true,
+
+                Ok(false) => (),
+
+                Err(quiche::h3::Error::Done) |
+                Err(quiche::h3::Error::StreamBlocked) => return false,
+
+                Err(e) => {
+                    error!("{} stream send failed {:?}", conn.trace_id(), e);
+
+                    return true;
+                },
+            }
+
+            match self.read_response(buf) {
+                Ok(true) => (),
+
+                Ok(false) => return false,
+
+                Err(e) => return self.fail(h3_conn, conn, stream_id, e),
+            }
+        }
+    }
+
+    /// Writes the buffered request bytes to the upstream.
+    fn flush_request(&mut self) -> Result<(), Failure> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(len) => {
+                    self.outgoing.drain(..len);
+                    self.last_progress = std::time::Instant::now();
+                },
+
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+
+                Err(e) => return Err(Failure::BadGateway(e.to_string())),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends any pending part of the response to the client. Returns true
+    /// once the whole response was sent.
+    fn send_response(
+        &mut self, h3_conn: &mut quiche::h3::Connection,
+        conn: &mut quiche::Connection, stream_id: u64,
+    ) -> quiche::h3::Result<bool> {
+        if let Some(headers) = &self.headers {
+            h3_conn.send_response(conn, stream_id, headers, false)?;
+
+            self.status = parse_status(headers);
+            self.headers = None;
+            self.response_started = true;
+        }
+
+        while self.written < self.body.len() {
+            let body = &self.body[self.written..];
+
+            self.written += h3_conn.send_body(conn, stream_id, body, false)?;
+        }
+
+        match self.end.take() {
+            Some(trailers) if trailers.is_empty() =>
+                match h3_conn.send_body(conn, stream_id, b"", true) {
+                    Ok(_) => Ok(true),
+
+                    Err(e) => {
+                        self.end = Some(trailers);
+                        Err(e)
+                    },
+                },
+
+            Some(trailers) =>
+                match h3_conn.send_trailers(conn, stream_id, &trailers) {
+                    Ok(_) => Ok(true),
+
+                    Err(e) => {
+                        self.end = Some(trailers);
+                        Err(e)
+                    },
+                },
+
+            None => Ok(false),
+        }
+    }
+
+    /// Makes progress on the upstream response. Returns false if there was
+    /// nothing to do until the upstream sends more data.
+    fn read_response(&mut self, buf: &mut [u8]) -> Result<bool, Failure> {
+        if self.parse_response()? {
+            return Ok(true);
+        }
+
+        if self.state == ResponseState::Done {
+            return Ok(false);
+        }
+
+        if self.upstream_closed {
+            if self.state != ResponseState::UntilClose {
+                return Err(Failure::BadGateway(
+                    "upstream closed the connection early".to_string(),
+                ));
+            }
+
+            self.end = Some(Vec::new());
+            self.state = ResponseState::Done;
+
+            return Ok(true);
+        }
+
+        match self.stream.read(buf) {
+            Ok(0) => self.upstream_closed = true,
+
+            Ok(len) => self.incoming.extend_from_slice(&buf[..len]),
+
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                if self.last_progress.elapsed() > UPSTREAM_TIMEOUT {
+                    return Err(Failure::Timeout);
+                }
+
+                return Ok(false);
+            },
+
+            Err(e) => return Err(Failure::BadGateway(e.to_string())),
+        }
+
+        self.last_progress = std::time::Instant::now();
+
+        Ok(true)
+    }
+
+    /// Parses as much of the buffered upstream response as possible, as long
+    /// as the previously parsed body was sent. Returns whether anything was
+    /// parsed.
+    fn parse_response(&mut self) -> Result<bool, Failure> {
+        if self.written < self.body.len() || self.end.is_some() {
+            return Ok(false);
+        }
+
+        match self.state {
+            ResponseState::Head => {
+                let len = match find(&self.incoming, b"\r\n\r\n") {
+                    Some(v) => v + 4,
+
+                    None if self.incoming.len() > MAX_HEAD_LEN =>
+                        return Err(Failure::BadGateway(
+                            "response head too large".to_string(),
+                        )),
+
+                    None => return Ok(false),
+                };
+
+                let head: Vec<u8> = self.incoming.drain(..len).collect();
+
+                self.parse_head(&head)?;
+            },
+
+            ResponseState::Body(left) | ResponseState::ChunkData(left) => {
+                if self.incoming.is_empty() {
+                    return Ok(false);
+                }
+
+                let len = std::cmp::min(left, self.incoming.len() as u64);
+
+                self.body = self.incoming.drain(..len as usize).collect();
+                self.written = 0;
+
+                self.state = match (self.state, left - len) {
+                    (ResponseState::Body(_), 0) => {
+                        self.end = Some(Vec::new());
+                        ResponseState::Done
+                    },
+
+                    (ResponseState::Body(_), left) => ResponseState::Body(left),
+
+                    (_, 0) => ResponseState::ChunkEnd,
+
+                    (_, left) => ResponseState::ChunkData(left),
+                };
+            },
+
+            ResponseState::ChunkSize => {
+                let len = match find(&self.incoming, b"\r\n") {
+                    Some(v) => v,
+
+                    None => return Ok(false),
+                };
+
+                let line: Vec<u8> = self.incoming.drain(..len + 2).collect();
+                let line = String::from_utf8_lossy(&line[..len]);
+
+                // Chunk extensions are ignored.
+                let size = line.split(';').next().unwrap_or("").trim();
+
+                self.state = match u64::from_str_radix(size, 16) {
+                    Ok(0) => ResponseState::Trailers,
+
+                    Ok(v) => ResponseState::ChunkData(v),
+
+                    Err(_) =>
+                        return Err(Failure::BadGateway(format!(
+                            "invalid chunk size {:?}",
+                            size
+                        ))),
+                };
+            },
+
+            ResponseState::ChunkEnd => {
+                if self.incoming.len() < 2 {
+                    return Ok(false);
+                }
+
+                if !self.incoming.starts_with(b"\r\n") {
+                    return Err(Failure::BadGateway(
+                        "invalid chunk terminator".to_string(),
+                    ));
+                }
+
+                self.incoming.drain(..2);
+                self.state = ResponseState::ChunkSize;
+            },
+
+            ResponseState::Trailers => {
+                let len = if self.incoming.starts_with(b"\r\n") {
+                    2
+                } else {
+                    match find(&self.incoming, b"\r\n\r\n") {
+                        Some(v) => v + 4,
+
+                        None if self.incoming.len() > MAX_HEAD_LEN =>
+                            return Err(Failure::BadGateway(
+                                "trailer section too large".to_string(),
+                            )),
+
+                        None => return Ok(false),
+                    }
+                };
+
+                let trailers: Vec<u8> = self.incoming.drain(..len).collect();
+                let trailers = String::from_utf8_lossy(&trailers);
+
+                self.end = Some(parse_fields(trailers.lines())?);
+                self.state = ResponseState::Done;
+            },
+
+            ResponseState::UntilClose => {
+                if self.incoming.is_empty() {
+                    return Ok(false);
+                }
+
+                self.body = std::mem::take(&mut self.incoming);
+                self.written = 0;
+            },
+
+            ResponseState::Done => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    /// Parses the status line and headers of the upstream response.
+    fn parse_head(&mut self, head: &[u8]) -> Result<(), Failure> {
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.lines();
+
+        let status_line = lines.next().unwrap_or("");
+        let mut parts = status_line.splitn(3, ' ');
+
+        let status = match (parts.next(), parts.next()) {
+            (Some(version), Some(status)) if version.starts_with("HTTP/1.") =>
+                status.parse::<u16>().ok(),
+
+            _ => None,
+        };
+
+        let status = match status {
+            Some(v) if (200..600).contains(&v) => v,
+
+            // Interim responses are dropped, a final one follows them.
+            Some(v) if (100..200).contains(&v) && v != 101 => return Ok(()),
+
+            _ =>
+                return Err(Failure::BadGateway(format!(
+                    "invalid status line {:?}",
+                    status_line
+                ))),
+        };
+
+        let mut chunked = false;
+        let mut length = None;
+
+        for line in head.lines().skip(1) {
+            if let Some((name, value)) = line.split_once(':') {
+                let name = name.trim().to_ascii_lowercase();
+
+                if name == "transfer-encoding" {
+                    chunked = value.to_ascii_lowercase().contains("chunked");
+                }
+
+                if name == "content-length" {
+                    length = value.trim().parse::<u64>().ok();
+                }
+            }
+        }
+
+        let mut headers = vec![quiche::h3::Header::new(
+            b":status",
+            status.to_string().as_bytes(),
+        )];
+
+        for hdr in parse_fields(lines)? {
+            // The length of a chunked body isn't known up front.
+            if chunked && hdr.name() == b"content-length" {
+                continue;
+            }
+
+            headers.push(hdr);
+        }
+
+        let no_body = self.head_request || status == 204 || status == 304;
+
+        self.state = match (no_body, chunked, length) {
+            (true, ..) | (false, false, Some(0)) => {
+                self.end = Some(Vec::new());
+                ResponseState::Done
+            },
+
+            (false, true, _) => ResponseState::ChunkSize,
+
+            (false, false, Some(v)) => ResponseState::Body(v),
+
+            (false, false, None) => ResponseState::UntilClose,
+        };
+
+        self.headers = Some(headers);
+
+        Ok(())
+    }
+
+    /// Responds to the client with an error, or resets the stream if the
+    /// response was already started. Always returns true.
+    fn fail(
+        &mut self, h3_conn: &mut quiche::h3::Connection,
+        conn: &mut quiche::Connection, stream_id: u64, failure: Failure,
+    ) -> bool {
+        error!(
+            "{} proxied request on stream {} failed: {}",
+            conn.trace_id(),
+            stream_id,
+            failure
+        );
+
+        if !self.response_started {
+            let status = match failure {
+                Failure::BadGateway(_) => 502,
+
+                Failure::Timeout => 504,
+            };
+
+            let headers = vec![
+                quiche::h3::Header::new(
+                    b":status",
+                    status.to_string().as_bytes(),
+                ),
+                quiche::h3::Header::new(b"server", b"quiche"),
+                quiche::h3::Header::new(b"content-length", b"0"),
+            ];
+
+            let res = h3_conn.send_response(conn, stream_id, &headers, true);
+
+            if res.is_ok() {
+                self.status = Some(status);
+
+                return true;
+            }
+        }
+
+        conn.stream_shutdown(
+            stream_id,
+            quiche::Shutdown::Write,
+            H3_INTERNAL_ERROR,
+        )
+        .ok();
+
+        true
+    }
+}
+
+/// Opens a non-blocking TCP connection towards the upstream.
+fn connect(addr: &SocketAddr) -> std::io::Result<mio::net::TcpStream> {
+    let stream = mio::net::TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+
+    Ok(stream)
+}
+
+/// Converts HTTP/1.1 header lines into HTTP/3 fields, dropping the ones that
+/// can't be forwarded.
+fn parse_fields<'a, I: Iterator<Item = &'a str>>(
+    lines: I,
+) -> Result<Vec<quiche::h3::Header>, Failure> {
+    let mut fields = Vec::new();
+
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+
+        let (name, value) = match line.split_once(':') {
+            Some(v) => v,
+
+            None =>
+                return Err(Failure::BadGateway(format!(
+                    "invalid header line {:?}",
+                    line
+                ))),
+        };
+
+        let name = name.trim().to_ascii_lowercase();
+
+        if is_hop_by_hop(&name) {
+            continue;
+        }
+
+        fields.push(quiche::h3::Header::new(
+            name.as_bytes(),
+            value.trim().as_bytes(),
+        ));
+    }
+
+    Ok(fields)
+}
+
+pub(crate) fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+fn parse_status(headers: &[quiche::h3::Header]) -> Option<u16> {
+    headers
+        .iter()
+        .find(|h| h.name() == b":status")
+        .and_then(|h| std::str::from_utf8(h.value()).ok())
+        .and_then(|v| v.parse().ok())
+}
+
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
@@ -0,0 +1,138 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Reloading of the server's TLS certificate chain and private key.
+//!
+//! A reload is triggered when the process receives `SIGHUP`, or when either
+//! file's modification time changes. The new certificate and key only apply
+//! to connections accepted after the reload, existing connections keep
+//! using the ones they were created with.
+
+use std::sync::atomic;
+
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+/// How often to check the files' modification time.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+static RELOAD_REQUESTED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+// The ABI is spelled out to avoid the `missing_abi` lint, which rustfmt would
+// otherwise undo.
+#[cfg(unix)]
+#[rustfmt::skip]
+extern "C" fn on_sighup(_: nix::libc::c_int) {
+    RELOAD_REQUESTED.store(true, atomic::Ordering::Relaxed);
+}
+
+/// Watches the certificate chain and private key files for changes.
+pub struct CertReloader {
+    cert: String,
+    key: String,
+    mtimes: (Option<SystemTime>, Option<SystemTime>),
+    last_check: Instant,
+}
+
+impl CertReloader {
+    /// Creates a reloader for the given files, and installs the `SIGHUP`
+    /// handler.
+    pub fn new(cert: &str, key: &str) -> Self {
+        #[cfg(unix)]
+        {
+            use nix::sys::signal;
+
+            let action = signal::SigAction::new(
+                signal::SigHandler::Handler(on_sighup),
+                signal::SaFlags::SA_RESTART,
+                signal::SigSet::empty(),
+            );
+
+            if let Err(e) =
+                unsafe { signal::sigaction(signal::Signal::SIGHUP, &action) }
+            {
+                error!("failed to install SIGHUP handler: {:?}", e);
+            }
+        }
+
+        CertReloader {
+            cert: cert.to_string(),
+            key: key.to_string(),
+            mtimes: (mtime(cert), mtime(key)),
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Loads the certificate chain and private key into `config` again, if a
+    /// reload was requested or the files changed since they were last loaded.
+    ///
+    /// Returns whether `config` was updated. If the new files can't be
+    /// loaded, the previous certificate and key are kept.
+    pub fn maybe_reload(&mut self, config: &mut quiche::Config) -> bool {
+        let mut reload = RELOAD_REQUESTED.swap(false, atomic::Ordering::Relaxed);
+
+        if self.last_check.elapsed() >= CHECK_INTERVAL {
+            self.last_check = Instant::now();
+
+            let mtimes = (mtime(&self.cert), mtime(&self.key));
+
+            if mtimes != self.mtimes {
+                self.mtimes = mtimes;
+
+                reload = true;
+            }
+        }
+
+        if !reload {
+            return false;
+        }
+
+        // Make sure the new files are valid and match each other, before
+        // touching the configuration used for new connections.
+        let res = quiche::Config::new(quiche::PROTOCOL_VERSION)
+            .and_then(|mut scratch| self.load(&mut scratch))
+            .and_then(|_| self.load(config));
+
+        if let Err(e) = res {
+            error!("failed to reload certificate: {:?}", e);
+            return false;
+        }
+
+        info!("reloaded certificate {} and key {}", self.cert, self.key);
+
+        true
+    }
+
+    fn load(&self, config: &mut quiche::Config) -> quiche::Result<()> {
+        config.load_cert_chain_from_pem_file(&self.cert)?;
+        config.load_priv_key_from_pem_file(&self.key)
+    }
+}
+
+fn mtime(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
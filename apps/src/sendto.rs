@@ -30,6 +30,8 @@ use std::io;
 
 use std::net;
 
+use std::sync::atomic;
+
 /// For Linux, try to detect GSO is available.
 #[cfg(target_os = "linux")]
 pub fn detect_gso(socket: &mio::net::UdpSocket, segment_size: usize) -> bool {
@@ -46,6 +48,103 @@ pub fn detect_gso(_socket: &mio::net::UdpSocket, _segment_size: usize) -> bool {
     false
 }
 
+/// Marks the packets sent on `socket` with the given DSCP value.
+#[cfg(unix)]
+pub fn set_dscp(socket: &mio::net::UdpSocket, dscp: u8) -> io::Result<()> {
+    use nix::libc;
+    use std::os::unix::io::AsRawFd;
+
+    // The DSCP is the upper six bits of the IPv4 TOS and IPv6 Traffic Class
+    // fields, the lower two being used for ECN.
+    let tos = libc::c_int::from(dscp) << 2;
+
+    let (level, name) = if socket.local_addr()?.is_ipv6() {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    };
+
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &tos as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// For non-Unix, packets can't be marked.
+#[cfg(not(unix))]
+pub fn set_dscp(_socket: &mio::net::UdpSocket, _dscp: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DSCP marking is only supported on Unix",
+    ))
+}
+
+/// Keeps track of the DSCP value set on a socket, so that the socket option
+/// is only changed when packets need a different marking than the previous
+/// ones.
+pub struct DscpMarker {
+    current: u8,
+}
+
+impl DscpMarker {
+    /// Creates a marker for a socket whose packets are marked with `dscp`.
+    pub fn new(dscp: u8) -> Self {
+        DscpMarker { current: dscp }
+    }
+
+    /// Marks the packets sent next on `socket` with the given DSCP value.
+    pub fn mark(
+        &mut self, socket: &mio::net::UdpSocket, dscp: u8,
+    ) -> io::Result<()> {
+        if dscp == self.current {
+            return Ok(());
+        }
+
+        // Don't try again for every packet if the value can't be set.
+        self.current = dscp;
+
+        set_dscp(socket, dscp)
+    }
+}
+
+/// Set once a GSO send failed at runtime, as it will likely fail again.
+static GSO_FAILED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+/// The largest number of segments the kernel accepts in a GSO buffer.
+const MAX_GSO_SEGMENTS: usize = 64;
+
+/// The largest GSO buffer, as it's sent as a single UDP datagram.
+const MAX_GSO_BUF_SIZE: usize = 65507;
+
+/// Returns the size of the largest GSO buffer of `segment_size` segments.
+fn gso_buf_size(segment_size: usize) -> usize {
+    let segments = cmp::min(MAX_GSO_SEGMENTS, MAX_GSO_BUF_SIZE / segment_size);
+
+    cmp::max(segments, 1) * segment_size
+}
+
+/// A burst of packets to the same destination, coalesced in a buffer. All
+/// packets are `segment_size` bytes, except for the last one which can be
+/// smaller.
+pub struct Burst<'a> {
+    pub buf: &'a [u8],
+
+    pub target: net::SocketAddr,
+
+    pub segment_size: usize,
+}
+
 /// Send packets using sendmsg() with GSO.
 #[cfg(target_os = "linux")]
 fn send_to_gso(
@@ -60,27 +159,29 @@ fn send_to_gso(
     use nix::sys::uio::IoVec;
     use std::os::unix::io::AsRawFd;
 
-    let iov = [IoVec::from_slice(buf)];
-    let segment_size = segment_size as u16;
-    let cmsg = ControlMessage::UdpGsoSegments(&segment_size);
     let dst = SockAddr::new_inet(InetAddr::from_std(target));
 
-    match sendmsg(
-        socket.as_raw_fd(),
-        &iov,
-        &[cmsg],
-        MsgFlags::empty(),
-        Some(&dst),
-    ) {
-        Ok(v) => Ok(v),
-        Err(e) => {
-            let e = match e.as_errno() {
-                Some(v) => io::Error::from(v),
-                None => io::Error::new(io::ErrorKind::Other, e),
-            };
-            Err(e)
-        },
+    let mut written = 0;
+
+    // Bursts larger than a single GSO buffer need multiple calls.
+    for buf in buf.chunks(gso_buf_size(segment_size)) {
+        let iov = [IoVec::from_slice(buf)];
+        let segment_size = segment_size as u16;
+        let cmsg = ControlMessage::UdpGsoSegments(&segment_size);
+
+        match sendmsg(
+            socket.as_raw_fd(),
+            &iov,
+            &[cmsg],
+            MsgFlags::empty(),
+            Some(&dst),
+        ) {
+            Ok(v) => written += v,
+            Err(e) => return partial_write(written, nix_to_io_error(e)),
+        }
     }
+
+    Ok(written)
 }
 
 /// For non-Linux, there is no GSO support.
@@ -100,7 +201,9 @@ pub fn detect_sendmmsg() -> bool {
         cfg!(target_os = "netbsd")
 }
 
-/// Send packets using sendmmsg().
+/// Send bursts of packets using a single sendmmsg() call. With GSO, each
+/// message is a GSO buffer holding as many packets of a burst as possible,
+/// otherwise each message is a single packet.
 #[cfg(any(
     target_os = "linux",
     target_os = "android",
@@ -108,8 +211,7 @@ pub fn detect_sendmmsg() -> bool {
     target_os = "netbsd",
 ))]
 fn send_to_sendmmsg(
-    socket: &mio::net::UdpSocket, buf: &[u8], target: &net::SocketAddr,
-    segment_size: usize,
+    socket: &mio::net::UdpSocket, bursts: &[Burst], enable_gso: bool,
 ) -> io::Result<usize> {
     use nix::sys::socket::sendmmsg;
     use nix::sys::socket::InetAddr;
@@ -119,38 +221,47 @@ fn send_to_sendmmsg(
     use nix::sys::uio::IoVec;
     use std::os::unix::io::AsRawFd;
 
-    let dst = SockAddr::new_inet(InetAddr::from_std(target));
-
-    let mut off = 0;
-    let mut left = buf.len();
-
-    let mut msgs = Vec::new();
     let mut iovs = Vec::new();
+    let mut segment_sizes = Vec::new();
+    let mut dsts = Vec::new();
 
-    while left > 0 {
-        let pkt_len = cmp::min(left, segment_size);
+    for burst in bursts {
+        let msg_size = if enable_gso {
+            gso_buf_size(burst.segment_size)
+        } else {
+            burst.segment_size
+        };
 
-        iovs.push([IoVec::from_slice(&buf[off..off + pkt_len])]);
+        let dst = SockAddr::new_inet(InetAddr::from_std(&burst.target));
 
-        off += pkt_len;
-        left -= pkt_len;
+        for buf in burst.buf.chunks(msg_size) {
+            iovs.push([IoVec::from_slice(buf)]);
+            segment_sizes.push(burst.segment_size as u16);
+            dsts.push(dst);
+        }
     }
 
-    for iov in iovs.iter() {
-        msgs.push(SendMmsgData {
+    let cmsgs: Vec<_> = segment_sizes
+        .iter()
+        .map(|segment_size| gso_cmsgs(segment_size, enable_gso))
+        .collect();
+
+    let msgs: Vec<_> = iovs
+        .iter()
+        .zip(cmsgs.iter())
+        .zip(dsts.iter())
+        .map(|((iov, cmsgs), dst)| SendMmsgData {
             iov,
-            cmsgs: &[],
-            addr: Some(dst),
+            cmsgs,
+            addr: Some(*dst),
             _lt: Default::default(),
-        });
-    }
+        })
+        .collect();
 
+    // The messages that weren't sent are reported with a length of 0.
     match sendmmsg(socket.as_raw_fd(), msgs.iter(), MsgFlags::empty()) {
         Ok(results) => Ok(results.iter().sum()),
-        Err(e) => match e.as_errno() {
-            Some(v) => Err(io::Error::from(v)),
-            None => Err(io::Error::new(io::ErrorKind::Other, e)),
-        },
+        Err(e) => Err(nix_to_io_error(e)),
     }
 }
 
@@ -162,58 +273,154 @@ fn send_to_sendmmsg(
     target_os = "netbsd",
 )))]
 fn send_to_sendmmsg(
-    _socket: &mio::net::UdpSocket, _buf: &[u8], _target: &net::SocketAddr,
-    _segment_size: usize,
+    _socket: &mio::net::UdpSocket, _bursts: &[Burst], _enable_gso: bool,
 ) -> io::Result<usize> {
     panic!("send_to_sendmmsg() should not be called on non-supported platforms");
 }
 
+/// Returns the control messages setting the GSO segment size of a message.
+#[cfg(target_os = "linux")]
+fn gso_cmsgs(
+    segment_size: &u16, enable_gso: bool,
+) -> Vec<nix::sys::socket::ControlMessage<'_>> {
+    if !enable_gso {
+        return Vec::new();
+    }
+
+    vec![nix::sys::socket::ControlMessage::UdpGsoSegments(
+        segment_size,
+    )]
+}
+
+/// For non-Linux, there is no GSO support.
+#[cfg(all(
+    not(target_os = "linux"),
+    any(target_os = "android", target_os = "freebsd", target_os = "netbsd",)
+))]
+fn gso_cmsgs(
+    _segment_size: &u16, _enable_gso: bool,
+) -> Vec<nix::sys::socket::ControlMessage<'_>> {
+    Vec::new()
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+))]
+fn nix_to_io_error(e: nix::Error) -> io::Error {
+    match e.as_errno() {
+        Some(v) => io::Error::from(v),
+        None => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+/// Returns the number of bytes written so far if some were, or the error
+/// that interrupted writing otherwise. Like with write(), the error is then
+/// reported by the next attempt at writing the rest.
+fn partial_write(written: usize, e: io::Error) -> io::Result<usize> {
+    if written > 0 {
+        return Ok(written);
+    }
+
+    Err(e)
+}
+
+/// Returns true if `e` is how the kernel or the NIC driver rejects GSO
+/// buffers, even though GSO was detected.
+fn is_gso_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(nix::libc::EIO) | Some(nix::libc::EINVAL)
+    )
+}
+
 /// A wrapper function of send_to().
 /// - when GSO enabled, send a packet using send_to_gso().
 /// - when sendmmsg() enabled, send a packet using send_to_sendmmsg().
 /// Otherwise, send packet using socket.send_to().
+///
+/// Returns the number of bytes sent, see send_bursts().
 pub fn send_to(
     socket: &mio::net::UdpSocket, buf: &[u8], target: &net::SocketAddr,
     segment_size: usize, enable_gso: bool, enable_sendmmsg: bool,
 ) -> io::Result<usize> {
-    if enable_gso {
-        match send_to_gso(socket, buf, target, segment_size) {
-            Ok(v) => {
-                return Ok(v);
-            },
-            Err(e) => {
-                return Err(e);
-            },
-        }
+    let burst = Burst {
+        buf,
+
+        target: *target,
+
+        segment_size,
+    };
+
+    send_bursts(socket, &[burst], enable_gso, enable_sendmmsg)
+}
+
+/// Sends bursts of packets, possibly to different destinations, in as few
+/// system calls as possible.
+///
+/// When sendmmsg() is enabled, all bursts are sent with a single call, as GSO
+/// buffers if GSO is also enabled. When only GSO is enabled, each burst is
+/// sent using send_to_gso(). Otherwise, each packet is sent separately.
+///
+/// Returns the number of bytes sent. When the socket's buffer fills up, this
+/// is less than the size of the bursts, and always covers whole packets, so
+/// the rest can be sent later from there. `WouldBlock` is returned only when
+/// nothing could be sent.
+///
+/// If a GSO send fails, the bursts are sent again without GSO, and GSO isn't
+/// used anymore.
+pub fn send_bursts(
+    socket: &mio::net::UdpSocket, bursts: &[Burst], enable_gso: bool,
+    enable_sendmmsg: bool,
+) -> io::Result<usize> {
+    let enable_gso = enable_gso && !GSO_FAILED.load(atomic::Ordering::Relaxed);
+
+    match send_bursts_once(socket, bursts, enable_gso, enable_sendmmsg) {
+        Err(e) if enable_gso && is_gso_error(&e) => {
+            warn!("GSO send failed, falling back to sending packets: {}", e);
+
+            GSO_FAILED.store(true, atomic::Ordering::Relaxed);
+
+            send_bursts_once(socket, bursts, false, enable_sendmmsg)
+        },
+
+        v => v,
     }
+}
 
+fn send_bursts_once(
+    socket: &mio::net::UdpSocket, bursts: &[Burst], enable_gso: bool,
+    enable_sendmmsg: bool,
+) -> io::Result<usize> {
     if enable_sendmmsg {
-        match send_to_sendmmsg(socket, buf, target, segment_size) {
-            Ok(v) => {
-                return Ok(v);
-            },
-            Err(e) => {
-                return Err(e);
-            },
-        }
+        return send_to_sendmmsg(socket, bursts, enable_gso);
     }
 
-    let mut off = 0;
-    let mut left = buf.len();
     let mut written = 0;
 
-    while left > 0 {
-        let pkt_len = cmp::min(left, segment_size);
+    for burst in bursts {
+        if enable_gso {
+            let sent =
+                send_to_gso(socket, burst.buf, &burst.target, burst.segment_size);
 
-        match socket.send_to(&buf[off..off + pkt_len], target) {
-            Ok(v) => {
-                written += v;
-            },
-            Err(e) => return Err(e),
+            match sent {
+                // The rest of the burst didn't fit in the socket's buffer.
+                Ok(v) if v < burst.buf.len() => return Ok(written + v),
+                Ok(v) => written += v,
+                Err(e) => return partial_write(written, e),
+            }
+
+            continue;
         }
 
-        off += pkt_len;
-        left -= pkt_len;
+        for buf in burst.buf.chunks(burst.segment_size) {
+            match socket.send_to(buf, &burst.target) {
+                Ok(v) => written += v,
+                Err(e) => return partial_write(written, e),
+            }
+        }
     }
 
     Ok(written)
@@ -0,0 +1,164 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Storage of resumption state for client connections.
+//!
+//! Clients can resume previous connections to the same origin by re-using the
+//! TLS session ticket (and NEW_TOKEN address validation token) received on a
+//! previous connection. The [`SessionStore`] trait, shared with the async
+//! client in quiche-async, abstracts where this state is kept, so that it can
+//! be shared across connections, and across client processes when using the
+//! file-backed [`FileSessionStore`].
+//!
+//! [`SessionStore`]: trait.SessionStore.html
+//! [`FileSessionStore`]: struct.FileSessionStore.html
+
+use std::io::prelude::*;
+
+use std::path::PathBuf;
+
+pub use quiche::SessionEntryKind;
+pub use quiche::SessionStore;
+
+fn extension(kind: SessionEntryKind) -> &'static str {
+    match kind {
+        SessionEntryKind::Session => "session",
+
+        SessionEntryKind::Token => "token",
+    }
+}
+
+/// Returns the origin of `url`, used as the key in a [`SessionStore`].
+///
+/// [`SessionStore`]: trait.SessionStore.html
+pub fn origin(url: &url::Url) -> String {
+    url.origin().ascii_serialization()
+}
+
+/// A [`SessionStore`] that keeps entries as files in a directory.
+///
+/// Each entry is stored in its own file, named after the origin and the kind
+/// of the entry. Files are replaced atomically, so that the same directory
+/// can be safely used by multiple client processes at the same time.
+///
+/// [`SessionStore`]: trait.SessionStore.html
+pub struct FileSessionStore {
+    dir: PathBuf,
+}
+
+impl FileSessionStore {
+    /// Creates a new store backed by the given directory.
+    ///
+    /// The directory is created if it doesn't exist yet.
+    pub fn new(dir: &str) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        Ok(FileSessionStore {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    fn path(&self, origin: &str, kind: SessionEntryKind) -> PathBuf {
+        // Only keep characters that are safe to use in file names.
+        let name: String = origin
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        let mut path = self.dir.clone();
+        path.push(format!("{}.{}", name, extension(kind)));
+        path
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn get(&self, origin: &str, kind: SessionEntryKind) -> Option<Vec<u8>> {
+        std::fs::read(self.path(origin, kind)).ok()
+    }
+
+    fn put(&mut self, origin: &str, kind: SessionEntryKind, value: &[u8]) {
+        let path = self.path(origin, kind);
+
+        // Write to a temporary file first and then rename it, so that other
+        // processes never observe a partially written entry.
+        let tmp = path.with_extension(format!(
+            "{}.{}.tmp",
+            extension(kind),
+            std::process::id()
+        ));
+
+        let res = std::fs::File::create(&tmp)
+            .and_then(|mut f| f.write_all(value))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+
+        if let Err(e) = res {
+            error!("failed to store {:?} for {}: {:?}", kind, origin, e);
+
+            std::fs::remove_file(&tmp).ok();
+        }
+    }
+}
+
+/// A [`SessionStore`] that keeps a single TLS session in the given file,
+/// regardless of the origin.
+///
+/// Tokens are not stored.
+///
+/// [`SessionStore`]: trait.SessionStore.html
+pub struct SingleFileSessionStore {
+    path: PathBuf,
+}
+
+impl SingleFileSessionStore {
+    /// Creates a new store backed by the given file.
+    pub fn new(path: &str) -> Self {
+        SingleFileSessionStore {
+            path: PathBuf::from(path),
+        }
+    }
+}
+
+impl SessionStore for SingleFileSessionStore {
+    fn get(&self, _origin: &str, kind: SessionEntryKind) -> Option<Vec<u8>> {
+        match kind {
+            SessionEntryKind::Session => std::fs::read(&self.path).ok(),
+
+            SessionEntryKind::Token => None,
+        }
+    }
+
+    fn put(&mut self, _origin: &str, kind: SessionEntryKind, value: &[u8]) {
+        if kind == SessionEntryKind::Session {
+            std::fs::write(&self.path, value).ok();
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Bandwidth throttling of response bodies.
+//!
+//! A [`Throttle`] is a token bucket refilled at a fixed rate in bytes per
+//! second, which allows bursts of up to 100ms worth of data. This emulates a
+//! constrained origin without requiring external traffic shaping.
+//!
+//! [`Throttle`]: struct.Throttle.html
+
+use std::cmp;
+
+use std::time::Duration;
+use std::time::Instant;
+
+/// How much data can be written at once, in time.
+const BURST_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The smallest burst, so that low rates still write full packets.
+const MIN_BURST: u64 = 1350;
+
+/// Limits the rate at which data is written.
+pub struct Throttle {
+    rate: u64,
+
+    burst: u64,
+
+    tokens: f64,
+
+    last_refill: Instant,
+}
+
+impl Throttle {
+    /// Creates a throttle allowing `rate` bytes per second.
+    pub fn new(rate: u64) -> Self {
+        let burst = rate * BURST_INTERVAL.as_millis() as u64 / 1000;
+        let burst = cmp::max(burst, MIN_BURST);
+
+        Throttle {
+            rate,
+
+            burst,
+
+            tokens: burst as f64,
+
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Returns how many bytes can be written right now. This is 0 until a
+    /// full packet can be written, so that data isn't trickled out in tiny
+    /// packets.
+    pub fn available(&mut self) -> usize {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.last_refill);
+
+        self.tokens += elapsed.as_secs_f64() * self.rate as f64;
+        self.tokens = self.tokens.min(self.burst as f64);
+        self.last_refill = now;
+
+        if self.tokens < MIN_BURST as f64 {
+            return 0;
+        }
+
+        self.tokens as usize
+    }
+
+    /// Records that `written` bytes were written.
+    pub fn consume(&mut self, written: usize) {
+        self.tokens -= written as f64;
+    }
+
+    /// Returns how long until a full packet can be written again, or `None`
+    /// if it can be written right now.
+    pub fn timeout(&self) -> Option<Duration> {
+        let missing = MIN_BURST as f64 - self.tokens;
+
+        if missing <= 0.0 {
+            return None;
+        }
+
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        let wait = missing / self.rate as f64 - elapsed;
+
+        if wait <= 0.0 {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(wait))
+    }
+}
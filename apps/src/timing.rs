@@ -0,0 +1,263 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Timing and transfer metrics of the client's requests.
+//!
+//! The metrics are printed after each request when `--write-out` or
+//! `--json-stats` are used, mirroring curl's `--write-out` variables so that
+//! existing monitoring scripts can be reused.
+
+use std::fmt::Write as _;
+
+use std::net::SocketAddr;
+
+use std::time::Duration;
+use std::time::Instant;
+
+/// Metrics about the connection the requests were sent on.
+///
+/// All times are relative to the start of the client, before the server's
+/// address was resolved.
+#[derive(Clone, Debug, Default)]
+pub struct ConnTimings {
+    /// Time until the server's address was resolved.
+    pub namelookup: Duration,
+
+    /// The address of the server that the connection was made to.
+    pub remote_addr: Option<SocketAddr>,
+
+    /// Time until the first packet was sent to the server.
+    pub connect: Duration,
+
+    /// Time until the handshake completed, if it did.
+    pub handshake: Option<Duration>,
+
+    /// The negotiated QUIC version.
+    pub version: u32,
+
+    /// The negotiated application protocol.
+    pub alpn: String,
+
+    /// Whether the server asked for a retry.
+    pub retry: bool,
+
+    /// Whether the requests were sent as 0-RTT data.
+    pub early_data: bool,
+
+    /// Whether the server accepted the 0-RTT data.
+    pub early_data_accepted: bool,
+
+    /// Whether the TLS session was resumed.
+    pub resumed: bool,
+}
+
+/// Metrics about a single request.
+///
+/// All times are relative to the start of the client, like those of
+/// [`ConnTimings`].
+///
+/// [`ConnTimings`]: struct.ConnTimings.html
+#[derive(Clone, Debug, Default)]
+pub struct RequestTimings {
+    /// The URL of the request.
+    pub url: String,
+
+    /// The status code of the response, if any.
+    pub status: Option<u16>,
+
+    /// Time until the request was sent.
+    pub start: Option<Duration>,
+
+    /// Time until the first byte of the response was received.
+    pub first_byte: Option<Duration>,
+
+    /// Time until the response was complete.
+    pub total: Option<Duration>,
+
+    /// The number of request body bytes sent.
+    pub bytes_sent: u64,
+
+    /// The number of response body bytes received.
+    pub bytes_recv: u64,
+}
+
+/// Returns the time elapsed between `start` and `t`.
+pub fn since(start: &Instant, t: Option<Instant>) -> Option<Duration> {
+    t.map(|t| t.duration_since(*start))
+}
+
+fn secs(d: Option<Duration>) -> String {
+    format!("{:.6}", d.unwrap_or_default().as_secs_f64())
+}
+
+/// Expands the `%{variable}` references and the `\n`, `\r` and `\t` escapes
+/// of a `--write-out` format string.
+///
+/// Unknown variables are left untouched.
+pub fn write_out(
+    format: &str, conn: &ConnTimings, req: &RequestTimings,
+) -> String {
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some('n')) => {
+                chars.next();
+                out.push('\n');
+            },
+
+            ('\\', Some('r')) => {
+                chars.next();
+                out.push('\r');
+            },
+
+            ('\\', Some('t')) => {
+                chars.next();
+                out.push('\t');
+            },
+
+            ('%', Some('%')) => {
+                chars.next();
+                out.push('%');
+            },
+
+            ('%', Some('{')) => {
+                chars.next();
+
+                let name: String =
+                    chars.by_ref().take_while(|&c| c != '}').collect();
+
+                match variable(&name, conn, req) {
+                    Some(v) => out.push_str(&v),
+
+                    None => write!(out, "%{{{}}}", name).unwrap(),
+                }
+            },
+
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+fn variable(
+    name: &str, conn: &ConnTimings, req: &RequestTimings,
+) -> Option<String> {
+    let v = match name {
+        "url_effective" => req.url.clone(),
+
+        "http_code" => format!("{:03}", req.status.unwrap_or(0)),
+
+        "http_version" => conn.alpn.clone(),
+
+        "quic_version" => format!("{:#010x}", conn.version),
+
+        "remote_ip" => conn
+            .remote_addr
+            .map(|a| a.ip().to_string())
+            .unwrap_or_default(),
+
+        "remote_port" => conn
+            .remote_addr
+            .map(|a| a.port().to_string())
+            .unwrap_or_default(),
+
+        "time_namelookup" => secs(Some(conn.namelookup)),
+
+        "time_connect" => secs(Some(conn.connect)),
+
+        "time_appconnect" => secs(conn.handshake),
+
+        "time_pretransfer" => secs(req.start),
+
+        "time_starttransfer" => secs(req.first_byte),
+
+        "time_total" => secs(req.total),
+
+        "size_upload" => req.bytes_sent.to_string(),
+
+        "size_download" => req.bytes_recv.to_string(),
+
+        "retry" => (conn.retry as u8).to_string(),
+
+        "early_data" => (conn.early_data as u8).to_string(),
+
+        "early_data_accepted" => (conn.early_data_accepted as u8).to_string(),
+
+        "resumed" => (conn.resumed as u8).to_string(),
+
+        _ => return None,
+    };
+
+    Some(v)
+}
+
+/// Formats the metrics of a request as a single-line JSON object.
+pub fn json_stats(conn: &ConnTimings, req: &RequestTimings) -> String {
+    let mut out = String::new();
+
+    write!(out, "{{\"url\":\"{}\",", req.url.replace('"', "\\\"")).unwrap();
+
+    match req.status {
+        Some(v) => write!(out, "\"status\":{},", v).unwrap(),
+
+        None => write!(out, "\"status\":null,").unwrap(),
+    }
+
+    write!(out, "\"alpn\":\"{}\",", conn.alpn).unwrap();
+    write!(out, "\"quic_version\":{},", conn.version).unwrap();
+
+    match conn.remote_addr {
+        Some(v) => write!(out, "\"remote_addr\":\"{}\",", v).unwrap(),
+
+        None => write!(out, "\"remote_addr\":null,").unwrap(),
+    }
+
+    write!(out, "\"retry\":{},", conn.retry).unwrap();
+    write!(out, "\"early_data\":{},", conn.early_data).unwrap();
+    write!(out, "\"early_data_accepted\":{},", conn.early_data_accepted).unwrap();
+    write!(out, "\"resumed\":{},", conn.resumed).unwrap();
+
+    let times = [
+        ("namelookup", Some(conn.namelookup)),
+        ("connect", Some(conn.connect)),
+        ("appconnect", conn.handshake),
+        ("pretransfer", req.start),
+        ("starttransfer", req.first_byte),
+        ("total", req.total),
+    ];
+
+    for (name, t) in times.iter() {
+        write!(out, "\"time_{}\":{},", name, secs(*t)).unwrap();
+    }
+
+    write!(out, "\"size_upload\":{},", req.bytes_sent).unwrap();
+    write!(out, "\"size_download\":{}}}", req.bytes_recv).unwrap();
+
+    out
+}
@@ -0,0 +1,343 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Address validation tokens, sent in Retry packets and NEW_TOKEN frames.
+//!
+//! Tokens are sealed with AES-256-GCM, using the client's IP address as
+//! additional authenticated data, so they can't be forged, nor used from
+//! another address. They carry their kind and the time they were issued at.
+//! Retry tokens also carry the original destination connection ID chosen by
+//! the client.
+//!
+//! The keys can be read from a file holding one hex-encoded 256-bit key per
+//! line. The first key is used to mint new tokens, and all of them are accepted
+//! when validating, so the keys can be rotated by adding a new one at the top
+//! of the file, and removing the oldest once the tokens it minted expired. The
+//! file is read again when its modification time changes.
+
+use std::net::IpAddr;
+use std::net::SocketAddr;
+
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use ring::aead;
+use ring::digest;
+use ring::rand::*;
+
+/// How long a Retry token remains valid after being issued.
+const RETRY_TOKEN_LIFETIME: Duration = Duration::from_secs(10);
+
+/// How long a NEW_TOKEN token remains valid after being issued, by default.
+const NEW_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often to check the key file's modification time.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const KEY_ID_LEN: usize = 4;
+
+const TIMESTAMP_LEN: usize = 8;
+
+const RETRY_TOKEN: u8 = 0;
+
+const NEW_TOKEN: u8 = 1;
+
+/// A token that was successfully validated.
+#[derive(Debug, PartialEq)]
+pub enum ValidToken {
+    /// A token sent in a Retry packet, with the original destination
+    /// connection ID it recorded.
+    Retry(quiche::ConnectionId<'static>),
+
+    /// A token sent in a NEW_TOKEN frame on a previous connection.
+    NewToken,
+}
+
+/// A key used to seal tokens, along with its identifier.
+///
+/// The identifier is derived from the key itself, so that the position of the
+/// key in the file can change when rotating keys.
+struct TokenKey {
+    id: [u8; KEY_ID_LEN],
+    key: aead::LessSafeKey,
+}
+
+impl TokenKey {
+    fn new(bytes: &[u8]) -> Option<TokenKey> {
+        let key = aead::UnboundKey::new(&aead::AES_256_GCM, bytes).ok()?;
+
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(
+            &digest::digest(&digest::SHA256, bytes).as_ref()[..KEY_ID_LEN],
+        );
+
+        Some(TokenKey {
+            id,
+            key: aead::LessSafeKey::new(key),
+        })
+    }
+}
+
+/// Mints and validates address validation tokens.
+pub struct AddressTokens {
+    keys: Vec<TokenKey>,
+    path: Option<String>,
+    mtime: Option<SystemTime>,
+    last_check: Instant,
+    new_token_lifetime: Duration,
+    rng: SystemRandom,
+}
+
+impl AddressTokens {
+    /// Creates a token minter using a random key, which is only valid for the
+    /// lifetime of the process.
+    pub fn with_random_key() -> Self {
+        let rng = SystemRandom::new();
+
+        let mut bytes = [0; 32];
+        rng.fill(&mut bytes).unwrap();
+
+        AddressTokens {
+            keys: vec![TokenKey::new(&bytes).unwrap()],
+            path: None,
+            mtime: None,
+            last_check: Instant::now(),
+            new_token_lifetime: NEW_TOKEN_LIFETIME,
+            rng,
+        }
+    }
+
+    /// Creates a token minter using the keys read from the given file.
+    pub fn from_file(path: &str) -> std::io::Result<Self> {
+        Ok(AddressTokens {
+            keys: read_keys(path)?,
+            path: Some(path.to_string()),
+            mtime: mtime(path),
+            last_check: Instant::now(),
+            new_token_lifetime: NEW_TOKEN_LIFETIME,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Sets how long the tokens sent in NEW_TOKEN frames remain valid.
+    ///
+    /// The default value is 24 hours.
+    pub fn set_new_token_lifetime(&mut self, v: Duration) {
+        self.new_token_lifetime = v;
+    }
+
+    /// Reads the key file again, if it changed since it was last read.
+    ///
+    /// If the new file can't be read, the previous keys are kept.
+    pub fn maybe_reload(&mut self) {
+        let path = match &self.path {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return;
+        }
+
+        self.last_check = Instant::now();
+
+        let mtime = mtime(path);
+
+        if mtime == self.mtime {
+            return;
+        }
+
+        self.mtime = mtime;
+
+        match read_keys(path) {
+            Ok(keys) => {
+                info!("reloaded {} token keys from {}", keys.len(), path);
+
+                self.keys = keys;
+            },
+
+            Err(e) => error!("failed to reload token keys: {:?}", e),
+        }
+    }
+
+    /// Generates a Retry token for the client at `src`, recording the original
+    /// destination connection ID of `hdr`.
+    pub fn mint_retry(&self, hdr: &quiche::Header, src: &SocketAddr) -> Vec<u8> {
+        self.mint(RETRY_TOKEN, &hdr.dcid, src)
+    }
+
+    /// Generates a token to send to the client at `src` in a NEW_TOKEN frame.
+    pub fn mint_new_token(&self, src: &SocketAddr) -> Vec<u8> {
+        self.mint(NEW_TOKEN, &[], src)
+    }
+
+    fn mint(&self, kind: u8, odcid: &[u8], src: &SocketAddr) -> Vec<u8> {
+        let key = &self.keys[0];
+
+        let mut nonce = [0; aead::NONCE_LEN];
+        self.rng.fill(&mut nonce).unwrap();
+
+        let mut token = Vec::new();
+        token.extend_from_slice(&key.id);
+        token.extend_from_slice(&nonce);
+
+        let mut sealed = vec![kind];
+        sealed.extend_from_slice(&unix_time().to_be_bytes());
+        sealed.extend_from_slice(odcid);
+
+        key.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(addr_bytes(src)),
+                &mut sealed,
+            )
+            .unwrap();
+
+        token.extend_from_slice(&sealed);
+
+        token
+    }
+
+    /// Validates a token sent by the client at `src`.
+    ///
+    /// `None` is returned if the token wasn't minted with one of the current
+    /// keys, if it was minted for another address, or if it expired.
+    pub fn validate(&self, src: &SocketAddr, token: &[u8]) -> Option<ValidToken> {
+        if token.len() < KEY_ID_LEN + aead::NONCE_LEN {
+            return None;
+        }
+
+        let (id, token) = token.split_at(KEY_ID_LEN);
+        let (nonce, sealed) = token.split_at(aead::NONCE_LEN);
+
+        let key = self.keys.iter().find(|k| k.id == id)?;
+
+        let nonce = aead::Nonce::try_assume_unique_for_key(nonce).ok()?;
+
+        let mut sealed = sealed.to_vec();
+
+        let plain = key
+            .key
+            .open_in_place(nonce, aead::Aad::from(addr_bytes(src)), &mut sealed)
+            .ok()?;
+
+        if plain.len() < 1 + TIMESTAMP_LEN {
+            return None;
+        }
+
+        let (kind, plain) = plain.split_at(1);
+        let (issued, odcid) = plain.split_at(TIMESTAMP_LEN);
+
+        let (lifetime, valid) = match kind[0] {
+            RETRY_TOKEN => (
+                RETRY_TOKEN_LIFETIME,
+                ValidToken::Retry(quiche::ConnectionId::from_vec(odcid.to_vec())),
+            ),
+
+            NEW_TOKEN => (self.new_token_lifetime, ValidToken::NewToken),
+
+            _ => return None,
+        };
+
+        let mut buf = [0; TIMESTAMP_LEN];
+        buf.copy_from_slice(issued);
+
+        let issued = u64::from_be_bytes(buf);
+        let now = unix_time();
+
+        // Tolerate some clock skew between servers sharing keys, as well.
+        let age = std::cmp::max(now, issued) - std::cmp::min(now, issued);
+
+        if age > lifetime.as_secs() {
+            return None;
+        }
+
+        Some(valid)
+    }
+}
+
+fn read_keys(path: &str) -> std::io::Result<Vec<TokenKey>> {
+    let invalid = |msg: &str| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+    };
+
+    let mut keys = Vec::new();
+
+    for line in std::fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let key = decode_hex(line)
+            .and_then(|bytes| TokenKey::new(&bytes))
+            .ok_or_else(|| invalid("keys must be 64 hex digits"))?;
+
+        keys.push(key);
+    }
+
+    if keys.is_empty() {
+        return Err(invalid("no keys found"));
+    }
+
+    Ok(keys)
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|c| match c {
+            [hi, lo] => Some((hex_digit(*hi)? << 4) | hex_digit(*lo)?),
+
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+fn addr_bytes(addr: &SocketAddr) -> Vec<u8> {
+    match addr.ip() {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn mtime(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -50,6 +50,17 @@ pub enum ConnectionState {
     Closed,
 }
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    ProbingStarted,
+    ProbingAbandoned,
+    ProbingSuccessful,
+    MigrationStarted,
+    MigrationAbandoned,
+    MigrationComplete,
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum ConnectivityEventType {
@@ -59,6 +70,7 @@ pub enum ConnectivityEventType {
     ConnectionIdUpdated,
     SpinBitUpdated,
     ConnectionStateUpdated,
+    MigrationStateUpdated,
 }
 
 #[serde_with::skip_serializing_none]
@@ -102,10 +114,10 @@ pub struct ConnectionClosed {
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct ConnectionIdUpdated {
-    owner: Option<TransportOwner>,
+    pub owner: Option<TransportOwner>,
 
-    old: Option<Bytes>,
-    new: Option<Bytes>,
+    pub old: Option<Bytes>,
+    pub new: Option<Bytes>,
 }
 
 #[serde_with::skip_serializing_none]
@@ -120,3 +132,13 @@ pub struct ConnectionStateUpdated {
     old: Option<ConnectionState>,
     new: ConnectionState,
 }
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MigrationStateUpdated {
+    pub old: Option<MigrationState>,
+    pub new: MigrationState,
+
+    pub local_address: Option<String>, // human-readable
+    pub remote_address: Option<String>, // human-readable
+}
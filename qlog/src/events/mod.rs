@@ -174,6 +174,9 @@ impl From<EventType> for EventImportance {
             EventType::ConnectivityEventType(
                 ConnectivityEventType::ConnectionStateUpdated,
             ) => EventImportance::Base,
+            EventType::ConnectivityEventType(
+                ConnectivityEventType::MigrationStateUpdated,
+            ) => EventImportance::Base,
 
             EventType::SecurityEventType(SecurityEventType::KeyUpdated) =>
                 EventImportance::Base,
@@ -333,6 +336,10 @@ impl From<&EventData> for EventType {
                 EventType::ConnectivityEventType(
                     ConnectivityEventType::ConnectionStateUpdated,
                 ),
+            EventData::MigrationStateUpdated { .. } =>
+                EventType::ConnectivityEventType(
+                    ConnectivityEventType::MigrationStateUpdated,
+                ),
 
             EventData::KeyUpdated { .. } =>
                 EventType::SecurityEventType(SecurityEventType::KeyUpdated),
@@ -480,6 +487,9 @@ pub enum EventData {
     #[serde(rename = "connectivity:connection_state_updated")]
     ConnectionStateUpdated(connectivity::ConnectionStateUpdated),
 
+    #[serde(rename = "connectivity:migration_state_updated")]
+    MigrationStateUpdated(connectivity::MigrationStateUpdated),
+
     // Security
     #[serde(rename = "security:key_updated")]
     KeyUpdated(security::KeyUpdated),
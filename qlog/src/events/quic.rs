@@ -710,8 +710,10 @@ pub struct MetricsUpdated {
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct CongestionStateUpdated {
-    old: Option<String>,
-    new: String,
+    pub old: Option<String>,
+    pub new: String,
+
+    pub trigger: Option<String>,
 }
 
 #[serde_with::skip_serializing_none]
@@ -48,19 +48,31 @@ pub enum KeyType {
     Client1RttSecret,
 }
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyUpdateOrRetiredTrigger {
+    Tls,
+    RemoteUpdate,
+    LocalUpdate,
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct KeyUpdated {
-    key_type: KeyType,
-    old: Option<Bytes>,
-    new: Option<Bytes>,
-    generation: Option<u32>,
+    pub key_type: KeyType,
+    pub old: Option<Bytes>,
+    pub new: Option<Bytes>,
+    pub generation: Option<u32>,
+
+    pub trigger: Option<KeyUpdateOrRetiredTrigger>,
 }
 
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct KeyRetired {
-    key_type: KeyType,
-    key: Option<Bytes>,
-    generation: Option<u32>,
+    pub key_type: KeyType,
+    pub key: Option<Bytes>,
+    pub generation: Option<u32>,
+
+    pub trigger: Option<KeyUpdateOrRetiredTrigger>,
 }
@@ -542,6 +542,35 @@ pub struct Qlog {
     pub traces: Vec<Trace>,
 }
 
+/// The header of a qlog in the JSON text sequences (JSON-SEQ) format.
+///
+/// This is the first record of the sequence, followed by one record for each
+/// event of the trace.
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QlogSeq {
+    pub qlog_version: String,
+    pub qlog_format: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub summary: Option<String>,
+
+    pub trace: TraceSeq,
+}
+
+/// A qlog trace in the JSON-SEQ format, without its events.
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TraceSeq {
+    pub vantage_point: VantagePoint,
+    pub title: Option<String>,
+    pub description: Option<String>,
+
+    pub configuration: Option<Configuration>,
+
+    pub common_fields: Option<CommonFields>,
+}
+
 impl Default for Qlog {
     fn default() -> Self {
         Qlog {
@@ -43,6 +43,12 @@ use crate::events::EventType;
 /// `finished_frames()`. While serializing frames, any attempts to log
 /// additional events are ignored.
 ///
+/// By default the output is a single JSON document, which is only
+/// well-formed once `finish_log()` is called. Using the JSON text sequences
+/// format instead (see `set_format()`), each event is written out and
+/// flushed as a separate record, so the output stays usable even if it is
+/// never finished.
+///
 /// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
 use super::*;
 
@@ -54,11 +60,25 @@ pub enum StreamerState {
     Finished,
 }
 
+/// The serialization format used by the streamer.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QlogFormat {
+    /// A single JSON document.
+    Json,
+
+    /// JSON text sequences (RFC 7464), with one record per event.
+    JsonSeq,
+}
+
+// The record separator that starts each JSON text sequences record.
+const RECORD_SEPARATOR: &[u8] = b"\x1e";
+
 pub struct QlogStreamer {
     start_time: std::time::Instant,
     writer: Box<dyn std::io::Write + Send + Sync>,
     qlog: Qlog,
     state: StreamerState,
+    format: QlogFormat,
     log_level: EventImportance,
     first_event: bool,
     first_frame: bool,
@@ -92,12 +112,34 @@ impl QlogStreamer {
             writer,
             qlog,
             state: StreamerState::Initial,
+            format: QlogFormat::Json,
             log_level,
             first_event: true,
             first_frame: false,
         }
     }
 
+    /// Sets the serialization format.
+    ///
+    /// This can only be called before the log is started. The default format
+    /// is `QlogFormat::Json`.
+    pub fn set_format(&mut self, format: QlogFormat) -> Result<()> {
+        if self.state != StreamerState::Initial {
+            return Err(Error::InvalidState);
+        }
+
+        self.qlog.qlog_format = match format {
+            QlogFormat::Json => "JSON",
+
+            QlogFormat::JsonSeq => "JSON-SEQ",
+        }
+        .to_string();
+
+        self.format = format;
+
+        Ok(())
+    }
+
     /// Starts qlog streaming serialization.
     ///
     /// This writes out the JSON-serialized form of all information up to qlog
@@ -108,6 +150,10 @@ impl QlogStreamer {
             return Err(Error::Done);
         }
 
+        if self.format == QlogFormat::JsonSeq {
+            return self.start_log_seq();
+        }
+
         // A qlog contains a trace holding a vector of events that we want to
         // serialize in a streaming manner. So at the start of serialization,
         // take off all closing delimiters, and leave us in a state to accept
@@ -129,6 +175,39 @@ impl QlogStreamer {
         Ok(())
     }
 
+    // Writes the header record, followed by a record for each of the events
+    // already in the trace.
+    fn start_log_seq(&mut self) -> Result<()> {
+        let trace = &self.qlog.traces[0];
+
+        let header = QlogSeq {
+            qlog_version: self.qlog.qlog_version.clone(),
+            qlog_format: self.qlog.qlog_format.clone(),
+            title: self.qlog.title.clone(),
+            description: self.qlog.description.clone(),
+            summary: self.qlog.summary.clone(),
+            trace: TraceSeq {
+                vantage_point: trace.vantage_point.clone(),
+                title: trace.title.clone(),
+                description: trace.description.clone(),
+                configuration: trace.configuration.clone(),
+                common_fields: trace.common_fields.clone(),
+            },
+        };
+
+        write_record(self.writer.as_mut(), &header)?;
+
+        for event in &trace.events {
+            write_record(self.writer.as_mut(), event)?;
+        }
+
+        self.writer.as_mut().flush()?;
+
+        self.state = StreamerState::Ready;
+
+        Ok(())
+    }
+
     /// Finishes qlog streaming serialization.
     ///
     /// The JSON-serialized output has remaining close delimiters added.
@@ -140,7 +219,10 @@ impl QlogStreamer {
             return Err(Error::InvalidState);
         }
 
-        self.writer.as_mut().write_all(b"]}]}")?;
+        // Each record is already complete in the JSON-SEQ format.
+        if self.format == QlogFormat::Json {
+            self.writer.as_mut().write_all(b"]}]}")?;
+        }
 
         self.state = StreamerState::Finished;
 
@@ -261,16 +343,27 @@ impl QlogStreamer {
             _ => return Err(Error::Done),
         };
 
-        let maybe_comma = if self.first_event {
-            self.first_event = false;
-            ""
+        if self.format == QlogFormat::JsonSeq {
+            self.writer.as_mut().write_all(RECORD_SEPARATOR)?;
+            self.writer.as_mut().write_all(ev.as_bytes())?;
+
+            // The record is completed by `finish_frames()` otherwise.
+            if !contains_frames {
+                self.writer.as_mut().write_all(b"\n")?;
+                self.writer.as_mut().flush()?;
+            }
         } else {
-            ","
-        };
+            let maybe_comma = if self.first_event {
+                self.first_event = false;
+                ""
+            } else {
+                ","
+            };
 
-        let out = format!("{}{}", maybe_comma, ev);
+            let out = format!("{}{}", maybe_comma, ev);
 
-        self.writer.as_mut().write_all(out.as_bytes())?;
+            self.writer.as_mut().write_all(out.as_bytes())?;
+        }
 
         if contains_frames {
             self.state = StreamerState::WritingFrames
@@ -319,11 +412,23 @@ impl QlogStreamer {
         }
 
         self.writer.as_mut().write_all(b"]}}")?;
+
+        if self.format == QlogFormat::JsonSeq {
+            self.writer.as_mut().write_all(b"\n")?;
+            self.writer.as_mut().flush()?;
+        }
+
         self.state = StreamerState::Ready;
 
         Ok(())
     }
 
+    /// Returns true while in the frame-serialization mode, i.e. when other
+    /// events can't be logged until `finish_frames()` is called.
+    pub fn is_writing_frames(&self) -> bool {
+        self.state == StreamerState::WritingFrames
+    }
+
     /// Returns the writer.
     #[allow(clippy::borrowed_box)]
     pub fn writer(&self) -> &Box<dyn std::io::Write + Send + Sync> {
@@ -331,6 +436,19 @@ impl QlogStreamer {
     }
 }
 
+// Writes a single JSON text sequences record.
+fn write_record<T: serde::Serialize>(
+    writer: &mut dyn std::io::Write, record: &T,
+) -> Result<()> {
+    let out = serde_json::to_string(record).map_err(|_| Error::Done)?;
+
+    writer.write_all(RECORD_SEPARATOR)?;
+    writer.write_all(out.as_bytes())?;
+    writer.write_all(b"\n")?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -533,4 +651,104 @@ mod tests {
 
         assert_eq!(log_string, written_string);
     }
+
+    #[test]
+    fn serialization_json_seq() {
+        let v: Vec<u8> = Vec::new();
+        let buff = std::io::Cursor::new(v);
+        let writer = Box::new(buff);
+
+        let trace = make_trace();
+        let pkt_hdr = make_pkt_hdr(quic::PacketType::Handshake);
+
+        let frame = QuicFrame::Stream {
+            stream_id: 0,
+            offset: 0,
+            length: 100,
+            fin: Some(true),
+            raw: None,
+        };
+
+        let event_data1 = EventData::PacketSent(quic::PacketSent {
+            header: pkt_hdr.clone(),
+            frames: Some(vec![]),
+            is_coalesced: None,
+            retry_token: None,
+            stateless_reset_token: None,
+            supported_versions: None,
+            raw: None,
+            datagram_id: None,
+        });
+
+        let event1 = Event::with_time(0.0, event_data1);
+
+        let event_data2 = EventData::PacketSent(quic::PacketSent {
+            header: pkt_hdr,
+            frames: None,
+            is_coalesced: None,
+            retry_token: None,
+            stateless_reset_token: None,
+            supported_versions: None,
+            raw: None,
+            datagram_id: None,
+        });
+
+        let event2 = Event::with_time(0.0, event_data2);
+
+        let mut s = streamer::QlogStreamer::new(
+            "version".to_string(),
+            Some("title".to_string()),
+            Some("description".to_string()),
+            None,
+            std::time::Instant::now(),
+            trace,
+            EventImportance::Base,
+            writer,
+        );
+
+        assert!(matches!(s.set_format(QlogFormat::JsonSeq), Ok(())));
+        assert!(matches!(s.start_log(), Ok(())));
+
+        // The format can't be changed once the log is started.
+        assert!(matches!(
+            s.set_format(QlogFormat::Json),
+            Err(Error::InvalidState)
+        ));
+
+        assert!(matches!(s.add_event(event1), Ok(true)));
+        assert!(matches!(s.add_frame(frame, false), Ok(())));
+        assert!(matches!(s.finish_frames(), Ok(())));
+
+        assert!(matches!(s.add_event(event2), Ok(false)));
+
+        let r = s.writer();
+        #[allow(clippy::borrowed_box)]
+        let w: &Box<std::io::Cursor<Vec<u8>>> = unsafe { std::mem::transmute(r) };
+
+        // Every record is complete before the log is finished.
+        let log_string = concat!(
+            "\x1e{\"qlog_version\":\"version\",\"qlog_format\":\"JSON-SEQ\",",
+            "\"title\":\"title\",\"description\":\"description\",",
+            "\"trace\":{\"vantage_point\":{\"type\":\"server\"},",
+            "\"title\":\"Quiche qlog trace\",",
+            "\"description\":\"Quiche qlog trace description\",",
+            "\"configuration\":{\"time_offset\":0.0}}}\n",
+            "\x1e{\"time\":0.0,\"name\":\"transport:packet_sent\",",
+            "\"data\":{\"header\":{\"packet_type\":\"handshake\",",
+            "\"packet_number\":0,\"version\":\"1\",\"scil\":8,\"dcil\":8,",
+            "\"scid\":\"7e37e4dcc6682da8\",\"dcid\":\"36ce104eee50101c\"},",
+            "\"frames\":[{\"frame_type\":\"stream\",\"stream_id\":0,",
+            "\"offset\":0,\"length\":100,\"fin\":true}]}}\n",
+            "\x1e{\"time\":0.0,\"name\":\"transport:packet_sent\",",
+            "\"data\":{\"header\":{\"packet_type\":\"handshake\",",
+            "\"packet_number\":0,\"version\":\"1\",\"scil\":8,\"dcil\":8,",
+            "\"scid\":\"7e37e4dcc6682da8\",\"dcid\":\"36ce104eee50101c\"}}}\n",
+        );
+
+        let written_string = std::str::from_utf8(w.as_ref().get_ref()).unwrap();
+
+        assert_eq!(log_string, written_string);
+
+        assert!(matches!(s.finish_log(), Ok(())));
+    }
 }
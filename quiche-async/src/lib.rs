@@ -0,0 +1,1089 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Async integration of [quiche] with the [tokio] runtime.
+//!
+//! An [`AsyncConnection`] owns a QUIC connection and the UDP socket it uses.
+//! A background task sends and receives packets and processes timeouts, so
+//! the connection makes progress without a hand-written event loop, while
+//! the application awaits on stream reads and writes.
+//!
+//! Connections are handles that can be cloned, and their methods take
+//! `&self`, so different tasks can for example read from and write to the
+//! same connection concurrently.
+//!
+//! The socket is used by a single connection, so this is meant for clients,
+//! and for servers that dedicate a socket to each peer. Servers sharing a
+//! socket among many connections still need to dispatch packets themselves.
+//!
+//! ## Examples:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), quiche_async::Error> {
+//! let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+//! config.set_application_protos(b"\x05hello")?;
+//! config.set_initial_max_data(1_000_000);
+//! config.set_initial_max_stream_data_bidi_local(1_000_000);
+//! config.set_initial_max_streams_bidi(1);
+//!
+//! let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+//! let peer = "127.0.0.1:4433".parse().unwrap();
+//!
+//! let conn = quiche_async::AsyncConnection::connect(
+//!     Some("quic.tech"),
+//!     socket,
+//!     peer,
+//!     &mut config,
+//! )
+//! .await?;
+//!
+//! conn.stream_write(0, b"hello", true).await?;
+//!
+//! let mut buf = [0; 65535];
+//! let (len, fin) = conn.stream_read(0, &mut buf).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [quiche]: https://docs.rs/quiche
+//! [tokio]: https://docs.rs/tokio
+//! [`AsyncConnection`]: struct.AsyncConnection.html
+
+#![warn(missing_docs)]
+
+use std::net::SocketAddr;
+
+use std::pin::Pin;
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::Weak;
+
+use tokio::net::UdpSocket;
+
+use tokio::sync::Notify;
+
+use ring::hmac;
+use ring::rand::SecureRandom;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// A specialized [`Result`] type for async connection operations.
+///
+/// [`Result`]: https://doc.rust-lang.org/std/result/enum.Result.html
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// An async connection error.
+#[derive(Debug)]
+pub enum Error {
+    /// The QUIC connection failed.
+    Quic(quiche::Error),
+
+    /// Reading from or writing to the socket failed.
+    Io(std::io::Error),
+
+    /// The connection is closed.
+    Closed,
+}
+
+impl Error {
+    /// Returns a copy of the error, as I/O errors can't be cloned.
+    fn duplicate(&self) -> Error {
+        match self {
+            Error::Quic(e) => Error::Quic(*e),
+
+            Error::Io(e) =>
+                Error::Io(std::io::Error::new(e.kind(), e.to_string())),
+
+            Error::Closed => Error::Closed,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Quic(e) => write!(f, "quic error: {}", e),
+
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+
+            Error::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Quic(e) => Some(e),
+
+            Error::Io(e) => Some(e),
+
+            Error::Closed => None,
+        }
+    }
+}
+
+impl std::convert::From<quiche::Error> for Error {
+    fn from(err: quiche::Error) -> Self {
+        Error::Quic(err)
+    }
+}
+
+impl std::convert::From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// A QUIC connection driven by the tokio runtime.
+///
+/// This is a handle to the connection, clones of which refer to the same
+/// connection. The connection is driven by a background task until it's
+/// closed, or until all of its handles are dropped, in which case it's
+/// dropped without notifying the peer.
+#[derive(Clone)]
+pub struct AsyncConnection {
+    shared: Arc<Shared>,
+}
+
+/// The state shared by the handles of a connection and its driver.
+struct Shared {
+    inner: Mutex<Inner>,
+
+    /// Notified by the driver when it processed received packets or
+    /// timeouts, or when it stopped.
+    changed: Notify,
+
+    /// Notified by the driver every time it sent all the packets it could.
+    flushed: Notify,
+
+    /// Notified when the application may have queued packets to send, and
+    /// when the last handle is dropped.
+    wake_driver: Arc<Notify>,
+}
+
+struct Inner {
+    conn: Pin<Box<quiche::Connection>>,
+
+    /// The first error that happened while driving the connection.
+    error: Option<Error>,
+
+    /// How many times the driver sent all the packets it could.
+    flushes: u64,
+
+    /// How many times the driver processed received packets or timeouts.
+    events: u64,
+
+    /// Where the client's resumption state is saved, until it's saved.
+    resumption: Option<Resumption>,
+}
+
+/// The store the resumption state of a client connection is saved to.
+struct Resumption {
+    origin: String,
+
+    store: Box<dyn quiche::SessionStore + Send>,
+}
+
+impl Inner {
+    /// Returns the reason why the connection can't make progress anymore,
+    /// if any.
+    fn failure(&self) -> Option<Error> {
+        if let Some(e) = &self.error {
+            return Some(e.duplicate());
+        }
+
+        if self.conn.is_closed() {
+            return Some(Error::Closed);
+        }
+
+        None
+    }
+
+    fn recv_pkt(&mut self, buf: &mut [u8], from: SocketAddr) {
+        let info = quiche::RecvInfo { from };
+
+        match self.conn.recv(buf, info) {
+            Ok(_) | Err(quiche::Error::Done) => (),
+
+            // The connection was closed by quiche, but the driver keeps
+            // going so that the peer is notified.
+            Err(e) => {
+                self.error.get_or_insert(e.into());
+            },
+        }
+    }
+
+    /// Saves the session and address validation token received from the
+    /// server, if the connection has a session store.
+    fn save_session(&mut self) {
+        let Resumption { origin, mut store } = match self.resumption.take() {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        if let Some(session) = self.conn.session() {
+            store.put(&origin, quiche::SessionEntryKind::Session, &session);
+        }
+
+        if let Some(token) = self.conn.new_token() {
+            store.put(&origin, quiche::SessionEntryKind::Token, token);
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.save_session();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // Lets the driver know it can stop.
+        self.wake_driver.notify_one();
+    }
+}
+
+impl AsyncConnection {
+    /// Wraps an existing connection using the given socket, and starts
+    /// driving it in the background.
+    ///
+    /// Packets are sent to the address returned by [`send()`], and all the
+    /// packets received on the socket are handed to the connection.
+    ///
+    /// This must be called from within a tokio runtime.
+    ///
+    /// [`send()`]: ../quiche/struct.Connection.html#method.send
+    pub fn new(conn: Pin<Box<quiche::Connection>>, socket: UdpSocket) -> Self {
+        AsyncConnection::with_resumption(conn, socket, None)
+    }
+
+    fn with_resumption(
+        conn: Pin<Box<quiche::Connection>>, socket: UdpSocket,
+        resumption: Option<Resumption>,
+    ) -> Self {
+        let wake_driver = Arc::new(Notify::new());
+
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(Inner {
+                conn,
+
+                error: None,
+
+                flushes: 0,
+
+                events: 0,
+
+                resumption,
+            }),
+
+            changed: Notify::new(),
+
+            flushed: Notify::new(),
+
+            wake_driver: wake_driver.clone(),
+        });
+
+        tokio::spawn(drive(Arc::downgrade(&shared), wake_driver, socket));
+
+        AsyncConnection { shared }
+    }
+
+    /// Creates a client connection to `peer` and completes the handshake.
+    pub async fn connect(
+        server_name: Option<&str>, socket: UdpSocket, peer: SocketAddr,
+        config: &mut quiche::Config,
+    ) -> Result<Self> {
+        let scid = new_conn_id()?;
+        let scid = quiche::ConnectionId::from_ref(&scid);
+
+        let conn = quiche::connect(server_name, &scid, peer, config)?;
+
+        let conn = AsyncConnection::new(conn, socket);
+        conn.handshake().await?;
+
+        Ok(conn)
+    }
+
+    /// Creates a client connection to `peer` and completes the handshake,
+    /// like [`connect()`], resuming the session saved in `store` for `origin`
+    /// if any.
+    ///
+    /// The session and address validation token received from the server
+    /// are saved to `store` once the connection is closed, or once all of
+    /// its handles are dropped.
+    ///
+    /// [`connect()`]: struct.AsyncConnection.html#method.connect
+    pub async fn connect_with_session_store(
+        server_name: Option<&str>, socket: UdpSocket, peer: SocketAddr,
+        config: &mut quiche::Config, origin: &str,
+        store: Box<dyn quiche::SessionStore + Send>,
+    ) -> Result<Self> {
+        let scid = new_conn_id()?;
+        let scid = quiche::ConnectionId::from_ref(&scid);
+
+        let mut conn = quiche::connect(server_name, &scid, peer, config)?;
+
+        // Sessions can't be resumed with the rustls backend, but tokens are
+        // still used.
+        #[cfg(not(feature = "rustls"))]
+        if let Some(session) =
+            store.get(origin, quiche::SessionEntryKind::Session)
+        {
+            conn.set_session(&session).ok();
+        }
+
+        if let Some(token) = store.get(origin, quiche::SessionEntryKind::Token) {
+            conn.set_token(&token).ok();
+        }
+
+        let resumption = Resumption {
+            origin: origin.to_string(),
+
+            store,
+        };
+
+        let conn =
+            AsyncConnection::with_resumption(conn, socket, Some(resumption));
+        conn.handshake().await?;
+
+        Ok(conn)
+    }
+
+    /// Waits for a client on `socket`, creates a server connection for it
+    /// and completes the handshake.
+    ///
+    /// Version negotiation is performed if needed, and the client's address
+    /// is validated with a stateless retry before the connection is created.
+    /// Packets from other peers received once the connection is created are
+    /// ignored.
+    pub async fn accept(
+        socket: UdpSocket, config: &mut quiche::Config,
+    ) -> Result<Self> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let mut out = vec![0; MAX_DATAGRAM_SIZE];
+
+        let rng = ring::rand::SystemRandom::new();
+
+        // Retry tokens are only valid for this call, so they are
+        // authenticated with a key that isn't kept around.
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &rng)
+            .map_err(|_| quiche::Error::CryptoFail)?;
+
+        let (len, from, conn) = loop {
+            let (len, from) = socket.recv_from(&mut buf).await?;
+
+            let hdr = match quiche::Header::from_slice(
+                &mut buf[..len],
+                quiche::MAX_CONN_ID_LEN,
+            ) {
+                Ok(v) => v,
+
+                Err(_) => continue,
+            };
+
+            if hdr.ty != quiche::Type::Initial {
+                continue;
+            }
+
+            if !config.is_version_supported(hdr.version) {
+                let len = quiche::negotiate_version_with_config(
+                    &hdr.scid, &hdr.dcid, config, &mut out,
+                )?;
+
+                socket.send_to(&out[..len], from).await?;
+                continue;
+            }
+
+            let token = hdr.token.as_deref().unwrap_or(&[]);
+
+            if token.is_empty() {
+                let scid = new_conn_id()?;
+                let scid = quiche::ConnectionId::from_ref(&scid);
+
+                let token = mint_token(&key, &from, &hdr.dcid);
+
+                let len = quiche::retry(
+                    &hdr.scid,
+                    &hdr.dcid,
+                    &scid,
+                    &token,
+                    hdr.version,
+                    &mut out,
+                )?;
+
+                socket.send_to(&out[..len], from).await?;
+                continue;
+            }
+
+            let odcid = match validate_token(&key, &from, token) {
+                Some(v) => v,
+
+                None => continue,
+            };
+
+            // Reuse the connection ID sent in the Retry packet.
+            let conn = quiche::accept(&hdr.dcid, Some(&odcid), from, config)?;
+
+            break (len, from, conn);
+        };
+
+        let conn = AsyncConnection::new(conn, socket);
+
+        conn.shared.lock().recv_pkt(&mut buf[..len], from);
+        conn.shared.wake_driver.notify_one();
+
+        conn.handshake().await?;
+
+        Ok(conn)
+    }
+
+    /// Calls `f` with the underlying connection, and returns its result.
+    ///
+    /// Packets generated by calls made through the connection, e.g. to
+    /// [`stream_send()`], are sent by the driver afterwards.
+    ///
+    /// The connection is locked while `f` runs, so the driver and other
+    /// handles wait for it to return.
+    ///
+    /// [`stream_send()`]: ../quiche/struct.Connection.html#method.stream_send
+    pub fn with_conn<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut quiche::Connection) -> T,
+    {
+        let v = f(&mut self.shared.lock().conn);
+
+        self.shared.wake_driver.notify_one();
+
+        v
+    }
+
+    /// Waits until the driver sent all the packets the connection had to
+    /// send when this was called.
+    ///
+    /// Packets are sent no earlier than the time requested by the
+    /// connection's pacer.
+    pub async fn send(&self) -> Result<()> {
+        let flushes = self.shared.lock().flushes;
+
+        self.wait(&self.shared.flushed, |inner| {
+            if inner.flushes > flushes {
+                return Some(Ok(()));
+            }
+
+            None
+        })
+        .await
+    }
+
+    /// Waits until the driver processed received packets or an expired
+    /// timeout.
+    ///
+    /// [`Closed`] is returned once the connection is closed.
+    ///
+    /// [`Closed`]: enum.Error.html#variant.Closed
+    pub async fn recv(&self) -> Result<()> {
+        let events = self.shared.lock().events;
+
+        self.wait(&self.shared.changed, |inner| {
+            if inner.events > events {
+                return Some(Ok(()));
+            }
+
+            None
+        })
+        .await
+    }
+
+    /// Waits until a stream has data to read, and returns its ID.
+    ///
+    /// This is useful to find out about streams opened by the peer, as
+    /// [`stream_read()`] fails if the stream doesn't exist yet.
+    ///
+    /// [`stream_read()`]: struct.AsyncConnection.html#method.stream_read
+    pub async fn readable(&self) -> Result<u64> {
+        self.wait(&self.shared.changed, |inner| {
+            inner.conn.readable().next().map(Ok)
+        })
+        .await
+    }
+
+    /// Reads contiguous data from a stream into `buf`, waiting until some is
+    /// available.
+    ///
+    /// Returns the number of bytes read and whether the stream is finished,
+    /// like [`stream_recv()`].
+    ///
+    /// [`stream_recv()`]: ../quiche/struct.Connection.html#method.stream_recv
+    pub async fn stream_read(
+        &self, stream_id: u64, buf: &mut [u8],
+    ) -> Result<(usize, bool)> {
+        self.wait(&self.shared.changed, |inner| {
+            match inner.conn.stream_recv(stream_id, buf) {
+                Ok(v) => Some(Ok(v)),
+
+                Err(quiche::Error::Done) => None,
+
+                Err(e) => Some(Err(e.into())),
+            }
+        })
+        .await
+    }
+
+    /// Writes all of `buf` to a stream, waiting for flow and congestion
+    /// control to allow it.
+    ///
+    /// If `fin` is `true` the stream is finished after the last byte.
+    pub async fn stream_write(
+        &self, stream_id: u64, buf: &[u8], fin: bool,
+    ) -> Result<usize> {
+        let mut written = 0;
+
+        self.wait(&self.shared.changed, |inner| {
+            match inner.conn.stream_send(stream_id, &buf[written..], fin) {
+                Ok(v) => written += v,
+
+                Err(quiche::Error::Done) => (),
+
+                Err(e) => return Some(Err(e.into())),
+            }
+
+            if written == buf.len() {
+                return Some(Ok(written));
+            }
+
+            None
+        })
+        .await
+    }
+
+    /// Closes the connection and waits until it's fully closed.
+    ///
+    /// The arguments are the same as [`close()`].
+    ///
+    /// [`close()`]: ../quiche/struct.Connection.html#method.close
+    pub async fn close(&self, app: bool, err: u64, reason: &[u8]) -> Result<()> {
+        match self.with_conn(|conn| conn.close(app, err, reason)) {
+            Ok(_) | Err(quiche::Error::Done) => (),
+
+            Err(e) => return Err(e.into()),
+        }
+
+        // Waits until the connection can't make progress anymore.
+        let res = self.wait(&self.shared.changed, |_| None::<Result<()>>);
+
+        match res.await {
+            Err(Error::Closed) => Ok(()),
+
+            res => res,
+        }
+    }
+
+    async fn handshake(&self) -> Result<()> {
+        self.wait(&self.shared.changed, |inner| {
+            if inner.conn.is_established() {
+                return Some(Ok(()));
+            }
+
+            None
+        })
+        .await
+    }
+
+    /// Calls `f` every time `notify` is notified, until it returns a result
+    /// or the connection can't make progress anymore.
+    async fn wait<T, F>(&self, notify: &Notify, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Inner) -> Option<Result<T>>,
+    {
+        let notified = notify.notified();
+        tokio::pin!(notified);
+
+        loop {
+            // Registers for notifications before checking the state, so that
+            // changes made in between aren't missed.
+            notified.as_mut().enable();
+
+            let res = {
+                let mut inner = self.shared.lock();
+
+                match f(&mut inner) {
+                    Some(res) => Some(res),
+
+                    None => inner.failure().map(Err),
+                }
+            };
+
+            // Calling `f` may have generated packets to send.
+            self.shared.wake_driver.notify_one();
+
+            if let Some(res) = res {
+                return res;
+            }
+
+            notified.as_mut().await;
+            notified.set(notify.notified());
+        }
+    }
+}
+
+/// Sends and receives the packets of a connection and processes its
+/// timeouts, until it's closed or all of its handles are dropped.
+async fn drive(shared: Weak<Shared>, wake: Arc<Notify>, socket: UdpSocket) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let mut out = vec![0; MAX_DATAGRAM_SIZE];
+
+    // The number of events waiters were last notified of.
+    let mut events = 0;
+
+    // Set when the socket failed, so the connection can't be driven anymore.
+    let mut failed = false;
+
+    loop {
+        let timeout = match shared.upgrade() {
+            Some(shared) => {
+                let res = flush(&shared, &socket, &mut out).await;
+
+                let mut inner = shared.lock();
+
+                inner.flushes += 1;
+
+                let stop = failed || res.is_err() || inner.conn.is_closed();
+
+                if let Err(e) = res {
+                    inner.error.get_or_insert(e);
+                }
+
+                // Waiters aren't notified of flushes that didn't follow any
+                // event, as nothing they wait for could have changed, and
+                // they would wake the driver up again.
+                let changed = stop || inner.events != events;
+
+                events = inner.events;
+
+                if inner.conn.is_closed() {
+                    inner.save_session();
+                }
+
+                let timeout = inner.conn.timeout();
+
+                drop(inner);
+
+                shared.flushed.notify_waiters();
+
+                if changed {
+                    shared.changed.notify_waiters();
+                }
+
+                if stop {
+                    return;
+                }
+
+                timeout
+            },
+
+            None => return,
+        };
+
+        let expired = async {
+            match timeout {
+                Some(v) => tokio::time::sleep(v).await,
+
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => {
+                let shared = match shared.upgrade() {
+                    Some(v) => v,
+
+                    None => return,
+                };
+
+                let mut inner = shared.lock();
+
+                inner.events += 1;
+
+                match res {
+                    Ok((len, from)) => {
+                        inner.recv_pkt(&mut buf[..len], from);
+
+                        // Process all the packets that are already available,
+                        // so they can be acknowledged together.
+                        while let Ok((len, from)) =
+                            socket.try_recv_from(&mut buf)
+                        {
+                            inner.recv_pkt(&mut buf[..len], from);
+                        }
+                    },
+
+                    Err(e) => {
+                        inner.error.get_or_insert(e.into());
+
+                        failed = true;
+                    },
+                }
+            },
+
+            _ = expired => {
+                if let Some(shared) = shared.upgrade() {
+                    let mut inner = shared.lock();
+
+                    inner.conn.on_timeout();
+                    inner.events += 1;
+                }
+            },
+
+            _ = wake.notified() => (),
+        }
+    }
+}
+
+/// Sends all the packets the connection has to send, no earlier than the
+/// time requested by its pacer.
+async fn flush(
+    shared: &Shared, socket: &UdpSocket, out: &mut [u8],
+) -> Result<()> {
+    loop {
+        let res = shared.lock().conn.send(out);
+
+        let (len, info) = match res {
+            Ok(v) => v,
+
+            Err(quiche::Error::Done) => return Ok(()),
+
+            Err(e) => return Err(e.into()),
+        };
+
+        tokio::time::sleep_until(info.at.into()).await;
+
+        socket.send_to(&out[..len], info.to).await?;
+    }
+}
+
+/// Generates a stateless retry token, which carries the original destination
+/// connection ID, authenticated along with the client's address.
+fn mint_token(key: &hmac::Key, from: &SocketAddr, odcid: &[u8]) -> Vec<u8> {
+    let tag = hmac::sign(key, &token_input(from, odcid));
+
+    let mut token = tag.as_ref().to_vec();
+    token.extend_from_slice(odcid);
+
+    token
+}
+
+/// Validates a stateless retry token, and returns the original destination
+/// connection ID it carries.
+fn validate_token<'a>(
+    key: &hmac::Key, from: &SocketAddr, token: &'a [u8],
+) -> Option<quiche::ConnectionId<'a>> {
+    let tag_len = hmac::HMAC_SHA256.digest_algorithm().output_len;
+
+    if token.len() < tag_len {
+        return None;
+    }
+
+    let (tag, odcid) = token.split_at(tag_len);
+
+    hmac::verify(key, &token_input(from, odcid), tag).ok()?;
+
+    Some(quiche::ConnectionId::from_ref(odcid))
+}
+
+fn token_input(from: &SocketAddr, odcid: &[u8]) -> Vec<u8> {
+    // The length prefix keeps the connection ID from extending the address.
+    let mut input = vec![odcid.len() as u8];
+
+    input.extend_from_slice(odcid);
+    input.extend_from_slice(from.to_string().as_bytes());
+
+    input
+}
+
+fn new_conn_id() -> Result<[u8; quiche::MAX_CONN_ID_LEN]> {
+    let mut id = [0; quiche::MAX_CONN_ID_LEN];
+
+    ring::rand::SystemRandom::new()
+        .fill(&mut id[..])
+        .map_err(|_| quiche::Error::CryptoFail)?;
+
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(is_server: bool) -> quiche::Config {
+        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+
+        if is_server {
+            config
+                .load_cert_chain_from_pem_file("../quiche/examples/cert.crt")
+                .unwrap();
+            config
+                .load_priv_key_from_pem_file("../quiche/examples/cert.key")
+                .unwrap();
+        }
+
+        config.set_application_protos(b"\x05hello").unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(100_000);
+        config.set_initial_max_stream_data_bidi_remote(100_000);
+        config.set_initial_max_streams_bidi(1);
+        config.set_max_idle_timeout(5_000);
+        config.verify_peer(false);
+
+        config
+    }
+
+    /// A session store shared by its clones, keeping a single origin's
+    /// entries in memory.
+    #[derive(Clone, Default)]
+    struct MemorySessionStore {
+        entries: Arc<Mutex<MemoryEntries>>,
+    }
+
+    #[derive(Default)]
+    struct MemoryEntries {
+        session: Option<Vec<u8>>,
+
+        token: Option<Vec<u8>>,
+    }
+
+    impl quiche::SessionStore for MemorySessionStore {
+        fn get(
+            &self, _origin: &str, kind: quiche::SessionEntryKind,
+        ) -> Option<Vec<u8>> {
+            let entries = self.entries.lock().unwrap();
+
+            match kind {
+                quiche::SessionEntryKind::Session => entries.session.clone(),
+
+                quiche::SessionEntryKind::Token => entries.token.clone(),
+            }
+        }
+
+        fn put(
+            &mut self, _origin: &str, kind: quiche::SessionEntryKind,
+            value: &[u8],
+        ) {
+            let mut entries = self.entries.lock().unwrap();
+
+            match kind {
+                quiche::SessionEntryKind::Session =>
+                    entries.session = Some(value.to_vec()),
+
+                quiche::SessionEntryKind::Token =>
+                    entries.token = Some(value.to_vec()),
+            }
+        }
+    }
+
+    async fn read_all(conn: &AsyncConnection, stream_id: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+
+        loop {
+            let (len, fin) = conn.stream_read(stream_id, &mut buf).await.unwrap();
+
+            data.extend_from_slice(&buf[..len]);
+
+            if fin {
+                return data;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn echo() {
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // Larger than the stream's flow control window, so that both ends
+        // need to wait for updates.
+        let data = vec![42; 300_000];
+
+        let server = async {
+            let conn = AsyncConnection::accept(server_socket, &mut config(true))
+                .await
+                .unwrap();
+
+            let stream_id = conn.readable().await.unwrap();
+            assert_eq!(stream_id, 0);
+
+            let data = read_all(&conn, stream_id).await;
+            conn.stream_write(stream_id, &data, true).await.unwrap();
+
+            // Wait for the client to close the connection.
+            assert!(matches!(conn.readable().await, Err(Error::Closed)));
+        };
+
+        let client = async {
+            let conn = AsyncConnection::connect(
+                Some("quic.tech"),
+                client_socket,
+                server_addr,
+                &mut config(false),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(conn.stream_write(0, &data, true).await.unwrap(), 300_000);
+            assert_eq!(read_all(&conn, 0).await, data);
+
+            conn.close(true, 0x00, b"done").await.unwrap();
+            assert!(conn.with_conn(|c| c.is_closed()));
+        };
+
+        tokio::join!(server, client);
+    }
+
+    #[tokio::test]
+    async fn concurrent_read_write() {
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // The server echoes data as it reads it, so neither side can make
+        // progress unless the client reads while it writes.
+        let data = vec![42; 300_000];
+
+        let server = async {
+            let conn = AsyncConnection::accept(server_socket, &mut config(true))
+                .await
+                .unwrap();
+
+            let mut buf = [0; 4096];
+
+            loop {
+                let (len, fin) = conn.stream_read(0, &mut buf).await.unwrap();
+                conn.stream_write(0, &buf[..len], fin).await.unwrap();
+
+                if fin {
+                    break;
+                }
+            }
+
+            assert!(matches!(conn.readable().await, Err(Error::Closed)));
+        };
+
+        let client = async {
+            let conn = AsyncConnection::connect(
+                Some("quic.tech"),
+                client_socket,
+                server_addr,
+                &mut config(false),
+            )
+            .await
+            .unwrap();
+
+            let write = conn.stream_write(0, &data, true);
+            let read = read_all(&conn, 0);
+
+            let (written, echoed) = tokio::join!(write, read);
+
+            assert_eq!(written.unwrap(), 300_000);
+            assert_eq!(echoed, data);
+
+            conn.close(true, 0x00, b"done").await.unwrap();
+        };
+
+        tokio::join!(server, client);
+    }
+
+    #[tokio::test]
+    #[cfg(not(feature = "rustls"))]
+    async fn resume_with_session_store() {
+        let store = MemorySessionStore::default();
+
+        // Session tickets are only valid for the server that issued them.
+        let mut server_config = config(true);
+
+        for &resumed in &[false, true] {
+            let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let server_addr = server_socket.local_addr().unwrap();
+
+            let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+            let server = async {
+                let conn =
+                    AsyncConnection::accept(server_socket, &mut server_config)
+                        .await
+                        .unwrap();
+
+                let data = read_all(&conn, 0).await;
+                conn.stream_write(0, &data, true).await.unwrap();
+
+                assert!(matches!(conn.readable().await, Err(Error::Closed)));
+            };
+
+            let client = async {
+                let conn = AsyncConnection::connect_with_session_store(
+                    Some("quic.tech"),
+                    client_socket,
+                    server_addr,
+                    &mut config(false),
+                    "https://quic.tech:4433",
+                    Box::new(store.clone()),
+                )
+                .await
+                .unwrap();
+
+                assert_eq!(conn.with_conn(|c| c.is_resumed()), resumed);
+
+                conn.stream_write(0, b"hello", true).await.unwrap();
+                assert_eq!(read_all(&conn, 0).await, b"hello");
+
+                conn.close(true, 0x00, b"done").await.unwrap();
+            };
+
+            tokio::join!(server, client);
+
+            // The session is saved once the connection is closed.
+            assert!(quiche::SessionStore::get(
+                &store,
+                "https://quic.tech:4433",
+                quiche::SessionEntryKind::Session
+            )
+            .is_some());
+        }
+    }
+}
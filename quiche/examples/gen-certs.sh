@@ -10,6 +10,7 @@ cat cert.crt >> cert-big.crt
 cat cert.crt >> cert-big.crt
 cat cert.crt >> cert-big.crt
 cat cert.crt >> cert-big.crt
+openssl req -new -x509 -batch -sha256 -days 10000 -key cert.key -out cert-alt.crt -subj '/C=GB/CN=quic.tech'
 rm cert.csr
 rm rootca.key
 rm rootca.srl
@@ -257,6 +257,10 @@ fn main() {
                         }
                     },
 
+                    Ok((_stream_id, quiche::h3::Event::Trailers { list })) => {
+                        info!("got response trailers {:?}", list);
+                    },
+
                     Ok((_stream_id, quiche::h3::Event::Finished)) => {
                         info!(
                             "response received in {:?}, closing...",
@@ -281,6 +285,19 @@ fn main() {
                         info!("GOAWAY id={}", goaway_id);
                     },
 
+                    Ok((_, quiche::h3::Event::PriorityUpdate { .. })) => (),
+
+                    Ok((_, quiche::h3::Event::ExtensionFrame { .. })) =>
+                        unreachable!(),
+
+                    Ok((_, quiche::h3::Event::ExtensionStream { .. })) =>
+                        unreachable!(),
+
+                    Ok((_, quiche::h3::Event::RawStream { .. })) =>
+                        unreachable!(),
+
+                    Ok((_, quiche::h3::Event::Settings)) => (),
+
                     Err(quiche::h3::Error::Done) => {
                         break;
                     },
@@ -358,6 +358,9 @@ fn main() {
                             );
                         },
 
+                        Ok((_stream_id, quiche::h3::Event::Trailers { .. })) =>
+                            (),
+
                         Ok((_stream_id, quiche::h3::Event::Finished)) => (),
 
                         Ok((_stream_id, quiche::h3::Event::Reset { .. })) => (),
@@ -366,6 +369,16 @@ fn main() {
 
                         Ok((_goaway_id, quiche::h3::Event::GoAway)) => (),
 
+                        Ok((_, quiche::h3::Event::PriorityUpdate { .. })) => (),
+
+                        Ok((_, quiche::h3::Event::ExtensionFrame { .. })) => (),
+
+                        Ok((_, quiche::h3::Event::ExtensionStream { .. })) => (),
+
+                        Ok((_, quiche::h3::Event::RawStream { .. })) => (),
+
+                        Ok((_, quiche::h3::Event::Settings)) => (),
+
                         Err(quiche::h3::Error::Done) => {
                             break;
                         },
@@ -72,7 +72,7 @@ fn main() {
         debug!("Got stream={} len={}", stream_id, len);
 
         if stream_id == 0 {
-            dec.control(&mut data[..len]).unwrap();
+            dec.control(&data[..len]).unwrap();
             continue;
         }
 
@@ -115,6 +115,12 @@ enum quiche_error {
 
     // Error in congestion control.
     QUICHE_ERR_CONGESTION_CONTROL = -14,
+
+    // The peer violated the local connection ID limit.
+    QUICHE_ERR_ID_LIMIT = -17,
+
+    // No spare connection ID issued by the peer is available.
+    QUICHE_ERR_OUT_OF_IDENTIFIERS = -18,
 };
 
 // Returns a human readable string with the quiche version number.
@@ -138,27 +144,94 @@ int quiche_config_load_cert_chain_from_pem_file(quiche_config *config,
 int quiche_config_load_priv_key_from_pem_file(quiche_config *config,
                                               const char *path);
 
+// Configures an additional certificate chain and private key, used when the
+// client requests the given server name.
+int quiche_config_add_cert_for_name(quiche_config *config, const char *name,
+                                    const char *cert_path,
+                                    const char *key_path);
+
 // Specifies a file where trusted CA certificates are stored for the purposes of certificate verification.
 int quiche_config_load_verify_locations_from_file(quiche_config *config,
                                                   const char *path);
 
+// Specifies a directory where trusted CA certificates are stored for the purposes of certificate verification.
+int quiche_config_load_verify_locations_from_directory(quiche_config *config,
+                                                       const char *path);
+
 // Configures whether to verify the peer's certificate.
 void quiche_config_verify_peer(quiche_config *config, bool v);
 
+// Configures whether servers require clients to present a certificate.
+void quiche_config_verify_peer_required_client_cert(quiche_config *config,
+                                                    bool v);
+
 // Configures whether to send GREASE.
 void quiche_config_grease(quiche_config *config, bool v);
 
+// Configures whether to send GREASE transport parameters.
+void quiche_config_grease_transport_params(quiche_config *config, bool v);
+
+// Configures whether to list a reserved version in Version Negotiation packets.
+void quiche_config_grease_versions(quiche_config *config, bool v);
+
+// Sets the index used to pick the GREASE values that are sent.
+void quiche_config_set_grease_index(quiche_config *config, uint64_t index);
+
+// Sets the key used to encrypt and decrypt session tickets.
+// Not available when quiche is built with rustls.
+int quiche_config_set_ticket_key(quiche_config *config,
+                                 const uint8_t *key, size_t key_len);
+
+// Sets the list of QUIC versions that can be negotiated, in order of
+// preference.
+int quiche_config_set_supported_versions(quiche_config *config,
+                                         const uint32_t *versions,
+                                         size_t versions_len);
+
 // Enables logging of secrets.
 void quiche_config_log_keys(quiche_config *config);
 
 // Enables sending or receiving early data.
 void quiche_config_enable_early_data(quiche_config *config);
 
+// Configures the Encrypted Client Hello configurations used by clients.
+// Not available when quiche is built with rustls.
+int quiche_config_set_ech_config_list(quiche_config *config,
+                                      const uint8_t *list, size_t list_len);
+
+// Adds a key used by servers to decrypt Encrypted Client Hello.
+// Not available when quiche is built with rustls.
+int quiche_config_add_ech_key(quiche_config *config,
+                              const uint8_t *ech_config, size_t ech_config_len,
+                              const uint8_t *private_key,
+                              size_t private_key_len, bool is_retry_config);
+
 // Configures the list of supported application protocols.
 int quiche_config_set_application_protos(quiche_config *config,
                                          const uint8_t *protos,
                                          size_t protos_len);
 
+// Sets the callback servers use to choose the application protocol. It
+// receives the requested server name (NULL if none) and the client's offered
+// protocols in wire format, and returns the index of the selected protocol
+// in that list, or a negative value to not negotiate any. Not available when
+// quiche is built with rustls.
+int quiche_config_set_alpn_selector(quiche_config *config,
+                                    int (*cb)(const uint8_t *server_name,
+                                              size_t server_name_len,
+                                              const uint8_t *protos,
+                                              size_t protos_len,
+                                              void *argp),
+                                    void *argp);
+
+// Adds application-layer protocol settings (ALPS) to send when the given ALPN
+// protocol is negotiated. Not available when quiche is built with rustls.
+int quiche_config_add_application_settings(quiche_config *config,
+                                           const uint8_t *proto,
+                                           size_t proto_len,
+                                           const uint8_t *settings,
+                                           size_t settings_len);
+
 // Sets the `max_idle_timeout` transport parameter, in milliseconds, default is
 // no timeout.
 void quiche_config_set_max_idle_timeout(quiche_config *config, uint64_t v);
@@ -196,6 +269,10 @@ void quiche_config_set_max_ack_delay(quiche_config *config, uint64_t v);
 // Sets the `disable_active_migration` transport parameter.
 void quiche_config_set_disable_active_migration(quiche_config *config, bool v);
 
+// Sets the `active_connection_id_limit` transport parameter.
+void quiche_config_set_active_connection_id_limit(quiche_config *config,
+                                                  uint64_t v);
+
 enum quiche_cc_algorithm {
     QUICHE_CC_RENO = 0,
     QUICHE_CC_CUBIC = 1,
@@ -204,20 +281,111 @@ enum quiche_cc_algorithm {
 // Sets the congestion control algorithm used.
 void quiche_config_set_cc_algorithm(quiche_config *config, enum quiche_cc_algorithm algo);
 
+// Configures the OCSP response stapled by servers to their certificate.
+int quiche_config_set_ocsp_response(quiche_config *config,
+                                    const uint8_t *response,
+                                    size_t response_len);
+
+// Configures the serialized SCT list sent by servers with their certificate.
+int quiche_config_set_sct_list(quiche_config *config, const uint8_t *list,
+                               size_t list_len);
+
+// Configures whether clients request an OCSP response from the server.
+void quiche_config_enable_ocsp_stapling(quiche_config *config);
+
+// Configures whether clients request signed certificate timestamps.
+void quiche_config_enable_signed_cert_timestamps(quiche_config *config);
+
+// Sets the callback clients use to check the server's certificate status. It
+// receives the server's DER certificate chain, starting with the leaf, and
+// the OCSP response and SCT list sent by the server (NULL if none), and
+// returns false to fail the handshake.
+void quiche_config_set_cert_status_verifier(quiche_config *config,
+                                            bool (*cb)(const uint8_t *const *certs,
+                                                       const size_t *certs_len,
+                                                       size_t certs_count,
+                                                       const uint8_t *ocsp_response,
+                                                       size_t ocsp_response_len,
+                                                       const uint8_t *sct_list,
+                                                       size_t sct_list_len,
+                                                       void *argp),
+                                            void *argp);
+
 // Configures whether to use HyStart++.
 void quiche_config_enable_hystart(quiche_config *config, bool v);
 
+// Configures whether to queue transport events for quiche_conn_poll_event().
+void quiche_config_enable_events(quiche_config *config, bool v);
+
+// Sets the maximum number of transport events waiting to be polled.
+void quiche_config_set_max_events(quiche_config *config, size_t v);
+
+// Sets the DSCP value outgoing packets should be marked with.
+void quiche_config_set_dscp(quiche_config *config, uint8_t v);
+
+// Sets the packet reordering threshold used for loss detection.
+void quiche_config_set_packet_threshold(quiche_config *config, uint64_t v);
+
+// Sets the time reordering threshold used for loss detection, as a multiple
+// of the RTT.
+void quiche_config_set_time_threshold(quiche_config *config, double v);
+
+// Sets the maximum number of times the probe timeout is doubled.
+void quiche_config_set_max_pto_backoff(quiche_config *config, uint32_t v);
+
+// Sets the RTT assumed before the first RTT sample, in milliseconds.
+void quiche_config_set_initial_rtt(quiche_config *config, uint64_t v);
+
+// Sets the maximum number of buffers kept for reuse when building packets.
+void quiche_config_set_buffer_pool_size(quiche_config *config, size_t v);
+
 // Configures whether to enable receiving DATAGRAM frames.
 void quiche_config_enable_dgram(quiche_config *config, bool enabled,
                                 size_t recv_queue_len,
                                 size_t send_queue_len);
 
+enum quiche_dgram_send_order {
+    QUICHE_DGRAM_SEND_ROUND_ROBIN = 0,
+    QUICHE_DGRAM_SEND_DATAGRAM_FIRST = 1,
+    QUICHE_DGRAM_SEND_STREAM_FIRST = 2,
+};
+
+// Sets the order in which DATAGRAM frames and stream data are sent.
+void quiche_config_set_dgram_send_order(quiche_config *config,
+                                        enum quiche_dgram_send_order order);
+
+// Sets the number of consecutive DATAGRAM packets sent in round-robin order.
+void quiche_config_set_dgram_send_weight(quiche_config *config, size_t v);
+
+// Sets how long received DATAGRAMs are kept, in milliseconds.
+void quiche_config_set_dgram_recv_ttl(quiche_config *config, uint64_t v);
+
 // Sets the maximum connection window.
 void quiche_config_set_max_connection_window(quiche_config *config, uint64_t v);
 
 // Sets the maximum stream window.
 void quiche_config_set_max_stream_window(quiche_config *config, uint64_t v);
 
+enum quiche_stream_credit_policy {
+    QUICHE_STREAM_CREDIT_REPLENISH = 0,
+    QUICHE_STREAM_CREDIT_FIXED = 1,
+    QUICHE_STREAM_CREDIT_MANUAL = 2,
+};
+
+// Sets how stream credits are given back to the peer as its streams close.
+void quiche_config_set_stream_credit_policy(
+    quiche_config *config, enum quiche_stream_credit_policy policy);
+
+// Sets the maximum amount of data a connection can buffer, in bytes.
+void quiche_config_set_max_buffered_bytes(quiche_config *config, size_t v);
+
+// Sets the maximum number of packets buffered before their keys are available.
+void quiche_config_set_max_undecryptable_packets(quiche_config *config,
+                                                 size_t v);
+
+// Sets how many statistics snapshots are kept in the connection's history.
+void quiche_config_set_stats_history_len(quiche_config *config, size_t v);
+
 // Frees the config object.
 void quiche_config_free(quiche_config *config);
 
@@ -256,9 +424,18 @@ ssize_t quiche_retry(const uint8_t *scid, size_t scid_len,
                      const uint8_t *token, size_t token_len,
                      uint32_t version, uint8_t *out, size_t out_len);
 
+// Writes an Initial packet refusing the connection with the given error code.
+ssize_t quiche_refuse(const uint8_t *scid, size_t scid_len,
+                      const uint8_t *dcid, size_t dcid_len,
+                      const uint8_t *new_scid, size_t new_scid_len,
+                      uint64_t error_code, uint32_t version,
+                      uint8_t *out, size_t out_len);
+
 // Returns true if the given protocol version is supported.
 bool quiche_version_is_supported(uint32_t version);
 
+// Creates a new connection using the given BoringSSL `SSL` object. Not
+// available when quiche is built with rustls.
 quiche_conn *quiche_conn_new_with_tls(const uint8_t *scid, size_t scid_len,
                                       const uint8_t *odcid, size_t odcid_len,
                                       const struct sockaddr *peer, size_t peer_len,
@@ -271,6 +448,30 @@ bool quiche_conn_set_keylog_path(quiche_conn *conn, const char *path);
 // Enables keylog to the specified file descriptor. Unix only.
 void quiche_conn_set_keylog_fd(quiche_conn *conn, int fd);
 
+// Sets the DSCP value the connection's outgoing packets should be marked with.
+void quiche_conn_set_dscp(quiche_conn *conn, uint8_t v);
+
+enum quiche_stream_scheduler {
+    QUICHE_STREAM_SCHEDULER_PRIORITY = 0,
+    QUICHE_STREAM_SCHEDULER_FIFO = 1,
+    QUICHE_STREAM_SCHEDULER_ROUND_ROBIN = 2,
+};
+
+// Sets the scheduler deciding which stream's data is sent next.
+void quiche_conn_set_stream_scheduler(quiche_conn *conn,
+                                      enum quiche_stream_scheduler scheduler);
+
+// Sets the callback receiving every datagram sent and received by the
+// connection, as it appears on the wire. `sent` is false for received
+// datagrams.
+void quiche_conn_set_packet_tap(quiche_conn *conn,
+                                void (*cb)(bool sent, const struct timespec *at,
+                                           const struct sockaddr *peer,
+                                           socklen_t peer_len,
+                                           const uint8_t *data, size_t data_len,
+                                           void *argp),
+                                void *argp);
+
 // Enables qlog to the specified file path. Returns true on success.
 bool quiche_conn_set_qlog_path(quiche_conn *conn, const char *path,
                           const char *log_title, const char *log_desc);
@@ -279,9 +480,38 @@ bool quiche_conn_set_qlog_path(quiche_conn *conn, const char *path,
 void quiche_conn_set_qlog_fd(quiche_conn *conn, int fd, const char *log_title,
                              const char *log_desc);
 
+enum quiche_qlog_format {
+    // A single JSON document, completed when the connection is closed.
+    QUICHE_QLOG_FORMAT_JSON = 0,
+
+    // JSON text sequences, with each event written as a separate record.
+    QUICHE_QLOG_FORMAT_JSON_SEQ = 1,
+};
+
+// Enables qlog to the specified file path, using the given format. Returns
+// true on success.
+bool quiche_conn_set_qlog_path_with_format(quiche_conn *conn, const char *path,
+                                           const char *log_title,
+                                           const char *log_desc,
+                                           enum quiche_qlog_format format);
+
+// Enables qlog to the specified file descriptor, using the given format. Unix
+// only.
+void quiche_conn_set_qlog_fd_with_format(quiche_conn *conn, int fd,
+                                         const char *log_title,
+                                         const char *log_desc,
+                                         enum quiche_qlog_format format);
+
 // Configures the given session for resumption.
+// Not available when quiche is built with rustls.
 int quiche_conn_set_session(quiche_conn *conn, const uint8_t *buf, size_t buf_len);
 
+// Sets the address validation token sent in the client's Initial packets.
+int quiche_conn_set_token(quiche_conn *conn, const uint8_t *buf, size_t buf_len);
+
+// Schedules a NEW_TOKEN frame carrying the given token to be sent to the client.
+int quiche_conn_send_new_token(quiche_conn *conn, const uint8_t *buf, size_t buf_len);
+
 typedef struct {
     struct sockaddr *from;
     socklen_t from_len;
@@ -304,10 +534,47 @@ typedef struct {
 ssize_t quiche_conn_send(quiche_conn *conn, uint8_t *out, size_t out_len,
                          quiche_send_info *out_info);
 
+// Additional information about outgoing packets. Like the other `_ext`
+// structures, it's versioned by its leading `size` field, which must be set to
+// `sizeof(quiche_send_info_ext)` by the application.
+typedef struct {
+    // The size of this structure, set by the application.
+    size_t size;
+
+    // The local address the packet should be sent from, only set
+    // (`from_len` is not 0) for packets probing a path.
+    struct sockaddr_storage from;
+    socklen_t from_len;
+
+    // The DSCP value the packet should be marked with.
+    uint8_t dscp;
+} quiche_send_info_ext;
+
+// Like `quiche_conn_send()`, but also fills `out_ext`, unless it's NULL.
+ssize_t quiche_conn_send_ext(quiche_conn *conn, uint8_t *out, size_t out_len,
+                             quiche_send_info *out_info,
+                             quiche_send_info_ext *out_ext);
+
+// Writes a burst of QUIC packets to be sent to the peer with UDP GSO, each in
+// its own `segment_size` bytes slot. Only the last one can be shorter.
+// `out_ext` is filled unless it's NULL.
+ssize_t quiche_conn_send_burst(quiche_conn *conn, uint8_t *out, size_t out_len,
+                               size_t segment_size,
+                               quiche_send_info *out_info,
+                               quiche_send_info_ext *out_ext);
+
 // Reads contiguous data from a stream.
 ssize_t quiche_conn_stream_recv(quiche_conn *conn, uint64_t stream_id,
                                 uint8_t *out, size_t buf_len, bool *fin);
 
+// Reads contiguous data from a stream without consuming it.
+ssize_t quiche_conn_stream_peek(quiche_conn *conn, uint64_t stream_id,
+                                uint8_t *out, size_t buf_len, bool *fin);
+
+// Discards contiguous data from a stream.
+ssize_t quiche_conn_stream_consume(quiche_conn *conn, uint64_t stream_id,
+                                   size_t len, bool *fin);
+
 // Writes data to a stream.
 ssize_t quiche_conn_stream_send(quiche_conn *conn, uint64_t stream_id,
                                 const uint8_t *buf, size_t buf_len, bool fin);
@@ -321,6 +588,11 @@ enum quiche_shutdown {
 int quiche_conn_stream_priority(quiche_conn *conn, uint64_t stream_id,
                                 uint8_t urgency, bool incremental);
 
+// Sets a deadline, `timeout_ms` milliseconds from now, after which the
+// stream is reset and stopped with the given error code if it isn't complete.
+int quiche_conn_stream_set_deadline(quiche_conn *conn, uint64_t stream_id,
+                                    uint64_t timeout_ms, uint64_t err);
+
 // Shuts down reading or writing from/to the specified stream.
 int quiche_conn_stream_shutdown(quiche_conn *conn, uint64_t stream_id,
                                 enum quiche_shutdown direction, uint64_t err);
@@ -343,15 +615,68 @@ quiche_stream_iter *quiche_conn_writable(quiche_conn *conn);
 // Returns the maximum possible size of egress UDP payloads.
 size_t quiche_conn_max_send_udp_payload_size(quiche_conn *conn);
 
+// Returns the recommended number of bytes to send in a single burst.
+size_t quiche_conn_send_quantum(quiche_conn *conn);
+
 // Returns the amount of time until the next timeout event, in nanoseconds.
 uint64_t quiche_conn_timeout_as_nanos(quiche_conn *conn);
 
 // Returns the amount of time until the next timeout event, in milliseconds.
 uint64_t quiche_conn_timeout_as_millis(quiche_conn *conn);
 
+enum quiche_timeout_reason {
+    QUICHE_TIMEOUT_DRAINING = 0,
+    QUICHE_TIMEOUT_IDLE = 1,
+    QUICHE_TIMEOUT_KEEP_ALIVE = 2,
+    QUICHE_TIMEOUT_LOSS_DETECTION = 3,
+    QUICHE_TIMEOUT_PACING = 4,
+    QUICHE_TIMEOUT_PATH_VALIDATION = 5,
+    QUICHE_TIMEOUT_STREAM_DEADLINE = 6,
+    QUICHE_TIMEOUT_SHUTDOWN = 7,
+};
+
+// Returns the earliest deadline of the connection and the reason for it.
+// Returns QUICHE_ERR_DONE if there is none.
+int quiche_conn_timeout_instant(quiche_conn *conn, struct timespec *out,
+                                enum quiche_timeout_reason *reason);
+
 // Processes a timeout event.
 void quiche_conn_on_timeout(quiche_conn *conn);
 
+// Callbacks invoked from quiche_conn_recv() and quiche_conn_on_timeout(), as
+// an alternative to polling the connection for events after every packet.
+// on_handshake_done and on_closed can also be invoked from quiche_conn_close()
+// and the quiche_conn_send() family, and are invoked exactly once whichever
+// call caused the change. Callbacks can be NULL, and are allowed to call back
+// into the connection (e.g. to read from the stream they are reporting).
+typedef struct {
+    // Must be set to `sizeof(quiche_conn_callbacks)`. Callbacks added in
+    // later versions of this structure are left unset for applications built
+    // against older versions of this header.
+    size_t size;
+
+    // Called for each stream that has data to read. Streams that are not
+    // fully read are reported again after the next packet.
+    void (*on_stream_readable)(quiche_conn *conn, uint64_t stream_id,
+                               void *argp);
+
+    // Called when there are DATAGRAMs waiting to be read.
+    void (*on_dgram)(quiche_conn *conn, void *argp);
+
+    // Called once the handshake completes after the callbacks were registered.
+    void (*on_handshake_done)(quiche_conn *conn, void *argp);
+
+    // Called once the connection is closed after the callbacks were registered.
+    void (*on_closed)(quiche_conn *conn, void *argp);
+} quiche_conn_callbacks;
+
+// Registers event callbacks on the connection, replacing any previously
+// registered ones. The structure is copied, and `argp` is passed to every
+// callback. Passing NULL unregisters the callbacks. Returns false, leaving the
+// current callbacks in place, if `size` is too small for the structure.
+bool quiche_conn_set_callbacks(quiche_conn *conn,
+                               const quiche_conn_callbacks *cbs, void *argp);
+
 // Closes the connection with the given error and reason.
 int quiche_conn_close(quiche_conn *conn, bool app, uint64_t err,
                       const uint8_t *reason, size_t reason_len);
@@ -369,12 +694,63 @@ void quiche_conn_destination_id(quiche_conn *conn, const uint8_t **out, size_t *
 void quiche_conn_application_proto(quiche_conn *conn, const uint8_t **out,
                                    size_t *out_len);
 
+// Returns the ALPN protocols offered by the client, in wire format. Only set
+// on the server.
+void quiche_conn_peer_application_protos(quiche_conn *conn,
+                                         const uint8_t **out, size_t *out_len);
+
+// Writes the standard name of the negotiated TLS cipher suite to `out`, not
+// NUL-terminated. Returns the name's length, or QUICHE_ERR_DONE if none.
+ssize_t quiche_conn_cipher_suite(quiche_conn *conn, uint8_t *out,
+                                 size_t out_len);
+
+// Writes the name of the negotiated TLS key exchange group to `out`, not
+// NUL-terminated. Returns the name's length, or QUICHE_ERR_DONE if none.
+ssize_t quiche_conn_key_exchange_group(quiche_conn *conn, uint8_t *out,
+                                       size_t out_len);
+
+// Writes the name of the peer's handshake signature algorithm to `out`, not
+// NUL-terminated. Returns the name's length, or QUICHE_ERR_DONE if none.
+ssize_t quiche_conn_peer_signature_algorithm(quiche_conn *conn, uint8_t *out,
+                                             size_t out_len);
+
+// Copies the OCSP response stapled by the server to `out`. Returns its
+// length, or QUICHE_ERR_DONE if none.
+ssize_t quiche_conn_ocsp_response(quiche_conn *conn, uint8_t *out,
+                                  size_t out_len);
+
+// Copies the serialized SCT list sent by the server to `out`. Returns its
+// length, or QUICHE_ERR_DONE if none.
+ssize_t quiche_conn_sct_list(quiche_conn *conn, uint8_t *out, size_t out_len);
+
+// Writes `out_len` bytes of keying material exported from the TLS session
+// with the given label and context to `out`. Returns QUICHE_ERR_DONE if the
+// handshake is not completed yet.
+int quiche_conn_export_keying_material(quiche_conn *conn,
+                                       const uint8_t *label, size_t label_len,
+                                       const uint8_t *context,
+                                       size_t context_len,
+                                       uint8_t *out, size_t out_len);
+
 // Returns the peer's leaf certificate (if any) as a DER-encoded buffer.
 void quiche_conn_peer_cert(quiche_conn *conn, const uint8_t **out, size_t *out_len);
 
 // Returns the serialized cryptographic session for the connection.
 void quiche_conn_session(quiche_conn *conn, const uint8_t **out, size_t *out_len);
 
+// Returns the latest address validation token received in a NEW_TOKEN frame.
+void quiche_conn_new_token(const quiche_conn *conn, const uint8_t **out,
+                           size_t *out_len);
+
+enum quiche_token_kind {
+    QUICHE_TOKEN_KIND_RETRY = 0,
+    QUICHE_TOKEN_KIND_NEW_TOKEN = 1,
+};
+
+// Returns the kind of the address validation token sent in the client's
+// Initial packets, as a quiche_token_kind, or QUICHE_ERR_DONE if there is none.
+int quiche_conn_token_kind(const quiche_conn *conn);
+
 // Returns true if the connection handshake is complete.
 bool quiche_conn_is_established(quiche_conn *conn);
 
@@ -382,12 +758,32 @@ bool quiche_conn_is_established(quiche_conn *conn);
 // enough to send or receive early data.
 bool quiche_conn_is_in_early_data(quiche_conn *conn);
 
+// Returns true if the early data sent by the client was accepted by the
+// server.
+bool quiche_conn_is_early_data_accepted(const quiche_conn *conn);
+
+// Returns true if the connection is resumed.
+bool quiche_conn_is_resumed(quiche_conn *conn);
+
+// Returns true if the Encrypted Client Hello was accepted by the server.
+bool quiche_conn_is_ech_accepted(quiche_conn *conn);
+
+// Returns the application-layer protocol settings (ALPS) received from the
+// peer. Returns false if none were received.
+bool quiche_conn_peer_application_settings(quiche_conn *conn,
+                                           const uint8_t **out,
+                                           size_t *out_len);
+
 // Returns whether there is stream or DATAGRAM data available to read.
 bool quiche_conn_is_readable(quiche_conn *conn);
 
 // Returns true if the connection is draining.
 bool quiche_conn_is_draining(quiche_conn *conn);
 
+// Returns the amount of time left before a draining connection is closed, in
+// nanoseconds, or UINT64_MAX if the connection is not draining.
+uint64_t quiche_conn_draining_timeout_as_nanos(quiche_conn *conn);
+
 // Returns the number of bidirectional streams that can be created
 // before the peer's stream count limit is reached.
 uint64_t quiche_conn_peer_streams_left_bidi(quiche_conn *conn);
@@ -396,6 +792,9 @@ uint64_t quiche_conn_peer_streams_left_bidi(quiche_conn *conn);
 // before the peer's stream count limit is reached.
 uint64_t quiche_conn_peer_streams_left_uni(quiche_conn *conn);
 
+// Allows the peer to open `n` more bidirectional or unidirectional streams.
+int quiche_conn_grant_streams(quiche_conn *conn, bool bidi, uint64_t n);
+
 // Returns true if the connection is closed.
 bool quiche_conn_is_closed(quiche_conn *conn);
 
@@ -410,6 +809,10 @@ bool quiche_conn_peer_error(quiche_conn *conn,
                             const uint8_t **reason,
                             size_t *reason_len);
 
+// Returns true if a transport connection error was received, and sets
+// `frame_type` to the type of the frame that triggered it.
+bool quiche_conn_peer_error_frame_type(quiche_conn *conn, uint64_t *frame_type);
+
 // Returns true if a connection error was queued or sent, and updates the provided
 // parameters accordingly.
 bool quiche_conn_local_error(quiche_conn *conn,
@@ -518,6 +921,200 @@ typedef struct {
 // Collects and returns statistics about the connection.
 void quiche_conn_stats(quiche_conn *conn, quiche_stats *out);
 
+// The structures below are versioned by their leading `size` field, which
+// must be set to the size of the structure (e.g. `sizeof(quiche_stats_ext)`)
+// before they are passed to quiche. New fields are only ever appended, and
+// quiche never writes past `size` bytes, so applications built against older
+// versions of this header keep working.
+
+typedef struct {
+    // The size of this structure, set by the application.
+    size_t size;
+
+    // The number of times sending was restricted by the anti-amplification
+    // limit.
+    size_t amplification_limited;
+
+    // The number of outgoing DATAGRAMs dropped because they expired.
+    size_t dgram_sent_dropped_expired;
+
+    // The number of received DATAGRAMs dropped because they expired.
+    size_t dgram_recv_dropped_expired;
+
+    // The total size of the DATAGRAMs waiting to be sent, in bytes.
+    size_t dgram_send_queue_bytes;
+
+    // The total size of the received DATAGRAMs waiting to be read, in bytes.
+    size_t dgram_recv_queue_bytes;
+
+    // The current pacing rate in bytes/s.
+    uint64_t pacing_rate;
+
+    // The current slow start threshold in bytes.
+    size_t ssthresh;
+
+    // The state of the congestion controller: 0 for slow start, 1 for
+    // congestion avoidance and 2 for recovery.
+    int cc_state;
+
+    // The number of congestion recovery episodes.
+    size_t congestion_events;
+
+    // The number of probe timeouts (PTO) that fired.
+    size_t pto;
+
+    // The number of packets declared lost that were later acked.
+    size_t spurious_lost;
+
+    // The number of times sending became blocked by the peer's
+    // connection-level flow control limit, and the total time spent blocked
+    // in nanoseconds.
+    size_t data_blocked;
+    uint64_t data_blocked_time;
+
+    // The number of times sending on a stream became blocked by the peer's
+    // stream-level flow control limit, and the total time spent blocked in
+    // nanoseconds, summed over streams.
+    size_t stream_data_blocked;
+    uint64_t stream_data_blocked_time;
+
+    // The number of DATA_BLOCKED and STREAM_DATA_BLOCKED frames received.
+    size_t peer_data_blocked;
+    size_t peer_stream_data_blocked;
+
+    // The amount of data currently buffered by the connection.
+    size_t buffered_bytes;
+
+    // The amount of memory held by the connection's buffers.
+    size_t retained_bytes;
+
+    // The number of transport events dropped because too many were waiting
+    // to be polled.
+    size_t events_dropped;
+} quiche_stats_ext;
+
+// Collects and returns the statistics that are not part of `quiche_stats`.
+void quiche_conn_stats_ext(quiche_conn *conn, quiche_stats_ext *out);
+
+// Releases memory held by the connection that is not currently needed.
+void quiche_conn_compact(quiche_conn *conn);
+
+typedef struct {
+    // The size of this structure, set by the application.
+    size_t size;
+
+    // The address of the peer on this path.
+    struct sockaddr_storage peer_addr;
+    socklen_t peer_addr_len;
+
+    // Whether this is the path currently used to send packets.
+    bool active;
+
+    // Whether the peer's address on this path was validated.
+    bool validated;
+
+    // The estimated round-trip time of the path, in nanoseconds.
+    uint64_t rtt;
+
+    // The estimated round-trip time variation, in nanoseconds.
+    uint64_t rttvar;
+
+    // The minimum round-trip time observed, in nanoseconds.
+    uint64_t min_rtt;
+
+    // The size of the path's congestion window in bytes.
+    size_t cwnd;
+
+    // The number of bytes in flight on the path.
+    size_t bytes_in_flight;
+
+    // The current PMTU of the path.
+    size_t pmtu;
+} quiche_path_stats;
+
+// Collects and returns statistics about the path at index `idx`. Returns
+// QUICHE_ERR_DONE if there is no such path.
+int quiche_conn_path_stats(quiche_conn *conn, size_t idx,
+                           quiche_path_stats *out);
+
+// Returns the congestion window and minimum RTT (in nanoseconds) of the
+// active path, to seed later connections to the same peer with. Returns
+// QUICHE_ERR_DONE if no RTT sample was taken yet.
+int quiche_conn_resume_state(quiche_conn *conn, size_t *out_cwnd,
+                             uint64_t *out_rtt);
+
+// Seeds the congestion controller with the state saved from a previous
+// connection to `peer`, using Careful Resume.
+int quiche_conn_set_resume_state(quiche_conn *conn,
+                                 const struct sockaddr *peer, socklen_t peer_len,
+                                 size_t cwnd, uint64_t rtt);
+
+// Issues a new source connection ID to the peer, along with its 16 bytes
+// stateless reset token.
+int quiche_conn_new_scid(quiche_conn *conn,
+                         const uint8_t *scid, size_t scid_len,
+                         const uint8_t *reset_token, uint64_t *scid_seq);
+
+// Writes the next source connection ID retired by the peer to `out`, which
+// must be at least QUICHE_MAX_CONN_ID_LEN bytes long, and returns its length.
+// Returns QUICHE_ERR_DONE if there is none.
+ssize_t quiche_conn_retired_scid_next(quiche_conn *conn, uint8_t *out,
+                                      size_t out_len);
+
+// Returns the number of spare connection IDs issued by the peer.
+size_t quiche_conn_available_dcids(quiche_conn *conn);
+
+// Starts validating the path between `local` and `peer` without migrating
+// the connection to it.
+int quiche_conn_probe_path(quiche_conn *conn,
+                           const struct sockaddr *local, socklen_t local_len,
+                           const struct sockaddr *peer, socklen_t peer_len,
+                           uint64_t *dcid_seq);
+
+enum quiche_path_event_type {
+    QUICHE_PATH_EVENT_VALIDATED = 0,
+    QUICHE_PATH_EVENT_FAILED_VALIDATION = 1,
+    QUICHE_PATH_EVENT_PEER_MIGRATED = 2,
+    QUICHE_PATH_EVENT_PEER_MIGRATION_FAILED = 3,
+};
+
+// Returns the next path validation outcome. Returns QUICHE_ERR_DONE if there
+// is none. For QUICHE_PATH_EVENT_PEER_MIGRATED and
+// QUICHE_PATH_EVENT_PEER_MIGRATION_FAILED, `local` is set to the client's
+// previous address and `peer` to its new one.
+int quiche_conn_path_event_next(quiche_conn *conn,
+                                enum quiche_path_event_type *out_type,
+                                struct sockaddr_storage *local,
+                                socklen_t *local_len,
+                                struct sockaddr_storage *peer,
+                                socklen_t *peer_len);
+
+enum quiche_event_type {
+    QUICHE_EVENT_HANDSHAKE_COMPLETED = 0,
+    QUICHE_EVENT_STREAMS_AVAILABLE = 1,
+    QUICHE_EVENT_STREAM_RESET = 2,
+    QUICHE_EVENT_STOP_SENDING = 3,
+    QUICHE_EVENT_MAX_DATA_UPDATED = 4,
+    QUICHE_EVENT_NEW_TOKEN = 5,
+    QUICHE_EVENT_PATH = 6,
+    QUICHE_EVENT_PEER_CLOSED = 7,
+    QUICHE_EVENT_STREAM_DEADLINE_EXPIRED = 8,
+    QUICHE_EVENT_DATA_BLOCKED = 9,
+    QUICHE_EVENT_STREAM_DATA_BLOCKED = 10,
+    QUICHE_EVENT_PEER_DATA_BLOCKED = 11,
+    QUICHE_EVENT_PEER_STREAM_DATA_BLOCKED = 12,
+    QUICHE_EVENT_VERSION_NEGOTIATED = 13,
+};
+
+// Returns the next transport event, if enabled. Returns QUICHE_ERR_DONE if
+// there is none. For stream events, `stream_id` and `value` are set to the
+// stream ID and error code. For QUICHE_EVENT_MAX_DATA_UPDATED and the blocked
+// events, `value` is set to the limit. For QUICHE_EVENT_VERSION_NEGOTIATED,
+// `value` is set to the new version. Path events are retrieved separately with
+// quiche_conn_path_event_next().
+int quiche_conn_poll_event(quiche_conn *conn, enum quiche_event_type *out_type,
+                           uint64_t *stream_id, uint64_t *value);
+
 // Returns the maximum DATAGRAM payload that can be sent.
 ssize_t quiche_conn_dgram_max_writable_len(quiche_conn *conn);
 
@@ -544,6 +1141,34 @@ ssize_t quiche_conn_dgram_recv(quiche_conn *conn, uint8_t *buf,
 ssize_t quiche_conn_dgram_send(quiche_conn *conn, const uint8_t *buf,
                                size_t buf_len);
 
+// Sends data in a DATAGRAM frame that is dropped if not sent within `ttl`
+// milliseconds.
+ssize_t quiche_conn_dgram_send_with_ttl(quiche_conn *conn, const uint8_t *buf,
+                                        size_t buf_len, uint64_t ttl);
+
+// Copies up to `len` bytes of the first received DATAGRAM into `buf`,
+// without removing it from the queue.
+ssize_t quiche_conn_dgram_recv_peek(quiche_conn *conn, uint8_t *buf,
+                                    size_t buf_len, size_t len);
+
+// Sends data in a DATAGRAM frame on the path between the given local and peer
+// addresses. A NULL `from` refers to the local address the connection's
+// packets are normally sent from.
+ssize_t quiche_conn_dgram_send_on_path(quiche_conn *conn, const uint8_t *buf,
+                                       size_t buf_len,
+                                       const struct sockaddr *from,
+                                       socklen_t from_len,
+                                       const struct sockaddr *to,
+                                       socklen_t to_len);
+
+// Returns the number of items in the DATAGRAM send queue of the path between
+// the given local and peer addresses.
+ssize_t quiche_conn_dgram_send_queue_len_on_path(quiche_conn *conn,
+                                                 const struct sockaddr *from,
+                                                 socklen_t from_len,
+                                                 const struct sockaddr *to,
+                                                 socklen_t to_len);
+
 // Purges queued outgoing DATAGRAMs matching the predicate.
 void quiche_conn_dgram_purge_outgoing(quiche_conn *conn,
                                       bool (*f)(uint8_t *, size_t));
@@ -626,6 +1251,15 @@ enum quiche_h3_error {
     // The requested operation cannot be served over HTTP/3. Peer should retry
     // over HTTP/1.1.
     QUICHE_H3_ERR_VERSION_FALLBACK = -20,
+
+    // An HTTP Datagram or a DATAGRAM capsule was malformed.
+    QUICHE_H3_ERR_DATAGRAM_ERROR = -21,
+
+    // Error on the QPACK encoder stream.
+    QUICHE_H3_ERR_QPACK_ENCODER_STREAM_ERROR = -22,
+
+    // Error on the QPACK decoder stream.
+    QUICHE_H3_ERR_QPACK_DECODER_STREAM_ERROR = -23,
 };
 
 // Stores configuration shared between multiple connections.
@@ -643,6 +1277,25 @@ void quiche_h3_config_set_qpack_max_table_capacity(quiche_h3_config *config, uin
 // Sets the `SETTINGS_QPACK_BLOCKED_STREAMS` setting.
 void quiche_h3_config_set_qpack_blocked_streams(quiche_h3_config *config, uint64_t v);
 
+// Configures whether header names and values are Huffman-encoded.
+void quiche_h3_config_enable_qpack_huffman(quiche_h3_config *config, bool v);
+
+// Configures whether received header sections are strictly validated.
+void quiche_h3_config_enable_strict_header_validation(quiche_h3_config *config,
+                                                      bool v);
+
+// Registers interest in frames of the given extension type.
+void quiche_h3_config_register_extension_frame(quiche_h3_config *config,
+                                               uint64_t ty);
+
+// Registers interest in unidirectional streams of the given extension type.
+void quiche_h3_config_register_extension_stream(quiche_h3_config *config,
+                                                uint64_t ty);
+
+// Registers a raw stream type, whose streams are not processed by HTTP/3.
+void quiche_h3_config_register_raw_stream(quiche_h3_config *config,
+                                          uint64_t ty);
+
 // Frees the HTTP/3 config object.
 void quiche_h3_config_free(quiche_h3_config *config);
 
@@ -664,6 +1317,12 @@ enum quiche_h3_event_type {
     QUICHE_H3_EVENT_DATAGRAM,
     QUICHE_H3_EVENT_GOAWAY,
     QUICHE_H3_EVENT_RESET,
+    QUICHE_H3_EVENT_PRIORITY_UPDATE,
+    QUICHE_H3_EVENT_TRAILERS,
+    QUICHE_H3_EVENT_EXTENSION_FRAME,
+    QUICHE_H3_EVENT_EXTENSION_STREAM,
+    QUICHE_H3_EVENT_RAW_STREAM,
+    QUICHE_H3_EVENT_SETTINGS,
 };
 
 typedef struct Http3Event quiche_h3_event;
@@ -675,7 +1334,8 @@ int64_t quiche_h3_conn_poll(quiche_h3_conn *conn, quiche_conn *quic_conn,
 // Returns the type of the event.
 enum quiche_h3_event_type quiche_h3_event_type(quiche_h3_event *ev);
 
-// Iterates over the headers in the event.
+// Iterates over the headers in the event, which can be of the HEADERS or
+// TRAILERS type.
 //
 // The `cb` callback will be called for each header in `ev`. `cb` should check
 // the validity of pseudo-headers and headers. If `cb` returns any value other
@@ -700,6 +1360,10 @@ int quiche_h3_for_each_setting(quiche_h3_conn *conn,
 // Check whether data will follow the headers on the stream.
 bool quiche_h3_event_headers_has_body(quiche_h3_event *ev);
 
+// Returns the stream ID and the new priority of a PRIORITY_UPDATE event.
+void quiche_h3_event_priority_update(quiche_h3_event *ev, uint64_t *stream_id,
+                                     uint8_t *urgency, bool *incremental);
+
 // Frees the HTTP/3 event object.
 void quiche_h3_event_free(quiche_h3_event *ev);
 
@@ -716,6 +1380,10 @@ int64_t quiche_h3_send_request(quiche_h3_conn *conn, quiche_conn *quic_conn,
                                quiche_h3_header *headers, size_t headers_len,
                                bool fin);
 
+// Opens a raw stream of the given type, not managed by HTTP/3.
+int64_t quiche_h3_open_raw_stream(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                                  uint64_t ty, bool bidi);
+
 // Sends an HTTP/3 response on the specified stream with default priority.
 int quiche_h3_send_response(quiche_h3_conn *conn, quiche_conn *quic_conn,
                             uint64_t stream_id, quiche_h3_header *headers,
@@ -732,10 +1400,24 @@ ssize_t quiche_h3_send_body(quiche_h3_conn *conn, quiche_conn *quic_conn,
                             uint64_t stream_id, uint8_t *body, size_t body_len,
                             bool fin);
 
+// Sends trailers on the given stream, finishing it.
+int quiche_h3_send_trailers(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                            uint64_t stream_id, quiche_h3_header *headers,
+                            size_t headers_len);
+
 // Reads request or response body data into the provided buffer.
 ssize_t quiche_h3_recv_body(quiche_h3_conn *conn, quiche_conn *quic_conn,
                             uint64_t stream_id, uint8_t *out, size_t out_len);
 
+// Sends a PRIORITY_UPDATE frame changing the priority of the given request.
+int quiche_h3_send_priority_update(quiche_h3_conn *conn,
+                                   quiche_conn *quic_conn, uint64_t stream_id,
+                                   uint8_t urgency, bool incremental);
+
+// Sends a GOAWAY frame to initiate graceful connection closure.
+int quiche_h3_send_goaway(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                          uint64_t id);
+
 // Returns whether the peer enabled HTTP/3 DATAGRAM frame support.
 bool quiche_h3_dgram_enabled_by_peer(quiche_h3_conn *conn,
                                      quiche_conn *quic_conn);
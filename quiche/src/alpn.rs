@@ -0,0 +1,45 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+/// A server-side hook choosing the application protocol negotiated via ALPN.
+///
+/// By default servers pick the first protocol offered by the client that is
+/// also in the list configured with [`set_application_protos()`]. Installing
+/// a selector with [`set_alpn_selector()`] replaces that static preference,
+/// allowing the choice to depend on the requested server name, for example.
+///
+/// [`set_application_protos()`]: struct.Config.html#method.set_application_protos
+/// [`set_alpn_selector()`]: struct.Config.html#method.set_alpn_selector
+pub trait AlpnSelector: Send + Sync {
+    /// Picks one of the protocols in `offered`, in the client's order of
+    /// preference, for a connection to `server_name`.
+    ///
+    /// Returning `None`, or a protocol the client didn't offer, means that
+    /// no application protocol is negotiated.
+    fn select<'a>(
+        &self, server_name: Option<&str>, offered: &[&'a [u8]],
+    ) -> Option<&'a [u8]>;
+}
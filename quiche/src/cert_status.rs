@@ -0,0 +1,54 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+/// A client-side hook checking the certificate status information sent by
+/// the server.
+///
+/// quiche doesn't interpret OCSP responses nor signed certificate timestamps
+/// (SCTs) itself. Installing a verifier with [`set_cert_status_verifier()`]
+/// lets clients enforce their own policy, e.g. requiring a valid OCSP
+/// response, before the handshake completes.
+///
+/// [`set_cert_status_verifier()`]: struct.Config.html#method.set_cert_status_verifier
+pub trait CertStatusVerifier: Send + Sync {
+    /// Checks the status of the server's certificate chain.
+    ///
+    /// `cert_chain` is the server's certificate chain in DER format, starting
+    /// with the leaf certificate. `ocsp_response` and `sct_list` are the
+    /// OCSP response and serialized SignedCertificateTimestampList sent by
+    /// the server, if any. They are only requested by clients that enabled
+    /// them with [`enable_ocsp_stapling()`] and
+    /// [`enable_signed_cert_timestamps()`] respectively.
+    ///
+    /// Returning `false` fails the handshake.
+    ///
+    /// [`enable_ocsp_stapling()`]: struct.Config.html#method.enable_ocsp_stapling
+    /// [`enable_signed_cert_timestamps()`]: struct.Config.html#method.enable_signed_cert_timestamps
+    fn verify(
+        &self, cert_chain: &[&[u8]], ocsp_response: Option<&[u8]>,
+        sct_list: Option<&[u8]>,
+    ) -> bool;
+}
@@ -0,0 +1,420 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::Error;
+use crate::Result;
+use crate::MAX_CONN_ID_LEN;
+
+use crate::frame;
+use crate::packet::ConnectionId;
+
+use alloc::collections::VecDeque;
+
+/// A source connection ID issued to the peer.
+struct SourceId {
+    seq: u64,
+    cid: ConnectionId<'static>,
+    reset_token: [u8; 16],
+}
+
+/// A destination connection ID issued by the peer.
+struct DestinationId {
+    seq: u64,
+    cid: ConnectionId<'static>,
+
+    /// Whether the ID is used to probe a path.
+    in_use: bool,
+}
+
+/// Keeps track of the connection IDs issued by both endpoints.
+///
+/// The destination connection ID currently used on the active path is only
+/// referred to by its sequence number, the ID itself is owned by the
+/// connection.
+pub struct ConnectionIdentifiers {
+    /// Source connection IDs not yet retired by the peer.
+    scids: VecDeque<SourceId>,
+
+    next_scid_seq: u64,
+
+    /// Sequence numbers of source connection IDs to advertise.
+    advertise_scids: VecDeque<u64>,
+
+    /// Source connection IDs retired by the peer, not yet reported to the
+    /// application.
+    retired_scids: VecDeque<ConnectionId<'static>>,
+
+    /// Spare and probing destination connection IDs, in sequence order.
+    dcids: VecDeque<DestinationId>,
+
+    active_dcid_seq: u64,
+
+    largest_retire_prior_to: u64,
+
+    /// Sequence numbers of destination connection IDs to retire.
+    retire_dcids: VecDeque<u64>,
+
+    /// Maximum number of destination connection IDs to keep, including the
+    /// active one.
+    dcid_limit: usize,
+}
+
+impl ConnectionIdentifiers {
+    pub fn new(scid: &ConnectionId, dcid_limit: u64) -> Self {
+        let initial = SourceId {
+            seq: 0,
+            cid: scid.to_vec().into(),
+            reset_token: [0; 16],
+        };
+
+        ConnectionIdentifiers {
+            scids: VecDeque::from(vec![initial]),
+            next_scid_seq: 1,
+            advertise_scids: VecDeque::new(),
+            retired_scids: VecDeque::new(),
+
+            dcids: VecDeque::new(),
+            active_dcid_seq: 0,
+            largest_retire_prior_to: 0,
+            retire_dcids: VecDeque::new(),
+            dcid_limit: dcid_limit as usize,
+        }
+    }
+
+    /// Issues a new source connection ID, returning its sequence number.
+    ///
+    /// Issuing an ID that was already issued returns the existing sequence
+    /// number.
+    pub fn new_scid(
+        &mut self, cid: ConnectionId<'static>, reset_token: u128, peer_limit: u64,
+    ) -> Result<u64> {
+        if cid.is_empty() || cid.len() > MAX_CONN_ID_LEN {
+            return Err(Error::InvalidState);
+        }
+
+        if let Some(e) = self.scids.iter().find(|e| e.cid == cid) {
+            return Ok(e.seq);
+        }
+
+        if self.scids.len() as u64 >= peer_limit {
+            return Err(Error::IdLimit);
+        }
+
+        let seq = self.next_scid_seq;
+
+        self.scids.push_back(SourceId {
+            seq,
+            cid,
+            reset_token: reset_token.to_be_bytes(),
+        });
+
+        self.next_scid_seq += 1;
+
+        self.advertise_scids.push_back(seq);
+
+        Ok(seq)
+    }
+
+    /// Returns the next NEW_CONNECTION_ID frame to send, if any.
+    pub fn next_new_conn_id(&mut self) -> Option<frame::Frame> {
+        while let Some(&seq) = self.advertise_scids.front() {
+            if let Some(e) = self.scids.iter().find(|e| e.seq == seq) {
+                return Some(frame::Frame::NewConnectionId {
+                    seq_num: e.seq,
+                    retire_prior_to: 0,
+                    conn_id: e.cid.to_vec(),
+                    reset_token: e.reset_token,
+                });
+            }
+
+            // The peer retired the ID before it was advertised.
+            self.advertise_scids.pop_front();
+        }
+
+        None
+    }
+
+    /// Marks the NEW_CONNECTION_ID frame returned by `next_new_conn_id()` as
+    /// sent.
+    pub fn on_new_conn_id_sent(&mut self) {
+        self.advertise_scids.pop_front();
+    }
+
+    /// Schedules a lost NEW_CONNECTION_ID frame for retransmission.
+    pub fn on_new_conn_id_lost(&mut self, seq: u64) {
+        if self.scids.iter().any(|e| e.seq == seq) &&
+            !self.advertise_scids.contains(&seq)
+        {
+            self.advertise_scids.push_back(seq);
+        }
+    }
+
+    /// Processes a RETIRE_CONNECTION_ID frame.
+    pub fn retire_scid(&mut self, seq: u64) -> Result<()> {
+        if seq >= self.next_scid_seq {
+            return Err(Error::InvalidPacket);
+        }
+
+        if let Some(pos) = self.scids.iter().position(|e| e.seq == seq) {
+            let e = self.scids.remove(pos).unwrap();
+
+            self.retired_scids.push_back(e.cid);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next source connection ID retired by the peer.
+    pub fn retired_scid_next(&mut self) -> Option<ConnectionId<'static>> {
+        self.retired_scids.pop_front()
+    }
+
+    /// Processes a NEW_CONNECTION_ID frame.
+    ///
+    /// If the frame retires the destination connection ID used on the active
+    /// path, a spare one replaces it and is returned.
+    pub fn new_dcid(
+        &mut self, seq: u64, cid: ConnectionId<'static>, retire_prior_to: u64,
+    ) -> Result<Option<ConnectionId<'static>>> {
+        if retire_prior_to > seq || cid.is_empty() || cid.len() > MAX_CONN_ID_LEN
+        {
+            return Err(Error::InvalidFrame(0x18));
+        }
+
+        if let Some(e) = self.dcids.iter().find(|e| e.seq == seq) {
+            if e.cid != cid {
+                return Err(Error::InvalidPacket);
+            }
+
+            return Ok(None);
+        }
+
+        if seq == self.active_dcid_seq {
+            return Ok(None);
+        }
+
+        // The ID was already retired by an earlier frame.
+        if seq < self.largest_retire_prior_to {
+            self.retire_dcid(seq);
+
+            return Ok(None);
+        }
+
+        let pos = self
+            .dcids
+            .iter()
+            .position(|e| e.seq > seq)
+            .unwrap_or(self.dcids.len());
+
+        self.dcids.insert(pos, DestinationId {
+            seq,
+            cid,
+            in_use: false,
+        });
+
+        let mut new_active = None;
+
+        if retire_prior_to > self.largest_retire_prior_to {
+            self.largest_retire_prior_to = retire_prior_to;
+
+            while let Some(e) = self.dcids.front() {
+                if e.seq >= retire_prior_to {
+                    break;
+                }
+
+                let seq = e.seq;
+                self.retire_dcid(seq);
+            }
+
+            if self.active_dcid_seq < retire_prior_to {
+                // The frame just added an ID that is not retired, so there
+                // is always a spare one.
+                let pos = self.dcids.iter().position(|e| !e.in_use).unwrap();
+                let e = self.dcids.remove(pos).unwrap();
+
+                self.push_retire(self.active_dcid_seq);
+
+                self.active_dcid_seq = e.seq;
+                new_active = Some(e.cid);
+            }
+        }
+
+        if self.dcids.len() + 1 > self.dcid_limit {
+            return Err(Error::IdLimit);
+        }
+
+        Ok(new_active)
+    }
+
+    /// Returns the number of destination connection IDs not used by any path.
+    pub fn spare_dcids(&self) -> usize {
+        self.dcids.iter().filter(|e| !e.in_use).count()
+    }
+
+    /// Reserves a spare destination connection ID to probe a path.
+    pub fn take_spare_dcid(&mut self) -> Option<(u64, ConnectionId<'static>)> {
+        let e = self.dcids.iter_mut().find(|e| !e.in_use)?;
+
+        e.in_use = true;
+
+        Some((e.seq, e.cid.clone()))
+    }
+
+    /// Returns the destination connection ID with the given sequence number,
+    /// unless it was retired.
+    pub fn dcid(&self, seq: u64) -> Option<&ConnectionId<'static>> {
+        self.dcids.iter().find(|e| e.seq == seq).map(|e| &e.cid)
+    }
+
+    /// Retires the destination connection ID with the given sequence number.
+    pub fn retire_dcid(&mut self, seq: u64) {
+        if let Some(pos) = self.dcids.iter().position(|e| e.seq == seq) {
+            self.dcids.remove(pos);
+        }
+
+        self.push_retire(seq);
+    }
+
+    fn push_retire(&mut self, seq: u64) {
+        if !self.retire_dcids.contains(&seq) {
+            self.retire_dcids.push_back(seq);
+        }
+    }
+
+    /// Returns the next RETIRE_CONNECTION_ID frame to send, if any.
+    pub fn next_retire_conn_id(&self) -> Option<frame::Frame> {
+        self.retire_dcids
+            .front()
+            .map(|&seq_num| frame::Frame::RetireConnectionId { seq_num })
+    }
+
+    /// Marks the RETIRE_CONNECTION_ID frame returned by
+    /// `next_retire_conn_id()` as sent.
+    pub fn on_retire_conn_id_sent(&mut self) {
+        self.retire_dcids.pop_front();
+    }
+
+    /// Schedules a lost RETIRE_CONNECTION_ID frame for retransmission.
+    pub fn on_retire_conn_id_lost(&mut self, seq: u64) {
+        self.push_retire(seq);
+    }
+
+    /// Returns true if there are frames to send.
+    pub fn has_frames(&self) -> bool {
+        !self.advertise_scids.is_empty() || !self.retire_dcids.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cid(v: u8) -> ConnectionId<'static> {
+        vec![v; 8].into()
+    }
+
+    #[test]
+    fn new_scid() {
+        let mut ids = ConnectionIdentifiers::new(&cid(0), 2);
+
+        assert_eq!(ids.new_scid(cid(1), 1, 2), Ok(1));
+        assert_eq!(ids.new_scid(cid(1), 1, 2), Ok(1));
+        assert_eq!(ids.new_scid(cid(2), 2, 2), Err(Error::IdLimit));
+
+        assert_eq!(
+            ids.next_new_conn_id(),
+            Some(frame::Frame::NewConnectionId {
+                seq_num: 1,
+                retire_prior_to: 0,
+                conn_id: vec![1; 8],
+                reset_token: 1_u128.to_be_bytes(),
+            })
+        );
+
+        ids.on_new_conn_id_sent();
+        assert_eq!(ids.next_new_conn_id(), None);
+
+        ids.on_new_conn_id_lost(1);
+        assert!(ids.has_frames());
+
+        assert_eq!(ids.retire_scid(2), Err(Error::InvalidPacket));
+        assert_eq!(ids.retire_scid(0), Ok(()));
+        assert_eq!(ids.retired_scid_next(), Some(cid(0)));
+        assert_eq!(ids.retired_scid_next(), None);
+
+        assert_eq!(ids.new_scid(cid(2), 2, 2), Ok(2));
+    }
+
+    #[test]
+    fn new_dcid() {
+        let mut ids = ConnectionIdentifiers::new(&cid(0), 3);
+
+        assert_eq!(ids.new_dcid(1, cid(1), 2), Err(Error::InvalidFrame(0x18)));
+
+        assert_eq!(ids.new_dcid(1, cid(1), 0), Ok(None));
+        assert_eq!(ids.new_dcid(1, cid(1), 0), Ok(None));
+        assert_eq!(ids.new_dcid(1, cid(2), 0), Err(Error::InvalidPacket));
+        assert_eq!(ids.new_dcid(2, cid(2), 0), Ok(None));
+        assert_eq!(ids.spare_dcids(), 2);
+
+        assert_eq!(ids.new_dcid(3, cid(3), 0), Err(Error::IdLimit));
+    }
+
+    #[test]
+    fn retire_prior_to() {
+        let mut ids = ConnectionIdentifiers::new(&cid(0), 3);
+
+        assert_eq!(ids.new_dcid(1, cid(1), 0), Ok(None));
+        assert_eq!(ids.take_spare_dcid(), Some((1, cid(1))));
+        assert_eq!(ids.spare_dcids(), 0);
+
+        // Retiring the active ID switches to the new spare one.
+        assert_eq!(ids.new_dcid(2, cid(2), 2), Ok(Some(cid(2))));
+        assert_eq!(ids.dcid(1), None);
+        assert_eq!(ids.spare_dcids(), 0);
+
+        assert_eq!(
+            ids.next_retire_conn_id(),
+            Some(frame::Frame::RetireConnectionId { seq_num: 1 })
+        );
+        ids.on_retire_conn_id_sent();
+
+        assert_eq!(
+            ids.next_retire_conn_id(),
+            Some(frame::Frame::RetireConnectionId { seq_num: 0 })
+        );
+        ids.on_retire_conn_id_sent();
+
+        assert!(!ids.has_frames());
+
+        // A late frame carrying an already retired ID is retired right away.
+        assert_eq!(ids.new_dcid(1, cid(1), 0), Ok(None));
+        assert_eq!(
+            ids.next_retire_conn_id(),
+            Some(frame::Frame::RetireConnectionId { seq_num: 1 })
+        );
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::time;
+
+/// A source of the current time.
+///
+/// Timers such as the idle timeout, loss detection and pacing are driven by
+/// the clock configured with [`set_clock()`]. Replacing the default
+/// [`SystemClock`] allows tests and network simulators to advance time
+/// deterministically.
+///
+/// [`set_clock()`]: struct.Config.html#method.set_clock
+/// [`SystemClock`]: struct.SystemClock.html
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> time::Instant;
+}
+
+/// The default clock, backed by [`std::time::Instant::now()`].
+///
+/// [`std::time::Instant::now()`]: https://doc.rust-lang.org/std/time/struct.Instant.html#method.now
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> time::Instant {
+        time::Instant::now()
+    }
+}
+
+/// The default clock without the standard library, which has no system clock
+/// to read the time from, so one must be configured explicitly.
+#[cfg(not(feature = "std"))]
+pub(crate) struct NoClock;
+
+#[cfg(not(feature = "std"))]
+impl Clock for NoClock {
+    fn now(&self) -> time::Instant {
+        panic!("no clock configured, see Config::set_clock()");
+    }
+}
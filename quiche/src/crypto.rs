@@ -24,12 +24,19 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use std::mem::MaybeUninit;
+#[cfg(not(feature = "rustls"))]
+use core::mem::MaybeUninit;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 use ring::aead;
 use ring::hkdf;
+use ring::hmac;
 
+#[cfg(not(feature = "rustls"))]
 use libc::c_int;
+#[cfg(not(feature = "rustls"))]
 use libc::c_void;
 
 use crate::Error;
@@ -41,6 +48,7 @@ use crate::packet;
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Level {
     Initial   = 0,
+    #[cfg_attr(feature = "rustls", allow(dead_code))]
     ZeroRTT   = 1,
     Handshake = 2,
     OneRTT    = 3,
@@ -60,19 +68,24 @@ impl Level {
     }
 }
 
+/// An AEAD algorithm used for packet protection.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Algorithm {
+    /// AES-128 in GCM mode, with SHA-256 as the HKDF hash function.
     #[allow(non_camel_case_types)]
     AES128_GCM,
 
+    /// AES-256 in GCM mode, with SHA-384 as the HKDF hash function.
     #[allow(non_camel_case_types)]
     AES256_GCM,
 
+    /// ChaCha20-Poly1305, with SHA-256 as the HKDF hash function.
     #[allow(non_camel_case_types)]
     ChaCha20_Poly1305,
 }
 
 impl Algorithm {
+    #[cfg(not(feature = "rustls"))]
     fn get_evp_aead(self) -> *const EVP_AEAD {
         match self {
             Algorithm::AES128_GCM => unsafe { EVP_aead_aes_128_gcm() },
@@ -83,6 +96,15 @@ impl Algorithm {
         }
     }
 
+    #[cfg(feature = "rustls")]
+    fn get_ring_aead(self) -> &'static aead::Algorithm {
+        match self {
+            Algorithm::AES128_GCM => &aead::AES_128_GCM,
+            Algorithm::AES256_GCM => &aead::AES_256_GCM,
+            Algorithm::ChaCha20_Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+
     fn get_ring_hp(self) -> &'static aead::quic::Algorithm {
         match self {
             Algorithm::AES128_GCM => &aead::quic::AES_128,
@@ -99,6 +121,7 @@ impl Algorithm {
         }
     }
 
+    /// Returns the length of the algorithm's keys, in bytes.
     pub fn key_len(self) -> usize {
         match self {
             Algorithm::AES128_GCM => 16,
@@ -107,6 +130,7 @@ impl Algorithm {
         }
     }
 
+    /// Returns the length of the algorithm's authentication tags, in bytes.
     pub fn tag_len(self) -> usize {
         if cfg!(feature = "fuzzing") {
             return 0;
@@ -119,6 +143,7 @@ impl Algorithm {
         }
     }
 
+    /// Returns the length of the algorithm's nonces, in bytes.
     pub fn nonce_len(self) -> usize {
         match self {
             Algorithm::AES128_GCM => 12,
@@ -128,36 +153,122 @@ impl Algorithm {
     }
 }
 
+/// A provider of the cryptographic primitives used to protect packets.
+///
+/// All methods default to quiche's built-in implementation, so a provider
+/// only needs to override the ones it wants to replace, e.g. to offload
+/// AES-GCM packet encryption to dedicated hardware.
+///
+/// A provider is set with [`Config::set_crypto_provider()`].
+///
+/// [`Config::set_crypto_provider()`]:
+/// struct.Config.html#method.set_crypto_provider
+pub trait CryptoProvider: Send + Sync {
+    /// Creates a key to encrypt and decrypt packet payloads.
+    ///
+    /// The nonce of each packet is formed by XORing `iv` with the packet
+    /// number, as described in RFC 9001.
+    fn new_packet_key(
+        &self, alg: Algorithm, key: &[u8], iv: &[u8],
+    ) -> Result<Box<dyn PacketKey>> {
+        Ok(Box::new(BuiltinPacketKey::new(alg, key, iv)?))
+    }
+
+    /// Creates a key to protect packet headers.
+    fn new_header_protection_key(
+        &self, alg: Algorithm, key: &[u8],
+    ) -> Result<Box<dyn HeaderProtectionKey>> {
+        let key = aead::quic::HeaderProtectionKey::new(alg.get_ring_hp(), key)
+            .map_err(|_| Error::CryptoFail)?;
+
+        Ok(Box::new(BuiltinHeaderProtectionKey(key)))
+    }
+
+    /// Performs HKDF-Extract using the hash function of the given algorithm,
+    /// and returns the resulting pseudorandom key.
+    fn hkdf_extract(
+        &self, alg: Algorithm, salt: &[u8], ikm: &[u8],
+    ) -> Result<Vec<u8>> {
+        let key = hmac::Key::new(alg.get_ring_digest().hmac_algorithm(), salt);
+
+        Ok(hmac::sign(&key, ikm).as_ref().to_vec())
+    }
+
+    /// Performs HKDF-Expand using the hash function of the given algorithm,
+    /// filling `out` with the output keying material.
+    fn hkdf_expand(
+        &self, alg: Algorithm, prk: &[u8], info: &[u8], out: &mut [u8],
+    ) -> Result<()> {
+        let prk = hkdf::Prk::new_less_safe(alg.get_ring_digest(), prk);
+
+        prk.expand(&[info], ArbitraryOutputLen(out.len()))
+            .map_err(|_| Error::CryptoFail)?
+            .fill(out)
+            .map_err(|_| Error::CryptoFail)?;
+
+        Ok(())
+    }
+}
+
+/// A key used to encrypt and decrypt packet payloads.
+pub trait PacketKey: Send + Sync {
+    /// Decrypts `buf` in place, including its trailing authentication tag,
+    /// using the nonce formed from the packet number `counter`.
+    ///
+    /// On success the length of the plaintext, which is written at the start
+    /// of `buf`, is returned.
+    fn open(&self, counter: u64, ad: &[u8], buf: &mut [u8]) -> Result<usize>;
+
+    /// Encrypts the first `in_len` bytes of `buf` in place, using the nonce
+    /// formed from the packet number `counter`.
+    ///
+    /// The encrypted `extra_in` bytes, if any, are written after the first
+    /// `in_len` bytes, followed by the authentication tag. `buf` is always
+    /// large enough to hold the whole output, whose length is returned on
+    /// success.
+    fn seal(
+        &self, counter: u64, ad: &[u8], buf: &mut [u8], in_len: usize,
+        extra_in: Option<&[u8]>,
+    ) -> Result<usize>;
+}
+
+/// A key used to protect packet headers.
+pub trait HeaderProtectionKey: Send + Sync {
+    /// Computes the header protection mask for the given ciphertext sample.
+    fn new_mask(&self, sample: &[u8]) -> Result<[u8; 5]>;
+}
+
+/// The provider used unless the application sets its own.
+pub struct DefaultCryptoProvider;
+
+impl CryptoProvider for DefaultCryptoProvider {}
+
 pub struct Open {
     alg: Algorithm,
 
-    ctx: EVP_AEAD_CTX,
-
-    hp_key: aead::quic::HeaderProtectionKey,
+    key: Box<dyn PacketKey>,
 
-    nonce: Vec<u8>,
+    hp_key: Box<dyn HeaderProtectionKey>,
 }
 
 impl Open {
     pub fn new(
-        alg: Algorithm, key: &[u8], iv: &[u8], hp_key: &[u8],
+        provider: &dyn CryptoProvider, alg: Algorithm, key: &[u8], iv: &[u8],
+        hp_key: &[u8],
     ) -> Result<Open> {
         Ok(Open {
             alg,
 
-            ctx: make_aead_ctx(alg, key)?,
-
-            hp_key: aead::quic::HeaderProtectionKey::new(
-                alg.get_ring_hp(),
-                hp_key,
-            )
-            .map_err(|_| Error::CryptoFail)?,
+            key: provider.new_packet_key(alg, key, iv)?,
 
-            nonce: Vec::from(iv),
+            hp_key: provider.new_header_protection_key(alg, hp_key)?,
         })
     }
 
-    pub fn from_secret(aead: Algorithm, secret: &[u8]) -> Result<Open> {
+    #[cfg_attr(feature = "rustls", allow(dead_code))]
+    pub fn from_secret(
+        provider: &dyn CryptoProvider, aead: Algorithm, secret: &[u8],
+    ) -> Result<Open> {
         let key_len = aead.key_len();
         let nonce_len = aead.nonce_len();
 
@@ -165,11 +276,24 @@ impl Open {
         let mut iv = vec![0; nonce_len];
         let mut pn_key = vec![0; key_len];
 
-        derive_pkt_key(aead, secret, &mut key)?;
-        derive_pkt_iv(aead, secret, &mut iv)?;
-        derive_hdr_key(aead, secret, &mut pn_key)?;
+        derive_pkt_key(provider, aead, secret, &mut key)?;
+        derive_pkt_iv(provider, aead, secret, &mut iv)?;
+        derive_hdr_key(provider, aead, secret, &mut pn_key)?;
 
-        Open::new(aead, &key, &iv, &pn_key)
+        Open::new(provider, aead, &key, &iv, &pn_key)
+    }
+
+    #[cfg(feature = "rustls")]
+    pub fn from_rustls(
+        aead: Algorithm, keys: rustls::quic::DirectionalKeys,
+    ) -> Open {
+        Open {
+            alg: aead,
+
+            key: Box::new(RustlsPacketKey(keys.packet)),
+
+            hp_key: Box::new(RustlsHeaderProtectionKey(keys.header)),
+        }
     }
 
     pub fn open_with_u64_counter(
@@ -179,34 +303,11 @@ impl Open {
             return Ok(buf.len());
         }
 
-        let tag_len = self.alg().tag_len();
-
-        let mut out_len = buf.len() - tag_len;
-
-        let max_out_len = out_len;
-
-        let nonce = make_nonce(&self.nonce, counter);
-
-        let rc = unsafe {
-            EVP_AEAD_CTX_open(
-                &self.ctx,          // ctx
-                buf.as_mut_ptr(),   // out
-                &mut out_len,       // out_len
-                max_out_len,        // max_out_len
-                nonce[..].as_ptr(), // nonce
-                nonce.len(),        // nonce_len
-                buf.as_ptr(),       // inp
-                buf.len(),          // in_len
-                ad.as_ptr(),        // ad
-                ad.len(),           // ad_len
-            )
-        };
-
-        if rc != 1 {
+        if buf.len() < self.alg().tag_len() {
             return Err(Error::CryptoFail);
         }
 
-        Ok(out_len)
+        self.key.open(counter, ad, buf)
     }
 
     pub fn new_mask(&self, sample: &[u8]) -> Result<[u8; 5]> {
@@ -214,12 +315,7 @@ impl Open {
             return Ok(<[u8; 5]>::default());
         }
 
-        let mask = self
-            .hp_key
-            .new_mask(sample)
-            .map_err(|_| Error::CryptoFail)?;
-
-        Ok(mask)
+        self.hp_key.new_mask(sample)
     }
 
     pub fn alg(&self) -> Algorithm {
@@ -230,33 +326,29 @@ impl Open {
 pub struct Seal {
     alg: Algorithm,
 
-    ctx: EVP_AEAD_CTX,
+    key: Box<dyn PacketKey>,
 
-    hp_key: aead::quic::HeaderProtectionKey,
-
-    nonce: Vec<u8>,
+    hp_key: Box<dyn HeaderProtectionKey>,
 }
 
 impl Seal {
     pub fn new(
-        alg: Algorithm, key: &[u8], iv: &[u8], hp_key: &[u8],
+        provider: &dyn CryptoProvider, alg: Algorithm, key: &[u8], iv: &[u8],
+        hp_key: &[u8],
     ) -> Result<Seal> {
         Ok(Seal {
             alg,
 
-            ctx: make_aead_ctx(alg, key)?,
+            key: provider.new_packet_key(alg, key, iv)?,
 
-            hp_key: aead::quic::HeaderProtectionKey::new(
-                alg.get_ring_hp(),
-                hp_key,
-            )
-            .map_err(|_| Error::CryptoFail)?,
-
-            nonce: Vec::from(iv),
+            hp_key: provider.new_header_protection_key(alg, hp_key)?,
         })
     }
 
-    pub fn from_secret(aead: Algorithm, secret: &[u8]) -> Result<Seal> {
+    #[cfg_attr(feature = "rustls", allow(dead_code))]
+    pub fn from_secret(
+        provider: &dyn CryptoProvider, aead: Algorithm, secret: &[u8],
+    ) -> Result<Seal> {
         let key_len = aead.key_len();
         let nonce_len = aead.nonce_len();
 
@@ -264,11 +356,24 @@ impl Seal {
         let mut iv = vec![0; nonce_len];
         let mut pn_key = vec![0; key_len];
 
-        derive_pkt_key(aead, secret, &mut key)?;
-        derive_pkt_iv(aead, secret, &mut iv)?;
-        derive_hdr_key(aead, secret, &mut pn_key)?;
+        derive_pkt_key(provider, aead, secret, &mut key)?;
+        derive_pkt_iv(provider, aead, secret, &mut iv)?;
+        derive_hdr_key(provider, aead, secret, &mut pn_key)?;
 
-        Seal::new(aead, &key, &iv, &pn_key)
+        Seal::new(provider, aead, &key, &iv, &pn_key)
+    }
+
+    #[cfg(feature = "rustls")]
+    pub fn from_rustls(
+        aead: Algorithm, keys: rustls::quic::DirectionalKeys,
+    ) -> Seal {
+        Seal {
+            alg: aead,
+
+            key: Box::new(RustlsPacketKey(keys.packet)),
+
+            hp_key: Box::new(RustlsHeaderProtectionKey(keys.header)),
+        }
     }
 
     pub fn seal_with_u64_counter(
@@ -286,44 +391,14 @@ impl Seal {
 
         let tag_len = self.alg().tag_len();
 
-        let mut out_tag_len = tag_len;
-
-        let (extra_in_ptr, extra_in_len) = match extra_in {
-            Some(v) => (v.as_ptr(), v.len()),
-
-            None => (std::ptr::null(), 0),
-        };
+        let extra_in_len = extra_in.map_or(0, |v| v.len());
 
         // Make sure all the outputs combined fit in the buffer.
         if in_len + tag_len + extra_in_len > buf.len() {
             return Err(Error::CryptoFail);
         }
 
-        let nonce = make_nonce(&self.nonce, counter);
-
-        let rc = unsafe {
-            EVP_AEAD_CTX_seal_scatter(
-                &self.ctx,                  // ctx
-                buf.as_mut_ptr(),           // out
-                buf[in_len..].as_mut_ptr(), // out_tag
-                &mut out_tag_len,           // out_tag_len
-                tag_len + extra_in_len,     // max_out_tag_len
-                nonce[..].as_ptr(),         // nonce
-                nonce.len(),                // nonce_len
-                buf.as_ptr(),               // inp
-                in_len,                     // in_len
-                extra_in_ptr,               // extra_in
-                extra_in_len,               // extra_in_len
-                ad.as_ptr(),                // ad
-                ad.len(),                   // ad_len
-            )
-        };
-
-        if rc != 1 {
-            return Err(Error::CryptoFail);
-        }
-
-        Ok(in_len + out_tag_len)
+        self.key.seal(counter, ad, buf, in_len, extra_in)
     }
 
     pub fn new_mask(&self, sample: &[u8]) -> Result<[u8; 5]> {
@@ -331,12 +406,7 @@ impl Seal {
             return Ok(<[u8; 5]>::default());
         }
 
-        let mask = self
-            .hp_key
-            .new_mask(sample)
-            .map_err(|_| Error::CryptoFail)?;
-
-        Ok(mask)
+        self.hp_key.new_mask(sample)
     }
 
     pub fn alg(&self) -> Algorithm {
@@ -345,7 +415,7 @@ impl Seal {
 }
 
 pub fn derive_initial_key_material(
-    cid: &[u8], version: u32, is_server: bool,
+    provider: &dyn CryptoProvider, cid: &[u8], version: u32, is_server: bool,
 ) -> Result<(Open, Seal)> {
     let mut secret = [0; 32];
 
@@ -354,44 +424,46 @@ pub fn derive_initial_key_material(
     let key_len = aead.key_len();
     let nonce_len = aead.nonce_len();
 
-    let initial_secret = derive_initial_secret(cid, version);
+    let initial_secret = derive_initial_secret(provider, cid, version)?;
 
     // Client.
     let mut client_key = vec![0; key_len];
     let mut client_iv = vec![0; nonce_len];
     let mut client_hp_key = vec![0; key_len];
 
-    derive_client_initial_secret(&initial_secret, &mut secret)?;
-    derive_pkt_key(aead, &secret, &mut client_key)?;
-    derive_pkt_iv(aead, &secret, &mut client_iv)?;
-    derive_hdr_key(aead, &secret, &mut client_hp_key)?;
+    derive_client_initial_secret(provider, &initial_secret, &mut secret)?;
+    derive_pkt_key(provider, aead, &secret, &mut client_key)?;
+    derive_pkt_iv(provider, aead, &secret, &mut client_iv)?;
+    derive_hdr_key(provider, aead, &secret, &mut client_hp_key)?;
 
     // Server.
     let mut server_key = vec![0; key_len];
     let mut server_iv = vec![0; nonce_len];
     let mut server_hp_key = vec![0; key_len];
 
-    derive_server_initial_secret(&initial_secret, &mut secret)?;
-    derive_pkt_key(aead, &secret, &mut server_key)?;
-    derive_pkt_iv(aead, &secret, &mut server_iv)?;
-    derive_hdr_key(aead, &secret, &mut server_hp_key)?;
+    derive_server_initial_secret(provider, &initial_secret, &mut secret)?;
+    derive_pkt_key(provider, aead, &secret, &mut server_key)?;
+    derive_pkt_iv(provider, aead, &secret, &mut server_iv)?;
+    derive_hdr_key(provider, aead, &secret, &mut server_hp_key)?;
 
     let (open, seal) = if is_server {
         (
-            Open::new(aead, &client_key, &client_iv, &client_hp_key)?,
-            Seal::new(aead, &server_key, &server_iv, &server_hp_key)?,
+            Open::new(provider, aead, &client_key, &client_iv, &client_hp_key)?,
+            Seal::new(provider, aead, &server_key, &server_iv, &server_hp_key)?,
         )
     } else {
         (
-            Open::new(aead, &server_key, &server_iv, &server_hp_key)?,
-            Seal::new(aead, &client_key, &client_iv, &client_hp_key)?,
+            Open::new(provider, aead, &server_key, &server_iv, &server_hp_key)?,
+            Seal::new(provider, aead, &client_key, &client_iv, &client_hp_key)?,
         )
     };
 
     Ok((open, seal))
 }
 
-fn derive_initial_secret(secret: &[u8], version: u32) -> hkdf::Prk {
+fn derive_initial_secret(
+    provider: &dyn CryptoProvider, secret: &[u8], version: u32,
+) -> Result<Vec<u8>> {
     const INITIAL_SALT: [u8; 20] = [
         0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6,
         0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
@@ -416,22 +488,26 @@ fn derive_initial_secret(secret: &[u8], version: u32) -> hkdf::Prk {
         _ => &INITIAL_SALT,
     };
 
-    let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, salt);
-    salt.extract(secret)
+    // Initial secrets always use SHA-256.
+    provider.hkdf_extract(Algorithm::AES128_GCM, salt, secret)
 }
 
-fn derive_client_initial_secret(prk: &hkdf::Prk, out: &mut [u8]) -> Result<()> {
+fn derive_client_initial_secret(
+    provider: &dyn CryptoProvider, prk: &[u8], out: &mut [u8],
+) -> Result<()> {
     const LABEL: &[u8] = b"client in";
-    hkdf_expand_label(prk, LABEL, out)
+    hkdf_expand_label(provider, Algorithm::AES128_GCM, prk, LABEL, out)
 }
 
-fn derive_server_initial_secret(prk: &hkdf::Prk, out: &mut [u8]) -> Result<()> {
+fn derive_server_initial_secret(
+    provider: &dyn CryptoProvider, prk: &[u8], out: &mut [u8],
+) -> Result<()> {
     const LABEL: &[u8] = b"server in";
-    hkdf_expand_label(prk, LABEL, out)
+    hkdf_expand_label(provider, Algorithm::AES128_GCM, prk, LABEL, out)
 }
 
 pub fn derive_hdr_key(
-    aead: Algorithm, secret: &[u8], out: &mut [u8],
+    provider: &dyn CryptoProvider, aead: Algorithm, secret: &[u8], out: &mut [u8],
 ) -> Result<()> {
     const LABEL: &[u8] = b"quic hp";
 
@@ -441,12 +517,11 @@ pub fn derive_hdr_key(
         return Err(Error::CryptoFail);
     }
 
-    let secret = hkdf::Prk::new_less_safe(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..key_len])
+    hkdf_expand_label(provider, aead, secret, LABEL, &mut out[..key_len])
 }
 
 pub fn derive_pkt_key(
-    aead: Algorithm, secret: &[u8], out: &mut [u8],
+    provider: &dyn CryptoProvider, aead: Algorithm, secret: &[u8], out: &mut [u8],
 ) -> Result<()> {
     const LABEL: &[u8] = b"quic key";
 
@@ -456,12 +531,11 @@ pub fn derive_pkt_key(
         return Err(Error::CryptoFail);
     }
 
-    let secret = hkdf::Prk::new_less_safe(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..key_len])
+    hkdf_expand_label(provider, aead, secret, LABEL, &mut out[..key_len])
 }
 
 pub fn derive_pkt_iv(
-    aead: Algorithm, secret: &[u8], out: &mut [u8],
+    provider: &dyn CryptoProvider, aead: Algorithm, secret: &[u8], out: &mut [u8],
 ) -> Result<()> {
     const LABEL: &[u8] = b"quic iv";
 
@@ -471,37 +545,268 @@ pub fn derive_pkt_iv(
         return Err(Error::CryptoFail);
     }
 
-    let secret = hkdf::Prk::new_less_safe(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..nonce_len])
+    hkdf_expand_label(provider, aead, secret, LABEL, &mut out[..nonce_len])
 }
 
-fn make_aead_ctx(alg: Algorithm, key: &[u8]) -> Result<EVP_AEAD_CTX> {
-    let mut ctx = MaybeUninit::uninit();
+#[cfg(not(feature = "rustls"))]
+struct BuiltinPacketKey {
+    alg: Algorithm,
+
+    ctx: EVP_AEAD_CTX,
+
+    nonce: Vec<u8>,
+}
+
+#[cfg(not(feature = "rustls"))]
+impl BuiltinPacketKey {
+    fn new(alg: Algorithm, key: &[u8], iv: &[u8]) -> Result<Self> {
+        let mut ctx = MaybeUninit::uninit();
+
+        let ctx = unsafe {
+            let aead = alg.get_evp_aead();
+
+            let rc = EVP_AEAD_CTX_init(
+                ctx.as_mut_ptr(),
+                aead,
+                key.as_ptr(),
+                alg.key_len(),
+                alg.tag_len(),
+                core::ptr::null_mut(),
+            );
+
+            if rc != 1 {
+                return Err(Error::CryptoFail);
+            }
+
+            ctx.assume_init()
+        };
+
+        Ok(BuiltinPacketKey {
+            alg,
+
+            ctx,
+
+            nonce: Vec::from(iv),
+        })
+    }
+}
+
+#[cfg(not(feature = "rustls"))]
+impl PacketKey for BuiltinPacketKey {
+    fn open(&self, counter: u64, ad: &[u8], buf: &mut [u8]) -> Result<usize> {
+        let tag_len = self.alg.tag_len();
+
+        let mut out_len = buf.len() - tag_len;
+
+        let max_out_len = out_len;
 
-    let ctx = unsafe {
-        let aead = alg.get_evp_aead();
+        let nonce = make_nonce(&self.nonce, counter);
 
-        let rc = EVP_AEAD_CTX_init(
-            ctx.as_mut_ptr(),
-            aead,
-            key.as_ptr(),
-            alg.key_len(),
-            alg.tag_len(),
-            std::ptr::null_mut(),
-        );
+        let rc = unsafe {
+            EVP_AEAD_CTX_open(
+                &self.ctx,          // ctx
+                buf.as_mut_ptr(),   // out
+                &mut out_len,       // out_len
+                max_out_len,        // max_out_len
+                nonce[..].as_ptr(), // nonce
+                nonce.len(),        // nonce_len
+                buf.as_ptr(),       // inp
+                buf.len(),          // in_len
+                ad.as_ptr(),        // ad
+                ad.len(),           // ad_len
+            )
+        };
 
         if rc != 1 {
             return Err(Error::CryptoFail);
         }
 
-        ctx.assume_init()
-    };
+        Ok(out_len)
+    }
+
+    fn seal(
+        &self, counter: u64, ad: &[u8], buf: &mut [u8], in_len: usize,
+        extra_in: Option<&[u8]>,
+    ) -> Result<usize> {
+        let tag_len = self.alg.tag_len();
+
+        let mut out_tag_len = tag_len;
+
+        let (extra_in_ptr, extra_in_len) = match extra_in {
+            Some(v) => (v.as_ptr(), v.len()),
+
+            None => (core::ptr::null(), 0),
+        };
+
+        let nonce = make_nonce(&self.nonce, counter);
+
+        let rc = unsafe {
+            EVP_AEAD_CTX_seal_scatter(
+                &self.ctx,                  // ctx
+                buf.as_mut_ptr(),           // out
+                buf[in_len..].as_mut_ptr(), // out_tag
+                &mut out_tag_len,           // out_tag_len
+                tag_len + extra_in_len,     // max_out_tag_len
+                nonce[..].as_ptr(),         // nonce
+                nonce.len(),                // nonce_len
+                buf.as_ptr(),               // inp
+                in_len,                     // in_len
+                extra_in_ptr,               // extra_in
+                extra_in_len,               // extra_in_len
+                ad.as_ptr(),                // ad
+                ad.len(),                   // ad_len
+            )
+        };
 
-    Ok(ctx)
+        if rc != 1 {
+            return Err(Error::CryptoFail);
+        }
+
+        Ok(in_len + out_tag_len)
+    }
+}
+
+#[cfg(feature = "rustls")]
+struct BuiltinPacketKey {
+    key: aead::LessSafeKey,
+
+    nonce: Vec<u8>,
+}
+
+#[cfg(feature = "rustls")]
+impl BuiltinPacketKey {
+    fn new(alg: Algorithm, key: &[u8], iv: &[u8]) -> Result<Self> {
+        let key = aead::UnboundKey::new(alg.get_ring_aead(), key)
+            .map_err(|_| Error::CryptoFail)?;
+
+        Ok(BuiltinPacketKey {
+            key: aead::LessSafeKey::new(key),
+
+            nonce: Vec::from(iv),
+        })
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl PacketKey for BuiltinPacketKey {
+    fn open(&self, counter: u64, ad: &[u8], buf: &mut [u8]) -> Result<usize> {
+        let nonce = make_nonce(&self.nonce, counter);
+        let nonce = aead::Nonce::assume_unique_for_key(nonce);
+
+        let out = self
+            .key
+            .open_in_place(nonce, aead::Aad::from(ad), buf)
+            .map_err(|_| Error::CryptoFail)?;
+
+        Ok(out.len())
+    }
+
+    fn seal(
+        &self, counter: u64, ad: &[u8], buf: &mut [u8], in_len: usize,
+        extra_in: Option<&[u8]>,
+    ) -> Result<usize> {
+        let nonce = make_nonce(&self.nonce, counter);
+        let nonce = aead::Nonce::assume_unique_for_key(nonce);
+
+        let in_len = copy_extra_in(buf, in_len, extra_in);
+
+        let (inp, out_tag) = buf.split_at_mut(in_len);
+
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(nonce, aead::Aad::from(ad), inp)
+            .map_err(|_| Error::CryptoFail)?;
+
+        out_tag[..tag.as_ref().len()].copy_from_slice(tag.as_ref());
+
+        Ok(in_len + tag.as_ref().len())
+    }
+}
+
+// Packet protection keys obtained from rustls once negotiated, as it doesn't
+// expose the underlying secrets.
+#[cfg(feature = "rustls")]
+struct RustlsPacketKey(rustls::quic::PacketKey);
+
+#[cfg(feature = "rustls")]
+impl PacketKey for RustlsPacketKey {
+    fn open(&self, counter: u64, ad: &[u8], buf: &mut [u8]) -> Result<usize> {
+        let out = self
+            .0
+            .decrypt_in_place(counter, ad, buf)
+            .map_err(|_| Error::CryptoFail)?;
+
+        Ok(out.len())
+    }
+
+    fn seal(
+        &self, counter: u64, ad: &[u8], buf: &mut [u8], in_len: usize,
+        extra_in: Option<&[u8]>,
+    ) -> Result<usize> {
+        let in_len = copy_extra_in(buf, in_len, extra_in);
+
+        let (inp, out_tag) = buf.split_at_mut(in_len);
+
+        let tag = self
+            .0
+            .encrypt_in_place(counter, ad, inp)
+            .map_err(|_| Error::CryptoFail)?;
+
+        out_tag[..tag.as_ref().len()].copy_from_slice(tag.as_ref());
+
+        Ok(in_len + tag.as_ref().len())
+    }
+}
+
+// There is no scatter API, so the extra input is simply appended to the
+// plaintext and encrypted together with it. Returns the new plaintext length.
+#[cfg(feature = "rustls")]
+fn copy_extra_in(
+    buf: &mut [u8], in_len: usize, extra_in: Option<&[u8]>,
+) -> usize {
+    match extra_in {
+        Some(extra) => {
+            buf[in_len..in_len + extra.len()].copy_from_slice(extra);
+
+            in_len + extra.len()
+        },
+
+        None => in_len,
+    }
+}
+
+struct BuiltinHeaderProtectionKey(aead::quic::HeaderProtectionKey);
+
+impl HeaderProtectionKey for BuiltinHeaderProtectionKey {
+    fn new_mask(&self, sample: &[u8]) -> Result<[u8; 5]> {
+        self.0.new_mask(sample).map_err(|_| Error::CryptoFail)
+    }
+}
+
+#[cfg(feature = "rustls")]
+struct RustlsHeaderProtectionKey(rustls::quic::HeaderProtectionKey);
+
+#[cfg(feature = "rustls")]
+impl HeaderProtectionKey for RustlsHeaderProtectionKey {
+    // rustls only exposes the mask by applying it, so apply it to a short
+    // header with a 4 bytes packet number of all zeros. Only the 5 least
+    // significant bits of the first byte are recovered, but those are the
+    // only ones ever used.
+    fn new_mask(&self, sample: &[u8]) -> Result<[u8; 5]> {
+        let mut first = 0x03;
+        let mut pn = [0; 4];
+
+        self.0
+            .encrypt_in_place(sample, &mut first, &mut pn)
+            .map_err(|_| Error::CryptoFail)?;
+
+        Ok([first ^ 0x03, pn[0], pn[1], pn[2], pn[3]])
+    }
 }
 
 fn hkdf_expand_label(
-    prk: &hkdf::Prk, label: &[u8], out: &mut [u8],
+    provider: &dyn CryptoProvider, alg: Algorithm, prk: &[u8], label: &[u8],
+    out: &mut [u8],
 ) -> Result<()> {
     const LABEL_PREFIX: &[u8] = b"tls13 ";
 
@@ -510,12 +815,7 @@ fn hkdf_expand_label(
 
     let info = [&out_len, &[label_len][..], LABEL_PREFIX, label, &[0][..]];
 
-    prk.expand(&info, ArbitraryOutputLen(out.len()))
-        .map_err(|_| Error::CryptoFail)?
-        .fill(out)
-        .map_err(|_| Error::CryptoFail)?;
-
-    Ok(())
+    provider.hkdf_expand(alg, prk, &info.concat(), out)
 }
 
 fn make_nonce(iv: &[u8], counter: u64) -> [u8; aead::NONCE_LEN] {
@@ -544,12 +844,14 @@ impl hkdf::KeyType for ArbitraryOutputLen {
 
 #[allow(non_camel_case_types)]
 #[repr(transparent)]
+#[cfg(not(feature = "rustls"))]
 struct EVP_AEAD(c_void);
 
 // NOTE: This structure is copied from <openssl/aead.h> in order to be able to
 // statically allocate it. While it is not often modified upstream, it needs to
 // be kept in sync.
 #[repr(C)]
+#[cfg(not(feature = "rustls"))]
 struct EVP_AEAD_CTX {
     aead: libc::uintptr_t,
     opaque: [u8; 580],
@@ -557,6 +859,7 @@ struct EVP_AEAD_CTX {
     tag_len: u8,
 }
 
+#[cfg(not(feature = "rustls"))]
 extern {
     // EVP_AEAD
     fn EVP_aead_aes_128_gcm() -> *const EVP_AEAD;
@@ -598,15 +901,21 @@ mod tests {
         let mut pkt_iv = [0; 12];
         let mut hdr_key = [0; 16];
 
+        let provider = DefaultCryptoProvider;
+
         let aead = Algorithm::AES128_GCM;
 
         let initial_secret =
-            derive_initial_secret(&dcid, crate::PROTOCOL_VERSION_V1);
+            derive_initial_secret(&provider, &dcid, crate::PROTOCOL_VERSION_V1)
+                .unwrap();
 
         // Client.
-        assert!(
-            derive_client_initial_secret(&initial_secret, &mut secret).is_ok()
-        );
+        assert!(derive_client_initial_secret(
+            &provider,
+            &initial_secret,
+            &mut secret
+        )
+        .is_ok());
         let expected_client_initial_secret = [
             0xc0, 0x0c, 0xf1, 0x51, 0xca, 0x5b, 0xe0, 0x75, 0xed, 0x0e, 0xbf,
             0xb5, 0xc8, 0x03, 0x23, 0xc4, 0x2d, 0x6b, 0x7d, 0xb6, 0x78, 0x81,
@@ -614,21 +923,21 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_client_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(&provider, aead, &secret, &mut pkt_key).is_ok());
         let expected_client_pkt_key = [
             0x1f, 0x36, 0x96, 0x13, 0xdd, 0x76, 0xd5, 0x46, 0x77, 0x30, 0xef,
             0xcb, 0xe3, 0xb1, 0xa2, 0x2d,
         ];
         assert_eq!(&pkt_key, &expected_client_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(&provider, aead, &secret, &mut pkt_iv).is_ok());
         let expected_client_pkt_iv = [
             0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25,
             0x5c,
         ];
         assert_eq!(&pkt_iv, &expected_client_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(&provider, aead, &secret, &mut hdr_key).is_ok());
         let expected_client_hdr_key = [
             0x9f, 0x50, 0x44, 0x9e, 0x04, 0xa0, 0xe8, 0x10, 0x28, 0x3a, 0x1e,
             0x99, 0x33, 0xad, 0xed, 0xd2,
@@ -636,9 +945,12 @@ mod tests {
         assert_eq!(&hdr_key, &expected_client_hdr_key);
 
         // Server.
-        assert!(
-            derive_server_initial_secret(&initial_secret, &mut secret).is_ok()
-        );
+        assert!(derive_server_initial_secret(
+            &provider,
+            &initial_secret,
+            &mut secret
+        )
+        .is_ok());
         let expected_server_initial_secret = [
             0x3c, 0x19, 0x98, 0x28, 0xfd, 0x13, 0x9e, 0xfd, 0x21, 0x6c, 0x15,
             0x5a, 0xd8, 0x44, 0xcc, 0x81, 0xfb, 0x82, 0xfa, 0x8d, 0x74, 0x46,
@@ -646,21 +958,21 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_server_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(&provider, aead, &secret, &mut pkt_key).is_ok());
         let expected_server_pkt_key = [
             0xcf, 0x3a, 0x53, 0x31, 0x65, 0x3c, 0x36, 0x4c, 0x88, 0xf0, 0xf3,
             0x79, 0xb6, 0x06, 0x7e, 0x37,
         ];
         assert_eq!(&pkt_key, &expected_server_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(&provider, aead, &secret, &mut pkt_iv).is_ok());
         let expected_server_pkt_iv = [
             0x0a, 0xc1, 0x49, 0x3c, 0xa1, 0x90, 0x58, 0x53, 0xb0, 0xbb, 0xa0,
             0x3e,
         ];
         assert_eq!(&pkt_iv, &expected_server_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(&provider, aead, &secret, &mut hdr_key).is_ok());
         let expected_server_hdr_key = [
             0xc2, 0x06, 0xb8, 0xd9, 0xb9, 0xf0, 0xf3, 0x76, 0x44, 0x43, 0x0b,
             0x49, 0x0e, 0xea, 0xa3, 0x14,
@@ -677,15 +989,24 @@ mod tests {
         let mut pkt_iv = [0; 12];
         let mut hdr_key = [0; 16];
 
+        let provider = DefaultCryptoProvider;
+
         let aead = Algorithm::AES128_GCM;
 
-        let initial_secret =
-            derive_initial_secret(&dcid, crate::PROTOCOL_VERSION_DRAFT29);
+        let initial_secret = derive_initial_secret(
+            &provider,
+            &dcid,
+            crate::PROTOCOL_VERSION_DRAFT29,
+        )
+        .unwrap();
 
         // Client.
-        assert!(
-            derive_client_initial_secret(&initial_secret, &mut secret).is_ok()
-        );
+        assert!(derive_client_initial_secret(
+            &provider,
+            &initial_secret,
+            &mut secret
+        )
+        .is_ok());
         let expected_client_initial_secret = [
             0x00, 0x88, 0x11, 0x92, 0x88, 0xf1, 0xd8, 0x66, 0x73, 0x3c, 0xee,
             0xed, 0x15, 0xff, 0x9d, 0x50, 0x90, 0x2c, 0xf8, 0x29, 0x52, 0xee,
@@ -693,21 +1014,21 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_client_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(&provider, aead, &secret, &mut pkt_key).is_ok());
         let expected_client_pkt_key = [
             0x17, 0x52, 0x57, 0xa3, 0x1e, 0xb0, 0x9d, 0xea, 0x93, 0x66, 0xd8,
             0xbb, 0x79, 0xad, 0x80, 0xba,
         ];
         assert_eq!(&pkt_key, &expected_client_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(&provider, aead, &secret, &mut pkt_iv).is_ok());
         let expected_client_pkt_iv = [
             0x6b, 0x26, 0x11, 0x4b, 0x9c, 0xba, 0x2b, 0x63, 0xa9, 0xe8, 0xdd,
             0x4f,
         ];
         assert_eq!(&pkt_iv, &expected_client_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(&provider, aead, &secret, &mut hdr_key).is_ok());
         let expected_client_hdr_key = [
             0x9d, 0xdd, 0x12, 0xc9, 0x94, 0xc0, 0x69, 0x8b, 0x89, 0x37, 0x4a,
             0x9c, 0x07, 0x7a, 0x30, 0x77,
@@ -715,9 +1036,12 @@ mod tests {
         assert_eq!(&hdr_key, &expected_client_hdr_key);
 
         // Server.
-        assert!(
-            derive_server_initial_secret(&initial_secret, &mut secret).is_ok()
-        );
+        assert!(derive_server_initial_secret(
+            &provider,
+            &initial_secret,
+            &mut secret
+        )
+        .is_ok());
         let expected_server_initial_secret = [
             0x00, 0x6f, 0x88, 0x13, 0x59, 0x24, 0x4d, 0xd9, 0xad, 0x1a, 0xcf,
             0x85, 0xf5, 0x95, 0xba, 0xd6, 0x7c, 0x13, 0xf9, 0xf5, 0x58, 0x6f,
@@ -725,21 +1049,21 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_server_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(&provider, aead, &secret, &mut pkt_key).is_ok());
         let expected_server_pkt_key = [
             0x14, 0x9d, 0x0b, 0x16, 0x62, 0xab, 0x87, 0x1f, 0xbe, 0x63, 0xc4,
             0x9b, 0x5e, 0x65, 0x5a, 0x5d,
         ];
         assert_eq!(&pkt_key, &expected_server_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(&provider, aead, &secret, &mut pkt_iv).is_ok());
         let expected_server_pkt_iv = [
             0xba, 0xb2, 0xb1, 0x2a, 0x4c, 0x76, 0x01, 0x6a, 0xce, 0x47, 0x85,
             0x6d,
         ];
         assert_eq!(&pkt_iv, &expected_server_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(&provider, aead, &secret, &mut hdr_key).is_ok());
         let expected_server_hdr_key = [
             0xc0, 0xc4, 0x99, 0xa6, 0x5a, 0x60, 0x02, 0x4a, 0x18, 0xa2, 0x50,
             0x97, 0x4e, 0xa0, 0x1d, 0xfa,
@@ -756,15 +1080,24 @@ mod tests {
         let mut pkt_iv = [0; 12];
         let mut hdr_key = [0; 16];
 
+        let provider = DefaultCryptoProvider;
+
         let aead = Algorithm::AES128_GCM;
 
-        let initial_secret =
-            derive_initial_secret(&dcid, crate::PROTOCOL_VERSION_DRAFT27);
+        let initial_secret = derive_initial_secret(
+            &provider,
+            &dcid,
+            crate::PROTOCOL_VERSION_DRAFT27,
+        )
+        .unwrap();
 
         // Client.
-        assert!(
-            derive_client_initial_secret(&initial_secret, &mut secret).is_ok()
-        );
+        assert!(derive_client_initial_secret(
+            &provider,
+            &initial_secret,
+            &mut secret
+        )
+        .is_ok());
         let expected_client_initial_secret = [
             0xfd, 0xa3, 0x95, 0x3a, 0xec, 0xc0, 0x40, 0xe4, 0x8b, 0x34, 0xe2,
             0x7e, 0xf8, 0x7d, 0xe3, 0xa6, 0x09, 0x8e, 0xcf, 0x0e, 0x38, 0xb7,
@@ -772,21 +1105,21 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_client_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(&provider, aead, &secret, &mut pkt_key).is_ok());
         let expected_client_pkt_key = [
             0xaf, 0x7f, 0xd7, 0xef, 0xeb, 0xd2, 0x18, 0x78, 0xff, 0x66, 0x81,
             0x12, 0x48, 0x98, 0x36, 0x94,
         ];
         assert_eq!(&pkt_key, &expected_client_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(&provider, aead, &secret, &mut pkt_iv).is_ok());
         let expected_client_pkt_iv = [
             0x86, 0x81, 0x35, 0x94, 0x10, 0xa7, 0x0b, 0xb9, 0xc9, 0x2f, 0x04,
             0x20,
         ];
         assert_eq!(&pkt_iv, &expected_client_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(&provider, aead, &secret, &mut hdr_key).is_ok());
         let expected_client_hdr_key = [
             0xa9, 0x80, 0xb8, 0xb4, 0xfb, 0x7d, 0x9f, 0xbc, 0x13, 0xe8, 0x14,
             0xc2, 0x31, 0x64, 0x25, 0x3d,
@@ -794,9 +1127,12 @@ mod tests {
         assert_eq!(&hdr_key, &expected_client_hdr_key);
 
         // Server.
-        assert!(
-            derive_server_initial_secret(&initial_secret, &mut secret).is_ok()
-        );
+        assert!(derive_server_initial_secret(
+            &provider,
+            &initial_secret,
+            &mut secret
+        )
+        .is_ok());
         let expected_server_initial_secret = [
             0x55, 0x43, 0x66, 0xb8, 0x19, 0x12, 0xff, 0x90, 0xbe, 0x41, 0xf1,
             0x7e, 0x80, 0x22, 0x21, 0x30, 0x90, 0xab, 0x17, 0xd8, 0x14, 0x91,
@@ -804,21 +1140,21 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_server_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(&provider, aead, &secret, &mut pkt_key).is_ok());
         let expected_server_pkt_key = [
             0x5d, 0x51, 0xda, 0x9e, 0xe8, 0x97, 0xa2, 0x1b, 0x26, 0x59, 0xcc,
             0xc7, 0xe5, 0xbf, 0xa5, 0x77,
         ];
         assert_eq!(&pkt_key, &expected_server_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(&provider, aead, &secret, &mut pkt_iv).is_ok());
         let expected_server_pkt_iv = [
             0x5e, 0x5a, 0xe6, 0x51, 0xfd, 0x1e, 0x84, 0x95, 0xaf, 0x13, 0x50,
             0x8b,
         ];
         assert_eq!(&pkt_iv, &expected_server_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(&provider, aead, &secret, &mut hdr_key).is_ok());
         let expected_server_hdr_key = [
             0xa8, 0xed, 0x82, 0xe6, 0x66, 0x4f, 0x86, 0x5a, 0xed, 0xf6, 0x10,
             0x69, 0x43, 0xf9, 0x5f, 0xb8,
@@ -834,13 +1170,15 @@ mod tests {
             0x7d, 0x60, 0x60, 0xf6, 0x88, 0xf3, 0x0f, 0x21, 0x63, 0x2b,
         ];
 
+        let provider = DefaultCryptoProvider;
+
         let aead = Algorithm::ChaCha20_Poly1305;
 
         let mut pkt_key = [0; 32];
         let mut pkt_iv = [0; 12];
         let mut hdr_key = [0; 32];
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(&provider, aead, &secret, &mut pkt_key).is_ok());
         let expected_pkt_key = [
             0xc6, 0xd9, 0x8f, 0xf3, 0x44, 0x1c, 0x3f, 0xe1, 0xb2, 0x18, 0x20,
             0x94, 0xf6, 0x9c, 0xaa, 0x2e, 0xd4, 0xb7, 0x16, 0xb6, 0x54, 0x88,
@@ -848,14 +1186,14 @@ mod tests {
         ];
         assert_eq!(&pkt_key, &expected_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(&provider, aead, &secret, &mut pkt_iv).is_ok());
         let expected_pkt_iv = [
             0xe0, 0x45, 0x9b, 0x34, 0x74, 0xbd, 0xd0, 0xe4, 0x4a, 0x41, 0xc1,
             0x44,
         ];
         assert_eq!(&pkt_iv, &expected_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(&provider, aead, &secret, &mut hdr_key).is_ok());
         let expected_hdr_key = [
             0x25, 0xa2, 0x82, 0xb9, 0xe8, 0x2f, 0x06, 0xf2, 0x1f, 0x48, 0x89,
             0x17, 0xa4, 0xfc, 0x8f, 0x1b, 0x73, 0x57, 0x36, 0x85, 0x60, 0x85,
@@ -27,14 +27,26 @@
 use crate::Error;
 use crate::Result;
 
-use std::collections::VecDeque;
+use crate::time;
+use alloc::collections::VecDeque;
+
+use alloc::vec::Vec;
+
+/// A queued DATAGRAM, along with the time after which it is discarded.
+struct Datagram {
+    data: Vec<u8>,
+    expiry: Option<time::Instant>,
+}
 
 /// Keeps track of DATAGRAM frames.
 #[derive(Default)]
 pub struct DatagramQueue {
-    queue: VecDeque<Vec<u8>>,
+    queue: VecDeque<Datagram>,
     queue_max_len: usize,
     queue_bytes_size: usize,
+
+    /// The number of queued DATAGRAMs that have an expiry time.
+    expiring: usize,
 }
 
 impl DatagramQueue {
@@ -43,33 +55,44 @@ impl DatagramQueue {
             queue: VecDeque::with_capacity(queue_max_len),
             queue_bytes_size: 0,
             queue_max_len,
+            expiring: 0,
         }
     }
 
     pub fn push(&mut self, data: Vec<u8>) -> Result<()> {
+        self.push_with_expiry(data, None)
+    }
+
+    pub fn push_with_expiry(
+        &mut self, data: Vec<u8>, expiry: Option<time::Instant>,
+    ) -> Result<()> {
         if self.is_full() {
             return Err(Error::Done);
         }
 
+        if expiry.is_some() {
+            self.expiring += 1;
+        }
+
         self.queue_bytes_size += data.len();
-        self.queue.push_back(data);
+        self.queue.push_back(Datagram { data, expiry });
 
         Ok(())
     }
 
     pub fn peek_front_len(&self) -> Option<usize> {
-        self.queue.front().map(|d| d.len())
+        self.queue.front().map(|d| d.data.len())
     }
 
     pub fn peek_front_bytes(&self, buf: &mut [u8], len: usize) -> Result<usize> {
         match self.queue.front() {
             Some(d) => {
-                let len = std::cmp::min(len, d.len());
+                let len = core::cmp::min(len, d.data.len());
                 if buf.len() < len {
                     return Err(Error::BufferTooShort);
                 }
 
-                buf[..len].copy_from_slice(&d[..len]);
+                buf[..len].copy_from_slice(&d.data[..len]);
                 Ok(len)
             },
 
@@ -79,8 +102,14 @@ impl DatagramQueue {
 
     pub fn pop(&mut self) -> Option<Vec<u8>> {
         if let Some(d) = self.queue.pop_front() {
-            self.queue_bytes_size = self.queue_bytes_size.saturating_sub(d.len());
-            return Some(d);
+            self.queue_bytes_size =
+                self.queue_bytes_size.saturating_sub(d.data.len());
+
+            if d.expiry.is_some() {
+                self.expiring -= 1;
+            }
+
+            return Some(d.data);
         }
 
         None
@@ -91,9 +120,31 @@ impl DatagramQueue {
     }
 
     pub fn purge<F: Fn(&[u8]) -> bool>(&mut self, f: F) {
-        self.queue.retain(|d| !f(d));
+        self.queue.retain(|d| !f(&d.data));
+        self.update_sizes();
+    }
+
+    /// Drops the DATAGRAMs that expired as of `now`, and returns how many
+    /// were dropped.
+    pub fn purge_expired(&mut self, now: time::Instant) -> usize {
+        if self.expiring == 0 {
+            return 0;
+        }
+
+        let len = self.queue.len();
+
+        self.queue
+            .retain(|d| !matches!(d.expiry, Some(e) if e <= now));
+        self.update_sizes();
+
+        len - self.queue.len()
+    }
+
+    fn update_sizes(&mut self) {
         self.queue_bytes_size =
-            self.queue.iter().fold(0, |total, d| total + d.len());
+            self.queue.iter().fold(0, |total, d| total + d.data.len());
+
+        self.expiring = self.queue.iter().filter(|d| d.expiry.is_some()).count();
     }
 
     pub fn is_full(&self) -> bool {
@@ -107,4 +158,17 @@ impl DatagramQueue {
     pub fn byte_size(&self) -> usize {
         self.queue_bytes_size
     }
+
+    pub fn retained(&self) -> usize {
+        self.queue.capacity() * core::mem::size_of::<Datagram>() +
+            self.queue.iter().map(|d| d.data.capacity()).sum::<usize>()
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        for d in self.queue.iter_mut() {
+            d.data.shrink_to_fit();
+        }
+
+        self.queue.shrink_to_fit();
+    }
 }
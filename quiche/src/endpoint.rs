@@ -0,0 +1,920 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! A socket-owning endpoint dispatching packets to connections.
+//!
+//! The [`Endpoint`] type takes care of the parts of a QUIC application that
+//! are the same everywhere: it owns the UDP sockets and a [mio] event loop,
+//! routes incoming packets to connections based on their connection ID,
+//! performs version negotiation and, optionally, stateless retry, drives
+//! timers, and sends the packets connections generate. Applications only
+//! need to handle the [`Event`]s it reports and use the connections.
+//!
+//! This module requires the `endpoint` feature.
+//!
+//! ## Examples:
+//!
+//! ```no_run
+//! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+//! use quiche::endpoint::Endpoint;
+//! use quiche::endpoint::Event;
+//!
+//! let addr = "0.0.0.0:4433".parse().unwrap();
+//! let mut endpoint = Endpoint::server(addr, config).unwrap();
+//!
+//! let mut buf = [0; 65535];
+//!
+//! loop {
+//!     endpoint.poll(None).unwrap();
+//!
+//!     while let Some(event) = endpoint.next_event() {
+//!         if let Event::Readable(handle) = event {
+//!             let conn = endpoint.conn_mut(handle).unwrap();
+//!
+//!             for stream_id in conn.readable() {
+//!                 while let Ok((read, fin)) =
+//!                     conn.stream_recv(stream_id, &mut buf)
+//!                 {
+//!                     // Handle the stream data.
+//!                 }
+//!             }
+//!         }
+//!     }
+//! }
+//! # Ok::<(), quiche::Error>(())
+//! ```
+//!
+//! [`Endpoint`]: struct.Endpoint.html
+//! [`Event`]: enum.Event.html
+//! [mio]: https://docs.rs/mio
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use std::io;
+
+use std::net::IpAddr;
+use std::net::SocketAddr;
+
+use std::pin::Pin;
+
+use std::time;
+
+use ring::hmac;
+
+use crate::Config;
+use crate::Connection;
+use crate::ConnectionId;
+use crate::Header;
+use crate::RecvInfo;
+use crate::Type;
+
+use crate::rand;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// The length of the tag authenticating retry tokens.
+const TOKEN_TAG_LEN: usize = 16;
+
+/// Identifies a connection owned by an [`Endpoint`].
+///
+/// [`Endpoint`]: struct.Endpoint.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectionHandle(u64);
+
+/// An event reported by [`next_event()`].
+///
+/// [`next_event()`]: struct.Endpoint.html#method.next_event
+pub enum Event {
+    /// A connection was accepted from a client.
+    Accepted(ConnectionHandle),
+
+    /// The handshake of a connection completed.
+    Established(ConnectionHandle),
+
+    /// Packets were received on an established connection, which may have
+    /// made streams or datagrams readable.
+    Readable(ConnectionHandle),
+
+    /// A connection closed and was removed from the endpoint.
+    ///
+    /// The connection is handed back so that its final state, e.g. its
+    /// [`stats()`] or [`peer_error()`], can be inspected.
+    ///
+    /// [`stats()`]: ../struct.Connection.html#method.stats
+    /// [`peer_error()`]: ../struct.Connection.html#method.peer_error
+    Closed(ConnectionHandle, Pin<Box<Connection>>),
+}
+
+struct Entry {
+    conn: Pin<Box<Connection>>,
+
+    /// The index of the socket the connection's packets are sent on.
+    socket: usize,
+
+    established: bool,
+}
+
+/// A set of UDP sockets shared by any number of QUIC connections.
+pub struct Endpoint {
+    poll: mio::Poll,
+
+    events: mio::Events,
+
+    sockets: Vec<mio::net::UdpSocket>,
+
+    config: Config,
+
+    is_server: bool,
+
+    retry: bool,
+
+    conn_id_key: hmac::Key,
+
+    token_key: hmac::Key,
+
+    conns: HashMap<ConnectionHandle, Entry>,
+
+    ids: HashMap<ConnectionId<'static>, ConnectionHandle>,
+
+    next_handle: u64,
+
+    pending_events: VecDeque<Event>,
+
+    buf: Vec<u8>,
+
+    out: Vec<u8>,
+}
+
+impl Endpoint {
+    /// Creates an endpoint bound to `addr` that accepts connections from
+    /// clients, using `config` for all of them.
+    pub fn server(addr: SocketAddr, config: Config) -> io::Result<Endpoint> {
+        Endpoint::new(addr, config, true)
+    }
+
+    /// Creates an endpoint bound to `addr` that only makes connections to
+    /// servers.
+    ///
+    /// Connections are created with [`connect()`].
+    ///
+    /// [`connect()`]: struct.Endpoint.html#method.connect
+    pub fn client(addr: SocketAddr, config: Config) -> io::Result<Endpoint> {
+        Endpoint::new(addr, config, false)
+    }
+
+    fn new(
+        addr: SocketAddr, config: Config, is_server: bool,
+    ) -> io::Result<Endpoint> {
+        let poll = mio::Poll::new()?;
+
+        let mut endpoint = Endpoint {
+            poll,
+
+            events: mio::Events::with_capacity(1024),
+
+            sockets: Vec::new(),
+
+            config,
+
+            is_server,
+
+            retry: false,
+
+            conn_id_key: new_hmac_key(),
+
+            token_key: new_hmac_key(),
+
+            conns: HashMap::new(),
+
+            ids: HashMap::new(),
+
+            next_handle: 0,
+
+            pending_events: VecDeque::new(),
+
+            buf: vec![0; MAX_DATAGRAM_SIZE],
+
+            out: vec![0; MAX_DATAGRAM_SIZE],
+        };
+
+        endpoint.add_socket(addr)?;
+
+        Ok(endpoint)
+    }
+
+    /// Binds an additional socket to `addr`, e.g. to accept connections on
+    /// both IPv4 and IPv6, and returns its local address.
+    ///
+    /// Connections accepted by a server are bound to the socket their first
+    /// packet was received on. Client connections use the first socket of
+    /// the same address family as the server.
+    pub fn add_socket(&mut self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        let socket = mio::net::UdpSocket::bind(&addr)?;
+
+        self.poll.register(
+            &socket,
+            mio::Token(self.sockets.len()),
+            mio::Ready::readable(),
+            mio::PollOpt::edge(),
+        )?;
+
+        let local_addr = socket.local_addr()?;
+
+        self.sockets.push(socket);
+
+        Ok(local_addr)
+    }
+
+    /// Configures whether to validate client addresses with a stateless
+    /// retry before accepting connections.
+    ///
+    /// Retry tokens are authenticated with a key generated when the endpoint
+    /// is created, and bound to the client's IP address.
+    ///
+    /// The default value is `false`.
+    pub fn enable_retry(&mut self, v: bool) {
+        self.retry = v;
+    }
+
+    /// Returns the local address the endpoint's first socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sockets[0].local_addr()
+    }
+
+    /// Returns the local addresses of all the endpoint's sockets.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.sockets.iter().map(|s| s.local_addr()).collect()
+    }
+
+    /// Returns a mutable reference to the configuration used for new
+    /// connections.
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    /// Creates a client connection to `peer`.
+    ///
+    /// The connection's packets are sent on the next call to [`poll()`].
+    ///
+    /// [`poll()`]: struct.Endpoint.html#method.poll
+    pub fn connect(
+        &mut self, server_name: Option<&str>, peer: SocketAddr,
+    ) -> io::Result<ConnectionHandle> {
+        let mut scid = [0; crate::MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid);
+        let scid = ConnectionId::from_vec(scid.to_vec());
+
+        let conn = crate::connect(server_name, &scid, peer, &mut self.config)
+            .map_err(to_io_error)?;
+
+        let socket = self
+            .sockets
+            .iter()
+            .position(|s| {
+                matches!(s.local_addr(), Ok(a) if a.is_ipv4() == peer.is_ipv4())
+            })
+            .unwrap_or(0);
+
+        Ok(self.insert(scid, conn, socket))
+    }
+
+    /// Issues a new source connection ID to the peer of the connection
+    /// identified by `handle`, and routes packets carrying it to the
+    /// connection.
+    ///
+    /// This should be used instead of the connection's [`new_scid()`], as the
+    /// endpoint drops packets carrying IDs it doesn't know about. IDs retired
+    /// by the peer stop being routed automatically.
+    ///
+    /// On success the ID's sequence number is returned. [`InvalidState`] is
+    /// returned if there is no such connection.
+    ///
+    /// [`new_scid()`]: ../struct.Connection.html#method.new_scid
+    /// [`InvalidState`]: ../enum.Error.html#variant.InvalidState
+    pub fn new_scid(&mut self, handle: ConnectionHandle) -> crate::Result<u64> {
+        let entry = self
+            .conns
+            .get_mut(&handle)
+            .ok_or(crate::Error::InvalidState)?;
+
+        let mut scid = [0; crate::MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid);
+        let scid = ConnectionId::from_vec(scid.to_vec());
+
+        let mut reset_token = [0; 16];
+        rand::rand_bytes(&mut reset_token);
+        let reset_token = u128::from_be_bytes(reset_token);
+
+        let seq = entry.conn.new_scid(&scid, reset_token)?;
+
+        self.ids.insert(scid, handle);
+
+        Ok(seq)
+    }
+
+    /// Returns a reference to the connection identified by `handle`.
+    pub fn conn(&self, handle: ConnectionHandle) -> Option<&Connection> {
+        self.conns.get(&handle).map(|e| &*e.conn)
+    }
+
+    /// Returns a mutable reference to the connection identified by `handle`.
+    ///
+    /// Packets generated by calls made through the returned reference are
+    /// sent on the next call to [`poll()`]. New connection IDs should be
+    /// issued with [`new_scid()`] instead.
+    ///
+    /// [`poll()`]: struct.Endpoint.html#method.poll
+    /// [`new_scid()`]: struct.Endpoint.html#method.new_scid
+    pub fn conn_mut(
+        &mut self, handle: ConnectionHandle,
+    ) -> Option<&mut Connection> {
+        self.conns.get_mut(&handle).map(|e| &mut *e.conn)
+    }
+
+    /// Returns the handles of all the connections owned by the endpoint.
+    pub fn handles(&self) -> Vec<ConnectionHandle> {
+        self.conns.keys().copied().collect()
+    }
+
+    /// Returns the next event, if any.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.pending_events.pop_front()
+    }
+
+    /// Runs one iteration of the event loop.
+    ///
+    /// This sends the packets connections have to send, waits until packets
+    /// are received, a connection's timer expires or `timeout` elapses, and
+    /// processes what happened. Events are then available from
+    /// [`next_event()`].
+    ///
+    /// Errors caused by individual packets, whether received or sent, are
+    /// logged and the packets dropped. Only errors of the event loop itself
+    /// are returned.
+    ///
+    /// [`next_event()`]: struct.Endpoint.html#method.next_event
+    pub fn poll(&mut self, timeout: Option<time::Duration>) -> io::Result<()> {
+        self.flush();
+
+        let conn_timeout =
+            self.conns.values().filter_map(|e| e.conn.timeout()).min();
+
+        let timeout = match (timeout, conn_timeout) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+
+            (a, b) => a.or(b),
+        };
+
+        self.poll.poll(&mut self.events, timeout)?;
+
+        // Sockets are read until they would block, whether or not they were
+        // reported as readable.
+        for socket in 0..self.sockets.len() {
+            self.recv(socket);
+        }
+
+        for entry in self.conns.values_mut() {
+            if entry.conn.timeout() == Some(time::Duration::ZERO) {
+                entry.conn.on_timeout();
+            }
+
+            // Packets carrying IDs retired by the peer are not expected
+            // anymore.
+            while let Some(id) = entry.conn.retired_scid_next() {
+                self.ids.remove(&id);
+            }
+        }
+
+        self.flush();
+
+        self.collect_closed();
+
+        Ok(())
+    }
+
+    /// Sends all the packets the connections have to send.
+    fn flush(&mut self) {
+        let sockets = &self.sockets;
+
+        let mut blocked = vec![false; sockets.len()];
+
+        for entry in self.conns.values_mut() {
+            loop {
+                let (len, info) = match entry.conn.send(&mut self.out) {
+                    Ok(v) => v,
+
+                    Err(crate::Error::Done) => break,
+
+                    Err(e) => {
+                        error!("{} send failed: {:?}", entry.conn.trace_id(), e);
+
+                        entry.conn.close(false, 0x1, b"fail").ok();
+                        break;
+                    },
+                };
+
+                // Packets probing a path are sent from the socket bound to
+                // the path's local address.
+                let socket = info
+                    .from
+                    .and_then(|from| {
+                        sockets
+                            .iter()
+                            .position(|s| s.local_addr().ok() == Some(from))
+                    })
+                    .unwrap_or(entry.socket);
+
+                if blocked[socket] {
+                    continue;
+                }
+
+                // In all cases the packet is lost, and loss recovery will take
+                // care of it.
+                match sockets[socket].send_to(&self.out[..len], &info.to) {
+                    Ok(_) => (),
+
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        trace!("send() would block");
+
+                        blocked[socket] = true;
+                    },
+
+                    Err(e) => {
+                        error!(
+                            "{} send to {} failed: {:?}",
+                            entry.conn.trace_id(),
+                            info.to,
+                            e
+                        );
+                    },
+                }
+            }
+        }
+    }
+
+    /// Reads all the available packets from a socket and dispatches them.
+    fn recv(&mut self, socket: usize) {
+        let mut readable = Vec::new();
+
+        loop {
+            let (len, from) = match self.sockets[socket].recv_from(&mut self.buf)
+            {
+                Ok(v) => v,
+
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+
+                // Some platforms report ICMP errors caused by earlier packets
+                // this way.
+                Err(e) => {
+                    error!("recv() failed: {:?}", e);
+                    break;
+                },
+            };
+
+            let mut pkt = self.buf[..len].to_vec();
+
+            let handle = match self.dispatch(&mut pkt, from, socket) {
+                Some(v) => v,
+
+                None => continue,
+            };
+
+            let entry = self.conns.get_mut(&handle).unwrap();
+
+            let info = RecvInfo { from };
+
+            if let Err(e) = entry.conn.recv(&mut pkt, info) {
+                error!("{} recv failed: {:?}", entry.conn.trace_id(), e);
+                continue;
+            }
+
+            if !entry.established && entry.conn.is_established() {
+                entry.established = true;
+
+                self.pending_events.push_back(Event::Established(handle));
+            }
+
+            if entry.established && !readable.contains(&handle) {
+                readable.push(handle);
+            }
+        }
+
+        self.pending_events
+            .extend(readable.into_iter().map(Event::Readable));
+    }
+
+    /// Finds the connection a packet received on `socket` belongs to,
+    /// creating it if the packet starts a new one.
+    ///
+    /// Returns `None` if the packet should be dropped.
+    fn dispatch(
+        &mut self, pkt: &mut [u8], from: SocketAddr, socket: usize,
+    ) -> Option<ConnectionHandle> {
+        let hdr = match Header::from_slice(pkt, crate::MAX_CONN_ID_LEN) {
+            Ok(v) => v,
+
+            Err(e) => {
+                trace!("parsing packet header failed: {:?}", e);
+                return None;
+            },
+        };
+
+        if let Some(&handle) = self.ids.get(&hdr.dcid) {
+            return Some(handle);
+        }
+
+        if !self.is_server || hdr.ty != Type::Initial {
+            return None;
+        }
+
+        let conn_id = self.derive_conn_id(&hdr.dcid);
+
+        // A retransmission of the client's first Initial packet.
+        if let Some(&handle) = self.ids.get(&conn_id) {
+            return Some(handle);
+        }
+
+        if !self.config.is_version_supported(hdr.version) {
+            let len = match crate::negotiate_version_with_config(
+                &hdr.scid,
+                &hdr.dcid,
+                &self.config,
+                &mut self.out,
+            ) {
+                Ok(v) => v,
+
+                Err(e) => {
+                    error!("version negotiation failed: {:?}", e);
+                    return None;
+                },
+            };
+
+            self.send_stateless(len, from, socket);
+
+            return None;
+        }
+
+        let mut scid = conn_id;
+        let mut odcid = None;
+
+        if self.retry {
+            // Token is always present in Initial packets.
+            let token = hdr.token.as_deref().unwrap_or_default();
+
+            if token.is_empty() {
+                let token = self.mint_token(&hdr, &from);
+
+                let len = match crate::retry(
+                    &hdr.scid,
+                    &hdr.dcid,
+                    &scid,
+                    &token,
+                    hdr.version,
+                    &mut self.out,
+                ) {
+                    Ok(v) => v,
+
+                    Err(e) => {
+                        error!("retry failed: {:?}", e);
+                        return None;
+                    },
+                };
+
+                self.send_stateless(len, from, socket);
+
+                return None;
+            }
+
+            odcid = match self.validate_token(&from, token) {
+                Some(v) => Some(v),
+
+                None => {
+                    trace!("invalid address validation token");
+                    return None;
+                },
+            };
+
+            // Reuse the source connection ID sent in the Retry packet.
+            scid = hdr.dcid.clone().into_owned();
+        }
+
+        let conn =
+            match crate::accept(&scid, odcid.as_ref(), from, &mut self.config) {
+                Ok(v) => v,
+
+                Err(e) => {
+                    error!("accepting connection from {} failed: {:?}", from, e);
+                    return None;
+                },
+            };
+
+        let handle = self.insert(scid, conn, socket);
+
+        self.pending_events.push_back(Event::Accepted(handle));
+
+        Some(handle)
+    }
+
+    fn insert(
+        &mut self, scid: ConnectionId<'static>, conn: Pin<Box<Connection>>,
+        socket: usize,
+    ) -> ConnectionHandle {
+        let handle = ConnectionHandle(self.next_handle);
+        self.next_handle += 1;
+
+        self.ids.insert(scid, handle);
+
+        self.conns.insert(handle, Entry {
+            conn,
+
+            socket,
+
+            established: false,
+        });
+
+        handle
+    }
+
+    /// Removes closed connections and reports them.
+    fn collect_closed(&mut self) {
+        let closed: Vec<ConnectionHandle> = self
+            .conns
+            .iter()
+            .filter(|(_, e)| e.conn.is_closed())
+            .map(|(&h, _)| h)
+            .collect();
+
+        for handle in closed {
+            self.ids.retain(|_, h| *h != handle);
+
+            let entry = self.conns.remove(&handle).unwrap();
+
+            self.pending_events
+                .push_back(Event::Closed(handle, entry.conn));
+        }
+    }
+
+    fn send_stateless(&mut self, len: usize, to: SocketAddr, socket: usize) {
+        match self.sockets[socket].send_to(&self.out[..len], &to) {
+            Ok(_) => (),
+
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+
+            Err(e) => error!("send to {} failed: {:?}", to, e),
+        }
+    }
+
+    /// Derives the server's connection ID from the client's original
+    /// destination connection ID, so that retransmitted Initial packets
+    /// reach the same connection.
+    fn derive_conn_id(&self, dcid: &[u8]) -> ConnectionId<'static> {
+        let tag = hmac::sign(&self.conn_id_key, dcid);
+
+        ConnectionId::from_vec(tag.as_ref()[..crate::MAX_CONN_ID_LEN].to_vec())
+    }
+
+    /// Generates a retry token binding the client's address to its original
+    /// destination connection ID.
+    fn mint_token(&self, hdr: &Header, src: &SocketAddr) -> Vec<u8> {
+        let tag = hmac::sign(&self.token_key, &token_input(src, &hdr.dcid));
+
+        let mut token = tag.as_ref()[..TOKEN_TAG_LEN].to_vec();
+        token.extend_from_slice(&hdr.dcid);
+
+        token
+    }
+
+    /// Validates a retry token, and returns the original destination
+    /// connection ID stored in it.
+    fn validate_token(
+        &self, src: &SocketAddr, token: &[u8],
+    ) -> Option<ConnectionId<'static>> {
+        if token.len() < TOKEN_TAG_LEN {
+            return None;
+        }
+
+        let (tag, odcid) = token.split_at(TOKEN_TAG_LEN);
+
+        let expected = hmac::sign(&self.token_key, &token_input(src, odcid));
+
+        if ring::constant_time::verify_slices_are_equal(
+            &expected.as_ref()[..TOKEN_TAG_LEN],
+            tag,
+        )
+        .is_err()
+        {
+            return None;
+        }
+
+        Some(ConnectionId::from_vec(odcid.to_vec()))
+    }
+}
+
+fn token_input(src: &SocketAddr, odcid: &[u8]) -> Vec<u8> {
+    let mut input = match src.ip() {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    };
+
+    input.extend_from_slice(odcid);
+
+    input
+}
+
+fn new_hmac_key() -> hmac::Key {
+    let mut key = [0; 32];
+    rand::rand_bytes(&mut key);
+
+    hmac::Key::new(hmac::HMAC_SHA256, &key)
+}
+
+fn to_io_error(e: crate::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(is_server: bool) -> Config {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+
+        if is_server {
+            config
+                .load_cert_chain_from_pem_file("examples/cert.crt")
+                .unwrap();
+            config
+                .load_priv_key_from_pem_file("examples/cert.key")
+                .unwrap();
+        }
+
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_max_idle_timeout(5_000);
+        config.verify_peer(false);
+
+        config
+    }
+
+    /// Polls both endpoints until `done` returns `true` for an event.
+    fn run_until<F>(
+        server: &mut Endpoint, client: &mut Endpoint, mut done: F,
+    ) -> Vec<Event>
+    where
+        F: FnMut(&Event) -> bool,
+    {
+        let mut events = Vec::new();
+
+        for _ in 0..1000 {
+            let timeout = Some(time::Duration::from_millis(10));
+
+            client.poll(timeout).unwrap();
+            server.poll(timeout).unwrap();
+
+            while let Some(e) =
+                client.next_event().or_else(|| server.next_event())
+            {
+                let is_done = done(&e);
+                events.push(e);
+
+                if is_done {
+                    return events;
+                }
+            }
+        }
+
+        panic!("condition not met");
+    }
+
+    fn handshake(retry: bool) {
+        let addr = "127.0.0.1:0".parse().unwrap();
+
+        let mut server = Endpoint::server(addr, config(true)).unwrap();
+        server.enable_retry(retry);
+        let server_addr = server.local_addr().unwrap();
+
+        let mut client = Endpoint::client(addr, config(false)).unwrap();
+        let handle = client.connect(Some("quic.tech"), server_addr).unwrap();
+
+        let mut server_handle = None;
+
+        run_until(&mut server, &mut client, |e| {
+            if let Event::Accepted(h) = e {
+                server_handle = Some(*h);
+            }
+
+            matches!(e, Event::Established(h) if Some(*h) == server_handle)
+        });
+
+        let server_handle = server_handle.unwrap();
+
+        assert!(client.conn(handle).unwrap().is_established());
+        assert_eq!(client.conn(handle).unwrap().did_retry, retry);
+
+        let conn = client.conn_mut(handle).unwrap();
+        assert_eq!(conn.stream_send(4, b"hello", true), Ok(5));
+
+        run_until(
+            &mut server,
+            &mut client,
+            |e| matches!(e, Event::Readable(h) if *h == server_handle),
+        );
+
+        let mut buf = [0; 5];
+        let conn = server.conn_mut(server_handle).unwrap();
+        assert_eq!(conn.stream_recv(4, &mut buf), Ok((5, true)));
+        assert_eq!(&buf, b"hello");
+
+        let conn = client.conn_mut(handle).unwrap();
+        assert_eq!(conn.close(true, 0x00, b"bye"), Ok(()));
+
+        let events = run_until(
+            &mut server,
+            &mut client,
+            |e| matches!(e, Event::Closed(h, _) if *h == server_handle),
+        );
+
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, Event::Closed(h, _) if *h == handle)));
+        assert!(server.handles().is_empty());
+    }
+
+    #[test]
+    fn connect() {
+        handshake(false);
+    }
+
+    #[test]
+    fn connect_with_retry() {
+        handshake(true);
+    }
+
+    #[test]
+    fn additional_socket() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+
+        let mut server = Endpoint::server(addr, config(true)).unwrap();
+        let server_addr = server.add_socket(addr).unwrap();
+        assert_eq!(server.local_addrs().unwrap().len(), 2);
+
+        let mut client = Endpoint::client(addr, config(false)).unwrap();
+        let handle = client.connect(Some("quic.tech"), server_addr).unwrap();
+
+        let mut server_handle = None;
+
+        run_until(&mut server, &mut client, |e| {
+            if let Event::Accepted(h) = e {
+                server_handle = Some(*h);
+            }
+
+            matches!(e, Event::Established(h) if Some(*h) == server_handle)
+        });
+
+        let server_handle = server_handle.unwrap();
+
+        // The connection is bound to the socket the client connected to.
+        assert_eq!(server.conns[&server_handle].socket, 1);
+        assert!(client.conn(handle).unwrap().is_established());
+
+        // New connection IDs are routed to the connection.
+        let ids = server.ids.len();
+        assert!(server.new_scid(server_handle).is_ok());
+        assert_eq!(server.ids.len(), ids + 1);
+        assert!(server.ids.values().all(|h| *h == server_handle));
+
+        assert_eq!(
+            server.new_scid(ConnectionHandle(42)),
+            Err(crate::Error::InvalidState)
+        );
+    }
+}
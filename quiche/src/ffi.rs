@@ -149,6 +149,22 @@ pub extern fn quiche_config_load_priv_key_from_pem_file(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_config_add_cert_for_name(
+    config: &mut Config, name: *const c_char, cert_path: *const c_char,
+    key_path: *const c_char,
+) -> c_int {
+    let name = unsafe { ffi::CStr::from_ptr(name).to_str().unwrap() };
+    let cert_path = unsafe { ffi::CStr::from_ptr(cert_path).to_str().unwrap() };
+    let key_path = unsafe { ffi::CStr::from_ptr(key_path).to_str().unwrap() };
+
+    match config.add_cert_for_name(name, cert_path, key_path) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_config_load_verify_locations_from_file(
     config: &mut Config, path: *const c_char,
@@ -167,11 +183,75 @@ pub extern fn quiche_config_verify_peer(config: &mut Config, v: bool) {
     config.verify_peer(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_verify_peer_required_client_cert(
+    config: &mut Config, v: bool,
+) {
+    config.verify_peer_required_client_cert(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_load_verify_locations_from_directory(
+    config: &mut Config, path: *const c_char,
+) -> c_int {
+    let path = unsafe { ffi::CStr::from_ptr(path).to_str().unwrap() };
+
+    match config.load_verify_locations_from_directory(path) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_config_grease(config: &mut Config, v: bool) {
     config.grease(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_grease_transport_params(
+    config: &mut Config, v: bool,
+) {
+    config.grease_transport_params(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_grease_versions(config: &mut Config, v: bool) {
+    config.grease_versions(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_grease_index(config: &mut Config, index: u64) {
+    config.set_grease_index(index);
+}
+
+#[no_mangle]
+#[cfg(not(feature = "rustls"))]
+pub extern fn quiche_config_set_ticket_key(
+    config: &mut Config, key: *const u8, key_len: size_t,
+) -> c_int {
+    let key = unsafe { slice::from_raw_parts(key, key_len) };
+
+    match config.set_ticket_key(key) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_supported_versions(
+    config: &mut Config, versions: *const u32, versions_len: size_t,
+) -> c_int {
+    let versions = unsafe { slice::from_raw_parts(versions, versions_len) };
+
+    match config.set_supported_versions(versions) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_config_log_keys(config: &mut Config) {
     config.log_keys();
@@ -182,6 +262,116 @@ pub extern fn quiche_config_enable_early_data(config: &mut Config) {
     config.enable_early_data();
 }
 
+#[no_mangle]
+#[cfg(not(feature = "rustls"))]
+pub extern fn quiche_config_set_ech_config_list(
+    config: &mut Config, list: *const u8, list_len: size_t,
+) -> c_int {
+    let list = unsafe { slice::from_raw_parts(list, list_len) };
+
+    match config.set_ech_config_list(list) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+#[cfg(not(feature = "rustls"))]
+pub extern fn quiche_config_add_application_settings(
+    config: &mut Config, proto: *const u8, proto_len: size_t,
+    settings: *const u8, settings_len: size_t,
+) -> c_int {
+    let proto = unsafe { slice::from_raw_parts(proto, proto_len) };
+    let settings = unsafe { slice::from_raw_parts(settings, settings_len) };
+
+    match config.add_application_settings(proto, settings) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[cfg(not(feature = "rustls"))]
+type AlpnSelectCallback = extern fn(
+    server_name: *const u8,
+    server_name_len: size_t,
+    protos: *const u8,
+    protos_len: size_t,
+    argp: *mut c_void,
+) -> c_int;
+
+#[cfg(not(feature = "rustls"))]
+struct FfiAlpnSelector {
+    cb: AlpnSelectCallback,
+    argp: atomic::AtomicPtr<c_void>,
+}
+
+#[cfg(not(feature = "rustls"))]
+impl AlpnSelector for FfiAlpnSelector {
+    fn select<'a>(
+        &self, server_name: Option<&str>, offered: &[&'a [u8]],
+    ) -> Option<&'a [u8]> {
+        let mut protos = Vec::new();
+
+        for proto in offered {
+            protos.push(proto.len() as u8);
+            protos.extend_from_slice(proto);
+        }
+
+        let (name, name_len) = match server_name {
+            Some(v) => (v.as_ptr(), v.len()),
+
+            None => (ptr::null(), 0),
+        };
+
+        let index = (self.cb)(
+            name,
+            name_len,
+            protos.as_ptr(),
+            protos.len(),
+            self.argp.load(atomic::Ordering::Relaxed),
+        );
+
+        if index < 0 {
+            return None;
+        }
+
+        offered.get(index as usize).copied()
+    }
+}
+
+#[no_mangle]
+#[cfg(not(feature = "rustls"))]
+pub extern fn quiche_config_set_alpn_selector(
+    config: &mut Config, cb: AlpnSelectCallback, argp: *mut c_void,
+) -> c_int {
+    let argp = atomic::AtomicPtr::new(argp);
+
+    match config.set_alpn_selector(Arc::new(FfiAlpnSelector { cb, argp })) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+#[cfg(not(feature = "rustls"))]
+pub extern fn quiche_config_add_ech_key(
+    config: &mut Config, ech_config: *const u8, ech_config_len: size_t,
+    private_key: *const u8, private_key_len: size_t, is_retry_config: bool,
+) -> c_int {
+    let ech_config = unsafe { slice::from_raw_parts(ech_config, ech_config_len) };
+    let private_key =
+        unsafe { slice::from_raw_parts(private_key, private_key_len) };
+
+    match config.add_ech_key(ech_config, private_key, is_retry_config) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_application_protos(
     config: &mut Config, protos: *const u8, protos_len: size_t,
@@ -264,6 +454,13 @@ pub extern fn quiche_config_set_disable_active_migration(
     config.set_disable_active_migration(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_active_connection_id_limit(
+    config: &mut Config, v: u64,
+) {
+    config.set_active_connection_id_limit(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_cc_algorithm_name(
     config: &mut Config, name: *const c_char,
@@ -283,11 +480,146 @@ pub extern fn quiche_config_set_cc_algorithm(
     config.set_cc_algorithm(algo);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_ocsp_response(
+    config: &mut Config, response: *const u8, response_len: size_t,
+) -> c_int {
+    let response = unsafe { slice::from_raw_parts(response, response_len) };
+
+    match config.set_ocsp_response(response) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_sct_list(
+    config: &mut Config, list: *const u8, list_len: size_t,
+) -> c_int {
+    let list = unsafe { slice::from_raw_parts(list, list_len) };
+
+    match config.set_sct_list(list) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_ocsp_stapling(config: &mut Config) {
+    config.enable_ocsp_stapling();
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_signed_cert_timestamps(config: &mut Config) {
+    config.enable_signed_cert_timestamps();
+}
+
+type CertStatusVerifyCallback = extern fn(
+    certs: *const *const u8,
+    certs_len: *const size_t,
+    certs_count: size_t,
+    ocsp_response: *const u8,
+    ocsp_response_len: size_t,
+    sct_list: *const u8,
+    sct_list_len: size_t,
+    argp: *mut c_void,
+) -> bool;
+
+struct FfiCertStatusVerifier {
+    cb: CertStatusVerifyCallback,
+    argp: atomic::AtomicPtr<c_void>,
+}
+
+impl CertStatusVerifier for FfiCertStatusVerifier {
+    fn verify(
+        &self, cert_chain: &[&[u8]], ocsp_response: Option<&[u8]>,
+        sct_list: Option<&[u8]>,
+    ) -> bool {
+        let certs: Vec<*const u8> =
+            cert_chain.iter().map(|c| c.as_ptr()).collect();
+        let certs_len: Vec<size_t> = cert_chain.iter().map(|c| c.len()).collect();
+
+        let (ocsp, ocsp_len) = match ocsp_response {
+            Some(v) => (v.as_ptr(), v.len()),
+
+            None => (ptr::null(), 0),
+        };
+
+        let (sct, sct_len) = match sct_list {
+            Some(v) => (v.as_ptr(), v.len()),
+
+            None => (ptr::null(), 0),
+        };
+
+        (self.cb)(
+            certs.as_ptr(),
+            certs_len.as_ptr(),
+            certs.len(),
+            ocsp,
+            ocsp_len,
+            sct,
+            sct_len,
+            self.argp.load(atomic::Ordering::Relaxed),
+        )
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_cert_status_verifier(
+    config: &mut Config, cb: CertStatusVerifyCallback, argp: *mut c_void,
+) {
+    let argp = atomic::AtomicPtr::new(argp);
+
+    config.set_cert_status_verifier(Arc::new(FfiCertStatusVerifier { cb, argp }));
+}
+
 #[no_mangle]
 pub extern fn quiche_config_enable_hystart(config: &mut Config, v: bool) {
     config.enable_hystart(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_dscp(config: &mut Config, v: u8) {
+    config.set_dscp(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_events(config: &mut Config, v: bool) {
+    config.enable_events(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_max_events(config: &mut Config, v: size_t) {
+    config.set_max_events(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_packet_threshold(config: &mut Config, v: u64) {
+    config.set_packet_threshold(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_time_threshold(config: &mut Config, v: f64) {
+    config.set_time_threshold(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_max_pto_backoff(config: &mut Config, v: u32) {
+    config.set_max_pto_backoff(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_initial_rtt(config: &mut Config, v: u64) {
+    config.set_initial_rtt(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_buffer_pool_size(config: &mut Config, v: size_t) {
+    config.set_buffer_pool_size(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_enable_dgram(
     config: &mut Config, enabled: bool, recv_queue_len: size_t,
@@ -296,6 +628,25 @@ pub extern fn quiche_config_enable_dgram(
     config.enable_dgram(enabled, recv_queue_len, send_queue_len);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_dgram_send_order(
+    config: &mut Config, order: DgramSendOrder,
+) {
+    config.set_dgram_send_order(order);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_dgram_send_weight(
+    config: &mut Config, v: size_t,
+) {
+    config.set_dgram_send_weight(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_dgram_recv_ttl(config: &mut Config, v: u64) {
+    config.set_dgram_recv_ttl(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_max_send_udp_payload_size(
     config: &mut Config, v: size_t,
@@ -315,6 +666,34 @@ pub extern fn quiche_config_set_max_stream_window(config: &mut Config, v: u64) {
     config.set_max_stream_window(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_stream_credit_policy(
+    config: &mut Config, policy: StreamCreditPolicy,
+) {
+    config.set_stream_credit_policy(policy);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_max_buffered_bytes(
+    config: &mut Config, v: size_t,
+) {
+    config.set_max_buffered_bytes(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_max_undecryptable_packets(
+    config: &mut Config, v: size_t,
+) {
+    config.set_max_undecryptable_packets(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_stats_history_len(
+    config: &mut Config, v: size_t,
+) {
+    config.set_stats_history_len(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_free(config: *mut Config) {
     unsafe { Box::from_raw(config) };
@@ -484,6 +863,31 @@ pub extern fn quiche_retry(
 }
 
 #[no_mangle]
+pub extern fn quiche_refuse(
+    scid: *const u8, scid_len: size_t, dcid: *const u8, dcid_len: size_t,
+    new_scid: *const u8, new_scid_len: size_t, error_code: u64, version: u32,
+    out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    let scid = unsafe { slice::from_raw_parts(scid, scid_len) };
+    let scid = ConnectionId::from_ref(scid);
+
+    let dcid = unsafe { slice::from_raw_parts(dcid, dcid_len) };
+    let dcid = ConnectionId::from_ref(dcid);
+
+    let new_scid = unsafe { slice::from_raw_parts(new_scid, new_scid_len) };
+    let new_scid = ConnectionId::from_ref(new_scid);
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match refuse(&scid, &dcid, &new_scid, error_code, version, out) {
+        Ok(v) => v as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
+#[no_mangle]
+#[cfg(not(feature = "rustls"))]
 pub extern fn quiche_conn_new_with_tls(
     scid: *const u8, scid_len: size_t, odcid: *const u8, odcid_len: size_t,
     peer: &sockaddr, peer_len: socklen_t, config: &mut Config, ssl: *mut c_void,
@@ -540,6 +944,79 @@ pub extern fn quiche_conn_set_keylog_path(
     true
 }
 
+type PacketTapCallback = extern fn(
+    sent: bool,
+    at: *const timespec,
+    peer: *const sockaddr,
+    peer_len: socklen_t,
+    data: *const u8,
+    data_len: size_t,
+    argp: *mut c_void,
+);
+
+struct FfiPacketTap {
+    cb: PacketTapCallback,
+    argp: atomic::AtomicPtr<c_void>,
+}
+
+impl PacketTap for FfiPacketTap {
+    fn on_datagram(&mut self, info: &TapInfo, data: &[u8]) {
+        let mut at = unsafe { std::mem::zeroed::<timespec>() };
+        std_time_to_c(&info.at, &mut at);
+
+        let mut peer = unsafe { std::mem::zeroed::<sockaddr_storage>() };
+        let peer_len = std_addr_to_c(&info.peer, &mut peer);
+
+        (self.cb)(
+            info.direction == PacketDirection::Sent,
+            &at,
+            &peer as *const _ as *const sockaddr,
+            peer_len,
+            data.as_ptr(),
+            data.len(),
+            self.argp.load(atomic::Ordering::Relaxed),
+        );
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_set_dscp(conn: &mut Connection, v: u8) {
+    conn.set_dscp(v);
+}
+
+/// The built-in stream schedulers, for `quiche_conn_set_stream_scheduler()`.
+#[repr(C)]
+pub enum StreamSchedulerKind {
+    Priority   = 0,
+    Fifo       = 1,
+    RoundRobin = 2,
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_set_stream_scheduler(
+    conn: &mut Connection, kind: StreamSchedulerKind,
+) {
+    let scheduler: Box<dyn StreamScheduler> = match kind {
+        StreamSchedulerKind::Priority => Box::new(PriorityScheduler::default()),
+
+        StreamSchedulerKind::Fifo => Box::new(FifoScheduler::default()),
+
+        StreamSchedulerKind::RoundRobin =>
+            Box::new(RoundRobinScheduler::default()),
+    };
+
+    conn.set_stream_scheduler(scheduler);
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_set_packet_tap(
+    conn: &mut Connection, cb: PacketTapCallback, argp: *mut c_void,
+) {
+    let argp = atomic::AtomicPtr::new(argp);
+
+    conn.set_packet_tap(Box::new(FfiPacketTap { cb, argp }));
+}
+
 #[no_mangle]
 #[cfg(unix)]
 pub extern fn quiche_conn_set_keylog_fd(conn: &mut Connection, fd: c_int) {
@@ -600,8 +1077,63 @@ pub extern fn quiche_conn_set_qlog_fd(
 }
 
 #[no_mangle]
-pub extern fn quiche_conn_set_session(
-    conn: &mut Connection, buf: *const u8, buf_len: size_t,
+#[cfg(feature = "qlog")]
+pub extern fn quiche_conn_set_qlog_path_with_format(
+    conn: &mut Connection, path: *const c_char, log_title: *const c_char,
+    log_desc: *const c_char, format: QlogFormat,
+) -> bool {
+    let filename = unsafe { ffi::CStr::from_ptr(path).to_str().unwrap() };
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(filename);
+
+    let writer = match file {
+        Ok(f) => std::io::BufWriter::new(f),
+
+        Err(_) => return false,
+    };
+
+    let title = unsafe { ffi::CStr::from_ptr(log_title).to_str().unwrap() };
+    let description = unsafe { ffi::CStr::from_ptr(log_desc).to_str().unwrap() };
+
+    conn.set_qlog_with_format(
+        Box::new(writer),
+        title.to_string(),
+        format!("{} id={}", description, conn.trace_id),
+        QlogLevel::Base,
+        format,
+    );
+
+    true
+}
+
+#[no_mangle]
+#[cfg(all(unix, feature = "qlog"))]
+pub extern fn quiche_conn_set_qlog_fd_with_format(
+    conn: &mut Connection, fd: c_int, log_title: *const c_char,
+    log_desc: *const c_char, format: QlogFormat,
+) {
+    let f = unsafe { std::fs::File::from_raw_fd(fd) };
+    let writer = std::io::BufWriter::new(f);
+
+    let title = unsafe { ffi::CStr::from_ptr(log_title).to_str().unwrap() };
+    let description = unsafe { ffi::CStr::from_ptr(log_desc).to_str().unwrap() };
+
+    conn.set_qlog_with_format(
+        Box::new(writer),
+        title.to_string(),
+        format!("{} id={}", description, conn.trace_id),
+        QlogLevel::Base,
+        format,
+    );
+}
+
+#[no_mangle]
+#[cfg(not(feature = "rustls"))]
+pub extern fn quiche_conn_set_session(
+    conn: &mut Connection, buf: *const u8, buf_len: size_t,
 ) -> c_int {
     let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
 
@@ -612,6 +1144,32 @@ pub extern fn quiche_conn_set_session(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_set_token(
+    conn: &mut Connection, buf: *const u8, buf_len: size_t,
+) -> c_int {
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    match conn.set_token(buf) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_send_new_token(
+    conn: &mut Connection, buf: *const u8, buf_len: size_t,
+) -> c_int {
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    match conn.send_new_token(buf) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[repr(C)]
 pub struct RecvInfo<'a> {
     from: &'a sockaddr,
@@ -636,11 +1194,15 @@ pub extern fn quiche_conn_recv(
 
     let buf = unsafe { slice::from_raw_parts_mut(buf, buf_len) };
 
-    match conn.recv(buf, info.into()) {
+    let rc = match conn.recv(buf, info.into()) {
         Ok(v) => v as ssize_t,
 
         Err(e) => e.to_c(),
-    }
+    };
+
+    run_callbacks(conn, true);
+
+    rc
 }
 
 #[repr(C)]
@@ -651,6 +1213,16 @@ pub struct SendInfo {
     at: timespec,
 }
 
+#[repr(C)]
+pub struct SendInfoExt {
+    size: size_t,
+
+    from: sockaddr_storage,
+    from_len: socklen_t,
+
+    dscp: u8,
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_send(
     conn: &mut Connection, out: *mut u8, out_len: size_t, out_info: &mut SendInfo,
@@ -661,17 +1233,100 @@ pub extern fn quiche_conn_send(
 
     let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
 
-    match conn.send(out) {
+    let rc = match conn.send(out) {
+        Ok((v, info)) => {
+            send_info_to_c(&info, out_info);
+
+            v as ssize_t
+        },
+
+        Err(e) => e.to_c(),
+    };
+
+    run_callbacks(conn, false);
+
+    rc
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_send_ext(
+    conn: &mut Connection, out: *mut u8, out_len: size_t,
+    out_info: &mut SendInfo, out_ext: *mut SendInfoExt,
+) -> ssize_t {
+    if out_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    let rc = match conn.send(out) {
         Ok((v, info)) => {
-            out_info.to_len = std_addr_to_c(&info.to, &mut out_info.to);
+            send_info_to_c(&info, out_info);
+            send_info_ext_to_c(&info, out_ext);
+
+            v as ssize_t
+        },
+
+        Err(e) => e.to_c(),
+    };
+
+    run_callbacks(conn, false);
+
+    rc
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_send_burst(
+    conn: &mut Connection, out: *mut u8, out_len: size_t, segment_size: size_t,
+    out_info: &mut SendInfo, out_ext: *mut SendInfoExt,
+) -> ssize_t {
+    if out_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
 
-            std_time_to_c(&info.at, &mut out_info.at);
+    let rc = match conn.send_burst(out, segment_size) {
+        Ok((v, info)) => {
+            send_info_to_c(&info, out_info);
+            send_info_ext_to_c(&info, out_ext);
 
             v as ssize_t
         },
 
         Err(e) => e.to_c(),
+    };
+
+    run_callbacks(conn, false);
+
+    rc
+}
+
+fn send_info_to_c(info: &crate::SendInfo, out_info: &mut SendInfo) {
+    out_info.to_len = std_addr_to_c(&info.to, &mut out_info.to);
+
+    std_time_to_c(&info.at, &mut out_info.at);
+}
+
+fn send_info_ext_to_c(info: &crate::SendInfo, out_ext: *mut SendInfoExt) {
+    if out_ext.is_null() {
+        return;
+    }
+
+    let mut ext = SendInfoExt {
+        size: std::mem::size_of::<SendInfoExt>(),
+
+        from: unsafe { std::mem::zeroed() },
+        from_len: 0,
+
+        dscp: info.dscp,
+    };
+
+    if let Some(from) = info.from {
+        ext.from_len = std_addr_to_c(&from, &mut ext.from);
     }
+
+    unsafe { copy_versioned(&ext, out_ext) };
 }
 
 #[no_mangle]
@@ -696,6 +1351,47 @@ pub extern fn quiche_conn_stream_recv(
     out_len as ssize_t
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_stream_peek(
+    conn: &Connection, stream_id: u64, out: *mut u8, out_len: size_t,
+    fin: &mut bool,
+) -> ssize_t {
+    if out_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    let (out_len, out_fin) = match conn.stream_peek(stream_id, out) {
+        Ok(v) => v,
+
+        Err(e) => return e.to_c(),
+    };
+
+    *fin = out_fin;
+
+    out_len as ssize_t
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_stream_consume(
+    conn: &mut Connection, stream_id: u64, len: size_t, fin: &mut bool,
+) -> ssize_t {
+    if len > <ssize_t>::max_value() as usize {
+        panic!("The provided length is too large");
+    }
+
+    let (len, out_fin) = match conn.stream_consume(stream_id, len) {
+        Ok(v) => v,
+
+        Err(e) => return e.to_c(),
+    };
+
+    *fin = out_fin;
+
+    len as ssize_t
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_stream_send(
     conn: &mut Connection, stream_id: u64, buf: *const u8, buf_len: size_t,
@@ -725,6 +1421,20 @@ pub extern fn quiche_conn_stream_priority(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_stream_set_deadline(
+    conn: &mut Connection, stream_id: u64, timeout_ms: u64, err: u64,
+) -> c_int {
+    let deadline =
+        conn.clock.now() + std::time::Duration::from_millis(timeout_ms);
+
+    match conn.stream_set_deadline(stream_id, deadline, err) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_stream_shutdown(
     conn: &mut Connection, stream_id: u64, direction: Shutdown, err: u64,
@@ -776,6 +1486,11 @@ pub extern fn quiche_conn_max_send_udp_payload_size(conn: &Connection) -> usize
     conn.max_send_udp_payload_size()
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_send_quantum(conn: &Connection) -> size_t {
+    conn.send_quantum()
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_is_readable(conn: &Connection) -> bool {
     conn.is_readable()
@@ -814,11 +1529,15 @@ pub extern fn quiche_conn_close(
 ) -> c_int {
     let reason = unsafe { slice::from_raw_parts(reason, reason_len) };
 
-    match conn.close(app, err, reason) {
+    let rc = match conn.close(app, err, reason) {
         Ok(_) => 0,
 
         Err(e) => e.to_c() as c_int,
-    }
+    };
+
+    run_callbacks(conn, false);
+
+    rc
 }
 
 #[no_mangle]
@@ -840,92 +1559,434 @@ pub extern fn quiche_conn_timeout_as_millis(conn: &mut Connection) -> u64 {
 }
 
 #[no_mangle]
-pub extern fn quiche_conn_on_timeout(conn: &mut Connection) {
-    conn.on_timeout()
-}
+pub extern fn quiche_conn_timeout_instant(
+    conn: &Connection, out: &mut timespec, reason: &mut c_int,
+) -> c_int {
+    let (at, r) = match conn.timeout_instant() {
+        Some(v) => v,
 
-#[no_mangle]
-pub extern fn quiche_conn_trace_id(
-    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
-) {
-    let trace_id = conn.trace_id();
+        None => return Error::Done.to_c() as c_int,
+    };
 
-    *out = trace_id.as_ptr();
-    *out_len = trace_id.len();
-}
+    std_time_to_c(&at, out);
 
-#[no_mangle]
-pub extern fn quiche_conn_source_id(
-    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
-) {
-    let conn_id = conn.source_id();
-    let id = conn_id.as_ref();
-    *out = id.as_ptr();
-    *out_len = id.len();
-}
+    *reason = match r {
+        TimeoutReason::Draining => 0,
 
-#[no_mangle]
-pub extern fn quiche_conn_destination_id(
-    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
-) {
-    let conn_id = conn.destination_id();
-    let id = conn_id.as_ref();
+        TimeoutReason::Idle => 1,
 
-    *out = id.as_ptr();
-    *out_len = id.len();
-}
+        TimeoutReason::KeepAlive => 2,
 
-#[no_mangle]
-pub extern fn quiche_conn_application_proto(
-    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
-) {
-    let proto = conn.application_proto();
+        TimeoutReason::LossDetection => 3,
 
-    *out = proto.as_ptr();
-    *out_len = proto.len();
-}
+        TimeoutReason::Pacing => 4,
 
-#[no_mangle]
-pub extern fn quiche_conn_peer_cert(
-    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
-) {
-    match conn.peer_cert() {
-        Some(peer_cert) => {
-            *out = peer_cert.as_ptr();
-            *out_len = peer_cert.len();
-        },
+        TimeoutReason::PathValidation => 5,
 
-        None => *out_len = 0,
-    }
-}
+        TimeoutReason::StreamDeadline => 6,
 
-#[no_mangle]
-pub extern fn quiche_conn_session(
-    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
-) {
-    match conn.session() {
-        Some(session) => {
-            *out = session.as_ptr();
-            *out_len = session.len();
-        },
+        TimeoutReason::Shutdown => 7,
+    };
 
-        None => *out_len = 0,
-    }
+    0
 }
 
 #[no_mangle]
-pub extern fn quiche_conn_is_established(conn: &mut Connection) -> bool {
-    conn.is_established()
+pub extern fn quiche_conn_on_timeout(conn: &mut Connection) {
+    conn.on_timeout();
+
+    run_callbacks(conn, true);
 }
 
-#[no_mangle]
-pub extern fn quiche_conn_is_in_early_data(conn: &mut Connection) -> bool {
-    conn.is_in_early_data()
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ConnCallbacks {
+    size: size_t,
+    on_stream_readable: Option<
+        extern fn(conn: *mut Connection, stream_id: u64, argp: *mut c_void),
+    >,
+    on_dgram: Option<ConnCallback>,
+    on_handshake_done: Option<ConnCallback>,
+    on_closed: Option<ConnCallback>,
 }
 
-#[no_mangle]
-pub extern fn quiche_conn_is_draining(conn: &mut Connection) -> bool {
-    conn.is_draining()
+type ConnCallback = extern fn(conn: *mut Connection, argp: *mut c_void);
+
+#[derive(Clone, Copy)]
+pub struct Callbacks {
+    cbs: ConnCallbacks,
+    argp: *mut c_void,
+
+    // Whether the handshake completion and the connection closure were
+    // reported already, so each is reported exactly once whichever call
+    // caused it.
+    handshake_done_reported: bool,
+    closed_reported: bool,
+}
+
+// The embedder is responsible for making `argp` safe to use from whichever
+// thread drives the connection.
+unsafe impl Send for Callbacks {}
+unsafe impl Sync for Callbacks {}
+
+#[no_mangle]
+pub extern fn quiche_conn_set_callbacks(
+    conn: &mut Connection, cbs: *const ConnCallbacks, argp: *mut c_void,
+) -> bool {
+    if cbs.is_null() {
+        conn.ffi_callbacks = None;
+        return true;
+    }
+
+    let mut out = ConnCallbacks {
+        size: std::mem::size_of::<ConnCallbacks>(),
+        on_stream_readable: None,
+        on_dgram: None,
+        on_handshake_done: None,
+        on_closed: None,
+    };
+
+    // Callbacks missing from older versions of the structure stay unset. Each
+    // callback is copied only if the structure is large enough to hold all
+    // of it, so a size that doesn't land on a field boundary can't result in
+    // a partially copied function pointer.
+    unsafe {
+        let size = (*cbs).size;
+
+        macro_rules! field_end {
+            ($field:ident) => {
+                ptr::addr_of!((*cbs).$field) as usize - cbs as usize +
+                    std::mem::size_of_val(&out.$field)
+            };
+        }
+
+        // The first callback was part of the structure from the start, so
+        // anything smaller is not a valid structure.
+        if size < field_end!(on_stream_readable) {
+            return false;
+        }
+
+        out.on_stream_readable = (*cbs).on_stream_readable;
+
+        if size >= field_end!(on_dgram) {
+            out.on_dgram = (*cbs).on_dgram;
+        }
+
+        if size >= field_end!(on_handshake_done) {
+            out.on_handshake_done = (*cbs).on_handshake_done;
+        }
+
+        if size >= field_end!(on_closed) {
+            out.on_closed = (*cbs).on_closed;
+        }
+    }
+
+    // Only changes happening after the callbacks are registered are
+    // reported.
+    conn.ffi_callbacks = Some(Callbacks {
+        cbs: out,
+        argp,
+        handshake_done_reported: conn.is_established(),
+        closed_reported: conn.is_closed(),
+    });
+
+    true
+}
+
+/// Invokes the registered callbacks for the connection state changes that
+/// weren't reported yet and, if `readiness` is true, for the streams and
+/// DATAGRAMs that can be read.
+fn run_callbacks(conn: &mut Connection, readiness: bool) {
+    let is_established = conn.is_established();
+    let is_closed = conn.is_closed();
+
+    let wants_readable = match conn.ffi_callbacks {
+        Some(ref cb) => readiness && cb.cbs.on_stream_readable.is_some(),
+
+        None => false,
+    };
+
+    let readable: Vec<u64> = if wants_readable {
+        conn.readable().collect()
+    } else {
+        Vec::new()
+    };
+
+    let has_dgram = readiness && conn.dgram_recv_queue_len() > 0;
+
+    let cb = match conn.ffi_callbacks.as_mut() {
+        Some(v) => v,
+
+        None => return,
+    };
+
+    let handshake_done = is_established && !cb.handshake_done_reported;
+    let closed = is_closed && !cb.closed_reported;
+
+    // Update the state before calling back, in case the callbacks cause
+    // callbacks to be run again.
+    cb.handshake_done_reported |= is_established;
+    cb.closed_reported |= is_closed;
+
+    let cb = *cb;
+
+    // The callbacks are allowed to call back into the connection.
+    let conn = conn as *mut Connection;
+
+    if let (true, Some(f)) = (handshake_done, cb.cbs.on_handshake_done) {
+        f(conn, cb.argp);
+    }
+
+    if let Some(f) = cb.cbs.on_stream_readable {
+        for stream_id in readable {
+            f(conn, stream_id, cb.argp);
+        }
+    }
+
+    if let (true, Some(f)) = (has_dgram, cb.cbs.on_dgram) {
+        f(conn, cb.argp);
+    }
+
+    if let (true, Some(f)) = (closed, cb.cbs.on_closed) {
+        f(conn, cb.argp);
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_trace_id(
+    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
+) {
+    let trace_id = conn.trace_id();
+
+    *out = trace_id.as_ptr();
+    *out_len = trace_id.len();
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_source_id(
+    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
+) {
+    let conn_id = conn.source_id();
+    let id = conn_id.as_ref();
+    *out = id.as_ptr();
+    *out_len = id.len();
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_destination_id(
+    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
+) {
+    let conn_id = conn.destination_id();
+    let id = conn_id.as_ref();
+
+    *out = id.as_ptr();
+    *out_len = id.len();
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_application_proto(
+    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
+) {
+    let proto = conn.application_proto();
+
+    *out = proto.as_ptr();
+    *out_len = proto.len();
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_peer_application_protos(
+    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
+) {
+    let protos = conn.peer_application_protos();
+
+    *out = protos.as_ptr();
+    *out_len = protos.len();
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_cipher_suite(
+    conn: &mut Connection, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    tls_name_to_c(conn.cipher_suite(), out, out_len)
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_key_exchange_group(
+    conn: &mut Connection, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    tls_name_to_c(conn.key_exchange_group(), out, out_len)
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_peer_signature_algorithm(
+    conn: &mut Connection, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    tls_name_to_c(conn.peer_signature_algorithm(), out, out_len)
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_ocsp_response(
+    conn: &mut Connection, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    tls_bytes_to_c(conn.ocsp_response(), out, out_len)
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_sct_list(
+    conn: &mut Connection, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    tls_bytes_to_c(conn.sct_list(), out, out_len)
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_export_keying_material(
+    conn: &Connection, label: *const u8, label_len: size_t, context: *const u8,
+    context_len: size_t, out: *mut u8, out_len: size_t,
+) -> c_int {
+    let label = unsafe { slice::from_raw_parts(label, label_len) };
+    let context = unsafe { slice::from_raw_parts(context, context_len) };
+
+    match conn.export_keying_material(label, context, out_len) {
+        Ok(v) => {
+            let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+            out.copy_from_slice(&v);
+
+            0
+        },
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+fn tls_name_to_c(name: Option<String>, out: *mut u8, out_len: size_t) -> ssize_t {
+    tls_bytes_to_c(name.map(String::into_bytes), out, out_len)
+}
+
+fn tls_bytes_to_c(
+    buf: Option<Vec<u8>>, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    let buf = match buf {
+        Some(v) => v,
+
+        None => return Error::Done.to_c(),
+    };
+
+    if buf.len() > out_len {
+        return Error::BufferTooShort.to_c();
+    }
+
+    let out = unsafe { slice::from_raw_parts_mut(out, buf.len()) };
+    out.copy_from_slice(&buf);
+
+    buf.len() as ssize_t
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_peer_cert(
+    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
+) {
+    match conn.peer_cert() {
+        Some(peer_cert) => {
+            *out = peer_cert.as_ptr();
+            *out_len = peer_cert.len();
+        },
+
+        None => *out_len = 0,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_session(
+    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
+) {
+    match conn.session() {
+        Some(session) => {
+            *out = session.as_ptr();
+            *out_len = session.len();
+        },
+
+        None => *out_len = 0,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_new_token(
+    conn: &Connection, out: &mut *const u8, out_len: &mut size_t,
+) {
+    match conn.new_token() {
+        Some(token) => {
+            *out = token.as_ptr();
+            *out_len = token.len();
+        },
+
+        None => *out_len = 0,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_token_kind(conn: &Connection) -> c_int {
+    match conn.token_kind() {
+        Some(kind) => kind as c_int,
+
+        None => Error::Done.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_is_established(conn: &mut Connection) -> bool {
+    conn.is_established()
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_is_in_early_data(conn: &mut Connection) -> bool {
+    conn.is_in_early_data()
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_is_early_data_accepted(conn: &Connection) -> bool {
+    conn.is_early_data_accepted()
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_is_resumed(conn: &mut Connection) -> bool {
+    conn.is_resumed()
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_is_ech_accepted(conn: &mut Connection) -> bool {
+    conn.is_ech_accepted()
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_peer_application_settings(
+    conn: &mut Connection, out: &mut *const u8, out_len: &mut size_t,
+) -> bool {
+    match conn.peer_application_settings() {
+        Some(settings) => {
+            *out = settings.as_ptr();
+            *out_len = settings.len();
+
+            true
+        },
+
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_is_draining(conn: &mut Connection) -> bool {
+    conn.is_draining()
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_draining_timeout_as_nanos(
+    conn: &mut Connection,
+) -> u64 {
+    match conn.draining_timeout() {
+        Some(timeout) => timeout.as_nanos() as u64,
+
+        None => std::u64::MAX,
+    }
 }
 
 #[no_mangle]
@@ -950,113 +2011,463 @@ pub extern fn quiche_conn_peer_error(
             *reason = conn_err.reason.as_ptr();
             *reason_len = conn_err.reason.len();
 
-            true
+            true
+        },
+
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_peer_error_frame_type(
+    conn: &mut Connection, frame_type: &mut u64,
+) -> bool {
+    match conn.peer_error.as_ref().and_then(|e| e.frame_type) {
+        Some(v) => {
+            *frame_type = v;
+
+            true
+        },
+
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_local_error(
+    conn: &mut Connection, is_app: *mut bool, error_code: *mut u64,
+    reason: &mut *const u8, reason_len: &mut size_t,
+) -> bool {
+    match &conn.local_error {
+        Some(conn_err) => unsafe {
+            *is_app = conn_err.is_app;
+            *error_code = conn_err.error_code;
+            *reason = conn_err.reason.as_ptr();
+            *reason_len = conn_err.reason.len();
+
+            true
+        },
+
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_stream_iter_next(
+    iter: &mut StreamIter, stream_id: *mut u64,
+) -> bool {
+    if let Some(v) = iter.next() {
+        unsafe { *stream_id = v };
+        return true;
+    }
+
+    false
+}
+
+#[no_mangle]
+pub extern fn quiche_stream_iter_free(iter: *mut StreamIter) {
+    unsafe { Box::from_raw(iter) };
+}
+
+#[repr(C)]
+pub struct Stats {
+    recv: usize,
+    sent: usize,
+    lost: usize,
+    retrans: usize,
+    rtt: u64,
+    cwnd: usize,
+    sent_bytes: u64,
+    lost_bytes: u64,
+    recv_bytes: u64,
+    stream_retrans_bytes: u64,
+    pmtu: usize,
+    delivery_rate: u64,
+    peer_max_idle_timeout: u64,
+    peer_max_udp_payload_size: u64,
+    peer_initial_max_data: u64,
+    peer_initial_max_stream_data_bidi_local: u64,
+    peer_initial_max_stream_data_bidi_remote: u64,
+    peer_initial_max_stream_data_uni: u64,
+    peer_initial_max_streams_bidi: u64,
+    peer_initial_max_streams_uni: u64,
+    peer_ack_delay_exponent: u64,
+    peer_max_ack_delay: u64,
+    peer_disable_active_migration: bool,
+    peer_active_conn_id_limit: u64,
+    peer_max_datagram_frame_size: ssize_t,
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_stats(conn: &Connection, out: &mut Stats) {
+    let stats = conn.stats();
+
+    out.recv = stats.recv;
+    out.sent = stats.sent;
+    out.lost = stats.lost;
+    out.retrans = stats.retrans;
+    out.rtt = stats.rtt.as_nanos() as u64;
+    out.cwnd = stats.cwnd;
+    out.sent_bytes = stats.sent_bytes;
+    out.lost_bytes = stats.lost_bytes;
+    out.recv_bytes = stats.recv_bytes;
+    out.stream_retrans_bytes = stats.stream_retrans_bytes;
+    out.pmtu = stats.pmtu;
+    out.delivery_rate = stats.delivery_rate;
+    out.peer_max_idle_timeout = stats.peer_max_idle_timeout;
+    out.peer_max_udp_payload_size = stats.peer_max_udp_payload_size;
+    out.peer_initial_max_data = stats.peer_initial_max_data;
+    out.peer_initial_max_stream_data_bidi_local =
+        stats.peer_initial_max_stream_data_bidi_local;
+    out.peer_initial_max_stream_data_bidi_remote =
+        stats.peer_initial_max_stream_data_bidi_remote;
+    out.peer_initial_max_stream_data_uni = stats.peer_initial_max_stream_data_uni;
+    out.peer_initial_max_streams_bidi = stats.peer_initial_max_streams_bidi;
+    out.peer_initial_max_streams_uni = stats.peer_initial_max_streams_uni;
+    out.peer_ack_delay_exponent = stats.peer_ack_delay_exponent;
+    out.peer_max_ack_delay = stats.peer_max_ack_delay;
+    out.peer_disable_active_migration = stats.peer_disable_active_migration;
+    out.peer_active_conn_id_limit = stats.peer_active_conn_id_limit;
+    out.peer_max_datagram_frame_size = match stats.peer_max_datagram_frame_size {
+        None => Error::Done.to_c(),
+
+        Some(v) => v as ssize_t,
+    }
+}
+
+// The structures below are versioned by their leading `size` field, which the
+// caller sets to the size of the structure it was compiled against. Fields are
+// only ever appended, and no more than `size` bytes are written back, so that
+// applications built against older headers keep working.
+#[repr(C)]
+pub struct StatsExt {
+    size: size_t,
+    amplification_limited: size_t,
+    dgram_sent_dropped_expired: size_t,
+    dgram_recv_dropped_expired: size_t,
+    dgram_send_queue_bytes: size_t,
+    dgram_recv_queue_bytes: size_t,
+    pacing_rate: u64,
+    ssthresh: size_t,
+    cc_state: c_int,
+    congestion_events: size_t,
+    pto: size_t,
+    spurious_lost: size_t,
+    data_blocked: size_t,
+    data_blocked_time: u64,
+    stream_data_blocked: size_t,
+    stream_data_blocked_time: u64,
+    peer_data_blocked: size_t,
+    peer_stream_data_blocked: size_t,
+    buffered_bytes: size_t,
+    retained_bytes: size_t,
+    events_dropped: size_t,
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_stats_ext(conn: &Connection, out: *mut StatsExt) {
+    let stats = conn.stats();
+
+    let cc_state = match stats.cc_state {
+        crate::CongestionState::SlowStart => 0,
+
+        crate::CongestionState::CongestionAvoidance => 1,
+
+        crate::CongestionState::Recovery => 2,
+    };
+
+    let ext = StatsExt {
+        size: std::mem::size_of::<StatsExt>(),
+
+        amplification_limited: stats.amplification_limited,
+
+        dgram_sent_dropped_expired: stats.dgram_sent_dropped_expired,
+
+        dgram_recv_dropped_expired: stats.dgram_recv_dropped_expired,
+
+        dgram_send_queue_bytes: stats.dgram_send_queue_bytes,
+
+        dgram_recv_queue_bytes: stats.dgram_recv_queue_bytes,
+
+        pacing_rate: stats.pacing_rate,
+
+        ssthresh: stats.ssthresh,
+
+        cc_state,
+
+        congestion_events: stats.congestion_events,
+
+        pto: stats.pto,
+
+        spurious_lost: stats.spurious_lost,
+
+        data_blocked: stats.data_blocked,
+
+        data_blocked_time: stats.data_blocked_time.as_nanos() as u64,
+
+        stream_data_blocked: stats.stream_data_blocked,
+
+        stream_data_blocked_time: stats.stream_data_blocked_time.as_nanos()
+            as u64,
+
+        peer_data_blocked: stats.peer_data_blocked,
+
+        peer_stream_data_blocked: stats.peer_stream_data_blocked,
+
+        buffered_bytes: stats.buffered_bytes,
+
+        retained_bytes: stats.retained_bytes,
+
+        events_dropped: stats.events_dropped,
+    };
+
+    unsafe { copy_versioned(&ext, out) };
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_compact(conn: &mut Connection) {
+    conn.compact()
+}
+
+#[repr(C)]
+pub struct PathStats {
+    size: size_t,
+    peer_addr: sockaddr_storage,
+    peer_addr_len: socklen_t,
+    active: bool,
+    validated: bool,
+    rtt: u64,
+    rttvar: u64,
+    min_rtt: u64,
+    cwnd: size_t,
+    bytes_in_flight: size_t,
+    pmtu: size_t,
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_path_stats(
+    conn: &Connection, idx: size_t, out: *mut PathStats,
+) -> c_int {
+    let stats = match conn.path_stats().nth(idx) {
+        Some(v) => v,
+
+        None => return Error::Done.to_c() as c_int,
+    };
+
+    let mut path = PathStats {
+        size: std::mem::size_of::<PathStats>(),
+
+        peer_addr: unsafe { std::mem::zeroed() },
+
+        peer_addr_len: 0,
+
+        active: stats.active,
+
+        validated: stats.validated,
+
+        rtt: stats.rtt.as_nanos() as u64,
+
+        rttvar: stats.rttvar.as_nanos() as u64,
+
+        min_rtt: stats.min_rtt.as_nanos() as u64,
+
+        cwnd: stats.cwnd,
+
+        bytes_in_flight: stats.bytes_in_flight,
+
+        pmtu: stats.pmtu,
+    };
+
+    path.peer_addr_len = std_addr_to_c(&stats.peer_addr, &mut path.peer_addr);
+
+    unsafe { copy_versioned(&path, out) };
+
+    0
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_resume_state(
+    conn: &Connection, out_cwnd: &mut size_t, out_rtt: &mut u64,
+) -> c_int {
+    match conn.resume_state() {
+        Some(state) => {
+            *out_cwnd = state.cwnd;
+            *out_rtt = state.rtt.as_nanos() as u64;
+
+            0
+        },
+
+        None => Error::Done.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_set_resume_state(
+    conn: &mut Connection, peer: &sockaddr, peer_len: socklen_t, cwnd: size_t,
+    rtt: u64,
+) -> c_int {
+    let state = ResumeState {
+        peer_addr: std_addr_from_c(peer, peer_len),
+
+        cwnd,
+
+        rtt: std::time::Duration::from_nanos(rtt),
+    };
+
+    match conn.set_resume_state(&state) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_new_scid(
+    conn: &mut Connection, scid: *const u8, scid_len: size_t,
+    reset_token: *const u8, scid_seq: &mut u64,
+) -> c_int {
+    let scid = unsafe { slice::from_raw_parts(scid, scid_len) };
+    let scid = ConnectionId::from_ref(scid);
+
+    let mut token = [0; 16];
+    token.copy_from_slice(unsafe { slice::from_raw_parts(reset_token, 16) });
+    let reset_token = u128::from_be_bytes(token);
+
+    match conn.new_scid(&scid, reset_token) {
+        Ok(v) => {
+            *scid_seq = v;
+
+            0
         },
 
-        None => false,
+        Err(e) => e.to_c() as c_int,
     }
 }
 
 #[no_mangle]
-pub extern fn quiche_conn_local_error(
-    conn: &mut Connection, is_app: *mut bool, error_code: *mut u64,
-    reason: &mut *const u8, reason_len: &mut size_t,
-) -> bool {
-    match &conn.local_error {
-        Some(conn_err) => unsafe {
-            *is_app = conn_err.is_app;
-            *error_code = conn_err.error_code;
-            *reason = conn_err.reason.as_ptr();
-            *reason_len = conn_err.reason.len();
+pub extern fn quiche_conn_retired_scid_next(
+    conn: &mut Connection, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    if out_len < MAX_CONN_ID_LEN {
+        return Error::BufferTooShort.to_c();
+    }
 
-            true
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match conn.retired_scid_next() {
+        Some(scid) => {
+            out[..scid.len()].copy_from_slice(&scid);
+
+            scid.len() as ssize_t
         },
 
-        None => false,
+        None => Error::Done.to_c(),
     }
 }
 
 #[no_mangle]
-pub extern fn quiche_stream_iter_next(
-    iter: &mut StreamIter, stream_id: *mut u64,
-) -> bool {
-    if let Some(v) = iter.next() {
-        unsafe { *stream_id = v };
-        return true;
-    }
-
-    false
+pub extern fn quiche_conn_available_dcids(conn: &Connection) -> size_t {
+    conn.available_dcids()
 }
 
 #[no_mangle]
-pub extern fn quiche_stream_iter_free(iter: *mut StreamIter) {
-    unsafe { Box::from_raw(iter) };
+pub extern fn quiche_conn_probe_path(
+    conn: &mut Connection, local: &sockaddr, local_len: socklen_t,
+    peer: &sockaddr, peer_len: socklen_t, dcid_seq: &mut u64,
+) -> c_int {
+    let local = std_addr_from_c(local, local_len);
+    let peer = std_addr_from_c(peer, peer_len);
+
+    match conn.probe_path(local, peer) {
+        Ok(v) => {
+            *dcid_seq = v;
+
+            0
+        },
+
+        Err(e) => e.to_c() as c_int,
+    }
 }
 
-#[repr(C)]
-pub struct Stats {
-    recv: usize,
-    sent: usize,
-    lost: usize,
-    retrans: usize,
-    rtt: u64,
-    cwnd: usize,
-    sent_bytes: u64,
-    lost_bytes: u64,
-    recv_bytes: u64,
-    stream_retrans_bytes: u64,
-    pmtu: usize,
-    delivery_rate: u64,
-    peer_max_idle_timeout: u64,
-    peer_max_udp_payload_size: u64,
-    peer_initial_max_data: u64,
-    peer_initial_max_stream_data_bidi_local: u64,
-    peer_initial_max_stream_data_bidi_remote: u64,
-    peer_initial_max_stream_data_uni: u64,
-    peer_initial_max_streams_bidi: u64,
-    peer_initial_max_streams_uni: u64,
-    peer_ack_delay_exponent: u64,
-    peer_max_ack_delay: u64,
-    peer_disable_active_migration: bool,
-    peer_active_conn_id_limit: u64,
-    peer_max_datagram_frame_size: ssize_t,
+#[no_mangle]
+pub extern fn quiche_conn_path_event_next(
+    conn: &mut Connection, out_type: &mut c_int, local: &mut sockaddr_storage,
+    local_len: &mut socklen_t, peer: &mut sockaddr_storage,
+    peer_len: &mut socklen_t,
+) -> c_int {
+    let (ty, l, p) = match conn.path_event_next() {
+        Some(PathEvent::Validated(l, p)) => (0, l, p),
+
+        Some(PathEvent::FailedValidation(l, p)) => (1, l, p),
+
+        Some(PathEvent::PeerMigrated(l, p)) => (2, l, p),
+
+        Some(PathEvent::PeerMigrationFailed(l, p)) => (3, l, p),
+
+        None => return Error::Done.to_c() as c_int,
+    };
+
+    *out_type = ty;
+    *local_len = std_addr_to_c(&l, local);
+    *peer_len = std_addr_to_c(&p, peer);
+
+    0
 }
 
 #[no_mangle]
-pub extern fn quiche_conn_stats(conn: &Connection, out: &mut Stats) {
-    let stats = conn.stats();
+pub extern fn quiche_conn_poll_event(
+    conn: &mut Connection, out_type: &mut c_int, stream_id: &mut u64,
+    value: &mut u64,
+) -> c_int {
+    let (ty, id, v) = match conn.poll_event() {
+        Some(TransportEvent::HandshakeCompleted) => (0, 0, 0),
 
-    out.recv = stats.recv;
-    out.sent = stats.sent;
-    out.lost = stats.lost;
-    out.retrans = stats.retrans;
-    out.rtt = stats.rtt.as_nanos() as u64;
-    out.cwnd = stats.cwnd;
-    out.sent_bytes = stats.sent_bytes;
-    out.lost_bytes = stats.lost_bytes;
-    out.recv_bytes = stats.recv_bytes;
-    out.stream_retrans_bytes = stats.stream_retrans_bytes;
-    out.pmtu = stats.pmtu;
-    out.delivery_rate = stats.delivery_rate;
-    out.peer_max_idle_timeout = stats.peer_max_idle_timeout;
-    out.peer_max_udp_payload_size = stats.peer_max_udp_payload_size;
-    out.peer_initial_max_data = stats.peer_initial_max_data;
-    out.peer_initial_max_stream_data_bidi_local =
-        stats.peer_initial_max_stream_data_bidi_local;
-    out.peer_initial_max_stream_data_bidi_remote =
-        stats.peer_initial_max_stream_data_bidi_remote;
-    out.peer_initial_max_stream_data_uni = stats.peer_initial_max_stream_data_uni;
-    out.peer_initial_max_streams_bidi = stats.peer_initial_max_streams_bidi;
-    out.peer_initial_max_streams_uni = stats.peer_initial_max_streams_uni;
-    out.peer_ack_delay_exponent = stats.peer_ack_delay_exponent;
-    out.peer_max_ack_delay = stats.peer_max_ack_delay;
-    out.peer_disable_active_migration = stats.peer_disable_active_migration;
-    out.peer_active_conn_id_limit = stats.peer_active_conn_id_limit;
-    out.peer_max_datagram_frame_size = match stats.peer_max_datagram_frame_size {
-        None => Error::Done.to_c(),
+        Some(TransportEvent::StreamsAvailable) => (1, 0, 0),
 
-        Some(v) => v as ssize_t,
-    }
+        Some(TransportEvent::StreamReset {
+            stream_id,
+            error_code,
+        }) => (2, stream_id, error_code),
+
+        Some(TransportEvent::StopSending {
+            stream_id,
+            error_code,
+        }) => (3, stream_id, error_code),
+
+        Some(TransportEvent::MaxDataUpdated { max }) => (4, 0, max),
+
+        Some(TransportEvent::NewToken) => (5, 0, 0),
+
+        Some(TransportEvent::Path(_)) => (6, 0, 0),
+
+        Some(TransportEvent::PeerClosed) => (7, 0, 0),
+
+        Some(TransportEvent::StreamDeadlineExpired {
+            stream_id,
+            error_code,
+        }) => (8, stream_id, error_code),
+
+        Some(TransportEvent::DataBlocked { limit }) => (9, 0, limit),
+
+        Some(TransportEvent::StreamDataBlocked { stream_id, limit }) =>
+            (10, stream_id, limit),
+
+        Some(TransportEvent::PeerDataBlocked { limit }) => (11, 0, limit),
+
+        Some(TransportEvent::PeerStreamDataBlocked { stream_id, limit }) =>
+            (12, stream_id, limit),
+
+        Some(TransportEvent::VersionNegotiated { version }) =>
+            (13, 0, u64::from(version)),
+
+        None => return Error::Done.to_c() as c_int,
+    };
+
+    *out_type = ty;
+    *stream_id = id;
+    *value = v;
+
+    0
 }
 
 #[no_mangle]
@@ -1118,6 +2529,25 @@ pub extern fn quiche_conn_dgram_send(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_dgram_send_with_ttl(
+    conn: &mut Connection, buf: *const u8, buf_len: size_t, ttl: u64,
+) -> ssize_t {
+    if buf_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    let ttl = std::time::Duration::from_millis(ttl);
+
+    match conn.dgram_send_with_ttl(buf, ttl) {
+        Ok(_) => buf_len as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_dgram_recv(
     conn: &mut Connection, out: *mut u8, out_len: size_t,
@@ -1137,6 +2567,59 @@ pub extern fn quiche_conn_dgram_recv(
     out_len as ssize_t
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_dgram_recv_peek(
+    conn: &Connection, out: *mut u8, out_len: size_t, len: size_t,
+) -> ssize_t {
+    if out_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match conn.dgram_recv_peek(out, len) {
+        Ok(v) => v as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_dgram_send_on_path(
+    conn: &mut Connection, buf: *const u8, buf_len: size_t,
+    from: *const sockaddr, from_len: socklen_t, to: &sockaddr, to_len: socklen_t,
+) -> ssize_t {
+    if buf_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    let from = optional_std_addr_from_c(from, from_len);
+    let to = std_addr_from_c(to, to_len);
+
+    match conn.dgram_send_on_path(buf, from, to) {
+        Ok(_) => buf_len as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_dgram_send_queue_len_on_path(
+    conn: &Connection, from: *const sockaddr, from_len: socklen_t, to: &sockaddr,
+    to_len: socklen_t,
+) -> ssize_t {
+    let from = optional_std_addr_from_c(from, from_len);
+    let to = std_addr_from_c(to, to_len);
+
+    match conn.dgram_send_queue_len_on_path(from, to) {
+        Some(v) => v as ssize_t,
+
+        None => Error::Done.to_c(),
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_dgram_purge_outgoing(
     conn: &mut Connection, f: extern fn(*const u8, size_t) -> bool,
@@ -1164,6 +2647,17 @@ pub extern fn quiche_conn_peer_streams_left_uni(conn: &mut Connection) -> u64 {
     conn.peer_streams_left_uni()
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_grant_streams(
+    conn: &mut Connection, bidi: bool, n: u64,
+) -> c_int {
+    match conn.grant_streams(bidi, n) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 fn std_addr_from_c(addr: &sockaddr, addr_len: socklen_t) -> SocketAddr {
     unsafe {
         match addr.sa_family as i32 {
@@ -1188,6 +2682,32 @@ fn std_addr_from_c(addr: &sockaddr, addr_len: socklen_t) -> SocketAddr {
     }
 }
 
+fn optional_std_addr_from_c(
+    addr: *const sockaddr, addr_len: socklen_t,
+) -> Option<SocketAddr> {
+    if addr.is_null() || addr_len == 0 {
+        return None;
+    }
+
+    Some(std_addr_from_c(unsafe { &*addr }, addr_len))
+}
+
+/// Copies `src` into `out`, writing no more than the number of bytes given by
+/// the `size_t` field at the start of `out`.
+unsafe fn copy_versioned<T>(src: &T, out: *mut T) {
+    let size = ptr::read(out as *const size_t);
+    let len = std::cmp::min(size, std::mem::size_of::<T>());
+
+    // Leave the caller's `size` untouched.
+    let skip = std::cmp::min(len, std::mem::size_of::<size_t>());
+
+    ptr::copy_nonoverlapping(
+        (src as *const T as *const u8).add(skip),
+        (out as *mut u8).add(skip),
+        len - skip,
+    );
+}
+
 fn std_addr_to_c(addr: &SocketAddr, out: &mut sockaddr_storage) -> socklen_t {
     unsafe {
         match addr {
@@ -1229,3 +2749,244 @@ fn std_time_to_c(_time: &std::time::Instant, out: &mut timespec) {
     out.tv_sec = 0;
     out.tv_nsec = 0;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Events {
+        handshake_done: usize,
+        readable: Vec<u64>,
+        dgram: usize,
+        closed: usize,
+    }
+
+    fn events<'a>(argp: *mut c_void) -> &'a mut Events {
+        unsafe { &mut *(argp as *mut Events) }
+    }
+
+    extern fn on_stream_readable(
+        _conn: *mut Connection, stream_id: u64, argp: *mut c_void,
+    ) {
+        events(argp).readable.push(stream_id);
+    }
+
+    extern fn on_dgram(_conn: *mut Connection, argp: *mut c_void) {
+        events(argp).dgram += 1;
+    }
+
+    extern fn on_handshake_done(_conn: *mut Connection, argp: *mut c_void) {
+        events(argp).handshake_done += 1;
+    }
+
+    extern fn on_closed(_conn: *mut Connection, argp: *mut c_void) {
+        events(argp).closed += 1;
+    }
+
+    fn set_callbacks(conn: &mut Connection, events: &mut Events) {
+        let cbs = ConnCallbacks {
+            size: std::mem::size_of::<ConnCallbacks>(),
+            on_stream_readable: Some(on_stream_readable),
+            on_dgram: Some(on_dgram),
+            on_handshake_done: Some(on_handshake_done),
+            on_closed: Some(on_closed),
+        };
+
+        assert!(quiche_conn_set_callbacks(
+            conn,
+            &cbs,
+            events as *mut Events as *mut c_void,
+        ));
+    }
+
+    /// Passes all the packets `from` has to send to `to` through the C API.
+    fn deliver(from: &mut Connection, to: &mut Connection) {
+        let mut buf = [0; 65535];
+
+        let mut addr: sockaddr_storage = unsafe { std::mem::zeroed() };
+        let addr_len = std_addr_to_c(&to.peer_addr, &mut addr);
+
+        let info = RecvInfo {
+            from: unsafe { &*(&addr as *const _ as *const sockaddr) },
+            from_len: addr_len,
+        };
+
+        while let Ok((len, _)) = from.send(&mut buf) {
+            let rc = quiche_conn_recv(to, buf.as_mut_ptr(), len, &info);
+            assert_eq!(rc, len as ssize_t);
+        }
+    }
+
+    fn send(conn: &mut Connection) -> ssize_t {
+        let mut buf = [0; 65535];
+        let mut info: SendInfo = unsafe { std::mem::zeroed() };
+
+        quiche_conn_send(conn, buf.as_mut_ptr(), buf.len(), &mut info)
+    }
+
+    #[test]
+    fn callbacks() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_dgram(true, 10, 10);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+
+        let mut ev = Events::default();
+        set_callbacks(&mut pipe.server, &mut ev);
+
+        while !pipe.server.is_established() {
+            deliver(&mut pipe.client, &mut pipe.server);
+            deliver(&mut pipe.server, &mut pipe.client);
+        }
+
+        // Flush what's left of the handshake.
+        deliver(&mut pipe.client, &mut pipe.server);
+
+        assert_eq!(ev.handshake_done, 1);
+        assert!(ev.readable.is_empty());
+        assert_eq!(ev.dgram, 0);
+
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
+        deliver(&mut pipe.client, &mut pipe.server);
+
+        assert_eq!(ev.readable, [4]);
+        assert_eq!(ev.dgram, 0);
+
+        // The stream wasn't read, so it's reported again.
+        assert_eq!(pipe.client.dgram_send(b"hello"), Ok(()));
+        deliver(&mut pipe.client, &mut pipe.server);
+
+        assert_eq!(ev.readable, [4, 4]);
+        assert_eq!(ev.dgram, 1);
+
+        assert_eq!(ev.handshake_done, 1);
+        assert_eq!(ev.closed, 0);
+
+        // Unregistered callbacks aren't called anymore.
+        quiche_conn_set_callbacks(&mut pipe.server, ptr::null(), ptr::null_mut());
+
+        assert_eq!(pipe.client.stream_send(8, b"hello", false), Ok(5));
+        deliver(&mut pipe.client, &mut pipe.server);
+
+        assert_eq!(ev.readable, [4, 4]);
+    }
+
+    #[test]
+    fn callbacks_size() {
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        let mut cbs = ConnCallbacks {
+            size: 0,
+            on_stream_readable: Some(on_stream_readable),
+            on_dgram: Some(on_dgram),
+            on_handshake_done: Some(on_handshake_done),
+            on_closed: Some(on_closed),
+        };
+
+        let ptr_size = std::mem::size_of::<usize>();
+
+        // Too small to hold the first callback.
+        cbs.size = ptr_size + ptr_size / 2;
+        assert!(!quiche_conn_set_callbacks(
+            &mut pipe.client,
+            &cbs,
+            ptr::null_mut()
+        ));
+        assert!(pipe.client.ffi_callbacks.is_none());
+
+        // Callbacks only partially covered by the size are left unset.
+        cbs.size = 3 * ptr_size + ptr_size / 2;
+        assert!(quiche_conn_set_callbacks(
+            &mut pipe.client,
+            &cbs,
+            ptr::null_mut()
+        ));
+
+        let registered = pipe.client.ffi_callbacks.unwrap().cbs;
+        assert!(registered.on_stream_readable.is_some());
+        assert!(registered.on_dgram.is_some());
+        assert!(registered.on_handshake_done.is_none());
+        assert!(registered.on_closed.is_none());
+    }
+
+    #[test]
+    fn callbacks_local_close() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.verify_peer(false);
+
+        let clock =
+            Arc::new(testing::ManualClock::new(std::time::Instant::now()));
+        config.set_clock(clock.clone());
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // The handshake completed before the callbacks were registered.
+        let mut ev = Events::default();
+        set_callbacks(&mut pipe.server, &mut ev);
+
+        assert_eq!(
+            quiche_conn_close(&mut pipe.server, true, 0, b"".as_ptr(), 0),
+            0
+        );
+        assert!(send(&mut pipe.server) > 0);
+        assert_eq!(ev.closed, 0);
+
+        // The connection is closed once it's done draining.
+        clock.advance(pipe.server.timeout().unwrap());
+        quiche_conn_on_timeout(&mut pipe.server);
+
+        assert!(pipe.server.is_closed());
+        assert_eq!(ev.closed, 1);
+
+        assert_eq!(send(&mut pipe.server), Error::Done.to_c());
+        quiche_conn_on_timeout(&mut pipe.server);
+
+        assert_eq!(ev.handshake_done, 0);
+        assert_eq!(ev.closed, 1);
+    }
+
+    #[test]
+    fn callbacks_close_before_handshake() {
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        let mut ev = Events::default();
+        set_callbacks(&mut pipe.client, &mut ev);
+
+        // Nothing was received yet, so the connection is closed immediately.
+        assert_eq!(
+            quiche_conn_close(&mut pipe.client, true, 0, b"".as_ptr(), 0),
+            0
+        );
+        assert!(pipe.client.is_closed());
+        assert_eq!(ev.closed, 1);
+
+        assert_eq!(send(&mut pipe.client), Error::Done.to_c());
+        quiche_conn_on_timeout(&mut pipe.client);
+
+        assert_eq!(ev.closed, 1);
+    }
+}
@@ -24,8 +24,8 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use std::time::Duration;
-use std::time::Instant;
+use crate::time::Duration;
+use crate::time::Instant;
 
 // When autotuning the receiver window, decide how much
 // we increase the window.
@@ -52,6 +52,10 @@ pub struct FlowControl {
 
     /// Last update time of max_data for autotuning the window.
     last_update: Option<Instant>,
+
+    /// Consumed bytes at the last update of max_data, used to estimate
+    /// the consumption rate when autotuning the window.
+    consumed_at_update: u64,
 }
 
 impl FlowControl {
@@ -98,25 +102,56 @@ impl FlowControl {
     }
 
     /// Commits the new max_data limit.
-    pub fn update_max_data(&mut self, now: Instant) {
-        self.max_data = self.max_data_next();
+    ///
+    /// This is usually the one returned by `max_data_next()`, but can be
+    /// lower to hold back credit.
+    pub fn update_max_data(&mut self, max_data: u64, now: Instant) {
+        self.max_data = max_data;
         self.last_update = Some(now);
+        self.consumed_at_update = self.consumed;
     }
 
     /// Autotune the window size. When there is an another update
-    /// within RTT x 2, bump the window x 1.5, capped by
-    /// max_window.
+    /// within RTT x 2, bump the window x 2, or to the amount of data
+    /// consumed over RTT x 2 at the rate observed since the last update
+    /// if that's larger, capped by max_window.
+    ///
+    /// The rate is measured over at least one RTT, as a shorter interval
+    /// only reflects how bursty the reads are, not how fast data arrives.
     pub fn autotune_window(&mut self, now: Instant, rtt: Duration) {
         if let Some(last_update) = self.last_update {
-            if now - last_update < rtt * WINDOW_TRIGGER_FACTOR {
-                self.window = std::cmp::min(
-                    self.window * WINDOW_INCREASE_FACTOR,
+            let elapsed = now - last_update;
+            let trigger = rtt * WINDOW_TRIGGER_FACTOR;
+
+            if elapsed < trigger {
+                let consumed = self.consumed - self.consumed_at_update;
+
+                let interval = core::cmp::max(elapsed, rtt);
+
+                let target = if interval.as_nanos() > 0 {
+                    let target = u128::from(consumed) * trigger.as_nanos() /
+                        interval.as_nanos();
+
+                    core::cmp::min(target, u128::from(self.max_window)) as u64
+                } else {
+                    0
+                };
+
+                self.window = core::cmp::min(
+                    core::cmp::max(self.window * WINDOW_INCREASE_FACTOR, target),
                     self.max_window,
                 );
             }
         }
     }
 
+    /// Sets both the current and the maximum window to the
+    /// given value.
+    pub fn set_window(&mut self, window: u64) {
+        self.window = window;
+        self.max_window = window;
+    }
+
     /// Make sure the lower bound of the window is same to
     /// the current window.
     pub fn ensure_window_lower_bound(&mut self, min_window: u64) {
@@ -171,7 +206,7 @@ mod tests {
         let max_data_next = fc.max_data_next();
         assert_eq!(fc.max_data_next(), consumed + 20);
 
-        fc.update_max_data(Instant::now());
+        fc.update_max_data(fc.max_data_next(), Instant::now());
         assert_eq!(fc.max_data(), max_data_next);
     }
 
@@ -188,7 +223,7 @@ mod tests {
         let max_data_next = fc.max_data_next();
         assert_eq!(max_data_next, consumed + w);
 
-        fc.update_max_data(Instant::now());
+        fc.update_max_data(fc.max_data_next(), Instant::now());
         assert_eq!(fc.max_data(), max_data_next);
 
         // Window size should be doubled.
@@ -204,6 +239,40 @@ mod tests {
         assert_eq!(max_data_next, consumed + consumed_inc + w);
     }
 
+    #[test]
+    fn autotune_window_consumption_rate() {
+        let mut fc = FlowControl::new(100, 20, 1000);
+
+        let rtt = Duration::from_millis(100);
+
+        let mut now = Instant::now();
+
+        fc.add_consumed(10);
+        fc.update_max_data(fc.max_data_next(), now);
+
+        // 60 bytes consumed in 150ms, so a window lasting 2 RTTs of 100ms
+        // needs to be 80 bytes.
+        now += Duration::from_millis(150);
+        fc.add_consumed(60);
+        fc.autotune_window(now, rtt);
+        assert_eq!(fc.window(), 80);
+
+        // 50 bytes consumed at once are measured over an RTT, so the window
+        // is only doubled.
+        fc.update_max_data(fc.max_data_next(), now);
+        now += Duration::from_millis(1);
+        fc.add_consumed(50);
+        fc.autotune_window(now, rtt);
+        assert_eq!(fc.window(), 160);
+
+        // The window is still capped by max_window.
+        fc.update_max_data(fc.max_data_next(), now);
+        now += Duration::from_millis(100);
+        fc.add_consumed(900);
+        fc.autotune_window(now, rtt);
+        assert_eq!(fc.window(), 1000);
+    }
+
     #[test]
     fn ensure_window_lower_bound() {
         let w = 20;
@@ -217,4 +286,22 @@ mod tests {
         fc.ensure_window_lower_bound(w * 2);
         assert_eq!(fc.window(), 40);
     }
+
+    #[test]
+    fn set_window() {
+        let mut fc = FlowControl::new(100, 20, 100);
+
+        fc.add_consumed(50);
+        assert_eq!(fc.max_data_next(), 70);
+
+        // Window is raised beyond the previous maximum.
+        fc.set_window(200);
+        assert_eq!(fc.window(), 200);
+        assert_eq!(fc.max_data_next(), 250);
+
+        // Autotuning doesn't grow the window past the new maximum.
+        fc.update_max_data(fc.max_data_next(), Instant::now());
+        fc.autotune_window(Instant::now(), Duration::from_millis(100));
+        assert_eq!(fc.window(), 200);
+    }
 }
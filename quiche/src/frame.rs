@@ -24,7 +24,15 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use std::convert::TryInto;
+use core::convert::TryInto;
+
+use alloc::sync::Arc;
+
+#[cfg(feature = "qlog")]
+use alloc::string::String;
+#[cfg(feature = "qlog")]
+use alloc::string::ToString;
+use alloc::vec::Vec;
 
 use crate::Error;
 use crate::Result;
@@ -69,6 +77,12 @@ pub enum Frame {
         ecn_counts: Option<EcnCounts>,
     },
 
+    ACKHeader {
+        ack_delay: u64,
+        largest_acked: u64,
+        length: usize,
+    },
+
     ResetStream {
         stream_id: u64,
         error_code: u64,
@@ -183,6 +197,16 @@ pub enum Frame {
 impl Frame {
     pub fn from_bytes(
         b: &mut octets::Octets, pkt: packet::Type,
+    ) -> Result<Frame> {
+        Frame::from_bytes_shared(b, pkt, None)
+    }
+
+    /// Parses a frame like `from_bytes()`, except that when `b` covers part
+    /// of a received datagram, given along with the offset `b` starts at,
+    /// STREAM frames reference the datagram instead of copying their data.
+    pub fn from_bytes_shared(
+        b: &mut octets::Octets, pkt: packet::Type,
+        dgram: Option<(&Arc<Vec<u8>>, usize)>,
     ) -> Result<Frame> {
         let frame_type = b.get_varint()?;
 
@@ -228,7 +252,7 @@ impl Frame {
                 token: b.get_bytes_with_varint_length()?.to_vec(),
             },
 
-            0x08..=0x0f => parse_stream_frame(frame_type, b)?,
+            0x08..=0x0f => parse_stream_frame(frame_type, b, dgram)?,
 
             0x10 => Frame::MaxData {
                 max: b.get_varint()?,
@@ -310,7 +334,7 @@ impl Frame {
 
             0x30 | 0x31 => parse_datagram_frame(frame_type, b)?,
 
-            _ => return Err(Error::InvalidFrame),
+            _ => return Err(Error::InvalidFrame(frame_type)),
         };
 
         let allowed = match (pkt, &frame) {
@@ -371,41 +395,11 @@ impl Frame {
                 ranges,
                 ecn_counts,
             } => {
-                if ecn_counts.is_none() {
-                    b.put_varint(0x02)?;
-                } else {
-                    b.put_varint(0x03)?;
-                }
-
-                let mut it = ranges.iter().rev();
-
-                let first = it.next().unwrap();
-                let ack_block = (first.end - 1) - first.start;
-
-                b.put_varint(first.end - 1)?;
-                b.put_varint(*ack_delay)?;
-                b.put_varint(it.len() as u64)?;
-                b.put_varint(ack_block)?;
-
-                let mut smallest_ack = first.start;
-
-                for block in it {
-                    let gap = smallest_ack - block.end - 1;
-                    let ack_block = (block.end - 1) - block.start;
-
-                    b.put_varint(gap)?;
-                    b.put_varint(ack_block)?;
-
-                    smallest_ack = block.start;
-                }
-
-                if let Some(ecn) = ecn_counts {
-                    b.put_varint(ecn.ect0_count)?;
-                    b.put_varint(ecn.ect1_count)?;
-                    b.put_varint(ecn.ecn_ce_count)?;
-                }
+                encode_ack(*ack_delay, ranges, ecn_counts.as_ref(), b)?;
             },
 
+            Frame::ACKHeader { .. } => (),
+
             Frame::ResetStream {
                 stream_id,
                 error_code,
@@ -587,38 +581,9 @@ impl Frame {
                 ack_delay,
                 ranges,
                 ecn_counts,
-            } => {
-                let mut it = ranges.iter().rev();
-
-                let first = it.next().unwrap();
-                let ack_block = (first.end - 1) - first.start;
-
-                let mut len = 1 + // frame type
-                    octets::varint_len(first.end - 1) + // largest_ack
-                    octets::varint_len(*ack_delay) + // ack_delay
-                    octets::varint_len(it.len() as u64) + // block_count
-                    octets::varint_len(ack_block); // first_block
-
-                let mut smallest_ack = first.start;
-
-                for block in it {
-                    let gap = smallest_ack - block.end - 1;
-                    let ack_block = (block.end - 1) - block.start;
-
-                    len += octets::varint_len(gap) + // gap
-                           octets::varint_len(ack_block); // ack_block
+            } => ack_wire_len(*ack_delay, ranges, ecn_counts.as_ref()),
 
-                    smallest_ack = block.start;
-                }
-
-                if let Some(ecn) = ecn_counts {
-                    len += octets::varint_len(ecn.ect0_count) +
-                        octets::varint_len(ecn.ect1_count) +
-                        octets::varint_len(ecn.ecn_ce_count);
-                }
-
-                len
-            },
+            Frame::ACKHeader { length, .. } => *length,
 
             Frame::ResetStream {
                 stream_id,
@@ -796,11 +761,22 @@ impl Frame {
             self,
             Frame::Padding { .. } |
                 Frame::ACK { .. } |
+                Frame::ACKHeader { .. } |
                 Frame::ApplicationClose { .. } |
                 Frame::ConnectionClose { .. }
         )
     }
 
+    pub fn probing(&self) -> bool {
+        matches!(
+            self,
+            Frame::Padding { .. } |
+                Frame::NewConnectionId { .. } |
+                Frame::PathChallenge { .. } |
+                Frame::PathResponse { .. }
+        )
+    }
+
     #[cfg(feature = "qlog")]
     pub fn to_qlog(&self) -> QuicFrame {
         match self {
@@ -812,28 +788,21 @@ impl Frame {
                 ack_delay,
                 ranges,
                 ecn_counts,
-            } => {
-                let ack_ranges = AckedRanges::Double(
-                    ranges.iter().map(|r| (r.start, r.end - 1)).collect(),
-                );
-
-                let (ect0, ect1, ce) = match ecn_counts {
-                    Some(ecn) => (
-                        Some(ecn.ect0_count),
-                        Some(ecn.ect1_count),
-                        Some(ecn.ecn_ce_count),
-                    ),
-
-                    None => (None, None, None),
-                };
-
-                QuicFrame::Ack {
-                    ack_delay: Some(*ack_delay as f32 / 1000.0),
-                    acked_ranges: Some(ack_ranges),
-                    ect1,
-                    ect0,
-                    ce,
-                }
+            } => ack_to_qlog(*ack_delay, ranges, ecn_counts.as_ref()),
+
+            Frame::ACKHeader {
+                ack_delay,
+                largest_acked,
+                ..
+            } => QuicFrame::Ack {
+                ack_delay: Some(*ack_delay as f32 / 1000.0),
+                acked_ranges: Some(AckedRanges::Double(vec![(
+                    *largest_acked,
+                    *largest_acked,
+                )])),
+                ect1: None,
+                ect0: None,
+                ce: None,
             },
 
             Frame::ResetStream {
@@ -983,8 +952,8 @@ impl Frame {
     }
 }
 
-impl std::fmt::Debug for Frame {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for Frame {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
             Frame::Padding { len } => {
                 write!(f, "PADDING len={}", len)?;
@@ -1006,6 +975,14 @@ impl std::fmt::Debug for Frame {
                 )?;
             },
 
+            Frame::ACKHeader {
+                ack_delay,
+                largest_acked,
+                ..
+            } => {
+                write!(f, "ACK delay={} largest={}", ack_delay, largest_acked)?;
+            },
+
             Frame::ResetStream {
                 stream_id,
                 error_code,
@@ -1163,7 +1140,7 @@ fn parse_ack_frame(ty: u64, b: &mut octets::Octets) -> Result<Frame> {
     let ack_block = b.get_varint()?;
 
     if largest_ack < ack_block {
-        return Err(Error::InvalidFrame);
+        return Err(Error::InvalidFrame(ty));
     }
 
     let mut smallest_ack = largest_ack - ack_block;
@@ -1176,14 +1153,14 @@ fn parse_ack_frame(ty: u64, b: &mut octets::Octets) -> Result<Frame> {
         let gap = b.get_varint()?;
 
         if smallest_ack < 2 + gap {
-            return Err(Error::InvalidFrame);
+            return Err(Error::InvalidFrame(ty));
         }
 
         let largest_ack = (smallest_ack - gap) - 2;
         let ack_block = b.get_varint()?;
 
         if largest_ack < ack_block {
-            return Err(Error::InvalidFrame);
+            return Err(Error::InvalidFrame(ty));
         }
 
         smallest_ack = largest_ack - ack_block;
@@ -1210,6 +1187,109 @@ fn parse_ack_frame(ty: u64, b: &mut octets::Octets) -> Result<Frame> {
     })
 }
 
+pub fn encode_ack(
+    ack_delay: u64, ranges: &ranges::RangeSet, ecn_counts: Option<&EcnCounts>,
+    b: &mut octets::OctetsMut,
+) -> Result<()> {
+    if ecn_counts.is_none() {
+        b.put_varint(0x02)?;
+    } else {
+        b.put_varint(0x03)?;
+    }
+
+    let mut it = ranges.iter().rev();
+
+    let first = it.next().unwrap();
+    let ack_block = (first.end - 1) - first.start;
+
+    b.put_varint(first.end - 1)?;
+    b.put_varint(ack_delay)?;
+    b.put_varint(it.len() as u64)?;
+    b.put_varint(ack_block)?;
+
+    let mut smallest_ack = first.start;
+
+    for block in it {
+        let gap = smallest_ack - block.end - 1;
+        let ack_block = (block.end - 1) - block.start;
+
+        b.put_varint(gap)?;
+        b.put_varint(ack_block)?;
+
+        smallest_ack = block.start;
+    }
+
+    if let Some(ecn) = ecn_counts {
+        b.put_varint(ecn.ect0_count)?;
+        b.put_varint(ecn.ect1_count)?;
+        b.put_varint(ecn.ecn_ce_count)?;
+    }
+
+    Ok(())
+}
+
+pub fn ack_wire_len(
+    ack_delay: u64, ranges: &ranges::RangeSet, ecn_counts: Option<&EcnCounts>,
+) -> usize {
+    let mut it = ranges.iter().rev();
+
+    let first = it.next().unwrap();
+    let ack_block = (first.end - 1) - first.start;
+
+    let mut len = 1 + // frame type
+        octets::varint_len(first.end - 1) + // largest_ack
+        octets::varint_len(ack_delay) + // ack_delay
+        octets::varint_len(it.len() as u64) + // block_count
+        octets::varint_len(ack_block); // first_block
+
+    let mut smallest_ack = first.start;
+
+    for block in it {
+        let gap = smallest_ack - block.end - 1;
+        let ack_block = (block.end - 1) - block.start;
+
+        len += octets::varint_len(gap) + // gap
+               octets::varint_len(ack_block); // ack_block
+
+        smallest_ack = block.start;
+    }
+
+    if let Some(ecn) = ecn_counts {
+        len += octets::varint_len(ecn.ect0_count) +
+            octets::varint_len(ecn.ect1_count) +
+            octets::varint_len(ecn.ecn_ce_count);
+    }
+
+    len
+}
+
+#[cfg(feature = "qlog")]
+pub fn ack_to_qlog(
+    ack_delay: u64, ranges: &ranges::RangeSet, ecn_counts: Option<&EcnCounts>,
+) -> QuicFrame {
+    let ack_ranges = AckedRanges::Double(
+        ranges.iter().map(|r| (r.start, r.end - 1)).collect(),
+    );
+
+    let (ect0, ect1, ce) = match ecn_counts {
+        Some(ecn) => (
+            Some(ecn.ect0_count),
+            Some(ecn.ect1_count),
+            Some(ecn.ecn_ce_count),
+        ),
+
+        None => (None, None, None),
+    };
+
+    QuicFrame::Ack {
+        ack_delay: Some(ack_delay as f32 / 1000.0),
+        acked_ranges: Some(ack_ranges),
+        ect1,
+        ect0,
+        ce,
+    }
+}
+
 pub fn encode_crypto_header(
     offset: u64, length: u64, b: &mut octets::OctetsMut,
 ) -> Result<()> {
@@ -1264,7 +1344,9 @@ pub fn encode_dgram_header(length: u64, b: &mut octets::OctetsMut) -> Result<()>
     Ok(())
 }
 
-fn parse_stream_frame(ty: u64, b: &mut octets::Octets) -> Result<Frame> {
+fn parse_stream_frame(
+    ty: u64, b: &mut octets::Octets, dgram: Option<(&Arc<Vec<u8>>, usize)>,
+) -> Result<Frame> {
     let first = ty as u8;
 
     let stream_id = b.get_varint()?;
@@ -1282,13 +1364,31 @@ fn parse_stream_frame(ty: u64, b: &mut octets::Octets) -> Result<Frame> {
     };
 
     if offset + len as u64 >= MAX_STREAM_SIZE {
-        return Err(Error::InvalidFrame);
+        return Err(Error::InvalidFrame(ty));
     }
 
     let fin = first & 0x01 != 0;
 
-    let data = b.get_bytes(len)?;
-    let data = stream::RangeBuf::from(data.as_ref(), offset, fin);
+    let data = match dgram {
+        Some((dgram, start)) => {
+            let data_start = start + b.off();
+
+            b.skip(len)?;
+
+            stream::RangeBuf::from_datagram(
+                dgram.clone(),
+                data_start,
+                len,
+                offset,
+                fin,
+            )
+        },
+
+        None => {
+            let data = b.get_bytes(len)?;
+            stream::RangeBuf::from(data.as_ref(), offset, fin)
+        },
+    };
 
     Ok(Frame::Stream { stream_id, data })
 }
@@ -1613,7 +1713,7 @@ mod tests {
         let mut b = octets::Octets::with_slice(&d);
         assert_eq!(
             Frame::from_bytes(&mut b, packet::Type::Short),
-            Err(Error::InvalidFrame)
+            Err(Error::InvalidFrame(0x0f))
         );
     }
 
@@ -0,0 +1,512 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Structured inputs for fuzzing and property testing.
+//!
+//! This module provides [`Arbitrary`] implementations for QUIC frames,
+//! transport parameters and HTTP/3 frames and headers, so that fuzzers can
+//! generate well-formed protocol messages instead of raw bytes. The generated
+//! values can be serialized to the wire format and then fed to a connection.
+//!
+//! It is only available when the `arbitrary` feature is enabled.
+//!
+//! ## Examples:
+//!
+//! ```no_run
+//! use arbitrary::Arbitrary;
+//!
+//! # let data = [0; 64];
+//! let mut u = arbitrary::Unstructured::new(&data);
+//!
+//! let frame = quiche::fuzz::Frame::arbitrary(&mut u).unwrap();
+//!
+//! let mut buf = [0; 1500];
+//! let len = frame.to_bytes(&mut buf)?;
+//! # Ok::<(), quiche::Error>(())
+//! ```
+//!
+//! [`Arbitrary`]: https://docs.rs/arbitrary/1/arbitrary/trait.Arbitrary.html
+
+use arbitrary::Arbitrary;
+use arbitrary::Unstructured;
+
+use crate::Result;
+
+use crate::frame;
+use crate::h3;
+use crate::h3::NameValue;
+use crate::octets;
+use crate::ranges;
+use crate::stream;
+
+const MAX_VARINT: u64 = (1 << 62) - 1;
+
+const MAX_ACK_RANGES: usize = 32;
+
+const MAX_PADDING_LEN: usize = 1500;
+
+const MAX_HEADERS: usize = 32;
+
+/// A QUIC frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame(frame::Frame);
+
+impl Frame {
+    /// Writes the frame to the given buffer.
+    ///
+    /// On success the number of bytes written is returned.
+    pub fn to_bytes(&self, out: &mut [u8]) -> Result<usize> {
+        let mut b = octets::OctetsMut::with_slice(out);
+
+        self.0.to_bytes(&mut b)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Frame {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let frame = match u.int_in_range(0..=21)? {
+            0 => frame::Frame::Padding {
+                len: u.int_in_range(1..=MAX_PADDING_LEN)?,
+            },
+
+            1 => frame::Frame::Ping,
+
+            2 => frame::Frame::ACK {
+                ack_delay: varint(u)?,
+                ranges: ack_ranges(u)?,
+                ecn_counts: None,
+            },
+
+            3 => frame::Frame::ResetStream {
+                stream_id: varint(u)?,
+                error_code: varint(u)?,
+                final_size: varint(u)?,
+            },
+
+            4 => frame::Frame::StopSending {
+                stream_id: varint(u)?,
+                error_code: varint(u)?,
+            },
+
+            5 => frame::Frame::Crypto {
+                data: range_buf(u)?,
+            },
+
+            6 => frame::Frame::NewToken {
+                token: Vec::arbitrary(u)?,
+            },
+
+            7 => frame::Frame::Stream {
+                stream_id: varint(u)?,
+                data: range_buf(u)?,
+            },
+
+            8 => frame::Frame::MaxData { max: varint(u)? },
+
+            9 => frame::Frame::MaxStreamData {
+                stream_id: varint(u)?,
+                max: varint(u)?,
+            },
+
+            10 => frame::Frame::MaxStreamsBidi { max: varint(u)? },
+
+            11 => frame::Frame::MaxStreamsUni { max: varint(u)? },
+
+            12 => frame::Frame::DataBlocked { limit: varint(u)? },
+
+            13 => frame::Frame::StreamDataBlocked {
+                stream_id: varint(u)?,
+                limit: varint(u)?,
+            },
+
+            14 => frame::Frame::StreamsBlockedBidi { limit: varint(u)? },
+
+            15 => frame::Frame::StreamsBlockedUni { limit: varint(u)? },
+
+            16 => {
+                let seq_num = varint(u)?;
+
+                frame::Frame::NewConnectionId {
+                    seq_num,
+                    retire_prior_to: u.int_in_range(0..=seq_num)?,
+                    conn_id: conn_id(u, 1)?,
+                    reset_token: <[u8; 16]>::arbitrary(u)?,
+                }
+            },
+
+            17 => frame::Frame::RetireConnectionId {
+                seq_num: varint(u)?,
+            },
+
+            18 => frame::Frame::PathChallenge {
+                data: <[u8; 8]>::arbitrary(u)?,
+            },
+
+            19 => frame::Frame::PathResponse {
+                data: <[u8; 8]>::arbitrary(u)?,
+            },
+
+            20 =>
+                if bool::arbitrary(u)? {
+                    frame::Frame::ConnectionClose {
+                        error_code: varint(u)?,
+                        frame_type: varint(u)?,
+                        reason: Vec::arbitrary(u)?,
+                    }
+                } else {
+                    frame::Frame::ApplicationClose {
+                        error_code: varint(u)?,
+                        reason: Vec::arbitrary(u)?,
+                    }
+                },
+
+            _ =>
+                if bool::arbitrary(u)? {
+                    frame::Frame::HandshakeDone
+                } else {
+                    frame::Frame::Datagram {
+                        data: Vec::arbitrary(u)?,
+                    }
+                },
+        };
+
+        Ok(Frame(frame))
+    }
+}
+
+/// A set of QUIC transport parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransportParams(crate::TransportParams);
+
+impl TransportParams {
+    /// Writes the transport parameters to the given buffer, as sent by a
+    /// server when `is_server` is true, or by a client otherwise.
+    ///
+    /// On success the number of bytes written is returned.
+    pub fn to_bytes(&self, is_server: bool, out: &mut [u8]) -> Result<usize> {
+        let raw = crate::TransportParams::encode(&self.0, is_server, out)?;
+
+        Ok(raw.len())
+    }
+}
+
+impl<'a> Arbitrary<'a> for TransportParams {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let optional_conn_id = |u: &mut Unstructured<'a>| {
+            if bool::arbitrary(u)? {
+                Ok(Some(crate::ConnectionId::from_vec(conn_id(u, 0)?)))
+            } else {
+                Ok(None)
+            }
+        };
+
+        // Values are kept within the limits enforced when decoding, so that
+        // the generated parameters aren't simply rejected by the peer.
+        let tp = crate::TransportParams {
+            original_destination_connection_id: optional_conn_id(u)?,
+            max_idle_timeout: varint(u)?,
+            stateless_reset_token: Option::<[u8; 16]>::arbitrary(u)?
+                .map(|v| v.to_vec()),
+            max_udp_payload_size: u.int_in_range(1200..=65527)?,
+            initial_max_data: varint(u)?,
+            initial_max_stream_data_bidi_local: varint(u)?,
+            initial_max_stream_data_bidi_remote: varint(u)?,
+            initial_max_stream_data_uni: varint(u)?,
+            initial_max_streams_bidi: u.int_in_range(0..=1 << 60)?,
+            initial_max_streams_uni: u.int_in_range(0..=1 << 60)?,
+            ack_delay_exponent: u.int_in_range(0..=20)?,
+            max_ack_delay: u.int_in_range(0..=crate::MAX_ACK_DELAY)?,
+            disable_active_migration: bool::arbitrary(u)?,
+            active_conn_id_limit: u.int_in_range(2..=MAX_VARINT)?,
+            initial_source_connection_id: optional_conn_id(u)?,
+            retry_source_connection_id: optional_conn_id(u)?,
+            max_datagram_frame_size: if bool::arbitrary(u)? {
+                Some(varint(u)?)
+            } else {
+                None
+            },
+        };
+
+        Ok(TransportParams(tp))
+    }
+}
+
+/// An HTTP/3 frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct H3Frame(h3::frame::Frame);
+
+impl H3Frame {
+    /// Writes the frame to the given buffer.
+    ///
+    /// On success the number of bytes written is returned.
+    pub fn to_bytes(&self, out: &mut [u8]) -> h3::Result<usize> {
+        let mut b = octets::OctetsMut::with_slice(out);
+
+        self.0.to_bytes(&mut b)
+    }
+}
+
+impl<'a> Arbitrary<'a> for H3Frame {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let optional_varint = |u: &mut Unstructured<'a>| {
+            if bool::arbitrary(u)? {
+                Ok(Some(varint(u)?))
+            } else {
+                Ok(None)
+            }
+        };
+
+        let frame = match u.int_in_range(0..=8)? {
+            0 => h3::frame::Frame::Data {
+                payload: Vec::arbitrary(u)?,
+            },
+
+            1 => h3::frame::Frame::Headers {
+                header_block: header_block(u)?,
+            },
+
+            2 => h3::frame::Frame::CancelPush {
+                push_id: varint(u)?,
+            },
+
+            3 => h3::frame::Frame::Settings {
+                max_field_section_size: optional_varint(u)?,
+                qpack_max_table_capacity: optional_varint(u)?,
+                qpack_blocked_streams: optional_varint(u)?,
+                h3_datagram: if bool::arbitrary(u)? {
+                    Some(u.int_in_range(0..=1)?)
+                } else {
+                    None
+                },
+                connect_protocol_enabled: if bool::arbitrary(u)? {
+                    Some(u.int_in_range(0..=1)?)
+                } else {
+                    None
+                },
+                grease: None,
+                raw: None,
+            },
+
+            4 => h3::frame::Frame::PushPromise {
+                push_id: varint(u)?,
+                header_block: header_block(u)?,
+            },
+
+            5 => h3::frame::Frame::GoAway { id: varint(u)? },
+
+            6 => h3::frame::Frame::MaxPushId {
+                push_id: varint(u)?,
+            },
+
+            7 => h3::frame::Frame::PriorityUpdateRequest {
+                prioritized_element_id: varint(u)?,
+                priority_field_value: h3::Priority::arbitrary(u)?
+                    .to_field_value(),
+            },
+
+            _ => h3::frame::Frame::PriorityUpdatePush {
+                prioritized_element_id: varint(u)?,
+                priority_field_value: h3::Priority::arbitrary(u)?
+                    .to_field_value(),
+            },
+        };
+
+        Ok(H3Frame(frame))
+    }
+}
+
+impl<'a> Arbitrary<'a> for h3::Header {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let name = <&[u8]>::arbitrary(u)?;
+        let value = <&[u8]>::arbitrary(u)?;
+
+        Ok(h3::Header::new(name, value))
+    }
+}
+
+impl<'a> Arbitrary<'a> for h3::Priority {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(h3::Priority::new(
+            u.int_in_range(0..=7)?,
+            bool::arbitrary(u)?,
+        ))
+    }
+}
+
+fn varint(u: &mut Unstructured) -> arbitrary::Result<u64> {
+    u.int_in_range(0..=MAX_VARINT)
+}
+
+fn conn_id(u: &mut Unstructured, min_len: usize) -> arbitrary::Result<Vec<u8>> {
+    let len = u.int_in_range(min_len..=crate::MAX_CONN_ID_LEN)?;
+
+    let mut cid = vec![0; len];
+    u.fill_buffer(&mut cid)?;
+
+    Ok(cid)
+}
+
+fn range_buf(u: &mut Unstructured) -> arbitrary::Result<stream::RangeBuf> {
+    let data = <&[u8]>::arbitrary(u)?;
+    let off = u.int_in_range(0..=MAX_VARINT - data.len() as u64)?;
+
+    Ok(stream::RangeBuf::from(data, off, bool::arbitrary(u)?))
+}
+
+// Generates a non-empty set of ACK ranges, separated by at least one
+// unacknowledged packet number, as required by the ACK frame encoding.
+fn ack_ranges(u: &mut Unstructured) -> arbitrary::Result<ranges::RangeSet> {
+    let mut ranges = ranges::RangeSet::default();
+
+    let largest = varint(u)?;
+    let mut smallest = u.int_in_range(0..=largest)?;
+
+    ranges.insert(smallest..largest + 1);
+
+    for _ in 0..u.int_in_range(0..=MAX_ACK_RANGES)? {
+        if smallest < 2 {
+            break;
+        }
+
+        let end = u.int_in_range(1..=smallest - 1)?;
+        let start = u.int_in_range(0..=end - 1)?;
+
+        ranges.insert(start..end);
+
+        smallest = start;
+    }
+
+    Ok(ranges)
+}
+
+// Generates a list of headers and encodes it with QPACK, so that the header
+// block can actually be decoded by the receiver.
+fn header_block(u: &mut Unstructured) -> arbitrary::Result<Vec<u8>> {
+    let count = u.int_in_range(0..=MAX_HEADERS)?;
+
+    let mut headers = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        headers.push(h3::Header::arbitrary(u)?);
+    }
+
+    let len: usize = headers
+        .iter()
+        .map(|h| h.name().len() + h.value().len() + 32)
+        .sum();
+
+    let mut out = vec![0; len + 2];
+
+    let len = h3::qpack::Encoder::new()
+        .encode(&headers, &mut out)
+        .map_err(|_| arbitrary::Error::IncorrectFormat)?;
+
+    out.truncate(len);
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::packet;
+
+    fn inputs() -> Vec<Vec<u8>> {
+        (0..64_u8)
+            .map(|seed| {
+                (0..512_u32)
+                    .map(|i| (i as u8).wrapping_mul(seed).wrapping_add(seed))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frame_roundtrip() {
+        for data in inputs() {
+            let mut u = Unstructured::new(&data);
+
+            let frame = Frame::arbitrary(&mut u).unwrap();
+
+            let mut buf = [0; 4096];
+            let len = frame.to_bytes(&mut buf).unwrap();
+
+            let mut b = octets::Octets::with_slice(&buf[..len]);
+            let decoded = frame::Frame::from_bytes(&mut b, packet::Type::Short);
+
+            let mut out = [0; 4096];
+            let out_len = Frame(decoded.unwrap()).to_bytes(&mut out).unwrap();
+
+            assert_eq!(&buf[..len], &out[..out_len]);
+        }
+    }
+
+    #[test]
+    fn transport_params_roundtrip() {
+        for data in inputs() {
+            let mut u = Unstructured::new(&data);
+
+            let tp = TransportParams::arbitrary(&mut u).unwrap();
+
+            let mut buf = [0; 4096];
+            let len = tp.to_bytes(true, &mut buf).unwrap();
+
+            let decoded =
+                crate::TransportParams::decode(&buf[..len], false).unwrap();
+
+            assert_eq!(decoded, tp.0);
+        }
+    }
+
+    #[test]
+    fn h3_frame_roundtrip() {
+        for data in inputs() {
+            let mut u = Unstructured::new(&data);
+
+            let frame = H3Frame::arbitrary(&mut u).unwrap();
+
+            let mut buf = [0; 4096];
+            let len = frame.to_bytes(&mut buf).unwrap();
+
+            let mut b = octets::Octets::with_slice(&buf[..len]);
+            let frame_type = b.get_varint().unwrap();
+            let payload_len = b.get_varint().unwrap();
+
+            let decoded = h3::frame::Frame::from_bytes(
+                frame_type,
+                payload_len,
+                b.get_bytes(payload_len as usize).unwrap().buf(),
+            )
+            .unwrap();
+
+            let mut out = [0; 4096];
+            let out_len = H3Frame(decoded).to_bytes(&mut out).unwrap();
+
+            assert_eq!(&buf[..len], &out[..out_len]);
+        }
+    }
+}
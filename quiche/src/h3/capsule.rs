@@ -0,0 +1,500 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+
+use super::connect_ip::AddressPrefix;
+use super::connect_ip::IpAddressRange;
+
+use super::Error;
+use super::Result;
+
+use crate::octets;
+
+pub const DATAGRAM_CAPSULE_TYPE_ID: u64 = 0x00;
+pub const ADDRESS_ASSIGN_CAPSULE_TYPE_ID: u64 = 0x01;
+pub const ADDRESS_REQUEST_CAPSULE_TYPE_ID: u64 = 0x02;
+pub const ROUTE_ADVERTISEMENT_CAPSULE_TYPE_ID: u64 = 0x03;
+
+/// A capsule, as defined by the Capsule Protocol ([RFC 9297]).
+///
+/// Capsules are sent as the payload of DATA frames on the stream of a
+/// request that enabled the Capsule Protocol, such as an extended CONNECT
+/// request. They can be written on a stream using the [`send_capsule()`]
+/// method, and parsed from data read with the [`recv_body()`] method.
+///
+/// [RFC 9297]: https://www.rfc-editor.org/rfc/rfc9297.html
+/// [`send_capsule()`]: struct.Connection.html#method.send_capsule
+/// [`recv_body()`]: struct.Connection.html#method.recv_body
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Capsule {
+    /// An HTTP Datagram sent over the request stream.
+    Datagram {
+        /// The HTTP Datagram payload.
+        payload: Vec<u8>,
+    },
+
+    /// The addresses assigned to the receiver of a CONNECT-IP tunnel
+    /// ([RFC 9484]).
+    ///
+    /// [RFC 9484]: https://www.rfc-editor.org/rfc/rfc9484.html
+    AddressAssign {
+        /// The assigned addresses. An empty list removes all previously
+        /// assigned addresses.
+        addresses: Vec<AddressPrefix>,
+    },
+
+    /// A request for addresses to be assigned to the sender of a CONNECT-IP
+    /// tunnel ([RFC 9484]).
+    ///
+    /// [RFC 9484]: https://www.rfc-editor.org/rfc/rfc9484.html
+    AddressRequest {
+        /// The requested addresses.
+        addresses: Vec<AddressPrefix>,
+    },
+
+    /// The IP address ranges the sender of a CONNECT-IP tunnel routes
+    /// packets towards ([RFC 9484]).
+    ///
+    /// [RFC 9484]: https://www.rfc-editor.org/rfc/rfc9484.html
+    RouteAdvertisement {
+        /// The advertised ranges, in ascending order.
+        ranges: Vec<IpAddressRange>,
+    },
+
+    /// A capsule of a type not known to quiche.
+    Unknown {
+        /// The capsule type.
+        capsule_type: u64,
+
+        /// The raw capsule value.
+        value: Vec<u8>,
+    },
+}
+
+impl Capsule {
+    /// Parses a capsule from the beginning of the given buffer.
+    ///
+    /// On success the capsule is returned together with the number of bytes
+    /// it occupied in the buffer.
+    ///
+    /// [`Done`] is returned if the buffer doesn't contain a full capsule yet,
+    /// in which case parsing should be retried once more data is available.
+    ///
+    /// [`MessageError`] is returned if the capsule is malformed.
+    ///
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`MessageError`]: enum.Error.html#variant.MessageError
+    pub fn from_bytes(buf: &[u8]) -> Result<(Capsule, usize)> {
+        let mut b = octets::Octets::with_slice(buf);
+
+        let capsule_type = b.get_varint().map_err(|_| Error::Done)?;
+        let value = b.get_bytes_with_varint_length().map_err(|_| Error::Done)?;
+
+        let capsule = match capsule_type {
+            DATAGRAM_CAPSULE_TYPE_ID => Capsule::Datagram {
+                payload: value.to_vec(),
+            },
+
+            ADDRESS_ASSIGN_CAPSULE_TYPE_ID => Capsule::AddressAssign {
+                addresses: parse_list(value, parse_address_prefix)?,
+            },
+
+            ADDRESS_REQUEST_CAPSULE_TYPE_ID => Capsule::AddressRequest {
+                addresses: parse_list(value, parse_address_prefix)?,
+            },
+
+            ROUTE_ADVERTISEMENT_CAPSULE_TYPE_ID => Capsule::RouteAdvertisement {
+                ranges: parse_list(value, parse_ip_address_range)?,
+            },
+
+            _ => Capsule::Unknown {
+                capsule_type,
+                value: value.to_vec(),
+            },
+        };
+
+        Ok((capsule, b.off()))
+    }
+
+    /// Writes the capsule to the given buffer.
+    ///
+    /// On success the number of bytes written is returned.
+    ///
+    /// [`MessageError`] is returned if a [`RouteAdvertisement`] range mixes
+    /// IPv4 and IPv6 addresses.
+    ///
+    /// [`MessageError`]: enum.Error.html#variant.MessageError
+    /// [`RouteAdvertisement`]: enum.Capsule.html#variant.RouteAdvertisement
+    pub fn to_bytes(&self, out: &mut [u8]) -> Result<usize> {
+        let mut b = octets::OctetsMut::with_slice(out);
+
+        b.put_varint(self.capsule_type())?;
+        b.put_varint(self.value_len() as u64)?;
+
+        match self {
+            Capsule::Datagram { payload } => {
+                b.put_bytes(payload)?;
+            },
+
+            Capsule::AddressAssign { addresses } |
+            Capsule::AddressRequest { addresses } =>
+                for a in addresses {
+                    b.put_varint(a.request_id)?;
+                    put_ip_addr(&mut b, &a.address)?;
+                    b.put_u8(a.prefix_len)?;
+                },
+
+            Capsule::RouteAdvertisement { ranges } => {
+                for r in ranges {
+                    // The IP version is only encoded once per range, so both
+                    // ends must use the same one.
+                    if r.start.is_ipv4() != r.end.is_ipv4() {
+                        return Err(Error::MessageError);
+                    }
+
+                    put_ip_addr(&mut b, &r.start)?;
+                    b.put_bytes(&ip_addr_octets(&r.end))?;
+                    b.put_u8(r.ip_protocol)?;
+                }
+            },
+
+            Capsule::Unknown { value, .. } => {
+                b.put_bytes(value)?;
+            },
+        }
+
+        Ok(b.off())
+    }
+
+    /// Returns the type of the capsule.
+    pub fn capsule_type(&self) -> u64 {
+        match self {
+            Capsule::Datagram { .. } => DATAGRAM_CAPSULE_TYPE_ID,
+
+            Capsule::AddressAssign { .. } => ADDRESS_ASSIGN_CAPSULE_TYPE_ID,
+
+            Capsule::AddressRequest { .. } => ADDRESS_REQUEST_CAPSULE_TYPE_ID,
+
+            Capsule::RouteAdvertisement { .. } =>
+                ROUTE_ADVERTISEMENT_CAPSULE_TYPE_ID,
+
+            Capsule::Unknown { capsule_type, .. } => *capsule_type,
+        }
+    }
+
+    /// Returns the number of bytes needed to encode the capsule.
+    pub fn wire_len(&self) -> usize {
+        let len = self.value_len();
+
+        octets::varint_len(self.capsule_type()) +
+            octets::varint_len(len as u64) +
+            len
+    }
+
+    fn value_len(&self) -> usize {
+        match self {
+            Capsule::Datagram { payload } => payload.len(),
+
+            Capsule::AddressAssign { addresses } |
+            Capsule::AddressRequest { addresses } => addresses
+                .iter()
+                .map(|a| {
+                    octets::varint_len(a.request_id) +
+                        1 +
+                        ip_addr_octets(&a.address).len() +
+                        1
+                })
+                .sum(),
+
+            Capsule::RouteAdvertisement { ranges } => ranges
+                .iter()
+                .map(|r| 1 + ip_addr_octets(&r.start).len() * 2 + 1)
+                .sum(),
+
+            Capsule::Unknown { value, .. } => value.len(),
+        }
+    }
+}
+
+// Parses a capsule value made of consecutive entries of the same kind.
+fn parse_list<T>(
+    mut b: octets::Octets, parse: fn(&mut octets::Octets) -> Result<T>,
+) -> Result<Vec<T>> {
+    let mut list = Vec::new();
+
+    while b.cap() > 0 {
+        list.push(parse(&mut b)?);
+    }
+
+    Ok(list)
+}
+
+fn parse_address_prefix(b: &mut octets::Octets) -> Result<AddressPrefix> {
+    let request_id = b.get_varint().map_err(|_| Error::MessageError)?;
+    let address = get_ip_addr(b)?;
+    let prefix_len = b.get_u8().map_err(|_| Error::MessageError)?;
+
+    if usize::from(prefix_len) > ip_addr_octets(&address).len() * 8 {
+        return Err(Error::MessageError);
+    }
+
+    Ok(AddressPrefix {
+        request_id,
+        address,
+        prefix_len,
+    })
+}
+
+fn parse_ip_address_range(b: &mut octets::Octets) -> Result<IpAddressRange> {
+    let start = get_ip_addr(b)?;
+
+    let end = match start {
+        IpAddr::V4(_) => get_ipv4_addr(b)?,
+
+        IpAddr::V6(_) => get_ipv6_addr(b)?,
+    };
+
+    let ip_protocol = b.get_u8().map_err(|_| Error::MessageError)?;
+
+    if start > end {
+        return Err(Error::MessageError);
+    }
+
+    Ok(IpAddressRange {
+        start,
+        end,
+        ip_protocol,
+    })
+}
+
+fn get_ip_addr(b: &mut octets::Octets) -> Result<IpAddr> {
+    match b.get_u8().map_err(|_| Error::MessageError)? {
+        4 => get_ipv4_addr(b),
+
+        6 => get_ipv6_addr(b),
+
+        _ => Err(Error::MessageError),
+    }
+}
+
+fn get_ipv4_addr(b: &mut octets::Octets) -> Result<IpAddr> {
+    let v = b.get_bytes(4).map_err(|_| Error::MessageError)?;
+
+    let mut octets = [0; 4];
+    octets.copy_from_slice(v.buf());
+
+    Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn get_ipv6_addr(b: &mut octets::Octets) -> Result<IpAddr> {
+    let v = b.get_bytes(16).map_err(|_| Error::MessageError)?;
+
+    let mut octets = [0; 16];
+    octets.copy_from_slice(v.buf());
+
+    Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+}
+
+fn put_ip_addr(b: &mut octets::OctetsMut, addr: &IpAddr) -> Result<()> {
+    let version = match addr {
+        IpAddr::V4(_) => 4,
+
+        IpAddr::V6(_) => 6,
+    };
+
+    b.put_u8(version)?;
+    b.put_bytes(&ip_addr_octets(addr))?;
+
+    Ok(())
+}
+
+fn ip_addr_octets(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(v) => v.octets().to_vec(),
+
+        IpAddr::V6(v) => v.octets().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagram() {
+        let mut d = [42; 128];
+
+        let capsule = Capsule::Datagram {
+            payload: b"hello".to_vec(),
+        };
+
+        let len = capsule.to_bytes(&mut d).unwrap();
+        assert_eq!(len, 7);
+        assert_eq!(len, capsule.wire_len());
+        assert_eq!(&d[..2], &[0x00, 0x05]);
+
+        assert_eq!(Capsule::from_bytes(&d[..len]), Ok((capsule, len)));
+    }
+
+    #[test]
+    fn address_assign() {
+        let mut d = [42; 128];
+
+        let capsule = Capsule::AddressAssign {
+            addresses: vec![
+                AddressPrefix {
+                    request_id: 0,
+                    address: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                    prefix_len: 32,
+                },
+                AddressPrefix {
+                    request_id: 1,
+                    address: "2001:db8::".parse().unwrap(),
+                    prefix_len: 64,
+                },
+            ],
+        };
+
+        let len = capsule.to_bytes(&mut d).unwrap();
+        assert_eq!(len, capsule.wire_len());
+        assert_eq!(len, 2 + 7 + 19);
+        assert_eq!(&d[..5], &[0x01, 26, 0, 4, 192]);
+
+        assert_eq!(Capsule::from_bytes(&d[..len]), Ok((capsule, len)));
+
+        let capsule = Capsule::AddressRequest { addresses: vec![] };
+
+        let len = capsule.to_bytes(&mut d).unwrap();
+        assert_eq!(&d[..len], &[0x02, 0]);
+
+        assert_eq!(Capsule::from_bytes(&d[..len]), Ok((capsule, len)));
+    }
+
+    #[test]
+    fn route_advertisement() {
+        let mut d = [42; 128];
+
+        let capsule = Capsule::RouteAdvertisement {
+            ranges: vec![
+                IpAddressRange {
+                    start: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)),
+                    end: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 255)),
+                    ip_protocol: 0,
+                },
+                IpAddressRange {
+                    start: "2001:db8::".parse().unwrap(),
+                    end: "2001:db8::ffff".parse().unwrap(),
+                    ip_protocol: 17,
+                },
+            ],
+        };
+
+        let len = capsule.to_bytes(&mut d).unwrap();
+        assert_eq!(len, capsule.wire_len());
+        assert_eq!(len, 2 + 10 + 34);
+
+        assert_eq!(Capsule::from_bytes(&d[..len]), Ok((capsule, len)));
+
+        // Both ends of a range must use the same IP version.
+        let capsule = Capsule::RouteAdvertisement {
+            ranges: vec![IpAddressRange {
+                start: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)),
+                end: "2001:db8::".parse().unwrap(),
+                ip_protocol: 0,
+            }],
+        };
+
+        assert_eq!(capsule.to_bytes(&mut d), Err(Error::MessageError));
+    }
+
+    #[test]
+    fn malformed_connect_ip() {
+        // Bad IP version.
+        let d = [0x01, 7, 0, 5, 192, 0, 2, 1, 32];
+        assert_eq!(Capsule::from_bytes(&d), Err(Error::MessageError));
+
+        // Prefix longer than the address.
+        let d = [0x01, 7, 0, 4, 192, 0, 2, 1, 33];
+        assert_eq!(Capsule::from_bytes(&d), Err(Error::MessageError));
+
+        // Truncated address.
+        let d = [0x01, 5, 0, 4, 192, 0, 2];
+        assert_eq!(Capsule::from_bytes(&d), Err(Error::MessageError));
+
+        // Range ending before its start.
+        let d = [0x03, 10, 4, 192, 0, 2, 255, 192, 0, 2, 0, 0];
+        assert_eq!(Capsule::from_bytes(&d), Err(Error::MessageError));
+    }
+
+    #[test]
+    fn unknown() {
+        let mut d = [42; 128];
+
+        let capsule = Capsule::Unknown {
+            capsule_type: 0x1234,
+            value: vec![1, 2, 3],
+        };
+
+        let len = capsule.to_bytes(&mut d).unwrap();
+        assert_eq!(len, capsule.wire_len());
+
+        assert_eq!(Capsule::from_bytes(&d[..len]), Ok((capsule, len)));
+    }
+
+    #[test]
+    fn partial() {
+        let mut d = [42; 128];
+
+        let capsule = Capsule::Datagram {
+            payload: vec![0; 64],
+        };
+
+        let len = capsule.to_bytes(&mut d).unwrap();
+
+        assert_eq!(Capsule::from_bytes(&d[..0]), Err(Error::Done));
+        assert_eq!(Capsule::from_bytes(&d[..1]), Err(Error::Done));
+        assert_eq!(Capsule::from_bytes(&d[..len - 1]), Err(Error::Done));
+
+        // Only the first capsule is consumed.
+        let (parsed, off) = Capsule::from_bytes(&d).unwrap();
+        assert_eq!(parsed, capsule);
+        assert_eq!(off, len);
+    }
+
+    #[test]
+    fn buffer_too_short() {
+        let mut d = [42; 4];
+
+        let capsule = Capsule::Datagram {
+            payload: vec![0; 64],
+        };
+
+        assert_eq!(capsule.to_bytes(&mut d), Err(Error::BufferTooShort));
+    }
+}
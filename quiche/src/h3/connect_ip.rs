@@ -0,0 +1,335 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Proxying IP in HTTP ([RFC 9484]).
+//!
+//! This module implements the protocol layer of CONNECT-IP, which lets a
+//! client establish an IP tunnel through an HTTP/3 proxy. Tunnel
+//! configuration is exchanged using the [`AddressAssign`],
+//! [`AddressRequest`] and [`RouteAdvertisement`] capsules on the request
+//! stream (see [`send_capsule()`]), while IP packets are exchanged as HTTP
+//! Datagrams using [`send_packet()`] and [`recv_packet()`].
+//!
+//! The scope of a tunnel is expressed with a URI template, such as the
+//! default [`DEFAULT_URI_TEMPLATE`], which the client expands with
+//! [`expand_path()`] and the proxy matches with [`parse_path()`].
+//!
+//! [RFC 9484]: https://www.rfc-editor.org/rfc/rfc9484.html
+//! [`AddressAssign`]: ../enum.Capsule.html#variant.AddressAssign
+//! [`AddressRequest`]: ../enum.Capsule.html#variant.AddressRequest
+//! [`RouteAdvertisement`]: ../enum.Capsule.html#variant.RouteAdvertisement
+//! [`send_capsule()`]: ../struct.Connection.html#method.send_capsule
+//! [`send_packet()`]: fn.send_packet.html
+//! [`recv_packet()`]: fn.recv_packet.html
+//! [`DEFAULT_URI_TEMPLATE`]: constant.DEFAULT_URI_TEMPLATE.html
+//! [`expand_path()`]: fn.expand_path.html
+//! [`parse_path()`]: fn.parse_path.html
+
+use std::net::IpAddr;
+
+use super::Connection;
+use super::Error;
+use super::Header;
+use super::NameValue;
+use super::Result;
+
+use super::uri_template;
+
+use crate::octets;
+
+/// The value of the `:protocol` pseudo-header for CONNECT-IP requests.
+pub const PROTOCOL: &[u8] = b"connect-ip";
+
+/// The default path template used to express the scope of a tunnel.
+pub const DEFAULT_URI_TEMPLATE: &str =
+    "/.well-known/masque/ip/{target}/{ipproto}/";
+
+/// The Context ID of HTTP Datagrams carrying IP packets.
+pub const IP_PACKET_CONTEXT_ID: u64 = 0;
+
+const TARGET: &str = "target";
+const IPPROTO: &str = "ipproto";
+
+const WILDCARD: &str = "*";
+
+/// An IP address prefix, as carried by the [`AddressAssign`] and
+/// [`AddressRequest`] capsules.
+///
+/// [`AddressAssign`]: ../enum.Capsule.html#variant.AddressAssign
+/// [`AddressRequest`]: ../enum.Capsule.html#variant.AddressRequest
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressPrefix {
+    /// The ID of the address request. Addresses assigned without a request
+    /// use 0.
+    pub request_id: u64,
+
+    /// The IP address.
+    pub address: IpAddr,
+
+    /// The number of leading bits of the address that form the prefix.
+    pub prefix_len: u8,
+}
+
+/// An inclusive range of IP addresses, as carried by the
+/// [`RouteAdvertisement`] capsule.
+///
+/// [`RouteAdvertisement`]: ../enum.Capsule.html#variant.RouteAdvertisement
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IpAddressRange {
+    /// The first address of the range.
+    pub start: IpAddr,
+
+    /// The last address of the range. It must be of the same IP version as
+    /// the first one.
+    pub end: IpAddr,
+
+    /// The IP protocol allowed in the range, or 0 for all protocols.
+    pub ip_protocol: u8,
+}
+
+/// Expands a path template with the given target and IP protocol.
+///
+/// A [`None`] target or protocol requests a tunnel that isn't restricted
+/// to specific hosts or protocols. The target is otherwise a hostname, an
+/// IP address or an IP prefix such as `192.0.2.0/24`.
+///
+/// [`None`] is returned if the template is malformed or uses variables
+/// other than `target` and `ipproto`.
+pub fn expand_path(
+    template: &str, target: Option<&str>, ip_protocol: Option<u8>,
+) -> Option<String> {
+    let ip_protocol = ip_protocol.map(|v| v.to_string());
+
+    uri_template::expand(template, &[
+        (TARGET, target.unwrap_or(WILDCARD)),
+        (IPPROTO, ip_protocol.as_deref().unwrap_or(WILDCARD)),
+    ])
+}
+
+/// Extracts the target and IP protocol from a request path, according to
+/// the given path template.
+///
+/// Targets and protocols that aren't restricted by the request, either
+/// because they are not part of the template or because they are set to
+/// the `*` wildcard, are returned as [`None`].
+///
+/// [`None`] is returned instead of the tuple if the path doesn't match the
+/// template, or if the IP protocol is invalid.
+pub fn parse_path(
+    template: &str, path: &str,
+) -> Option<(Option<String>, Option<u8>)> {
+    let mut target = None;
+    let mut ip_protocol = None;
+
+    for (name, value) in uri_template::parse(template, path)? {
+        if value == WILDCARD {
+            continue;
+        }
+
+        match name {
+            TARGET => target = Some(value),
+
+            IPPROTO => ip_protocol = Some(value.parse::<u8>().ok()?),
+
+            _ => (),
+        }
+    }
+
+    Some((target, ip_protocol))
+}
+
+/// Returns the headers of a CONNECT-IP request towards the given proxy
+/// authority, with a path generated by [`expand_path()`].
+///
+/// [`expand_path()`]: fn.expand_path.html
+pub fn request_headers(authority: &str, path: &str) -> Vec<Header> {
+    vec![
+        Header::new(b":method", b"CONNECT"),
+        Header::new(b":protocol", PROTOCOL),
+        Header::new(b":scheme", b"https"),
+        Header::new(b":authority", authority.as_bytes()),
+        Header::new(b":path", path.as_bytes()),
+        Header::new(b"capsule-protocol", b"?1"),
+    ]
+}
+
+/// Returns whether the given request headers describe a CONNECT-IP request.
+pub fn is_request<T: NameValue>(headers: &[T]) -> bool {
+    let mut method = None;
+    let mut protocol = None;
+
+    for h in headers {
+        match h.name() {
+            b":method" => method = Some(h.value()),
+
+            b":protocol" => protocol = Some(h.value()),
+
+            _ => (),
+        }
+    }
+
+    method == Some(b"CONNECT") && protocol == Some(PROTOCOL)
+}
+
+/// Sends an IP packet through the tunnel of the given request stream.
+///
+/// The packet is sent as an HTTP Datagram, so like QUIC DATAGRAMs it might
+/// be dropped before reaching the peer.
+///
+/// [`FrameUnexpected`] is returned if the stream is not a bidirectional
+/// request stream.
+///
+/// [`FrameUnexpected`]: ../enum.Error.html#variant.FrameUnexpected
+pub fn send_packet(
+    h3_conn: &mut Connection, conn: &mut crate::Connection, stream_id: u64,
+    packet: &[u8],
+) -> Result<()> {
+    if !crate::stream::is_bidi(stream_id) {
+        return Err(Error::FrameUnexpected);
+    }
+
+    let mut d = vec![0; octets::varint_len(IP_PACKET_CONTEXT_ID) + packet.len()];
+    let mut b = octets::OctetsMut::with_slice(&mut d);
+
+    b.put_varint(IP_PACKET_CONTEXT_ID)?;
+    b.put_bytes(packet)?;
+
+    h3_conn.send_dgram(conn, stream_id / 4, &d)
+}
+
+/// Reads a datagram received through any tunnel into the provided buffer.
+///
+/// On success the ID of the tunnel's request stream is returned, together
+/// with the datagram's Context ID and the length of its payload, which is
+/// written at the start of the buffer.
+///
+/// The payload is an IP packet when the Context ID is
+/// [`IP_PACKET_CONTEXT_ID`]. Other Context IDs are returned as well, so that
+/// the application can handle those it negotiated with the peer, and drop
+/// the others.
+///
+/// This reads HTTP Datagrams using [`recv_dgram()`], so it is meant for
+/// connections that only exchange datagrams with CONNECT-IP tunnels.
+/// Datagrams that don't carry a Context ID are malformed and are dropped.
+///
+/// [`Done`] is returned if there are no more datagrams to read.
+///
+/// [`IP_PACKET_CONTEXT_ID`]: constant.IP_PACKET_CONTEXT_ID.html
+/// [`recv_dgram()`]: ../struct.Connection.html#method.recv_dgram
+/// [`Done`]: ../enum.Error.html#variant.Done
+pub fn recv_packet(
+    h3_conn: &mut Connection, conn: &mut crate::Connection, buf: &mut [u8],
+) -> Result<(u64, u64, usize)> {
+    loop {
+        let (len, flow_id, flow_id_len) = h3_conn.recv_dgram(conn, buf)?;
+
+        let mut b = octets::Octets::with_slice(&buf[flow_id_len..len]);
+
+        let context_id = match b.get_varint() {
+            Ok(v) => v,
+
+            Err(_) => {
+                trace!("dropped datagram without Context ID");
+                continue;
+            },
+        };
+
+        let off = flow_id_len + b.off();
+
+        buf.copy_within(off..len, 0);
+
+        return Ok((flow_id * 4, context_id, len - off));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand() {
+        assert_eq!(
+            expand_path(DEFAULT_URI_TEMPLATE, None, None),
+            Some("/.well-known/masque/ip/%2A/%2A/".to_string())
+        );
+
+        assert_eq!(
+            expand_path(DEFAULT_URI_TEMPLATE, Some("192.0.2.0/24"), Some(17)),
+            Some("/.well-known/masque/ip/192.0.2.0%2F24/17/".to_string())
+        );
+
+        assert_eq!(
+            expand_path("/vpn", Some("example.com"), None),
+            Some("/vpn".to_string())
+        );
+
+        assert_eq!(expand_path("/ip/{target}/{x}", None, None), None);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_path(DEFAULT_URI_TEMPLATE, "/.well-known/masque/ip/*/*/"),
+            Some((None, None))
+        );
+
+        assert_eq!(
+            parse_path(DEFAULT_URI_TEMPLATE, "/.well-known/masque/ip/%2A/6/"),
+            Some((None, Some(6)))
+        );
+
+        assert_eq!(
+            parse_path(
+                DEFAULT_URI_TEMPLATE,
+                "/.well-known/masque/ip/2001%3Adb8%3A%3A%2F32/*/"
+            ),
+            Some((Some("2001:db8::/32".to_string()), None))
+        );
+
+        assert_eq!(parse_path("/vpn", "/vpn"), Some((None, None)));
+
+        assert_eq!(parse_path(DEFAULT_URI_TEMPLATE, "/index.html"), None);
+        assert_eq!(
+            parse_path(DEFAULT_URI_TEMPLATE, "/.well-known/masque/ip/*/300/"),
+            None
+        );
+    }
+
+    #[test]
+    fn request() {
+        let path = expand_path(DEFAULT_URI_TEMPLATE, None, None).unwrap();
+
+        let headers = request_headers("proxy.example.org", &path);
+        assert!(is_request(&headers));
+        assert!(!crate::h3::connect_udp::is_request(&headers));
+
+        let headers = crate::h3::connect_udp::request_headers(
+            "proxy.example.org",
+            "/.well-known/masque/udp/192.0.2.6/443/",
+        );
+        assert!(!is_request(&headers));
+    }
+}
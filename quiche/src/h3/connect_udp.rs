@@ -0,0 +1,290 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Proxying UDP in HTTP ([RFC 9298]).
+//!
+//! This module implements the protocol layer of CONNECT-UDP, which lets a
+//! client ask an HTTP/3 proxy to open a UDP flow towards a target host. UDP
+//! payloads are then exchanged as HTTP Datagrams associated with the request
+//! stream (see [`send_dgram()`]).
+//!
+//! The target of a request is expressed with a URI template, such as the
+//! default [`DEFAULT_URI_TEMPLATE`], which the client expands with
+//! [`expand_path()`] and the proxy matches with [`parse_path()`]. Only
+//! simple `{variable}` expressions are supported in templates.
+//!
+//! [RFC 9298]: https://www.rfc-editor.org/rfc/rfc9298.html
+//! [`send_dgram()`]: ../struct.Connection.html#method.send_dgram
+//! [`DEFAULT_URI_TEMPLATE`]: constant.DEFAULT_URI_TEMPLATE.html
+//! [`expand_path()`]: fn.expand_path.html
+//! [`parse_path()`]: fn.parse_path.html
+
+use super::Error;
+use super::Header;
+use super::NameValue;
+use super::Result;
+
+use super::uri_template;
+
+use crate::octets;
+
+/// The value of the `:protocol` pseudo-header for CONNECT-UDP requests.
+pub const PROTOCOL: &[u8] = b"connect-udp";
+
+/// The default path template used to express the target of a request.
+pub const DEFAULT_URI_TEMPLATE: &str =
+    "/.well-known/masque/udp/{target_host}/{target_port}/";
+
+/// The Context ID of HTTP Datagrams carrying UDP payloads.
+pub const UDP_PAYLOAD_CONTEXT_ID: u64 = 0;
+
+const TARGET_HOST: &str = "target_host";
+const TARGET_PORT: &str = "target_port";
+
+/// Expands a path template with the given target host and port.
+///
+/// The host is percent-encoded as needed, e.g. for the colons of IPv6
+/// addresses.
+///
+/// [`None`] is returned if the template is malformed or doesn't contain
+/// both the `target_host` and `target_port` variables.
+pub fn expand_path(
+    template: &str, target_host: &str, target_port: u16,
+) -> Option<String> {
+    let names = uri_template::variables(template)?;
+
+    if !names.contains(&TARGET_HOST) || !names.contains(&TARGET_PORT) {
+        return None;
+    }
+
+    let port = target_port.to_string();
+
+    uri_template::expand(template, &[
+        (TARGET_HOST, target_host),
+        (TARGET_PORT, &port),
+    ])
+}
+
+/// Extracts the target host and port from a request path, according to the
+/// given path template.
+///
+/// [`None`] is returned if the path doesn't match the template, or if the
+/// target is invalid.
+pub fn parse_path(template: &str, path: &str) -> Option<(String, u16)> {
+    let mut host = None;
+    let mut port = None;
+
+    for (name, value) in uri_template::parse(template, path)? {
+        match name {
+            TARGET_HOST => host = Some(value),
+
+            TARGET_PORT => port = Some(value.parse::<u16>().ok()?),
+
+            _ => (),
+        }
+    }
+
+    match (host, port) {
+        (Some(host), Some(port)) if port != 0 => Some((host, port)),
+
+        _ => None,
+    }
+}
+
+/// Returns the headers of a CONNECT-UDP request towards the given proxy
+/// authority, with a path generated by [`expand_path()`].
+///
+/// [`expand_path()`]: fn.expand_path.html
+pub fn request_headers(authority: &str, path: &str) -> Vec<Header> {
+    vec![
+        Header::new(b":method", b"CONNECT"),
+        Header::new(b":protocol", PROTOCOL),
+        Header::new(b":scheme", b"https"),
+        Header::new(b":authority", authority.as_bytes()),
+        Header::new(b":path", path.as_bytes()),
+        Header::new(b"capsule-protocol", b"?1"),
+    ]
+}
+
+/// Returns whether the given request headers describe a CONNECT-UDP request.
+pub fn is_request<T: NameValue>(headers: &[T]) -> bool {
+    let mut method = None;
+    let mut protocol = None;
+
+    for h in headers {
+        match h.name() {
+            b":method" => method = Some(h.value()),
+
+            b":protocol" => protocol = Some(h.value()),
+
+            _ => (),
+        }
+    }
+
+    method == Some(b"CONNECT") && protocol == Some(PROTOCOL)
+}
+
+/// Prepends the given Context ID to a payload, producing the content of an
+/// HTTP Datagram for [`send_dgram()`].
+///
+/// [`send_dgram()`]: ../struct.Connection.html#method.send_dgram
+pub fn encode_payload(context_id: u64, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut d = vec![0; octets::varint_len(context_id) + payload.len()];
+    let mut b = octets::OctetsMut::with_slice(&mut d);
+
+    b.put_varint(context_id)?;
+    b.put_bytes(payload)?;
+
+    Ok(d)
+}
+
+/// Splits the content of an HTTP Datagram into its Context ID and payload.
+///
+/// UDP payloads use the [`UDP_PAYLOAD_CONTEXT_ID`] Context ID. Datagrams
+/// with unknown Context IDs should be dropped.
+///
+/// [`DatagramError`] is returned if the datagram is malformed.
+///
+/// [`UDP_PAYLOAD_CONTEXT_ID`]: constant.UDP_PAYLOAD_CONTEXT_ID.html
+/// [`DatagramError`]: ../enum.Error.html#variant.DatagramError
+pub fn decode_payload(buf: &[u8]) -> Result<(u64, &[u8])> {
+    let mut b = octets::Octets::with_slice(buf);
+
+    let context_id = b.get_varint().map_err(|_| Error::DatagramError)?;
+
+    Ok((context_id, &buf[b.off()..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand() {
+        assert_eq!(
+            expand_path(DEFAULT_URI_TEMPLATE, "192.0.2.6", 443),
+            Some("/.well-known/masque/udp/192.0.2.6/443/".to_string())
+        );
+
+        assert_eq!(
+            expand_path(DEFAULT_URI_TEMPLATE, "2001:db8::42", 53),
+            Some("/.well-known/masque/udp/2001%3Adb8%3A%3A42/53/".to_string())
+        );
+
+        assert_eq!(
+            expand_path("/masque?h={target_host}&p={target_port}", "a.b", 1),
+            Some("/masque?h=a.b&p=1".to_string())
+        );
+
+        // Both variables are required.
+        assert_eq!(expand_path("/masque/{target_host}/", "a.b", 1), None);
+
+        // Unknown variables and malformed expressions are rejected.
+        assert_eq!(
+            expand_path("/{target_host}/{target_port}/{x}", "a", 1),
+            None
+        );
+        assert_eq!(expand_path("/{target_host}/{target_port", "a", 1), None);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_path(
+                DEFAULT_URI_TEMPLATE,
+                "/.well-known/masque/udp/192.0.2.6/443/"
+            ),
+            Some(("192.0.2.6".to_string(), 443))
+        );
+
+        assert_eq!(
+            parse_path(
+                DEFAULT_URI_TEMPLATE,
+                "/.well-known/masque/udp/2001%3Adb8%3A%3A42/53/"
+            ),
+            Some(("2001:db8::42".to_string(), 53))
+        );
+
+        assert_eq!(
+            parse_path(
+                "/masque?h={target_host}&p={target_port}",
+                "/masque?h=example.com&p=8080"
+            ),
+            Some(("example.com".to_string(), 8080))
+        );
+
+        // Paths that don't match the template.
+        assert_eq!(parse_path(DEFAULT_URI_TEMPLATE, "/index.html"), None);
+        assert_eq!(
+            parse_path(DEFAULT_URI_TEMPLATE, "/.well-known/masque/udp/a/1/x"),
+            None
+        );
+        assert_eq!(
+            parse_path(DEFAULT_URI_TEMPLATE, "/.well-known/masque/udp//1/"),
+            None
+        );
+
+        // Invalid ports.
+        assert_eq!(
+            parse_path(DEFAULT_URI_TEMPLATE, "/.well-known/masque/udp/a/0/"),
+            None
+        );
+        assert_eq!(
+            parse_path(DEFAULT_URI_TEMPLATE, "/.well-known/masque/udp/a/99999/"),
+            None
+        );
+    }
+
+    #[test]
+    fn request() {
+        let path = expand_path(DEFAULT_URI_TEMPLATE, "192.0.2.6", 443).unwrap();
+
+        let headers = request_headers("proxy.example.org", &path);
+        assert!(is_request(&headers));
+
+        let headers = vec![
+            Header::new(b":method", b"CONNECT"),
+            Header::new(b":authority", b"example.org:443"),
+        ];
+        assert!(!is_request(&headers));
+    }
+
+    #[test]
+    fn payload() {
+        let d = encode_payload(UDP_PAYLOAD_CONTEXT_ID, b"hello").unwrap();
+        assert_eq!(d, b"\x00hello");
+
+        assert_eq!(
+            decode_payload(&d),
+            Ok((UDP_PAYLOAD_CONTEXT_ID, &b"hello"[..]))
+        );
+
+        let d = encode_payload(1234, b"").unwrap();
+        assert_eq!(decode_payload(&d), Ok((1234, &b""[..])));
+
+        assert_eq!(decode_payload(b""), Err(Error::DatagramError));
+    }
+}
@@ -68,6 +68,41 @@ pub extern fn quiche_h3_config_set_qpack_blocked_streams(
     config.set_qpack_blocked_streams(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_config_enable_qpack_huffman(
+    config: &mut h3::Config, v: bool,
+) {
+    config.enable_qpack_huffman(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_config_enable_strict_header_validation(
+    config: &mut h3::Config, v: bool,
+) {
+    config.enable_strict_header_validation(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_config_register_extension_frame(
+    config: &mut h3::Config, ty: u64,
+) {
+    config.register_extension_frame(ty);
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_config_register_extension_stream(
+    config: &mut h3::Config, ty: u64,
+) {
+    config.register_extension_stream(ty);
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_config_register_raw_stream(
+    config: &mut h3::Config, ty: u64,
+) {
+    config.register_raw_stream(ty);
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_config_free(config: *mut h3::Config) {
     unsafe { Box::from_raw(config) };
@@ -139,6 +174,18 @@ pub extern fn quiche_h3_event_type(ev: &h3::Event) -> u32 {
         h3::Event::GoAway { .. } => 4,
 
         h3::Event::Reset { .. } => 5,
+
+        h3::Event::PriorityUpdate { .. } => 6,
+
+        h3::Event::Trailers { .. } => 7,
+
+        h3::Event::ExtensionFrame { .. } => 8,
+
+        h3::Event::ExtensionStream { .. } => 9,
+
+        h3::Event::RawStream { .. } => 10,
+
+        h3::Event::Settings => 11,
     }
 }
 
@@ -157,7 +204,7 @@ pub extern fn quiche_h3_event_for_each_header(
     argp: *mut c_void,
 ) -> c_int {
     match ev {
-        h3::Event::Headers { list, .. } =>
+        h3::Event::Headers { list, .. } | h3::Event::Trailers { list } =>
             for h in list {
                 let rc = cb(
                     h.name().as_ptr(),
@@ -187,6 +234,24 @@ pub extern fn quiche_h3_event_headers_has_body(ev: &h3::Event) -> bool {
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_event_priority_update(
+    ev: &h3::Event, stream_id: *mut u64, urgency: *mut u8, incremental: *mut bool,
+) {
+    match ev {
+        h3::Event::PriorityUpdate {
+            stream_id: id,
+            priority,
+        } => unsafe {
+            *stream_id = *id;
+            *urgency = priority.urgency();
+            *incremental = priority.incremental();
+        },
+
+        _ => unreachable!(),
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_event_free(ev: *mut h3::Event) {
     unsafe { Box::from_raw(ev) };
@@ -215,6 +280,17 @@ pub extern fn quiche_h3_send_request(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_open_raw_stream(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, ty: u64, bidi: bool,
+) -> i64 {
+    match conn.open_raw_stream(quic_conn, ty, bidi) {
+        Ok(v) => v as i64,
+
+        Err(e) => e.to_c() as i64,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_send_response(
     conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
@@ -269,6 +345,20 @@ pub extern fn quiche_h3_send_body(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_send_trailers(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
+    headers: *const Header, headers_len: size_t,
+) -> c_int {
+    let trailers = headers_from_ptr(headers, headers_len);
+
+    match conn.send_trailers(quic_conn, stream_id, &trailers) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_recv_body(
     conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
@@ -287,6 +377,31 @@ pub extern fn quiche_h3_recv_body(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_send_priority_update(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
+    urgency: u8, incremental: bool,
+) -> c_int {
+    let priority = h3::Priority::new(urgency, incremental);
+
+    match conn.send_priority_update(quic_conn, stream_id, &priority) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_send_goaway(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, id: u64,
+) -> c_int {
+    match conn.send_goaway(quic_conn, id) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_dgram_enabled_by_peer(
     conn: &h3::Connection, quic_conn: &Connection,
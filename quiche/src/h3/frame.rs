@@ -34,12 +34,17 @@ pub const CANCEL_PUSH_FRAME_TYPE_ID: u64 = 0x3;
 pub const SETTINGS_FRAME_TYPE_ID: u64 = 0x4;
 pub const PUSH_PROMISE_FRAME_TYPE_ID: u64 = 0x5;
 pub const GOAWAY_FRAME_TYPE_ID: u64 = 0x6;
+pub const ORIGIN_FRAME_TYPE_ID: u64 = 0xC;
 pub const MAX_PUSH_FRAME_TYPE_ID: u64 = 0xD;
+pub const PRIORITY_UPDATE_FRAME_REQUEST_TYPE_ID: u64 = 0xF0700;
+pub const PRIORITY_UPDATE_FRAME_PUSH_TYPE_ID: u64 = 0xF0701;
 
 pub const SETTINGS_QPACK_MAX_TABLE_CAPACITY: u64 = 0x1;
 pub const SETTINGS_MAX_FIELD_SECTION_SIZE: u64 = 0x6;
 pub const SETTINGS_QPACK_BLOCKED_STREAMS: u64 = 0x7;
-pub const SETTINGS_H3_DATAGRAM: u64 = 0x276;
+pub const SETTINGS_ENABLE_CONNECT_PROTOCOL: u64 = 0x8;
+pub const SETTINGS_H3_DATAGRAM_00: u64 = 0x276;
+pub const SETTINGS_H3_DATAGRAM: u64 = 0x33;
 
 // Permit between 16 maximally-encoded and 128 minimally-encoded SETTINGS.
 const MAX_SETTINGS_PAYLOAD_SIZE: usize = 256;
@@ -63,6 +68,7 @@ pub enum Frame {
         qpack_max_table_capacity: Option<u64>,
         qpack_blocked_streams: Option<u64>,
         h3_datagram: Option<u64>,
+        connect_protocol_enabled: Option<u64>,
         grease: Option<(u64, u64)>,
         raw: Option<Vec<(u64, u64)>>,
     },
@@ -80,7 +86,24 @@ pub enum Frame {
         push_id: u64,
     },
 
-    Unknown,
+    Origin {
+        origins: Vec<Vec<u8>>,
+    },
+
+    PriorityUpdateRequest {
+        prioritized_element_id: u64,
+        priority_field_value: Vec<u8>,
+    },
+
+    PriorityUpdatePush {
+        prioritized_element_id: u64,
+        priority_field_value: Vec<u8>,
+    },
+
+    Unknown {
+        raw_type: u64,
+        payload: Vec<u8>,
+    },
 }
 
 impl Frame {
@@ -117,7 +140,17 @@ impl Frame {
                 push_id: b.get_varint()?,
             },
 
-            _ => Frame::Unknown,
+            ORIGIN_FRAME_TYPE_ID =>
+                parse_origin_frame(payload_length as usize, &mut b)?,
+
+            PRIORITY_UPDATE_FRAME_REQUEST_TYPE_ID |
+            PRIORITY_UPDATE_FRAME_PUSH_TYPE_ID =>
+                parse_priority_update(frame_type, payload_length, &mut b)?,
+
+            _ => Frame::Unknown {
+                raw_type: frame_type,
+                payload: bytes.to_vec(),
+            },
         };
 
         Ok(frame)
@@ -153,6 +186,7 @@ impl Frame {
                 qpack_max_table_capacity,
                 qpack_blocked_streams,
                 h3_datagram,
+                connect_protocol_enabled,
                 grease,
                 ..
             } => {
@@ -178,6 +212,11 @@ impl Frame {
                     len += octets::varint_len(*val);
                 }
 
+                if let Some(val) = connect_protocol_enabled {
+                    len += octets::varint_len(SETTINGS_ENABLE_CONNECT_PROTOCOL);
+                    len += octets::varint_len(*val);
+                }
+
                 if let Some(val) = grease {
                     len += octets::varint_len(val.0);
                     len += octets::varint_len(val.1);
@@ -206,6 +245,11 @@ impl Frame {
                     b.put_varint(*val as u64)?;
                 }
 
+                if let Some(val) = connect_protocol_enabled {
+                    b.put_varint(SETTINGS_ENABLE_CONNECT_PROTOCOL)?;
+                    b.put_varint(*val)?;
+                }
+
                 if let Some(val) = grease {
                     b.put_varint(val.0)?;
                     b.put_varint(val.1)?;
@@ -238,7 +282,56 @@ impl Frame {
                 b.put_varint(*push_id)?;
             },
 
-            Frame::Unknown => unreachable!(),
+            Frame::Origin { origins } => {
+                let len: usize = origins.iter().map(|o| 2 + o.len()).sum();
+
+                b.put_varint(ORIGIN_FRAME_TYPE_ID)?;
+                b.put_varint(len as u64)?;
+
+                for origin in origins {
+                    if origin.len() > u16::MAX as usize {
+                        return Err(super::Error::FrameError);
+                    }
+
+                    b.put_u16(origin.len() as u16)?;
+                    b.put_bytes(origin)?;
+                }
+            },
+
+            Frame::PriorityUpdateRequest {
+                prioritized_element_id,
+                priority_field_value,
+            } => {
+                let len = octets::varint_len(*prioritized_element_id) +
+                    priority_field_value.len();
+
+                b.put_varint(PRIORITY_UPDATE_FRAME_REQUEST_TYPE_ID)?;
+                b.put_varint(len as u64)?;
+
+                b.put_varint(*prioritized_element_id)?;
+                b.put_bytes(priority_field_value)?;
+            },
+
+            Frame::PriorityUpdatePush {
+                prioritized_element_id,
+                priority_field_value,
+            } => {
+                let len = octets::varint_len(*prioritized_element_id) +
+                    priority_field_value.len();
+
+                b.put_varint(PRIORITY_UPDATE_FRAME_PUSH_TYPE_ID)?;
+                b.put_varint(len as u64)?;
+
+                b.put_varint(*prioritized_element_id)?;
+                b.put_bytes(priority_field_value)?;
+            },
+
+            Frame::Unknown { raw_type, payload } => {
+                b.put_varint(*raw_type)?;
+                b.put_varint(payload.len() as u64)?;
+
+                b.put_bytes(payload)?;
+            },
         }
 
         Ok(before - b.cap())
@@ -290,8 +383,36 @@ impl std::fmt::Debug for Frame {
                 write!(f, "MAX_PUSH_ID push_id={}", push_id)?;
             },
 
-            Frame::Unknown => {
-                write!(f, "UNKNOWN")?;
+            Frame::Origin { origins } => {
+                write!(f, "ORIGIN count={}", origins.len())?;
+            },
+
+            Frame::PriorityUpdateRequest {
+                prioritized_element_id,
+                priority_field_value,
+            } => {
+                write!(
+                    f,
+                    "PRIORITY_UPDATE request_stream_id={}, priority_field_len={}",
+                    prioritized_element_id,
+                    priority_field_value.len()
+                )?;
+            },
+
+            Frame::PriorityUpdatePush {
+                prioritized_element_id,
+                priority_field_value,
+            } => {
+                write!(
+                    f,
+                    "PRIORITY_UPDATE push_id={}, priority_field_len={}",
+                    prioritized_element_id,
+                    priority_field_value.len()
+                )?;
+            },
+
+            Frame::Unknown { raw_type, payload } => {
+                write!(f, "UNKNOWN type={} len={}", raw_type, payload.len())?;
             },
         }
 
@@ -306,6 +427,7 @@ fn parse_settings_frame(
     let mut qpack_max_table_capacity = None;
     let mut qpack_blocked_streams = None;
     let mut h3_datagram = None;
+    let mut connect_protocol_enabled = None;
     let mut raw = Vec::new();
 
     // Reject SETTINGS frames that are too long.
@@ -334,7 +456,8 @@ fn parse_settings_frame(
                 qpack_blocked_streams = Some(value);
             },
 
-            SETTINGS_H3_DATAGRAM => {
+            // Also accept the codepoint used by earlier drafts of RFC 9297.
+            SETTINGS_H3_DATAGRAM | SETTINGS_H3_DATAGRAM_00 => {
                 if value > 1 {
                     return Err(super::Error::SettingsError);
                 }
@@ -342,6 +465,14 @@ fn parse_settings_frame(
                 h3_datagram = Some(value);
             },
 
+            SETTINGS_ENABLE_CONNECT_PROTOCOL => {
+                if value > 1 {
+                    return Err(super::Error::SettingsError);
+                }
+
+                connect_protocol_enabled = Some(value);
+            },
+
             // Reserved values overlap with HTTP/2 and MUST be rejected
             0x0 | 0x2 | 0x3 | 0x4 | 0x5 =>
                 return Err(super::Error::SettingsError),
@@ -356,6 +487,7 @@ fn parse_settings_frame(
         qpack_max_table_capacity,
         qpack_blocked_streams,
         h3_datagram,
+        connect_protocol_enabled,
         grease: None,
         raw: Some(raw),
     })
@@ -374,6 +506,46 @@ fn parse_push_promise(
     })
 }
 
+fn parse_origin_frame(
+    payload_length: usize, b: &mut octets::Octets,
+) -> Result<Frame> {
+    let mut origins = Vec::new();
+
+    while b.off() < payload_length {
+        let len = b.get_u16()?;
+
+        origins.push(b.get_bytes(len as usize)?.to_vec());
+    }
+
+    Ok(Frame::Origin { origins })
+}
+
+fn parse_priority_update(
+    frame_type: u64, payload_length: u64, b: &mut octets::Octets,
+) -> Result<Frame> {
+    let prioritized_element_id = b.get_varint()?;
+    let priority_field_value_length = payload_length
+        .checked_sub(octets::varint_len(prioritized_element_id) as u64)
+        .ok_or(super::Error::FrameError)?;
+    let priority_field_value =
+        b.get_bytes(priority_field_value_length as usize)?.to_vec();
+
+    match frame_type {
+        PRIORITY_UPDATE_FRAME_REQUEST_TYPE_ID =>
+            Ok(Frame::PriorityUpdateRequest {
+                prioritized_element_id,
+                priority_field_value,
+            }),
+
+        PRIORITY_UPDATE_FRAME_PUSH_TYPE_ID => Ok(Frame::PriorityUpdatePush {
+            prioritized_element_id,
+            priority_field_value,
+        }),
+
+        _ => unreachable!(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -477,11 +649,12 @@ mod tests {
             qpack_max_table_capacity: Some(0),
             qpack_blocked_streams: Some(0),
             h3_datagram: Some(0),
+            connect_protocol_enabled: None,
             grease: None,
             raw: Some(raw_settings),
         };
 
-        let frame_payload_len = 9;
+        let frame_payload_len = 8;
         let frame_header_len = 2;
 
         let wire_len = {
@@ -511,6 +684,7 @@ mod tests {
             qpack_max_table_capacity: Some(0),
             qpack_blocked_streams: Some(0),
             h3_datagram: Some(0),
+            connect_protocol_enabled: None,
             grease: Some((33, 33)),
             raw: Default::default(),
         };
@@ -530,11 +704,12 @@ mod tests {
             qpack_max_table_capacity: Some(0),
             qpack_blocked_streams: Some(0),
             h3_datagram: Some(0),
+            connect_protocol_enabled: None,
             grease: None,
             raw: Some(raw_settings),
         };
 
-        let frame_payload_len = 11;
+        let frame_payload_len = 10;
         let frame_header_len = 2;
 
         let wire_len = {
@@ -566,6 +741,7 @@ mod tests {
             qpack_max_table_capacity: None,
             qpack_blocked_streams: None,
             h3_datagram: None,
+            connect_protocol_enabled: None,
             grease: None,
             raw: Some(raw_settings),
         };
@@ -602,11 +778,12 @@ mod tests {
             qpack_max_table_capacity: None,
             qpack_blocked_streams: None,
             h3_datagram: Some(1),
+            connect_protocol_enabled: None,
             grease: None,
             raw: Some(raw_settings),
         };
 
-        let frame_payload_len = 3;
+        let frame_payload_len = 2;
         let frame_header_len = 2;
 
         let wire_len = {
@@ -627,6 +804,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn settings_h3_dgram_draft() {
+        let mut d = [42; 128];
+
+        let frame_payload_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            b.put_varint(SETTINGS_H3_DATAGRAM_00).unwrap();
+            b.put_varint(1).unwrap();
+            b.off()
+        };
+
+        assert_eq!(
+            Frame::from_bytes(
+                SETTINGS_FRAME_TYPE_ID,
+                frame_payload_len as u64,
+                &d[..frame_payload_len]
+            )
+            .unwrap(),
+            Frame::Settings {
+                max_field_section_size: None,
+                qpack_max_table_capacity: None,
+                qpack_blocked_streams: None,
+                h3_datagram: Some(1),
+                connect_protocol_enabled: None,
+                grease: None,
+                raw: Some(vec![(SETTINGS_H3_DATAGRAM_00, 1)]),
+            }
+        );
+    }
+
     #[test]
     fn settings_h3_dgram_bad() {
         let mut d = [42; 128];
@@ -636,11 +843,83 @@ mod tests {
             qpack_max_table_capacity: None,
             qpack_blocked_streams: None,
             h3_datagram: Some(5),
+            connect_protocol_enabled: None,
             grease: None,
             raw: Default::default(),
         };
 
-        let frame_payload_len = 3;
+        let frame_payload_len = 2;
+        let frame_header_len = 2;
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, frame_header_len + frame_payload_len);
+
+        assert_eq!(
+            Frame::from_bytes(
+                SETTINGS_FRAME_TYPE_ID,
+                frame_payload_len as u64,
+                &d[frame_header_len..]
+            ),
+            Err(crate::h3::Error::SettingsError)
+        );
+    }
+
+    #[test]
+    fn settings_connect_protocol_only() {
+        let mut d = [42; 128];
+
+        let raw_settings = vec![(SETTINGS_ENABLE_CONNECT_PROTOCOL, 1)];
+
+        let frame = Frame::Settings {
+            max_field_section_size: None,
+            qpack_max_table_capacity: None,
+            qpack_blocked_streams: None,
+            h3_datagram: None,
+            connect_protocol_enabled: Some(1),
+            grease: None,
+            raw: Some(raw_settings),
+        };
+
+        let frame_payload_len = 2;
+        let frame_header_len = 2;
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, frame_header_len + frame_payload_len);
+
+        assert_eq!(
+            Frame::from_bytes(
+                SETTINGS_FRAME_TYPE_ID,
+                frame_payload_len as u64,
+                &d[frame_header_len..]
+            )
+            .unwrap(),
+            frame
+        );
+    }
+
+    #[test]
+    fn settings_connect_protocol_bad() {
+        let mut d = [42; 128];
+
+        let frame = Frame::Settings {
+            max_field_section_size: None,
+            qpack_max_table_capacity: None,
+            qpack_blocked_streams: None,
+            h3_datagram: None,
+            connect_protocol_enabled: Some(2),
+            grease: None,
+            raw: Default::default(),
+        };
+
+        let frame_payload_len = 2;
         let frame_header_len = 2;
 
         let wire_len = {
@@ -674,6 +953,7 @@ mod tests {
             qpack_max_table_capacity: Some(0),
             qpack_blocked_streams: Some(0),
             h3_datagram: None,
+            connect_protocol_enabled: None,
             grease: None,
             raw: Some(raw_settings),
         };
@@ -877,10 +1157,149 @@ mod tests {
         );
     }
 
+    #[test]
+    fn origin() {
+        let mut d = [42; 128];
+
+        let frame = Frame::Origin {
+            origins: vec![
+                b"https://example.com".to_vec(),
+                b"https://example.org".to_vec(),
+            ],
+        };
+
+        let frame_payload_len = 42;
+        let frame_header_len = 2;
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, frame_header_len + frame_payload_len);
+
+        assert_eq!(
+            Frame::from_bytes(
+                ORIGIN_FRAME_TYPE_ID,
+                frame_payload_len as u64,
+                &d[frame_header_len..]
+            )
+            .unwrap(),
+            frame
+        );
+    }
+
+    #[test]
+    fn origin_truncated() {
+        // The origin is shorter than its length.
+        let d = [0x00, 0x05, b'a', b'b'];
+
+        assert_eq!(
+            Frame::from_bytes(ORIGIN_FRAME_TYPE_ID, 4, &d),
+            Err(crate::h3::Error::BufferTooShort)
+        );
+    }
+
+    #[test]
+    fn priority_update_request() {
+        let mut d = [42; 128];
+
+        let frame = Frame::PriorityUpdateRequest {
+            prioritized_element_id: 4,
+            priority_field_value: b"u=3, i".to_vec(),
+        };
+
+        let frame_payload_len = 7;
+        let frame_header_len = 5;
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, frame_header_len + frame_payload_len);
+
+        assert_eq!(
+            Frame::from_bytes(
+                PRIORITY_UPDATE_FRAME_REQUEST_TYPE_ID,
+                frame_payload_len as u64,
+                &d[frame_header_len..]
+            )
+            .unwrap(),
+            frame
+        );
+    }
+
+    #[test]
+    fn priority_update_push() {
+        let mut d = [42; 128];
+
+        let frame = Frame::PriorityUpdatePush {
+            prioritized_element_id: 6,
+            priority_field_value: b"u=5".to_vec(),
+        };
+
+        let frame_payload_len = 4;
+        let frame_header_len = 5;
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, frame_header_len + frame_payload_len);
+
+        assert_eq!(
+            Frame::from_bytes(
+                PRIORITY_UPDATE_FRAME_PUSH_TYPE_ID,
+                frame_payload_len as u64,
+                &d[frame_header_len..]
+            )
+            .unwrap(),
+            frame
+        );
+    }
+
+    #[test]
+    fn unknown() {
+        let mut d = [42; 128];
+
+        let frame = Frame::Unknown {
+            raw_type: 0x4143,
+            payload: vec![1, 2, 3, 4, 5],
+        };
+
+        let frame_payload_len = 5;
+        let frame_header_len = 5;
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, frame_header_len + frame_payload_len);
+
+        assert_eq!(
+            Frame::from_bytes(
+                0x4143,
+                frame_payload_len as u64,
+                &d[frame_header_len..wire_len]
+            )
+            .unwrap(),
+            frame
+        );
+    }
+
     #[test]
     fn unknown_type() {
         let d = [42; 12];
 
-        assert_eq!(Frame::from_bytes(255, 12345, &d[..]), Ok(Frame::Unknown));
+        assert_eq!(
+            Frame::from_bytes(255, 12345, &d[..]),
+            Ok(Frame::Unknown {
+                raw_type: 255,
+                payload: d.to_vec(),
+            })
+        );
     }
 }
@@ -160,6 +160,10 @@
 //!             # return Ok(());
 //!         },
 //!
+//!         Ok((stream_id, quiche::h3::Event::Trailers { list })) => {
+//!             // Trailers were received, handle them.
+//!         },
+//!
 //!         Ok((stream_id, quiche::h3::Event::Finished)) => {
 //!             // Peer terminated stream, handle it.
 //!         },
@@ -174,6 +178,26 @@
 //!              // Peer signalled it is going away, handle it.
 //!         },
 //!
+//!         Ok((stream_id, quiche::h3::Event::PriorityUpdate { .. })) => {
+//!              // Peer changed the priority of a request, handle it.
+//!         },
+//!
+//!         Ok((stream_id, quiche::h3::Event::ExtensionFrame { .. })) => {
+//!              // Peer sent a frame of a registered extension type, handle it.
+//!         },
+//!
+//!         Ok((stream_id, quiche::h3::Event::ExtensionStream { .. })) => {
+//!              // Peer opened a stream of a registered extension type.
+//!         },
+//!
+//!         Ok((stream_id, quiche::h3::Event::RawStream { .. })) => {
+//!              // Peer opened a raw stream, read it from the QUIC connection.
+//!         },
+//!
+//!         Ok((_, quiche::h3::Event::Settings)) => {
+//!              // Peer sent its SETTINGS, check them with peer_settings().
+//!         },
+//!
 //!         Err(quiche::h3::Error::Done) => {
 //!             // Done reading.
 //!             break;
@@ -220,6 +244,10 @@
 //!             }
 //!         },
 //!
+//!         Ok((stream_id, quiche::h3::Event::Trailers { list })) => {
+//!             // Trailers were received, handle them.
+//!         },
+//!
 //!         Ok((stream_id, quiche::h3::Event::Finished)) => {
 //!             // Peer terminated stream, handle it.
 //!         },
@@ -234,6 +262,26 @@
 //!              // Peer signalled it is going away, handle it.
 //!         },
 //!
+//!         Ok((stream_id, quiche::h3::Event::PriorityUpdate { .. })) => {
+//!              // Peer changed the priority of a request, handle it.
+//!         },
+//!
+//!         Ok((stream_id, quiche::h3::Event::ExtensionFrame { .. })) => {
+//!              // Peer sent a frame of a registered extension type, handle it.
+//!         },
+//!
+//!         Ok((stream_id, quiche::h3::Event::ExtensionStream { .. })) => {
+//!              // Peer opened a stream of a registered extension type.
+//!         },
+//!
+//!         Ok((stream_id, quiche::h3::Event::RawStream { .. })) => {
+//!              // Peer opened a raw stream, read it from the QUIC connection.
+//!         },
+//!
+//!         Ok((_, quiche::h3::Event::Settings)) => {
+//!              // Peer sent its SETTINGS, check them with peer_settings().
+//!         },
+//!
 //!         Err(quiche::h3::Error::Done) => {
 //!             // Done reading.
 //!             break;
@@ -278,6 +326,7 @@
 //! [`send_body()`]: struct.Connection.html#method.send_body
 
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
 
 use crate::octets;
@@ -294,6 +343,32 @@ pub const APPLICATION_PROTOCOL: &[u8] = b"\x02h3\x05h3-29\x05h3-28\x05h3-27";
 // The offset used when converting HTTP/3 urgency to quiche urgency.
 const PRIORITY_URGENCY_OFFSET: u8 = 124;
 
+// The default and largest valid values of the urgency priority parameter.
+const PRIORITY_URGENCY_DEFAULT: u8 = 3;
+const PRIORITY_URGENCY_MAX: u8 = 7;
+
+// The H3_NO_ERROR application error code.
+const H3_NO_ERROR: u64 = 0x100;
+
+// The default amount of time a graceful shutdown waits for outstanding
+// requests to complete, in milliseconds.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10_000;
+
+// The largest Quarter Stream ID an HTTP/3 DATAGRAM can refer to.
+const MAX_QUARTER_STREAM_ID: u64 = (1 << 60) - 1;
+
+// The largest value of a variable-length integer.
+const MAX_GOAWAY_ID: u64 = (1 << 62) - 1;
+
+// The maximum number of origins received in ORIGIN frames that are retained.
+const MAX_PEER_ORIGINS: usize = 1024;
+
+// The largest encoded size of a header block's prefix, that is its Required
+// Insert Count and Base. The field lines that follow take fewer bytes than they
+// count towards the field section size, unless their strings are Huffman
+// encoded into something longer, which encoders have no reason to do.
+const MAX_HEADER_BLOCK_PREFIX_LEN: u64 = 20;
+
 /// A specialized [`Result`] type for quiche HTTP/3 operations.
 ///
 /// This type is used throughout quiche's HTTP/3 public API for any operation
@@ -342,6 +417,12 @@ pub enum Error {
     /// QPACK Header block decompression failure.
     QpackDecompressionFailed,
 
+    /// Error on the QPACK encoder stream.
+    QpackEncoderStreamError,
+
+    /// Error on the QPACK decoder stream.
+    QpackDecoderStreamError,
+
     /// Error originated from the transport layer.
     TransportError(crate::Error),
 
@@ -372,6 +453,9 @@ pub enum Error {
     /// The requested operation cannot be served over HTTP/3. Peer should retry
     /// over HTTP/1.1.
     VersionFallback,
+
+    /// An HTTP Datagram or a DATAGRAM capsule was malformed.
+    DatagramError,
 }
 
 impl Error {
@@ -387,6 +471,8 @@ impl Error {
             Error::IdError => 0x108,
             Error::MissingSettings => 0x10A,
             Error::QpackDecompressionFailed => 0x200,
+            Error::QpackEncoderStreamError => 0x201,
+            Error::QpackDecoderStreamError => 0x202,
             Error::BufferTooShort => 0x999,
             Error::TransportError { .. } => 0xFF,
             Error::StreamBlocked => 0xFF,
@@ -397,6 +483,7 @@ impl Error {
             Error::MessageError => 0x10E,
             Error::ConnectError => 0x10F,
             Error::VersionFallback => 0x110,
+            Error::DatagramError => 0x33,
         }
     }
 
@@ -423,6 +510,9 @@ impl Error {
             Error::MessageError => -18,
             Error::ConnectError => -19,
             Error::VersionFallback => -20,
+            Error::DatagramError => -21,
+            Error::QpackEncoderStreamError => -22,
+            Error::QpackDecoderStreamError => -23,
         }
     }
 }
@@ -460,6 +550,21 @@ pub struct Config {
     max_field_section_size: Option<u64>,
     qpack_max_table_capacity: Option<u64>,
     qpack_blocked_streams: Option<u64>,
+    connect_protocol_enabled: Option<u64>,
+
+    qpack_huffman: bool,
+
+    strict_headers: bool,
+
+    grease_frames: bool,
+    grease_streams: bool,
+    grease_settings: bool,
+
+    extension_frames: HashSet<u64>,
+    extension_streams: HashSet<u64>,
+    raw_streams: HashSet<u64>,
+
+    shutdown_timeout: std::time::Duration,
 }
 
 impl Config {
@@ -469,35 +574,234 @@ impl Config {
             max_field_section_size: None,
             qpack_max_table_capacity: None,
             qpack_blocked_streams: None,
+            connect_protocol_enabled: None,
+
+            qpack_huffman: true,
+
+            strict_headers: false,
+
+            grease_frames: true,
+            grease_streams: true,
+            grease_settings: true,
+
+            extension_frames: HashSet::new(),
+            extension_streams: HashSet::new(),
+            raw_streams: HashSet::new(),
+
+            shutdown_timeout: std::time::Duration::from_millis(
+                DEFAULT_SHUTDOWN_TIMEOUT,
+            ),
         })
     }
 
     /// Sets the `SETTINGS_MAX_FIELD_SECTION_SIZE` setting.
     ///
-    /// By default no limit is enforced. When a request whose headers exceed
-    /// the limit set by the application is received, the call to the [`poll()`]
-    /// method will return the [`Error::ExcessiveLoad`] error, and the
-    /// connection will be closed.
+    /// The limit is advertised to the peer, and enforced on the header and
+    /// trailer sections it sends. The size of a section is the sum of the
+    /// lengths of its field names and values, plus 32 bytes for each field.
+    ///
+    /// By default no limit is enforced. When a section exceeding the limit is
+    /// received, the stream is reset with the [`Error::ExcessiveLoad`] error
+    /// code, and a [`Reset`] event is returned by the [`poll()`] method.
+    /// Sections whose encoded size alone exceeds the limit are rejected as
+    /// soon as the length of their HEADERS frame is received.
     ///
     /// [`poll()`]: struct.Connection.html#method.poll
     /// [`Error::ExcessiveLoad`]: enum.Error.html#variant.ExcessiveLoad
+    /// [`Reset`]: enum.Event.html#variant.Reset
     pub fn set_max_field_section_size(&mut self, v: u64) {
         self.max_field_section_size = Some(v);
     }
 
     /// Sets the `SETTINGS_QPACK_MAX_TABLE_CAPACITY` setting.
     ///
-    /// The default value is `0`.
+    /// This is the maximum size of the dynamic table the peer's QPACK encoder
+    /// is allowed to use. The default value is `0`, which prevents the peer
+    /// from using the dynamic table.
     pub fn set_qpack_max_table_capacity(&mut self, v: u64) {
         self.qpack_max_table_capacity = Some(v);
     }
 
     /// Sets the `SETTINGS_QPACK_BLOCKED_STREAMS` setting.
     ///
-    /// The default value is `0`.
+    /// This is the maximum number of streams whose headers can be blocked
+    /// waiting for dynamic table updates. The default value is `0`.
     pub fn set_qpack_blocked_streams(&mut self, v: u64) {
         self.qpack_blocked_streams = Some(v);
     }
+
+    /// Configures whether header names and values sent to the peer are
+    /// Huffman-encoded by QPACK.
+    ///
+    /// The default value is `true`.
+    pub fn enable_qpack_huffman(&mut self, v: bool) {
+        self.qpack_huffman = v;
+    }
+
+    /// Configures whether header and trailer sections received from the peer
+    /// are strictly validated.
+    ///
+    /// When enabled, sections carrying invalid field names or values,
+    /// connection-specific fields, or missing, unknown, duplicated or
+    /// misplaced pseudo-headers are treated as malformed, as required by
+    /// [RFC 9114]. The stream is then reset with the [`Error::MessageError`]
+    /// error code, and a [`Reset`] event is returned by the [`poll()`]
+    /// method instead of the section.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [RFC 9114]: https://www.rfc-editor.org/rfc/rfc9114.html#section-4.1.2
+    /// [`Error::MessageError`]: enum.Error.html#variant.MessageError
+    /// [`Reset`]: enum.Event.html#variant.Reset
+    /// [`poll()`]: struct.Connection.html#method.poll
+    pub fn enable_strict_header_validation(&mut self, v: bool) {
+        self.strict_headers = v;
+    }
+
+    /// Sets the `SETTINGS_ENABLE_CONNECT_PROTOCOL` setting.
+    ///
+    /// When enabled, the peer is allowed to send extended CONNECT requests
+    /// ([RFC 9220]), which carry a `:protocol` pseudo-header. Such requests
+    /// are otherwise rejected as malformed.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [RFC 9220]: https://www.rfc-editor.org/rfc/rfc9220.html
+    pub fn enable_extended_connect(&mut self, enabled: bool) {
+        self.connect_protocol_enabled = if enabled { Some(1) } else { None };
+    }
+
+    /// Configures whether to send frames of reserved types on request
+    /// streams.
+    ///
+    /// GREASE values are only sent if they are also enabled on the underlying
+    /// QUIC connection with [`grease()`].
+    ///
+    /// The default value is `true`.
+    ///
+    /// [`grease()`]: ../struct.Config.html#method.grease
+    pub fn grease_frames(&mut self, v: bool) {
+        self.grease_frames = v;
+    }
+
+    /// Configures whether to open a unidirectional stream of a reserved type.
+    ///
+    /// GREASE values are only sent if they are also enabled on the underlying
+    /// QUIC connection with [`grease()`].
+    ///
+    /// The default value is `true`.
+    ///
+    /// [`grease()`]: ../struct.Config.html#method.grease
+    pub fn grease_streams(&mut self, v: bool) {
+        self.grease_streams = v;
+    }
+
+    /// Configures whether to include a reserved setting in the SETTINGS frame.
+    ///
+    /// GREASE values are only sent if they are also enabled on the underlying
+    /// QUIC connection with [`grease()`].
+    ///
+    /// The default value is `true`.
+    ///
+    /// [`grease()`]: ../struct.Config.html#method.grease
+    pub fn grease_settings(&mut self, v: bool) {
+        self.grease_settings = v;
+    }
+
+    /// Registers interest in frames of the given type.
+    ///
+    /// Frames of unknown types are discarded by default. Once registered,
+    /// frames of this type received on the control, request or push streams
+    /// are reported to the application with an [`ExtensionFrame`] event
+    /// instead. Frame types defined by HTTP/3 itself are not affected.
+    ///
+    /// [`ExtensionFrame`]: enum.Event.html#variant.ExtensionFrame
+    pub fn register_extension_frame(&mut self, ty: u64) {
+        self.extension_frames.insert(ty);
+    }
+
+    /// Registers interest in unidirectional streams of the given type.
+    ///
+    /// Unidirectional streams of unknown types are discarded by default. Once
+    /// registered, streams of this type opened by the peer are reported to
+    /// the application with an [`ExtensionStream`] event, and their data can
+    /// then be read with [`recv_body()`]. Stream types defined by HTTP/3
+    /// itself are not affected.
+    ///
+    /// [`ExtensionStream`]: enum.Event.html#variant.ExtensionStream
+    /// [`recv_body()`]: struct.Connection.html#method.recv_body
+    pub fn register_extension_stream(&mut self, ty: u64) {
+        self.extension_streams.insert(ty);
+    }
+
+    /// Registers the given raw stream type.
+    ///
+    /// Streams opened by the peer that start with this type, encoded as a
+    /// variable-length integer, are reported to the application with a
+    /// [`RawStream`] event, and are not processed by HTTP/3 any further. Both
+    /// unidirectional streams and bidirectional streams that haven't carried
+    /// a request yet are recognized.
+    ///
+    /// [`RawStream`]: enum.Event.html#variant.RawStream
+    pub fn register_raw_stream(&mut self, ty: u64) {
+        self.raw_streams.insert(ty);
+    }
+
+    /// Sets the maximum amount of time a graceful [`shutdown()`] waits for
+    /// outstanding requests to complete before closing the connection, in
+    /// milliseconds.
+    ///
+    /// The default value is `10000`.
+    ///
+    /// [`shutdown()`]: struct.Connection.html#method.shutdown
+    pub fn set_shutdown_timeout(&mut self, v: u64) {
+        self.shutdown_timeout = std::time::Duration::from_millis(v);
+    }
+
+    /// Configures the QUIC `config` to also send these settings with ALPS
+    /// (Application-Layer Protocol Settings) during the handshake, when the
+    /// `h3` protocol is negotiated.
+    ///
+    /// This lets the peer use the settings as soon as the handshake
+    /// completes, rather than waiting for them on the control stream. The
+    /// settings received through ALPS are available from [`peer_settings()`]
+    /// as soon as the connection is created with [`with_transport()`], if the
+    /// handshake completed.
+    ///
+    /// DATAGRAM support must be configured on `config` before this is called.
+    ///
+    /// This is not available with the rustls backend.
+    ///
+    /// [`peer_settings()`]: struct.Connection.html#method.peer_settings
+    /// [`with_transport()`]: struct.Connection.html#method.with_transport
+    #[cfg(not(feature = "rustls"))]
+    pub fn add_application_settings(
+        &self, config: &mut crate::Config,
+    ) -> Result<()> {
+        let h3_datagram = config
+            .local_transport_params
+            .max_datagram_frame_size
+            .map(|_| 1);
+
+        let frame = frame::Frame::Settings {
+            max_field_section_size: self.max_field_section_size,
+            qpack_max_table_capacity: self.qpack_max_table_capacity,
+            qpack_blocked_streams: self.qpack_blocked_streams,
+            h3_datagram,
+            connect_protocol_enabled: self.connect_protocol_enabled,
+            grease: None,
+            raw: Default::default(),
+        };
+
+        let mut d = [0; 128];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        let len = frame.to_bytes(&mut b)?;
+
+        config.add_application_settings(b"h3", &d[..len])?;
+
+        Ok(())
+    }
 }
 
 /// A trait for types with associated string name and value.
@@ -520,6 +824,38 @@ impl Header {
     pub fn new(name: &[u8], value: &[u8]) -> Self {
         Self(name.to_vec(), value.to_vec())
     }
+
+    /// Creates a new header with a canonical name and a validated value.
+    ///
+    /// The name is converted to lowercase, and leading and trailing
+    /// whitespace is removed from the value. Both are then validated with
+    /// [`validate_header_name()`] and [`validate_header_value()`]
+    /// respectively, so that the resulting header can be safely encoded into
+    /// a field section.
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// let h = quiche::h3::Header::canonical(b"Content-Type", b" text/html ")?;
+    /// assert_eq!(h, quiche::h3::Header::new(b"content-type", b"text/html"));
+    /// # Ok::<(), quiche::h3::HeaderError>(())
+    /// ```
+    ///
+    /// [`validate_header_name()`]: fn.validate_header_name.html
+    /// [`validate_header_value()`]: fn.validate_header_value.html
+    pub fn canonical(
+        name: &[u8], value: &[u8],
+    ) -> std::result::Result<Self, HeaderError> {
+        let name = name.to_ascii_lowercase();
+
+        validate_header_name(&name)?;
+
+        let value = trim_whitespace(value);
+
+        validate_header_value(value)?;
+
+        Ok(Self(name, value.to_vec()))
+    }
 }
 
 impl NameValue for Header {
@@ -553,2041 +889,6184 @@ impl<'a> NameValue for HeaderRef<'a> {
     }
 }
 
-/// An HTTP/3 connection event.
-#[derive(Clone, Debug, PartialEq)]
-pub enum Event {
-    /// Request/response headers were received.
-    Headers {
-        /// The list of received header fields. The application should validate
-        /// pseudo-headers and headers.
-        list: Vec<Header>,
+/// An error returned when validating a header field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderError {
+    /// The header name is empty.
+    EmptyName,
 
-        /// Whether data will follow the headers on the stream.
-        has_body: bool,
-    },
+    /// The header name contains an uppercase character.
+    UppercaseName,
 
-    /// Data was received.
-    ///
-    /// This indicates that the application can use the [`recv_body()`] method
-    /// to retrieve the data from the stream.
-    ///
-    /// Note that [`recv_body()`] will need to be called repeatedly until the
-    /// [`Done`] value is returned, as the event will not be re-armed until all
-    /// buffered data is read.
-    ///
-    /// [`recv_body()`]: struct.Connection.html#method.recv_body
-    /// [`Done`]: enum.Error.html#variant.Done
-    Data,
+    /// The header name contains a character that is not a valid token
+    /// character.
+    InvalidNameChar(u8),
 
-    /// Stream was closed,
-    Finished,
+    /// The header value contains a line folding (obs-fold) sequence.
+    ObsFold,
 
-    /// Stream was reset.
-    ///
-    /// The associated data represents the error code sent by the peer.
-    Reset(u64),
+    /// The header value contains a forbidden character, such as NUL, CR, LF
+    /// or other control characters.
+    InvalidValueChar(u8),
 
-    /// DATAGRAM was received.
-    ///
-    /// This indicates that the application can use the [`recv_dgram()`] method
-    /// to retrieve the HTTP/3 DATAGRAM.
-    ///
-    /// Note that [`recv_dgram()`] will need to be called repeatedly until the
-    /// [`Done`] value is returned, as the event will not be re-armed until all
-    /// buffered DATAGRAMs with the same flow ID are read.
-    ///
-    /// [`recv_dgram()`]: struct.Connection.html#method.recv_dgram
-    /// [`Done`]: enum.Error.html#variant.Done
-    Datagram,
+    /// The header value starts or ends with whitespace.
+    SurroundingWhitespace,
+}
 
-    /// GOAWAY was received.
-    GoAway,
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
 }
 
-struct ConnectionSettings {
-    pub max_field_section_size: Option<u64>,
-    pub qpack_max_table_capacity: Option<u64>,
-    pub qpack_blocked_streams: Option<u64>,
-    pub h3_datagram: Option<u64>,
-    pub raw: Option<Vec<(u64, u64)>>,
+impl std::error::Error for HeaderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
 }
 
-struct QpackStreams {
-    pub encoder_stream_id: Option<u64>,
-    pub decoder_stream_id: Option<u64>,
+impl std::convert::From<HeaderError> for Error {
+    fn from(_err: HeaderError) -> Self {
+        Error::MessageError
+    }
 }
 
-/// An HTTP/3 connection.
-pub struct Connection {
-    is_server: bool,
+/// Validates an HTTP/3 header name.
+///
+/// The name must be non-empty, lowercase and only contain token characters
+/// as defined by [RFC 7230]. Pseudo-header names (e.g. `:method`) are allowed.
+///
+/// Note that this does not check whether a pseudo-header is known or allowed
+/// in a specific message.
+///
+/// [RFC 7230]: https://tools.ietf.org/html/rfc7230#section-3.2.6
+pub fn validate_header_name(name: &[u8]) -> std::result::Result<(), HeaderError> {
+    let token = match name {
+        [] | [b':'] => return Err(HeaderError::EmptyName),
 
-    next_request_stream_id: u64,
-    next_uni_stream_id: u64,
+        [b':', rest @ ..] => rest,
 
-    streams: HashMap<u64, stream::Stream>,
+        _ => name,
+    };
 
-    local_settings: ConnectionSettings,
-    peer_settings: ConnectionSettings,
+    for b in token {
+        if b.is_ascii_uppercase() {
+            return Err(HeaderError::UppercaseName);
+        }
 
-    control_stream_id: Option<u64>,
-    peer_control_stream_id: Option<u64>,
+        if !is_token_char(*b) {
+            return Err(HeaderError::InvalidNameChar(*b));
+        }
+    }
 
-    qpack_encoder: qpack::Encoder,
-    qpack_decoder: qpack::Decoder,
+    Ok(())
+}
 
-    local_qpack_streams: QpackStreams,
-    peer_qpack_streams: QpackStreams,
+/// Validates an HTTP/3 header value.
+///
+/// The value must not contain NUL, CR, LF or other control characters
+/// (except for horizontal tabs), and must not start or end with whitespace.
+/// Line folding (obs-fold) is reported separately as [`HeaderError::ObsFold`].
+///
+/// [`HeaderError::ObsFold`]: enum.HeaderError.html#variant.ObsFold
+pub fn validate_header_value(
+    value: &[u8],
+) -> std::result::Result<(), HeaderError> {
+    for (i, b) in value.iter().enumerate() {
+        match *b {
+            b'\r' | b'\n' => {
+                let next = value[i + 1..].iter().find(|b| **b != b'\n');
+
+                if let Some(b' ') | Some(b'\t') = next {
+                    return Err(HeaderError::ObsFold);
+                }
 
-    max_push_id: u64,
+                return Err(HeaderError::InvalidValueChar(*b));
+            },
 
-    finished_streams: VecDeque<u64>,
+            b'\t' => (),
 
-    frames_greased: bool,
+            0x00..=0x1f | 0x7f => return Err(HeaderError::InvalidValueChar(*b)),
 
-    local_goaway_id: Option<u64>,
-    peer_goaway_id: Option<u64>,
+            _ => (),
+        }
+    }
 
-    dgram_event_triggered: bool,
+    match (value.first(), value.last()) {
+        (Some(f), Some(l)) if is_whitespace(*f) || is_whitespace(*l) =>
+            Err(HeaderError::SurroundingWhitespace),
+
+        _ => Ok(()),
+    }
 }
 
-impl Connection {
-    fn new(
-        config: &Config, is_server: bool, enable_dgram: bool,
-    ) -> Result<Connection> {
-        let initial_uni_stream_id = if is_server { 0x3 } else { 0x2 };
-        let h3_datagram = if enable_dgram { Some(1) } else { None };
+// Returns whether the headers are those of an informational (1xx) response.
+fn is_informational<T: NameValue>(headers: &[T]) -> bool {
+    headers
+        .iter()
+        .any(|h| h.name() == b":status" && h.value().first() == Some(&b'1'))
+}
 
-        Ok(Connection {
-            is_server,
+// Checks the pseudo-headers of CONNECT and extended CONNECT requests. Other
+// requests are not validated.
+fn validate_connect_request<T: NameValue>(
+    headers: &[T], extended_connect_enabled: bool,
+) -> Result<()> {
+    let mut method = None;
+    let mut protocol = None;
 
-            next_request_stream_id: 0,
+    let mut has_scheme = false;
+    let mut has_path = false;
+    let mut has_authority = false;
 
-            next_uni_stream_id: initial_uni_stream_id,
+    for h in headers {
+        match h.name() {
+            b":method" => method = Some(h.value()),
 
-            streams: HashMap::new(),
+            b":protocol" => protocol = Some(h.value()),
 
-            local_settings: ConnectionSettings {
-                max_field_section_size: config.max_field_section_size,
-                qpack_max_table_capacity: config.qpack_max_table_capacity,
-                qpack_blocked_streams: config.qpack_blocked_streams,
-                h3_datagram,
-                raw: Default::default(),
-            },
+            b":scheme" => has_scheme = true,
 
-            peer_settings: ConnectionSettings {
-                max_field_section_size: None,
-                qpack_max_table_capacity: None,
-                qpack_blocked_streams: None,
-                h3_datagram: None,
-                raw: Default::default(),
-            },
+            b":path" => has_path = true,
 
-            control_stream_id: None,
-            peer_control_stream_id: None,
+            b":authority" => has_authority = true,
 
-            qpack_encoder: qpack::Encoder::new(),
-            qpack_decoder: qpack::Decoder::new(),
+            _ => (),
+        }
+    }
 
-            local_qpack_streams: QpackStreams {
-                encoder_stream_id: None,
-                decoder_stream_id: None,
-            },
+    let is_connect = method == Some(b"CONNECT");
 
-            peer_qpack_streams: QpackStreams {
-                encoder_stream_id: None,
-                decoder_stream_id: None,
-            },
+    let valid = match protocol {
+        // Extended CONNECT requires all the usual request pseudo-headers.
+        Some(_) =>
+            is_connect &&
+                extended_connect_enabled &&
+                has_scheme &&
+                has_path &&
+                has_authority,
 
-            max_push_id: 0,
+        None if is_connect => has_authority && !has_scheme && !has_path,
 
-            finished_streams: VecDeque::new(),
+        None => true,
+    };
 
-            frames_greased: false,
+    if !valid {
+        return Err(Error::MessageError);
+    }
 
-            local_goaway_id: None,
-            peer_goaway_id: None,
+    Ok(())
+}
 
-            dgram_event_triggered: false,
-        })
-    }
+// Checks that a header or trailer section is well-formed, as defined by
+// RFC 9114 Sections 4.1.2, 4.2 and 4.3.
+fn validate_field_section<T: NameValue>(
+    headers: &[T], is_request: bool, is_trailers: bool,
+) -> Result<()> {
+    let allowed_pseudo: &[&[u8]] = if is_request {
+        &[
+            b":method",
+            b":scheme",
+            b":authority",
+            b":path",
+            b":protocol",
+        ]
+    } else {
+        &[b":status"]
+    };
+
+    let mut pseudo: Vec<&[u8]> = Vec::new();
+    let mut regular_seen = false;
+
+    for h in headers {
+        let name = h.name();
+
+        validate_header_name(name)?;
+        validate_header_value(h.value())?;
+
+        if name.starts_with(b":") {
+            // Pseudo-headers must be known, appear at most once and precede
+            // all regular fields. Trailers can't carry any.
+            if is_trailers ||
+                regular_seen ||
+                !allowed_pseudo.contains(&name) ||
+                pseudo.contains(&name)
+            {
+                return Err(Error::MessageError);
+            }
 
-    /// Creates a new HTTP/3 connection using the provided QUIC connection.
-    ///
-    /// This will also initiate the HTTP/3 handshake with the peer by opening
-    /// all control streams (including QPACK) and sending the local settings.
-    ///
-    /// On success the new connection is returned.
-    ///
-    /// The [`StreamLimit`] error is returned when the HTTP/3 control stream
-    /// cannot be created.
-    ///
-    /// [`StreamLimit`]: ../enum.Error.html#variant.InvalidState
-    pub fn with_transport(
-        conn: &mut super::Connection, config: &Config,
-    ) -> Result<Connection> {
-        let mut http3_conn =
-            Connection::new(config, conn.is_server, conn.dgram_enabled())?;
+            pseudo.push(name);
 
-        match http3_conn.send_settings(conn) {
-            Ok(_) => (),
+            continue;
+        }
 
-            Err(e) => {
-                conn.close(true, e.to_wire(), b"Error opening control stream")?;
-                return Err(e);
-            },
-        };
+        regular_seen = true;
 
-        // Try opening QPACK streams, but ignore errors if it fails since we
-        // don't need them right now.
-        http3_conn.open_qpack_encoder_stream(conn).ok();
-        http3_conn.open_qpack_decoder_stream(conn).ok();
+        match name {
+            b"connection" | b"keep-alive" | b"proxy-connection" |
+            b"transfer-encoding" | b"upgrade" => return Err(Error::MessageError),
 
-        if conn.grease {
-            // Try opening a GREASE stream, but ignore errors since it's not
-            // critical.
-            http3_conn.open_grease_stream(conn).ok();
+            b"te" if h.value() != b"trailers" => return Err(Error::MessageError),
+
+            _ => (),
         }
+    }
 
-        Ok(http3_conn)
+    if is_trailers {
+        return Ok(());
     }
 
-    /// Sends an HTTP/3 request.
-    ///
-    /// The request is encoded from the provided list of headers without a
-    /// body, and sent on a newly allocated stream. To include a body,
-    /// set `fin` as `false` and subsequently call [`send_body()`] with the
-    /// same `conn` and the `stream_id` returned from this method.
-    ///
-    /// On success the newly allocated stream ID is returned.
-    ///
-    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
-    /// doesn't have enough capacity for the operation to complete. When this
-    /// happens the application should retry the operation once the stream is
-    /// reported as writable again.
-    ///
-    /// [`send_body()`]: struct.Connection.html#method.send_body
-    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
-    pub fn send_request<T: NameValue>(
-        &mut self, conn: &mut super::Connection, headers: &[T], fin: bool,
-    ) -> Result<u64> {
-        // If we received a GOAWAY from the peer, MUST NOT initiate new
-        // requests.
-        if self.peer_goaway_id.is_some() {
-            return Err(Error::FrameUnexpected);
+    let value = |name: &[u8]| {
+        headers.iter().find(|h| h.name() == name).map(|h| h.value())
+    };
+
+    let valid = if is_request {
+        match value(b":method") {
+            // CONNECT requests are checked by validate_connect_request().
+            Some(b"CONNECT") => true,
+
+            Some(_) =>
+                value(b":scheme").is_some() &&
+                    matches!(value(b":path"), Some(v) if !v.is_empty()),
+
+            None => false,
         }
+    } else {
+        match value(b":status") {
+            Some(v) => v.len() == 3 && v.iter().all(|b| b.is_ascii_digit()),
 
-        let stream_id = self.next_request_stream_id;
+            None => false,
+        }
+    };
 
-        self.streams
-            .insert(stream_id, stream::Stream::new(stream_id, true));
+    if !valid {
+        return Err(Error::MessageError);
+    }
 
-        // The underlying QUIC stream does not exist yet, so calls to e.g.
-        // stream_capacity() will fail. By writing a 0-length buffer, we force
-        // the creation of the QUIC stream state, without actually writing
-        // anything.
-        if let Err(e) = conn.stream_send(stream_id, b"", false) {
-            self.streams.remove(&stream_id);
+    Ok(())
+}
 
-            if e == super::Error::Done {
-                return Err(Error::StreamBlocked);
-            }
+// See https://tools.ietf.org/html/rfc7230#section-3.2.6
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
 
-            return Err(e.into());
-        };
+fn is_whitespace(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
 
-        self.send_headers(conn, stream_id, headers, fin)?;
+fn trim_whitespace(mut value: &[u8]) -> &[u8] {
+    while let Some((&b, rest)) = value.split_first() {
+        if !is_whitespace(b) {
+            break;
+        }
 
-        // To avoid skipping stream IDs, we only calculate the next available
-        // stream ID when a request has been successfully buffered.
-        self.next_request_stream_id = self
-            .next_request_stream_id
-            .checked_add(4)
-            .ok_or(Error::IdError)?;
+        value = rest;
+    }
 
-        Ok(stream_id)
+    while let Some((&b, rest)) = value.split_last() {
+        if !is_whitespace(b) {
+            break;
+        }
+
+        value = rest;
     }
 
-    /// Sends an HTTP/3 response on the specified stream with default priority.
-    ///
-    /// This method sends the provided `headers` without a body. To include a
-    /// body, set `fin` as `false` and subsequently call [`send_body()`] with
-    /// the same `conn` and `stream_id`.
-    ///
-    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
-    /// doesn't have enough capacity for the operation to complete. When this
-    /// happens the application should retry the operation once the stream is
-    /// reported as writable again.
-    ///
-    /// [`send_body()`]: struct.Connection.html#method.send_body
-    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
-    pub fn send_response<T: NameValue>(
-        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
-        fin: bool,
-    ) -> Result<()> {
-        let priority = "u=3";
+    value
+}
 
-        self.send_response_with_priority(
-            conn, stream_id, headers, priority, fin,
-        )?;
+/// Extensible priority parameters of an HTTP request, as defined in RFC 9218.
+///
+/// Priorities are signaled by clients using the `priority` header field and
+/// PRIORITY_UPDATE frames. The urgency ranges from `0` (most urgent) to `7`,
+/// and incremental responses are sent interleaved with other responses of
+/// the same urgency.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Priority {
+    urgency: u8,
+    incremental: bool,
+}
 
-        Ok(())
+impl Default for Priority {
+    fn default() -> Self {
+        Priority {
+            urgency: PRIORITY_URGENCY_DEFAULT,
+            incremental: false,
+        }
     }
+}
 
-    /// Sends an HTTP/3 response on the specified stream with specified
-    /// priority.
-    ///
-    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
-    /// doesn't have enough capacity for the operation to complete. When this
-    /// happens the application should retry the operation once the stream is
-    /// reported as writable again.
+impl Priority {
+    /// Creates new priority parameters.
     ///
-    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
-    pub fn send_response_with_priority<T: NameValue>(
-        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
-        priority: &str, fin: bool,
-    ) -> Result<()> {
-        if !self.streams.contains_key(&stream_id) {
-            return Err(Error::FrameUnexpected);
+    /// Urgency values larger than `7` are capped.
+    pub fn new(urgency: u8, incremental: bool) -> Priority {
+        Priority {
+            urgency: std::cmp::min(urgency, PRIORITY_URGENCY_MAX),
+            incremental,
         }
+    }
 
-        let mut urgency = 3u8.saturating_add(PRIORITY_URGENCY_OFFSET);
-        let mut incremental = false;
+    /// Returns the urgency parameter.
+    pub fn urgency(&self) -> u8 {
+        self.urgency
+    }
 
-        for param in priority.split(',') {
-            if param.trim() == "i" {
-                incremental = true;
-                continue;
-            }
+    /// Returns the incremental parameter.
+    pub fn incremental(&self) -> bool {
+        self.incremental
+    }
 
-            if param.trim().starts_with("u=") {
-                // u is an sh-integer (an i64) but it has a constrained range of
-                // 0-7. So detect anything outside that range and clamp it to
-                // the lowest urgency in order to avoid it interfering with
-                // valid items.
-                //
-                // TODO: this also detects when u is not an sh-integer and
-                // clamps it in the same way. A real structured header parser
-                // would actually fail to parse.
-                let mut u = param
-                    .rsplit('=')
-                    .next()
-                    .unwrap()
-                    .parse::<i64>()
-                    .unwrap_or(7);
-
-                if !(0..=7).contains(&u) {
-                    u = 7;
-                }
+    /// Parses priority parameters from a Structured Fields dictionary, such
+    /// as the value of the `priority` header field.
+    ///
+    /// As required by RFC 9218, unknown members, as well as parameters with
+    /// invalid or out of range values, are ignored and the default value of
+    /// the respective parameter applies.
+    pub fn parse(value: &[u8]) -> Priority {
+        let mut priority = Priority::default();
 
-                // The HTTP/3 urgency needs to be shifted into the quiche
-                // urgency range.
-                urgency = (u as u8).saturating_add(PRIORITY_URGENCY_OFFSET);
-            }
-        }
+        for member in split_sf_dictionary(value) {
+            // Parameters attached to the member are not used.
+            let member = member.split(|&b| b == b';').next().unwrap_or(member);
 
-        conn.stream_priority(stream_id, urgency, incremental)?;
+            let (key, value) = match member.iter().position(|&b| b == b'=') {
+                Some(i) => (&member[..i], Some(&member[i + 1..])),
 
-        self.send_headers(conn, stream_id, headers, fin)?;
+                None => (member, None),
+            };
 
-        Ok(())
-    }
+            match key {
+                b"u" => {
+                    let u = match value.and_then(parse_sf_integer) {
+                        Some(v) => v,
 
-    fn encode_header_block<T: NameValue>(
-        &mut self, headers: &[T],
-    ) -> Result<Vec<u8>> {
-        let headers_len = headers
-            .iter()
-            .fold(0, |acc, h| acc + h.value().len() + h.name().len() + 32);
+                        None => continue,
+                    };
 
-        let mut header_block = vec![0; headers_len];
-        let len = self
-            .qpack_encoder
-            .encode(headers, &mut header_block)
-            .map_err(|_| Error::InternalError)?;
+                    if (0..=i64::from(PRIORITY_URGENCY_MAX)).contains(&u) {
+                        priority.urgency = u as u8;
+                    }
+                },
 
-        header_block.truncate(len);
+                b"i" => match value {
+                    None | Some(b"?1") => priority.incremental = true,
 
-        Ok(header_block)
-    }
+                    Some(b"?0") => priority.incremental = false,
 
-    fn send_headers<T: NameValue>(
-        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
-        fin: bool,
-    ) -> Result<()> {
-        let mut d = [42; 10];
-        let mut b = octets::OctetsMut::with_slice(&mut d);
+                    Some(_) => (),
+                },
 
-        if !self.frames_greased && conn.grease {
-            self.send_grease_frames(conn, stream_id)?;
-            self.frames_greased = true;
+                _ => (),
+            }
         }
 
-        let header_block = self.encode_header_block(headers)?;
-
-        let overhead = octets::varint_len(frame::HEADERS_FRAME_TYPE_ID) +
-            octets::varint_len(header_block.len() as u64);
+        priority
+    }
 
-        // Headers need to be sent atomically, so make sure the stream has
-        // enough capacity.
-        match conn.stream_writable(stream_id, overhead + header_block.len()) {
-            Ok(true) => (),
+    /// Serializes the priority parameters as a Structured Fields dictionary,
+    /// suitable for use as the value of the `priority` header field.
+    ///
+    /// Parameters that have their default value are omitted, so the default
+    /// priority serializes to an empty value.
+    pub fn to_field_value(&self) -> Vec<u8> {
+        let mut members = Vec::new();
 
-            Ok(false) => return Err(Error::StreamBlocked),
+        if self.urgency != PRIORITY_URGENCY_DEFAULT {
+            members.push(format!("u={}", self.urgency));
+        }
 
-            Err(e) => {
-                if conn.stream_finished(stream_id) {
-                    self.streams.remove(&stream_id);
-                }
+        if self.incremental {
+            members.push("i".to_string());
+        }
 
-                return Err(e.into());
-            },
-        };
+        members.join(", ").into_bytes()
+    }
 
-        trace!(
-            "{} tx frm HEADERS stream={} len={} fin={}",
-            conn.trace_id(),
-            stream_id,
-            header_block.len(),
-            fin
-        );
+    /// Returns the stream urgency used by the transport for this priority.
+    fn transport_urgency(&self) -> u8 {
+        self.urgency.saturating_add(PRIORITY_URGENCY_OFFSET)
+    }
+}
 
-        b.put_varint(frame::HEADERS_FRAME_TYPE_ID)?;
-        b.put_varint(header_block.len() as u64)?;
-        let off = b.off();
-        conn.stream_send(stream_id, &d[..off], false)?;
+/// Splits a Structured Fields dictionary into its members, with surrounding
+/// whitespace removed.
+fn split_sf_dictionary(value: &[u8]) -> Vec<&[u8]> {
+    let mut members = Vec::new();
 
-        // Sending header block separately avoids unnecessary copy.
-        conn.stream_send(stream_id, &header_block, fin)?;
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
 
-        if let Some(s) = self.streams.get_mut(&stream_id) {
-            s.initialize_local();
-        }
+    for (i, &b) in value.iter().enumerate() {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
 
-        if fin && conn.stream_finished(stream_id) {
-            self.streams.remove(&stream_id);
-        }
+                b'\\' => escaped = true,
 
-        Ok(())
-    }
+                b'"' => in_string = false,
 
-    /// Sends an HTTP/3 body chunk on the given stream.
-    ///
-    /// On success the number of bytes written is returned, or [`Done`] if no
-    /// bytes could be written (e.g. because the stream is blocked).
-    ///
-    /// Note that the number of written bytes returned can be lower than the
-    /// length of the input buffer when the underlying QUIC stream doesn't have
-    /// enough capacity for the operation to complete.
-    ///
-    /// When a partial write happens (including when [`Done`] is returned) the
-    /// application should retry the operation once the stream is reported as
-    /// writable again.
-    ///
-    /// [`Done`]: enum.Error.html#variant.Done
-    pub fn send_body(
-        &mut self, conn: &mut super::Connection, stream_id: u64, body: &[u8],
-        fin: bool,
-    ) -> Result<usize> {
-        let mut d = [42; 10];
-        let mut b = octets::OctetsMut::with_slice(&mut d);
+                _ => (),
+            }
 
-        // Validate that it is sane to send data on the stream.
-        if stream_id % 4 != 0 {
-            return Err(Error::FrameUnexpected);
+            continue;
         }
 
-        match self.streams.get(&stream_id) {
-            Some(s) =>
-                if !s.local_initialized() {
-                    return Err(Error::FrameUnexpected);
-                },
+        match b {
+            b'"' => in_string = true,
 
-            None => {
-                return Err(Error::FrameUnexpected);
+            b',' => {
+                members.push(trim_whitespace(&value[start..i]));
+                start = i + 1;
             },
-        };
 
-        // Avoid sending 0-length DATA frames when the fin flag is false.
-        if body.is_empty() && !fin {
-            return Err(Error::Done);
+            _ => (),
         }
+    }
 
-        let overhead = octets::varint_len(frame::DATA_FRAME_TYPE_ID) +
-            octets::varint_len(body.len() as u64);
+    members.push(trim_whitespace(&value[start..]));
 
-        let stream_cap = match conn.stream_capacity(stream_id) {
-            Ok(v) => v,
-
-            Err(e) => {
-                if conn.stream_finished(stream_id) {
-                    self.streams.remove(&stream_id);
-                }
-
-                return Err(e.into());
-            },
-        };
+    members.retain(|m| !m.is_empty());
 
-        if stream_cap < overhead + body.len() {
-            // Ensure the peer is notified that the connection or stream is
-            // blocked when the stream's capacity is limited by flow control.
-            let _ = conn.stream_writable(stream_id, overhead + body.len());
-        }
+    members
+}
 
-        // Make sure there is enough capacity to send the DATA frame header.
-        if stream_cap < overhead {
-            return Err(Error::Done);
-        }
+/// Parses a Structured Fields integer.
+fn parse_sf_integer(value: &[u8]) -> Option<i64> {
+    let digits = value.strip_prefix(b"-").unwrap_or(value);
 
-        // Cap the frame payload length to the stream's capacity.
-        let body_len = std::cmp::min(body.len(), stream_cap - overhead);
+    if digits.is_empty() ||
+        digits.len() > 15 ||
+        !digits.iter().all(u8::is_ascii_digit)
+    {
+        return None;
+    }
 
-        // If we can't send the entire body, set the fin flag to false so the
-        // application can try again later.
-        let fin = if body_len != body.len() { false } else { fin };
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
 
-        // Again, avoid sending 0-length DATA frames when the fin flag is false.
-        if body_len == 0 && !fin {
-            return Err(Error::Done);
-        }
+/// An HTTP/3 connection event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Request/response headers were received.
+    Headers {
+        /// The list of received header fields. The application should validate
+        /// pseudo-headers and headers.
+        list: Vec<Header>,
 
-        trace!(
-            "{} tx frm DATA stream={} len={} fin={}",
-            conn.trace_id(),
-            stream_id,
-            body_len,
-            fin
-        );
+        /// Whether data will follow the headers on the stream.
+        has_body: bool,
+    },
 
-        b.put_varint(frame::DATA_FRAME_TYPE_ID)?;
-        b.put_varint(body_len as u64)?;
-        let off = b.off();
-        conn.stream_send(stream_id, &d[..off], false)?;
+    /// Data was received.
+    ///
+    /// This indicates that the application can use the [`recv_body()`] method
+    /// to retrieve the data from the stream.
+    ///
+    /// Note that [`recv_body()`] will need to be called repeatedly until the
+    /// [`Done`] value is returned, as the event will not be re-armed until all
+    /// buffered data is read.
+    ///
+    /// [`recv_body()`]: struct.Connection.html#method.recv_body
+    /// [`Done`]: enum.Error.html#variant.Done
+    Data,
 
-        // Return how many bytes were written, excluding the frame header.
-        // Sending body separately avoids unnecessary copy.
-        let written = conn.stream_send(stream_id, &body[..body_len], fin)?;
+    /// A trailer section was received after the request/response body.
+    ///
+    /// Trailers are followed by the end of the stream.
+    Trailers {
+        /// The list of received trailer fields. The application should
+        /// validate them, e.g. by checking that no pseudo-headers are present.
+        list: Vec<Header>,
+    },
 
-        if fin && written == body.len() && conn.stream_finished(stream_id) {
-            self.streams.remove(&stream_id);
-        }
+    /// Stream was closed,
+    Finished,
 
-        Ok(written)
-    }
+    /// Stream was reset.
+    ///
+    /// The associated data represents the error code sent by the peer. Streams
+    /// can also be reset locally with the [`ExcessiveLoad`] error code, when
+    /// the peer exceeds the limit set with [`set_max_field_section_size()`],
+    /// or with the [`MessageError`] error code, when a malformed section is
+    /// received with [`enable_strict_header_validation()`] enabled.
+    ///
+    /// If the stream was reset before its headers were received, and nothing
+    /// is left to send on it locally, all the HTTP/3 state associated with
+    /// the stream is released, and no further events will be reported for it.
+    /// Applications should release any per-request state of their own when
+    /// receiving this event.
+    ///
+    /// [`ExcessiveLoad`]: enum.Error.html#variant.ExcessiveLoad
+    /// [`set_max_field_section_size()`]: struct.Config.html#method.set_max_field_section_size
+    /// [`MessageError`]: enum.Error.html#variant.MessageError
+    /// [`enable_strict_header_validation()`]: struct.Config.html#method.enable_strict_header_validation
+    Reset(u64),
 
-    /// Returns whether the peer enabled HTTP/3 DATAGRAM frame support.
+    /// DATAGRAM was received.
     ///
-    /// Support is signalled by the peer's SETTINGS, so this method always
-    /// returns false until they have been processed using the [`poll()`]
-    /// method.
+    /// This indicates that the application can use the [`recv_dgram()`] method
+    /// to retrieve the HTTP/3 DATAGRAM.
     ///
-    /// [`poll()`]: struct.Connection.html#method.poll
-    pub fn dgram_enabled_by_peer(&self, conn: &super::Connection) -> bool {
-        self.peer_settings.h3_datagram == Some(1) &&
-            conn.dgram_max_writable_len().is_some()
-    }
+    /// Note that [`recv_dgram()`] will need to be called repeatedly until the
+    /// [`Done`] value is returned, as the event will not be re-armed until all
+    /// buffered DATAGRAMs with the same flow ID are read.
+    ///
+    /// [`recv_dgram()`]: struct.Connection.html#method.recv_dgram
+    /// [`Done`]: enum.Error.html#variant.Done
+    Datagram,
 
-    /// Sends an HTTP/3 DATAGRAM with the specified flow ID.
-    pub fn send_dgram(
-        &mut self, conn: &mut super::Connection, flow_id: u64, buf: &[u8],
-    ) -> Result<()> {
-        let len = octets::varint_len(flow_id) + buf.len();
-        let mut d = vec![0; len as usize];
-        let mut b = octets::OctetsMut::with_slice(&mut d);
+    /// GOAWAY was received.
+    GoAway,
 
-        b.put_varint(flow_id)?;
-        b.put_bytes(buf)?;
+    /// PRIORITY_UPDATE was received.
+    ///
+    /// This is only reported to servers, for requests that were already
+    /// opened by the client. The new priority has already been applied to
+    /// the request stream when this event is returned. Updates received
+    /// before the corresponding request are buffered, and applied once the
+    /// request's headers are received.
+    PriorityUpdate {
+        /// The ID of the request stream the update applies to.
+        stream_id: u64,
+
+        /// The new priority of the request.
+        priority: Priority,
+    },
 
-        conn.dgram_send_vec(d)?;
+    /// A frame of a type registered with [`register_extension_frame()`] was
+    /// received.
+    ///
+    /// [`register_extension_frame()`]: struct.Config.html#method.register_extension_frame
+    ExtensionFrame {
+        /// The type of the frame.
+        frame_type: u64,
 
-        Ok(())
-    }
+        /// The payload of the frame.
+        payload: Vec<u8>,
+    },
 
-    /// Reads a DATAGRAM into the provided buffer.
+    /// The peer opened a unidirectional stream of a type registered with
+    /// [`register_extension_stream()`].
     ///
-    /// Applications should call this method whenever the [`poll()`] method
-    /// returns a [`Datagram`] event.
+    /// The stream's data is then reported with [`Data`] events, and can be
+    /// read with the [`recv_body()`] method. The end of the stream is
+    /// reported with a [`Finished`] event.
     ///
-    /// On success the DATAGRAM data is returned, with length and Flow ID and
-    /// length of the Flow ID.
+    /// [`register_extension_stream()`]: struct.Config.html#method.register_extension_stream
+    /// [`Data`]: enum.Event.html#variant.Data
+    /// [`recv_body()`]: struct.Connection.html#method.recv_body
+    /// [`Finished`]: enum.Event.html#variant.Finished
+    ExtensionStream {
+        /// The type of the stream.
+        stream_type: u64,
+    },
+
+    /// The peer opened a stream of a type registered with
+    /// [`register_raw_stream()`].
     ///
-    /// [`Done`] is returned if there is no data to read.
+    /// HTTP/3 stops processing the stream once its type is read, so the
+    /// application needs to use the QUIC connection's [`stream_recv()`] and
+    /// [`stream_send()`] methods to exchange data on it.
     ///
-    /// [`BufferTooShort`] is returned if the provided buffer is too small for
-    /// the data.
+    /// [`register_raw_stream()`]: struct.Config.html#method.register_raw_stream
+    /// [`stream_recv()`]: ../struct.Connection.html#method.stream_recv
+    /// [`stream_send()`]: ../struct.Connection.html#method.stream_send
+    RawStream {
+        /// The type of the stream.
+        stream_type: u64,
+    },
+
+    /// The peer's SETTINGS frame was received.
     ///
-    /// [`poll()`]: struct.Connection.html#method.poll
-    /// [`Datagram`]: enum.Event.html#variant.Datagram
-    /// [`Done`]: enum.Error.html#variant.Done
-    /// [`BufferTooShort`]: enum.Error.html#variant.BufferTooShort
-    pub fn recv_dgram(
-        &mut self, conn: &mut super::Connection, buf: &mut [u8],
-    ) -> Result<(usize, u64, usize)> {
-        let len = conn.dgram_recv(buf)?;
-        let mut b = octets::Octets::with_slice(buf);
-        let flow_id = b.get_varint()?;
-        Ok((len, flow_id, b.off()))
-    }
+    /// The settings can be retrieved with the [`peer_settings()`] method.
+    ///
+    /// [`peer_settings()`]: struct.Connection.html#method.peer_settings
+    Settings,
+}
 
-    /// Returns the maximum HTTP/3 DATAGRAM payload that can be sent.
-    pub fn dgram_max_writable_len(
-        &self, conn: &super::Connection, flow_id: u64,
-    ) -> Option<usize> {
-        let flow_id_len = octets::varint_len(flow_id);
-        match conn.dgram_max_writable_len() {
-            None => None,
-            Some(len) => len.checked_sub(flow_id_len),
-        }
-    }
+/// HTTP/3 settings of a connection endpoint.
+///
+/// Settings that were not included in the SETTINGS frame are set to `None`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionSettings {
+    /// The `SETTINGS_MAX_FIELD_SECTION_SIZE` setting.
+    pub max_field_section_size: Option<u64>,
 
-    // A helper function for determining if there is a DATAGRAM event.
-    fn process_dgrams(
-        &mut self, conn: &mut super::Connection,
-    ) -> Result<(u64, Event)> {
-        let mut d = [0; 8];
+    /// The `SETTINGS_QPACK_MAX_TABLE_CAPACITY` setting.
+    pub qpack_max_table_capacity: Option<u64>,
 
-        match conn.dgram_recv_peek(&mut d, 8) {
-            Ok(_) => {
-                if self.dgram_event_triggered {
-                    return Err(Error::Done);
-                }
+    /// The `SETTINGS_QPACK_BLOCKED_STREAMS` setting.
+    pub qpack_blocked_streams: Option<u64>,
 
-                self.dgram_event_triggered = true;
+    /// The `SETTINGS_H3_DATAGRAM` setting.
+    pub h3_datagram: Option<u64>,
 
-                Ok((0, Event::Datagram))
-            },
+    /// The `SETTINGS_ENABLE_CONNECT_PROTOCOL` setting.
+    pub connect_protocol_enabled: Option<u64>,
 
-            Err(crate::Error::Done) => {
-                // The dgram recv queue is empty, so re-arm the Datagram event
-                // so it is issued next time a DATAGRAM is received.
-                self.dgram_event_triggered = false;
+    /// All the settings as received, including unknown and reserved ones, in
+    /// the same order as in the SETTINGS frame.
+    pub raw: Option<Vec<(u64, u64)>>,
+}
 
-                Err(Error::Done)
-            },
+struct QpackStreams {
+    pub encoder_stream_id: Option<u64>,
+    pub decoder_stream_id: Option<u64>,
+}
 
-            Err(e) => Err(Error::TransportError(e)),
-        }
-    }
+/// An HTTP/3 connection.
+pub struct Connection {
+    is_server: bool,
 
-    /// Reads request or response body data into the provided buffer.
-    ///
-    /// Applications should call this method whenever the [`poll()`] method
-    /// returns a [`Data`] event.
-    ///
-    /// On success the amount of bytes read is returned, or [`Done`] if there
-    /// is no data to read.
-    ///
-    /// [`poll()`]: struct.Connection.html#method.poll
-    /// [`Data`]: enum.Event.html#variant.Data
-    /// [`Done`]: enum.Error.html#variant.Done
-    pub fn recv_body(
-        &mut self, conn: &mut super::Connection, stream_id: u64, out: &mut [u8],
-    ) -> Result<usize> {
-        let mut total = 0;
+    next_request_stream_id: u64,
+    next_uni_stream_id: u64,
 
-        // Try to consume all buffered data for the stream, even across multiple
-        // DATA frames.
-        while total < out.len() {
-            let stream = self.streams.get_mut(&stream_id).ok_or(Error::Done)?;
+    streams: HashMap<u64, stream::Stream>,
 
-            if stream.state() != stream::State::Data {
-                break;
-            }
+    local_settings: ConnectionSettings,
+    peer_settings: ConnectionSettings,
 
-            let (read, fin) =
-                match stream.try_consume_data(conn, &mut out[total..]) {
-                    Ok(v) => v,
+    control_stream_id: Option<u64>,
+    peer_control_stream_id: Option<u64>,
 
-                    Err(Error::Done) => break,
+    qpack_encoder: qpack::Encoder,
+    qpack_decoder: qpack::Decoder,
 
-                    Err(e) => return Err(e),
-                };
+    local_qpack_streams: QpackStreams,
+    peer_qpack_streams: QpackStreams,
 
-            total += read;
+    blocked_streams: HashMap<u64, Vec<u8>>,
 
-            // No more data to read, we are done.
-            if read == 0 || fin {
-                break;
-            }
+    max_push_id: u64,
 
-            // Process incoming data from the stream. For example, if a whole
-            // DATA frame was consumed, and another one is queued behind it,
-            // this will ensure the additional data will also be returned to
-            // the application.
-            match self.process_readable_stream(conn, stream_id, false) {
-                Ok(_) => unreachable!(),
+    finished_streams: VecDeque<u64>,
 
-                Err(Error::Done) => (),
+    frames_greased: bool,
 
-                Err(e) => return Err(e),
-            };
+    strict_headers: bool,
 
-            if conn.stream_finished(stream_id) {
-                break;
-            }
-        }
+    grease_frames: bool,
+    grease_streams: bool,
+    grease_settings: bool,
 
-        // While body is being received, the stream is marked as finished only
-        // when all data is read by the application.
-        if conn.stream_finished(stream_id) {
-            self.process_finished_stream(stream_id);
-        }
+    extension_frames: HashSet<u64>,
+    extension_streams: HashSet<u64>,
+    raw_streams: HashSet<u64>,
+    raw_stream_ids: HashSet<u64>,
 
-        if total == 0 {
-            return Err(Error::Done);
-        }
-
-        Ok(total)
-    }
-
-    /// Processes HTTP/3 data received from the peer.
-    ///
-    /// On success it returns an [`Event`] and an ID, or [`Done`] when there are
-    /// no events to report.
-    ///
-    /// Note that all events are edge-triggered, meaning that once reported they
-    /// will not be reported again by calling this method again, until the event
-    /// is re-armed.
-    ///
-    /// The events [`Headers`], [`Data`] and [`Finished`] return a stream ID,
-    /// which is used in methods [`recv_body()`], [`send_response()`] or
-    /// [`send_body()`].
-    ///
-    /// The event [`Datagram`] returns a dummy value of `0`, this should be
-    /// ignored by the application.
-    ///
-    /// The event [`GoAway`] returns an ID that depends on the connection role.
-    /// A client receives the largest processed stream ID. A server receives the
-    /// the largest permitted push ID.
-    ///
-    /// If an error occurs while processing data, the connection is closed with
-    /// the appropriate error code, using the transport's [`close()`] method.
-    ///
-    /// [`Event`]: enum.Event.html
-    /// [`Done`]: enum.Error.html#variant.Done
-    /// [`Headers`]: enum.Event.html#variant.Headers
-    /// [`Data`]: enum.Event.html#variant.Data
-    /// [`Finished`]: enum.Event.html#variant.Finished
-    /// [`Datagram`]: enum.Event.html#variant.Datagram
-    /// [`GoAway`]: enum.Event.html#variant.GoAWay
-    /// [`recv_body()`]: struct.Connection.html#method.recv_body
-    /// [`send_response()`]: struct.Connection.html#method.send_response
-    /// [`send_body()`]: struct.Connection.html#method.send_body
-    /// [`recv_dgram()`]: struct.Connection.html#method.recv_dgram
-    /// [`close()`]: ../struct.Connection.html#method.close
-    pub fn poll(&mut self, conn: &mut super::Connection) -> Result<(u64, Event)> {
-        // When connection close is initiated by the local application (e.g. due
-        // to a protocol error), the connection itself might be in a broken
-        // state, so return early.
-        if conn.local_error.is_some() {
-            return Err(Error::Done);
-        }
-
-        // Process control streams first.
-        if let Some(stream_id) = self.peer_control_stream_id {
-            match self.process_control_stream(conn, stream_id) {
-                Ok(ev) => return Ok(ev),
+    local_goaway_id: Option<u64>,
+    peer_goaway_id: Option<u64>,
 
-                Err(Error::Done) => (),
+    peer_origins: Vec<Vec<u8>>,
 
-                Err(e) => return Err(e),
-            };
-        }
+    largest_processed_request: Option<u64>,
 
-        if let Some(stream_id) = self.peer_qpack_streams.encoder_stream_id {
-            match self.process_control_stream(conn, stream_id) {
-                Ok(ev) => return Ok(ev),
+    pending_priority_updates: HashMap<u64, Priority>,
 
-                Err(Error::Done) => (),
+    shutdown_timeout: std::time::Duration,
+    shutdown: Option<PendingShutdown>,
 
-                Err(e) => return Err(e),
-            };
-        }
+    dgram_event_triggered: bool,
+}
 
-        if let Some(stream_id) = self.peer_qpack_streams.decoder_stream_id {
-            match self.process_control_stream(conn, stream_id) {
-                Ok(ev) => return Ok(ev),
+/// State of a graceful shutdown waiting for requests to complete.
+struct PendingShutdown {
+    error_code: u64,
+    reason: Vec<u8>,
+}
 
-                Err(Error::Done) => (),
+impl Connection {
+    fn new(
+        config: &Config, is_server: bool, enable_dgram: bool,
+    ) -> Result<Connection> {
+        let initial_uni_stream_id = if is_server { 0x3 } else { 0x2 };
 
-                Err(e) => return Err(e),
-            };
-        }
+        // Servers can only open bidirectional streams as raw streams, but
+        // those still need to use server-initiated stream IDs.
+        let initial_bidi_stream_id = if is_server { 0x1 } else { 0x0 };
+        let h3_datagram = if enable_dgram { Some(1) } else { None };
 
-        // Process finished streams list.
-        if let Some(finished) = self.finished_streams.pop_front() {
-            return Ok((finished, Event::Finished));
-        }
+        // The encoder's dynamic table uses no more memory than the decoder's,
+        // whatever the peer allows.
+        let mut qpack_encoder = qpack::Encoder::new();
+        qpack_encoder.enable_huffman(config.qpack_huffman);
+        qpack_encoder.set_table_capacity_limit(
+            config.qpack_max_table_capacity.unwrap_or(0),
+        );
 
-        // Process queued DATAGRAMs if the poll threshold allows it.
-        match self.process_dgrams(conn) {
-            Ok(v) => return Ok(v),
+        let mut qpack_decoder = qpack::Decoder::new();
+        qpack_decoder
+            .set_max_table_capacity(config.qpack_max_table_capacity.unwrap_or(0));
 
-            Err(Error::Done) => (),
+        Ok(Connection {
+            is_server,
 
-            Err(e) => return Err(e),
-        };
+            next_request_stream_id: initial_bidi_stream_id,
 
-        // Process HTTP/3 data from readable streams.
-        for s in conn.readable() {
-            trace!("{} stream id {} is readable", conn.trace_id(), s);
+            next_uni_stream_id: initial_uni_stream_id,
 
-            let ev = match self.process_readable_stream(conn, s, true) {
-                Ok(v) => Some(v),
+            streams: HashMap::new(),
 
-                Err(Error::Done) => None,
+            local_settings: ConnectionSettings {
+                max_field_section_size: config.max_field_section_size,
+                qpack_max_table_capacity: config.qpack_max_table_capacity,
+                qpack_blocked_streams: config.qpack_blocked_streams,
+                h3_datagram,
+                connect_protocol_enabled: config.connect_protocol_enabled,
+                raw: Default::default(),
+            },
 
-                // Return early if the stream was reset, to avoid returning
-                // a Finished event later as well.
-                Err(Error::TransportError(crate::Error::StreamReset(e))) =>
-                    return Ok((s, Event::Reset(e))),
+            peer_settings: ConnectionSettings {
+                max_field_section_size: None,
+                qpack_max_table_capacity: None,
+                qpack_blocked_streams: None,
+                h3_datagram: None,
+                connect_protocol_enabled: None,
+                raw: Default::default(),
+            },
 
-                Err(e) => return Err(e),
-            };
+            control_stream_id: None,
+            peer_control_stream_id: None,
 
-            if conn.stream_finished(s) {
-                self.process_finished_stream(s);
-            }
+            qpack_encoder,
+            qpack_decoder,
 
-            // TODO: check if stream is completed so it can be freed
-            if let Some(ev) = ev {
-                return Ok(ev);
-            }
-        }
+            local_qpack_streams: QpackStreams {
+                encoder_stream_id: None,
+                decoder_stream_id: None,
+            },
 
-        // Process finished streams list once again, to make sure `Finished`
-        // events are returned when receiving empty stream frames with the fin
-        // flag set.
-        if let Some(finished) = self.finished_streams.pop_front() {
-            return Ok((finished, Event::Finished));
-        }
+            peer_qpack_streams: QpackStreams {
+                encoder_stream_id: None,
+                decoder_stream_id: None,
+            },
 
-        Err(Error::Done)
-    }
+            blocked_streams: HashMap::new(),
 
-    /// Sends a GOAWAY frame to initiate graceful connection closure.
-    ///
-    /// When quiche is used in the server role, the `id` parameter is the stream
-    /// ID of the highest processed request. This can be any valid ID between 0
-    /// and 2^62-4. However, the ID cannot be increased. Failure to satisfy
-    /// these conditions will return an error.
-    ///
-    /// This method does not close the QUIC connection. Applications are
-    /// required to call [`close()`] themselves.
-    ///
-    /// [`close()`]: ../struct.Connection.html#method.close
-    pub fn send_goaway(
-        &mut self, conn: &mut super::Connection, id: u64,
-    ) -> Result<()> {
-        let mut id = id;
+            max_push_id: 0,
 
-        // TODO: server push
-        //
-        // In the meantime always send 0 from client.
-        if !self.is_server {
-            id = 0;
-        }
+            finished_streams: VecDeque::new(),
 
-        if self.is_server && id % 4 != 0 {
-            return Err(Error::IdError);
-        }
+            frames_greased: false,
 
-        if let Some(sent_id) = self.local_goaway_id {
-            if id > sent_id {
-                return Err(Error::IdError);
-            }
-        }
+            strict_headers: config.strict_headers,
 
-        if let Some(stream_id) = self.control_stream_id {
-            let mut d = [42; 10];
-            let mut b = octets::OctetsMut::with_slice(&mut d);
+            grease_frames: config.grease_frames,
+            grease_streams: config.grease_streams,
+            grease_settings: config.grease_settings,
 
-            let frame = frame::Frame::GoAway { id };
+            extension_frames: config.extension_frames.clone(),
+            extension_streams: config.extension_streams.clone(),
+            raw_streams: config.raw_streams.clone(),
+            raw_stream_ids: HashSet::new(),
 
-            let wire_len = frame.to_bytes(&mut b)?;
-            let stream_cap = conn.stream_capacity(stream_id)?;
+            local_goaway_id: None,
+            peer_goaway_id: None,
 
-            if stream_cap < wire_len {
-                return Err(Error::StreamBlocked);
-            }
+            peer_origins: Vec::new(),
 
-            trace!("{} tx frm {:?}", conn.trace_id(), frame);
+            largest_processed_request: None,
 
-            let off = b.off();
-            conn.stream_send(stream_id, &d[..off], false)?;
+            pending_priority_updates: HashMap::new(),
 
-            self.local_goaway_id = Some(id);
-        }
+            shutdown_timeout: config.shutdown_timeout,
+            shutdown: None,
 
-        Ok(())
+            dgram_event_triggered: false,
+        })
     }
 
-    /// Gets the raw settings from peer including unknown and reserved types.
+    /// Creates a new HTTP/3 connection using the provided QUIC connection.
     ///
-    /// The order of settings is the same as received in the SETTINGS frame.
-    pub fn peer_settings_raw(&self) -> Option<&[(u64, u64)]> {
-        self.peer_settings.raw.as_deref()
-    }
-
-    fn open_uni_stream(
-        &mut self, conn: &mut super::Connection, ty: u64,
-    ) -> Result<u64> {
-        let stream_id = self.next_uni_stream_id;
+    /// This will also initiate the HTTP/3 handshake with the peer by opening
+    /// all control streams (including QPACK) and sending the local settings.
+    ///
+    /// On success the new connection is returned.
+    ///
+    /// The [`StreamLimit`] error is returned when the HTTP/3 control stream
+    /// cannot be created.
+    ///
+    /// [`StreamLimit`]: ../enum.Error.html#variant.InvalidState
+    pub fn with_transport(
+        conn: &mut super::Connection, config: &Config,
+    ) -> Result<Connection> {
+        let mut http3_conn =
+            Connection::new(config, conn.is_server, conn.dgram_enabled())?;
 
-        let mut d = [0; 8];
-        let mut b = octets::OctetsMut::with_slice(&mut d);
+        match http3_conn.send_settings(conn) {
+            Ok(_) => (),
 
-        match ty {
-            // Control and QPACK streams are the most important to schedule.
-            stream::HTTP3_CONTROL_STREAM_TYPE_ID |
-            stream::QPACK_ENCODER_STREAM_TYPE_ID |
-            stream::QPACK_DECODER_STREAM_TYPE_ID => {
-                conn.stream_priority(stream_id, 0, true)?;
+            Err(e) => {
+                conn.close(true, e.to_wire(), b"Error opening control stream")?;
+                return Err(e);
             },
+        };
 
-            // TODO: Server push
-            stream::HTTP3_PUSH_STREAM_TYPE_ID => (),
+        // Try opening QPACK streams, but ignore errors if it fails since we
+        // don't need them right now.
+        http3_conn.open_qpack_encoder_stream(conn).ok();
+        http3_conn.open_qpack_decoder_stream(conn).ok();
 
-            // Anything else is a GREASE stream, so make it the least important.
-            _ => {
-                conn.stream_priority(stream_id, 255, true)?;
-            },
+        if conn.grease && http3_conn.grease_streams {
+            // Try opening a GREASE stream, but ignore errors since it's not
+            // critical.
+            http3_conn.open_grease_stream(conn).ok();
         }
 
-        conn.stream_send(stream_id, b.put_varint(ty)?, false)?;
+        // Use the settings the peer sent with ALPS, if any, until its
+        // SETTINGS frame is received on the control stream.
+        if let Some(settings) = conn.peer_application_settings() {
+            let settings = settings.to_vec();
 
-        // To avoid skipping stream IDs, we only calculate the next available
-        // stream ID when data has been successfully buffered.
-        self.next_uni_stream_id = self
-            .next_uni_stream_id
-            .checked_add(4)
-            .ok_or(Error::IdError)?;
+            http3_conn.process_application_settings(conn, &settings)?;
+        }
 
-        Ok(stream_id)
+        Ok(http3_conn)
     }
 
-    fn open_qpack_encoder_stream(
-        &mut self, conn: &mut super::Connection,
-    ) -> Result<()> {
-        self.local_qpack_streams.encoder_stream_id = Some(
-            self.open_uni_stream(conn, stream::QPACK_ENCODER_STREAM_TYPE_ID)?,
-        );
-
-        Ok(())
-    }
+    /// Sends an HTTP/3 request.
+    ///
+    /// The request is encoded from the provided list of headers without a
+    /// body, and sent on a newly allocated stream. To include a body,
+    /// set `fin` as `false` and subsequently call [`send_body()`] with the
+    /// same `conn` and the `stream_id` returned from this method.
+    ///
+    /// On success the newly allocated stream ID is returned.
+    ///
+    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
+    /// doesn't have enough capacity for the operation to complete. When this
+    /// happens the application should retry the operation once the stream is
+    /// reported as writable again.
+    ///
+    /// [`send_body()`]: struct.Connection.html#method.send_body
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_request<T: NameValue>(
+        &mut self, conn: &mut super::Connection, headers: &[T], fin: bool,
+    ) -> Result<u64> {
+        // If we received a GOAWAY from the peer, MUST NOT initiate new
+        // requests.
+        if self.peer_goaway_id.is_some() {
+            return Err(Error::FrameUnexpected);
+        }
 
-    fn open_qpack_decoder_stream(
-        &mut self, conn: &mut super::Connection,
-    ) -> Result<()> {
-        self.local_qpack_streams.decoder_stream_id = Some(
-            self.open_uni_stream(conn, stream::QPACK_DECODER_STREAM_TYPE_ID)?,
-        );
+        validate_connect_request(
+            headers,
+            self.extended_connect_enabled_by_peer(),
+        )?;
 
-        Ok(())
-    }
+        let stream_id = self.next_request_stream_id;
 
-    /// Send GREASE frames on the provided stream ID.
-    fn send_grease_frames(
-        &mut self, conn: &mut super::Connection, stream_id: u64,
-    ) -> Result<()> {
-        let mut d = [0; 8];
+        self.streams
+            .insert(stream_id, stream::Stream::new(stream_id, true));
 
-        let stream_cap = match conn.stream_capacity(stream_id) {
-            Ok(v) => v,
+        // The underlying QUIC stream does not exist yet, so calls to e.g.
+        // stream_capacity() will fail. By writing a 0-length buffer, we force
+        // the creation of the QUIC stream state, without actually writing
+        // anything.
+        if let Err(e) = conn.stream_send(stream_id, b"", false) {
+            self.streams.remove(&stream_id);
 
-            Err(e) => {
-                if conn.stream_finished(stream_id) {
-                    self.streams.remove(&stream_id);
-                }
+            if e == super::Error::Done {
+                return Err(Error::StreamBlocked);
+            }
 
-                return Err(e.into());
-            },
+            return Err(e.into());
         };
 
-        let grease_frame1 = grease_value();
-        let grease_frame2 = grease_value();
-        let grease_payload = b"GREASE is the word";
+        self.send_headers(conn, stream_id, headers, fin)?;
 
-        let overhead = octets::varint_len(grease_frame1) + // frame type
-            1 + // payload len
-            octets::varint_len(grease_frame2) + // frame type
-            1 + // payload len
-            grease_payload.len(); // payload
+        // To avoid skipping stream IDs, we only calculate the next available
+        // stream ID when a request has been successfully buffered.
+        self.next_request_stream_id = self
+            .next_request_stream_id
+            .checked_add(4)
+            .ok_or(Error::IdError)?;
 
-        // Don't send GREASE if there is not enough capacity for it. Greasing
-        // will _not_ be attempted again later on.
-        if stream_cap < overhead {
-            return Ok(());
+        Ok(stream_id)
+    }
+
+    /// Opens a raw stream of the given type.
+    ///
+    /// Raw streams share the QUIC connection with HTTP/3, but are not managed
+    /// by it. The stream type is sent as a variable-length integer at the
+    /// start of the stream, so that peers that registered it with
+    /// [`register_raw_stream()`] can identify the stream. Afterwards the
+    /// application exchanges data on the stream directly with the QUIC
+    /// connection's [`stream_send()`] and [`stream_recv()`] methods.
+    ///
+    /// When `bidi` is `true` a bidirectional stream is opened, otherwise a
+    /// unidirectional one.
+    ///
+    /// On success the newly allocated stream ID is returned.
+    ///
+    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
+    /// doesn't have enough capacity for the stream type.
+    ///
+    /// [`register_raw_stream()`]: struct.Config.html#method.register_raw_stream
+    /// [`stream_send()`]: ../struct.Connection.html#method.stream_send
+    /// [`stream_recv()`]: ../struct.Connection.html#method.stream_recv
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn open_raw_stream(
+        &mut self, conn: &mut super::Connection, ty: u64, bidi: bool,
+    ) -> Result<u64> {
+        if !bidi {
+            return self.open_uni_stream(conn, ty);
         }
 
-        trace!("{} tx frm GREASE stream={}", conn.trace_id(), stream_id);
+        let stream_id = self.next_request_stream_id;
 
-        // Empty GREASE frame.
+        let mut d = [0; 8];
         let mut b = octets::OctetsMut::with_slice(&mut d);
-        conn.stream_send(stream_id, b.put_varint(grease_frame1)?, false)?;
 
-        let mut b = octets::OctetsMut::with_slice(&mut d);
-        conn.stream_send(stream_id, b.put_varint(0)?, false)?;
+        match conn.stream_send(stream_id, b.put_varint(ty)?, false) {
+            Ok(_) => (),
 
-        // GREASE frame with payload.
-        let mut b = octets::OctetsMut::with_slice(&mut d);
-        conn.stream_send(stream_id, b.put_varint(grease_frame2)?, false)?;
+            Err(super::Error::Done) => return Err(Error::StreamBlocked),
 
-        let mut b = octets::OctetsMut::with_slice(&mut d);
-        conn.stream_send(stream_id, b.put_varint(18)?, false)?;
+            Err(e) => return Err(e.into()),
+        };
 
-        conn.stream_send(stream_id, grease_payload, false)?;
+        let mut stream = stream::Stream::new(stream_id, true);
+        stream.set_raw();
 
-        Ok(())
+        self.streams.insert(stream_id, stream);
+        self.raw_stream_ids.insert(stream_id);
+
+        self.next_request_stream_id = self
+            .next_request_stream_id
+            .checked_add(4)
+            .ok_or(Error::IdError)?;
+
+        Ok(stream_id)
     }
 
-    /// Opens a new unidirectional stream with a GREASE type and sends some
-    /// unframed payload.
-    fn open_grease_stream(&mut self, conn: &mut super::Connection) -> Result<()> {
-        match self.open_uni_stream(conn, grease_value()) {
-            Ok(stream_id) => {
-                trace!("{} open GREASE stream {}", conn.trace_id(), stream_id);
+    /// Sends an HTTP/3 response on the specified stream with the priority
+    /// signaled by the client.
+    ///
+    /// If the client didn't signal a priority for the request, the default
+    /// priority is used.
+    ///
+    /// This method sends the provided `headers` without a body. To include a
+    /// body, set `fin` as `false` and subsequently call [`send_body()`] with
+    /// the same `conn` and `stream_id`.
+    ///
+    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
+    /// doesn't have enough capacity for the operation to complete. When this
+    /// happens the application should retry the operation once the stream is
+    /// reported as writable again.
+    ///
+    /// [`send_body()`]: struct.Connection.html#method.send_body
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_response<T: NameValue>(
+        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
+        fin: bool,
+    ) -> Result<()> {
+        let priority = self.peer_priority(stream_id).unwrap_or_default();
 
-                conn.stream_send(stream_id, b"GREASE is the word", true)?;
-            },
+        self.send_response_with_params(conn, stream_id, headers, priority, fin)
+    }
 
-            Err(Error::IdError) => {
-                trace!("{} GREASE stream blocked", conn.trace_id(),);
+    /// Sends an HTTP/3 response on the specified stream with specified
+    /// priority.
+    ///
+    /// The `priority` argument is parsed as the value of the `priority`
+    /// header field, as described in [`Priority::parse()`].
+    ///
+    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
+    /// doesn't have enough capacity for the operation to complete. When this
+    /// happens the application should retry the operation once the stream is
+    /// reported as writable again.
+    ///
+    /// [`Priority::parse()`]: struct.Priority.html#method.parse
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_response_with_priority<T: NameValue>(
+        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
+        priority: &str, fin: bool,
+    ) -> Result<()> {
+        let priority = Priority::parse(priority.as_bytes());
 
-                return Ok(());
-            },
+        self.send_response_with_params(conn, stream_id, headers, priority, fin)
+    }
 
-            Err(e) => return Err(e),
-        };
+    fn send_response_with_params<T: NameValue>(
+        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
+        priority: Priority, fin: bool,
+    ) -> Result<()> {
+        if !self.streams.contains_key(&stream_id) {
+            return Err(Error::FrameUnexpected);
+        }
+
+        conn.stream_priority(
+            stream_id,
+            priority.transport_urgency(),
+            priority.incremental(),
+        )?;
+
+        self.send_headers(conn, stream_id, headers, fin)?;
 
         Ok(())
     }
 
-    /// Sends SETTINGS frame based on HTTP/3 configuration.
-    fn send_settings(&mut self, conn: &mut super::Connection) -> Result<()> {
-        self.control_stream_id = Some(
-            self.open_uni_stream(conn, stream::HTTP3_CONTROL_STREAM_TYPE_ID)?,
-        );
+    fn encode_header_block<T: NameValue>(
+        &mut self, stream_id: u64, headers: &[T],
+    ) -> Result<Vec<u8>> {
+        let headers_len = headers
+            .iter()
+            .fold(0, |acc, h| acc + h.value().len() + h.name().len() + 32);
 
-        let grease = if conn.grease {
-            Some((grease_value(), grease_value()))
-        } else {
-            None
-        };
+        let mut header_block = vec![0; headers_len];
+        let len = self
+            .qpack_encoder
+            .encode_on_stream(stream_id, headers, &mut header_block)
+            .map_err(|_| Error::InternalError)?;
 
-        let frame = frame::Frame::Settings {
-            max_field_section_size: self.local_settings.max_field_section_size,
-            qpack_max_table_capacity: self
-                .local_settings
-                .qpack_max_table_capacity,
-            qpack_blocked_streams: self.local_settings.qpack_blocked_streams,
-            h3_datagram: self.local_settings.h3_datagram,
-            grease,
-            raw: Default::default(),
-        };
+        header_block.truncate(len);
 
-        let mut d = [42; 128];
-        let mut b = octets::OctetsMut::with_slice(&mut d);
+        Ok(header_block)
+    }
 
-        frame.to_bytes(&mut b)?;
+    fn send_headers<T: NameValue>(
+        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
+        fin: bool,
+    ) -> Result<()> {
+        if !self.frames_greased && conn.grease && self.grease_frames {
+            self.send_grease_frames(conn, stream_id)?;
+            self.frames_greased = true;
+        }
 
-        let off = b.off();
+        let header_block = self.encode_header_block(stream_id, headers)?;
 
-        if let Some(id) = self.control_stream_id {
-            conn.stream_send(id, &d[..off], false)?;
+        // The encoder must not wait for the acknowledgement of a header block
+        // that was never sent.
+        if let Err(e) =
+            self.send_header_block(conn, stream_id, &header_block, fin)
+        {
+            self.qpack_encoder.discard_last_section(stream_id);
+
+            return Err(e);
         }
 
         Ok(())
     }
 
-    fn process_control_stream(
+    fn send_header_block(
         &mut self, conn: &mut super::Connection, stream_id: u64,
-    ) -> Result<(u64, Event)> {
-        if conn.stream_finished(stream_id) {
-            conn.close(
-                true,
-                Error::ClosedCriticalStream.to_wire(),
-                b"Critical stream closed.",
-            )?;
-
-            return Err(Error::ClosedCriticalStream);
-        }
+        header_block: &[u8], fin: bool,
+    ) -> Result<()> {
+        let mut d = [42; 10];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
 
-        match self.process_readable_stream(conn, stream_id, true) {
-            Ok(ev) => return Ok(ev),
+        // Send any dynamic table insertions the header block depends on.
+        self.send_qpack_instructions(conn)?;
 
-            Err(Error::Done) => (),
+        let overhead = octets::varint_len(frame::HEADERS_FRAME_TYPE_ID) +
+            octets::varint_len(header_block.len() as u64);
 
-            Err(e) => return Err(e),
-        };
+        // Headers need to be sent atomically, so make sure the stream has
+        // enough capacity.
+        match conn.stream_writable(stream_id, overhead + header_block.len()) {
+            Ok(true) => (),
 
-        if conn.stream_finished(stream_id) {
-            conn.close(
-                true,
-                Error::ClosedCriticalStream.to_wire(),
-                b"Critical stream closed.",
-            )?;
+            Ok(false) => return Err(Error::StreamBlocked),
 
-            return Err(Error::ClosedCriticalStream);
-        }
+            Err(e) => {
+                if conn.stream_finished(stream_id) {
+                    self.streams.remove(&stream_id);
+                }
 
-        Err(Error::Done)
-    }
+                return Err(e.into());
+            },
+        };
 
-    fn process_readable_stream(
-        &mut self, conn: &mut super::Connection, stream_id: u64, polling: bool,
-    ) -> Result<(u64, Event)> {
-        self.streams
-            .entry(stream_id)
-            .or_insert_with(|| stream::Stream::new(stream_id, false));
+        trace!(
+            "{} tx frm HEADERS stream={} len={} fin={}",
+            conn.trace_id(),
+            stream_id,
+            header_block.len(),
+            fin
+        );
 
-        // We need to get a fresh reference to the stream for each
-        // iteration, to avoid borrowing `self` for the entire duration
-        // of the loop, because we'll need to borrow it again in the
-        // `State::FramePayload` case below.
-        while let Some(stream) = self.streams.get_mut(&stream_id) {
-            match stream.state() {
-                stream::State::StreamType => {
-                    stream.try_fill_buffer(conn)?;
+        b.put_varint(frame::HEADERS_FRAME_TYPE_ID)?;
+        b.put_varint(header_block.len() as u64)?;
+        let off = b.off();
+        conn.stream_send(stream_id, &d[..off], false)?;
 
-                    let varint = match stream.try_consume_varint() {
-                        Ok(v) => v,
+        // Sending header block separately avoids unnecessary copy.
+        conn.stream_send(stream_id, header_block, fin)?;
 
-                        Err(_) => continue,
-                    };
+        if let Some(s) = self.streams.get_mut(&stream_id) {
+            s.initialize_local();
 
-                    let ty = stream::Type::deserialize(varint)?;
+            if fin {
+                s.finish_local();
+            }
+        }
 
-                    if let Err(e) = stream.set_ty(ty) {
-                        conn.close(true, e.to_wire(), b"")?;
-                        return Err(e);
-                    }
+        if fin && conn.stream_finished(stream_id) {
+            self.streams.remove(&stream_id);
+        }
 
-                    match &ty {
-                        stream::Type::Control => {
-                            // Only one control stream allowed.
-                            if self.peer_control_stream_id.is_some() {
-                                conn.close(
-                                    true,
-                                    Error::StreamCreationError.to_wire(),
-                                    b"Received multiple control streams",
-                                )?;
+        Ok(())
+    }
 
-                                return Err(Error::StreamCreationError);
-                            }
+    /// Sends a trailer section on the given stream, which is then finished.
+    ///
+    /// Trailers can only be sent after the headers of a request or response,
+    /// and typically follow its body. They must not contain pseudo-headers.
+    ///
+    /// The [`FrameUnexpected`] error is returned if no headers were sent on
+    /// the stream yet, or if it was already finished. The [`MessageError`]
+    /// error is returned if the trailers contain a pseudo-header.
+    ///
+    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
+    /// doesn't have enough capacity for the operation to complete. When this
+    /// happens the application should retry the operation once the stream is
+    /// reported as writable again.
+    ///
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    /// [`MessageError`]: enum.Error.html#variant.MessageError
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_trailers<T: NameValue>(
+        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
+    ) -> Result<()> {
+        match self.streams.get(&stream_id) {
+            Some(s) if s.local_initialized() && !s.local_finished() => (),
 
-                            trace!(
-                                "{} open peer's control stream {}",
-                                conn.trace_id(),
-                                stream_id
-                            );
+            _ => return Err(Error::FrameUnexpected),
+        };
 
-                            self.peer_control_stream_id = Some(stream_id);
-                        },
+        if headers.iter().any(|h| h.name().starts_with(b":")) {
+            return Err(Error::MessageError);
+        }
 
-                        stream::Type::Push => {
-                            // Only clients can receive push stream.
-                            if self.is_server {
-                                conn.close(
-                                    true,
-                                    Error::StreamCreationError.to_wire(),
-                                    b"Server received push stream.",
-                                )?;
+        self.send_headers(conn, stream_id, headers, true)
+    }
 
-                                return Err(Error::StreamCreationError);
-                            }
-                        },
+    /// Sends an HTTP/3 body chunk on the given stream.
+    ///
+    /// On success the number of bytes written is returned, or [`Done`] if no
+    /// bytes could be written (e.g. because the stream is blocked).
+    ///
+    /// Note that the number of written bytes returned can be lower than the
+    /// length of the input buffer when the underlying QUIC stream doesn't have
+    /// enough capacity for the operation to complete.
+    ///
+    /// When a partial write happens (including when [`Done`] is returned) the
+    /// application should retry the operation once the stream is reported as
+    /// writable again.
+    ///
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn send_body(
+        &mut self, conn: &mut super::Connection, stream_id: u64, body: &[u8],
+        fin: bool,
+    ) -> Result<usize> {
+        let mut d = [42; 10];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
 
-                        stream::Type::QpackEncoder => {
-                            // Only one qpack encoder stream allowed.
-                            if self.peer_qpack_streams.encoder_stream_id.is_some()
-                            {
-                                conn.close(
-                                    true,
-                                    Error::StreamCreationError.to_wire(),
-                                    b"Received multiple QPACK encoder streams",
-                                )?;
+        // Validate that it is sane to send data on the stream.
+        if stream_id % 4 != 0 {
+            return Err(Error::FrameUnexpected);
+        }
 
-                                return Err(Error::StreamCreationError);
-                            }
+        match self.streams.get(&stream_id) {
+            Some(s) =>
+                if !s.local_initialized() {
+                    return Err(Error::FrameUnexpected);
+                },
 
-                            self.peer_qpack_streams.encoder_stream_id =
-                                Some(stream_id);
-                        },
+            None => {
+                return Err(Error::FrameUnexpected);
+            },
+        };
 
-                        stream::Type::QpackDecoder => {
-                            // Only one qpack decoder allowed.
-                            if self.peer_qpack_streams.decoder_stream_id.is_some()
-                            {
-                                conn.close(
-                                    true,
-                                    Error::StreamCreationError.to_wire(),
-                                    b"Received multiple QPACK decoder streams",
-                                )?;
+        // Avoid sending 0-length DATA frames when the fin flag is false.
+        if body.is_empty() && !fin {
+            return Err(Error::Done);
+        }
 
-                                return Err(Error::StreamCreationError);
-                            }
+        let overhead = octets::varint_len(frame::DATA_FRAME_TYPE_ID) +
+            octets::varint_len(body.len() as u64);
 
-                            self.peer_qpack_streams.decoder_stream_id =
-                                Some(stream_id);
-                        },
+        let stream_cap = match conn.stream_capacity(stream_id) {
+            Ok(v) => v,
 
-                        stream::Type::Unknown => {
-                            // Unknown stream types are ignored.
-                            // TODO: we MAY send STOP_SENDING
-                        },
+            Err(e) => {
+                if conn.stream_finished(stream_id) {
+                    self.streams.remove(&stream_id);
+                }
 
-                        stream::Type::Request => unreachable!(),
-                    }
-                },
+                return Err(e.into());
+            },
+        };
 
-                stream::State::PushId => {
-                    stream.try_fill_buffer(conn)?;
+        if stream_cap < overhead + body.len() {
+            // Ensure the peer is notified that the connection or stream is
+            // blocked when the stream's capacity is limited by flow control.
+            let _ = conn.stream_writable(stream_id, overhead + body.len());
+        }
 
-                    let varint = match stream.try_consume_varint() {
-                        Ok(v) => v,
+        // Make sure there is enough capacity to send the DATA frame header.
+        if stream_cap < overhead {
+            return Err(Error::Done);
+        }
 
-                        Err(_) => continue,
-                    };
+        // Cap the frame payload length to the stream's capacity.
+        let body_len = std::cmp::min(body.len(), stream_cap - overhead);
 
-                    if let Err(e) = stream.set_push_id(varint) {
-                        conn.close(true, e.to_wire(), b"")?;
-                        return Err(e);
-                    }
-                },
+        // If we can't send the entire body, set the fin flag to false so the
+        // application can try again later.
+        let fin = if body_len != body.len() { false } else { fin };
 
-                stream::State::FrameType => {
-                    stream.try_fill_buffer(conn)?;
+        // Again, avoid sending 0-length DATA frames when the fin flag is false.
+        if body_len == 0 && !fin {
+            return Err(Error::Done);
+        }
 
-                    let varint = match stream.try_consume_varint() {
-                        Ok(v) => v,
+        trace!(
+            "{} tx frm DATA stream={} len={} fin={}",
+            conn.trace_id(),
+            stream_id,
+            body_len,
+            fin
+        );
 
-                        Err(_) => continue,
-                    };
+        b.put_varint(frame::DATA_FRAME_TYPE_ID)?;
+        b.put_varint(body_len as u64)?;
+        let off = b.off();
+        conn.stream_send(stream_id, &d[..off], false)?;
 
-                    match stream.set_frame_type(varint) {
-                        Err(Error::FrameUnexpected) => {
-                            let msg = format!("Unexpected frame type {}", varint);
+        // Return how many bytes were written, excluding the frame header.
+        // Sending body separately avoids unnecessary copy.
+        let written = conn.stream_send(stream_id, &body[..body_len], fin)?;
 
-                            conn.close(
-                                true,
-                                Error::FrameUnexpected.to_wire(),
-                                msg.as_bytes(),
-                            )?;
+        if fin && written == body.len() {
+            if let Some(s) = self.streams.get_mut(&stream_id) {
+                s.finish_local();
+            }
+        }
 
-                            return Err(Error::FrameUnexpected);
-                        },
+        if fin && written == body.len() && conn.stream_finished(stream_id) {
+            self.streams.remove(&stream_id);
+        }
 
-                        Err(e) => {
-                            conn.close(
-                                true,
-                                e.to_wire(),
-                                b"Error handling frame.",
-                            )?;
+        Ok(written)
+    }
 
-                            return Err(e);
-                        },
+    /// Sends a capsule on the given stream.
+    ///
+    /// The capsule is sent as the payload of a single DATA frame. Unlike
+    /// [`send_body()`], partial writes never happen: [`StreamBlocked`] is
+    /// returned if the stream doesn't have enough capacity for the whole
+    /// capsule, in which case the application should retry the operation
+    /// once the stream is reported as writable again.
+    ///
+    /// [`send_body()`]: struct.Connection.html#method.send_body
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_capsule(
+        &mut self, conn: &mut super::Connection, stream_id: u64,
+        capsule: &Capsule,
+    ) -> Result<()> {
+        match self.streams.get(&stream_id) {
+            Some(s) if s.local_initialized() => (),
 
-                        _ => (),
-                    }
-                },
+            _ => return Err(Error::FrameUnexpected),
+        };
 
-                stream::State::FramePayloadLen => {
-                    stream.try_fill_buffer(conn)?;
+        let len = capsule.wire_len();
 
-                    let varint = match stream.try_consume_varint() {
-                        Ok(v) => v,
-
-                        Err(_) => continue,
-                    };
+        let overhead = octets::varint_len(frame::DATA_FRAME_TYPE_ID) +
+            octets::varint_len(len as u64);
 
-                    if let Err(e) = stream.set_frame_payload_len(varint) {
-                        conn.close(true, e.to_wire(), b"")?;
-                        return Err(e);
-                    }
-                },
+        if conn.stream_capacity(stream_id)? < overhead + len {
+            let _ = conn.stream_writable(stream_id, overhead + len);
 
-                stream::State::FramePayload => {
-                    // Do not emit events when not polling.
-                    if !polling {
-                        break;
-                    }
+            return Err(Error::StreamBlocked);
+        }
 
-                    stream.try_fill_buffer(conn)?;
+        let mut d = vec![0; len];
+        capsule.to_bytes(&mut d)?;
 
-                    let frame = match stream.try_consume_frame() {
-                        Ok(frame) => frame,
+        self.send_body(conn, stream_id, &d, false)?;
 
-                        Err(Error::Done) => return Err(Error::Done),
+        Ok(())
+    }
 
-                        Err(e) => {
-                            conn.close(
-                                true,
-                                e.to_wire(),
-                                b"Error handling frame.",
-                            )?;
+    /// Returns whether the peer enabled HTTP/3 DATAGRAM frame support.
+    ///
+    /// Support is signalled by the peer's SETTINGS, so this method always
+    /// returns false until they have been processed using the [`poll()`]
+    /// method.
+    ///
+    /// [`poll()`]: struct.Connection.html#method.poll
+    pub fn dgram_enabled_by_peer(&self, conn: &super::Connection) -> bool {
+        self.peer_settings.h3_datagram == Some(1) &&
+            conn.dgram_max_writable_len().is_some()
+    }
 
-                            return Err(e);
-                        },
-                    };
+    /// Returns whether the peer enabled extended CONNECT support.
+    ///
+    /// Support is signalled by the peer's SETTINGS, so this method always
+    /// returns false until they have been processed using the [`poll()`]
+    /// method.
+    ///
+    /// [`poll()`]: struct.Connection.html#method.poll
+    pub fn extended_connect_enabled_by_peer(&self) -> bool {
+        self.peer_settings.connect_protocol_enabled == Some(1)
+    }
 
-                    match self.process_frame(conn, stream_id, frame) {
-                        Ok(ev) => return Ok(ev),
+    /// Sends an HTTP/3 DATAGRAM with the specified flow ID.
+    ///
+    /// As defined by RFC 9297, the flow ID is the Quarter Stream ID of the
+    /// request stream the DATAGRAM is associated with, that is the stream ID
+    /// divided by 4.
+    ///
+    /// [`IdError`] is returned if the flow ID is larger than the maximum
+    /// Quarter Stream ID, and [`FrameUnexpected`] if the request stream it
+    /// maps to isn't open.
+    ///
+    /// [`IdError`]: enum.Error.html#variant.IdError
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    pub fn send_dgram(
+        &mut self, conn: &mut super::Connection, flow_id: u64, buf: &[u8],
+    ) -> Result<()> {
+        if flow_id > MAX_QUARTER_STREAM_ID {
+            return Err(Error::IdError);
+        }
 
-                        Err(Error::Done) => (),
+        if conn.streams.get(flow_id * 4).is_none() {
+            return Err(Error::FrameUnexpected);
+        }
 
-                        Err(e) => return Err(e),
-                    };
-                },
+        let len = octets::varint_len(flow_id) + buf.len();
+        let mut d = vec![0; len as usize];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
 
-                stream::State::Data => {
-                    // Do not emit events when not polling.
-                    if !polling {
-                        break;
-                    }
+        b.put_varint(flow_id)?;
+        b.put_bytes(buf)?;
 
-                    if !stream.try_trigger_data_event() {
-                        break;
-                    }
+        conn.dgram_send_vec(d)?;
 
-                    return Ok((stream_id, Event::Data));
-                },
+        Ok(())
+    }
 
-                stream::State::QpackInstruction => {
-                    let mut d = [0; 4096];
+    /// Reads a DATAGRAM into the provided buffer.
+    ///
+    /// Applications should call this method whenever the [`poll()`] method
+    /// returns a [`Datagram`] event.
+    ///
+    /// On success the DATAGRAM data is returned, with length and Flow ID and
+    /// length of the Flow ID. The Flow ID is the Quarter Stream ID of the
+    /// associated request stream, as described in [`send_dgram()`].
+    ///
+    /// DATAGRAMs associated with request streams that weren't opened yet are
+    /// dropped, as allowed by RFC 9297.
+    ///
+    /// [`Done`] is returned if there is no data to read.
+    ///
+    /// [`BufferTooShort`] is returned if the provided buffer is too small for
+    /// the data.
+    ///
+    /// [`DatagramError`] is returned if the DATAGRAM is malformed, in which
+    /// case the connection is also closed.
+    ///
+    /// [`poll()`]: struct.Connection.html#method.poll
+    /// [`Datagram`]: enum.Event.html#variant.Datagram
+    /// [`send_dgram()`]: struct.Connection.html#method.send_dgram
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`BufferTooShort`]: enum.Error.html#variant.BufferTooShort
+    /// [`DatagramError`]: enum.Error.html#variant.DatagramError
+    pub fn recv_dgram(
+        &mut self, conn: &mut super::Connection, buf: &mut [u8],
+    ) -> Result<(usize, u64, usize)> {
+        loop {
+            let len = conn.dgram_recv(buf)?;
+            let mut b = octets::Octets::with_slice(&buf[..len]);
 
-                    // Read data from the stream and discard immediately.
-                    loop {
-                        conn.stream_recv(stream_id, &mut d)?;
-                    }
-                },
+            let flow_id = match b.get_varint() {
+                Ok(v) if v <= MAX_QUARTER_STREAM_ID => v,
 
-                stream::State::Drain => {
-                    // Discard incoming data on the stream.
-                    conn.stream_shutdown(
-                        stream_id,
-                        crate::Shutdown::Read,
-                        0x100,
+                _ => {
+                    conn.close(
+                        true,
+                        Error::DatagramError.to_wire(),
+                        b"Malformed HTTP/3 DATAGRAM",
                     )?;
 
-                    break;
+                    return Err(Error::DatagramError);
                 },
+            };
 
-                stream::State::Finished => break,
-            }
-        }
-
-        Err(Error::Done)
-    }
+            let stream_id = flow_id * 4;
 
-    fn process_finished_stream(&mut self, stream_id: u64) {
-        let stream = match self.streams.get_mut(&stream_id) {
-            Some(v) => v,
+            // The request stream doesn't exist, and never did.
+            if conn.streams.get(stream_id).is_none() &&
+                !conn.streams.is_collected(stream_id)
+            {
+                trace!(
+                    "{} dropping DATAGRAM for unknown stream {}",
+                    conn.trace_id(),
+                    stream_id
+                );
 
-            None => return,
-        };
+                continue;
+            }
 
-        if stream.state() == stream::State::Finished {
-            return;
+            return Ok((len, flow_id, b.off()));
         }
+    }
 
-        match stream.ty() {
-            Some(stream::Type::Request) | Some(stream::Type::Push) => {
-                stream.finished();
-
-                self.finished_streams.push_back(stream_id);
-            },
-
-            _ => (),
-        };
+    /// Returns the maximum HTTP/3 DATAGRAM payload that can be sent.
+    pub fn dgram_max_writable_len(
+        &self, conn: &super::Connection, flow_id: u64,
+    ) -> Option<usize> {
+        let flow_id_len = octets::varint_len(flow_id);
+        match conn.dgram_max_writable_len() {
+            None => None,
+            Some(len) => len.checked_sub(flow_id_len),
+        }
     }
 
-    fn process_frame(
-        &mut self, conn: &mut super::Connection, stream_id: u64,
-        frame: frame::Frame,
+    // A helper function for determining if there is a DATAGRAM event.
+    fn process_dgrams(
+        &mut self, conn: &mut super::Connection,
     ) -> Result<(u64, Event)> {
-        trace!(
-            "{} rx frm {:?} stream={}",
-            conn.trace_id(),
-            frame,
-            stream_id
-        );
+        let mut d = [0; 8];
 
-        match frame {
-            frame::Frame::Settings {
-                max_field_section_size,
-                qpack_max_table_capacity,
-                qpack_blocked_streams,
-                h3_datagram,
-                raw,
-                ..
-            } => {
-                self.peer_settings = ConnectionSettings {
-                    max_field_section_size,
-                    qpack_max_table_capacity,
-                    qpack_blocked_streams,
-                    h3_datagram,
-                    raw,
-                };
+        match conn.dgram_recv_peek(&mut d, 8) {
+            Ok(_) => {
+                if self.dgram_event_triggered {
+                    return Err(Error::Done);
+                }
 
-                if let Some(1) = h3_datagram {
-                    // The peer MUST have also enabled DATAGRAM with a TP
-                    if conn.dgram_max_writable_len().is_none() {
-                        conn.close(
-                            true,
-                            Error::SettingsError.to_wire(),
-                            b"H3_DATAGRAM sent with value 1 but max_datagram_frame_size TP not set.",
-                        )?;
+                self.dgram_event_triggered = true;
 
-                        return Err(Error::SettingsError);
-                    }
-                }
+                Ok((0, Event::Datagram))
             },
 
-            frame::Frame::Headers { header_block } => {
-                if Some(stream_id) == self.peer_control_stream_id {
-                    conn.close(
-                        true,
-                        Error::FrameUnexpected.to_wire(),
-                        b"HEADERS received on control stream",
-                    )?;
+            Err(crate::Error::Done) => {
+                // The dgram recv queue is empty, so re-arm the Datagram event
+                // so it is issued next time a DATAGRAM is received.
+                self.dgram_event_triggered = false;
 
-                    return Err(Error::FrameUnexpected);
-                }
+                Err(Error::Done)
+            },
 
-                // Use "infinite" as default value for max_field_section_size if
-                // it is not configured by the application.
-                let max_size = self
-                    .local_settings
-                    .max_field_section_size
-                    .unwrap_or(std::u64::MAX);
+            Err(e) => Err(Error::TransportError(e)),
+        }
+    }
 
-                let headers = match self
-                    .qpack_decoder
-                    .decode(&header_block[..], max_size)
-                {
-                    Ok(v) => v,
+    /// Reads request or response body data into the provided buffer.
+    ///
+    /// Applications should call this method whenever the [`poll()`] method
+    /// returns a [`Data`] event.
+    ///
+    /// On success the amount of bytes read is returned, or [`Done`] if there
+    /// is no data to read.
+    ///
+    /// [`poll()`]: struct.Connection.html#method.poll
+    /// [`Data`]: enum.Event.html#variant.Data
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn recv_body(
+        &mut self, conn: &mut super::Connection, stream_id: u64, out: &mut [u8],
+    ) -> Result<usize> {
+        let mut total = 0;
+
+        // Try to consume all buffered data for the stream, even across multiple
+        // DATA frames.
+        while total < out.len() {
+            let stream = self.streams.get_mut(&stream_id).ok_or(Error::Done)?;
 
-                    Err(e) => {
-                        let e = match e {
-                            qpack::Error::HeaderListTooLarge =>
-                                Error::ExcessiveLoad,
+            if stream.state() != stream::State::Data {
+                break;
+            }
 
-                            _ => Error::QpackDecompressionFailed,
-                        };
+            let (read, fin) =
+                match stream.try_consume_data(conn, &mut out[total..]) {
+                    Ok(v) => v,
 
-                        conn.close(true, e.to_wire(), b"Error parsing headers.")?;
+                    Err(Error::Done) => break,
 
-                        return Err(e);
-                    },
+                    Err(e) => return Err(e),
                 };
 
-                let has_body = !conn.stream_finished(stream_id);
+            total += read;
 
-                return Ok((stream_id, Event::Headers {
-                    list: headers,
-                    has_body,
-                }));
-            },
+            // No more data to read, we are done.
+            if read == 0 || fin {
+                break;
+            }
 
-            frame::Frame::Data { .. } => {
-                if Some(stream_id) == self.peer_control_stream_id {
-                    conn.close(
-                        true,
-                        Error::FrameUnexpected.to_wire(),
-                        b"DATA received on control stream",
-                    )?;
+            // Process incoming data from the stream. For example, if a whole
+            // DATA frame was consumed, and another one is queued behind it,
+            // this will ensure the additional data will also be returned to
+            // the application.
+            match self.process_readable_stream(conn, stream_id, false) {
+                Ok(_) => unreachable!(),
 
-                    return Err(Error::FrameUnexpected);
-                }
+                Err(Error::Done) => (),
 
-                // Do nothing. The Data event is returned separately.
-            },
+                Err(e) => return Err(e),
+            };
 
-            frame::Frame::GoAway { id } => {
-                if Some(stream_id) != self.peer_control_stream_id {
-                    conn.close(
-                        true,
-                        Error::FrameUnexpected.to_wire(),
-                        b"GOAWAY received on non-control stream",
-                    )?;
+            if conn.stream_finished(stream_id) {
+                break;
+            }
+        }
 
-                    return Err(Error::FrameUnexpected);
-                }
+        // While body is being received, the stream is marked as finished only
+        // when all data is read by the application.
+        if conn.stream_finished(stream_id) {
+            self.process_finished_stream(stream_id);
+        }
 
-                if !self.is_server && id % 4 != 0 {
-                    conn.close(
-                        true,
-                        Error::FrameUnexpected.to_wire(),
-                        b"GOAWAY received with ID of non-request stream",
-                    )?;
+        if total == 0 {
+            return Err(Error::Done);
+        }
 
-                    return Err(Error::IdError);
-                }
+        Ok(total)
+    }
 
-                if let Some(received_id) = self.peer_goaway_id {
-                    if id > received_id {
-                        conn.close(
-                            true,
-                            Error::IdError.to_wire(),
-                            b"GOAWAY received with ID larger than previously received",
-                        )?;
+    /// Processes HTTP/3 data received from the peer.
+    ///
+    /// On success it returns an [`Event`] and an ID, or [`Done`] when there are
+    /// no events to report.
+    ///
+    /// Note that all events are edge-triggered, meaning that once reported they
+    /// will not be reported again by calling this method again, until the event
+    /// is re-armed.
+    ///
+    /// The events [`Headers`], [`Data`] and [`Finished`] return a stream ID,
+    /// which is used in methods [`recv_body()`], [`send_response()`] or
+    /// [`send_body()`].
+    ///
+    /// The event [`Datagram`] returns a dummy value of `0`, this should be
+    /// ignored by the application.
+    ///
+    /// The event [`GoAway`] returns an ID that depends on the connection role.
+    /// A client receives the ID of the first request stream that the server
+    /// will not process. A server receives the first push ID that it is not
+    /// allowed to use.
+    ///
+    /// If an error occurs while processing data, the connection is closed with
+    /// the appropriate error code, using the transport's [`close()`] method.
+    ///
+    /// [`Event`]: enum.Event.html
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`Headers`]: enum.Event.html#variant.Headers
+    /// [`Data`]: enum.Event.html#variant.Data
+    /// [`Finished`]: enum.Event.html#variant.Finished
+    /// [`Datagram`]: enum.Event.html#variant.Datagram
+    /// [`GoAway`]: enum.Event.html#variant.GoAway
+    /// [`recv_body()`]: struct.Connection.html#method.recv_body
+    /// [`send_response()`]: struct.Connection.html#method.send_response
+    /// [`send_body()`]: struct.Connection.html#method.send_body
+    /// [`recv_dgram()`]: struct.Connection.html#method.recv_dgram
+    /// [`close()`]: ../struct.Connection.html#method.close
+    pub fn poll(&mut self, conn: &mut super::Connection) -> Result<(u64, Event)> {
+        // When connection close is initiated by the local application (e.g. due
+        // to a protocol error), the connection itself might be in a broken
+        // state, so return early.
+        if conn.local_error.is_some() {
+            return Err(Error::Done);
+        }
 
-                        return Err(Error::IdError);
-                    }
-                }
+        // Close the connection if a pending graceful shutdown is complete.
+        if self.check_shutdown(conn) {
+            return Err(Error::Done);
+        }
 
-                self.peer_goaway_id = Some(id);
+        // Retry sending QPACK instructions that didn't fit in the encoder or
+        // decoder streams before.
+        self.send_qpack_instructions(conn)?;
 
-                return Ok((id, Event::GoAway));
-            },
+        self.collect_raw_streams(conn);
 
-            frame::Frame::MaxPushId { push_id } => {
-                if Some(stream_id) != self.peer_control_stream_id {
-                    conn.close(
-                        true,
-                        Error::FrameUnexpected.to_wire(),
-                        b"MAX_PUSH_ID received on non-control stream",
-                    )?;
+        // Process control streams first.
+        if let Some(stream_id) = self.peer_control_stream_id {
+            match self.process_control_stream(conn, stream_id) {
+                Ok(ev) => return Ok(ev),
 
-                    return Err(Error::FrameUnexpected);
-                }
+                Err(Error::Done) => (),
 
-                if !self.is_server {
-                    conn.close(
-                        true,
-                        Error::FrameUnexpected.to_wire(),
-                        b"MAX_PUSH_ID received by client",
-                    )?;
+                Err(e) => return Err(e),
+            };
+        }
 
-                    return Err(Error::FrameUnexpected);
-                }
+        if let Some(stream_id) = self.peer_qpack_streams.encoder_stream_id {
+            match self.process_control_stream(conn, stream_id) {
+                Ok(ev) => return Ok(ev),
 
-                if push_id < self.max_push_id {
-                    conn.close(
-                        true,
-                        Error::IdError.to_wire(),
-                        b"MAX_PUSH_ID reduced limit",
-                    )?;
+                Err(Error::Done) => (),
 
-                    return Err(Error::IdError);
-                }
+                Err(e) => return Err(e),
+            };
+        }
 
-                self.max_push_id = push_id;
-            },
+        if let Some(stream_id) = self.peer_qpack_streams.decoder_stream_id {
+            match self.process_control_stream(conn, stream_id) {
+                Ok(ev) => return Ok(ev),
 
-            frame::Frame::PushPromise { .. } => {
-                if self.is_server {
-                    conn.close(
-                        true,
-                        Error::FrameUnexpected.to_wire(),
-                        b"PUSH_PROMISE received by server",
-                    )?;
+                Err(Error::Done) => (),
 
-                    return Err(Error::FrameUnexpected);
-                }
+                Err(e) => return Err(e),
+            };
+        }
 
-                if stream_id % 4 != 0 {
-                    conn.close(
-                        true,
-                        Error::FrameUnexpected.to_wire(),
-                        b"PUSH_PROMISE received on non-request stream",
-                    )?;
+        // Process header blocks unblocked by new dynamic table entries.
+        match self.process_blocked_streams(conn) {
+            Ok(ev) => return Ok(ev),
 
-                    return Err(Error::FrameUnexpected);
-                }
+            Err(Error::Done) => (),
 
-                // TODO: implement more checks and PUSH_PROMISE event
-            },
+            Err(e) => return Err(e),
+        };
 
-            frame::Frame::CancelPush { .. } => {
-                if Some(stream_id) != self.peer_control_stream_id {
-                    conn.close(
-                        true,
-                        Error::FrameUnexpected.to_wire(),
-                        b"CANCEL_PUSH received on non-control stream",
-                    )?;
+        // Process finished streams list.
+        if let Some(finished) = self.finished_streams.pop_front() {
+            return Ok((finished, Event::Finished));
+        }
 
-                    return Err(Error::FrameUnexpected);
-                }
+        // Process queued DATAGRAMs if the poll threshold allows it.
+        match self.process_dgrams(conn) {
+            Ok(v) => return Ok(v),
 
-                // TODO: implement CANCEL_PUSH frame
-            },
+            Err(Error::Done) => (),
+
+            Err(e) => return Err(e),
+        };
+
+        // Process HTTP/3 data from readable streams.
+        for s in conn.readable() {
+            trace!("{} stream id {} is readable", conn.trace_id(), s);
+
+            let ev = match self.process_readable_stream(conn, s, true) {
+                Ok(v) => Some(v),
+
+                Err(Error::Done) => None,
+
+                // Return early if the stream was reset, to avoid returning
+                // a Finished event later as well.
+                Err(Error::TransportError(crate::Error::StreamReset(e))) => {
+                    self.collect_reset_stream(conn, s);
+
+                    return Ok((s, Event::Reset(e)));
+                },
 
-            frame::Frame::Unknown => (),
+                Err(e) => return Err(e),
+            };
+
+            if conn.stream_finished(s) {
+                self.process_finished_stream(s);
+            }
+
+            // TODO: check if stream is completed so it can be freed
+            if let Some(ev) = ev {
+                return Ok(ev);
+            }
         }
 
-        Err(Error::Done)
-    }
-}
+        // Process header blocks unblocked by encoder instructions that were
+        // only read in the loop above.
+        match self.process_blocked_streams(conn) {
+            Ok(ev) => return Ok(ev),
 
-/// Generates an HTTP/3 GREASE variable length integer.
-fn grease_value() -> u64 {
-    let n = super::rand::rand_u64_uniform(148_764_065_110_560_899);
-    31 * n + 33
-}
+            Err(Error::Done) => (),
 
-#[doc(hidden)]
-pub mod testing {
-    use super::*;
+            Err(e) => return Err(e),
+        };
 
-    use crate::testing;
+        // Process finished streams list once again, to make sure `Finished`
+        // events are returned when receiving empty stream frames with the fin
+        // flag set.
+        if let Some(finished) = self.finished_streams.pop_front() {
+            return Ok((finished, Event::Finished));
+        }
 
-    /// Session is an HTTP/3 test helper structure. It holds a client, server
-    /// and pipe that allows them to communicate.
+        // Send acknowledgements for the header blocks decoded so far.
+        self.send_qpack_instructions(conn)?;
+
+        Err(Error::Done)
+    }
+
+    /// Sends a GOAWAY frame to initiate graceful connection closure.
     ///
-    /// `default()` creates a session with some sensible default
-    /// configuration. `with_configs()` allows for providing a specific
-    /// configuration.
+    /// When quiche is used in the server role, the `id` parameter is the stream
+    /// ID of the first request that will not be processed, so requests on
+    /// streams with lower IDs can still complete. This can be any valid client
+    /// bidirectional stream ID between 0 and 2^62-4. Requests received on
+    /// streams with higher or equal IDs are rejected with the
+    /// `H3_REQUEST_REJECTED` error.
     ///
-    /// `handshake()` performs all the steps needed to establish an HTTP/3
-    /// connection.
+    /// When quiche is used in the client role, the `id` parameter is the first
+    /// push ID the server is not allowed to use.
     ///
-    /// Some utility functions are provided that make it less verbose to send
-    /// request, responses and individual headers. The full quiche API remains
-    /// available for any test that need to do unconventional things (such as
-    /// bad behaviour that triggers errors).
-    pub struct Session {
-        pub pipe: testing::Pipe,
-        pub client: Connection,
-        pub server: Connection,
-    }
-
-    impl Session {
-        pub fn default() -> Result<Session> {
-            let mut config = crate::Config::new(crate::PROTOCOL_VERSION)?;
-            config.load_cert_chain_from_pem_file("examples/cert.crt")?;
-            config.load_priv_key_from_pem_file("examples/cert.key")?;
-            config.set_application_protos(b"\x02h3")?;
-            config.set_initial_max_data(1500);
-            config.set_initial_max_stream_data_bidi_local(150);
-            config.set_initial_max_stream_data_bidi_remote(150);
-            config.set_initial_max_stream_data_uni(150);
-            config.set_initial_max_streams_bidi(5);
-            config.set_initial_max_streams_uni(5);
-            config.verify_peer(false);
-            config.enable_dgram(true, 3, 3);
-            config.set_ack_delay_exponent(8);
-
-            let h3_config = Config::new()?;
-            Session::with_configs(&mut config, &h3_config)
+    /// In both cases, the ID cannot be increased by subsequent calls. Failure
+    /// to satisfy these conditions will return the [`IdError`] error.
+    ///
+    /// This method does not close the QUIC connection. Applications are
+    /// required to call [`close()`] themselves, or use [`shutdown()`] instead.
+    ///
+    /// [`IdError`]: enum.Error.html#variant.IdError
+    /// [`close()`]: ../struct.Connection.html#method.close
+    /// [`shutdown()`]: struct.Connection.html#method.shutdown
+    pub fn send_goaway(
+        &mut self, conn: &mut super::Connection, id: u64,
+    ) -> Result<()> {
+        if id > MAX_GOAWAY_ID {
+            return Err(Error::IdError);
         }
 
-        pub fn with_configs(
-            config: &mut crate::Config, h3_config: &Config,
-        ) -> Result<Session> {
-            let pipe = testing::Pipe::with_config(config)?;
-            let client_dgram = pipe.client.dgram_enabled();
-            let server_dgram = pipe.server.dgram_enabled();
-            Ok(Session {
-                pipe,
-                client: Connection::new(h3_config, false, client_dgram)?,
-                server: Connection::new(h3_config, true, server_dgram)?,
-            })
+        if self.is_server &&
+            (!crate::stream::is_bidi(id) ||
+                !crate::stream::is_local(id, false))
+        {
+            return Err(Error::IdError);
         }
 
-        /// Do the HTTP/3 handshake so both ends are in sane initial state.
-        pub fn handshake(&mut self) -> Result<()> {
-            self.pipe.handshake()?;
+        if let Some(sent_id) = self.local_goaway_id {
+            if id > sent_id {
+                return Err(Error::IdError);
+            }
+        }
 
-            // Client streams.
-            self.client.send_settings(&mut self.pipe.client)?;
-            self.pipe.advance().ok();
+        if let Some(stream_id) = self.control_stream_id {
+            let mut d = [42; 10];
+            let mut b = octets::OctetsMut::with_slice(&mut d);
 
-            self.client
-                .open_qpack_encoder_stream(&mut self.pipe.client)?;
-            self.pipe.advance().ok();
+            let frame = frame::Frame::GoAway { id };
 
-            self.client
-                .open_qpack_decoder_stream(&mut self.pipe.client)?;
-            self.pipe.advance().ok();
+            let wire_len = frame.to_bytes(&mut b)?;
+            let stream_cap = conn.stream_capacity(stream_id)?;
 
-            if self.pipe.client.grease {
-                self.client.open_grease_stream(&mut self.pipe.client)?;
+            if stream_cap < wire_len {
+                return Err(Error::StreamBlocked);
             }
 
-            self.pipe.advance().ok();
+            trace!("{} tx frm {:?}", conn.trace_id(), frame);
 
-            // Server streams.
-            self.server.send_settings(&mut self.pipe.server)?;
-            self.pipe.advance().ok();
+            let off = b.off();
+            conn.stream_send(stream_id, &d[..off], false)?;
 
-            self.server
-                .open_qpack_encoder_stream(&mut self.pipe.server)?;
-            self.pipe.advance().ok();
+            self.local_goaway_id = Some(id);
+        }
 
-            self.server
-                .open_qpack_decoder_stream(&mut self.pipe.server)?;
-            self.pipe.advance().ok();
+        Ok(())
+    }
 
-            if self.pipe.server.grease {
-                self.server.open_grease_stream(&mut self.pipe.server)?;
-            }
+    /// Sends an ORIGIN frame advertising additional origins.
+    ///
+    /// As defined by RFC 9412, servers can use the ORIGIN frame to indicate
+    /// which origins, other than the one the connection was established for,
+    /// clients can use the connection for. Each origin is serialized as in
+    /// RFC 6454, e.g. `https://example.com`.
+    ///
+    /// Only servers can send ORIGIN frames, so the [`FrameUnexpected`] error
+    /// is returned when quiche is used in the client role. The
+    /// [`StreamBlocked`] error is returned when the control stream doesn't
+    /// have enough capacity for the frame.
+    ///
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_origin(
+        &mut self, conn: &mut super::Connection, origins: &[&[u8]],
+    ) -> Result<()> {
+        if !self.is_server {
+            return Err(Error::FrameUnexpected);
+        }
 
-            self.advance().ok();
+        if let Some(stream_id) = self.control_stream_id {
+            let frame = frame::Frame::Origin {
+                origins: origins.iter().map(|o| o.to_vec()).collect(),
+            };
 
-            while self.client.poll(&mut self.pipe.client).is_ok() {
-                // Do nothing.
-            }
+            let payload_len: usize = origins.iter().map(|o| 2 + o.len()).sum();
 
-            while self.server.poll(&mut self.pipe.server).is_ok() {
-                // Do nothing.
+            let mut d = vec![0; payload_len + 16];
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+
+            let wire_len = frame.to_bytes(&mut b)?;
+            let stream_cap = conn.stream_capacity(stream_id)?;
+
+            if stream_cap < wire_len {
+                return Err(Error::StreamBlocked);
             }
 
-            Ok(())
+            trace!("{} tx frm {:?}", conn.trace_id(), frame);
+
+            let off = b.off();
+            conn.stream_send(stream_id, &d[..off], false)?;
         }
 
-        /// Advances the session pipe over the buffer.
-        pub fn advance(&mut self) -> crate::Result<()> {
-            self.pipe.advance()
+        Ok(())
+    }
+
+    /// Returns the origins advertised by the server with ORIGIN frames.
+    ///
+    /// Together with the origin the connection was established for, these
+    /// form the connection's origin set. The origins are returned in the
+    /// order they were first received, and at most 1024 of them are retained.
+    ///
+    /// Servers never receive ORIGIN frames, so this is always empty for them.
+    pub fn peer_origins(&self) -> &[Vec<u8>] {
+        &self.peer_origins
+    }
+
+    /// Returns whether the request on the given stream is within the limit
+    /// set by a GOAWAY frame.
+    ///
+    /// When quiche is used in the server role, this checks the GOAWAY frame
+    /// sent with [`send_goaway()`] or [`shutdown()`], and requests that are
+    /// not allowed are rejected automatically. When quiche is used in the
+    /// client role, this checks the GOAWAY frame received from the server,
+    /// and requests that are not allowed will not be processed by the
+    /// server, so they can be safely retried on a new connection.
+    ///
+    /// `true` is returned if no GOAWAY frame was sent or received.
+    ///
+    /// [`send_goaway()`]: struct.Connection.html#method.send_goaway
+    /// [`shutdown()`]: struct.Connection.html#method.shutdown
+    pub fn is_request_allowed(&self, stream_id: u64) -> bool {
+        let goaway_id = if self.is_server {
+            self.local_goaway_id
+        } else {
+            self.peer_goaway_id
+        };
+
+        match goaway_id {
+            Some(id) => stream_id < id,
+
+            None => true,
         }
+    }
 
-        /// Polls the client for events.
-        pub fn poll_client(&mut self) -> Result<(u64, Event)> {
-            self.client.poll(&mut self.pipe.client)
+    /// Returns whether a GOAWAY frame was sent or received on the connection.
+    ///
+    /// Once this returns `true`, the connection is being drained and no new
+    /// requests should be initiated on it.
+    pub fn is_draining(&self) -> bool {
+        self.local_goaway_id.is_some() || self.peer_goaway_id.is_some()
+    }
+
+    /// Sends a PRIORITY_UPDATE frame to change the priority of a request.
+    ///
+    /// The frame is sent on the control stream, and refers to the request
+    /// sent on the stream `stream_id`. Only clients can send PRIORITY_UPDATE
+    /// frames, so the [`FrameUnexpected`] error is returned when quiche is
+    /// used in the server role, and the [`IdError`] error is returned if
+    /// `stream_id` isn't the ID of a request stream. The urgency is capped to
+    /// `7`, the lowest priority.
+    ///
+    /// The [`StreamBlocked`] error is returned when the control stream
+    /// doesn't have enough capacity for the frame. When this happens the
+    /// application should retry the operation later. The [`InternalError`]
+    /// error is returned if the control stream couldn't be opened.
+    ///
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    /// [`IdError`]: enum.Error.html#variant.IdError
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    /// [`InternalError`]: enum.Error.html#variant.InternalError
+    pub fn send_priority_update(
+        &mut self, conn: &mut super::Connection, stream_id: u64,
+        priority: &Priority,
+    ) -> Result<()> {
+        if self.is_server {
+            return Err(Error::FrameUnexpected);
         }
 
-        /// Polls the server for events.
-        pub fn poll_server(&mut self) -> Result<(u64, Event)> {
-            self.server.poll(&mut self.pipe.server)
+        // Only client-initiated bidirectional streams carry requests.
+        if !crate::stream::is_bidi(stream_id) ||
+            !crate::stream::is_local(stream_id, false)
+        {
+            return Err(Error::IdError);
         }
 
-        /// Sends a request from client with default headers.
-        ///
-        /// On success it returns the newly allocated stream and the headers.
-        pub fn send_request(&mut self, fin: bool) -> Result<(u64, Vec<Header>)> {
-            let req = vec![
-                Header::new(b":method", b"GET"),
-                Header::new(b":scheme", b"https"),
-                Header::new(b":authority", b"quic.tech"),
-                Header::new(b":path", b"/test"),
-                Header::new(b"user-agent", b"quiche-test"),
-            ];
+        let control_stream_id =
+            self.control_stream_id.ok_or(Error::InternalError)?;
 
-            let stream =
-                self.client.send_request(&mut self.pipe.client, &req, fin)?;
+        // Priorities are normally built with `Priority::new()`, which caps the
+        // urgency already.
+        let priority = Priority::new(priority.urgency, priority.incremental);
 
-            self.advance().ok();
+        let priority_field_value = priority.to_field_value();
 
-            Ok((stream, req))
+        let mut d = vec![0; priority_field_value.len() + 24];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        let frame = frame::Frame::PriorityUpdateRequest {
+            prioritized_element_id: stream_id,
+            priority_field_value,
+        };
+
+        let wire_len = frame.to_bytes(&mut b)?;
+        let stream_cap = conn.stream_capacity(control_stream_id)?;
+
+        if stream_cap < wire_len {
+            return Err(Error::StreamBlocked);
         }
 
-        /// Sends a response from server with default headers.
-        ///
-        /// On success it returns the headers.
-        pub fn send_response(
-            &mut self, stream: u64, fin: bool,
-        ) -> Result<Vec<Header>> {
-            let resp = vec![
-                Header::new(b":status", b"200"),
-                Header::new(b"server", b"quiche-test"),
-            ];
+        trace!("{} tx frm {:?}", conn.trace_id(), frame);
 
-            self.server.send_response(
-                &mut self.pipe.server,
-                stream,
-                &resp,
-                fin,
-            )?;
+        conn.stream_send(control_stream_id, &d[..wire_len], false)?;
 
-            self.advance().ok();
+        Ok(())
+    }
 
-            Ok(resp)
+    /// Returns the latest priority signaled by the peer for the request on
+    /// the given stream.
+    ///
+    /// `None` is returned if the peer didn't signal a priority for the
+    /// request, in which case the default priority applies.
+    pub fn peer_priority(&self, stream_id: u64) -> Option<Priority> {
+        self.streams.get(&stream_id)?.peer_priority()
+    }
+
+    /// Records the priority signaled by the peer for the given stream, and
+    /// applies it to the underlying transport stream.
+    fn set_peer_priority(
+        &mut self, conn: &mut super::Connection, stream_id: u64,
+        priority: Priority,
+    ) -> Result<()> {
+        if let Some(s) = self.streams.get_mut(&stream_id) {
+            s.set_peer_priority(priority);
         }
 
-        /// Sends some default payload from client.
-        ///
-        /// On success it returns the payload.
-        pub fn send_body_client(
-            &mut self, stream: u64, fin: bool,
-        ) -> Result<Vec<u8>> {
-            let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        conn.stream_priority(
+            stream_id,
+            priority.transport_urgency(),
+            priority.incremental(),
+        )?;
 
-            self.client
-                .send_body(&mut self.pipe.client, stream, &bytes, fin)?;
+        Ok(())
+    }
 
-            self.advance().ok();
+    /// Shuts down the HTTP/3 connection.
+    ///
+    /// When `graceful` is `true`, a GOAWAY frame is sent to the peer to stop
+    /// it from initiating new requests, and the QUIC connection is closed
+    /// once all outstanding requests have completed, or the timeout set with
+    /// [`set_shutdown_timeout()`] expires, whichever happens first. Requests
+    /// are checked whenever [`poll()`] is called, and the timeout is part of
+    /// the QUIC connection's [`timeout()`], so the application needs to keep
+    /// processing the connection as usual in the meantime. When quiche
+    /// is used in the server role, the GOAWAY frame carries the stream ID
+    /// following the last request that was processed.
+    ///
+    /// When `graceful` is `false`, the QUIC connection is closed right away.
+    ///
+    /// The connection is closed with the application error code mapped from
+    /// `error`, or `H3_NO_ERROR` if `error` is `None`, and the given `reason`.
+    ///
+    /// [`set_shutdown_timeout()`]: struct.Config.html#method.set_shutdown_timeout
+    /// [`poll()`]: struct.Connection.html#method.poll
+    /// [`timeout()`]: ../struct.Connection.html#method.timeout
+    pub fn shutdown(
+        &mut self, conn: &mut super::Connection, graceful: bool,
+        error: Option<Error>, reason: &[u8],
+    ) -> Result<()> {
+        let error_code = match error {
+            Some(e) => e.to_wire(),
 
-            Ok(bytes)
-        }
+            None => H3_NO_ERROR,
+        };
 
-        /// Fetches DATA payload from the server.
-        ///
-        /// On success it returns the number of bytes received.
-        pub fn recv_body_client(
-            &mut self, stream: u64, buf: &mut [u8],
-        ) -> Result<usize> {
-            self.client.recv_body(&mut self.pipe.client, stream, buf)
+        if !graceful {
+            conn.close(true, error_code, reason)?;
+
+            return Ok(());
         }
 
-        /// Sends some default payload from server.
-        ///
-        /// On success it returns the payload.
-        pub fn send_body_server(
-            &mut self, stream: u64, fin: bool,
-        ) -> Result<Vec<u8>> {
-            let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let id = match self.largest_processed_request {
+            Some(v) => v + 4,
 
-            self.server
-                .send_body(&mut self.pipe.server, stream, &bytes, fin)?;
+            None => 0,
+        };
 
-            self.advance().ok();
+        // The GOAWAY ID can't be increased, so only send a new frame if the
+        // previously sent one didn't already cover this one.
+        let send_goaway = match self.local_goaway_id {
+            Some(sent_id) => id < sent_id,
 
-            Ok(bytes)
-        }
+            None => true,
+        };
 
-        /// Fetches DATA payload from the client.
-        ///
-        /// On success it returns the number of bytes received.
-        pub fn recv_body_server(
-            &mut self, stream: u64, buf: &mut [u8],
-        ) -> Result<usize> {
-            self.server.recv_body(&mut self.pipe.server, stream, buf)
+        if send_goaway {
+            self.send_goaway(conn, id)?;
         }
 
-        /// Sends a single HTTP/3 frame from the client.
-        pub fn send_frame_client(
-            &mut self, frame: frame::Frame, stream_id: u64, fin: bool,
-        ) -> Result<()> {
+        conn.close_at(
+            conn.clock.now() + self.shutdown_timeout,
+            error_code,
+            reason,
+        );
+
+        self.shutdown = Some(PendingShutdown {
+            error_code,
+
+            reason: reason.to_vec(),
+        });
+
+        self.check_shutdown(conn);
+
+        Ok(())
+    }
+
+    /// Closes the QUIC connection if a graceful shutdown is pending, and all
+    /// requests have completed.
+    ///
+    /// The shutdown timeout is enforced by the QUIC connection itself.
+    /// Returns `true` if the connection was closed.
+    fn check_shutdown(&mut self, conn: &mut super::Connection) -> bool {
+        let shutdown = match &self.shutdown {
+            Some(v) => v,
+
+            None => return false,
+        };
+
+        // Request streams are garbage collected by the transport once they
+        // are complete.
+        let pending_requests = self.streams.keys().any(|id| {
+            crate::stream::is_bidi(*id) && conn.streams.get(*id).is_some()
+        });
+
+        if pending_requests {
+            return false;
+        }
+
+        trace!("{} graceful shutdown complete", conn.trace_id());
+
+        conn.close(true, shutdown.error_code, &shutdown.reason).ok();
+
+        self.shutdown = None;
+
+        true
+    }
+
+    /// Gets the raw settings from peer including unknown and reserved types.
+    ///
+    /// The order of settings is the same as received in the SETTINGS frame.
+    pub fn peer_settings_raw(&self) -> Option<&[(u64, u64)]> {
+        self.peer_settings.raw.as_deref()
+    }
+
+    /// Gets the settings decoded from the peer's SETTINGS frame.
+    ///
+    /// This returns `None` until the SETTINGS frame has been received, which
+    /// is signalled by the [`Settings`] event.
+    ///
+    /// [`Settings`]: enum.Event.html#variant.Settings
+    pub fn peer_settings(&self) -> Option<&ConnectionSettings> {
+        self.peer_settings.raw.as_ref()?;
+
+        Some(&self.peer_settings)
+    }
+
+    fn open_uni_stream(
+        &mut self, conn: &mut super::Connection, ty: u64,
+    ) -> Result<u64> {
+        let stream_id = self.next_uni_stream_id;
+
+        let mut d = [0; 8];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        match ty {
+            // Control and QPACK streams are the most important to schedule.
+            stream::HTTP3_CONTROL_STREAM_TYPE_ID |
+            stream::QPACK_ENCODER_STREAM_TYPE_ID |
+            stream::QPACK_DECODER_STREAM_TYPE_ID => {
+                conn.stream_priority(stream_id, 0, true)?;
+            },
+
+            // TODO: Server push
+            stream::HTTP3_PUSH_STREAM_TYPE_ID => (),
+
+            // Anything else is a GREASE or raw stream, so make it the least
+            // important.
+            _ => {
+                conn.stream_priority(stream_id, 255, true)?;
+            },
+        }
+
+        conn.stream_send(stream_id, b.put_varint(ty)?, false)?;
+
+        // To avoid skipping stream IDs, we only calculate the next available
+        // stream ID when data has been successfully buffered.
+        self.next_uni_stream_id = self
+            .next_uni_stream_id
+            .checked_add(4)
+            .ok_or(Error::IdError)?;
+
+        Ok(stream_id)
+    }
+
+    fn open_qpack_encoder_stream(
+        &mut self, conn: &mut super::Connection,
+    ) -> Result<()> {
+        self.local_qpack_streams.encoder_stream_id = Some(
+            self.open_uni_stream(conn, stream::QPACK_ENCODER_STREAM_TYPE_ID)?,
+        );
+
+        Ok(())
+    }
+
+    /// Sends pending QPACK instructions on the local encoder and decoder
+    /// streams.
+    fn send_qpack_instructions(
+        &mut self, conn: &mut super::Connection,
+    ) -> Result<()> {
+        if let Some(stream_id) = self.local_qpack_streams.encoder_stream_id {
+            let instructions = self.qpack_encoder.instructions();
+
+            let written = send_qpack_stream_data(conn, stream_id, instructions)?;
+            self.qpack_encoder.instructions_sent(written);
+        }
+
+        if let Some(stream_id) = self.local_qpack_streams.decoder_stream_id {
+            let instructions = self.qpack_decoder.instructions();
+
+            let written = send_qpack_stream_data(conn, stream_id, instructions)?;
+            self.qpack_decoder.instructions_sent(written);
+        }
+
+        Ok(())
+    }
+
+    fn open_qpack_decoder_stream(
+        &mut self, conn: &mut super::Connection,
+    ) -> Result<()> {
+        self.local_qpack_streams.decoder_stream_id = Some(
+            self.open_uni_stream(conn, stream::QPACK_DECODER_STREAM_TYPE_ID)?,
+        );
+
+        Ok(())
+    }
+
+    /// Send GREASE frames on the provided stream ID.
+    fn send_grease_frames(
+        &mut self, conn: &mut super::Connection, stream_id: u64,
+    ) -> Result<()> {
+        let mut d = [0; 8];
+
+        let stream_cap = match conn.stream_capacity(stream_id) {
+            Ok(v) => v,
+
+            Err(e) => {
+                if conn.stream_finished(stream_id) {
+                    self.streams.remove(&stream_id);
+                }
+
+                return Err(e.into());
+            },
+        };
+
+        let grease_frame1 = grease_value(conn.grease_index);
+        let grease_frame2 = grease_value(conn.grease_index);
+        let grease_payload = b"GREASE is the word";
+
+        let overhead = octets::varint_len(grease_frame1) + // frame type
+            1 + // payload len
+            octets::varint_len(grease_frame2) + // frame type
+            1 + // payload len
+            grease_payload.len(); // payload
+
+        // Don't send GREASE if there is not enough capacity for it. Greasing
+        // will _not_ be attempted again later on.
+        if stream_cap < overhead {
+            return Ok(());
+        }
+
+        trace!("{} tx frm GREASE stream={}", conn.trace_id(), stream_id);
+
+        // Empty GREASE frame.
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        conn.stream_send(stream_id, b.put_varint(grease_frame1)?, false)?;
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        conn.stream_send(stream_id, b.put_varint(0)?, false)?;
+
+        // GREASE frame with payload.
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        conn.stream_send(stream_id, b.put_varint(grease_frame2)?, false)?;
+
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+        conn.stream_send(stream_id, b.put_varint(18)?, false)?;
+
+        conn.stream_send(stream_id, grease_payload, false)?;
+
+        Ok(())
+    }
+
+    /// Opens a new unidirectional stream with a GREASE type and sends some
+    /// unframed payload.
+    fn open_grease_stream(&mut self, conn: &mut super::Connection) -> Result<()> {
+        match self.open_uni_stream(conn, grease_value(conn.grease_index)) {
+            Ok(stream_id) => {
+                trace!("{} open GREASE stream {}", conn.trace_id(), stream_id);
+
+                conn.stream_send(stream_id, b"GREASE is the word", true)?;
+            },
+
+            Err(Error::IdError) => {
+                trace!("{} GREASE stream blocked", conn.trace_id(),);
+
+                return Ok(());
+            },
+
+            Err(e) => return Err(e),
+        };
+
+        Ok(())
+    }
+
+    /// Sends SETTINGS frame based on HTTP/3 configuration.
+    fn send_settings(&mut self, conn: &mut super::Connection) -> Result<()> {
+        self.control_stream_id = Some(
+            self.open_uni_stream(conn, stream::HTTP3_CONTROL_STREAM_TYPE_ID)?,
+        );
+
+        let grease = if conn.grease && self.grease_settings {
+            Some((
+                grease_value(conn.grease_index),
+                grease_value(conn.grease_index),
+            ))
+        } else {
+            None
+        };
+
+        let frame = frame::Frame::Settings {
+            max_field_section_size: self.local_settings.max_field_section_size,
+            qpack_max_table_capacity: self
+                .local_settings
+                .qpack_max_table_capacity,
+            qpack_blocked_streams: self.local_settings.qpack_blocked_streams,
+            h3_datagram: self.local_settings.h3_datagram,
+            connect_protocol_enabled: self
+                .local_settings
+                .connect_protocol_enabled,
+            grease,
+            raw: Default::default(),
+        };
+
+        let mut d = [42; 128];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        frame.to_bytes(&mut b)?;
+
+        let off = b.off();
+
+        if let Some(id) = self.control_stream_id {
+            conn.stream_send(id, &d[..off], false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Processes the SETTINGS frame received from the peer with ALPS.
+    fn process_application_settings(
+        &mut self, conn: &mut super::Connection, settings: &[u8],
+    ) -> Result<()> {
+        let frame = (|| {
+            let mut b = octets::Octets::with_slice(settings);
+
+            let frame_type = b.get_varint()?;
+            let payload_len = b.get_varint()?;
+
+            if frame_type != frame::SETTINGS_FRAME_TYPE_ID ||
+                b.cap() as u64 != payload_len
+            {
+                return Err(Error::SettingsError);
+            }
+
+            frame::Frame::from_bytes(frame_type, payload_len, b.as_ref())
+        })();
+
+        let settings = match frame {
+            Ok(frame::Frame::Settings {
+                max_field_section_size,
+                qpack_max_table_capacity,
+                qpack_blocked_streams,
+                h3_datagram,
+                connect_protocol_enabled,
+                raw,
+                ..
+            }) => ConnectionSettings {
+                max_field_section_size,
+                qpack_max_table_capacity,
+                qpack_blocked_streams,
+                h3_datagram,
+                connect_protocol_enabled,
+                raw,
+            },
+
+            _ => {
+                conn.close(
+                    true,
+                    Error::SettingsError.to_wire(),
+                    b"Invalid SETTINGS received with ALPS",
+                )?;
+
+                return Err(Error::SettingsError);
+            },
+        };
+
+        trace!("{} rx application settings {:?}", conn.trace_id(), settings);
+
+        self.set_peer_settings(conn, settings)
+    }
+
+    /// Applies the settings received from the peer.
+    fn set_peer_settings(
+        &mut self, conn: &mut super::Connection, settings: ConnectionSettings,
+    ) -> Result<()> {
+        // Allow the local encoder to use the dynamic table within the limits
+        // set by the peer's decoder.
+        self.qpack_encoder
+            .set_max_table_capacity(
+                settings.qpack_max_table_capacity.unwrap_or(0),
+            )
+            .map_err(|_| Error::InternalError)?;
+
+        self.qpack_encoder
+            .set_max_blocked_streams(settings.qpack_blocked_streams.unwrap_or(0));
+
+        if let Some(1) = settings.h3_datagram {
+            // The peer MUST have also enabled DATAGRAM with a TP
+            if conn.dgram_max_writable_len().is_none() {
+                conn.close(
+                    true,
+                    Error::SettingsError.to_wire(),
+                    b"H3_DATAGRAM sent with value 1 but max_datagram_frame_size TP not set.",
+                )?;
+
+                return Err(Error::SettingsError);
+            }
+        }
+
+        self.peer_settings = settings;
+
+        Ok(())
+    }
+
+    fn process_control_stream(
+        &mut self, conn: &mut super::Connection, stream_id: u64,
+    ) -> Result<(u64, Event)> {
+        if conn.stream_finished(stream_id) {
+            conn.close(
+                true,
+                Error::ClosedCriticalStream.to_wire(),
+                b"Critical stream closed.",
+            )?;
+
+            return Err(Error::ClosedCriticalStream);
+        }
+
+        match self.process_readable_stream(conn, stream_id, true) {
+            Ok(ev) => return Ok(ev),
+
+            Err(Error::Done) => (),
+
+            Err(e) => return Err(e),
+        };
+
+        if conn.stream_finished(stream_id) {
+            conn.close(
+                true,
+                Error::ClosedCriticalStream.to_wire(),
+                b"Critical stream closed.",
+            )?;
+
+            return Err(Error::ClosedCriticalStream);
+        }
+
+        Err(Error::Done)
+    }
+
+    /// Drops the state of a request stream that was reset by the peer before
+    /// its headers were received.
+    ///
+    /// The state is only dropped if nothing else can be done with the stream
+    /// locally, that is if a request or response was not started, or if it
+    /// was already completely sent.
+    fn collect_reset_stream(
+        &mut self, conn: &mut super::Connection, stream_id: u64,
+    ) {
+        let stream = match self.streams.get(&stream_id) {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        if stream.ty() != Some(stream::Type::Request) || stream.headers_received()
+        {
+            return;
+        }
+
+        let local_done = match conn.streams.get(stream_id) {
+            Some(s) => !stream.local_initialized() || s.send.is_fin(),
+
+            None => true,
+        };
+
+        if local_done {
+            trace!(
+                "{} dropping state of reset stream {}",
+                conn.trace_id(),
+                stream_id
+            );
+
+            self.streams.remove(&stream_id);
+        }
+    }
+
+    /// Drops the state of raw streams that were finished or reset by the
+    /// peer, once the application read all their data.
+    fn collect_raw_streams(&mut self, conn: &super::Connection) {
+        let streams = &mut self.streams;
+
+        self.raw_stream_ids.retain(|id| {
+            if conn.stream_finished(*id) && !conn.stream_readable(*id) {
+                streams.remove(id);
+
+                return false;
+            }
+
+            true
+        });
+    }
+
+    fn process_readable_stream(
+        &mut self, conn: &mut super::Connection, stream_id: u64, polling: bool,
+    ) -> Result<(u64, Event)> {
+        // Reject requests that were initiated after the GOAWAY frame.
+        if self.is_server &&
+            crate::stream::is_bidi(stream_id) &&
+            !self.is_request_allowed(stream_id)
+        {
+            let e = Error::RequestRejected.to_wire();
+
+            conn.stream_shutdown(stream_id, crate::Shutdown::Read, e)
+                .ok();
+            conn.stream_shutdown(stream_id, crate::Shutdown::Write, e)
+                .ok();
+
+            self.streams.remove(&stream_id);
+
+            return Err(Error::Done);
+        }
+
+        // Stop reading from streams whose headers are blocked, until the
+        // dynamic table entries they depend on are received.
+        if self.blocked_streams.contains_key(&stream_id) {
+            return Err(Error::Done);
+        }
+
+        self.streams
+            .entry(stream_id)
+            .or_insert_with(|| stream::Stream::new(stream_id, false));
+
+        if self.is_server && crate::stream::is_bidi(stream_id) {
+            self.largest_processed_request =
+                std::cmp::max(self.largest_processed_request, Some(stream_id));
+        }
+
+        // We need to get a fresh reference to the stream for each
+        // iteration, to avoid borrowing `self` for the entire duration
+        // of the loop, because we'll need to borrow it again in the
+        // `State::FramePayload` case below.
+        while let Some(stream) = self.streams.get_mut(&stream_id) {
+            match stream.state() {
+                stream::State::StreamType => {
+                    stream.try_fill_buffer(conn)?;
+
+                    let varint = match stream.try_consume_varint() {
+                        Ok(v) => v,
+
+                        Err(_) => continue,
+                    };
+
+                    let ty = match stream::Type::deserialize(varint)? {
+                        stream::Type::Unknown
+                            if self.raw_streams.contains(&varint) =>
+                            stream::Type::Raw,
+
+                        stream::Type::Unknown
+                            if self.extension_streams.contains(&varint) =>
+                            stream::Type::Extension,
+
+                        ty => ty,
+                    };
+
+                    if let Err(e) = stream.set_ty(ty) {
+                        conn.close(true, e.to_wire(), b"")?;
+                        return Err(e);
+                    }
+
+                    match &ty {
+                        stream::Type::Control => {
+                            // Only one control stream allowed.
+                            if self.peer_control_stream_id.is_some() {
+                                conn.close(
+                                    true,
+                                    Error::StreamCreationError.to_wire(),
+                                    b"Received multiple control streams",
+                                )?;
+
+                                return Err(Error::StreamCreationError);
+                            }
+
+                            trace!(
+                                "{} open peer's control stream {}",
+                                conn.trace_id(),
+                                stream_id
+                            );
+
+                            self.peer_control_stream_id = Some(stream_id);
+                        },
+
+                        stream::Type::Push => {
+                            // Only clients can receive push stream.
+                            if self.is_server {
+                                conn.close(
+                                    true,
+                                    Error::StreamCreationError.to_wire(),
+                                    b"Server received push stream.",
+                                )?;
+
+                                return Err(Error::StreamCreationError);
+                            }
+                        },
+
+                        stream::Type::QpackEncoder => {
+                            // Only one qpack encoder stream allowed.
+                            if self.peer_qpack_streams.encoder_stream_id.is_some()
+                            {
+                                conn.close(
+                                    true,
+                                    Error::StreamCreationError.to_wire(),
+                                    b"Received multiple QPACK encoder streams",
+                                )?;
+
+                                return Err(Error::StreamCreationError);
+                            }
+
+                            self.peer_qpack_streams.encoder_stream_id =
+                                Some(stream_id);
+                        },
+
+                        stream::Type::QpackDecoder => {
+                            // Only one qpack decoder allowed.
+                            if self.peer_qpack_streams.decoder_stream_id.is_some()
+                            {
+                                conn.close(
+                                    true,
+                                    Error::StreamCreationError.to_wire(),
+                                    b"Received multiple QPACK decoder streams",
+                                )?;
+
+                                return Err(Error::StreamCreationError);
+                            }
+
+                            self.peer_qpack_streams.decoder_stream_id =
+                                Some(stream_id);
+                        },
+
+                        stream::Type::Extension => {
+                            trace!(
+                                "{} open peer's extension stream {} type={}",
+                                conn.trace_id(),
+                                stream_id,
+                                varint
+                            );
+
+                            return Ok((stream_id, Event::ExtensionStream {
+                                stream_type: varint,
+                            }));
+                        },
+
+                        stream::Type::Raw => {
+                            trace!(
+                                "{} open peer's raw stream {} type={}",
+                                conn.trace_id(),
+                                stream_id,
+                                varint
+                            );
+
+                            self.raw_stream_ids.insert(stream_id);
+
+                            return Ok((stream_id, Event::RawStream {
+                                stream_type: varint,
+                            }));
+                        },
+
+                        stream::Type::Unknown => {
+                            // Unknown stream types are ignored.
+                            // TODO: we MAY send STOP_SENDING
+                        },
+
+                        stream::Type::Request => unreachable!(),
+                    }
+                },
+
+                stream::State::PushId => {
+                    stream.try_fill_buffer(conn)?;
+
+                    let varint = match stream.try_consume_varint() {
+                        Ok(v) => v,
+
+                        Err(_) => continue,
+                    };
+
+                    if let Err(e) = stream.set_push_id(varint) {
+                        conn.close(true, e.to_wire(), b"")?;
+                        return Err(e);
+                    }
+                },
+
+                stream::State::FrameType => {
+                    stream.try_fill_buffer(conn)?;
+
+                    let varint = match stream.try_consume_varint() {
+                        Ok(v) => v,
+
+                        Err(_) => continue,
+                    };
+
+                    // Request streams opened by the peer can also start with
+                    // a raw stream type instead of a frame.
+                    if crate::stream::is_bidi(stream_id) &&
+                        !crate::stream::is_local(stream_id, self.is_server) &&
+                        !stream.headers_received() &&
+                        self.raw_streams.contains(&varint)
+                    {
+                        trace!(
+                            "{} open peer's raw stream {} type={}",
+                            conn.trace_id(),
+                            stream_id,
+                            varint
+                        );
+
+                        stream.set_raw();
+
+                        self.raw_stream_ids.insert(stream_id);
+
+                        return Ok((stream_id, Event::RawStream {
+                            stream_type: varint,
+                        }));
+                    }
+
+                    match stream.set_frame_type(varint) {
+                        Err(Error::FrameUnexpected) => {
+                            let msg = format!("Unexpected frame type {}", varint);
+
+                            conn.close(
+                                true,
+                                Error::FrameUnexpected.to_wire(),
+                                msg.as_bytes(),
+                            )?;
+
+                            return Err(Error::FrameUnexpected);
+                        },
+
+                        Err(e) => {
+                            conn.close(
+                                true,
+                                e.to_wire(),
+                                b"Error handling frame.",
+                            )?;
+
+                            return Err(e);
+                        },
+
+                        _ => (),
+                    }
+                },
+
+                stream::State::FramePayloadLen => {
+                    stream.try_fill_buffer(conn)?;
+
+                    let varint = match stream.try_consume_varint() {
+                        Ok(v) => v,
+
+                        Err(_) => continue,
+                    };
+
+                    // Reject header sections that are too large before
+                    // buffering them.
+                    let max_header_block_len = self
+                        .local_settings
+                        .max_field_section_size
+                        .map(|v| v.saturating_add(MAX_HEADER_BLOCK_PREFIX_LEN));
+
+                    if stream.frame_type() == Some(frame::HEADERS_FRAME_TYPE_ID) &&
+                        matches!(max_header_block_len, Some(max) if varint > max)
+                    {
+                        trace!(
+                            "{} header block on stream {} is too large",
+                            conn.trace_id(),
+                            stream_id
+                        );
+
+                        self.reset_stream(conn, stream_id, Error::ExcessiveLoad)?;
+
+                        let e = Error::ExcessiveLoad.to_wire();
+
+                        return Ok((stream_id, Event::Reset(e)));
+                    }
+
+                    if let Err(e) = stream.set_frame_payload_len(varint) {
+                        conn.close(true, e.to_wire(), b"")?;
+                        return Err(e);
+                    }
+                },
+
+                stream::State::FramePayload => {
+                    // Do not emit events when not polling.
+                    if !polling {
+                        break;
+                    }
+
+                    stream.try_fill_buffer(conn)?;
+
+                    let frame = match stream.try_consume_frame() {
+                        Ok(frame) => frame,
+
+                        Err(Error::Done) => return Err(Error::Done),
+
+                        Err(e) => {
+                            conn.close(
+                                true,
+                                e.to_wire(),
+                                b"Error handling frame.",
+                            )?;
+
+                            return Err(e);
+                        },
+                    };
+
+                    match self.process_frame(conn, stream_id, frame) {
+                        Ok(ev) => return Ok(ev),
+
+                        Err(Error::Done) => (),
+
+                        Err(e) => return Err(e),
+                    };
+                },
+
+                stream::State::Data => {
+                    // Do not emit events when not polling.
+                    if !polling {
+                        break;
+                    }
+
+                    if !stream.try_trigger_data_event() {
+                        break;
+                    }
+
+                    return Ok((stream_id, Event::Data));
+                },
+
+                stream::State::QpackInstruction => {
+                    let mut d = [0; 4096];
+
+                    loop {
+                        let (len, _) = conn.stream_recv(stream_id, &mut d)?;
+
+                        let (res, e) = if Some(stream_id) ==
+                            self.peer_qpack_streams.encoder_stream_id
+                        {
+                            (
+                                self.qpack_decoder.control(&d[..len]),
+                                Error::QpackEncoderStreamError,
+                            )
+                        } else {
+                            (
+                                self.qpack_encoder.control(&d[..len]),
+                                Error::QpackDecoderStreamError,
+                            )
+                        };
+
+                        if res.is_err() {
+                            conn.close(
+                                true,
+                                e.to_wire(),
+                                b"Invalid QPACK instruction",
+                            )?;
+
+                            return Err(e);
+                        }
+                    }
+                },
+
+                stream::State::Drain => {
+                    // Discard incoming data on the stream.
+                    conn.stream_shutdown(
+                        stream_id,
+                        crate::Shutdown::Read,
+                        0x100,
+                    )?;
+
+                    break;
+                },
+
+                stream::State::Raw => break,
+
+                stream::State::Finished => break,
+            }
+        }
+
+        Err(Error::Done)
+    }
+
+    fn process_finished_stream(&mut self, stream_id: u64) {
+        // The stream is only finished once its headers are unblocked.
+        if self.blocked_streams.contains_key(&stream_id) {
+            return;
+        }
+
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        if stream.state() == stream::State::Finished {
+            return;
+        }
+
+        match stream.ty() {
+            Some(stream::Type::Request) |
+            Some(stream::Type::Push) |
+            Some(stream::Type::Extension) => {
+                stream.finished();
+
+                self.finished_streams.push_back(stream_id);
+            },
+
+            _ => (),
+        };
+    }
+
+    /// Checks that a PRIORITY_UPDATE frame received on the given stream is
+    /// allowed, and closes the connection otherwise.
+    fn check_priority_update(
+        &mut self, conn: &mut super::Connection, stream_id: u64,
+    ) -> Result<()> {
+        if Some(stream_id) != self.peer_control_stream_id {
+            conn.close(
+                true,
+                Error::FrameUnexpected.to_wire(),
+                b"PRIORITY_UPDATE received on non-control stream",
+            )?;
+
+            return Err(Error::FrameUnexpected);
+        }
+
+        if !self.is_server {
+            conn.close(
+                true,
+                Error::FrameUnexpected.to_wire(),
+                b"PRIORITY_UPDATE received by client",
+            )?;
+
+            return Err(Error::FrameUnexpected);
+        }
+
+        Ok(())
+    }
+
+    /// Decodes a header block received on the given stream, and closes the
+    /// connection if it is invalid.
+    ///
+    /// `None` is returned if the header block depends on dynamic table entries
+    /// that were not received yet. If the header block is too large, the
+    /// stream is reset and the [`ExcessiveLoad`] error is returned instead.
+    ///
+    /// [`ExcessiveLoad`]: enum.Error.html#variant.ExcessiveLoad
+    fn decode_header_block(
+        &mut self, conn: &mut super::Connection, stream_id: u64,
+        header_block: &[u8],
+    ) -> Result<Option<Vec<Header>>> {
+        // Use "infinite" as default value for max_field_section_size if
+        // it is not configured by the application.
+        let max_size = self
+            .local_settings
+            .max_field_section_size
+            .unwrap_or(std::u64::MAX);
+
+        match self.qpack_decoder.decode_on_stream(
+            stream_id,
+            header_block,
+            max_size,
+        ) {
+            Ok(v) => Ok(Some(v)),
+
+            Err(qpack::Error::Blocked) => Ok(None),
+
+            Err(qpack::Error::HeaderListTooLarge) => {
+                trace!(
+                    "{} header block on stream {} is too large",
+                    conn.trace_id(),
+                    stream_id
+                );
+
+                self.reset_stream(conn, stream_id, Error::ExcessiveLoad)?;
+
+                Err(Error::ExcessiveLoad)
+            },
+
+            Err(_) => {
+                let e = Error::QpackDecompressionFailed;
+
+                conn.close(true, e.to_wire(), b"Error parsing headers.")?;
+
+                Err(e)
+            },
+        }
+    }
+
+    /// Resets both sides of a stream that carried a malformed or excessive
+    /// message, and drops its state.
+    fn reset_stream(
+        &mut self, conn: &mut super::Connection, stream_id: u64, error: Error,
+    ) -> Result<()> {
+        let e = error.to_wire();
+
+        conn.stream_shutdown(stream_id, crate::Shutdown::Read, e)
+            .ok();
+        conn.stream_shutdown(stream_id, crate::Shutdown::Write, e)
+            .ok();
+
+        self.qpack_decoder
+            .cancel_stream(stream_id)
+            .map_err(|_| Error::InternalError)?;
+
+        self.streams.remove(&stream_id);
+        self.blocked_streams.remove(&stream_id);
+
+        Ok(())
+    }
+
+    /// Processes the headers decoded from a HEADERS frame.
+    fn process_headers(
+        &mut self, conn: &mut super::Connection, stream_id: u64,
+        headers: Vec<Header>,
+    ) -> Result<(u64, Event)> {
+        let has_body = !conn.stream_finished(stream_id);
+
+        // Informational responses are followed by more headers, so
+        // only the ones after the final headers are trailers.
+        let informational = !self.is_server && is_informational(&headers);
+
+        let (first_headers, trailers) = match self.streams.get_mut(&stream_id) {
+            Some(s) if s.headers_received() => {
+                s.set_trailers_received();
+
+                (false, true)
+            },
+
+            Some(s) => {
+                if !informational {
+                    s.set_headers_received();
+                }
+
+                (true, false)
+            },
+
+            None => (false, false),
+        };
+
+        if self.strict_headers &&
+            validate_field_section(&headers, self.is_server, trailers).is_err()
+        {
+            trace!(
+                "{} malformed header section on stream {}",
+                conn.trace_id(),
+                stream_id
+            );
+
+            self.reset_stream(conn, stream_id, Error::MessageError)?;
+
+            let e = Error::MessageError.to_wire();
+
+            return Ok((stream_id, Event::Reset(e)));
+        }
+
+        if trailers {
+            return Ok((stream_id, Event::Trailers { list: headers }));
+        }
+
+        // Reject malformed CONNECT requests by resetting the stream,
+        // without notifying the application.
+        if self.is_server &&
+            first_headers &&
+            validate_connect_request(
+                &headers,
+                self.local_settings.connect_protocol_enabled == Some(1),
+            )
+            .is_err()
+        {
+            self.reset_stream(conn, stream_id, Error::MessageError)?;
+
+            return Err(Error::Done);
+        }
+
+        // Apply the priority signaled by the client to the response.
+        // A PRIORITY_UPDATE frame received before the request takes
+        // precedence over the header field.
+        if self.is_server && first_headers {
+            let priority = match self.pending_priority_updates.remove(&stream_id)
+            {
+                Some(v) => Some(v),
+
+                None => headers
+                    .iter()
+                    .find(|h| h.name() == b"priority")
+                    .map(|h| Priority::parse(h.value())),
+            };
+
+            if let Some(priority) = priority {
+                self.set_peer_priority(conn, stream_id, priority)?;
+            }
+        }
+
+        Ok((stream_id, Event::Headers {
+            list: headers,
+            has_body,
+        }))
+    }
+
+    /// Processes header blocks that were waiting on dynamic table updates.
+    fn process_blocked_streams(
+        &mut self, conn: &mut super::Connection,
+    ) -> Result<(u64, Event)> {
+        // The header blocks of streams that were reset by the peer, or that
+        // the application stopped reading, will never be decoded, so the
+        // encoder is told not to wait for them anymore.
+        let cancelled: Vec<u64> = self
+            .blocked_streams
+            .keys()
+            .copied()
+            .filter(|id| match conn.streams.get(*id) {
+                Some(s) => s.recv.is_reset() || s.recv.is_shutdown(),
+
+                None => true,
+            })
+            .collect();
+
+        for stream_id in cancelled {
+            trace!(
+                "{} cancelling blocked stream {}",
+                conn.trace_id(),
+                stream_id
+            );
+
+            self.blocked_streams.remove(&stream_id);
+
+            self.qpack_decoder
+                .cancel_stream(stream_id)
+                .map_err(|_| Error::InternalError)?;
+        }
+
+        let mut stream_ids: Vec<u64> =
+            self.blocked_streams.keys().copied().collect();
+
+        stream_ids.sort_unstable();
+
+        for stream_id in stream_ids {
+            let header_block = match self.blocked_streams.get(&stream_id) {
+                Some(v) => v.clone(),
+
+                None => continue,
+            };
+
+            let headers =
+                match self.decode_header_block(conn, stream_id, &header_block) {
+                    Ok(Some(v)) => v,
+
+                    Ok(None) => continue,
+
+                    Err(Error::ExcessiveLoad) => {
+                        let e = Error::ExcessiveLoad.to_wire();
+
+                        return Ok((stream_id, Event::Reset(e)));
+                    },
+
+                    Err(e) => return Err(e),
+                };
+
+            self.blocked_streams.remove(&stream_id);
+
+            let ev = self.process_headers(conn, stream_id, headers);
+
+            // The stream might have been read completely before it was
+            // unblocked.
+            if conn.stream_finished(stream_id) {
+                self.process_finished_stream(stream_id);
+            }
+
+            match ev {
+                Err(Error::Done) => (),
+
+                _ => return ev,
+            }
+        }
+
+        Err(Error::Done)
+    }
+
+    fn process_frame(
+        &mut self, conn: &mut super::Connection, stream_id: u64,
+        frame: frame::Frame,
+    ) -> Result<(u64, Event)> {
+        trace!(
+            "{} rx frm {:?} stream={}",
+            conn.trace_id(),
+            frame,
+            stream_id
+        );
+
+        match frame {
+            frame::Frame::Settings {
+                max_field_section_size,
+                qpack_max_table_capacity,
+                qpack_blocked_streams,
+                h3_datagram,
+                connect_protocol_enabled,
+                raw,
+                ..
+            } => {
+                let settings = ConnectionSettings {
+                    max_field_section_size,
+                    qpack_max_table_capacity,
+                    qpack_blocked_streams,
+                    h3_datagram,
+                    connect_protocol_enabled,
+                    raw,
+                };
+
+                self.set_peer_settings(conn, settings)?;
+
+                return Ok((stream_id, Event::Settings));
+            },
+
+            frame::Frame::Headers { header_block } => {
+                if Some(stream_id) == self.peer_control_stream_id {
+                    conn.close(
+                        true,
+                        Error::FrameUnexpected.to_wire(),
+                        b"HEADERS received on control stream",
+                    )?;
+
+                    return Err(Error::FrameUnexpected);
+                }
+
+                let headers = match self.decode_header_block(
+                    conn,
+                    stream_id,
+                    &header_block,
+                ) {
+                    Ok(Some(v)) => v,
+
+                    // Wait for the dynamic table entries the header block
+                    // depends on to be received on the encoder stream.
+                    Ok(None) => {
+                        let max_blocked = self
+                            .local_settings
+                            .qpack_blocked_streams
+                            .unwrap_or(0);
+
+                        if self.blocked_streams.len() as u64 >= max_blocked {
+                            let e = Error::QpackDecompressionFailed;
+
+                            conn.close(
+                                true,
+                                e.to_wire(),
+                                b"Too many blocked streams.",
+                            )?;
+
+                            return Err(e);
+                        }
+
+                        self.blocked_streams.insert(stream_id, header_block);
+
+                        return Err(Error::Done);
+                    },
+
+                    // The stream was reset.
+                    Err(Error::ExcessiveLoad) => {
+                        let e = Error::ExcessiveLoad.to_wire();
+
+                        return Ok((stream_id, Event::Reset(e)));
+                    },
+
+                    Err(e) => return Err(e),
+                };
+
+                return self.process_headers(conn, stream_id, headers);
+            },
+
+            frame::Frame::Data { .. } => {
+                if Some(stream_id) == self.peer_control_stream_id {
+                    conn.close(
+                        true,
+                        Error::FrameUnexpected.to_wire(),
+                        b"DATA received on control stream",
+                    )?;
+
+                    return Err(Error::FrameUnexpected);
+                }
+
+                // Do nothing. The Data event is returned separately.
+            },
+
+            frame::Frame::GoAway { id } => {
+                if Some(stream_id) != self.peer_control_stream_id {
+                    conn.close(
+                        true,
+                        Error::FrameUnexpected.to_wire(),
+                        b"GOAWAY received on non-control stream",
+                    )?;
+
+                    return Err(Error::FrameUnexpected);
+                }
+
+                if !self.is_server &&
+                    (!crate::stream::is_bidi(id) ||
+                        !crate::stream::is_local(id, false))
+                {
+                    conn.close(
+                        true,
+                        Error::IdError.to_wire(),
+                        b"GOAWAY received with ID of non-request stream",
+                    )?;
+
+                    return Err(Error::IdError);
+                }
+
+                if let Some(received_id) = self.peer_goaway_id {
+                    if id > received_id {
+                        conn.close(
+                            true,
+                            Error::IdError.to_wire(),
+                            b"GOAWAY received with ID larger than previously received",
+                        )?;
+
+                        return Err(Error::IdError);
+                    }
+                }
+
+                self.peer_goaway_id = Some(id);
+
+                return Ok((id, Event::GoAway));
+            },
+
+            frame::Frame::MaxPushId { push_id } => {
+                if Some(stream_id) != self.peer_control_stream_id {
+                    conn.close(
+                        true,
+                        Error::FrameUnexpected.to_wire(),
+                        b"MAX_PUSH_ID received on non-control stream",
+                    )?;
+
+                    return Err(Error::FrameUnexpected);
+                }
+
+                if !self.is_server {
+                    conn.close(
+                        true,
+                        Error::FrameUnexpected.to_wire(),
+                        b"MAX_PUSH_ID received by client",
+                    )?;
+
+                    return Err(Error::FrameUnexpected);
+                }
+
+                if push_id < self.max_push_id {
+                    conn.close(
+                        true,
+                        Error::IdError.to_wire(),
+                        b"MAX_PUSH_ID reduced limit",
+                    )?;
+
+                    return Err(Error::IdError);
+                }
+
+                self.max_push_id = push_id;
+            },
+
+            frame::Frame::PushPromise { .. } => {
+                if self.is_server {
+                    conn.close(
+                        true,
+                        Error::FrameUnexpected.to_wire(),
+                        b"PUSH_PROMISE received by server",
+                    )?;
+
+                    return Err(Error::FrameUnexpected);
+                }
+
+                if stream_id % 4 != 0 {
+                    conn.close(
+                        true,
+                        Error::FrameUnexpected.to_wire(),
+                        b"PUSH_PROMISE received on non-request stream",
+                    )?;
+
+                    return Err(Error::FrameUnexpected);
+                }
+
+                // TODO: implement more checks and PUSH_PROMISE event
+            },
+
+            frame::Frame::CancelPush { .. } => {
+                if Some(stream_id) != self.peer_control_stream_id {
+                    conn.close(
+                        true,
+                        Error::FrameUnexpected.to_wire(),
+                        b"CANCEL_PUSH received on non-control stream",
+                    )?;
+
+                    return Err(Error::FrameUnexpected);
+                }
+
+                // TODO: implement CANCEL_PUSH frame
+            },
+
+            frame::Frame::Origin { origins } => {
+                // Only servers send ORIGIN frames, so ignore any sent by
+                // clients.
+                if self.is_server {
+                    return Err(Error::Done);
+                }
+
+                for origin in origins {
+                    if self.peer_origins.len() >= MAX_PEER_ORIGINS {
+                        break;
+                    }
+
+                    if !self.peer_origins.contains(&origin) {
+                        self.peer_origins.push(origin);
+                    }
+                }
+            },
+
+            frame::Frame::PriorityUpdateRequest {
+                prioritized_element_id,
+                priority_field_value,
+            } => {
+                self.check_priority_update(conn, stream_id)?;
+
+                // Only client-initiated bidirectional streams carry requests,
+                // and the client can't refer to streams it's not allowed to
+                // open.
+                let max_stream_id = conn.streams.max_streams_bidi() * 4;
+
+                if !crate::stream::is_bidi(prioritized_element_id) ||
+                    !crate::stream::is_local(prioritized_element_id, false) ||
+                    prioritized_element_id >= max_stream_id
+                {
+                    conn.close(
+                        true,
+                        Error::IdError.to_wire(),
+                        b"PRIORITY_UPDATE received with invalid stream ID",
+                    )?;
+
+                    return Err(Error::IdError);
+                }
+
+                let priority = Priority::parse(&priority_field_value);
+
+                let headers_received = self
+                    .streams
+                    .get(&prioritized_element_id)
+                    .map(|s| s.headers_received());
+
+                match headers_received {
+                    Some(true) => {
+                        self.set_peer_priority(
+                            conn,
+                            prioritized_element_id,
+                            priority,
+                        )?;
+
+                        return Ok((
+                            prioritized_element_id,
+                            Event::PriorityUpdate {
+                                stream_id: prioritized_element_id,
+                                priority,
+                            },
+                        ));
+                    },
+
+                    // Requests that were already completed are ignored.
+                    _ if conn.streams.is_collected(prioritized_element_id) => (),
+
+                    // The request hasn't been received yet, so buffer the
+                    // update until its headers are.
+                    _ => {
+                        self.pending_priority_updates
+                            .insert(prioritized_element_id, priority);
+                    },
+                }
+            },
+
+            frame::Frame::PriorityUpdatePush {
+                prioritized_element_id,
+                ..
+            } => {
+                self.check_priority_update(conn, stream_id)?;
+
+                if prioritized_element_id >= self.max_push_id {
+                    conn.close(
+                        true,
+                        Error::IdError.to_wire(),
+                        b"PRIORITY_UPDATE received with invalid push ID",
+                    )?;
+
+                    return Err(Error::IdError);
+                }
+
+                // Server push is not supported, so there's nothing to
+                // prioritize.
+            },
+
+            frame::Frame::Unknown { raw_type, payload } => {
+                if self.extension_frames.contains(&raw_type) {
+                    return Ok((stream_id, Event::ExtensionFrame {
+                        frame_type: raw_type,
+                        payload,
+                    }));
+                }
+            },
+        }
+
+        Err(Error::Done)
+    }
+}
+
+/// Sends as much of the given QPACK instructions as possible on a stream, and
+/// returns how many bytes were written.
+fn send_qpack_stream_data(
+    conn: &mut super::Connection, stream_id: u64, buf: &[u8],
+) -> Result<usize> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+
+    match conn.stream_send(stream_id, buf, false) {
+        Ok(v) => Ok(v),
+
+        Err(crate::Error::Done) => Ok(0),
+
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Generates an HTTP/3 GREASE variable length integer.
+///
+/// A random value is generated, unless `index` is set and forces a specific
+/// one.
+fn grease_value(index: Option<u64>) -> u64 {
+    super::grease_value(index, 33)
+}
+
+#[doc(hidden)]
+pub mod testing {
+    use super::*;
+
+    use crate::testing;
+
+    /// Session is an HTTP/3 test helper structure. It holds a client, server
+    /// and pipe that allows them to communicate.
+    ///
+    /// `default()` creates a session with some sensible default
+    /// configuration. `with_configs()` allows for providing a specific
+    /// configuration.
+    ///
+    /// `handshake()` performs all the steps needed to establish an HTTP/3
+    /// connection.
+    ///
+    /// Some utility functions are provided that make it less verbose to send
+    /// request, responses and individual headers. The full quiche API remains
+    /// available for any test that need to do unconventional things (such as
+    /// bad behaviour that triggers errors).
+    pub struct Session {
+        pub pipe: testing::Pipe,
+        pub client: Connection,
+        pub server: Connection,
+    }
+
+    impl Session {
+        pub fn default() -> Result<Session> {
+            let mut config = crate::Config::new(crate::PROTOCOL_VERSION)?;
+            config.load_cert_chain_from_pem_file("examples/cert.crt")?;
+            config.load_priv_key_from_pem_file("examples/cert.key")?;
+            config.set_application_protos(b"\x02h3")?;
+            config.set_initial_max_data(1500);
+            config.set_initial_max_stream_data_bidi_local(150);
+            config.set_initial_max_stream_data_bidi_remote(150);
+            config.set_initial_max_stream_data_uni(150);
+            config.set_initial_max_streams_bidi(5);
+            config.set_initial_max_streams_uni(5);
+            config.verify_peer(false);
+            config.enable_dgram(true, 3, 3);
+            config.set_ack_delay_exponent(8);
+
+            let h3_config = Config::new()?;
+            Session::with_configs(&mut config, &h3_config)
+        }
+
+        pub fn with_configs(
+            config: &mut crate::Config, h3_config: &Config,
+        ) -> Result<Session> {
+            let pipe = testing::Pipe::with_config(config)?;
+            let client_dgram = pipe.client.dgram_enabled();
+            let server_dgram = pipe.server.dgram_enabled();
+            Ok(Session {
+                pipe,
+                client: Connection::new(h3_config, false, client_dgram)?,
+                server: Connection::new(h3_config, true, server_dgram)?,
+            })
+        }
+
+        /// Do the HTTP/3 handshake so both ends are in sane initial state.
+        pub fn handshake(&mut self) -> Result<()> {
+            self.pipe.handshake()?;
+
+            // Client streams.
+            self.client.send_settings(&mut self.pipe.client)?;
+            self.pipe.advance().ok();
+
+            self.client
+                .open_qpack_encoder_stream(&mut self.pipe.client)?;
+            self.pipe.advance().ok();
+
+            self.client
+                .open_qpack_decoder_stream(&mut self.pipe.client)?;
+            self.pipe.advance().ok();
+
+            if self.pipe.client.grease && self.client.grease_streams {
+                self.client.open_grease_stream(&mut self.pipe.client)?;
+            }
+
+            self.pipe.advance().ok();
+
+            // Server streams.
+            self.server.send_settings(&mut self.pipe.server)?;
+            self.pipe.advance().ok();
+
+            self.server
+                .open_qpack_encoder_stream(&mut self.pipe.server)?;
+            self.pipe.advance().ok();
+
+            self.server
+                .open_qpack_decoder_stream(&mut self.pipe.server)?;
+            self.pipe.advance().ok();
+
+            if self.pipe.server.grease && self.server.grease_streams {
+                self.server.open_grease_stream(&mut self.pipe.server)?;
+            }
+
+            self.advance().ok();
+
+            while self.client.poll(&mut self.pipe.client).is_ok() {
+                // Do nothing.
+            }
+
+            while self.server.poll(&mut self.pipe.server).is_ok() {
+                // Do nothing.
+            }
+
+            Ok(())
+        }
+
+        /// Advances the session pipe over the buffer.
+        pub fn advance(&mut self) -> crate::Result<()> {
+            self.pipe.advance()
+        }
+
+        /// Polls the client for events.
+        pub fn poll_client(&mut self) -> Result<(u64, Event)> {
+            self.client.poll(&mut self.pipe.client)
+        }
+
+        /// Polls the server for events.
+        pub fn poll_server(&mut self) -> Result<(u64, Event)> {
+            self.server.poll(&mut self.pipe.server)
+        }
+
+        /// Sends a request from client with default headers.
+        ///
+        /// On success it returns the newly allocated stream and the headers.
+        pub fn send_request(&mut self, fin: bool) -> Result<(u64, Vec<Header>)> {
+            let req = vec![
+                Header::new(b":method", b"GET"),
+                Header::new(b":scheme", b"https"),
+                Header::new(b":authority", b"quic.tech"),
+                Header::new(b":path", b"/test"),
+                Header::new(b"user-agent", b"quiche-test"),
+            ];
+
+            let stream =
+                self.client.send_request(&mut self.pipe.client, &req, fin)?;
+
+            self.advance().ok();
+
+            Ok((stream, req))
+        }
+
+        /// Sends a response from server with default headers.
+        ///
+        /// On success it returns the headers.
+        pub fn send_response(
+            &mut self, stream: u64, fin: bool,
+        ) -> Result<Vec<Header>> {
+            let resp = vec![
+                Header::new(b":status", b"200"),
+                Header::new(b"server", b"quiche-test"),
+            ];
+
+            self.server.send_response(
+                &mut self.pipe.server,
+                stream,
+                &resp,
+                fin,
+            )?;
+
+            self.advance().ok();
+
+            Ok(resp)
+        }
+
+        /// Sends some default payload from client.
+        ///
+        /// On success it returns the payload.
+        pub fn send_body_client(
+            &mut self, stream: u64, fin: bool,
+        ) -> Result<Vec<u8>> {
+            let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
+            self.client
+                .send_body(&mut self.pipe.client, stream, &bytes, fin)?;
+
+            self.advance().ok();
+
+            Ok(bytes)
+        }
+
+        /// Fetches DATA payload from the server.
+        ///
+        /// On success it returns the number of bytes received.
+        pub fn recv_body_client(
+            &mut self, stream: u64, buf: &mut [u8],
+        ) -> Result<usize> {
+            self.client.recv_body(&mut self.pipe.client, stream, buf)
+        }
+
+        /// Sends some default payload from server.
+        ///
+        /// On success it returns the payload.
+        pub fn send_body_server(
+            &mut self, stream: u64, fin: bool,
+        ) -> Result<Vec<u8>> {
+            let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
+            self.server
+                .send_body(&mut self.pipe.server, stream, &bytes, fin)?;
+
+            self.advance().ok();
+
+            Ok(bytes)
+        }
+
+        /// Fetches DATA payload from the client.
+        ///
+        /// On success it returns the number of bytes received.
+        pub fn recv_body_server(
+            &mut self, stream: u64, buf: &mut [u8],
+        ) -> Result<usize> {
+            self.server.recv_body(&mut self.pipe.server, stream, buf)
+        }
+
+        /// Sends a single HTTP/3 frame from the client.
+        pub fn send_frame_client(
+            &mut self, frame: frame::Frame, stream_id: u64, fin: bool,
+        ) -> Result<()> {
+            let mut d = [42; 65535];
+
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+
+            frame.to_bytes(&mut b)?;
+
+            let off = b.off();
+            self.pipe.client.stream_send(stream_id, &d[..off], fin)?;
+
+            self.advance().ok();
+
+            Ok(())
+        }
+
+        /// Send an HTTP/3 DATAGRAM with default data from the client.
+        ///
+        /// On success it returns the data.
+        pub fn send_dgram_client(&mut self, flow_id: u64) -> Result<Vec<u8>> {
+            let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
+            self.client
+                .send_dgram(&mut self.pipe.client, flow_id, &bytes)?;
+
+            self.advance().ok();
+
+            Ok(bytes)
+        }
+
+        /// Receives an HTTP/3 DATAGRAM from the server.
+        ///
+        /// On success it returns the DATAGRAM length, flow ID and flow ID
+        /// length.
+        pub fn recv_dgram_client(
+            &mut self, buf: &mut [u8],
+        ) -> Result<(usize, u64, usize)> {
+            self.client.recv_dgram(&mut self.pipe.client, buf)
+        }
+
+        /// Send an HTTP/3 DATAGRAM with default data from the server
+        ///
+        /// On success it returns the data.
+        pub fn send_dgram_server(&mut self, flow_id: u64) -> Result<Vec<u8>> {
+            let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
+            self.server
+                .send_dgram(&mut self.pipe.server, flow_id, &bytes)?;
+
+            self.advance().ok();
+
+            Ok(bytes)
+        }
+
+        /// Receives an HTTP/3 DATAGRAM from the client.
+        ///
+        /// On success it returns the DATAGRAM length, flow ID and flow ID
+        /// length.
+        pub fn recv_dgram_server(
+            &mut self, buf: &mut [u8],
+        ) -> Result<(usize, u64, usize)> {
+            self.server.recv_dgram(&mut self.pipe.server, buf)
+        }
+
+        /// Sends a single HTTP/3 frame from the server.
+        pub fn send_frame_server(
+            &mut self, frame: frame::Frame, stream_id: u64, fin: bool,
+        ) -> Result<()> {
             let mut d = [42; 65535];
 
-            let mut b = octets::OctetsMut::with_slice(&mut d);
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+
+            frame.to_bytes(&mut b)?;
+
+            let off = b.off();
+            self.pipe.server.stream_send(stream_id, &d[..off], fin)?;
+
+            self.advance().ok();
+
+            Ok(())
+        }
+
+        /// Sends an arbitrary buffer of HTTP/3 stream data from the client.
+        pub fn send_arbitrary_stream_data_client(
+            &mut self, data: &[u8], stream_id: u64, fin: bool,
+        ) -> Result<()> {
+            self.pipe.client.stream_send(stream_id, data, fin)?;
+
+            self.advance().ok();
+
+            Ok(())
+        }
+
+        /// Sends an arbitrary buffer of HTTP/3 stream data from the server.
+        pub fn send_arbitrary_stream_data_server(
+            &mut self, data: &[u8], stream_id: u64, fin: bool,
+        ) -> Result<()> {
+            self.pipe.server.stream_send(stream_id, data, fin)?;
+
+            self.advance().ok();
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::testing::*;
+
+    #[test]
+    /// Make sure that random GREASE values is within the specified limit.
+    fn grease_value_in_varint_limit() {
+        assert!(grease_value(None) < 2u64.pow(62) - 1);
+        assert!(grease_value(Some(crate::MAX_GREASE_INDEX)) < 2u64.pow(62) - 1);
+    }
+
+    #[test]
+    /// Send a request with no body, get a response with no body.
+    fn request_no_body_response_no_body() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        assert_eq!(stream, 0);
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        let resp = s.send_response(stream, true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Send a request with no body, get a response with one DATA frame.
+    fn request_no_body_response_one_chunk() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+        assert_eq!(stream, 0);
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        let resp = s.send_response(stream, false).unwrap();
+
+        let body = s.send_body_server(stream, true).unwrap();
+
+        let mut recv_buf = vec![0; body.len()];
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+
+        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
+
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Send a request with no body, get a response with multiple DATA frames.
+    fn request_no_body_response_many_chunks() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        let total_data_frames = 4;
+
+        let resp = s.send_response(stream, false).unwrap();
+
+        for _ in 0..total_data_frames - 1 {
+            s.send_body_server(stream, false).unwrap();
+        }
+
+        let body = s.send_body_server(stream, true).unwrap();
+
+        let mut recv_buf = vec![0; body.len()];
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+
+        for _ in 0..total_data_frames {
+            assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
+        }
+
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Send a request with one DATA frame, get a response with no body.
+    fn request_one_chunk_response_no_body() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        let body = s.send_body_client(stream, true).unwrap();
+
+        let mut recv_buf = vec![0; body.len()];
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        let resp = s.send_response(stream, true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+    }
+
+    #[test]
+    /// Send a request with multiple DATA frames, get a response with no body.
+    fn request_many_chunks_response_no_body() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        let total_data_frames = 4;
+
+        for _ in 0..total_data_frames - 1 {
+            s.send_body_client(stream, false).unwrap();
+        }
+
+        let body = s.send_body_client(stream, true).unwrap();
+
+        let mut recv_buf = vec![0; body.len()];
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        for _ in 0..total_data_frames {
+            assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
+        }
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        let resp = s.send_response(stream, true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+    }
+
+    #[test]
+    /// Send a request with multiple DATA frames, get a response with one DATA
+    /// frame.
+    fn many_requests_many_chunks_response_one_chunk() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let mut reqs = Vec::new();
+
+        let (stream1, req1) = s.send_request(false).unwrap();
+        assert_eq!(stream1, 0);
+        reqs.push(req1);
+
+        let (stream2, req2) = s.send_request(false).unwrap();
+        assert_eq!(stream2, 4);
+        reqs.push(req2);
+
+        let (stream3, req3) = s.send_request(false).unwrap();
+        assert_eq!(stream3, 8);
+        reqs.push(req3);
+
+        let body = s.send_body_client(stream1, false).unwrap();
+        s.send_body_client(stream2, false).unwrap();
+        s.send_body_client(stream3, false).unwrap();
+
+        let mut recv_buf = vec![0; body.len()];
+
+        // Reverse order of writes.
+
+        s.send_body_client(stream3, true).unwrap();
+        s.send_body_client(stream2, true).unwrap();
+        s.send_body_client(stream1, true).unwrap();
+
+        for _ in 0..reqs.len() {
+            let (stream, ev) = s.poll_server().unwrap();
+            let ev_headers = Event::Headers {
+                list: reqs[(stream / 4) as usize].clone(),
+                has_body: true,
+            };
+            assert_eq!(ev, ev_headers);
+            assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+            assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
+            assert_eq!(s.poll_client(), Err(Error::Done));
+
+            assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
+            assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        }
+
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        let mut resps = Vec::new();
+
+        let resp1 = s.send_response(stream1, true).unwrap();
+        resps.push(resp1);
+
+        let resp2 = s.send_response(stream2, true).unwrap();
+        resps.push(resp2);
+
+        let resp3 = s.send_response(stream3, true).unwrap();
+        resps.push(resp3);
+
+        for _ in 0..resps.len() {
+            let (stream, ev) = s.poll_client().unwrap();
+            let ev_headers = Event::Headers {
+                list: resps[(stream / 4) as usize].clone(),
+                has_body: false,
+            };
+            assert_eq!(ev, ev_headers);
+            assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        }
+
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Send a request with no body, get a response with one DATA frame and an
+    /// empty FIN after reception from the client.
+    fn request_no_body_response_one_chunk_empty_fin() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        let resp = s.send_response(stream, false).unwrap();
+
+        let body = s.send_body_server(stream, false).unwrap();
+
+        let mut recv_buf = vec![0; body.len()];
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+
+        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
+
+        assert_eq!(s.pipe.server.stream_send(stream, &[], true), Ok(0));
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Try to send DATA frames before HEADERS.
+    fn body_response_before_headers() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+        assert_eq!(stream, 0);
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        assert_eq!(
+            s.send_body_server(stream, true),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Send a request and a response with trailers.
+    fn trailers() {
+        let mut buf = [0; 65535];
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        let body = s.send_body_client(stream, false).unwrap();
+
+        let trailers = vec![Header::new(b"grpc-status", b"0")];
+
+        s.client
+            .send_trailers(&mut s.pipe.client, stream, &trailers)
+            .unwrap();
+        s.advance().ok();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        let ev_trailers = Event::Trailers {
+            list: trailers.clone(),
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_server(stream, &mut buf), Ok(body.len()));
+        assert_eq!(s.poll_server(), Ok((stream, ev_trailers.clone())));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        let resp = s.send_response(stream, false).unwrap();
+
+        s.server
+            .send_trailers(&mut s.pipe.server, stream, &trailers)
+            .unwrap();
+        s.advance().ok();
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, ev_trailers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Try to send trailers when they are not allowed.
+    fn send_trailers_invalid() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let trailers = vec![Header::new(b"grpc-status", b"0")];
+
+        // No headers were sent on the stream.
+        assert_eq!(
+            s.client.send_trailers(&mut s.pipe.client, 0, &trailers),
+            Err(Error::FrameUnexpected)
+        );
+
+        let (stream, _) = s.send_request(false).unwrap();
+
+        // Trailers can't contain pseudo-headers.
+        assert_eq!(
+            s.client
+                .send_trailers(&mut s.pipe.client, stream, &[Header::new(
+                    b":status", b"200"
+                )]),
+            Err(Error::MessageError)
+        );
+
+        s.client
+            .send_trailers(&mut s.pipe.client, stream, &trailers)
+            .unwrap();
+
+        // The stream is finished after trailers.
+        assert_eq!(
+            s.client
+                .send_trailers(&mut s.pipe.client, stream, &trailers),
+            Err(Error::FrameUnexpected)
+        );
+    }
+
+    #[test]
+    /// Send a DATA frame after trailers.
+    fn data_after_trailers() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        let header_block = s.client.encode_header_block(stream, &req).unwrap();
+
+        s.send_frame_client(
+            frame::Frame::Headers { header_block },
+            stream,
+            false,
+        )
+        .unwrap();
+
+        s.send_frame_client(
+            frame::Frame::Data {
+                payload: vec![1, 2, 3],
+            },
+            stream,
+            false,
+        )
+        .unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req.clone(),
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Trailers { list: req })));
+        assert_eq!(s.poll_server(), Err(Error::FrameUnexpected));
+    }
+
+    #[test]
+    /// Send an informational response before the final one.
+    fn informational_response() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        let info = vec![Header::new(b":status", b"103")];
+
+        s.server
+            .send_response(&mut s.pipe.server, stream, &info, false)
+            .unwrap();
+
+        let resp = s.send_response(stream, true).unwrap();
+
+        let ev_info = Event::Headers {
+            list: info,
+            has_body: true,
+        };
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_info)));
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+    }
+
+    #[test]
+    /// Try to send DATA frames on wrong streams, ensure the API returns an
+    /// error before anything hits the transport layer.
+    fn send_body_invalid_client_stream() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        assert_eq!(s.send_body_client(0, true), Err(Error::FrameUnexpected));
+
+        assert_eq!(
+            s.send_body_client(s.client.control_stream_id.unwrap(), true),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(
+            s.send_body_client(
+                s.client.local_qpack_streams.encoder_stream_id.unwrap(),
+                true
+            ),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(
+            s.send_body_client(
+                s.client.local_qpack_streams.decoder_stream_id.unwrap(),
+                true
+            ),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(
+            s.send_body_client(s.client.peer_control_stream_id.unwrap(), true),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(
+            s.send_body_client(
+                s.client.peer_qpack_streams.encoder_stream_id.unwrap(),
+                true
+            ),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(
+            s.send_body_client(
+                s.client.peer_qpack_streams.decoder_stream_id.unwrap(),
+                true
+            ),
+            Err(Error::FrameUnexpected)
+        );
+    }
+
+    #[test]
+    /// Try to send DATA frames on wrong streams, ensure the API returns an
+    /// error before anything hits the transport layer.
+    fn send_body_invalid_server_stream() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        assert_eq!(s.send_body_server(0, true), Err(Error::FrameUnexpected));
+
+        assert_eq!(
+            s.send_body_server(s.server.control_stream_id.unwrap(), true),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(
+            s.send_body_server(
+                s.server.local_qpack_streams.encoder_stream_id.unwrap(),
+                true
+            ),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(
+            s.send_body_server(
+                s.server.local_qpack_streams.decoder_stream_id.unwrap(),
+                true
+            ),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(
+            s.send_body_server(s.server.peer_control_stream_id.unwrap(), true),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(
+            s.send_body_server(
+                s.server.peer_qpack_streams.encoder_stream_id.unwrap(),
+                true
+            ),
+            Err(Error::FrameUnexpected)
+        );
+
+        assert_eq!(
+            s.send_body_server(
+                s.server.peer_qpack_streams.decoder_stream_id.unwrap(),
+                true
+            ),
+            Err(Error::FrameUnexpected)
+        );
+    }
+
+    #[test]
+    /// Send a MAX_PUSH_ID frame from the client on a valid stream.
+    fn max_push_id_from_client_good() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.send_frame_client(
+            frame::Frame::MaxPushId { push_id: 1 },
+            s.client.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_server(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Send a MAX_PUSH_ID frame from the client on an invalid stream.
+    fn max_push_id_from_client_bad_stream() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        s.send_frame_client(
+            frame::Frame::MaxPushId { push_id: 2 },
+            stream,
+            false,
+        )
+        .unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Err(Error::FrameUnexpected));
+    }
+
+    #[test]
+    /// Send a sequence of MAX_PUSH_ID frames from the client that attempt to
+    /// reduce the limit.
+    fn max_push_id_from_client_limit_reduction() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.send_frame_client(
+            frame::Frame::MaxPushId { push_id: 2 },
+            s.client.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        s.send_frame_client(
+            frame::Frame::MaxPushId { push_id: 1 },
+            s.client.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_server(), Err(Error::IdError));
+    }
+
+    #[test]
+    /// Send a MAX_PUSH_ID frame from the server, which is forbidden.
+    fn max_push_id_from_server() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.send_frame_server(
+            frame::Frame::MaxPushId { push_id: 1 },
+            s.server.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_client(), Err(Error::FrameUnexpected));
+    }
+
+    #[test]
+    /// Send a PUSH_PROMISE frame from the client, which is forbidden.
+    fn push_promise_from_client() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        let header_block = s.client.encode_header_block(stream, &req).unwrap();
+
+        s.send_frame_client(
+            frame::Frame::PushPromise {
+                push_id: 1,
+                header_block,
+            },
+            stream,
+            false,
+        )
+        .unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Err(Error::FrameUnexpected));
+    }
+
+    #[test]
+    /// Send a CANCEL_PUSH frame from the client.
+    fn cancel_push_from_client() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.send_frame_client(
+            frame::Frame::CancelPush { push_id: 1 },
+            s.client.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_server(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Send a CANCEL_PUSH frame from the client on an invalid stream.
+    fn cancel_push_from_client_bad_stream() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        s.send_frame_client(
+            frame::Frame::CancelPush { push_id: 2 },
+            stream,
+            false,
+        )
+        .unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Err(Error::FrameUnexpected));
+    }
+
+    #[test]
+    /// Send a CANCEL_PUSH frame from the client.
+    fn cancel_push_from_server() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.send_frame_server(
+            frame::Frame::CancelPush { push_id: 1 },
+            s.server.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Send a GOAWAY frame from the client.
+    fn goaway_from_client_good() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.client.send_goaway(&mut s.pipe.client, 100).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Ok((100, Event::GoAway)));
+
+        // The push ID can't be increased.
+        assert_eq!(
+            s.client.send_goaway(&mut s.pipe.client, 101),
+            Err(Error::IdError)
+        );
+
+        s.client.send_goaway(&mut s.pipe.client, 0).unwrap();
+    }
+
+    #[test]
+    /// Send a GOAWAY frame from the server.
+    fn goaway_from_server_good() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.server.send_goaway(&mut s.pipe.server, 4000).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Ok((4000, Event::GoAway)));
+    }
+
+    #[test]
+    /// A client MUST NOT send a request after it receives GOAWAY.
+    fn client_request_after_goaway() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.server.send_goaway(&mut s.pipe.server, 4000).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Ok((4000, Event::GoAway)));
+
+        assert_eq!(s.send_request(true), Err(Error::FrameUnexpected));
+    }
+
+    #[test]
+    /// Requests initiated after the server sent GOAWAY are rejected.
+    fn server_reject_request_after_goaway() {
+        let mut buf = [0; 65535];
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        assert!(!s.client.is_draining());
+        assert!(s.server.is_request_allowed(4));
+
+        s.server.send_goaway(&mut s.pipe.server, 4).unwrap();
+
+        assert!(s.server.is_draining());
+        assert!(s.server.is_request_allowed(0));
+        assert!(!s.server.is_request_allowed(4));
+
+        // The client sends requests before learning about the GOAWAY.
+        let (stream1, req) = s.send_request(true).unwrap();
+        let (stream2, _) = s.send_request(true).unwrap();
+        assert_eq!((stream1, stream2), (0, 4));
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream1, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream1, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Ok((4, Event::GoAway)));
+        assert!(s.client.is_draining());
+        assert!(s.client.is_request_allowed(stream1));
+        assert!(!s.client.is_request_allowed(stream2));
+
+        assert_eq!(
+            s.pipe.client.stream_recv(stream2, &mut buf),
+            Err(crate::Error::StreamReset(Error::RequestRejected.to_wire()))
+        );
+    }
+
+    #[test]
+    /// Send a GOAWAY frame with a non-request stream ID from the server.
+    fn goaway_from_server_bad_id() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        assert_eq!(
+            s.server.send_goaway(&mut s.pipe.server, 2),
+            Err(Error::IdError)
+        );
+        assert_eq!(
+            s.server.send_goaway(&mut s.pipe.server, 1),
+            Err(Error::IdError)
+        );
+    }
+
+    #[test]
+    /// Shut down the connection without waiting for requests to complete.
+    fn shutdown_immediate() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        assert_eq!(
+            s.server.shutdown(
+                &mut s.pipe.server,
+                false,
+                Some(Error::InternalError),
+                b"bye"
+            ),
+            Ok(())
+        );
+
+        assert_eq!(s.server.local_goaway_id, None);
+        assert_eq!(
+            s.pipe.server.local_error,
+            Some(crate::ConnectionError {
+                is_app: true,
+                error_code: 0x102,
+                reason: b"bye".to_vec(),
+                frame_type: None,
+            })
+        );
+    }
+
+    #[test]
+    /// Shut down the connection gracefully when there are no requests.
+    fn shutdown_graceful_idle() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        assert_eq!(
+            s.server.shutdown(&mut s.pipe.server, true, None, b""),
+            Ok(())
+        );
+
+        assert_eq!(s.server.local_goaway_id, Some(0));
+        assert_eq!(
+            s.pipe.server.local_error,
+            Some(crate::ConnectionError {
+                is_app: true,
+                error_code: H3_NO_ERROR,
+                reason: vec![],
+                frame_type: None,
+            })
+        );
+    }
+
+    #[test]
+    /// Shut down the connection gracefully while a request is in progress.
+    fn shutdown_graceful_pending_request() {
+        let clock = std::sync::Arc::new(crate::testing::ManualClock::new(
+            std::time::Instant::now(),
+        ));
+
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+        config.set_clock(clock.clone());
+
+        let h3_config = Config::new().unwrap();
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        assert_eq!(
+            s.server.shutdown(&mut s.pipe.server, true, None, b""),
+            Ok(())
+        );
+
+        // The GOAWAY frame covers the request being processed.
+        assert_eq!(s.server.local_goaway_id, Some(4));
+        assert_eq!(s.pipe.server.local_error, None);
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Ok((4, Event::GoAway)));
+
+        // The connection is closed once the shutdown timeout expires, even
+        // without polling.
+        let timeout = s.pipe.server.timeout().unwrap();
+        assert!(timeout <= std::time::Duration::from_millis(10_000));
+
+        clock.advance(std::time::Duration::from_millis(10_000));
+        s.pipe.server.on_timeout();
+
+        assert_eq!(
+            s.pipe.server.local_error.as_ref().map(|e| e.error_code),
+            Some(H3_NO_ERROR)
+        );
+    }
+
+    #[test]
+    /// Send a GOAWAY frame from the server, using an invalid goaway ID.
+    fn goaway_from_server_invalid_id() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.send_frame_server(
+            frame::Frame::GoAway { id: 1 },
+            s.server.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_client(), Err(Error::IdError));
+    }
+
+    #[test]
+    /// Send multiple GOAWAY frames from the server, that increase the goaway
+    /// ID.
+    fn goaway_from_server_increase_id() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.send_frame_server(
+            frame::Frame::GoAway { id: 0 },
+            s.server.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        s.send_frame_server(
+            frame::Frame::GoAway { id: 4 },
+            s.server.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_client(), Ok((0, Event::GoAway)));
+
+        assert_eq!(s.poll_client(), Err(Error::IdError));
+    }
+
+    #[test]
+    /// Ensure quiche allocates streams for client and server roles as expected.
+    fn uni_stream_local_counting() {
+        let config = Config::new().unwrap();
+
+        let h3_cln = Connection::new(&config, false, false).unwrap();
+        assert_eq!(h3_cln.next_uni_stream_id, 2);
+
+        let h3_srv = Connection::new(&config, true, false).unwrap();
+        assert_eq!(h3_srv.next_uni_stream_id, 3);
+    }
+
+    #[test]
+    /// Client opens multiple control streams, which is forbidden.
+    fn open_multiple_control_streams() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let stream_id = s.client.next_uni_stream_id;
+
+        let mut d = [42; 8];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        s.pipe
+            .client
+            .stream_send(
+                stream_id,
+                b.put_varint(stream::HTTP3_CONTROL_STREAM_TYPE_ID).unwrap(),
+                false,
+            )
+            .unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Err(Error::StreamCreationError));
+    }
+
+    #[test]
+    /// Client closes the control stream, which is forbidden.
+    fn close_control_stream() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let mut control_stream_closed = false;
+
+        s.send_frame_client(
+            frame::Frame::MaxPushId { push_id: 1 },
+            s.client.control_stream_id.unwrap(),
+            true,
+        )
+        .unwrap();
+
+        loop {
+            match s.server.poll(&mut s.pipe.server) {
+                Ok(_) => (),
+
+                Err(Error::Done) => {
+                    break;
+                },
+
+                Err(Error::ClosedCriticalStream) => {
+                    control_stream_closed = true;
+                    break;
+                },
+
+                Err(_) => (),
+            }
+        }
+
+        assert!(control_stream_closed);
+    }
+
+    #[test]
+    /// Client closes QPACK stream, which is forbidden.
+    fn close_qpack_stream() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let mut qpack_stream_closed = false;
+
+        let stream_id = s.client.local_qpack_streams.encoder_stream_id.unwrap();
+
+        // Set Dynamic Table Capacity of 0.
+        let d = [0x20; 1];
+
+        s.pipe.client.stream_send(stream_id, &d, false).unwrap();
+        s.pipe.client.stream_send(stream_id, &d, true).unwrap();
+
+        s.advance().ok();
+
+        loop {
+            match s.server.poll(&mut s.pipe.server) {
+                Ok(_) => (),
+
+                Err(Error::Done) => {
+                    break;
+                },
+
+                Err(Error::ClosedCriticalStream) => {
+                    qpack_stream_closed = true;
+                    break;
+                },
+
+                Err(_) => (),
+            }
+        }
+
+        assert!(qpack_stream_closed);
+    }
+
+    #[test]
+    /// Client sends QPACK data.
+    fn qpack_data() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let e_stream_id = s.client.local_qpack_streams.encoder_stream_id.unwrap();
+        let d_stream_id = s.client.local_qpack_streams.decoder_stream_id.unwrap();
+
+        // Set Dynamic Table Capacity of 0.
+        let d = [0x20; 20];
+
+        s.pipe.client.stream_send(e_stream_id, &d, false).unwrap();
+        s.advance().ok();
+
+        // Stream Cancellation of stream 0.
+        let d = [0x40; 20];
+
+        s.pipe.client.stream_send(d_stream_id, &d, false).unwrap();
+        s.advance().ok();
+
+        loop {
+            match s.server.poll(&mut s.pipe.server) {
+                Ok(_) => (),
+
+                Err(Error::Done) => {
+                    break;
+                },
+
+                Err(_) => {
+                    panic!();
+                },
+            }
+        }
+    }
+
+    #[test]
+    /// Tests limits for the stream state buffer maximum size.
+    fn max_state_buf_size() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+            Header::new(b"user-agent", b"quiche-test"),
+        ];
+
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, false),
+            Ok(0)
+        );
+
+        s.advance().ok();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.server.poll(&mut s.pipe.server), Ok((0, ev_headers)));
+
+        // DATA frames don't consume the state buffer, so can be of any size.
+        let mut d = [42; 128];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        let frame_type = b.put_varint(frame::DATA_FRAME_TYPE_ID).unwrap();
+        s.pipe.client.stream_send(0, frame_type, false).unwrap();
+
+        let frame_len = b.put_varint(1 << 24).unwrap();
+        s.pipe.client.stream_send(0, frame_len, false).unwrap();
+
+        s.pipe.client.stream_send(0, &d, false).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.server.poll(&mut s.pipe.server), Ok((0, Event::Data)));
+
+        // GREASE frames consume the state buffer, so need to be limited.
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let mut d = [42; 128];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        let frame_type = b.put_varint(148_764_065_110_560_899).unwrap();
+        s.pipe.client.stream_send(0, frame_type, false).unwrap();
+
+        let frame_len = b.put_varint(1 << 24).unwrap();
+        s.pipe.client.stream_send(0, frame_len, false).unwrap();
+
+        s.pipe.client.stream_send(0, &d, false).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::ExcessiveLoad));
+    }
+
+    #[test]
+    /// Tests that DATA frames are properly truncated depending on the request
+    /// stream's outgoing flow control capacity.
+    fn stream_backpressure() {
+        let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        let total_data_frames = 6;
+
+        for _ in 0..total_data_frames {
+            assert_eq!(
+                s.client
+                    .send_body(&mut s.pipe.client, stream, &bytes, false),
+                Ok(bytes.len())
+            );
+
+            s.advance().ok();
+        }
+
+        assert_eq!(
+            s.client.send_body(&mut s.pipe.client, stream, &bytes, true),
+            Ok(bytes.len() - 2)
+        );
+
+        s.advance().ok();
+
+        let mut recv_buf = vec![0; bytes.len()];
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        for _ in 0..total_data_frames {
+            assert_eq!(
+                s.recv_body_server(stream, &mut recv_buf),
+                Ok(bytes.len())
+            );
+        }
+
+        assert_eq!(
+            s.recv_body_server(stream, &mut recv_buf),
+            Ok(bytes.len() - 2)
+        );
+
+        // Fin flag from last send_body() call was not sent as the buffer was
+        // only partially written.
+        assert_eq!(s.poll_server(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Tests that the max header list size setting is enforced.
+    fn request_max_header_size_limit() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.set_max_field_section_size(65);
+
+        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
+
+        s.handshake().unwrap();
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+            Header::new(b"aaaaaaa", b"aaaaaaaa"),
+        ];
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(stream, 0);
+
+        let ev_reset = Event::Reset(Error::to_wire(Error::ExcessiveLoad));
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_reset.clone())));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        // Only the stream is reset, not the whole connection.
+        assert!(s.pipe.server.local_error.is_none());
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_reset)));
+    }
+
+    #[test]
+    /// Tests that header blocks too large for the max field section size are
+    /// rejected before they are received in full.
+    fn headers_too_large_frame_len() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.set_max_field_section_size(65);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+
+        s.handshake().unwrap();
+
+        // Only the start of the frame fits in the stream's flow control
+        // window, so its payload can't be received in full.
+        let header_block = vec![0; 500];
+
+        s.send_frame_client(frame::Frame::Headers { header_block }, 0, false)
+            .unwrap();
+
+        let ev_reset = Event::Reset(Error::to_wire(Error::ExcessiveLoad));
+
+        assert_eq!(s.poll_server(), Ok((0, ev_reset)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        // Only the stream is reset, not the whole connection.
+        assert!(s.pipe.server.local_error.is_none());
+    }
+
+    #[test]
+    /// Tests that malformed requests are rejected when strict header
+    /// validation is enabled.
+    fn strict_header_validation() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.enable_strict_header_validation(true);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+
+        s.handshake().unwrap();
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+            Header::new(b"transfer-encoding", b"chunked"),
+        ];
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
+
+        s.advance().ok();
+
+        let ev_reset = Event::Reset(Error::to_wire(Error::MessageError));
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_reset.clone())));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert!(s.pipe.server.local_error.is_none());
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_reset)));
+
+        // Well-formed requests are still accepted.
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+    }
+
+    #[test]
+    /// Tests that malformed responses are rejected when strict header
+    /// validation is enabled.
+    fn strict_header_validation_response() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.enable_strict_header_validation(true);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+
+        let resp = vec![
+            Header::new(b"server", b"quiche-test"),
+            Header::new(b":status", b"200"),
+        ];
+
+        s.server
+            .send_response(&mut s.pipe.server, stream, &resp, true)
+            .unwrap();
+
+        s.advance().ok();
+
+        let ev_reset = Event::Reset(Error::to_wire(Error::MessageError));
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_reset)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Tests that Error::TransportError contains a transport error.
+    fn transport_error() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+            Header::new(b"user-agent", b"quiche-test"),
+        ];
+
+        // We need to open all streams in the same flight, so we can't use the
+        // Session::send_request() method because it also calls advance(),
+        // otherwise the server would send a MAX_STREAMS frame and the client
+        // wouldn't hit the streams limit.
+        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(0));
+        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(4));
+        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(8));
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, true),
+            Ok(12)
+        );
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, true),
+            Ok(16)
+        );
+
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, true),
+            Err(Error::TransportError(crate::Error::StreamLimit(20)))
+        );
+    }
+
+    #[test]
+    /// Tests that sending DATA before HEADERS causes an error.
+    fn data_before_headers() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let mut d = [42; 128];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        let frame_type = b.put_varint(frame::DATA_FRAME_TYPE_ID).unwrap();
+        s.pipe.client.stream_send(0, frame_type, false).unwrap();
+
+        let frame_len = b.put_varint(5).unwrap();
+        s.pipe.client.stream_send(0, frame_len, false).unwrap();
+
+        s.pipe.client.stream_send(0, b"hello", false).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(
+            s.server.poll(&mut s.pipe.server),
+            Err(Error::FrameUnexpected)
+        );
+    }
+
+    #[test]
+    /// Tests that calling poll() after an error occured does nothing.
+    fn poll_after_error() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let mut d = [42; 128];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        let frame_type = b.put_varint(148_764_065_110_560_899).unwrap();
+        s.pipe.client.stream_send(0, frame_type, false).unwrap();
+
+        let frame_len = b.put_varint(1 << 24).unwrap();
+        s.pipe.client.stream_send(0, frame_len, false).unwrap();
+
+        s.pipe.client.stream_send(0, &d, false).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::ExcessiveLoad));
+
+        // Try to call poll() again after an error occurred.
+        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::Done));
+    }
+
+    #[test]
+    /// Tests that we limit sending HEADERS based on the stream capacity.
+    fn headers_blocked() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(70);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+
+        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
+
+        s.handshake().unwrap();
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+        ];
+
+        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(0));
+
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, true),
+            Err(Error::StreamBlocked)
+        );
+
+        s.advance().ok();
+
+        // Once the server gives flow control credits back, we can send the
+        // request.
+        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(4));
+    }
+
+    #[test]
+    /// Ensure StreamBlocked when connection flow control prevents headers.
+    fn headers_blocked_on_conn() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(70);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+
+        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
+
+        s.handshake().unwrap();
+
+        // After the HTTP handshake, some bytes of connection flow control have
+        // been consumed. Fill the connection with more grease data on the control
+        // stream.
+        let d = [42; 28];
+        assert_eq!(s.pipe.client.stream_send(2, &d, false), Ok(23));
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+        ];
+
+        // There is 0 connection-level flow control, so sending a request is
+        // blocked.
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, true),
+            Err(Error::StreamBlocked)
+        );
+
+        // Emit the control stream data and drain it at the server via poll() to
+        // consumes it via poll() and gives back flow control.
+        s.advance().ok();
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        s.advance().ok();
+
+        // Now we can send the request.
+        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(0));
+    }
+
+    #[test]
+    /// Test handling of 0-length DATA writes with and without fin.
+    fn zero_length_data() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        assert_eq!(
+            s.client.send_body(&mut s.pipe.client, 0, b"", false),
+            Err(Error::Done)
+        );
+        assert_eq!(s.client.send_body(&mut s.pipe.client, 0, b"", true), Ok(0));
+
+        s.advance().ok();
+
+        let mut recv_buf = vec![0; 100];
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Err(Error::Done));
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        let resp = s.send_response(stream, false).unwrap();
+
+        assert_eq!(
+            s.server.send_body(&mut s.pipe.server, 0, b"", false),
+            Err(Error::Done)
+        );
+        assert_eq!(s.server.send_body(&mut s.pipe.server, 0, b"", true), Ok(0));
+
+        s.advance().ok();
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+
+        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_client(stream, &mut recv_buf), Err(Error::Done));
+
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Tests that blocked 0-length DATA writes are reported correctly.
+    fn zero_length_data_blocked() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(69);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+
+        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
+
+        s.handshake().unwrap();
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+        ];
+
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, false),
+            Ok(0)
+        );
+
+        assert_eq!(
+            s.client.send_body(&mut s.pipe.client, 0, b"", true),
+            Err(Error::Done)
+        );
+
+        s.advance().ok();
+
+        // Once the server gives flow control credits back, we can send the body.
+        assert_eq!(s.client.send_body(&mut s.pipe.client, 0, b"", true), Ok(0));
+    }
+
+    #[test]
+    /// Tests that receiving a H3_DATAGRAM setting is ok.
+    fn dgram_setting() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(70);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.enable_dgram(true, 1000, 1000);
+        config.verify_peer(false);
+
+        let h3_config = Config::new().unwrap();
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        assert_eq!(s.pipe.handshake(), Ok(()));
+
+        s.client.send_settings(&mut s.pipe.client).unwrap();
+        assert_eq!(s.pipe.advance(), Ok(()));
+
+        // Before processing SETTINGS (via poll), HTTP/3 DATAGRAMS are not
+        // enabled.
+        assert!(!s.server.dgram_enabled_by_peer(&s.pipe.server));
+
+        // When everything is ok, poll reports the SETTINGS and DATAGRAM is
+        // enabled.
+        let control_stream_id = s.client.control_stream_id.unwrap();
+
+        assert_eq!(
+            s.server.poll(&mut s.pipe.server),
+            Ok((control_stream_id, Event::Settings))
+        );
+        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::Done));
+        assert!(s.server.dgram_enabled_by_peer(&s.pipe.server));
+
+        // Now detect things on the client
+        s.server.send_settings(&mut s.pipe.server).unwrap();
+        assert_eq!(s.pipe.advance(), Ok(()));
+        assert!(!s.client.dgram_enabled_by_peer(&s.pipe.client));
+
+        let control_stream_id = s.server.control_stream_id.unwrap();
+
+        assert_eq!(
+            s.client.poll(&mut s.pipe.client),
+            Ok((control_stream_id, Event::Settings))
+        );
+        assert_eq!(s.client.poll(&mut s.pipe.client), Err(Error::Done));
+        assert!(s.client.dgram_enabled_by_peer(&s.pipe.client));
+    }
+
+    #[test]
+    /// Tests that receiving a H3_DATAGRAM setting when no TP is set generates
+    /// an error.
+    fn dgram_setting_no_tp() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(70);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let h3_config = Config::new().unwrap();
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        assert_eq!(s.pipe.handshake(), Ok(()));
+
+        s.client.control_stream_id = Some(
+            s.client
+                .open_uni_stream(
+                    &mut s.pipe.client,
+                    stream::HTTP3_CONTROL_STREAM_TYPE_ID,
+                )
+                .unwrap(),
+        );
+
+        let settings = frame::Frame::Settings {
+            max_field_section_size: None,
+            qpack_max_table_capacity: None,
+            qpack_blocked_streams: None,
+            h3_datagram: Some(1),
+            connect_protocol_enabled: None,
+            grease: None,
+            raw: Default::default(),
+        };
+
+        s.send_frame_client(settings, s.client.control_stream_id.unwrap(), false)
+            .unwrap();
+
+        assert_eq!(s.pipe.advance(), Ok(()));
+
+        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::SettingsError));
+    }
+
+    #[test]
+    /// Tests that receiving SETTINGS with prohibited values generates an error.
+    fn settings_h2_prohibited() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(70);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let h3_config = Config::new().unwrap();
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        assert_eq!(s.pipe.handshake(), Ok(()));
+
+        s.client.control_stream_id = Some(
+            s.client
+                .open_uni_stream(
+                    &mut s.pipe.client,
+                    stream::HTTP3_CONTROL_STREAM_TYPE_ID,
+                )
+                .unwrap(),
+        );
+
+        s.server.control_stream_id = Some(
+            s.server
+                .open_uni_stream(
+                    &mut s.pipe.server,
+                    stream::HTTP3_CONTROL_STREAM_TYPE_ID,
+                )
+                .unwrap(),
+        );
+
+        let frame_payload_len = 2u64;
+        let settings = [
+            frame::SETTINGS_FRAME_TYPE_ID as u8,
+            frame_payload_len as u8,
+            0x2, // 0x2 is a reserved setting type
+            1,
+        ];
+
+        s.send_arbitrary_stream_data_client(
+            &settings,
+            s.client.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        s.send_arbitrary_stream_data_server(
+            &settings,
+            s.server.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.pipe.advance(), Ok(()));
 
-            frame.to_bytes(&mut b)?;
+        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::SettingsError));
 
-            let off = b.off();
-            self.pipe.client.stream_send(stream_id, &d[..off], fin)?;
+        assert_eq!(s.client.poll(&mut s.pipe.client), Err(Error::SettingsError));
+    }
 
-            self.advance().ok();
+    #[test]
+    /// Send a single DATAGRAM.
+    fn single_dgram() {
+        let mut buf = [0; 65535];
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
 
-            Ok(())
-        }
+        // We'll send default data of 10 bytes on flow ID 0.
+        let result = (11, 0, 1);
 
-        /// Send an HTTP/3 DATAGRAM with default data from the client.
-        ///
-        /// On success it returns the data.
-        pub fn send_dgram_client(&mut self, flow_id: u64) -> Result<Vec<u8>> {
-            let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        // The flow is associated with the request stream.
+        let (stream, req) = s.send_request(false).unwrap();
 
-            self.client
-                .send_dgram(&mut self.pipe.client, flow_id, &bytes)?;
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-            self.advance().ok();
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
 
-            Ok(bytes)
-        }
+        s.send_dgram_client(0).unwrap();
 
-        /// Receives an HTTP/3 DATAGRAM from the server.
-        ///
-        /// On success it returns the DATAGRAM length, flow ID and flow ID
-        /// length.
-        pub fn recv_dgram_client(
-            &mut self, buf: &mut [u8],
-        ) -> Result<(usize, u64, usize)> {
-            self.client.recv_dgram(&mut self.pipe.client, buf)
-        }
+        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        /// Send an HTTP/3 DATAGRAM with default data from the server
-        ///
-        /// On success it returns the data.
-        pub fn send_dgram_server(&mut self, flow_id: u64) -> Result<Vec<u8>> {
-            let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        s.send_dgram_server(0).unwrap();
+        assert_eq!(s.poll_client(), Ok((0, Event::Datagram)));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(result));
+    }
 
-            self.server
-                .send_dgram(&mut self.pipe.server, flow_id, &bytes)?;
+    #[test]
+    /// Send DATAGRAMs with an invalid Quarter Stream ID.
+    fn dgram_bad_flow_id() {
+        let mut buf = [0; 65535];
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
 
-            self.advance().ok();
+        assert_eq!(
+            s.client.send_dgram(
+                &mut s.pipe.client,
+                MAX_QUARTER_STREAM_ID + 1,
+                b"hello"
+            ),
+            Err(Error::IdError)
+        );
 
-            Ok(bytes)
-        }
+        let mut d = [0; 16];
+        let len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            b.put_varint(MAX_QUARTER_STREAM_ID + 1).unwrap();
+            b.put_bytes(b"hello").unwrap();
+            b.off()
+        };
 
-        /// Receives an HTTP/3 DATAGRAM from the client.
-        ///
-        /// On success it returns the DATAGRAM length, flow ID and flow ID
-        /// length.
-        pub fn recv_dgram_server(
-            &mut self, buf: &mut [u8],
-        ) -> Result<(usize, u64, usize)> {
-            self.server.recv_dgram(&mut self.pipe.server, buf)
-        }
+        s.pipe.client.dgram_send(&d[..len]).unwrap();
+        s.advance().ok();
 
-        /// Sends a single HTTP/3 frame from the server.
-        pub fn send_frame_server(
-            &mut self, frame: frame::Frame, stream_id: u64, fin: bool,
-        ) -> Result<()> {
-            let mut d = [42; 65535];
+        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
+        assert_eq!(s.recv_dgram_server(&mut buf), Err(Error::DatagramError));
+    }
 
-            let mut b = octets::OctetsMut::with_slice(&mut d);
+    #[test]
+    /// Send DATAGRAMs on flows without a request stream.
+    fn dgram_unknown_stream() {
+        let mut buf = [0; 65535];
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
 
-            frame.to_bytes(&mut b)?;
+        assert_eq!(
+            s.client.send_dgram(&mut s.pipe.client, 1, b"hello"),
+            Err(Error::FrameUnexpected)
+        );
 
-            let off = b.off();
-            self.pipe.server.stream_send(stream_id, &d[..off], fin)?;
+        // DATAGRAMs for streams the peer never opened are dropped.
+        s.pipe.client.dgram_send(b"\x01hello").unwrap();
+        s.advance().ok();
 
-            self.advance().ok();
+        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
+        assert_eq!(s.recv_dgram_server(&mut buf), Err(Error::Done));
+        assert_eq!(s.pipe.server.local_error(), None);
+    }
 
-            Ok(())
-        }
+    #[test]
+    /// Send capsules on a request stream.
+    fn capsules() {
+        let mut buf = [0; 65535];
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
 
-        /// Sends an arbitrary buffer of HTTP/3 stream data from the client.
-        pub fn send_arbitrary_stream_data_client(
-            &mut self, data: &[u8], stream_id: u64, fin: bool,
-        ) -> Result<()> {
-            self.pipe.client.stream_send(stream_id, data, fin)?;
+        let (stream, req) = s.send_request(false).unwrap();
 
-            self.advance().ok();
+        let datagram = Capsule::Datagram {
+            payload: b"hello".to_vec(),
+        };
 
-            Ok(())
-        }
+        let unknown = Capsule::Unknown {
+            capsule_type: 0x2a,
+            value: vec![1, 2, 3],
+        };
 
-        /// Sends an arbitrary buffer of HTTP/3 stream data from the server.
-        pub fn send_arbitrary_stream_data_server(
-            &mut self, data: &[u8], stream_id: u64, fin: bool,
-        ) -> Result<()> {
-            self.pipe.server.stream_send(stream_id, data, fin)?;
+        s.client
+            .send_capsule(&mut s.pipe.client, stream, &datagram)
+            .unwrap();
+        s.client
+            .send_capsule(&mut s.pipe.client, stream, &unknown)
+            .unwrap();
+        s.advance().ok();
 
-            self.advance().ok();
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-            Ok(())
-        }
-    }
-}
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let len = s.recv_body_server(stream, &mut buf).unwrap();
+        assert_eq!(len, datagram.wire_len() + unknown.wire_len());
 
-    use super::testing::*;
+        let (capsule, off) = Capsule::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(capsule, datagram);
 
-    #[test]
-    /// Make sure that random GREASE values is within the specified limit.
-    fn grease_value_in_varint_limit() {
-        assert!(grease_value() < 2u64.pow(62) - 1);
+        let (capsule, _) = Capsule::from_bytes(&buf[off..len]).unwrap();
+        assert_eq!(capsule, unknown);
+
+        // Capsules can't be sent on streams without a request.
+        assert_eq!(
+            s.client.send_capsule(&mut s.pipe.client, 4, &datagram),
+            Err(Error::FrameUnexpected)
+        );
     }
 
     #[test]
-    /// Send a request with no body, get a response with no body.
-    fn request_no_body_response_no_body() {
-        let mut s = Session::default().unwrap();
+    /// Send an extended CONNECT request to a server that enabled them.
+    fn extended_connect() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.enable_extended_connect(true);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+
+        assert!(!s.client.extended_connect_enabled_by_peer());
+
         s.handshake().unwrap();
 
-        let (stream, req) = s.send_request(true).unwrap();
+        assert!(s.client.extended_connect_enabled_by_peer());
+        assert!(s.server.extended_connect_enabled_by_peer());
 
-        assert_eq!(stream, 0);
+        let req = connect_udp::request_headers(
+            "proxy.example.org",
+            "/.well-known/masque/udp/192.0.2.6/443/",
+        );
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, false)
+            .unwrap();
+        s.advance().ok();
 
         let ev_headers = Event::Headers {
             list: req,
-            has_body: false,
+            has_body: true,
         };
 
         assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
 
-        let resp = s.send_response(stream, true).unwrap();
+        // Extended CONNECT requests need all the request pseudo-headers.
+        let req = vec![
+            Header::new(b":method", b"CONNECT"),
+            Header::new(b":protocol", b"connect-udp"),
+            Header::new(b":authority", b"proxy.example.org"),
+        ];
 
-        let ev_headers = Event::Headers {
-            list: resp,
-            has_body: false,
-        };
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, false),
+            Err(Error::MessageError)
+        );
 
-        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
-        assert_eq!(s.poll_client(), Err(Error::Done));
+        // The :protocol pseudo-header is only valid for CONNECT.
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":protocol", b"connect-udp"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"proxy.example.org"),
+            Header::new(b":path", b"/"),
+        ];
+
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, false),
+            Err(Error::MessageError)
+        );
     }
 
     #[test]
-    /// Send a request with no body, get a response with one DATA frame.
-    fn request_no_body_response_one_chunk() {
+    /// Send extended CONNECT requests to a server that didn't enable them.
+    fn extended_connect_disabled() {
+        let mut buf = [0; 65535];
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        let (stream, req) = s.send_request(true).unwrap();
-        assert_eq!(stream, 0);
+        assert!(!s.client.extended_connect_enabled_by_peer());
 
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: false,
-        };
+        let req = connect_udp::request_headers(
+            "proxy.example.org",
+            "/.well-known/masque/udp/192.0.2.6/443/",
+        );
 
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, false),
+            Err(Error::MessageError)
+        );
 
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        // Bypass the client checks, the server resets the stream.
+        let mut d = [42; 128];
+        let len = qpack::Encoder::new().encode(&req, &mut d).unwrap();
 
-        let resp = s.send_response(stream, false).unwrap();
+        let header_block = d[..len].to_vec();
 
-        let body = s.send_body_server(stream, true).unwrap();
+        s.send_frame_client(frame::Frame::Headers { header_block }, 0, false)
+            .unwrap();
 
-        let mut recv_buf = vec![0; body.len()];
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        s.advance().ok();
+
+        assert_eq!(
+            s.pipe.client.stream_recv(0, &mut buf),
+            Err(crate::Error::StreamReset(Error::MessageError.to_wire()))
+        );
+    }
+
+    #[test]
+    /// Send regular CONNECT requests.
+    fn connect() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let req = vec![
+            Header::new(b":method", b"CONNECT"),
+            Header::new(b":authority", b"example.org:443"),
+        ];
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, false)
+            .unwrap();
+        s.advance().ok();
 
         let ev_headers = Event::Headers {
-            list: resp,
+            list: req,
             has_body: true,
         };
 
-        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        // CONNECT requests must not have a :scheme or :path.
+        let req = vec![
+            Header::new(b":method", b"CONNECT"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"example.org:443"),
+            Header::new(b":path", b"/"),
+        ];
+
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, false),
+            Err(Error::MessageError)
+        );
 
-        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
-        assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
+        let req = vec![Header::new(b":method", b"CONNECT")];
 
-        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
-        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(
+            s.client.send_request(&mut s.pipe.client, &req, false),
+            Err(Error::MessageError)
+        );
     }
 
     #[test]
-    /// Send a request with no body, get a response with multiple DATA frames.
-    fn request_no_body_response_many_chunks() {
+    /// Exchange IP packets through a CONNECT-IP tunnel.
+    fn connect_ip_packets() {
+        let mut buf = [0; 65535];
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        let (stream, req) = s.send_request(true).unwrap();
+        let packet = [0x45, 0, 0, 20];
 
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: false,
-        };
+        // Open the request streams the tunnels are bound to.
+        for _ in 0..3 {
+            s.send_request(false).unwrap();
+        }
 
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        connect_ip::send_packet(&mut s.client, &mut s.pipe.client, 4, &packet)
+            .unwrap();
 
-        let total_data_frames = 4;
+        // Datagrams using other Context IDs are returned to the application.
+        s.client
+            .send_dgram(&mut s.pipe.client, 1, b"\x02hi")
+            .unwrap();
 
-        let resp = s.send_response(stream, false).unwrap();
+        // Datagrams without a Context ID are dropped.
+        s.client.send_dgram(&mut s.pipe.client, 1, b"").unwrap();
 
-        for _ in 0..total_data_frames - 1 {
-            s.send_body_server(stream, false).unwrap();
-        }
+        connect_ip::send_packet(&mut s.client, &mut s.pipe.client, 8, &packet)
+            .unwrap();
+        s.advance().ok();
 
-        let body = s.send_body_server(stream, true).unwrap();
+        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
 
-        let mut recv_buf = vec![0; body.len()];
+        assert_eq!(
+            connect_ip::recv_packet(&mut s.server, &mut s.pipe.server, &mut buf),
+            Ok((4, connect_ip::IP_PACKET_CONTEXT_ID, packet.len()))
+        );
+        assert_eq!(&buf[..packet.len()], &packet);
 
-        let ev_headers = Event::Headers {
-            list: resp,
-            has_body: true,
-        };
+        assert_eq!(
+            connect_ip::recv_packet(&mut s.server, &mut s.pipe.server, &mut buf),
+            Ok((4, 2, 2))
+        );
+        assert_eq!(&buf[..2], b"hi");
 
-        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
-        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(
+            connect_ip::recv_packet(&mut s.server, &mut s.pipe.server, &mut buf),
+            Ok((8, connect_ip::IP_PACKET_CONTEXT_ID, packet.len()))
+        );
+        assert_eq!(&buf[..packet.len()], &packet);
 
-        for _ in 0..total_data_frames {
-            assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
-        }
+        assert_eq!(
+            connect_ip::recv_packet(&mut s.server, &mut s.pipe.server, &mut buf),
+            Err(Error::Done)
+        );
 
-        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
-        assert_eq!(s.poll_client(), Err(Error::Done));
+        // Tunnels can only be bound to request streams.
+        assert_eq!(
+            connect_ip::send_packet(
+                &mut s.client,
+                &mut s.pipe.client,
+                2,
+                &packet
+            ),
+            Err(Error::FrameUnexpected)
+        );
     }
 
     #[test]
-    /// Send a request with one DATA frame, get a response with no body.
-    fn request_one_chunk_response_no_body() {
+    /// Send multiple DATAGRAMs.
+    fn multiple_dgram() {
+        let mut buf = [0; 65535];
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        let (stream, req) = s.send_request(false).unwrap();
-
-        let body = s.send_body_client(stream, true).unwrap();
+        // We'll send default data of 10 bytes on flow ID 0.
+        let result = (11, 0, 1);
 
-        let mut recv_buf = vec![0; body.len()];
+        // The flow is associated with the request stream.
+        let (stream, req) = s.send_request(false).unwrap();
 
         let ev_headers = Event::Headers {
             list: req,
@@ -2596,39 +7075,48 @@ mod tests {
 
         assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
 
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
-
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(0).unwrap();
 
-        let resp = s.send_response(stream, true).unwrap();
+        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Err(Error::Done));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        let ev_headers = Event::Headers {
-            list: resp,
-            has_body: false,
-        };
+        s.send_dgram_server(0).unwrap();
+        s.send_dgram_server(0).unwrap();
+        s.send_dgram_server(0).unwrap();
 
-        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Ok((0, Event::Datagram)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Err(Error::Done));
+        assert_eq!(s.poll_client(), Err(Error::Done));
     }
 
     #[test]
-    /// Send a request with multiple DATA frames, get a response with no body.
-    fn request_many_chunks_response_no_body() {
+    /// Send more DATAGRAMs than the send queue allows.
+    fn multiple_dgram_overflow() {
+        let mut buf = [0; 65535];
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        let (stream, req) = s.send_request(false).unwrap();
-
-        let total_data_frames = 4;
-
-        for _ in 0..total_data_frames - 1 {
-            s.send_body_client(stream, false).unwrap();
-        }
-
-        let body = s.send_body_client(stream, true).unwrap();
+        // We'll send default data of 10 bytes on flow ID 0.
+        let result = (11, 0, 1);
 
-        let mut recv_buf = vec![0; body.len()];
+        // The flow is associated with the request stream.
+        let (stream, req) = s.send_request(false).unwrap();
 
         let ev_headers = Event::Headers {
             list: req,
@@ -2636,120 +7124,136 @@ mod tests {
         };
 
         assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-
-        for _ in 0..total_data_frames {
-            assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
-        }
-
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
-
-        let resp = s.send_response(stream, true).unwrap();
 
-        let ev_headers = Event::Headers {
-            list: resp,
-            has_body: false,
-        };
+        // Five DATAGRAMs
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(0).unwrap();
 
-        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        // Only 3 independent DATAGRAM events will fire.
+        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Err(Error::Done));
+        assert_eq!(s.poll_server(), Err(Error::Done));
     }
 
     #[test]
-    /// Send a request with multiple DATA frames, get a response with one DATA
-    /// frame.
-    fn many_requests_many_chunks_response_one_chunk() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
-
-        let mut reqs = Vec::new();
-
-        let (stream1, req1) = s.send_request(false).unwrap();
-        assert_eq!(stream1, 0);
-        reqs.push(req1);
-
-        let (stream2, req2) = s.send_request(false).unwrap();
-        assert_eq!(stream2, 4);
-        reqs.push(req2);
+    /// Send a single DATAGRAM and request. Ensure that poll continuously cycles
+    /// between the two types if the data is not read.
+    fn poll_yield_cycling() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+        config.enable_dgram(true, 100, 100);
 
-        let (stream3, req3) = s.send_request(false).unwrap();
-        assert_eq!(stream3, 8);
-        reqs.push(req3);
+        let mut h3_config = Config::new().unwrap();
+        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
+        s.handshake().unwrap();
 
-        let body = s.send_body_client(stream1, false).unwrap();
-        s.send_body_client(stream2, false).unwrap();
-        s.send_body_client(stream3, false).unwrap();
+        // Send request followed by DATAGRAM on client side.
+        let (stream, req) = s.send_request(false).unwrap();
 
-        let mut recv_buf = vec![0; body.len()];
+        s.send_body_client(stream, true).unwrap();
 
-        // Reverse order of writes.
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-        s.send_body_client(stream3, true).unwrap();
-        s.send_body_client(stream2, true).unwrap();
-        s.send_body_client(stream1, true).unwrap();
+        s.send_dgram_client(0).unwrap();
 
-        for _ in 0..reqs.len() {
-            let (stream, ev) = s.poll_server().unwrap();
-            let ev_headers = Event::Headers {
-                list: reqs[(stream / 4) as usize].clone(),
-                has_body: true,
-            };
-            assert_eq!(ev, ev_headers);
-            assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-            assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
-            assert_eq!(s.poll_client(), Err(Error::Done));
+        // Now let's test the poll counts and yielding.
+        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
 
-            assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
-            assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
-        }
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
 
         assert_eq!(s.poll_server(), Err(Error::Done));
+    }
 
-        let mut resps = Vec::new();
-
-        let resp1 = s.send_response(stream1, true).unwrap();
-        resps.push(resp1);
+    #[test]
+    /// Send a single DATAGRAM and request. Ensure that poll
+    /// yield cycles and cleanly exits if data is read.
+    fn poll_yield_single_read() {
+        let mut buf = [0; 65535];
 
-        let resp2 = s.send_response(stream2, true).unwrap();
-        resps.push(resp2);
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+        config.enable_dgram(true, 100, 100);
 
-        let resp3 = s.send_response(stream3, true).unwrap();
-        resps.push(resp3);
+        let mut h3_config = Config::new().unwrap();
+        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
+        s.handshake().unwrap();
 
-        for _ in 0..resps.len() {
-            let (stream, ev) = s.poll_client().unwrap();
-            let ev_headers = Event::Headers {
-                list: resps[(stream / 4) as usize].clone(),
-                has_body: false,
-            };
-            assert_eq!(ev, ev_headers);
-            assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
-        }
+        // We'll send default data of 10 bytes on flow ID 0.
+        let result = (11, 0, 1);
 
-        assert_eq!(s.poll_client(), Err(Error::Done));
-    }
+        // Send request followed by DATAGRAM on client side.
+        let (stream, req) = s.send_request(false).unwrap();
 
-    #[test]
-    /// Send a request with no body, get a response with one DATA frame and an
-    /// empty FIN after reception from the client.
-    fn request_no_body_response_one_chunk_empty_fin() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        let body = s.send_body_client(stream, true).unwrap();
 
-        let (stream, req) = s.send_request(true).unwrap();
+        let mut recv_buf = vec![0; body.len()];
 
         let ev_headers = Event::Headers {
             list: req,
-            has_body: false,
+            has_body: true,
         };
 
+        s.send_dgram_client(0).unwrap();
+
+        // Now let's test the poll counts and yielding.
+        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
+
         assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
+
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
         assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
+        // Send response followed by DATAGRAM on server side
         let resp = s.send_response(stream, false).unwrap();
 
-        let body = s.send_body_server(stream, false).unwrap();
+        let body = s.send_body_server(stream, true).unwrap();
 
         let mut recv_buf = vec![0; body.len()];
 
@@ -2758,247 +7262,215 @@ mod tests {
             has_body: true,
         };
 
-        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        s.send_dgram_server(0).unwrap();
 
-        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
-        assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
+        // Now let's test the poll counts and yielding.
+        assert_eq!(s.poll_client(), Ok((0, Event::Datagram)));
 
-        assert_eq!(s.pipe.server.stream_send(stream, &[], true), Ok(0));
-        s.advance().ok();
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
 
-        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
         assert_eq!(s.poll_client(), Err(Error::Done));
-    }
-
-    #[test]
-    /// Try to send DATA frames before HEADERS.
-    fn body_response_before_headers() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
-
-        let (stream, req) = s.send_request(true).unwrap();
-        assert_eq!(stream, 0);
-
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: false,
-        };
 
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(result));
 
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
 
-        assert_eq!(
-            s.send_body_server(stream, true),
-            Err(Error::FrameUnexpected)
-        );
+        assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
 
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
         assert_eq!(s.poll_client(), Err(Error::Done));
     }
 
     #[test]
-    /// Try to send DATA frames on wrong streams, ensure the API returns an
-    /// error before anything hits the transport layer.
-    fn send_body_invalid_client_stream() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+    /// Send a multiple DATAGRAMs and requests. Ensure that poll
+    /// yield cycles and cleanly exits if data is read.
+    fn poll_yield_multi_read() {
+        let mut buf = [0; 65535];
 
-        assert_eq!(s.send_body_client(0, true), Err(Error::FrameUnexpected));
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+        config.enable_dgram(true, 100, 100);
 
-        assert_eq!(
-            s.send_body_client(s.client.control_stream_id.unwrap(), true),
-            Err(Error::FrameUnexpected)
-        );
+        let mut h3_config = Config::new().unwrap();
+        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
+        s.handshake().unwrap();
 
-        assert_eq!(
-            s.send_body_client(
-                s.client.local_qpack_streams.encoder_stream_id.unwrap(),
-                true
-            ),
-            Err(Error::FrameUnexpected)
-        );
+        // 10 bytes on flow ID 0 and 1.
+        let flow_0_result = (11, 0, 1);
+        let flow_1_result = (11, 1, 1);
 
-        assert_eq!(
-            s.send_body_client(
-                s.client.local_qpack_streams.decoder_stream_id.unwrap(),
-                true
-            ),
-            Err(Error::FrameUnexpected)
-        );
+        // Flow ID 0 is associated with a first request, and flow ID 1 with the
+        // one below.
+        let (stream, req) = s.send_request(false).unwrap();
 
-        assert_eq!(
-            s.send_body_client(s.client.peer_control_stream_id.unwrap(), true),
-            Err(Error::FrameUnexpected)
-        );
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-        assert_eq!(
-            s.send_body_client(
-                s.client.peer_qpack_streams.encoder_stream_id.unwrap(),
-                true
-            ),
-            Err(Error::FrameUnexpected)
-        );
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
 
-        assert_eq!(
-            s.send_body_client(
-                s.client.peer_qpack_streams.decoder_stream_id.unwrap(),
-                true
-            ),
-            Err(Error::FrameUnexpected)
-        );
-    }
+        // Send requests followed by DATAGRAMs on client side.
+        let (stream, req) = s.send_request(false).unwrap();
 
-    #[test]
-    /// Try to send DATA frames on wrong streams, ensure the API returns an
-    /// error before anything hits the transport layer.
-    fn send_body_invalid_server_stream() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        let body = s.send_body_client(stream, true).unwrap();
 
-        assert_eq!(s.send_body_server(0, true), Err(Error::FrameUnexpected));
+        let mut recv_buf = vec![0; body.len()];
 
-        assert_eq!(
-            s.send_body_server(s.server.control_stream_id.unwrap(), true),
-            Err(Error::FrameUnexpected)
-        );
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-        assert_eq!(
-            s.send_body_server(
-                s.server.local_qpack_streams.encoder_stream_id.unwrap(),
-                true
-            ),
-            Err(Error::FrameUnexpected)
-        );
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(1).unwrap();
+        s.send_dgram_client(1).unwrap();
+        s.send_dgram_client(1).unwrap();
+        s.send_dgram_client(1).unwrap();
+        s.send_dgram_client(1).unwrap();
 
-        assert_eq!(
-            s.send_body_server(
-                s.server.local_qpack_streams.decoder_stream_id.unwrap(),
-                true
-            ),
-            Err(Error::FrameUnexpected)
-        );
+        // Now let's test the poll counts and yielding.
+        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
 
-        assert_eq!(
-            s.send_body_server(s.server.peer_control_stream_id.unwrap(), true),
-            Err(Error::FrameUnexpected)
-        );
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
 
-        assert_eq!(
-            s.send_body_server(
-                s.server.peer_qpack_streams.encoder_stream_id.unwrap(),
-                true
-            ),
-            Err(Error::FrameUnexpected)
-        );
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        assert_eq!(
-            s.send_body_server(
-                s.server.peer_qpack_streams.decoder_stream_id.unwrap(),
-                true
-            ),
-            Err(Error::FrameUnexpected)
-        );
-    }
+        // Second cycle, start to read
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-    #[test]
-    /// Send a MAX_PUSH_ID frame from the client on a valid stream.
-    fn max_push_id_from_client_good() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
 
-        s.send_frame_client(
-            frame::Frame::MaxPushId { push_id: 1 },
-            s.client.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
+        // Third cycle.
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_1_result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_1_result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_1_result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_1_result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_1_result));
         assert_eq!(s.poll_server(), Err(Error::Done));
-    }
 
-    #[test]
-    /// Send a MAX_PUSH_ID frame from the client on an invalid stream.
-    fn max_push_id_from_client_bad_stream() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        // Send response followed by DATAGRAM on server side
+        let resp = s.send_response(stream, false).unwrap();
 
-        let (stream, req) = s.send_request(false).unwrap();
+        let body = s.send_body_server(stream, true).unwrap();
 
-        s.send_frame_client(
-            frame::Frame::MaxPushId { push_id: 2 },
-            stream,
-            false,
-        )
-        .unwrap();
+        let mut recv_buf = vec![0; body.len()];
 
         let ev_headers = Event::Headers {
-            list: req,
+            list: resp,
             has_body: true,
         };
 
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Err(Error::FrameUnexpected));
-    }
+        s.send_dgram_server(0).unwrap();
+        s.send_dgram_server(0).unwrap();
+        s.send_dgram_server(0).unwrap();
+        s.send_dgram_server(0).unwrap();
+        s.send_dgram_server(0).unwrap();
+        s.send_dgram_server(1).unwrap();
+        s.send_dgram_server(1).unwrap();
+        s.send_dgram_server(1).unwrap();
+        s.send_dgram_server(1).unwrap();
+        s.send_dgram_server(1).unwrap();
 
-    #[test]
-    /// Send a sequence of MAX_PUSH_ID frames from the client that attempt to
-    /// reduce the limit.
-    fn max_push_id_from_client_limit_reduction() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        assert_eq!(s.poll_client(), Ok((0, Event::Datagram)));
 
-        s.send_frame_client(
-            frame::Frame::MaxPushId { push_id: 2 },
-            s.client.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
 
-        s.send_frame_client(
-            frame::Frame::MaxPushId { push_id: 1 },
-            s.client.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
+        assert_eq!(s.poll_client(), Err(Error::Done));
 
-        assert_eq!(s.poll_server(), Err(Error::IdError));
+        // Second cycle, start to read
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_0_result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_0_result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_0_result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+
+        assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+
+        assert_eq!(s.poll_client(), Err(Error::Done));
+
+        // Third cycle.
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_0_result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_0_result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_1_result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_1_result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_1_result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_1_result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_1_result));
+        assert_eq!(s.poll_client(), Err(Error::Done));
     }
 
     #[test]
-    /// Send a MAX_PUSH_ID frame from the server, which is forbidden.
-    fn max_push_id_from_server() {
+    /// Tests that the Finished event is not issued for streams of unknown type
+    /// (e.g. GREASE).
+    fn finished_is_for_requests() {
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        s.send_frame_server(
-            frame::Frame::MaxPushId { push_id: 1 },
-            s.server.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        assert_eq!(s.poll_client(), Err(Error::FrameUnexpected));
+        assert_eq!(s.client.open_grease_stream(&mut s.pipe.client), Ok(()));
+        assert_eq!(s.pipe.advance(), Ok(()));
+
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.poll_server(), Err(Error::Done));
     }
 
     #[test]
-    /// Send a PUSH_PROMISE frame from the client, which is forbidden.
-    fn push_promise_from_client() {
+    /// Tests that streams are marked as finished only once.
+    fn finished_once() {
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
         let (stream, req) = s.send_request(false).unwrap();
+        let body = s.send_body_client(stream, true).unwrap();
 
-        let header_block = s.client.encode_header_block(&req).unwrap();
-
-        s.send_frame_client(
-            frame::Frame::PushPromise {
-                push_id: 1,
-                header_block,
-            },
-            stream,
-            false,
-        )
-        .unwrap();
+        let mut recv_buf = vec![0; body.len()];
 
         let ev_headers = Event::Headers {
             list: req,
@@ -3006,385 +7478,385 @@ mod tests {
         };
 
         assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Err(Error::FrameUnexpected));
-    }
-
-    #[test]
-    /// Send a CANCEL_PUSH frame from the client.
-    fn cancel_push_from_client() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
 
-        s.send_frame_client(
-            frame::Frame::CancelPush { push_id: 1 },
-            s.client.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
 
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Err(Error::Done));
         assert_eq!(s.poll_server(), Err(Error::Done));
     }
 
     #[test]
-    /// Send a CANCEL_PUSH frame from the client on an invalid stream.
-    fn cancel_push_from_client_bad_stream() {
+    /// Tests that the Data event is properly re-armed.
+    fn data_event_rearm() {
+        let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
         let (stream, req) = s.send_request(false).unwrap();
 
-        s.send_frame_client(
-            frame::Frame::CancelPush { push_id: 2 },
-            stream,
-            false,
-        )
-        .unwrap();
+        let mut recv_buf = vec![0; bytes.len()];
 
         let ev_headers = Event::Headers {
             list: req,
             has_body: true,
         };
 
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Err(Error::FrameUnexpected));
-    }
-
-    #[test]
-    /// Send a CANCEL_PUSH frame from the client.
-    fn cancel_push_from_server() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
-
-        s.send_frame_server(
-            frame::Frame::CancelPush { push_id: 1 },
-            s.server.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
-
-        assert_eq!(s.poll_client(), Err(Error::Done));
-    }
-
-    #[test]
-    /// Send a GOAWAY frame from the client.
-    fn goaway_from_client_good() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        // Manually send an incomplete DATA frame (i.e. the frame size is longer
+        // than the actual data sent).
+        {
+            let mut d = [42; 10];
+            let mut b = octets::OctetsMut::with_slice(&mut d);
 
-        s.client.send_goaway(&mut s.pipe.client, 100).unwrap();
+            b.put_varint(frame::DATA_FRAME_TYPE_ID).unwrap();
+            b.put_varint(bytes.len() as u64).unwrap();
+            let off = b.off();
+            s.pipe.client.stream_send(stream, &d[..off], false).unwrap();
 
-        s.advance().ok();
+            assert_eq!(
+                s.pipe.client.stream_send(stream, &bytes[..5], false),
+                Ok(5)
+            );
 
-        // TODO: server push
-        assert_eq!(s.poll_server(), Ok((0, Event::GoAway)));
-    }
+            s.advance().ok();
+        }
 
-    #[test]
-    /// Send a GOAWAY frame from the server.
-    fn goaway_from_server_good() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        s.server.send_goaway(&mut s.pipe.server, 4000).unwrap();
+        // Read the available body data.
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(5));
 
+        // Send the remaining DATA payload.
+        assert_eq!(s.pipe.client.stream_send(stream, &bytes[5..], false), Ok(5));
         s.advance().ok();
 
-        assert_eq!(s.poll_client(), Ok((4000, Event::GoAway)));
-    }
-
-    #[test]
-    /// A client MUST NOT send a request after it receives GOAWAY.
-    fn client_request_after_goaway() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        s.server.send_goaway(&mut s.pipe.server, 4000).unwrap();
+        // Read the rest of the body data.
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(5));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        s.advance().ok();
+        // Send more data.
+        let body = s.send_body_client(stream, false).unwrap();
 
-        assert_eq!(s.poll_client(), Ok((4000, Event::GoAway)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        assert_eq!(s.send_request(true), Err(Error::FrameUnexpected));
-    }
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
 
-    #[test]
-    /// Send a GOAWAY frame from the server, using an invalid goaway ID.
-    fn goaway_from_server_invalid_id() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        // Send more data, then trailers.
+        let body = s.send_body_client(stream, false).unwrap();
 
-        s.send_frame_server(
-            frame::Frame::GoAway { id: 1 },
-            s.server.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
+        let trailers = vec![Header::new(b"hello", b"world")];
 
-        assert_eq!(s.poll_client(), Err(Error::IdError));
-    }
+        s.client
+            .send_trailers(&mut s.pipe.client, stream, &trailers)
+            .unwrap();
 
-    #[test]
-    /// Send multiple GOAWAY frames from the server, that increase the goaway
-    /// ID.
-    fn goaway_from_server_increase_id() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        let ev_trailers = Event::Trailers { list: trailers };
 
-        s.send_frame_server(
-            frame::Frame::GoAway { id: 0 },
-            s.server.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
+        s.advance().ok();
 
-        s.send_frame_server(
-            frame::Frame::GoAway { id: 4 },
-            s.server.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
 
-        assert_eq!(s.poll_client(), Ok((0, Event::GoAway)));
+        assert_eq!(s.poll_server(), Ok((stream, ev_trailers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
 
-        assert_eq!(s.poll_client(), Err(Error::IdError));
-    }
+        let (stream, req) = s.send_request(false).unwrap();
 
-    #[test]
-    /// Ensure quiche allocates streams for client and server roles as expected.
-    fn uni_stream_local_counting() {
-        let config = Config::new().unwrap();
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-        let h3_cln = Connection::new(&config, false, false).unwrap();
-        assert_eq!(h3_cln.next_uni_stream_id, 2);
+        // Manually send an incomplete DATA frame (i.e. only the header is sent).
+        {
+            let mut d = [42; 10];
+            let mut b = octets::OctetsMut::with_slice(&mut d);
 
-        let h3_srv = Connection::new(&config, true, false).unwrap();
-        assert_eq!(h3_srv.next_uni_stream_id, 3);
-    }
+            b.put_varint(frame::DATA_FRAME_TYPE_ID).unwrap();
+            b.put_varint(bytes.len() as u64).unwrap();
+            let off = b.off();
+            s.pipe.client.stream_send(stream, &d[..off], false).unwrap();
 
-    #[test]
-    /// Client opens multiple control streams, which is forbidden.
-    fn open_multiple_control_streams() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+            s.advance().ok();
+        }
 
-        let stream_id = s.client.next_uni_stream_id;
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        let mut d = [42; 8];
-        let mut b = octets::OctetsMut::with_slice(&mut d);
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Err(Error::Done));
 
-        s.pipe
-            .client
-            .stream_send(
-                stream_id,
-                b.put_varint(stream::HTTP3_CONTROL_STREAM_TYPE_ID).unwrap(),
-                false,
-            )
-            .unwrap();
+        assert_eq!(s.pipe.client.stream_send(stream, &bytes[..5], false), Ok(5));
 
         s.advance().ok();
 
-        assert_eq!(s.poll_server(), Err(Error::StreamCreationError));
-    }
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-    #[test]
-    /// Client closes the control stream, which is forbidden.
-    fn close_control_stream() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(5));
 
-        let mut control_stream_closed = false;
+        assert_eq!(s.pipe.client.stream_send(stream, &bytes[5..], false), Ok(5));
+        s.advance().ok();
 
-        s.send_frame_client(
-            frame::Frame::MaxPushId { push_id: 1 },
-            s.client.control_stream_id.unwrap(),
-            true,
-        )
-        .unwrap();
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        loop {
-            match s.server.poll(&mut s.pipe.server) {
-                Ok(_) => (),
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(5));
 
-                Err(Error::Done) => {
-                    break;
-                },
+        // Buffer multiple data frames.
+        let body = s.send_body_client(stream, false).unwrap();
+        s.send_body_client(stream, false).unwrap();
+        s.send_body_client(stream, false).unwrap();
 
-                Err(Error::ClosedCriticalStream) => {
-                    control_stream_closed = true;
-                    break;
-                },
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-                Err(_) => (),
-            }
+        {
+            let mut d = [42; 10];
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+
+            b.put_varint(frame::DATA_FRAME_TYPE_ID).unwrap();
+            b.put_varint(0).unwrap();
+            let off = b.off();
+            s.pipe.client.stream_send(stream, &d[..off], true).unwrap();
+
+            s.advance().ok();
         }
 
-        assert!(control_stream_closed);
+        let mut recv_buf = vec![0; bytes.len() * 3];
+
+        assert_eq!(
+            s.recv_body_server(stream, &mut recv_buf),
+            Ok(body.len() * 3)
+        );
     }
 
     #[test]
-    /// Client closes QPACK stream, which is forbidden.
-    fn close_qpack_stream() {
-        let mut s = Session::default().unwrap();
+    /// Tests that the Datagram event is properly re-armed.
+    fn dgram_event_rearm() {
+        let mut buf = [0; 65535];
+
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+        config.enable_dgram(true, 100, 100);
+
+        let mut h3_config = Config::new().unwrap();
+        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
         s.handshake().unwrap();
 
-        let mut qpack_stream_closed = false;
+        // 10 bytes on flow ID 0 and 1.
+        let flow_0_result = (11, 0, 1);
+        let flow_1_result = (11, 1, 1);
 
-        let stream_id = s.client.local_qpack_streams.encoder_stream_id.unwrap();
-        let d = [0; 1];
+        // Flow ID 0 is associated with a first request, and flow ID 1 with the
+        // one below.
+        let (stream, req) = s.send_request(false).unwrap();
 
-        s.pipe.client.stream_send(stream_id, &d, false).unwrap();
-        s.pipe.client.stream_send(stream_id, &d, true).unwrap();
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-        s.advance().ok();
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
 
-        loop {
-            match s.server.poll(&mut s.pipe.server) {
-                Ok(_) => (),
+        // Send requests followed by DATAGRAMs on client side.
+        let (stream, req) = s.send_request(false).unwrap();
 
-                Err(Error::Done) => {
-                    break;
-                },
+        let body = s.send_body_client(stream, true).unwrap();
 
-                Err(Error::ClosedCriticalStream) => {
-                    qpack_stream_closed = true;
-                    break;
-                },
+        let mut recv_buf = vec![0; body.len()];
 
-                Err(_) => (),
-            }
-        }
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-        assert!(qpack_stream_closed);
-    }
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(1).unwrap();
+        s.send_dgram_client(1).unwrap();
 
-    #[test]
-    /// Client sends QPACK data.
-    fn qpack_data() {
-        // TODO: QPACK instructions are ignored until dynamic table support is
-        // added so we just test that the data is safely ignored.
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
 
-        let e_stream_id = s.client.local_qpack_streams.encoder_stream_id.unwrap();
-        let d_stream_id = s.client.local_qpack_streams.decoder_stream_id.unwrap();
-        let d = [0; 20];
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
 
-        s.pipe.client.stream_send(e_stream_id, &d, false).unwrap();
-        s.advance().ok();
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
 
-        s.pipe.client.stream_send(d_stream_id, &d, false).unwrap();
-        s.advance().ok();
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
 
-        loop {
-            match s.server.poll(&mut s.pipe.server) {
-                Ok(_) => (),
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_1_result));
 
-                Err(Error::Done) => {
-                    break;
-                },
+        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_1_result));
 
-                Err(_) => {
-                    panic!();
-                },
-            }
-        }
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        s.send_dgram_client(0).unwrap();
+        s.send_dgram_client(1).unwrap();
+
+        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_1_result));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
     }
 
     #[test]
-    /// Tests limits for the stream state buffer maximum size.
-    fn max_state_buf_size() {
+    fn reset_stream() {
+        let mut buf = [0; 65535];
+
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        let req = vec![
-            Header::new(b":method", b"GET"),
-            Header::new(b":scheme", b"https"),
-            Header::new(b":authority", b"quic.tech"),
-            Header::new(b":path", b"/test"),
-            Header::new(b"user-agent", b"quiche-test"),
-        ];
-
-        assert_eq!(
-            s.client.send_request(&mut s.pipe.client, &req, false),
-            Ok(0)
-        );
-
-        s.advance().ok();
+        // Client sends request.
+        let (stream, req) = s.send_request(false).unwrap();
 
         let ev_headers = Event::Headers {
             list: req,
             has_body: true,
         };
 
-        assert_eq!(s.server.poll(&mut s.pipe.server), Ok((0, ev_headers)));
+        // Server sends response and closes stream.
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        // DATA frames don't consume the state buffer, so can be of any size.
-        let mut d = [42; 128];
-        let mut b = octets::OctetsMut::with_slice(&mut d);
+        let resp = s.send_response(stream, true).unwrap();
 
-        let frame_type = b.put_varint(frame::DATA_FRAME_TYPE_ID).unwrap();
-        s.pipe.client.stream_send(0, frame_type, false).unwrap();
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+
+        // Client sends RESET_STREAM, closing stream.
+        let frames = [crate::frame::Frame::ResetStream {
+            stream_id: stream,
+            error_code: 42,
+            final_size: 68,
+        }];
 
-        let frame_len = b.put_varint(1 << 24).unwrap();
-        s.pipe.client.stream_send(0, frame_len, false).unwrap();
+        let pkt_type = crate::packet::Type::Short;
+        assert_eq!(
+            s.pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Ok(39)
+        );
 
-        s.pipe.client.stream_send(0, &d, false).unwrap();
+        // Server issues Reset event for the stream.
+        assert_eq!(s.poll_server(), Ok((stream, Event::Reset(42))));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        s.advance().ok();
+        // Sending RESET_STREAM again shouldn't trigger another Reset event.
+        assert_eq!(
+            s.pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Ok(39)
+        );
 
-        assert_eq!(s.server.poll(&mut s.pipe.server), Ok((0, Event::Data)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+    }
 
-        // GREASE frames consume the state buffer, so need to be limited.
+    #[test]
+    fn reset_finished_at_server() {
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        let mut d = [42; 128];
-        let mut b = octets::OctetsMut::with_slice(&mut d);
+        // Client sends HEADERS and doesn't fin
+        let (stream, _req) = s.send_request(false).unwrap();
 
-        let frame_type = b.put_varint(148_764_065_110_560_899).unwrap();
-        s.pipe.client.stream_send(0, frame_type, false).unwrap();
+        // ..then Client sends RESET_STREAM
+        assert_eq!(
+            s.pipe.client.stream_shutdown(0, crate::Shutdown::Write, 0),
+            Ok(())
+        );
 
-        let frame_len = b.put_varint(1 << 24).unwrap();
-        s.pipe.client.stream_send(0, frame_len, false).unwrap();
+        assert_eq!(s.pipe.advance(), Ok(()));
 
-        s.pipe.client.stream_send(0, &d, false).unwrap();
+        // Server receives just a reset
+        assert_eq!(s.poll_server(), Ok((stream, Event::Reset(0))));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        s.advance().ok();
+        // Client sends HEADERS and fin
+        let (stream, req) = s.send_request(true).unwrap();
 
-        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::ExcessiveLoad));
+        // ..then Client sends RESET_STREAM
+        assert_eq!(
+            s.pipe.client.stream_shutdown(4, crate::Shutdown::Write, 0),
+            Ok(())
+        );
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        // Server receives headers and fin.
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
     }
 
     #[test]
-    /// Tests that DATA frames are properly truncated depending on the request
-    /// stream's outgoing flow control capacity.
-    fn stream_backpressure() {
-        let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
-
+    /// State of streams reset before headers are received is released.
+    fn reset_before_headers_collects_state() {
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        let (stream, req) = s.send_request(false).unwrap();
+        // Client sends HEADERS and doesn't fin, then resets the stream before
+        // the server reads the headers.
+        let (stream, _req) = s.send_request(false).unwrap();
 
-        let total_data_frames = 6;
+        assert_eq!(
+            s.pipe
+                .client
+                .stream_shutdown(stream, crate::Shutdown::Write, 42),
+            Ok(())
+        );
 
-        for _ in 0..total_data_frames {
-            assert_eq!(
-                s.client
-                    .send_body(&mut s.pipe.client, stream, &bytes, false),
-                Ok(bytes.len())
-            );
+        assert_eq!(s.pipe.advance(), Ok(()));
 
-            s.advance().ok();
-        }
+        assert_eq!(s.poll_server(), Ok((stream, Event::Reset(42))));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        assert_eq!(
-            s.client.send_body(&mut s.pipe.client, stream, &bytes, true),
-            Ok(bytes.len() - 2)
-        );
+        assert!(!s.server.streams.contains_key(&stream));
 
-        s.advance().ok();
+        // The server can't respond to a request it never received.
+        assert_eq!(s.send_response(stream, true), Err(Error::FrameUnexpected));
 
-        let mut recv_buf = vec![0; bytes.len()];
+        // Streams reset after the headers were received are kept around.
+        let (stream, req) = s.send_request(false).unwrap();
 
         let ev_headers = Event::Headers {
             list: req,
@@ -3392,342 +7864,326 @@ mod tests {
         };
 
         assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-
-        for _ in 0..total_data_frames {
-            assert_eq!(
-                s.recv_body_server(stream, &mut recv_buf),
-                Ok(bytes.len())
-            );
-        }
 
         assert_eq!(
-            s.recv_body_server(stream, &mut recv_buf),
-            Ok(bytes.len() - 2)
+            s.pipe
+                .client
+                .stream_shutdown(stream, crate::Shutdown::Write, 42),
+            Ok(())
         );
 
-        // Fin flag from last send_body() call was not sent as the buffer was
-        // only partially written.
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.pipe.advance(), Ok(()));
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Reset(42))));
+        assert!(s.server.streams.contains_key(&stream));
     }
 
     #[test]
-    /// Tests that the max header list size setting is enforced.
-    fn request_max_header_size_limit() {
-        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config.set_application_protos(b"\x02h3").unwrap();
-        config.set_initial_max_data(1500);
-        config.set_initial_max_stream_data_bidi_local(150);
-        config.set_initial_max_stream_data_bidi_remote(150);
-        config.set_initial_max_stream_data_uni(150);
-        config.set_initial_max_streams_bidi(5);
-        config.set_initial_max_streams_uni(5);
-        config.verify_peer(false);
-
-        let mut h3_config = Config::new().unwrap();
-        h3_config.set_max_field_section_size(65);
-
-        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
-
+    fn reset_finished_at_client() {
+        let mut buf = [0; 65535];
+        let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        let req = vec![
-            Header::new(b":method", b"GET"),
-            Header::new(b":scheme", b"https"),
-            Header::new(b":authority", b"quic.tech"),
-            Header::new(b":path", b"/test"),
-            Header::new(b"aaaaaaa", b"aaaaaaaa"),
-        ];
+        // Client sends HEADERS and doesn't fin
+        let (stream, req) = s.send_request(false).unwrap();
 
-        let stream = s
-            .client
-            .send_request(&mut s.pipe.client, &req, true)
-            .unwrap();
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-        s.advance().ok();
+        // Server receives headers.
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        assert_eq!(stream, 0);
+        // Server sends response and doesn't fin
+        s.send_response(stream, false).unwrap();
 
-        assert_eq!(s.poll_server(), Err(Error::ExcessiveLoad));
+        assert_eq!(s.pipe.advance(), Ok(()));
 
+        // .. then Server sends RESET_STREAM
         assert_eq!(
-            s.pipe.server.local_error.as_ref().unwrap().error_code,
-            Error::to_wire(Error::ExcessiveLoad)
+            s.pipe
+                .server
+                .stream_shutdown(stream, crate::Shutdown::Write, 0),
+            Ok(())
         );
-    }
-
-    #[test]
-    /// Tests that Error::TransportError contains a transport error.
-    fn transport_error() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
-
-        let req = vec![
-            Header::new(b":method", b"GET"),
-            Header::new(b":scheme", b"https"),
-            Header::new(b":authority", b"quic.tech"),
-            Header::new(b":path", b"/test"),
-            Header::new(b"user-agent", b"quiche-test"),
-        ];
 
-        // We need to open all streams in the same flight, so we can't use the
-        // Session::send_request() method because it also calls advance(),
-        // otherwise the server would send a MAX_STREAMS frame and the client
-        // wouldn't hit the streams limit.
-        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(0));
-        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(4));
-        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(8));
-        assert_eq!(
-            s.client.send_request(&mut s.pipe.client, &req, true),
-            Ok(12)
-        );
-        assert_eq!(
-            s.client.send_request(&mut s.pipe.client, &req, true),
-            Ok(16)
-        );
+        assert_eq!(s.pipe.advance(), Ok(()));
 
-        assert_eq!(
-            s.client.send_request(&mut s.pipe.client, &req, true),
-            Err(Error::TransportError(crate::Error::StreamLimit))
-        );
-    }
+        // Client receives Reset only
+        assert_eq!(s.poll_client(), Ok((stream, Event::Reset(0))));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-    #[test]
-    /// Tests that sending DATA before HEADERS causes an error.
-    fn data_before_headers() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        // Client sends headers and fin.
+        let (stream, req) = s.send_request(true).unwrap();
 
-        let mut d = [42; 128];
-        let mut b = octets::OctetsMut::with_slice(&mut d);
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
 
-        let frame_type = b.put_varint(frame::DATA_FRAME_TYPE_ID).unwrap();
-        s.pipe.client.stream_send(0, frame_type, false).unwrap();
+        // Server receives headers and fin.
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        let frame_len = b.put_varint(5).unwrap();
-        s.pipe.client.stream_send(0, frame_len, false).unwrap();
+        // Server sends response and fin
+        let resp = s.send_response(stream, true).unwrap();
 
-        s.pipe.client.stream_send(0, b"hello", false).unwrap();
+        assert_eq!(s.pipe.advance(), Ok(()));
 
-        s.advance().ok();
+        // ..then Server sends RESET_STREAM
+        let frames = [crate::frame::Frame::ResetStream {
+            stream_id: stream,
+            error_code: 42,
+            final_size: 68,
+        }];
 
+        let pkt_type = crate::packet::Type::Short;
         assert_eq!(
-            s.server.poll(&mut s.pipe.server),
-            Err(Error::FrameUnexpected)
+            s.pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Ok(39)
         );
+
+        assert_eq!(s.pipe.advance(), Ok(()));
+
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: false,
+        };
+
+        // Client receives headers and fin.
+        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
     }
 
     #[test]
-    /// Tests that calling poll() after an error occured does nothing.
-    fn poll_after_error() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
-
-        let mut d = [42; 128];
-        let mut b = octets::OctetsMut::with_slice(&mut d);
+    fn header_canonical() {
+        assert_eq!(
+            Header::canonical(b"Content-Type", b"\t text/html "),
+            Ok(Header::new(b"content-type", b"text/html"))
+        );
 
-        let frame_type = b.put_varint(148_764_065_110_560_899).unwrap();
-        s.pipe.client.stream_send(0, frame_type, false).unwrap();
+        assert_eq!(
+            Header::canonical(b":Method", b"GET"),
+            Ok(Header::new(b":method", b"GET"))
+        );
 
-        let frame_len = b.put_varint(1 << 24).unwrap();
-        s.pipe.client.stream_send(0, frame_len, false).unwrap();
+        assert_eq!(
+            Header::canonical(b"x-empty", b"   "),
+            Ok(Header::new(b"x-empty", b""))
+        );
 
-        s.pipe.client.stream_send(0, &d, false).unwrap();
+        assert_eq!(Header::canonical(b"", b"v"), Err(HeaderError::EmptyName));
+        assert_eq!(Header::canonical(b":", b"v"), Err(HeaderError::EmptyName));
 
-        s.advance().ok();
+        assert_eq!(
+            Header::canonical(b"foo bar", b"v"),
+            Err(HeaderError::InvalidNameChar(b' '))
+        );
 
-        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::ExcessiveLoad));
+        assert_eq!(
+            Header::canonical(b"foo", b"a\r\n b"),
+            Err(HeaderError::ObsFold)
+        );
 
-        // Try to call poll() again after an error occurred.
-        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::Done));
+        assert_eq!(
+            Header::canonical(b"foo", b"a\r\nb"),
+            Err(HeaderError::InvalidValueChar(b'\r'))
+        );
     }
 
     #[test]
-    /// Tests that we limit sending HEADERS based on the stream capacity.
-    fn headers_blocked() {
-        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config.set_application_protos(b"\x02h3").unwrap();
-        config.set_initial_max_data(70);
-        config.set_initial_max_stream_data_bidi_local(150);
-        config.set_initial_max_stream_data_bidi_remote(150);
-        config.set_initial_max_stream_data_uni(150);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(5);
-        config.verify_peer(false);
+    fn header_validation() {
+        assert_eq!(validate_header_name(b"x-custom_header"), Ok(()));
+        assert_eq!(validate_header_name(b":path"), Ok(()));
 
-        let mut h3_config = Config::new().unwrap();
+        assert_eq!(
+            validate_header_name(b"X-Custom"),
+            Err(HeaderError::UppercaseName)
+        );
 
-        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
+        assert_eq!(
+            validate_header_name(b"a:b"),
+            Err(HeaderError::InvalidNameChar(b':'))
+        );
 
-        s.handshake().unwrap();
+        assert_eq!(
+            validate_header_name(b"caf\xc3\xa9"),
+            Err(HeaderError::InvalidNameChar(0xc3))
+        );
 
-        let req = vec![
-            Header::new(b":method", b"GET"),
-            Header::new(b":scheme", b"https"),
-            Header::new(b":authority", b"quic.tech"),
-            Header::new(b":path", b"/test"),
-        ];
+        assert_eq!(validate_header_value(b"a\tb \x80\xff"), Ok(()));
+        assert_eq!(validate_header_value(b""), Ok(()));
 
-        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(0));
+        assert_eq!(
+            validate_header_value(b"a\x00b"),
+            Err(HeaderError::InvalidValueChar(0))
+        );
 
         assert_eq!(
-            s.client.send_request(&mut s.pipe.client, &req, true),
-            Err(Error::StreamBlocked)
+            validate_header_value(b"a\x7f"),
+            Err(HeaderError::InvalidValueChar(0x7f))
         );
 
-        s.advance().ok();
+        assert_eq!(validate_header_value(b"a\n\tb"), Err(HeaderError::ObsFold));
 
-        // Once the server gives flow control credits back, we can send the
-        // request.
-        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(4));
+        assert_eq!(
+            validate_header_value(b" a"),
+            Err(HeaderError::SurroundingWhitespace)
+        );
+
+        assert_eq!(Error::from(HeaderError::EmptyName), Error::MessageError);
     }
 
     #[test]
-    /// Ensure StreamBlocked when connection flow control prevents headers.
-    fn headers_blocked_on_conn() {
-        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config.set_application_protos(b"\x02h3").unwrap();
-        config.set_initial_max_data(70);
-        config.set_initial_max_stream_data_bidi_local(150);
-        config.set_initial_max_stream_data_bidi_remote(150);
-        config.set_initial_max_stream_data_uni(150);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(5);
-        config.verify_peer(false);
-
-        let mut h3_config = Config::new().unwrap();
-
-        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
-
-        s.handshake().unwrap();
-
-        // After the HTTP handshake, some bytes of connection flow control have
-        // been consumed. Fill the connection with more grease data on the control
-        // stream.
-        let d = [42; 28];
-        assert_eq!(s.pipe.client.stream_send(2, &d, false), Ok(23));
-
+    fn field_section_validation() {
         let req = vec![
             Header::new(b":method", b"GET"),
             Header::new(b":scheme", b"https"),
             Header::new(b":authority", b"quic.tech"),
             Header::new(b":path", b"/test"),
+            Header::new(b"te", b"trailers"),
         ];
 
-        // There is 0 connection-level flow control, so sending a request is
-        // blocked.
-        assert_eq!(
-            s.client.send_request(&mut s.pipe.client, &req, true),
-            Err(Error::StreamBlocked)
-        );
+        assert_eq!(validate_field_section(&req, true, false), Ok(()));
 
-        // Emit the control stream data and drain it at the server via poll() to
-        // consumes it via poll() and gives back flow control.
-        s.advance().ok();
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        s.advance().ok();
+        // Response pseudo-headers aren't allowed in requests.
+        assert!(validate_field_section(&req, false, false).is_err());
 
-        // Now we can send the request.
-        assert_eq!(s.client.send_request(&mut s.pipe.client, &req, true), Ok(0));
-    }
+        // Neither are pseudo-headers in trailers.
+        assert!(validate_field_section(&req, true, true).is_err());
 
-    #[test]
-    /// Test handling of 0-length DATA writes with and without fin.
-    fn zero_length_data() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        let connect = vec![
+            Header::new(b":method", b"CONNECT"),
+            Header::new(b":authority", b"quic.tech:443"),
+        ];
 
-        let (stream, req) = s.send_request(false).unwrap();
+        assert_eq!(validate_field_section(&connect, true, false), Ok(()));
 
-        assert_eq!(
-            s.client.send_body(&mut s.pipe.client, 0, b"", false),
-            Err(Error::Done)
-        );
-        assert_eq!(s.client.send_body(&mut s.pipe.client, 0, b"", true), Ok(0));
+        let invalid: &[&[Header]] = &[
+            // Missing :path.
+            &[
+                Header::new(b":method", b"GET"),
+                Header::new(b":scheme", b"https"),
+            ],
+            // Empty :path.
+            &[
+                Header::new(b":method", b"GET"),
+                Header::new(b":scheme", b"https"),
+                Header::new(b":path", b""),
+            ],
+            // Duplicate pseudo-header.
+            &[
+                Header::new(b":method", b"GET"),
+                Header::new(b":method", b"GET"),
+                Header::new(b":scheme", b"https"),
+                Header::new(b":path", b"/"),
+            ],
+            // Pseudo-header after a regular field.
+            &[
+                Header::new(b":method", b"GET"),
+                Header::new(b":scheme", b"https"),
+                Header::new(b"user-agent", b"quiche-test"),
+                Header::new(b":path", b"/"),
+            ],
+            // Unknown pseudo-header.
+            &[
+                Header::new(b":method", b"GET"),
+                Header::new(b":scheme", b"https"),
+                Header::new(b":path", b"/"),
+                Header::new(b":foo", b"bar"),
+            ],
+            // Connection-specific field.
+            &[
+                Header::new(b":method", b"GET"),
+                Header::new(b":scheme", b"https"),
+                Header::new(b":path", b"/"),
+                Header::new(b"connection", b"close"),
+            ],
+            // TE with a value other than "trailers".
+            &[
+                Header::new(b":method", b"GET"),
+                Header::new(b":scheme", b"https"),
+                Header::new(b":path", b"/"),
+                Header::new(b"te", b"gzip"),
+            ],
+            // Uppercase field name.
+            &[
+                Header::new(b":method", b"GET"),
+                Header::new(b":scheme", b"https"),
+                Header::new(b":path", b"/"),
+                Header::new(b"User-Agent", b"quiche-test"),
+            ],
+        ];
 
-        s.advance().ok();
+        for headers in invalid {
+            assert_eq!(
+                validate_field_section(headers, true, false),
+                Err(Error::MessageError)
+            );
+        }
 
-        let mut recv_buf = vec![0; 100];
+        let resp = vec![Header::new(b":status", b"200")];
 
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: true,
-        };
+        assert_eq!(validate_field_section(&resp, false, false), Ok(()));
 
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        let resp = vec![Header::new(b":status", b"2000")];
 
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Err(Error::Done));
+        assert!(validate_field_section(&resp, false, false).is_err());
 
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        let trailers = vec![Header::new(b"grpc-status", b"0")];
 
-        let resp = s.send_response(stream, false).unwrap();
+        assert_eq!(validate_field_section(&trailers, false, true), Ok(()));
+    }
 
+    #[test]
+    fn priority_parse() {
+        assert_eq!(Priority::parse(b""), Priority::default());
+        assert_eq!(Priority::parse(b"u=5"), Priority::new(5, false));
+        assert_eq!(Priority::parse(b"u=1, i"), Priority::new(1, true));
+        assert_eq!(Priority::parse(b"i=?1,u=0"), Priority::new(0, true));
+        assert_eq!(Priority::parse(b"u=2, i=?0"), Priority::new(2, false));
+
+        // Later members override earlier ones.
+        assert_eq!(Priority::parse(b"u=2, u=6"), Priority::new(6, false));
+
+        // Parameters are ignored.
+        assert_eq!(Priority::parse(b"u=4;a=b, i;c"), Priority::new(4, true));
+
+        // Invalid and out of range values are ignored.
+        assert_eq!(Priority::parse(b"u=8"), Priority::default());
+        assert_eq!(Priority::parse(b"u=-1"), Priority::default());
+        assert_eq!(Priority::parse(b"u=a, i=1"), Priority::default());
+        assert_eq!(Priority::parse(b"u=+2"), Priority::default());
+
+        // Unknown members are ignored, including strings with separators.
         assert_eq!(
-            s.server.send_body(&mut s.pipe.server, 0, b"", false),
-            Err(Error::Done)
+            Priority::parse(b"foo=\"u=1, i\", u=6"),
+            Priority::new(6, false)
         );
-        assert_eq!(s.server.send_body(&mut s.pipe.server, 0, b"", true), Ok(0));
-
-        s.advance().ok();
-
-        let ev_headers = Event::Headers {
-            list: resp,
-            has_body: true,
-        };
-
-        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
-
-        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
-        assert_eq!(s.recv_body_client(stream, &mut recv_buf), Err(Error::Done));
-
-        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
-        assert_eq!(s.poll_client(), Err(Error::Done));
     }
 
     #[test]
-    /// Tests that blocked 0-length DATA writes are reported correctly.
-    fn zero_length_data_blocked() {
-        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config.set_application_protos(b"\x02h3").unwrap();
-        config.set_initial_max_data(69);
-        config.set_initial_max_stream_data_bidi_local(150);
-        config.set_initial_max_stream_data_bidi_remote(150);
-        config.set_initial_max_stream_data_uni(150);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(5);
-        config.verify_peer(false);
+    fn priority_serialize() {
+        assert_eq!(Priority::default().to_field_value(), b"");
+        assert_eq!(Priority::new(3, true).to_field_value(), b"i");
+        assert_eq!(Priority::new(0, false).to_field_value(), b"u=0");
+        assert_eq!(Priority::new(6, true).to_field_value(), b"u=6, i");
 
-        let mut h3_config = Config::new().unwrap();
+        // Urgency is capped.
+        assert_eq!(Priority::new(42, false).to_field_value(), b"u=7");
 
-        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
+        let priority = Priority::new(1, true);
+        assert_eq!(Priority::parse(&priority.to_field_value()), priority);
+    }
 
+    #[test]
+    /// The priority header field of a request is applied to its stream.
+    fn priority_header() {
+        let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
         let req = vec![
@@ -3735,284 +8191,260 @@ mod tests {
             Header::new(b":scheme", b"https"),
             Header::new(b":authority", b"quic.tech"),
             Header::new(b":path", b"/test"),
+            Header::new(b"priority", b"u=1, i"),
         ];
 
-        assert_eq!(
-            s.client.send_request(&mut s.pipe.client, &req, false),
-            Ok(0)
-        );
-
-        assert_eq!(
-            s.client.send_body(&mut s.pipe.client, 0, b"", true),
-            Err(Error::Done)
-        );
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
 
         s.advance().ok();
 
-        // Once the server gives flow control credits back, we can send the body.
-        assert_eq!(s.client.send_body(&mut s.pipe.client, 0, b"", true), Ok(0));
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        assert_eq!(s.server.peer_priority(stream), Some(Priority::new(1, true)));
+
+        let transport_stream = s.pipe.server.streams.get(stream).unwrap();
+        assert_eq!(transport_stream.urgency, 1 + PRIORITY_URGENCY_OFFSET);
+        assert!(transport_stream.incremental);
+
+        // The default response uses the signaled priority.
+        s.send_response(stream, false).unwrap();
+
+        let transport_stream = s.pipe.server.streams.get(stream).unwrap();
+        assert_eq!(transport_stream.urgency, 1 + PRIORITY_URGENCY_OFFSET);
     }
 
     #[test]
-    /// Tests that receiving a H3_DATAGRAM setting is ok.
-    fn dgram_setting() {
-        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config.set_application_protos(b"\x02h3").unwrap();
-        config.set_initial_max_data(70);
-        config.set_initial_max_stream_data_bidi_local(150);
-        config.set_initial_max_stream_data_bidi_remote(150);
-        config.set_initial_max_stream_data_uni(150);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(5);
-        config.enable_dgram(true, 1000, 1000);
-        config.verify_peer(false);
+    fn send_priority_update() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, _) = s.send_request(false).unwrap();
+
+        assert_eq!(
+            s.client.send_priority_update(
+                &mut s.pipe.client,
+                stream,
+                &Priority::new(0, true)
+            ),
+            Ok(())
+        );
 
-        let h3_config = Config::new().unwrap();
+        // Only request streams can be prioritized.
+        assert_eq!(
+            s.client.send_priority_update(
+                &mut s.pipe.client,
+                2,
+                &Priority::default()
+            ),
+            Err(Error::IdError)
+        );
 
-        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
-        assert_eq!(s.pipe.handshake(), Ok(()));
+        // Servers can't send PRIORITY_UPDATE frames.
+        assert_eq!(
+            s.server.send_priority_update(
+                &mut s.pipe.server,
+                stream,
+                &Priority::default()
+            ),
+            Err(Error::FrameUnexpected)
+        );
 
-        s.client.send_settings(&mut s.pipe.client).unwrap();
-        assert_eq!(s.pipe.advance(), Ok(()));
+        s.advance().ok();
 
-        // Before processing SETTINGS (via poll), HTTP/3 DATAGRAMS are not
-        // enabled.
-        assert!(!s.server.dgram_enabled_by_peer(&s.pipe.server));
+        // The frame can't be sent without a control stream.
+        let control_stream_id = s.client.control_stream_id.take();
 
-        // When everything is ok, poll returns Done and DATAGRAM is enabled.
-        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::Done));
-        assert!(s.server.dgram_enabled_by_peer(&s.pipe.server));
+        assert_eq!(
+            s.client.send_priority_update(
+                &mut s.pipe.client,
+                stream,
+                &Priority::default()
+            ),
+            Err(Error::InternalError)
+        );
 
-        // Now detect things on the client
-        s.server.send_settings(&mut s.pipe.server).unwrap();
-        assert_eq!(s.pipe.advance(), Ok(()));
-        assert!(!s.client.dgram_enabled_by_peer(&s.pipe.client));
-        assert_eq!(s.client.poll(&mut s.pipe.client), Err(Error::Done));
-        assert!(s.client.dgram_enabled_by_peer(&s.pipe.client));
+        s.client.control_stream_id = control_stream_id;
     }
 
     #[test]
-    /// Tests that receiving a H3_DATAGRAM setting when no TP is set generates
-    /// an error.
-    fn dgram_setting_no_tp() {
-        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config.set_application_protos(b"\x02h3").unwrap();
-        config.set_initial_max_data(70);
-        config.set_initial_max_stream_data_bidi_local(150);
-        config.set_initial_max_stream_data_bidi_remote(150);
-        config.set_initial_max_stream_data_uni(150);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(5);
-        config.verify_peer(false);
+    /// The urgency of PRIORITY_UPDATE frames is capped.
+    fn send_priority_update_max_urgency() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
 
-        let h3_config = Config::new().unwrap();
+        let (stream, req) = s.send_request(false).unwrap();
 
-        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
-        assert_eq!(s.pipe.handshake(), Ok(()));
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-        s.client.control_stream_id = Some(
-            s.client
-                .open_uni_stream(
-                    &mut s.pipe.client,
-                    stream::HTTP3_CONTROL_STREAM_TYPE_ID,
-                )
-                .unwrap(),
-        );
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
 
-        let settings = frame::Frame::Settings {
-            max_field_section_size: None,
-            qpack_max_table_capacity: None,
-            qpack_blocked_streams: None,
-            h3_datagram: Some(1),
-            grease: None,
-            raw: Default::default(),
+        let priority = Priority {
+            urgency: 9,
+            incremental: false,
         };
 
-        s.send_frame_client(settings, s.client.control_stream_id.unwrap(), false)
+        s.client
+            .send_priority_update(&mut s.pipe.client, stream, &priority)
             .unwrap();
+        s.advance().ok();
 
-        assert_eq!(s.pipe.advance(), Ok(()));
-
-        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::SettingsError));
+        assert_eq!(
+            s.poll_server(),
+            Ok((stream, Event::PriorityUpdate {
+                stream_id: stream,
+                priority: Priority::new(7, false),
+            }))
+        );
     }
 
     #[test]
-    /// Tests that receiving SETTINGS with prohibited values generates an error.
-    fn settings_h2_prohibited() {
-        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config.set_application_protos(b"\x02h3").unwrap();
-        config.set_initial_max_data(70);
-        config.set_initial_max_stream_data_bidi_local(150);
-        config.set_initial_max_stream_data_bidi_remote(150);
-        config.set_initial_max_stream_data_uni(150);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(5);
-        config.verify_peer(false);
+    /// Server receives a PRIORITY_UPDATE for an open request.
+    fn priority_update_request() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
 
-        let h3_config = Config::new().unwrap();
+        let (stream, req) = s.send_request(false).unwrap();
 
-        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
-        assert_eq!(s.pipe.handshake(), Ok(()));
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-        s.client.control_stream_id = Some(
-            s.client
-                .open_uni_stream(
-                    &mut s.pipe.client,
-                    stream::HTTP3_CONTROL_STREAM_TYPE_ID,
-                )
-                .unwrap(),
-        );
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.server.peer_priority(stream), None);
 
-        s.server.control_stream_id = Some(
-            s.server
-                .open_uni_stream(
-                    &mut s.pipe.server,
-                    stream::HTTP3_CONTROL_STREAM_TYPE_ID,
-                )
-                .unwrap(),
+        let priority = Priority::new(1, true);
+
+        s.client
+            .send_priority_update(&mut s.pipe.client, stream, &priority)
+            .unwrap();
+        s.advance().ok();
+
+        assert_eq!(
+            s.poll_server(),
+            Ok((stream, Event::PriorityUpdate {
+                stream_id: stream,
+                priority
+            }))
         );
+        assert_eq!(s.server.peer_priority(stream), Some(priority));
 
-        let frame_payload_len = 2u64;
-        let settings = [
-            frame::SETTINGS_FRAME_TYPE_ID as u8,
-            frame_payload_len as u8,
-            0x2, // 0x2 is a reserved setting type
-            1,
-        ];
+        let transport_stream = s.pipe.server.streams.get(stream).unwrap();
+        assert_eq!(transport_stream.urgency, 1 + PRIORITY_URGENCY_OFFSET);
+        assert!(transport_stream.incremental);
+    }
 
-        s.send_arbitrary_stream_data_client(
-            &settings,
+    #[test]
+    /// Server receives a PRIORITY_UPDATE before the request it refers to.
+    fn priority_update_before_request() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let priority = Priority::new(5, false);
+
+        s.send_frame_client(
+            frame::Frame::PriorityUpdateRequest {
+                prioritized_element_id: 0,
+                priority_field_value: priority.to_field_value(),
+            },
             s.client.control_stream_id.unwrap(),
             false,
         )
         .unwrap();
 
-        s.send_arbitrary_stream_data_server(
-            &settings,
-            s.server.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
+        assert_eq!(s.poll_server(), Err(Error::Done));
 
-        assert_eq!(s.pipe.advance(), Ok(()));
+        let (stream, req) = s.send_request(true).unwrap();
+        assert_eq!(stream, 0);
 
-        assert_eq!(s.server.poll(&mut s.pipe.server), Err(Error::SettingsError));
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
 
-        assert_eq!(s.client.poll(&mut s.pipe.client), Err(Error::SettingsError));
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.server.peer_priority(stream), Some(priority));
     }
 
     #[test]
-    /// Send a single DATAGRAM.
-    fn single_dgram() {
-        let mut buf = [0; 65535];
+    /// Send a PRIORITY_UPDATE frame from the client on a request stream.
+    fn priority_update_bad_stream() {
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        // We'll send default data of 10 bytes on flow ID 0.
-        let result = (11, 0, 1);
+        let (stream, req) = s.send_request(false).unwrap();
 
-        s.send_dgram_client(0).unwrap();
+        s.send_frame_client(
+            frame::Frame::PriorityUpdateRequest {
+                prioritized_element_id: stream,
+                priority_field_value: b"u=1".to_vec(),
+            },
+            stream,
+            false,
+        )
+        .unwrap();
 
-        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
 
-        s.send_dgram_server(0).unwrap();
-        assert_eq!(s.poll_client(), Ok((0, Event::Datagram)));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(result));
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Err(Error::FrameUnexpected));
     }
 
     #[test]
-    /// Send multiple DATAGRAMs.
-    fn multiple_dgram() {
-        let mut buf = [0; 65535];
+    /// Send a PRIORITY_UPDATE frame referring to a non-request stream.
+    fn priority_update_bad_id() {
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        // We'll send default data of 10 bytes on flow ID 0.
-        let result = (11, 0, 1);
-
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(0).unwrap();
-
-        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Err(Error::Done));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-
-        s.send_dgram_server(0).unwrap();
-        s.send_dgram_server(0).unwrap();
-        s.send_dgram_server(0).unwrap();
+        s.send_frame_client(
+            frame::Frame::PriorityUpdateRequest {
+                prioritized_element_id: 2,
+                priority_field_value: b"u=1".to_vec(),
+            },
+            s.client.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
 
-        assert_eq!(s.poll_client(), Ok((0, Event::Datagram)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Err(Error::Done));
-        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.poll_server(), Err(Error::IdError));
     }
 
     #[test]
-    /// Send more DATAGRAMs than the send queue allows.
-    fn multiple_dgram_overflow() {
-        let mut buf = [0; 65535];
+    /// Send a PRIORITY_UPDATE frame from the server, which is forbidden.
+    fn priority_update_from_server() {
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        // We'll send default data of 10 bytes on flow ID 0.
-        let result = (11, 0, 1);
-
-        // Five DATAGRAMs
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(0).unwrap();
-
-        // Only 3 independent DATAGRAM events will fire.
-        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Err(Error::Done));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        s.send_frame_server(
+            frame::Frame::PriorityUpdateRequest {
+                prioritized_element_id: 0,
+                priority_field_value: b"u=1".to_vec(),
+            },
+            s.server.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_client(), Err(Error::FrameUnexpected));
     }
 
     #[test]
-    /// Send a single DATAGRAM and request. Ensure that poll continuously cycles
-    /// between the two types if the data is not read.
-    fn poll_yield_cycling() {
+    /// Forcing the GREASE index makes the reserved settings deterministic.
+    fn grease_settings_forced() {
         let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -4025,42 +8457,23 @@ mod tests {
         config.set_initial_max_stream_data_bidi_local(150);
         config.set_initial_max_stream_data_bidi_remote(150);
         config.set_initial_max_stream_data_uni(150);
-        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_bidi(5);
         config.set_initial_max_streams_uni(5);
+        config.set_grease_index(0);
         config.verify_peer(false);
-        config.enable_dgram(true, 100, 100);
-
-        let mut h3_config = Config::new().unwrap();
-        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
-        s.handshake().unwrap();
 
-        // Send request followed by DATAGRAM on client side.
-        let (stream, req) = s.send_request(false).unwrap();
-
-        s.send_body_client(stream, true).unwrap();
-
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: true,
-        };
-
-        s.send_dgram_client(0).unwrap();
-
-        // Now let's test the poll counts and yielding.
-        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
+        let h3_config = Config::new().unwrap();
 
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        assert_eq!(s.handshake(), Ok(()));
 
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        let raw = s.server.peer_settings_raw().unwrap();
+        assert!(raw.contains(&(0x21, 0x21)));
     }
 
     #[test]
-    /// Send a single DATAGRAM and request. Ensure that poll
-    /// yield cycles and cleanly exits if data is read.
-    fn poll_yield_single_read() {
-        let mut buf = [0; 65535];
-
+    /// Reserved settings are not sent when disabled.
+    fn grease_settings_disabled() {
         let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -4073,86 +8486,25 @@ mod tests {
         config.set_initial_max_stream_data_bidi_local(150);
         config.set_initial_max_stream_data_bidi_remote(150);
         config.set_initial_max_stream_data_uni(150);
-        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_bidi(5);
         config.set_initial_max_streams_uni(5);
+        config.set_grease_index(0);
         config.verify_peer(false);
-        config.enable_dgram(true, 100, 100);
 
         let mut h3_config = Config::new().unwrap();
-        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
-        s.handshake().unwrap();
-
-        // We'll send default data of 10 bytes on flow ID 0.
-        let result = (11, 0, 1);
-
-        // Send request followed by DATAGRAM on client side.
-        let (stream, req) = s.send_request(false).unwrap();
-
-        let body = s.send_body_client(stream, true).unwrap();
-
-        let mut recv_buf = vec![0; body.len()];
-
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: true,
-        };
-
-        s.send_dgram_client(0).unwrap();
-
-        // Now let's test the poll counts and yielding.
-        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
-
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-
-        assert_eq!(s.poll_server(), Err(Error::Done));
-
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(result));
-
-        assert_eq!(s.poll_server(), Err(Error::Done));
-
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-
-        // Send response followed by DATAGRAM on server side
-        let resp = s.send_response(stream, false).unwrap();
-
-        let body = s.send_body_server(stream, true).unwrap();
-
-        let mut recv_buf = vec![0; body.len()];
-
-        let ev_headers = Event::Headers {
-            list: resp,
-            has_body: true,
-        };
-
-        s.send_dgram_server(0).unwrap();
-
-        // Now let's test the poll counts and yielding.
-        assert_eq!(s.poll_client(), Ok((0, Event::Datagram)));
-
-        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
-
-        assert_eq!(s.poll_client(), Err(Error::Done));
-
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(result));
-
-        assert_eq!(s.poll_client(), Err(Error::Done));
+        h3_config.grease_settings(false);
+        h3_config.grease_streams(false);
 
-        assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        assert_eq!(s.handshake(), Ok(()));
 
-        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
-        assert_eq!(s.poll_client(), Err(Error::Done));
+        let raw = s.server.peer_settings_raw().unwrap();
+        assert!(!raw.iter().any(|(id, _)| *id == 0x21));
     }
 
     #[test]
-    /// Send a multiple DATAGRAMs and requests. Ensure that poll
-    /// yield cycles and cleanly exits if data is read.
-    fn poll_yield_multi_read() {
-        let mut buf = [0; 65535];
-
+    /// Send requests with headers compressed using the QPACK dynamic table.
+    fn qpack_dynamic_table() {
         let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -4165,346 +8517,360 @@ mod tests {
         config.set_initial_max_stream_data_bidi_local(150);
         config.set_initial_max_stream_data_bidi_remote(150);
         config.set_initial_max_stream_data_uni(150);
-        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_bidi(5);
         config.set_initial_max_streams_uni(5);
         config.verify_peer(false);
-        config.enable_dgram(true, 100, 100);
 
         let mut h3_config = Config::new().unwrap();
-        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
-        s.handshake().unwrap();
-
-        // 10 bytes on flow ID 0 and 2.
-        let flow_0_result = (11, 0, 1);
-        let flow_2_result = (11, 2, 1);
-
-        // Send requests followed by DATAGRAMs on client side.
-        let (stream, req) = s.send_request(false).unwrap();
-
-        let body = s.send_body_client(stream, true).unwrap();
-
-        let mut recv_buf = vec![0; body.len()];
-
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: true,
-        };
-
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(2).unwrap();
-        s.send_dgram_client(2).unwrap();
-        s.send_dgram_client(2).unwrap();
-        s.send_dgram_client(2).unwrap();
-        s.send_dgram_client(2).unwrap();
-
-        // Now let's test the poll counts and yielding.
-        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
-
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        h3_config.set_qpack_max_table_capacity(1024);
+        h3_config.set_qpack_blocked_streams(10);
 
-        assert_eq!(s.poll_server(), Err(Error::Done));
-
-        // Second cycle, start to read
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
-
-        assert_eq!(s.poll_server(), Err(Error::Done));
-
-        // Third cycle.
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_2_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_2_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_2_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_2_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_2_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
 
-        // Send response followed by DATAGRAM on server side
-        let resp = s.send_response(stream, false).unwrap();
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+            Header::new(b"user-agent", b"quiche-test"),
+            Header::new(b"x-custom", b"custom value"),
+        ];
 
-        let body = s.send_body_server(stream, true).unwrap();
+        // The first request inserts headers into the dynamic table, and the
+        // second one references them once they are acknowledged.
+        for _ in 0..2 {
+            let stream = s
+                .client
+                .send_request(&mut s.pipe.client, &req, true)
+                .unwrap();
 
-        let mut recv_buf = vec![0; body.len()];
+            s.advance().ok();
 
-        let ev_headers = Event::Headers {
-            list: resp,
-            has_body: true,
-        };
+            let ev_headers = Event::Headers {
+                list: req.clone(),
+                has_body: false,
+            };
 
-        s.send_dgram_server(0).unwrap();
-        s.send_dgram_server(0).unwrap();
-        s.send_dgram_server(0).unwrap();
-        s.send_dgram_server(0).unwrap();
-        s.send_dgram_server(0).unwrap();
-        s.send_dgram_server(2).unwrap();
-        s.send_dgram_server(2).unwrap();
-        s.send_dgram_server(2).unwrap();
-        s.send_dgram_server(2).unwrap();
-        s.send_dgram_server(2).unwrap();
+            assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+            assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+            assert_eq!(s.poll_server(), Err(Error::Done));
 
-        assert_eq!(s.poll_client(), Ok((0, Event::Datagram)));
+            s.advance().ok();
 
-        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_client(), Ok((stream, Event::Data)));
+            assert_eq!(s.poll_client(), Err(Error::Done));
 
-        assert_eq!(s.poll_client(), Err(Error::Done));
+            assert!(s.client.qpack_encoder.instructions().is_empty());
+            assert!(s.server.qpack_decoder.instructions().is_empty());
+        }
+    }
 
-        // Second cycle, start to read
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_0_result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_0_result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_0_result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
+    #[test]
+    /// Cancel blocked header blocks when their stream is reset, or is not
+    /// read anymore.
+    fn qpack_blocked_stream_cancelled() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
 
-        assert_eq!(s.recv_body_client(stream, &mut recv_buf), Ok(body.len()));
-        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
+        let mut h3_config = Config::new().unwrap();
+        h3_config.set_qpack_max_table_capacity(1024);
+        h3_config.set_qpack_blocked_streams(10);
 
-        assert_eq!(s.poll_client(), Err(Error::Done));
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
 
-        // Third cycle.
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_0_result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_0_result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_2_result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_2_result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_2_result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_2_result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_client(&mut buf), Ok(flow_2_result));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-    }
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+            Header::new(b"x-custom", b"custom value"),
+        ];
 
-    #[test]
-    /// Tests that the Finished event is not issued for streams of unknown type
-    /// (e.g. GREASE).
-    fn finished_is_for_requests() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        // Send header blocks referencing the dynamic table without the
+        // encoder instructions they depend on, so that they are blocked.
+        for &stream in &[0, 4] {
+            let mut header_block = vec![0; 128];
+            let len = s
+                .client
+                .qpack_encoder
+                .encode_on_stream(stream, &req, &mut header_block)
+                .unwrap();
+            header_block.truncate(len);
+
+            s.send_frame_client(
+                frame::Frame::Headers { header_block },
+                stream,
+                false,
+            )
+            .unwrap();
+        }
 
-        assert_eq!(s.poll_client(), Err(Error::Done));
         assert_eq!(s.poll_server(), Err(Error::Done));
+        assert!(s.server.blocked_streams.contains_key(&0));
+        assert!(s.server.blocked_streams.contains_key(&4));
 
-        assert_eq!(s.client.open_grease_stream(&mut s.pipe.client), Ok(()));
-        assert_eq!(s.pipe.advance(), Ok(()));
+        // Stream 0 is reset by the client.
+        s.pipe
+            .client
+            .stream_shutdown(0, crate::Shutdown::Write, 42)
+            .unwrap();
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Ok((0, Event::Reset(42))));
+        assert!(!s.server.blocked_streams.contains_key(&0));
+
+        // Stream Cancellation for stream 0.
+        assert_eq!(s.server.qpack_decoder.instructions(), &[0x40]);
+
+        // Stream 4 is not read anymore by the server.
+        s.pipe
+            .server
+            .stream_shutdown(4, crate::Shutdown::Read, 0)
+            .unwrap();
 
-        assert_eq!(s.poll_client(), Err(Error::Done));
         assert_eq!(s.poll_server(), Err(Error::Done));
+        assert!(s.server.blocked_streams.is_empty());
+
+        // Both Stream Cancellations were sent.
+        assert!(s.server.qpack_decoder.instructions().is_empty());
     }
 
     #[test]
-    /// Tests that streams are marked as finished only once.
-    fn finished_once() {
-        let mut s = Session::default().unwrap();
+    /// Advertise QPACK settings and send headers without Huffman encoding.
+    fn qpack_settings() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.set_qpack_max_table_capacity(1024);
+        h3_config.set_qpack_blocked_streams(10);
+        h3_config.enable_qpack_huffman(false);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
         s.handshake().unwrap();
 
-        let (stream, req) = s.send_request(false).unwrap();
-        let body = s.send_body_client(stream, true).unwrap();
+        assert_eq!(s.client.peer_settings.qpack_max_table_capacity, Some(1024));
+        assert_eq!(s.client.peer_settings.qpack_blocked_streams, Some(10));
+        assert_eq!(s.server.peer_settings.qpack_max_table_capacity, Some(1024));
+        assert_eq!(s.server.peer_settings.qpack_blocked_streams, Some(10));
 
-        let mut recv_buf = vec![0; body.len()];
+        let (stream, req) = s.send_request(true).unwrap();
 
         let ev_headers = Event::Headers {
             list: req,
-            has_body: true,
+            has_body: false,
         };
 
         assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
         assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
-
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Err(Error::Done));
-        assert_eq!(s.poll_server(), Err(Error::Done));
     }
 
     #[test]
-    /// Tests that the Data event is properly re-armed.
-    fn data_event_rearm() {
-        let bytes = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+    /// Frames of registered extension types are reported to the application.
+    fn extension_frame() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
 
-        let mut s = Session::default().unwrap();
+        let mut h3_config = Config::new().unwrap();
+        h3_config.register_extension_frame(0x4143);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
         s.handshake().unwrap();
 
-        let (stream, req) = s.send_request(false).unwrap();
+        let control_stream_id = s.client.control_stream_id.unwrap();
 
-        let mut recv_buf = vec![0; bytes.len()];
+        s.send_frame_client(
+            frame::Frame::Unknown {
+                raw_type: 0x4143,
+                payload: vec![1, 2, 3, 4, 5],
+            },
+            control_stream_id,
+            false,
+        )
+        .unwrap();
 
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: true,
+        let ev_extension = Event::ExtensionFrame {
+            frame_type: 0x4143,
+            payload: vec![1, 2, 3, 4, 5],
         };
 
-        // Manually send an incomplete DATA frame (i.e. the frame size is longer
-        // than the actual data sent).
-        {
-            let mut d = [42; 10];
-            let mut b = octets::OctetsMut::with_slice(&mut d);
-
-            b.put_varint(frame::DATA_FRAME_TYPE_ID).unwrap();
-            b.put_varint(bytes.len() as u64).unwrap();
-            let off = b.off();
-            s.pipe.client.stream_send(stream, &d[..off], false).unwrap();
-
-            assert_eq!(
-                s.pipe.client.stream_send(stream, &bytes[..5], false),
-                Ok(5)
-            );
-
-            s.advance().ok();
-        }
-
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Ok((control_stream_id, ev_extension)));
         assert_eq!(s.poll_server(), Err(Error::Done));
 
-        // Read the available body data.
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(5));
-
-        // Send the remaining DATA payload.
-        assert_eq!(s.pipe.client.stream_send(stream, &bytes[5..], false), Ok(5));
-        s.advance().ok();
-
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        // Frames of other unknown types are still discarded.
+        s.send_frame_client(
+            frame::Frame::Unknown {
+                raw_type: 0x4144,
+                payload: vec![1, 2, 3, 4, 5],
+            },
+            control_stream_id,
+            false,
+        )
+        .unwrap();
 
-        // Read the rest of the body data.
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(5));
         assert_eq!(s.poll_server(), Err(Error::Done));
+    }
 
-        // Send more data.
-        let body = s.send_body_client(stream, false).unwrap();
-
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+    #[test]
+    /// Unidirectional streams of registered extension types are passed
+    /// through to the application.
+    fn extension_stream() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
 
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
+        let mut h3_config = Config::new().unwrap();
+        h3_config.register_extension_stream(0x4143);
 
-        // Send more data, then HEADERS, then more data.
-        let body = s.send_body_client(stream, false).unwrap();
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
 
-        let trailers = vec![Header::new(b"hello", b"world")];
+        let stream = s
+            .client
+            .open_uni_stream(&mut s.pipe.client, 0x4143)
+            .unwrap();
 
-        s.client
-            .send_headers(&mut s.pipe.client, stream, &trailers, false)
+        s.send_arbitrary_stream_data_client(b"hello", stream, true)
             .unwrap();
 
-        let ev_trailers = Event::Headers {
-            list: trailers,
-            has_body: true,
+        let ev_extension = Event::ExtensionStream {
+            stream_type: 0x4143,
         };
 
-        s.advance().ok();
-
-        s.send_body_client(stream, false).unwrap();
-
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
-
-        assert_eq!(s.poll_server(), Ok((stream, ev_trailers)));
-
+        assert_eq!(s.poll_server(), Ok((stream, ev_extension)));
         assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
-
-        let (stream, req) = s.send_request(false).unwrap();
-
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: true,
-        };
-
-        // Manually send an incomplete DATA frame (i.e. only the header is sent).
-        {
-            let mut d = [42; 10];
-            let mut b = octets::OctetsMut::with_slice(&mut d);
-
-            b.put_varint(frame::DATA_FRAME_TYPE_ID).unwrap();
-            b.put_varint(bytes.len() as u64).unwrap();
-            let off = b.off();
-            s.pipe.client.stream_send(stream, &d[..off], false).unwrap();
 
-            s.advance().ok();
-        }
+        let mut recv_buf = vec![0; 10];
+        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(5));
+        assert_eq!(&recv_buf[..5], b"hello");
 
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
         assert_eq!(s.poll_server(), Err(Error::Done));
+    }
 
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Err(Error::Done));
-
-        assert_eq!(s.pipe.client.stream_send(stream, &bytes[..5], false), Ok(5));
+    #[test]
+    /// Bidirectional raw streams are not processed by HTTP/3.
+    fn raw_stream_bidi() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
 
-        s.advance().ok();
+        let mut h3_config = Config::new().unwrap();
+        h3_config.register_raw_stream(0x4143);
 
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
 
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(5));
+        let stream = s
+            .client
+            .open_raw_stream(&mut s.pipe.client, 0x4143, true)
+            .unwrap();
+        assert_eq!(stream, 0);
 
-        assert_eq!(s.pipe.client.stream_send(stream, &bytes[5..], false), Ok(5));
+        s.pipe.client.stream_send(stream, b"hello", false).unwrap();
         s.advance().ok();
 
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
-
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(5));
-
-        // Buffer multiple data frames.
-        let body = s.send_body_client(stream, false).unwrap();
-        s.send_body_client(stream, false).unwrap();
-        s.send_body_client(stream, false).unwrap();
+        let ev_raw = Event::RawStream {
+            stream_type: 0x4143,
+        };
 
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Ok((stream, ev_raw)));
         assert_eq!(s.poll_server(), Err(Error::Done));
 
-        {
-            let mut d = [42; 10];
-            let mut b = octets::OctetsMut::with_slice(&mut d);
-
-            b.put_varint(frame::DATA_FRAME_TYPE_ID).unwrap();
-            b.put_varint(0).unwrap();
-            let off = b.off();
-            s.pipe.client.stream_send(stream, &d[..off], true).unwrap();
+        let mut recv_buf = vec![0; 10];
+        assert_eq!(
+            s.pipe.server.stream_recv(stream, &mut recv_buf),
+            Ok((5, false))
+        );
+        assert_eq!(&recv_buf[..5], b"hello");
 
-            s.advance().ok();
-        }
+        s.pipe.server.stream_send(stream, b"world", true).unwrap();
+        s.advance().ok();
 
-        let mut recv_buf = vec![0; bytes.len() * 3];
+        assert_eq!(s.poll_client(), Err(Error::Done));
 
         assert_eq!(
-            s.recv_body_server(stream, &mut recv_buf),
-            Ok(body.len() * 3)
+            s.pipe.client.stream_recv(stream, &mut recv_buf),
+            Ok((5, true))
         );
-    }
+        assert_eq!(&recv_buf[..5], b"world");
 
-    #[test]
-    /// Tests that the Datagram event is properly re-armed.
-    fn dgram_event_rearm() {
-        let mut buf = [0; 65535];
+        // Once finished, the raw stream's state is dropped.
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert!(!s.client.streams.contains_key(&stream));
+
+        // Requests use the following stream ID.
+        let (stream, _) = s.send_request(true).unwrap();
+        assert_eq!(stream, 4);
+    }
 
+    #[test]
+    /// Servers open bidirectional raw streams with server-initiated IDs.
+    fn raw_stream_bidi_server() {
         let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -4517,252 +8883,288 @@ mod tests {
         config.set_initial_max_stream_data_bidi_local(150);
         config.set_initial_max_stream_data_bidi_remote(150);
         config.set_initial_max_stream_data_uni(150);
-        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_bidi(5);
         config.set_initial_max_streams_uni(5);
         config.verify_peer(false);
-        config.enable_dgram(true, 100, 100);
 
         let mut h3_config = Config::new().unwrap();
-        let mut s = Session::with_configs(&mut config, &mut h3_config).unwrap();
-        s.handshake().unwrap();
+        h3_config.register_raw_stream(0x4143);
 
-        // 10 bytes on flow ID 0 and 2.
-        let flow_0_result = (11, 0, 1);
-        let flow_2_result = (11, 2, 1);
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
 
-        // Send requests followed by DATAGRAMs on client side.
-        let (stream, req) = s.send_request(false).unwrap();
+        let (req_stream, req) = s.send_request(true).unwrap();
+        assert_eq!(req_stream, 0);
 
-        let body = s.send_body_client(stream, true).unwrap();
+        let stream = s
+            .server
+            .open_raw_stream(&mut s.pipe.server, 0x4143, true)
+            .unwrap();
+        assert_eq!(stream, 1);
 
-        let mut recv_buf = vec![0; body.len()];
+        s.pipe.server.stream_send(stream, b"hello", true).unwrap();
+        s.advance().ok();
 
         let ev_headers = Event::Headers {
             list: req,
-            has_body: true,
+            has_body: false,
         };
 
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(2).unwrap();
-        s.send_dgram_client(2).unwrap();
-
-        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
-
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
-
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
-
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
-
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_2_result));
-
-        assert_eq!(s.poll_server(), Err(Error::Done));
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_2_result));
-
-        assert_eq!(s.poll_server(), Err(Error::Done));
-
-        s.send_dgram_client(0).unwrap();
-        s.send_dgram_client(2).unwrap();
+        assert_eq!(s.poll_server(), Ok((req_stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((req_stream, Event::Finished)));
 
-        assert_eq!(s.poll_server(), Ok((0, Event::Datagram)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        let ev_raw = Event::RawStream {
+            stream_type: 0x4143,
+        };
 
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_0_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.poll_client(), Ok((stream, ev_raw)));
 
-        assert_eq!(s.recv_dgram_server(&mut buf), Ok(flow_2_result));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        let mut recv_buf = vec![0; 10];
+        assert_eq!(
+            s.pipe.client.stream_recv(stream, &mut recv_buf),
+            Ok((5, true))
+        );
+        assert_eq!(&recv_buf[..5], b"hello");
 
-        assert_eq!(s.recv_body_server(stream, &mut recv_buf), Ok(body.len()));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert!(!s.client.streams.contains_key(&stream));
     }
 
     #[test]
-    fn reset_stream() {
-        let mut buf = [0; 65535];
-
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+    /// Unidirectional raw streams are not processed by HTTP/3.
+    fn raw_stream_uni() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
 
-        // Client sends request.
-        let (stream, req) = s.send_request(false).unwrap();
+        let mut h3_config = Config::new().unwrap();
+        h3_config.register_raw_stream(0x4143);
 
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: true,
-        };
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
 
-        // Server sends response and closes stream.
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        let stream = s
+            .client
+            .open_raw_stream(&mut s.pipe.client, 0x4143, false)
+            .unwrap();
 
-        let resp = s.send_response(stream, true).unwrap();
+        s.pipe.client.stream_send(stream, b"hello", true).unwrap();
+        s.advance().ok();
 
-        let ev_headers = Event::Headers {
-            list: resp,
-            has_body: false,
+        let ev_raw = Event::RawStream {
+            stream_type: 0x4143,
         };
 
-        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
-        assert_eq!(s.poll_client(), Err(Error::Done));
-
-        // Client sends RESET_STREAM, closing stream.
-        let frames = [crate::frame::Frame::ResetStream {
-            stream_id: stream,
-            error_code: 42,
-            final_size: 68,
-        }];
-
-        let pkt_type = crate::packet::Type::Short;
-        assert_eq!(
-            s.pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Ok(39)
-        );
-
-        // Server issues Reset event for the stream.
-        assert_eq!(s.poll_server(), Ok((stream, Event::Reset(42))));
+        assert_eq!(s.poll_server(), Ok((stream, ev_raw)));
         assert_eq!(s.poll_server(), Err(Error::Done));
 
-        // Sending RESET_STREAM again shouldn't trigger another Reset event.
+        let mut recv_buf = vec![0; 10];
         assert_eq!(
-            s.pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Ok(39)
+            s.pipe.server.stream_recv(stream, &mut recv_buf),
+            Ok((5, true))
         );
+        assert_eq!(&recv_buf[..5], b"hello");
 
         assert_eq!(s.poll_server(), Err(Error::Done));
+        assert!(!s.server.raw_stream_ids.contains(&stream));
     }
 
     #[test]
-    fn reset_finished_at_server() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+    /// The peer's SETTINGS are reported to the application.
+    fn peer_settings() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+        config.grease(false);
 
-        // Client sends HEADERS and doesn't fin
-        let (stream, _req) = s.send_request(false).unwrap();
+        let mut h3_config = Config::new().unwrap();
+        h3_config.set_max_field_section_size(1024);
+        h3_config.enable_extended_connect(true);
 
-        // ..then Client sends RESET_STREAM
-        assert_eq!(
-            s.pipe.client.stream_shutdown(0, crate::Shutdown::Write, 0),
-            Ok(())
-        );
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        assert_eq!(s.pipe.handshake(), Ok(()));
 
+        assert_eq!(s.server.peer_settings(), None);
+
+        s.client.send_settings(&mut s.pipe.client).unwrap();
         assert_eq!(s.pipe.advance(), Ok(()));
 
-        // Server receives just a reset
-        assert_eq!(s.poll_server(), Ok((stream, Event::Reset(0))));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        let control_stream_id = s.client.control_stream_id.unwrap();
 
-        // Client sends HEADERS and fin
-        let (stream, req) = s.send_request(true).unwrap();
+        assert_eq!(s.poll_server(), Ok((control_stream_id, Event::Settings)));
 
-        // ..then Client sends RESET_STREAM
+        let settings = s.server.peer_settings().unwrap();
+        assert_eq!(settings.max_field_section_size, Some(1024));
+        assert_eq!(settings.qpack_max_table_capacity, None);
+        assert_eq!(settings.qpack_blocked_streams, None);
+        assert_eq!(settings.connect_protocol_enabled, Some(1));
         assert_eq!(
-            s.pipe.client.stream_shutdown(4, crate::Shutdown::Write, 0),
-            Ok(())
+            settings.raw,
+            Some(vec![
+                (frame::SETTINGS_MAX_FIELD_SECTION_SIZE, 1024),
+                (frame::SETTINGS_ENABLE_CONNECT_PROTOCOL, 1)
+            ])
         );
 
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: false,
-        };
-
-        // Server receives headers and fin.
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert!(s.server.extended_connect_enabled_by_peer());
     }
 
     #[test]
-    fn reset_finished_at_client() {
-        let mut buf = [0; 65535];
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+    #[cfg(not(feature = "rustls"))]
+    /// Settings sent with ALPS are available once the handshake completes.
+    fn peer_settings_alps() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+        config.grease(false);
 
-        // Client sends HEADERS and doesn't fin
-        let (stream, req) = s.send_request(false).unwrap();
+        let mut h3_config = Config::new().unwrap();
+        h3_config.set_max_field_section_size(1024);
+        h3_config.enable_extended_connect(true);
+        h3_config.add_application_settings(&mut config).unwrap();
 
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: true,
-        };
+        let mut pipe = crate::testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Server receives headers.
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        let mut client =
+            Connection::with_transport(&mut pipe.client, &h3_config).unwrap();
+        let server =
+            Connection::with_transport(&mut pipe.server, &h3_config).unwrap();
 
-        // Server sends response and doesn't fin
-        s.send_response(stream, false).unwrap();
+        // The control streams weren't exchanged yet.
+        let settings = server.peer_settings().unwrap();
+        assert_eq!(settings.max_field_section_size, Some(1024));
+        assert_eq!(settings.connect_protocol_enabled, Some(1));
+        assert!(server.extended_connect_enabled_by_peer());
 
-        assert_eq!(s.pipe.advance(), Ok(()));
+        assert!(client.peer_settings().is_some());
+
+        // The SETTINGS frame still arrives on the control stream.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let control_stream_id = server.control_stream_id.unwrap();
 
-        // .. then Server sends RESET_STREAM
         assert_eq!(
-            s.pipe
-                .server
-                .stream_shutdown(stream, crate::Shutdown::Write, 0),
-            Ok(())
+            client.poll(&mut pipe.client),
+            Ok((control_stream_id, Event::Settings))
         );
+    }
 
-        assert_eq!(s.pipe.advance(), Ok(()));
+    #[test]
+    /// Origins advertised by the server are added to the client's origin set.
+    fn origin() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
 
-        // Client receives Reset only
-        assert_eq!(s.poll_client(), Ok((stream, Event::Reset(0))));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert!(s.client.peer_origins().is_empty());
 
-        // Client sends headers and fin.
-        let (stream, req) = s.send_request(true).unwrap();
+        let origins: [&[u8]; 2] =
+            [b"https://example.com", b"https://example.org"];
 
-        let ev_headers = Event::Headers {
-            list: req,
-            has_body: false,
-        };
+        assert_eq!(s.server.send_origin(&mut s.pipe.server, &origins), Ok(()));
+        s.advance().ok();
 
-        // Server receives headers and fin.
-        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
-        assert_eq!(s.poll_server(), Err(Error::Done));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.client.peer_origins(), &[
+            b"https://example.com".to_vec(),
+            b"https://example.org".to_vec(),
+        ]);
 
-        // Server sends response and fin
-        let resp = s.send_response(stream, true).unwrap();
+        // Origins already in the set are not duplicated.
+        let origins: [&[u8]; 2] =
+            [b"https://example.org", b"https://example.net"];
 
-        assert_eq!(s.pipe.advance(), Ok(()));
+        assert_eq!(s.server.send_origin(&mut s.pipe.server, &origins), Ok(()));
+        s.advance().ok();
 
-        // ..then Server sends RESET_STREAM
-        let frames = [crate::frame::Frame::ResetStream {
-            stream_id: stream,
-            error_code: 42,
-            final_size: 68,
-        }];
+        assert_eq!(s.poll_client(), Err(Error::Done));
+        assert_eq!(s.client.peer_origins(), &[
+            b"https://example.com".to_vec(),
+            b"https://example.org".to_vec(),
+            b"https://example.net".to_vec(),
+        ]);
 
-        let pkt_type = crate::packet::Type::Short;
+        // Clients can't send ORIGIN frames.
         assert_eq!(
-            s.pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Ok(39)
+            s.client.send_origin(&mut s.pipe.client, &origins),
+            Err(Error::FrameUnexpected)
         );
+    }
 
-        assert_eq!(s.pipe.advance(), Ok(()));
+    fn check_send(_: &mut impl Send) {}
 
-        let ev_headers = Event::Headers {
-            list: resp,
-            has_body: false,
-        };
+    #[test]
+    fn config_must_be_send() {
+        let mut config = Config::new().unwrap();
+        check_send(&mut config);
+    }
 
-        // Client receives headers and fin.
-        assert_eq!(s.poll_client(), Ok((stream, ev_headers)));
-        assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
-        assert_eq!(s.poll_client(), Err(Error::Done));
+    #[test]
+    fn connection_must_be_send() {
+        let mut s = Session::default().unwrap();
+        check_send(&mut s.client);
+    }
+
+    fn check_sync(_: &mut impl Sync) {}
+
+    #[test]
+    fn config_must_be_sync() {
+        let mut config = Config::new().unwrap();
+        check_sync(&mut config);
+    }
+
+    #[test]
+    fn connection_must_be_sync() {
+        let mut s = Session::default().unwrap();
+        check_sync(&mut s.client);
     }
 }
 
+pub use capsule::Capsule;
+
+mod capsule;
+pub mod connect_ip;
+pub mod connect_udp;
 #[cfg(feature = "ffi")]
 mod ffi;
-mod frame;
+pub(crate) mod frame;
 #[doc(hidden)]
 pub mod qpack;
 mod stream;
+mod uri_template;
@@ -31,10 +31,24 @@ use super::Result;
 
 use crate::h3::Header;
 
+use super::dynamic_table::DynamicTable;
+
+use super::encoder::encode_int;
+
 use super::INDEXED;
 use super::INDEXED_WITH_POST_BASE;
+use super::INSERT_COUNT_INCREMENT;
+use super::INSERT_WITH_LITERAL_NAME;
+use super::INSERT_WITH_NAME_REF;
 use super::LITERAL;
 use super::LITERAL_WITH_NAME_REF;
+use super::SECTION_ACKNOWLEDGEMENT;
+use super::SET_DYNAMIC_TABLE_CAPACITY;
+use super::STREAM_CANCELLATION;
+
+/// The overhead added to the size of each field when computing the size of a
+/// field section, as per RFC 9114 Section 4.2.2.
+const FIELD_OVERHEAD: u64 = 32;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum Representation {
@@ -69,7 +83,17 @@ impl Representation {
 
 /// A QPACK decoder.
 #[derive(Default)]
-pub struct Decoder {}
+pub struct Decoder {
+    table: DynamicTable,
+
+    max_table_capacity: u64,
+
+    acked_insert_count: u64,
+
+    instructions: Vec<u8>,
+
+    encoder_buf: Vec<u8>,
+}
 
 impl Decoder {
     /// Creates a new QPACK decoder.
@@ -77,12 +101,121 @@ impl Decoder {
         Decoder::default()
     }
 
-    /// Processes control instructions from the encoder.
-    pub fn control(&mut self, _buf: &mut [u8]) -> Result<()> {
-        // TODO: process control instructions
+    /// Sets the maximum capacity of the dynamic table.
+    ///
+    /// This is the value of the local `SETTINGS_QPACK_MAX_TABLE_CAPACITY`
+    /// setting. The encoder is not allowed to use the dynamic table when this
+    /// is zero, which is the default.
+    pub fn set_max_table_capacity(&mut self, v: u64) {
+        self.max_table_capacity = v;
+    }
+
+    /// Returns the decoder instructions that need to be sent on the decoder
+    /// stream.
+    pub fn instructions(&self) -> &[u8] {
+        &self.instructions
+    }
+
+    /// Marks the first `len` bytes returned by [`instructions()`] as sent.
+    ///
+    /// [`instructions()`]: struct.Decoder.html#method.instructions
+    pub fn instructions_sent(&mut self, len: usize) {
+        self.instructions.drain(..len.min(self.instructions.len()));
+    }
+
+    /// Queues a Stream Cancellation instruction for a stream whose header
+    /// blocks will not be decoded anymore, e.g. because it was reset.
+    ///
+    /// Nothing is queued when the dynamic table is disabled.
+    pub fn cancel_stream(&mut self, stream_id: u64) -> Result<()> {
+        if self.max_table_capacity == 0 {
+            return Ok(());
+        }
+
+        self.write_instruction(|b| {
+            encode_int(stream_id, STREAM_CANCELLATION, 6, b)
+        })
+    }
+
+    /// Processes instructions received on the peer's encoder stream.
+    ///
+    /// An Insert Count Increment instruction is queued for any new dynamic
+    /// table entry.
+    pub fn control(&mut self, data: &[u8]) -> Result<()> {
+        let mut buf = std::mem::take(&mut self.encoder_buf);
+        buf.extend_from_slice(data);
+
+        let mut off = 0;
+
+        while off < buf.len() {
+            let mut b = octets::Octets::with_slice(&buf[off..]);
+
+            match self.process_instruction(&mut b) {
+                Ok(()) => (),
+
+                // Wait for the rest of the instruction.
+                Err(Error::BufferTooShort) => break,
+
+                Err(e) => return Err(e),
+            };
+
+            off += b.off();
+        }
+
+        buf.drain(..off);
+
+        // A single instruction can't be larger than the table capacity, plus
+        // the overhead of its integers and Huffman encoding.
+        if buf.len() as u64 > self.max_table_capacity * 4 + 32 {
+            return Err(Error::InvalidInstruction);
+        }
+
+        self.encoder_buf = buf;
+
+        let increment = self.table.insert_count() - self.acked_insert_count;
+
+        if increment > 0 {
+            self.write_instruction(|b| {
+                encode_int(increment, INSERT_COUNT_INCREMENT, 6, b)
+            })?;
+
+            self.acked_insert_count = self.table.insert_count();
+        }
+
         Ok(())
     }
 
+    /// Decodes a QPACK header block received on the given stream into a list
+    /// of headers.
+    ///
+    /// The [`Blocked`] error is returned if the header block references
+    /// dynamic table entries that weren't received yet, in which case it
+    /// should be decoded again after more encoder instructions are processed.
+    /// Otherwise, a Section Acknowledgement instruction is queued if the
+    /// header block references the dynamic table.
+    ///
+    /// [`Blocked`]: enum.Error.html#variant.Blocked
+    pub fn decode_on_stream(
+        &mut self, stream_id: u64, buf: &[u8], max_size: u64,
+    ) -> Result<Vec<Header>> {
+        let mut b = octets::Octets::with_slice(buf);
+
+        let required_insert_count = self.required_insert_count(&mut b)?;
+
+        let headers = self.decode(buf, max_size)?;
+
+        if required_insert_count > 0 {
+            self.write_instruction(|b| {
+                encode_int(stream_id, SECTION_ACKNOWLEDGEMENT, 7, b)
+            })?;
+
+            self.acked_insert_count =
+                self.acked_insert_count.max(required_insert_count);
+        }
+
+        Ok(headers)
+    }
+
     /// Decodes a QPACK header block into a list of headers.
     pub fn decode(&mut self, buf: &[u8], max_size: u64) -> Result<Vec<Header>> {
         let mut b = octets::Octets::with_slice(buf);
@@ -91,11 +224,31 @@ impl Decoder {
 
         let mut left = max_size;
 
-        let req_insert_count = decode_int(&mut b, 8)?;
-        let base = decode_int(&mut b, 7)?;
+        let req_insert_count = self.required_insert_count(&mut b)?;
+
+        let base = if req_insert_count == 0 {
+            decode_int(&mut b, 7)?
+        } else {
+            let sign = b.peek_u8()? & 0x80 == 0x80;
+            let delta = decode_int(&mut b, 7)?;
+
+            if sign {
+                req_insert_count
+                    .checked_sub(delta + 1)
+                    .ok_or(Error::InvalidDynamicTableIndex)?
+            } else {
+                req_insert_count
+                    .checked_add(delta)
+                    .ok_or(Error::InvalidDynamicTableIndex)?
+            }
+        };
 
         trace!("Header count={} base={}", req_insert_count, base);
 
+        if req_insert_count > self.table.insert_count() {
+            return Err(Error::Blocked);
+        }
+
         while b.cap() > 0 {
             let first = b.peek_u8()?;
 
@@ -108,15 +261,15 @@ impl Decoder {
 
                     trace!("Indexed index={} static={}", index, s);
 
-                    if !s {
-                        // TODO: implement dynamic table
-                        return Err(Error::InvalidHeaderValue);
-                    }
-
-                    let (name, value) = lookup_static(index)?;
+                    let (name, value) = if s {
+                        lookup_static(index)?
+                    } else {
+                        let index = relative_index(base, index)?;
+                        self.lookup_dynamic(index, req_insert_count)?
+                    };
 
                     left = left
-                        .checked_sub((name.len() + value.len()) as u64)
+                        .checked_sub(field_size(name, value))
                         .ok_or(Error::HeaderListTooLarge)?;
 
                     let hdr = Header::new(name, value);
@@ -128,8 +281,16 @@ impl Decoder {
 
                     trace!("Indexed With Post Base index={}", index);
 
-                    // TODO: implement dynamic table
-                    return Err(Error::InvalidHeaderValue);
+                    let index = post_base_index(base, index)?;
+                    let (name, value) =
+                        self.lookup_dynamic(index, req_insert_count)?;
+
+                    left = left
+                        .checked_sub(field_size(name, value))
+                        .ok_or(Error::HeaderListTooLarge)?;
+
+                    let hdr = Header::new(name, value);
+                    out.push(hdr);
                 },
 
                 Representation::Literal => {
@@ -154,7 +315,7 @@ impl Decoder {
                     );
 
                     left = left
-                        .checked_sub((name.len() + value.len()) as u64)
+                        .checked_sub(field_size(&name, &value))
                         .ok_or(Error::HeaderListTooLarge)?;
 
                     // Instead of calling Header::new(), create Header directly
@@ -177,15 +338,15 @@ impl Decoder {
                         value
                     );
 
-                    if !s {
-                        // TODO: implement dynamic table
-                        return Err(Error::InvalidHeaderValue);
-                    }
-
-                    let (name, _) = lookup_static(name_idx)?;
+                    let (name, _) = if s {
+                        lookup_static(name_idx)?
+                    } else {
+                        let index = relative_index(base, name_idx)?;
+                        self.lookup_dynamic(index, req_insert_count)?
+                    };
 
                     left = left
-                        .checked_sub((name.len() + value.len()) as u64)
+                        .checked_sub(field_size(name, &value))
                         .ok_or(Error::HeaderListTooLarge)?;
 
                     // Instead of calling Header::new(), create Header directly
@@ -196,16 +357,195 @@ impl Decoder {
                 },
 
                 Representation::LiteralWithPostBase => {
-                    trace!("Literal With Post Base");
+                    let name_idx = decode_int(&mut b, 3)?;
+                    let value = decode_str(&mut b)?;
+
+                    trace!(
+                        "Literal With Post Base name_idx={} value={:?}",
+                        name_idx,
+                        value
+                    );
+
+                    let index = post_base_index(base, name_idx)?;
+                    let (name, _) =
+                        self.lookup_dynamic(index, req_insert_count)?;
 
-                    // TODO: implement dynamic table
-                    return Err(Error::InvalidHeaderValue);
+                    left = left
+                        .checked_sub(field_size(name, &value))
+                        .ok_or(Error::HeaderListTooLarge)?;
+
+                    let hdr = Header(name.to_vec(), value);
+                    out.push(hdr);
                 },
             }
         }
 
         Ok(out)
     }
+
+    /// Decodes the Required Insert Count of a header block, as per RFC 9204
+    /// Section 4.5.1.1.
+    fn required_insert_count(&self, b: &mut octets::Octets) -> Result<u64> {
+        let encoded = decode_int(b, 8)?;
+
+        if encoded == 0 {
+            return Ok(0);
+        }
+
+        let max_entries = self.max_table_capacity / 32;
+        let full_range = 2 * max_entries;
+
+        if encoded > full_range {
+            return Err(Error::InvalidDynamicTableIndex);
+        }
+
+        let max_value = self.table.insert_count() + max_entries;
+        let max_wrapped = (max_value / full_range) * full_range;
+
+        let mut req_insert_count = max_wrapped + encoded - 1;
+
+        if req_insert_count > max_value {
+            if req_insert_count <= full_range {
+                return Err(Error::InvalidDynamicTableIndex);
+            }
+
+            req_insert_count -= full_range;
+        }
+
+        if req_insert_count == 0 {
+            return Err(Error::InvalidDynamicTableIndex);
+        }
+
+        Ok(req_insert_count)
+    }
+
+    /// Returns the dynamic table entry with the given absolute index, which
+    /// must be lower than the header block's Required Insert Count.
+    fn lookup_dynamic(
+        &self, index: u64, req_insert_count: u64,
+    ) -> Result<(&[u8], &[u8])> {
+        if index >= req_insert_count {
+            return Err(Error::InvalidDynamicTableIndex);
+        }
+
+        self.table.get(index).ok_or(Error::InvalidDynamicTableIndex)
+    }
+
+    fn process_instruction(&mut self, b: &mut octets::Octets) -> Result<()> {
+        let first = b.peek_u8()?;
+
+        if first & INSERT_WITH_NAME_REF == INSERT_WITH_NAME_REF {
+            const STATIC: u8 = 0x40;
+
+            let s = first & STATIC == STATIC;
+            let name_idx = decode_int(b, 6)?;
+            let value = decode_str(b)?;
+
+            trace!(
+                "Insert With Name Reference name_idx={} static={} value={:?}",
+                name_idx,
+                s,
+                value
+            );
+
+            let name = if s {
+                lookup_static(name_idx)?.0.to_vec()
+            } else {
+                let index = relative_index(self.table.insert_count(), name_idx)?;
+
+                let (name, _) = self
+                    .table
+                    .get(index)
+                    .ok_or(Error::InvalidDynamicTableIndex)?;
+
+                name.to_vec()
+            };
+
+            return self.table.insert(name, value);
+        }
+
+        if first & INSERT_WITH_LITERAL_NAME == INSERT_WITH_LITERAL_NAME {
+            let name_huff = first & 0x20 == 0x20;
+            let name_len = decode_int(b, 5)? as usize;
+
+            let mut name = b.get_bytes(name_len)?;
+
+            let name = if name_huff {
+                super::huffman::decode(&mut name)?
+            } else {
+                name.to_vec()
+            };
+
+            let value = decode_str(b)?;
+
+            trace!("Insert With Literal Name name={:?} value={:?}", name, value);
+
+            return self.table.insert(name, value);
+        }
+
+        if first & SET_DYNAMIC_TABLE_CAPACITY == SET_DYNAMIC_TABLE_CAPACITY {
+            let capacity = decode_int(b, 5)?;
+
+            trace!("Set Dynamic Table Capacity capacity={}", capacity);
+
+            if capacity > self.max_table_capacity {
+                return Err(Error::InvalidInstruction);
+            }
+
+            self.table.set_capacity(capacity);
+
+            return Ok(());
+        }
+
+        let index = decode_int(b, 5)?;
+
+        trace!("Duplicate index={}", index);
+
+        let index = relative_index(self.table.insert_count(), index)?;
+
+        let (name, value) = self
+            .table
+            .get(index)
+            .ok_or(Error::InvalidDynamicTableIndex)?;
+
+        let (name, value) = (name.to_vec(), value.to_vec());
+
+        self.table.insert(name, value)
+    }
+
+    fn write_instruction<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut octets::OctetsMut) -> Result<()>,
+    {
+        let mut buf = [0; 16];
+        let mut b = octets::OctetsMut::with_slice(&mut buf);
+
+        f(&mut b)?;
+
+        let off = b.off();
+        self.instructions.extend_from_slice(&buf[..off]);
+
+        Ok(())
+    }
+}
+
+/// Returns the size of a field when computing the size of a field section.
+fn field_size(name: &[u8], value: &[u8]) -> u64 {
+    (name.len() + value.len()) as u64 + FIELD_OVERHEAD
+}
+
+/// Converts an index relative to `base` into an absolute index.
+fn relative_index(base: u64, index: u64) -> Result<u64> {
+    index
+        .checked_add(1)
+        .and_then(|v| base.checked_sub(v))
+        .ok_or(Error::InvalidDynamicTableIndex)
+}
+
+/// Converts a post-base index into an absolute index.
+fn post_base_index(base: u64, index: u64) -> Result<u64> {
+    base.checked_add(index)
+        .ok_or(Error::InvalidDynamicTableIndex)
 }
 
 fn lookup_static(idx: u64) -> Result<(&'static [u8], &'static [u8])> {
@@ -216,7 +556,7 @@ fn lookup_static(idx: u64) -> Result<(&'static [u8], &'static [u8])> {
     Ok(super::static_table::STATIC_TABLE[idx as usize])
 }
 
-fn decode_int(b: &mut octets::Octets, prefix: usize) -> Result<u64> {
+pub fn decode_int(b: &mut octets::Octets, prefix: usize) -> Result<u64> {
     let mask = 2u64.pow(prefix as u32) - 1;
 
     let mut val = u64::from(b.get_u8()?);
@@ -271,6 +611,8 @@ mod tests {
 
     use crate::octets;
 
+    use crate::h3::Header;
+
     #[test]
     fn decode_int1() {
         let mut encoded = [0b01010, 0x02];
@@ -287,6 +629,141 @@ mod tests {
         assert_eq!(decode_int(&mut b, 5), Ok(1337));
     }
 
+    #[test]
+    fn max_size() {
+        let headers =
+            vec![Header::new(b":path", b"/"), Header::new(b"foo", b"bar")];
+
+        let mut enc = super::super::Encoder::new();
+        let mut dec = Decoder::new();
+
+        let mut encoded = [0; 240];
+        let len = enc.encode(&headers, &mut encoded).unwrap();
+
+        // Each field accounts for an additional 32 bytes.
+        assert_eq!(dec.decode(&encoded[..len], 76), Ok(headers));
+        assert_eq!(
+            dec.decode(&encoded[..len], 75),
+            Err(Error::HeaderListTooLarge)
+        );
+    }
+
+    #[test]
+    fn stream_cancellation() {
+        let mut dec = Decoder::new();
+
+        // Nothing to cancel without a dynamic table.
+        assert_eq!(dec.cancel_stream(4), Ok(()));
+        assert!(dec.instructions().is_empty());
+
+        dec.set_max_table_capacity(220);
+
+        assert_eq!(dec.cancel_stream(4), Ok(()));
+        assert_eq!(dec.instructions(), &[0x44]);
+    }
+
+    #[test]
+    fn dynamic_table() {
+        let headers = vec![
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b"x-custom", b"custom value"),
+        ];
+
+        let mut enc = super::super::Encoder::new();
+        assert_eq!(enc.set_max_table_capacity(220), Ok(()));
+        enc.set_max_blocked_streams(1);
+
+        let mut dec = Decoder::new();
+        dec.set_max_table_capacity(220);
+
+        let mut encoded = [0; 240];
+        let len = enc.encode_on_stream(0, &headers, &mut encoded).unwrap();
+
+        // The encoder instructions weren't received yet.
+        assert_eq!(
+            dec.decode_on_stream(0, &encoded[..len], u64::MAX),
+            Err(Error::Blocked)
+        );
+
+        assert_eq!(dec.control(enc.instructions()), Ok(()));
+
+        assert_eq!(
+            dec.decode_on_stream(0, &encoded[..len], u64::MAX),
+            Ok(headers)
+        );
+
+        // Insert Count Increment followed by Section Acknowledgement.
+        assert_eq!(dec.instructions(), &[0x02, 0x80]);
+
+        assert_eq!(enc.control(dec.instructions()), Ok(()));
+    }
+
+    #[test]
+    fn dynamic_table_post_base() {
+        let mut dec = Decoder::new();
+        dec.set_max_table_capacity(220);
+
+        let instructions = [
+            // Set Dynamic Table Capacity.
+            0x3f, 0xbd, 0x01,
+            // Insert With Name Reference to the static ":authority" entry.
+            0xc0, 0x03, b'f', b'o', b'o', // Duplicate.
+            0x00,
+        ];
+
+        // Split the instructions across multiple calls.
+        assert_eq!(dec.control(&instructions[..5]), Ok(()));
+        assert_eq!(dec.control(&instructions[5..]), Ok(()));
+        assert_eq!(dec.instructions(), &[0x02]);
+
+        let block = [
+            // Required Insert Count of 2, Base of 0.
+            0x03, 0x81, // Indexed Field Line With Post-Base Index.
+            0x11, // Literal Field Line With Post-Base Name Reference.
+            0x00, 0x03, b'b', b'a', b'r',
+        ];
+
+        assert_eq!(
+            dec.decode_on_stream(4, &block, u64::MAX),
+            Ok(vec![
+                Header::new(b":authority", b"foo"),
+                Header::new(b":authority", b"bar"),
+            ])
+        );
+
+        // Reference to an entry past the Required Insert Count.
+        let block = [0x02, 0x80, 0x11];
+
+        assert_eq!(
+            dec.decode(&block, u64::MAX),
+            Err(Error::InvalidDynamicTableIndex)
+        );
+    }
+
+    #[test]
+    fn invalid_encoder_instructions() {
+        let mut dec = Decoder::new();
+        dec.set_max_table_capacity(220);
+
+        // Set Dynamic Table Capacity above the maximum.
+        assert_eq!(
+            dec.control(&[0x3f, 0xbe, 0x01]),
+            Err(Error::InvalidInstruction)
+        );
+
+        let mut dec = Decoder::new();
+        dec.set_max_table_capacity(220);
+
+        // Duplicate of a missing entry.
+        assert_eq!(dec.control(&[0x00]), Err(Error::InvalidDynamicTableIndex));
+
+        // Insert With Literal Name into a table with no capacity.
+        assert_eq!(
+            dec.control(&[0x41, b'a', 0x01, b'b']),
+            Err(Error::InvalidInstruction)
+        );
+    }
+
     #[test]
     fn decode_int3() {
         let mut encoded = [0b101010];
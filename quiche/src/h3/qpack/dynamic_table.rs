@@ -0,0 +1,225 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::collections::VecDeque;
+
+use super::Error;
+use super::Result;
+
+/// The overhead added to the length of an entry's name and value when
+/// computing its size, as per RFC 9204 Section 3.2.1.
+const ENTRY_OVERHEAD: u64 = 32;
+
+/// Returns the size of a dynamic table entry.
+pub fn entry_size(name: &[u8], value: &[u8]) -> u64 {
+    (name.len() + value.len()) as u64 + ENTRY_OVERHEAD
+}
+
+/// A QPACK dynamic table.
+///
+/// Entries are identified by their absolute index, which is the number of
+/// insertions that preceded them.
+#[derive(Default)]
+pub struct DynamicTable {
+    entries: VecDeque<(Vec<u8>, Vec<u8>)>,
+
+    capacity: u64,
+
+    size: u64,
+
+    insert_count: u64,
+}
+
+impl DynamicTable {
+    /// Returns the current capacity of the table.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Returns the sum of the size of all entries in the table.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the total number of insertions into the table.
+    pub fn insert_count(&self) -> u64 {
+        self.insert_count
+    }
+
+    /// Returns the absolute index of the oldest entry still in the table.
+    pub fn dropped(&self) -> u64 {
+        self.insert_count - self.entries.len() as u64
+    }
+
+    /// Returns the entry with the given absolute index.
+    pub fn get(&self, index: u64) -> Option<(&[u8], &[u8])> {
+        let pos = index.checked_sub(self.dropped())?;
+
+        self.entries
+            .get(pos as usize)
+            .map(|(n, v)| (n.as_slice(), v.as_slice()))
+    }
+
+    /// Looks up the newest entries matching the given name and value.
+    ///
+    /// Returns the absolute index of the newest entry matching both name and
+    /// value, if any, and that of the newest entry matching only the name.
+    pub fn find(&self, name: &[u8], value: &[u8]) -> (Option<u64>, Option<u64>) {
+        let mut name_match = None;
+
+        for (pos, (n, v)) in self.entries.iter().enumerate().rev() {
+            if n.as_slice() != name {
+                continue;
+            }
+
+            let index = self.dropped() + pos as u64;
+
+            if v.as_slice() == value {
+                return (Some(index), name_match.or(Some(index)));
+            }
+
+            if name_match.is_none() {
+                name_match = Some(index);
+            }
+        }
+
+        (None, name_match)
+    }
+
+    /// Returns the sum of the size of the entries up to, and including, the
+    /// one with the given absolute index.
+    pub fn size_until(&self, index: u64) -> u64 {
+        let count = (index + 1).saturating_sub(self.dropped());
+
+        self.entries
+            .iter()
+            .take(count as usize)
+            .map(|(n, v)| entry_size(n, v))
+            .sum()
+    }
+
+    /// Returns whether an entry of the given size can be inserted without
+    /// evicting any entry with absolute index equal to or greater than
+    /// `evict_limit`.
+    pub fn can_insert(&self, size: u64, evict_limit: u64) -> bool {
+        if size > self.capacity {
+            return false;
+        }
+
+        let mut available = self.capacity - self.size;
+
+        for (pos, (n, v)) in self.entries.iter().enumerate() {
+            if available >= size {
+                break;
+            }
+
+            if self.dropped() + pos as u64 >= evict_limit {
+                return false;
+            }
+
+            available += entry_size(n, v);
+        }
+
+        available >= size
+    }
+
+    /// Changes the capacity of the table, evicting entries as needed.
+    pub fn set_capacity(&mut self, capacity: u64) {
+        self.capacity = capacity;
+
+        self.evict(0);
+    }
+
+    /// Inserts a new entry, evicting older entries as needed.
+    pub fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let size = entry_size(&name, &value);
+
+        if size > self.capacity {
+            return Err(Error::InvalidInstruction);
+        }
+
+        self.evict(size);
+
+        self.entries.push_back((name, value));
+        self.size += size;
+        self.insert_count += 1;
+
+        Ok(())
+    }
+
+    /// Evicts the oldest entries until there's room for `size` bytes.
+    fn evict(&mut self, size: u64) {
+        while self.size + size > self.capacity {
+            let (n, v) = match self.entries.pop_front() {
+                Some(v) => v,
+
+                None => break,
+            };
+
+            self.size -= entry_size(&n, &v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_evict() {
+        let mut table = DynamicTable::default();
+        table.set_capacity(100);
+
+        assert!(table.insert(b"foo".to_vec(), b"bar".to_vec()).is_ok());
+        assert!(table.insert(b"baz".to_vec(), b"qux".to_vec()).is_ok());
+        assert_eq!(table.size(), 76);
+        assert_eq!(table.insert_count(), 2);
+        assert_eq!(table.dropped(), 0);
+
+        // The oldest entry is referenced, so it can't be evicted.
+        assert!(!table.can_insert(38, 0));
+        assert!(table.can_insert(38, 1));
+
+        assert!(table.insert(b"foo".to_vec(), b"quux".to_vec()).is_ok());
+        assert_eq!(table.dropped(), 1);
+        assert_eq!(table.get(0), None);
+        assert_eq!(table.get(1), Some((&b"baz"[..], &b"qux"[..])));
+        assert_eq!(table.get(2), Some((&b"foo"[..], &b"quux"[..])));
+        assert_eq!(table.get(3), None);
+
+        assert_eq!(table.find(b"foo", b"bar"), (None, Some(2)));
+        assert_eq!(table.find(b"baz", b"qux"), (Some(1), Some(1)));
+
+        assert_eq!(
+            table.insert(vec![0; 60], vec![0; 60]),
+            Err(Error::InvalidInstruction)
+        );
+
+        table.set_capacity(40);
+        assert_eq!(table.size(), 39);
+        assert_eq!(table.dropped(), 2);
+    }
+}
@@ -24,19 +24,77 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use super::Error;
 use super::Result;
 
 use crate::octets;
 
 use crate::h3::NameValue;
 
+use super::decoder::decode_int;
+
+use super::dynamic_table::entry_size;
+use super::dynamic_table::DynamicTable;
+
+use super::DUPLICATE;
 use super::INDEXED;
+use super::INSERT_WITH_LITERAL_NAME;
+use super::INSERT_WITH_NAME_REF;
 use super::LITERAL;
 use super::LITERAL_WITH_NAME_REF;
+use super::SECTION_ACKNOWLEDGEMENT;
+use super::SET_DYNAMIC_TABLE_CAPACITY;
+use super::STREAM_CANCELLATION;
+
+/// The maximum length of an incomplete decoder instruction that is buffered
+/// while waiting for more data.
+const MAX_PENDING_INSTRUCTION_LEN: usize = 16;
+
+/// How a single field line of a header block is encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FieldLine {
+    Static(u64),
+    Dynamic(u64),
+    StaticNameRef(u64),
+    DynamicNameRef(u64),
+    Literal,
+}
+
+/// A header block referencing the dynamic table that hasn't been acknowledged
+/// by the decoder yet.
+struct Section {
+    required_insert_count: u64,
+
+    /// The smallest absolute index referenced by the header block.
+    min_index: u64,
+}
 
 /// A QPACK encoder.
 #[derive(Default)]
-pub struct Encoder {}
+pub struct Encoder {
+    table: DynamicTable,
+
+    max_table_capacity: u64,
+
+    table_capacity_limit: Option<u64>,
+
+    max_blocked_streams: u64,
+
+    known_received_count: u64,
+
+    sections: HashMap<u64, VecDeque<Section>>,
+
+    last_section: Option<u64>,
+
+    instructions: Vec<u8>,
+
+    decoder_buf: Vec<u8>,
+
+    disable_huffman: bool,
+}
 
 impl Encoder {
     /// Creates a new QPACK encoder.
@@ -44,51 +102,489 @@ impl Encoder {
         Encoder::default()
     }
 
+    /// Sets the maximum capacity of the dynamic table.
+    ///
+    /// This is the value of the peer's `SETTINGS_QPACK_MAX_TABLE_CAPACITY`
+    /// setting, and should only be set once. The dynamic table is only used
+    /// by [`encode_on_stream()`] when this is not zero.
+    ///
+    /// The capacity actually used is further limited by
+    /// [`set_table_capacity_limit()`].
+    ///
+    /// [`encode_on_stream()`]: struct.Encoder.html#method.encode_on_stream
+    /// [`set_table_capacity_limit()`]: struct.Encoder.html#method.set_table_capacity_limit
+    pub fn set_max_table_capacity(&mut self, v: u64) -> Result<()> {
+        self.max_table_capacity = v;
+
+        let v = match self.table_capacity_limit {
+            Some(limit) => std::cmp::min(v, limit),
+
+            None => v,
+        };
+
+        if v == self.table.capacity() {
+            return Ok(());
+        }
+
+        self.table.set_capacity(v);
+
+        self.write_instruction(16, |b| {
+            encode_int(v, SET_DYNAMIC_TABLE_CAPACITY, 5, b)
+        })
+    }
+
+    /// Limits the capacity of the dynamic table, regardless of the maximum
+    /// allowed by the peer, to bound the memory it uses.
+    ///
+    /// This needs to be called before [`set_max_table_capacity()`].
+    ///
+    /// [`set_max_table_capacity()`]: struct.Encoder.html#method.set_max_table_capacity
+    pub fn set_table_capacity_limit(&mut self, v: u64) {
+        self.table_capacity_limit = Some(v);
+    }
+
+    /// Sets the maximum number of streams that can be blocked waiting for
+    /// dynamic table updates.
+    ///
+    /// This is the value of the peer's `SETTINGS_QPACK_BLOCKED_STREAMS`
+    /// setting.
+    pub fn set_max_blocked_streams(&mut self, v: u64) {
+        self.max_blocked_streams = v;
+    }
+
+    /// Configures whether header names and values are Huffman-encoded.
+    ///
+    /// The default value is `true`.
+    pub fn enable_huffman(&mut self, v: bool) {
+        self.disable_huffman = !v;
+    }
+
+    /// Returns the encoder instructions that need to be sent on the encoder
+    /// stream.
+    pub fn instructions(&self) -> &[u8] {
+        &self.instructions
+    }
+
+    /// Marks the first `len` bytes returned by [`instructions()`] as sent.
+    ///
+    /// [`instructions()`]: struct.Encoder.html#method.instructions
+    pub fn instructions_sent(&mut self, len: usize) {
+        self.instructions.drain(..len.min(self.instructions.len()));
+    }
+
+    /// Processes instructions received on the peer's decoder stream.
+    pub fn control(&mut self, data: &[u8]) -> Result<()> {
+        let mut buf = std::mem::take(&mut self.decoder_buf);
+        buf.extend_from_slice(data);
+
+        let mut off = 0;
+
+        while off < buf.len() {
+            let mut b = octets::Octets::with_slice(&buf[off..]);
+
+            let first = b.peek_u8()?;
+
+            let prefix = if first & SECTION_ACKNOWLEDGEMENT != 0 {
+                7
+            } else {
+                6
+            };
+
+            let v = match decode_int(&mut b, prefix) {
+                Ok(v) => v,
+
+                // Wait for the rest of the instruction.
+                Err(Error::BufferTooShort) => break,
+
+                Err(e) => return Err(e),
+            };
+
+            if first & SECTION_ACKNOWLEDGEMENT != 0 {
+                self.section_acknowledged(v)?;
+            } else if first & STREAM_CANCELLATION != 0 {
+                trace!("Stream Cancellation stream={}", v);
+
+                self.sections.remove(&v);
+            } else {
+                self.insert_count_increment(v)?;
+            }
+
+            off += b.off();
+        }
+
+        buf.drain(..off);
+
+        if buf.len() > MAX_PENDING_INSTRUCTION_LEN {
+            return Err(Error::InvalidInstruction);
+        }
+
+        self.decoder_buf = buf;
+
+        Ok(())
+    }
+
+    /// Forgets the header block most recently encoded for the given stream
+    /// by [`encode_on_stream()`], for example because it couldn't be sent.
+    ///
+    /// [`encode_on_stream()`]: struct.Encoder.html#method.encode_on_stream
+    pub fn discard_last_section(&mut self, stream_id: u64) {
+        if self.last_section.take() != Some(stream_id) {
+            return;
+        }
+
+        if let Some(sections) = self.sections.get_mut(&stream_id) {
+            sections.pop_back();
+        }
+
+        self.sections.retain(|_, s| !s.is_empty());
+    }
+
     /// Encodes a list of headers into a QPACK header block.
+    ///
+    /// The dynamic table is never used by this method.
     pub fn encode<T: NameValue>(
         &mut self, headers: &[T], out: &mut [u8],
     ) -> Result<usize> {
-        let mut b = octets::OctetsMut::with_slice(out);
+        let lines: Vec<FieldLine> =
+            headers.iter().map(static_field_line).collect();
+
+        self.encode_block(headers, &lines, 0, out)
+    }
+
+    /// Encodes a list of headers into a QPACK header block to be sent on the
+    /// given stream.
+    ///
+    /// Headers might be inserted into the dynamic table, in which case the
+    /// corresponding encoder instructions are queued and can be retrieved
+    /// with [`instructions()`].
+    ///
+    /// [`instructions()`]: struct.Encoder.html#method.instructions
+    pub fn encode_on_stream<T: NameValue>(
+        &mut self, stream_id: u64, headers: &[T], out: &mut [u8],
+    ) -> Result<usize> {
+        self.last_section = None;
+
+        if self.table.capacity() == 0 {
+            return self.encode(headers, out);
+        }
 
-        // Required Insert Count.
-        encode_int(0, 0, 8, &mut b)?;
+        let can_block = self.can_block(stream_id);
 
-        // Base.
-        encode_int(0, 0, 7, &mut b)?;
+        let outstanding_min_index = self
+            .sections
+            .values()
+            .flatten()
+            .map(|s| s.min_index)
+            .min()
+            .unwrap_or(u64::MAX);
+
+        let mut min_index = u64::MAX;
+        let mut required_insert_count = 0;
+
+        let mut lines = Vec::with_capacity(headers.len());
 
         for h in headers {
-            match lookup_static(h) {
-                Some((idx, true)) => {
+            let evict_limit = outstanding_min_index.min(min_index);
+
+            let line = self.field_line(h, can_block, evict_limit)?;
+
+            if let FieldLine::Dynamic(index) | FieldLine::DynamicNameRef(index) =
+                line
+            {
+                min_index = min_index.min(index);
+                required_insert_count = required_insert_count.max(index + 1);
+            }
+
+            lines.push(line);
+        }
+
+        let len =
+            self.encode_block(headers, &lines, required_insert_count, out)?;
+
+        if required_insert_count > 0 {
+            self.sections
+                .entry(stream_id)
+                .or_default()
+                .push_back(Section {
+                    required_insert_count,
+                    min_index,
+                });
+
+            self.last_section = Some(stream_id);
+        }
+
+        Ok(len)
+    }
+
+    /// Chooses how to encode a single header, inserting it into the dynamic
+    /// table when possible.
+    fn field_line<T: NameValue>(
+        &mut self, h: &T, can_block: bool, evict_limit: u64,
+    ) -> Result<FieldLine> {
+        let static_match = lookup_static(h);
+
+        if let Some((idx, true)) = static_match {
+            return Ok(FieldLine::Static(idx));
+        }
+
+        let name = h.name().to_ascii_lowercase();
+        let value = h.value();
+
+        let (exact_match, name_match) = self.table.find(&name, value);
+
+        let size = entry_size(&name, value);
+
+        // Avoid inserting entries that would take most of the table, as they
+        // would evict everything else.
+        let can_insert = can_block &&
+            size <= self.table.capacity() / 4 * 3 &&
+            self.table.can_insert(size, evict_limit);
+
+        if let Some(index) = exact_match {
+            if !self.is_draining(index) && self.can_reference(index, can_block) {
+                return Ok(FieldLine::Dynamic(index));
+            }
+
+            // Refresh entries that are about to be evicted, so they can keep
+            // being referenced.
+            if can_insert {
+                let rel = self.table.insert_count() - 1 - index;
+
+                self.write_instruction(16, |b| encode_int(rel, DUPLICATE, 5, b))?;
+
+                self.table.insert(name, value.to_vec())?;
+
+                return Ok(FieldLine::Dynamic(self.table.insert_count() - 1));
+            }
+
+            if self.can_reference(index, can_block) {
+                return Ok(FieldLine::Dynamic(index));
+            }
+        }
+
+        if can_insert {
+            let len = (name.len() + value.len()) * 4 + 32;
+            let huffman = !self.disable_huffman;
+
+            match (static_match, name_match) {
+                (Some((idx, _)), _) => {
+                    const STATIC: u8 = 0x40;
+
+                    self.write_instruction(len, |b| {
+                        encode_int(idx, INSERT_WITH_NAME_REF | STATIC, 6, b)?;
+                        encode_str(value, 7, huffman, b)
+                    })?;
+                },
+
+                (None, Some(index)) => {
+                    let rel = self.table.insert_count() - 1 - index;
+
+                    self.write_instruction(len, |b| {
+                        encode_int(rel, INSERT_WITH_NAME_REF, 6, b)?;
+                        encode_str(value, 7, huffman, b)
+                    })?;
+                },
+
+                (None, None) => {
+                    self.write_instruction(len, |b| {
+                        const HUFFMAN: u8 = 0x20;
+
+                        let first = INSERT_WITH_LITERAL_NAME;
+                        encode_name(&name, first, HUFFMAN, 5, huffman, b)?;
+
+                        encode_str(value, 7, huffman, b)
+                    })?;
+                },
+            }
+
+            self.table.insert(name, value.to_vec())?;
+
+            return Ok(FieldLine::Dynamic(self.table.insert_count() - 1));
+        }
+
+        if let Some((idx, false)) = static_match {
+            return Ok(FieldLine::StaticNameRef(idx));
+        }
+
+        if let Some(index) = name_match {
+            if self.can_reference(index, can_block) {
+                return Ok(FieldLine::DynamicNameRef(index));
+            }
+        }
+
+        Ok(FieldLine::Literal)
+    }
+
+    /// Writes the header block prefix and the given field lines.
+    fn encode_block<T: NameValue>(
+        &self, headers: &[T], lines: &[FieldLine], required_insert_count: u64,
+        out: &mut [u8],
+    ) -> Result<usize> {
+        let mut b = octets::OctetsMut::with_slice(out);
+
+        let huffman = !self.disable_huffman;
+
+        // All field lines are encoded relative to the current insert count,
+        // so no post-base indexing is needed.
+        let base = self.table.insert_count();
+
+        if required_insert_count == 0 {
+            // Required Insert Count.
+            encode_int(0, 0, 8, &mut b)?;
+
+            // Base.
+            encode_int(0, 0, 7, &mut b)?;
+        } else {
+            let max_entries = self.max_table_capacity / 32;
+
+            // Encoded Required Insert Count.
+            let encoded = required_insert_count % (2 * max_entries) + 1;
+            encode_int(encoded, 0, 8, &mut b)?;
+
+            // Delta Base, the sign bit is always 0 since the base is never
+            // smaller than the Required Insert Count.
+            encode_int(base - required_insert_count, 0, 7, &mut b)?;
+        }
+
+        for (h, line) in headers.iter().zip(lines) {
+            match *line {
+                FieldLine::Static(idx) => {
                     const STATIC: u8 = 0x40;
 
                     // Encode as statically indexed.
                     encode_int(idx, INDEXED | STATIC, 6, &mut b)?;
                 },
 
-                Some((idx, false)) => {
+                FieldLine::Dynamic(index) => {
+                    // Encode as dynamically indexed.
+                    encode_int(base - 1 - index, INDEXED, 6, &mut b)?;
+                },
+
+                FieldLine::StaticNameRef(idx) => {
                     const STATIC: u8 = 0x10;
 
                     // Encode value as literal with static name reference.
                     encode_int(idx, LITERAL_WITH_NAME_REF | STATIC, 4, &mut b)?;
-                    encode_str(h.value(), 7, &mut b)?;
+                    encode_str(h.value(), 7, huffman, &mut b)?;
                 },
 
-                None => {
-                    // Encode as fully literal.
-                    let name_len =
-                        super::huffman::encode_output_length(h.name(), true)?;
+                FieldLine::DynamicNameRef(index) => {
+                    // Encode value as literal with dynamic name reference.
+                    let rel = base - 1 - index;
 
-                    encode_int(name_len as u64, LITERAL | 0x08, 3, &mut b)?;
+                    encode_int(rel, LITERAL_WITH_NAME_REF, 4, &mut b)?;
+                    encode_str(h.value(), 7, huffman, &mut b)?;
+                },
 
-                    super::huffman::encode(h.name(), &mut b, true)?;
+                FieldLine::Literal => {
+                    const HUFFMAN: u8 = 0x08;
 
-                    encode_str(h.value(), 7, &mut b)?;
+                    // Encode as fully literal.
+                    encode_name(h.name(), LITERAL, HUFFMAN, 3, huffman, &mut b)?;
+
+                    encode_str(h.value(), 7, huffman, &mut b)?;
                 },
             };
         }
 
         Ok(b.off())
     }
+
+    /// Returns whether a header block sent on the given stream can reference
+    /// entries that the decoder might not have received yet.
+    fn can_block(&self, stream_id: u64) -> bool {
+        let known_received_count = self.known_received_count;
+
+        let is_blocking = |sections: &VecDeque<Section>| {
+            sections
+                .iter()
+                .any(|s| s.required_insert_count > known_received_count)
+        };
+
+        // The stream is already blocked, so it doesn't count against the
+        // limit again.
+        if let Some(sections) = self.sections.get(&stream_id) {
+            if is_blocking(sections) {
+                return true;
+            }
+        }
+
+        let blocked = self.sections.values().filter(|s| is_blocking(s)).count();
+
+        (blocked as u64) < self.max_blocked_streams
+    }
+
+    fn can_reference(&self, index: u64, can_block: bool) -> bool {
+        can_block || index < self.known_received_count
+    }
+
+    /// Returns whether the entry is among the oldest ones in a table that is
+    /// almost full, and thus likely to be evicted soon.
+    fn is_draining(&self, index: u64) -> bool {
+        let capacity = self.table.capacity();
+
+        self.table.size() > capacity / 4 * 3 &&
+            self.table.size_until(index) <= capacity / 4
+    }
+
+    fn section_acknowledged(&mut self, stream_id: u64) -> Result<()> {
+        trace!("Section Acknowledgement stream={}", stream_id);
+
+        let section = self
+            .sections
+            .get_mut(&stream_id)
+            .and_then(|s| s.pop_front())
+            .ok_or(Error::InvalidInstruction)?;
+
+        self.sections.retain(|_, s| !s.is_empty());
+
+        self.known_received_count =
+            self.known_received_count.max(section.required_insert_count);
+
+        Ok(())
+    }
+
+    fn insert_count_increment(&mut self, increment: u64) -> Result<()> {
+        trace!("Insert Count Increment increment={}", increment);
+
+        let known_received_count = self
+            .known_received_count
+            .checked_add(increment)
+            .ok_or(Error::InvalidInstruction)?;
+
+        if increment == 0 || known_received_count > self.table.insert_count() {
+            return Err(Error::InvalidInstruction);
+        }
+
+        self.known_received_count = known_received_count;
+
+        Ok(())
+    }
+
+    fn write_instruction<F>(&mut self, len: usize, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut octets::OctetsMut) -> Result<()>,
+    {
+        let mut buf = vec![0; len];
+        let mut b = octets::OctetsMut::with_slice(&mut buf);
+
+        f(&mut b)?;
+
+        let off = b.off();
+        self.instructions.extend_from_slice(&buf[..off]);
+
+        Ok(())
+    }
+}
+
+fn static_field_line<T: NameValue>(h: &T) -> FieldLine {
+    match lookup_static(h) {
+        Some((idx, true)) => FieldLine::Static(idx),
+
+        Some((idx, false)) => FieldLine::StaticNameRef(idx),
+
+        None => FieldLine::Literal,
+    }
 }
 
 fn lookup_static<T: NameValue>(h: &T) -> Option<(u64, bool)> {
@@ -115,7 +611,7 @@ fn lookup_static<T: NameValue>(h: &T) -> Option<(u64, bool)> {
     name_match
 }
 
-fn encode_int(
+pub fn encode_int(
     mut v: u64, first: u8, prefix: usize, b: &mut octets::OctetsMut,
 ) -> Result<()> {
     let mask = 2u64.pow(prefix as u32) - 1;
@@ -144,7 +640,40 @@ fn encode_int(
     Ok(())
 }
 
-fn encode_str(v: &[u8], prefix: usize, b: &mut octets::OctetsMut) -> Result<()> {
+/// Encodes a header name, converted to lower case.
+fn encode_name(
+    name: &[u8], first: u8, huffman_flag: u8, prefix: usize, huffman: bool,
+    b: &mut octets::OctetsMut,
+) -> Result<()> {
+    if !huffman {
+        encode_int(name.len() as u64, first, prefix, b)?;
+
+        for c in name {
+            b.put_u8(c.to_ascii_lowercase())?;
+        }
+
+        return Ok(());
+    }
+
+    let len = super::huffman::encode_output_length(name, true)?;
+
+    encode_int(len as u64, first | huffman_flag, prefix, b)?;
+
+    super::huffman::encode(name, b, true)?;
+
+    Ok(())
+}
+
+fn encode_str(
+    v: &[u8], prefix: usize, huffman: bool, b: &mut octets::OctetsMut,
+) -> Result<()> {
+    if !huffman {
+        encode_int(v.len() as u64, 0, prefix, b)?;
+        b.put_bytes(v)?;
+
+        return Ok(());
+    }
+
     let len = super::huffman::encode_output_length(v, false)?;
 
     encode_int(len as u64, 0x80, prefix, b)?;
@@ -160,6 +689,8 @@ mod tests {
 
     use crate::octets;
 
+    use crate::h3::Header;
+
     #[test]
     fn encode_int1() {
         let expected = [0b01010];
@@ -182,6 +713,154 @@ mod tests {
         assert_eq!(expected, encoded);
     }
 
+    #[test]
+    fn dynamic_table_disabled() {
+        let headers = vec![Header::new(b"foo", b"bar")];
+
+        let mut static_encoded = [0; 240];
+        let static_len = Encoder::new()
+            .encode(&headers, &mut static_encoded)
+            .unwrap();
+
+        let mut enc = Encoder::new();
+        enc.set_max_blocked_streams(100);
+
+        let mut encoded = [0; 240];
+        let len = enc.encode_on_stream(0, &headers, &mut encoded).unwrap();
+
+        assert_eq!(&encoded[..len], &static_encoded[..static_len]);
+        assert!(enc.instructions().is_empty());
+    }
+
+    #[test]
+    fn dynamic_table_insert() {
+        let headers = vec![Header::new(b"foo", b"bar")];
+
+        let mut static_encoded = [0; 240];
+        let static_len = Encoder::new()
+            .encode(&headers, &mut static_encoded)
+            .unwrap();
+
+        let mut enc = Encoder::new();
+        assert_eq!(enc.set_max_table_capacity(220), Ok(()));
+        enc.set_max_blocked_streams(1);
+
+        let mut encoded = [0; 240];
+
+        // The header is inserted and referenced from the header block.
+        let len = enc.encode_on_stream(0, &headers, &mut encoded).unwrap();
+        assert_eq!(&encoded[..len], &[0x02, 0x00, 0x80]);
+
+        // Set Dynamic Table Capacity followed by Insert With Literal Name.
+        assert_eq!(&enc.instructions()[..3], &[0x3f, 0xbd, 0x01]);
+        assert_eq!(enc.instructions()[3] & 0xe0, 0x60);
+
+        let instructions_len = enc.instructions().len();
+        enc.instructions_sent(instructions_len);
+        assert!(enc.instructions().is_empty());
+
+        // Another stream can't be blocked, so the entry can't be referenced
+        // until it's acknowledged.
+        let len = enc.encode_on_stream(4, &headers, &mut encoded).unwrap();
+        assert_eq!(&encoded[..len], &static_encoded[..static_len]);
+        assert!(enc.instructions().is_empty());
+
+        // Section Acknowledgement for stream 0.
+        assert_eq!(enc.control(&[0x80]), Ok(()));
+
+        let len = enc.encode_on_stream(4, &headers, &mut encoded).unwrap();
+        assert_eq!(&encoded[..len], &[0x02, 0x00, 0x80]);
+        assert!(enc.instructions().is_empty());
+    }
+
+    #[test]
+    fn dynamic_table_capacity_limit() {
+        let headers = vec![Header::new(b"foo", b"bar")];
+
+        let mut enc = Encoder::new();
+        enc.set_table_capacity_limit(100);
+        assert_eq!(enc.set_max_table_capacity(220), Ok(()));
+        enc.set_max_blocked_streams(1);
+
+        // Set Dynamic Table Capacity to the limit rather than the maximum.
+        assert_eq!(enc.instructions(), &[0x3f, 0x45]);
+
+        // The Required Insert Count is still encoded based on the maximum.
+        let mut encoded = [0; 240];
+        let len = enc.encode_on_stream(0, &headers, &mut encoded).unwrap();
+        assert_eq!(&encoded[..len], &[0x02, 0x00, 0x80]);
+
+        // Without any capacity the dynamic table is not used at all.
+        let mut enc = Encoder::new();
+        enc.set_table_capacity_limit(0);
+        assert_eq!(enc.set_max_table_capacity(220), Ok(()));
+        enc.set_max_blocked_streams(1);
+
+        assert!(enc.instructions().is_empty());
+
+        let mut static_encoded = [0; 240];
+        let static_len = Encoder::new()
+            .encode(&headers, &mut static_encoded)
+            .unwrap();
+
+        let len = enc.encode_on_stream(0, &headers, &mut encoded).unwrap();
+        assert_eq!(&encoded[..len], &static_encoded[..static_len]);
+        assert!(enc.instructions().is_empty());
+    }
+
+    #[test]
+    fn dynamic_table_discard_section() {
+        let headers = vec![Header::new(b"foo", b"bar")];
+
+        let mut enc = Encoder::new();
+        assert_eq!(enc.set_max_table_capacity(220), Ok(()));
+        enc.set_max_blocked_streams(1);
+
+        let mut encoded = [0; 240];
+
+        let len = enc.encode_on_stream(0, &headers, &mut encoded).unwrap();
+        assert_eq!(&encoded[..len], &[0x02, 0x00, 0x80]);
+
+        // The header block is never sent, so stream 0 doesn't count as
+        // blocked anymore, and another stream can reference the entry.
+        enc.discard_last_section(0);
+
+        let len = enc.encode_on_stream(4, &headers, &mut encoded).unwrap();
+        assert_eq!(&encoded[..len], &[0x02, 0x00, 0x80]);
+
+        // No section is pending on stream 0 anymore.
+        assert_eq!(enc.control(&[0x80]), Err(Error::InvalidInstruction));
+    }
+
+    #[test]
+    fn decoder_instructions() {
+        let headers = vec![Header::new(b"foo", b"bar")];
+
+        let mut enc = Encoder::new();
+        assert_eq!(enc.set_max_table_capacity(220), Ok(()));
+        enc.set_max_blocked_streams(1);
+
+        let mut encoded = [0; 240];
+        assert!(enc.encode_on_stream(0, &headers, &mut encoded).is_ok());
+
+        // Insert Count Increment of zero.
+        assert_eq!(enc.control(&[0x00]), Err(Error::InvalidInstruction));
+
+        // Insert Count Increment past the number of insertions.
+        assert_eq!(enc.control(&[0x02]), Err(Error::InvalidInstruction));
+
+        // Stream Cancellation split across multiple calls.
+        assert_eq!(enc.control(&[0x7f]), Ok(()));
+        assert_eq!(enc.control(&[0x01]), Ok(()));
+
+        // Stream Cancellation for stream 0 followed by Insert Count
+        // Increment.
+        assert_eq!(enc.control(&[0x40, 0x01]), Ok(()));
+
+        // Section Acknowledgement for a stream with no pending section.
+        assert_eq!(enc.control(&[0x80]), Err(Error::InvalidInstruction));
+    }
+
     #[test]
     fn encode_int3() {
         let expected = [0b101010];
@@ -31,6 +31,15 @@ const INDEXED_WITH_POST_BASE: u8 = 0b0001_0000;
 const LITERAL: u8 = 0b0010_0000;
 const LITERAL_WITH_NAME_REF: u8 = 0b0100_0000;
 
+const SET_DYNAMIC_TABLE_CAPACITY: u8 = 0b0010_0000;
+const INSERT_WITH_NAME_REF: u8 = 0b1000_0000;
+const INSERT_WITH_LITERAL_NAME: u8 = 0b0100_0000;
+const DUPLICATE: u8 = 0b0000_0000;
+
+const SECTION_ACKNOWLEDGEMENT: u8 = 0b1000_0000;
+const STREAM_CANCELLATION: u8 = 0b0100_0000;
+const INSERT_COUNT_INCREMENT: u8 = 0b0000_0000;
+
 /// A specialized [`Result`] type for quiche QPACK operations.
 ///
 /// This type is used throughout quiche's QPACK public API for any operation
@@ -51,11 +60,21 @@ pub enum Error {
     /// The QPACK static table index provided doesn't exist.
     InvalidStaticTableIndex,
 
+    /// The QPACK dynamic table index provided doesn't exist.
+    InvalidDynamicTableIndex,
+
     /// The decoded QPACK header name or value is not valid.
     InvalidHeaderValue,
 
     /// The decoded header list exceeded the size limit.
     HeaderListTooLarge,
+
+    /// The QPACK encoder or decoder instruction is not valid.
+    InvalidInstruction,
+
+    /// The QPACK header block references dynamic table entries that were not
+    /// received yet.
+    Blocked,
 }
 
 impl std::fmt::Display for Error {
@@ -151,12 +170,43 @@ mod tests {
 
         assert_eq!(headers_expected, headers_out);
     }
+
+    #[test]
+    fn without_huffman() {
+        let mut encoded = [0u8; 41];
+
+        let headers_expected = vec![
+            crate::h3::Header::new(b":status", b"200"),
+            crate::h3::Header::new(b":path", b"/HeLlO"),
+            crate::h3::Header::new(b"woot", b"woot"),
+            crate::h3::Header::new(b"hello", b"WorlD"),
+            crate::h3::Header::new(b"foo", b"BaR"),
+        ];
+
+        let headers_in = vec![
+            crate::h3::Header::new(b":StAtUs", b"200"),
+            crate::h3::Header::new(b":PaTh", b"/HeLlO"),
+            crate::h3::Header::new(b"WooT", b"woot"),
+            crate::h3::Header::new(b"hello", b"WorlD"),
+            crate::h3::Header::new(b"fOo", b"BaR"),
+        ];
+
+        let mut enc = Encoder::new();
+        enc.enable_huffman(false);
+        assert_eq!(enc.encode(&headers_in, &mut encoded), Ok(41));
+
+        let mut dec = Decoder::new();
+        let headers_out = dec.decode(&encoded, u64::MAX).unwrap();
+
+        assert_eq!(headers_expected, headers_out);
+    }
 }
 
 pub use decoder::Decoder;
 pub use encoder::Encoder;
 
 mod decoder;
+mod dynamic_table;
 mod encoder;
 mod huffman;
 mod static_table;
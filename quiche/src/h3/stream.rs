@@ -25,6 +25,7 @@
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use super::Error;
+use super::Priority;
 use super::Result;
 
 use crate::octets;
@@ -45,6 +46,8 @@ pub enum Type {
     Push,
     QpackEncoder,
     QpackDecoder,
+    Extension,
+    Raw,
     Unknown,
 }
 
@@ -74,6 +77,9 @@ pub enum State {
     /// Reading and discarding data.
     Drain,
 
+    /// Data is read by the application directly from the transport.
+    Raw,
+
     /// All data has been read.
     Finished,
 }
@@ -139,6 +145,18 @@ pub struct Stream {
     /// Whether the stream has been locally initialized.
     local_initialized: bool,
 
+    /// Whether the local endpoint has finished the stream.
+    local_finished: bool,
+
+    /// Whether a complete HEADERS frame has been received from the peer.
+    headers_received: bool,
+
+    /// Whether a trailer section has been received from the peer.
+    trailers_received: bool,
+
+    /// The latest priority signaled by the peer, if any.
+    peer_priority: Option<Priority>,
+
     /// Whether a `Data` event has been triggered for this stream.
     data_event_triggered: bool,
 }
@@ -178,6 +196,14 @@ impl Stream {
             remote_initialized: false,
             local_initialized: false,
 
+            local_finished: false,
+
+            headers_received: false,
+
+            trailers_received: false,
+
+            peer_priority: None,
+
             data_event_triggered: false,
         }
     }
@@ -186,6 +212,11 @@ impl Stream {
         self.ty
     }
 
+    /// Returns the type of the frame currently being parsed, if any.
+    pub fn frame_type(&self) -> Option<u64> {
+        self.frame_type
+    }
+
     pub fn state(&self) -> State {
         self.state
     }
@@ -207,6 +238,13 @@ impl Stream {
                 State::QpackInstruction
             },
 
+            // Extension streams are not framed, so all of their data is
+            // passed through to the application.
+            Type::Extension =>
+                return self.state_transition(State::Data, usize::MAX, false),
+
+            Type::Raw => return self.state_transition(State::Raw, 0, false),
+
             Type::Unknown => State::Drain,
         };
 
@@ -266,6 +304,14 @@ impl Stream {
             },
 
             Some(Type::Request) => {
+                // Only the end of the stream can follow a trailer section.
+                if self.trailers_received &&
+                    (ty == frame::HEADERS_FRAME_TYPE_ID ||
+                        ty == frame::DATA_FRAME_TYPE_ID)
+                {
+                    return Err(Error::FrameUnexpected);
+                }
+
                 // Request stream starts uninitialized and only HEADERS
                 // is accepted. Other frames cause an error.
                 if !self.is_local {
@@ -288,6 +334,15 @@ impl Stream {
                         (frame::MAX_PUSH_FRAME_TYPE_ID, _) =>
                             return Err(Error::FrameUnexpected),
 
+                        (frame::ORIGIN_FRAME_TYPE_ID, _) =>
+                            return Err(Error::FrameUnexpected),
+
+                        (frame::PRIORITY_UPDATE_FRAME_REQUEST_TYPE_ID, _) =>
+                            return Err(Error::FrameUnexpected),
+
+                        (frame::PRIORITY_UPDATE_FRAME_PUSH_TYPE_ID, _) =>
+                            return Err(Error::FrameUnexpected),
+
                         // All other frames can be ignored regardless of stream
                         // state.
                         _ => (),
@@ -313,6 +368,15 @@ impl Stream {
                     frame::MAX_PUSH_FRAME_TYPE_ID =>
                         return Err(Error::FrameUnexpected),
 
+                    frame::ORIGIN_FRAME_TYPE_ID =>
+                        return Err(Error::FrameUnexpected),
+
+                    frame::PRIORITY_UPDATE_FRAME_REQUEST_TYPE_ID =>
+                        return Err(Error::FrameUnexpected),
+
+                    frame::PRIORITY_UPDATE_FRAME_PUSH_TYPE_ID =>
+                        return Err(Error::FrameUnexpected),
+
                     _ => (),
                 }
             },
@@ -401,6 +465,41 @@ impl Stream {
         self.local_initialized
     }
 
+    /// Marks the stream as finished by the local endpoint.
+    pub fn finish_local(&mut self) {
+        self.local_finished = true;
+    }
+
+    /// Whether the stream has been finished by the local endpoint.
+    pub fn local_finished(&self) -> bool {
+        self.local_finished
+    }
+
+    /// Marks the stream as having received a complete HEADERS frame.
+    pub fn set_headers_received(&mut self) {
+        self.headers_received = true;
+    }
+
+    /// Whether a complete HEADERS frame has been received on the stream.
+    pub fn headers_received(&self) -> bool {
+        self.headers_received
+    }
+
+    /// Marks the stream as having received a trailer section.
+    pub fn set_trailers_received(&mut self) {
+        self.trailers_received = true;
+    }
+
+    /// Sets the latest priority signaled by the peer.
+    pub fn set_peer_priority(&mut self, priority: Priority) {
+        self.peer_priority = Some(priority);
+    }
+
+    /// Returns the latest priority signaled by the peer, if any.
+    pub fn peer_priority(&self) -> Option<Priority> {
+        self.peer_priority
+    }
+
     /// Tries to fill the state buffer by reading data from the given cursor.
     ///
     /// This is intended to replace `try_fill_buffer()` in tests, in order to
@@ -490,6 +589,14 @@ impl Stream {
         Ok((len, fin))
     }
 
+    /// Hands the stream over to the application, which then reads its data
+    /// directly from the transport.
+    pub fn set_raw(&mut self) {
+        self.ty = Some(Type::Raw);
+
+        let _ = self.state_transition(State::Raw, 0, false);
+    }
+
     /// Marks the stream as finished.
     pub fn finished(&mut self) {
         let _ = self.state_transition(State::Finished, 0, false);
@@ -592,6 +699,7 @@ mod tests {
             qpack_max_table_capacity: Some(0),
             qpack_blocked_streams: Some(0),
             h3_datagram: None,
+            connect_protocol_enabled: None,
             grease: None,
             raw: Some(raw_settings),
         };
@@ -655,6 +763,7 @@ mod tests {
             qpack_max_table_capacity: Some(0),
             qpack_blocked_streams: Some(0),
             h3_datagram: None,
+            connect_protocol_enabled: None,
             grease: None,
             raw: Some(raw_settings),
         };
@@ -727,6 +836,7 @@ mod tests {
             qpack_max_table_capacity: Some(0),
             qpack_blocked_streams: Some(0),
             h3_datagram: None,
+            connect_protocol_enabled: None,
             grease: None,
             raw: Some(raw_settings),
         };
@@ -778,6 +888,7 @@ mod tests {
             qpack_max_table_capacity: Some(0),
             qpack_blocked_streams: Some(0),
             h3_datagram: None,
+            connect_protocol_enabled: None,
             grease: None,
             raw: Some(raw_settings),
         };
@@ -1015,6 +1126,39 @@ mod tests {
         assert_eq!(stream.state, State::Drain);
     }
 
+    #[test]
+    fn extension() {
+        let mut stream = Stream::new(2, false);
+
+        let mut d = vec![42; 20];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        b.put_varint(0x30).unwrap();
+        b.put_bytes(&[1, 2, 3, 4, 5]).unwrap();
+
+        let mut cursor = std::io::Cursor::new(d);
+
+        // Parse stream type.
+        stream.try_fill_buffer_for_tests(&mut cursor).unwrap();
+
+        let stream_ty = stream.try_consume_varint().unwrap();
+        assert_eq!(stream_ty, 0x30);
+        assert_eq!(Type::deserialize(stream_ty), Ok(Type::Unknown));
+
+        stream.set_ty(Type::Extension).unwrap();
+        assert_eq!(stream.state, State::Data);
+
+        // All stream data is passed through.
+        let mut recv_buf = vec![0; 19];
+        assert_eq!(
+            stream.try_consume_data_for_tests(&mut cursor, &mut recv_buf),
+            Ok(19)
+        );
+        assert_eq!(&recv_buf[..5], &[1, 2, 3, 4, 5]);
+
+        assert_eq!(stream.state, State::Data);
+    }
+
     #[test]
     fn data_before_headers() {
         let mut stream = Stream::new(0, false);
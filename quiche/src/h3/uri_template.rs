@@ -0,0 +1,234 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Minimal support for the URI templates used by the MASQUE protocols.
+//!
+//! Only simple `{variable}` expressions are supported.
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Literal(&'a str),
+    Variable(&'a str),
+}
+
+/// Returns the names of the variables used in the given template.
+///
+/// [`None`] is returned if the template is malformed.
+pub fn variables(template: &str) -> Option<Vec<&str>> {
+    let names = tokenize(template)?
+        .into_iter()
+        .filter_map(|t| match t {
+            Token::Variable(name) => Some(name),
+
+            Token::Literal(_) => None,
+        })
+        .collect();
+
+    Some(names)
+}
+
+/// Expands the given template, percent-encoding variable values as needed.
+///
+/// [`None`] is returned if the template is malformed, or if it uses a
+/// variable that is not in `vars`.
+pub fn expand(template: &str, vars: &[(&str, &str)]) -> Option<String> {
+    let mut path = String::new();
+
+    for token in tokenize(template)? {
+        match token {
+            Token::Literal(v) => path.push_str(v),
+
+            Token::Variable(name) => {
+                let (_, value) = vars.iter().find(|(n, _)| *n == name)?;
+
+                path.push_str(&percent_encode(value));
+            },
+        }
+    }
+
+    Some(path)
+}
+
+/// Matches a path against the given template, returning the decoded value
+/// of each variable.
+///
+/// [`None`] is returned if the path doesn't match the template.
+pub fn parse<'a>(
+    template: &'a str, path: &str,
+) -> Option<Vec<(&'a str, String)>> {
+    let tokens = tokenize(template)?;
+
+    let mut vars = Vec::new();
+
+    let mut rest = path;
+
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Literal(v) => rest = rest.strip_prefix(v)?,
+
+            Token::Variable(name) => {
+                // A variable extends until the following literal, if any.
+                let end = match tokens.get(i + 1) {
+                    Some(Token::Literal(v)) => rest.find(v)?,
+
+                    Some(Token::Variable(_)) => return None,
+
+                    None => rest.len(),
+                };
+
+                let value = &rest[..end];
+
+                if value.is_empty() || value.contains(['/', '?', '&']) {
+                    return None;
+                }
+
+                vars.push((*name, percent_decode(value)?));
+
+                rest = &rest[end..];
+            },
+        }
+    }
+
+    if !rest.is_empty() {
+        return None;
+    }
+
+    Some(vars)
+}
+
+// Splits a URI template into literals and simple variable expressions.
+fn tokenize(template: &str) -> Option<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+
+    let mut rest = template;
+
+    while !rest.is_empty() {
+        match rest.find('{') {
+            Some(0) => {
+                let end = rest.find('}')?;
+                let name = &rest[1..end];
+
+                if name.is_empty() ||
+                    !name
+                        .bytes()
+                        .all(|c| c.is_ascii_alphanumeric() || c == b'_')
+                {
+                    return None;
+                }
+
+                tokens.push(Token::Variable(name));
+
+                rest = &rest[end + 1..];
+            },
+
+            Some(start) => {
+                tokens.push(Token::Literal(&rest[..start]));
+
+                rest = &rest[start..];
+            },
+
+            None => {
+                tokens.push(Token::Literal(rest));
+
+                rest = "";
+            },
+        }
+    }
+
+    Some(tokens)
+}
+
+fn percent_encode(v: &str) -> String {
+    let mut out = String::with_capacity(v.len());
+
+    for c in v.bytes() {
+        if c.is_ascii_alphanumeric() || b"-._~".contains(&c) {
+            out.push(c as char);
+        } else {
+            out.push_str(&format!("%{:02X}", c));
+        }
+    }
+
+    out
+}
+
+fn percent_decode(v: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(v.len());
+
+    let mut bytes = v.bytes();
+
+    while let Some(c) = bytes.next() {
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+
+        let hi = (bytes.next()? as char).to_digit(16)?;
+        let lo = (bytes.next()? as char).to_digit(16)?;
+
+        out.push((hi * 16 + lo) as u8);
+    }
+
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            tokenize("/a/{b}/{c}"),
+            Some(vec![
+                Token::Literal("/a/"),
+                Token::Variable("b"),
+                Token::Literal("/"),
+                Token::Variable("c"),
+            ])
+        );
+
+        assert_eq!(variables("/a/{b}/{c}"), Some(vec!["b", "c"]));
+        assert_eq!(variables("/a/"), Some(vec![]));
+
+        assert_eq!(tokenize("/a/{}"), None);
+        assert_eq!(tokenize("/a/{b-c}"), None);
+        assert_eq!(tokenize("/a/{b"), None);
+    }
+
+    #[test]
+    fn percent_encoding() {
+        assert_eq!(percent_encode("a.b-c_d~e"), "a.b-c_d~e");
+        assert_eq!(percent_encode("192.0.2.0/24"), "192.0.2.0%2F24");
+        assert_eq!(
+            percent_decode("192.0.2.0%2f24"),
+            Some("192.0.2.0/24".into())
+        );
+
+        assert_eq!(percent_decode("%4"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+}
@@ -286,18 +286,67 @@
 //! or [`accept()`]. Otherwise the connection will use a default CC algorithm.
 //!
 //! [`CongestionControlAlgorithm`]: enum.CongestionControlAlgorithm.html
+//!
+//! ## Thread safety
+//!
+//! [`Config`] and [`Connection`], as well as their [HTTP/3 counterparts], are
+//! both `Send` and `Sync`, so connections can be moved to, and sharded
+//! across, worker threads without any wrapper. For this reason, the objects
+//! applications hand over to quiche, such as log writers, crypto providers
+//! and clocks, are required to be `Send` and `Sync` too.
+//!
+//! Methods that change the state of a connection take `&mut self`, so a
+//! connection used by several threads at the same time still needs to be
+//! protected by a lock, such as a [`Mutex`]. Only methods taking `&self`,
+//! e.g. [`stats()`] or [`timeout()`], can be called through a shared
+//! reference.
+//!
+//! [`Config`]: struct.Config.html
+//! [`Connection`]: struct.Connection.html
+//! [HTTP/3 counterparts]: h3/struct.Connection.html
+//! [`Mutex`]: https://doc.rust-lang.org/std/sync/struct.Mutex.html
+//! [`stats()`]: struct.Connection.html#method.stats
+//! [`timeout()`]: struct.Connection.html#method.timeout
+//!
+//! ## Building without the standard library
+//!
+//! The core transport can be built on top of the `core` and `alloc` crates
+//! only, by disabling the default `std` feature. In this configuration
+//! `quiche::Instant` replaces `std::time::Instant`, and a [`Clock`] must
+//! be configured with [`set_clock()`] before creating connections. The
+//! [HTTP/3 module] as well as the `qlog`, `ffi`, `endpoint` and `rustls`
+//! features require the standard library.
+//!
+//! [`Clock`]: trait.Clock.html
+//! [`set_clock()`]: struct.Config.html#method.set_clock
 
 #![allow(clippy::upper_case_acronyms)]
 #![warn(missing_docs)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[macro_use]
+extern crate alloc;
 
 #[macro_use]
 extern crate log;
 
+#[cfg(all(feature = "qlog", not(feature = "std")))]
+compile_error!("the \"qlog\" feature requires the \"std\" feature");
+
+#[cfg(feature = "rustls")]
+extern crate rustls_crate as rustls;
+
+#[cfg(feature = "qlog")]
+use qlog::events::connectivity::ConnectivityEventType;
+#[cfg(feature = "qlog")]
+use qlog::events::connectivity::MigrationState;
 #[cfg(feature = "qlog")]
 use qlog::events::connectivity::TransportOwner;
 #[cfg(feature = "qlog")]
 use qlog::events::quic::RecoveryEventType;
 #[cfg(feature = "qlog")]
+use qlog::events::quic::SecurityEventType;
+#[cfg(feature = "qlog")]
 use qlog::events::quic::TransportEventType;
 #[cfg(feature = "qlog")]
 use qlog::events::DataRecipient;
@@ -312,15 +361,31 @@ use qlog::events::EventType;
 #[cfg(feature = "qlog")]
 use qlog::events::RawInfo;
 
-use std::cmp;
-use std::time;
+use core::cmp;
+
+#[cfg(feature = "std")]
+use std::io::IoSliceMut;
 
+#[cfg(feature = "std")]
 use std::net::SocketAddr;
 
-use std::pin::Pin;
-use std::str::FromStr;
+#[cfg(not(feature = "std"))]
+use core::net::SocketAddr;
 
-use std::collections::VecDeque;
+use core::pin::Pin;
+use core::str::FromStr;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+#[cfg(feature = "qlog")]
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use alloc::sync::Arc;
+
+use alloc::collections::VecDeque;
+
+use crate::collections::Map;
 
 /// The current QUIC wire version.
 pub const PROTOCOL_VERSION: u32 = PROTOCOL_VERSION_V1;
@@ -333,6 +398,14 @@ const PROTOCOL_VERSION_DRAFT27: u32 = 0xff00_001b;
 const PROTOCOL_VERSION_DRAFT28: u32 = 0xff00_001c;
 const PROTOCOL_VERSION_DRAFT29: u32 = 0xff00_001d;
 
+// The supported versions, in order of preference.
+const PROTOCOL_VERSIONS: [u32; 4] = [
+    PROTOCOL_VERSION_V1,
+    PROTOCOL_VERSION_DRAFT29,
+    PROTOCOL_VERSION_DRAFT28,
+    PROTOCOL_VERSION_DRAFT27,
+];
+
 /// The maximum length of a connection ID.
 pub const MAX_CONN_ID_LEN: usize = crate::packet::MAX_CID_LEN as usize;
 
@@ -350,6 +423,14 @@ const PAYLOAD_MIN_LEN: usize = 20;
 
 const MAX_AMPLIFICATION_FACTOR: usize = 3;
 
+// The number of PATH_CHALLENGE frames sent on a path before its validation is
+// considered failed. Each is given one PTO to be answered.
+const MAX_PATH_CHALLENGES: usize = 3;
+
+// The maximum number of PATH_RESPONSE frames waiting to be sent to other
+// addresses than the peer's.
+const MAX_PATH_RESPONSES: usize = 8;
+
 // The maximum number of tracked packet number ranges that need to be acked.
 //
 // This represents more or less how many ack blocks can fit in a typical packet.
@@ -364,6 +445,9 @@ const MAX_SEND_UDP_PAYLOAD_SIZE: usize = 1200;
 // The default length of DATAGRAM queues.
 const DEFAULT_MAX_DGRAM_QUEUE_LEN: usize = 0;
 
+// The default number of packet frame buffers kept for reuse by a connection.
+const DEFAULT_BUFFER_POOL_SIZE: usize = 64;
+
 // The DATAGRAM standard recommends either none or 65536 as maximum DATAGRAM
 // frames size. We enforce the recommendation for forward compatibility.
 const MAX_DGRAM_FRAME_SIZE: u64 = 65536;
@@ -371,11 +455,44 @@ const MAX_DGRAM_FRAME_SIZE: u64 = 65536;
 // The length of the payload length field.
 const PAYLOAD_LENGTH_LEN: usize = 2;
 
-// The number of undecryptable that can be buffered.
-const MAX_UNDECRYPTABLE_PACKETS: usize = 10;
+// The default number of undecryptable packets that can be buffered.
+const DEFAULT_MAX_UNDECRYPTABLE_PACKETS: usize = 10;
+
+// The default maximum number of transport events waiting to be polled.
+const DEFAULT_MAX_EVENTS: usize = 1024;
+
+// The maximum number of spare receive buffers kept by a connection.
+const MAX_RECV_BUF_POOL_SIZE: usize = 16;
+
+// The minimum interval between two entries of the statistics history.
+const STATS_HISTORY_INTERVAL: time::Duration = time::Duration::from_millis(100);
 
 const RESERVED_VERSION_MASK: u32 = 0xfafafafa;
 
+// The largest `N` for which all `31 * N + offset` GREASE codepoints still fit
+// in a variable-length integer.
+const MAX_GREASE_INDEX: u64 = 148_764_065_110_560_898;
+
+// The maximum length of the payload of a GREASE transport parameter.
+const MAX_GREASE_PARAM_LEN: usize = 16;
+
+// The maximum length of a transport parameter's type and length fields.
+const MAX_PARAM_HEADER_LEN: usize = 16;
+
+// The CRYPTO_ERROR code of the TLS bad_certificate_status_response alert,
+// sent when the verifier rejects the server's certificate status.
+const CERT_STATUS_ALERT_ERROR: u64 = 0x100 + 113;
+
+// The largest valid value of the max_ack_delay transport parameter, in
+// milliseconds.
+const MAX_ACK_DELAY: u64 = 2_u64.pow(14) - 1;
+
+// The largest valid value of the ack_delay_exponent transport parameter.
+const MAX_ACK_DELAY_EXPONENT: u64 = 20;
+
+// The largest valid DSCP value, which is 6 bits long.
+const MAX_DSCP: u8 = 63;
+
 // The default size of the receiver connection flow control window.
 const DEFAULT_CONNECTION_WINDOW: u64 = 48 * 1024;
 
@@ -392,10 +509,11 @@ const CONNECTION_WINDOW_FACTOR: f64 = 1.5;
 /// can produce an error.
 ///
 /// [`Result`]: https://doc.rust-lang.org/std/result/enum.Result.html
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = core::result::Result<T, Error>;
 
 /// A QUIC error.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
 pub enum Error {
     /// There is no more work to do.
     Done,
@@ -408,7 +526,9 @@ pub enum Error {
 
     /// The provided packet cannot be parsed because it contains an invalid
     /// frame.
-    InvalidFrame,
+    ///
+    /// The type of the offending frame is provided as associated data.
+    InvalidFrame(u64),
 
     /// The provided packet cannot be parsed.
     InvalidPacket,
@@ -433,10 +553,17 @@ pub enum Error {
     TlsFail,
 
     /// The peer violated the local flow control limits.
-    FlowControl,
+    ///
+    /// The ID of the stream whose data exceeded the stream or connection
+    /// limit is provided as associated data.
+    FlowControl(u64),
 
-    /// The peer violated the local stream limits.
-    StreamLimit,
+    /// The peer violated the local stream limits, or the local endpoint tried
+    /// to open a stream beyond the peer's limits.
+    ///
+    /// The ID of the stream that could not be opened is provided as
+    /// associated data.
+    StreamLimit(u64),
 
     /// The specified stream was stopped by the peer.
     ///
@@ -451,62 +578,91 @@ pub enum Error {
     StreamReset(u64),
 
     /// The received data exceeds the stream's final size.
-    FinalSize,
+    ///
+    /// The stream ID is provided as associated data.
+    FinalSize(u64),
 
     /// Error in congestion control.
     CongestionControl,
+
+    /// The peer violated the local connection ID limit.
+    IdLimit,
+
+    /// No spare connection ID issued by the peer is available.
+    OutOfIdentifiers,
 }
 
 impl Error {
-    fn to_wire(self) -> u64 {
+    /// Returns the transport error code sent to the peer when the error
+    /// causes the connection to be closed.
+    pub fn to_wire(self) -> u64 {
         match self {
             Error::Done => 0x0,
-            Error::InvalidFrame => 0x7,
+            Error::InvalidFrame(..) => 0x7,
             Error::InvalidStreamState(..) => 0x5,
             Error::InvalidTransportParam => 0x8,
-            Error::FlowControl => 0x3,
-            Error::StreamLimit => 0x4,
-            Error::FinalSize => 0x6,
+            Error::FlowControl(..) => 0x3,
+            Error::StreamLimit(..) => 0x4,
+            Error::FinalSize(..) => 0x6,
+            Error::IdLimit => 0x9,
             _ => 0xa,
         }
     }
 
+    /// Returns a short description of the error, sent to the peer as the
+    /// reason of the `CONNECTION_CLOSE` frame.
+    fn to_reason(self) -> &'static str {
+        match self {
+            Error::InvalidFrame(..) => "frame encoding error",
+            Error::InvalidStreamState(..) => "stream state error",
+            Error::InvalidTransportParam => "transport parameter error",
+            Error::FlowControl(..) => "flow control error",
+            Error::StreamLimit(..) => "stream limit error",
+            Error::FinalSize(..) => "final size error",
+            Error::IdLimit => "connection id limit error",
+            _ => "protocol violation",
+        }
+    }
+
     #[cfg(feature = "ffi")]
     fn to_c(self) -> libc::ssize_t {
         match self {
             Error::Done => -1,
             Error::BufferTooShort => -2,
             Error::UnknownVersion => -3,
-            Error::InvalidFrame => -4,
+            Error::InvalidFrame(_) => -4,
             Error::InvalidPacket => -5,
             Error::InvalidState => -6,
             Error::InvalidStreamState(_) => -7,
             Error::InvalidTransportParam => -8,
             Error::CryptoFail => -9,
             Error::TlsFail => -10,
-            Error::FlowControl => -11,
-            Error::StreamLimit => -12,
-            Error::FinalSize => -13,
+            Error::FlowControl(_) => -11,
+            Error::StreamLimit(_) => -12,
+            Error::FinalSize(_) => -13,
             Error::CongestionControl => -14,
             Error::StreamStopped { .. } => -15,
             Error::StreamReset { .. } => -16,
+            Error::IdLimit => -17,
+            Error::OutOfIdentifiers => -18,
         }
     }
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         None
     }
 }
 
-impl std::convert::From<octets::BufferTooShortError> for Error {
+impl core::convert::From<octets::BufferTooShortError> for Error {
     fn from(_err: octets::BufferTooShortError) -> Self {
         Error::BufferTooShort
     }
@@ -519,18 +675,77 @@ pub struct RecvInfo {
     pub from: SocketAddr,
 }
 
+/// A datagram being processed by `recv()`.
+enum Datagram<'a> {
+    /// A buffer borrowed from the application.
+    Borrowed(&'a mut [u8]),
+
+    /// A buffer handed over to the connection with `recv_owned()`, that STREAM
+    /// frames can reference instead of copying their data.
+    Owned(Arc<Vec<u8>>),
+}
+
+impl Datagram<'_> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Datagram::Borrowed(v) => v,
+
+            Datagram::Owned(v) => v,
+        }
+    }
+
+    fn as_mut(&mut self) -> &mut [u8] {
+        match self {
+            Datagram::Borrowed(v) => v,
+
+            // The buffer is only copied if frames from a previous coalesced
+            // packet already reference it.
+            Datagram::Owned(v) => Arc::make_mut(v).as_mut_slice(),
+        }
+    }
+
+    fn shared(&self) -> Option<&Arc<Vec<u8>>> {
+        match self {
+            Datagram::Borrowed(_) => None,
+
+            Datagram::Owned(v) => Some(v),
+        }
+    }
+}
+
 /// Ancillary information about outgoing packets.
+///
+/// More fields may be added in the future, so this can't be constructed
+/// outside of quiche.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
 pub struct SendInfo {
+    /// The local address the packet should be sent from.
+    ///
+    /// This is only set for packets probing a path started with
+    /// [`probe_path()`], otherwise the packet should be sent from the address
+    /// used by the connection.
+    ///
+    /// [`probe_path()`]: struct.Connection.html#method.probe_path
+    pub from: Option<SocketAddr>,
+
     /// The address the packet should be sent to.
     pub to: SocketAddr,
 
     /// The time to send the packet out.
     pub at: time::Instant,
+
+    /// The DSCP value the packet should be marked with, that is the upper six
+    /// bits of the IPv4 TOS or IPv6 Traffic Class field.
+    pub dscp: u8,
 }
 
 /// Represents information carried by `CONNECTION_CLOSE` frames.
+///
+/// More fields may be added in the future, so this can't be constructed
+/// outside of quiche.
 #[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
 pub struct ConnectionError {
     /// Whether the error came from the application or the transport layer.
     pub is_app: bool,
@@ -540,6 +755,13 @@ pub struct ConnectionError {
 
     /// The reason carried by the `CONNECTION_CLOSE` frame.
     pub reason: Vec<u8>,
+
+    /// The type of the frame that triggered the error, as carried by
+    /// transport `CONNECTION_CLOSE` frames.
+    ///
+    /// This is `None` for application errors, and for local errors not caused
+    /// by a frame received from the peer.
+    pub frame_type: Option<u64>,
 }
 
 /// The stream's side to shutdown.
@@ -570,29 +792,156 @@ pub enum QlogLevel {
     Extra = 2,
 }
 
+/// Qlog event category.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg(feature = "qlog")]
+pub enum QlogCategory {
+    /// Connection-level events, e.g. connection start and close.
+    Connectivity = 0,
+
+    /// TLS and key update events.
+    Security     = 1,
+
+    /// Transport events, e.g. packets sent and received.
+    Transport    = 2,
+
+    /// Loss recovery and congestion control events.
+    Recovery     = 3,
+
+    /// HTTP/3 events.
+    Http         = 4,
+
+    /// QPACK events.
+    Qpack        = 5,
+}
+
+/// Qlog output format.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg(feature = "qlog")]
+pub enum QlogFormat {
+    /// A single JSON document, completed when the connection is closed.
+    Json    = 0,
+
+    /// JSON text sequences, with each event written as a separate record.
+    JsonSeq = 1,
+}
+
+/// The order in which DATAGRAM frames and stream data are sent.
+///
+/// This should be used when calling [`set_dgram_send_order()`].
+///
+/// [`set_dgram_send_order()`]: struct.Config.html#method.set_dgram_send_order
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DgramSendOrder {
+    /// Alternate between packets carrying DATAGRAM frames and packets
+    /// carrying stream data, as configured with [`set_dgram_send_weight()`].
+    ///
+    /// [`set_dgram_send_weight()`]: struct.Config.html#method.set_dgram_send_weight
+    RoundRobin    = 0,
+
+    /// Send queued DATAGRAM frames before any stream data.
+    DatagramFirst = 1,
+
+    /// Send stream data before any queued DATAGRAM frames.
+    StreamFirst   = 2,
+}
+
+/// The origin of the address validation token sent in a client's Initial
+/// packets.
+///
+/// This is returned by [`token_kind()`].
+///
+/// [`token_kind()`]: struct.Connection.html#method.token_kind
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    /// The token was received in a Retry packet, and is only valid for the
+    /// current connection.
+    Retry    = 0,
+
+    /// The token was received in a NEW_TOKEN frame during a previous
+    /// connection, and was set with [`set_token()`].
+    ///
+    /// [`set_token()`]: struct.Connection.html#method.set_token
+    NewToken = 1,
+}
+
 /// Stores configuration shared between multiple connections.
 pub struct Config {
     local_transport_params: TransportParams,
 
     version: u32,
 
+    supported_versions: Vec<u32>,
+
     tls_ctx: tls::Context,
 
+    server_certs: Arc<tls::ServerCerts>,
+
+    #[cfg(not(feature = "rustls"))]
+    ech_config_list: Option<Vec<u8>>,
+    #[cfg(not(feature = "rustls"))]
+    ech_keys: Vec<tls::EchKey>,
+
     application_protos: Vec<Vec<u8>>,
 
+    #[cfg(not(feature = "rustls"))]
+    alpn_selector: Option<Arc<dyn AlpnSelector>>,
+
+    #[cfg(not(feature = "rustls"))]
+    application_settings: Vec<(Vec<u8>, Vec<u8>)>,
+
+    cert_status_verifier: Option<Arc<dyn CertStatusVerifier>>,
+
     grease: bool,
+    grease_transport_params: bool,
+    grease_versions: bool,
+    grease_index: Option<u64>,
 
     cc_algorithm: CongestionControlAlgorithm,
 
     hystart: bool,
 
+    events: bool,
+
+    max_events: usize,
+
+    dscp: u8,
+
+    packet_threshold: u64,
+    time_threshold: f64,
+    max_pto_backoff: u32,
+    initial_rtt: time::Duration,
+
     dgram_recv_max_queue_len: usize,
     dgram_send_max_queue_len: usize,
 
+    dgram_send_order: DgramSendOrder,
+    dgram_send_weight: usize,
+
+    dgram_recv_ttl: Option<time::Duration>,
+
     max_send_udp_payload_size: usize,
 
     max_connection_window: u64,
     max_stream_window: u64,
+
+    stream_credit_policy: StreamCreditPolicy,
+
+    stats_history_len: usize,
+
+    max_buffered_bytes: usize,
+
+    max_undecryptable_pkts: usize,
+
+    buffer_pool_size: usize,
+
+    crypto_provider: Arc<dyn CryptoProvider>,
+
+    clock: Arc<dyn Clock>,
 }
 
 // See https://quicwg.org/base-drafts/rfc9000.html#section-15
@@ -600,6 +949,19 @@ fn is_reserved_version(version: u32) -> bool {
     version & RESERVED_VERSION_MASK == version
 }
 
+// Generates a GREASE codepoint of the form `31 * N + offset`.
+//
+// `N` is chosen at random, unless `index` forces a specific value.
+pub(crate) fn grease_value(index: Option<u64>, offset: u64) -> u64 {
+    let n = match index {
+        Some(v) => v,
+
+        None => rand::rand_u64_uniform(MAX_GREASE_INDEX + 1),
+    };
+
+    31 * n + offset
+}
+
 impl Config {
     /// Creates a config object with the given version.
     ///
@@ -619,19 +981,68 @@ impl Config {
         Ok(Config {
             local_transport_params: TransportParams::default(),
             version,
+            supported_versions: PROTOCOL_VERSIONS.to_vec(),
             tls_ctx,
+            server_certs: Arc::new(Map::new()),
+            #[cfg(not(feature = "rustls"))]
+            ech_config_list: None,
+            #[cfg(not(feature = "rustls"))]
+            ech_keys: Vec::new(),
             application_protos: Vec::new(),
+
+            #[cfg(not(feature = "rustls"))]
+            alpn_selector: None,
+            #[cfg(not(feature = "rustls"))]
+            application_settings: Vec::new(),
+            cert_status_verifier: None,
             grease: true,
+            grease_transport_params: true,
+            grease_versions: true,
+            grease_index: None,
             cc_algorithm: CongestionControlAlgorithm::CUBIC,
             hystart: true,
 
+            events: false,
+
+            max_events: DEFAULT_MAX_EVENTS,
+
+            dscp: 0,
+
+            packet_threshold: recovery::INITIAL_PACKET_THRESHOLD,
+            time_threshold: recovery::INITIAL_TIME_THRESHOLD,
+            max_pto_backoff: recovery::MAX_PTO_BACKOFF,
+            initial_rtt: recovery::INITIAL_RTT,
+
             dgram_recv_max_queue_len: DEFAULT_MAX_DGRAM_QUEUE_LEN,
             dgram_send_max_queue_len: DEFAULT_MAX_DGRAM_QUEUE_LEN,
 
+            dgram_send_order: DgramSendOrder::RoundRobin,
+            dgram_send_weight: 1,
+
+            dgram_recv_ttl: None,
+
             max_send_udp_payload_size: MAX_SEND_UDP_PAYLOAD_SIZE,
 
             max_connection_window: MAX_CONNECTION_WINDOW,
             max_stream_window: stream::MAX_STREAM_WINDOW,
+
+            stream_credit_policy: StreamCreditPolicy::Replenish,
+
+            stats_history_len: 0,
+
+            max_buffered_bytes: 0,
+
+            max_undecryptable_pkts: DEFAULT_MAX_UNDECRYPTABLE_PACKETS,
+
+            buffer_pool_size: DEFAULT_BUFFER_POOL_SIZE,
+
+            crypto_provider: Arc::new(crypto::DefaultCryptoProvider),
+
+            #[cfg(feature = "std")]
+            clock: Arc::new(clock::SystemClock),
+
+            #[cfg(not(feature = "std"))]
+            clock: Arc::new(clock::NoClock),
         })
     }
 
@@ -666,6 +1077,49 @@ impl Config {
         self.tls_ctx.use_privkey_file(file)
     }
 
+    /// Configures an additional certificate chain and private key, used by
+    /// servers when the client requests `name` using the TLS Server Name
+    /// Indication extension.
+    ///
+    /// The content of `cert_file` and `key_file` is parsed as a PEM-encoded
+    /// certificate chain and private key respectively. A `name` starting with
+    /// `*.` matches any single label in its place (e.g. `*.example.com`
+    /// matches `www.example.com`), exact names take precedence over it.
+    ///
+    /// Clients that don't send a known name are presented the certificate
+    /// configured with [`load_cert_chain_from_pem_file()`].
+    ///
+    /// [`load_cert_chain_from_pem_file()`]: struct.Config.html#method.load_cert_chain_from_pem_file
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut config = quiche::Config::new(0xbabababa)?;
+    /// config.add_cert_for_name(
+    ///     "example.com",
+    ///     "/path/to/example.com.pem",
+    ///     "/path/to/example.com.key",
+    /// )?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn add_cert_for_name(
+        &mut self, name: &str, cert_file: &str, key_file: &str,
+    ) -> Result<()> {
+        let mut ctx = tls::Context::new()?;
+
+        ctx.use_certificate_chain_file(cert_file)?;
+        ctx.use_privkey_file(key_file)?;
+
+        let mut certs = (*self.server_certs).clone();
+        certs.insert(name.to_ascii_lowercase(), Arc::new(ctx));
+
+        self.server_certs = Arc::new(certs);
+
+        self.tls_ctx.enable_server_name_selection();
+
+        Ok(())
+    }
+
     /// Specifies a file where trusted CA certificates are stored for the
     /// purposes of certificate verification.
     ///
@@ -708,13 +1162,89 @@ impl Config {
         self.tls_ctx.set_verify(verify);
     }
 
+    /// Configures whether servers require clients to present a certificate.
+    ///
+    /// When enabled, the client's certificate is verified against the
+    /// certificate authorities configured with
+    /// [`load_verify_locations_from_file()`] or
+    /// [`load_verify_locations_from_directory()`], and the handshake fails if
+    /// the client doesn't send one. Clients present the certificate chain and
+    /// private key configured with [`load_cert_chain_from_pem_file()`] and
+    /// [`load_priv_key_from_pem_file()`].
+    ///
+    /// When the handshake fails because of the peer's certificate, the reason
+    /// is included in the connection's [`local_error()`].
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`load_verify_locations_from_file()`]: struct.Config.html#method.load_verify_locations_from_file
+    /// [`load_verify_locations_from_directory()`]: struct.Config.html#method.load_verify_locations_from_directory
+    /// [`load_cert_chain_from_pem_file()`]: struct.Config.html#method.load_cert_chain_from_pem_file
+    /// [`load_priv_key_from_pem_file()`]: struct.Config.html#method.load_priv_key_from_pem_file
+    /// [`local_error()`]: struct.Connection.html#method.local_error
+    pub fn verify_peer_required_client_cert(&mut self, required: bool) {
+        self.tls_ctx.set_require_peer_cert(required);
+    }
+
     /// Configures whether to send GREASE values.
     ///
+    /// This acts as a global switch: when disabled, no GREASE values are sent
+    /// at all, regardless of what the more specific options, such as
+    /// [`grease_transport_params()`], are set to.
+    ///
     /// The default value is `true`.
+    ///
+    /// [`grease_transport_params()`]:
+    /// struct.Config.html#method.grease_transport_params
     pub fn grease(&mut self, grease: bool) {
         self.grease = grease;
     }
 
+    /// Configures whether to send a reserved transport parameter.
+    ///
+    /// This has no effect if GREASE is disabled with [`grease()`].
+    ///
+    /// The default value is `true`.
+    ///
+    /// [`grease()`]: struct.Config.html#method.grease
+    pub fn grease_transport_params(&mut self, v: bool) {
+        self.grease_transport_params = v;
+    }
+
+    /// Configures whether to list a reserved version in Version Negotiation
+    /// packets written with [`negotiate_version_with_config()`].
+    ///
+    /// This has no effect if GREASE is disabled with [`grease()`].
+    ///
+    /// The default value is `true`.
+    ///
+    /// [`negotiate_version_with_config()`]: fn.negotiate_version_with_config.html
+    /// [`grease()`]: struct.Config.html#method.grease
+    pub fn grease_versions(&mut self, v: bool) {
+        self.grease_versions = v;
+    }
+
+    /// Forces the value of `N` used to derive reserved codepoints.
+    ///
+    /// By default GREASE codepoints are of the form `31 * N + 27` (for
+    /// transport parameters) and `31 * N + 33` (for HTTP/3 frame types,
+    /// stream types and settings), where `N` is chosen at random every time a
+    /// codepoint is generated. Setting an index makes all of them, as well as
+    /// the associated payloads, deterministic, which is mostly useful for
+    /// interoperability testing.
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// # let mut config = quiche::Config::new(0xbabababa)?;
+    /// // Always send transport parameter 0x1b and HTTP/3 type 0x21.
+    /// config.set_grease_index(0);
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn set_grease_index(&mut self, index: u64) {
+        self.grease_index = Some(index.min(MAX_GREASE_INDEX));
+    }
+
     /// Enables logging of secrets.
     ///
     /// When logging is enabled, the [`set_keylog()`] method must be called on
@@ -723,6 +1253,7 @@ impl Config {
     ///
     /// [`set_keylog()`]: struct.Connection.html#method.set_keylog
     /// [keylog]: https://developer.mozilla.org/en-US/docs/Mozilla/Projects/NSS/Key_Log_Format
+    #[cfg(feature = "std")]
     pub fn log_keys(&mut self) {
         self.tls_ctx.enable_keylog();
     }
@@ -737,11 +1268,119 @@ impl Config {
     /// specific key (e.g. in order to support resumption across multiple
     /// servers), in which case the application is also responsible for
     /// rotating the key to provide forward secrecy.
+    ///
+    /// This is not available with the rustls backend.
+    #[cfg(not(feature = "rustls"))]
     pub fn set_ticket_key(&mut self, key: &[u8]) -> Result<()> {
         self.tls_ctx.set_ticket_key(key)
     }
 
+    /// Configures the OCSP response stapled by servers to the certificate
+    /// loaded with [`load_cert_chain_from_pem_file()`].
+    ///
+    /// The response is only sent to clients that request it.
+    ///
+    /// [`load_cert_chain_from_pem_file()`]: struct.Config.html#method.load_cert_chain_from_pem_file
+    pub fn set_ocsp_response(&mut self, response: &[u8]) -> Result<()> {
+        self.tls_ctx.set_ocsp_response(response)
+    }
+
+    /// Configures the signed certificate timestamps (SCTs) sent by servers
+    /// along with the certificate loaded with
+    /// [`load_cert_chain_from_pem_file()`].
+    ///
+    /// `list` is a serialized SignedCertificateTimestampList structure (see
+    /// [RFC 6962]). It is only sent to clients that request it.
+    ///
+    /// [`load_cert_chain_from_pem_file()`]: struct.Config.html#method.load_cert_chain_from_pem_file
+    /// [RFC 6962]: https://www.rfc-editor.org/rfc/rfc6962.html#section-3.3
+    pub fn set_sct_list(&mut self, list: &[u8]) -> Result<()> {
+        self.tls_ctx.set_sct_list(list)
+    }
+
+    /// Configures whether clients request an OCSP response from the server.
+    ///
+    /// The response, if any, can be retrieved with [`ocsp_response()`].
+    ///
+    /// [`ocsp_response()`]: struct.Connection.html#method.ocsp_response
+    pub fn enable_ocsp_stapling(&mut self) {
+        self.tls_ctx.enable_ocsp_stapling();
+    }
+
+    /// Configures whether clients request signed certificate timestamps from
+    /// the server.
+    ///
+    /// The timestamps, if any, can be retrieved with [`sct_list()`].
+    ///
+    /// [`sct_list()`]: struct.Connection.html#method.sct_list
+    pub fn enable_signed_cert_timestamps(&mut self) {
+        self.tls_ctx.enable_signed_cert_timestamps();
+    }
+
+    /// Sets the verifier clients use to check the OCSP response and signed
+    /// certificate timestamps sent by the server.
+    ///
+    /// The verifier is called once the server's certificate chain was
+    /// received and verified, and the handshake fails with a
+    /// `bad_certificate_status_response` alert if it returns `false`.
+    pub fn set_cert_status_verifier(
+        &mut self, verifier: Arc<dyn CertStatusVerifier>,
+    ) {
+        self.cert_status_verifier = Some(verifier);
+    }
+
+    /// Configures the Encrypted Client Hello (ECH) configurations used by
+    /// clients.
+    ///
+    /// `list` is a serialized ECHConfigList structure, as published by the
+    /// server (e.g. in its HTTPS DNS record). When set, the server name passed
+    /// to [`connect()`] is only sent encrypted, and the public name of the
+    /// selected configuration is sent in its place.
+    ///
+    /// This is not available with the rustls backend.
+    ///
+    /// [`connect()`]: fn.connect.html
+    #[cfg(not(feature = "rustls"))]
+    pub fn set_ech_config_list(&mut self, list: &[u8]) -> Result<()> {
+        // Validate the list now, rather than when creating connections.
+        self.tls_ctx.new_handshake()?.set_ech_config_list(list)?;
+
+        self.ech_config_list = Some(list.to_vec());
+
+        Ok(())
+    }
+
+    /// Adds a key used by servers to decrypt Encrypted Client Hello (ECH).
+    ///
+    /// `ech_config` is the serialized ECHConfig structure published for the
+    /// key, and `private_key` the corresponding raw X25519 private key. When
+    /// `is_retry_config` is true, the configuration is also sent to clients
+    /// whose ECH was rejected, so they can retry with it.
+    ///
+    /// This is not available with the rustls backend.
+    #[cfg(not(feature = "rustls"))]
+    pub fn add_ech_key(
+        &mut self, ech_config: &[u8], private_key: &[u8], is_retry_config: bool,
+    ) -> Result<()> {
+        self.ech_keys.push(tls::EchKey {
+            config: ech_config.to_vec(),
+            private_key: private_key.to_vec(),
+            is_retry_config,
+        });
+
+        if let Err(e) = self.tls_ctx.set_ech_keys(&self.ech_keys) {
+            self.ech_keys.pop();
+
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
     /// Enables sending or receiving early data.
+    ///
+    /// This has no effect with the rustls backend, which doesn't support
+    /// session resumption, so early data is never sent nor accepted.
     pub fn enable_early_data(&mut self) {
         self.tls_ctx.set_early_data_enabled(true);
     }
@@ -780,32 +1419,165 @@ impl Config {
         self.tls_ctx.set_alpn(&self.application_protos)
     }
 
-    /// Sets the `max_idle_timeout` transport parameter, in milliseconds.
+    /// Sets the selector servers use to choose the application protocol
+    /// among the ones offered by the client.
     ///
-    /// The default value is infinite, that is, no timeout is used.
-    pub fn set_max_idle_timeout(&mut self, v: u64) {
-        self.local_transport_params.max_idle_timeout = v;
-    }
-
-    /// Sets the `max_udp_payload_size transport` parameter.
+    /// The selector takes precedence over the preference order in
+    /// [`set_application_protos()`], which is still used for clients.
     ///
-    /// The default value is `65527`.
-    pub fn set_max_recv_udp_payload_size(&mut self, v: usize) {
-        self.local_transport_params.max_udp_payload_size = v as u64;
-    }
-
-    /// Sets the maximum outgoing UDP payload size.
+    /// This is not available with the rustls backend.
     ///
-    /// The default and minimum value is `1200`.
-    pub fn set_max_send_udp_payload_size(&mut self, v: usize) {
-        self.max_send_udp_payload_size = cmp::max(v, MAX_SEND_UDP_PAYLOAD_SIZE);
-    }
-
-    /// Sets the `initial_max_data` transport parameter.
+    /// [`set_application_protos()`]: struct.Config.html#method.set_application_protos
     ///
-    /// When set to a non-zero value quiche will only allow at most `v` bytes
-    /// of incoming stream data to be buffered for the whole connection (that
-    /// is, data that is not yet read by the application) and will allow more
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// struct PreferCustom;
+    ///
+    /// impl quiche::AlpnSelector for PreferCustom {
+    ///     fn select<'a>(
+    ///         &self, _server_name: Option<&str>, offered: &[&'a [u8]],
+    ///     ) -> Option<&'a [u8]> {
+    ///         offered
+    ///             .iter()
+    ///             .find(|p| **p == b"custom")
+    ///             .or_else(|| offered.iter().find(|p| **p == b"h3"))
+    ///             .copied()
+    ///     }
+    /// }
+    ///
+    /// # let mut config = quiche::Config::new(0xbabababa)?;
+    /// config.set_alpn_selector(std::sync::Arc::new(PreferCustom))?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    #[cfg(not(feature = "rustls"))]
+    pub fn set_alpn_selector(
+        &mut self, selector: Arc<dyn AlpnSelector>,
+    ) -> Result<()> {
+        self.tls_ctx.enable_alpn_selection()?;
+
+        self.alpn_selector = Some(selector);
+
+        Ok(())
+    }
+
+    /// Adds application-layer protocol settings (ALPS) to send during the
+    /// handshake when the given ALPN protocol is negotiated.
+    ///
+    /// `proto` is a single protocol name, without length prefix. The
+    /// settings are only sent, and the peer's received, when both endpoints
+    /// configured settings for the negotiated protocol. The peer's settings
+    /// can then be retrieved with [`peer_application_settings()`].
+    ///
+    /// This is not available with the rustls backend.
+    ///
+    /// [`peer_application_settings()`]: struct.Connection.html#method.peer_application_settings
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut config = quiche::Config::new(0xbabababa)?;
+    /// config.set_application_protos(b"\x02h3")?;
+    /// config.add_application_settings(b"h3", b"\x00")?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    #[cfg(not(feature = "rustls"))]
+    pub fn add_application_settings(
+        &mut self, proto: &[u8], settings: &[u8],
+    ) -> Result<()> {
+        // Validate the settings now, rather than when creating connections.
+        self.tls_ctx
+            .new_handshake()?
+            .add_application_settings(proto, settings)?;
+
+        self.application_settings
+            .push((proto.to_vec(), settings.to_vec()));
+
+        Ok(())
+    }
+
+    /// Configures the list of supported QUIC versions, in order of
+    /// preference.
+    ///
+    /// On the client this is used to select the version to switch to when
+    /// receiving a Version Negotiation packet, in which case the first version
+    /// in `versions` that is also supported by the server is used. Note that
+    /// the version used for the first Initial packet is the one passed to
+    /// [`new()`].
+    ///
+    /// On the server this configures the versions clients are allowed to use,
+    /// as well as the list of versions advertised by
+    /// [`negotiate_version_with_config()`].
+    ///
+    /// The [`UnknownVersion`] error is returned if `versions` is empty, or if
+    /// any of the versions is not supported.
+    ///
+    /// The default value is all the supported versions, with the final
+    /// version being preferred over the draft ones.
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// # let mut config = quiche::Config::new(0xbabababa)?;
+    /// config.set_supported_versions(&[quiche::PROTOCOL_VERSION])?;
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    ///
+    /// [`new()`]: struct.Config.html#method.new
+    /// [`negotiate_version_with_config()`]: fn.negotiate_version_with_config.html
+    /// [`UnknownVersion`]: enum.Error.html#variant.UnknownVersion
+    pub fn set_supported_versions(&mut self, versions: &[u32]) -> Result<()> {
+        if versions.is_empty() ||
+            !versions.iter().all(|&v| version_is_supported(v))
+        {
+            return Err(Error::UnknownVersion);
+        }
+
+        self.supported_versions = versions.to_vec();
+
+        Ok(())
+    }
+
+    /// Returns true if the given protocol version is one of the versions
+    /// configured with [`set_supported_versions()`].
+    ///
+    /// Servers should use this rather than [`version_is_supported()`] to
+    /// decide whether to send a Version Negotiation packet, as connections
+    /// using other versions are rejected by [`accept()`].
+    ///
+    /// [`set_supported_versions()`]: struct.Config.html#method.set_supported_versions
+    /// [`version_is_supported()`]: fn.version_is_supported.html
+    /// [`accept()`]: fn.accept.html
+    pub fn is_version_supported(&self, version: u32) -> bool {
+        self.supported_versions.contains(&version)
+    }
+
+    /// Sets the `max_idle_timeout` transport parameter, in milliseconds.
+    ///
+    /// The default value is infinite, that is, no timeout is used.
+    pub fn set_max_idle_timeout(&mut self, v: u64) {
+        self.local_transport_params.max_idle_timeout = v;
+    }
+
+    /// Sets the `max_udp_payload_size transport` parameter.
+    ///
+    /// The default value is `65527`.
+    pub fn set_max_recv_udp_payload_size(&mut self, v: usize) {
+        self.local_transport_params.max_udp_payload_size = v as u64;
+    }
+
+    /// Sets the maximum outgoing UDP payload size.
+    ///
+    /// The default and minimum value is `1200`.
+    pub fn set_max_send_udp_payload_size(&mut self, v: usize) {
+        self.max_send_udp_payload_size = cmp::max(v, MAX_SEND_UDP_PAYLOAD_SIZE);
+    }
+
+    /// Sets the `initial_max_data` transport parameter.
+    ///
+    /// When set to a non-zero value quiche will only allow at most `v` bytes
+    /// of incoming stream data to be buffered for the whole connection (that
+    /// is, data that is not yet read by the application) and will allow more
     /// data to be received as the buffer is consumed by the application.
     ///
     /// The default value is `0`.
@@ -889,16 +1661,27 @@ impl Config {
 
     /// Sets the `ack_delay_exponent` transport parameter.
     ///
+    /// This is the exponent used to encode the ACK Delay field of the ACK
+    /// frames sent by the local endpoint, with lower values providing more
+    /// precise delays to the peer's RTT estimator. Values above 20 are
+    /// invalid, and are capped to 20.
+    ///
     /// The default value is `3`.
     pub fn set_ack_delay_exponent(&mut self, v: u64) {
-        self.local_transport_params.ack_delay_exponent = v;
+        self.local_transport_params.ack_delay_exponent =
+            cmp::min(v, MAX_ACK_DELAY_EXPONENT);
     }
 
-    /// Sets the `max_ack_delay` transport parameter.
+    /// Sets the `max_ack_delay` transport parameter, in milliseconds.
+    ///
+    /// This is the maximum amount of time the local endpoint promises to
+    /// delay sending acknowledgments by, which the peer takes into account
+    /// when computing its retransmission timers. Values of 2^14 or greater
+    /// are invalid, and are capped to 2^14-1.
     ///
     /// The default value is `25`.
     pub fn set_max_ack_delay(&mut self, v: u64) {
-        self.local_transport_params.max_ack_delay = v;
+        self.local_transport_params.max_ack_delay = cmp::min(v, MAX_ACK_DELAY);
     }
 
     /// Sets the `disable_active_migration` transport parameter.
@@ -908,6 +1691,17 @@ impl Config {
         self.local_transport_params.disable_active_migration = v;
     }
 
+    /// Sets the `active_connection_id_limit` transport parameter.
+    ///
+    /// This is the maximum number of connection IDs issued by the peer that
+    /// are kept, including the one in use, which limits how many paths can be
+    /// probed at the same time. Values lower than `2` are ignored.
+    ///
+    /// The default value is `2`.
+    pub fn set_active_connection_id_limit(&mut self, v: u64) {
+        self.local_transport_params.active_conn_id_limit = cmp::max(v, 2);
+    }
+
     /// Sets the congestion control algorithm used by string.
     ///
     /// The default value is `cubic`. On error `Error::CongestionControl`
@@ -940,6 +1734,152 @@ impl Config {
         self.hystart = v;
     }
 
+    /// Configures whether connections should queue transport events to be
+    /// retrieved with [`poll_event()`].
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`poll_event()`]: struct.Connection.html#method.poll_event
+    pub fn enable_events(&mut self, v: bool) {
+        self.events = v;
+    }
+
+    /// Sets the maximum number of transport events waiting to be retrieved
+    /// with [`poll_event()`].
+    ///
+    /// Events that are identical to one already waiting are not queued again,
+    /// and a new connection-level flow control limit replaces the previous
+    /// one. Other events are dropped once the limit is reached, and counted
+    /// in [`Stats::events_dropped`].
+    ///
+    /// The default value is `1024`.
+    ///
+    /// [`poll_event()`]: struct.Connection.html#method.poll_event
+    /// [`Stats::events_dropped`]: struct.Stats.html#structfield.events_dropped
+    pub fn set_max_events(&mut self, v: usize) {
+        self.max_events = v;
+    }
+
+    /// Sets the DSCP value outgoing packets should be marked with.
+    ///
+    /// The value is reported in the [`SendInfo`] of each packet, for the
+    /// application to apply when sending the packet out. It can be changed
+    /// for individual connections with [`set_dscp()`]. Values above 63 are
+    /// invalid, and are capped to 63.
+    ///
+    /// The default value is `0`.
+    ///
+    /// [`SendInfo`]: struct.SendInfo.html
+    /// [`set_dscp()`]: struct.Connection.html#method.set_dscp
+    pub fn set_dscp(&mut self, v: u8) {
+        self.dscp = cmp::min(v, MAX_DSCP);
+    }
+
+    /// Sets the number of packets a packet must be outstanding for, after a
+    /// later packet was acknowledged, before it is declared lost.
+    ///
+    /// The threshold is still raised when spurious losses are detected.
+    ///
+    /// The default value is `3`.
+    pub fn set_packet_threshold(&mut self, v: u64) {
+        self.packet_threshold = cmp::max(v, 1);
+    }
+
+    /// Sets the multiple of the RTT a packet must be outstanding for, after a
+    /// later packet was acknowledged, before it is declared lost.
+    ///
+    /// Values below `1.0` are treated as `1.0`.
+    ///
+    /// The default value is `1.125`.
+    pub fn set_time_threshold(&mut self, v: f64) {
+        self.time_threshold = v.max(1.0);
+    }
+
+    /// Sets the maximum number of times the probe timeout is doubled after
+    /// consecutive timeouts without an acknowledgement.
+    ///
+    /// The default value is `16`.
+    pub fn set_max_pto_backoff(&mut self, v: u32) {
+        self.max_pto_backoff = cmp::min(v, 31);
+    }
+
+    /// Sets the RTT assumed before the first RTT sample is taken, in
+    /// milliseconds.
+    ///
+    /// The default value is `333`.
+    pub fn set_initial_rtt(&mut self, v: u64) {
+        self.initial_rtt = time::Duration::from_millis(cmp::max(v, 1));
+    }
+
+    /// Sets the maximum number of buffers each connection keeps for reuse
+    /// when building packets.
+    ///
+    /// Buffers used to track the frames of sent packets are recycled once the
+    /// packets are acked or declared lost, instead of being allocated anew
+    /// for every packet. A value of `0` disables reuse.
+    ///
+    /// The default value is `64`.
+    pub fn set_buffer_pool_size(&mut self, v: usize) {
+        self.buffer_pool_size = v;
+    }
+
+    /// Sets the provider of the cryptographic primitives used to protect
+    /// packets.
+    ///
+    /// This allows replacing the built-in AEAD, header protection and HKDF
+    /// implementations, e.g. to use a hardware accelerator. With the
+    /// `rustls` feature only Initial packets use the provider, as the keys
+    /// negotiated by rustls can't be exported.
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// struct Accelerator;
+    ///
+    /// impl quiche::CryptoProvider for Accelerator {
+    ///     // Override the primitives to replace here.
+    /// }
+    ///
+    /// let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// config.set_crypto_provider(std::sync::Arc::new(Accelerator));
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn set_crypto_provider(&mut self, provider: Arc<dyn CryptoProvider>) {
+        self.crypto_provider = provider;
+    }
+
+    /// Sets the clock connections use to read the current time.
+    ///
+    /// All timers of a connection, including those of its HTTP/3 layer, are
+    /// based on this clock, and the deadline returned by [`timeout()`] is
+    /// expressed in its time. This allows tests and simulators to advance
+    /// virtual time deterministically instead of sleeping.
+    ///
+    /// The default is [`SystemClock`]. Without the standard library there is
+    /// no system clock, so a clock must be set before creating connections.
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// struct Fixed(std::time::Instant);
+    ///
+    /// impl quiche::Clock for Fixed {
+    ///     fn now(&self) -> std::time::Instant {
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// config.set_clock(std::sync::Arc::new(Fixed(std::time::Instant::now())));
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    ///
+    /// [`timeout()`]: struct.Connection.html#method.timeout
+    /// [`SystemClock`]: struct.SystemClock.html
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
     /// Configures whether to enable receiving DATAGRAM frames.
     ///
     /// When enabled, the `max_datagram_frame_size` transport parameter is set
@@ -958,8 +1898,45 @@ impl Config {
         self.dgram_send_max_queue_len = send_queue_len;
     }
 
+    /// Sets the order in which DATAGRAM frames and stream data are sent.
+    ///
+    /// A packet carrying DATAGRAM frames doesn't carry stream data, so this
+    /// decides which of the two is sent first when both are pending. The
+    /// other kind is still sent when nothing of the preferred kind is.
+    ///
+    /// The default value is `DgramSendOrder::RoundRobin`.
+    pub fn set_dgram_send_order(&mut self, order: DgramSendOrder) {
+        self.dgram_send_order = order;
+    }
+
+    /// Sets the number of consecutive packets carrying DATAGRAM frames that
+    /// can be sent before stream data gets a turn, when using
+    /// `DgramSendOrder::RoundRobin`.
+    ///
+    /// The default value is `1`. A value of `0` is treated as `1`.
+    pub fn set_dgram_send_weight(&mut self, v: usize) {
+        self.dgram_send_weight = cmp::max(v, 1);
+    }
+
+    /// Sets how long received DATAGRAMs are kept in the receive queue, in
+    /// milliseconds.
+    ///
+    /// DATAGRAMs that weren't read by the application within this time are
+    /// dropped when new ones are received, or when calling [`dgram_recv()`].
+    ///
+    /// The default is no limit.
+    ///
+    /// [`dgram_recv()`]: struct.Connection.html#method.dgram_recv
+    pub fn set_dgram_recv_ttl(&mut self, v: u64) {
+        self.dgram_recv_ttl = Some(time::Duration::from_millis(v));
+    }
+
     /// Sets the maximum size of the connection window.
     ///
+    /// The receive windows start at the configured initial limits, and are
+    /// grown automatically when the application consumes data fast enough
+    /// relative to the path's RTT, up to this size.
+    ///
     /// The default value is MAX_CONNECTION_WINDOW (24MBytes).
     pub fn set_max_connection_window(&mut self, v: u64) {
         self.max_connection_window = v;
@@ -971,6 +1948,84 @@ impl Config {
     pub fn set_max_stream_window(&mut self, v: u64) {
         self.max_stream_window = v;
     }
+
+    /// Sets how stream credits are given back to the peer as the streams it
+    /// opened are closed.
+    ///
+    /// The initial credits are set with [`set_initial_max_streams_bidi()`]
+    /// and [`set_initial_max_streams_uni()`]. Unless the policy is
+    /// [`StreamCreditPolicy::Fixed`], which keeps the number of concurrent
+    /// streams to those, more can be given with [`grant_streams()`], for
+    /// example to raise the concurrency gradually once a client is
+    /// authenticated.
+    ///
+    /// The default value is [`StreamCreditPolicy::Replenish`].
+    ///
+    /// [`set_initial_max_streams_bidi()`]:
+    /// struct.Config.html#method.set_initial_max_streams_bidi
+    /// [`set_initial_max_streams_uni()`]:
+    /// struct.Config.html#method.set_initial_max_streams_uni
+    /// [`StreamCreditPolicy::Fixed`]:
+    /// enum.StreamCreditPolicy.html#variant.Fixed
+    /// [`grant_streams()`]: struct.Connection.html#method.grant_streams
+    /// [`StreamCreditPolicy::Replenish`]:
+    /// enum.StreamCreditPolicy.html#variant.Replenish
+    pub fn set_stream_credit_policy(&mut self, policy: StreamCreditPolicy) {
+        self.stream_credit_policy = policy;
+    }
+
+    /// Sets the number of entries kept in the connection's statistics
+    /// history.
+    ///
+    /// When enabled, the connection records a snapshot of its statistics at
+    /// most every 100 milliseconds while packets are being sent or received,
+    /// keeping only the most recent `v` snapshots. The history can then be
+    /// retrieved with [`stats_history()`], for example once the connection
+    /// is closed, without having to enable qlog.
+    ///
+    /// The default value is `0`, which disables the history.
+    ///
+    /// [`stats_history()`]: struct.Connection.html#method.stats_history
+    pub fn set_stats_history_len(&mut self, v: usize) {
+        self.stats_history_len = v;
+    }
+
+    /// Sets the maximum amount of data a connection can buffer, in bytes.
+    ///
+    /// This covers the send and receive buffers of all streams, the DATAGRAM
+    /// queues and the packets stored until they can be decrypted. Once the
+    /// limit is reached, [`stream_send()`] and [`dgram_send()`] stop
+    /// accepting data, and flow control credit is held back so that the peer
+    /// can't send more data than the connection has room for.
+    ///
+    /// Credit granted by the initial flow control limits can't be held back,
+    /// so they should be lower than this limit for it to be strict.
+    ///
+    /// The current amount is reported by the [`buffered_bytes`] statistic.
+    ///
+    /// The default value is `0`, meaning there is no limit.
+    ///
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    /// [`dgram_send()`]: struct.Connection.html#method.dgram_send
+    /// [`buffered_bytes`]: struct.Stats.html#structfield.buffered_bytes
+    pub fn set_max_buffered_bytes(&mut self, v: usize) {
+        self.max_buffered_bytes = v;
+    }
+
+    /// Sets the maximum number of packets buffered because they arrived
+    /// before the keys needed to decrypt them.
+    ///
+    /// This happens when packets are reordered during the handshake, for
+    /// example when 0-RTT packets arrive before the client's Initial, or
+    /// Handshake and 1-RTT packets before the server's Initial. Buffered
+    /// packets are processed as soon as their keys become available, instead
+    /// of waiting for the peer to retransmit them. Setting this to `0`
+    /// disables buffering.
+    ///
+    /// The default value is `10`.
+    pub fn set_max_undecryptable_packets(&mut self, v: usize) {
+        self.max_undecryptable_pkts = v;
+    }
 }
 
 /// A QUIC connection.
@@ -1013,6 +2068,22 @@ pub struct Connection {
     /// List of supported application protocols.
     application_protos: Vec<Vec<u8>>,
 
+    /// Server-side hook choosing the application protocol.
+    #[cfg(not(feature = "rustls"))]
+    alpn_selector: Option<Arc<dyn AlpnSelector>>,
+
+    /// Client-side hook checking the server's certificate status.
+    cert_status_verifier: Option<Arc<dyn CertStatusVerifier>>,
+
+    /// Certificates to select from based on the requested server name.
+    server_certs: Arc<tls::ServerCerts>,
+
+    /// Provider of the packet protection primitives.
+    crypto_provider: Arc<dyn CryptoProvider>,
+
+    /// Source of the current time.
+    clock: Arc<dyn Clock>,
+
     /// Total number of received packets.
     recv_count: usize,
 
@@ -1051,6 +2122,14 @@ pub struct Connection {
     /// Total number of bytes sent over the connection.
     sent_bytes: u64,
 
+    /// Number of times sending was restricted by the anti-amplification
+    /// limit.
+    amplification_limited_count: usize,
+
+    /// Whether sending was restricted by the anti-amplification limit the
+    /// last time packets were sent.
+    amplification_limited: bool,
+
     /// Total number of bytes recevied over the connection.
     recv_bytes: u64,
 
@@ -1068,6 +2147,15 @@ pub struct Connection {
     /// Received address verification token.
     token: Option<Vec<u8>>,
 
+    /// Where the address verification token came from.
+    token_kind: Option<TokenKind>,
+
+    /// Address validation token to send to the client in a NEW_TOKEN frame.
+    new_token: Option<Vec<u8>>,
+
+    /// Latest address validation token received in a NEW_TOKEN frame.
+    peer_new_token: Option<Vec<u8>>,
+
     /// Error code and reason to be sent to the peer in a CONNECTION_CLOSE
     /// frame.
     local_error: Option<ConnectionError>,
@@ -1079,21 +2167,99 @@ pub struct Connection {
     /// Received path challenge.
     challenge: Option<[u8; 8]>,
 
+    /// Path challenges received from other addresses than the peer's, along
+    /// with the address to answer to.
+    path_responses: VecDeque<([u8; 8], SocketAddr)>,
+
+    /// Paths being validated with `probe_path()`.
+    path_probes: Vec<PathProbe>,
+
+    /// Outcomes of path validations not yet reported to the application.
+    path_events: VecDeque<PathEvent>,
+
+    /// Transport events not yet reported to the application, if enabled.
+    events: Option<VecDeque<TransportEvent>>,
+
+    /// Maximum number of transport events waiting to be polled.
+    max_events: usize,
+
+    /// Number of transport events dropped because the queue was full.
+    events_dropped: usize,
+
+    /// DSCP value outgoing packets should be marked with.
+    dscp: u8,
+
+    /// Ongoing validation of the client's new address.
+    migration: Option<PeerMigration>,
+
+    /// Connection IDs issued by both endpoints.
+    ids: cid::ConnectionIdentifiers,
+
     /// The connection-level limit at which send blocking occurred.
     blocked_limit: Option<u64>,
 
+    /// When the connection became blocked by the peer's connection-level flow
+    /// control limit, if it still is.
+    data_blocked_since: Option<time::Instant>,
+
+    /// Total number of times the connection and its streams became blocked by
+    /// the peer's flow control limits.
+    data_blocked_count: usize,
+    stream_data_blocked_count: usize,
+
+    /// Total time spent blocked by the peer's flow control limits, not
+    /// counting the ongoing periods.
+    data_blocked_time: time::Duration,
+    stream_data_blocked_time: time::Duration,
+
+    /// Total number of DATA_BLOCKED and STREAM_DATA_BLOCKED frames received.
+    peer_data_blocked_count: usize,
+    peer_stream_data_blocked_count: usize,
+
     /// Idle timeout expiration time.
     idle_timer: Option<time::Instant>,
 
+    /// Keep-alive interval, if keep-alive is enabled.
+    keep_alive: Option<time::Duration>,
+
+    /// Keep-alive timer.
+    keep_alive_timer: Option<time::Instant>,
+
+    /// Whether a keep-alive PING needs to be sent.
+    keep_alive_pending: bool,
+
+    /// Most recent statistics snapshots, oldest first.
+    stats_history: VecDeque<StatsSnapshot>,
+
+    /// Maximum number of statistics snapshots to keep.
+    stats_history_len: usize,
+
+    /// Maximum amount of data buffered by the connection, or 0 if unlimited.
+    max_buffered_bytes: usize,
+
+    /// Maximum number of undecryptable packets to buffer.
+    max_undecryptable_pkts: usize,
+
     /// Draining timeout expiration time.
     draining_timer: Option<time::Instant>,
 
-    /// List of raw packets that were received before they could be decrypted.
-    undecryptable_pkts: VecDeque<(Vec<u8>, RecvInfo)>,
+    /// Time at which the connection is closed with the given application
+    /// error code and reason, unless it was closed before.
+    close_deadline: Option<(time::Instant, u64, Vec<u8>)>,
+
+    /// List of raw packets that were received before they could be decrypted,
+    /// along with their type.
+    undecryptable_pkts: VecDeque<(Vec<u8>, RecvInfo, packet::Type)>,
+
+    /// Spare receive buffers handed over by the application.
+    recv_buf_pool: Vec<Vec<u8>>,
 
     /// The negotiated ALPN protocol.
     alpn: Vec<u8>,
 
+    /// The ALPN protocol list offered by the client, in wire format.
+    peer_application_protos: Vec<u8>,
+
     /// Whether this is a server-side connection.
     is_server: bool,
 
@@ -1144,18 +2310,49 @@ pub struct Connection {
     /// Whether to send GREASE.
     grease: bool,
 
+    /// The supported versions, in order of preference.
+    supported_versions: Vec<u32>,
+
+    /// Whether to send a reserved transport parameter.
+    grease_transport_params: bool,
+
+    /// Forced index used to derive GREASE codepoints, if any.
+    grease_index: Option<u64>,
+
     /// TLS keylog writer.
+    #[cfg(feature = "std")]
     keylog: Option<Box<dyn std::io::Write + Send + Sync>>,
 
+    /// Hook receiving every datagram sent and received.
+    packet_tap: Option<Box<dyn PacketTap>>,
+
     #[cfg(feature = "qlog")]
     qlog: QlogInfo,
 
+    /// Event callbacks registered through the C API.
+    #[cfg(feature = "ffi")]
+    ffi_callbacks: Option<ffi::Callbacks>,
+
     /// DATAGRAM queues.
     dgram_recv_queue: dgram::DatagramQueue,
     dgram_send_queue: dgram::DatagramQueue,
 
     /// Whether to emit DATAGRAM frames in the next packet.
     emit_dgram: bool,
+
+    /// Number of consecutive packets that carried DATAGRAM frames.
+    dgram_burst: usize,
+
+    /// Order and weight of DATAGRAM frames relative to stream data.
+    dgram_send_order: DgramSendOrder,
+    dgram_send_weight: usize,
+
+    /// How long received DATAGRAMs are kept, if limited.
+    dgram_recv_ttl: Option<time::Duration>,
+
+    /// Number of DATAGRAMs dropped because they expired.
+    dgram_sent_dropped_expired: usize,
+    dgram_recv_dropped_expired: usize,
 }
 
 /// Creates a new server-side connection.
@@ -1239,11 +2436,39 @@ pub fn connect(
 /// }
 /// # Ok::<(), quiche::Error>(())
 /// ```
+///
+/// The packet advertises all the supported versions, as well as a randomly
+/// generated reserved version (see [`negotiate_version_with_config()`] to
+/// change this).
+///
+/// [`negotiate_version_with_config()`]: fn.negotiate_version_with_config.html
 #[inline]
 pub fn negotiate_version(
     scid: &ConnectionId, dcid: &ConnectionId, out: &mut [u8],
 ) -> Result<usize> {
-    packet::negotiate_version(scid, dcid, out)
+    packet::negotiate_version(scid, dcid, &PROTOCOL_VERSIONS, true, out)
+}
+
+/// Writes a version negotiation packet based on the given configuration.
+///
+/// This is the same as [`negotiate_version()`], except that the packet
+/// advertises the versions configured with [`set_supported_versions()`], and
+/// a reserved version is only included if enabled with [`grease_versions()`].
+///
+/// [`negotiate_version()`]: fn.negotiate_version.html
+/// [`set_supported_versions()`]: struct.Config.html#method.set_supported_versions
+/// [`grease_versions()`]: struct.Config.html#method.grease_versions
+#[inline]
+pub fn negotiate_version_with_config(
+    scid: &ConnectionId, dcid: &ConnectionId, config: &Config, out: &mut [u8],
+) -> Result<usize> {
+    packet::negotiate_version(
+        scid,
+        dcid,
+        &config.supported_versions,
+        config.grease && config.grease_versions,
+        out,
+    )
 }
 
 /// Writes a stateless retry packet.
@@ -1312,22 +2537,61 @@ pub fn retry(
     packet::retry(scid, dcid, new_scid, token, version, out)
 }
 
-/// Returns true if the given protocol version is supported.
-#[inline]
-pub fn version_is_supported(version: u32) -> bool {
-    matches!(
-        version,
-        PROTOCOL_VERSION_V1 |
-            PROTOCOL_VERSION_DRAFT27 |
-            PROTOCOL_VERSION_DRAFT28 |
-            PROTOCOL_VERSION_DRAFT29
-    )
-}
-
-/// Pushes a frame to the output packet if there is enough space.
+/// Writes an Initial packet closing the connection the client tried to open.
 ///
-/// Returns `true` on success, `false` otherwise. In case of failure it means
-/// there is no room to add the frame in the packet. You may retry to add the
+/// This can be used by servers to refuse new connections, e.g. when they are
+/// overloaded, without creating a [`Connection`] and processing the client's
+/// handshake. The packet carries a `CONNECTION_CLOSE` frame with the given
+/// transport error code, typically `0x2` (`CONNECTION_REFUSED`).
+///
+/// The `scid` and `dcid` parameters are the source connection ID and the
+/// destination connection ID extracted from the received client's Initial
+/// packet, and `new_scid` is the server's source connection ID.
+///
+/// [`Connection`]: struct.Connection.html
+///
+/// ## Examples:
+///
+/// ```no_run
+/// # let mut buf = [0; 512];
+/// # let mut out = [0; 512];
+/// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+/// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+/// let (len, src) = socket.recv_from(&mut buf).unwrap();
+///
+/// let hdr =
+///     quiche::Header::from_slice(&mut buf[..len], quiche::MAX_CONN_ID_LEN)?;
+///
+/// let len =
+///     quiche::refuse(&hdr.scid, &hdr.dcid, &scid, 0x2, hdr.version, &mut out)?;
+///
+/// socket.send_to(&out[..len], &src).unwrap();
+/// # Ok::<(), quiche::Error>(())
+/// ```
+#[inline]
+pub fn refuse(
+    scid: &ConnectionId, dcid: &ConnectionId, new_scid: &ConnectionId,
+    error_code: u64, version: u32, out: &mut [u8],
+) -> Result<usize> {
+    packet::refuse(scid, dcid, new_scid, error_code, version, out)
+}
+
+/// Returns true if the given protocol version is supported.
+#[inline]
+pub fn version_is_supported(version: u32) -> bool {
+    matches!(
+        version,
+        PROTOCOL_VERSION_V1 |
+            PROTOCOL_VERSION_DRAFT27 |
+            PROTOCOL_VERSION_DRAFT28 |
+            PROTOCOL_VERSION_DRAFT29
+    )
+}
+
+/// Pushes a frame to the output packet if there is enough space.
+///
+/// Returns `true` on success, `false` otherwise. In case of failure it means
+/// there is no room to add the frame in the packet. You may retry to add the
 /// frame later.
 macro_rules! push_frame_to_pkt {
     ($out:expr, $frames:expr, $frame:expr, $left:expr) => {{
@@ -1367,7 +2631,7 @@ macro_rules! qlog_with_type {
     ($ty:expr, $qlog:expr, $qlog_streamer_ref:ident, $body:block) => {{
         #[cfg(feature = "qlog")]
         {
-            if EventImportance::from($ty).is_contained_in(&$qlog.level) {
+            if $qlog.is_enabled($ty) {
                 if let Some($qlog_streamer_ref) = &mut $qlog.streamer {
                     $body
                 }
@@ -1376,6 +2640,33 @@ macro_rules! qlog_with_type {
     }};
 }
 
+/// Enters a tracing span for the rest of the enclosing block, if the tracing
+/// feature is enabled.
+macro_rules! trace_span {
+    ($name:literal, $($field:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!($name, $($field)*).entered();
+    };
+}
+
+/// Records a transport event as a structured tracing event with the given
+/// fields if the tracing feature is enabled, or as a plain log record with
+/// the remaining arguments, if any, otherwise.
+macro_rules! trace_event {
+    ({ $($field:tt)* }) => {{
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($field)*);
+    }};
+
+    ({ $($field:tt)* }, $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($field)*);
+
+        #[cfg(not(feature = "tracing"))]
+        trace!($($arg)+);
+    }};
+}
+
 #[cfg(feature = "qlog")]
 const QLOG_PARAMS_SET: EventType =
     EventType::TransportEventType(TransportEventType::ParametersSet);
@@ -1396,11 +2687,53 @@ const QLOG_DATA_MV: EventType =
 const QLOG_METRICS: EventType =
     EventType::RecoveryEventType(RecoveryEventType::MetricsUpdated);
 
+#[cfg(feature = "qlog")]
+const QLOG_CC_STATE_UPDATED: EventType =
+    EventType::RecoveryEventType(RecoveryEventType::CongestionStateUpdated);
+
+#[cfg(feature = "qlog")]
+const QLOG_DATAGRAMS_RX: EventType =
+    EventType::TransportEventType(TransportEventType::DatagramsReceived);
+
+#[cfg(feature = "qlog")]
+const QLOG_DATAGRAMS_TX: EventType =
+    EventType::TransportEventType(TransportEventType::DatagramsSent);
+
+#[cfg(feature = "qlog")]
+const QLOG_CID_UPDATED: EventType =
+    EventType::ConnectivityEventType(ConnectivityEventType::ConnectionIdUpdated);
+
+#[cfg(feature = "qlog")]
+const QLOG_MIGRATION_STATE_UPDATED: EventType = EventType::ConnectivityEventType(
+    ConnectivityEventType::MigrationStateUpdated,
+);
+
+#[cfg(feature = "qlog")]
+const QLOG_KEY_UPDATED: EventType =
+    EventType::SecurityEventType(SecurityEventType::KeyUpdated);
+
 #[cfg(feature = "qlog")]
 struct QlogInfo {
     streamer: Option<qlog::streamer::QlogStreamer>,
     logged_peer_params: bool,
     level: EventImportance,
+
+    /// Bitmask of the enabled `QlogCategory` values.
+    categories: u32,
+
+    /// Only the events of one out of every `packet_sampling` packets are
+    /// logged.
+    packet_sampling: u64,
+
+    /// The number of packets to skip before the next one is logged.
+    packets_until_sample: u64,
+
+    /// Whether the events of the current packet are skipped.
+    skip_packet: bool,
+
+    /// Events triggered while the frames of a packet were being logged, to
+    /// be written once the packet event is complete.
+    deferred: Vec<(EventData, time::Instant)>,
 }
 
 #[cfg(feature = "qlog")]
@@ -1410,6 +2743,82 @@ impl Default for QlogInfo {
             streamer: None,
             logged_peer_params: false,
             level: EventImportance::Base,
+            categories: u32::MAX,
+            packet_sampling: 1,
+            packets_until_sample: 0,
+            skip_packet: false,
+            deferred: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "qlog")]
+impl QlogInfo {
+    /// Returns whether events of the given type should be logged.
+    fn is_enabled(&self, ty: EventType) -> bool {
+        if !EventImportance::from(ty).is_contained_in(&self.level) {
+            return false;
+        }
+
+        let category = match ty {
+            EventType::ConnectivityEventType(_) => QlogCategory::Connectivity,
+            EventType::SecurityEventType(_) => QlogCategory::Security,
+            EventType::TransportEventType(_) => QlogCategory::Transport,
+            EventType::RecoveryEventType(_) => QlogCategory::Recovery,
+            EventType::Http3EventType(_) => QlogCategory::Http,
+            EventType::QpackEventType(_) => QlogCategory::Qpack,
+
+            _ => return true,
+        };
+
+        if self.categories & (1 << category as u32) == 0 {
+            return false;
+        }
+
+        if self.skip_packet && (ty == QLOG_PACKET_RX || ty == QLOG_PACKET_TX) {
+            return false;
+        }
+
+        true
+    }
+
+    /// Decides whether the events of the next sent or received packet should
+    /// be logged, according to the sampling rate.
+    fn on_packet(&mut self) {
+        self.skip_packet = self.packets_until_sample > 0;
+
+        self.packets_until_sample = match self.packets_until_sample {
+            0 => self.packet_sampling - 1,
+
+            v => v - 1,
+        };
+    }
+
+    /// Logs an event, or defers it if the frames of a packet are currently
+    /// being logged, as the streamer would drop it otherwise.
+    fn add_event_data(
+        q: &mut qlog::streamer::QlogStreamer,
+        deferred: &mut Vec<(EventData, time::Instant)>, ev_data: EventData,
+        now: time::Instant,
+    ) {
+        if q.is_writing_frames() {
+            deferred.push((ev_data, now));
+            return;
+        }
+
+        q.add_event_data_with_instant(ev_data, now).ok();
+    }
+
+    /// Concludes the frames of a packet event and logs the events deferred
+    /// in the meantime.
+    fn finish_frames(
+        q: &mut qlog::streamer::QlogStreamer,
+        deferred: &mut Vec<(EventData, time::Instant)>,
+    ) {
+        q.finish_frames().ok();
+
+        for (ev_data, now) in deferred.drain(..) {
+            q.add_event_data_with_instant(ev_data, now).ok();
         }
     }
 }
@@ -1432,6 +2841,8 @@ impl Connection {
         let scid_as_hex: Vec<String> =
             scid.iter().map(|b| format!("{:02x}", b)).collect();
 
+        let now = config.clock.now();
+
         let mut conn = Box::pin(Connection {
             version: config.version,
 
@@ -1441,9 +2852,9 @@ impl Connection {
             trace_id: scid_as_hex.join(""),
 
             pkt_num_spaces: [
-                packet::PktNumSpace::new(),
-                packet::PktNumSpace::new(),
-                packet::PktNumSpace::new(),
+                packet::PktNumSpace::new(now),
+                packet::PktNumSpace::new(now),
+                packet::PktNumSpace::new(now),
             ],
 
             peer_transport_params: TransportParams::default(),
@@ -1460,10 +2871,24 @@ impl Connection {
 
             application_protos: config.application_protos.clone(),
 
+            #[cfg(not(feature = "rustls"))]
+            alpn_selector: config.alpn_selector.clone(),
+
+            cert_status_verifier: config.cert_status_verifier.clone(),
+
+            server_certs: config.server_certs.clone(),
+
+            crypto_provider: config.crypto_provider.clone(),
+
+            clock: config.clock.clone(),
+
             recv_count: 0,
             sent_count: 0,
             retrans_count: 0,
             sent_bytes: 0,
+
+            amplification_limited_count: 0,
+            amplification_limited: false,
             recv_bytes: 0,
 
             rx_data: 0,
@@ -1495,22 +2920,87 @@ impl Connection {
 
             token: None,
 
+            token_kind: None,
+
+            new_token: None,
+
+            peer_new_token: None,
+
             local_error: None,
 
             peer_error: None,
 
             challenge: None,
 
+            path_responses: VecDeque::new(),
+
+            path_probes: Vec::new(),
+
+            path_events: VecDeque::new(),
+
+            events: if config.events {
+                Some(VecDeque::new())
+            } else {
+                None
+            },
+
+            max_events: config.max_events,
+
+            events_dropped: 0,
+
+            dscp: config.dscp,
+
+            migration: None,
+
+            ids: cid::ConnectionIdentifiers::new(
+                scid,
+                config.local_transport_params.active_conn_id_limit,
+            ),
+
             blocked_limit: None,
 
+            data_blocked_since: None,
+
+            data_blocked_count: 0,
+
+            stream_data_blocked_count: 0,
+
+            data_blocked_time: time::Duration::ZERO,
+
+            stream_data_blocked_time: time::Duration::ZERO,
+
+            peer_data_blocked_count: 0,
+
+            peer_stream_data_blocked_count: 0,
+
             idle_timer: None,
 
+            keep_alive: None,
+
+            keep_alive_timer: None,
+
+            keep_alive_pending: false,
+
+            stats_history: VecDeque::with_capacity(config.stats_history_len),
+
+            stats_history_len: config.stats_history_len,
+
+            max_buffered_bytes: config.max_buffered_bytes,
+
+            max_undecryptable_pkts: config.max_undecryptable_pkts,
+
             draining_timer: None,
 
+            close_deadline: None,
+
             undecryptable_pkts: VecDeque::new(),
 
+            recv_buf_pool: Vec::new(),
+
             alpn: Vec::new(),
 
+            peer_application_protos: Vec::new(),
+
             is_server,
 
             derived_initial_secrets: false,
@@ -1544,11 +3034,23 @@ impl Connection {
 
             grease: config.grease,
 
+            supported_versions: config.supported_versions.clone(),
+
+            grease_transport_params: config.grease_transport_params,
+
+            grease_index: config.grease_index,
+
+            #[cfg(feature = "std")]
             keylog: None,
 
+            packet_tap: None,
+
             #[cfg(feature = "qlog")]
             qlog: Default::default(),
 
+            #[cfg(feature = "ffi")]
+            ffi_callbacks: None,
+
             dgram_recv_queue: dgram::DatagramQueue::new(
                 config.dgram_recv_max_queue_len,
             ),
@@ -1558,6 +3060,16 @@ impl Connection {
             ),
 
             emit_dgram: true,
+
+            dgram_burst: 0,
+
+            dgram_send_order: config.dgram_send_order,
+            dgram_send_weight: config.dgram_send_weight,
+
+            dgram_recv_ttl: config.dgram_recv_ttl,
+
+            dgram_sent_dropped_expired: 0,
+            dgram_recv_dropped_expired: 0,
         });
 
         if let Some(odcid) = odcid {
@@ -1579,6 +3091,21 @@ impl Connection {
         conn.handshake
             .use_legacy_codepoint(config.version != PROTOCOL_VERSION_V1);
 
+        #[cfg(not(feature = "rustls"))]
+        {
+            if !is_server {
+                if let Some(list) = &config.ech_config_list {
+                    conn.handshake.set_ech_config_list(list)?;
+                }
+            }
+
+            for (proto, settings) in &config.application_settings {
+                conn.handshake.add_application_settings(proto, settings)?;
+            }
+        }
+
+        conn.streams.set_credit_policy(config.stream_credit_policy);
+
         conn.encode_transport_params()?;
 
         // Derive initial secrets for the client. We can do this here because
@@ -1588,6 +3115,7 @@ impl Connection {
             rand::rand_bytes(&mut dcid[..]);
 
             let (aead_open, aead_seal) = crypto::derive_initial_key_material(
+                &*conn.crypto_provider,
                 &dcid,
                 conn.version,
                 conn.is_server,
@@ -1615,10 +3143,53 @@ impl Connection {
     ///
     /// [`Writer`]: https://doc.rust-lang.org/std/io/trait.Write.html
     #[inline]
+    #[cfg(feature = "std")]
     pub fn set_keylog(&mut self, writer: Box<dyn std::io::Write + Send + Sync>) {
         self.keylog = Some(writer);
     }
 
+    /// Sets the hook receiving every datagram sent and received by the
+    /// connection.
+    ///
+    /// This needs to be called as soon as the connection is created, to avoid
+    /// missing early datagrams. See [`PacketTap`].
+    ///
+    /// [`PacketTap`]: trait.PacketTap.html
+    #[inline]
+    pub fn set_packet_tap(&mut self, tap: Box<dyn PacketTap>) {
+        self.packet_tap = Some(tap);
+    }
+
+    /// Sets the scheduler deciding which stream's data is sent next.
+    ///
+    /// Streams that are already waiting to send data are moved to the new
+    /// scheduler. The default is [`PriorityScheduler`].
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let from = "127.0.0.1:1234".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// let scheduler = quiche::RoundRobinScheduler::default();
+    /// conn.set_stream_scheduler(Box::new(scheduler));
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    ///
+    /// [`PriorityScheduler`]: struct.PriorityScheduler.html
+    pub fn set_stream_scheduler(&mut self, scheduler: Box<dyn StreamScheduler>) {
+        self.streams.set_scheduler(scheduler);
+    }
+
+    /// Sets the DSCP value the connection's outgoing packets should be marked
+    /// with, overriding the one set with [`Config::set_dscp()`].
+    ///
+    /// [`Config::set_dscp()`]: struct.Config.html#method.set_dscp
+    pub fn set_dscp(&mut self, v: u8) {
+        self.dscp = cmp::min(v, MAX_DSCP);
+    }
+
     /// Sets qlog output to the designated [`Writer`].
     ///
     /// Only events included in `QlogLevel::Base` are written.
@@ -1647,6 +3218,33 @@ impl Connection {
     pub fn set_qlog_with_level(
         &mut self, writer: Box<dyn std::io::Write + Send + Sync>, title: String,
         description: String, qlog_level: QlogLevel,
+    ) {
+        self.set_qlog_with_format(
+            writer,
+            title,
+            description,
+            qlog_level,
+            QlogFormat::Json,
+        )
+    }
+
+    /// Sets qlog output to the designated [`Writer`], using the specified
+    /// `QlogFormat`.
+    ///
+    /// Only qlog events included in the specified `QlogLevel` are written.
+    ///
+    /// With `QlogFormat::JsonSeq` each event is flushed to the writer as soon
+    /// as it is logged, so the output remains usable even if the connection
+    /// never finishes, e.g. because the process crashed.
+    ///
+    /// This needs to be called as soon as the connection is created, to avoid
+    /// missing some early logs.
+    ///
+    /// [`Writer`]: https://doc.rust-lang.org/std/io/trait.Write.html
+    #[cfg(feature = "qlog")]
+    pub fn set_qlog_with_format(
+        &mut self, writer: Box<dyn std::io::Write + Send + Sync>, title: String,
+        description: String, qlog_level: QlogLevel, qlog_format: QlogFormat,
     ) {
         let vp = if self.is_server {
             qlog::VantagePointType::Server
@@ -1684,12 +3282,20 @@ impl Connection {
             Some(title),
             Some(description),
             None,
-            time::Instant::now(),
+            self.clock.now(),
             trace,
             self.qlog.level.clone(),
             writer,
         );
 
+        let format = match qlog_format {
+            QlogFormat::Json => qlog::streamer::QlogFormat::Json,
+
+            QlogFormat::JsonSeq => qlog::streamer::QlogFormat::JsonSeq,
+        };
+
+        streamer.set_format(format).ok();
+
         streamer.start_log().ok();
 
         let ev_data = self
@@ -1699,7 +3305,41 @@ impl Connection {
         // This event occurs very early, so just mark the relative time as 0.0.
         streamer.add_event(Event::with_time(0.0, ev_data)).ok();
 
+        let ev_data = self.recovery.parameters_qlog();
+        streamer.add_event(Event::with_time(0.0, ev_data)).ok();
+
         self.qlog.streamer = Some(streamer);
+
+        // Clients derive their Initial keys when the connection is created,
+        // before qlog can be enabled.
+        if self.derived_initial_secrets {
+            self.qlog_initial_keys_updated();
+        }
+    }
+
+    /// Sets the qlog event categories to log.
+    ///
+    /// Only the events of the given categories are written, in addition to
+    /// the `QlogLevel` filtering. By default events of all categories are
+    /// written.
+    #[cfg(feature = "qlog")]
+    pub fn set_qlog_categories(&mut self, categories: &[QlogCategory]) {
+        self.qlog.categories = categories
+            .iter()
+            .fold(0, |mask, &category| mask | (1 << category as u32));
+    }
+
+    /// Sets the qlog packet sampling rate.
+    ///
+    /// Only the events of one out of every `n` sent or received packets are
+    /// written, which reduces the cost of logging busy connections. Other
+    /// events are not affected.
+    ///
+    /// The default value is `1`, meaning that all packets are logged. A value
+    /// of `0` is treated as `1`.
+    #[cfg(feature = "qlog")]
+    pub fn set_qlog_packet_sampling(&mut self, n: u64) {
+        self.qlog.packet_sampling = cmp::max(n, 1);
     }
 
     /// Configures the given session for resumption.
@@ -1710,8 +3350,11 @@ impl Connection {
     /// This must only be called immediately after creating a connection, that
     /// is, before any packet is sent or received.
     ///
+    /// This is not available with the rustls backend.
+    ///
     /// [`session()`]: struct.Connection.html#method.session
     #[inline]
+    #[cfg(not(feature = "rustls"))]
     pub fn set_session(&mut self, session: &[u8]) -> Result<()> {
         let mut b = octets::Octets::with_slice(session);
 
@@ -1731,49 +3374,239 @@ impl Connection {
         Ok(())
     }
 
-    /// Processes QUIC packets received from the peer.
+    /// Sets the address validation token sent in the client's Initial packets.
     ///
-    /// On success the number of bytes processed from the input buffer is
-    /// returned. On error the connection will be closed by calling [`close()`]
-    /// with the appropriate error code.
+    /// This is a token received from the server in a NEW_TOKEN frame during a
+    /// previous connection, as returned by [`new_token()`], which allows the
+    /// server to validate the client's address without a stateless retry.
     ///
-    /// Coalesced packets will be processed as necessary.
+    /// This must only be called on clients, before any packet is sent,
+    /// otherwise [`InvalidState`] is returned.
     ///
-    /// Note that the contents of the input buffer `buf` might be modified by
-    /// this function due to, for example, in-place decryption.
+    /// [`new_token()`]: struct.Connection.html#method.new_token
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn set_token(&mut self, token: &[u8]) -> Result<()> {
+        if self.is_server || self.sent_count > 0 {
+            return Err(Error::InvalidState);
+        }
+
+        self.token = Some(token.to_vec());
+        self.token_kind = Some(TokenKind::NewToken);
+
+        Ok(())
+    }
+
+    /// Schedules a NEW_TOKEN frame carrying the given address validation
+    /// token to be sent to the client.
     ///
-    /// [`close()`]: struct.Connection.html#method.close
+    /// The client can use the token in a future connection to prove that it
+    /// owns its address, so it doesn't need to go through a stateless retry.
+    /// The frame is sent once the handshake is complete, and retransmitted if
+    /// lost. A token that wasn't sent yet is replaced.
     ///
-    /// ## Examples:
+    /// This must only be called on servers, with a non-empty token, otherwise
+    /// [`InvalidState`] is returned.
     ///
-    /// ```no_run
-    /// # let mut buf = [0; 512];
-    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
-    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
-    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-    /// # let from = "127.0.0.1:1234".parse().unwrap();
-    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
-    /// loop {
-    ///     let (read, from) = socket.recv_from(&mut buf).unwrap();
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn send_new_token(&mut self, token: &[u8]) -> Result<()> {
+        if !self.is_server || token.is_empty() {
+            return Err(Error::InvalidState);
+        }
+
+        self.new_token = Some(token.to_vec());
+
+        Ok(())
+    }
+
+    /// Configures the connection to send keep-alive packets.
     ///
-    ///     let recv_info = quiche::RecvInfo { from };
+    /// When enabled, an ack-eliciting PING frame is sent whenever no
+    /// ack-eliciting packet was sent or received for the duration of
+    /// `interval`, which prevents the idle timeout from expiring while the
+    /// connection is otherwise idle. The interval is capped to half of the
+    /// connection's idle timeout, so that a lost keep-alive can be
+    /// retransmitted before the connection times out.
     ///
-    ///     let read = match conn.recv(&mut buf[..read], recv_info) {
-    ///         Ok(v) => v,
+    /// Keep-alive packets are only sent once the handshake is complete. The
+    /// [`timeout()`] method takes the keep-alive timer into account, so no
+    /// additional timers are required in the application.
     ///
-    ///         Err(e) => {
-    ///             // An error occurred, handle it.
-    ///             break;
-    ///         },
-    ///     };
-    /// }
-    /// # Ok::<(), quiche::Error>(())
-    /// ```
-    pub fn recv(&mut self, buf: &mut [u8], info: RecvInfo) -> Result<usize> {
-        let len = buf.len();
+    /// A zero `interval` disables keep-alive, which is the default.
+    ///
+    /// [`timeout()`]: struct.Connection.html#method.timeout
+    pub fn set_keep_alive(&mut self, interval: time::Duration) {
+        if interval == time::Duration::ZERO {
+            self.keep_alive = None;
+            self.keep_alive_timer = None;
+            self.keep_alive_pending = false;
 
-        if len == 0 {
-            return Err(Error::BufferTooShort);
+            return;
+        }
+
+        self.keep_alive = Some(interval);
+
+        self.update_keep_alive_timer(self.clock.now());
+    }
+
+    /// Changes the local `max_idle_timeout` of a live connection, in
+    /// milliseconds.
+    ///
+    /// The timeout advertised to the peer in the transport parameters can't
+    /// be changed, so the new value only affects how long the local endpoint
+    /// waits before closing an idle connection. As with the transport
+    /// parameter, the effective timeout is the minimum of the local and
+    /// peer's values, and 0 disables the local timeout.
+    pub fn set_max_idle_timeout(&mut self, v: u64) {
+        let now = self.clock.now();
+
+        self.local_transport_params.max_idle_timeout = v;
+
+        if self.idle_timer.is_some() {
+            self.idle_timer = self.idle_timeout().map(|t| now + t);
+        }
+
+        self.update_keep_alive_timer(now);
+    }
+
+    /// Changes the connection-level receive window, in bytes.
+    ///
+    /// If the new window allows the peer to send more data than the current
+    /// flow control limit, a MAX_DATA frame is sent as soon as possible.
+    /// Credit that was already granted to the peer can't be taken back, so
+    /// lowering the window only takes effect for future updates.
+    ///
+    /// Window autotuning won't grow the window beyond this value.
+    pub fn set_max_data(&mut self, v: u64) {
+        self.flow_control.set_window(v);
+
+        if self.max_rx_data_next() > self.max_rx_data() {
+            self.almost_full = true;
+        }
+    }
+
+    /// Changes the receive window of an existing stream, in bytes.
+    ///
+    /// If the new window allows the peer to send more data on the stream
+    /// than the current flow control limit, a MAX_STREAM_DATA frame is sent
+    /// as soon as possible. As with [`set_max_data()`], lowering the window
+    /// only takes effect for future updates.
+    ///
+    /// The [`InvalidStreamState`] error is returned if the stream doesn't
+    /// exist or can't receive data (i.e. it's a local unidirectional
+    /// stream).
+    ///
+    /// [`set_max_data()`]: struct.Connection.html#method.set_max_data
+    /// [`InvalidStreamState`]: enum.Error.html#variant.InvalidStreamState
+    pub fn set_stream_max_data(&mut self, stream_id: u64, v: u64) -> Result<()> {
+        if stream::is_local(stream_id, self.is_server) &&
+            !stream::is_bidi(stream_id)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        let stream = self
+            .streams
+            .get_mut(stream_id)
+            .ok_or(Error::InvalidStreamState(stream_id))?;
+
+        if stream.recv.set_window(v) {
+            self.streams.mark_almost_full(stream_id, true);
+        }
+
+        Ok(())
+    }
+
+    /// Changes the receive window of locally-initiated bidirectional streams
+    /// created from now on, in bytes.
+    ///
+    /// This overrides the value configured with
+    /// [`set_initial_max_stream_data_bidi_local()`] for new streams, as if
+    /// [`set_stream_max_data()`] was called on them once created. Existing
+    /// streams are not affected.
+    ///
+    /// [`set_initial_max_stream_data_bidi_local()`]: struct.Config.html#method.set_initial_max_stream_data_bidi_local
+    /// [`set_stream_max_data()`]: struct.Connection.html#method.set_stream_max_data
+    pub fn set_max_stream_data_bidi_local(&mut self, v: u64) {
+        self.streams.set_window_bidi_local(v);
+    }
+
+    /// Changes the receive window of remotely-initiated bidirectional
+    /// streams created from now on, in bytes.
+    ///
+    /// This overrides the value configured with
+    /// [`set_initial_max_stream_data_bidi_remote()`] for new streams, as if
+    /// [`set_stream_max_data()`] was called on them once created. Existing
+    /// streams are not affected.
+    ///
+    /// [`set_initial_max_stream_data_bidi_remote()`]: struct.Config.html#method.set_initial_max_stream_data_bidi_remote
+    /// [`set_stream_max_data()`]: struct.Connection.html#method.set_stream_max_data
+    pub fn set_max_stream_data_bidi_remote(&mut self, v: u64) {
+        self.streams.set_window_bidi_remote(v);
+    }
+
+    /// Changes the receive window of remotely-initiated unidirectional
+    /// streams created from now on, in bytes.
+    ///
+    /// This overrides the value configured with
+    /// [`set_initial_max_stream_data_uni()`] for new streams, as if
+    /// [`set_stream_max_data()`] was called on them once created. Existing
+    /// streams are not affected.
+    ///
+    /// [`set_initial_max_stream_data_uni()`]: struct.Config.html#method.set_initial_max_stream_data_uni
+    /// [`set_stream_max_data()`]: struct.Connection.html#method.set_stream_max_data
+    pub fn set_max_stream_data_uni(&mut self, v: u64) {
+        self.streams.set_window_uni(v);
+    }
+
+    /// Processes QUIC packets received from the peer.
+    ///
+    /// On success the number of bytes processed from the input buffer is
+    /// returned. On error the connection will be closed by calling [`close()`]
+    /// with the appropriate error code.
+    ///
+    /// Coalesced packets will be processed as necessary.
+    ///
+    /// Note that the contents of the input buffer `buf` might be modified by
+    /// this function due to, for example, in-place decryption.
+    ///
+    /// [`close()`]: struct.Connection.html#method.close
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut buf = [0; 512];
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let from = "127.0.0.1:1234".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// loop {
+    ///     let (read, from) = socket.recv_from(&mut buf).unwrap();
+    ///
+    ///     let recv_info = quiche::RecvInfo { from };
+    ///
+    ///     let read = match conn.recv(&mut buf[..read], recv_info) {
+    ///         Ok(v) => v,
+    ///
+    ///         Err(e) => {
+    ///             // An error occurred, handle it.
+    ///             break;
+    ///         },
+    ///     };
+    /// }
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn recv(&mut self, buf: &mut [u8], info: RecvInfo) -> Result<usize> {
+        self.recv_dgram(&mut Datagram::Borrowed(buf), info)
+    }
+
+    fn recv_dgram(
+        &mut self, dgram: &mut Datagram, info: RecvInfo,
+    ) -> Result<usize> {
+        let len = dgram.as_ref().len();
+
+        if len == 0 {
+            return Err(Error::BufferTooShort);
         }
 
         // Keep track of how many bytes we received from the client, so we
@@ -1787,12 +3620,45 @@ impl Connection {
             self.max_send_bytes += len * MAX_AMPLIFICATION_FACTOR;
         }
 
+        qlog_with_type!(QLOG_DATAGRAMS_RX, self.qlog, q, {
+            let ev_data = EventData::DatagramsReceived(
+                qlog::events::quic::DatagramsReceived {
+                    count: Some(1),
+
+                    raw: Some(vec![RawInfo {
+                        length: Some(len as u64),
+                        payload_length: None,
+                        data: None,
+                    }]),
+
+                    datagram_ids: None,
+                },
+            );
+
+            q.add_event_data_with_instant(ev_data, self.clock.now())
+                .ok();
+        });
+
+        if let Some(tap) = self.packet_tap.as_mut() {
+            let info = TapInfo {
+                direction: PacketDirection::Received,
+
+                at: self.clock.now(),
+
+                local: None,
+
+                peer: info.from,
+            };
+
+            tap.on_datagram(&info, dgram.as_ref());
+        }
+
         let mut done = 0;
         let mut left = len;
 
         // Process coalesced packets.
         while left > 0 {
-            let read = match self.recv_single(&mut buf[len - left..len], &info) {
+            let read = match self.recv_single(dgram, len - left, &info) {
                 Ok(v) => v,
 
                 Err(Error::Done) => left,
@@ -1809,26 +3675,160 @@ impl Connection {
             left -= read;
         }
 
-        // Process previously undecryptable 0-RTT packets if the decryption key
-        // is now available.
-        if self.pkt_num_spaces[packet::EPOCH_APPLICATION]
-            .crypto_0rtt_open
-            .is_some()
+        // Process previously undecryptable packets if their decryption keys
+        // are now available.
+        //
+        // Even though the packets were previously "accepted", it should be
+        // safe to forward the error, as it also comes from the `recv()`
+        // method.
+        self.process_undecryptable()?;
+
+        Ok(done)
+    }
+
+    /// Processes QUIC packets received from the peer, taking ownership of the
+    /// datagram buffer.
+    ///
+    /// This behaves like [`recv()`], except that once the datagram has been
+    /// processed its buffer is retained by the connection instead of being
+    /// dropped. Retained buffers are used to hold packets that need to be
+    /// buffered internally (e.g. 0-RTT packets received before the keys to
+    /// decrypt them are available) without allocating, and can be retrieved
+    /// again by the application with [`take_recv_buf()`] to receive the next
+    /// datagram into.
+    ///
+    /// Stream data that can be read right away is not copied out of the
+    /// datagram, which is instead kept until all of that data is read by the
+    /// application, and then freed rather than retained.
+    ///
+    /// [`recv()`]: struct.Connection.html#method.recv
+    /// [`take_recv_buf()`]: struct.Connection.html#method.take_recv_buf
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let from = "127.0.0.1:1234".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// loop {
+    ///     let mut buf = conn.take_recv_buf();
+    ///     buf.resize(65535, 0);
+    ///
+    ///     let (read, from) = socket.recv_from(&mut buf).unwrap();
+    ///     buf.truncate(read);
+    ///
+    ///     let recv_info = quiche::RecvInfo { from };
+    ///
+    ///     let read = match conn.recv_owned(buf, recv_info) {
+    ///         Ok(v) => v,
+    ///
+    ///         Err(e) => {
+    ///             // An error occurred, handle it.
+    ///             break;
+    ///         },
+    ///     };
+    /// }
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn recv_owned(&mut self, buf: Vec<u8>, info: RecvInfo) -> Result<usize> {
+        let mut dgram = Datagram::Owned(Arc::new(buf));
+
+        let r = self.recv_dgram(&mut dgram, info);
+
+        // The buffer can only be reused if no received data references it.
+        if let Datagram::Owned(buf) = dgram {
+            if let Ok(buf) = Arc::try_unwrap(buf) {
+                self.recycle_recv_buf(buf);
+            }
+        }
+
+        r
+    }
+
+    /// Returns a spare receive buffer previously handed over to the
+    /// connection via [`recv_owned()`].
+    ///
+    /// The returned buffer is empty, but may have spare capacity. If no
+    /// buffer is available a new one is allocated.
+    ///
+    /// [`recv_owned()`]: struct.Connection.html#method.recv_owned
+    pub fn take_recv_buf(&mut self) -> Vec<u8> {
+        self.recv_buf_pool.pop().unwrap_or_default()
+    }
+
+    /// Returns the given buffer to the receive buffer pool.
+    fn recycle_recv_buf(&mut self, mut buf: Vec<u8>) {
+        if self.recv_buf_pool.len() < MAX_RECV_BUF_POOL_SIZE {
+            buf.clear();
+            self.recv_buf_pool.push(buf);
+        }
+    }
+
+    /// Processes a previously buffered packet.
+    ///
+    /// The packet is not passed to the packet tap again, as it already was
+    /// when it was first received.
+    fn recv_buffered(&mut self, buf: &mut [u8], info: RecvInfo) -> Result<usize> {
+        let tap = self.packet_tap.take();
+
+        let r = self.recv(buf, info);
+
+        self.packet_tap = tap;
+
+        r
+    }
+
+    /// Processes the buffered undecryptable packets whose decryption keys are
+    /// now available.
+    ///
+    /// The other packets stay buffered. On error all the packets are dropped.
+    fn process_undecryptable(&mut self) -> Result<()> {
+        if !self
+            .undecryptable_pkts
+            .iter()
+            .any(|(_, _, ty)| self.has_read_key(*ty))
         {
-            while let Some((mut pkt, info)) = self.undecryptable_pkts.pop_front()
-            {
-                if let Err(e) = self.recv(&mut pkt, info) {
-                    self.undecryptable_pkts.clear();
+            return Ok(());
+        }
 
-                    // Even though the packet was previously "accepted", it
-                    // should be safe to forward the error, as it also comes
-                    // from the `recv()` method.
-                    return Err(e);
-                }
+        // Processing a packet might install new keys, or buffer it again, so
+        // go through a snapshot of the buffer rather than the buffer itself.
+        let pkts = core::mem::take(&mut self.undecryptable_pkts);
+
+        for (mut pkt, info, ty) in pkts {
+            if !self.has_read_key(ty) {
+                self.undecryptable_pkts.push_back((pkt, info, ty));
+                continue;
+            }
+
+            if let Err(e) = self.recv_buffered(&mut pkt, info) {
+                self.undecryptable_pkts.clear();
+
+                return Err(e);
             }
+
+            self.recycle_recv_buf(pkt);
         }
 
-        Ok(done)
+        Ok(())
+    }
+
+    /// Returns true if the key needed to decrypt packets of the given type is
+    /// available.
+    fn has_read_key(&self, ty: packet::Type) -> bool {
+        if ty == packet::Type::ZeroRTT {
+            return self.pkt_num_spaces[packet::EPOCH_APPLICATION]
+                .crypto_0rtt_open
+                .is_some();
+        }
+
+        match ty.to_epoch() {
+            Ok(epoch) => self.pkt_num_spaces[epoch].crypto_open.is_some(),
+
+            Err(_) => false,
+        }
     }
 
     /// Processes a single QUIC packet received from the peer.
@@ -1840,8 +3840,14 @@ impl Connection {
     /// On error, an error other than [`Done`] is returned.
     ///
     /// [`Done`]: enum.Error.html#variant.Done
-    fn recv_single(&mut self, buf: &mut [u8], info: &RecvInfo) -> Result<usize> {
-        let now = time::Instant::now();
+    fn recv_single(
+        &mut self, dgram: &mut Datagram, start: usize, info: &RecvInfo,
+    ) -> Result<usize> {
+        trace_span!("recv_packet", conn = %self.trace_id, from = %info.from);
+
+        let buf = &mut dgram.as_mut()[start..];
+
+        let now = self.clock.now();
 
         if buf.is_empty() {
             return Err(Error::Done);
@@ -1894,7 +3900,12 @@ impl Connection {
                 return Err(Error::Done);
             }
 
-            trace!("{} rx pkt {:?}", self.trace_id, hdr);
+            trace_event!(
+                { ty = ?hdr.ty, "rx pkt" },
+                "{} rx pkt {:?}",
+                self.trace_id,
+                hdr
+            );
 
             let versions = hdr.versions.ok_or(Error::Done)?;
 
@@ -1904,24 +3915,16 @@ impl Connection {
                 return Err(Error::Done);
             }
 
-            let supported_versions =
-                versions.iter().filter(|&&v| version_is_supported(v));
-
-            let mut found_version = false;
-
-            for &v in supported_versions {
-                found_version = true;
-
-                // The final version takes precedence over draft ones.
-                if v == PROTOCOL_VERSION_V1 {
-                    self.version = v;
-                    break;
-                }
-
-                self.version = cmp::max(self.version, v);
-            }
+            // Pick the most preferred version that is also supported by the
+            // server.
+            let version = self
+                .supported_versions
+                .iter()
+                .find(|v| versions.contains(v));
 
-            if !found_version {
+            if let Some(&v) = version {
+                self.version = v;
+            } else {
                 // We don't support any of the versions offered.
                 //
                 // While a man-in-the-middle attacker might be able to
@@ -1936,6 +3939,7 @@ impl Connection {
 
             // Derive Initial secrets based on the new version.
             let (aead_open, aead_seal) = crypto::derive_initial_key_material(
+                &*self.crypto_provider,
                 &self.dcid,
                 self.version,
                 self.is_server,
@@ -1951,6 +3955,9 @@ impl Connection {
             self.pkt_num_spaces[packet::EPOCH_INITIAL].crypto_seal =
                 Some(aead_seal);
 
+            #[cfg(feature = "qlog")]
+            self.qlog_initial_keys_updated();
+
             self.handshake
                 .use_legacy_codepoint(self.version != PROTOCOL_VERSION_V1);
 
@@ -1958,6 +3965,10 @@ impl Connection {
             // using a different format.
             self.encode_transport_params()?;
 
+            self.push_event(TransportEvent::VersionNegotiated {
+                version: self.version,
+            });
+
             return Err(Error::Done);
         }
 
@@ -1979,20 +3990,27 @@ impl Connection {
                 return Err(Error::Done);
             }
 
-            trace!("{} rx pkt {:?}", self.trace_id, hdr);
+            trace_event!(
+                { ty = ?hdr.ty, "rx pkt" },
+                "{} rx pkt {:?}",
+                self.trace_id,
+                hdr
+            );
 
             self.token = hdr.token;
+            self.token_kind = Some(TokenKind::Retry);
             self.did_retry = true;
 
             // Remember peer's new connection ID.
             self.odcid = Some(self.dcid.clone());
 
-            self.dcid = hdr.scid.clone();
+            self.set_dcid(hdr.scid.clone());
 
             self.rscid = Some(self.dcid.clone());
 
             // Derive Initial secrets using the new connection ID.
             let (aead_open, aead_seal) = crypto::derive_initial_key_material(
+                &*self.crypto_provider,
                 &hdr.scid,
                 self.version,
                 self.is_server,
@@ -2008,11 +4026,14 @@ impl Connection {
             self.pkt_num_spaces[packet::EPOCH_INITIAL].crypto_seal =
                 Some(aead_seal);
 
+            #[cfg(feature = "qlog")]
+            self.qlog_initial_keys_updated();
+
             return Err(Error::Done);
         }
 
         if self.is_server && !self.did_version_negotiation {
-            if !version_is_supported(hdr.version) {
+            if !self.supported_versions.contains(&hdr.version) {
                 return Err(Error::UnknownVersion);
             }
 
@@ -2062,6 +4083,7 @@ impl Connection {
         // Derive initial secrets on the server.
         if !self.derived_initial_secrets {
             let (aead_open, aead_seal) = crypto::derive_initial_key_material(
+                &*self.crypto_provider,
                 &hdr.dcid,
                 self.version,
                 self.is_server,
@@ -2073,6 +4095,9 @@ impl Connection {
                 Some(aead_seal);
 
             self.derived_initial_secrets = true;
+
+            #[cfg(feature = "qlog")]
+            self.qlog_initial_keys_updated();
         }
 
         // Select packet number space epoch based on the received packet's type.
@@ -2092,19 +4117,29 @@ impl Connection {
             Some(v) => v,
 
             None => {
-                if hdr.ty == packet::Type::ZeroRTT &&
-                    self.undecryptable_pkts.len() < MAX_UNDECRYPTABLE_PACKETS &&
-                    !self.is_established()
+                // Packets can arrive before the read key they need is
+                // available, e.g. 0-RTT packets before the client's Initial,
+                // or Handshake and 1-RTT packets before the server's Initial,
+                // so buffer them and process them later. Packets whose key was
+                // already discarded can never be decrypted though.
+                let key_pending = match hdr.ty {
+                    packet::Type::ZeroRTT => !self.is_established(),
+
+                    packet::Type::Handshake => !self.handshake_confirmed,
+
+                    packet::Type::Short => true,
+
+                    _ => false,
+                };
+
+                if key_pending &&
+                    self.undecryptable_pkts.len() < self.max_undecryptable_pkts
                 {
-                    // Buffer 0-RTT packets when the required read key is not
-                    // available yet, and process them later.
-                    //
-                    // TODO: in the future we might want to buffer other types
-                    // of undecryptable packets as well.
                     let pkt_len = b.off() + payload_len;
-                    let pkt = (b.buf()[..pkt_len]).to_vec();
+                    let mut pkt = self.take_recv_buf();
+                    pkt.extend_from_slice(&b.buf()[..pkt_len]);
 
-                    self.undecryptable_pkts.push_back((pkt, *info));
+                    self.undecryptable_pkts.push_back((pkt, *info, hdr.ty));
                     return Ok(pkt_len);
                 }
 
@@ -2133,7 +4168,8 @@ impl Connection {
 
         let pn_len = hdr.pkt_num_len;
 
-        trace!(
+        trace_event!(
+            { ty = ?hdr.ty, len = payload_len, pn, "rx pkt" },
             "{} rx pkt {:?} len={} pn={}",
             self.trace_id,
             hdr,
@@ -2141,6 +4177,9 @@ impl Connection {
             pn
         );
 
+        #[cfg(feature = "qlog")]
+        self.qlog.on_packet();
+
         qlog_with_type!(QLOG_PACKET_RX, self.qlog, q, {
             let packet_size = b.len();
 
@@ -2173,19 +4212,34 @@ impl Connection {
             q.add_event_data_with_instant(ev_data, now).ok();
         });
 
-        let mut payload = packet::decrypt_pkt(
-            &mut b,
-            pn,
-            pn_len,
-            payload_len,
-            aead,
-        )
-        .map_err(|e| {
-            drop_pkt_on_err(e, self.recv_count, self.is_server, &self.trace_id)
-        })?;
+        let payload_off = start + b.off();
+
+        let payload = packet::decrypt_pkt(&mut b, pn, pn_len, payload_len, aead)
+            .map_err(|e| {
+                drop_pkt_on_err(
+                    e,
+                    self.recv_count,
+                    self.is_server,
+                    &self.trace_id,
+                )
+            })?;
+
+        let payload_end = payload_off + payload.cap();
+
+        // Parse the decrypted payload from the datagram, so that the frames
+        // can reference it if the connection owns it.
+        let mut payload =
+            octets::Octets::with_slice(&dgram.as_ref()[payload_off..payload_end]);
+
+        let shared = dgram.shared().map(|v| (v, payload_off));
 
         if self.pkt_num_spaces[epoch].recv_pkt_num.contains(pn) {
-            trace!("{} ignored duplicate packet {}", self.trace_id, pn);
+            trace_event!(
+                { pn, "ignored duplicate packet" },
+                "{} ignored duplicate packet {}",
+                self.trace_id,
+                pn
+            );
             return Err(Error::Done);
         }
 
@@ -2201,13 +4255,13 @@ impl Connection {
 
             // Replace the randomly generated destination connection ID with
             // the one supplied by the server.
-            self.dcid = hdr.scid.clone();
+            self.set_dcid(hdr.scid.clone());
 
             self.got_peer_conn_id = true;
         }
 
         if self.is_server && !self.got_peer_conn_id {
-            self.dcid = hdr.scid.clone();
+            self.set_dcid(hdr.scid.clone());
 
             if !self.did_retry &&
                 (self.version >= PROTOCOL_VERSION_DRAFT28 ||
@@ -2228,9 +4282,36 @@ impl Connection {
         // ACK and PADDING.
         let mut ack_elicited = false;
 
+        // Whether the packet only contains probing frames, which don't cause
+        // the connection to migrate to the address it was received from.
+        let mut probing = true;
+
         // Process packet payload.
         while payload.cap() > 0 {
-            let frame = frame::Frame::from_bytes(&mut payload, hdr.ty)?;
+            // Keep track of the frame's type, so it can be reported to the peer
+            // if the frame turns out to be invalid.
+            let frame_type = payload.peek_bytes(payload.cap())?.get_varint()?;
+
+            let frame = match frame::Frame::from_bytes_shared(
+                &mut payload,
+                hdr.ty,
+                shared,
+            ) {
+                Ok(v) => v,
+
+                // A truncated frame is an encoding error as well.
+                Err(Error::InvalidFrame(_)) | Err(Error::BufferTooShort) => {
+                    let e = Error::InvalidFrame(frame_type);
+
+                    self.close_on_frame_error(e, frame_type);
+                    return Err(e);
+                },
+
+                Err(e) => {
+                    self.close_on_frame_error(e, frame_type);
+                    return Err(e);
+                },
+            };
 
             qlog_with_type!(QLOG_PACKET_RX, self.qlog, q, {
                 q.add_frame(frame.to_qlog(), false).ok();
@@ -2240,19 +4321,32 @@ impl Connection {
                 ack_elicited = true;
             }
 
-            if let Err(e) = self.process_frame(frame, epoch, now) {
+            if !frame.probing() {
+                probing = false;
+            }
+
+            if let Err(e) = self.process_frame(frame, info.from, epoch, now) {
                 qlog_with_type!(QLOG_PACKET_RX, self.qlog, q, {
                     // Always conclude frame writing on error.
-                    q.finish_frames().ok();
+                    QlogInfo::finish_frames(q, &mut self.qlog.deferred);
                 });
 
-                return Err(e);
-            }
+                // Report the exact type of the frame, e.g. for STREAM frames
+                // with different flags.
+                let e = match e {
+                    Error::InvalidFrame(_) => Error::InvalidFrame(frame_type),
+
+                    e => e,
+                };
+
+                self.close_on_frame_error(e, frame_type);
+                return Err(e);
+            }
         }
 
         qlog_with_type!(QLOG_PACKET_RX, self.qlog, q, {
             // Always conclude frame writing.
-            q.finish_frames().ok();
+            QlogInfo::finish_frames(q, &mut self.qlog.deferred);
         });
 
         qlog_with_type!(QLOG_PACKET_RX, self.qlog, q, {
@@ -2261,6 +4355,12 @@ impl Connection {
             }
         });
 
+        qlog_with_type!(QLOG_CC_STATE_UPDATED, self.qlog, q, {
+            if let Some(ev_data) = self.recovery.hystart_qlog() {
+                q.add_event_data_with_instant(ev_data, now).ok();
+            }
+        });
+
         // Only log the remote transport parameters once the connection is
         // established (i.e. after frames have been fully parsed) and only
         // once per connection.
@@ -2281,15 +4381,13 @@ impl Connection {
         // Process acked frames.
         for acked in self.recovery.acked[epoch].drain(..) {
             match acked {
-                frame::Frame::ACK { ranges, .. } => {
+                frame::Frame::ACKHeader { largest_acked, .. } => {
                     // Stop acknowledging packets less than or equal to the
                     // largest acknowledged in the sent ACK frame that, in
                     // turn, got acked.
-                    if let Some(largest_acked) = ranges.last() {
-                        self.pkt_num_spaces[epoch]
-                            .recv_pkt_need_ack
-                            .remove_until(largest_acked);
-                    }
+                    self.pkt_num_spaces[epoch]
+                        .recv_pkt_need_ack
+                        .remove_until(largest_acked);
                 },
 
                 frame::Frame::CryptoHeader { offset, length } => {
@@ -2363,6 +4461,16 @@ impl Connection {
         self.pkt_num_spaces[epoch].ack_elicited =
             cmp::max(self.pkt_num_spaces[epoch].ack_elicited, ack_elicited);
 
+        // Only the packet with the largest packet number so far can move the
+        // connection, so that reordered packets don't make it flip back. Note
+        // that duplicate packets were already discarded.
+        let migrated = self.is_server &&
+            self.is_established() &&
+            epoch == packet::EPOCH_APPLICATION &&
+            !probing &&
+            pn >= self.pkt_num_spaces[epoch].largest_rx_pkt_num &&
+            info.from != self.peer_addr;
+
         self.pkt_num_spaces[epoch].largest_rx_pkt_num =
             cmp::max(self.pkt_num_spaces[epoch].largest_rx_pkt_num, pn);
 
@@ -2370,15 +4478,23 @@ impl Connection {
             self.idle_timer = Some(now + idle_timeout);
         }
 
+        self.update_keep_alive_timer(now);
+
         // Update send capacity.
         self.update_tx_cap();
 
         self.recv_count += 1;
 
-        let read = b.off() + aead_tag_len;
+        let read = payload_end - start + aead_tag_len;
 
         self.recv_bytes += read as u64;
 
+        if migrated {
+            self.on_peer_migrated(info.from, read);
+        }
+
+        self.update_stats_history(now);
+
         // An Handshake packet has been received from the client and has been
         // successfully processed, so we can drop the initial state and consider
         // the client's address to be verified.
@@ -2462,27 +4578,15 @@ impl Connection {
             self.do_handshake()?;
         }
 
-        // Process previously undecryptable 0-RTT packets if the decryption key
-        // is now available.
-        if self.pkt_num_spaces[packet::EPOCH_APPLICATION]
-            .crypto_0rtt_open
-            .is_some()
-        {
-            while let Some((mut pkt, info)) = self.undecryptable_pkts.pop_front()
-            {
-                if self.recv(&mut pkt, info).is_err() {
-                    self.undecryptable_pkts.clear();
-
-                    // Forwarding the error value here could confuse
-                    // applications, as they may not expect getting a `recv()`
-                    // error when calling `send()`.
-                    //
-                    // We simply fall-through to sending packets, which should
-                    // take care of terminating the connection as needed.
-                    break;
-                }
-            }
-        }
+        // Process previously undecryptable packets if their decryption keys
+        // are now available.
+        //
+        // Forwarding the error value here could confuse applications, as they
+        // may not expect getting a `recv()` error when calling `send()`.
+        //
+        // We simply fall-through to sending packets, which should take care
+        // of terminating the connection as needed.
+        self.process_undecryptable().ok();
 
         // There's no point in trying to send a packet if the Initial secrets
         // have not been derived yet, so return early.
@@ -2490,6 +4594,23 @@ impl Connection {
             return Err(Error::Done);
         }
 
+        // Packets probing other paths are not coalesced with anything else.
+        if let Some((written, info)) = self.send_path_probe(out)? {
+            self.tap_sent(&out[..written], &info);
+
+            return Ok((written, info));
+        }
+
+        // Only count the times sending becomes restricted by the
+        // anti-amplification limit, not every attempt while it is.
+        let amplification_limited = self.is_anti_amplification_limited();
+
+        if amplification_limited && !self.amplification_limited {
+            self.amplification_limited_count += 1;
+        }
+
+        self.amplification_limited = amplification_limited;
+
         let mut has_initial = false;
 
         let mut done = 0;
@@ -2552,22 +4673,364 @@ impl Connection {
             done += pad_len;
         }
 
+        qlog_with_type!(QLOG_DATAGRAMS_TX, self.qlog, q, {
+            let ev_data =
+                EventData::DatagramsSent(qlog::events::quic::DatagramsSent {
+                    count: Some(1),
+
+                    raw: Some(vec![RawInfo {
+                        length: Some(done as u64),
+                        payload_length: None,
+                        data: None,
+                    }]),
+
+                    datagram_ids: None,
+                });
+
+            q.add_event_data_with_instant(ev_data, self.clock.now())
+                .ok();
+        });
+
         let info = SendInfo {
+            from: None,
+
             to: self.peer_addr,
 
             at: self
                 .recovery
                 .get_packet_send_time()
-                .unwrap_or_else(time::Instant::now),
+                .unwrap_or_else(|| self.clock.now()),
+
+            dscp: self.dscp,
         };
 
+        self.tap_sent(&out[..done], &info);
+
+        Ok((done, info))
+    }
+
+    /// Writes a burst of consecutive packets into the given buffer, suitable
+    /// for sending with UDP generic segmentation offload (GSO).
+    ///
+    /// Each UDP datagram is written in its own `segment_size` bytes slot of
+    /// `out`, as returned by [`send()`]. All datagrams have exactly that size
+    /// except the last one, which can be shorter, and all share the returned
+    /// [`SendInfo`]. The burst also ends early when packets need to be sent
+    /// to a different address, such as path probes, which are then returned
+    /// by the next call.
+    ///
+    /// The `segment_size` would typically be [`max_send_udp_payload_size()`],
+    /// and the length of `out` [`send_quantum()`], so that the burst doesn't
+    /// exceed what the pacer allows.
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`SendInfo`]: struct.SendInfo.html
+    /// [`max_send_udp_payload_size()`]: struct.Connection.html#method.max_send_udp_payload_size
+    /// [`send_quantum()`]: struct.Connection.html#method.send_quantum
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut out = [0; 65535];
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let from = "127.0.0.1:1234".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// let segment_size = conn.max_send_udp_payload_size();
+    /// let burst = conn.send_quantum();
+    ///
+    /// let (write, send_info) = conn.send_burst(&mut out[..burst], segment_size)?;
+    ///
+    /// // Hand `out[..write]` to the socket with a `segment_size` GSO size.
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn send_burst(
+        &mut self, out: &mut [u8], segment_size: usize,
+    ) -> Result<(usize, SendInfo)> {
+        if segment_size == 0 {
+            return Err(Error::BufferTooShort);
+        }
+
+        let len = cmp::min(out.len(), segment_size);
+
+        // Path probes are sent on their own.
+        let probe_pending = self.has_pending_path_probe();
+
+        let (mut done, info) = self.send(&mut out[..len])?;
+
+        if probe_pending {
+            return Ok((done, info));
+        }
+
+        // Only the last datagram of the burst can be shorter than the
+        // segment size.
+        while done % segment_size == 0 &&
+            done < out.len() &&
+            !self.has_pending_path_probe()
+        {
+            let len = cmp::min(out.len() - done, segment_size);
+
+            let written = match self.send(&mut out[done..done + len]) {
+                Ok((v, _)) => v,
+
+                Err(Error::Done) | Err(Error::BufferTooShort) => break,
+
+                Err(e) => return Err(e),
+            };
+
+            done += written;
+        }
+
         Ok((done, info))
     }
 
+    /// Returns whether a packet needs to be sent on a path other than the
+    /// active one.
+    fn has_pending_path_probe(&self) -> bool {
+        self.path_probes.iter().any(|p| p.pending) ||
+            !self.path_responses.is_empty()
+    }
+
+    /// Passes a sent datagram to the packet tap, if any.
+    fn tap_sent(&mut self, buf: &[u8], send_info: &SendInfo) {
+        if let Some(tap) = self.packet_tap.as_mut() {
+            let info = TapInfo {
+                direction: PacketDirection::Sent,
+
+                at: send_info.at,
+
+                local: send_info.from,
+
+                peer: send_info.to,
+            };
+
+            tap.on_datagram(&info, buf);
+        }
+    }
+
+    /// Writes a packet carrying a PATH_CHALLENGE for a probed path, or a
+    /// PATH_RESPONSE to a challenge received on a path other than the active
+    /// one.
+    fn send_path_probe(
+        &mut self, out: &mut [u8],
+    ) -> Result<Option<(usize, SendInfo)>> {
+        let now = self.clock.now();
+
+        if !self.is_established() || self.local_error.is_some() {
+            return Ok(None);
+        }
+
+        let epoch = packet::EPOCH_APPLICATION;
+
+        let (frame, dcid, from, to) =
+            if let Some(p) = self.path_probes.iter_mut().find(|p| p.pending) {
+                let dcid = match self.ids.dcid(p.dcid_seq) {
+                    Some(v) => v.clone(),
+
+                    None => return Err(Error::InvalidState),
+                };
+
+                let mut data = [0; 8];
+                rand::rand_bytes(&mut data);
+
+                p.challenges.push(data);
+                p.pending = false;
+                p.timer = Some(now + self.recovery.pto());
+
+                let frame = frame::Frame::PathChallenge { data };
+
+                (frame, dcid, Some(p.local), p.peer)
+            } else if let Some((data, to)) = self.path_responses.pop_front() {
+                let frame = frame::Frame::PathResponse { data };
+
+                (frame, self.dcid.clone(), None, to)
+            } else {
+                return Ok(None);
+            };
+
+        let pn = self.pkt_num_spaces[epoch].next_pkt_num;
+        let pn_len = packet::pkt_num_len(pn)?;
+
+        let crypto_overhead = self.pkt_num_spaces[epoch]
+            .crypto_overhead()
+            .ok_or(Error::Done)?;
+
+        // Probing packets are padded so that the path can be used for full
+        // sized packets, as long as the buffer is large enough.
+        let len = cmp::min(out.len(), MIN_CLIENT_INITIAL_LEN);
+
+        let mut b = octets::OctetsMut::with_slice(&mut out[..len]);
+
+        let hdr = Header {
+            ty: packet::Type::Short,
+
+            version: self.version,
+
+            dcid,
+            scid: ConnectionId::from_ref(&self.scid),
+
+            pkt_num: 0,
+            pkt_num_len: pn_len,
+
+            token: None,
+
+            versions: None,
+            key_phase: false,
+        };
+
+        hdr.to_bytes(&mut b)?;
+
+        packet::encode_pkt_num(pn, &mut b)?;
+
+        let payload_offset = b.off();
+
+        let mut left = len
+            .checked_sub(payload_offset + crypto_overhead)
+            .ok_or(Error::BufferTooShort)?;
+
+        let mut frames = Vec::new();
+
+        if !push_frame_to_pkt!(b, frames, frame, left) {
+            return Err(Error::BufferTooShort);
+        }
+
+        if left > 0 {
+            let frame = frame::Frame::Padding { len: left };
+
+            frame.to_bytes(&mut b)?;
+
+            frames.push(frame);
+        }
+
+        let payload_len = b.off() - payload_offset;
+
+        trace_event!(
+            { ty = ?hdr.ty, len = payload_len, pn, %to, "tx pkt" },
+            "{} tx pkt {:?} len={} pn={} to={}",
+            self.trace_id,
+            hdr,
+            payload_len,
+            pn,
+            to
+        );
+
+        #[cfg(feature = "qlog")]
+        self.qlog.on_packet();
+
+        qlog_with_type!(QLOG_PACKET_TX, self.qlog, q, {
+            let qlog_pkt_hdr = qlog::events::quic::PacketHeader::with_type(
+                hdr.ty.to_qlog(),
+                pn,
+                Some(hdr.version),
+                Some(&hdr.scid),
+                Some(&hdr.dcid),
+            );
+
+            let length = payload_len + payload_offset + crypto_overhead;
+            let qlog_raw_info = RawInfo {
+                length: Some(length as u64),
+                payload_length: Some(payload_len as u64),
+                data: None,
+            };
+
+            let ev_data = EventData::PacketSent(qlog::events::quic::PacketSent {
+                header: qlog_pkt_hdr,
+                frames: Some(vec![]),
+                is_coalesced: None,
+                retry_token: None,
+                stateless_reset_token: None,
+                supported_versions: None,
+                raw: Some(qlog_raw_info),
+                datagram_id: None,
+            });
+
+            q.add_event_data_with_instant(ev_data, now).ok();
+        });
+
+        for frame in &mut frames {
+            trace_event!(
+                { frame = ?frame, "tx frm" },
+                "{} tx frm {:?}",
+                self.trace_id,
+                frame
+            );
+
+            qlog_with_type!(QLOG_PACKET_TX, self.qlog, q, {
+                q.add_frame(frame.to_qlog(), false).ok();
+            });
+        }
+
+        qlog_with_type!(QLOG_PACKET_TX, self.qlog, q, {
+            q.finish_frames().ok();
+        });
+
+        let aead = match self.pkt_num_spaces[epoch].crypto_seal {
+            Some(ref v) => v,
+            None => return Err(Error::InvalidState),
+        };
+
+        let written = packet::encrypt_pkt(
+            &mut b,
+            pn,
+            pn_len,
+            payload_len,
+            payload_offset,
+            None,
+            aead,
+        )?;
+
+        // The packet is tracked like any other, so that it counts towards the
+        // bytes in flight and is declared lost if not acknowledged. Its
+        // frames are not retransmitted, as challenges are repeated when the
+        // probe timer fires instead.
+        let sent_pkt = recovery::Sent {
+            pkt_num: pn,
+            frames,
+            time_sent: now,
+            time_acked: None,
+            time_lost: None,
+            size: written,
+            ack_eliciting: true,
+            in_flight: true,
+            delivered: 0,
+            delivered_time: now,
+            recent_delivered_packet_sent_time: now,
+            is_app_limited: false,
+            has_data: false,
+        };
+
+        self.recovery.on_packet_sent(
+            sent_pkt,
+            epoch,
+            self.handshake_status(),
+            now,
+            &self.trace_id,
+        );
+
+        self.pkt_num_spaces[epoch].next_pkt_num += 1;
+
+        self.sent_count += 1;
+        self.sent_bytes += written as u64;
+
+        let info = SendInfo {
+            from,
+
+            to,
+
+            at: now,
+
+            dscp: self.dscp,
+        };
+
+        Ok(Some((written, info)))
+    }
+
     fn send_single(
         &mut self, out: &mut [u8], has_initial: bool,
     ) -> Result<(packet::Type, usize)> {
-        let now = time::Instant::now();
+        trace_span!("send_packet", conn = %self.trace_id);
+
+        let now = self.clock.now();
 
         if out.is_empty() {
             return Err(Error::BufferTooShort);
@@ -2635,7 +5098,7 @@ impl Connection {
                     self.stream_retrans_bytes += length as u64;
                 },
 
-                frame::Frame::ACK { .. } => {
+                frame::Frame::ACKHeader { .. } => {
                     self.pkt_num_spaces[epoch].ack_elicited = true;
                 },
 
@@ -2655,16 +5118,44 @@ impl Connection {
                     self.handshake_done_sent = false;
                 },
 
-                frame::Frame::MaxStreamData { stream_id, .. } => {
-                    if self.streams.get(stream_id).is_some() {
-                        self.streams.mark_almost_full(stream_id, true);
-                    }
+                // Retransmit NEW_TOKEN, unless a new token replaced it.
+                frame::Frame::NewToken { token } if self.new_token.is_none() => {
+                    self.new_token = Some(token);
+                },
+
+                frame::Frame::NewConnectionId { seq_num, .. } => {
+                    self.ids.on_new_conn_id_lost(seq_num);
+                },
+
+                frame::Frame::RetireConnectionId { seq_num } => {
+                    self.ids.on_retire_conn_id_lost(seq_num);
+                },
+
+                frame::Frame::MaxStreamData { stream_id, .. } => {
+                    if self.streams.get(stream_id).is_some() {
+                        self.streams.mark_almost_full(stream_id, true);
+                    }
                 },
 
                 frame::Frame::MaxData { .. } => {
                     self.almost_full = true;
                 },
 
+                // Retransmit DATA_BLOCKED and STREAM_DATA_BLOCKED only if the
+                // limit wasn't raised in the meantime.
+                frame::Frame::DataBlocked { limit }
+                    if limit == self.max_tx_data =>
+                {
+                    self.blocked_limit = Some(limit);
+                },
+
+                frame::Frame::StreamDataBlocked { stream_id, limit }
+                    if self.streams.get(stream_id).map(|s| s.send.max_off()) ==
+                        Some(limit) =>
+                {
+                    self.streams.mark_blocked(stream_id, true, limit);
+                },
+
                 _ => (),
             }
         }
@@ -2744,11 +5235,12 @@ impl Connection {
             return Err(Error::Done);
         }
 
-        let mut frames: Vec<frame::Frame> = Vec::new();
+        let mut frames = self.recovery.frames_buf();
 
         let mut ack_eliciting = false;
         let mut in_flight = false;
         let mut has_data = false;
+        let mut has_challenge = false;
 
         let header_offset = b.off();
 
@@ -2777,14 +5269,25 @@ impl Connection {
                 2_u64
                     .pow(self.local_transport_params.ack_delay_exponent as u32);
 
-            let frame = frame::Frame::ACK {
-                ack_delay,
-                ranges: self.pkt_num_spaces[epoch].recv_pkt_need_ack.clone(),
-                ecn_counts: None, // sending ECN is not supported at this time
-            };
+            // Encode the frame straight from the set of packets to be acked,
+            // and only keep track of the largest one in the sent packet.
+            let ranges = &self.pkt_num_spaces[epoch].recv_pkt_need_ack;
 
-            if push_frame_to_pkt!(b, frames, frame, left) {
-                self.pkt_num_spaces[epoch].ack_elicited = false;
+            // Sending ECN is not supported at this time.
+            let ack_len = frame::ack_wire_len(ack_delay, ranges, None);
+
+            if ack_len <= left {
+                frame::encode_ack(ack_delay, ranges, None, &mut b)?;
+
+                let frame = frame::Frame::ACKHeader {
+                    ack_delay,
+                    largest_acked: ranges.last().unwrap(),
+                    length: ack_len,
+                };
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    self.pkt_num_spaces[epoch].ack_elicited = false;
+                }
             }
         }
 
@@ -2801,6 +5304,63 @@ impl Connection {
                 }
             }
 
+            // Create NEW_TOKEN frame.
+            if self.is_established() {
+                if let Some(token) = self.new_token.clone() {
+                    let frame = frame::Frame::NewToken { token };
+
+                    if push_frame_to_pkt!(b, frames, frame, left) {
+                        self.new_token = None;
+
+                        ack_eliciting = true;
+                        in_flight = true;
+                    }
+                }
+            }
+
+            // Create NEW_CONNECTION_ID frames.
+            while let Some(frame) = self.ids.next_new_conn_id() {
+                if !push_frame_to_pkt!(b, frames, frame, left) {
+                    break;
+                }
+
+                self.ids.on_new_conn_id_sent();
+
+                ack_eliciting = true;
+                in_flight = true;
+            }
+
+            // Create RETIRE_CONNECTION_ID frames.
+            while let Some(frame) = self.ids.next_retire_conn_id() {
+                if !push_frame_to_pkt!(b, frames, frame, left) {
+                    break;
+                }
+
+                self.ids.on_retire_conn_id_sent();
+
+                ack_eliciting = true;
+                in_flight = true;
+            }
+
+            // Create PATH_CHALLENGE frame for the client's new address.
+            if let Some(m) = self.migration.as_mut().filter(|m| m.pending) {
+                let mut data = [0; 8];
+                rand::rand_bytes(&mut data);
+
+                let frame = frame::Frame::PathChallenge { data };
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    m.challenges.push(data);
+                    m.pending = false;
+                    m.timer = Some(now + self.recovery.pto());
+
+                    has_challenge = true;
+
+                    ack_eliciting = true;
+                    in_flight = true;
+                }
+            }
+
             // Create MAX_STREAMS_BIDI frame.
             if self.streams.should_update_max_streams_bidi() {
                 let frame = frame::Frame::MaxStreamsBidi {
@@ -2841,6 +5401,9 @@ impl Connection {
                 }
             }
 
+            // The room left in the connection's buffers, if limited.
+            let buffered_left = self.buffered_bytes_left().map(|v| v as u64);
+
             // Create MAX_STREAM_DATA frames as needed.
             for stream_id in self.streams.almost_full() {
                 let stream = match self.streams.get_mut(stream_id) {
@@ -2857,15 +5420,30 @@ impl Connection {
                 // Autotune the stream window size.
                 stream.recv.autotune_window(now, self.recovery.rtt());
 
-                let frame = frame::Frame::MaxStreamData {
-                    stream_id,
-                    max: stream.recv.max_data_next(),
+                // Don't grant more credit than there is room for. The
+                // connection-level limit below bounds the total across
+                // streams.
+                let max = match buffered_left {
+                    Some(left) => cmp::min(
+                        stream.recv.max_data_next(),
+                        stream.recv.max_off() + left,
+                    ),
+
+                    None => stream.recv.max_data_next(),
                 };
 
+                // The credit is withheld until the application reads data.
+                if max <= stream.recv.max_data() {
+                    self.streams.mark_almost_full(stream_id, false);
+                    continue;
+                }
+
+                let frame = frame::Frame::MaxStreamData { stream_id, max };
+
                 if push_frame_to_pkt!(b, frames, frame, left) {
                     let recv_win = stream.recv.window();
 
-                    stream.recv.update_max_data(now);
+                    stream.recv.update_max_data(max, now);
 
                     self.streams.mark_almost_full(stream_id, false);
 
@@ -2889,18 +5467,30 @@ impl Connection {
                 // Autotune the connection window size.
                 self.flow_control.autotune_window(now, self.recovery.rtt());
 
-                let frame = frame::Frame::MaxData {
-                    max: self.max_rx_data_next(),
+                // Don't let the peer send more data than there is room for in
+                // the connection's buffers.
+                let max = match buffered_left {
+                    Some(left) =>
+                        cmp::min(self.max_rx_data_next(), self.rx_data + left),
+
+                    None => self.max_rx_data_next(),
                 };
 
-                if push_frame_to_pkt!(b, frames, frame, left) {
+                if max <= self.max_rx_data() {
+                    // The credit is withheld until the application reads data.
                     self.almost_full = false;
+                } else {
+                    let frame = frame::Frame::MaxData { max };
 
-                    // Commits the new max_rx_data limit.
-                    self.flow_control.update_max_data(now);
+                    if push_frame_to_pkt!(b, frames, frame, left) {
+                        self.almost_full = false;
 
-                    ack_eliciting = true;
-                    in_flight = true;
+                        // Commits the new max_rx_data limit.
+                        self.flow_control.update_max_data(max, now);
+
+                        ack_eliciting = true;
+                        in_flight = true;
+                    }
                 }
             }
 
@@ -2985,7 +5575,7 @@ impl Connection {
                 // Create ConnectionClose frame.
                 let frame = frame::Frame::ConnectionClose {
                     error_code: conn_err.error_code,
-                    frame_type: 0,
+                    frame_type: conn_err.frame_type.unwrap_or(0),
                     reason: conn_err.reason.clone(),
                 };
 
@@ -3077,16 +5667,31 @@ impl Connection {
         }
 
         // The preference of data-bearing frame to include in a packet
-        // is managed by `self.emit_dgram`. However, whether any frames
+        // is managed by `self.dgram_send_order`, and by `self.emit_dgram`
+        // when alternating between the two. However, whether any frames
         // can be sent depends on the state of their buffers. In the case
         // where one type is preferred but its buffer is empty, fall back
         // to the other type in order not to waste this function call.
         let mut dgram_emitted = false;
+
+        // Drop DATAGRAMs that are no longer worth sending.
+        self.dgram_sent_dropped_expired +=
+            self.dgram_send_queue.purge_expired(now);
+
         let dgrams_to_emit = self.dgram_max_writable_len().is_some();
         let stream_to_emit = self.streams.has_flushable();
 
-        let mut do_dgram = self.emit_dgram && dgrams_to_emit;
-        let do_stream = !self.emit_dgram && stream_to_emit;
+        let prefer_dgram = match self.dgram_send_order {
+            DgramSendOrder::RoundRobin => self.emit_dgram,
+
+            DgramSendOrder::DatagramFirst =>
+                self.dgram_send_queue.peek_front_len().is_some(),
+
+            DgramSendOrder::StreamFirst => !stream_to_emit,
+        };
+
+        let mut do_dgram = prefer_dgram && dgrams_to_emit;
+        let do_stream = !prefer_dgram && stream_to_emit;
 
         if !do_stream && dgrams_to_emit {
             do_dgram = true;
@@ -3275,11 +5880,22 @@ impl Connection {
             }
         }
 
-        // Alternate trying to send DATAGRAMs next time.
-        self.emit_dgram = !dgram_emitted;
+        // Alternate trying to send DATAGRAMs next time, once the configured
+        // number of consecutive DATAGRAM packets has been sent.
+        if dgram_emitted {
+            self.dgram_burst += 1;
+        } else {
+            self.dgram_burst = 0;
+        }
+
+        self.emit_dgram = self.dgram_burst < self.dgram_send_weight;
 
-        // Create PING for PTO probe if no other ack-elicitng frame is sent.
-        if self.recovery.loss_probes[epoch] > 0 &&
+        let keep_alive =
+            self.keep_alive_pending && epoch == packet::EPOCH_APPLICATION;
+
+        // Create PING for PTO probe or keep-alive if no other ack-elicitng
+        // frame is sent.
+        if (self.recovery.loss_probes[epoch] > 0 || keep_alive) &&
             !ack_eliciting &&
             left >= 1 &&
             !is_closing
@@ -3298,6 +5914,8 @@ impl Connection {
         }
 
         if frames.is_empty() {
+            self.recovery.recycle_frames_buf(frames);
+
             // When we reach this point we are not able to write more, so set
             // app_limited to false.
             self.recovery.update_app_limited(false);
@@ -3308,8 +5926,13 @@ impl Connection {
         // datagram, so use PADDING frames instead.
         //
         // This is only needed if an Initial packet has already been written to
-        // the UDP datagram, as Initial always requires padding.
-        if has_initial && pkt_type == packet::Type::Short && left >= 1 {
+        // the UDP datagram, as Initial always requires padding, or if the
+        // packet carries a PATH_CHALLENGE, which also requires padding as far
+        // as the anti-amplification limit allows.
+        if (has_initial || has_challenge) &&
+            pkt_type == packet::Type::Short &&
+            left >= 1
+        {
             let frame = frame::Frame::Padding { len: left };
 
             if push_frame_to_pkt!(b, frames, frame, left) {
@@ -3342,7 +5965,8 @@ impl Connection {
                 .put_varint_with_len(len as u64, PAYLOAD_LENGTH_LEN)?;
         }
 
-        trace!(
+        trace_event!(
+            { ty = ?hdr.ty, len = payload_len, pn, "tx pkt" },
             "{} tx pkt {:?} len={} pn={}",
             self.trace_id,
             hdr,
@@ -3350,6 +5974,9 @@ impl Connection {
             pn
         );
 
+        #[cfg(feature = "qlog")]
+        self.qlog.on_packet();
+
         qlog_with_type!(QLOG_PACKET_TX, self.qlog, q, {
             let qlog_pkt_hdr = qlog::events::quic::PacketHeader::with_type(
                 hdr.ty.to_qlog(),
@@ -3385,7 +6012,34 @@ impl Connection {
         });
 
         for frame in &mut frames {
-            trace!("{} tx frm {:?}", self.trace_id, frame);
+            // The sent packet only tracks the largest acked packet of the ACK
+            // frame, so log the full frame from the set it was encoded from.
+            if let frame::Frame::ACKHeader { ack_delay, .. } = frame {
+                let ranges = &self.pkt_num_spaces[epoch].recv_pkt_need_ack;
+
+                trace_event!(
+                    { ack_delay, blocks = ?ranges, "tx frm ACK" },
+                    "{} tx frm ACK delay={} blocks={:?} ecn_counts=None",
+                    self.trace_id,
+                    ack_delay,
+                    ranges
+                );
+
+                qlog_with_type!(QLOG_PACKET_TX, self.qlog, q, {
+                    let frame = frame::ack_to_qlog(*ack_delay, ranges, None);
+
+                    q.add_frame(frame, false).ok();
+                });
+
+                continue;
+            }
+
+            trace_event!(
+                { frame = ?frame, "tx frm" },
+                "{} tx frm {:?}",
+                self.trace_id,
+                frame
+            );
 
             qlog_with_type!(QLOG_PACKET_TX, self.qlog, q, {
                 q.add_frame(frame.to_qlog(), false).ok();
@@ -3457,6 +6111,8 @@ impl Connection {
 
         self.max_send_bytes = self.max_send_bytes.saturating_sub(written);
 
+        self.update_stats_history(now);
+
         // (Re)start the idle timer if we are sending the first ack-eliciting
         // packet since last receiving a packet.
         if ack_eliciting && !self.ack_eliciting_sent {
@@ -3467,6 +6123,12 @@ impl Connection {
 
         if ack_eliciting {
             self.ack_eliciting_sent = true;
+
+            if epoch == packet::EPOCH_APPLICATION {
+                self.keep_alive_pending = false;
+            }
+
+            self.update_keep_alive_timer(now);
         }
 
         Ok((pkt_type, written))
@@ -3504,6 +6166,89 @@ impl Connection {
     pub fn stream_recv(
         &mut self, stream_id: u64, out: &mut [u8],
     ) -> Result<(usize, bool)> {
+        self.stream_recv_with(stream_id, |recv| recv.emit(out))
+    }
+
+    /// Reads contiguous data from a stream into the provided slices.
+    ///
+    /// This is the same as [`stream_recv()`], except that the data is
+    /// scattered across multiple buffers: each buffer is filled up to its
+    /// capacity, in order, before moving on to the next one.
+    ///
+    /// On success the total amount of bytes read and a flag indicating the
+    /// fin state is returned as a tuple, or [`Done`] if there is no data to
+    /// read.
+    ///
+    /// [`stream_recv()`]: struct.Connection.html#method.stream_recv
+    /// [`Done`]: enum.Error.html#variant.Done
+    #[cfg(feature = "std")]
+    pub fn stream_recv_v(
+        &mut self, stream_id: u64, bufs: &mut [IoSliceMut],
+    ) -> Result<(usize, bool)> {
+        self.stream_recv_with(stream_id, |recv| recv.emit_vectored(bufs))
+    }
+
+    /// Reads contiguous data from a stream into the provided slice, without
+    /// consuming it.
+    ///
+    /// This is the same as [`stream_recv()`], except that the data is left in
+    /// the stream's receive buffer, and the flow control credit isn't
+    /// returned to the peer, until it is consumed with [`stream_consume()`]
+    /// or read again with [`stream_recv()`]. On success the fin flag
+    /// indicates whether the stream would be finished once the peeked data
+    /// is consumed.
+    ///
+    /// [`stream_recv()`]: struct.Connection.html#method.stream_recv
+    /// [`stream_consume()`]: struct.Connection.html#method.stream_consume
+    pub fn stream_peek(
+        &self, stream_id: u64, out: &mut [u8],
+    ) -> Result<(usize, bool)> {
+        // We can't read on our own unidirectional streams.
+        if !stream::is_bidi(stream_id) &&
+            stream::is_local(stream_id, self.is_server)
+        {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        let stream = self
+            .streams
+            .get(stream_id)
+            .ok_or(Error::InvalidStreamState(stream_id))?;
+
+        if !stream.is_readable() {
+            return Err(Error::Done);
+        }
+
+        stream.recv.peek(out)
+    }
+
+    /// Discards up to `len` bytes of contiguous data from a stream.
+    ///
+    /// This is the same as [`stream_recv()`], except that the data isn't
+    /// copied anywhere. It is meant to be used after inspecting the data
+    /// with [`stream_peek()`].
+    ///
+    /// On success the amount of bytes consumed and a flag indicating the fin
+    /// state is returned as a tuple, or [`Done`] if there is no data to
+    /// consume.
+    ///
+    /// [`stream_recv()`]: struct.Connection.html#method.stream_recv
+    /// [`stream_peek()`]: struct.Connection.html#method.stream_peek
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn stream_consume(
+        &mut self, stream_id: u64, len: usize,
+    ) -> Result<(usize, bool)> {
+        self.stream_recv_with(stream_id, |recv| recv.consume(len))
+    }
+
+    /// Consumes data from a stream's receive buffer with the given function,
+    /// and updates the flow control and stream state accordingly.
+    fn stream_recv_with<F>(
+        &mut self, stream_id: u64, f: F,
+    ) -> Result<(usize, bool)>
+    where
+        F: FnOnce(&mut stream::RecvBuf) -> Result<(usize, bool)>,
+    {
         // We can't read on our own unidirectional streams.
         if !stream::is_bidi(stream_id) &&
             stream::is_local(stream_id, self.is_server)
@@ -3525,7 +6270,7 @@ impl Connection {
         #[cfg(feature = "qlog")]
         let offset = stream.recv.off_front();
 
-        let (read, fin) = match stream.recv.emit(out) {
+        let (read, fin) = match f(&mut stream.recv) {
             Ok(v) => v,
 
             Err(e) => {
@@ -3570,7 +6315,7 @@ impl Connection {
                 data: None,
             });
 
-            let now = time::Instant::now();
+            let now = self.clock.now();
             q.add_event_data_with_instant(ev_data, now).ok();
         });
 
@@ -3630,6 +6375,44 @@ impl Connection {
     pub fn stream_send(
         &mut self, stream_id: u64, buf: &[u8], fin: bool,
     ) -> Result<usize> {
+        self.stream_send_with(stream_id, buf.len(), fin, |send, len, fin| {
+            send.write(&buf[..len], fin)
+        })
+    }
+
+    /// Writes data to a stream without copying it.
+    ///
+    /// This is the same as [`stream_send()`], except that the stream's send
+    /// buffer keeps a reference to the given buffer instead of copying the
+    /// data into its own, and retransmissions are read from the same buffer.
+    /// The buffer is released once all the data it holds has been acked, or
+    /// the stream is collected.
+    ///
+    /// As with [`stream_send()`], the number of written bytes returned can be
+    /// lower than the length of the input buffer. In that case the remaining
+    /// data needs to be passed again, e.g. by copying it into a new buffer, or
+    /// with [`stream_send()`].
+    ///
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    pub fn stream_send_zc(
+        &mut self, stream_id: u64, buf: Arc<[u8]>, fin: bool,
+    ) -> Result<usize> {
+        self.stream_send_with(stream_id, buf.len(), fin, |send, len, fin| {
+            send.write_shared(buf, len, fin)
+        })
+    }
+
+    /// Writes up to `len` bytes to a stream's send buffer with the given
+    /// function, and updates the flow control and stream state accordingly.
+    ///
+    /// The function is passed the number of bytes it is allowed to write, and
+    /// the fin flag to use.
+    fn stream_send_with<F>(
+        &mut self, stream_id: u64, len: usize, fin: bool, f: F,
+    ) -> Result<usize>
+    where
+        F: FnOnce(&mut stream::SendBuf, usize, bool) -> Result<usize>,
+    {
         // We can't write on the peer's unidirectional streams.
         if !stream::is_bidi(stream_id) &&
             !stream::is_local(stream_id, self.is_server)
@@ -3642,8 +6425,8 @@ impl Connection {
         //
         // Note that this is separate from "send capacity" as that also takes
         // congestion control into consideration.
-        if self.max_tx_data - self.tx_data < buf.len() as u64 {
-            self.blocked_limit = Some(self.max_tx_data);
+        if self.max_tx_data - self.tx_data < len as u64 {
+            self.mark_data_blocked();
         }
 
         // Truncate the input buffer based on the connection's send capacity if
@@ -3651,16 +6434,18 @@ impl Connection {
         //
         // When the cap is zero, the method returns Ok(0) *only* when the passed
         // buffer is empty. We return Error::Done otherwise.
-        let cap = self.tx_cap;
-        if cap == 0 && !(fin && buf.is_empty()) {
+        //
+        // Data is also held off once the connection buffers too much of it.
+        let cap = match self.buffered_bytes_left() {
+            Some(left) => cmp::min(self.tx_cap, left),
+
+            None => self.tx_cap,
+        };
+        if cap == 0 && !(fin && len == 0) {
             return Err(Error::Done);
         }
 
-        let (buf, fin) = if cap < buf.len() {
-            (&buf[..cap], false)
-        } else {
-            (buf, fin)
-        };
+        let (len, fin) = if cap < len { (cap, false) } else { (len, fin) };
 
         // Get existing stream or create a new one.
         let stream = self.get_or_create_stream(stream_id, true)?;
@@ -3670,7 +6455,7 @@ impl Connection {
 
         let was_flushable = stream.is_flushable();
 
-        let sent = match stream.send.write(buf, fin) {
+        let sent = match f(&mut stream.send, len, fin) {
             Ok(v) => v,
 
             Err(e) => {
@@ -3686,12 +6471,12 @@ impl Connection {
 
         let writable = stream.is_writable();
 
-        let empty_fin = buf.is_empty() && fin;
+        let empty_fin = len == 0 && fin;
 
-        if sent < buf.len() {
+        if sent < len {
             let max_off = stream.send.max_off();
 
-            self.streams.mark_blocked(stream_id, true, max_off);
+            self.mark_stream_data_blocked(stream_id, max_off);
         } else {
             self.streams.mark_blocked(stream_id, false, 0);
         }
@@ -3725,11 +6510,11 @@ impl Connection {
                 data: None,
             });
 
-            let now = time::Instant::now();
+            let now = self.clock.now();
             q.add_event_data_with_instant(ev_data, now).ok();
         });
 
-        if sent == 0 && !buf.is_empty() {
+        if sent == 0 && len > 0 {
             return Err(Error::Done);
         }
 
@@ -3743,7 +6528,8 @@ impl Connection {
     /// created with a default priority of `127`.
     ///
     /// The target stream is created if it did not exist before calling this
-    /// method.
+    /// method. If the stream already has data queued for sending, the new
+    /// priority applies to it immediately.
     pub fn stream_priority(
         &mut self, stream_id: u64, urgency: u8, incremental: bool,
     ) -> Result<()> {
@@ -3761,10 +6547,19 @@ impl Connection {
             return Ok(());
         }
 
+        let old_urgency = stream.urgency;
+        let old_incremental = stream.incremental;
+
         stream.urgency = urgency;
         stream.incremental = incremental;
 
-        // TODO: reprioritization
+        self.streams.update_flushable_priority(
+            stream_id,
+            old_urgency,
+            old_incremental,
+            urgency,
+            incremental,
+        );
 
         Ok(())
     }
@@ -3825,6 +6620,112 @@ impl Connection {
         Ok(())
     }
 
+    /// Sets a deadline for the specified stream to complete.
+    ///
+    /// If the stream still has outgoing data that wasn't sent or acked by the
+    /// peer when the deadline expires, it is shut down for writing, and a
+    /// `RESET_STREAM` frame is sent to the peer. Similarly, if not all of the
+    /// incoming data was received and read yet, the stream is shut down for
+    /// reading, and a `STOP_SENDING` frame is sent. Both frames carry the
+    /// error code passed as the `err` argument, and a
+    /// [`StreamDeadlineExpired`] event is queued.
+    ///
+    /// The deadline is processed as part of [`on_timeout()`], and is taken
+    /// into account by [`timeout()`]. Calling this method again replaces the
+    /// previous deadline of the stream.
+    ///
+    /// The target stream is created if it did not exist before calling this
+    /// method.
+    ///
+    /// [`StreamDeadlineExpired`]:
+    /// enum.TransportEvent.html#variant.StreamDeadlineExpired
+    /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
+    /// [`timeout()`]: struct.Connection.html#method.timeout
+    pub fn stream_set_deadline(
+        &mut self, stream_id: u64, deadline: time::Instant, err: u64,
+    ) -> Result<()> {
+        // Get existing stream or create a new one, but if the stream
+        // has already been closed and collected, ignore the deadline.
+        match self.get_or_create_stream(stream_id, true) {
+            Ok(_) => (),
+
+            Err(Error::Done) => return Ok(()),
+
+            Err(e) => return Err(e),
+        };
+
+        self.streams.set_deadline(stream_id, deadline, err);
+
+        Ok(())
+    }
+
+    /// Resets and stops the streams whose deadline expired.
+    fn on_stream_deadlines(&mut self, now: time::Instant) {
+        for (stream_id, err) in self.streams.take_expired_deadlines(now) {
+            let stream = match self.streams.get(stream_id) {
+                Some(v) => v,
+
+                None => continue,
+            };
+
+            let has_send = stream.bidi || stream.local;
+            let has_recv = stream.bidi || !stream.local;
+
+            let reset = has_send && !stream.send.is_complete();
+            let stop = has_recv && !stream.recv.is_fin();
+
+            let mut expired = false;
+
+            if reset &&
+                self.stream_shutdown(stream_id, Shutdown::Write, err)
+                    .is_ok()
+            {
+                expired = true;
+            }
+
+            if stop &&
+                self.stream_shutdown(stream_id, Shutdown::Read, err).is_ok()
+            {
+                expired = true;
+            }
+
+            if !expired {
+                continue;
+            }
+
+            trace!("{} stream {} deadline expired", self.trace_id, stream_id);
+
+            self.push_event(TransportEvent::StreamDeadlineExpired {
+                stream_id,
+                error_code: err,
+            });
+        }
+    }
+
+    /// Allows the peer to open `n` more bidirectional or unidirectional
+    /// streams.
+    ///
+    /// The new limit is sent to the peer in a `MAX_STREAMS` frame, in addition
+    /// to the credits given back according to the configured
+    /// [`StreamCreditPolicy`].
+    ///
+    /// Returns [`InvalidState`] if the policy is
+    /// [`StreamCreditPolicy::Fixed`].
+    ///
+    /// [`StreamCreditPolicy`]: enum.StreamCreditPolicy.html
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    /// [`StreamCreditPolicy::Fixed`]:
+    /// enum.StreamCreditPolicy.html#variant.Fixed
+    pub fn grant_streams(&mut self, bidi: bool, n: u64) -> Result<()> {
+        if self.streams.credit_policy() == StreamCreditPolicy::Fixed {
+            return Err(Error::InvalidState);
+        }
+
+        self.streams.grant_streams(bidi, n);
+
+        Ok(())
+    }
+
     /// Returns the stream's send capacity in bytes.
     ///
     /// If the specified stream doesn't exist (including when it has already
@@ -3840,7 +6741,12 @@ impl Connection {
     #[inline]
     pub fn stream_capacity(&self, stream_id: u64) -> Result<usize> {
         if let Some(stream) = self.streams.get(stream_id) {
-            let cap = cmp::min(self.tx_cap, stream.send.cap()?);
+            let mut cap = cmp::min(self.tx_cap, stream.send.cap()?);
+
+            if let Some(left) = self.buffered_bytes_left() {
+                cap = cmp::min(cap, left);
+            }
+
             return Ok(cap);
         };
 
@@ -3890,13 +6796,15 @@ impl Connection {
             None => return Err(Error::InvalidStreamState(stream_id)),
         };
 
+        let stream_blocked = stream.send.cap()? < len;
+        let max_off = stream.send.max_off();
+
         if self.max_tx_data - self.tx_data < len as u64 {
-            self.blocked_limit = Some(self.max_tx_data);
+            self.mark_data_blocked();
         }
 
-        if stream.send.cap()? < len {
-            let max_off = stream.send.max_off();
-            self.streams.mark_blocked(stream_id, true, max_off);
+        if stream_blocked {
+            self.mark_stream_data_blocked(stream_id, max_off);
         }
 
         Ok(false)
@@ -3954,7 +6862,7 @@ impl Connection {
         &mut self, stream_id: u64, data: T,
     ) -> Result<()>
     where
-        T: std::any::Any + Send + Sync,
+        T: core::any::Any + Send + Sync,
     {
         // Get existing stream.
         let stream = self.streams.get_mut(stream_id).ok_or(Error::Done)?;
@@ -3977,7 +6885,7 @@ impl Connection {
     /// struct.Connection.html#method.stream_init_application_data
     pub fn stream_application_data(
         &mut self, stream_id: u64,
-    ) -> Option<&mut dyn std::any::Any> {
+    ) -> Option<&mut dyn core::any::Any> {
         // Get existing stream.
         let stream = self.streams.get_mut(stream_id)?;
 
@@ -3990,6 +6898,10 @@ impl Connection {
 
     /// Returns an iterator over streams that have outstanding data to read.
     ///
+    /// Streams are returned in priority order, as set with
+    /// [`stream_priority()`]: streams with lower urgency first, and streams
+    /// with the same urgency in order of their stream IDs.
+    ///
     /// Note that the iterator will only include streams that were readable at
     /// the time the iterator itself was created (i.e. when `readable()` was
     /// called). To account for newly readable streams, the iterator needs to
@@ -4013,13 +6925,29 @@ impl Connection {
     /// }
     /// # Ok::<(), quiche::Error>(())
     /// ```
+    ///
+    /// [`stream_priority()`]: struct.Connection.html#method.stream_priority
     #[inline]
     pub fn readable(&self) -> StreamIter {
         self.streams.readable()
     }
 
+    /// Returns the highest priority stream that has outstanding data to read.
+    ///
+    /// This is the same as the first stream returned by [`readable()`], but
+    /// without creating an iterator over all readable streams.
+    ///
+    /// [`readable()`]: struct.Connection.html#method.readable
+    #[inline]
+    pub fn readable_next(&self) -> Option<u64> {
+        self.streams.readable_next()
+    }
+
     /// Returns an iterator over streams that can be written to.
     ///
+    /// Streams are returned in priority order, in the same way as with
+    /// [`readable()`].
+    ///
     /// A "writable" stream is a stream that has enough flow control capacity to
     /// send data to the peer. To avoid buffering an infinite amount of data,
     /// streams are only allowed to buffer outgoing data up to the amount that
@@ -4048,6 +6976,8 @@ impl Connection {
     /// }
     /// # Ok::<(), quiche::Error>(())
     /// ```
+    ///
+    /// [`readable()`]: struct.Connection.html#method.readable
     #[inline]
     pub fn writable(&self) -> StreamIter {
         // If there is not enough connection-level send capacity, none of the
@@ -4059,6 +6989,21 @@ impl Connection {
         self.streams.writable()
     }
 
+    /// Returns the highest priority stream that can be written to.
+    ///
+    /// This is the same as the first stream returned by [`writable()`], but
+    /// without creating an iterator over all writable streams.
+    ///
+    /// [`writable()`]: struct.Connection.html#method.writable
+    #[inline]
+    pub fn writable_next(&self) -> Option<u64> {
+        if self.tx_cap == 0 {
+            return None;
+        }
+
+        self.streams.writable_next()
+    }
+
     /// Returns the maximum possible size of egress UDP payloads.
     ///
     /// This is the maximum size of UDP payloads that can be sent, and depends
@@ -4084,6 +7029,25 @@ impl Connection {
         }
     }
 
+    /// Returns the recommended number of bytes to send in a single burst.
+    ///
+    /// This is derived from the current pacing rate and congestion window,
+    /// and is always a multiple of [`max_send_udp_payload_size()`], so it can
+    /// be used to size the buffer passed to [`send_burst()`].
+    ///
+    /// [`max_send_udp_payload_size()`]: struct.Connection.html#method.max_send_udp_payload_size
+    /// [`send_burst()`]: struct.Connection.html#method.send_burst
+    pub fn send_quantum(&self) -> usize {
+        let segment_size = self.max_send_udp_payload_size();
+
+        let quantum = cmp::min(
+            self.recovery.send_quantum(),
+            self.recovery.cwnd_available(),
+        );
+
+        cmp::max(quantum / segment_size, 1) * segment_size
+    }
+
     /// Reads the first received DATAGRAM.
     ///
     /// On success the DATAGRAM's data is returned along with its size.
@@ -4113,6 +7077,8 @@ impl Connection {
     /// ```
     #[inline]
     pub fn dgram_recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.dgram_purge_expired_incoming(self.clock.now());
+
         match self.dgram_recv_queue.pop() {
             Some(d) => {
                 if d.len() > buf.len() {
@@ -4135,6 +7101,8 @@ impl Connection {
     /// [`dgram_recv()`]: struct.Connection.html#method.dgram_recv
     #[inline]
     pub fn dgram_recv_vec(&mut self) -> Result<Vec<u8>> {
+        self.dgram_purge_expired_incoming(self.clock.now());
+
         match self.dgram_recv_queue.pop() {
             Some(d) => Ok(d),
 
@@ -4232,6 +7200,10 @@ impl Connection {
             return Err(Error::BufferTooShort);
         }
 
+        if matches!(self.buffered_bytes_left(), Some(left) if buf.len() > left) {
+            return Err(Error::Done);
+        }
+
         self.dgram_send_queue.push(buf.to_vec())?;
 
         if self.dgram_send_queue.byte_size() > self.recovery.cwnd_available() {
@@ -4248,6 +7220,29 @@ impl Connection {
     ///
     /// [`dgram_send()`]: struct.Connection.html#method.dgram_send
     pub fn dgram_send_vec(&mut self, buf: Vec<u8>) -> Result<()> {
+        self.dgram_send_vec_with_expiry(buf, None)
+    }
+
+    /// Sends data in a DATAGRAM frame that expires after the given time.
+    ///
+    /// This is the same as [`dgram_send()`] but the DATAGRAM is dropped
+    /// instead of being sent if it is still queued once `ttl` has elapsed,
+    /// which is useful for real-time data that is worthless when late. Such
+    /// drops are counted in the [`dgram_sent_dropped_expired`] statistic.
+    ///
+    /// [`dgram_send()`]: struct.Connection.html#method.dgram_send
+    /// [`dgram_sent_dropped_expired`]: struct.Stats.html#structfield.dgram_sent_dropped_expired
+    pub fn dgram_send_with_ttl(
+        &mut self, buf: &[u8], ttl: time::Duration,
+    ) -> Result<()> {
+        let expiry = self.clock.now() + ttl;
+
+        self.dgram_send_vec_with_expiry(buf.to_vec(), Some(expiry))
+    }
+
+    fn dgram_send_vec_with_expiry(
+        &mut self, buf: Vec<u8>, expiry: Option<time::Instant>,
+    ) -> Result<()> {
         let max_payload_len = match self.dgram_max_writable_len() {
             Some(v) => v,
 
@@ -4258,7 +7253,11 @@ impl Connection {
             return Err(Error::BufferTooShort);
         }
 
-        self.dgram_send_queue.push(buf)?;
+        if matches!(self.buffered_bytes_left(), Some(left) if buf.len() > left) {
+            return Err(Error::Done);
+        }
+
+        self.dgram_send_queue.push_with_expiry(buf, expiry)?;
 
         if self.dgram_send_queue.byte_size() > self.recovery.cwnd_available() {
             self.recovery.update_app_limited(false);
@@ -4267,6 +7266,75 @@ impl Connection {
         Ok(())
     }
 
+    /// Sends data in a DATAGRAM frame on the path between the given local and
+    /// peer addresses.
+    ///
+    /// This is the same as [`dgram_send()`] but the DATAGRAM is queued on the
+    /// send queue of a specific network path, so that latency-sensitive data
+    /// can be pinned to it.
+    ///
+    /// As in [`SendInfo`], a `from` address of `None` refers to the local
+    /// address the connection's packets are normally sent from.
+    ///
+    /// Only the active path is currently supported, so [`InvalidState`] is
+    /// returned unless `from` is `None` and `to` matches the address of the
+    /// peer the connection is talking to.
+    ///
+    /// [`dgram_send()`]: struct.Connection.html#method.dgram_send
+    /// [`SendInfo`]: struct.SendInfo.html
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn dgram_send_on_path(
+        &mut self, buf: &[u8], from: Option<SocketAddr>, to: SocketAddr,
+    ) -> Result<()> {
+        if !self.is_active_path(from, to) {
+            return Err(Error::InvalidState);
+        }
+
+        self.dgram_send(buf)
+    }
+
+    /// Sends data in a DATAGRAM frame on the path between the given local and
+    /// peer addresses.
+    ///
+    /// This is the same as [`dgram_send_on_path()`] but takes a `Vec<u8>`
+    /// instead of a slice.
+    ///
+    /// [`dgram_send_on_path()`]:
+    /// struct.Connection.html#method.dgram_send_on_path
+    pub fn dgram_send_vec_on_path(
+        &mut self, buf: Vec<u8>, from: Option<SocketAddr>, to: SocketAddr,
+    ) -> Result<()> {
+        if !self.is_active_path(from, to) {
+            return Err(Error::InvalidState);
+        }
+
+        self.dgram_send_vec(buf)
+    }
+
+    /// Returns the number of items in the DATAGRAM send queue of the path
+    /// between the given local and peer addresses.
+    ///
+    /// See [`dgram_send_on_path()`] for how the path is identified. [`None`]
+    /// is returned if there is no such path.
+    ///
+    /// [`dgram_send_on_path()`]:
+    /// struct.Connection.html#method.dgram_send_on_path
+    pub fn dgram_send_queue_len_on_path(
+        &self, from: Option<SocketAddr>, to: SocketAddr,
+    ) -> Option<usize> {
+        if !self.is_active_path(from, to) {
+            return None;
+        }
+
+        Some(self.dgram_send_queue.len())
+    }
+
+    /// Returns whether the given local and peer addresses identify the path
+    /// the connection is using.
+    fn is_active_path(&self, from: Option<SocketAddr>, to: SocketAddr) -> bool {
+        (from, to) == (None, self.peer_addr)
+    }
+
     /// Purges queued outgoing DATAGRAMs matching the predicate.
     ///
     /// In other words, remove all elements `e` such that `f(&e)` returns true.
@@ -4287,6 +7355,12 @@ impl Connection {
         self.dgram_send_queue.purge(f);
     }
 
+    /// Drops received DATAGRAMs that the application didn't read in time.
+    fn dgram_purge_expired_incoming(&mut self, now: time::Instant) {
+        self.dgram_recv_dropped_expired +=
+            self.dgram_recv_queue.purge_expired(now);
+    }
+
     /// Returns the maximum DATAGRAM payload that can be sent.
     ///
     /// [`None`] is returned if the peer hasn't advertised a maximum DATAGRAM
@@ -4347,47 +7421,97 @@ impl Connection {
     ///
     /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
     pub fn timeout(&self) -> Option<time::Duration> {
+        let (timeout, _) = self.timeout_instant()?;
+
+        let now = self.clock.now();
+
+        if timeout <= now {
+            return Some(time::Duration::ZERO);
+        }
+
+        Some(timeout.duration_since(now))
+    }
+
+    /// Returns the earliest deadline of the connection, along with the reason
+    /// for it.
+    ///
+    /// This is the absolute counterpart of [`timeout()`], which covers the
+    /// same deadlines. The connection's [`on_timeout()`] method should be
+    /// called once the deadline is reached. For [`TimeoutReason::Pacing`]
+    /// this doesn't do anything, but it's a good time to release the packets
+    /// held back until their [`SendInfo::at`] time, and to call [`send()`]
+    /// again.
+    ///
+    /// [`timeout()`]: struct.Connection.html#method.timeout
+    /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
+    /// [`TimeoutReason::Pacing`]: enum.TimeoutReason.html#variant.Pacing
+    /// [`SendInfo::at`]: struct.SendInfo.html#structfield.at
+    /// [`send()`]: struct.Connection.html#method.send
+    pub fn timeout_instant(&self) -> Option<(time::Instant, TimeoutReason)> {
         if self.is_closed() {
             return None;
         }
 
-        let timeout = if self.is_draining() {
+        if self.is_draining() {
             // Draining timer takes precedence over all other timers. If it is
             // set it means the connection is closing so there's no point in
             // processing the other timers.
-            self.draining_timer
-        } else {
-            // Use the lowest timer value (i.e. "sooner") among idle and loss
-            // detection timers. If they are both unset (i.e. `None`) then the
-            // result is `None`, but if at least one of them is set then a
-            // `Some(...)` value is returned.
-            let timers = [self.idle_timer, self.recovery.loss_detection_timer()];
-
-            timers.iter().filter_map(|&x| x).min()
-        };
+            return self.draining_timer.map(|t| (t, TimeoutReason::Draining));
+        }
 
-        if let Some(timeout) = timeout {
-            let now = time::Instant::now();
+        let now = self.clock.now();
 
-            if timeout <= now {
-                return Some(time::Duration::ZERO);
-            }
+        // Only wait for pacing if the packets sent last are not due yet.
+        let pacing = self.recovery.get_packet_send_time().filter(|&t| t > now);
 
-            return Some(timeout.duration_since(now));
-        }
+        // Use the lowest timer value (i.e. "sooner") among all timers. If they
+        // are all unset (i.e. `None`) then the result is `None`, but if at
+        // least one of them is set then a `Some(...)` value is returned.
+        let timers = [
+            (self.idle_timer, TimeoutReason::Idle),
+            (self.keep_alive_timer, TimeoutReason::KeepAlive),
+            (
+                self.recovery.loss_detection_timer(),
+                TimeoutReason::LossDetection,
+            ),
+            (pacing, TimeoutReason::Pacing),
+            (
+                self.path_probes.iter().filter_map(|p| p.timer).min(),
+                TimeoutReason::PathValidation,
+            ),
+            (
+                self.migration.as_ref().and_then(|m| m.timer),
+                TimeoutReason::PathValidation,
+            ),
+            (self.streams.next_deadline(), TimeoutReason::StreamDeadline),
+            (
+                self.close_deadline
+                    .as_ref()
+                    .filter(|_| self.local_error.is_none())
+                    .map(|(t, ..)| *t),
+                TimeoutReason::Shutdown,
+            ),
+        ];
 
-        None
+        timers
+            .iter()
+            .filter_map(|&(timer, reason)| timer.map(|t| (t, reason)))
+            .min_by_key(|&(t, _)| t)
     }
 
     /// Processes a timeout event.
     ///
     /// If no timeout has occurred it does nothing.
     pub fn on_timeout(&mut self) {
-        let now = time::Instant::now();
+        let now = self.clock.now();
 
         if let Some(draining_timer) = self.draining_timer {
             if draining_timer <= now {
-                trace!("{} draining timeout expired", self.trace_id);
+                trace_event!(
+                    { "draining timeout expired" },
+                    "{} draining timeout expired",
+                    self.trace_id
+                );
 
                 qlog_with!(self.qlog, q, {
                     q.finish_log().ok();
@@ -4404,7 +7528,11 @@ impl Connection {
 
         if let Some(timer) = self.idle_timer {
             if timer <= now {
-                trace!("{} idle timeout expired", self.trace_id);
+                trace_event!(
+                    { "idle timeout expired" },
+                    "{} idle timeout expired",
+                    self.trace_id
+                );
 
                 qlog_with!(self.qlog, q, {
                     q.finish_log().ok();
@@ -4416,9 +7544,112 @@ impl Connection {
             }
         }
 
+        if let Some(timer) = self.keep_alive_timer {
+            if timer <= now {
+                trace_event!(
+                    { "keep-alive timeout expired" },
+                    "{} keep-alive timeout expired",
+                    self.trace_id
+                );
+
+                self.keep_alive_timer = None;
+                self.keep_alive_pending = true;
+            }
+        }
+
+        if let Some(m) = self.migration.as_mut() {
+            match m.timer {
+                Some(timer) if timer <= now => {
+                    m.timer = None;
+
+                    if m.challenges.len() < MAX_PATH_CHALLENGES {
+                        m.pending = true;
+                    } else {
+                        trace!(
+                            "{} peer address {} validation failed",
+                            self.trace_id,
+                            self.peer_addr
+                        );
+
+                        let ev = PathEvent::PeerMigrationFailed(
+                            m.prev_addr,
+                            self.peer_addr,
+                        );
+
+                        // Go back to the last address known to be valid.
+                        self.peer_addr = m.prev_addr;
+                        self.verified_peer_address = true;
+                        self.migration = None;
+
+                        self.push_path_event(ev);
+                    }
+                },
+
+                _ => (),
+            }
+        }
+
+        let mut i = 0;
+
+        while i < self.path_probes.len() {
+            let p = &mut self.path_probes[i];
+
+            i += 1;
+
+            match p.timer {
+                Some(timer) if timer <= now => p.timer = None,
+
+                _ => continue,
+            }
+
+            if p.challenges.len() < MAX_PATH_CHALLENGES {
+                p.pending = true;
+                continue;
+            }
+
+            trace!(
+                "{} path validation failed local={} peer={}",
+                self.trace_id,
+                p.local,
+                p.peer
+            );
+
+            let p = self.path_probes.remove(i - 1);
+            i -= 1;
+
+            // Don't reuse the connection ID on another path.
+            self.ids.retire_dcid(p.dcid_seq);
+
+            self.push_path_event(PathEvent::FailedValidation(p.local, p.peer));
+        }
+
+        self.on_stream_deadlines(now);
+
+        match self.close_deadline {
+            Some((timer, ..)) if timer <= now => {
+                let (_, err, reason) = self.close_deadline.take().unwrap();
+
+                trace_event!(
+                    { "close deadline expired" },
+                    "{} close deadline expired",
+                    self.trace_id
+                );
+
+                self.close(true, err, &reason).ok();
+            },
+
+            _ => (),
+        }
+
         if let Some(timer) = self.recovery.loss_detection_timer() {
             if timer <= now {
-                trace!("{} loss detection timeout expired", self.trace_id);
+                trace_span!("loss_detection_timeout", conn = %self.trace_id);
+
+                trace_event!(
+                    { "loss detection timeout expired" },
+                    "{} loss detection timeout expired",
+                    self.trace_id
+                );
 
                 self.recovery.on_loss_detection_timeout(
                     self.handshake_status(),
@@ -4431,6 +7662,12 @@ impl Connection {
                         q.add_event_data_with_instant(ev_data, now).ok();
                     }
                 });
+
+                qlog_with_type!(QLOG_CC_STATE_UPDATED, self.qlog, q, {
+                    if let Some(ev_data) = self.recovery.hystart_qlog() {
+                        q.add_event_data_with_instant(ev_data, now).ok();
+                    }
+                });
             }
         }
     }
@@ -4466,6 +7703,7 @@ impl Connection {
             is_app: app,
             error_code: err,
             reason: reason.to_vec(),
+            frame_type: None,
         });
 
         // When no packet was successfully processed close connection immediately.
@@ -4476,6 +7714,37 @@ impl Connection {
         Ok(())
     }
 
+    /// Closes the connection with the given application error and reason
+    /// once the deadline is reached, unless it was closed before.
+    ///
+    /// The deadline is covered by [`timeout()`] and [`on_timeout()`].
+    ///
+    /// [`timeout()`]: struct.Connection.html#method.timeout
+    /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
+    #[cfg(feature = "std")]
+    pub(crate) fn close_at(
+        &mut self, deadline: time::Instant, err: u64, reason: &[u8],
+    ) {
+        self.close_deadline = Some((deadline, err, reason.to_vec()));
+    }
+
+    /// Closes the connection after failing to process a frame of the given
+    /// type.
+    ///
+    /// Unlike [`close()`], the frame type and the error are reported to the
+    /// peer in the `CONNECTION_CLOSE` frame.
+    ///
+    /// [`close()`]: struct.Connection.html#method.close
+    fn close_on_frame_error(&mut self, e: Error, frame_type: u64) {
+        let reason = e.to_reason();
+
+        if self.close(false, e.to_wire(), reason.as_bytes()).is_ok() {
+            if let Some(ref mut conn_err) = self.local_error {
+                conn_err.frame_type = Some(frame_type);
+            }
+        }
+    }
+
     /// Returns a string uniquely representing the connection.
     ///
     /// This can be used for logging purposes to differentiate between multiple
@@ -4493,6 +7762,22 @@ impl Connection {
         self.alpn.as_ref()
     }
 
+    /// Returns the list of ALPN protocols offered by the client, as received
+    /// in the TLS ClientHello.
+    ///
+    /// The list is in the same wire format as the one passed to
+    /// [`set_application_protos()`], that is a sequence of non-empty,
+    /// 8-bit length-prefixed strings.
+    ///
+    /// This is only set on the server, and is empty until the ClientHello is
+    /// processed.
+    ///
+    /// [`set_application_protos()`]: struct.Config.html#method.set_application_protos
+    #[inline]
+    pub fn peer_application_protos(&self) -> &[u8] {
+        self.peer_application_protos.as_ref()
+    }
+
     /// Returns the server name requested by the client.
     #[inline]
     pub fn server_name(&self) -> Option<&str> {
@@ -4505,6 +7790,95 @@ impl Connection {
         self.handshake.peer_cert()
     }
 
+    /// Returns the peer's certificate chain (if any) as a vector of
+    /// DER-encoded buffers.
+    ///
+    /// The certificate at index 0 is the peer's leaf certificate, the other
+    /// certificates (if any) are the chain certificate authorities used to
+    /// sign the leaf certificate, in the order they were sent by the peer.
+    #[inline]
+    pub fn peer_cert_chain(&self) -> Option<Vec<Vec<u8>>> {
+        self.handshake.peer_cert_chain()
+    }
+
+    /// Returns the OCSP response stapled by the server, if any.
+    ///
+    /// This is only set on clients that enabled it with
+    /// [`enable_ocsp_stapling()`]. Note that quiche doesn't check the
+    /// response itself; applications can verify it against the server's
+    /// certificate chain during the handshake with
+    /// [`set_cert_status_verifier()`].
+    ///
+    /// [`enable_ocsp_stapling()`]: struct.Config.html#method.enable_ocsp_stapling
+    /// [`set_cert_status_verifier()`]: struct.Config.html#method.set_cert_status_verifier
+    #[inline]
+    pub fn ocsp_response(&self) -> Option<Vec<u8>> {
+        self.handshake.ocsp_response()
+    }
+
+    /// Returns the serialized SignedCertificateTimestampList sent by the
+    /// server, if any.
+    ///
+    /// This is only set on clients that enabled it with
+    /// [`enable_signed_cert_timestamps()`]. As with [`ocsp_response()`],
+    /// verifying the timestamps is up to the application.
+    ///
+    /// [`enable_signed_cert_timestamps()`]: struct.Config.html#method.enable_signed_cert_timestamps
+    /// [`ocsp_response()`]: struct.Connection.html#method.ocsp_response
+    #[inline]
+    pub fn sct_list(&self) -> Option<Vec<u8>> {
+        self.handshake.sct_list()
+    }
+
+    /// Returns the standard name of the negotiated TLS cipher suite (e.g.
+    /// `TLS_AES_128_GCM_SHA256`), if any.
+    #[inline]
+    pub fn cipher_suite(&self) -> Option<String> {
+        self.handshake.cipher_name()
+    }
+
+    /// Returns the name of the negotiated TLS key exchange group (e.g.
+    /// `X25519`), if any.
+    #[inline]
+    pub fn key_exchange_group(&self) -> Option<String> {
+        self.handshake.curve()
+    }
+
+    /// Returns the name of the signature algorithm used by the peer to sign
+    /// the handshake (e.g. `ecdsa_secp256r1_sha256`), if any.
+    #[inline]
+    pub fn peer_signature_algorithm(&self) -> Option<String> {
+        self.handshake.sigalg()
+    }
+
+    /// Exports `out_len` bytes of keying material from the TLS session, as
+    /// defined in [RFC 5705] and [RFC 8446 section 7.5].
+    ///
+    /// Both endpoints derive the same output for the same `label` and
+    /// `context`, which lets protocols running on top of QUIC bind their own
+    /// authentication to the connection. Note that with TLS 1.3 an empty
+    /// `context` is equivalent to no context.
+    ///
+    /// Returns [`Done`] if the handshake is not completed yet.
+    ///
+    /// [RFC 5705]: https://www.rfc-editor.org/rfc/rfc5705.html
+    /// [RFC 8446 section 7.5]: https://www.rfc-editor.org/rfc/rfc8446.html#section-7.5
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn export_keying_material(
+        &self, label: &[u8], context: &[u8], out_len: usize,
+    ) -> Result<Vec<u8>> {
+        if !self.handshake.is_completed() {
+            return Err(Error::Done);
+        }
+
+        let mut out = vec![0; out_len];
+
+        self.handshake
+            .export_keying_material(label, context, &mut out)?;
+
+        Ok(out)
+    }
+
     /// Returns the serialized cryptographic session for the connection.
     ///
     /// This can be used by a client to cache a connection's session, and resume
@@ -4516,6 +7890,33 @@ impl Connection {
         self.session.clone()
     }
 
+    /// Returns the latest address validation token received from the server
+    /// in a NEW_TOKEN frame, if any.
+    ///
+    /// This can be used by a client to cache the token, and send it in a
+    /// later connection to the same server using the [`set_token()`] method.
+    ///
+    /// [`set_token()`]: struct.Connection.html#method.set_token
+    #[inline]
+    pub fn new_token(&self) -> Option<&[u8]> {
+        self.peer_new_token.as_deref()
+    }
+
+    /// Returns the kind of the address validation token sent in the client's
+    /// Initial packets, if any.
+    ///
+    /// Tokens received in Retry packets are only valid for the current
+    /// connection, so only tokens of kind [`NewToken`] are worth caching for
+    /// later connections.
+    ///
+    /// This always returns `None` on servers.
+    ///
+    /// [`NewToken`]: enum.TokenKind.html#variant.NewToken
+    #[inline]
+    pub fn token_kind(&self) -> Option<TokenKind> {
+        self.token_kind
+    }
+
     /// Returns the source connection ID.
     ///
     /// Note that the value returned can change throughout the connection's
@@ -4534,6 +7935,35 @@ impl Connection {
         ConnectionId::from_ref(self.dcid.as_ref())
     }
 
+    /// Returns the QUIC version used by the peer.
+    ///
+    /// This is the version the connection settled on, possibly after version
+    /// negotiation. `None` is returned until a packet is received from the
+    /// peer, as until then the version is not confirmed.
+    #[inline]
+    pub fn peer_version(&self) -> Option<u32> {
+        if self.got_peer_conn_id {
+            return Some(self.version);
+        }
+
+        None
+    }
+
+    /// Returns true if the connection is using a version that was selected
+    /// after receiving a Version Negotiation packet from the server.
+    ///
+    /// This always returns false on the server.
+    #[inline]
+    pub fn is_version_negotiated(&self) -> bool {
+        !self.is_server && self.did_version_negotiation
+    }
+
+    /// Returns true if the peer's address was validated with a Retry packet.
+    #[inline]
+    pub fn did_retry(&self) -> bool {
+        self.did_retry
+    }
+
     /// Returns true if the connection handshake is complete.
     #[inline]
     pub fn is_established(&self) -> bool {
@@ -4546,6 +7976,36 @@ impl Connection {
         self.handshake.is_resumed()
     }
 
+    /// Returns true if the Encrypted Client Hello was accepted by the server.
+    #[inline]
+    pub fn is_ech_accepted(&self) -> bool {
+        self.handshake.is_ech_accepted()
+    }
+
+    /// Returns the application-layer protocol settings (ALPS) received from
+    /// the peer, if any.
+    ///
+    /// This is `None` unless both endpoints configured settings for the
+    /// negotiated ALPN protocol with [`add_application_settings()`].
+    ///
+    /// [`add_application_settings()`]: struct.Config.html#method.add_application_settings
+    #[inline]
+    pub fn peer_application_settings(&self) -> Option<&[u8]> {
+        self.handshake.peer_application_settings()
+    }
+
+    /// Returns the ECH configurations sent by the server when rejecting the
+    /// client's Encrypted Client Hello, if any.
+    ///
+    /// Clients can use them as the list passed to [`set_ech_config_list()`]
+    /// when retrying the connection.
+    ///
+    /// [`set_ech_config_list()`]: struct.Config.html#method.set_ech_config_list
+    #[inline]
+    pub fn ech_retry_configs(&self) -> Option<Vec<u8>> {
+        self.handshake.ech_retry_configs()
+    }
+
     /// Returns true if the connection has a pending handshake that has
     /// progressed enough to send or receive early data.
     #[inline]
@@ -4553,6 +8013,51 @@ impl Connection {
         self.handshake.is_in_early_data()
     }
 
+    /// Returns true if the early data sent by the client was accepted by the
+    /// server.
+    ///
+    /// This is only meaningful once the handshake is complete.
+    #[inline]
+    pub fn is_early_data_accepted(&self) -> bool {
+        self.handshake.is_early_data_accepted()
+    }
+
+    /// Returns true if sending is currently restricted by the
+    /// anti-amplification limit.
+    ///
+    /// Before the client's address is validated, a server can only send up to
+    /// three times the amount of data it received from the client. This
+    /// returns true when that limit doesn't allow a full-sized packet to be
+    /// sent, in which case the server will not be able to make progress until
+    /// more data is received from the client (e.g. because the client's
+    /// packets were lost).
+    ///
+    /// This always returns false for client connections, as well as after
+    /// the peer's address has been validated.
+    #[inline]
+    pub fn is_anti_amplification_limited(&self) -> bool {
+        match self.anti_amplification_budget() {
+            Some(v) => v < self.max_send_udp_payload_size(),
+
+            None => false,
+        }
+    }
+
+    /// Returns the number of bytes that can be sent before the peer's address
+    /// is validated.
+    ///
+    /// `None` is returned if the anti-amplification limit doesn't apply to
+    /// the connection, either because it's a client connection, or because the
+    /// peer's address has already been validated.
+    #[inline]
+    pub fn anti_amplification_budget(&self) -> Option<usize> {
+        if !self.is_server || self.verified_peer_address {
+            return None;
+        }
+
+        Some(self.max_send_bytes)
+    }
+
     /// Returns whether there is stream or DATAGRAM data available to read.
     #[inline]
     pub fn is_readable(&self) -> bool {
@@ -4577,6 +8082,24 @@ impl Connection {
         self.draining_timer.is_some()
     }
 
+    /// Returns the amount of time left before a draining connection is
+    /// closed.
+    ///
+    /// A connection drains for three times the probe timeout after sending
+    /// or receiving a `CONNECTION_CLOSE` frame, so that packets still in
+    /// flight are absorbed by it. Once this duration has elapsed, the
+    /// [`on_timeout()`] method closes the connection and its state can be
+    /// dropped.
+    ///
+    /// `None` is returned if the connection is not draining.
+    ///
+    /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
+    pub fn draining_timeout(&self) -> Option<time::Duration> {
+        let timer = self.draining_timer?;
+
+        Some(timer.saturating_duration_since(self.clock.now()))
+    }
+
     /// Returns true if the connection is closed.
     ///
     /// If this returns true, the connection object can be dropped.
@@ -4617,6 +8140,41 @@ impl Connection {
         self.local_error.as_ref()
     }
 
+    /// Releases memory held by the connection that is not currently needed.
+    ///
+    /// Internal buffers grow to accommodate bursts of traffic, and keep their
+    /// capacity afterwards. This shrinks them back to what their current
+    /// contents require, frees the frames of sent packets that were already
+    /// acked or declared lost, and drops the spare buffers kept for reuse.
+    ///
+    /// This is meant to be called on connections that have been idle for a
+    /// while, such as when holding many mostly idle connections. Calling it
+    /// on busy connections is safe, but the freed buffers will likely need to
+    /// be allocated again soon after.
+    ///
+    /// The amount of memory held by the connection's buffers is reported as
+    /// part of the connection's [`Stats`].
+    ///
+    /// [`Stats`]: struct.Stats.html
+    pub fn compact(&mut self) {
+        let now = self.clock.now();
+
+        self.recovery.shrink_to_fit(now);
+
+        self.streams.shrink_to_fit();
+
+        for space in self.pkt_num_spaces.iter_mut() {
+            space.crypto_stream.shrink_to_fit();
+        }
+
+        self.dgram_recv_queue.shrink_to_fit();
+        self.dgram_send_queue.shrink_to_fit();
+
+        self.undecryptable_pkts.shrink_to_fit();
+
+        self.recv_buf_pool = Vec::new();
+    }
+
     /// Collects and returns statistics about the connection.
     #[inline]
     pub fn stats(&self) -> Stats {
@@ -4631,8 +8189,32 @@ impl Connection {
             lost_bytes: self.recovery.bytes_lost,
             recv_bytes: self.recv_bytes,
             stream_retrans_bytes: self.stream_retrans_bytes,
+            amplification_limited: self.amplification_limited_count,
+            dgram_sent_dropped_expired: self.dgram_sent_dropped_expired,
+            dgram_recv_dropped_expired: self.dgram_recv_dropped_expired,
+            dgram_send_queue_bytes: self.dgram_send_queue.byte_size(),
+            dgram_recv_queue_bytes: self.dgram_recv_queue.byte_size(),
             pmtu: self.recovery.max_datagram_size(),
             delivery_rate: self.recovery.delivery_rate(),
+            pacing_rate: self.recovery.pacing_rate(),
+            ssthresh: self.recovery.ssthresh(),
+            cc_state: self.recovery.congestion_state(),
+            congestion_events: self.recovery.congestion_event_count,
+            pto: self.recovery.pto_total_count,
+            spurious_lost: self.recovery.lost_spurious_count,
+            data_blocked: self.data_blocked_count,
+            data_blocked_time: self.data_blocked_time +
+                self.data_blocked_since
+                    .map_or(time::Duration::ZERO, |since| {
+                        self.clock.now().saturating_duration_since(since)
+                    }),
+            stream_data_blocked: self.stream_data_blocked_count,
+            stream_data_blocked_time: self.stream_data_blocked_time,
+            peer_data_blocked: self.peer_data_blocked_count,
+            peer_stream_data_blocked: self.peer_stream_data_blocked_count,
+            buffered_bytes: self.buffered_bytes(),
+            retained_bytes: self.retained_bytes(),
+            events_dropped: self.events_dropped,
             peer_max_idle_timeout: self.peer_transport_params.max_idle_timeout,
             peer_max_udp_payload_size: self
                 .peer_transport_params
@@ -4669,16 +8251,303 @@ impl Connection {
         }
     }
 
-    fn encode_transport_params(&mut self) -> Result<()> {
-        let mut raw_params = [0; 128];
-
-        let raw_params = TransportParams::encode(
-            &self.local_transport_params,
+    /// Returns the recorded statistics history, oldest entry first.
+    ///
+    /// The history is empty unless it was enabled with
+    /// [`set_stats_history_len()`].
+    ///
+    /// [`set_stats_history_len()`]:
+    /// struct.Config.html#method.set_stats_history_len
+    pub fn stats_history(&self) -> Vec<StatsSnapshot> {
+        self.stats_history.iter().copied().collect()
+    }
+
+    /// Collects and returns statistics about each of the connection's network
+    /// paths.
+    ///
+    /// Only the active path is currently supported, so a single entry is
+    /// returned, for the path towards the address of the peer the connection
+    /// is talking to.
+    pub fn path_stats(&self) -> impl Iterator<Item = PathStats> {
+        core::iter::once(PathStats {
+            peer_addr: self.peer_addr,
+            active: true,
+            validated: !self.is_server || self.verified_peer_address,
+            rtt: self.recovery.rtt(),
+            rttvar: self.recovery.rttvar(),
+            min_rtt: self.recovery.min_rtt(),
+            cwnd: self.recovery.cwnd(),
+            bytes_in_flight: self.recovery.bytes_in_flight(),
+            pmtu: self.recovery.max_datagram_size(),
+        })
+    }
+
+    /// Returns the congestion control state of the active path, to be passed
+    /// to [`set_resume_state()`] on a later connection to the same peer.
+    ///
+    /// `None` is returned if no RTT sample was taken on the path yet.
+    ///
+    /// [`set_resume_state()`]: struct.Connection.html#method.set_resume_state
+    pub fn resume_state(&self) -> Option<ResumeState> {
+        let min_rtt = self.recovery.min_rtt();
+
+        if min_rtt.is_zero() {
+            return None;
+        }
+
+        Some(ResumeState {
+            peer_addr: self.peer_addr,
+            cwnd: self.recovery.cwnd(),
+            rtt: min_rtt,
+        })
+    }
+
+    /// Seeds the congestion controller with the state saved from a previous
+    /// connection, using Careful Resume.
+    ///
+    /// Once the first round of packets confirms that the path is consistent
+    /// with the saved RTT, the congestion window jumps to half of the saved
+    /// one. If congestion is detected before the packets sent with the
+    /// increased window are acked, the window is reduced to half of what was
+    /// acked in the meantime.
+    ///
+    /// The state is only applied if the peer has the same IP address as the
+    /// one it was saved for, and must be set before the handshake completes,
+    /// otherwise [`InvalidState`] is returned. Applications should not use
+    /// state saved too long ago, as the path is then unlikely to be the same.
+    ///
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn set_resume_state(&mut self, state: &ResumeState) -> Result<()> {
+        if state.peer_addr.ip() != self.peer_addr.ip() || self.is_established() {
+            return Err(Error::InvalidState);
+        }
+
+        self.recovery.setup_resume(state.cwnd, state.rtt);
+
+        Ok(())
+    }
+
+    /// Issues a new source connection ID to the peer, along with its
+    /// stateless reset token.
+    ///
+    /// The peer needs spare connection IDs to probe paths, and to answer
+    /// probes sent with [`probe_path()`]. The application is responsible for
+    /// routing packets carrying the new ID to this connection.
+    ///
+    /// On success the ID's sequence number is returned. Issuing more IDs than
+    /// the peer's `active_connection_id_limit` transport parameter allows
+    /// returns [`IdLimit`], and [`InvalidState`] is returned if the
+    /// connection uses a zero-length source connection ID.
+    ///
+    /// [`probe_path()`]: struct.Connection.html#method.probe_path
+    /// [`IdLimit`]: enum.Error.html#variant.IdLimit
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn new_scid(
+        &mut self, scid: &ConnectionId, reset_token: u128,
+    ) -> Result<u64> {
+        if self.scid.is_empty() {
+            return Err(Error::InvalidState);
+        }
+
+        let limit = self.peer_transport_params.active_conn_id_limit;
+
+        self.ids.new_scid(scid.to_vec().into(), reset_token, limit)
+    }
+
+    /// Returns the next source connection ID retired by the peer, if any.
+    ///
+    /// Packets carrying a retired ID are no longer expected, so the
+    /// application can stop routing them to this connection.
+    pub fn retired_scid_next(&mut self) -> Option<ConnectionId<'static>> {
+        self.ids.retired_scid_next()
+    }
+
+    /// Returns the number of connection IDs issued by the peer that are not
+    /// used by any path, and can be used by [`probe_path()`].
+    ///
+    /// [`probe_path()`]: struct.Connection.html#method.probe_path
+    pub fn available_dcids(&self) -> usize {
+        self.ids.spare_dcids()
+    }
+
+    /// Starts validating the path between the given local and peer
+    /// addresses, without migrating the connection to it.
+    ///
+    /// A PATH_CHALLENGE frame is sent on the path, in its own packet returned
+    /// by [`send()`], whose [`SendInfo`] tells which local address to send it
+    /// from. The outcome is reported by [`path_event_next()`]: the path is
+    /// validated once the peer answers, or the validation fails if
+    /// [`timeout()`] expires three times without an answer.
+    ///
+    /// Each probed path uses a spare connection ID issued by the peer, and
+    /// [`OutOfIdentifiers`] is returned if there is none. Probing a path
+    /// again restarts its validation with the same ID. [`InvalidState`] is
+    /// returned if the handshake is not complete yet.
+    ///
+    /// On success the sequence number of the connection ID used on the path
+    /// is returned.
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`SendInfo`]: struct.SendInfo.html
+    /// [`path_event_next()`]: struct.Connection.html#method.path_event_next
+    /// [`timeout()`]: struct.Connection.html#method.timeout
+    /// [`OutOfIdentifiers`]: enum.Error.html#variant.OutOfIdentifiers
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn probe_path(
+        &mut self, local: SocketAddr, peer: SocketAddr,
+    ) -> Result<u64> {
+        if !self.is_established() || self.is_draining() {
+            return Err(Error::InvalidState);
+        }
+
+        let probe = self
+            .path_probes
+            .iter_mut()
+            .find(|p| p.local == local && p.peer == peer);
+
+        let dcid_seq = match probe {
+            Some(p) => {
+                p.challenges.clear();
+                p.pending = true;
+                p.timer = None;
+                p.validated = false;
+
+                p.dcid_seq
+            },
+
+            None => {
+                let (dcid_seq, _) =
+                    self.ids.take_spare_dcid().ok_or(Error::OutOfIdentifiers)?;
+
+                self.path_probes.push(PathProbe {
+                    local,
+                    peer,
+                    dcid_seq,
+                    challenges: Vec::new(),
+                    pending: true,
+                    timer: None,
+                    validated: false,
+                });
+
+                dcid_seq
+            },
+        };
+
+        qlog_with_type!(QLOG_MIGRATION_STATE_UPDATED, self.qlog, q, {
+            let ev_data = migration_state_to_qlog(
+                MigrationState::ProbingStarted,
+                Some(local),
+                peer,
+            );
+
+            let now = self.clock.now();
+            QlogInfo::add_event_data(q, &mut self.qlog.deferred, ev_data, now);
+        });
+
+        Ok(dcid_seq)
+    }
+
+    /// Returns the next path validation outcome, if any.
+    ///
+    /// See [`probe_path()`].
+    ///
+    /// [`probe_path()`]: struct.Connection.html#method.probe_path
+    pub fn path_event_next(&mut self) -> Option<PathEvent> {
+        self.path_events.pop_front()
+    }
+
+    /// Returns the next transport event, if any.
+    ///
+    /// Events are only queued when enabled with [`enable_events()`], in
+    /// which case this should be called after [`recv()`] and [`on_timeout()`]
+    /// until it returns `None`, instead of checking the state of every
+    /// stream.
+    ///
+    /// Path events are still also reported by [`path_event_next()`].
+    ///
+    /// [`enable_events()`]: struct.Config.html#method.enable_events
+    /// [`recv()`]: struct.Connection.html#method.recv
+    /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
+    /// [`path_event_next()`]: struct.Connection.html#method.path_event_next
+    pub fn poll_event(&mut self) -> Option<TransportEvent> {
+        self.events.as_mut()?.pop_front()
+    }
+
+    /// Queues a transport event, if events are enabled.
+    fn push_event(&mut self, ev: TransportEvent) {
+        let events = match self.events.as_mut() {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        if events.contains(&ev) {
+            return;
+        }
+
+        // Only the latest limit is relevant.
+        if let TransportEvent::MaxDataUpdated { .. } = ev {
+            let queued = events
+                .iter_mut()
+                .find(|e| matches!(e, TransportEvent::MaxDataUpdated { .. }));
+
+            if let Some(e) = queued {
+                *e = ev;
+                return;
+            }
+        }
+
+        if events.len() >= self.max_events {
+            self.events_dropped += 1;
+            return;
+        }
+
+        events.push_back(ev);
+    }
+
+    /// Queues the outcome of a path validation.
+    fn push_path_event(&mut self, ev: PathEvent) {
+        qlog_with_type!(QLOG_MIGRATION_STATE_UPDATED, self.qlog, q, {
+            let now = self.clock.now();
+            QlogInfo::add_event_data(
+                q,
+                &mut self.qlog.deferred,
+                ev.to_qlog(),
+                now,
+            );
+        });
+
+        self.path_events.push_back(ev);
+
+        self.push_event(TransportEvent::Path(ev));
+    }
+
+    fn encode_transport_params(&mut self) -> Result<()> {
+        let mut max_len = self.local_transport_params.max_encoded_len();
+
+        if self.grease && self.grease_transport_params {
+            max_len += MAX_PARAM_HEADER_LEN + MAX_GREASE_PARAM_LEN;
+        }
+
+        let mut raw_params = vec![0; max_len];
+
+        let mut len = TransportParams::encode(
+            &self.local_transport_params,
             self.is_server,
             &mut raw_params,
-        )?;
+        )?
+        .len();
 
-        self.handshake.set_quic_transport_params(raw_params)?;
+        if self.grease && self.grease_transport_params {
+            let mut b = octets::OctetsMut::with_slice(&mut raw_params[len..]);
+
+            TransportParams::encode_grease(&mut b, self.grease_index)?;
+
+            len += b.off();
+        }
+
+        self.handshake
+            .set_quic_transport_params(&raw_params[..len])?;
 
         Ok(())
     }
@@ -4771,6 +8640,29 @@ impl Connection {
     /// Continues the handshake.
     ///
     /// If the connection is already established, it does nothing.
+    /// Checks the server's certificate status with the configured verifier,
+    /// if any.
+    fn verify_cert_status(&self) -> bool {
+        let verifier = match &self.cert_status_verifier {
+            Some(v) if !self.is_server => v,
+
+            _ => return true,
+        };
+
+        let cert_chain = self.handshake.peer_cert_chain().unwrap_or_default();
+        let cert_chain: Vec<&[u8]> =
+            cert_chain.iter().map(|c| c.as_slice()).collect();
+
+        let ocsp_response = self.handshake.ocsp_response();
+        let sct_list = self.handshake.sct_list();
+
+        verifier.verify(
+            &cert_chain,
+            ocsp_response.as_deref(),
+            sct_list.as_deref(),
+        )
+    }
+
     fn do_handshake(&mut self) -> Result<()> {
         if self.handshake_completed {
             // Handshake is already complete, nothing more to do.
@@ -4802,6 +8694,19 @@ impl Connection {
             Err(e) => return Err(e),
         };
 
+        if self.handshake.is_completed() && !self.verify_cert_status() {
+            let error = ConnectionError {
+                is_app: false,
+                error_code: CERT_STATUS_ALERT_ERROR,
+                reason: b"certificate status verification failed".to_vec(),
+                frame_type: None,
+            };
+
+            self.local_error = Some(error);
+
+            return Err(Error::TlsFail);
+        }
+
         self.handshake_completed = self.handshake.is_completed();
 
         self.alpn = self.handshake.alpn_protocol().to_vec();
@@ -4816,14 +8721,17 @@ impl Connection {
         }
 
         // Once the handshake is completed there's no point in processing 0-RTT
-        // packets anymore, so clear the buffer now.
+        // packets anymore, so remove them from the buffer now.
         if self.handshake_completed {
-            self.undecryptable_pkts.clear();
+            self.undecryptable_pkts
+                .retain(|(_, _, ty)| *ty != packet::Type::ZeroRTT);
+
+            self.push_event(TransportEvent::HandshakeCompleted);
         }
 
         trace!("{} connection established: proto={:?} cipher={:?} curve={:?} sigalg={:?} resumed={} {:?}",
                &self.trace_id,
-               std::str::from_utf8(self.application_proto()),
+               core::str::from_utf8(self.application_proto()),
                self.handshake.cipher(),
                self.handshake.curve(),
                self.handshake.sigalg(),
@@ -4884,6 +8792,10 @@ impl Connection {
         // Application epoch.
         if (self.is_established() || self.is_in_early_data()) &&
             (self.should_send_handshake_done() ||
+                self.new_token.is_some() ||
+                self.ids.has_frames() ||
+                matches!(self.migration, Some(ref m) if m.pending) ||
+                self.keep_alive_pending ||
                 self.almost_full ||
                 self.blocked_limit.is_some() ||
                 self.dgram_send_queue.has_pending() ||
@@ -4925,9 +8837,15 @@ impl Connection {
 
     /// Processes an incoming frame.
     fn process_frame(
-        &mut self, frame: frame::Frame, epoch: packet::Epoch, now: time::Instant,
+        &mut self, frame: frame::Frame, from: SocketAddr, epoch: packet::Epoch,
+        now: time::Instant,
     ) -> Result<()> {
-        trace!("{} rx frm {:?}", self.trace_id, frame);
+        trace_event!(
+            { frame = ?frame, "rx frm" },
+            "{} rx frm {:?}",
+            self.trace_id,
+            frame
+        );
 
         match frame {
             frame::Frame::Padding { .. } => (),
@@ -4935,13 +8853,17 @@ impl Connection {
             frame::Frame::Ping => (),
 
             frame::Frame::ACK {
-                ranges, ack_delay, ..
+                ranges,
+                ack_delay,
+                ecn_counts,
             } => {
+                let frame_type = if ecn_counts.is_some() { 0x03 } else { 0x02 };
+
                 let ack_delay = ack_delay
                     .checked_mul(2_u64.pow(
                         self.peer_transport_params.ack_delay_exponent as u32,
                     ))
-                    .ok_or(Error::InvalidFrame)?;
+                    .ok_or(Error::InvalidFrame(frame_type))?;
 
                 if epoch == packet::EPOCH_HANDSHAKE {
                     self.peer_verified_address = true;
@@ -5003,12 +8925,13 @@ impl Connection {
                 };
 
                 let was_readable = stream.is_readable();
+                let was_reset = stream.recv.is_reset();
 
                 let max_off_delta =
                     stream.recv.reset(error_code, final_size)? as u64;
 
                 if max_off_delta > max_rx_data_left {
-                    return Err(Error::FlowControl);
+                    return Err(Error::FlowControl(stream_id));
                 }
 
                 if !was_readable && stream.is_readable() {
@@ -5016,6 +8939,13 @@ impl Connection {
                 }
 
                 self.rx_data += max_off_delta;
+
+                if !was_reset {
+                    self.push_event(TransportEvent::StreamReset {
+                        stream_id,
+                        error_code,
+                    });
+                }
             },
 
             frame::Frame::StopSending {
@@ -5065,6 +8995,11 @@ impl Connection {
                     if !was_writable {
                         self.streams.mark_writable(stream_id, true);
                     }
+
+                    self.push_event(TransportEvent::StopSending {
+                        stream_id,
+                        error_code,
+                    });
                 }
             },
 
@@ -5092,12 +9027,28 @@ impl Connection {
                 }
             },
 
+            frame::Frame::ACKHeader { .. } => unreachable!(),
+
             frame::Frame::CryptoHeader { .. } => unreachable!(),
 
-            // TODO: implement stateless retry
-            frame::Frame::NewToken { .. } => (),
+            frame::Frame::NewToken { token } => {
+                if self.is_server {
+                    return Err(Error::InvalidPacket);
+                }
+
+                if token.is_empty() {
+                    return Err(Error::InvalidFrame(0x07));
+                }
+
+                self.peer_new_token = Some(token);
+
+                self.push_event(TransportEvent::NewToken);
+            },
 
-            frame::Frame::Stream { stream_id, data } => {
+            frame::Frame::Stream {
+                stream_id,
+                mut data,
+            } => {
                 // Peer can't send on our unidirectional streams.
                 if !stream::is_bidi(stream_id) &&
                     stream::is_local(stream_id, self.is_server)
@@ -5130,7 +9081,16 @@ impl Connection {
                     data.max_off().saturating_sub(stream.recv.max_off());
 
                 if max_off_delta > max_rx_data_left {
-                    return Err(Error::FlowControl);
+                    return Err(Error::FlowControl(stream_id));
+                }
+
+                // Data referencing a datagram received with `recv_owned()`
+                // is only worth keeping as is if it can be read right away,
+                // and if it makes up most of the datagram. Otherwise copy it
+                // out rather than hold on to the whole datagram, as the
+                // memory it pins isn't accounted for by flow control.
+                if data.off() > stream.recv.off_front() || data.is_oversized() {
+                    data.shrink_to_fit();
                 }
 
                 let was_readable = stream.is_readable();
@@ -5146,9 +9106,17 @@ impl Connection {
 
             frame::Frame::StreamHeader { .. } => unreachable!(),
 
-            frame::Frame::MaxData { max } => {
-                self.max_tx_data = cmp::max(self.max_tx_data, max);
-            },
+            frame::Frame::MaxData { max } =>
+                if max > self.max_tx_data {
+                    self.max_tx_data = max;
+
+                    if let Some(since) = self.data_blocked_since.take() {
+                        self.data_blocked_time +=
+                            now.saturating_duration_since(since);
+                    }
+
+                    self.push_event(TransportEvent::MaxDataUpdated { max });
+                },
 
             frame::Frame::MaxStreamData { stream_id, max } => {
                 // Peer can't receive on its own unidirectional streams.
@@ -5178,6 +9146,12 @@ impl Connection {
 
                 let was_flushable = stream.is_flushable();
 
+                let unblocked = if max > stream.send.max_off() {
+                    stream.blocked_since.take()
+                } else {
+                    None
+                };
+
                 stream.send.update_max_data(max);
 
                 let writable = stream.is_writable();
@@ -5193,59 +9167,182 @@ impl Connection {
                 if writable {
                     self.streams.mark_writable(stream_id, true);
                 }
+
+                if let Some(since) = unblocked {
+                    self.stream_data_blocked_time +=
+                        now.saturating_duration_since(since);
+                }
             },
 
             frame::Frame::MaxStreamsBidi { max } => {
                 if max > MAX_STREAM_ID {
-                    return Err(Error::InvalidFrame);
+                    return Err(Error::InvalidFrame(0x12));
                 }
 
+                let left = self.streams.peer_streams_left_bidi();
+
                 self.streams.update_peer_max_streams_bidi(max);
+
+                if self.streams.peer_streams_left_bidi() > left {
+                    self.push_event(TransportEvent::StreamsAvailable);
+                }
             },
 
             frame::Frame::MaxStreamsUni { max } => {
                 if max > MAX_STREAM_ID {
-                    return Err(Error::InvalidFrame);
+                    return Err(Error::InvalidFrame(0x13));
                 }
 
+                let left = self.streams.peer_streams_left_uni();
+
                 self.streams.update_peer_max_streams_uni(max);
+
+                if self.streams.peer_streams_left_uni() > left {
+                    self.push_event(TransportEvent::StreamsAvailable);
+                }
             },
 
-            frame::Frame::DataBlocked { .. } => (),
+            frame::Frame::DataBlocked { limit } => {
+                self.peer_data_blocked_count += 1;
 
-            frame::Frame::StreamDataBlocked { .. } => (),
+                self.push_event(TransportEvent::PeerDataBlocked { limit });
+            },
+
+            frame::Frame::StreamDataBlocked { stream_id, limit } => {
+                self.peer_stream_data_blocked_count += 1;
+
+                self.push_event(TransportEvent::PeerStreamDataBlocked {
+                    stream_id,
+                    limit,
+                });
+            },
 
             frame::Frame::StreamsBlockedBidi { limit } =>
                 if limit > MAX_STREAM_ID {
-                    return Err(Error::InvalidFrame);
+                    return Err(Error::InvalidFrame(0x16));
                 },
 
             frame::Frame::StreamsBlockedUni { limit } =>
                 if limit > MAX_STREAM_ID {
-                    return Err(Error::InvalidFrame);
+                    return Err(Error::InvalidFrame(0x17));
                 },
 
-            // TODO: implement connection migration
-            frame::Frame::NewConnectionId { .. } => (),
+            frame::Frame::NewConnectionId {
+                seq_num,
+                retire_prior_to,
+                conn_id,
+                ..
+            } => {
+                // An endpoint using a zero-length connection ID can't be
+                // issued new ones.
+                if self.dcid.is_empty() {
+                    return Err(Error::InvalidPacket);
+                }
+
+                let new_active = self.ids.new_dcid(
+                    seq_num,
+                    conn_id.into(),
+                    retire_prior_to,
+                )?;
+
+                if let Some(dcid) = new_active {
+                    self.set_dcid(dcid);
+                }
+
+                // Stop probing paths whose connection ID was retired.
+                let ids = &self.ids;
+                let mut failed = Vec::new();
+
+                self.path_probes.retain(|p| {
+                    if ids.dcid(p.dcid_seq).is_some() {
+                        return true;
+                    }
+
+                    if !p.validated {
+                        failed.push(PathEvent::FailedValidation(p.local, p.peer));
+                    }
+
+                    false
+                });
 
-            // TODO: implement connection migration
-            frame::Frame::RetireConnectionId { .. } => (),
+                for ev in failed {
+                    self.push_path_event(ev);
+                }
+            },
 
-            frame::Frame::PathChallenge { data } => {
-                self.challenge = Some(data);
+            frame::Frame::RetireConnectionId { seq_num } => {
+                self.ids.retire_scid(seq_num)?;
             },
 
-            frame::Frame::PathResponse { .. } => (),
+            frame::Frame::PathChallenge { data } =>
+                if from == self.peer_addr {
+                    self.challenge = Some(data);
+                } else {
+                    // Answer on the path the challenge was received on, and
+                    // only to the latest challenge. Challenges from too many
+                    // other addresses are ignored, the peer will retry them.
+                    match self.path_responses.iter().position(|r| r.1 == from) {
+                        Some(i) => self.path_responses[i].0 = data,
+
+                        None =>
+                            if self.path_responses.len() < MAX_PATH_RESPONSES {
+                                self.path_responses.push_back((data, from));
+                            },
+                    }
+                },
+
+            frame::Frame::PathResponse { data } => {
+                let migration = self
+                    .migration
+                    .as_ref()
+                    .filter(|m| m.challenges.contains(&data));
+
+                if let Some(m) = migration {
+                    trace!(
+                        "{} peer address {} validated",
+                        self.trace_id,
+                        self.peer_addr
+                    );
+
+                    let ev = PathEvent::PeerMigrated(m.prev_addr, self.peer_addr);
+
+                    self.push_path_event(ev);
+
+                    self.verified_peer_address = true;
+                    self.migration = None;
+                }
+
+                let probe = self
+                    .path_probes
+                    .iter_mut()
+                    .find(|p| !p.validated && p.challenges.contains(&data));
+
+                if let Some(p) = probe {
+                    p.validated = true;
+                    p.pending = false;
+                    p.timer = None;
+                    p.challenges.clear();
+
+                    let ev = PathEvent::Validated(p.local, p.peer);
+
+                    self.push_path_event(ev);
+                }
+            },
 
             frame::Frame::ConnectionClose {
-                error_code, reason, ..
+                error_code,
+                frame_type,
+                reason,
             } => {
                 self.peer_error = Some(ConnectionError {
                     is_app: false,
                     error_code,
                     reason,
+                    frame_type: Some(frame_type),
                 });
                 self.draining_timer = Some(now + (self.recovery.pto() * 3));
+
+                self.push_event(TransportEvent::PeerClosed);
             },
 
             frame::Frame::ApplicationClose { error_code, reason } => {
@@ -5253,8 +9350,11 @@ impl Connection {
                     is_app: true,
                     error_code,
                     reason,
+                    frame_type: None,
                 });
                 self.draining_timer = Some(now + (self.recovery.pto() * 3));
+
+                self.push_event(TransportEvent::PeerClosed);
             },
 
             frame::Frame::HandshakeDone => {
@@ -5279,12 +9379,16 @@ impl Connection {
                     return Err(Error::InvalidState);
                 }
 
+                self.dgram_purge_expired_incoming(now);
+
                 // If recv queue is full, discard oldest
                 if self.dgram_recv_queue.is_full() {
                     self.dgram_recv_queue.pop();
                 }
 
-                self.dgram_recv_queue.push(data)?;
+                let expiry = self.dgram_recv_ttl.map(|ttl| now + ttl);
+
+                self.dgram_recv_queue.push_with_expiry(data, expiry)?;
             },
 
             frame::Frame::DatagramHeader { .. } => unreachable!(),
@@ -5293,54 +9397,182 @@ impl Connection {
         Ok(())
     }
 
-    /// Drops the keys and recovery state for the given epoch.
-    fn drop_epoch_state(&mut self, epoch: packet::Epoch, now: time::Instant) {
-        if self.pkt_num_spaces[epoch].crypto_open.is_none() {
-            return;
-        }
+    /// Replaces the connection ID used as destination of outgoing packets.
+    fn set_dcid(&mut self, dcid: ConnectionId<'static>) {
+        qlog_with_type!(QLOG_CID_UPDATED, self.qlog, q, {
+            let ev_data = EventData::ConnectionIdUpdated(
+                qlog::events::connectivity::ConnectionIdUpdated {
+                    owner: Some(TransportOwner::Remote),
 
-        self.pkt_num_spaces[epoch].crypto_open = None;
-        self.pkt_num_spaces[epoch].crypto_seal = None;
-        self.pkt_num_spaces[epoch].clear();
+                    old: qlog::HexSlice::maybe_string(Some(&self.dcid)),
+                    new: qlog::HexSlice::maybe_string(Some(&dcid)),
+                },
+            );
 
-        self.recovery.on_pkt_num_space_discarded(
-            epoch,
-            self.handshake_status(),
-            now,
-        );
+            let now = self.clock.now();
+            QlogInfo::add_event_data(q, &mut self.qlog.deferred, ev_data, now);
+        });
 
-        trace!("{} dropped epoch {} state", self.trace_id, epoch);
+        self.dcid = dcid;
     }
 
-    /// Returns true if the connection-level flow control needs to be updated.
-    ///
-    /// This happens when the new max data limit is at least double the amount
-    /// of data that can be received before blocking.
-    fn should_update_max_data(&self) -> bool {
-        self.flow_control.should_update_max_data()
-    }
+    /// Starts sending to the client's new address, limited by the
+    /// anti-amplification limit until the address is validated.
+    fn on_peer_migrated(&mut self, from: SocketAddr, len: usize) {
+        let prev_addr = match self.migration {
+            // Moving back to the address used before the migration started
+            // doesn't need validating it again.
+            Some(ref m) if m.prev_addr == from => {
+                trace!("{} peer moved back to {}", self.trace_id, from);
 
-    /// Returns the connection level flow control limit.
-    fn max_rx_data(&self) -> u64 {
-        self.flow_control.max_data()
-    }
+                self.peer_addr = from;
+                self.verified_peer_address = true;
+                self.migration = None;
 
-    /// Returns the updated connection level flow control limit.
-    fn max_rx_data_next(&self) -> u64 {
-        self.flow_control.max_data_next()
-    }
+                return;
+            },
 
-    /// Returns true if the HANDSHAKE_DONE frame needs to be sent.
-    fn should_send_handshake_done(&self) -> bool {
-        self.is_established() && !self.handshake_done_sent && self.is_server
-    }
+            Some(ref m) => m.prev_addr,
 
-    /// Returns the idle timeout value.
-    ///
-    /// `None` is returned if both end-points disabled the idle timeout.
-    fn idle_timeout(&mut self) -> Option<time::Duration> {
-        // If the transport parameter is set to 0, then the respective endpoint
-        // decided to disable the idle timeout. If both are disabled we should
+            None => self.peer_addr,
+        };
+
+        trace!(
+            "{} peer migrated from {} to {}",
+            self.trace_id,
+            self.peer_addr,
+            from
+        );
+
+        // The congestion state is kept if only the port changed, which is
+        // likely a NAT rebinding on the same path.
+        if from.ip() != self.peer_addr.ip() {
+            self.recovery.on_path_change();
+        }
+
+        self.peer_addr = from;
+
+        self.verified_peer_address = false;
+        self.max_send_bytes = len * MAX_AMPLIFICATION_FACTOR;
+
+        self.migration = Some(PeerMigration {
+            prev_addr,
+            challenges: Vec::new(),
+            pending: true,
+            timer: None,
+        });
+
+        qlog_with_type!(QLOG_MIGRATION_STATE_UPDATED, self.qlog, q, {
+            let ev_data = migration_state_to_qlog(
+                MigrationState::MigrationStarted,
+                None,
+                from,
+            );
+
+            let now = self.clock.now();
+            QlogInfo::add_event_data(q, &mut self.qlog.deferred, ev_data, now);
+        });
+    }
+
+    /// Logs the derivation of new Initial packet protection keys.
+    #[cfg(feature = "qlog")]
+    fn qlog_initial_keys_updated(&mut self) {
+        self.qlog_key_updated(crypto::Level::Initial, false);
+        self.qlog_key_updated(crypto::Level::Initial, true);
+    }
+
+    /// Logs the installation of new packet protection keys by the TLS stack.
+    #[cfg(feature = "qlog")]
+    fn qlog_key_updated(&mut self, level: crypto::Level, is_write: bool) {
+        use qlog::events::security::KeyType;
+
+        let is_server_key = is_write == self.is_server;
+
+        let key_type = match (level, is_server_key) {
+            (crypto::Level::Initial, true) => KeyType::ServerInitialSecret,
+            (crypto::Level::Initial, false) => KeyType::ClientInitialSecret,
+
+            (crypto::Level::ZeroRTT, true) => KeyType::Server0RttSecret,
+            (crypto::Level::ZeroRTT, false) => KeyType::Client0RttSecret,
+
+            (crypto::Level::Handshake, true) => KeyType::ServerHandshakeSecret,
+            (crypto::Level::Handshake, false) => KeyType::ClientHandshakeSecret,
+
+            (crypto::Level::OneRTT, true) => KeyType::Server1RttSecret,
+            (crypto::Level::OneRTT, false) => KeyType::Client1RttSecret,
+        };
+
+        qlog_with_type!(QLOG_KEY_UPDATED, self.qlog, q, {
+            let ev_data =
+                EventData::KeyUpdated(qlog::events::security::KeyUpdated {
+                    key_type,
+                    old: None,
+                    new: None,
+                    generation: None,
+
+                    trigger: Some(
+                        qlog::events::security::KeyUpdateOrRetiredTrigger::Tls,
+                    ),
+                });
+
+            let now = self.clock.now();
+            QlogInfo::add_event_data(q, &mut self.qlog.deferred, ev_data, now);
+        });
+    }
+
+    /// Drops the keys and recovery state for the given epoch.
+    fn drop_epoch_state(&mut self, epoch: packet::Epoch, now: time::Instant) {
+        if self.pkt_num_spaces[epoch].crypto_open.is_none() {
+            return;
+        }
+
+        self.pkt_num_spaces[epoch].crypto_open = None;
+        self.pkt_num_spaces[epoch].crypto_seal = None;
+        self.pkt_num_spaces[epoch].clear();
+
+        self.recovery.on_pkt_num_space_discarded(
+            epoch,
+            self.handshake_status(),
+            now,
+        );
+
+        trace_event!(
+            { epoch, "dropped epoch state" },
+            "{} dropped epoch {} state",
+            self.trace_id,
+            epoch
+        );
+    }
+
+    /// Returns true if the connection-level flow control needs to be updated.
+    ///
+    /// This happens when the new max data limit is at least double the amount
+    /// of data that can be received before blocking.
+    fn should_update_max_data(&self) -> bool {
+        self.flow_control.should_update_max_data()
+    }
+
+    /// Returns the connection level flow control limit.
+    fn max_rx_data(&self) -> u64 {
+        self.flow_control.max_data()
+    }
+
+    /// Returns the updated connection level flow control limit.
+    fn max_rx_data_next(&self) -> u64 {
+        self.flow_control.max_data_next()
+    }
+
+    /// Returns true if the HANDSHAKE_DONE frame needs to be sent.
+    fn should_send_handshake_done(&self) -> bool {
+        self.is_established() && !self.handshake_done_sent && self.is_server
+    }
+
+    /// Returns the idle timeout value.
+    ///
+    /// `None` is returned if both end-points disabled the idle timeout.
+    fn idle_timeout(&mut self) -> Option<time::Duration> {
+        // If the transport parameter is set to 0, then the respective endpoint
+        // decided to disable the idle timeout. If both are disabled we should
         // not set any timeout.
         if self.local_transport_params.max_idle_timeout == 0 &&
             self.peer_transport_params.max_idle_timeout == 0
@@ -5367,6 +9599,55 @@ impl Connection {
         Some(idle_timeout)
     }
 
+    /// Records a new statistics snapshot, if the history is enabled and
+    /// enough time has passed since the previous one.
+    fn update_stats_history(&mut self, now: time::Instant) {
+        if self.stats_history_len == 0 {
+            return;
+        }
+
+        if let Some(last) = self.stats_history.back() {
+            if now < last.time + STATS_HISTORY_INTERVAL {
+                return;
+            }
+        }
+
+        if self.stats_history.len() >= self.stats_history_len {
+            self.stats_history.pop_front();
+        }
+
+        self.stats_history.push_back(StatsSnapshot {
+            time: now,
+            rtt: self.recovery.rtt(),
+            cwnd: self.recovery.cwnd(),
+            lost: self.recovery.lost_count,
+            lost_bytes: self.recovery.bytes_lost,
+            sent_bytes: self.sent_bytes,
+            recv_bytes: self.recv_bytes,
+            delivery_rate: self.recovery.delivery_rate(),
+        });
+    }
+
+    /// (Re)starts the keep-alive timer, if keep-alive is enabled.
+    fn update_keep_alive_timer(&mut self, now: time::Instant) {
+        let interval = match self.keep_alive {
+            Some(v) if self.is_established() && !self.is_draining() => v,
+
+            _ => {
+                self.keep_alive_timer = None;
+                return;
+            },
+        };
+
+        let interval = match self.idle_timeout() {
+            Some(idle_timeout) => cmp::min(interval, idle_timeout / 2),
+
+            None => interval,
+        };
+
+        self.keep_alive_timer = Some(now + interval);
+    }
+
     /// Returns the connection's handshake status for use in loss recovery.
     fn handshake_status(&self) -> recovery::HandshakeStatus {
         recovery::HandshakeStatus {
@@ -5386,6 +9667,110 @@ impl Connection {
             self.max_tx_data - self.tx_data,
         ) as usize;
     }
+
+    /// Returns the amount of data buffered by the connection.
+    fn buffered_bytes(&self) -> usize {
+        let undecryptable: usize = self
+            .undecryptable_pkts
+            .iter()
+            .map(|(pkt, ..)| pkt.len())
+            .sum();
+
+        self.streams.buffered() +
+            self.dgram_send_queue.byte_size() +
+            self.dgram_recv_queue.byte_size() +
+            undecryptable
+    }
+
+    /// Returns how much more data the connection can buffer, or `None` if
+    /// there is no limit.
+    fn buffered_bytes_left(&self) -> Option<usize> {
+        if self.max_buffered_bytes == 0 {
+            return None;
+        }
+
+        Some(
+            self.max_buffered_bytes
+                .saturating_sub(self.buffered_bytes()),
+        )
+    }
+
+    /// Returns the amount of memory held by the connection's buffers.
+    fn retained_bytes(&self) -> usize {
+        let crypto: usize = self
+            .pkt_num_spaces
+            .iter()
+            .map(|s| s.crypto_stream.retained())
+            .sum();
+
+        let undecryptable: usize = self
+            .undecryptable_pkts
+            .iter()
+            .map(|(pkt, ..)| pkt.capacity())
+            .sum();
+
+        let recv_buf_pool: usize =
+            self.recv_buf_pool.iter().map(|b| b.capacity()).sum();
+
+        self.streams.retained() +
+            self.recovery.retained() +
+            self.dgram_send_queue.retained() +
+            self.dgram_recv_queue.retained() +
+            crypto +
+            undecryptable +
+            recv_buf_pool
+    }
+
+    /// Marks the connection as blocked by the peer's connection-level flow
+    /// control limit, so that a DATA_BLOCKED frame is sent.
+    fn mark_data_blocked(&mut self) {
+        self.blocked_limit = Some(self.max_tx_data);
+
+        if self.data_blocked_since.is_some() {
+            return;
+        }
+
+        self.data_blocked_since = Some(self.clock.now());
+        self.data_blocked_count += 1;
+
+        trace_event!(
+            { limit = self.max_tx_data, "blocked by connection flow control" },
+            "{} blocked by connection flow control limit={}",
+            self.trace_id,
+            self.max_tx_data
+        );
+
+        self.push_event(TransportEvent::DataBlocked {
+            limit: self.max_tx_data,
+        });
+    }
+
+    /// Marks the stream as blocked by the peer's stream-level flow control
+    /// limit, so that a STREAM_DATA_BLOCKED frame is sent.
+    fn mark_stream_data_blocked(&mut self, stream_id: u64, limit: u64) {
+        self.streams.mark_blocked(stream_id, true, limit);
+
+        let now = self.clock.now();
+
+        match self.streams.get_mut(stream_id) {
+            Some(stream) if stream.blocked_since.is_none() =>
+                stream.blocked_since = Some(now),
+
+            _ => return,
+        }
+
+        self.stream_data_blocked_count += 1;
+
+        trace_event!(
+            { stream_id, limit, "stream blocked by flow control" },
+            "{} stream {} blocked by flow control limit={}",
+            self.trace_id,
+            stream_id,
+            limit
+        );
+
+        self.push_event(TransportEvent::StreamDataBlocked { stream_id, limit });
+    }
 }
 
 /// Maps an `Error` to `Error::Done`, or itself.
@@ -5416,7 +9801,11 @@ fn drop_pkt_on_err(
         return e;
     }
 
-    trace!("{} dropped invalid packet", trace_id);
+    trace_event!(
+        { conn = %trace_id, "dropped invalid packet" },
+        "{} dropped invalid packet",
+        trace_id
+    );
 
     // Ignore other invalid packets that haven't been authenticated to prevent
     // man-in-the-middle and man-on-the-side attacks.
@@ -5460,12 +9849,93 @@ pub struct Stats {
     /// The number of stream bytes retranmitted.
     pub stream_retrans_bytes: u64,
 
+    /// The number of times sending was restricted by the anti-amplification
+    /// limit before the peer's address was validated.
+    pub amplification_limited: usize,
+
+    /// The number of outgoing DATAGRAMs dropped because they expired before
+    /// they could be sent.
+    pub dgram_sent_dropped_expired: usize,
+
+    /// The number of received DATAGRAMs dropped because they expired before
+    /// the application read them.
+    pub dgram_recv_dropped_expired: usize,
+
+    /// The total size of the DATAGRAMs waiting to be sent, in bytes.
+    pub dgram_send_queue_bytes: usize,
+
+    /// The total size of the received DATAGRAMs waiting to be read, in bytes.
+    pub dgram_recv_queue_bytes: usize,
+
     /// The current PMTU for the connection.
     pub pmtu: usize,
 
     /// The most recent data delivery rate estimate in bytes/s.
     pub delivery_rate: u64,
 
+    /// The current pacing rate in bytes/s, or `0` if there is no round-trip
+    /// time estimate yet.
+    pub pacing_rate: u64,
+
+    /// The current slow start threshold in bytes.
+    pub ssthresh: usize,
+
+    /// The current state of the congestion controller.
+    pub cc_state: CongestionState,
+
+    /// The number of congestion recovery episodes.
+    pub congestion_events: usize,
+
+    /// The number of probe timeouts (PTO) that fired.
+    pub pto: usize,
+
+    /// The number of packets declared lost that were later acked.
+    pub spurious_lost: usize,
+
+    /// The number of times sending became blocked by the peer's
+    /// connection-level flow control limit.
+    pub data_blocked: usize,
+
+    /// The total time sending was blocked by the peer's connection-level flow
+    /// control limit.
+    pub data_blocked_time: time::Duration,
+
+    /// The number of times sending on a stream became blocked by the peer's
+    /// stream-level flow control limit.
+    pub stream_data_blocked: usize,
+
+    /// The total time streams were blocked by the peer's stream-level flow
+    /// control limits, summed over streams. Only periods that ended, when the
+    /// peer raised the limit, are counted.
+    pub stream_data_blocked_time: time::Duration,
+
+    /// The number of `DATA_BLOCKED` frames received.
+    pub peer_data_blocked: usize,
+
+    /// The number of `STREAM_DATA_BLOCKED` frames received.
+    pub peer_stream_data_blocked: usize,
+
+    /// The amount of data currently buffered by the connection, see
+    /// [`Config::set_max_buffered_bytes()`].
+    ///
+    /// [`Config::set_max_buffered_bytes()`]:
+    /// struct.Config.html#method.set_max_buffered_bytes
+    pub buffered_bytes: usize,
+
+    /// The amount of memory held by the connection's buffers, in bytes,
+    /// including allocated capacity that is currently unused. This is an
+    /// estimate, see [`Connection::compact()`].
+    ///
+    /// [`Connection::compact()`]: struct.Connection.html#method.compact
+    pub retained_bytes: usize,
+
+    /// The number of transport events dropped because too many were waiting
+    /// to be polled, see [`Config::set_max_events()`].
+    ///
+    /// [`Config::set_max_events()`]:
+    /// struct.Config.html#method.set_max_events
+    pub events_dropped: usize,
+
     /// The maximum idle timeout.
     pub peer_max_idle_timeout: u64,
 
@@ -5506,15 +9976,55 @@ pub struct Stats {
     pub peer_max_datagram_frame_size: Option<u64>,
 }
 
-impl std::fmt::Debug for Stats {
+impl core::fmt::Debug for Stats {
     #[inline]
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(
             f,
             "recv={} sent={} lost={} rtt={:?} cwnd={}",
             self.recv, self.sent, self.lost, self.rtt, self.cwnd,
         )?;
 
+        if self.amplification_limited > 0 {
+            write!(f, " amplification_limited={}", self.amplification_limited)?;
+        }
+
+        if self.dgram_sent_dropped_expired > 0 {
+            write!(
+                f,
+                " dgram_sent_dropped_expired={}",
+                self.dgram_sent_dropped_expired
+            )?;
+        }
+
+        if self.dgram_recv_dropped_expired > 0 {
+            write!(
+                f,
+                " dgram_recv_dropped_expired={}",
+                self.dgram_recv_dropped_expired
+            )?;
+        }
+
+        if self.data_blocked > 0 {
+            write!(
+                f,
+                " data_blocked={} data_blocked_time={:?}",
+                self.data_blocked, self.data_blocked_time
+            )?;
+        }
+
+        if self.stream_data_blocked > 0 {
+            write!(
+                f,
+                " stream_data_blocked={} stream_data_blocked_time={:?}",
+                self.stream_data_blocked, self.stream_data_blocked_time
+            )?;
+        }
+
+        if self.events_dropped > 0 {
+            write!(f, " events_dropped={}", self.events_dropped)?;
+        }
+
         write!(f, " peer_tps={{")?;
 
         write!(f, " max_idle_timeout={},", self.peer_max_idle_timeout,)?;
@@ -5583,72 +10093,422 @@ impl std::fmt::Debug for Stats {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-struct TransportParams {
-    pub original_destination_connection_id: Option<ConnectionId<'static>>,
-    pub max_idle_timeout: u64,
-    pub stateless_reset_token: Option<Vec<u8>>,
-    pub max_udp_payload_size: u64,
-    pub initial_max_data: u64,
-    pub initial_max_stream_data_bidi_local: u64,
-    pub initial_max_stream_data_bidi_remote: u64,
-    pub initial_max_stream_data_uni: u64,
-    pub initial_max_streams_bidi: u64,
-    pub initial_max_streams_uni: u64,
-    pub ack_delay_exponent: u64,
-    pub max_ack_delay: u64,
-    pub disable_active_migration: bool,
-    // pub preferred_address: ...,
-    pub active_conn_id_limit: u64,
-    pub initial_source_connection_id: Option<ConnectionId<'static>>,
-    pub retry_source_connection_id: Option<ConnectionId<'static>>,
-    pub max_datagram_frame_size: Option<u64>,
-}
-
-impl Default for TransportParams {
-    fn default() -> TransportParams {
-        TransportParams {
-            original_destination_connection_id: None,
-            max_idle_timeout: 0,
-            stateless_reset_token: None,
-            max_udp_payload_size: 65527,
-            initial_max_data: 0,
-            initial_max_stream_data_bidi_local: 0,
-            initial_max_stream_data_bidi_remote: 0,
-            initial_max_stream_data_uni: 0,
-            initial_max_streams_bidi: 0,
-            initial_max_streams_uni: 0,
-            ack_delay_exponent: 3,
-            max_ack_delay: 25,
-            disable_active_migration: false,
-            active_conn_id_limit: 2,
-            initial_source_connection_id: None,
-            retry_source_connection_id: None,
-            max_datagram_frame_size: None,
-        }
-    }
-}
+/// A snapshot of connection statistics at a point in time.
+///
+/// See [`stats_history()`].
+///
+/// [`stats_history()`]: struct.Connection.html#method.stats_history
+#[derive(Clone, Copy, Debug)]
+pub struct StatsSnapshot {
+    /// The time at which the snapshot was taken.
+    pub time: time::Instant,
 
-impl TransportParams {
-    fn decode(buf: &[u8], is_server: bool) -> Result<TransportParams> {
-        let mut params = octets::Octets::with_slice(buf);
+    /// The estimated round-trip time of the connection.
+    pub rtt: time::Duration,
 
-        let mut tp = TransportParams::default();
+    /// The size of the connection's congestion window in bytes.
+    pub cwnd: usize,
 
-        while params.cap() > 0 {
-            let id = params.get_varint()?;
+    /// The number of QUIC packets that were lost.
+    pub lost: usize,
 
-            let mut val = params.get_bytes_with_varint_length()?;
+    /// The number of bytes lost.
+    pub lost_bytes: u64,
 
-            // TODO: forbid duplicated param
+    /// The number of sent bytes.
+    pub sent_bytes: u64,
 
-            match id {
-                0x0000 => {
-                    if is_server {
-                        return Err(Error::InvalidTransportParam);
-                    }
+    /// The number of received bytes.
+    pub recv_bytes: u64,
 
-                    tp.original_destination_connection_id =
+    /// The most recent data delivery rate estimate in bytes/s.
+    pub delivery_rate: u64,
+}
+
+/// Statistics about a network path.
+///
+/// See [`path_stats()`].
+///
+/// [`path_stats()`]: struct.Connection.html#method.path_stats
+#[derive(Clone, Copy, Debug)]
+pub struct PathStats {
+    /// The address of the peer on the path.
+    pub peer_addr: SocketAddr,
+
+    /// Whether the path is the one currently used to send packets.
+    pub active: bool,
+
+    /// Whether the peer's address on the path has been validated.
+    pub validated: bool,
+
+    /// The estimated round-trip time of the path.
+    pub rtt: time::Duration,
+
+    /// The estimated variation in the round-trip time of the path.
+    pub rttvar: time::Duration,
+
+    /// The minimum round-trip time observed on the path.
+    pub min_rtt: time::Duration,
+
+    /// The size of the path's congestion window in bytes.
+    pub cwnd: usize,
+
+    /// The number of bytes sent on the path that haven't yet been acked or
+    /// declared lost.
+    pub bytes_in_flight: usize,
+
+    /// The current PMTU for the path.
+    pub pmtu: usize,
+}
+
+/// Congestion control state saved from a connection.
+///
+/// See [`resume_state()`] and [`set_resume_state()`].
+///
+/// [`resume_state()`]: struct.Connection.html#method.resume_state
+/// [`set_resume_state()`]: struct.Connection.html#method.set_resume_state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResumeState {
+    /// The address of the peer the state was saved for.
+    pub peer_addr: SocketAddr,
+
+    /// The size of the congestion window in bytes.
+    pub cwnd: usize,
+
+    /// The minimum round-trip time observed.
+    pub rtt: time::Duration,
+}
+
+/// The outcome of a path validation started with [`probe_path()`].
+///
+/// See [`path_event_next()`].
+///
+/// [`probe_path()`]: struct.Connection.html#method.probe_path
+/// [`path_event_next()`]: struct.Connection.html#method.path_event_next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathEvent {
+    /// The peer answered a challenge sent on the path identified by the
+    /// local and peer addresses.
+    Validated(SocketAddr, SocketAddr),
+
+    /// The peer did not answer any of the challenges sent on the path
+    /// identified by the local and peer addresses.
+    FailedValidation(SocketAddr, SocketAddr),
+
+    /// The client moved from the first address to the second one, for
+    /// example because of a NAT rebinding, and the new address was
+    /// validated.
+    ///
+    /// This is only reported by servers.
+    PeerMigrated(SocketAddr, SocketAddr),
+
+    /// The client moved from the first address to the second one, but the
+    /// new address did not answer any of the challenges, so the connection
+    /// went back to the first one.
+    ///
+    /// This is only reported by servers.
+    PeerMigrationFailed(SocketAddr, SocketAddr),
+}
+
+impl PathEvent {
+    #[cfg(feature = "qlog")]
+    fn to_qlog(self) -> EventData {
+        match self {
+            PathEvent::Validated(local, peer) => migration_state_to_qlog(
+                MigrationState::ProbingSuccessful,
+                Some(local),
+                peer,
+            ),
+
+            PathEvent::FailedValidation(local, peer) => migration_state_to_qlog(
+                MigrationState::ProbingAbandoned,
+                Some(local),
+                peer,
+            ),
+
+            PathEvent::PeerMigrated(_, peer) => migration_state_to_qlog(
+                MigrationState::MigrationComplete,
+                None,
+                peer,
+            ),
+
+            PathEvent::PeerMigrationFailed(_, peer) => migration_state_to_qlog(
+                MigrationState::MigrationAbandoned,
+                None,
+                peer,
+            ),
+        }
+    }
+}
+
+/// Creates a qlog event for a change of the state of a path validation or
+/// of a migration to the given path.
+#[cfg(feature = "qlog")]
+fn migration_state_to_qlog(
+    new: MigrationState, local: Option<SocketAddr>, peer: SocketAddr,
+) -> EventData {
+    EventData::MigrationStateUpdated(
+        qlog::events::connectivity::MigrationStateUpdated {
+            old: None,
+            new,
+
+            local_address: local.map(|v| v.to_string()),
+            remote_address: Some(peer.to_string()),
+        },
+    )
+}
+
+/// The reason of the earliest connection deadline.
+///
+/// See [`timeout_instant()`].
+///
+/// [`timeout_instant()`]: struct.Connection.html#method.timeout_instant
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutReason {
+    /// The connection is done draining, and will be closed.
+    Draining,
+
+    /// The idle timeout expires, and the connection will be closed.
+    Idle,
+
+    /// A keep-alive PING is due.
+    KeepAlive,
+
+    /// The loss detection or probe timeout (PTO) timer fires.
+    LossDetection,
+
+    /// The packets sent last are due according to pacing.
+    Pacing,
+
+    /// A path or peer address validation needs a retry, or failed.
+    PathValidation,
+
+    /// A stream deadline set with [`stream_set_deadline()`] expires.
+    ///
+    /// [`stream_set_deadline()`]:
+    /// struct.Connection.html#method.stream_set_deadline
+    StreamDeadline,
+
+    /// The timeout of a graceful HTTP/3 shutdown expires, and the connection
+    /// will be closed.
+    Shutdown,
+}
+
+/// A transport event.
+///
+/// See [`poll_event()`].
+///
+/// [`poll_event()`]: struct.Connection.html#method.poll_event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportEvent {
+    /// The handshake completed.
+    HandshakeCompleted,
+
+    /// The peer raised its stream count limits, so more streams can be
+    /// created.
+    StreamsAvailable,
+
+    /// The peer reset the stream with a `RESET_STREAM` frame.
+    StreamReset {
+        /// The ID of the stream.
+        stream_id: u64,
+
+        /// The error code carried by the frame.
+        error_code: u64,
+    },
+
+    /// The peer asked to stop sending on the stream with a `STOP_SENDING`
+    /// frame.
+    StopSending {
+        /// The ID of the stream.
+        stream_id: u64,
+
+        /// The error code carried by the frame.
+        error_code: u64,
+    },
+
+    /// A stream was reset and stopped because its deadline expired, see
+    /// [`stream_set_deadline()`].
+    ///
+    /// [`stream_set_deadline()`]:
+    /// struct.Connection.html#method.stream_set_deadline
+    StreamDeadlineExpired {
+        /// The ID of the stream.
+        stream_id: u64,
+
+        /// The error code carried by the `RESET_STREAM` and `STOP_SENDING`
+        /// frames.
+        error_code: u64,
+    },
+
+    /// The peer raised the connection-level flow control limit.
+    MaxDataUpdated {
+        /// The new limit, in bytes.
+        max: u64,
+    },
+
+    /// Sending is blocked by the peer's connection-level flow control limit,
+    /// and a `DATA_BLOCKED` frame will be sent.
+    DataBlocked {
+        /// The limit, in bytes.
+        limit: u64,
+    },
+
+    /// Sending on the stream is blocked by the peer's stream-level flow
+    /// control limit, and a `STREAM_DATA_BLOCKED` frame will be sent.
+    StreamDataBlocked {
+        /// The ID of the stream.
+        stream_id: u64,
+
+        /// The limit, in bytes.
+        limit: u64,
+    },
+
+    /// The peer is blocked by the local connection-level flow control limit,
+    /// as signalled with a `DATA_BLOCKED` frame.
+    PeerDataBlocked {
+        /// The limit, in bytes.
+        limit: u64,
+    },
+
+    /// The peer is blocked by the local flow control limit of the stream, as
+    /// signalled with a `STREAM_DATA_BLOCKED` frame.
+    PeerStreamDataBlocked {
+        /// The ID of the stream.
+        stream_id: u64,
+
+        /// The limit, in bytes.
+        limit: u64,
+    },
+
+    /// The server sent a new address validation token, see [`new_token()`].
+    ///
+    /// [`new_token()`]: struct.Connection.html#method.new_token
+    NewToken,
+
+    /// A path validation completed.
+    Path(PathEvent),
+
+    /// The peer closed the connection, see [`peer_error()`].
+    ///
+    /// [`peer_error()`]: struct.Connection.html#method.peer_error
+    PeerClosed,
+
+    /// The client switched to another version after receiving a Version
+    /// Negotiation packet, see [`is_version_negotiated()`].
+    ///
+    /// This is only reported by clients.
+    ///
+    /// [`is_version_negotiated()`]:
+    /// struct.Connection.html#method.is_version_negotiated
+    VersionNegotiated {
+        /// The version the connection now uses.
+        version: u32,
+    },
+}
+
+/// A path being validated on demand.
+struct PathProbe {
+    local: SocketAddr,
+
+    peer: SocketAddr,
+
+    /// Sequence number of the destination connection ID used on the path.
+    dcid_seq: u64,
+
+    /// Data of the challenges sent on the path so far.
+    challenges: Vec<[u8; 8]>,
+
+    /// Whether a new challenge needs to be sent.
+    pending: bool,
+
+    /// When the latest challenge is considered lost.
+    timer: Option<time::Instant>,
+
+    validated: bool,
+}
+
+/// The validation of the client's address after it migrated.
+struct PeerMigration {
+    /// The address the client used before migrating, known to be valid.
+    prev_addr: SocketAddr,
+
+    /// Data of the challenges sent to the new address so far.
+    challenges: Vec<[u8; 8]>,
+
+    /// Whether a new challenge needs to be sent.
+    pending: bool,
+
+    /// When the latest challenge is considered lost.
+    timer: Option<time::Instant>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct TransportParams {
+    pub original_destination_connection_id: Option<ConnectionId<'static>>,
+    pub max_idle_timeout: u64,
+    pub stateless_reset_token: Option<Vec<u8>>,
+    pub max_udp_payload_size: u64,
+    pub initial_max_data: u64,
+    pub initial_max_stream_data_bidi_local: u64,
+    pub initial_max_stream_data_bidi_remote: u64,
+    pub initial_max_stream_data_uni: u64,
+    pub initial_max_streams_bidi: u64,
+    pub initial_max_streams_uni: u64,
+    pub ack_delay_exponent: u64,
+    pub max_ack_delay: u64,
+    pub disable_active_migration: bool,
+    // pub preferred_address: ...,
+    pub active_conn_id_limit: u64,
+    pub initial_source_connection_id: Option<ConnectionId<'static>>,
+    pub retry_source_connection_id: Option<ConnectionId<'static>>,
+    pub max_datagram_frame_size: Option<u64>,
+}
+
+impl Default for TransportParams {
+    fn default() -> TransportParams {
+        TransportParams {
+            original_destination_connection_id: None,
+            max_idle_timeout: 0,
+            stateless_reset_token: None,
+            max_udp_payload_size: 65527,
+            initial_max_data: 0,
+            initial_max_stream_data_bidi_local: 0,
+            initial_max_stream_data_bidi_remote: 0,
+            initial_max_stream_data_uni: 0,
+            initial_max_streams_bidi: 0,
+            initial_max_streams_uni: 0,
+            ack_delay_exponent: 3,
+            max_ack_delay: 25,
+            disable_active_migration: false,
+            active_conn_id_limit: 2,
+            initial_source_connection_id: None,
+            retry_source_connection_id: None,
+            max_datagram_frame_size: None,
+        }
+    }
+}
+
+impl TransportParams {
+    fn decode(buf: &[u8], is_server: bool) -> Result<TransportParams> {
+        let mut params = octets::Octets::with_slice(buf);
+
+        let mut tp = TransportParams::default();
+
+        while params.cap() > 0 {
+            let id = params.get_varint()?;
+
+            let mut val = params.get_bytes_with_varint_length()?;
+
+            // TODO: forbid duplicated param
+
+            match id {
+                0x0000 => {
+                    if is_server {
+                        return Err(Error::InvalidTransportParam);
+                    }
+
+                    tp.original_destination_connection_id =
                         Some(val.to_vec().into());
                 },
 
@@ -5711,7 +10571,7 @@ impl TransportParams {
                 0x000a => {
                     let ack_delay_exponent = val.get_varint()?;
 
-                    if ack_delay_exponent > 20 {
+                    if ack_delay_exponent > MAX_ACK_DELAY_EXPONENT {
                         return Err(Error::InvalidTransportParam);
                     }
 
@@ -5721,7 +10581,7 @@ impl TransportParams {
                 0x000b => {
                     let max_ack_delay = val.get_varint()?;
 
-                    if max_ack_delay >= 2_u64.pow(14) {
+                    if max_ack_delay > MAX_ACK_DELAY {
                         return Err(Error::InvalidTransportParam);
                     }
 
@@ -5783,6 +10643,54 @@ impl TransportParams {
         Ok(())
     }
 
+    fn encode_grease(
+        b: &mut octets::OctetsMut, index: Option<u64>,
+    ) -> Result<()> {
+        let mut val = [0; MAX_GREASE_PARAM_LEN];
+
+        // When the GREASE index is forced the parameter is sent with an empty
+        // value, otherwise its length and content are random.
+        let len = match index {
+            Some(_) => 0,
+
+            None => {
+                let len = rand::rand_u64_uniform(val.len() as u64 + 1) as usize;
+                rand::rand_bytes(&mut val[..len]);
+                len
+            },
+        };
+
+        TransportParams::encode_param(b, grease_value(index, 27), len)?;
+        b.put_bytes(&val[..len])?;
+
+        Ok(())
+    }
+
+    /// Returns an upper bound on the length of the encoded parameters.
+    fn max_encoded_len(&self) -> usize {
+        // All other parameters have either no value or an integer value.
+        const INTEGER_PARAMS: usize = 13;
+
+        let cids = [
+            &self.original_destination_connection_id,
+            &self.initial_source_connection_id,
+            &self.retry_source_connection_id,
+        ];
+
+        let cids_len: usize = cids
+            .iter()
+            .filter_map(|cid| cid.as_ref())
+            .map(|cid| MAX_PARAM_HEADER_LEN + cid.len())
+            .sum();
+
+        let token_len = self
+            .stateless_reset_token
+            .as_ref()
+            .map_or(0, |token| MAX_PARAM_HEADER_LEN + token.len());
+
+        INTEGER_PARAMS * (MAX_PARAM_HEADER_LEN + 8) + cids_len + token_len
+    }
+
     fn encode<'a>(
         tp: &TransportParams, is_server: bool, out: &'a mut [u8],
     ) -> Result<&'a mut [u8]> {
@@ -5874,7 +10782,7 @@ impl TransportParams {
             b.put_varint(tp.initial_max_streams_uni)?;
         }
 
-        if tp.ack_delay_exponent != 0 {
+        if tp.ack_delay_exponent != 3 {
             TransportParams::encode_param(
                 &mut b,
                 0x000a,
@@ -5883,7 +10791,7 @@ impl TransportParams {
             b.put_varint(tp.ack_delay_exponent)?;
         }
 
-        if tp.max_ack_delay != 0 {
+        if tp.max_ack_delay != 25 {
             TransportParams::encode_param(
                 &mut b,
                 0x000b,
@@ -5990,10 +10898,15 @@ impl TransportParams {
     }
 }
 
-#[doc(hidden)]
+/// Helpers for testing applications built on quiche.
+#[cfg(feature = "std")]
 pub mod testing {
     use super::*;
 
+    pub use crate::simulator::LinkConfig;
+    pub use crate::simulator::Simulator;
+
+    #[doc(hidden)]
     pub struct Pipe {
         pub client: Pin<Box<Connection>>,
         pub server: Pin<Box<Connection>>,
@@ -6104,6 +11017,30 @@ pub mod testing {
             })
         }
 
+        pub fn with_client_and_server_config(
+            client_config: &mut Config, server_config: &mut Config,
+        ) -> Result<Pipe> {
+            let mut client_scid = [0; 16];
+            rand::rand_bytes(&mut client_scid[..]);
+            let client_scid = ConnectionId::from_ref(&client_scid);
+            let client_addr = "127.0.0.1:1234".parse().unwrap();
+
+            let mut server_scid = [0; 16];
+            rand::rand_bytes(&mut server_scid[..]);
+            let server_scid = ConnectionId::from_ref(&server_scid);
+            let server_addr = "127.0.0.1:4321".parse().unwrap();
+
+            Ok(Pipe {
+                client: connect(
+                    Some("quic.tech"),
+                    &client_scid,
+                    client_addr,
+                    client_config,
+                )?,
+                server: accept(&server_scid, None, server_addr, server_config)?,
+            })
+        }
+
         pub fn handshake(&mut self) -> Result<()> {
             while !self.client.is_established() || !self.server.is_established() {
                 let flight = emit_flight(&mut self.client)?;
@@ -6166,6 +11103,7 @@ pub mod testing {
         }
     }
 
+    #[doc(hidden)]
     pub fn recv_send(
         conn: &mut Connection, buf: &mut [u8], len: usize,
     ) -> Result<usize> {
@@ -6188,6 +11126,27 @@ pub mod testing {
         Ok(off)
     }
 
+    /// A clock that only moves when it is explicitly advanced.
+    #[doc(hidden)]
+    pub struct ManualClock(std::sync::Mutex<time::Instant>);
+
+    impl ManualClock {
+        pub fn new(now: time::Instant) -> ManualClock {
+            ManualClock(std::sync::Mutex::new(now))
+        }
+
+        pub fn advance(&self, duration: time::Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> time::Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[doc(hidden)]
     pub fn process_flight(
         conn: &mut Connection, flight: Vec<Vec<u8>>,
     ) -> Result<()> {
@@ -6202,6 +11161,7 @@ pub mod testing {
         Ok(())
     }
 
+    #[doc(hidden)]
     pub fn emit_flight(conn: &mut Connection) -> Result<Vec<Vec<u8>>> {
         let mut flight = Vec::new();
 
@@ -6226,6 +11186,7 @@ pub mod testing {
         Ok(flight)
     }
 
+    #[doc(hidden)]
     pub fn encode_pkt(
         conn: &mut Connection, pkt_type: packet::Type, frames: &[frame::Frame],
         buf: &mut [u8],
@@ -6290,6 +11251,7 @@ pub mod testing {
         Ok(written)
     }
 
+    #[doc(hidden)]
     pub fn decode_pkt(
         conn: &mut Connection, buf: &mut [u8], len: usize,
     ) -> Result<Vec<frame::Frame>> {
@@ -6357,6 +11319,7 @@ mod tests {
         let raw_params =
             TransportParams::encode(&tp, true, &mut raw_params).unwrap();
         assert_eq!(raw_params.len(), 94);
+        assert!(raw_params.len() <= tp.max_encoded_len());
 
         let new_tp = TransportParams::decode(&raw_params, false).unwrap();
 
@@ -6387,12 +11350,68 @@ mod tests {
         let raw_params =
             TransportParams::encode(&tp, false, &mut raw_params).unwrap();
         assert_eq!(raw_params.len(), 69);
+        assert!(raw_params.len() <= tp.max_encoded_len());
 
         let new_tp = TransportParams::decode(&raw_params, true).unwrap();
 
         assert_eq!(new_tp, tp);
     }
 
+    #[test]
+    fn transport_params_grease() {
+        let mut raw_params = [42; 256];
+
+        // Forced GREASE index.
+        let len = {
+            let mut b = octets::OctetsMut::with_slice(&mut raw_params);
+            TransportParams::encode_grease(&mut b, Some(0)).unwrap();
+            b.off()
+        };
+
+        assert_eq!(&raw_params[..len], &[0x1b, 0x00]);
+
+        // Random GREASE parameters are ignored by the peer.
+        let tp = TransportParams::default();
+
+        let len = {
+            let len = TransportParams::encode(&tp, false, &mut raw_params)
+                .unwrap()
+                .len();
+
+            let mut b = octets::OctetsMut::with_slice(&mut raw_params[len..]);
+            TransportParams::encode_grease(&mut b, None).unwrap();
+            len + b.off()
+        };
+
+        let new_tp = TransportParams::decode(&raw_params[..len], true).unwrap();
+
+        assert_eq!(new_tp, tp);
+    }
+
+    #[test]
+    fn handshake_grease_transport_params() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_grease_index(42);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        config.grease_transport_params(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+    }
+
     #[test]
     fn unknown_version() {
         let mut config = Config::new(0xbabababa).unwrap();
@@ -6429,8 +11448,12 @@ mod tests {
             .unwrap();
         config.verify_peer(false);
 
+        config.enable_events(true);
+
         let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
 
+        assert_eq!(pipe.client.peer_version(), None);
+
         let (mut len, _) = pipe.client.send(&mut buf).unwrap();
 
         let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
@@ -6438,162 +11461,229 @@ mod tests {
 
         assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(TransportEvent::VersionNegotiated {
+                version: PROTOCOL_VERSION
+            })
+        );
+        assert_eq!(pipe.client.poll_event(), None);
+
         assert_eq!(pipe.handshake(), Ok(()));
 
         assert_eq!(pipe.client.version, PROTOCOL_VERSION);
         assert_eq!(pipe.server.version, PROTOCOL_VERSION);
+
+        assert_eq!(pipe.client.peer_version(), Some(PROTOCOL_VERSION));
+        assert_eq!(pipe.server.peer_version(), Some(PROTOCOL_VERSION));
+
+        assert!(pipe.client.is_version_negotiated());
+        assert!(!pipe.server.is_version_negotiated());
     }
 
     #[test]
-    fn verify_custom_root() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config.verify_peer(true);
+    fn version_negotiation_preference() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(0xbabababa).unwrap();
         config
-            .load_verify_locations_from_file("examples/rootca.crt")
+            .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
         config
-            .set_application_protos(b"\x06proto1\x06proto2")
+            .set_supported_versions(&[PROTOCOL_VERSION_DRAFT29, PROTOCOL_VERSION])
             .unwrap();
+        config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
+        len = crate::negotiate_version(&hdr.scid, &hdr.dcid, &mut buf).unwrap();
+
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
         assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_DRAFT29);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_DRAFT29);
     }
 
     #[test]
-    fn missing_initial_source_connection_id() {
+    fn version_negotiation_with_config() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let scid = ConnectionId::from_ref(b"scid");
+        let dcid = ConnectionId::from_ref(b"dcid");
 
-        // Reset initial_source_connection_id.
-        pipe.client
-            .local_transport_params
-            .initial_source_connection_id = None;
-        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config.set_supported_versions(&[PROTOCOL_VERSION]).unwrap();
 
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert!(config.is_version_supported(PROTOCOL_VERSION));
+        assert!(!config.is_version_supported(PROTOCOL_VERSION_DRAFT29));
 
-        // Server rejects transport parameters.
-        assert_eq!(
-            pipe.server_recv(&mut buf[..len]),
-            Err(Error::InvalidTransportParam)
-        );
-    }
+        // Reserved versions are included when GREASE is enabled.
+        let len = negotiate_version_with_config(&scid, &dcid, &config, &mut buf)
+            .unwrap();
 
-    #[test]
-    fn invalid_initial_source_connection_id() {
-        let mut buf = [0; 65535];
+        let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
+        let versions = hdr.versions.unwrap();
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0], PROTOCOL_VERSION);
+        assert!(is_reserved_version(versions[1]));
 
-        // Scramble initial_source_connection_id.
-        pipe.client
-            .local_transport_params
-            .initial_source_connection_id = Some(b"bogus value".to_vec().into());
-        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
+        config.grease_versions(false);
 
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let len = negotiate_version_with_config(&scid, &dcid, &config, &mut buf)
+            .unwrap();
 
-        // Server rejects transport parameters.
-        assert_eq!(
-            pipe.server_recv(&mut buf[..len]),
-            Err(Error::InvalidTransportParam)
-        );
+        let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.versions, Some(vec![PROTOCOL_VERSION]));
+
+        config.grease_versions(true);
+        config.grease(false);
+
+        let len = negotiate_version_with_config(&scid, &dcid, &config, &mut buf)
+            .unwrap();
+
+        let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.versions, Some(vec![PROTOCOL_VERSION]));
     }
 
     #[test]
-    fn handshake() {
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+    fn config_supported_versions_invalid() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
 
         assert_eq!(
-            pipe.client.application_proto(),
-            pipe.server.application_proto()
+            config.set_supported_versions(&[]),
+            Err(Error::UnknownVersion)
         );
 
-        assert_eq!(pipe.server.server_name(), Some("quic.tech"));
+        assert_eq!(
+            config.set_supported_versions(&[PROTOCOL_VERSION, 0xbabababa]),
+            Err(Error::UnknownVersion)
+        );
     }
 
     #[test]
-    fn handshake_done() {
-        let mut pipe = testing::Pipe::default().unwrap();
-
-        // Disable session tickets on the server (SSL_OP_NO_TICKET) to avoid
-        // triggering 1-RTT packet send with a CRYPTO frame.
-        pipe.server.handshake.set_options(0x0000_4000);
+    #[cfg(not(feature = "rustls"))]
+    fn verify_custom_root() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config.verify_peer(true);
+        config
+            .load_verify_locations_from_file("examples/rootca.crt")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
 
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
-
-        assert!(pipe.server.handshake_done_sent);
     }
 
     #[test]
-    fn handshake_confirmation() {
-        let mut pipe = testing::Pipe::default().unwrap();
-
-        // Client sends initial flight.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+    fn peer_cert_chain() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
 
-        // Server sends initial flight.
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        assert!(!pipe.client.is_established());
-        assert!(!pipe.client.handshake_confirmed);
+        assert_eq!(pipe.client.peer_cert_chain(), None);
+        assert_eq!(pipe.client.cipher_suite(), None);
 
-        assert!(!pipe.server.is_established());
-        assert!(!pipe.server.handshake_confirmed);
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        let chain = pipe.client.peer_cert_chain().unwrap();
+        assert_eq!(chain.len(), 5);
+        assert_eq!(Some(&chain[0]), pipe.client.peer_cert().as_ref());
 
-        // Client sends Handshake packet and completes handshake.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        // The server didn't ask for a client certificate.
+        assert_eq!(pipe.server.peer_cert_chain(), None);
 
-        assert!(pipe.client.is_established());
-        assert!(!pipe.client.handshake_confirmed);
+        assert!(pipe.client.cipher_suite().is_some());
+        assert_eq!(pipe.client.cipher_suite(), pipe.server.cipher_suite());
 
-        assert!(!pipe.server.is_established());
-        assert!(!pipe.server.handshake_confirmed);
+        // Not exposed by rustls.
+        #[cfg(not(feature = "rustls"))]
+        {
+            assert_eq!(
+                pipe.client.key_exchange_group(),
+                Some("X25519".to_string())
+            );
+            assert!(pipe.client.peer_signature_algorithm().is_some());
+        }
+    }
 
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+    #[test]
+    fn export_keying_material() {
+        let mut pipe = testing::Pipe::default().unwrap();
 
-        // Server completes handshake and sends HANDSHAKE_DONE.
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        assert_eq!(
+            pipe.client
+                .export_keying_material(b"EXPORTER-test", b"", 32),
+            Err(Error::Done)
+        );
 
-        assert!(pipe.client.is_established());
-        assert!(!pipe.client.handshake_confirmed);
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert!(pipe.server.is_established());
-        assert!(!pipe.server.handshake_confirmed);
+        let client = pipe
+            .client
+            .export_keying_material(b"EXPORTER-test", b"ctx", 32)
+            .unwrap();
+        let server = pipe
+            .server
+            .export_keying_material(b"EXPORTER-test", b"ctx", 32)
+            .unwrap();
 
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        assert_eq!(client.len(), 32);
+        assert_eq!(client, server);
 
-        // Client acks 1-RTT packet, and confirms handshake.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        // A different label or context derives different keying material.
+        assert_ne!(
+            pipe.client
+                .export_keying_material(b"EXPORTER-other", b"ctx", 32),
+            Ok(client.clone())
+        );
+        assert_ne!(
+            pipe.client
+                .export_keying_material(b"EXPORTER-test", b"other", 32),
+            Ok(client)
+        );
+    }
 
-        assert!(pipe.client.is_established());
-        assert!(pipe.client.handshake_confirmed);
+    #[test]
+    fn peer_application_protos() {
+        let mut pipe = testing::Pipe::default().unwrap();
 
-        assert!(pipe.server.is_established());
-        assert!(!pipe.server.handshake_confirmed);
+        assert_eq!(pipe.server.peer_application_protos(), b"");
 
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Server handshake is confirmed.
-        assert!(pipe.client.is_established());
-        assert!(pipe.client.handshake_confirmed);
+        assert_eq!(
+            pipe.server.peer_application_protos(),
+            b"\x06proto1\x06proto2"
+        );
+        assert_eq!(pipe.server.application_proto(), b"proto1");
 
-        assert!(pipe.server.is_established());
-        assert!(pipe.server.handshake_confirmed);
+        // Only set on the server.
+        assert_eq!(pipe.client.peer_application_protos(), b"");
     }
 
     #[test]
-    fn handshake_resumption() {
-        const SESSION_TICKET_KEY: [u8; 48] = [0xa; 48];
+    fn ocsp_response_and_sct_list() {
+        // A SignedCertificateTimestampList holding a single dummy SCT.
+        let sct_list = [0x00, 0x05, 0x00, 0x03, 0x01, 0x02, 0x03];
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -6603,144 +11693,195 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
+        config.set_ocsp_response(b"ocsp").unwrap();
+        config.set_sct_list(&sct_list).unwrap();
+        config.verify_peer(false);
 
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        // The client didn't ask for them.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.is_established(), true);
-        assert_eq!(pipe.server.is_established(), true);
+        assert_eq!(pipe.client.ocsp_response(), None);
+        assert_eq!(pipe.client.sct_list(), None);
 
-        assert_eq!(pipe.client.is_resumed(), false);
-        assert_eq!(pipe.server.is_resumed(), false);
+        config.enable_ocsp_stapling();
+        config.enable_signed_cert_timestamps();
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Configure session on new connection and perform handshake.
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
+        assert_eq!(pipe.client.ocsp_response(), Some(b"ocsp".to_vec()));
+        assert_eq!(pipe.client.sct_list(), Some(sct_list.to_vec()));
+
+        assert_eq!(pipe.server.ocsp_response(), None);
+        assert_eq!(pipe.server.sct_list(), None);
+    }
+
+    struct RequireOcsp;
+
+    impl CertStatusVerifier for RequireOcsp {
+        fn verify(
+            &self, cert_chain: &[&[u8]], ocsp_response: Option<&[u8]>,
+            _sct_list: Option<&[u8]>,
+        ) -> bool {
+            assert!(!cert_chain.is_empty());
+
+            ocsp_response == Some(b"ocsp")
+        }
+    }
+
+    #[test]
+    fn cert_status_verifier() {
+        let mut client_config = Config::new(PROTOCOL_VERSION).unwrap();
+        client_config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        client_config.verify_peer(false);
+        client_config.enable_ocsp_stapling();
+        client_config.set_cert_status_verifier(Arc::new(RequireOcsp));
+
+        let mut server_config = Config::new(PROTOCOL_VERSION).unwrap();
+        server_config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
-        config
+        server_config
             .load_priv_key_from_pem_file("examples/cert.key")
             .unwrap();
-        config
+        server_config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        // The server doesn't staple an OCSP response.
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
+        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
 
-        assert_eq!(pipe.client.set_session(&session), Ok(()));
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert!(!pipe.client.is_established());
 
-        assert_eq!(pipe.client.is_established(), true);
-        assert_eq!(pipe.server.is_established(), true);
+        let err = pipe.client.local_error().unwrap();
+        assert_eq!(err.error_code, 0x171);
 
-        assert_eq!(pipe.client.is_resumed(), true);
-        assert_eq!(pipe.server.is_resumed(), true);
+        server_config.set_ocsp_response(b"ocsp").unwrap();
+
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
     }
 
     #[test]
-    fn handshake_alpn_mismatch() {
-        let mut buf = [0; 65535];
-
+    #[cfg(not(feature = "rustls"))]
+    fn ech_config() {
         let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(b"\x06proto3\x06proto4")
-            .unwrap();
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
 
-        assert_eq!(pipe.client.application_proto(), b"");
-        assert_eq!(pipe.server.application_proto(), b"");
+        assert_eq!(config.set_ech_config_list(b"invalid"), Err(Error::TlsFail));
+        assert_eq!(
+            config.add_ech_key(b"invalid", &[0; 32], true),
+            Err(Error::TlsFail)
+        );
 
-        // Server should only send one packet in response to ALPN mismatch.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        // ECH isn't used by default.
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
-        assert_eq!(pipe.server.sent_count, 1);
+        assert!(!pipe.client.is_ech_accepted());
+        assert!(!pipe.server.is_ech_accepted());
+        assert_eq!(pipe.client.ech_retry_configs(), None);
     }
 
     #[test]
-    fn handshake_0rtt() {
-        let mut buf = [0; 65535];
+    #[cfg(not(feature = "rustls"))]
+    fn ech_accepted() {
+        // ECHConfig for an X25519 key, using "public.example" as public name.
+        let ech_config = [
+            0xfe, 0x0d, 0x00, 0x41, 0x01, 0x00, 0x20, 0x00, 0x20, 0x07, 0xa3,
+            0x7c, 0xbc, 0x14, 0x20, 0x93, 0xc8, 0xb7, 0x55, 0xdc, 0x1b, 0x10,
+            0xe8, 0x6c, 0xb4, 0x26, 0x37, 0x4a, 0xd1, 0x6a, 0xa8, 0x53, 0xed,
+            0x0b, 0xdf, 0xc0, 0xb2, 0xb8, 0x6d, 0x1c, 0x7c, 0x00, 0x08, 0x00,
+            0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x03, 0x00, 0x0e, 0x70, 0x75,
+            0x62, 0x6c, 0x69, 0x63, 0x2e, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c,
+            0x65, 0x00, 0x00,
+        ];
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
+        let private_key: Vec<u8> = (1..=32).collect();
+
+        let mut ech_config_list =
+            (ech_config.len() as u16).to_be_bytes().to_vec();
+        ech_config_list.extend_from_slice(&ech_config);
+
+        let mut client_config = Config::new(PROTOCOL_VERSION).unwrap();
+        client_config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        client_config.verify_peer(false);
+        client_config.set_ech_config_list(&ech_config_list).unwrap();
+
+        let mut server_config = Config::new(PROTOCOL_VERSION).unwrap();
+        server_config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
-        config
+        server_config
             .load_priv_key_from_pem_file("examples/cert.key")
             .unwrap();
-        config
+        server_config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
-        config.verify_peer(false);
+        server_config
+            .add_ech_key(&ech_config, &private_key, true)
+            .unwrap();
 
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
-
-        // Configure session on new connection.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.client.set_session(&session), Ok(()));
-
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert!(pipe.client.is_ech_accepted());
+        assert!(pipe.server.is_ech_accepted());
+        assert_eq!(pipe.client.ech_retry_configs(), None);
 
-        // Client sends 0-RTT packet.
-        let pkt_type = packet::Type::ZeroRTT;
+        // The server saw the inner, encrypted, server name.
+        assert_eq!(pipe.server.server_name(), Some("quic.tech"));
+    }
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, true),
-        }];
+    #[test]
+    #[cfg(not(feature = "rustls"))]
+    fn application_settings() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config
+            .add_application_settings(b"proto1", b"hello")
+            .unwrap();
+        config.verify_peer(false);
 
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Ok(1200)
-        );
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
 
-        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
+        assert_eq!(pipe.client.peer_application_settings(), None);
 
-        // 0-RTT stream data is readable.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
-        assert_eq!(&b[..5], b"aaaaa");
+        assert_eq!(pipe.client.peer_application_settings(), Some(&b"hello"[..]));
+        assert_eq!(pipe.server.peer_application_settings(), Some(&b"hello"[..]));
     }
 
     #[test]
-    fn handshake_0rtt_reordered() {
-        let mut buf = [0; 65535];
-
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+    #[cfg(not(feature = "rustls"))]
+    fn application_settings_not_negotiated() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -6750,68 +11891,38 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
+        config
+            .add_application_settings(b"proto2", b"hello")
+            .unwrap();
         config.verify_peer(false);
 
-        // Perform initial handshake.
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
-
-        // Configure session on new connection.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.client.set_session(&session), Ok(()));
-
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        let mut initial = (&buf[..len]).to_vec();
-
-        // Client sends 0-RTT packet.
-        let pkt_type = packet::Type::ZeroRTT;
-
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, true),
-        }];
-
-        let len =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
-        let mut zrtt = (&buf[..len]).to_vec();
-
-        // 0-RTT packet is received before the Initial one.
-        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
-
-        assert_eq!(pipe.server.undecryptable_pkts.len(), 1);
-        assert_eq!(pipe.server.undecryptable_pkts[0].0.len(), zrtt.len());
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        // Settings were only configured for a protocol that wasn't selected.
+        assert_eq!(pipe.server.application_proto(), b"proto1");
+        assert_eq!(pipe.client.peer_application_settings(), None);
+        assert_eq!(pipe.server.peer_application_settings(), None);
+    }
 
-        // Initial packet is also received.
-        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
+    #[cfg(not(feature = "rustls"))]
+    struct LastOffered;
 
-        // 0-RTT stream data is readable.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+    #[cfg(not(feature = "rustls"))]
+    impl AlpnSelector for LastOffered {
+        fn select<'a>(
+            &self, server_name: Option<&str>, offered: &[&'a [u8]],
+        ) -> Option<&'a [u8]> {
+            assert_eq!(server_name, Some("quic.tech"));
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
-        assert_eq!(&b[..5], b"aaaaa");
+            offered.last().copied()
+        }
     }
 
     #[test]
-    fn handshake_0rtt_truncated() {
-        let mut buf = [0; 65535];
-
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+    #[cfg(not(feature = "rustls"))]
+    fn alpn_selector() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -6825,68 +11936,100 @@ mod tests {
         config.set_initial_max_stream_data_bidi_local(15);
         config.set_initial_max_stream_data_bidi_remote(15);
         config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
         config.verify_peer(false);
+        config.set_alpn_selector(Arc::new(LastOffered)).unwrap();
 
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
+        // The static preference list would have picked "proto1".
+        assert_eq!(pipe.client.application_proto(), b"proto2");
+        assert_eq!(pipe.server.application_proto(), b"proto2");
+    }
 
-        // Configure session on new connection.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.client.set_session(&session), Ok(()));
+    #[test]
+    #[cfg(not(feature = "rustls"))]
+    fn verify_client_cert() {
+        let mut server_config = Config::new(PROTOCOL_VERSION).unwrap();
+        server_config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        server_config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        server_config
+            .load_verify_locations_from_file("examples/rootca.crt")
+            .unwrap();
+        server_config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        server_config.verify_peer_required_client_cert(true);
 
-        // Client sends initial flight.
-        pipe.client.send(&mut buf).unwrap();
+        let mut client_config = Config::new(PROTOCOL_VERSION).unwrap();
+        client_config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        client_config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        client_config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        client_config.verify_peer(false);
 
-        // Client sends 0-RTT packet.
-        let pkt_type = packet::Type::ZeroRTT;
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut server_config,
+        )
+        .unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, true),
-        }];
+        assert!(pipe.server.peer_cert().is_some());
 
-        let len =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
+        // The handshake fails when the client doesn't present a certificate.
+        let mut pipe =
+            testing::Pipe::with_server_config(&mut server_config).unwrap();
+        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
 
-        // Simulate a truncated packet by sending one byte less.
-        let mut zrtt = (&buf[..len - 1]).to_vec();
+        let err = pipe.server.local_error().unwrap();
+        assert_eq!(err.error_code, 0x174);
+        assert_eq!(err.reason, b"peer did not send a certificate");
+    }
 
-        // 0-RTT packet is received before the Initial one.
-        assert_eq!(pipe.server_recv(&mut zrtt), Err(Error::InvalidPacket));
+    #[test]
+    fn config_max_ack_delay() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        assert_eq!(config.local_transport_params.max_ack_delay, 25);
 
-        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
+        config.set_max_ack_delay(100);
+        assert_eq!(config.local_transport_params.max_ack_delay, 100);
 
-        assert!(pipe.server.is_closed());
+        // Invalid values are capped.
+        config.set_max_ack_delay(u64::MAX);
+        assert_eq!(config.local_transport_params.max_ack_delay, MAX_ACK_DELAY);
     }
 
     #[test]
-    /// Tests that a pre-v1 client can connect to a v1-enabled server, by making
-    /// the server downgrade to the pre-v1 version.
-    fn handshake_downgrade_v1() {
-        let mut config = Config::new(PROTOCOL_VERSION_DRAFT29).unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.verify_peer(false);
+    fn config_ack_delay_exponent() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        assert_eq!(config.local_transport_params.ack_delay_exponent, 3);
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        config.set_ack_delay_exponent(0);
+        assert_eq!(config.local_transport_params.ack_delay_exponent, 0);
 
-        assert_eq!(pipe.client.version, PROTOCOL_VERSION_DRAFT29);
-        assert_eq!(pipe.server.version, PROTOCOL_VERSION_DRAFT29);
+        // Invalid values are capped.
+        config.set_ack_delay_exponent(u64::MAX);
+        assert_eq!(
+            config.local_transport_params.ack_delay_exponent,
+            MAX_ACK_DELAY_EXPONENT
+        );
     }
 
     #[test]
-    fn limit_handshake_data() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+    fn peer_ack_delay_params_zero() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
-            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
         config
             .load_priv_key_from_pem_file("examples/cert.key")
@@ -6894,44 +12037,21 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
+        config.set_ack_delay_exponent(0);
+        config.set_max_ack_delay(0);
+        config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
-
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        let client_sent = flight.iter().fold(0, |out, p| out + p.len());
-        testing::process_flight(&mut pipe.server, flight).unwrap();
-
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-        let server_sent = flight.iter().fold(0, |out, p| out + p.len());
-
-        assert_eq!(server_sent, client_sent * MAX_AMPLIFICATION_FACTOR);
-    }
-
-    #[test]
-    fn stream() {
-        let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert!(!pipe.server.stream_finished(4));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
-        assert_eq!(&b[..12], b"hello, world");
-
-        assert!(pipe.server.stream_finished(4));
+        // Values equal to zero are still sent, as the defaults are non-zero.
+        assert_eq!(pipe.client.stats().peer_ack_delay_exponent, 0);
+        assert_eq!(pipe.client.stats().peer_max_ack_delay, 0);
+        assert_eq!(pipe.client.recovery.max_ack_delay, time::Duration::ZERO);
     }
 
     #[test]
-    fn zero_rtt() {
-        let mut buf = [0; 65535];
-
+    fn peer_max_ack_delay() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -6942,1213 +12062,4124 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
+        config.set_max_ack_delay(100);
         config.verify_peer(false);
 
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
-
-        // Configure session on new connection.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.client.set_session(&session), Ok(()));
+        // The peer's value is used when computing the PTO.
+        assert_eq!(
+            pipe.client.recovery.max_ack_delay,
+            time::Duration::from_millis(100)
+        );
+        assert_eq!(pipe.client.stats().peer_max_ack_delay, 100);
+    }
 
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        let mut initial = (&buf[..len]).to_vec();
+    #[test]
+    fn invalid_max_ack_delay() {
+        let mut buf = [0; 65535];
 
-        assert_eq!(pipe.client.is_in_early_data(), true);
+        let mut pipe = testing::Pipe::default().unwrap();
 
-        // Client sends 0-RTT data.
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        // Advertise a max_ack_delay that is too large.
+        pipe.client.local_transport_params.max_ack_delay = MAX_ACK_DELAY + 1;
+        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
 
+        // Client sends initial flight.
         let (len, _) = pipe.client.send(&mut buf).unwrap();
-        let mut zrtt = (&buf[..len]).to_vec();
-
-        // Server receives packets.
-        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
-        assert_eq!(pipe.server.is_in_early_data(), true);
 
-        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
-
-        // 0-RTT stream data is readable.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
-        assert_eq!(&b[..12], b"hello, world");
+        // Server rejects transport parameters.
+        assert_eq!(
+            pipe.server_recv(&mut buf[..len]),
+            Err(Error::InvalidTransportParam)
+        );
     }
 
     #[test]
-    fn stream_send_on_32bit_arch() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(2_u64.pow(32) + 5);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(0);
-        config.verify_peer(false);
+    fn missing_initial_source_connection_id() {
+        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut pipe = testing::Pipe::default().unwrap();
 
-        // In 32bit arch, send_capacity() should be min(2^32+5, cwnd),
-        // not min(5, cwnd)
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        // Reset initial_source_connection_id.
+        pipe.client
+            .local_transport_params
+            .initial_source_connection_id = None;
+        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
 
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        assert!(!pipe.server.stream_finished(4));
+        // Server rejects transport parameters.
+        assert_eq!(
+            pipe.server_recv(&mut buf[..len]),
+            Err(Error::InvalidTransportParam)
+        );
     }
 
     #[test]
-    fn empty_stream_frame() {
+    fn invalid_initial_source_connection_id() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, false),
-        }];
-
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
-
-        let mut readable = pipe.server.readable();
-        assert_eq!(readable.next(), Some(4));
-
-        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((5, false)));
-
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"", 5, true),
-        }];
-
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
-
-        let mut readable = pipe.server.readable();
-        assert_eq!(readable.next(), Some(4));
 
-        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((0, true)));
+        // Scramble initial_source_connection_id.
+        pipe.client
+            .local_transport_params
+            .initial_source_connection_id = Some(b"bogus value".to_vec().into());
+        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"", 15, true),
-        }];
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let pkt_type = packet::Type::Short;
+        // Server rejects transport parameters.
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FinalSize)
+            pipe.server_recv(&mut buf[..len]),
+            Err(Error::InvalidTransportParam)
         );
     }
 
     #[test]
-    /// Tests that receiving a MAX_STREAM_DATA frame for a receive-only
-    /// unidirectional stream is forbidden.
-    fn max_stream_data_receive_uni() {
-        let mut buf = [0; 65535];
-
+    fn handshake() {
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client opens unidirectional stream.
-        assert_eq!(pipe.client.stream_send(2, b"hello", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Client sends MAX_STREAM_DATA on local unidirectional stream.
-        let frames = [frame::Frame::MaxStreamData {
-            stream_id: 2,
-            max: 1024,
-        }];
-
-        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidStreamState(2)),
+            pipe.client.application_proto(),
+            pipe.server.application_proto()
         );
+
+        assert_eq!(pipe.server.server_name(), Some("quic.tech"));
     }
 
     #[test]
-    fn empty_payload() {
-        let mut buf = [0; 65535];
-
+    fn handshake_done() {
         let mut pipe = testing::Pipe::default().unwrap();
+
+        // Disable session tickets on the server (SSL_OP_NO_TICKET) to avoid
+        // triggering 1-RTT packet send with a CRYPTO frame. The rustls backend
+        // never sends them.
+        #[cfg(not(feature = "rustls"))]
+        pipe.server.handshake.set_options(0x0000_4000);
+
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Send a packet with no frames.
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &[], &mut buf),
-            Err(Error::InvalidPacket)
-        );
+        assert!(pipe.server.handshake_done_sent);
     }
 
     #[test]
-    fn min_payload() {
+    fn new_token() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
 
-        // Send a non-ack-eliciting packet.
-        let frames = [frame::Frame::Padding { len: 4 }];
-
-        let pkt_type = packet::Type::Initial;
-        let written =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        // Only servers send tokens, and they can't be empty.
+        assert_eq!(
+            pipe.client.send_new_token(b"token"),
+            Err(Error::InvalidState)
+        );
+        assert_eq!(pipe.server.send_new_token(b""), Err(Error::InvalidState));
 
-        assert_eq!(pipe.server.max_send_bytes, 195);
+        assert_eq!(pipe.server.send_new_token(b"quiche test new token"), Ok(()));
 
-        // Force server to send a single PING frame.
-        pipe.server.recovery.loss_probes[packet::EPOCH_INITIAL] = 1;
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Artifically limit the amount of bytes the server can send.
-        pipe.server.max_send_bytes = 60;
+        assert_eq!(pipe.client.new_token(), Some(&b"quiche test new token"[..]));
+        assert_eq!(pipe.server.new_token(), None);
 
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
-    }
+        // The token is sent in the client's Initial packets of a new connection.
+        let mut pipe = testing::Pipe::default().unwrap();
 
-    #[test]
-    fn flow_control_limit() {
-        let mut buf = [0; 65535];
+        assert_eq!(pipe.server.set_token(b"token"), Err(Error::InvalidState));
+        assert_eq!(pipe.client.token_kind(), None);
+        assert_eq!(pipe.client.set_token(b"quiche test new token"), Ok(()));
+        assert_eq!(pipe.client.token_kind(), Some(TokenKind::NewToken));
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        assert_eq!(hdr.token.unwrap(), b"quiche test new token");
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
-        );
+        assert_eq!(pipe.client.set_token(b"token"), Err(Error::InvalidState));
     }
 
     #[test]
-    fn flow_control_limit_dup() {
+    fn new_token_lost() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
+
         assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let frames = [
-            // One byte less than stream limit.
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaa", 0, false),
-            },
-            // Same stream, but one byte more.
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-        ];
+        assert_eq!(pipe.server.send_new_token(b"token"), Ok(()));
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        // Lose the packet carrying NEW_TOKEN, and trigger a PTO.
+        assert!(pipe.server.send(&mut buf).is_ok());
+        assert_eq!(pipe.server.new_token, None);
+
+        let timer = pipe.server.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+        pipe.server.on_timeout();
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.new_token(), Some(&b"token"[..]));
     }
 
     #[test]
-    fn flow_control_update() {
-        let mut buf = [0; 65535];
-
+    fn handshake_confirmation() {
         let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        // Client sends initial flight.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let pkt_type = packet::Type::Short;
+        // Server sends initial flight.
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
 
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        assert!(!pipe.client.is_established());
+        assert!(!pipe.client.handshake_confirmed);
 
-        pipe.server.stream_recv(4, &mut buf).unwrap();
-        pipe.server.stream_recv(8, &mut buf).unwrap();
+        assert!(!pipe.server.is_established());
+        assert!(!pipe.server.handshake_confirmed);
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 8,
-            data: stream::RangeBuf::from(b"a", 1, false),
-        }];
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        // Client sends Handshake packet and completes handshake.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
 
-        assert!(len > 0);
+        assert!(pipe.client.is_established());
+        assert!(!pipe.client.handshake_confirmed);
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-        let mut iter = frames.iter();
+        assert!(!pipe.server.is_established());
+        assert!(!pipe.server.handshake_confirmed);
 
-        // Ignore ACK.
-        iter.next().unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::MaxStreamData {
-                stream_id: 4,
-                max: 30
-            })
-        );
-        assert_eq!(iter.next(), Some(&frame::Frame::MaxData { max: 61 }));
+        // Server completes handshake and sends HANDSHAKE_DONE.
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+
+        assert!(pipe.client.is_established());
+        assert!(!pipe.client.handshake_confirmed);
+
+        assert!(pipe.server.is_established());
+        assert!(!pipe.server.handshake_confirmed);
+
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        // Client acks 1-RTT packet, and confirms handshake.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+
+        assert!(pipe.client.is_established());
+        assert!(pipe.client.handshake_confirmed);
+
+        assert!(pipe.server.is_established());
+        assert!(!pipe.server.handshake_confirmed);
+
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        // Server handshake is confirmed.
+        assert!(pipe.client.is_established());
+        assert!(pipe.client.handshake_confirmed);
+
+        assert!(pipe.server.is_established());
+        assert!(pipe.server.handshake_confirmed);
     }
 
     #[test]
-    /// Tests that flow control is properly updated even when a stream is shut
-    /// down.
-    fn flow_control_drain() {
-        let mut pipe = testing::Pipe::default().unwrap();
+    #[cfg(not(feature = "rustls"))]
+    fn handshake_resumption() {
+        const SESSION_TICKET_KEY: [u8; 48] = [0xa; 48];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client opens a stream and sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.is_established(), true);
+        assert_eq!(pipe.server.is_established(), true);
 
-        // Server receives data, without reading it.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.is_resumed(), false);
+        assert_eq!(pipe.server.is_resumed(), false);
 
-        // In the meantime, client sends more data.
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", true), Ok(5));
+        // Extract session,
+        let session = pipe.client.session().unwrap();
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", true), Ok(5));
+        // Configure session on new connection and perform handshake.
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
 
-        // Server shuts down one stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.set_session(&session), Ok(()));
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Flush connection.
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.is_established(), true);
+        assert_eq!(pipe.server.is_established(), true);
+
+        assert_eq!(pipe.client.is_resumed(), true);
+        assert_eq!(pipe.server.is_resumed(), true);
     }
 
     #[test]
-    fn stream_flow_control_limit_bidi() {
+    fn handshake_alpn_mismatch() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(b"\x06proto3\x06proto4")
+            .unwrap();
+        config.verify_peer(false);
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaaa", 0, true),
-        }];
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
-        );
+        assert_eq!(pipe.client.application_proto(), b"");
+        assert_eq!(pipe.server.application_proto(), b"");
+
+        // Server should only send one packet in response to ALPN mismatch.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        assert_eq!(pipe.server.sent_count, 1);
     }
 
     #[test]
-    fn stream_flow_control_limit_uni() {
+    #[cfg(not(feature = "rustls"))]
+    fn handshake_0rtt() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
+        // Extract session,
+        let session = pipe.client.session().unwrap();
+
+        // Configure session on new connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(&session), Ok(()));
+
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        // Client sends 0-RTT packet.
+        let pkt_type = packet::Type::ZeroRTT;
+
         let frames = [frame::Frame::Stream {
-            stream_id: 2,
-            data: stream::RangeBuf::from(b"aaaaaaaaaaa", 0, true),
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaa", 0, true),
         }];
 
-        let pkt_type = packet::Type::Short;
         assert_eq!(
             pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
+            Ok(1200)
         );
+
+        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
+
+        // 0-RTT stream data is readable.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"aaaaa");
     }
 
     #[test]
-    fn stream_flow_control_update() {
+    #[cfg(not(feature = "rustls"))]
+    fn handshake_0rtt_reordered() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaaaaaa", 0, false),
-        }];
+        // Extract session,
+        let session = pipe.client.session().unwrap();
 
-        let pkt_type = packet::Type::Short;
+        // Configure session on new connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(&session), Ok(()));
 
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut initial = (&buf[..len]).to_vec();
 
-        pipe.server.stream_recv(4, &mut buf).unwrap();
+        // Client sends 0-RTT packet.
+        let pkt_type = packet::Type::ZeroRTT;
 
         let frames = [frame::Frame::Stream {
             stream_id: 4,
-            data: stream::RangeBuf::from(b"a", 9, false),
+            data: stream::RangeBuf::from(b"aaaaa", 0, true),
         }];
 
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        let len =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+        let mut zrtt = (&buf[..len]).to_vec();
 
-        assert!(len > 0);
+        // 0-RTT packet is received before the Initial one.
+        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-        let mut iter = frames.iter();
+        assert_eq!(pipe.server.undecryptable_pkts.len(), 1);
+        assert_eq!(pipe.server.undecryptable_pkts[0].0.len(), zrtt.len());
 
-        // Ignore ACK.
-        iter.next().unwrap();
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::MaxStreamData {
-                stream_id: 4,
-                max: 24,
-            })
-        );
+        // Initial packet is also received.
+        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
+
+        // 0-RTT stream data is readable.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"aaaaa");
     }
 
     #[test]
-    fn stream_left_bidi() {
+    #[cfg(not(feature = "rustls"))]
+    fn recv_owned() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        assert_eq!(3, pipe.client.peer_streams_left_bidi());
-        assert_eq!(3, pipe.server.peer_streams_left_bidi());
-
-        pipe.server.stream_send(1, b"a", false).ok();
-        assert_eq!(2, pipe.server.peer_streams_left_bidi());
-        pipe.server.stream_send(5, b"a", false).ok();
-        assert_eq!(1, pipe.server.peer_streams_left_bidi());
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
 
-        pipe.server.stream_send(9, b"a", false).ok();
-        assert_eq!(0, pipe.server.peer_streams_left_bidi());
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
+        let session = pipe.client.session().unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(&session), Ok(()));
 
-        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_bidi());
-    }
+        let info = RecvInfo {
+            from: pipe.server.peer_addr,
+        };
 
-    #[test]
-    fn stream_left_uni() {
-        let mut buf = [0; 65535];
+        // No spare buffer yet, so a new one is allocated.
+        assert_eq!(pipe.server.take_recv_buf().capacity(), 0);
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let initial = buf[..len].to_vec();
 
-        assert_eq!(3, pipe.client.peer_streams_left_uni());
-        assert_eq!(3, pipe.server.peer_streams_left_uni());
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaa", 0, true),
+        }];
 
-        pipe.server.stream_send(3, b"a", false).ok();
-        assert_eq!(2, pipe.server.peer_streams_left_uni());
-        pipe.server.stream_send(7, b"a", false).ok();
-        assert_eq!(1, pipe.server.peer_streams_left_uni());
+        let len = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::ZeroRTT,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
+        let zrtt = buf[..len].to_vec();
 
-        pipe.server.stream_send(11, b"a", false).ok();
-        assert_eq!(0, pipe.server.peer_streams_left_uni());
+        // 0-RTT packet is buffered, and its datagram buffer is retained.
+        assert_eq!(pipe.server.recv_owned(zrtt, info), Ok(len));
+        assert_eq!(pipe.server.undecryptable_pkts.len(), 1);
+        assert_eq!(pipe.server.recv_buf_pool.len(), 1);
 
-        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
+        // Once the Initial packet is processed, the buffered 0-RTT packet's
+        // buffer is returned to the pool as well.
+        let initial_len = initial.len();
+        assert_eq!(pipe.server.recv_owned(initial, info), Ok(initial_len));
+        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
+        assert_eq!(pipe.server.recv_buf_pool.len(), 2);
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        let spare = pipe.server.take_recv_buf();
+        assert!(spare.is_empty());
+        assert!(spare.capacity() > 0);
 
-        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_uni());
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"aaaaa");
     }
 
     #[test]
-    fn stream_limit_bidi() {
+    fn recv_owned_stream_data() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(1000);
+        config.set_initial_max_stream_data_bidi_remote(1000);
+        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 16,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 20,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 24,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 28,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        let info = RecvInfo {
+            from: pipe.server.peer_addr,
+        };
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::StreamLimit),
-        );
-    }
+        let pool_len = pipe.server.recv_buf_pool.len();
 
-    #[test]
-    fn stream_limit_max_bidi() {
-        let mut buf = [0; 65535];
+        // In-order data making up most of the datagram references it, so its
+        // buffer is not returned to the pool.
+        assert_eq!(pipe.client.stream_send(4, &[0; 500], false), Ok(500));
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let dgram = buf[..len].to_vec();
 
-        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
+        assert_eq!(pipe.server.recv_owned(dgram, info), Ok(len));
+        assert_eq!(pipe.server.recv_buf_pool.len(), pool_len);
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        // Small chunks are copied instead of pinning the whole datagram, so
+        // the buffer can be reused.
+        assert_eq!(pipe.client.stream_send(8, b"hello", false), Ok(5));
 
-        let frames = [frame::Frame::MaxStreamsBidi {
-            max: MAX_STREAM_ID + 1,
-        }];
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let dgram = buf[..len].to_vec();
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
-        );
-    }
+        assert_eq!(pipe.server.recv_owned(dgram, info), Ok(len));
+        assert_eq!(pipe.server.recv_buf_pool.len(), pool_len + 1);
 
-    #[test]
-    fn stream_limit_uni() {
-        let mut buf = [0; 65535];
+        // Out-of-order data is copied as well.
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"world", 510, false),
+        }];
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let len = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Short,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
+        let dgram = buf[..len].to_vec();
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 2,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 6,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 10,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 14,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 18,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 22,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 26,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        assert_eq!(pipe.server.recv_owned(dgram, info), Ok(len));
+        assert_eq!(pipe.server.recv_buf_pool.len(), pool_len + 2);
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::StreamLimit),
-        );
+        let mut b = [0; 1000];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((500, false)));
+        assert_eq!(pipe.server.stream_recv(8, &mut b), Ok((5, false)));
+        assert_eq!(&b[..5], b"hello");
     }
 
     #[test]
-    fn stream_limit_max_uni() {
+    #[cfg(not(feature = "rustls"))]
+    fn handshake_0rtt_truncated() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
 
-        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        // Extract session,
+        let session = pipe.client.session().unwrap();
 
-        let frames = [frame::Frame::MaxStreamsUni {
-            max: MAX_STREAM_ID + 1,
-        }];
+        // Configure session on new connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(&session), Ok(()));
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
-        );
-    }
+        // Client sends initial flight.
+        pipe.client.send(&mut buf).unwrap();
 
-    #[test]
-    fn streams_blocked_max_bidi() {
-        let mut buf = [0; 65535];
+        // Client sends 0-RTT packet.
+        let pkt_type = packet::Type::ZeroRTT;
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        let frames = [frame::Frame::StreamsBlockedBidi {
-            limit: MAX_STREAM_ID,
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaa", 0, true),
         }];
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        let len =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
 
-        let frames = [frame::Frame::StreamsBlockedBidi {
-            limit: MAX_STREAM_ID + 1,
-        }];
+        // Simulate a truncated packet by sending one byte less.
+        let mut zrtt = (&buf[..len - 1]).to_vec();
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
-        );
+        // 0-RTT packet is received before the Initial one.
+        assert_eq!(pipe.server_recv(&mut zrtt), Err(Error::InvalidPacket));
+
+        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
+
+        assert!(pipe.server.is_closed());
     }
 
     #[test]
-    fn streams_blocked_max_uni() {
-        let mut buf = [0; 65535];
+    fn handshake_reordered() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        let frames = [frame::Frame::StreamsBlockedUni {
-            limit: MAX_STREAM_ID,
-        }];
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        // The server's flight spans multiple datagrams, and only the first
+        // one carries the Initial packet.
+        let mut flight = testing::emit_flight(&mut pipe.server).unwrap();
+        assert!(flight.len() > 1);
 
-        let frames = [frame::Frame::StreamsBlockedUni {
-            limit: MAX_STREAM_ID + 1,
-        }];
+        let initial = flight.remove(0);
+        let handshake_pkts = flight.len();
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
-        );
+        // Handshake packets received before the Initial one are buffered.
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+        assert_eq!(pipe.client.undecryptable_pkts.len(), handshake_pkts);
+
+        // Once the Initial packet is processed, the buffered packets are too.
+        testing::process_flight(&mut pipe.client, vec![initial]).unwrap();
+        assert_eq!(pipe.client.undecryptable_pkts.len(), 0);
+
+        assert_eq!(pipe.handshake(), Ok(()));
     }
 
     #[test]
-    fn stream_data_overlap() {
-        let mut buf = [0; 65535];
+    fn handshake_reordered_buffering_disabled() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut client_config = Config::new(PROTOCOL_VERSION).unwrap();
+        client_config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        client_config.set_max_undecryptable_packets(0);
+        client_config.verify_peer(false);
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"bbbbb", 3, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"ccccc", 6, false),
-            },
-        ];
+        let mut pipe = testing::Pipe::with_client_and_server_config(
+            &mut client_config,
+            &mut config,
+        )
+        .unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
-        assert_eq!(&b[..11], b"aaaaabbbccc");
+        let mut flight = testing::emit_flight(&mut pipe.server).unwrap();
+        let initial = flight.remove(0);
+
+        // Handshake packets received before the Initial one are dropped.
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+        assert_eq!(pipe.client.undecryptable_pkts.len(), 0);
+
+        // Only the Initial packet can be processed.
+        testing::process_flight(&mut pipe.client, vec![initial]).unwrap();
+        assert!(!pipe.client.is_established());
     }
 
     #[test]
-    fn stream_data_overlap_with_reordering() {
-        let mut buf = [0; 65535];
+    /// Tests that a pre-v1 client can connect to a v1-enabled server, by making
+    /// the server downgrade to the pre-v1 version.
+    fn handshake_downgrade_v1() {
+        let mut config = Config::new(PROTOCOL_VERSION_DRAFT29).unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"ccccc", 6, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"bbbbb", 3, false),
-            },
-        ];
-
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
-
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
-        assert_eq!(&b[..11], b"aaaaabccccc");
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_DRAFT29);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_DRAFT29);
     }
 
     #[test]
-    /// Tests that receiving a valid RESET_STREAM frame when all data has
-    /// already been read, notifies the application.
-    fn reset_stream_data_recvd() {
-        let mut b = [0; 15];
-        let mut buf = [0; 65535];
-
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+    fn limit_handshake_data() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        // Server gets data and sends data back, closing stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        let client_sent = flight.iter().fold(0, |out, p| out + p.len());
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, false)));
-        assert!(!pipe.server.stream_finished(4));
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        let server_sent = flight.iter().fold(0, |out, p| out + p.len());
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(server_sent, client_sent * MAX_AMPLIFICATION_FACTOR);
+    }
 
-        assert_eq!(pipe.server.stream_send(4, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+    #[test]
+    fn server_name_cert_selection() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        let default_cert = pipe.client.peer_cert().unwrap();
 
-        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((0, true)));
-        assert!(pipe.client.stream_finished(4));
+        for (name, selected) in &[
+            ("quic.tech", true),
+            ("*.tech", true),
+            ("example.com", false),
+        ] {
+            let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+            config
+                .load_cert_chain_from_pem_file("examples/cert.crt")
+                .unwrap();
+            config
+                .load_priv_key_from_pem_file("examples/cert.key")
+                .unwrap();
+            config
+                .set_application_protos(b"\x06proto1\x06proto2")
+                .unwrap();
 
-        // Client sends RESET_STREAM, closing stream.
-        let frames = [frame::Frame::ResetStream {
-            stream_id: 4,
-            error_code: 42,
-            final_size: 5,
-        }];
+            config
+                .add_cert_for_name(
+                    name,
+                    "examples/cert-alt.crt",
+                    "examples/cert.key",
+                )
+                .unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+            let mut pipe =
+                testing::Pipe::with_server_config(&mut config).unwrap();
+            assert_eq!(pipe.handshake(), Ok(()));
 
-        // Server is notified of stream readability, due to reset.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+            let cert = pipe.client.peer_cert().unwrap();
+            assert_eq!(cert != default_cert, *selected);
+        }
 
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         assert_eq!(
-            pipe.server.stream_recv(4, &mut b),
-            Err(Error::StreamReset(42))
+            config.add_cert_for_name(
+                "quic.tech",
+                "examples/missing.crt",
+                "examples/cert.key"
+            ),
+            Err(Error::TlsFail)
         );
-
-        assert!(pipe.server.stream_finished(4));
-
-        // Sending RESET_STREAM again shouldn't make stream readable again.
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
     }
 
     #[test]
-    /// Tests that receiving a valid RESET_STREAM frame when all data has _not_
-    /// been read, discards all buffered data and notifies the application.
-    fn reset_stream_data_not_recvd() {
-        let mut b = [0; 15];
-        let mut buf = [0; 65535];
+    fn custom_crypto_provider() {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::atomic::Ordering;
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        struct CountingProvider(AtomicUsize);
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"h", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        impl CryptoProvider for CountingProvider {
+            fn new_packet_key(
+                &self, alg: Algorithm, key: &[u8], iv: &[u8],
+            ) -> Result<Box<dyn PacketKey>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
 
-        // Server gets data and sends data back, closing stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+                crypto::DefaultCryptoProvider.new_packet_key(alg, key, iv)
+            }
+        }
 
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((1, false)));
-        assert!(!pipe.server.stream_finished(4));
+        let provider = Arc::new(CountingProvider(AtomicUsize::new(0)));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_crypto_provider(provider.clone());
 
-        assert_eq!(pipe.server.stream_send(4, b"", true), Ok(0));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut r = pipe.client.readable();
+        let mut b = [0; 5];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b, b"hello");
+
+        // An opening and a sealing key for Initial packets, and with
+        // BoringSSL, for Handshake and 1-RTT packets too.
+        let expected = if cfg!(feature = "rustls") { 2 } else { 6 };
+        assert_eq!(provider.0.load(Ordering::SeqCst), expected);
+    }
+
+    #[test]
+    fn custom_clock() {
+        let clock = Arc::new(testing::ManualClock::new(time::Instant::now()));
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_max_idle_timeout(5000);
+        config.set_clock(clock.clone());
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Time doesn't pass unless the clock is advanced.
+        let timeout = pipe.server.timeout().unwrap();
+        assert!(timeout > time::Duration::ZERO);
+        assert_eq!(pipe.server.timeout(), Some(timeout));
+
+        pipe.server.on_timeout();
+        assert!(!pipe.server.is_closed());
+
+        clock.advance(time::Duration::from_secs(5));
+        assert_eq!(pipe.server.timeout(), Some(time::Duration::ZERO));
+
+        pipe.server.on_timeout();
+        assert!(pipe.server.is_closed());
+        assert!(pipe.server.is_timed_out());
+    }
+
+    #[test]
+    fn stream() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(!pipe.server.stream_finished(4));
+
+        let mut r = pipe.server.readable();
         assert_eq!(r.next(), Some(4));
         assert_eq!(r.next(), None);
 
-        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((0, true)));
-        assert!(pipe.client.stream_finished(4));
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
+        assert_eq!(&b[..12], b"hello, world");
 
-        // Client sends RESET_STREAM, closing stream.
-        let frames = [frame::Frame::ResetStream {
+        assert!(pipe.server.stream_finished(4));
+    }
+
+    #[test]
+    #[cfg(not(feature = "rustls"))]
+    fn zero_rtt() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Extract session,
+        let session = pipe.client.session().unwrap();
+
+        // Configure session on new connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(&session), Ok(()));
+
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut initial = (&buf[..len]).to_vec();
+
+        assert_eq!(pipe.client.is_in_early_data(), true);
+
+        // Client sends 0-RTT data.
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut zrtt = (&buf[..len]).to_vec();
+
+        // Server receives packets.
+        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
+        assert_eq!(pipe.server.is_in_early_data(), true);
+
+        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
+
+        // 0-RTT stream data is readable.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
+        assert_eq!(&b[..12], b"hello, world");
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(pipe.client.is_established());
+        assert!(pipe.client.is_resumed());
+        assert!(pipe.client.is_early_data_accepted());
+    }
+
+    #[test]
+    fn stream_send_on_32bit_arch() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(2_u64.pow(32) + 5);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(0);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // In 32bit arch, send_capacity() should be min(2^32+5, cwnd),
+        // not min(5, cwnd)
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(!pipe.server.stream_finished(4));
+    }
+
+    #[test]
+    fn empty_stream_frame() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::Stream {
             stream_id: 4,
-            error_code: 42,
-            final_size: 5,
+            data: stream::RangeBuf::from(b"aaaaa", 0, false),
         }];
 
         let pkt_type = packet::Type::Short;
         assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
 
-        // Server is notified of stream readability, due to reset.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        let mut readable = pipe.server.readable();
+        assert_eq!(readable.next(), Some(4));
 
-        assert_eq!(
-            pipe.server.stream_recv(4, &mut b),
-            Err(Error::StreamReset(42))
-        );
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((5, false)));
 
-        assert!(pipe.server.stream_finished(4));
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"", 5, true),
+        }];
 
-        // Sending RESET_STREAM again shouldn't make stream readable again.
+        let pkt_type = packet::Type::Short;
         assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        let mut readable = pipe.server.readable();
+        assert_eq!(readable.next(), Some(4));
+
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((0, true)));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"", 15, true),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FinalSize(4))
+        );
     }
 
     #[test]
-    /// Tests that RESET_STREAM frames exceeding the connection-level flow
-    /// control limit cause an error.
-    fn reset_stream_flow_control() {
+    /// Tests that receiving a MAX_STREAM_DATA frame for a receive-only
+    /// unidirectional stream is forbidden.
+    fn max_stream_data_receive_uni() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::ResetStream {
-                stream_id: 8,
-                error_code: 0,
-                final_size: 15,
-            },
-            frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        // Client opens unidirectional stream.
+        assert_eq!(pipe.client.stream_send(2, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Client sends MAX_STREAM_DATA on local unidirectional stream.
+        let frames = [frame::Frame::MaxStreamData {
+            stream_id: 2,
+            max: 1024,
+        }];
 
         let pkt_type = packet::Type::Short;
         assert_eq!(
             pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
+            Err(Error::InvalidStreamState(2)),
         );
     }
 
     #[test]
-    /// Tests that RESET_STREAM frames exceeding the stream-level flow control
-    /// limit cause an error.
-    fn reset_stream_flow_control_stream() {
+    fn empty_payload() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::ResetStream {
-                stream_id: 4,
-                error_code: 0,
-                final_size: 16, // Past stream's flow control limit.
-            },
-        ];
+        // Send a packet with no frames.
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &[], &mut buf),
+            Err(Error::InvalidPacket)
+        );
+    }
+
+    #[test]
+    fn min_payload() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // Send a non-ack-eliciting packet.
+        let frames = [frame::Frame::Padding { len: 4 }];
+
+        let pkt_type = packet::Type::Initial;
+        let written =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+
+        assert_eq!(pipe.server.max_send_bytes, 195);
+
+        // Force server to send a single PING frame.
+        pipe.server.recovery.loss_probes[packet::EPOCH_INITIAL] = 1;
+
+        // Artifically limit the amount of bytes the server can send.
+        pipe.server.max_send_bytes = 60;
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+    }
+
+    #[test]
+    fn anti_amplification_limit() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // The limit doesn't apply to clients.
+        assert_eq!(pipe.client.anti_amplification_budget(), None);
+        assert!(!pipe.client.is_anti_amplification_limited());
+
+        // Nothing received by the server yet.
+        assert_eq!(pipe.server.anti_amplification_budget(), Some(0));
+        assert!(pipe.server.is_anti_amplification_limited());
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+
+        testing::recv_send(&mut pipe.server, &mut buf, len).unwrap();
+
+        // The server can send up to 3 times the amount of data received.
+        let sent = pipe.server.stats().sent_bytes as usize;
+        assert_eq!(
+            pipe.server.anti_amplification_budget(),
+            Some(MIN_CLIENT_INITIAL_LEN * MAX_AMPLIFICATION_FACTOR - sent)
+        );
+
+        // Repeated attempts to send while limited are only counted once.
+        pipe.server.max_send_bytes = 60;
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+
+        assert_eq!(pipe.server.stats().amplification_limited, 1);
+
+        // Once the handshake completes the client's address is validated.
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.server.anti_amplification_budget(), None);
+        assert!(!pipe.server.is_anti_amplification_limited());
+    }
+
+    #[test]
+    fn stats_congestion_control() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let stats = pipe.client.stats();
+        assert_eq!(stats.cc_state, CongestionState::SlowStart);
+        assert_eq!(stats.ssthresh, usize::MAX);
+        assert_eq!(stats.congestion_events, 0);
+        assert_eq!(stats.pto, 0);
+        assert_eq!(stats.spurious_lost, 0);
+        assert!(stats.pacing_rate > 0);
+
+        // Lose a packet, and trigger a PTO.
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+
+        let mut buf = [0; 65535];
+        assert!(pipe.client.send(&mut buf).is_ok());
+
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
+        pipe.client.on_timeout();
+
+        assert_eq!(pipe.client.stats().pto, 1);
+    }
+
+    #[test]
+    fn stats_history() {
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // History is disabled by default.
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert!(pipe.client.stats_history().is_empty());
+
+        pipe.client.stats_history_len = 2;
+
+        let now = time::Instant::now();
+
+        pipe.client.update_stats_history(now);
+        assert_eq!(pipe.client.stats_history().len(), 1);
+
+        // Snapshots aren't taken more often than the history interval.
+        pipe.client
+            .update_stats_history(now + time::Duration::from_millis(50));
+        assert_eq!(pipe.client.stats_history().len(), 1);
+
+        pipe.client
+            .update_stats_history(now + STATS_HISTORY_INTERVAL);
+        assert_eq!(pipe.client.stats_history().len(), 2);
+
+        // Only the most recent snapshots are kept.
+        pipe.client
+            .update_stats_history(now + STATS_HISTORY_INTERVAL * 2);
+
+        let history = pipe.client.stats_history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].time, now + STATS_HISTORY_INTERVAL);
+        assert_eq!(history[1].time, now + STATS_HISTORY_INTERVAL * 2);
+        assert_eq!(history[1].sent_bytes, pipe.client.sent_bytes);
+        assert_eq!(history[1].cwnd, pipe.client.recovery.cwnd());
+    }
+
+    #[test]
+    #[cfg(feature = "qlog")]
+    fn qlog_filtering() {
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        assert!(pipe.client.qlog.is_enabled(QLOG_PACKET_TX));
+        assert!(pipe.client.qlog.is_enabled(QLOG_METRICS));
+
+        pipe.client.set_qlog_categories(&[QlogCategory::Recovery]);
+
+        assert!(!pipe.client.qlog.is_enabled(QLOG_PACKET_TX));
+        assert!(!pipe.client.qlog.is_enabled(QLOG_DATA_MV));
+        assert!(pipe.client.qlog.is_enabled(QLOG_METRICS));
+
+        pipe.client.set_qlog_categories(&[
+            QlogCategory::Transport,
+            QlogCategory::Recovery,
+        ]);
+        pipe.client.set_qlog_packet_sampling(3);
+
+        // Only one out of every 3 packets is logged.
+        let mut logged = Vec::new();
+
+        for _ in 0..6 {
+            pipe.client.qlog.on_packet();
+            logged.push(pipe.client.qlog.is_enabled(QLOG_PACKET_TX));
+
+            // Other events are not sampled.
+            assert!(pipe.client.qlog.is_enabled(QLOG_DATA_MV));
+        }
+
+        assert_eq!(logged, [true, false, false, true, false, false]);
+    }
+
+    #[test]
+    #[cfg(feature = "qlog")]
+    fn qlog_connection_events() {
+        #[derive(Clone, Default)]
+        struct SharedBuf(alloc::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for SharedBuf {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        let buf = SharedBuf::default();
+
+        pipe.client.set_qlog_with_level(
+            Box::new(buf.clone()),
+            "title".to_string(),
+            "description".to_string(),
+            QlogLevel::Extra,
+        );
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let log = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+
+        assert!(log.contains("transport:datagrams_sent"));
+        assert!(log.contains("transport:datagrams_received"));
+        assert!(log.contains("connectivity:connection_id_updated"));
+        assert!(log.contains("\"key_type\":\"server_handshake_secret\""));
+        assert!(log.contains("\"key_type\":\"client_1rtt_secret\""));
+        assert!(log.contains("recovery:parameters_set"));
+    }
+
+    #[test]
+    fn path_stats() {
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // The client's address isn't validated by the server yet.
+        let paths: Vec<PathStats> = pipe.server.path_stats().collect();
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].active);
+        assert!(!paths[0].validated);
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let paths: Vec<PathStats> = pipe.server.path_stats().collect();
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].validated);
+
+        let stats = pipe.client.stats();
+
+        let paths: Vec<PathStats> = pipe.client.path_stats().collect();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].peer_addr, pipe.client.peer_addr);
+        assert!(paths[0].active);
+        assert!(paths[0].validated);
+        assert_eq!(paths[0].rtt, stats.rtt);
+        assert_eq!(paths[0].cwnd, stats.cwnd);
+        assert_eq!(paths[0].pmtu, stats.pmtu);
+        assert_eq!(
+            paths[0].bytes_in_flight,
+            pipe.client.recovery.bytes_in_flight()
+        );
+    }
+
+    #[test]
+    fn resume_state() {
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // No RTT sample yet.
+        assert_eq!(pipe.client.resume_state(), None);
+
+        let state = ResumeState {
+            peer_addr: pipe.client.peer_addr,
+            cwnd: 100_000,
+            rtt: time::Duration::from_millis(50),
+        };
+
+        // The state belongs to another peer.
+        let other = ResumeState {
+            peer_addr: "192.0.2.1:443".parse().unwrap(),
+            ..state
+        };
+        assert_eq!(
+            pipe.client.set_resume_state(&other),
+            Err(Error::InvalidState)
+        );
+
+        assert_eq!(pipe.client.set_resume_state(&state), Ok(()));
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let saved = pipe.client.resume_state().unwrap();
+        assert_eq!(saved.peer_addr, pipe.client.peer_addr);
+        assert_eq!(saved.cwnd, pipe.client.stats().cwnd);
+        assert_eq!(saved.rtt, pipe.client.recovery.min_rtt());
+
+        // Too late to seed the congestion controller.
+        assert_eq!(
+            pipe.client.set_resume_state(&state),
+            Err(Error::InvalidState)
+        );
+    }
+
+    #[test]
+    fn new_scid() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.available_dcids(), 0);
+
+        let scid = ConnectionId::from_ref(&[0xba; 16]);
+        assert_eq!(pipe.server.new_scid(&scid, 0xba), Ok(1));
+        assert_eq!(pipe.server.new_scid(&scid, 0xba), Ok(1));
+
+        // The client only accepts one spare connection ID.
+        let other = ConnectionId::from_ref(&[0xbb; 16]);
+        assert_eq!(pipe.server.new_scid(&other, 0xbb), Err(Error::IdLimit));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.available_dcids(), 1);
+    }
+
+    #[test]
+    fn retire_prior_to() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let scid = pipe.client.scid.clone();
+
+        // Ask the server to retire the connection ID it is using.
+        let frames = [frame::Frame::NewConnectionId {
+            seq_num: 1,
+            retire_prior_to: 1,
+            conn_id: vec![0xba; 16],
+            reset_token: [0xba; 16],
+        }];
+
+        let pkt_type = packet::Type::Short;
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        assert_eq!(pipe.server.dcid, ConnectionId::from_ref(&[0xba; 16]));
+
+        // The server retires the old connection ID.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        assert_eq!(pipe.client.retired_scid_next(), Some(scid));
+        assert_eq!(pipe.client.retired_scid_next(), None);
+
+        // Connection IDs can't be retired before they are issued.
+        let frames = [frame::Frame::RetireConnectionId { seq_num: 1 }];
+
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidPacket)
+        );
+    }
+
+    #[test]
+    fn probe_path() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        let local = "127.0.0.1:5678".parse().unwrap();
+        let peer = pipe.client.peer_addr;
+
+        assert_eq!(
+            pipe.client.probe_path(local, peer),
+            Err(Error::InvalidState)
+        );
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // The server hasn't issued any spare connection ID yet.
+        assert_eq!(
+            pipe.client.probe_path(local, peer),
+            Err(Error::OutOfIdentifiers)
+        );
+
+        let scid = ConnectionId::from_ref(&[0xba; 16]);
+        assert_eq!(pipe.server.new_scid(&scid, 0xba), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.probe_path(local, peer), Ok(1));
+        assert_eq!(pipe.client.available_dcids(), 0);
+
+        let (len, info) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(info.from, Some(local));
+        assert_eq!(info.to, peer);
+
+        // The server answers on the path the challenge was received on.
+        let info = RecvInfo { from: local };
+        assert_eq!(pipe.server.recv(&mut buf[..len], info), Ok(len));
+
+        let (len, info) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(info.from, None);
+        assert_eq!(info.to, local);
+
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(local, peer))
+        );
+        assert_eq!(pipe.client.path_event_next(), None);
+
+        // Probing the path again reuses its connection ID.
+        assert_eq!(pipe.client.probe_path(local, peer), Ok(1));
+
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(local, peer))
+        );
+    }
+
+    #[test]
+    fn probe_path_failed() {
+        let mut buf = [0; 65535];
+
+        let clock = Arc::new(testing::ManualClock::new(time::Instant::now()));
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_max_idle_timeout(180_000);
+        config.verify_peer(false);
+        config.set_clock(clock.clone());
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let scid = ConnectionId::from_ref(&[0xba; 16]);
+        assert_eq!(pipe.server.new_scid(&scid, 0xba), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let local = "127.0.0.1:5678".parse().unwrap();
+        let peer = pipe.client.peer_addr;
+
+        assert_eq!(pipe.client.probe_path(local, peer), Ok(1));
+
+        // Drop all challenges.
+        for _ in 0..MAX_PATH_CHALLENGES {
+            let (_, info) = pipe.client.send(&mut buf).unwrap();
+            assert_eq!(info.from, Some(local));
+
+            clock.advance(time::Duration::from_secs(1));
+            pipe.client.on_timeout();
+        }
+
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::FailedValidation(local, peer))
+        );
+
+        // The connection ID is retired rather than used on another path.
+        assert_eq!(pipe.client.available_dcids(), 0);
+        assert_eq!(
+            pipe.client.probe_path(local, peer),
+            Err(Error::OutOfIdentifiers)
+        );
+
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.retired_scid_next(), Some(scid.into_owned()));
+    }
+
+    #[test]
+    fn nat_rebinding() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let old = pipe.server.peer_addr;
+        let new = "127.0.0.1:5678".parse().unwrap();
+
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut first = buf[..len].to_vec();
+
+        assert_eq!(pipe.client.stream_send(4, b"b", false), Ok(1));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut second = buf[..len].to_vec();
+
+        // The second packet arrives first, through a new NAT binding.
+        let info = RecvInfo { from: new };
+        assert_eq!(pipe.server.recv(&mut second, info), Ok(second.len()));
+        assert_eq!(pipe.server.peer_addr, new);
+
+        // A reordered packet doesn't move the connection back.
+        let info = RecvInfo { from: old };
+        assert_eq!(pipe.server.recv(&mut first, info), Ok(first.len()));
+        assert_eq!(pipe.server.peer_addr, new);
+
+        // Only the port changed, so the RTT estimate is kept.
+        assert!(!pipe.server.recovery.min_rtt().is_zero());
+
+        // The new address is challenged, within the anti-amplification limit.
+        let budget = (first.len() + second.len()) * MAX_AMPLIFICATION_FACTOR;
+        assert_eq!(pipe.server.anti_amplification_budget(), Some(budget));
+
+        let (len, info) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(info.to, new);
+        assert_eq!(len, budget);
+
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.server.path_event_next(), None);
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let info = RecvInfo { from: new };
+        assert_eq!(pipe.server.recv(&mut buf[..len], info), Ok(len));
+
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::PeerMigrated(old, new))
+        );
+        assert_eq!(pipe.server.anti_amplification_budget(), None);
+    }
+
+    #[test]
+    fn nat_rebinding_probing() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let old = pipe.server.peer_addr;
+        let new = "127.0.0.1:5678".parse().unwrap();
+
+        let frames = [frame::Frame::PathChallenge { data: [0xba; 8] }];
+
+        let pkt_type = packet::Type::Short;
+        let len =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+
+        // Probing packets don't move the connection.
+        let info = RecvInfo { from: new };
+        assert_eq!(pipe.server.recv(&mut buf[..len], info), Ok(len));
+        assert_eq!(pipe.server.peer_addr, old);
+        assert_eq!(pipe.server.anti_amplification_budget(), None);
+    }
+
+    #[test]
+    fn path_challenge_from_many_addresses() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::PathChallenge { data: [0xba; 8] }];
+
+        let pkt_type = packet::Type::Short;
+
+        for i in 0..MAX_PATH_RESPONSES + 1 {
+            let len = testing::encode_pkt(
+                &mut pipe.client,
+                pkt_type,
+                &frames,
+                &mut buf,
+            )
+            .unwrap();
+
+            let from = format!("127.0.0.1:{}", 5000 + i).parse().unwrap();
+            let info = RecvInfo { from };
+            assert_eq!(pipe.server.recv(&mut buf[..len], info), Ok(len));
+        }
+
+        // Challenges from too many addresses are not answered.
+        assert_eq!(pipe.server.path_responses.len(), MAX_PATH_RESPONSES);
+
+        for _ in 0..MAX_PATH_RESPONSES {
+            let (_, info) = pipe.server.send(&mut buf).unwrap();
+            assert_ne!(info.to, pipe.server.peer_addr);
+        }
+
+        assert!(pipe.server.path_responses.is_empty());
+    }
+
+    #[test]
+    fn nat_rebinding_failed() {
+        let mut buf = [0; 65535];
+
+        let clock = Arc::new(testing::ManualClock::new(time::Instant::now()));
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_max_idle_timeout(180_000);
+        config.set_clock(clock.clone());
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let old = pipe.server.peer_addr;
+        let new = "127.0.0.2:5678".parse().unwrap();
+
+        for _ in 0..MAX_PATH_CHALLENGES {
+            // Keep the anti-amplification limit from blocking challenges.
+            assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+            let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+            let info = RecvInfo { from: new };
+            assert_eq!(pipe.server.recv(&mut buf[..len], info), Ok(len));
+            assert_eq!(pipe.server.peer_addr, new);
+
+            // The client changed IP address, so the RTT estimate is reset.
+            assert!(pipe.server.recovery.min_rtt().is_zero());
+
+            // Drop the challenge.
+            let (_, info) = pipe.server.send(&mut buf).unwrap();
+            assert_eq!(info.to, new);
+
+            clock.advance(time::Duration::from_secs(1));
+            pipe.server.on_timeout();
+        }
+
+        // The server goes back to the previous address.
+        assert_eq!(pipe.server.peer_addr, old);
+        assert_eq!(pipe.server.anti_amplification_budget(), None);
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::PeerMigrationFailed(old, new))
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
+    }
+
+    #[test]
+    fn keep_alive() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // Keep-alive doesn't start before the handshake completes.
+        pipe.client.set_keep_alive(time::Duration::from_millis(100));
+        assert_eq!(pipe.client.keep_alive_timer, None);
+
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        pipe.client.set_keep_alive(time::Duration::from_millis(100));
+        assert!(pipe.client.keep_alive_timer.is_some());
+        assert!(
+            pipe.client.timeout().unwrap() <= time::Duration::from_millis(100)
+        );
+
+        // Nothing to send until the keep-alive timer expires.
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+
+        pipe.client.keep_alive_timer = Some(time::Instant::now());
+        pipe.client.on_timeout();
+        assert!(pipe.client.keep_alive_pending);
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
+        assert!(frames.contains(&frame::Frame::Ping));
+
+        assert!(!pipe.client.keep_alive_pending);
+        assert!(pipe.client.keep_alive_timer.is_some());
+
+        // Disabling keep-alive clears the timer.
+        pipe.client.set_keep_alive(time::Duration::ZERO);
+        assert_eq!(pipe.client.keep_alive_timer, None);
+    }
+
+    #[test]
+    fn timeout_instant() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let (at, reason) = pipe.client.timeout_instant().unwrap();
+        assert_eq!(reason, TimeoutReason::Idle);
+        assert_eq!(Some(at), pipe.client.idle_timer);
+
+        pipe.client.set_keep_alive(time::Duration::from_millis(100));
+        assert_eq!(
+            pipe.client.timeout_instant(),
+            pipe.client
+                .keep_alive_timer
+                .map(|t| (t, TimeoutReason::KeepAlive))
+        );
+
+        let now = time::Instant::now();
+
+        assert_eq!(pipe.client.stream_set_deadline(4, now, 0), Ok(()));
+        assert_eq!(
+            pipe.client.timeout_instant(),
+            Some((now, TimeoutReason::StreamDeadline))
+        );
+        assert_eq!(pipe.client.timeout(), Some(time::Duration::ZERO));
+
+        // Draining takes precedence over everything else.
+        assert_eq!(pipe.server.close(false, 0x0, b""), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.client.timeout_instant(),
+            pipe.client
+                .draining_timer
+                .map(|t| (t, TimeoutReason::Draining))
+        );
+    }
+
+    #[test]
+    fn runtime_flow_control_update() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, false)));
+
+        // Unknown streams and streams that can't receive data are rejected.
+        assert_eq!(
+            pipe.server.set_stream_max_data(8, 100),
+            Err(Error::InvalidStreamState(8))
+        );
+        assert_eq!(
+            pipe.server.set_stream_max_data(3, 100),
+            Err(Error::InvalidStreamState(3))
+        );
+
+        pipe.server.set_max_data(1000);
+        assert_eq!(pipe.server.set_stream_max_data(4, 100), Ok(()));
+
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let mut pkt = buf[..len].to_vec();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut pkt, len).unwrap();
+
+        assert!(frames.contains(&frame::Frame::MaxStreamData {
+            stream_id: 4,
+            max: 105,
+        }));
+        assert!(frames.contains(&frame::Frame::MaxData { max: 1005 }));
+
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        // The client can now send more than the initial limits allowed.
+        let data = [0xa; 100];
+        assert_eq!(pipe.client.stream_send(4, &data, false), Ok(100));
+    }
+
+    #[test]
+    fn runtime_stream_flow_control_update() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        pipe.server.set_max_data(1000);
+        pipe.server.set_max_stream_data_bidi_remote(100);
+
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let mut pkt = buf[..len].to_vec();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut pkt, len).unwrap();
+
+        // Only the new stream gets the larger window.
+        assert!(frames.contains(&frame::Frame::MaxStreamData {
+            stream_id: 8,
+            max: 100,
+        }));
+        assert!(!frames.iter().any(|f| matches!(
+            f,
+            frame::Frame::MaxStreamData { stream_id: 4, .. }
+        )));
+
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        let data = [0xa; 95];
+        assert_eq!(pipe.client.stream_send(8, &data, false), Ok(95));
+    }
+
+    #[test]
+    fn runtime_idle_timeout_update() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(
+            pipe.client.idle_timeout(),
+            Some(time::Duration::from_millis(180_000))
+        );
+
+        pipe.client.set_max_idle_timeout(5_000);
+        assert_eq!(
+            pipe.client.idle_timeout(),
+            Some(time::Duration::from_millis(5_000))
+        );
+        assert!(
+            pipe.client.timeout().unwrap() <= time::Duration::from_millis(5_000)
+        );
+
+        // The peer's value still applies when the local timeout is disabled.
+        pipe.client.set_max_idle_timeout(0);
+        assert_eq!(
+            pipe.client.idle_timeout(),
+            Some(time::Duration::from_millis(180_000))
+        );
+    }
+
+    #[test]
+    fn flow_control_limit() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl(12)),
+        );
+    }
+
+    #[test]
+    fn flow_control_limit_dup() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            // One byte less than stream limit.
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaa", 0, false),
+            },
+            // Same stream, but one byte more.
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+    }
+
+    #[test]
+    fn flow_control_update() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        pipe.server.stream_recv(4, &mut buf).unwrap();
+        pipe.server.stream_recv(8, &mut buf).unwrap();
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 8,
+            data: stream::RangeBuf::from(b"a", 1, false),
+        }];
+
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        assert!(len > 0);
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        let mut iter = frames.iter();
+
+        // Ignore ACK.
+        iter.next().unwrap();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::MaxStreamData {
+                stream_id: 4,
+                max: 30
+            })
+        );
+        assert_eq!(iter.next(), Some(&frame::Frame::MaxData { max: 61 }));
+    }
+
+    #[test]
+    /// Tests that flow control is properly updated even when a stream is shut
+    /// down.
+    fn flow_control_drain() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client opens a stream and sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server receives data, without reading it.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        // In the meantime, client sends more data.
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", true), Ok(5));
+
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", true), Ok(5));
+
+        // Server shuts down one stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        // Flush connection.
+        assert_eq!(pipe.advance(), Ok(()));
+    }
+
+    #[test]
+    fn stream_flow_control_limit_bidi() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaaa", 0, true),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl(4)),
+        );
+    }
+
+    #[test]
+    fn stream_flow_control_limit_uni() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 2,
+            data: stream::RangeBuf::from(b"aaaaaaaaaaa", 0, true),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl(2)),
+        );
+    }
+
+    #[test]
+    fn stream_flow_control_update() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaaaaaa", 0, false),
+        }];
+
+        let pkt_type = packet::Type::Short;
+
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        pipe.server.stream_recv(4, &mut buf).unwrap();
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"a", 9, false),
+        }];
+
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        assert!(len > 0);
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        let mut iter = frames.iter();
+
+        // Ignore ACK.
+        iter.next().unwrap();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::MaxStreamData {
+                stream_id: 4,
+                max: 24,
+            })
+        );
+    }
+
+    #[test]
+    fn stream_left_bidi() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(3, pipe.client.peer_streams_left_bidi());
+        assert_eq!(3, pipe.server.peer_streams_left_bidi());
+
+        pipe.server.stream_send(1, b"a", false).ok();
+        assert_eq!(2, pipe.server.peer_streams_left_bidi());
+        pipe.server.stream_send(5, b"a", false).ok();
+        assert_eq!(1, pipe.server.peer_streams_left_bidi());
+
+        pipe.server.stream_send(9, b"a", false).ok();
+        assert_eq!(0, pipe.server.peer_streams_left_bidi());
+
+        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_bidi());
+    }
+
+    #[test]
+    fn stream_left_uni() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(3, pipe.client.peer_streams_left_uni());
+        assert_eq!(3, pipe.server.peer_streams_left_uni());
+
+        pipe.server.stream_send(3, b"a", false).ok();
+        assert_eq!(2, pipe.server.peer_streams_left_uni());
+        pipe.server.stream_send(7, b"a", false).ok();
+        assert_eq!(1, pipe.server.peer_streams_left_uni());
+
+        pipe.server.stream_send(11, b"a", false).ok();
+        assert_eq!(0, pipe.server.peer_streams_left_uni());
+
+        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_uni());
+    }
+
+    #[test]
+    fn stream_limit_bidi() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 16,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 20,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 24,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 28,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::StreamLimit(16)),
+        );
+    }
+
+    #[test]
+    fn stream_limit_max_bidi() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let frames = [frame::Frame::MaxStreamsBidi {
+            max: MAX_STREAM_ID + 1,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame(0x12)),
+        );
+    }
+
+    #[test]
+    fn stream_limit_uni() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 2,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 6,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 10,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 14,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 18,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 22,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 26,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::StreamLimit(14)),
+        );
+    }
+
+    #[test]
+    fn stream_limit_max_uni() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let frames = [frame::Frame::MaxStreamsUni {
+            max: MAX_STREAM_ID + 1,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame(0x13)),
+        );
+    }
+
+    #[test]
+    fn streams_blocked_max_bidi() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::StreamsBlockedBidi {
+            limit: MAX_STREAM_ID,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let frames = [frame::Frame::StreamsBlockedBidi {
+            limit: MAX_STREAM_ID + 1,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame(0x16)),
+        );
+    }
+
+    #[test]
+    fn streams_blocked_max_uni() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::StreamsBlockedUni {
+            limit: MAX_STREAM_ID,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let frames = [frame::Frame::StreamsBlockedUni {
+            limit: MAX_STREAM_ID + 1,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame(0x17)),
+        );
+    }
+
+    #[test]
+    fn stream_data_overlap() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"bbbbb", 3, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"ccccc", 6, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
+        assert_eq!(&b[..11], b"aaaaabbbccc");
+    }
+
+    #[test]
+    fn stream_data_overlap_with_reordering() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"ccccc", 6, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"bbbbb", 3, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
+        assert_eq!(&b[..11], b"aaaaabccccc");
+    }
+
+    #[test]
+    /// Tests that receiving a valid RESET_STREAM frame when all data has
+    /// already been read, notifies the application.
+    fn reset_stream_data_recvd() {
+        let mut b = [0; 15];
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server gets data and sends data back, closing stream.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, false)));
+        assert!(!pipe.server.stream_finished(4));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_send(4, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((0, true)));
+        assert!(pipe.client.stream_finished(4));
+
+        // Client sends RESET_STREAM, closing stream.
+        let frames = [frame::Frame::ResetStream {
+            stream_id: 4,
+            error_code: 42,
+            final_size: 5,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+
+        // Server is notified of stream readability, due to reset.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(
+            pipe.server.stream_recv(4, &mut b),
+            Err(Error::StreamReset(42))
+        );
+
+        assert!(pipe.server.stream_finished(4));
+
+        // Sending RESET_STREAM again shouldn't make stream readable again.
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+    }
+
+    #[test]
+    /// Tests that receiving a valid RESET_STREAM frame when all data has _not_
+    /// been read, discards all buffered data and notifies the application.
+    fn reset_stream_data_not_recvd() {
+        let mut b = [0; 15];
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"h", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server gets data and sends data back, closing stream.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((1, false)));
+        assert!(!pipe.server.stream_finished(4));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_send(4, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((0, true)));
+        assert!(pipe.client.stream_finished(4));
+
+        // Client sends RESET_STREAM, closing stream.
+        let frames = [frame::Frame::ResetStream {
+            stream_id: 4,
+            error_code: 42,
+            final_size: 5,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+
+        // Server is notified of stream readability, due to reset.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(
+            pipe.server.stream_recv(4, &mut b),
+            Err(Error::StreamReset(42))
+        );
+
+        assert!(pipe.server.stream_finished(4));
+
+        // Sending RESET_STREAM again shouldn't make stream readable again.
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+    }
+
+    #[test]
+    /// Tests that RESET_STREAM frames exceeding the connection-level flow
+    /// control limit cause an error.
+    fn reset_stream_flow_control() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::ResetStream {
+                stream_id: 8,
+                error_code: 0,
+                final_size: 15,
+            },
+            frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl(12)),
+        );
+    }
+
+    #[test]
+    /// Tests that RESET_STREAM frames exceeding the stream-level flow control
+    /// limit cause an error.
+    fn reset_stream_flow_control_stream() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::ResetStream {
+                stream_id: 4,
+                error_code: 0,
+                final_size: 16, // Past stream's flow control limit.
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl(4)),
+        );
+    }
+
+    #[test]
+    fn path_challenge() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::PathChallenge { data: [0xba; 8] }];
+
+        let pkt_type = packet::Type::Short;
+
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        assert!(len > 0);
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        let mut iter = frames.iter();
+
+        // Ignore ACK.
+        iter.next().unwrap();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::PathResponse { data: [0xba; 8] })
+        );
+    }
+
+    #[test]
+    /// Simulates reception of an early 1-RTT packet on the server, by
+    /// delaying the client's Handshake packet that completes the handshake.
+    fn early_1rtt_packet() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // Client sends initial flight
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        // Server sends initial flight.
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        // Client sends Handshake packet.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+
+        // Emulate handshake packet delay by not making server process client
+        // packet.
+        let delayed = flight.clone();
+
+        testing::emit_flight(&mut pipe.server).ok();
+
+        assert!(pipe.client.is_established());
+
+        // Send 1-RTT packet #0.
+        let frames = [frame::Frame::Stream {
+            stream_id: 0,
+            data: stream::RangeBuf::from(b"hello, world", 0, true),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        let written =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+
+        // Send 1-RTT packet #1.
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"hello, world", 0, true),
+        }];
+
+        let written =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+
+        assert!(!pipe.server.is_established());
+
+        // Client sent 1-RTT packets 0 and 1, but server hasn't received them.
+        //
+        // Note that `largest_rx_pkt_num` is initialized to 0, so we need to
+        // send another 1-RTT packet to make this check meaningful.
+        assert_eq!(
+            pipe.server.pkt_num_spaces[packet::EPOCH_APPLICATION]
+                .largest_rx_pkt_num,
+            0
+        );
+
+        // Process delayed packet.
+        testing::process_flight(&mut pipe.server, delayed).unwrap();
+
+        assert!(pipe.server.is_established());
+
+        assert_eq!(
+            pipe.server.pkt_num_spaces[packet::EPOCH_APPLICATION]
+                .largest_rx_pkt_num,
+            0
+        );
+    }
+
+    #[test]
+    fn stop_sending() {
+        let mut b = [0; 15];
+
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends some data, and closes stream.
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server gets data.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert!(pipe.server.stream_finished(4));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        // Server sends data, until blocked.
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        loop {
+            if pipe.server.stream_send(4, b"world", false) == Err(Error::Done) {
+                break;
+            }
+
+            assert_eq!(pipe.advance(), Ok(()));
+        }
+
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), None);
+
+        // Client sends STOP_SENDING.
+        let frames = [frame::Frame::StopSending {
+            stream_id: 4,
+            error_code: 42,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        // Server sent a RESET_STREAM frame in response.
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+
+        let mut iter = frames.iter();
+
+        // Skip ACK frame.
+        iter.next();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::ResetStream {
+                stream_id: 4,
+                error_code: 42,
+                final_size: 15,
+            })
+        );
+
+        // Stream is writable, but writing returns an error.
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(
+            pipe.server.stream_send(4, b"world", true),
+            Err(Error::StreamStopped(42)),
+        );
+
+        assert_eq!(pipe.server.streams.len(), 1);
+
+        // Client acks RESET_STREAM frame.
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert(0..6);
+
+        let frames = [frame::Frame::ACK {
+            ack_delay: 15,
+            ranges,
+            ecn_counts: None,
+        }];
+
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(0));
+
+        // Stream is collected on the server after RESET_STREAM is acked.
+        assert_eq!(pipe.server.streams.len(), 0);
+
+        // Sending STOP_SENDING again shouldn't trigger RESET_STREAM again.
+        let frames = [frame::Frame::StopSending {
+            stream_id: 4,
+            error_code: 42,
+        }];
+
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+
+        assert_eq!(frames.len(), 1);
+
+        match frames.iter().next() {
+            Some(frame::Frame::ACK { .. }) => (),
+
+            f => panic!("expected ACK frame, got {:?}", f),
+        };
+
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), None);
+    }
+
+    #[test]
+    fn stop_sending_fin() {
+        let mut b = [0; 15];
+
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends some data, and closes stream.
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server gets data.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert!(pipe.server.stream_finished(4));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        // Server sends data...
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_send(4, b"world", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // ...and buffers more, and closes stream.
+        assert_eq!(pipe.server.stream_send(4, b"world", true), Ok(5));
+
+        // Client sends STOP_SENDING before server flushes stream.
+        let frames = [frame::Frame::StopSending {
+            stream_id: 4,
+            error_code: 42,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        // Server sent a RESET_STREAM frame in response.
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+
+        let mut iter = frames.iter();
+
+        // Skip ACK frame.
+        iter.next();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::ResetStream {
+                stream_id: 4,
+                error_code: 42,
+                final_size: 5,
+            })
+        );
+
+        // No more frames are sent by the server.
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    /// Tests that resetting a stream restores flow control for unsent data.
+    fn stop_sending_unsent_tx_cap() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(15);
+        config.set_initial_max_stream_data_bidi_local(30);
+        config.set_initial_max_stream_data_bidi_remote(30);
+        config.set_initial_max_stream_data_uni(30);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(0);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+
+        // Server sends some data.
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server buffers some data, until send capacity limit reached.
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(
+            pipe.server.stream_send(4, b"hello", false),
+            Err(Error::Done)
+        );
+        assert_eq!(
+            pipe.server.stream_send(8, b"hello", false),
+            Err(Error::Done)
+        );
+
+        // Client sends STOP_SENDING.
+        let frames = [frame::Frame::StopSending {
+            stream_id: 4,
+            error_code: 42,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        // Server can now send more data (on a different stream).
+        assert_eq!(pipe.client.stream_send(8, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
+        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
+        assert_eq!(
+            pipe.server.stream_send(8, b"hello", false),
+            Err(Error::Done)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+    }
+
+    #[test]
+    fn stream_shutdown_read() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", false), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 1);
+
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let mut dummy = buf[..len].to_vec();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut dummy, len).unwrap();
+        let mut iter = frames.iter();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::StopSending {
+                stream_id: 4,
+                error_code: 42,
+            })
+        );
+
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Sending more data is forbidden.
+        let mut r = pipe.client.writable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(
+            pipe.client.stream_send(4, b"bye", false),
+            Err(Error::StreamStopped(42))
+        );
+
+        // Server sends some data, without reading the incoming data, and closes
+        // the stream.
+        assert_eq!(pipe.server.stream_send(4, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Client reads the data.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.client.stream_recv(4, &mut buf), Ok((12, true)));
+
+        // Stream is collected on both sides.
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
+
+        assert_eq!(
+            pipe.server.stream_shutdown(4, Shutdown::Read, 0),
+            Err(Error::Done)
+        );
+    }
+
+    #[test]
+    fn stream_shutdown_read_after_fin() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 1);
+
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        // Server has nothing to send.
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server sends some data, without reading the incoming data, and closes
+        // the stream.
+        assert_eq!(pipe.server.stream_send(4, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Client reads the data.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.client.stream_recv(4, &mut buf), Ok((12, true)));
+
+        // Stream is collected on both sides.
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
+
+        assert_eq!(
+            pipe.server.stream_shutdown(4, Shutdown::Read, 0),
+            Err(Error::Done)
+        );
+    }
+
+    #[test]
+    fn stream_shutdown_write() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", false), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 1);
+
+        // Server sends some data.
+        assert_eq!(pipe.server.stream_send(4, b"goodbye, world", false), Ok(14));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Write, 42), Ok(()));
+
+        let mut r = pipe.server.writable();
+        assert_eq!(r.next(), None);
+
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let mut dummy = buf[..len].to_vec();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut dummy, len).unwrap();
+        let mut iter = frames.iter();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::ResetStream {
+                stream_id: 4,
+                error_code: 42,
+                final_size: 14,
+            })
+        );
+
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Sending more data is forbidden.
+        assert_eq!(
+            pipe.server.stream_send(4, b"bye", false),
+            Err(Error::FinalSize(4))
+        );
+
+        // Client sends some data and closes the stream.
+        assert_eq!(pipe.client.stream_send(4, b"bye", true), Ok(3));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server reads the data.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((15, true)));
+
+        // Client processes readable streams.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(
+            pipe.client.stream_recv(4, &mut buf),
+            Err(Error::StreamReset(42))
+        );
+
+        // Stream is collected on both sides.
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
+
+        assert_eq!(
+            pipe.server.stream_shutdown(4, Shutdown::Write, 0),
+            Err(Error::Done)
+        );
+    }
+
+    #[test]
+    /// Tests that shutting down a stream restores flow control for unsent data.
+    fn stream_shutdown_write_unsent_tx_cap() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(15);
+        config.set_initial_max_stream_data_bidi_local(30);
+        config.set_initial_max_stream_data_bidi_remote(30);
+        config.set_initial_max_stream_data_uni(30);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(0);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+
+        // Server sends some data.
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server buffers some data, until send capacity limit reached.
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(
+            pipe.server.stream_send(4, b"hello", false),
+            Err(Error::Done)
+        );
+        assert_eq!(
+            pipe.server.stream_send(8, b"hello", false),
+            Err(Error::Done)
+        );
+
+        // Client shouldn't update flow control.
+        assert_eq!(pipe.client.should_update_max_data(), false);
+
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Write, 42), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server can now send more data (on a different stream).
+        assert_eq!(pipe.client.stream_send(8, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
+        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
+        assert_eq!(
+            pipe.server.stream_send(8, b"hello", false),
+            Err(Error::Done)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+    }
+
+    #[test]
+    /// Tests that the order of flushable streams scheduled on the wire is the
+    /// same as the order of `stream_send()` calls done by the application.
+    fn stream_round_robin() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
+        let mut iter = frames.iter();
+
+        // Skip ACK frame.
+        iter.next();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            })
+        );
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
+        assert_eq!(
+            frames.iter().next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            })
+        );
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
+        assert_eq!(
+            frames.iter().next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
+            })
+        );
+    }
+
+    #[test]
+    /// Tests that streams are scheduled in order of their stream IDs with the
+    /// FIFO scheduler, regardless of their priority.
+    fn stream_scheduler_fifo() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_priority(4, 255, true), Ok(()));
+
+        // Streams already queued are moved to the new scheduler.
+        pipe.client
+            .set_stream_scheduler(Box::new(FifoScheduler::default()));
+
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+
+        for stream_id in [0, 4, 8] {
+            let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
+            assert!(frames.iter().any(|f| f ==
+                &frame::Frame::Stream {
+                    stream_id,
+                    data: stream::RangeBuf::from(b"aaaaa", 0, false),
+                }));
+        }
+    }
+
+    #[test]
+    /// Tests the readable iterator.
+    fn stream_readable() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // No readable streams.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), None);
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server received stream.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(
+            pipe.server.stream_send(4, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        // Client drains stream.
+        let mut b = [0; 15];
+        pipe.client.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), None);
+
+        // Server shuts down stream.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 0), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        // Client creates multiple streams.
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(12, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.len(), 2);
+
+        assert!(r.next().is_some());
+        assert!(r.next().is_some());
+        assert!(r.next().is_none());
+
+        assert_eq!(r.len(), 0);
+    }
+
+    #[test]
+    fn stream_peek_consume() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let mut b = [0; 15];
+        assert_eq!(
+            pipe.server.stream_peek(4, &mut b),
+            Err(Error::InvalidStreamState(4))
+        );
+
+        assert_eq!(pipe.client.stream_send(4, b"\x05hello", true), Ok(6));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Peek at the length prefix, then at the whole message.
+        assert_eq!(pipe.server.stream_peek(4, &mut b[..1]), Ok((1, false)));
+        assert_eq!(b[0], 5);
+
+        assert_eq!(pipe.server.stream_peek(4, &mut b), Ok((6, true)));
+        assert_eq!(&b[..6], b"\x05hello");
+
+        // Peeking doesn't return flow control credit, consuming does.
+        assert_eq!(pipe.server.max_rx_data_next(), 45);
+
+        assert_eq!(pipe.server.stream_consume(4, 1), Ok((1, false)));
+        assert_eq!(pipe.server.max_rx_data_next(), 46);
+
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"hello");
+
+        assert_eq!(pipe.server.stream_peek(4, &mut b), Err(Error::Done));
+    }
+
+    #[test]
+    fn stream_send_zc() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let data: Arc<[u8]> = Arc::from(&b"helloworldsomething"[..]);
+
+        // The stream's flow control limit only allows 15 bytes to be
+        // buffered.
+        assert_eq!(pipe.client.stream_send_zc(4, data.clone(), true), Ok(15));
+        assert!(Arc::strong_count(&data) > 1);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Once all the data is acked the shared buffer is released.
+        assert_eq!(Arc::strong_count(&data), 1);
+
+        let mut b = [0; 20];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((15, false)));
+        assert_eq!(&b[..15], b"helloworldsomet");
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let rest: Arc<[u8]> = Arc::from(&b"hing"[..]);
+        assert_eq!(pipe.client.stream_send_zc(4, rest, true), Ok(4));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((4, true)));
+        assert_eq!(&b[..4], b"hing");
+    }
+
+    #[test]
+    fn stream_recv_v() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"helloworld", true), Ok(10));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut a = [0; 4];
+        let mut b = [0; 4];
+        let mut c = [0; 4];
+
+        {
+            let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+            assert_eq!(pipe.server.stream_recv_v(4, &mut bufs), Ok((8, false)));
+        }
+        assert_eq!(&a, b"hell");
+        assert_eq!(&b, b"owor");
+
+        {
+            let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut c)];
+            assert_eq!(pipe.server.stream_recv_v(4, &mut bufs), Ok((2, true)));
+        }
+        assert_eq!(&a[..2], b"ld");
+
+        assert_eq!(pipe.server.max_rx_data_next(), 55);
+
+        let mut bufs = [IoSliceMut::new(&mut a)];
+        assert_eq!(pipe.server.stream_recv_v(4, &mut bufs), Err(Error::Done));
+    }
+
+    #[test]
+    fn stream_readable_writable_priority_order() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Without priorities streams are returned in order of their IDs.
+        let r: Vec<u64> = pipe.server.readable().collect();
+        assert_eq!(r, vec![0, 4, 8]);
+
+        assert_eq!(pipe.server.stream_priority(8, 0, false), Ok(()));
+        assert_eq!(pipe.server.stream_priority(0, 200, false), Ok(()));
+
+        let r: Vec<u64> = pipe.server.readable().collect();
+        assert_eq!(r, vec![8, 4, 0]);
+        assert_eq!(pipe.server.readable_next(), Some(8));
+
+        let w: Vec<u64> = pipe.server.writable().collect();
+        assert_eq!(w, vec![8, 4, 0]);
+        assert_eq!(pipe.server.writable_next(), Some(8));
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(8, &mut b), Ok((5, false)));
+
+        assert_eq!(pipe.server.readable_next(), Some(4));
+    }
+
+    #[test]
+    /// Tests the writable iterator.
+    fn stream_writable() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // No writable streams.
+        let mut w = pipe.client.writable();
+        assert_eq!(w.next(), None);
+
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+
+        // Client created stream.
+        let mut w = pipe.client.writable();
+        assert_eq!(w.next(), Some(4));
+        assert_eq!(w.next(), None);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server created stream.
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), Some(4));
+        assert_eq!(w.next(), None);
+
+        assert_eq!(
+            pipe.server.stream_send(4, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+
+        // Server stream is full.
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), None);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Client drains stream.
+        let mut b = [0; 15];
+        pipe.client.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server stream is writable again.
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), Some(4));
+        assert_eq!(w.next(), None);
+
+        // Server suts down stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Write, 0), Ok(()));
+
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), None);
+
+        // Client creates multiple streams.
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(12, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut w = pipe.server.writable();
+        assert_eq!(w.len(), 2);
+
+        assert!(w.next().is_some());
+        assert!(w.next().is_some());
+        assert!(w.next().is_none());
+
+        assert_eq!(w.len(), 0);
+
+        // Server finishes stream.
+        assert_eq!(pipe.server.stream_send(12, b"aaaaa", true), Ok(5));
+
+        let mut w = pipe.server.writable();
+        assert_eq!(w.next(), Some(8));
+        assert_eq!(w.next(), None);
+    }
+
+    #[test]
+    /// Tests that we don't exceed the per-connection flow control limit set by
+    /// the peer.
+    fn flow_control_limit_send() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.client.stream_send(4, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Err(Error::Done));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert!(r.next().is_some());
+        assert!(r.next().is_some());
+        assert!(r.next().is_none());
+    }
+
+    #[test]
+    /// Tests that invalid packets received before any other valid ones cause
+    /// the server to close the connection immediately.
+    fn invalid_initial_server() {
+        let mut buf = [0; 65535];
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        let frames = [frame::Frame::Padding { len: 10 }];
+
+        let written = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Initial,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Corrupt the packets's last byte to make decryption fail (the last
+        // byte is part of the AEAD tag, so changing it means that the packet
+        // cannot be authenticated during decryption).
+        buf[written - 1] = !buf[written - 1];
+
+        assert_eq!(pipe.server.timeout(), None);
+
+        assert_eq!(
+            pipe.server_recv(&mut buf[..written]),
+            Err(Error::CryptoFail)
+        );
+
+        assert!(pipe.server.is_closed());
+    }
+
+    #[test]
+    /// Tests that invalid Initial packets received to cause
+    /// the client to close the connection immediately.
+    fn invalid_initial_client() {
+        let mut buf = [0; 65535];
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Server sends initial flight.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(1200));
+
+        let frames = [frame::Frame::Padding { len: 10 }];
+
+        let written = testing::encode_pkt(
+            &mut pipe.server,
+            packet::Type::Initial,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Corrupt the packets's last byte to make decryption fail (the last
+        // byte is part of the AEAD tag, so changing it means that the packet
+        // cannot be authenticated during decryption).
+        buf[written - 1] = !buf[written - 1];
+
+        // Client will ignore invalid packet.
+        assert_eq!(pipe.client_recv(&mut buf[..written]), Ok(71));
+
+        // The connection should be alive...
+        assert_eq!(pipe.client.is_closed(), false);
+
+        // ...and the idle timeout should be armed.
+        assert!(pipe.client.idle_timer.is_some());
+    }
+
+    #[test]
+    /// Tests that packets with invalid payload length received before any other
+    /// valid packet cause the server to close the connection immediately.
+    fn invalid_initial_payload() {
+        let mut buf = [0; 65535];
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        let mut b = octets::OctetsMut::with_slice(&mut buf);
+
+        let epoch = packet::Type::Initial.to_epoch().unwrap();
+
+        let pn = 0;
+        let pn_len = packet::pkt_num_len(pn).unwrap();
+
+        let hdr = Header {
+            ty: packet::Type::Initial,
+            version: pipe.client.version,
+            dcid: ConnectionId::from_ref(&pipe.client.dcid),
+            scid: ConnectionId::from_ref(&pipe.client.scid),
+            pkt_num: 0,
+            pkt_num_len: pn_len,
+            token: pipe.client.token.clone(),
+            versions: None,
+            key_phase: false,
+        };
+
+        hdr.to_bytes(&mut b).unwrap();
+
+        // Payload length is invalid!!!
+        let payload_len = 4096;
+
+        let len = pn_len + payload_len;
+        b.put_varint(len as u64).unwrap();
+
+        packet::encode_pkt_num(pn, &mut b).unwrap();
+
+        let payload_offset = b.off();
+
+        let frames = [frame::Frame::Padding { len: 10 }];
+
+        for frame in &frames {
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        let space = &mut pipe.client.pkt_num_spaces[epoch];
+
+        // Use correct payload length when encrypting the packet.
+        let payload_len = frames.iter().fold(0, |acc, x| acc + x.wire_len());
+
+        let aead = space.crypto_seal.as_ref().unwrap();
+
+        let written = packet::encrypt_pkt(
+            &mut b,
+            pn,
+            pn_len,
+            payload_len,
+            payload_offset,
+            None,
+            aead,
+        )
+        .unwrap();
+
+        assert_eq!(pipe.server.timeout(), None);
 
-        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
+            pipe.server_recv(&mut buf[..written]),
+            Err(Error::InvalidPacket)
         );
+
+        assert!(pipe.server.is_closed());
     }
 
     #[test]
-    fn path_challenge() {
+    /// Tests that invalid packets don't cause the connection to be closed.
+    fn invalid_packet() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::PathChallenge { data: [0xba; 8] }];
-
-        let pkt_type = packet::Type::Short;
+        let frames = [frame::Frame::Padding { len: 10 }];
 
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        let written = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Short,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
 
-        assert!(len > 0);
+        // Corrupt the packets's last byte to make decryption fail (the last
+        // byte is part of the AEAD tag, so changing it means that the packet
+        // cannot be authenticated during decryption).
+        buf[written - 1] = !buf[written - 1];
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-        let mut iter = frames.iter();
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
 
-        // Ignore ACK.
-        iter.next().unwrap();
+        // Corrupt the packets's first byte to make the header fail decoding.
+        buf[0] = 255;
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::PathResponse { data: [0xba; 8] })
-        );
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
     }
 
     #[test]
-    /// Simulates reception of an early 1-RTT packet on the server, by
-    /// delaying the client's Handshake packet that completes the handshake.
-    fn early_1rtt_packet() {
+    fn recv_empty_buffer() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends initial flight
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..0]), Err(Error::BufferTooShort));
+    }
 
-        // Server sends initial flight.
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+    #[test]
+    /// Tests that the MAX_STREAMS frame is sent for bidirectional streams.
+    fn stream_limit_update_bidi() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(0);
+        config.verify_peer(false);
 
-        // Client sends Handshake packet.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Emulate handshake packet delay by not making server process client
-        // packet.
-        let delayed = flight.clone();
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        testing::emit_flight(&mut pipe.server).ok();
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert!(pipe.client.is_established());
+        assert_eq!(pipe.client.stream_send(4, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Send 1-RTT packet #0.
-        let frames = [frame::Frame::Stream {
-            stream_id: 0,
-            data: stream::RangeBuf::from(b"hello, world", 0, true),
-        }];
+        assert_eq!(pipe.client.stream_send(0, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let pkt_type = packet::Type::Short;
-        let written =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        pipe.server.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        // Server sends stream data, with fin.
+        assert_eq!(pipe.server.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Send 1-RTT packet #1.
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"hello, world", 0, true),
-        }];
+        assert_eq!(pipe.server.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let written =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
+        assert_eq!(pipe.server.stream_send(4, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        assert_eq!(pipe.server.stream_send(0, b"b", true), Ok(1));
 
-        assert!(!pipe.server.is_established());
+        // Server sends MAX_STREAMS.
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sent 1-RTT packets 0 and 1, but server hasn't received them.
-        //
-        // Note that `largest_rx_pkt_num` is initialized to 0, so we need to
-        // send another 1-RTT packet to make this check meaningful.
-        assert_eq!(
-            pipe.server.pkt_num_spaces[packet::EPOCH_APPLICATION]
-                .largest_rx_pkt_num,
-            0
-        );
+        // Client tries to create new streams.
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Process delayed packet.
-        testing::process_flight(&mut pipe.server, delayed).unwrap();
+        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert!(pipe.server.is_established());
+        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
         assert_eq!(
-            pipe.server.pkt_num_spaces[packet::EPOCH_APPLICATION]
-                .largest_rx_pkt_num,
-            0
+            pipe.client.stream_send(20, b"a", false),
+            Err(Error::StreamLimit(20))
         );
+
+        assert_eq!(pipe.server.readable().len(), 3);
     }
 
     #[test]
-    fn stop_sending() {
-        let mut b = [0; 15];
-
+    fn stream_credit_policy() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        // Client sends some data, and closes stream.
-        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Server gets data.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
-        assert!(pipe.server.stream_finished(4));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
-
-        // Server sends data, until blocked.
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        loop {
-            if pipe.server.stream_send(4, b"world", false) == Err(Error::Done) {
-                break;
-            }
-
-            assert_eq!(pipe.advance(), Ok(()));
-        }
-
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), None);
-
-        // Client sends STOP_SENDING.
-        let frames = [frame::Frame::StopSending {
-            stream_id: 4,
-            error_code: 42,
-        }];
-
-        let pkt_type = packet::Type::Short;
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(1);
+        config.verify_peer(false);
+        config.set_stream_credit_policy(StreamCreditPolicy::Manual);
 
-        // Server sent a RESET_STREAM frame in response.
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut iter = frames.iter();
+        // The stream is completed on both sides.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Skip ACK frame.
-        iter.next();
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((1, true)));
+        assert_eq!(pipe.server.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::ResetStream {
-                stream_id: 4,
-                error_code: 42,
-                final_size: 15,
-            })
-        );
+        assert_eq!(pipe.client.stream_recv(0, &mut buf), Ok((1, true)));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Stream is writable, but writing returns an error.
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert!(pipe.server.streams.is_collected(0));
 
+        // But the credit isn't given back.
+        assert_eq!(pipe.client.peer_streams_left_bidi(), 0);
         assert_eq!(
-            pipe.server.stream_send(4, b"world", true),
-            Err(Error::StreamStopped(42)),
+            pipe.client.stream_send(4, b"a", false),
+            Err(Error::StreamLimit(4))
         );
 
-        assert_eq!(pipe.server.streams.len(), 1);
-
-        // Client acks RESET_STREAM frame.
-        let mut ranges = ranges::RangeSet::default();
-        ranges.insert(0..6);
-
-        let frames = [frame::Frame::ACK {
-            ack_delay: 15,
-            ranges,
-            ecn_counts: None,
-        }];
-
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(0));
+        assert_eq!(pipe.server.grant_streams(true, 2), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Stream is collected on the server after RESET_STREAM is acked.
-        assert_eq!(pipe.server.streams.len(), 0);
+        assert_eq!(pipe.client.peer_streams_left_bidi(), 2);
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Sending STOP_SENDING again shouldn't trigger RESET_STREAM again.
-        let frames = [frame::Frame::StopSending {
-            stream_id: 4,
-            error_code: 42,
-        }];
+        // With a fixed window the credit is given back...
+        config.set_stream_credit_policy(StreamCreditPolicy::Fixed);
 
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(frames.len(), 1);
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((1, true)));
+        assert_eq!(pipe.server.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        match frames.iter().next() {
-            Some(frame::Frame::ACK { .. }) => (),
+        assert_eq!(pipe.client.stream_recv(0, &mut buf), Ok((1, true)));
+        assert_eq!(pipe.advance(), Ok(()));
 
-            f => panic!("expected ACK frame, got {:?}", f),
-        };
+        assert_eq!(pipe.client.peer_streams_left_bidi(), 1);
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
 
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), None);
+        // ...but the window can't be raised.
+        assert_eq!(pipe.server.grant_streams(true, 1), Err(Error::InvalidState));
     }
 
     #[test]
-    fn stop_sending_fin() {
-        let mut b = [0; 15];
-
-        let mut buf = [0; 65535];
+    fn max_buffered_bytes() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
+        config.set_max_buffered_bytes(10);
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data, and closes stream.
-        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        // Local writes are truncated to the limit.
+        assert_eq!(pipe.server.stream_send(1, b"aaaaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.server.stream_send(1, b"a", false), Err(Error::Done));
+        assert_eq!(pipe.server.stream_capacity(1), Ok(0));
+        assert_eq!(pipe.server.stats().buffered_bytes, 10);
+
+        // Once the client acknowledges the data, more can be written.
         assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.stats().buffered_bytes, 0);
+        assert_eq!(pipe.server.stream_send(1, b"a", false), Ok(1));
 
-        // Server gets data.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        // The peer can use all the credit it was granted, even beyond the
+        // limit.
+        assert_eq!(pipe.client.stream_send(0, b"aaaaaaaaaaaa", false), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.stats().buffered_bytes, 12);
+        assert_eq!(pipe.server.local_error(), None);
 
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
-        assert!(pipe.server.stream_finished(4));
+        // But new credit only leaves room for as much data as the limit.
+        let mut buf = [0; 12];
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((12, false)));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.stream_send(0, &[0; 20], false), Ok(10));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.stats().buffered_bytes, 10);
 
-        // Server sends data...
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        // Credit is withheld until the application reads the data.
+        assert_eq!(pipe.server.stream_recv(0, &mut buf[..5]), Ok((5, false)));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stream_send(0, &[0; 20], false), Ok(5));
+    }
 
-        assert_eq!(pipe.server.stream_send(4, b"world", false), Ok(5));
+    #[test]
+    fn compact() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"hello, world", false), Ok(12));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // ...and buffers more, and closes stream.
-        assert_eq!(pipe.server.stream_send(4, b"world", true), Ok(5));
+        // Only part of the data is read by the application.
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b[..5]), Ok((5, false)));
 
-        // Client sends STOP_SENDING before server flushes stream.
-        let frames = [frame::Frame::StopSending {
-            stream_id: 4,
-            error_code: 42,
-        }];
+        pipe.server.recycle_recv_buf(Vec::with_capacity(1500));
 
-        let pkt_type = packet::Type::Short;
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        let retained = pipe.server.stats().retained_bytes;
 
-        // Server sent a RESET_STREAM frame in response.
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        pipe.server.compact();
 
-        let mut iter = frames.iter();
+        assert!(pipe.server.stats().retained_bytes < retained);
+        assert!(pipe.server.recv_buf_pool.is_empty());
+        assert_eq!(pipe.server.stats().buffered_bytes, 7);
 
-        // Skip ACK frame.
-        iter.next();
+        // The connection keeps working as usual.
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((7, false)));
+        assert_eq!(&b[..7], b", world");
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::ResetStream {
-                stream_id: 4,
-                error_code: 42,
-                final_size: 5,
-            })
-        );
+        assert_eq!(pipe.server.stream_send(0, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // No more frames are sent by the server.
-        assert_eq!(iter.next(), None);
+        assert_eq!(pipe.client.stream_recv(0, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"hello");
     }
 
     #[test]
-    /// Tests that resetting a stream restores flow control for unsent data.
-    fn stop_sending_unsent_tx_cap() {
-        let mut buf = [0; 65535];
-
+    /// Tests that the MAX_STREAMS frame is sent for unidirectional streams.
+    fn stream_limit_update_uni() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -8159,288 +16190,371 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(15);
-        config.set_initial_max_stream_data_bidi_local(30);
-        config.set_initial_max_stream_data_bidi_remote(30);
-        config.set_initial_max_stream_data_uni(30);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(0);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(0);
+        config.set_initial_max_streams_uni(3);
         config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(2, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.stream_send(6, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(pipe.client.stream_send(6, b"b", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Server sends some data.
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(2, b"b", true), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server buffers some data, until send capacity limit reached.
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(
-            pipe.server.stream_send(4, b"hello", false),
-            Err(Error::Done)
-        );
-        assert_eq!(
-            pipe.server.stream_send(8, b"hello", false),
-            Err(Error::Done)
-        );
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(2, &mut b).unwrap();
+        pipe.server.stream_recv(6, &mut b).unwrap();
 
-        // Client sends STOP_SENDING.
-        let frames = [frame::Frame::StopSending {
-            stream_id: 4,
-            error_code: 42,
-        }];
+        // Server sends MAX_STREAMS.
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let pkt_type = packet::Type::Short;
-        pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        // Client tries to create new streams.
+        assert_eq!(pipe.client.stream_send(10, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Server can now send more data (on a different stream).
-        assert_eq!(pipe.client.stream_send(8, b"hello", true), Ok(5));
+        assert_eq!(pipe.client.stream_send(14, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(18, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
-        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
         assert_eq!(
-            pipe.server.stream_send(8, b"hello", false),
-            Err(Error::Done)
+            pipe.client.stream_send(22, b"a", false),
+            Err(Error::StreamLimit(22))
         );
-        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.readable().len(), 3);
     }
 
     #[test]
-    fn stream_shutdown_read() {
-        let mut buf = [0; 65535];
-
+    /// Tests that the stream's fin flag is properly flushed even if there's no
+    /// data in the buffer, and that the buffer becomes readable on the other
+    /// side.
+    fn stream_zero_length_fin() {
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", false), Ok(12));
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
         assert_eq!(pipe.advance(), Ok(()));
 
         let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert_eq!(r.next(), Some(0));
+        assert!(r.next().is_none());
 
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 1);
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
+        // Client sends zero-length frame.
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
+        // Stream should be readable on the server after receiving empty fin.
         let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert_eq!(r.next(), Some(0));
+        assert!(r.next().is_none());
 
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut dummy = buf[..len].to_vec();
+        // Client sends zero-length frame (again).
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut dummy, len).unwrap();
-        let mut iter = frames.iter();
+        // Stream should _not_ be readable on the server after receiving empty
+        // fin, because it was already finished.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+    }
+
+    #[test]
+    /// Tests that the stream's fin flag is properly flushed even if there's no
+    /// data in the buffer, that the buffer becomes readable on the other
+    /// side and stays readable even if the stream is fin'd locally.
+    fn stream_zero_length_fin_deferred_collection() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
         assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::StopSending {
-                stream_id: 4,
-                error_code: 42,
-            })
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
         );
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert!(r.next().is_none());
+
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Client sends zero-length frame.
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        // Server sends zero-length frame.
+        assert_eq!(pipe.server.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Stream should be readable on the server after receiving empty fin.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert!(r.next().is_none());
 
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Sending more data is forbidden.
-        let mut r = pipe.client.writable();
-        assert_eq!(r.next(), Some(4));
+        // Client sends zero-length frame (again).
+        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Stream should _not_ be readable on the server after receiving empty
+        // fin, because it was already finished.
+        let mut r = pipe.server.readable();
         assert_eq!(r.next(), None);
 
-        assert_eq!(
-            pipe.client.stream_send(4, b"bye", false),
-            Err(Error::StreamStopped(42))
-        );
+        // Stream _is_readable on the client side.
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(0));
 
-        // Server sends some data, without reading the incoming data, and closes
-        // the stream.
-        assert_eq!(pipe.server.stream_send(4, b"hello, world", true), Ok(12));
+        pipe.client.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client reads the data.
+        // Stream is completed and _is not_ readable.
         let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(4));
         assert_eq!(r.next(), None);
+    }
 
-        assert_eq!(pipe.client.stream_recv(4, &mut buf), Ok((12, true)));
+    #[test]
+    /// Tests that the stream gets created with stream_send() even if there's
+    /// no data in the buffer and the fin flag is not set.
+    fn stream_zero_length_non_fin() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Stream is collected on both sides.
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+        assert_eq!(pipe.client.stream_send(0, b"", false), Ok(0));
 
-        assert_eq!(
-            pipe.server.stream_shutdown(4, Shutdown::Read, 0),
-            Err(Error::Done)
-        );
+        // The stream now should have been created.
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Sending an empty non-fin should not change any stream state on the
+        // other side.
+        let mut r = pipe.server.readable();
+        assert!(r.next().is_none());
     }
 
     #[test]
-    fn stream_shutdown_read_after_fin() {
+    /// Tests that completed streams are garbage collected.
+    fn collect_streams() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
+
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert!(!pipe.client.stream_finished(0));
+        assert!(!pipe.server.stream_finished(0));
 
         assert_eq!(pipe.client.streams.len(), 1);
         assert_eq!(pipe.server.streams.len(), 1);
 
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
-
-        // Server has nothing to send.
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
-
+        let mut b = [0; 5];
+        pipe.server.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server sends some data, without reading the incoming data, and closes
-        // the stream.
-        assert_eq!(pipe.server.stream_send(4, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.server.stream_send(0, b"aaaaa", true), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client reads the data.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert!(!pipe.client.stream_finished(0));
+        assert!(pipe.server.stream_finished(0));
 
-        assert_eq!(pipe.client.stream_recv(4, &mut buf), Ok((12, true)));
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 0);
+
+        let mut b = [0; 5];
+        pipe.client.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Stream is collected on both sides.
         assert_eq!(pipe.client.streams.len(), 0);
         assert_eq!(pipe.server.streams.len(), 0);
 
+        assert!(pipe.client.stream_finished(0));
+        assert!(pipe.server.stream_finished(0));
+
+        assert_eq!(pipe.client.stream_send(0, b"", true), Err(Error::Done));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 0,
+            data: stream::RangeBuf::from(b"aa", 0, false),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+    }
+
+    #[test]
+    fn config_set_cc_algorithm_name() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+
+        assert_eq!(config.set_cc_algorithm_name("reno"), Ok(()));
+
+        // Unknown name.
         assert_eq!(
-            pipe.server.stream_shutdown(4, Shutdown::Read, 0),
-            Err(Error::Done)
+            config.set_cc_algorithm_name("???"),
+            Err(Error::CongestionControl)
         );
     }
 
     #[test]
-    fn stream_shutdown_write() {
-        let mut buf = [0; 65535];
-
+    fn peer_cert() {
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", false), Ok(12));
-        assert_eq!(pipe.advance(), Ok(()));
+        match pipe.client.peer_cert() {
+            Some(c) => assert_eq!(c.len(), 753),
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+            None => panic!("missing server certificate"),
+        }
+    }
 
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+    #[test]
+    fn retry() {
+        let mut buf = [0; 65535];
 
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 1);
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
 
-        // Server sends some data.
-        assert_eq!(pipe.server.stream_send(4, b"goodbye, world", false), Ok(14));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Write, 42), Ok(()));
+        // Client sends initial flight.
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let mut r = pipe.server.writable();
-        assert_eq!(r.next(), None);
+        // Server sends Retry packet.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
 
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        let odcid = hdr.dcid.clone();
 
-        let mut dummy = buf[..len].to_vec();
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut dummy, len).unwrap();
-        let mut iter = frames.iter();
+        let token = b"quiche test retry token";
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::ResetStream {
-                stream_id: 4,
-                error_code: 42,
-                final_size: 14,
-            })
-        );
+        len = packet::retry(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            token,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
 
+        // Client receives Retry and sends new Initial.
         assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-        assert_eq!(pipe.advance(), Ok(()));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        // Sending more data is forbidden.
-        assert_eq!(
-            pipe.server.stream_send(4, b"bye", false),
-            Err(Error::FinalSize)
-        );
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        assert_eq!(&hdr.token.unwrap(), token);
+        assert_eq!(pipe.client.token_kind(), Some(TokenKind::Retry));
+
+        // Server accepts connection.
+        let from = "127.0.0.1:1234".parse().unwrap();
+        pipe.server = accept(&scid, Some(&odcid), from, &mut config).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        // Client sends some data and closes the stream.
-        assert_eq!(pipe.client.stream_send(4, b"bye", true), Ok(3));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads the data.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert!(pipe.client.is_established());
+        assert!(pipe.server.is_established());
 
-        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((15, true)));
+        assert!(pipe.client.did_retry());
+        assert!(pipe.server.did_retry());
+    }
 
-        // Client processes readable streams.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+    #[test]
+    fn refuse() {
+        let mut buf = [0; 65535];
 
-        assert_eq!(
-            pipe.client.stream_recv(4, &mut buf),
-            Err(Error::StreamReset(42))
-        );
+        let mut pipe = testing::Pipe::default().unwrap();
 
-        // Stream is collected on both sides.
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Server refuses the connection.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
+
+        let len = packet::refuse(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            0x2,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Client receives CONNECTION_CLOSE and stops.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
         assert_eq!(
-            pipe.server.stream_shutdown(4, Shutdown::Write, 0),
-            Err(Error::Done)
+            pipe.client.peer_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x2,
+                reason: Vec::new(),
+                frame_type: Some(0),
+            })
         );
+
+        assert!(pipe.client.is_draining());
+        assert!(!pipe.client.is_established());
     }
 
     #[test]
-    /// Tests that shutting down a stream restores flow control for unsent data.
-    fn stream_shutdown_write_unsent_tx_cap() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+    fn missing_retry_source_connection_id() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -8450,555 +16564,585 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(15);
-        config.set_initial_max_stream_data_bidi_local(30);
-        config.set_initial_max_stream_data_bidi_remote(30);
-        config.set_initial_max_stream_data_uni(30);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(0);
-        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client sends initial flight.
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        // Server sends Retry packet.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
 
-        // Server sends some data.
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let token = b"quiche test retry token";
 
-        // Server buffers some data, until send capacity limit reached.
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(pipe.server.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(
-            pipe.server.stream_send(4, b"hello", false),
-            Err(Error::Done)
-        );
-        assert_eq!(
-            pipe.server.stream_send(8, b"hello", false),
-            Err(Error::Done)
-        );
+        len = packet::retry(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            token,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
 
-        // Client shouldn't update flow control.
-        assert_eq!(pipe.client.should_update_max_data(), false);
+        // Client receives Retry and sends new Initial.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Write, 42), Ok(()));
-        assert_eq!(pipe.advance(), Ok(()));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        // Server can now send more data (on a different stream).
-        assert_eq!(pipe.client.stream_send(8, b"hello", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server accepts connection and send first flight. But original
+        // destination connection ID is ignored.
+        let from = "127.0.0.1:1234".parse().unwrap();
+        pipe.server = accept(&scid, None, from, &mut config).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
 
-        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
-        assert_eq!(pipe.server.stream_send(8, b"hello", false), Ok(5));
         assert_eq!(
-            pipe.server.stream_send(8, b"hello", false),
-            Err(Error::Done)
+            testing::process_flight(&mut pipe.client, flight),
+            Err(Error::InvalidTransportParam)
         );
-        assert_eq!(pipe.advance(), Ok(()));
     }
 
     #[test]
-    /// Tests that the order of flushable streams scheduled on the wire is the
-    /// same as the order of `stream_send()` calls done by the application.
-    fn stream_round_robin() {
+    fn invalid_retry_source_connection_id() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        // Client sends initial flight.
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+        // Server sends Retry packet.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
 
-        let mut iter = frames.iter();
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
 
-        // Skip ACK frame.
-        iter.next();
+        let token = b"quiche test retry token";
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            })
-        );
+        len = packet::retry(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            token,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Client receives Retry and sends new Initial.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
         let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+        // Server accepts connection and send first flight. But original
+        // destination connection ID is invalid.
+        let from = "127.0.0.1:1234".parse().unwrap();
+        let odcid = ConnectionId::from_ref(b"bogus value");
+        pipe.server = accept(&scid, Some(&odcid), from, &mut config).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
 
         assert_eq!(
-            frames.iter().next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            })
+            testing::process_flight(&mut pipe.client, flight),
+            Err(Error::InvalidTransportParam)
         );
+    }
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+    fn check_send(_: &mut impl Send) {}
 
-        assert_eq!(
-            frames.iter().next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            })
-        );
+    #[test]
+    fn config_must_be_send() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        check_send(&mut config);
     }
 
     #[test]
-    /// Tests the readable iterator.
-    fn stream_readable() {
+    fn connection_must_be_send() {
         let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        // No readable streams.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Server received stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(
-            pipe.server.stream_send(4, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
-        );
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        // Client drains stream.
-        let mut b = [0; 15];
-        pipe.client.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
-
-        // Server shuts down stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 0), Ok(()));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
-
-        // Client creates multiple streams.
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(12, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        check_send(&mut pipe.client);
+    }
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.len(), 2);
+    fn check_sync(_: &mut impl Sync) {}
 
-        assert!(r.next().is_some());
-        assert!(r.next().is_some());
-        assert!(r.next().is_none());
+    #[test]
+    fn config_must_be_sync() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        check_sync(&mut config);
+    }
 
-        assert_eq!(r.len(), 0);
+    #[test]
+    fn connection_must_be_sync() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        check_sync(&mut pipe.client);
     }
 
     #[test]
-    /// Tests the writable iterator.
-    fn stream_writable() {
+    fn data_blocked() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // No writable streams.
-        let mut w = pipe.client.writable();
-        assert_eq!(w.next(), None);
-
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-
-        // Client created stream.
-        let mut w = pipe.client.writable();
-        assert_eq!(w.next(), Some(4));
-        assert_eq!(w.next(), None);
+        assert_eq!(pipe.client.stream_send(0, b"aaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.client.blocked_limit, None);
+        assert_eq!(pipe.advance(), Ok(()));
 
+        assert_eq!(pipe.client.stream_send(4, b"aaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.client.blocked_limit, None);
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server created stream.
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), Some(4));
-        assert_eq!(w.next(), None);
+        assert_eq!(pipe.client.stream_send(8, b"aaaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.client.blocked_limit, Some(30));
 
-        assert_eq!(
-            pipe.server.stream_send(4, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
-        );
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.blocked_limit, None);
 
-        // Server stream is full.
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), None);
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
 
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut iter = frames.iter();
 
-        // Client drains stream.
-        let mut b = [0; 15];
-        pipe.client.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(iter.next(), Some(&frame::Frame::DataBlocked { limit: 30 }));
 
-        // Server stream is writable again.
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), Some(4));
-        assert_eq!(w.next(), None);
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"aaaaaaaaaa", 0, false),
+            })
+        );
 
-        // Server suts down stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Write, 0), Ok(()));
+        assert_eq!(iter.next(), None);
+    }
 
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), None);
+    #[test]
+    fn stream_data_blocked() {
+        let mut buf = [0; 65535];
 
-        // Client creates multiple streams.
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(12, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
 
-        let mut w = pipe.server.writable();
-        assert_eq!(w.len(), 2);
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
 
-        assert!(w.next().is_some());
-        assert!(w.next().is_some());
-        assert!(w.next().is_none());
+        assert_eq!(pipe.client.stream_send(0, b"aaaaaa", false), Ok(5));
+        assert_eq!(pipe.client.streams.blocked().len(), 1);
 
-        assert_eq!(w.len(), 0);
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
 
-        // Server finishes stream.
-        assert_eq!(pipe.server.stream_send(12, b"aaaaa", true), Ok(5));
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
 
-        let mut w = pipe.server.writable();
-        assert_eq!(w.next(), Some(8));
-        assert_eq!(w.next(), None);
-    }
+        let mut iter = frames.iter();
 
-    #[test]
-    /// Tests that we don't exceed the per-connection flow control limit set by
-    /// the peer.
-    fn flow_control_limit_send() {
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        // Skip ACK frame.
+        iter.next();
 
         assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
+            iter.next(),
+            Some(&frame::Frame::StreamDataBlocked {
+                stream_id: 0,
+                limit: 15,
+            })
         );
-        assert_eq!(pipe.advance(), Ok(()));
+
         assert_eq!(
-            pipe.client.stream_send(4, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            })
         );
-        assert_eq!(pipe.advance(), Ok(()));
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Err(Error::Done));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut r = pipe.server.readable();
-        assert!(r.next().is_some());
-        assert!(r.next().is_some());
-        assert!(r.next().is_none());
-    }
 
-    #[test]
-    /// Tests that invalid packets received before any other valid ones cause
-    /// the server to close the connection immediately.
-    fn invalid_initial_server() {
-        let mut buf = [0; 65535];
-        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(iter.next(), None);
 
-        let frames = [frame::Frame::Padding { len: 10 }];
+        // Send from another stream, make sure we don't send STREAM_DATA_BLOCKED
+        // again.
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
 
-        let written = testing::encode_pkt(
-            &mut pipe.client,
-            packet::Type::Initial,
-            &frames,
-            &mut buf,
-        )
-        .unwrap();
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
 
-        // Corrupt the packets's last byte to make decryption fail (the last
-        // byte is part of the AEAD tag, so changing it means that the packet
-        // cannot be authenticated during decryption).
-        buf[written - 1] = !buf[written - 1];
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
 
-        assert_eq!(pipe.server.timeout(), None);
+        let mut iter = frames.iter();
 
         assert_eq!(
-            pipe.server_recv(&mut buf[..written]),
-            Err(Error::CryptoFail)
+            iter.next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            })
         );
 
-        assert!(pipe.server.is_closed());
-    }
+        assert_eq!(iter.next(), None);
 
-    #[test]
-    /// Tests that invalid Initial packets received to cause
-    /// the client to close the connection immediately.
-    fn invalid_initial_client() {
-        let mut buf = [0; 65535];
-        let mut pipe = testing::Pipe::default().unwrap();
+        // Send again from blocked stream and make sure it is marked as blocked
+        // again.
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaa", false),
+            Err(Error::Done)
+        );
+        assert_eq!(pipe.client.streams.blocked().len(), 1);
 
-        // Client sends initial flight.
         let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.streams.blocked().len(), 0);
 
-        // Server sends initial flight.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(1200));
-
-        let frames = [frame::Frame::Padding { len: 10 }];
-
-        let written = testing::encode_pkt(
-            &mut pipe.server,
-            packet::Type::Initial,
-            &frames,
-            &mut buf,
-        )
-        .unwrap();
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
 
-        // Corrupt the packets's last byte to make decryption fail (the last
-        // byte is part of the AEAD tag, so changing it means that the packet
-        // cannot be authenticated during decryption).
-        buf[written - 1] = !buf[written - 1];
+        let mut iter = frames.iter();
 
-        // Client will ignore invalid packet.
-        assert_eq!(pipe.client_recv(&mut buf[..written]), Ok(71));
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::StreamDataBlocked {
+                stream_id: 0,
+                limit: 15,
+            })
+        );
 
-        // The connection should be alive...
-        assert_eq!(pipe.client.is_closed(), false);
+        assert_eq!(iter.next(), Some(&frame::Frame::Padding { len: 1 }));
 
-        // ...and the idle timeout should be armed.
-        assert!(pipe.client.idle_timer.is_some());
+        assert_eq!(iter.next(), None);
     }
 
     #[test]
-    /// Tests that packets with invalid payload length received before any other
-    /// valid packet cause the server to close the connection immediately.
-    fn invalid_initial_payload() {
+    fn flow_control_blocked_events() {
         let mut buf = [0; 65535];
-        let mut pipe = testing::Pipe::default().unwrap();
 
-        let mut b = octets::OctetsMut::with_slice(&mut buf);
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
+        config.enable_events(true);
 
-        let epoch = packet::Type::Initial.to_epoch().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let pn = 0;
-        let pn_len = packet::pkt_num_len(pn).unwrap();
+        while pipe.client.poll_event().is_some() {}
+        while pipe.server.poll_event().is_some() {}
 
-        let hdr = Header {
-            ty: packet::Type::Initial,
-            version: pipe.client.version,
-            dcid: ConnectionId::from_ref(&pipe.client.dcid),
-            scid: ConnectionId::from_ref(&pipe.client.scid),
-            pkt_num: 0,
-            pkt_num_len: pn_len,
-            token: pipe.client.token.clone(),
-            versions: None,
-            key_phase: false,
-        };
+        // The stream is blocked first.
+        assert_eq!(pipe.client.stream_send(0, &[0; 20], false), Ok(15));
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(TransportEvent::StreamDataBlocked {
+                stream_id: 0,
+                limit: 15,
+            })
+        );
+        assert_eq!(pipe.client.poll_event(), None);
+
+        // Then the connection.
+        assert_eq!(pipe.client.stream_send(4, &[0; 20], false), Ok(15));
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(TransportEvent::DataBlocked { limit: 30 })
+        );
+        assert_eq!(pipe.client.poll_event(), None);
+
+        // Staying blocked doesn't raise new events.
+        assert_eq!(pipe.client.stream_send(8, &[0; 5], false), Err(Error::Done));
+        assert_eq!(pipe.client.poll_event(), None);
+
+        assert_eq!(pipe.advance(), Ok(()));
 
-        hdr.to_bytes(&mut b).unwrap();
+        let mut events = Vec::new();
 
-        // Payload length is invalid!!!
-        let payload_len = 4096;
+        while let Some(ev) = pipe.server.poll_event() {
+            events.push(ev);
+        }
 
-        let len = pn_len + payload_len;
-        b.put_varint(len as u64).unwrap();
+        assert!(events.contains(&TransportEvent::PeerDataBlocked { limit: 30 }));
+        assert!(events.contains(&TransportEvent::PeerStreamDataBlocked {
+            stream_id: 0,
+            limit: 15,
+        }));
 
-        packet::encode_pkt_num(pn, &mut b).unwrap();
+        let stats = pipe.client.stats();
+        assert_eq!(stats.data_blocked, 1);
+        assert_eq!(stats.stream_data_blocked, 1);
+        assert_eq!(stats.stream_data_blocked_time, time::Duration::ZERO);
 
-        let payload_offset = b.off();
+        let stats = pipe.server.stats();
+        assert!(stats.peer_data_blocked >= 1);
+        assert!(stats.peer_stream_data_blocked >= 1);
 
-        let frames = [frame::Frame::Padding { len: 10 }];
+        // Reading the data gives more credit to the client, which unblocks it.
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((15, false)));
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((15, false)));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        for frame in &frames {
-            frame.to_bytes(&mut b).unwrap();
-        }
+        assert!(pipe.client.data_blocked_since.is_none());
+        assert!(pipe.client.streams.get(0).unwrap().blocked_since.is_none());
 
-        let space = &mut pipe.client.pkt_num_spaces[epoch];
+        assert_eq!(pipe.client.stats().data_blocked, 1);
+        assert_eq!(pipe.client.stats().stream_data_blocked, 1);
+    }
 
-        // Use correct payload length when encrypting the packet.
-        let payload_len = frames.iter().fold(0, |acc, x| acc + x.wire_len());
+    #[test]
+    fn app_limited_true() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
 
-        let aead = space.crypto_seal.as_ref().unwrap();
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let written = packet::encrypt_pkt(
-            &mut b,
-            pn,
-            pn_len,
-            payload_len,
-            payload_offset,
-            None,
-            aead,
-        )
-        .unwrap();
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server.timeout(), None);
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            pipe.server_recv(&mut buf[..written]),
-            Err(Error::InvalidPacket)
-        );
+        // Server sends stream data smaller than cwnd.
+        let send_buf = [0; 10000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf, false), Ok(10000));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert!(pipe.server.is_closed());
+        // app_limited should be true because we send less than cwnd.
+        assert_eq!(pipe.server.recovery.app_limited(), true);
     }
 
     #[test]
-    /// Tests that invalid packets don't cause the connection to be closed.
-    fn invalid_packet() {
-        let mut buf = [0; 65535];
+    fn app_limited_false() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Padding { len: 10 }];
-
-        let written = testing::encode_pkt(
-            &mut pipe.client,
-            packet::Type::Short,
-            &frames,
-            &mut buf,
-        )
-        .unwrap();
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Corrupt the packets's last byte to make decryption fail (the last
-        // byte is part of the AEAD tag, so changing it means that the packet
-        // cannot be authenticated during decryption).
-        buf[written - 1] = !buf[written - 1];
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        // Server sends stream data bigger than cwnd.
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
 
-        // Corrupt the packets's first byte to make the header fail decoding.
-        buf[0] = 255;
+        testing::emit_flight(&mut pipe.server).ok();
 
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+        // We can't create a new packet header because there is no room by cwnd.
+        // app_limited should be false because we can't send more by cwnd.
+        assert_eq!(pipe.server.recovery.app_limited(), false);
     }
 
     #[test]
-    fn recv_empty_buffer() {
-        let mut buf = [0; 65535];
+    fn app_limited_false_no_frame() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1405);
+        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.server_recv(&mut buf[..0]), Err(Error::BufferTooShort));
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server sends stream data bigger than cwnd.
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+
+        testing::emit_flight(&mut pipe.server).ok();
+
+        // We can't create a new packet header because there is no room by cwnd.
+        // app_limited should be false because we can't send more by cwnd.
+        assert_eq!(pipe.server.recovery.app_limited(), false);
     }
 
     #[test]
-    /// Tests that the MAX_STREAMS frame is sent for bidirectional streams.
-    fn stream_limit_update_bidi() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
+    fn app_limited_false_no_header() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(0);
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1406);
         config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
         // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        // Server reads stream data.
+        let mut b = [0; 15];
+        pipe.server.stream_recv(0, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(4, b"b", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Server sends stream data bigger than cwnd.
+        let send_buf1 = [0; 20000];
+        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
 
-        assert_eq!(pipe.client.stream_send(0, b"b", true), Ok(1));
+        testing::emit_flight(&mut pipe.server).ok();
+
+        // We can't create a new frame because there is no room by cwnd.
+        // app_limited should be false because we can't send more by cwnd.
+        assert_eq!(pipe.server.recovery.app_limited(), false);
+    }
+
+    #[test]
+    fn app_limited_not_changed_on_no_new_frames() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
         // Server reads stream data.
         let mut b = [0; 15];
         pipe.server.stream_recv(0, &mut b).unwrap();
-        pipe.server.stream_recv(4, &mut b).unwrap();
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server sends stream data, with fin.
-        assert_eq!(pipe.server.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client's app_limited is true because its bytes-in-flight
+        // is much smaller than the current cwnd.
+        assert_eq!(pipe.client.recovery.app_limited(), true);
 
-        assert_eq!(pipe.server.stream_send(4, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client has no new frames to send - returns Done.
+        assert_eq!(testing::emit_flight(&mut pipe.client), Err(Error::Done));
 
-        assert_eq!(pipe.server.stream_send(4, b"b", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client's app_limited should remain the same.
+        assert_eq!(pipe.client.recovery.app_limited(), true);
+    }
 
-        assert_eq!(pipe.server.stream_send(0, b"b", true), Ok(1));
+    #[test]
+    fn limit_ack_ranges() {
+        let mut buf = [0; 65535];
 
-        // Server sends MAX_STREAMS.
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client tries to create new streams.
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let epoch = packet::EPOCH_APPLICATION;
 
-        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(), 0);
 
-        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let frames = [frame::Frame::Ping, frame::Frame::Padding { len: 3 }];
+
+        let pkt_type = packet::Type::Short;
+
+        let mut last_packet_sent = 0;
+
+        for _ in 0..512 {
+            let recv_count = pipe.server.recv_count;
+
+            last_packet_sent = pipe.client.pkt_num_spaces[epoch].next_pkt_num;
+
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
+                .unwrap();
+
+            assert_eq!(pipe.server.recv_count, recv_count + 1);
+
+            // Skip packet number.
+            pipe.client.pkt_num_spaces[epoch].next_pkt_num += 1;
+        }
+
+        assert_eq!(
+            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(),
+            MAX_ACK_RANGES
+        );
 
         assert_eq!(
-            pipe.client.stream_send(20, b"a", false),
-            Err(Error::StreamLimit)
+            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.first(),
+            Some(last_packet_sent - ((MAX_ACK_RANGES as u64) - 1) * 2)
         );
 
-        assert_eq!(pipe.server.readable().len(), 3);
+        assert_eq!(
+            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.last(),
+            Some(last_packet_sent)
+        );
     }
 
     #[test]
-    /// Tests that the MAX_STREAMS frame is sent for unidirectional streams.
-    fn stream_limit_update_uni() {
+    /// Tests that streams are correctly scheduled based on their priority.
+    fn stream_priority() {
+        // Limit 1-RTT packet size to avoid congestion control interference.
+        const MAX_TEST_PACKET_SIZE: usize = 540;
+
+        let mut buf = [0; 65535];
+
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -9009,264 +17153,220 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(0);
-        config.set_initial_max_streams_uni(3);
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
+        config.set_initial_max_stream_data_uni(0);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(0);
         config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(2, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(6, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(6, b"b", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(2, b"b", true), Ok(1));
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(2, &mut b).unwrap();
-        pipe.server.stream_recv(6, &mut b).unwrap();
-
-        // Server sends MAX_STREAMS.
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Client tries to create new streams.
-        assert_eq!(pipe.client.stream_send(10, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(14, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(18, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            pipe.client.stream_send(22, b"a", false),
-            Err(Error::StreamLimit)
-        );
-
-        assert_eq!(pipe.server.readable().len(), 3);
-    }
-
-    #[test]
-    /// Tests that the stream's fin flag is properly flushed even if there's no
-    /// data in the buffer, and that the buffer becomes readable on the other
-    /// side.
-    fn stream_zero_length_fin() {
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
-        );
+        assert_eq!(pipe.client.stream_send(20, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
-
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut b = [0; 1];
 
-        // Client sends zero-length frame.
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+        let out = [b'b'; 500];
 
-        // Stream should be readable on the server after receiving empty fin.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
+        // Server prioritizes streams as follows:
+        //  * Stream 8 and 16 have the same priority but are non-incremental.
+        //  * Stream 4, 12 and 20 have the same priority but 20 is non-incremental
+        //    and 4 and 12 are incremental.
+        //  * Stream 0 is on its own.
 
-        let mut b = [0; 15];
         pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Client sends zero-length frame (again).
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Stream should _not_ be readable on the server after receiving empty
-        // fin, because it was already finished.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
-    }
+        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
+        pipe.server.stream_send(0, &out, false).unwrap();
+        pipe.server.stream_send(0, &out, false).unwrap();
+        pipe.server.stream_send(0, &out, false).unwrap();
 
-    #[test]
-    /// Tests that the stream's fin flag is properly flushed even if there's no
-    /// data in the buffer, that the buffer becomes readable on the other
-    /// side and stays readable even if the stream is fin'd locally.
-    fn stream_zero_length_fin_deferred_collection() {
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        pipe.server.stream_recv(12, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
+        pipe.server.stream_send(12, &out, false).unwrap();
+        pipe.server.stream_send(12, &out, false).unwrap();
+        pipe.server.stream_send(12, &out, false).unwrap();
 
-        assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
-        );
-        assert_eq!(pipe.advance(), Ok(()));
+        pipe.server.stream_recv(16, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(16, 10, false), Ok(()));
+        pipe.server.stream_send(16, &out, false).unwrap();
+        pipe.server.stream_send(16, &out, false).unwrap();
+        pipe.server.stream_send(16, &out, false).unwrap();
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
+        pipe.server.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
+        pipe.server.stream_send(4, &out, false).unwrap();
+        pipe.server.stream_send(4, &out, false).unwrap();
+        pipe.server.stream_send(4, &out, false).unwrap();
 
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        pipe.server.stream_recv(8, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(8, 10, false), Ok(()));
+        pipe.server.stream_send(8, &out, false).unwrap();
+        pipe.server.stream_send(8, &out, false).unwrap();
+        pipe.server.stream_send(8, &out, false).unwrap();
 
-        // Client sends zero-length frame.
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+        pipe.server.stream_recv(20, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(20, 42, false), Ok(()));
+        pipe.server.stream_send(20, &out, false).unwrap();
+        pipe.server.stream_send(20, &out, false).unwrap();
+        pipe.server.stream_send(20, &out, false).unwrap();
 
-        // Server sends zero-length frame.
-        assert_eq!(pipe.server.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+        // First is stream 8.
+        let mut off = 0;
 
-        // Stream should be readable on the server after receiving empty fin.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(0));
-        assert!(r.next().is_none());
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+            let stream = frames.iter().next().unwrap();
 
-        // Client sends zero-length frame (again).
-        assert_eq!(pipe.client.stream_send(0, b"", true), Ok(0));
-        assert_eq!(pipe.advance(), Ok(()));
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
 
-        // Stream should _not_ be readable on the server after receiving empty
-        // fin, because it was already finished.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
 
-        // Stream _is_readable on the client side.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(0));
+                _ => unreachable!(),
+            };
+        }
 
-        pipe.client.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        // Then is stream 16.
+        let mut off = 0;
 
-        // Stream is completed and _is not_ readable.
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), None);
-    }
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-    #[test]
-    /// Tests that the stream gets created with stream_send() even if there's
-    /// no data in the buffer and the fin flag is not set.
-    fn stream_zero_length_non_fin() {
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+            let stream = frames.iter().next().unwrap();
 
-        assert_eq!(pipe.client.stream_send(0, b"", false), Ok(0));
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 16,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
 
-        // The stream now should have been created.
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.advance(), Ok(()));
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
 
-        // Sending an empty non-fin should not change any stream state on the
-        // other side.
-        let mut r = pipe.server.readable();
-        assert!(r.next().is_none());
-    }
+                _ => unreachable!(),
+            };
+        }
 
-    #[test]
-    /// Tests that completed streams are garbage collected.
-    fn collect_streams() {
-        let mut buf = [0; 65535];
+        // Then is stream 20.
+        let mut off = 0;
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+            let stream = frames.iter().next().unwrap();
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 20,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
 
-        assert!(!pipe.client.stream_finished(0));
-        assert!(!pipe.server.stream_finished(0));
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
 
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 1);
+                _ => unreachable!(),
+            };
+        }
 
-        let mut b = [0; 5];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        // Then are stream 12 and 4, with the same priority, incrementally.
+        let mut off = 0;
 
-        assert_eq!(pipe.server.stream_send(0, b"aaaaa", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-        assert!(!pipe.client.stream_finished(0));
-        assert!(pipe.server.stream_finished(0));
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
 
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 0);
+            assert_eq!(
+                frames.iter().next(),
+                Some(&frame::Frame::Stream {
+                    stream_id: 12,
+                    data: stream::RangeBuf::from(&out, off, false),
+                })
+            );
 
-        let mut b = [0; 5];
-        pipe.client.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
 
-        assert!(pipe.client.stream_finished(0));
-        assert!(pipe.server.stream_finished(0));
+            let stream = frames.iter().next().unwrap();
 
-        assert_eq!(pipe.client.stream_send(0, b"", true), Err(Error::Done));
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 0,
-            data: stream::RangeBuf::from(b"aa", 0, false),
-        }];
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
-    }
+                _ => unreachable!(),
+            };
+        }
 
-    #[test]
-    fn config_set_cc_algorithm_name() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        // Final is stream 0.
+        let mut off = 0;
 
-        assert_eq!(config.set_cc_algorithm_name("reno"), Ok(()));
+        for _ in 1..=3 {
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-        // Unknown name.
-        assert_eq!(
-            config.set_cc_algorithm_name("???"),
-            Err(Error::CongestionControl)
-        );
-    }
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+            let stream = frames.iter().next().unwrap();
 
-    #[test]
-    fn peer_cert() {
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(&out, off, false),
+            });
 
-        match pipe.client.peer_cert() {
-            Some(c) => assert_eq!(c.len(), 753),
+            off = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
 
-            None => panic!("missing server certificate"),
+                _ => unreachable!(),
+            };
         }
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
     }
 
     #[test]
-    fn retry() {
+    /// Tests that changing a stream's priority is correctly propagated.
+    fn stream_reprioritize() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -9276,115 +17376,117 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(0);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(0);
+        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
-
-        // Client sends initial flight.
-        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
-
-        // Server sends Retry packet.
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
-
-        let odcid = hdr.dcid.clone();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut scid = [0; MAX_CONN_ID_LEN];
-        rand::rand_bytes(&mut scid[..]);
-        let scid = ConnectionId::from_ref(&scid);
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let token = b"quiche test retry token";
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        len = packet::retry(
-            &hdr.scid,
-            &hdr.dcid,
-            &scid,
-            token,
-            hdr.version,
-            &mut buf,
-        )
-        .unwrap();
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client receives Retry and sends new Initial.
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut b = [0; 1];
 
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
-        assert_eq!(&hdr.token.unwrap(), token);
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
+        pipe.server.stream_send(0, b"b", false).unwrap();
 
-        // Server accepts connection.
-        let from = "127.0.0.1:1234".parse().unwrap();
-        pipe.server = accept(&scid, Some(&odcid), from, &mut config).unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        pipe.server.stream_recv(12, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
+        pipe.server.stream_send(12, b"b", false).unwrap();
 
-        assert_eq!(pipe.advance(), Ok(()));
+        pipe.server.stream_recv(8, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(8, 10, true), Ok(()));
+        pipe.server.stream_send(8, b"b", false).unwrap();
 
-        assert!(pipe.client.is_established());
-        assert!(pipe.server.is_established());
-    }
+        pipe.server.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
+        pipe.server.stream_send(4, b"b", false).unwrap();
 
-    #[test]
-    fn missing_retry_source_connection_id() {
-        let mut buf = [0; 65535];
+        // Stream 0 is re-prioritized!!!
+        assert_eq!(pipe.server.stream_priority(0, 20, true), Ok(()));
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
+        // First is stream 8.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
 
-        // Client sends initial flight.
-        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(
+            frames.iter().next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
+        );
 
-        // Server sends Retry packet.
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        // Then is stream 0.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-        let mut scid = [0; MAX_CONN_ID_LEN];
-        rand::rand_bytes(&mut scid[..]);
-        let scid = ConnectionId::from_ref(&scid);
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
 
-        let token = b"quiche test retry token";
+        assert_eq!(
+            frames.iter().next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
+        );
 
-        len = packet::retry(
-            &hdr.scid,
-            &hdr.dcid,
-            &scid,
-            token,
-            hdr.version,
-            &mut buf,
-        )
-        .unwrap();
+        // Then are stream 12 and 4, with the same priority.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-        // Client receives Retry and sends new Initial.
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(
+            frames.iter().next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
+        );
 
-        // Server accepts connection and send first flight. But original
-        // destination connection ID is ignored.
-        let from = "127.0.0.1:1234".parse().unwrap();
-        pipe.server = accept(&scid, None, from, &mut config).unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
 
         assert_eq!(
-            testing::process_flight(&mut pipe.client, flight),
-            Err(Error::InvalidTransportParam)
+            frames.iter().next(),
+            Some(&frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"b", 0, false),
+            })
         );
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
     }
 
-    #[test]
-    fn invalid_retry_source_connection_id() {
+    #[test]
+    /// Tests that streams and datagrams are correctly scheduled.
+    fn stream_datagram_priority() {
+        // Limit 1-RTT packet size to avoid congestion control interference.
+        const MAX_TEST_PACKET_SIZE: usize = 540;
+
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -9394,441 +17496,350 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
+        config.set_initial_max_stream_data_uni(0);
+        config.set_initial_max_streams_bidi(100);
+        config.set_initial_max_streams_uni(0);
+        config.enable_dgram(true, 10, 10);
+        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
-
-        // Client sends initial flight.
-        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
-
-        // Server sends Retry packet.
-        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut scid = [0; MAX_CONN_ID_LEN];
-        rand::rand_bytes(&mut scid[..]);
-        let scid = ConnectionId::from_ref(&scid);
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let token = b"quiche test retry token";
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        len = packet::retry(
-            &hdr.scid,
-            &hdr.dcid,
-            &scid,
-            token,
-            hdr.version,
-            &mut buf,
-        )
-        .unwrap();
+        let mut b = [0; 1];
 
-        // Client receives Retry and sends new Initial.
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        let out = [b'b'; 500];
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        // Server prioritizes Stream 0 and 4 with the same urgency with
+        // incremental, meaning the frames should be sent in round-robin
+        // fashion. It also sends DATAGRAMS which are always interleaved with
+        // STREAM frames. So we'll expect a mix of frame types regardless
+        // of the order that the application writes things in.
 
-        // Server accepts connection and send first flight. But original
-        // destination connection ID is invalid.
-        let from = "127.0.0.1:1234".parse().unwrap();
-        let odcid = ConnectionId::from_ref(b"bogus value");
-        pipe.server = accept(&scid, Some(&odcid), from, &mut config).unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
+        pipe.server.stream_send(0, &out, false).unwrap();
+        pipe.server.stream_send(0, &out, false).unwrap();
+        pipe.server.stream_send(0, &out, false).unwrap();
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        assert_eq!(pipe.server.stream_priority(4, 255, true), Ok(()));
+        pipe.server.stream_send(4, &out, false).unwrap();
+        pipe.server.stream_send(4, &out, false).unwrap();
+        pipe.server.stream_send(4, &out, false).unwrap();
 
-        assert_eq!(
-            testing::process_flight(&mut pipe.client, flight),
-            Err(Error::InvalidTransportParam)
-        );
-    }
+        for _ in 1..=6 {
+            assert_eq!(pipe.server.dgram_send(&out), Ok(()));
+        }
 
-    fn check_send(_: &mut impl Send) {}
+        let mut off_0 = 0;
+        let mut off_4 = 0;
 
-    #[test]
-    fn config_must_be_send() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        check_send(&mut config);
-    }
+        for _ in 1..=3 {
+            // DATAGRAM
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-    #[test]
-    fn connection_must_be_send() {
-        let mut pipe = testing::Pipe::default().unwrap();
-        check_send(&mut pipe.client);
-    }
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+            let mut frame_iter = frames.iter();
 
-    fn check_sync(_: &mut impl Sync) {}
+            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
+                data: out.into(),
+            });
+            assert_eq!(frame_iter.next(), None);
 
-    #[test]
-    fn config_must_be_sync() {
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        check_sync(&mut config);
-    }
+            // STREAM 0
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-    #[test]
-    fn connection_must_be_sync() {
-        let mut pipe = testing::Pipe::default().unwrap();
-        check_sync(&mut pipe.client);
-    }
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+            let mut frame_iter = frames.iter();
+            let stream = frame_iter.next().unwrap();
 
-    #[test]
-    fn data_blocked() {
-        let mut buf = [0; 65535];
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(&out, off_0, false),
+            });
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+            off_0 = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaaaaaaa", false), Ok(10));
-        assert_eq!(pipe.client.blocked_limit, None);
-        assert_eq!(pipe.advance(), Ok(()));
+                _ => unreachable!(),
+            };
+            assert_eq!(frame_iter.next(), None);
 
-        assert_eq!(pipe.client.stream_send(4, b"aaaaaaaaaa", false), Ok(10));
-        assert_eq!(pipe.client.blocked_limit, None);
-        assert_eq!(pipe.advance(), Ok(()));
+            // DATAGRAM
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaaaaaaaa", false), Ok(10));
-        assert_eq!(pipe.client.blocked_limit, Some(30));
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+            let mut frame_iter = frames.iter();
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.blocked_limit, None);
+            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
+                data: out.into(),
+            });
+            assert_eq!(frame_iter.next(), None);
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+            // STREAM 4
+            let (len, _) =
+                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
 
-        let mut iter = frames.iter();
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+            let mut frame_iter = frames.iter();
+            let stream = frame_iter.next().unwrap();
 
-        assert_eq!(iter.next(), Some(&frame::Frame::DataBlocked { limit: 30 }));
+            assert_eq!(stream, &frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(&out, off_4, false),
+            });
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"aaaaaaaaaa", 0, false),
-            })
-        );
+            off_4 = match stream {
+                frame::Frame::Stream { data, .. } => data.max_off(),
 
-        assert_eq!(iter.next(), None);
+                _ => unreachable!(),
+            };
+            assert_eq!(frame_iter.next(), None);
+        }
     }
 
     #[test]
-    fn stream_data_blocked() {
+    /// Tests that old data is retransmitted on PTO.
+    fn early_retransmit() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-
-        assert_eq!(pipe.client.stream_send(0, b"aaaaaa", false), Ok(5));
-        assert_eq!(pipe.client.streams.blocked().len(), 1);
-
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
-
-        let mut iter = frames.iter();
-
-        // Skip ACK frame.
-        iter.next();
-
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::StreamDataBlocked {
-                stream_id: 0,
-                limit: 15,
-            })
-        );
-
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            })
-        );
-
-        assert_eq!(iter.next(), None);
-
-        // Send from another stream, make sure we don't send STREAM_DATA_BLOCKED
-        // again.
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
-
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
-
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+        // Client sends stream data.
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut iter = frames.iter();
+        // Client sends more stream data, but packet is lost
+        assert_eq!(pipe.client.stream_send(4, b"b", false), Ok(1));
+        assert!(pipe.client.send(&mut buf).is_ok());
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            })
-        );
+        // Wait until PTO expires. Since the RTT is very low, wait a bit more.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
 
-        assert_eq!(iter.next(), None);
+        pipe.client.on_timeout();
 
-        // Send again from blocked stream and make sure it is marked as blocked
-        // again.
-        assert_eq!(
-            pipe.client.stream_send(0, b"aaaaaa", false),
-            Err(Error::Done)
-        );
-        assert_eq!(pipe.client.streams.blocked().len(), 1);
+        let epoch = packet::EPOCH_APPLICATION;
+        assert_eq!(pipe.client.recovery.loss_probes[epoch], 1);
 
+        // Client retransmits stream data in PTO probe.
         let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.streams.blocked().len(), 0);
+        assert_eq!(pipe.client.recovery.loss_probes[epoch], 0);
 
         let frames =
             testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
 
         let mut iter = frames.iter();
 
+        // Skip ACK frame.
+        iter.next();
+
         assert_eq!(
             iter.next(),
-            Some(&frame::Frame::StreamDataBlocked {
-                stream_id: 0,
-                limit: 15,
+            Some(&frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"b", 0, false),
             })
         );
-
-        assert_eq!(iter.next(), Some(&frame::Frame::Padding { len: 1 }));
-
-        assert_eq!(iter.next(), None);
     }
 
     #[test]
-    fn app_limited_true() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+    /// Tests that PTO probe packets are not coalesced together.
+    fn dont_coalesce_probes() {
+        let mut buf = [0; 65535];
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut pipe = testing::Pipe::default().unwrap();
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client sends Initial packet.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
 
-        // Server sends stream data smaller than cwnd.
-        let send_buf = [0; 10000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf, false), Ok(10000));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Wait for PTO to expire.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
 
-        // app_limited should be true because we send less than cwnd.
-        assert_eq!(pipe.server.recovery.app_limited(), true);
-    }
+        pipe.client.on_timeout();
 
-    #[test]
-    fn app_limited_false() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
+        let epoch = packet::EPOCH_INITIAL;
+        assert_eq!(pipe.client.recovery.loss_probes[epoch], 1);
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        // Client sends PTO probe.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+        assert_eq!(pipe.client.recovery.loss_probes[epoch], 0);
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Wait for PTO to expire.
+        let timer = pipe.client.timeout().unwrap();
+        std::thread::sleep(timer + time::Duration::from_millis(1));
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        pipe.client.on_timeout();
 
-        // Server sends stream data bigger than cwnd.
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+        assert_eq!(pipe.client.recovery.loss_probes[epoch], 2);
 
-        testing::emit_flight(&mut pipe.server).ok();
+        // Client sends first PTO probe.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+        assert_eq!(pipe.client.recovery.loss_probes[epoch], 1);
 
-        // We can't create a new packet header because there is no room by cwnd.
-        // app_limited should be false because we can't send more by cwnd.
-        assert_eq!(pipe.server.recovery.app_limited(), false);
+        // Client sends second PTO probe.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+        assert_eq!(pipe.client.recovery.loss_probes[epoch], 0);
     }
 
     #[test]
-    fn app_limited_false_no_frame() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1405);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Server sends stream data bigger than cwnd.
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+    fn coalesce_padding_short() {
+        let mut buf = [0; 65535];
 
-        testing::emit_flight(&mut pipe.server).ok();
+        let mut pipe = testing::Pipe::default().unwrap();
 
-        // We can't create a new packet header because there is no room by cwnd.
-        // app_limited should be false because we can't send more by cwnd.
-        assert_eq!(pipe.server.recovery.app_limited(), false);
-    }
+        // Client sends first flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-    #[test]
-    fn app_limited_false_no_header() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1406);
-        config.verify_peer(false);
+        // Server sends first flight.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
         // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Server sends stream data bigger than cwnd.
-        let send_buf1 = [0; 20000];
-        assert_eq!(pipe.server.stream_send(0, &send_buf1, false), Ok(12000));
+        assert_eq!(pipe.client.is_established(), true);
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
 
-        testing::emit_flight(&mut pipe.server).ok();
+        // Client sends second flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        // We can't create a new frame because there is no room by cwnd.
-        // app_limited should be false because we can't send more by cwnd.
-        assert_eq!(pipe.server.recovery.app_limited(), false);
+        // None of the sent packets should have been dropped.
+        assert_eq!(pipe.client.sent_count, pipe.server.recv_count);
+        assert_eq!(pipe.server.sent_count, pipe.client.recv_count);
     }
 
     #[test]
-    fn app_limited_not_changed_on_no_new_frames() {
+    /// Tests that client avoids handshake deadlock by arming PTO.
+    fn handshake_anti_deadlock() {
+        let mut buf = [0; 65535];
+
         let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(50000);
-        config.set_initial_max_stream_data_bidi_local(50000);
-        config.set_initial_max_stream_data_bidi_remote(50000);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.handshake_status().has_handshake_keys, false);
+        assert_eq!(pipe.client.handshake_status().peer_verified_address, false);
+        assert_eq!(pipe.server.handshake_status().has_handshake_keys, false);
+        assert_eq!(pipe.server.handshake_status().peer_verified_address, true);
 
-        // Server reads stream data.
-        let mut b = [0; 15];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        // Client sends padded Initial.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
 
-        // Client's app_limited is true because its bytes-in-flight
-        // is much smaller than the current cwnd.
-        assert_eq!(pipe.client.recovery.app_limited(), true);
+        // Server receives client's Initial and sends own Initial and Handshake
+        // until it's blocked by the anti-amplification limit.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
 
-        // Client has no new frames to send - returns Done.
-        assert_eq!(testing::emit_flight(&mut pipe.client), Err(Error::Done));
+        assert_eq!(pipe.client.handshake_status().has_handshake_keys, false);
+        assert_eq!(pipe.client.handshake_status().peer_verified_address, false);
+        assert_eq!(pipe.server.handshake_status().has_handshake_keys, true);
+        assert_eq!(pipe.server.handshake_status().peer_verified_address, true);
 
-        // Client's app_limited should remain the same.
-        assert_eq!(pipe.client.recovery.app_limited(), true);
+        // Client receives the server flight and sends Handshake ACK, but it is
+        // lost.
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+        testing::emit_flight(&mut pipe.client).unwrap();
+
+        assert_eq!(pipe.client.handshake_status().has_handshake_keys, true);
+        assert_eq!(pipe.client.handshake_status().peer_verified_address, false);
+        assert_eq!(pipe.server.handshake_status().has_handshake_keys, true);
+        assert_eq!(pipe.server.handshake_status().peer_verified_address, true);
+
+        // Make sure client's PTO timer is armed.
+        assert!(pipe.client.timeout().is_some());
     }
 
     #[test]
-    fn limit_ack_ranges() {
+    /// Tests that packets with corrupted type (from Handshake to Initial) are
+    /// properly ignored.
+    fn handshake_packet_type_corruption() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
 
-        let epoch = packet::EPOCH_APPLICATION;
-
-        assert_eq!(pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(), 0);
-
-        let frames = [frame::Frame::Ping, frame::Frame::Padding { len: 3 }];
+        // Client sends padded Initial.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
 
-        let pkt_type = packet::Type::Short;
+        // Server receives client's Initial and sends own Initial and Handshake.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        let mut last_packet_sent = 0;
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        for _ in 0..512 {
-            let recv_count = pipe.server.recv_count;
+        // Client sends Initial packet with ACK.
+        let (ty, len) = pipe.client.send_single(&mut buf, false).unwrap();
+        assert_eq!(ty, Type::Initial);
 
-            last_packet_sent = pipe.client.pkt_num_spaces[epoch].next_pkt_num;
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf)
-                .unwrap();
+        // Client sends Handshake packet.
+        let (ty, len) = pipe.client.send_single(&mut buf, false).unwrap();
+        assert_eq!(ty, Type::Handshake);
 
-            assert_eq!(pipe.server.recv_count, recv_count + 1);
+        // Packet type is corrupted to Initial.
+        buf[0] &= !(0x20);
 
-            // Skip packet number.
-            pipe.client.pkt_num_spaces[epoch].next_pkt_num += 1;
-        }
+        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.ty, Type::Initial);
 
-        assert_eq!(
-            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.len(),
-            MAX_ACK_RANGES
-        );
+        // Server receives corrupted packet without returning an error.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+    }
 
-        assert_eq!(
-            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.first(),
-            Some(last_packet_sent - ((MAX_ACK_RANGES as u64) - 1) * 2)
-        );
+    #[test]
+    fn dgram_send_fails_invalidstate() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
         assert_eq!(
-            pipe.server.pkt_num_spaces[epoch].recv_pkt_need_ack.last(),
-            Some(last_packet_sent)
+            pipe.client.dgram_send(b"hello, world"),
+            Err(Error::InvalidState)
         );
     }
 
     #[test]
-    /// Tests that streams are correctly scheduled based on their priority.
-    fn stream_priority() {
-        // Limit 1-RTT packet size to avoid congestion control interference.
-        const MAX_TEST_PACKET_SIZE: usize = 540;
-
+    fn dgram_send_app_limited() {
         let mut buf = [0; 65535];
+        let send_buf = [0xcf; 1000];
 
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
@@ -9840,220 +17851,234 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(1_000_000);
-        config.set_initial_max_stream_data_bidi_local(1_000_000);
-        config.set_initial_max_stream_data_bidi_remote(1_000_000);
-        config.set_initial_max_stream_data_uni(0);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(0);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 1000, 1000);
+        config.set_max_recv_udp_payload_size(1200);
         config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(16, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(20, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut b = [0; 1];
-
-        let out = [b'b'; 500];
-
-        // Server prioritizes streams as follows:
-        //  * Stream 8 and 16 have the same priority but are non-incremental.
-        //  * Stream 4, 12 and 20 have the same priority but 20 is non-incremental
-        //    and 4 and 12 are incremental.
-        //  * Stream 0 is on its own.
-
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
-        pipe.server.stream_send(0, &out, false).unwrap();
-        pipe.server.stream_send(0, &out, false).unwrap();
-        pipe.server.stream_send(0, &out, false).unwrap();
+        for _ in 0..1000 {
+            assert_eq!(pipe.client.dgram_send(&send_buf), Ok(()));
+        }
 
-        pipe.server.stream_recv(12, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
-        pipe.server.stream_send(12, &out, false).unwrap();
-        pipe.server.stream_send(12, &out, false).unwrap();
-        pipe.server.stream_send(12, &out, false).unwrap();
+        assert!(!pipe.client.recovery.app_limited());
+        assert_eq!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
 
-        pipe.server.stream_recv(16, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(16, 10, false), Ok(()));
-        pipe.server.stream_send(16, &out, false).unwrap();
-        pipe.server.stream_send(16, &out, false).unwrap();
-        pipe.server.stream_send(16, &out, false).unwrap();
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        pipe.server.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
-        pipe.server.stream_send(4, &out, false).unwrap();
-        pipe.server.stream_send(4, &out, false).unwrap();
-        pipe.server.stream_send(4, &out, false).unwrap();
+        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 0);
+        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
+        assert!(!pipe.client.recovery.app_limited());
 
-        pipe.server.stream_recv(8, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(8, 10, false), Ok(()));
-        pipe.server.stream_send(8, &out, false).unwrap();
-        pipe.server.stream_send(8, &out, false).unwrap();
-        pipe.server.stream_send(8, &out, false).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        pipe.server.stream_recv(20, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(20, 42, false), Ok(()));
-        pipe.server.stream_send(20, &out, false).unwrap();
-        pipe.server.stream_send(20, &out, false).unwrap();
-        pipe.server.stream_send(20, &out, false).unwrap();
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        // First is stream 8.
-        let mut off = 0;
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 0);
+        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-            let stream = frames.iter().next().unwrap();
+        assert!(!pipe.client.recovery.app_limited());
+    }
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
+    #[test]
+    fn dgram_single_datagram() {
+        let mut buf = [0; 65535];
 
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 10);
+        config.verify_peer(false);
 
-                _ => unreachable!(),
-            };
-        }
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Then is stream 16.
-        let mut off = 0;
+        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
 
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-            let stream = frames.iter().next().unwrap();
+        let result1 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result1, Ok(12));
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 16,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
+        let result2 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result2, Err(Error::Done));
+    }
 
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+    #[test]
+    fn dgram_send_on_path() {
+        let mut buf = [0; 65535];
 
-                _ => unreachable!(),
-            };
-        }
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 10);
+        config.verify_peer(false);
 
-        // Then is stream 20.
-        let mut off = 0;
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        let active = pipe.client.peer_addr;
+        let unknown = "127.0.0.1:9999".parse().unwrap();
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-            let stream = frames.iter().next().unwrap();
+        assert_eq!(
+            pipe.client
+                .dgram_send_on_path(b"hello, world", None, unknown),
+            Err(Error::InvalidState)
+        );
+        assert_eq!(
+            pipe.client.dgram_send_queue_len_on_path(None, unknown),
+            None
+        );
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 20,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
+        // The peer address alone doesn't identify the path.
+        assert_eq!(
+            pipe.client.dgram_send_on_path(
+                b"hello, world",
+                Some(unknown),
+                active
+            ),
+            Err(Error::InvalidState)
+        );
+        assert_eq!(
+            pipe.client
+                .dgram_send_queue_len_on_path(Some(unknown), active),
+            None
+        );
 
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        assert_eq!(
+            pipe.client
+                .dgram_send_on_path(b"hello, world", None, active),
+            Ok(())
+        );
+        assert_eq!(
+            pipe.client.dgram_send_vec_on_path(
+                b"ciao, mondo".to_vec(),
+                None,
+                active
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            pipe.client.dgram_send_queue_len_on_path(None, active),
+            Some(2)
+        );
 
-                _ => unreachable!(),
-            };
-        }
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Then are stream 12 and 4, with the same priority, incrementally.
-        let mut off = 0;
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Ok(12));
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Ok(11));
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Err(Error::Done));
+    }
 
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+    #[test]
+    fn dgram_multiple_datagrams() {
+        let mut buf = [0; 65535];
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 10);
+        config.verify_peer(false);
 
-            assert_eq!(
-                frames.iter().next(),
-                Some(&frame::Frame::Stream {
-                    stream_id: 12,
-                    data: stream::RangeBuf::from(&out, off, false),
-                })
-            );
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        assert_eq!(pipe.client.dgram_send_queue_len(), 0);
+        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 0);
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Ok(()));
 
-            let stream = frames.iter().next().unwrap();
+        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 34);
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
+        pipe.client
+            .dgram_purge_outgoing(|d: &[u8]| -> bool { d[0] == b'c' });
 
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        assert_eq!(pipe.client.dgram_send_queue_len(), 2);
+        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 23);
 
-                _ => unreachable!(),
-            };
-        }
+        // Before packets exchanged, no dgrams on server receive side.
+        assert_eq!(pipe.server.dgram_recv_queue_len(), 0);
 
-        // Final is stream 0.
-        let mut off = 0;
+        assert_eq!(pipe.advance(), Ok(()));
 
-        for _ in 1..=3 {
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+        // After packets exchanged, no dgrams on client send side.
+        assert_eq!(pipe.client.dgram_send_queue_len(), 0);
+        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 0);
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-            let stream = frames.iter().next().unwrap();
+        assert_eq!(pipe.server.dgram_recv_queue_len(), 2);
+        assert_eq!(pipe.server.dgram_recv_queue_byte_size(), 23);
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(&out, off, false),
-            });
+        let result1 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result1, Ok(12));
+        assert_eq!(buf[0], b'h');
+        assert_eq!(buf[1], b'e');
 
-            off = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        let result2 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result2, Ok(11));
+        assert_eq!(buf[0], b'h');
+        assert_eq!(buf[1], b'o');
 
-                _ => unreachable!(),
-            };
-        }
+        let result3 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result3, Err(Error::Done));
 
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        assert_eq!(pipe.server.dgram_recv_queue_len(), 0);
+        assert_eq!(pipe.server.dgram_recv_queue_byte_size(), 0);
     }
 
     #[test]
-    /// Tests that changing a stream's priority is correctly propagated.
-    ///
-    /// Re-prioritization is not supported, so this should fail.
-    #[should_panic]
-    fn stream_reprioritize() {
+    fn dgram_send_ttl() {
         let mut buf = [0; 65535];
 
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
@@ -10069,112 +18094,126 @@ mod tests {
         config.set_initial_max_data(30);
         config.set_initial_max_stream_data_bidi_local(15);
         config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(0);
-        config.set_initial_max_streams_bidi(5);
-        config.set_initial_max_streams_uni(0);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 10);
         config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        let ttl = time::Duration::from_secs(0);
 
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.dgram_send_with_ttl(b"stale", ttl), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.dgram_send_queue_len(), 2);
+        assert_eq!(pipe.client.stats().dgram_send_queue_bytes, 17);
 
-        assert_eq!(pipe.client.stream_send(12, b"a", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut b = [0; 1];
-
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
-        pipe.server.stream_send(0, b"b", false).unwrap();
-
-        pipe.server.stream_recv(12, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(12, 42, true), Ok(()));
-        pipe.server.stream_send(12, b"b", false).unwrap();
+        // The expired DATAGRAM is dropped instead of being sent.
+        assert_eq!(pipe.client.dgram_send_queue_len(), 0);
+        assert_eq!(pipe.client.stats().dgram_sent_dropped_expired, 1);
 
-        pipe.server.stream_recv(8, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(8, 10, true), Ok(()));
-        pipe.server.stream_send(8, b"b", false).unwrap();
+        assert_eq!(pipe.server.dgram_recv_queue_len(), 1);
+        assert_eq!(pipe.server.stats().dgram_recv_queue_bytes, 12);
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Ok(12));
+    }
 
-        pipe.server.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(4, 42, true), Ok(()));
-        pipe.server.stream_send(4, b"b", false).unwrap();
+    #[test]
+    fn dgram_recv_ttl() {
+        let mut buf = [0; 65535];
 
-        // Stream 0 is re-prioritized!!!
-        assert_eq!(pipe.server.stream_priority(0, 20, true), Ok(()));
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 10);
+        config.set_dgram_recv_ttl(0);
+        config.verify_peer(false);
 
-        // First is stream 8.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            frames.iter().next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
-        );
+        assert_eq!(pipe.server.dgram_recv_queue_len(), 1);
 
-        // Then is stream 0.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        // The DATAGRAM expired before the application read it.
+        assert_eq!(pipe.server.dgram_recv(&mut buf), Err(Error::Done));
+        assert_eq!(pipe.server.stats().dgram_recv_dropped_expired, 1);
+    }
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+    #[test]
+    fn dgram_send_order_datagram_first() {
+        let mut buf = [0; 65535];
+        let send_buf = [0xcf; 1000];
 
-        assert_eq!(
-            frames.iter().next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
-        );
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 10);
+        config.set_dgram_send_order(DgramSendOrder::DatagramFirst);
+        config.verify_peer(false);
 
-        // Then are stream 12 and 4, with the same priority.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
 
-        assert_eq!(
-            frames.iter().next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
-        );
+        for _ in 0..3 {
+            assert_eq!(pipe.client.dgram_send(&send_buf), Ok(()));
+        }
 
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        // All DATAGRAMs are sent before the stream data.
+        for i in 1..=3 {
+            let (len, _) = pipe.client.send(&mut buf).unwrap();
+            assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+            assert_eq!(pipe.server.dgram_recv_queue_len(), i);
+            assert_eq!(pipe.server.readable().len(), 0);
+        }
 
-        assert_eq!(
-            frames.iter().next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
-        );
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
     }
 
-    #[test]
-    /// Tests that streams and datagrams are correctly scheduled.
-    fn stream_datagram_priority() {
-        // Limit 1-RTT packet size to avoid congestion control interference.
-        const MAX_TEST_PACKET_SIZE: usize = 540;
-
+    #[test]
+    fn dgram_send_weight() {
         let mut buf = [0; 65535];
+        let send_buf = [0xcf; 1000];
 
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
@@ -10186,254 +18225,197 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(1_000_000);
-        config.set_initial_max_stream_data_bidi_local(1_000_000);
-        config.set_initial_max_stream_data_bidi_remote(1_000_000);
-        config.set_initial_max_stream_data_uni(0);
-        config.set_initial_max_streams_bidi(100);
-        config.set_initial_max_streams_uni(0);
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
         config.enable_dgram(true, 10, 10);
+        config.set_dgram_send_weight(2);
         config.verify_peer(false);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        let mut b = [0; 1];
-
-        let out = [b'b'; 500];
-
-        // Server prioritizes Stream 0 and 4 with the same urgency with
-        // incremental, meaning the frames should be sent in round-robin
-        // fashion. It also sends DATAGRAMS which are always interleaved with
-        // STREAM frames. So we'll expect a mix of frame types regardless
-        // of the order that the application writes things in.
-
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.server.stream_priority(0, 255, true), Ok(()));
-        pipe.server.stream_send(0, &out, false).unwrap();
-        pipe.server.stream_send(0, &out, false).unwrap();
-        pipe.server.stream_send(0, &out, false).unwrap();
-
-        assert_eq!(pipe.server.stream_priority(4, 255, true), Ok(()));
-        pipe.server.stream_send(4, &out, false).unwrap();
-        pipe.server.stream_send(4, &out, false).unwrap();
-        pipe.server.stream_send(4, &out, false).unwrap();
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
 
-        for _ in 1..=6 {
-            assert_eq!(pipe.server.dgram_send(&out), Ok(()));
+        for _ in 0..3 {
+            assert_eq!(pipe.client.dgram_send(&send_buf), Ok(()));
         }
 
-        let mut off_0 = 0;
-        let mut off_4 = 0;
-
-        for _ in 1..=3 {
-            // DATAGRAM
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
-
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-            let mut frame_iter = frames.iter();
-
-            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
-                data: out.into(),
-            });
-            assert_eq!(frame_iter.next(), None);
-
-            // STREAM 0
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
-
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-            let mut frame_iter = frames.iter();
-            let stream = frame_iter.next().unwrap();
-
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(&out, off_0, false),
-            });
-
-            off_0 = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
-
-                _ => unreachable!(),
-            };
-            assert_eq!(frame_iter.next(), None);
+        // Two DATAGRAMs are sent before the stream data gets a turn.
+        for i in 1..=2 {
+            let (len, _) = pipe.client.send(&mut buf).unwrap();
+            assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-            // DATAGRAM
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
-
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-            let mut frame_iter = frames.iter();
-
-            assert_eq!(frame_iter.next().unwrap(), &frame::Frame::Datagram {
-                data: out.into(),
-            });
-            assert_eq!(frame_iter.next(), None);
-
-            // STREAM 4
-            let (len, _) =
-                pipe.server.send(&mut buf[..MAX_TEST_PACKET_SIZE]).unwrap();
+            assert_eq!(pipe.server.dgram_recv_queue_len(), i);
+            assert_eq!(pipe.server.readable().len(), 0);
+        }
 
-            let frames =
-                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-            let mut frame_iter = frames.iter();
-            let stream = frame_iter.next().unwrap();
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-            assert_eq!(stream, &frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(&out, off_4, false),
-            });
+        assert_eq!(pipe.server.dgram_recv_queue_len(), 2);
+        assert_eq!(pipe.server.readable().len(), 1);
 
-            off_4 = match stream {
-                frame::Frame::Stream { data, .. } => data.max_off(),
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-                _ => unreachable!(),
-            };
-            assert_eq!(frame_iter.next(), None);
-        }
+        assert_eq!(pipe.server.dgram_recv_queue_len(), 3);
     }
 
     #[test]
-    /// Tests that old data is retransmitted on PTO.
-    fn early_retransmit() {
+    fn dgram_send_queue_overflow() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        // Client sends stream data.
-        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
-        assert_eq!(pipe.advance(), Ok(()));
-
-        // Client sends more stream data, but packet is lost
-        assert_eq!(pipe.client.stream_send(4, b"b", false), Ok(1));
-        assert!(pipe.client.send(&mut buf).is_ok());
-
-        // Wait until PTO expires. Since the RTT is very low, wait a bit more.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
-
-        pipe.client.on_timeout();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 2);
+        config.verify_peer(false);
 
-        let epoch = packet::EPOCH_APPLICATION;
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 1);
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client retransmits stream data in PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 0);
+        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Err(Error::Done));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut iter = frames.iter();
+        let result1 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result1, Ok(12));
+        assert_eq!(buf[0], b'h');
+        assert_eq!(buf[1], b'e');
 
-        // Skip ACK frame.
-        iter.next();
+        let result2 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result2, Ok(11));
+        assert_eq!(buf[0], b'c');
+        assert_eq!(buf[1], b'i');
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"b", 0, false),
-            })
-        );
+        let result3 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result3, Err(Error::Done));
     }
 
     #[test]
-    /// Tests that PTO probe packets are not coalesced together.
-    fn dont_coalesce_probes() {
+    fn dgram_recv_queue_overflow() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 2, 10);
+        config.set_max_recv_udp_payload_size(1200);
+        config.verify_peer(false);
 
-        // Client sends Initial packet.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Wait for PTO to expire.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
+        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Ok(()));
 
-        pipe.client.on_timeout();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let epoch = packet::EPOCH_INITIAL;
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 1);
+        let result1 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result1, Ok(11));
+        assert_eq!(buf[0], b'c');
+        assert_eq!(buf[1], b'i');
 
-        // Client sends PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 0);
+        let result2 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result2, Ok(11));
+        assert_eq!(buf[0], b'h');
+        assert_eq!(buf[1], b'o');
 
-        // Wait for PTO to expire.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
+        let result3 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result3, Err(Error::Done));
+    }
 
-        pipe.client.on_timeout();
+    #[test]
+    fn dgram_send_max_size() {
+        let mut buf = [0; MAX_DGRAM_FRAME_SIZE as usize];
 
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 2);
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 10);
+        config.set_max_recv_udp_payload_size(1452);
+        config.verify_peer(false);
 
-        // Client sends first PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 1);
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
 
-        // Client sends second PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 0);
-    }
+        // Before handshake (before peer settings) we don't know max dgram size
+        assert_eq!(pipe.client.dgram_max_writable_len(), None);
 
-    #[test]
-    fn coalesce_padding_short() {
-        let mut buf = [0; 65535];
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let max_dgram_size = pipe.client.dgram_max_writable_len().unwrap();
 
-        // Client sends first flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        // Tests use a 16-byte connection ID, so the max datagram frame payload
+        // size is (1200 byte-long packet - 40 bytes overhead)
+        assert_eq!(max_dgram_size, 1160);
 
-        // Server sends first flight.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
-        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        let dgram_packet: Vec<u8> = vec![42; max_dgram_size];
 
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
-        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.client.dgram_send(&dgram_packet), Ok(()));
 
-        // Client sends stream data.
-        assert_eq!(pipe.client.is_established(), true);
-        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends second flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let result1 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result1, Ok(max_dgram_size));
 
-        // None of the sent packets should have been dropped.
-        assert_eq!(pipe.client.sent_count, pipe.server.recv_count);
-        assert_eq!(pipe.server.sent_count, pipe.client.recv_count);
+        let result2 = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(result2, Err(Error::Done));
     }
 
     #[test]
-    /// Tests that client avoids handshake deadlock by arming PTO.
-    fn handshake_anti_deadlock() {
+    /// Tests is_readable check.
+    fn is_readable() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
-            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
         config
             .load_priv_key_from_pem_file("examples/cert.key")
@@ -10441,303 +18423,292 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.enable_dgram(true, 10, 10);
+        config.set_max_recv_udp_payload_size(1452);
+        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.handshake_status().has_handshake_keys, false);
-        assert_eq!(pipe.client.handshake_status().peer_verified_address, false);
-        assert_eq!(pipe.server.handshake_status().has_handshake_keys, false);
-        assert_eq!(pipe.server.handshake_status().peer_verified_address, true);
+        // No readable data.
+        assert_eq!(pipe.client.is_readable(), false);
+        assert_eq!(pipe.server.is_readable(), false);
 
-        // Client sends padded Initial.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Server receives client's Initial and sends own Initial and Handshake
-        // until it's blocked by the anti-amplification limit.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        // Server received stream.
+        assert_eq!(pipe.client.is_readable(), false);
+        assert_eq!(pipe.server.is_readable(), true);
 
-        assert_eq!(pipe.client.handshake_status().has_handshake_keys, false);
-        assert_eq!(pipe.client.handshake_status().peer_verified_address, false);
-        assert_eq!(pipe.server.handshake_status().has_handshake_keys, true);
-        assert_eq!(pipe.server.handshake_status().peer_verified_address, true);
+        assert_eq!(
+            pipe.server.stream_send(4, b"aaaaaaaaaaaaaaa", false),
+            Ok(15)
+        );
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client receives the server flight and sends Handshake ACK, but it is
-        // lost.
-        testing::process_flight(&mut pipe.client, flight).unwrap();
-        testing::emit_flight(&mut pipe.client).unwrap();
+        // Client received stream.
+        assert_eq!(pipe.client.is_readable(), true);
+        assert_eq!(pipe.server.is_readable(), true);
 
-        assert_eq!(pipe.client.handshake_status().has_handshake_keys, true);
-        assert_eq!(pipe.client.handshake_status().peer_verified_address, false);
-        assert_eq!(pipe.server.handshake_status().has_handshake_keys, true);
-        assert_eq!(pipe.server.handshake_status().peer_verified_address, true);
+        // Client drains stream.
+        let mut b = [0; 15];
+        pipe.client.stream_recv(4, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Make sure client's PTO timer is armed.
-        assert!(pipe.client.timeout().is_some());
-    }
+        assert_eq!(pipe.client.is_readable(), false);
+        assert_eq!(pipe.server.is_readable(), true);
 
-    #[test]
-    /// Tests that packets with corrupted type (from Handshake to Initial) are
-    /// properly ignored.
-    fn handshake_packet_type_corruption() {
-        let mut buf = [0; 65535];
+        // Server shuts down stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 0), Ok(()));
+        assert_eq!(pipe.server.is_readable(), false);
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        // Server received dgram.
+        assert_eq!(pipe.client.dgram_send(b"dddddddddddddd"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client sends padded Initial.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        assert_eq!(pipe.client.is_readable(), false);
+        assert_eq!(pipe.server.is_readable(), true);
 
-        // Server receives client's Initial and sends own Initial and Handshake.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        // Client received dgram.
+        assert_eq!(pipe.server.dgram_send(b"dddddddddddddd"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        assert_eq!(pipe.client.is_readable(), true);
+        assert_eq!(pipe.server.is_readable(), true);
 
-        // Client sends Initial packet with ACK.
-        let (ty, len) = pipe.client.send_single(&mut buf, false).unwrap();
-        assert_eq!(ty, Type::Initial);
+        // Drain the dgram queues.
+        let r = pipe.server.dgram_recv(&mut buf);
+        assert_eq!(r, Ok(14));
+        assert_eq!(pipe.server.is_readable(), false);
 
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        let r = pipe.client.dgram_recv(&mut buf);
+        assert_eq!(r, Ok(14));
+        assert_eq!(pipe.client.is_readable(), false);
+    }
 
-        // Client sends Handshake packet.
-        let (ty, len) = pipe.client.send_single(&mut buf, false).unwrap();
-        assert_eq!(ty, Type::Handshake);
+    #[test]
+    fn close() {
+        let mut buf = [0; 65535];
 
-        // Packet type is corrupted to Initial.
-        buf[0] &= !(0x20);
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
-        assert_eq!(hdr.ty, Type::Initial);
+        assert_eq!(pipe.client.close(false, 0x1234, b"hello?"), Ok(()));
 
-        // Server receives corrupted packet without returning an error.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(
+            pipe.client.close(false, 0x4321, b"hello?"),
+            Err(Error::Done)
+        );
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
+        assert_eq!(
+            frames.iter().next(),
+            Some(&frame::Frame::ConnectionClose {
+                error_code: 0x1234,
+                frame_type: 0,
+                reason: b"hello?".to_vec(),
+            })
+        );
     }
 
     #[test]
-    fn dgram_send_fails_invalidstate() {
+    fn app_close() {
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
+        assert_eq!(pipe.client.close(true, 0x1234, b"hello!"), Ok(()));
+
+        assert_eq!(pipe.client.close(true, 0x4321, b"hello!"), Err(Error::Done));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
         assert_eq!(
-            pipe.client.dgram_send(b"hello, world"),
-            Err(Error::InvalidState)
+            frames.iter().next(),
+            Some(&frame::Frame::ApplicationClose {
+                error_code: 0x1234,
+                reason: b"hello!".to_vec(),
+            })
         );
     }
 
     #[test]
-    fn dgram_send_app_limited() {
-        let mut buf = [0; 65535];
-        let send_buf = [0xcf; 1000];
-
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 1000, 1000);
-        config.set_max_recv_udp_payload_size(1200);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+    fn peer_error() {
+        let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        for _ in 0..1000 {
-            assert_eq!(pipe.client.dgram_send(&send_buf), Ok(()));
-        }
+        assert_eq!(pipe.server.close(false, 0x1234, b"hello?"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert!(!pipe.client.recovery.app_limited());
-        assert_eq!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
+        assert_eq!(
+            pipe.client.peer_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x1234u64,
+                reason: b"hello?".to_vec(),
+                frame_type: Some(0),
+            })
+        );
+    }
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+    #[test]
+    fn app_peer_error() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 0);
-        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
-        assert!(!pipe.client.recovery.app_limited());
+        assert_eq!(pipe.server.close(true, 0x1234, b"hello!"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(
+            pipe.client.peer_error(),
+            Some(&ConnectionError {
+                is_app: true,
+                error_code: 0x1234u64,
+                reason: b"hello!".to_vec(),
+                frame_type: None,
+            })
+        );
+    }
 
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+    #[test]
+    fn local_error() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        assert_eq!(pipe.server.local_error(), None);
 
-        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 0);
-        assert_ne!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
+        assert_eq!(pipe.server.close(true, 0x1234, b"hello!"), Ok(()));
 
-        assert!(!pipe.client.recovery.app_limited());
+        assert_eq!(
+            pipe.server.local_error(),
+            Some(&ConnectionError {
+                is_app: true,
+                error_code: 0x1234u64,
+                reason: b"hello!".to_vec(),
+                frame_type: None,
+            })
+        );
     }
 
     #[test]
-    fn dgram_single_datagram() {
+    fn peer_error_frame_type() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 10, 10);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
-
-        assert_eq!(pipe.advance(), Ok(()));
+        let frames = [frame::Frame::ConnectionClose {
+            error_code: 0x7,
+            frame_type: 0x8,
+            reason: b"bad stream".to_vec(),
+        }];
 
-        let result1 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result1, Ok(12));
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        let result2 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result2, Err(Error::Done));
+        assert_eq!(
+            pipe.server.peer_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x7,
+                reason: b"bad stream".to_vec(),
+                frame_type: Some(0x8),
+            })
+        );
     }
 
     #[test]
-    fn dgram_multiple_datagrams() {
+    fn local_error_frame_type() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 10, 10);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.dgram_send_queue_len(), 0);
-        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 0);
-
-        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Ok(()));
-
-        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 34);
-
-        pipe.client
-            .dgram_purge_outgoing(|d: &[u8]| -> bool { d[0] == b'c' });
+        let frames = [frame::Frame::MaxStreamsBidi {
+            max: MAX_STREAM_ID + 1,
+        }];
 
-        assert_eq!(pipe.client.dgram_send_queue_len(), 2);
-        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 23);
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame(0x12)),
+        );
 
-        // Before packets exchanged, no dgrams on server receive side.
-        assert_eq!(pipe.server.dgram_recv_queue_len(), 0);
+        assert_eq!(
+            pipe.server.local_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x7,
+                reason: b"frame encoding error".to_vec(),
+                frame_type: Some(0x12),
+            })
+        );
 
+        // The frame type is reported to the client.
         assert_eq!(pipe.advance(), Ok(()));
 
-        // After packets exchanged, no dgrams on client send side.
-        assert_eq!(pipe.client.dgram_send_queue_len(), 0);
-        assert_eq!(pipe.client.dgram_send_queue_byte_size(), 0);
-
-        assert_eq!(pipe.server.dgram_recv_queue_len(), 2);
-        assert_eq!(pipe.server.dgram_recv_queue_byte_size(), 23);
-
-        let result1 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result1, Ok(12));
-        assert_eq!(buf[0], b'h');
-        assert_eq!(buf[1], b'e');
-
-        let result2 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result2, Ok(11));
-        assert_eq!(buf[0], b'h');
-        assert_eq!(buf[1], b'o');
-
-        let result3 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result3, Err(Error::Done));
-
-        assert_eq!(pipe.server.dgram_recv_queue_len(), 0);
-        assert_eq!(pipe.server.dgram_recv_queue_byte_size(), 0);
+        assert_eq!(
+            pipe.client.peer_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0x7,
+                reason: b"frame encoding error".to_vec(),
+                frame_type: Some(0x12),
+            })
+        );
     }
 
     #[test]
-    fn dgram_send_queue_overflow() {
+    fn local_error_ack_frame_type() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 10, 2);
-        config.verify_peer(false);
-
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Err(Error::Done));
-
-        assert_eq!(pipe.advance(), Ok(()));
+        // Acknowledging packets that were never sent is a protocol violation.
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert(1000..1001);
 
-        let result1 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result1, Ok(12));
-        assert_eq!(buf[0], b'h');
-        assert_eq!(buf[1], b'e');
+        let frames = [frame::Frame::ACK {
+            ack_delay: 0,
+            ranges,
+            ecn_counts: None,
+        }];
 
-        let result2 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result2, Ok(11));
-        assert_eq!(buf[0], b'c');
-        assert_eq!(buf[1], b'i');
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidPacket),
+        );
 
-        let result3 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result3, Err(Error::Done));
+        assert_eq!(
+            pipe.server.local_error(),
+            Some(&ConnectionError {
+                is_app: false,
+                error_code: 0xa,
+                reason: b"protocol violation".to_vec(),
+                frame_type: Some(0x02),
+            })
+        );
     }
 
     #[test]
-    fn dgram_recv_queue_overflow() {
-        let mut buf = [0; 65535];
-
+    fn poll_event() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -10751,40 +18722,59 @@ mod tests {
         config.set_initial_max_data(30);
         config.set_initial_max_stream_data_bidi_local(15);
         config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
         config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 2, 10);
-        config.set_max_recv_udp_payload_size(1200);
         config.verify_peer(false);
+        config.enable_events(true);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.dgram_send(b"hello, world"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"ciao, mondo"), Ok(()));
-        assert_eq!(pipe.client.dgram_send(b"hola, mundo"), Ok(()));
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(TransportEvent::HandshakeCompleted)
+        );
+        assert_eq!(pipe.client.poll_event(), None);
 
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(TransportEvent::HandshakeCompleted)
+        );
+        assert_eq!(pipe.server.poll_event(), None);
+
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let result1 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result1, Ok(11));
-        assert_eq!(buf[0], b'c');
-        assert_eq!(buf[1], b'i');
+        // The server stops the stream, and the client resets it in turn.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let result2 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result2, Ok(11));
-        assert_eq!(buf[0], b'h');
-        assert_eq!(buf[1], b'o');
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(TransportEvent::StopSending {
+                stream_id: 4,
+                error_code: 42,
+            })
+        );
+        assert_eq!(pipe.client.poll_event(), None);
 
-        let result3 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result3, Err(Error::Done));
+        assert_eq!(
+            pipe.server.poll_event(),
+            Some(TransportEvent::StreamReset {
+                stream_id: 4,
+                error_code: 42,
+            })
+        );
+        assert_eq!(pipe.server.poll_event(), None);
+
+        assert_eq!(pipe.server.close(true, 0x1234, b""), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.poll_event(), Some(TransportEvent::PeerClosed));
+        assert_eq!(pipe.client.poll_event(), None);
     }
 
     #[test]
-    fn dgram_send_max_size() {
-        let mut buf = [0; MAX_DGRAM_FRAME_SIZE as usize];
-
+    fn poll_event_coalesced() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -10795,47 +18785,53 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
-        config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 10, 10);
-        config.set_max_recv_udp_payload_size(1452);
         config.verify_peer(false);
+        config.enable_events(true);
+        config.set_max_events(3);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-
-        // Before handshake (before peer settings) we don't know max dgram size
-        assert_eq!(pipe.client.dgram_max_writable_len(), None);
-
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let max_dgram_size = pipe.client.dgram_max_writable_len().unwrap();
+        while pipe.client.poll_event().is_some() {}
 
-        // Tests use a 16-byte connection ID, so the max datagram frame payload
-        // size is (1200 byte-long packet - 40 bytes overhead)
-        assert_eq!(max_dgram_size, 1160);
+        let events = [
+            TransportEvent::StreamsAvailable,
+            TransportEvent::MaxDataUpdated { max: 10 },
+            TransportEvent::StreamsAvailable,
+            TransportEvent::MaxDataUpdated { max: 20 },
+            TransportEvent::NewToken,
+            TransportEvent::PeerClosed,
+        ];
 
-        let dgram_packet: Vec<u8> = vec![42; max_dgram_size];
+        for ev in events.iter() {
+            pipe.client.push_event(*ev);
+        }
 
-        assert_eq!(pipe.client.dgram_send(&dgram_packet), Ok(()));
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(TransportEvent::StreamsAvailable)
+        );
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(TransportEvent::MaxDataUpdated { max: 20 })
+        );
+        assert_eq!(pipe.client.poll_event(), Some(TransportEvent::NewToken));
+        assert_eq!(pipe.client.poll_event(), None);
 
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stats().events_dropped, 1);
+    }
 
-        let result1 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result1, Ok(max_dgram_size));
+    #[test]
+    fn poll_event_disabled() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let result2 = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(result2, Err(Error::Done));
+        assert_eq!(pipe.client.poll_event(), None);
+        assert_eq!(pipe.server.poll_event(), None);
     }
 
     #[test]
-    /// Tests is_readable check.
-    fn is_readable() {
-        let mut buf = [0; 65535];
-
+    fn stream_deadline() {
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -10849,180 +18845,213 @@ mod tests {
         config.set_initial_max_data(30);
         config.set_initial_max_stream_data_bidi_local(15);
         config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
         config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(3);
-        config.enable_dgram(true, 10, 10);
-        config.set_max_recv_udp_payload_size(1452);
         config.verify_peer(false);
+        config.enable_events(true);
 
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // No readable data.
-        assert_eq!(pipe.client.is_readable(), false);
-        assert_eq!(pipe.server.is_readable(), false);
+        while pipe.client.poll_event().is_some() {}
+        while pipe.server.poll_event().is_some() {}
 
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(8, b"world", false), Ok(5));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server received stream.
-        assert_eq!(pipe.client.is_readable(), false);
-        assert_eq!(pipe.server.is_readable(), true);
+        let now = time::Instant::now();
 
+        assert_eq!(pipe.client.stream_set_deadline(4, now, 42), Ok(()));
         assert_eq!(
-            pipe.server.stream_send(4, b"aaaaaaaaaaaaaaa", false),
-            Ok(15)
+            pipe.client.stream_set_deadline(
+                8,
+                now + time::Duration::from_secs(3600),
+                43
+            ),
+            Ok(())
         );
-        assert_eq!(pipe.advance(), Ok(()));
 
-        // Client received stream.
-        assert_eq!(pipe.client.is_readable(), true);
-        assert_eq!(pipe.server.is_readable(), true);
+        assert_eq!(pipe.client.timeout(), Some(time::Duration::ZERO));
 
-        // Client drains stream.
-        let mut b = [0; 15];
-        pipe.client.stream_recv(4, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        pipe.client.on_timeout();
 
-        assert_eq!(pipe.client.is_readable(), false);
-        assert_eq!(pipe.server.is_readable(), true);
+        assert_eq!(
+            pipe.client.poll_event(),
+            Some(TransportEvent::StreamDeadlineExpired {
+                stream_id: 4,
+                error_code: 42,
+            })
+        );
+        assert_eq!(pipe.client.poll_event(), None);
 
-        // Server shuts down stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 0), Ok(()));
-        assert_eq!(pipe.server.is_readable(), false);
+        assert_eq!(
+            pipe.client.stream_send(4, b"again", false),
+            Err(Error::FinalSize(4))
+        );
+        assert_eq!(pipe.client.stream_recv(4, &mut [0; 5]), Err(Error::Done));
 
-        // Server received dgram.
-        assert_eq!(pipe.client.dgram_send(b"dddddddddddddd"), Ok(()));
+        // The server is told to reset and stop the stream.
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.is_readable(), false);
-        assert_eq!(pipe.server.is_readable(), true);
-
-        // Client received dgram.
-        assert_eq!(pipe.server.dgram_send(b"dddddddddddddd"), Ok(()));
-        assert_eq!(pipe.advance(), Ok(()));
+        let mut events = Vec::new();
 
-        assert_eq!(pipe.client.is_readable(), true);
-        assert_eq!(pipe.server.is_readable(), true);
+        while let Some(ev) = pipe.server.poll_event() {
+            events.push(ev);
+        }
 
-        // Drain the dgram queues.
-        let r = pipe.server.dgram_recv(&mut buf);
-        assert_eq!(r, Ok(14));
-        assert_eq!(pipe.server.is_readable(), false);
+        assert!(events.contains(&TransportEvent::StreamReset {
+            stream_id: 4,
+            error_code: 42,
+        }));
+        assert!(events.contains(&TransportEvent::StopSending {
+            stream_id: 4,
+            error_code: 42,
+        }));
+        assert_eq!(events.len(), 2);
 
-        let r = pipe.client.dgram_recv(&mut buf);
-        assert_eq!(r, Ok(14));
-        assert_eq!(pipe.client.is_readable(), false);
+        // The other stream is left alone.
+        assert!(pipe.client.timeout().unwrap() > time::Duration::from_secs(60));
+        assert_eq!(pipe.client.stream_send(8, b"!", false), Ok(1));
     }
 
     #[test]
-    fn close() {
+    fn dscp() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.verify_peer(false);
+        config.set_dscp(46);
 
-        assert_eq!(pipe.client.close(false, 0x1234, b"hello?"), Ok(()));
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
 
-        assert_eq!(
-            pipe.client.close(false, 0x4321, b"hello?"),
-            Err(Error::Done)
-        );
+        let (len, info) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(info.dscp, 46);
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.client.is_established());
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+        // Connections can override the configured value.
+        pipe.client.set_dscp(10);
 
-        assert_eq!(
-            frames.iter().next(),
-            Some(&frame::Frame::ConnectionClose {
-                error_code: 0x1234,
-                frame_type: 0,
-                reason: b"hello?".to_vec(),
-            })
-        );
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+
+        let (len, info) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(info.dscp, 10);
+
+        // The server uses the default value.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        let (_, info) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(info.dscp, 0);
+
+        // Invalid values are capped.
+        pipe.client.set_dscp(u8::MAX);
+        assert_eq!(pipe.client.dscp, MAX_DSCP);
     }
 
     #[test]
-    fn app_close() {
-        let mut buf = [0; 65535];
+    fn packet_tap() {
+        type Datagrams = Vec<(TapInfo, Vec<u8>)>;
+
+        struct Tap(Arc<std::sync::Mutex<Datagrams>>);
+
+        impl PacketTap for Tap {
+            fn on_datagram(&mut self, info: &TapInfo, data: &[u8]) {
+                self.0.lock().unwrap().push((*info, data.to_vec()));
+            }
+        }
 
         let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.close(true, 0x1234, b"hello!"), Ok(()));
+        let client_tap = Arc::new(std::sync::Mutex::new(Vec::new()));
+        pipe.client
+            .set_packet_tap(Box::new(Tap(client_tap.clone())));
 
-        assert_eq!(pipe.client.close(true, 0x4321, b"hello!"), Err(Error::Done));
+        let server_tap = Arc::new(std::sync::Mutex::new(Vec::new()));
+        pipe.server
+            .set_packet_tap(Box::new(Tap(server_tap.clone())));
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+        let client_tap = client_tap.lock().unwrap();
+        let server_tap = server_tap.lock().unwrap();
 
-        assert_eq!(
-            frames.iter().next(),
-            Some(&frame::Frame::ApplicationClose {
-                error_code: 0x1234,
-                reason: b"hello!".to_vec(),
-            })
-        );
-    }
+        assert_eq!(client_tap[0].0.direction, PacketDirection::Sent);
+        assert_eq!(client_tap[0].0.local, None);
+        assert_eq!(client_tap[0].0.peer, pipe.client.peer_addr);
 
-    #[test]
-    fn peer_error() {
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(server_tap[0].0.direction, PacketDirection::Received);
+        assert_eq!(server_tap[0].0.peer, pipe.server.peer_addr);
 
-        assert_eq!(pipe.server.close(false, 0x1234, b"hello?"), Ok(()));
-        assert_eq!(pipe.advance(), Ok(()));
+        // Every datagram sent by one side was received by the other, as it
+        // appeared on the wire.
+        let datagrams = |tap: &Datagrams, dir| {
+            tap.iter()
+                .filter(|(info, _)| info.direction == dir)
+                .map(|(_, data)| data.clone())
+                .collect::<Vec<_>>()
+        };
 
         assert_eq!(
-            pipe.client.peer_error(),
-            Some(&ConnectionError {
-                is_app: false,
-                error_code: 0x1234u64,
-                reason: b"hello?".to_vec()
-            })
+            datagrams(&client_tap, PacketDirection::Sent),
+            datagrams(&server_tap, PacketDirection::Received)
+        );
+        assert_eq!(
+            datagrams(&server_tap, PacketDirection::Sent),
+            datagrams(&client_tap, PacketDirection::Received)
         );
     }
 
     #[test]
-    fn app_peer_error() {
-        let mut pipe = testing::Pipe::default().unwrap();
+    fn draining_timeout() {
+        let clock = Arc::new(testing::ManualClock::new(time::Instant::now()));
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.verify_peer(false);
+        config.set_clock(clock.clone());
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
+        assert_eq!(pipe.client.draining_timeout(), None);
+        assert_eq!(pipe.server.draining_timeout(), None);
+
         assert_eq!(pipe.server.close(true, 0x1234, b"hello!"), Ok(()));
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(
-            pipe.client.peer_error(),
-            Some(&ConnectionError {
-                is_app: true,
-                error_code: 0x1234u64,
-                reason: b"hello!".to_vec()
-            })
-        );
-    }
+        // Both endpoints drain for 3 PTOs.
+        for conn in [&pipe.client, &pipe.server] {
+            assert!(conn.is_draining());
 
-    #[test]
-    fn local_error() {
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+            let deadline = conn.timeout().unwrap();
 
-        assert_eq!(pipe.server.local_error(), None);
+            let timeout = conn.draining_timeout().unwrap();
+            assert!(timeout <= deadline);
+            assert!(timeout <= conn.recovery.pto() * 3);
+        }
 
-        assert_eq!(pipe.server.close(true, 0x1234, b"hello!"), Ok(()));
+        pipe.client.on_timeout();
+        assert!(!pipe.client.is_closed());
 
-        assert_eq!(
-            pipe.server.local_error(),
-            Some(&ConnectionError {
-                is_app: true,
-                error_code: 0x1234u64,
-                reason: b"hello!".to_vec()
-            })
-        );
+        // The connection is closed once the drain period is over.
+        let timeout = pipe.client.draining_timeout().unwrap();
+        clock.advance(timeout);
+
+        pipe.client.on_timeout();
+        assert!(pipe.client.is_closed());
     }
 
     #[test]
@@ -11147,22 +19176,123 @@ mod tests {
 
         assert_eq!(pipe.advance(), Ok(()));
     }
+
+    #[test]
+    fn send_burst() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(100000);
+        config.set_initial_max_stream_data_bidi_local(100000);
+        config.set_initial_max_stream_data_bidi_remote(100000);
+        config.set_initial_max_streams_bidi(10);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, &buf[..5000], true), Ok(5000));
+
+        let segment_size = pipe.client.max_send_udp_payload_size();
+
+        let quantum = pipe.client.send_quantum();
+        assert!(quantum >= segment_size * 2);
+        assert_eq!(quantum % segment_size, 0);
+
+        let (len, info) = pipe
+            .client
+            .send_burst(&mut buf[..quantum], segment_size)
+            .unwrap();
+        assert_eq!(info.to, pipe.client.peer_addr);
+
+        // The stream data doesn't fit in a single datagram, and only the last
+        // one is short.
+        assert!(len > segment_size);
+        assert!(len < quantum);
+        assert_ne!(len % segment_size, 0);
+
+        for segment in buf[..len].chunks_mut(segment_size) {
+            let segment_len = segment.len();
+            assert_eq!(pipe.server_recv(segment), Ok(segment_len));
+        }
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(0));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((5000, true)));
+
+        // Nothing left to send.
+        assert_eq!(
+            pipe.client.send_burst(&mut buf[..quantum], segment_size),
+            Err(Error::Done)
+        );
+    }
 }
 
+pub use crate::alpn::AlpnSelector;
+
+pub use crate::cert_status::CertStatusVerifier;
+
+pub use crate::clock::Clock;
+#[cfg(feature = "std")]
+pub use crate::clock::SystemClock;
+
+#[cfg(not(feature = "std"))]
+pub use crate::time::Instant;
+
+pub use crate::tap::PacketDirection;
+pub use crate::tap::PacketTap;
+pub use crate::tap::TapInfo;
+
+pub use crate::crypto::Algorithm;
+pub use crate::crypto::CryptoProvider;
+pub use crate::crypto::HeaderProtectionKey;
+pub use crate::crypto::PacketKey;
+
 pub use crate::packet::ConnectionId;
 pub use crate::packet::Header;
 pub use crate::packet::Type;
 
 pub use crate::recovery::CongestionControlAlgorithm;
+pub use crate::recovery::CongestionState;
+
+pub use crate::scheduler::FifoScheduler;
+pub use crate::scheduler::PriorityScheduler;
+pub use crate::scheduler::RoundRobinScheduler;
+pub use crate::scheduler::StreamScheduler;
 
+pub use crate::session_store::SessionEntryKind;
+pub use crate::session_store::SessionStore;
+
+pub use crate::stream::StreamCreditPolicy;
 pub use crate::stream::StreamIter;
 
+mod alpn;
+mod cert_status;
+mod cid;
+mod clock;
+mod collections;
 mod crypto;
 mod dgram;
+#[cfg(feature = "endpoint")]
+pub mod endpoint;
 #[cfg(feature = "ffi")]
 mod ffi;
 mod flowcontrol;
 mod frame;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+#[cfg(feature = "std")]
 pub mod h3;
 mod minmax;
 mod octets;
@@ -11170,5 +19300,15 @@ mod packet;
 mod rand;
 mod ranges;
 mod recovery;
+mod scheduler;
+mod session_store;
+#[cfg(feature = "std")]
+mod simulator;
 mod stream;
+mod tap;
+mod time;
+#[cfg(not(feature = "rustls"))]
+mod tls;
+#[cfg(feature = "rustls")]
+#[path = "tls_rustls.rs"]
 mod tls;
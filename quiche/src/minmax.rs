@@ -52,8 +52,8 @@
 // every new min and overwrites 2nd & 3rd choices. The same property
 // holds for 2nd & 3rd best.
 
-use std::time::Duration;
-use std::time::Instant;
+use crate::time::Duration;
+use crate::time::Instant;
 
 #[derive(Copy, Clone)]
 struct MinmaxSample<T> {
@@ -66,12 +66,9 @@ pub struct Minmax<T> {
 }
 
 impl<T: PartialOrd + Copy> Minmax<T> {
-    pub fn new(val: T) -> Self {
+    pub fn new(val: T, time: Instant) -> Self {
         Minmax {
-            estimate: [MinmaxSample {
-                time: Instant::now(),
-                value: val,
-            }; 3],
+            estimate: [MinmaxSample { time, value: val }; 3],
         }
     }
 
@@ -173,7 +170,7 @@ mod tests {
 
     #[test]
     fn reset_filter_rtt() {
-        let mut f = Minmax::new(Duration::ZERO);
+        let mut f = Minmax::new(Duration::ZERO, Instant::now());
         let now = Instant::now();
         let rtt = Duration::from_millis(50);
 
@@ -192,7 +189,7 @@ mod tests {
 
     #[test]
     fn reset_filter_bandwidth() {
-        let mut f = Minmax::new(0);
+        let mut f = Minmax::new(0, Instant::now());
         let now = Instant::now();
         let bw = 2000;
 
@@ -211,7 +208,7 @@ mod tests {
 
     #[test]
     fn get_windowed_min_rtt() {
-        let mut f = Minmax::new(Duration::ZERO);
+        let mut f = Minmax::new(Duration::ZERO, Instant::now());
         let rtt_25 = Duration::from_millis(25);
         let rtt_24 = Duration::from_millis(24);
         let win = Duration::from_millis(500);
@@ -235,7 +232,7 @@ mod tests {
 
     #[test]
     fn get_windowed_min_bandwidth() {
-        let mut f = Minmax::new(0);
+        let mut f = Minmax::new(0, Instant::now());
         let bw_200 = 200;
         let bw_500 = 500;
         let win = Duration::from_millis(500);
@@ -259,7 +256,7 @@ mod tests {
 
     #[test]
     fn get_windowed_max_rtt() {
-        let mut f = Minmax::new(Duration::ZERO);
+        let mut f = Minmax::new(Duration::ZERO, Instant::now());
         let rtt_25 = Duration::from_millis(25);
         let rtt_24 = Duration::from_millis(24);
         let win = Duration::from_millis(500);
@@ -283,7 +280,7 @@ mod tests {
 
     #[test]
     fn get_windowed_max_bandwidth() {
-        let mut f = Minmax::new(0);
+        let mut f = Minmax::new(0, Instant::now());
         let bw_200 = 200;
         let bw_500 = 500;
         let win = Duration::from_millis(500);
@@ -307,7 +304,7 @@ mod tests {
 
     #[test]
     fn get_windowed_min_estimates_rtt() {
-        let mut f = Minmax::new(Duration::ZERO);
+        let mut f = Minmax::new(Duration::ZERO, Instant::now());
         let rtt_25 = Duration::from_millis(25);
         let rtt_24 = Duration::from_millis(24);
         let rtt_23 = Duration::from_millis(23);
@@ -339,7 +336,7 @@ mod tests {
 
     #[test]
     fn get_windowed_min_estimates_bandwidth() {
-        let mut f = Minmax::new(0);
+        let mut f = Minmax::new(0, Instant::now());
         let bw_500 = 500;
         let bw_400 = 400;
         let bw_300 = 300;
@@ -371,7 +368,7 @@ mod tests {
 
     #[test]
     fn get_windowed_max_estimates_rtt() {
-        let mut f = Minmax::new(Duration::ZERO);
+        let mut f = Minmax::new(Duration::ZERO, Instant::now());
         let rtt_25 = Duration::from_millis(25);
         let rtt_24 = Duration::from_millis(24);
         let rtt_23 = Duration::from_millis(23);
@@ -403,7 +400,7 @@ mod tests {
 
     #[test]
     fn get_windowed_max_estimates_bandwidth() {
-        let mut f = Minmax::new(0);
+        let mut f = Minmax::new(0, Instant::now());
         let bw_500 = 500;
         let bw_400 = 400;
         let bw_300 = 300;
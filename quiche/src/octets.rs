@@ -25,14 +25,16 @@
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 /// Zero-copy abstraction for parsing and constructing network packets.
-use std::mem;
-use std::ptr;
+use core::mem;
+use core::ptr;
+
+use alloc::vec::Vec;
 
 /// A specialized [`Result`] type for [`OctetsMut`] operations.
 ///
 /// [`Result`]: https://doc.rust-lang.org/std/result/enum.Result.html
 /// [`OctetsMut`]: struct.OctetsMut.html
-pub type Result<T> = std::result::Result<T, BufferTooShortError>;
+pub type Result<T> = core::result::Result<T, BufferTooShortError>;
 
 /// An error indicating that the provided [`OctetsMut`] is not big enough.
 ///
@@ -40,12 +42,13 @@ pub type Result<T> = std::result::Result<T, BufferTooShortError>;
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct BufferTooShortError;
 
-impl std::fmt::Display for BufferTooShortError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for BufferTooShortError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "BufferTooShortError")
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for BufferTooShortError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         None
@@ -1013,7 +1016,7 @@ mod tests {
     fn varint_too_large() {
         let mut d = [0; 3];
         let mut b = OctetsMut::with_slice(&mut d);
-        assert!(b.put_varint(std::u64::MAX).is_err());
+        assert!(b.put_varint(core::u64::MAX).is_err());
     }
 
     #[test]
@@ -24,7 +24,9 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use std::time;
+use crate::time;
+
+use alloc::vec::Vec;
 
 use ring::aead;
 
@@ -32,6 +34,7 @@ use crate::Error;
 use crate::Result;
 
 use crate::crypto;
+use crate::frame;
 use crate::octets;
 use crate::rand;
 use crate::ranges;
@@ -201,14 +204,14 @@ impl<'a> AsRef<[u8]> for ConnectionId<'a> {
     }
 }
 
-impl<'a> std::hash::Hash for ConnectionId<'a> {
+impl<'a> core::hash::Hash for ConnectionId<'a> {
     #[inline]
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
         self.as_ref().hash(state);
     }
 }
 
-impl<'a> std::ops::Deref for ConnectionId<'a> {
+impl<'a> core::ops::Deref for ConnectionId<'a> {
     type Target = [u8];
 
     #[inline]
@@ -227,9 +230,9 @@ impl<'a> Clone for ConnectionId<'a> {
     }
 }
 
-impl<'a> std::fmt::Debug for ConnectionId<'a> {
+impl<'a> core::fmt::Debug for ConnectionId<'a> {
     #[inline]
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         for c in self.as_ref() {
             write!(f, "{:02x}", c)?;
         }
@@ -479,8 +482,8 @@ impl<'a> Header<'a> {
     }
 }
 
-impl<'a> std::fmt::Debug for Header<'a> {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl<'a> core::fmt::Debug for Header<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "{:?}", self.ty)?;
 
         if self.ty != Type::Short {
@@ -513,11 +516,11 @@ impl<'a> std::fmt::Debug for Header<'a> {
 }
 
 pub fn pkt_num_len(pn: u64) -> Result<usize> {
-    let len = if pn < u64::from(std::u8::MAX) {
+    let len = if pn < u64::from(core::u8::MAX) {
         1
-    } else if pn < u64::from(std::u16::MAX) {
+    } else if pn < u64::from(core::u16::MAX) {
         2
-    } else if pn < u64::from(std::u32::MAX) {
+    } else if pn < u64::from(core::u32::MAX) {
         4
     } else {
         return Err(Error::InvalidPacket);
@@ -686,7 +689,7 @@ pub fn encode_pkt_num(pn: u64, b: &mut octets::OctetsMut) -> Result<()> {
 }
 
 pub fn negotiate_version(
-    scid: &[u8], dcid: &[u8], out: &mut [u8],
+    scid: &[u8], dcid: &[u8], versions: &[u32], grease: bool, out: &mut [u8],
 ) -> Result<usize> {
     let mut b = octets::OctetsMut::with_slice(out);
 
@@ -699,14 +702,25 @@ pub fn negotiate_version(
     b.put_bytes(scid)?;
     b.put_u8(dcid.len() as u8)?;
     b.put_bytes(dcid)?;
-    b.put_u32(crate::PROTOCOL_VERSION_V1)?;
-    b.put_u32(crate::PROTOCOL_VERSION_DRAFT29)?;
-    b.put_u32(crate::PROTOCOL_VERSION_DRAFT28)?;
-    b.put_u32(crate::PROTOCOL_VERSION_DRAFT27)?;
+
+    for v in versions {
+        b.put_u32(*v)?;
+    }
+
+    // Add a reserved version to exercise the peer's handling of unknown
+    // versions.
+    if grease {
+        b.put_u32(grease_version())?;
+    }
 
     Ok(b.off())
 }
 
+/// Generates a random reserved version of the form `0x?a?a?a?a`.
+pub fn grease_version() -> u32 {
+    (rand::rand_u64() as u32 & 0xf0f0_f0f0) | 0x0a0a_0a0a
+}
+
 pub fn retry(
     scid: &[u8], dcid: &[u8], new_scid: &[u8], token: &[u8], version: u32,
     out: &mut [u8],
@@ -738,6 +752,61 @@ pub fn retry(
     Ok(b.off())
 }
 
+pub fn refuse(
+    scid: &[u8], dcid: &[u8], new_scid: &[u8], error_code: u64, version: u32,
+    out: &mut [u8],
+) -> Result<usize> {
+    let mut b = octets::OctetsMut::with_slice(out);
+
+    if !crate::version_is_supported(version) {
+        return Err(Error::UnknownVersion);
+    }
+
+    // Initial keys are derived from the destination connection ID chosen by
+    // the client, so no state is needed to protect the packet.
+    let (_, aead) = crypto::derive_initial_key_material(
+        &crypto::DefaultCryptoProvider,
+        dcid,
+        version,
+        true,
+    )?;
+
+    // Always encode the packet number in 4 bytes, so there is enough payload
+    // to sample for header protection.
+    let pn_len = 4;
+
+    let hdr = Header {
+        ty: Type::Initial,
+        version,
+        dcid: ConnectionId::from_ref(scid),
+        scid: ConnectionId::from_ref(new_scid),
+        pkt_num: 0,
+        pkt_num_len: pn_len,
+        token: None,
+        versions: None,
+        key_phase: false,
+    };
+
+    hdr.to_bytes(&mut b)?;
+
+    let frame = frame::Frame::ConnectionClose {
+        error_code,
+        frame_type: 0,
+        reason: Vec::new(),
+    };
+
+    let payload_len = frame.wire_len();
+
+    b.put_varint((pn_len + payload_len + aead.alg().tag_len()) as u64)?;
+    b.put_u32(0)?;
+
+    let payload_offset = b.off();
+
+    frame.to_bytes(&mut b)?;
+
+    encrypt_pkt(&mut b, 0, pn_len, payload_len, payload_offset, None, &aead)
+}
+
 pub fn verify_retry_integrity(
     b: &octets::OctetsMut, odcid: &[u8], version: u32,
 ) -> Result<()> {
@@ -832,17 +901,18 @@ pub struct PktNumSpace {
     pub crypto_seal: Option<crypto::Seal>,
 
     pub crypto_0rtt_open: Option<crypto::Open>,
+    #[cfg_attr(feature = "rustls", allow(dead_code))]
     pub crypto_0rtt_seal: Option<crypto::Seal>,
 
     pub crypto_stream: stream::Stream,
 }
 
 impl PktNumSpace {
-    pub fn new() -> PktNumSpace {
+    pub fn new(now: time::Instant) -> PktNumSpace {
         PktNumSpace {
             largest_rx_pkt_num: 0,
 
-            largest_rx_pkt_time: time::Instant::now(),
+            largest_rx_pkt_time: now,
 
             next_pkt_num: 0,
 
@@ -858,9 +928,12 @@ impl PktNumSpace {
             crypto_0rtt_open: None,
             crypto_0rtt_seal: None,
 
+            // The crypto stream has no ID, but it's not subject to flow
+            // control nor final size limits, so the ID is never reported.
             crypto_stream: stream::Stream::new(
-                std::u64::MAX,
-                std::u64::MAX,
+                0,
+                core::u64::MAX,
+                core::u64::MAX,
                 true,
                 true,
                 stream::MAX_STREAM_WINDOW,
@@ -870,8 +943,9 @@ impl PktNumSpace {
 
     pub fn clear(&mut self) {
         self.crypto_stream = stream::Stream::new(
-            std::u64::MAX,
-            std::u64::MAX,
+            0,
+            core::u64::MAX,
+            core::u64::MAX,
             true,
             true,
             stream::MAX_STREAM_WINDOW,
@@ -935,7 +1009,7 @@ impl PktNumWindow {
 
     fn upper(&self) -> u64 {
         self.lower
-            .saturating_add(std::mem::size_of::<u128>() as u64 * 8) -
+            .saturating_add(core::mem::size_of::<u128>() as u64 * 8) -
             1
     }
 }
@@ -1215,7 +1289,7 @@ mod tests {
         assert!(!win.contains(1025));
         assert!(!win.contains(1026));
 
-        win.insert(std::u64::MAX - 1);
+        win.insert(core::u64::MAX - 1);
         assert!(win.contains(0));
         assert!(win.contains(1));
         assert!(win.contains(2));
@@ -1237,8 +1311,8 @@ mod tests {
         assert!(win.contains(1024));
         assert!(win.contains(1025));
         assert!(win.contains(1026));
-        assert!(!win.contains(std::u64::MAX - 2));
-        assert!(win.contains(std::u64::MAX - 1));
+        assert!(!win.contains(core::u64::MAX - 2));
+        assert!(win.contains(core::u64::MAX - 1));
     }
 
     fn assert_decrypt_initial_pkt(
@@ -1252,9 +1326,13 @@ mod tests {
 
         let payload_len = b.get_varint().unwrap() as usize;
 
-        let (aead, _) =
-            crypto::derive_initial_key_material(dcid, hdr.version, is_server)
-                .unwrap();
+        let (aead, _) = crypto::derive_initial_key_material(
+            &crypto::DefaultCryptoProvider,
+            dcid,
+            hdr.version,
+            is_server,
+        )
+        .unwrap();
 
         decrypt_hdr(&mut b, &mut hdr, &aead).unwrap();
         assert_eq!(hdr.pkt_num_len, expected_pn_len);
@@ -1821,7 +1899,12 @@ mod tests {
 
         let alg = crypto::Algorithm::ChaCha20_Poly1305;
 
-        let aead = crypto::Open::from_secret(alg, &secret).unwrap();
+        let aead = crypto::Open::from_secret(
+            &crypto::DefaultCryptoProvider,
+            alg,
+            &secret,
+        )
+        .unwrap();
 
         let mut hdr = Header::from_bytes(&mut b, 0).unwrap();
         assert_eq!(hdr.ty, Type::Short);
@@ -1855,9 +1938,13 @@ mod tests {
 
         b.put_bytes(header).unwrap();
 
-        let (_, aead) =
-            crypto::derive_initial_key_material(dcid, hdr.version, is_server)
-                .unwrap();
+        let (_, aead) = crypto::derive_initial_key_material(
+            &crypto::DefaultCryptoProvider,
+            dcid,
+            hdr.version,
+            is_server,
+        )
+        .unwrap();
 
         let payload_len = frames.len();
 
@@ -2753,7 +2840,12 @@ mod tests {
 
         let alg = crypto::Algorithm::ChaCha20_Poly1305;
 
-        let aead = crypto::Seal::from_secret(alg, &secret).unwrap();
+        let aead = crypto::Seal::from_secret(
+            &crypto::DefaultCryptoProvider,
+            alg,
+            &secret,
+        )
+        .unwrap();
 
         let pn = 654_360_564;
         let pn_len = 3;
@@ -2804,8 +2896,13 @@ mod tests {
 
         let payload_len = b.get_varint().unwrap() as usize;
 
-        let (aead, _) =
-            crypto::derive_initial_key_material(b"", hdr.version, true).unwrap();
+        let (aead, _) = crypto::derive_initial_key_material(
+            &crypto::DefaultCryptoProvider,
+            b"",
+            hdr.version,
+            true,
+        )
+        .unwrap();
 
         assert_eq!(
             decrypt_pkt(&mut b, 0, 1, payload_len, &aead),
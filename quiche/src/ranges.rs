@@ -24,11 +24,13 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use std::ops::Range;
+use core::ops::Range;
 
-use std::collections::btree_map;
-use std::collections::BTreeMap;
-use std::collections::Bound;
+use alloc::collections::btree_map;
+use alloc::collections::BTreeMap;
+use core::ops::Bound;
+
+use alloc::vec::Vec;
 
 #[derive(Clone, PartialEq, PartialOrd)]
 pub struct RangeSet {
@@ -56,8 +58,8 @@ impl RangeSet {
             if range_overlaps(&r, &item) {
                 self.inner.remove(&r.start);
 
-                start = std::cmp::min(start, r.start);
-                end = std::cmp::max(end, r.end);
+                start = core::cmp::min(start, r.start);
+                end = core::cmp::max(end, r.end);
             }
         }
 
@@ -77,8 +79,8 @@ impl RangeSet {
             // New range overlaps with existing range in the set, merge them.
             self.inner.remove(&r.start);
 
-            start = std::cmp::min(start, r.start);
-            end = std::cmp::max(end, r.end);
+            start = core::cmp::min(start, r.start);
+            end = core::cmp::max(end, r.end);
         }
 
         if self.inner.len() >= self.capacity {
@@ -154,7 +156,7 @@ impl RangeSet {
 
 impl Default for RangeSet {
     fn default() -> Self {
-        Self::new(std::usize::MAX)
+        Self::new(core::usize::MAX)
     }
 }
 
@@ -180,8 +182,8 @@ impl PartialEq<Range<u64>> for RangeSet {
     }
 }
 
-impl std::fmt::Debug for RangeSet {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for RangeSet {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         let ranges: Vec<Range<u64>> = self
             .iter()
             .map(|mut r| {
@@ -31,10 +31,10 @@
 //!
 //! Note that Slow Start can use HyStart++ when enabled.
 
-use std::cmp;
+use core::cmp;
 
-use std::time::Duration;
-use std::time::Instant;
+use crate::time::Duration;
+use crate::time::Instant;
 
 use crate::packet;
 use crate::recovery;
@@ -133,8 +133,10 @@ impl State {
     fn w_cubic(&self, t: Duration, max_datagram_size: usize) -> f64 {
         let w_max = self.w_max / max_datagram_size as f64;
 
-        (C * (t.as_secs_f64() - self.k).powi(3) + w_max) *
-            max_datagram_size as f64
+        // `powi()` is only available with the standard library.
+        let t_k = t.as_secs_f64() - self.k;
+
+        (C * (t_k * t_k * t_k) + w_max) * max_datagram_size as f64
     }
 
     // W_est = W_est + alpha_aimd * (segments_acked / cwnd)  (Eq. 4)
@@ -251,14 +253,7 @@ fn on_packet_acked(
             r.bytes_acked_sl -= r.max_datagram_size;
         }
 
-        if r.hystart.on_packet_acked(
-            epoch,
-            packet,
-            r.latest_rtt,
-            r.congestion_window,
-            now,
-            r.max_datagram_size,
-        ) {
+        if r.hystart.on_packet_acked(epoch, packet, r.latest_rtt, now) {
             // Exit to congestion avoidance if CSS ends.
             r.ssthresh = r.congestion_window;
         }
@@ -418,7 +413,7 @@ fn has_custom_pacing() -> bool {
     false
 }
 
-fn debug_fmt(r: &Recovery, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+fn debug_fmt(r: &Recovery, f: &mut core::fmt::Formatter) -> core::fmt::Result {
     write!(
         f,
         "cubic={{ k={} w_max={} }} ",
@@ -747,6 +742,10 @@ mod tests {
         // Now we are in CSS.
         assert_eq!(r.hystart.css_start_time().is_some(), true);
         assert_eq!(r.cwnd(), cwnd_prev + r.max_datagram_size);
+        assert_eq!(
+            r.hystart.take_transition(),
+            Some(hystart::Transition::SlowStartToCss)
+        );
 
         // 3rd round, which RTT is less than previous round to
         // trigger back to Slow Start.
@@ -778,6 +777,10 @@ mod tests {
 
         // Now we are back in Slow Start.
         assert_eq!(r.hystart.css_start_time().is_some(), false);
+        assert_eq!(
+            r.hystart.take_transition(),
+            Some(hystart::Transition::CssToSlowStart)
+        );
         assert_eq!(
             r.cwnd(),
             cwnd_prev +
@@ -880,6 +883,10 @@ mod tests {
         // Now we are in CSS.
         assert_eq!(r.hystart.css_start_time().is_some(), true);
         assert_eq!(r.cwnd(), cwnd_prev + r.max_datagram_size);
+        assert_eq!(
+            r.hystart.take_transition(),
+            Some(hystart::Transition::SlowStartToCss)
+        );
 
         // Run 5 (CSS_ROUNDS) in CSS, to exit to congestion avoidance.
         let rtt_css = Duration::from_millis(100);
@@ -910,6 +917,10 @@ mod tests {
 
         // Now we are in congestion avoidance.
         assert_eq!(r.cwnd(), r.ssthresh);
+        assert_eq!(
+            r.hystart.take_transition(),
+            Some(hystart::Transition::CssToCongestionAvoidance)
+        );
     }
 
     #[test]
@@ -29,10 +29,10 @@
 //! This implements the algorithm for estimating delivery rate as described in
 //! <https://tools.ietf.org/html/draft-cheng-iccrg-delivery-rate-estimation-00>
 
-use std::cmp;
+use core::cmp;
 
-use std::time::Duration;
-use std::time::Instant;
+use crate::time::Duration;
+use crate::time::Instant;
 
 use crate::recovery::Sent;
 
@@ -126,8 +126,8 @@ impl Rate {
     }
 }
 
-impl std::fmt::Debug for Rate {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for Rate {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "delivered={:?} ", self.delivered)?;
 
         if let Some(t) = self.delivered_time {
@@ -161,8 +161,8 @@ struct RateSample {
     ack_elapsed: Duration,
 }
 
-impl std::fmt::Debug for RateSample {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for RateSample {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "delivery_rate={:?} ", self.delivery_rate)?;
         write!(f, "interval={:?} ", self.interval)?;
         write!(f, "delivered={:?} ", self.delivered)?;
@@ -179,8 +179,6 @@ impl std::fmt::Debug for RateSample {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     use crate::recovery::*;
 
     #[test]
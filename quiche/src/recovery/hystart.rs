@@ -26,30 +26,47 @@
 
 //! HyStart++
 //!
-//! This implementation is based on the following I-D:
+//! This implementation is based on the following RFC:
 //!
-//! <https://datatracker.ietf.org/doc/html/draft-ietf-tcpm-hystartplusplus-03>
+//! <https://datatracker.ietf.org/doc/html/rfc9406>
 
-use std::cmp;
-use std::time::Duration;
-use std::time::Instant;
+use crate::time::Duration;
+use crate::time::Instant;
+use core::cmp;
 
 use crate::packet;
 use crate::recovery;
 
-/// Constants from I-D.
-const LOW_CWND: usize = 16;
-
+/// Constants from RFC.
 const MIN_RTT_THRESH: Duration = Duration::from_millis(4);
 
 const MAX_RTT_THRESH: Duration = Duration::from_millis(16);
 
+const MIN_RTT_DIVISOR: u32 = 8;
+
 pub const N_RTT_SAMPLE: usize = 8;
 
 pub const CSS_GROWTH_DIVISOR: usize = 4;
 
 pub const CSS_ROUNDS: usize = 5;
 
+/// A change of HyStart++ phase, triggered by one of its exit conditions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transition {
+    /// The RTT increased enough to leave slow start for Conservative Slow
+    /// Start.
+    SlowStartToCss,
+
+    /// The RTT went back below the CSS baseline, so slow start resumes.
+    CssToSlowStart,
+
+    /// CSS lasted for `CSS_ROUNDS` rounds, so congestion avoidance starts.
+    CssToCongestionAvoidance,
+
+    /// A congestion event ended CSS.
+    CssToRecovery,
+}
+
 #[derive(Default)]
 pub struct Hystart {
     enabled: bool,
@@ -67,10 +84,12 @@ pub struct Hystart {
     css_start_time: Option<Instant>,
 
     css_round_count: usize,
+
+    transition: Option<Transition>,
 }
 
-impl std::fmt::Debug for Hystart {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for Hystart {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "window_end={:?} ", self.window_end)?;
         write!(f, "last_round_min_rtt={:?} ", self.last_round_min_rtt)?;
         write!(f, "current_round_min_rtt={:?} ", self.current_round_min_rtt)?;
@@ -116,6 +135,12 @@ impl Hystart {
             self.css_start_time().is_some()
     }
 
+    /// Returns the latest phase change, if any happened since the last call.
+    #[cfg(any(test, feature = "qlog"))]
+    pub fn take_transition(&mut self) -> Option<Transition> {
+        self.transition.take()
+    }
+
     pub fn start_round(&mut self, pkt_num: u64) {
         if self.window_end.is_none() {
             self.window_end = Some(pkt_num);
@@ -131,7 +156,7 @@ impl Hystart {
     // On receiving ACK. Returns true if need to enter Congestion Avoidance.
     pub fn on_packet_acked(
         &mut self, epoch: packet::Epoch, packet: &recovery::Acked, rtt: Duration,
-        cwnd: usize, now: Instant, max_datagram_size: usize,
+        now: Instant,
     ) -> bool {
         if !(self.enabled && epoch == packet::EPOCH_APPLICATION) {
             return false;
@@ -143,15 +168,16 @@ impl Hystart {
 
         // Slow Start.
         if self.css_start_time().is_none() {
-            if cwnd >= (LOW_CWND * max_datagram_size) &&
-                self.rtt_sample_count >= N_RTT_SAMPLE
+            if self.rtt_sample_count >= N_RTT_SAMPLE &&
+                self.current_round_min_rtt != Duration::MAX &&
+                self.last_round_min_rtt != Duration::MAX
             {
-                self.rtt_sample_count = 0;
-
                 // clamp(min_rtt_thresh, last_round_min_rtt/8,
                 // max_rtt_thresh)
-                let rtt_thresh =
-                    cmp::max(self.last_round_min_rtt / 8, MIN_RTT_THRESH);
+                let rtt_thresh = cmp::max(
+                    self.last_round_min_rtt / MIN_RTT_DIVISOR,
+                    MIN_RTT_THRESH,
+                );
                 let rtt_thresh = cmp::min(rtt_thresh, MAX_RTT_THRESH);
 
                 // Check if we can exit to CSS.
@@ -160,20 +186,22 @@ impl Hystart {
                 {
                     self.css_baseline_min_rtt = self.current_round_min_rtt;
                     self.css_start_time = Some(now);
+
+                    self.transition = Some(Transition::SlowStartToCss);
                 }
             }
         } else {
             // Conservative Slow Start.
-            if self.rtt_sample_count >= N_RTT_SAMPLE {
-                self.rtt_sample_count = 0;
+            if self.rtt_sample_count >= N_RTT_SAMPLE &&
+                self.current_round_min_rtt < self.css_baseline_min_rtt
+            {
+                self.css_baseline_min_rtt = Duration::MAX;
 
-                if self.current_round_min_rtt < self.css_baseline_min_rtt {
-                    self.css_baseline_min_rtt = Duration::MAX;
+                // Back to Slow Start.
+                self.css_start_time = None;
+                self.css_round_count = 0;
 
-                    // Back to Slow Start.
-                    self.css_start_time = None;
-                    self.css_round_count = 0;
-                }
+                self.transition = Some(Transition::CssToSlowStart);
             }
         }
 
@@ -189,6 +217,10 @@ impl Hystart {
                     // End of CSS - exit to congestion avoidance.
                     if self.css_round_count >= CSS_ROUNDS {
                         self.css_round_count = 0;
+
+                        self.transition =
+                            Some(Transition::CssToCongestionAvoidance);
+
                         return true;
                     }
                 }
@@ -205,6 +237,10 @@ impl Hystart {
 
     // Exit HyStart++ when entering congestion avoidance.
     pub fn congestion_event(&mut self) {
+        if self.css_start_time.is_some() {
+            self.transition = Some(Transition::CssToRecovery);
+        }
+
         self.window_end = None;
         self.css_start_time = None;
     }
@@ -248,5 +284,14 @@ mod tests {
         hspp.congestion_event();
 
         assert_eq!(hspp.window_end, None);
+
+        // Only leaving CSS is reported.
+        assert_eq!(hspp.take_transition(), None);
+
+        hspp.css_start_time = Some(Instant::now());
+        hspp.congestion_event();
+
+        assert_eq!(hspp.take_transition(), Some(Transition::CssToRecovery));
+        assert_eq!(hspp.take_transition(), None);
     }
 }
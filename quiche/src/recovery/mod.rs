@@ -24,15 +24,22 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use std::cmp;
+use core::cmp;
 
-use std::str::FromStr;
+use core::str::FromStr;
 
-use std::time::Duration;
-use std::time::Instant;
+use crate::time::Duration;
+use crate::time::Instant;
 
-use std::collections::VecDeque;
+use alloc::collections::VecDeque;
 
+use alloc::sync::Arc;
+
+#[cfg(feature = "qlog")]
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use crate::Clock;
 use crate::Config;
 use crate::Error;
 use crate::Result;
@@ -46,15 +53,17 @@ use crate::ranges;
 use qlog::events::EventData;
 
 // Loss Recovery
-const INITIAL_PACKET_THRESHOLD: u64 = 3;
+pub(crate) const INITIAL_PACKET_THRESHOLD: u64 = 3;
 
 const MAX_PACKET_THRESHOLD: u64 = 20;
 
-const INITIAL_TIME_THRESHOLD: f64 = 9.0 / 8.0;
+pub(crate) const INITIAL_TIME_THRESHOLD: f64 = 9.0 / 8.0;
 
 const GRANULARITY: Duration = Duration::from_millis(1);
 
-const INITIAL_RTT: Duration = Duration::from_millis(333);
+pub(crate) const INITIAL_RTT: Duration = Duration::from_millis(333);
+
+pub(crate) const MAX_PTO_BACKOFF: u32 = 16;
 
 const PERSISTENT_CONGESTION_THRESHOLD: u32 = 3;
 
@@ -73,6 +82,15 @@ const PACING_MULTIPLIER: f64 = 1.25;
 
 const MICROS_PER_SEC: u64 = 1_000_000;
 
+// The amount of time worth of data at the pacing rate that can be sent in a
+// single burst.
+const SEND_QUANTUM_INTERVAL: Duration = Duration::from_millis(1);
+
+const MIN_SEND_QUANTUM_PACKETS: usize = 2;
+
+// The largest burst, which also fits in a single UDP GSO send.
+const MAX_SEND_QUANTUM: usize = 64_000;
+
 pub struct Recovery {
     loss_detection_timer: Option<Instant>,
 
@@ -88,6 +106,8 @@ pub struct Recovery {
 
     smoothed_rtt: Option<Duration>,
 
+    initial_rtt: Duration,
+
     rttvar: Duration,
 
     minmax_filter: minmax::Minmax<Duration>,
@@ -104,10 +124,28 @@ pub struct Recovery {
 
     pub acked: [Vec<frame::Frame>; packet::EPOCH_COUNT],
 
+    // Frame buffers of discarded sent packets, kept for reuse by new packets.
+    frames_pool: Vec<Vec<frame::Frame>>,
+
+    frames_pool_size: usize,
+
+    // Scratch space for the packets acked by a single ACK frame.
+    newly_acked: Vec<Acked>,
+
     pub lost_count: usize,
 
     pub lost_spurious_count: usize,
 
+    pub pto_total_count: usize,
+
+    pub congestion_event_count: usize,
+
+    // The time the current congestion recovery episode started at.
+    congestion_event_time: Option<Instant>,
+
+    // The time the most recently sent packet that was acked was sent at.
+    largest_acked_sent_time: Option<Instant>,
+
     pub loss_probes: [usize; packet::EPOCH_COUNT],
 
     in_flight_count: [usize; packet::EPOCH_COUNT],
@@ -118,8 +156,12 @@ pub struct Recovery {
 
     pkt_thresh: u64,
 
+    max_pkt_thresh: u64,
+
     time_thresh: f64,
 
+    max_pto_backoff: u32,
+
     // Congestion control.
     cc_ops: &'static CongestionControlOps,
 
@@ -146,6 +188,9 @@ pub struct Recovery {
     // HyStart++.
     hystart: hystart::Hystart,
 
+    // Careful Resume.
+    resume: resume::Resume,
+
     // Pacing.
     pacing_rate: u64,
 
@@ -154,6 +199,8 @@ pub struct Recovery {
     // RFC6937 PRR.
     prr: prr::PRR,
 
+    clock: Arc<dyn Clock>,
+
     #[cfg(feature = "qlog")]
     qlog_metrics: QlogMetrics,
 }
@@ -167,23 +214,28 @@ impl Recovery {
 
             time_of_last_sent_ack_eliciting_pkt: [None; packet::EPOCH_COUNT],
 
-            largest_acked_pkt: [std::u64::MAX; packet::EPOCH_COUNT],
+            largest_acked_pkt: [core::u64::MAX; packet::EPOCH_COUNT],
 
             largest_sent_pkt: [0; packet::EPOCH_COUNT],
 
             latest_rtt: Duration::ZERO,
 
-            // This field should be initialized to `INITIAL_RTT` for the initial
+            // This field should be initialized to `initial_rtt` for the initial
             // PTO calculation, but it also needs to be an `Option` to track
             // whether any RTT sample was received, so the initial value is
             // handled by the `rtt()` method instead.
             smoothed_rtt: None,
 
-            minmax_filter: minmax::Minmax::new(Duration::ZERO),
+            initial_rtt: config.initial_rtt,
+
+            minmax_filter: minmax::Minmax::new(
+                Duration::ZERO,
+                config.clock.now(),
+            ),
 
             min_rtt: Duration::ZERO,
 
-            rttvar: INITIAL_RTT / 2,
+            rttvar: config.initial_rtt / 2,
 
             max_ack_delay: Duration::ZERO,
 
@@ -195,9 +247,23 @@ impl Recovery {
 
             acked: [Vec::new(), Vec::new(), Vec::new()],
 
+            frames_pool: Vec::new(),
+
+            frames_pool_size: config.buffer_pool_size,
+
+            newly_acked: Vec::new(),
+
             lost_count: 0,
             lost_spurious_count: 0,
 
+            pto_total_count: 0,
+
+            congestion_event_count: 0,
+
+            congestion_event_time: None,
+
+            largest_acked_sent_time: None,
+
             loss_probes: [0; packet::EPOCH_COUNT],
 
             in_flight_count: [0; packet::EPOCH_COUNT],
@@ -205,13 +271,20 @@ impl Recovery {
             congestion_window: config.max_send_udp_payload_size *
                 INITIAL_WINDOW_PACKETS,
 
-            pkt_thresh: INITIAL_PACKET_THRESHOLD,
+            pkt_thresh: config.packet_threshold,
+
+            max_pkt_thresh: cmp::max(
+                MAX_PACKET_THRESHOLD,
+                config.packet_threshold,
+            ),
 
-            time_thresh: INITIAL_TIME_THRESHOLD,
+            time_thresh: config.time_threshold,
+
+            max_pto_backoff: config.max_pto_backoff,
 
             bytes_in_flight: 0,
 
-            ssthresh: std::usize::MAX,
+            ssthresh: core::usize::MAX,
 
             bytes_acked_sl: 0,
 
@@ -235,12 +308,16 @@ impl Recovery {
 
             hystart: hystart::Hystart::new(config.hystart),
 
+            resume: resume::Resume::default(),
+
             pacing_rate: 0,
 
             last_packet_scheduled_time: None,
 
             prr: prr::PRR::default(),
 
+            clock: config.clock.clone(),
+
             #[cfg(feature = "qlog")]
             qlog_metrics: QlogMetrics::default(),
         }
@@ -250,6 +327,37 @@ impl Recovery {
         (self.cc_ops.on_init)(self);
     }
 
+    /// Resets the congestion controller and RTT estimator after the peer
+    /// moved to a new path, whose characteristics are unknown.
+    pub fn on_path_change(&mut self) {
+        let now = self.clock.now();
+
+        self.latest_rtt = Duration::ZERO;
+        self.smoothed_rtt = None;
+        self.minmax_filter = minmax::Minmax::new(Duration::ZERO, now);
+        self.min_rtt = Duration::ZERO;
+        self.rttvar = self.initial_rtt / 2;
+
+        self.congestion_window = self.max_datagram_size * INITIAL_WINDOW_PACKETS;
+        self.ssthresh = usize::MAX;
+        self.bytes_acked_sl = 0;
+        self.bytes_acked_ca = 0;
+        self.congestion_recovery_start_time = None;
+
+        self.cubic_state = cubic::State::default();
+        self.hystart = hystart::Hystart::new(self.hystart.enabled());
+        self.resume = resume::Resume::default();
+        self.prr = prr::PRR::default();
+
+        self.on_init();
+    }
+
+    /// Starts Careful Resume with the congestion window and minimum RTT saved
+    /// from a previous connection on the same path.
+    pub fn setup_resume(&mut self, cwnd: usize, rtt: Duration) {
+        self.resume.setup(cwnd, rtt);
+    }
+
     pub fn on_packet_sent(
         &mut self, mut pkt: Sent, epoch: packet::Epoch,
         handshake_status: HandshakeStatus, now: Instant, trace_id: &str,
@@ -280,6 +388,14 @@ impl Recovery {
 
             self.prr.on_packet_sent(sent_bytes);
 
+            if epoch == packet::EPOCH_APPLICATION {
+                if let Some(cwnd) =
+                    self.resume.on_packet_sent(pkt_num, self.bytes_in_flight)
+                {
+                    self.congestion_window = cwnd;
+                }
+            }
+
             self.set_loss_detection_timer(handshake_status, now);
         }
 
@@ -293,7 +409,8 @@ impl Recovery {
 
         // Pacing: Set the pacing rate if CC doesn't do its own.
         if !(self.cc_ops.has_custom_pacing)() {
-            if let Some(srtt) = self.smoothed_rtt {
+            // Careful Resume paces the jump window over the saved RTT.
+            if let Some(srtt) = self.resume.pacing_rtt().or(self.smoothed_rtt) {
                 let cwnd = self.congestion_window as u64;
                 let rate =
                     (cwnd * MICROS_PER_SEC) as f64 / srtt.as_micros() as f64;
@@ -305,7 +422,7 @@ impl Recovery {
         self.schedule_next_packet(epoch, now, sent_bytes);
 
         self.bytes_sent += sent_bytes;
-        trace!("{} {:?}", trace_id, self);
+        self.trace_state(trace_id);
     }
 
     fn on_packet_sent_cc(&mut self, sent_bytes: usize, now: Instant) {
@@ -369,7 +486,7 @@ impl Recovery {
             return Err(Error::InvalidPacket);
         }
 
-        if self.largest_acked_pkt[epoch] == std::u64::MAX {
+        if self.largest_acked_pkt[epoch] == core::u64::MAX {
             self.largest_acked_pkt[epoch] = largest_acked;
         } else {
             self.largest_acked_pkt[epoch] =
@@ -381,7 +498,7 @@ impl Recovery {
         let mut largest_newly_acked_pkt_num = 0;
         let mut largest_newly_acked_sent_time = now;
 
-        let mut newly_acked = Vec::new();
+        let mut newly_acked = core::mem::take(&mut self.newly_acked);
 
         let mut undo_cwnd = false;
 
@@ -410,7 +527,7 @@ impl Recovery {
                     // Calculate new packet reordering threshold.
                     let pkt_thresh =
                         self.largest_acked_pkt[epoch] - unacked.pkt_num + 1;
-                    let pkt_thresh = cmp::min(MAX_PACKET_THRESHOLD, pkt_thresh);
+                    let pkt_thresh = cmp::min(self.max_pkt_thresh, pkt_thresh);
 
                     self.pkt_thresh = cmp::max(self.pkt_thresh, pkt_thresh);
 
@@ -418,7 +535,7 @@ impl Recovery {
                     let loss_delay = max_rtt.mul_f64(self.time_thresh);
                     if now.duration_since(unacked.time_sent) > loss_delay {
                         // TODO: do time threshold update
-                        self.time_thresh = 5_f64 / 4_f64;
+                        self.time_thresh = f64::max(self.time_thresh, 5.0 / 4.0);
                     }
 
                     if unacked.in_flight {
@@ -453,7 +570,12 @@ impl Recovery {
                     size: unacked.size,
                 });
 
-                trace!("{} packet newly acked {}", trace_id, unacked.pkt_num);
+                trace_event!(
+                    { pn = unacked.pkt_num, size = unacked.size, "acked" },
+                    "{} packet newly acked {}",
+                    trace_id,
+                    unacked.pkt_num
+                );
             }
         }
 
@@ -465,9 +587,16 @@ impl Recovery {
         self.delivery_rate.estimate();
 
         if newly_acked.is_empty() {
+            self.newly_acked = newly_acked;
+
             return Ok(());
         }
 
+        self.largest_acked_sent_time = cmp::max(
+            self.largest_acked_sent_time,
+            Some(largest_newly_acked_sent_time),
+        );
+
         if largest_newly_acked_pkt_num == largest_acked && has_ack_eliciting {
             let latest_rtt = now - largest_newly_acked_sent_time;
 
@@ -484,7 +613,9 @@ impl Recovery {
         // packets list.
         self.detect_lost_packets(epoch, now, trace_id);
 
-        self.on_packets_acked(newly_acked, epoch, now);
+        self.on_packets_acked(newly_acked.drain(..), epoch, now);
+
+        self.newly_acked = newly_acked;
 
         self.pto_count = 0;
 
@@ -507,7 +638,7 @@ impl Recovery {
 
             self.set_loss_detection_timer(handshake_status, now);
 
-            trace!("{} {:?}", trace_id, self);
+            self.trace_state(trace_id);
             return;
         }
 
@@ -529,6 +660,7 @@ impl Recovery {
         };
 
         self.pto_count += 1;
+        self.pto_total_count += 1;
 
         self.loss_probes[epoch] =
             cmp::min(self.pto_count as usize, MAX_PTO_PROBES_COUNT);
@@ -555,7 +687,25 @@ impl Recovery {
 
         self.set_loss_detection_timer(handshake_status, now);
 
-        trace!("{} {:?}", trace_id, self);
+        self.trace_state(trace_id);
+    }
+
+    /// Records the congestion control and RTT state.
+    fn trace_state(&self, trace_id: &str) {
+        trace_event!(
+            {
+                conn = %trace_id,
+                cwnd = self.congestion_window,
+                ssthresh = self.ssthresh,
+                bytes_in_flight = self.bytes_in_flight,
+                srtt = ?self.smoothed_rtt,
+                pto_count = self.pto_count,
+                "recovery state"
+            },
+            "{} {:?}",
+            trace_id,
+            self
+        );
     }
 
     pub fn on_pkt_num_space_discarded(
@@ -594,7 +744,7 @@ impl Recovery {
     pub fn cwnd_available(&self) -> usize {
         // Ignore cwnd when sending probe packets.
         if self.loss_probes.iter().any(|&x| x > 0) {
-            return std::usize::MAX;
+            return core::usize::MAX;
         }
 
         // Open more space (snd_cnt) for PRR when allowed.
@@ -603,13 +753,73 @@ impl Recovery {
     }
 
     pub fn rtt(&self) -> Duration {
-        self.smoothed_rtt.unwrap_or(INITIAL_RTT)
+        self.smoothed_rtt.unwrap_or(self.initial_rtt)
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    pub fn min_rtt(&self) -> Duration {
+        self.min_rtt
+    }
+
+    pub fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    pub fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    pub fn pacing_rate(&self) -> u64 {
+        self.pacing_rate
+    }
+
+    // Returns the number of bytes that can be sent in a single burst without
+    // defeating pacing.
+    pub fn send_quantum(&self) -> usize {
+        let quantum = if self.pacing_rate == 0 {
+            // No rate is known before the first RTT sample, so allow the
+            // initial window to go out at once.
+            self.max_datagram_size * INITIAL_WINDOW_PACKETS
+        } else {
+            (self.pacing_rate * SEND_QUANTUM_INTERVAL.as_micros() as u64 /
+                MICROS_PER_SEC) as usize
+        };
+
+        cmp::min(
+            cmp::max(quantum, self.max_datagram_size * MIN_SEND_QUANTUM_PACKETS),
+            MAX_SEND_QUANTUM,
+        )
+    }
+
+    pub fn congestion_state(&self) -> CongestionState {
+        // The recovery episode ends once a packet sent after it started is
+        // acked.
+        if let Some(t) = self.congestion_event_time {
+            if !matches!(self.largest_acked_sent_time, Some(v) if v > t) {
+                return CongestionState::Recovery;
+            }
+        }
+
+        if self.congestion_window < self.ssthresh {
+            CongestionState::SlowStart
+        } else {
+            CongestionState::CongestionAvoidance
+        }
     }
 
     pub fn pto(&self) -> Duration {
         self.rtt() + cmp::max(self.rttvar * 4, GRANULARITY)
     }
 
+    // The factor the PTO is multiplied by, doubling with each consecutive
+    // PTO up to the configured limit.
+    fn pto_backoff(&self) -> u32 {
+        2_u32.pow(cmp::min(self.pto_count, self.max_pto_backoff))
+    }
+
     pub fn delivery_rate(&self) -> u64 {
         self.delivery_rate.delivery_rate()
     }
@@ -690,7 +900,7 @@ impl Recovery {
     fn pto_time_and_space(
         &self, handshake_status: HandshakeStatus, now: Instant,
     ) -> (Option<Instant>, packet::Epoch) {
-        let mut duration = self.pto() * 2_u32.pow(self.pto_count);
+        let mut duration = self.pto() * self.pto_backoff();
 
         // Arm PTO from now when there are no inflight packets.
         if self.bytes_in_flight == 0 {
@@ -717,7 +927,7 @@ impl Recovery {
                 }
 
                 // Include max_ack_delay and backoff for Application Data.
-                duration += self.max_ack_delay * 2_u32.pow(self.pto_count);
+                duration += self.max_ack_delay * self.pto_backoff();
             }
 
             let new_time =
@@ -756,6 +966,8 @@ impl Recovery {
     fn detect_lost_packets(
         &mut self, epoch: packet::Epoch, now: Instant, trace_id: &str,
     ) {
+        trace_span!("detect_lost_packets", conn = %trace_id, epoch);
+
         let largest_acked = self.largest_acked_pkt[epoch];
 
         self.loss_time[epoch] = None;
@@ -799,7 +1011,8 @@ impl Recovery {
                     self.in_flight_count[epoch] =
                         self.in_flight_count[epoch].saturating_sub(1);
 
-                    trace!(
+                    trace_event!(
+                        { pn = unacked.pkt_num, size = unacked.size, "lost" },
                         "{} packet {} lost on epoch {}",
                         trace_id,
                         unacked.pkt_num,
@@ -856,15 +1069,106 @@ impl Recovery {
             }
         }
 
-        // Then remove elements up to the previously found index.
-        self.sent[epoch].drain(..lowest_non_expired_pkt_index);
+        // Then remove elements up to the previously found index, and keep
+        // their frame buffers around for reuse.
+        for pkt in self.sent[epoch].drain(..lowest_non_expired_pkt_index) {
+            let mut frames = pkt.frames;
+
+            if self.frames_pool.len() < self.frames_pool_size &&
+                frames.capacity() > 0
+            {
+                frames.clear();
+
+                self.frames_pool.push(frames);
+            }
+        }
     }
 
-    fn on_packets_acked(
-        &mut self, acked: Vec<Acked>, epoch: packet::Epoch, now: Instant,
+    /// Returns an empty buffer for the frames of a new packet, reusing the
+    /// buffer of a previously discarded packet if possible.
+    pub fn frames_buf(&mut self) -> Vec<frame::Frame> {
+        self.frames_pool.pop().unwrap_or_default()
+    }
+
+    /// Returns a frames buffer to the pool, so it can be reused.
+    pub fn recycle_frames_buf(&mut self, mut frames: Vec<frame::Frame>) {
+        if self.frames_pool.len() < self.frames_pool_size && frames.capacity() > 0
+        {
+            frames.clear();
+
+            self.frames_pool.push(frames);
+        }
+    }
+
+    /// Returns the amount of memory held by the state of sent packets and by
+    /// the frame buffers, in bytes.
+    pub fn retained(&self) -> usize {
+        let frame_size = core::mem::size_of::<frame::Frame>();
+
+        let mut retained =
+            self.newly_acked.capacity() * core::mem::size_of::<Acked>();
+
+        for e in packet::EPOCH_INITIAL..packet::EPOCH_COUNT {
+            retained += self.sent[e].capacity() * core::mem::size_of::<Sent>();
+
+            for pkt in &self.sent[e] {
+                retained += pkt.frames.capacity() * frame_size;
+            }
+
+            retained +=
+                (self.lost[e].capacity() + self.acked[e].capacity()) * frame_size;
+        }
+
+        for frames in &self.frames_pool {
+            retained += frames.capacity() * frame_size;
+        }
+
+        retained
+    }
+
+    /// Releases the memory that is not needed anymore.
+    ///
+    /// Acked and lost packets are only removed once all the packets sent
+    /// before them are acked or lost too, so their frame buffers are freed
+    /// here instead. The frame buffers kept for reuse are freed as well.
+    pub fn shrink_to_fit(&mut self, now: Instant) {
+        for e in packet::EPOCH_INITIAL..packet::EPOCH_COUNT {
+            for pkt in self.sent[e].iter_mut() {
+                if pkt.time_acked.is_some() || pkt.time_lost.is_some() {
+                    pkt.frames = Vec::new();
+                }
+            }
+
+            self.drain_packets(e, now);
+
+            self.sent[e].shrink_to_fit();
+            self.lost[e].shrink_to_fit();
+            self.acked[e].shrink_to_fit();
+        }
+
+        self.frames_pool = Vec::new();
+        self.newly_acked = Vec::new();
+    }
+
+    fn on_packets_acked<I: IntoIterator<Item = Acked>>(
+        &mut self, acked: I, epoch: packet::Epoch, now: Instant,
     ) {
         for pkt in acked {
             (self.cc_ops.on_packet_acked)(self, &pkt, epoch, now);
+
+            if epoch != packet::EPOCH_APPLICATION {
+                continue;
+            }
+
+            if let Some(cwnd) = self.resume.on_packet_acked(
+                &pkt,
+                self.congestion_window,
+                self.min_rtt,
+                self.bytes_in_flight,
+                !self.app_limited,
+            ) {
+                self.congestion_window = cwnd;
+            }
         }
     }
 
@@ -892,6 +1196,17 @@ impl Recovery {
 
         self.congestion_event(largest_lost_pkt.time_sent, epoch, now);
 
+        if epoch == packet::EPOCH_APPLICATION {
+            let min_window = self.max_datagram_size * MINIMUM_WINDOW_PACKETS;
+
+            if let Some((cwnd, ssthresh)) =
+                self.resume.on_congestion_event(min_window)
+            {
+                self.congestion_window = cwnd;
+                self.ssthresh = ssthresh;
+            }
+        }
+
         if self.in_persistent_congestion(largest_lost_pkt.pkt_num) {
             self.collapse_cwnd();
         }
@@ -902,6 +1217,9 @@ impl Recovery {
     ) {
         if !self.in_congestion_recovery(time_sent) {
             (self.cc_ops.checkpoint)(self);
+
+            self.congestion_event_count += 1;
+            self.congestion_event_time = Some(now);
         }
 
         (self.cc_ops.congestion_event)(self, time_sent, epoch, now);
@@ -925,6 +1243,38 @@ impl Recovery {
         self.app_limited
     }
 
+    /// Creates a qlog event for the latest HyStart++ phase change, if any.
+    #[cfg(feature = "qlog")]
+    pub fn hystart_qlog(&mut self) -> Option<EventData> {
+        use hystart::Transition;
+
+        let (old, new, trigger) = match self.hystart.take_transition()? {
+            Transition::SlowStartToCss =>
+                ("slow_start", "conservative_slow_start", "rtt_increase"),
+
+            Transition::CssToSlowStart =>
+                ("conservative_slow_start", "slow_start", "rtt_decrease"),
+
+            Transition::CssToCongestionAvoidance => (
+                "conservative_slow_start",
+                "congestion_avoidance",
+                "css_rounds",
+            ),
+
+            Transition::CssToRecovery =>
+                ("conservative_slow_start", "recovery", "congestion_event"),
+        };
+
+        Some(EventData::CongestionStateUpdated(
+            qlog::events::quic::CongestionStateUpdated {
+                old: Some(old.to_string()),
+                new: new.to_string(),
+
+                trigger: Some(format!("hystart_{}", trigger)),
+            },
+        ))
+    }
+
     #[cfg(feature = "qlog")]
     pub fn maybe_qlog(&mut self) -> Option<EventData> {
         let qlog_metrics = QlogMetrics {
@@ -939,6 +1289,30 @@ impl Recovery {
 
         self.qlog_metrics.maybe_update(qlog_metrics)
     }
+
+    /// Creates a qlog event for the loss detection and congestion control
+    /// parameters.
+    #[cfg(feature = "qlog")]
+    pub fn parameters_qlog(&self) -> EventData {
+        let minimum_window = self.max_datagram_size * MINIMUM_WINDOW_PACKETS;
+
+        EventData::RecoveryParametersSet(
+            qlog::events::quic::RecoveryParametersSet {
+                reordering_threshold: Some(self.pkt_thresh as u16),
+                time_threshold: Some(self.time_thresh as f32),
+                timer_granularity: Some(GRANULARITY.as_millis() as u16),
+                initial_rtt: Some(self.initial_rtt.as_secs_f32() * 1000.0),
+
+                max_datagram_size: Some(self.max_datagram_size as u32),
+                initial_congestion_window: Some(self.congestion_window as u64),
+                minimum_congestion_window: Some(minimum_window as u32),
+                loss_reduction_factor: Some(LOSS_REDUCTION_FACTOR as f32),
+                persistent_congestion_threshold: Some(
+                    PERSISTENT_CONGESTION_THRESHOLD as u16,
+                ),
+            },
+        )
+    }
 }
 
 /// Available congestion control algorithms.
@@ -960,7 +1334,7 @@ impl FromStr for CongestionControlAlgorithm {
     /// Converts a string to `CongestionControlAlgorithm`.
     ///
     /// If `name` is not valid, `Error::CongestionControl` is returned.
-    fn from_str(name: &str) -> std::result::Result<Self, Self::Err> {
+    fn from_str(name: &str) -> core::result::Result<Self, Self::Err> {
         match name {
             "reno" => Ok(CongestionControlAlgorithm::Reno),
             "cubic" => Ok(CongestionControlAlgorithm::CUBIC),
@@ -970,6 +1344,21 @@ impl FromStr for CongestionControlAlgorithm {
     }
 }
 
+/// The state of the congestion controller.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CongestionState {
+    /// The congestion window grows exponentially.
+    SlowStart,
+
+    /// The congestion window grows following the congestion avoidance mode
+    /// of the congestion control algorithm.
+    CongestionAvoidance,
+
+    /// The congestion window was reduced following a loss, and the packets
+    /// sent before that are still being acked.
+    Recovery,
+}
+
 pub struct CongestionControlOps {
     pub on_init: fn(r: &mut Recovery),
 
@@ -993,8 +1382,10 @@ pub struct CongestionControlOps {
 
     pub has_custom_pacing: fn() -> bool,
 
-    pub debug_fmt:
-        fn(r: &Recovery, formatter: &mut std::fmt::Formatter) -> std::fmt::Result,
+    pub debug_fmt: fn(
+        r: &Recovery,
+        formatter: &mut core::fmt::Formatter,
+    ) -> core::fmt::Result,
 }
 
 impl From<CongestionControlAlgorithm> for &'static CongestionControlOps {
@@ -1006,11 +1397,11 @@ impl From<CongestionControlAlgorithm> for &'static CongestionControlOps {
     }
 }
 
-impl std::fmt::Debug for Recovery {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for Recovery {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self.loss_detection_timer {
             Some(v) => {
-                let now = Instant::now();
+                let now = self.clock.now();
 
                 if v > now {
                     let d = v.duration_since(now);
@@ -1052,6 +1443,10 @@ impl std::fmt::Debug for Recovery {
             write!(f, "hystart={:?} ", self.hystart)?;
         }
 
+        if self.resume.phase() != resume::Phase::Normal {
+            write!(f, "resume={:?} ", self.resume)?;
+        }
+
         // CC-specific debug info
         (self.cc_ops.debug_fmt)(self, f)?;
 
@@ -1088,8 +1483,8 @@ pub struct Sent {
     pub has_data: bool,
 }
 
-impl std::fmt::Debug for Sent {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for Sent {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "pkt_num={:?} ", self.pkt_num)?;
         write!(f, "pkt_sent_time={:?} ", self.time_sent.elapsed())?;
         write!(f, "pkt_size={:?} ", self.size)?;
@@ -1281,6 +1676,35 @@ mod tests {
         assert_eq!(r.cwnd(), r.max_datagram_size * MINIMUM_WINDOW_PACKETS);
     }
 
+    #[test]
+    fn custom_loss_detection_params() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_packet_threshold(10);
+        cfg.set_time_threshold(2.0);
+        cfg.set_max_pto_backoff(3);
+        cfg.set_initial_rtt(600);
+
+        let mut r = Recovery::new(&cfg);
+
+        assert_eq!(r.pkt_thresh, 10);
+        assert_eq!(r.max_pkt_thresh, MAX_PACKET_THRESHOLD);
+        assert_eq!(r.time_thresh, 2.0);
+
+        // No RTT sample yet, so the initial RTT is used.
+        assert_eq!(r.rtt(), Duration::from_millis(600));
+        assert_eq!(
+            r.pto(),
+            Duration::from_millis(600) + Duration::from_millis(300) * 4
+        );
+
+        // The backoff stops doubling once the limit is reached.
+        r.pto_count = 2;
+        assert_eq!(r.pto_backoff(), 4);
+
+        r.pto_count = 10;
+        assert_eq!(r.pto_backoff(), 8);
+    }
+
     #[test]
     fn loss_on_pto() {
         let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
@@ -1428,6 +1852,7 @@ mod tests {
         assert_eq!(r.loss_probes[packet::EPOCH_APPLICATION], 1);
         assert_eq!(r.lost_count, 0);
         assert_eq!(r.pto_count, 1);
+        assert_eq!(r.pto_total_count, 1);
 
         let p = Sent {
             pkt_num: 4,
@@ -1809,6 +2234,10 @@ mod tests {
             Ok(())
         );
 
+        // The first packet is declared lost, starting a recovery episode.
+        assert_eq!(r.congestion_event_count, 1);
+        assert_eq!(r.congestion_state(), CongestionState::Recovery);
+
         now += Duration::from_millis(10);
 
         let mut acked = ranges::RangeSet::default();
@@ -1846,6 +2275,34 @@ mod tests {
         assert_eq!(r.sent[packet::EPOCH_APPLICATION].len(), 0);
     }
 
+    #[test]
+    fn send_quantum() {
+        let cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+
+        let mut r = Recovery::new(&cfg);
+
+        // The initial window can be sent at once before pacing kicks in.
+        assert_eq!(
+            r.send_quantum(),
+            r.max_datagram_size() * INITIAL_WINDOW_PACKETS
+        );
+
+        // 1ms worth of data at 10MB/s.
+        r.set_pacing_rate(10_000_000);
+        assert_eq!(r.send_quantum(), 10_000);
+
+        // Slow paths still get a couple of packets per burst.
+        r.set_pacing_rate(1_000);
+        assert_eq!(
+            r.send_quantum(),
+            r.max_datagram_size() * MIN_SEND_QUANTUM_PACKETS
+        );
+
+        // Fast paths are limited to a single GSO send.
+        r.set_pacing_rate(1_000_000_000);
+        assert_eq!(r.send_quantum(), MAX_SEND_QUANTUM);
+    }
+
     #[test]
     fn pacing() {
         let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
@@ -1981,6 +2438,145 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn frames_buf_reuse() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_buffer_pool_size(1);
+
+        let mut r = Recovery::new(&cfg);
+
+        let now = Instant::now();
+
+        let frames = r.frames_buf();
+        assert_eq!(frames.capacity(), 0);
+
+        for pkt_num in 0..3 {
+            let mut frames = r.frames_buf();
+            frames.push(frame::Frame::Ping);
+
+            let p = Sent {
+                pkt_num,
+                frames,
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                recent_delivered_packet_sent_time: now,
+                is_app_limited: false,
+                has_data: false,
+            };
+
+            r.on_packet_sent(
+                p,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(0..3);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                25,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                ""
+            ),
+            Ok(())
+        );
+
+        assert_eq!(r.sent[packet::EPOCH_APPLICATION].len(), 0);
+
+        // Only up to the configured number of buffers are kept around.
+        assert_eq!(r.frames_pool.len(), 1);
+
+        let frames = r.frames_buf();
+        assert!(frames.is_empty());
+        assert!(frames.capacity() > 0);
+
+        assert_eq!(r.frames_pool.len(), 0);
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+
+        let mut r = Recovery::new(&cfg);
+
+        let now = Instant::now();
+
+        for pkt_num in 0..3 {
+            let mut frames = r.frames_buf();
+            frames.push(frame::Frame::Ping);
+
+            let p = Sent {
+                pkt_num,
+                frames,
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                recent_delivered_packet_sent_time: now,
+                is_app_limited: false,
+                has_data: false,
+            };
+
+            r.on_packet_sent(
+                p,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+
+        // Packet 0 is not acked, so the following ones can't be removed yet.
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(1..3);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                25,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                ""
+            ),
+            Ok(())
+        );
+
+        assert_eq!(r.sent[packet::EPOCH_APPLICATION].len(), 3);
+        assert!(r.sent[packet::EPOCH_APPLICATION][1].frames.capacity() > 0);
+
+        let retained = r.retained();
+
+        r.shrink_to_fit(now);
+
+        assert!(r.retained() < retained);
+
+        // The acked packets are still tracked, but without frame buffers.
+        assert_eq!(r.sent[packet::EPOCH_APPLICATION].len(), 3);
+        assert_eq!(r.sent[packet::EPOCH_APPLICATION][0].frames, vec![
+            frame::Frame::Ping
+        ]);
+        assert_eq!(r.sent[packet::EPOCH_APPLICATION][1].frames.capacity(), 0);
+        assert_eq!(r.sent[packet::EPOCH_APPLICATION][2].frames.capacity(), 0);
+    }
 }
 
 mod cubic;
@@ -1988,3 +2584,4 @@ mod delivery_rate;
 mod hystart;
 mod prr;
 mod reno;
+mod resume;
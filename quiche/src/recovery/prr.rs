@@ -30,7 +30,7 @@
 //!
 //! <https://datatracker.ietf.org/doc/html/rfc6937>
 
-use std::cmp;
+use core::cmp;
 
 #[derive(Default, Debug)]
 pub struct PRR {
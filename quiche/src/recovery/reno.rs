@@ -28,8 +28,8 @@
 //!
 //! Note that Slow Start can use HyStart++ when enabled.
 
-use std::cmp;
-use std::time::Instant;
+use crate::time::Instant;
+use core::cmp;
 
 use crate::packet;
 use crate::recovery;
@@ -80,14 +80,7 @@ fn on_packet_acked(
             r.congestion_window += r.max_datagram_size;
         }
 
-        if r.hystart.on_packet_acked(
-            epoch,
-            packet,
-            r.latest_rtt,
-            r.congestion_window,
-            now,
-            r.max_datagram_size,
-        ) {
+        if r.hystart.on_packet_acked(epoch, packet, r.latest_rtt, now) {
             // Exit to congestion avoidance if CSS ends.
             r.ssthresh = r.congestion_window;
         }
@@ -150,7 +143,7 @@ fn has_custom_pacing() -> bool {
     false
 }
 
-fn debug_fmt(_r: &Recovery, _f: &mut std::fmt::Formatter) -> std::fmt::Result {
+fn debug_fmt(_r: &Recovery, _f: &mut core::fmt::Formatter) -> core::fmt::Result {
     Ok(())
 }
 
@@ -158,7 +151,7 @@ fn debug_fmt(_r: &Recovery, _f: &mut std::fmt::Formatter) -> std::fmt::Result {
 mod tests {
     use super::*;
 
-    use std::time::Duration;
+    use crate::time::Duration;
 
     #[test]
     fn reno_init() {
@@ -204,8 +197,8 @@ mod tests {
             ack_eliciting: true,
             in_flight: true,
             delivered: 0,
-            delivered_time: std::time::Instant::now(),
-            recent_delivered_packet_sent_time: std::time::Instant::now(),
+            delivered_time: crate::time::Instant::now(),
+            recent_delivered_packet_sent_time: crate::time::Instant::now(),
             is_app_limited: false,
             has_data: false,
         };
@@ -248,8 +241,8 @@ mod tests {
             ack_eliciting: true,
             in_flight: true,
             delivered: 0,
-            delivered_time: std::time::Instant::now(),
-            recent_delivered_packet_sent_time: std::time::Instant::now(),
+            delivered_time: crate::time::Instant::now(),
+            recent_delivered_packet_sent_time: crate::time::Instant::now(),
             is_app_limited: false,
             has_data: false,
         };
@@ -0,0 +1,381 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Careful Resume
+//!
+//! This implementation is based on the following I-D:
+//!
+//! <https://datatracker.ietf.org/doc/html/draft-ietf-tsvwg-careful-resume-05>
+//!
+//! The congestion window and RTT saved from a previous connection to the
+//! same peer are used to jump the congestion window of a new connection to
+//! half of the saved value, once the path has been confirmed to look alike.
+//! The jump is then validated by the acknowledgement of the packets sent
+//! with it, and retreats to a safe value if congestion is detected before
+//! that happens.
+
+use crate::time::Duration;
+use core::cmp;
+
+use crate::recovery;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for the path to be confirmed, using the initial window.
+    Reconnaissance,
+
+    /// Sending with the jump window, without knowing yet whether the path
+    /// can sustain it.
+    Unvalidated,
+
+    /// Waiting for the packets sent with the jump window to be acked.
+    Validating,
+
+    /// Congestion was detected before the jump window was validated.
+    SafeRetreat,
+
+    /// Careful Resume is not used (anymore).
+    Normal,
+}
+
+pub struct Resume {
+    phase: Phase,
+
+    saved_cwnd: usize,
+
+    saved_rtt: Duration,
+
+    jump_cwnd: usize,
+
+    // The number of bytes acked since the jump, used as an estimate of the
+    // capacity of the path.
+    pipesize: usize,
+
+    first_unvalidated_pkt: Option<u64>,
+
+    last_unvalidated_pkt: u64,
+}
+
+impl core::fmt::Debug for Resume {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "phase={:?} ", self.phase)?;
+        write!(f, "saved_cwnd={} ", self.saved_cwnd)?;
+        write!(f, "saved_rtt={:?} ", self.saved_rtt)?;
+        write!(f, "pipesize={}", self.pipesize)?;
+
+        Ok(())
+    }
+}
+
+impl Default for Resume {
+    fn default() -> Self {
+        Resume {
+            phase: Phase::Normal,
+
+            saved_cwnd: 0,
+
+            saved_rtt: Duration::ZERO,
+
+            jump_cwnd: 0,
+
+            pipesize: 0,
+
+            first_unvalidated_pkt: None,
+
+            last_unvalidated_pkt: 0,
+        }
+    }
+}
+
+impl Resume {
+    /// Starts Careful Resume with the state saved from a previous
+    /// connection.
+    pub fn setup(&mut self, saved_cwnd: usize, saved_rtt: Duration) {
+        *self = Resume {
+            phase: Phase::Reconnaissance,
+
+            saved_cwnd,
+
+            saved_rtt,
+
+            ..Default::default()
+        };
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Returns the RTT to compute the pacing rate with, if it differs from
+    /// the smoothed RTT.
+    pub fn pacing_rtt(&self) -> Option<Duration> {
+        if self.phase == Phase::Unvalidated {
+            return Some(self.saved_rtt);
+        }
+
+        None
+    }
+
+    /// Returns the new congestion window, if the phase requires changing it.
+    pub fn on_packet_sent(
+        &mut self, pkt_num: u64, bytes_in_flight: usize,
+    ) -> Option<usize> {
+        if self.phase != Phase::Unvalidated {
+            return None;
+        }
+
+        self.first_unvalidated_pkt.get_or_insert(pkt_num);
+        self.last_unvalidated_pkt = pkt_num;
+
+        // The whole jump window is in flight, stop sending until it starts
+        // being acked.
+        if bytes_in_flight >= self.jump_cwnd {
+            self.phase = Phase::Validating;
+
+            return Some(bytes_in_flight);
+        }
+
+        None
+    }
+
+    /// Returns the new congestion window, if the phase requires changing it.
+    pub fn on_packet_acked(
+        &mut self, packet: &recovery::Acked, cwnd: usize, min_rtt: Duration,
+        bytes_in_flight: usize, cwnd_limited: bool,
+    ) -> Option<usize> {
+        match self.phase {
+            Phase::Reconnaissance => {
+                // The path changed, so the saved state doesn't apply to it.
+                if min_rtt < self.saved_rtt / 2 {
+                    self.phase = Phase::Normal;
+                    return None;
+                }
+
+                if !cwnd_limited {
+                    return None;
+                }
+
+                self.jump_cwnd = self.saved_cwnd / 2;
+
+                // Jumping wouldn't increase the window.
+                if self.jump_cwnd <= cwnd {
+                    self.phase = Phase::Normal;
+                    return None;
+                }
+
+                self.phase = Phase::Unvalidated;
+                self.pipesize = cwnd;
+
+                Some(self.jump_cwnd)
+            },
+
+            Phase::Unvalidated => {
+                self.pipesize += packet.size;
+
+                // The first packet sent with the jump window was acked.
+                if let Some(pn) = self.first_unvalidated_pkt {
+                    if packet.pkt_num >= pn {
+                        self.phase = Phase::Validating;
+
+                        return Some(cmp::max(bytes_in_flight, self.pipesize));
+                    }
+                }
+
+                // The window isn't increased until the jump is validated.
+                Some(self.jump_cwnd)
+            },
+
+            Phase::Validating => {
+                self.pipesize += packet.size;
+
+                if packet.pkt_num >= self.last_unvalidated_pkt {
+                    self.phase = Phase::Normal;
+                }
+
+                None
+            },
+
+            Phase::SafeRetreat => {
+                if packet.pkt_num >= self.last_unvalidated_pkt {
+                    self.phase = Phase::Normal;
+                }
+
+                // The window isn't increased until the retreat is over.
+                Some(cmp::min(cwnd, self.pipesize))
+            },
+
+            Phase::Normal => None,
+        }
+    }
+
+    /// Returns the new congestion window and slow start threshold, if the
+    /// phase requires changing them.
+    pub fn on_congestion_event(
+        &mut self, min_window: usize,
+    ) -> Option<(usize, usize)> {
+        match self.phase {
+            Phase::Reconnaissance => {
+                self.phase = Phase::Normal;
+
+                None
+            },
+
+            Phase::Unvalidated | Phase::Validating => {
+                self.phase = Phase::SafeRetreat;
+
+                // Only half of what the path was seen to deliver is assumed
+                // to be safe.
+                self.pipesize = cmp::max(self.pipesize / 2, min_window);
+
+                Some((self.pipesize, self.pipesize))
+            },
+
+            Phase::SafeRetreat | Phase::Normal => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acked(pkt_num: u64) -> recovery::Acked {
+        recovery::Acked {
+            pkt_num,
+            time_sent: crate::time::Instant::now(),
+            size: 1000,
+        }
+    }
+
+    #[test]
+    fn jump_and_validate() {
+        let mut r = Resume::default();
+        r.setup(100_000, Duration::from_millis(50));
+
+        assert_eq!(r.phase(), Phase::Reconnaissance);
+
+        // Not cwnd-limited yet.
+        let rtt = Duration::from_millis(40);
+        assert_eq!(r.on_packet_acked(&acked(0), 10_000, rtt, 0, false), None);
+        assert_eq!(r.phase(), Phase::Reconnaissance);
+
+        assert_eq!(
+            r.on_packet_acked(&acked(1), 10_000, rtt, 9_000, true),
+            Some(50_000)
+        );
+        assert_eq!(r.phase(), Phase::Unvalidated);
+        assert_eq!(r.pacing_rtt(), Some(Duration::from_millis(50)));
+
+        assert_eq!(r.on_packet_sent(10, 20_000), None);
+        assert_eq!(r.on_packet_sent(11, 21_000), None);
+
+        // Acks of packets sent before the jump don't grow the window.
+        assert_eq!(
+            r.on_packet_acked(&acked(2), 50_000, rtt, 20_000, true),
+            Some(50_000)
+        );
+
+        assert_eq!(r.on_packet_sent(12, 50_000), Some(50_000));
+        assert_eq!(r.phase(), Phase::Validating);
+        assert_eq!(r.pacing_rtt(), None);
+
+        assert_eq!(
+            r.on_packet_acked(&acked(11), 50_000, rtt, 40_000, true),
+            None
+        );
+        assert_eq!(r.phase(), Phase::Validating);
+
+        assert_eq!(
+            r.on_packet_acked(&acked(12), 50_000, rtt, 40_000, true),
+            None
+        );
+        assert_eq!(r.phase(), Phase::Normal);
+    }
+
+    #[test]
+    fn path_changed() {
+        let mut r = Resume::default();
+        r.setup(100_000, Duration::from_millis(50));
+
+        let rtt = Duration::from_millis(20);
+        assert_eq!(r.on_packet_acked(&acked(0), 10_000, rtt, 9_000, true), None);
+        assert_eq!(r.phase(), Phase::Normal);
+    }
+
+    #[test]
+    fn no_gain() {
+        let mut r = Resume::default();
+        r.setup(15_000, Duration::from_millis(50));
+
+        let rtt = Duration::from_millis(50);
+        assert_eq!(r.on_packet_acked(&acked(0), 10_000, rtt, 9_000, true), None);
+        assert_eq!(r.phase(), Phase::Normal);
+    }
+
+    #[test]
+    fn safe_retreat() {
+        let mut r = Resume::default();
+        r.setup(100_000, Duration::from_millis(50));
+
+        let rtt = Duration::from_millis(50);
+        assert_eq!(
+            r.on_packet_acked(&acked(0), 10_000, rtt, 9_000, true),
+            Some(50_000)
+        );
+
+        assert_eq!(r.on_packet_sent(10, 20_000), None);
+        assert_eq!(r.on_packet_sent(11, 30_000), None);
+
+        // Loss while the jump is unvalidated.
+        assert_eq!(r.on_congestion_event(2_400), Some((5_000, 5_000)));
+        assert_eq!(r.phase(), Phase::SafeRetreat);
+
+        // The window doesn't grow until the jump's packets are acked.
+        assert_eq!(
+            r.on_packet_acked(&acked(10), 6_000, rtt, 20_000, true),
+            Some(5_000)
+        );
+        assert_eq!(r.phase(), Phase::SafeRetreat);
+
+        assert_eq!(
+            r.on_packet_acked(&acked(11), 7_000, rtt, 20_000, true),
+            Some(5_000)
+        );
+        assert_eq!(r.phase(), Phase::Normal);
+
+        // Further congestion is handled by the congestion controller alone.
+        assert_eq!(r.on_congestion_event(2_400), None);
+    }
+
+    #[test]
+    fn loss_before_jump() {
+        let mut r = Resume::default();
+        r.setup(100_000, Duration::from_millis(50));
+
+        assert_eq!(r.on_congestion_event(2_400), None);
+        assert_eq!(r.phase(), Phase::Normal);
+    }
+}
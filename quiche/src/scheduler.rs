@@ -0,0 +1,293 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use alloc::collections::BTreeMap;
+use alloc::collections::BinaryHeap;
+use alloc::collections::VecDeque;
+
+use core::cmp::Reverse;
+
+use alloc::boxed::Box;
+
+/// Decides which stream's data goes into the next packet.
+///
+/// The connection pushes a stream to the scheduler when it has data ready to
+/// be sent, and enough flow control credit to send at least some of it. When
+/// building a packet, the connection pops streams and writes a STREAM frame
+/// for each of them. A stream that still has data left afterwards is pushed
+/// again.
+///
+/// A stream is never pushed again while it's queued. Popped streams might
+/// have been reset or stopped in the meantime, in which case they are
+/// skipped.
+///
+/// The built-in schedulers are [`PriorityScheduler`] (the default),
+/// [`FifoScheduler`] and [`RoundRobinScheduler`]. A scheduler is set with
+/// [`set_stream_scheduler()`].
+///
+/// [`PriorityScheduler`]: struct.PriorityScheduler.html
+/// [`FifoScheduler`]: struct.FifoScheduler.html
+/// [`RoundRobinScheduler`]: struct.RoundRobinScheduler.html
+/// [`set_stream_scheduler()`]:
+/// struct.Connection.html#method.set_stream_scheduler
+pub trait StreamScheduler: Send + Sync {
+    /// Queues a stream with the given priority, as set with
+    /// [`stream_priority()`].
+    ///
+    /// [`stream_priority()`]: struct.Connection.html#method.stream_priority
+    fn push(&mut self, stream_id: u64, urgency: u8, incremental: bool);
+
+    /// Removes and returns the stream whose data should be sent next.
+    fn pop(&mut self) -> Option<u64>;
+
+    /// Returns true if no stream is queued.
+    fn is_empty(&self) -> bool;
+
+    /// Changes the priority of a stream, if it's queued.
+    ///
+    /// The default implementation does nothing, which is only suitable for
+    /// schedulers that ignore priorities.
+    fn update_priority(
+        &mut self, _stream_id: u64, _old_urgency: u8, _old_incremental: bool,
+        _urgency: u8, _incremental: bool,
+    ) {
+    }
+}
+
+impl Default for Box<dyn StreamScheduler> {
+    fn default() -> Self {
+        Box::<PriorityScheduler>::default()
+    }
+}
+
+/// Schedules streams according to their priority.
+///
+/// Streams with lower urgency level are scheduled first, and within the same
+/// urgency level non-incremental streams are scheduled first, in the order of
+/// their stream IDs, and incremental streams are scheduled in a round-robin
+/// fashion after all non-incremental streams have been flushed.
+#[derive(Default)]
+pub struct PriorityScheduler {
+    /// Each urgency level has two queues, one for non-incremental streams and
+    /// one for incremental ones.
+    queues: BTreeMap<u8, (BinaryHeap<Reverse<u64>>, VecDeque<u64>)>,
+}
+
+impl StreamScheduler for PriorityScheduler {
+    fn push(&mut self, stream_id: u64, urgency: u8, incremental: bool) {
+        // Push the element to the back of the queue corresponding to the given
+        // urgency. If the queue doesn't exist yet, create it first.
+        let queues = self
+            .queues
+            .entry(urgency)
+            .or_insert_with(|| (BinaryHeap::new(), VecDeque::new()));
+
+        if !incremental {
+            // Non-incremental streams are scheduled in order of their stream ID.
+            queues.0.push(Reverse(stream_id))
+        } else {
+            // Incremental streams are scheduled in a round-robin fashion.
+            queues.1.push_back(stream_id)
+        };
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        // Remove the first element from the queue corresponding to the lowest
+        // urgency that has elements.
+        let (node, clear) =
+            if let Some((urgency, queues)) = self.queues.iter_mut().next() {
+                let node = if !queues.0.is_empty() {
+                    queues.0.pop().map(|x| x.0)
+                } else {
+                    queues.1.pop_front()
+                };
+
+                let clear = if queues.0.is_empty() && queues.1.is_empty() {
+                    Some(*urgency)
+                } else {
+                    None
+                };
+
+                (node, clear)
+            } else {
+                (None, None)
+            };
+
+        // Remove the queue from the list of queues if it is now empty, so that
+        // the next time `pop()` is called the next queue with elements is used.
+        if let Some(urgency) = &clear {
+            self.queues.remove(urgency);
+        }
+
+        node
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    fn update_priority(
+        &mut self, stream_id: u64, old_urgency: u8, old_incremental: bool,
+        urgency: u8, incremental: bool,
+    ) {
+        let (removed, clear) = match self.queues.get_mut(&old_urgency) {
+            Some(queues) => {
+                let removed = if !old_incremental {
+                    let len = queues.0.len();
+
+                    queues.0 = core::mem::take(&mut queues.0)
+                        .into_iter()
+                        .filter(|x| x.0 != stream_id)
+                        .collect();
+
+                    queues.0.len() != len
+                } else {
+                    let len = queues.1.len();
+
+                    queues.1.retain(|&x| x != stream_id);
+
+                    queues.1.len() != len
+                };
+
+                (removed, queues.0.is_empty() && queues.1.is_empty())
+            },
+
+            None => (false, false),
+        };
+
+        if clear {
+            self.queues.remove(&old_urgency);
+        }
+
+        if removed {
+            self.push(stream_id, urgency, incremental);
+        }
+    }
+}
+
+/// Schedules streams in the order they were created, ignoring priorities.
+///
+/// A stream's data is sent completely before moving on to the next stream,
+/// unless the stream runs out of flow control credit.
+#[derive(Default)]
+pub struct FifoScheduler {
+    queue: BinaryHeap<Reverse<u64>>,
+}
+
+impl StreamScheduler for FifoScheduler {
+    fn push(&mut self, stream_id: u64, _urgency: u8, _incremental: bool) {
+        self.queue.push(Reverse(stream_id));
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        self.queue.pop().map(|x| x.0)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// Schedules streams in a round-robin fashion, ignoring priorities.
+///
+/// Each stream gets to send one STREAM frame in turn, in the order they
+/// became ready to send data.
+#[derive(Default)]
+pub struct RoundRobinScheduler {
+    queue: VecDeque<u64>,
+}
+
+impl StreamScheduler for RoundRobinScheduler {
+    fn push(&mut self, stream_id: u64, _urgency: u8, _incremental: bool) {
+        self.queue.push_back(stream_id);
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        self.queue.pop_front()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority() {
+        let mut s = PriorityScheduler::default();
+
+        s.push(8, 3, true);
+        s.push(4, 3, true);
+        s.push(12, 3, false);
+        s.push(0, 0, true);
+
+        assert_eq!(s.pop(), Some(0));
+        assert_eq!(s.pop(), Some(12));
+        assert_eq!(s.pop(), Some(8));
+        assert_eq!(s.pop(), Some(4));
+        assert_eq!(s.pop(), None);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn fifo() {
+        let mut s = FifoScheduler::default();
+
+        s.push(8, 0, true);
+        s.push(4, 7, false);
+
+        assert_eq!(s.pop(), Some(4));
+
+        // The stream is pushed again as it still has data to send, and keeps
+        // its turn.
+        s.push(4, 7, false);
+
+        assert_eq!(s.pop(), Some(4));
+        assert_eq!(s.pop(), Some(8));
+        assert_eq!(s.pop(), None);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut s = RoundRobinScheduler::default();
+
+        s.push(8, 0, false);
+        s.push(4, 7, false);
+
+        assert_eq!(s.pop(), Some(8));
+
+        s.push(8, 0, false);
+
+        assert_eq!(s.pop(), Some(4));
+        assert_eq!(s.pop(), Some(8));
+        assert_eq!(s.pop(), None);
+        assert!(s.is_empty());
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use alloc::vec::Vec;
+
+/// The type of state kept in a [`SessionStore`].
+///
+/// [`SessionStore`]: trait.SessionStore.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionEntryKind {
+    /// A serialized TLS session, as returned by [`session()`].
+    ///
+    /// [`session()`]: struct.Connection.html#method.session
+    Session,
+
+    /// An address validation token received in a NEW_TOKEN frame, as
+    /// returned by [`new_token()`].
+    ///
+    /// [`new_token()`]: struct.Connection.html#method.new_token
+    Token,
+}
+
+/// Storage of client resumption state, keyed by origin.
+///
+/// Clients can resume previous connections to the same origin by re-using the
+/// TLS session ticket and address validation token received on a previous
+/// connection. Implementations of this trait abstract where this state is
+/// kept, so that it can be shared across connections.
+///
+/// The origin is an opaque string chosen by the application, such as the
+/// ASCII serialization of a URL's origin (e.g. `https://example.com:4433`).
+pub trait SessionStore {
+    /// Returns the stored entry of the given kind for `origin`, if any.
+    fn get(&self, origin: &str, kind: SessionEntryKind) -> Option<Vec<u8>>;
+
+    /// Stores an entry of the given kind for `origin`, replacing any entry
+    /// already present.
+    fn put(&mut self, origin: &str, kind: SessionEntryKind, value: &[u8]);
+}
@@ -0,0 +1,640 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Simulation of a network link between two in-memory endpoints.
+
+use std::collections::BTreeMap;
+
+use std::net::SocketAddr;
+
+use std::pin::Pin;
+
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use std::time;
+
+use crate::Clock;
+use crate::Config;
+use crate::Connection;
+use crate::ConnectionId;
+use crate::Error;
+use crate::RecvInfo;
+use crate::Result;
+
+use crate::rand;
+
+/// The model of the link connecting the two endpoints of a [`Simulator`].
+///
+/// The same model is used in both directions.
+///
+/// [`Simulator`]: struct.Simulator.html
+#[derive(Clone, Debug)]
+pub struct LinkConfig {
+    /// One-way propagation delay of each packet.
+    pub delay: time::Duration,
+
+    /// Probability, between `0.0` and `1.0`, of a packet being lost.
+    pub loss: f64,
+
+    /// Probability, between `0.0` and `1.0`, of a packet being reordered.
+    ///
+    /// A reordered packet is delayed by twice the propagation delay, so that
+    /// packets sent after it can overtake it.
+    pub reorder: f64,
+
+    /// Bandwidth of the link in bits per second, or `0` for no limit.
+    pub bandwidth: u64,
+
+    /// Seed used to decide which packets are lost or reordered.
+    ///
+    /// Simulations with the same seed and the same inputs behave the same.
+    pub seed: u64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> LinkConfig {
+        LinkConfig {
+            delay: time::Duration::from_millis(10),
+
+            loss: 0.0,
+
+            reorder: 0.0,
+
+            bandwidth: 0,
+
+            seed: 0,
+        }
+    }
+}
+
+/// Connects a client and a server through a simulated network link.
+///
+/// Packets sent by either endpoint are carried over a link following a
+/// [`LinkConfig`], and timers are driven by a virtual clock that only moves
+/// forward when the simulation does, so no sockets or real waiting are
+/// involved.
+///
+/// ## Examples:
+///
+/// ```no_run
+/// # let mut client_config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+/// # let mut server_config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+/// use quiche::testing::LinkConfig;
+/// use quiche::testing::Simulator;
+///
+/// let link = LinkConfig {
+///     delay: std::time::Duration::from_millis(50),
+///     loss: 0.01,
+///     ..LinkConfig::default()
+/// };
+///
+/// let mut sim = Simulator::new(
+///     Some("quic.tech"),
+///     &mut client_config,
+///     &mut server_config,
+///     link,
+/// )?;
+///
+/// sim.handshake()?;
+///
+/// sim.client.stream_send(0, b"hello", true)?;
+/// sim.run_until(|sim| sim.server.stream_finished(0))?;
+/// # Ok::<(), quiche::Error>(())
+/// ```
+///
+/// [`LinkConfig`]: struct.LinkConfig.html
+pub struct Simulator {
+    /// The client endpoint.
+    pub client: Pin<Box<Connection>>,
+
+    /// The server endpoint.
+    pub server: Pin<Box<Connection>>,
+
+    clock: Arc<VirtualClock>,
+
+    link: LinkConfig,
+
+    /// Packets in flight from the client to the server.
+    to_server: Link,
+
+    /// Packets in flight from the server to the client.
+    to_client: Link,
+
+    rng: u64,
+}
+
+impl Simulator {
+    /// Creates a client and a server connected through a link following
+    /// `link`.
+    ///
+    /// The simulator's virtual clock is installed into both configurations,
+    /// see [`set_clock()`].
+    ///
+    /// [`set_clock()`]: ../struct.Config.html#method.set_clock
+    pub fn new(
+        server_name: Option<&str>, client_config: &mut Config,
+        server_config: &mut Config, link: LinkConfig,
+    ) -> Result<Simulator> {
+        let clock = Arc::new(VirtualClock(Mutex::new(time::Instant::now())));
+
+        client_config.set_clock(clock.clone());
+        server_config.set_clock(clock.clone());
+
+        let mut client_scid = [0; 16];
+        rand::rand_bytes(&mut client_scid[..]);
+        let client_scid = ConnectionId::from_ref(&client_scid);
+        let client_addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+
+        let mut server_scid = [0; 16];
+        rand::rand_bytes(&mut server_scid[..]);
+        let server_scid = ConnectionId::from_ref(&server_scid);
+        let server_addr: SocketAddr = "127.0.0.1:4321".parse().unwrap();
+
+        let client = crate::connect(
+            server_name,
+            &client_scid,
+            server_addr,
+            client_config,
+        )?;
+
+        let server =
+            crate::accept(&server_scid, None, client_addr, server_config)?;
+
+        let rng = link.seed;
+
+        Ok(Simulator {
+            client,
+
+            server,
+
+            clock,
+
+            link,
+
+            to_server: Link::default(),
+
+            to_client: Link::default(),
+
+            rng,
+        })
+    }
+
+    /// Returns the current time of the simulation.
+    pub fn now(&self) -> time::Instant {
+        self.clock.now()
+    }
+
+    /// Runs the simulation until both endpoints have completed the
+    /// handshake.
+    pub fn handshake(&mut self) -> Result<()> {
+        self.run_until(|sim| {
+            sim.client.is_established() && sim.server.is_established()
+        })
+    }
+
+    /// Runs the simulation until `done` returns `true`.
+    ///
+    /// The condition is checked before each step. If there is nothing left
+    /// to do before it is met, e.g. because the connections were closed,
+    /// [`Done`] is returned.
+    ///
+    /// [`Done`]: ../enum.Error.html#variant.Done
+    pub fn run_until<F>(&mut self, mut done: F) -> Result<()>
+    where
+        F: FnMut(&Simulator) -> bool,
+    {
+        loop {
+            self.flush()?;
+
+            if done(self) {
+                return Ok(());
+            }
+
+            match self.next_event() {
+                Some(at) => self.step(at)?,
+
+                None => return Err(Error::Done),
+            }
+        }
+    }
+
+    /// Runs the simulation for the given amount of virtual time.
+    pub fn run_for(&mut self, duration: time::Duration) -> Result<()> {
+        let end = self.now() + duration;
+
+        loop {
+            self.flush()?;
+
+            match self.next_event() {
+                Some(at) if at <= end => self.step(at)?,
+
+                _ => break,
+            }
+        }
+
+        self.clock.set(end);
+
+        Ok(())
+    }
+
+    /// Moves the clock to `at`, delivers the packets arriving by then and
+    /// fires expired timers.
+    fn step(&mut self, at: time::Instant) -> Result<()> {
+        if at > self.now() {
+            self.clock.set(at);
+        }
+
+        let now = self.now();
+
+        for mut pkt in self.to_server.arrived(now) {
+            let info = RecvInfo {
+                from: self.server.peer_addr,
+            };
+
+            match self.server.recv(&mut pkt, info) {
+                Ok(_) | Err(Error::Done) => (),
+
+                Err(e) => return Err(e),
+            }
+        }
+
+        for mut pkt in self.to_client.arrived(now) {
+            let info = RecvInfo {
+                from: self.client.peer_addr,
+            };
+
+            match self.client.recv(&mut pkt, info) {
+                Ok(_) | Err(Error::Done) => (),
+
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.client.timeout() == Some(time::Duration::ZERO) {
+            self.client.on_timeout();
+        }
+
+        if self.server.timeout() == Some(time::Duration::ZERO) {
+            self.server.on_timeout();
+        }
+
+        Ok(())
+    }
+
+    /// Puts all the packets the endpoints have to send on the link.
+    fn flush(&mut self) -> Result<()> {
+        let now = self.now();
+
+        let mut out = [0; 65535];
+
+        loop {
+            let (written, info) = match self.client.send(&mut out) {
+                Ok(v) => v,
+
+                Err(Error::Done) => break,
+
+                Err(e) => return Err(e),
+            };
+
+            let at = std::cmp::max(now, info.at);
+            let (lost, reordered) = self.roll();
+
+            if !lost {
+                self.to_server
+                    .send(&out[..written], at, reordered, &self.link);
+            }
+        }
+
+        loop {
+            let (written, info) = match self.server.send(&mut out) {
+                Ok(v) => v,
+
+                Err(Error::Done) => break,
+
+                Err(e) => return Err(e),
+            };
+
+            let at = std::cmp::max(now, info.at);
+            let (lost, reordered) = self.roll();
+
+            if !lost {
+                self.to_client
+                    .send(&out[..written], at, reordered, &self.link);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the time of the next packet arrival or timer expiration.
+    fn next_event(&self) -> Option<time::Instant> {
+        let now = self.now();
+
+        let events = [
+            self.to_server.next_arrival(),
+            self.to_client.next_arrival(),
+            self.client.timeout().map(|d| now + d),
+            self.server.timeout().map(|d| now + d),
+        ];
+
+        events.iter().filter_map(|&x| x).min()
+    }
+
+    /// Decides whether the next packet is lost, and if not whether it's
+    /// reordered.
+    fn roll(&mut self) -> (bool, bool) {
+        let lost = self.next_f64() < self.link.loss;
+        let reordered = self.next_f64() < self.link.reorder;
+
+        (lost, reordered)
+    }
+
+    /// Returns a pseudo-random number in `[0, 1)`, using SplitMix64.
+    fn next_f64(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// One direction of the simulated link.
+#[derive(Default)]
+struct Link {
+    /// Packets in flight, keyed by arrival time and sequence number.
+    in_flight: BTreeMap<(time::Instant, u64), Vec<u8>>,
+
+    /// The time the link is done transmitting the previous packet.
+    busy_until: Option<time::Instant>,
+
+    next_seq: u64,
+}
+
+impl Link {
+    fn send(
+        &mut self, pkt: &[u8], at: time::Instant, reordered: bool,
+        config: &LinkConfig,
+    ) {
+        let start = match self.busy_until {
+            Some(busy_until) if busy_until > at => busy_until,
+
+            _ => at,
+        };
+
+        let tx_time = if config.bandwidth > 0 {
+            let nanos =
+                pkt.len() as u128 * 8 * 1_000_000_000 / config.bandwidth as u128;
+
+            time::Duration::from_nanos(nanos as u64)
+        } else {
+            time::Duration::ZERO
+        };
+
+        self.busy_until = Some(start + tx_time);
+
+        let mut arrival = start + tx_time + config.delay;
+
+        if reordered {
+            arrival += config.delay;
+        }
+
+        self.in_flight
+            .insert((arrival, self.next_seq), pkt.to_vec());
+        self.next_seq += 1;
+    }
+
+    fn next_arrival(&self) -> Option<time::Instant> {
+        self.in_flight.keys().next().map(|&(at, _)| at)
+    }
+
+    /// Removes and returns the packets that arrived by `now`, in order.
+    fn arrived(&mut self, now: time::Instant) -> Vec<Vec<u8>> {
+        let mut pkts = Vec::new();
+
+        while let Some(&(at, seq)) = self.in_flight.keys().next() {
+            if at > now {
+                break;
+            }
+
+            pkts.push(self.in_flight.remove(&(at, seq)).unwrap());
+        }
+
+        pkts
+    }
+}
+
+/// A clock that only moves when the simulation advances it.
+struct VirtualClock(Mutex<time::Instant>);
+
+impl VirtualClock {
+    fn set(&self, now: time::Instant) {
+        *self.0.lock().unwrap() = now;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> time::Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(is_server: bool) -> Config {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+
+        if is_server {
+            config
+                .load_cert_chain_from_pem_file("examples/cert.crt")
+                .unwrap();
+            config
+                .load_priv_key_from_pem_file("examples/cert.key")
+                .unwrap();
+        }
+
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(1_000_000);
+        config.set_initial_max_stream_data_bidi_local(1_000_000);
+        config.set_initial_max_stream_data_bidi_remote(1_000_000);
+        config.set_initial_max_streams_bidi(3);
+        config.set_max_idle_timeout(30_000);
+        config.verify_peer(false);
+
+        config
+    }
+
+    fn simulator(link: LinkConfig) -> Simulator {
+        Simulator::new(
+            Some("quic.tech"),
+            &mut config(false),
+            &mut config(true),
+            link,
+        )
+        .unwrap()
+    }
+
+    /// Sends `len` bytes on a client stream and runs the simulation until
+    /// the server has read all of them.
+    fn transfer(sim: &mut Simulator, len: usize) {
+        let data = vec![42; len];
+        let mut sent = 0;
+        let mut recvd = 0;
+
+        let mut buf = [0; 65535];
+
+        while recvd < len {
+            if sent < len {
+                match sim.client.stream_send(0, &data[sent..], true) {
+                    Ok(v) => sent += v,
+
+                    Err(Error::Done) => (),
+
+                    Err(e) => panic!("stream_send failed: {:?}", e),
+                }
+            }
+
+            let pending = sent < len;
+
+            sim.run_until(|sim| {
+                sim.server.stream_readable(0) ||
+                    pending && sim.client.stream_capacity(0).unwrap_or(0) > 0
+            })
+            .unwrap();
+
+            while let Ok((read, _)) = sim.server.stream_recv(0, &mut buf) {
+                recvd += read;
+            }
+        }
+
+        assert!(sim.server.stream_finished(0));
+    }
+
+    #[test]
+    fn handshake() {
+        let mut sim = simulator(LinkConfig {
+            delay: time::Duration::from_millis(50),
+            ..LinkConfig::default()
+        });
+
+        let start = sim.now();
+        assert_eq!(sim.handshake(), Ok(()));
+
+        // The server completes the handshake after 1.5 round-trips.
+        assert_eq!(sim.now() - start, time::Duration::from_millis(150));
+    }
+
+    #[test]
+    fn lossy_link() {
+        let mut sim = simulator(LinkConfig {
+            loss: 0.1,
+            reorder: 0.1,
+            seed: 42,
+            ..LinkConfig::default()
+        });
+
+        assert_eq!(sim.handshake(), Ok(()));
+
+        transfer(&mut sim, 100_000);
+
+        assert!(sim.client.stats().lost > 0);
+    }
+
+    #[test]
+    fn bandwidth() {
+        let mut sim = simulator(LinkConfig {
+            delay: time::Duration::ZERO,
+            bandwidth: 8_000_000,
+            ..LinkConfig::default()
+        });
+
+        assert_eq!(sim.handshake(), Ok(()));
+
+        let start = sim.now();
+        transfer(&mut sim, 100_000);
+
+        // At 1 MB/s, 100 KB take at least 100ms to go through.
+        assert!(sim.now() - start >= time::Duration::from_millis(100));
+    }
+
+    #[test]
+    fn careful_resume() {
+        let link = LinkConfig {
+            delay: time::Duration::from_millis(50),
+            ..LinkConfig::default()
+        };
+
+        let mut sim = simulator(link.clone());
+        assert_eq!(sim.handshake(), Ok(()));
+
+        transfer(&mut sim, 1_000_000);
+
+        let state = sim.client.resume_state().unwrap();
+        assert_eq!(state.rtt, time::Duration::from_millis(100));
+
+        // Transfer the same amount of data with and without the saved state.
+        let mut elapsed = Vec::new();
+
+        for resume in [false, true] {
+            let mut sim = simulator(link.clone());
+
+            if resume {
+                assert_eq!(sim.client.set_resume_state(&state), Ok(()));
+            }
+
+            assert_eq!(sim.handshake(), Ok(()));
+
+            let start = sim.now();
+            transfer(&mut sim, 300_000);
+
+            elapsed.push(sim.now() - start);
+        }
+
+        assert!(elapsed[1] < elapsed[0]);
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let mut sim = simulator(LinkConfig::default());
+
+        assert_eq!(sim.handshake(), Ok(()));
+
+        sim.run_for(time::Duration::from_secs(29)).unwrap();
+        assert!(!sim.client.is_closed());
+
+        assert_eq!(sim.run_until(|_| false), Err(Error::Done));
+        assert!(sim.client.is_timed_out());
+        assert!(sim.server.is_timed_out());
+    }
+}
@@ -24,19 +24,24 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use std::cmp;
+use core::cmp;
 
-use std::sync::Arc;
+#[cfg(feature = "std")]
+use std::io::IoSliceMut;
 
-use std::collections::hash_map;
+use alloc::sync::Arc;
 
-use std::collections::BTreeMap;
-use std::collections::BinaryHeap;
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::collections::VecDeque;
+use alloc::collections::BinaryHeap;
+use alloc::collections::VecDeque;
 
-use std::time;
+use crate::collections::map;
+use crate::collections::Map;
+use crate::collections::Set;
+
+use crate::time;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 use crate::Error;
 use crate::Result;
@@ -44,6 +49,8 @@ use crate::Result;
 use crate::flowcontrol;
 use crate::ranges;
 
+use crate::scheduler::StreamScheduler;
+
 const DEFAULT_URGENCY: u8 = 127;
 
 #[cfg(test)]
@@ -62,14 +69,14 @@ pub const MAX_STREAM_WINDOW: u64 = 16 * 1024 * 1024;
 #[derive(Default)]
 pub struct StreamMap {
     /// Map of streams indexed by stream ID.
-    streams: HashMap<u64, Stream>,
+    streams: Map<u64, Stream>,
 
     /// Set of streams that were completed and garbage collected.
     ///
     /// Instead of keeping the full stream state forever, we collect completed
     /// streams to save memory, but we still need to keep track of previously
     /// created streams, to prevent peers from re-creating them.
-    collected: HashSet<u64>,
+    collected: Set<u64>,
 
     /// Peer's maximum bidirectional stream count limit.
     peer_max_streams_bidi: u64,
@@ -97,53 +104,64 @@ pub struct StreamMap {
     /// The total number of unidirectional streams opened by the local endpoint.
     local_opened_streams_uni: u64,
 
-    /// Queue of stream IDs corresponding to streams that have buffered data
-    /// ready to be sent to the peer. This also implies that the stream has
-    /// enough flow control credits to send at least some of that data.
-    ///
-    /// Streams are grouped by their priority, where each urgency level has two
-    /// queues, one for non-incremental streams and one for incremental ones.
-    ///
-    /// Streams with lower urgency level are scheduled first, and within the
-    /// same urgency level Non-incremental streams are scheduled first, in the
-    /// order of their stream IDs, and incremental streams are scheduled in a
-    /// round-robin fashion after all non-incremental streams have been flushed.
-    flushable: BTreeMap<u8, (BinaryHeap<std::cmp::Reverse<u64>>, VecDeque<u64>)>,
+    /// Schedules the streams that have buffered data ready to be sent to the
+    /// peer. This also implies that the stream has enough flow control credits
+    /// to send at least some of that data.
+    scheduler: Box<dyn StreamScheduler>,
 
     /// Set of stream IDs corresponding to streams that have outstanding data
     /// to read. This is used to generate a `StreamIter` of streams without
     /// having to iterate over the full list of streams.
-    readable: HashSet<u64>,
+    readable: Set<u64>,
 
     /// Set of stream IDs corresponding to streams that have enough flow control
     /// capacity to be written to, and is not finished. This is used to generate
     /// a `StreamIter` of streams without having to iterate over the full list
     /// of streams.
-    writable: HashSet<u64>,
+    writable: Set<u64>,
 
     /// Set of stream IDs corresponding to streams that are almost out of flow
     /// control credit and need to send MAX_STREAM_DATA. This is used to
     /// generate a `StreamIter` of streams without having to iterate over the
     /// full list of streams.
-    almost_full: HashSet<u64>,
+    almost_full: Set<u64>,
 
     /// Set of stream IDs corresponding to streams that are blocked. The value
     /// of the map elements represents the offset of the stream at which the
     /// blocking occurred.
-    blocked: HashMap<u64, u64>,
+    blocked: Map<u64, u64>,
 
     /// Set of stream IDs corresponding to streams that are reset. The value
     /// of the map elements is a tuple of the error code and final size values
     /// to include in the RESET_STREAM frame.
-    reset: HashMap<u64, (u64, u64)>,
+    reset: Map<u64, (u64, u64)>,
 
     /// Set of stream IDs corresponding to streams that are shutdown on the
     /// receive side, and need to send a STOP_SENDING frame. The value of the
     /// map elements is the error code to include in the STOP_SENDING frame.
-    stopped: HashMap<u64, u64>,
+    stopped: Map<u64, u64>,
+
+    /// Set of stream IDs corresponding to streams that have a deadline. The
+    /// value of the map elements is a tuple of the deadline and the error
+    /// code to reset and stop the stream with once the deadline expires.
+    deadlines: Map<u64, (time::Instant, u64)>,
+
+    /// Whether a MAX_STREAMS frame needs to be sent because the application
+    /// granted more streams to the peer.
+    local_max_streams_bidi_granted: bool,
+    local_max_streams_uni_granted: bool,
+
+    /// How stream credits are given back to the peer.
+    credit_policy: StreamCreditPolicy,
 
     /// The maximum size of a stream window.
     max_stream_window: u64,
+
+    /// The receive windows of streams created from now on, overriding the
+    /// initial flow control limits advertised in the transport parameters.
+    window_bidi_local: Option<u64>,
+    window_bidi_remote: Option<u64>,
+    window_uni: Option<u64>,
 }
 
 impl StreamMap {
@@ -190,7 +208,7 @@ impl StreamMap {
         peer_params: &crate::TransportParams, local: bool, is_server: bool,
     ) -> Result<&mut Stream> {
         let stream = match self.streams.entry(id) {
-            hash_map::Entry::Vacant(v) => {
+            map::Entry::Vacant(v) => {
                 // Stream has already been closed and garbage collected.
                 if self.collected.contains(&id) {
                     return Err(Error::Done);
@@ -227,7 +245,7 @@ impl StreamMap {
                         if self.local_opened_streams_bidi >=
                             self.peer_max_streams_bidi
                         {
-                            return Err(Error::StreamLimit);
+                            return Err(Error::StreamLimit(id));
                         }
 
                         self.local_opened_streams_bidi += 1;
@@ -237,7 +255,7 @@ impl StreamMap {
                         if self.local_opened_streams_uni >=
                             self.peer_max_streams_uni
                         {
-                            return Err(Error::StreamLimit);
+                            return Err(Error::StreamLimit(id));
                         }
 
                         self.local_opened_streams_uni += 1;
@@ -247,7 +265,7 @@ impl StreamMap {
                         if self.peer_opened_streams_bidi >=
                             self.local_max_streams_bidi
                         {
-                            return Err(Error::StreamLimit);
+                            return Err(Error::StreamLimit(id));
                         }
 
                         self.peer_opened_streams_bidi += 1;
@@ -257,24 +275,46 @@ impl StreamMap {
                         if self.peer_opened_streams_uni >=
                             self.local_max_streams_uni
                         {
-                            return Err(Error::StreamLimit);
+                            return Err(Error::StreamLimit(id));
                         }
 
                         self.peer_opened_streams_uni += 1;
                     },
                 };
 
-                let s = Stream::new(
+                let mut s = Stream::new(
+                    id,
                     max_rx_data,
                     max_tx_data,
                     is_bidi(id),
                     local,
                     self.max_stream_window,
                 );
+
+                trace_event!({ stream_id = id, local, "stream created" });
+
+                let window = match (local, is_bidi(id)) {
+                    (true, true) => self.window_bidi_local,
+
+                    (true, false) => None,
+
+                    (false, true) => self.window_bidi_remote,
+
+                    (false, false) => self.window_uni,
+                };
+
+                // Announce the new limit right away if it's larger than the
+                // initial one.
+                if let Some(window) = window {
+                    if s.recv.set_window(window) {
+                        self.almost_full.insert(id);
+                    }
+                }
+
                 v.insert(s)
             },
 
-            hash_map::Entry::Occupied(v) => v.into_mut(),
+            map::Entry::Occupied(v) => v.into_mut(),
         };
 
         // Stream might already be writable due to initial flow control limits.
@@ -285,8 +325,26 @@ impl StreamMap {
         Ok(stream)
     }
 
-    /// Pushes the stream ID to the back of the flushable streams queue with
-    /// the specified urgency.
+    /// Sets the receive window of locally-initiated bidirectional streams
+    /// created from now on.
+    pub fn set_window_bidi_local(&mut self, v: u64) {
+        self.window_bidi_local = Some(v);
+    }
+
+    /// Sets the receive window of remotely-initiated bidirectional streams
+    /// created from now on.
+    pub fn set_window_bidi_remote(&mut self, v: u64) {
+        self.window_bidi_remote = Some(v);
+    }
+
+    /// Sets the receive window of remotely-initiated unidirectional streams
+    /// created from now on.
+    pub fn set_window_uni(&mut self, v: u64) {
+        self.window_uni = Some(v);
+    }
+
+    /// Pushes the stream ID to the flushable streams queue with the specified
+    /// priority.
     ///
     /// Note that the caller is responsible for checking that the specified
     /// stream ID was not in the queue already before calling this.
@@ -295,57 +353,48 @@ impl StreamMap {
     /// unfairly scheduled more often than other streams, and might also cause
     /// spurious cycles through the queue, so it should be avoided.
     pub fn push_flushable(&mut self, stream_id: u64, urgency: u8, incr: bool) {
-        // Push the element to the back of the queue corresponding to the given
-        // urgency. If the queue doesn't exist yet, create it first.
-        let queues = self
-            .flushable
-            .entry(urgency)
-            .or_insert_with(|| (BinaryHeap::new(), VecDeque::new()));
-
-        if !incr {
-            // Non-incremental streams are scheduled in order of their stream ID.
-            queues.0.push(std::cmp::Reverse(stream_id))
-        } else {
-            // Incremental streams are scheduled in a round-robin fashion.
-            queues.1.push_back(stream_id)
-        };
+        self.scheduler.push(stream_id, urgency, incr);
+    }
+
+    /// Moves the stream ID to the flushable streams queue corresponding to
+    /// the new priority, if it was queued with the old one.
+    ///
+    /// This does nothing if the stream was not already in the queue.
+    pub fn update_flushable_priority(
+        &mut self, stream_id: u64, old_urgency: u8, old_incr: bool, urgency: u8,
+        incr: bool,
+    ) {
+        self.scheduler.update_priority(
+            stream_id,
+            old_urgency,
+            old_incr,
+            urgency,
+            incr,
+        );
     }
 
-    /// Removes and returns the first stream ID from the flushable streams
-    /// queue with the specified urgency.
+    /// Removes and returns the next stream ID to send data for from the
+    /// flushable streams queue.
     ///
     /// Note that if the stream is still flushable after sending some of its
     /// outstanding data, it needs to be added back to the queue.
     pub fn pop_flushable(&mut self) -> Option<u64> {
-        // Remove the first element from the queue corresponding to the lowest
-        // urgency that has elements.
-        let (node, clear) =
-            if let Some((urgency, queues)) = self.flushable.iter_mut().next() {
-                let node = if !queues.0.is_empty() {
-                    queues.0.pop().map(|x| x.0)
-                } else {
-                    queues.1.pop_front()
-                };
-
-                let clear = if queues.0.is_empty() && queues.1.is_empty() {
-                    Some(*urgency)
-                } else {
-                    None
-                };
+        self.scheduler.pop()
+    }
 
-                (node, clear)
-            } else {
-                (None, None)
-            };
+    /// Replaces the scheduler of flushable streams, moving the streams queued
+    /// in the current one to the new one.
+    pub fn set_scheduler(&mut self, mut scheduler: Box<dyn StreamScheduler>) {
+        while let Some(stream_id) = self.scheduler.pop() {
+            let (urgency, incremental) = self
+                .streams
+                .get(&stream_id)
+                .map_or((DEFAULT_URGENCY, true), |s| (s.urgency, s.incremental));
 
-        // Remove the queue from the list of queues if it is now empty, so that
-        // the next time `pop_flushable()` is called the next queue with elements
-        // is used.
-        if let Some(urgency) = &clear {
-            self.flushable.remove(urgency);
+            scheduler.push(stream_id, urgency, incremental);
         }
 
-        node
+        self.scheduler = scheduler;
     }
 
     /// Adds or removes the stream ID to/from the readable streams set.
@@ -424,6 +473,37 @@ impl StreamMap {
         }
     }
 
+    /// Sets the stream's deadline, replacing any previous one.
+    pub fn set_deadline(
+        &mut self, stream_id: u64, deadline: time::Instant, error_code: u64,
+    ) {
+        self.deadlines.insert(stream_id, (deadline, error_code));
+    }
+
+    /// Returns the earliest stream deadline.
+    pub fn next_deadline(&self) -> Option<time::Instant> {
+        self.deadlines.values().map(|(deadline, _)| *deadline).min()
+    }
+
+    /// Removes the deadlines that expired at `now`, returning the IDs of the
+    /// affected streams along with their error codes.
+    pub fn take_expired_deadlines(
+        &mut self, now: time::Instant,
+    ) -> Vec<(u64, u64)> {
+        let expired: Vec<(u64, u64)> = self
+            .deadlines
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(id, (_, error_code))| (*id, *error_code))
+            .collect();
+
+        for (id, _) in &expired {
+            self.deadlines.remove(id);
+        }
+
+        expired
+    }
+
     /// Updates the peer's maximum bidirectional stream count limit.
     pub fn update_peer_max_streams_bidi(&mut self, v: u64) {
         self.peer_max_streams_bidi = cmp::max(self.peer_max_streams_bidi, v);
@@ -437,6 +517,13 @@ impl StreamMap {
     /// Commits the new max_streams_bidi limit.
     pub fn update_max_streams_bidi(&mut self) {
         self.local_max_streams_bidi = self.local_max_streams_bidi_next;
+        self.local_max_streams_bidi_granted = false;
+    }
+
+    /// Returns the current local max_streams_bidi limit.
+    #[cfg(feature = "std")]
+    pub fn max_streams_bidi(&self) -> u64 {
+        self.local_max_streams_bidi
     }
 
     /// Returns the new max_streams_bidi limit.
@@ -447,6 +534,7 @@ impl StreamMap {
     /// Commits the new max_streams_uni limit.
     pub fn update_max_streams_uni(&mut self) {
         self.local_max_streams_uni = self.local_max_streams_uni_next;
+        self.local_max_streams_uni_granted = false;
     }
 
     /// Returns the new max_streams_uni limit.
@@ -454,6 +542,35 @@ impl StreamMap {
         self.local_max_streams_uni_next
     }
 
+    /// Sets how stream credits are given back to the peer.
+    pub fn set_credit_policy(&mut self, policy: StreamCreditPolicy) {
+        self.credit_policy = policy;
+    }
+
+    /// Returns how stream credits are given back to the peer.
+    pub fn credit_policy(&self) -> StreamCreditPolicy {
+        self.credit_policy
+    }
+
+    /// Raises the number of streams the peer is allowed to open by `n`.
+    pub fn grant_streams(&mut self, bidi: bool, n: u64) {
+        if bidi {
+            self.local_max_streams_bidi_next = cmp::min(
+                self.local_max_streams_bidi_next.saturating_add(n),
+                crate::MAX_STREAM_ID,
+            );
+
+            self.local_max_streams_bidi_granted = true;
+        } else {
+            self.local_max_streams_uni_next = cmp::min(
+                self.local_max_streams_uni_next.saturating_add(n),
+                crate::MAX_STREAM_ID,
+            );
+
+            self.local_max_streams_uni_granted = true;
+        }
+    }
+
     /// Returns the number of bidirectional streams that can be created
     /// before the peer's stream count limit is reached.
     pub fn peer_streams_left_bidi(&self) -> u64 {
@@ -471,7 +588,7 @@ impl StreamMap {
     /// This should only be called when Stream::is_complete() returns true for
     /// the given stream.
     pub fn collect(&mut self, stream_id: u64, local: bool) {
-        if !local {
+        if !local && self.credit_policy != StreamCreditPolicy::Manual {
             // If the stream was created by the peer, give back a max streams
             // credit.
             if is_bidi(stream_id) {
@@ -484,17 +601,61 @@ impl StreamMap {
         }
 
         self.streams.remove(&stream_id);
+        self.deadlines.remove(&stream_id);
         self.collected.insert(stream_id);
+
+        trace_event!({ stream_id, local, "stream collected" });
     }
 
-    /// Creates an iterator over streams that have outstanding data to read.
+    /// Creates an iterator over streams that have outstanding data to read,
+    /// in priority order.
     pub fn readable(&self) -> StreamIter {
-        StreamIter::from(&self.readable)
+        self.prioritized(&self.readable)
     }
 
-    /// Creates an iterator over streams that can be written to.
+    /// Creates an iterator over streams that can be written to, in priority
+    /// order.
     pub fn writable(&self) -> StreamIter {
-        StreamIter::from(&self.writable)
+        self.prioritized(&self.writable)
+    }
+
+    /// Returns the highest priority stream that has outstanding data to read.
+    pub fn readable_next(&self) -> Option<u64> {
+        self.readable
+            .iter()
+            .copied()
+            .min_by_key(|id| self.priority_key(*id))
+    }
+
+    /// Returns the highest priority stream that can be written to.
+    pub fn writable_next(&self) -> Option<u64> {
+        self.writable
+            .iter()
+            .copied()
+            .min_by_key(|id| self.priority_key(*id))
+    }
+
+    /// Creates an iterator over the given streams, yielding streams with
+    /// lower urgency first, and streams with the same urgency in order of
+    /// their stream IDs.
+    fn prioritized(&self, streams: &Set<u64>) -> StreamIter {
+        let mut streams: Vec<u64> = streams.iter().copied().collect();
+
+        // The iterator pops streams from the back of the list.
+        streams.sort_unstable_by_key(|id| {
+            core::cmp::Reverse(self.priority_key(*id))
+        });
+
+        StreamIter { streams }
+    }
+
+    fn priority_key(&self, stream_id: u64) -> (u8, u64) {
+        let urgency = self
+            .streams
+            .get(&stream_id)
+            .map_or(DEFAULT_URGENCY, |s| s.urgency);
+
+        (urgency, stream_id)
     }
 
     /// Creates an iterator over streams that need to send MAX_STREAM_DATA.
@@ -503,23 +664,29 @@ impl StreamMap {
     }
 
     /// Creates an iterator over streams that need to send STREAM_DATA_BLOCKED.
-    pub fn blocked(&self) -> hash_map::Iter<u64, u64> {
+    pub fn blocked(&self) -> map::Iter<u64, u64> {
         self.blocked.iter()
     }
 
     /// Creates an iterator over streams that need to send RESET_STREAM.
-    pub fn reset(&self) -> hash_map::Iter<u64, (u64, u64)> {
+    pub fn reset(&self) -> map::Iter<u64, (u64, u64)> {
         self.reset.iter()
     }
 
     /// Creates an iterator over streams that need to send STOP_SENDING.
-    pub fn stopped(&self) -> hash_map::Iter<u64, u64> {
+    pub fn stopped(&self) -> map::Iter<u64, u64> {
         self.stopped.iter()
     }
 
     /// Returns true if there are any streams that have data to write.
     pub fn has_flushable(&self) -> bool {
-        !self.flushable.is_empty()
+        !self.scheduler.is_empty()
+    }
+
+    /// Returns true if the stream was completed and garbage collected.
+    #[cfg(feature = "std")]
+    pub fn is_collected(&self, stream_id: u64) -> bool {
+        self.collected.contains(&stream_id)
     }
 
     /// Returns true if there are any streams that have data to read.
@@ -552,16 +719,58 @@ impl StreamMap {
     /// by sending a MAX_STREAMS frame to the peer.
     pub fn should_update_max_streams_bidi(&self) -> bool {
         self.local_max_streams_bidi_next != self.local_max_streams_bidi &&
-            self.local_max_streams_bidi_next / 2 >
-                self.local_max_streams_bidi - self.peer_opened_streams_bidi
+            (self.local_max_streams_bidi_granted ||
+                self.local_max_streams_bidi_next / 2 >
+                    self.local_max_streams_bidi -
+                        self.peer_opened_streams_bidi)
     }
 
     /// Returns true if the max unidirectional streams count needs to be updated
     /// by sending a MAX_STREAMS frame to the peer.
     pub fn should_update_max_streams_uni(&self) -> bool {
         self.local_max_streams_uni_next != self.local_max_streams_uni &&
-            self.local_max_streams_uni_next / 2 >
-                self.local_max_streams_uni - self.peer_opened_streams_uni
+            (self.local_max_streams_uni_granted ||
+                self.local_max_streams_uni_next / 2 >
+                    self.local_max_streams_uni -
+                        self.peer_opened_streams_uni)
+    }
+
+    /// Returns the amount of data buffered by all streams, in both
+    /// directions.
+    pub fn buffered(&self) -> usize {
+        self.streams
+            .values()
+            .map(|s| s.recv.buffered() + s.send.buffered())
+            .sum()
+    }
+
+    /// Returns the amount of memory held by the buffers of all streams, in
+    /// bytes, including allocated capacity that is currently unused.
+    pub fn retained(&self) -> usize {
+        self.streams.values().map(|s| s.retained()).sum()
+    }
+
+    /// Releases the memory held by the map and by its streams that is not
+    /// needed anymore.
+    pub fn shrink_to_fit(&mut self) {
+        for stream in self.streams.values_mut() {
+            stream.shrink_to_fit();
+        }
+
+        // The B-tree maps used without the standard library don't keep any
+        // spare capacity.
+        #[cfg(feature = "std")]
+        {
+            self.streams.shrink_to_fit();
+            self.collected.shrink_to_fit();
+            self.readable.shrink_to_fit();
+            self.writable.shrink_to_fit();
+            self.almost_full.shrink_to_fit();
+            self.blocked.shrink_to_fit();
+            self.reset.shrink_to_fit();
+            self.stopped.shrink_to_fit();
+            self.deadlines.shrink_to_fit();
+        }
     }
 
     /// Returns the number of active streams in the map.
@@ -571,6 +780,42 @@ impl StreamMap {
     }
 }
 
+/// How stream credits are given back to the peer as its streams are closed.
+///
+/// See [`Config::set_stream_credit_policy()`].
+///
+/// [`Config::set_stream_credit_policy()`]:
+/// struct.Config.html#method.set_stream_credit_policy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub enum StreamCreditPolicy {
+    /// A credit is given back whenever a stream opened by the peer is closed,
+    /// so the peer can keep up to the initial number of streams open at any
+    /// time, and more can be granted with [`grant_streams()`] (default).
+    ///
+    /// [`grant_streams()`]: struct.Connection.html#method.grant_streams
+    Replenish = 0,
+
+    /// Like [`Replenish`], but the number of streams the peer can keep open
+    /// is fixed to the initial limits and can't be raised.
+    ///
+    /// [`Replenish`]: enum.StreamCreditPolicy.html#variant.Replenish
+    Fixed     = 1,
+
+    /// Credits are only given with [`grant_streams()`].
+    ///
+    /// [`grant_streams()`]: struct.Connection.html#method.grant_streams
+    Manual    = 2,
+}
+
+// Deriving `Default` for enums requires Rust 1.62.
+#[allow(clippy::derivable_impls)]
+impl Default for StreamCreditPolicy {
+    fn default() -> Self {
+        StreamCreditPolicy::Replenish
+    }
+}
+
 /// A QUIC stream.
 #[derive(Default)]
 pub struct Stream {
@@ -587,29 +832,34 @@ pub struct Stream {
     pub local: bool,
 
     /// Application data.
-    pub data: Option<Box<dyn std::any::Any + Send + Sync>>,
+    pub data: Option<Box<dyn core::any::Any + Send + Sync>>,
 
     /// The stream's urgency (lower is better). Default is `DEFAULT_URGENCY`.
     pub urgency: u8,
 
     /// Whether the stream can be flushed incrementally. Default is `true`.
     pub incremental: bool,
+
+    /// When the stream became blocked by the peer's flow control limit, if it
+    /// still is.
+    pub blocked_since: Option<time::Instant>,
 }
 
 impl Stream {
-    /// Creates a new stream with the given flow control limits.
+    /// Creates a new stream with the given ID and flow control limits.
     pub fn new(
-        max_rx_data: u64, max_tx_data: u64, bidi: bool, local: bool,
+        id: u64, max_rx_data: u64, max_tx_data: u64, bidi: bool, local: bool,
         max_window: u64,
     ) -> Stream {
         Stream {
-            recv: RecvBuf::new(max_rx_data, max_window),
-            send: SendBuf::new(max_tx_data),
+            recv: RecvBuf::new(id, max_rx_data, max_window),
+            send: SendBuf::new(id, max_tx_data),
             bidi,
             local,
             data: None,
             urgency: DEFAULT_URGENCY,
             incremental: true,
+            blocked_since: None,
         }
     }
 
@@ -656,6 +906,18 @@ impl Stream {
             (false, false) => self.recv.is_fin(),
         }
     }
+
+    /// Returns the amount of memory held by the stream's buffers, in bytes.
+    pub fn retained(&self) -> usize {
+        self.recv.retained() + self.send.retained()
+    }
+
+    /// Releases the memory held by the stream's buffers that is not needed
+    /// anymore.
+    pub fn shrink_to_fit(&mut self) {
+        self.recv.shrink_to_fit();
+        self.send.shrink_to_fit();
+    }
 }
 
 /// Returns true if the stream was created locally.
@@ -676,7 +938,7 @@ pub struct StreamIter {
 
 impl StreamIter {
     #[inline]
-    fn from(streams: &HashSet<u64>) -> Self {
+    fn from(streams: &Set<u64>) -> Self {
         StreamIter {
             streams: streams.iter().copied().collect(),
         }
@@ -706,6 +968,9 @@ impl ExactSizeIterator for StreamIter {
 /// into a slice.
 #[derive(Debug, Default)]
 pub struct RecvBuf {
+    /// The ID of the stream, reported in errors.
+    id: u64,
+
     /// Chunks of data received from the peer that have not yet been read by
     /// the application, ordered by offset.
     data: BinaryHeap<RangeBuf>,
@@ -716,6 +981,9 @@ pub struct RecvBuf {
     /// The total length of data received on this stream.
     len: u64,
 
+    /// The amount of data currently buffered.
+    buffered: usize,
+
     /// Receiver flow controller.
     flow_control: flowcontrol::FlowControl,
 
@@ -731,8 +999,9 @@ pub struct RecvBuf {
 
 impl RecvBuf {
     /// Creates a new receive buffer.
-    fn new(max_data: u64, max_window: u64) -> RecvBuf {
+    fn new(id: u64, max_data: u64, max_window: u64) -> RecvBuf {
         RecvBuf {
+            id,
             flow_control: flowcontrol::FlowControl::new(
                 max_data,
                 cmp::min(max_data, DEFAULT_STREAM_WINDOW),
@@ -749,24 +1018,24 @@ impl RecvBuf {
     /// buffer.
     pub fn write(&mut self, buf: RangeBuf) -> Result<()> {
         if buf.max_off() > self.max_data() {
-            return Err(Error::FlowControl);
+            return Err(Error::FlowControl(self.id));
         }
 
         if let Some(fin_off) = self.fin_off {
             // Stream's size is known, forbid data beyond that point.
             if buf.max_off() > fin_off {
-                return Err(Error::FinalSize);
+                return Err(Error::FinalSize(self.id));
             }
 
             // Stream's size is already known, forbid changing it.
             if buf.fin() && fin_off != buf.max_off() {
-                return Err(Error::FinalSize);
+                return Err(Error::FinalSize(self.id));
             }
         }
 
         // Stream's known size is lower than data already received.
         if buf.fin() && buf.max_off() < self.len {
-            return Err(Error::FinalSize);
+            return Err(Error::FinalSize(self.id));
         }
 
         // We already saved the final offset, so there's nothing else we
@@ -783,6 +1052,12 @@ impl RecvBuf {
 
         if buf.fin() {
             self.fin_off = Some(buf.max_off());
+
+            trace_event!({
+                stream_id = self.id,
+                final_size = buf.max_off(),
+                "stream fin received"
+            });
         }
 
         // No need to store empty buffer that doesn't carry the fin flag.
@@ -846,6 +1121,7 @@ impl RecvBuf {
             self.len = cmp::max(self.len, buf.max_off());
 
             if !self.drain {
+                self.buffered += buf.len();
                 self.data.push(buf);
             }
         }
@@ -863,8 +1139,96 @@ impl RecvBuf {
     /// On success the amount of data read, and a flag indicating if there is
     /// no more data in the buffer, are returned as a tuple.
     pub fn emit(&mut self, out: &mut [u8]) -> Result<(usize, bool)> {
+        let cap = out.len();
+
+        self.emit_or_consume(Some(out), cap)
+    }
+
+    /// Writes data from the receive buffer into the given output buffers, in
+    /// order.
+    ///
+    /// This is the same as `emit()`, except that each buffer is filled up to
+    /// its capacity before moving on to the next one.
+    #[cfg(feature = "std")]
+    pub fn emit_vectored(
+        &mut self, bufs: &mut [IoSliceMut],
+    ) -> Result<(usize, bool)> {
+        if !self.ready() {
+            return Err(Error::Done);
+        }
+
+        // The stream was reset, so return the error code instead.
+        if let Some(e) = self.error {
+            return Err(Error::StreamReset(e));
+        }
+
+        let mut len = 0;
+
+        for out in bufs.iter_mut() {
+            if !self.ready() {
+                break;
+            }
+
+            let cap = out.len();
+
+            let (read, _) = self.emit(out)?;
+
+            len += read;
+
+            if read < cap {
+                break;
+            }
+        }
+
+        Ok((len, self.is_fin()))
+    }
+
+    /// Discards up to `len` bytes of contiguous data from the receive buffer,
+    /// as if they were read with `emit()`.
+    pub fn consume(&mut self, len: usize) -> Result<(usize, bool)> {
+        self.emit_or_consume(None, len)
+    }
+
+    /// Copies contiguous data from the receive buffer into the given output
+    /// buffer, without consuming it.
+    ///
+    /// On success the amount of data copied, and a flag indicating if there
+    /// would be no more data in the buffer once it is consumed, are returned
+    /// as a tuple.
+    pub fn peek(&self, out: &mut [u8]) -> Result<(usize, bool)> {
+        if !self.ready() {
+            return Err(Error::Done);
+        }
+
+        if let Some(e) = self.error {
+            return Err(Error::StreamReset(e));
+        }
+
+        // The heap can't be iterated in order, so sort the buffers first.
+        let mut bufs: Vec<&RangeBuf> = self.data.iter().collect();
+        bufs.sort_unstable_by_key(|b| b.off());
+
+        let mut len = 0;
+
+        for buf in bufs {
+            if len == out.len() || buf.off() != self.off + len as u64 {
+                break;
+            }
+
+            let buf_len = cmp::min(buf.len(), out.len() - len);
+
+            out[len..len + buf_len].copy_from_slice(&buf[..buf_len]);
+
+            len += buf_len;
+        }
+
+        Ok((len, self.fin_off == Some(self.off + len as u64)))
+    }
+
+    fn emit_or_consume(
+        &mut self, mut out: Option<&mut [u8]>, mut cap: usize,
+    ) -> Result<(usize, bool)> {
         let mut len = 0;
-        let mut cap = out.len();
 
         if !self.ready() {
             return Err(Error::Done);
@@ -884,9 +1248,12 @@ impl RecvBuf {
 
             let buf_len = cmp::min(buf.len(), cap);
 
-            out[len..len + buf_len].copy_from_slice(&buf[..buf_len]);
+            if let Some(out) = out.as_mut() {
+                out[len..len + buf_len].copy_from_slice(&buf[..buf_len]);
+            }
 
             self.off += buf_len as u64;
+            self.buffered -= buf_len;
 
             len += buf_len;
             cap -= buf_len;
@@ -898,7 +1265,7 @@ impl RecvBuf {
                 break;
             }
 
-            std::collections::binary_heap::PeekMut::pop(buf);
+            alloc::collections::binary_heap::PeekMut::pop(buf);
         }
 
         // Update consumed bytes for flow control.
@@ -912,13 +1279,13 @@ impl RecvBuf {
         // Stream's size is already known, forbid changing it.
         if let Some(fin_off) = self.fin_off {
             if fin_off != final_size {
-                return Err(Error::FinalSize);
+                return Err(Error::FinalSize(self.id));
             }
         }
 
         // Stream's known size is lower than data already received.
         if final_size < self.len {
-            return Err(Error::FinalSize);
+            return Err(Error::FinalSize(self.id));
         }
 
         // Calculate how many bytes need to be removed from the connection flow
@@ -931,10 +1298,18 @@ impl RecvBuf {
 
         self.error = Some(error_code);
 
+        trace_event!({
+            stream_id = self.id,
+            error_code,
+            final_size,
+            "stream reset by peer"
+        });
+
         // Clear all data already buffered.
         self.off = final_size;
 
         self.data.clear();
+        self.buffered = 0;
 
         // In order to ensure the application is notified when the stream is
         // reset, enqueue a zero-length buffer at the final size offset.
@@ -945,8 +1320,8 @@ impl RecvBuf {
     }
 
     /// Commits the new max_data limit.
-    pub fn update_max_data(&mut self, now: time::Instant) {
-        self.flow_control.update_max_data(now);
+    pub fn update_max_data(&mut self, max_data: u64, now: time::Instant) {
+        self.flow_control.update_max_data(max_data, now);
     }
 
     /// Return the new max_data limit.
@@ -955,7 +1330,7 @@ impl RecvBuf {
     }
 
     /// Return the current flow control limit.
-    fn max_data(&self) -> u64 {
+    pub fn max_data(&self) -> u64 {
         self.flow_control.max_data()
     }
 
@@ -964,6 +1339,15 @@ impl RecvBuf {
         self.flow_control.window()
     }
 
+    /// Sets the receive window to the given value.
+    ///
+    /// Returns true if the flow control limit can be raised as a result.
+    pub fn set_window(&mut self, window: u64) -> bool {
+        self.flow_control.set_window(window);
+
+        self.fin_off.is_none() && self.max_data_next() > self.max_data()
+    }
+
     /// Autotune the window size.
     pub fn autotune_window(&mut self, now: time::Instant, rtt: time::Duration) {
         self.flow_control.autotune_window(now, rtt);
@@ -977,7 +1361,10 @@ impl RecvBuf {
 
         self.drain = true;
 
+        trace_event!({ stream_id = self.id, "stream shut down for reading" });
+
         self.data.clear();
+        self.buffered = 0;
 
         self.off = self.max_off();
 
@@ -1011,6 +1398,42 @@ impl RecvBuf {
         false
     }
 
+    /// Returns true if the stream was reset by the peer.
+    pub fn is_reset(&self) -> bool {
+        self.error.is_some()
+    }
+
+    /// Returns true if the application stopped receiving data.
+    #[cfg(feature = "std")]
+    pub fn is_shutdown(&self) -> bool {
+        self.drain
+    }
+
+    /// Returns the amount of data buffered and not read yet.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Returns the amount of memory held by the buffer, in bytes.
+    pub fn retained(&self) -> usize {
+        self.data.capacity() * core::mem::size_of::<RangeBuf>() +
+            self.data.iter().map(|b| b.retained()).sum::<usize>()
+    }
+
+    /// Releases the memory held by the buffer that is not needed anymore.
+    ///
+    /// Chunks referencing a larger shared buffer, such as a whole received
+    /// datagram, are copied to buffers of their own.
+    pub fn shrink_to_fit(&mut self) {
+        self.data = core::mem::take(&mut self.data)
+            .into_iter()
+            .map(|mut b| {
+                b.shrink_to_fit();
+                b
+            })
+            .collect();
+    }
+
     /// Returns true if the stream has data to be read.
     fn ready(&self) -> bool {
         let buf = match self.data.peek() {
@@ -1034,6 +1457,9 @@ impl RecvBuf {
 /// retransmitted to be re-buffered).
 #[derive(Debug, Default)]
 pub struct SendBuf {
+    /// The ID of the stream, reported in errors.
+    id: u64,
+
     /// Chunks of data to be sent, ordered by offset.
     data: VecDeque<RangeBuf>,
 
@@ -1046,6 +1472,10 @@ pub struct SendBuf {
     /// The amount of data currently buffered.
     len: u64,
 
+    /// The amount of data held in the buffer, including data that was sent
+    /// but not acked yet.
+    buffered: usize,
+
     /// The maximum offset we are allowed to send to the peer.
     max_data: u64,
 
@@ -1064,8 +1494,9 @@ pub struct SendBuf {
 
 impl SendBuf {
     /// Creates a new send buffer.
-    fn new(max_data: u64) -> SendBuf {
+    fn new(id: u64, max_data: u64) -> SendBuf {
         SendBuf {
+            id,
             max_data,
             ..SendBuf::default()
         }
@@ -1076,17 +1507,47 @@ impl SendBuf {
     /// The number of bytes that were actually stored in the buffer is returned
     /// (this may be lower than the size of the input buffer, in case of partial
     /// writes).
-    pub fn write(&mut self, mut data: &[u8], mut fin: bool) -> Result<usize> {
-        let max_off = self.off + data.len() as u64;
+    pub fn write(&mut self, data: &[u8], fin: bool) -> Result<usize> {
+        self.write_with(data.len(), fin, |start, len, off, fin| {
+            RangeBuf::from(&data[start..start + len], off, fin)
+        })
+    }
+
+    /// Inserts the first `len` bytes of the given shared buffer at the end of
+    /// the buffer, without copying them.
+    ///
+    /// This is the same as `write()`, except that the send buffer keeps a
+    /// reference to `data` until all of it has been acked.
+    pub fn write_shared(
+        &mut self, data: Arc<[u8]>, len: usize, fin: bool,
+    ) -> Result<usize> {
+        let len = cmp::min(len, data.len());
+
+        self.write_with(len, fin, |start, len, off, fin| {
+            RangeBuf::from_shared(data.clone(), start, len, off, fin)
+        })
+    }
+
+    /// Inserts `len` bytes of data at the end of the buffer, using the given
+    /// function to create buffers from ranges of the input data.
+    ///
+    /// The function is passed the start and length of each range within the
+    /// input data, as well as its stream offset and fin flag.
+    fn write_with<F>(
+        &mut self, mut len: usize, mut fin: bool, mut new_buf: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(usize, usize, u64, bool) -> RangeBuf,
+    {
+        let max_off = self.off + len as u64;
 
         // Get the stream send capacity. This will return an error if the stream
         // was stopped.
         let capacity = self.cap()?;
 
-        if data.len() > capacity {
+        if len > capacity {
             // Truncate the input buffer according to the stream's capacity.
-            let len = capacity;
-            data = &data[..len];
+            len = capacity;
 
             // We are not buffering the full input, so clear the fin flag.
             fin = false;
@@ -1095,49 +1556,58 @@ impl SendBuf {
         if let Some(fin_off) = self.fin_off {
             // Can't write past final offset.
             if max_off > fin_off {
-                return Err(Error::FinalSize);
+                return Err(Error::FinalSize(self.id));
             }
 
             // Can't "undo" final offset.
             if max_off == fin_off && !fin {
-                return Err(Error::FinalSize);
+                return Err(Error::FinalSize(self.id));
             }
         }
 
         if fin {
             self.fin_off = Some(max_off);
+
+            trace_event!({
+                stream_id = self.id,
+                final_size = max_off,
+                "stream fin queued"
+            });
         }
 
         // Don't queue data that was already fully acked.
         if self.ack_off() >= max_off {
-            return Ok(data.len());
+            return Ok(len);
         }
 
         // We already recorded the final offset, so we can just discard the
         // empty buffer now.
-        if data.is_empty() {
-            return Ok(data.len());
+        if len == 0 {
+            return Ok(len);
         }
 
-        let mut len = 0;
+        let mut written = 0;
 
         // Split the remaining input data into consistently-sized buffers to
         // avoid fragmentation.
-        for chunk in data.chunks(SEND_BUFFER_SIZE) {
-            len += chunk.len();
+        while written < len {
+            let chunk_len = cmp::min(len - written, SEND_BUFFER_SIZE);
 
-            let fin = len == data.len() && fin;
+            let fin = written + chunk_len == len && fin;
 
-            let buf = RangeBuf::from(chunk, self.off, fin);
+            let buf = new_buf(written, chunk_len, self.off, fin);
 
             // The new data can simply be appended at the end of the send buffer.
             self.data.push_back(buf);
 
-            self.off += chunk.len() as u64;
-            self.len += chunk.len() as u64;
+            self.off += chunk_len as u64;
+            self.len += chunk_len as u64;
+            self.buffered += chunk_len;
+
+            written += chunk_len;
         }
 
-        Ok(len)
+        Ok(written)
     }
 
     /// Writes data from the send buffer into the given output buffer.
@@ -1242,7 +1712,9 @@ impl SendBuf {
         }
 
         if let Some(drop) = drop_until {
-            self.data.drain(..=drop);
+            for buf in self.data.drain(..=drop) {
+                self.buffered -= buf.len;
+            }
 
             // When a buffer is marked for retransmission, but then acked before
             // it could be retransmitted, we might end up decreasing the SendBuf
@@ -1309,6 +1781,7 @@ impl SendBuf {
 
         // Drop all buffered data.
         self.data.clear();
+        self.buffered = 0;
 
         // Mark all data as acked.
         self.ack(0, self.off as usize);
@@ -1332,6 +1805,13 @@ impl SendBuf {
 
         self.error = Some(error_code);
 
+        trace_event!({
+            stream_id = self.id,
+            error_code,
+            final_size = fin_off,
+            "stream stopped by peer"
+        });
+
         Ok((fin_off, unsent))
     }
 
@@ -1343,7 +1823,15 @@ impl SendBuf {
 
         self.shutdown = true;
 
-        self.reset()
+        let (fin_off, unsent) = self.reset()?;
+
+        trace_event!({
+            stream_id = self.id,
+            final_size = fin_off,
+            "stream shut down for writing"
+        });
+
+        Ok((fin_off, unsent))
     }
 
     /// Returns the largest offset of data buffered.
@@ -1403,6 +1891,27 @@ impl SendBuf {
         self.error.is_some()
     }
 
+    /// Returns the amount of data held in the buffer, including data that was
+    /// sent but not acked yet.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Returns the amount of memory held by the buffer, in bytes.
+    pub fn retained(&self) -> usize {
+        self.data.capacity() * core::mem::size_of::<RangeBuf>() +
+            self.data.iter().map(|b| b.retained()).sum::<usize>()
+    }
+
+    /// Releases the memory held by the buffer that is not needed anymore.
+    pub fn shrink_to_fit(&mut self) {
+        for buf in self.data.iter_mut() {
+            buf.shrink_to_fit();
+        }
+
+        self.data.shrink_to_fit();
+    }
+
     /// Returns true if there is data to be written.
     fn ready(&self) -> bool {
         !self.data.is_empty() && self.off_front() < self.off
@@ -1413,7 +1922,7 @@ impl SendBuf {
         match self.acked.iter().next() {
             // Only consider the initial range if it contiguously covers the
             // start of the stream (i.e. from offset 0).
-            Some(std::ops::Range { start: 0, end }) => end,
+            Some(core::ops::Range { start: 0, end }) => end,
 
             Some(_) | None => 0,
         }
@@ -1451,7 +1960,7 @@ pub struct RangeBuf {
     /// To avoid neeless allocations when a RangeBuf is split, this field is
     /// reference-counted and can be shared between multiple RangeBuf objects,
     /// and sliced using the `start` and `len` values.
-    data: Arc<Vec<u8>>,
+    data: RangeBufData,
 
     /// The initial offset within the internal buffer.
     start: usize,
@@ -1473,7 +1982,7 @@ impl RangeBuf {
     /// Creates a new `RangeBuf` from the given slice.
     pub fn from(buf: &[u8], off: u64, fin: bool) -> RangeBuf {
         RangeBuf {
-            data: Arc::new(Vec::from(buf)),
+            data: RangeBufData::Slice(Arc::from(buf)),
             start: 0,
             pos: 0,
             len: buf.len(),
@@ -1482,6 +1991,36 @@ impl RangeBuf {
         }
     }
 
+    /// Creates a new `RangeBuf` referencing `len` bytes of the given shared
+    /// buffer, starting at `start`.
+    pub fn from_shared(
+        data: Arc<[u8]>, start: usize, len: usize, off: u64, fin: bool,
+    ) -> RangeBuf {
+        RangeBuf {
+            data: RangeBufData::Slice(data),
+            start,
+            pos: start,
+            len,
+            off,
+            fin,
+        }
+    }
+
+    /// Creates a new `RangeBuf` referencing `len` bytes of the given received
+    /// datagram, starting at `start`.
+    pub fn from_datagram(
+        data: Arc<Vec<u8>>, start: usize, len: usize, off: u64, fin: bool,
+    ) -> RangeBuf {
+        RangeBuf {
+            data: RangeBufData::Datagram(data),
+            start,
+            pos: start,
+            len,
+            off,
+            fin,
+        }
+    }
+
     /// Returns whether `self` holds the final offset in the stream.
     pub fn fin(&self) -> bool {
         self.fin
@@ -1512,6 +2051,34 @@ impl RangeBuf {
         self.pos += count;
     }
 
+    /// Returns the size of the internal buffer referenced by `self`.
+    ///
+    /// As the internal buffer can be shared, this might be larger than the
+    /// data `self` covers.
+    pub fn retained(&self) -> usize {
+        self.data.capacity()
+    }
+
+    /// Returns true if the internal buffer referenced by `self` is more than
+    /// twice as large as the data `self` covers.
+    pub fn is_oversized(&self) -> bool {
+        self.retained() > self.len.saturating_mul(2)
+    }
+
+    /// Copies the data covered by `self` to an internal buffer of its own, if
+    /// the current one is larger than that.
+    pub fn shrink_to_fit(&mut self) {
+        if self.retained() <= self.len {
+            return;
+        }
+
+        self.data = RangeBufData::Slice(Arc::from(
+            &self.data[self.start..self.start + self.len],
+        ));
+        self.pos -= self.start;
+        self.start = 0;
+    }
+
     /// Splits the buffer into two at the given index.
     pub fn split_off(&mut self, at: usize) -> RangeBuf {
         assert!(
@@ -1538,7 +2105,46 @@ impl RangeBuf {
     }
 }
 
-impl std::ops::Deref for RangeBuf {
+/// The internal buffer of a `RangeBuf`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum RangeBufData {
+    /// A buffer allocated for the data.
+    Slice(Arc<[u8]>),
+
+    /// A datagram received from the peer, owned by the connection.
+    Datagram(Arc<Vec<u8>>),
+}
+
+impl RangeBufData {
+    /// Returns the amount of memory held by the buffer, in bytes.
+    fn capacity(&self) -> usize {
+        match self {
+            RangeBufData::Slice(v) => v.len(),
+
+            RangeBufData::Datagram(v) => v.capacity(),
+        }
+    }
+}
+
+impl Default for RangeBufData {
+    fn default() -> RangeBufData {
+        RangeBufData::Slice(Arc::from(&[][..]))
+    }
+}
+
+impl core::ops::Deref for RangeBufData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            RangeBufData::Slice(v) => v,
+
+            RangeBufData::Datagram(v) => v,
+        }
+    }
+}
+
+impl core::ops::Deref for RangeBuf {
     type Target = [u8];
 
     fn deref(&self) -> &[u8] {
@@ -1571,7 +2177,7 @@ mod tests {
 
     #[test]
     fn empty_read() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1581,7 +2187,7 @@ mod tests {
 
     #[test]
     fn empty_stream_frame() {
-        let mut recv = RecvBuf::new(15, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, 15, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let buf = RangeBuf::from(b"hello", 0, false);
@@ -1602,7 +2208,7 @@ mod tests {
 
         // Check flow control for empty buffer.
         let buf = RangeBuf::from(b"", 16, false);
-        assert_eq!(recv.write(buf), Err(Error::FlowControl));
+        assert_eq!(recv.write(buf), Err(Error::FlowControl(0)));
 
         // Store fin empty buffer.
         let buf = RangeBuf::from(b"", 5, true);
@@ -1627,9 +2233,9 @@ mod tests {
 
         // Validate final size with fin empty buffers.
         let buf = RangeBuf::from(b"", 6, true);
-        assert_eq!(recv.write(buf), Err(Error::FinalSize));
+        assert_eq!(recv.write(buf), Err(Error::FinalSize(0)));
         let buf = RangeBuf::from(b"", 4, true);
-        assert_eq!(recv.write(buf), Err(Error::FinalSize));
+        assert_eq!(recv.write(buf), Err(Error::FinalSize(0)));
 
         let mut buf = [0; 32];
         assert_eq!(recv.emit(&mut buf), Ok((0, true)));
@@ -1637,7 +2243,7 @@ mod tests {
 
     #[test]
     fn ordered_read() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1672,9 +2278,96 @@ mod tests {
         assert_eq!(recv.emit(&mut buf), Err(Error::Done));
     }
 
+    #[test]
+    fn peek_and_consume() {
+        let mut recv = RecvBuf::new(0, u64::MAX, DEFAULT_STREAM_WINDOW);
+
+        let mut buf = [0; 32];
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        let second = RangeBuf::from(b"world", 5, false);
+        let third = RangeBuf::from(b"something", 15, true);
+
+        assert_eq!(recv.peek(&mut buf), Err(Error::Done));
+
+        assert!(recv.write(third).is_ok());
+        assert!(recv.write(second).is_ok());
+        assert!(recv.write(first).is_ok());
+
+        // Only contiguous data is peeked, and nothing is consumed.
+        assert_eq!(recv.peek(&mut buf), Ok((10, false)));
+        assert_eq!(&buf[..10], b"helloworld");
+        assert_eq!(recv.off, 0);
+
+        assert_eq!(recv.peek(&mut buf[..7]), Ok((7, false)));
+        assert_eq!(&buf[..7], b"hellowo");
+
+        assert_eq!(recv.consume(7), Ok((7, false)));
+        assert_eq!(recv.off, 7);
+
+        assert_eq!(recv.peek(&mut buf), Ok((3, false)));
+        assert_eq!(&buf[..3], b"rld");
+
+        assert_eq!(recv.consume(32), Ok((3, false)));
+        assert_eq!(recv.peek(&mut buf), Err(Error::Done));
+
+        let fourth = RangeBuf::from(b"other", 10, false);
+        assert!(recv.write(fourth).is_ok());
+
+        assert_eq!(recv.peek(&mut buf), Ok((14, true)));
+        assert_eq!(&buf[..14], b"othersomething");
+
+        assert_eq!(recv.emit(&mut buf), Ok((14, true)));
+    }
+
+    #[test]
+    fn vectored_read() {
+        let mut recv = RecvBuf::new(0, u64::MAX, DEFAULT_STREAM_WINDOW);
+
+        let mut a = [0; 4];
+        let mut b = [0; 4];
+        let mut c = [0; 32];
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        let second = RangeBuf::from(b"world", 5, false);
+        let third = RangeBuf::from(b"something", 15, true);
+
+        assert!(recv.write(first).is_ok());
+        assert!(recv.write(third).is_ok());
+
+        {
+            let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+            assert_eq!(recv.emit_vectored(&mut bufs), Ok((5, false)));
+        }
+        assert_eq!(&a, b"hell");
+        assert_eq!(&b[..1], b"o");
+
+        {
+            let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+            assert_eq!(recv.emit_vectored(&mut bufs), Err(Error::Done));
+        }
+
+        let fourth = RangeBuf::from(b"other", 10, false);
+        assert!(recv.write(second).is_ok());
+        assert!(recv.write(fourth).is_ok());
+
+        {
+            let mut bufs = [
+                IoSliceMut::new(&mut a),
+                IoSliceMut::new(&mut b),
+                IoSliceMut::new(&mut c),
+            ];
+            assert_eq!(recv.emit_vectored(&mut bufs), Ok((19, true)));
+        }
+        assert_eq!(&a, b"worl");
+        assert_eq!(&b, b"doth");
+        assert_eq!(&c[..11], b"ersomething");
+        assert!(recv.is_fin());
+    }
+
     #[test]
     fn split_read() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1714,7 +2407,7 @@ mod tests {
 
     #[test]
     fn incomplete_read() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1742,7 +2435,7 @@ mod tests {
 
     #[test]
     fn zero_len_read() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1770,7 +2463,7 @@ mod tests {
 
     #[test]
     fn past_read() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1797,7 +2490,7 @@ mod tests {
         assert_eq!(recv.off, 9);
         assert_eq!(recv.data.len(), 0);
 
-        assert_eq!(recv.write(third), Err(Error::FinalSize));
+        assert_eq!(recv.write(third), Err(Error::FinalSize(0)));
 
         assert!(recv.write(fourth).is_ok());
         assert_eq!(recv.len, 9);
@@ -1809,7 +2502,7 @@ mod tests {
 
     #[test]
     fn fully_overlapping_read() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1840,7 +2533,7 @@ mod tests {
 
     #[test]
     fn fully_overlapping_read2() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1871,7 +2564,7 @@ mod tests {
 
     #[test]
     fn fully_overlapping_read3() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1902,7 +2595,7 @@ mod tests {
 
     #[test]
     fn fully_overlapping_read_multi() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1939,7 +2632,7 @@ mod tests {
 
     #[test]
     fn overlapping_start_read() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1969,7 +2662,7 @@ mod tests {
 
     #[test]
     fn overlapping_end_read() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -1999,7 +2692,7 @@ mod tests {
 
     #[test]
     fn partially_multi_overlapping_reordered_read() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -2036,7 +2729,7 @@ mod tests {
 
     #[test]
     fn partially_multi_overlapping_reordered_read2() {
-        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        let mut recv = RecvBuf::new(0, core::u64::MAX, DEFAULT_STREAM_WINDOW);
         assert_eq!(recv.len, 0);
 
         let mut buf = [0; 32];
@@ -2093,7 +2786,7 @@ mod tests {
     fn empty_write() {
         let mut buf = [0; 5];
 
-        let mut send = SendBuf::new(std::u64::MAX);
+        let mut send = SendBuf::new(0, core::u64::MAX);
         assert_eq!(send.len, 0);
 
         let (written, fin) = send.emit(&mut buf).unwrap();
@@ -2105,7 +2798,7 @@ mod tests {
     fn multi_write() {
         let mut buf = [0; 128];
 
-        let mut send = SendBuf::new(std::u64::MAX);
+        let mut send = SendBuf::new(0, core::u64::MAX);
         assert_eq!(send.len, 0);
 
         let first = b"something";
@@ -2128,7 +2821,7 @@ mod tests {
     fn split_write() {
         let mut buf = [0; 10];
 
-        let mut send = SendBuf::new(std::u64::MAX);
+        let mut send = SendBuf::new(0, core::u64::MAX);
         assert_eq!(send.len, 0);
 
         let first = b"something";
@@ -2171,7 +2864,7 @@ mod tests {
     fn resend() {
         let mut buf = [0; 15];
 
-        let mut send = SendBuf::new(std::u64::MAX);
+        let mut send = SendBuf::new(0, core::u64::MAX);
         assert_eq!(send.len, 0);
         assert_eq!(send.off_front(), 0);
 
@@ -2230,6 +2923,43 @@ mod tests {
         assert_eq!(send.off_front(), 19);
     }
 
+    #[test]
+    fn write_shared() {
+        let mut buf = [0; 20];
+
+        let mut send = SendBuf::new(0, 15);
+
+        let data: Arc<[u8]> = Arc::from(&b"somethinghelloworld"[..]);
+
+        // The input is truncated to the stream's capacity, and split into
+        // buffers that all reference the same data.
+        assert_eq!(send.write_shared(data.clone(), data.len(), true), Ok(15));
+        assert_eq!(send.data.len(), 3);
+        assert_eq!(Arc::strong_count(&data), 4);
+        assert_eq!(send.fin_off, None);
+
+        assert_eq!(send.emit(&mut buf[..9]), Ok((9, false)));
+        assert_eq!(&buf[..9], b"something");
+
+        send.retransmit(0, 9);
+
+        assert_eq!(send.emit(&mut buf), Ok((15, false)));
+        assert_eq!(&buf[..15], b"somethinghellow");
+
+        send.ack_and_drop(0, 15);
+        assert_eq!(send.data.len(), 0);
+        assert_eq!(Arc::strong_count(&data), 1);
+
+        send.update_max_data(19);
+
+        let rest: Arc<[u8]> = Arc::from(&b"orld"[..]);
+        assert_eq!(send.write_shared(rest, 4, true), Ok(4));
+        assert_eq!(send.fin_off, Some(19));
+
+        assert_eq!(send.emit(&mut buf), Ok((4, true)));
+        assert_eq!(&buf[..4], b"orld");
+    }
+
     #[test]
     fn write_blocked_by_off() {
         let mut buf = [0; 10];
@@ -2304,7 +3034,7 @@ mod tests {
     fn zero_len_write() {
         let mut buf = [0; 10];
 
-        let mut send = SendBuf::new(std::u64::MAX);
+        let mut send = SendBuf::new(0, core::u64::MAX);
         assert_eq!(send.len, 0);
 
         let first = b"something";
@@ -2326,7 +3056,7 @@ mod tests {
 
     #[test]
     fn recv_flow_control() {
-        let mut stream = Stream::new(15, 0, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 15, 0, true, true, DEFAULT_STREAM_WINDOW);
         assert!(!stream.recv.almost_full());
 
         let mut buf = [0; 32];
@@ -2339,7 +3069,7 @@ mod tests {
         assert_eq!(stream.recv.write(first), Ok(()));
         assert!(!stream.recv.almost_full());
 
-        assert_eq!(stream.recv.write(third), Err(Error::FlowControl));
+        assert_eq!(stream.recv.write(third), Err(Error::FlowControl(0)));
 
         let (len, fin) = stream.recv.emit(&mut buf).unwrap();
         assert_eq!(&buf[..len], b"helloworld");
@@ -2347,7 +3077,8 @@ mod tests {
 
         assert!(stream.recv.almost_full());
 
-        stream.recv.update_max_data(time::Instant::now());
+        let max_data = stream.recv.max_data_next();
+        stream.recv.update_max_data(max_data, time::Instant::now());
         assert_eq!(stream.recv.max_data_next(), 25);
         assert!(!stream.recv.almost_full());
 
@@ -2357,19 +3088,19 @@ mod tests {
 
     #[test]
     fn recv_past_fin() {
-        let mut stream = Stream::new(15, 0, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 15, 0, true, true, DEFAULT_STREAM_WINDOW);
         assert!(!stream.recv.almost_full());
 
         let first = RangeBuf::from(b"hello", 0, true);
         let second = RangeBuf::from(b"world", 5, false);
 
         assert_eq!(stream.recv.write(first), Ok(()));
-        assert_eq!(stream.recv.write(second), Err(Error::FinalSize));
+        assert_eq!(stream.recv.write(second), Err(Error::FinalSize(0)));
     }
 
     #[test]
     fn recv_fin_dup() {
-        let mut stream = Stream::new(15, 0, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 15, 0, true, true, DEFAULT_STREAM_WINDOW);
         assert!(!stream.recv.almost_full());
 
         let first = RangeBuf::from(b"hello", 0, true);
@@ -2387,31 +3118,31 @@ mod tests {
 
     #[test]
     fn recv_fin_change() {
-        let mut stream = Stream::new(15, 0, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 15, 0, true, true, DEFAULT_STREAM_WINDOW);
         assert!(!stream.recv.almost_full());
 
         let first = RangeBuf::from(b"hello", 0, true);
         let second = RangeBuf::from(b"world", 5, true);
 
         assert_eq!(stream.recv.write(second), Ok(()));
-        assert_eq!(stream.recv.write(first), Err(Error::FinalSize));
+        assert_eq!(stream.recv.write(first), Err(Error::FinalSize(0)));
     }
 
     #[test]
     fn recv_fin_lower_than_received() {
-        let mut stream = Stream::new(15, 0, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 15, 0, true, true, DEFAULT_STREAM_WINDOW);
         assert!(!stream.recv.almost_full());
 
         let first = RangeBuf::from(b"hello", 0, true);
         let second = RangeBuf::from(b"world", 5, false);
 
         assert_eq!(stream.recv.write(second), Ok(()));
-        assert_eq!(stream.recv.write(first), Err(Error::FinalSize));
+        assert_eq!(stream.recv.write(first), Err(Error::FinalSize(0)));
     }
 
     #[test]
     fn recv_fin_flow_control() {
-        let mut stream = Stream::new(15, 0, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 15, 0, true, true, DEFAULT_STREAM_WINDOW);
         assert!(!stream.recv.almost_full());
 
         let mut buf = [0; 32];
@@ -2431,18 +3162,18 @@ mod tests {
 
     #[test]
     fn recv_fin_reset_mismatch() {
-        let mut stream = Stream::new(15, 0, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 15, 0, true, true, DEFAULT_STREAM_WINDOW);
         assert!(!stream.recv.almost_full());
 
         let first = RangeBuf::from(b"hello", 0, true);
 
         assert_eq!(stream.recv.write(first), Ok(()));
-        assert_eq!(stream.recv.reset(0, 10), Err(Error::FinalSize));
+        assert_eq!(stream.recv.reset(0, 10), Err(Error::FinalSize(0)));
     }
 
     #[test]
     fn recv_reset_dup() {
-        let mut stream = Stream::new(15, 0, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 15, 0, true, true, DEFAULT_STREAM_WINDOW);
         assert!(!stream.recv.almost_full());
 
         let first = RangeBuf::from(b"hello", 0, false);
@@ -2454,32 +3185,32 @@ mod tests {
 
     #[test]
     fn recv_reset_change() {
-        let mut stream = Stream::new(15, 0, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 15, 0, true, true, DEFAULT_STREAM_WINDOW);
         assert!(!stream.recv.almost_full());
 
         let first = RangeBuf::from(b"hello", 0, false);
 
         assert_eq!(stream.recv.write(first), Ok(()));
         assert_eq!(stream.recv.reset(0, 5), Ok(0));
-        assert_eq!(stream.recv.reset(0, 10), Err(Error::FinalSize));
+        assert_eq!(stream.recv.reset(0, 10), Err(Error::FinalSize(0)));
     }
 
     #[test]
     fn recv_reset_lower_than_received() {
-        let mut stream = Stream::new(15, 0, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 15, 0, true, true, DEFAULT_STREAM_WINDOW);
         assert!(!stream.recv.almost_full());
 
         let first = RangeBuf::from(b"hello", 0, false);
 
         assert_eq!(stream.recv.write(first), Ok(()));
-        assert_eq!(stream.recv.reset(0, 4), Err(Error::FinalSize));
+        assert_eq!(stream.recv.reset(0, 4), Err(Error::FinalSize(0)));
     }
 
     #[test]
     fn send_flow_control() {
         let mut buf = [0; 25];
 
-        let mut stream = Stream::new(0, 15, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 15, true, true, DEFAULT_STREAM_WINDOW);
 
         let first = b"hello";
         let second = b"world";
@@ -2522,7 +3253,7 @@ mod tests {
 
     #[test]
     fn send_past_fin() {
-        let mut stream = Stream::new(0, 15, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 15, true, true, DEFAULT_STREAM_WINDOW);
 
         let first = b"hello";
         let second = b"world";
@@ -2533,12 +3264,12 @@ mod tests {
         assert_eq!(stream.send.write(second, true), Ok(5));
         assert!(stream.send.is_fin());
 
-        assert_eq!(stream.send.write(third, false), Err(Error::FinalSize));
+        assert_eq!(stream.send.write(third, false), Err(Error::FinalSize(0)));
     }
 
     #[test]
     fn send_fin_dup() {
-        let mut stream = Stream::new(0, 15, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 15, true, true, DEFAULT_STREAM_WINDOW);
 
         assert_eq!(stream.send.write(b"hello", true), Ok(5));
         assert!(stream.send.is_fin());
@@ -2549,14 +3280,14 @@ mod tests {
 
     #[test]
     fn send_undo_fin() {
-        let mut stream = Stream::new(0, 15, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 15, true, true, DEFAULT_STREAM_WINDOW);
 
         assert_eq!(stream.send.write(b"hello", true), Ok(5));
         assert!(stream.send.is_fin());
 
         assert_eq!(
             stream.send.write(b"helloworld", true),
-            Err(Error::FinalSize)
+            Err(Error::FinalSize(0))
         );
     }
 
@@ -2564,7 +3295,7 @@ mod tests {
     fn send_fin_max_data_match() {
         let mut buf = [0; 15];
 
-        let mut stream = Stream::new(0, 15, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 15, true, true, DEFAULT_STREAM_WINDOW);
 
         let slice = b"hellohellohello";
 
@@ -2580,7 +3311,7 @@ mod tests {
     fn send_fin_zero_length() {
         let mut buf = [0; 5];
 
-        let mut stream = Stream::new(0, 15, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 15, true, true, DEFAULT_STREAM_WINDOW);
 
         assert_eq!(stream.send.write(b"hello", false), Ok(5));
         assert_eq!(stream.send.write(b"", true), Ok(0));
@@ -2596,7 +3327,7 @@ mod tests {
     fn send_ack() {
         let mut buf = [0; 5];
 
-        let mut stream = Stream::new(0, 15, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 15, true, true, DEFAULT_STREAM_WINDOW);
 
         assert_eq!(stream.send.write(b"hello", false), Ok(5));
         assert_eq!(stream.send.write(b"world", false), Ok(5));
@@ -2626,7 +3357,7 @@ mod tests {
     fn send_ack_reordering() {
         let mut buf = [0; 5];
 
-        let mut stream = Stream::new(0, 15, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 15, true, true, DEFAULT_STREAM_WINDOW);
 
         assert_eq!(stream.send.write(b"hello", false), Ok(5));
         assert_eq!(stream.send.write(b"world", false), Ok(5));
@@ -2663,7 +3394,7 @@ mod tests {
 
     #[test]
     fn recv_data_below_off() {
-        let mut stream = Stream::new(15, 0, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 15, 0, true, true, DEFAULT_STREAM_WINDOW);
 
         let first = RangeBuf::from(b"hello", 0, false);
 
@@ -2685,7 +3416,8 @@ mod tests {
 
     #[test]
     fn stream_complete() {
-        let mut stream = Stream::new(30, 30, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream =
+            Stream::new(0, 30, 30, true, true, DEFAULT_STREAM_WINDOW);
 
         assert_eq!(stream.send.write(b"hello", false), Ok(5));
         assert_eq!(stream.send.write(b"world", false), Ok(5));
@@ -2728,7 +3460,7 @@ mod tests {
     fn send_fin_zero_length_output() {
         let mut buf = [0; 5];
 
-        let mut stream = Stream::new(0, 15, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 15, true, true, DEFAULT_STREAM_WINDOW);
 
         assert_eq!(stream.send.write(b"hello", false), Ok(5));
         assert_eq!(stream.send.off_front(), 0);
@@ -2753,7 +3485,7 @@ mod tests {
     fn send_emit() {
         let mut buf = [0; 5];
 
-        let mut stream = Stream::new(0, 20, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 20, true, true, DEFAULT_STREAM_WINDOW);
 
         assert_eq!(stream.send.write(b"hello", false), Ok(5));
         assert_eq!(stream.send.write(b"world", false), Ok(5));
@@ -2805,7 +3537,7 @@ mod tests {
     fn send_emit_ack() {
         let mut buf = [0; 5];
 
-        let mut stream = Stream::new(0, 20, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 20, true, true, DEFAULT_STREAM_WINDOW);
 
         assert_eq!(stream.send.write(b"hello", false), Ok(5));
         assert_eq!(stream.send.write(b"world", false), Ok(5));
@@ -2872,7 +3604,7 @@ mod tests {
     fn send_emit_retransmit() {
         let mut buf = [0; 5];
 
-        let mut stream = Stream::new(0, 20, true, true, DEFAULT_STREAM_WINDOW);
+        let mut stream = Stream::new(0, 0, 20, true, true, DEFAULT_STREAM_WINDOW);
 
         assert_eq!(stream.send.write(b"hello", false), Ok(5));
         assert_eq!(stream.send.write(b"world", false), Ok(5));
@@ -3094,4 +3826,73 @@ mod tests {
 
         assert_eq!(&new_new_buf[..], b"");
     }
+
+    #[test]
+    fn rangebuf_shrink_to_fit() {
+        let mut buf = RangeBuf::from(b"helloworld", 5, true);
+        let mut new_buf = buf.split_off(5);
+
+        assert_eq!(buf.retained(), 10);
+        assert_eq!(new_buf.retained(), 10);
+
+        new_buf.consume(2);
+        new_buf.shrink_to_fit();
+
+        assert_eq!(new_buf.retained(), 5);
+        assert_eq!(new_buf.start, 0);
+        assert_eq!(new_buf.pos, 2);
+        assert_eq!(new_buf.len, 5);
+        assert_eq!(new_buf.off, 10);
+
+        assert_eq!(new_buf.len(), 3);
+        assert_eq!(new_buf.off(), 12);
+        assert!(new_buf.fin());
+
+        assert_eq!(&new_buf[..], b"rld");
+
+        // Already compact buffers are left untouched.
+        new_buf.shrink_to_fit();
+        assert_eq!(new_buf.retained(), 5);
+        assert_eq!(&new_buf[..], b"rld");
+    }
+
+    #[test]
+    fn rangebuf_datagram_oversized() {
+        let mut dgram = Vec::with_capacity(1500);
+        dgram.extend_from_slice(&[0; 1000]);
+        let dgram = Arc::new(dgram);
+
+        // The whole capacity of the datagram is retained, not just its length.
+        let mut small = RangeBuf::from_datagram(dgram.clone(), 10, 1, 0, false);
+        assert_eq!(small.retained(), 1500);
+        assert!(small.is_oversized());
+
+        small.shrink_to_fit();
+        assert_eq!(small.retained(), 1);
+        assert!(!small.is_oversized());
+
+        let large = RangeBuf::from_datagram(dgram, 10, 900, 0, false);
+        assert_eq!(large.retained(), 1500);
+        assert!(!large.is_oversized());
+    }
+
+    #[test]
+    fn flushable_reprioritize() {
+        let mut streams = StreamMap::new(5, 5, 5);
+
+        streams.push_flushable(0, 255, true);
+        streams.push_flushable(4, 42, false);
+        streams.push_flushable(8, 42, true);
+
+        // Streams not in the queue are not added to it.
+        streams.update_flushable_priority(12, 127, true, 0, true);
+
+        streams.update_flushable_priority(0, 255, true, 10, false);
+        streams.update_flushable_priority(4, 42, false, 42, true);
+
+        assert_eq!(streams.pop_flushable(), Some(0));
+        assert_eq!(streams.pop_flushable(), Some(8));
+        assert_eq!(streams.pop_flushable(), Some(4));
+        assert_eq!(streams.pop_flushable(), None);
+    }
 }
@@ -0,0 +1,93 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#[cfg(feature = "std")]
+use std::net::SocketAddr;
+
+#[cfg(not(feature = "std"))]
+use core::net::SocketAddr;
+
+use crate::time;
+
+/// The direction of a datagram passed to a [`PacketTap`].
+///
+/// [`PacketTap`]: trait.PacketTap.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketDirection {
+    /// The datagram was returned by [`send()`].
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    Sent,
+
+    /// The datagram was passed to [`recv()`].
+    ///
+    /// [`recv()`]: struct.Connection.html#method.recv
+    Received,
+}
+
+/// Ancillary information about a datagram passed to a [`PacketTap`].
+///
+/// [`PacketTap`]: trait.PacketTap.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TapInfo {
+    /// Whether the datagram was sent or received.
+    pub direction: PacketDirection,
+
+    /// The time the datagram was sent or received at.
+    ///
+    /// For sent datagrams this is the time it should be sent at, as returned
+    /// in [`SendInfo`].
+    ///
+    /// [`SendInfo`]: struct.SendInfo.html
+    pub at: time::Instant,
+
+    /// The local address of the path, if known.
+    ///
+    /// This is only set for datagrams sent on a path started with
+    /// [`probe_path()`].
+    ///
+    /// [`probe_path()`]: struct.Connection.html#method.probe_path
+    pub local: Option<SocketAddr>,
+
+    /// The peer address of the path.
+    pub peer: SocketAddr,
+}
+
+/// A hook receiving every datagram sent and received by a connection.
+///
+/// Datagrams are passed to the tap exactly as they appear on the wire, that
+/// is encrypted and header-protected, so they can be written to a capture
+/// file such as PCAPNG. Combined with the secrets logged with
+/// [`set_keylog()`], tools like Wireshark can then decrypt them.
+///
+/// The tap is set with [`set_packet_tap()`].
+///
+/// [`set_keylog()`]: struct.Connection.html#method.set_keylog
+/// [`set_packet_tap()`]: struct.Connection.html#method.set_packet_tap
+pub trait PacketTap: Send + Sync {
+    /// Called with each datagram sent or received by the connection.
+    fn on_datagram(&mut self, info: &TapInfo, data: &[u8]);
+}
@@ -0,0 +1,146 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Time types used by the transport.
+//!
+//! With the standard library, points in time are represented with
+//! `std::time::Instant`. Without it there is no system clock, so points in
+//! time are represented with the [`Instant`] type defined here instead, and
+//! are only ever produced by the [`Clock`] configured with [`set_clock()`].
+//!
+//! [`Instant`]: struct.Instant.html
+//! [`Clock`]: trait.Clock.html
+//! [`set_clock()`]: struct.Config.html#method.set_clock
+
+pub use core::time::Duration;
+
+#[cfg(feature = "std")]
+pub use std::time::Instant;
+
+#[cfg(not(feature = "std"))]
+use core::ops::Add;
+#[cfg(not(feature = "std"))]
+use core::ops::AddAssign;
+#[cfg(not(feature = "std"))]
+use core::ops::Sub;
+#[cfg(not(feature = "std"))]
+use core::ops::SubAssign;
+
+/// A point in time, measured as the time elapsed since an epoch chosen by the
+/// [`Clock`] that produced it.
+///
+/// This is only used without the standard library, where it replaces
+/// `std::time::Instant`.
+///
+/// [`Clock`]: trait.Clock.html
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+#[cfg(not(feature = "std"))]
+impl Instant {
+    /// Returns the point in time `elapsed` after the clock's epoch.
+    pub const fn from_elapsed(elapsed: Duration) -> Instant {
+        Instant(elapsed)
+    }
+
+    /// Returns the time elapsed since the clock's epoch.
+    pub const fn elapsed(&self) -> Duration {
+        self.0
+    }
+
+    /// Returns the time elapsed from `earlier` to this point in time, or zero
+    /// if `earlier` is later than this.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+
+    /// Returns the time elapsed from `earlier` to this point in time, or
+    /// `None` if `earlier` is later than this.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    /// Returns the time elapsed from `earlier` to this point in time, or zero
+    /// if `earlier` is later than this.
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    /// Returns the point in time `d` after this one, or `None` on overflow.
+    pub fn checked_add(&self, d: Duration) -> Option<Instant> {
+        self.0.checked_add(d).map(Instant)
+    }
+
+    /// Returns the point in time `d` before this one, or `None` if it would
+    /// be before the clock's epoch.
+    pub fn checked_sub(&self, d: Duration) -> Option<Instant> {
+        self.0.checked_sub(d).map(Instant)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, d: Duration) -> Instant {
+        self.checked_add(d)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, d: Duration) {
+        *self = *self + d;
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, d: Duration) -> Instant {
+        self.checked_sub(d)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, d: Duration) {
+        *self = *self - d;
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
@@ -24,10 +24,31 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use std::ffi;
-use std::ptr;
-use std::slice;
+#[cfg(feature = "std")]
+use std::ffi::CStr;
+#[cfg(feature = "std")]
+use std::ffi::CString;
 
+#[cfg(not(feature = "std"))]
+use alloc::ffi::CString;
+#[cfg(not(feature = "std"))]
+use core::ffi::CStr;
+
+use core::ptr;
+use core::slice;
+
+use core::sync::atomic;
+use core::sync::atomic::AtomicI32;
+
+use crate::collections::Map;
+
+use alloc::sync::Arc;
+
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
 use std::io::Write;
 
 use libc::c_char;
@@ -49,6 +70,31 @@ use crate::packet;
 const TLS1_3_VERSION: u16 = 0x0304;
 const TLS_ALERT_ERROR: u64 = 0x100;
 
+const SSL_VERIFY_PEER: c_int = 0x01;
+const SSL_VERIFY_FAIL_IF_NO_PEER_CERT: c_int = 0x02;
+
+const SSL_AD_CERTIFICATE_REQUIRED: u8 = 116;
+
+const X509_V_OK: c_long = 0;
+
+const SSL_TLSEXT_ERR_OK: c_int = 0;
+const SSL_TLSEXT_ERR_ALERT_FATAL: c_int = 2;
+
+/// Certificates keyed by the lowercase server name they are used for.
+pub type ServerCerts = Map<String, Arc<Context>>;
+
+/// A key used by servers to decrypt Encrypted Client Hello.
+pub struct EchKey {
+    /// The serialized ECHConfig the key belongs to.
+    pub config: Vec<u8>,
+
+    /// The raw X25519 private key.
+    pub private_key: Vec<u8>,
+
+    /// Whether the config is sent to clients whose ECH was rejected.
+    pub is_retry_config: bool,
+}
+
 #[allow(non_camel_case_types)]
 #[repr(transparent)]
 struct SSL_METHOD(c_void);
@@ -91,6 +137,18 @@ struct STACK_OF(c_void);
 #[repr(transparent)]
 struct CRYPTO_BUFFER(c_void);
 
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct SSL_ECH_KEYS(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct EVP_HPKE_KEM(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct EVP_HPKE_KEY(c_void);
+
 #[repr(C)]
 #[allow(non_camel_case_types)]
 struct SSL_QUIC_METHOD {
@@ -123,10 +181,41 @@ struct SSL_QUIC_METHOD {
         extern fn(ssl: *mut SSL, level: crypto::Level, alert: u8) -> c_int,
 }
 
-lazy_static::lazy_static! {
-    static ref QUICHE_EX_DATA_INDEX: c_int = unsafe {
-        SSL_get_ex_new_index(0, ptr::null(), ptr::null(), ptr::null(), ptr::null())
+/// The index of the connection in the ex data of SSL objects, or -1 if it
+/// wasn't allocated yet.
+static QUICHE_EX_DATA_INDEX: AtomicI32 = AtomicI32::new(-1);
+
+/// Returns the index of the connection in the ex data of SSL objects,
+/// allocating it on first use.
+fn quiche_ex_data_index() -> c_int {
+    let index = QUICHE_EX_DATA_INDEX.load(atomic::Ordering::Acquire);
+
+    if index >= 0 {
+        return index;
+    }
+
+    let index = unsafe {
+        SSL_get_ex_new_index(
+            0,
+            ptr::null(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null(),
+        )
     };
+
+    // If another thread allocated an index in the meantime, use that instead,
+    // so that all connections agree on it.
+    match QUICHE_EX_DATA_INDEX.compare_exchange(
+        -1,
+        index,
+        atomic::Ordering::AcqRel,
+        atomic::Ordering::Acquire,
+    ) {
+        Ok(_) => index,
+
+        Err(v) => v,
+    }
 }
 
 static QUICHE_STREAM_METHOD: SSL_QUIC_METHOD = SSL_QUIC_METHOD {
@@ -162,12 +251,12 @@ impl Context {
     }
 
     pub fn load_verify_locations_from_file(&mut self, file: &str) -> Result<()> {
-        let file = ffi::CString::new(file).map_err(|_| Error::TlsFail)?;
+        let file = CString::new(file).map_err(|_| Error::TlsFail)?;
         map_result(unsafe {
             SSL_CTX_load_verify_locations(
                 self.as_mut_ptr(),
                 file.as_ptr(),
-                std::ptr::null(),
+                core::ptr::null(),
             )
         })
     }
@@ -175,25 +264,25 @@ impl Context {
     pub fn load_verify_locations_from_directory(
         &mut self, path: &str,
     ) -> Result<()> {
-        let path = ffi::CString::new(path).map_err(|_| Error::TlsFail)?;
+        let path = CString::new(path).map_err(|_| Error::TlsFail)?;
         map_result(unsafe {
             SSL_CTX_load_verify_locations(
                 self.as_mut_ptr(),
-                std::ptr::null(),
+                core::ptr::null(),
                 path.as_ptr(),
             )
         })
     }
 
     pub fn use_certificate_chain_file(&mut self, file: &str) -> Result<()> {
-        let cstr = ffi::CString::new(file).map_err(|_| Error::TlsFail)?;
+        let cstr = CString::new(file).map_err(|_| Error::TlsFail)?;
         map_result(unsafe {
             SSL_CTX_use_certificate_chain_file(self.as_mut_ptr(), cstr.as_ptr())
         })
     }
 
     pub fn use_privkey_file(&mut self, file: &str) -> Result<()> {
-        let cstr = ffi::CString::new(file).map_err(|_| Error::TlsFail)?;
+        let cstr = CString::new(file).map_err(|_| Error::TlsFail)?;
         map_result(unsafe {
             SSL_CTX_use_PrivateKey_file(self.as_mut_ptr(), cstr.as_ptr(), 1)
         })
@@ -207,7 +296,7 @@ impl Context {
     #[cfg(windows)]
     fn load_ca_certs(&mut self) -> Result<()> {
         unsafe {
-            let cstr = ffi::CString::new("Root").map_err(|_| Error::TlsFail)?;
+            let cstr = CString::new("Root").map_err(|_| Error::TlsFail)?;
             let sys_store = winapi::um::wincrypt::CertOpenSystemStoreA(
                 0,
                 cstr.as_ptr() as winapi::um::winnt::LPCSTR,
@@ -267,8 +356,10 @@ impl Context {
     }
 
     pub fn set_verify(&mut self, verify: bool) {
+        let mode = unsafe { SSL_CTX_get_verify_mode(self.as_mut_ptr()) };
+
         let mode = if verify {
-            0x01 // SSL_VERIFY_PEER
+            mode | SSL_VERIFY_PEER
         } else {
             0x00 // SSL_VERIFY_NONE
         };
@@ -278,6 +369,21 @@ impl Context {
         }
     }
 
+    pub fn set_require_peer_cert(&mut self, require: bool) {
+        let mode = unsafe { SSL_CTX_get_verify_mode(self.as_mut_ptr()) };
+
+        let mode = if require {
+            mode | SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT
+        } else {
+            mode & !SSL_VERIFY_FAIL_IF_NO_PEER_CERT
+        };
+
+        unsafe {
+            SSL_CTX_set_verify(self.as_mut_ptr(), mode, ptr::null());
+        }
+    }
+
+    #[cfg(feature = "std")]
     pub fn enable_keylog(&mut self) {
         unsafe {
             SSL_CTX_set_keylog_callback(self.as_mut_ptr(), keylog);
@@ -311,6 +417,72 @@ impl Context {
         })
     }
 
+    pub fn enable_alpn_selection(&mut self) -> Result<()> {
+        unsafe {
+            SSL_CTX_set_alpn_select_cb(
+                self.as_mut_ptr(),
+                select_alpn,
+                ptr::null_mut(),
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn enable_server_name_selection(&mut self) {
+        unsafe {
+            SSL_CTX_set_tlsext_servername_callback(
+                self.as_mut_ptr(),
+                select_server_cert,
+            );
+        }
+    }
+
+    pub fn set_ech_keys(&mut self, keys: &[EchKey]) -> Result<()> {
+        unsafe {
+            let ech_keys = SSL_ECH_KEYS_new();
+            if ech_keys.is_null() {
+                return Err(Error::TlsFail);
+            }
+
+            let rc = keys
+                .iter()
+                .try_for_each(|k| {
+                    let hpke_key = EVP_HPKE_KEY_new();
+                    if hpke_key.is_null() {
+                        return Err(Error::TlsFail);
+                    }
+
+                    let rc = map_result(EVP_HPKE_KEY_init(
+                        hpke_key,
+                        EVP_hpke_x25519_hkdf_sha256(),
+                        k.private_key.as_ptr(),
+                        k.private_key.len(),
+                    ))
+                    .and_then(|_| {
+                        map_result(SSL_ECH_KEYS_add(
+                            ech_keys,
+                            k.is_retry_config as c_int,
+                            k.config.as_ptr(),
+                            k.config.len(),
+                            hpke_key,
+                        ))
+                    });
+
+                    EVP_HPKE_KEY_free(hpke_key);
+
+                    rc
+                })
+                .and_then(|_| {
+                    map_result(SSL_CTX_set1_ech_keys(self.as_mut_ptr(), ech_keys))
+                });
+
+            SSL_ECH_KEYS_free(ech_keys);
+
+            rc
+        }
+    }
+
     pub fn set_ticket_key(&mut self, key: &[u8]) -> Result<()> {
         map_result(unsafe {
             SSL_CTX_set_tlsext_ticket_keys(
@@ -329,6 +501,34 @@ impl Context {
         }
     }
 
+    pub fn set_ocsp_response(&mut self, response: &[u8]) -> Result<()> {
+        map_result(unsafe {
+            SSL_CTX_set_ocsp_response(
+                self.as_mut_ptr(),
+                response.as_ptr(),
+                response.len(),
+            )
+        })
+    }
+
+    pub fn set_sct_list(&mut self, list: &[u8]) -> Result<()> {
+        map_result(unsafe {
+            SSL_CTX_set_signed_cert_timestamp_list(
+                self.as_mut_ptr(),
+                list.as_ptr(),
+                list.len(),
+            )
+        })
+    }
+
+    pub fn enable_ocsp_stapling(&mut self) {
+        unsafe { SSL_CTX_enable_ocsp_stapling(self.as_mut_ptr()) }
+    }
+
+    pub fn enable_signed_cert_timestamps(&mut self) {
+        unsafe { SSL_CTX_enable_signed_cert_timestamps(self.as_mut_ptr()) }
+    }
+
     fn as_mut_ptr(&mut self) -> *mut SSL_CTX {
         self.0
     }
@@ -339,8 +539,8 @@ impl Context {
 // should be its only owner), and there is no interior mutability, as the
 // pointer is not accessed directly outside of this module, and the Context
 // object API should preserve Rust's borrowing guarantees.
-unsafe impl std::marker::Send for Context {}
-unsafe impl std::marker::Sync for Context {}
+unsafe impl core::marker::Send for Context {}
+unsafe impl core::marker::Sync for Context {}
 
 impl Drop for Context {
     fn drop(&mut self) {
@@ -366,7 +566,7 @@ impl Handshake {
     ) -> Result<()> {
         self.set_state(is_server);
 
-        self.set_ex_data(*QUICHE_EX_DATA_INDEX, conn)?;
+        self.set_ex_data(quiche_ex_data_index(), conn)?;
 
         self.set_min_proto_version(TLS1_3_VERSION);
         self.set_max_proto_version(TLS1_3_VERSION);
@@ -439,7 +639,7 @@ impl Handshake {
     }
 
     pub fn set_host_name(&mut self, name: &str) -> Result<()> {
-        let cstr = ffi::CString::new(name).map_err(|_| Error::TlsFail)?;
+        let cstr = CString::new(name).map_err(|_| Error::TlsFail)?;
         let rc =
             unsafe { SSL_set_tlsext_host_name(self.as_mut_ptr(), cstr.as_ptr()) };
         map_result_ssl(self, rc)?;
@@ -506,7 +706,7 @@ impl Handshake {
                 0, // TLSEXT_NAMETYPE_host_name
             );
 
-            ffi::CStr::from_ptr(ptr)
+            CStr::from_ptr(ptr)
         };
 
         s.to_str().ok()
@@ -573,6 +773,22 @@ impl Handshake {
         get_cipher_from_ptr(cipher.ok()?).ok()
     }
 
+    pub fn cipher_name(&self) -> Option<String> {
+        let name = unsafe {
+            let cipher =
+                map_result_ptr(SSL_get_current_cipher(self.as_ptr())).ok()?;
+
+            let name = SSL_CIPHER_standard_name(cipher);
+            match CStr::from_ptr(name).to_str() {
+                Ok(v) => v,
+
+                Err(_) => return None,
+            }
+        };
+
+        Some(name.to_string())
+    }
+
     pub fn curve(&self) -> Option<String> {
         let curve = unsafe {
             let curve_id = SSL_get_curve_id(self.as_ptr());
@@ -581,7 +797,7 @@ impl Handshake {
             }
 
             let curve_name = SSL_get_curve_name(curve_id);
-            match ffi::CStr::from_ptr(curve_name).to_str() {
+            match CStr::from_ptr(curve_name).to_str() {
                 Ok(v) => v,
 
                 Err(_) => return None,
@@ -599,7 +815,7 @@ impl Handshake {
             }
 
             let sigalg_name = SSL_get_signature_algorithm_name(sigalg_id, 1);
-            match ffi::CStr::from_ptr(sigalg_name).to_str() {
+            match CStr::from_ptr(sigalg_name).to_str() {
                 Ok(v) => v,
 
                 Err(_) => return None,
@@ -633,6 +849,57 @@ impl Handshake {
         Some(peer_cert)
     }
 
+    pub fn peer_cert_chain(&self) -> Option<Vec<Vec<u8>>> {
+        let cert_chain = unsafe {
+            let chain =
+                map_result_ptr(SSL_get0_peer_certificates(self.as_ptr())).ok()?;
+
+            let num = sk_num(chain);
+            if num <= 0 {
+                return None;
+            }
+
+            let mut cert_chain = Vec::with_capacity(num as usize);
+
+            for i in 0..num {
+                let buffer =
+                    map_result_ptr(sk_value(chain, i) as *const CRYPTO_BUFFER)
+                        .ok()?;
+
+                let out_len = CRYPTO_BUFFER_len(buffer);
+                if out_len == 0 {
+                    return None;
+                }
+
+                let out = CRYPTO_BUFFER_data(buffer);
+                let der = slice::from_raw_parts(out, out_len);
+                cert_chain.push(der.to_vec());
+            }
+
+            cert_chain
+        };
+
+        Some(cert_chain)
+    }
+
+    pub fn export_keying_material(
+        &self, label: &[u8], context: &[u8], out: &mut [u8],
+    ) -> Result<()> {
+        let rc = unsafe {
+            SSL_export_keying_material(
+                self.0,
+                out.as_mut_ptr(),
+                out.len(),
+                label.as_ptr() as *const c_char,
+                label.len(),
+                context.as_ptr(),
+                context.len(),
+                1,
+            )
+        };
+        map_result(rc)
+    }
+
     pub fn is_completed(&self) -> bool {
         unsafe { SSL_in_init(self.as_ptr()) == 0 }
     }
@@ -641,10 +908,112 @@ impl Handshake {
         unsafe { SSL_session_reused(self.as_ptr()) == 1 }
     }
 
+    pub fn set_ech_config_list(&mut self, list: &[u8]) -> Result<()> {
+        let rc = unsafe {
+            SSL_set1_ech_config_list(self.as_mut_ptr(), list.as_ptr(), list.len())
+        };
+        map_result_ssl(self, rc)
+    }
+
+    pub fn is_ech_accepted(&self) -> bool {
+        unsafe { SSL_ech_accepted(self.as_ptr()) == 1 }
+    }
+
+    pub fn ocsp_response(&self) -> Option<Vec<u8>> {
+        let mut out: *const u8 = ptr::null();
+        let mut out_len: usize = 0;
+
+        unsafe {
+            SSL_get0_ocsp_response(self.as_ptr(), &mut out, &mut out_len);
+        }
+
+        if out.is_null() || out_len == 0 {
+            return None;
+        }
+
+        Some(unsafe { slice::from_raw_parts(out, out_len) }.to_vec())
+    }
+
+    pub fn sct_list(&self) -> Option<Vec<u8>> {
+        let mut out: *const u8 = ptr::null();
+        let mut out_len: usize = 0;
+
+        unsafe {
+            SSL_get0_signed_cert_timestamp_list(
+                self.as_ptr(),
+                &mut out,
+                &mut out_len,
+            );
+        }
+
+        if out.is_null() || out_len == 0 {
+            return None;
+        }
+
+        Some(unsafe { slice::from_raw_parts(out, out_len) }.to_vec())
+    }
+
+    pub fn add_application_settings(
+        &mut self, proto: &[u8], settings: &[u8],
+    ) -> Result<()> {
+        let rc = unsafe {
+            SSL_add_application_settings(
+                self.as_mut_ptr(),
+                proto.as_ptr(),
+                proto.len(),
+                settings.as_ptr(),
+                settings.len(),
+            )
+        };
+        map_result_ssl(self, rc)
+    }
+
+    pub fn peer_application_settings(&self) -> Option<&[u8]> {
+        let mut out: *const u8 = ptr::null();
+        let mut out_len: usize = 0;
+
+        unsafe {
+            if SSL_has_application_settings(self.as_ptr()) != 1 {
+                return None;
+            }
+
+            SSL_get0_peer_application_settings(
+                self.as_ptr(),
+                &mut out,
+                &mut out_len,
+            );
+        }
+
+        if out.is_null() {
+            return Some(&[]);
+        }
+
+        Some(unsafe { slice::from_raw_parts(out, out_len) })
+    }
+
+    pub fn ech_retry_configs(&self) -> Option<Vec<u8>> {
+        let mut out: *const u8 = ptr::null();
+        let mut out_len: usize = 0;
+
+        unsafe {
+            SSL_get0_ech_retry_configs(self.as_ptr(), &mut out, &mut out_len);
+        }
+
+        if out.is_null() || out_len == 0 {
+            return None;
+        }
+
+        Some(unsafe { slice::from_raw_parts(out, out_len) }.to_vec())
+    }
+
     pub fn is_in_early_data(&self) -> bool {
         unsafe { SSL_in_early_data(self.as_ptr()) == 1 }
     }
 
+    pub fn is_early_data_accepted(&self) -> bool {
+        unsafe { SSL_early_data_accepted(self.as_ptr()) == 1 }
+    }
+
     pub fn clear(&mut self) -> Result<()> {
         let rc = unsafe { SSL_clear(self.as_mut_ptr()) };
         map_result_ssl(self, rc)
@@ -664,8 +1033,8 @@ impl Handshake {
 // Handshake should be its only owner), and there is no interior mutability, as
 // the pointer is not accessed directly outside of this module, and the
 // Handshake object API should preserve Rust's borrowing guarantees.
-unsafe impl std::marker::Send for Handshake {}
-unsafe impl std::marker::Sync for Handshake {}
+unsafe impl core::marker::Send for Handshake {}
+unsafe impl core::marker::Sync for Handshake {}
 
 impl Drop for Handshake {
     fn drop(&mut self) {
@@ -698,7 +1067,7 @@ extern fn set_read_secret(
     secret: *const u8, secret_len: usize,
 ) -> c_int {
     let conn =
-        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+        match get_ex_data_from_ptr::<Connection>(ssl, quiche_ex_data_index()) {
             Some(v) => v,
 
             None => return 0,
@@ -726,18 +1095,27 @@ extern fn set_read_secret(
     if level != crypto::Level::ZeroRTT || conn.is_server {
         let secret = unsafe { slice::from_raw_parts(secret, secret_len) };
 
-        let open = match crypto::Open::from_secret(aead, secret) {
-            Ok(v) => v,
+        let open =
+            match crypto::Open::from_secret(&*conn.crypto_provider, aead, secret)
+            {
+                Ok(v) => v,
 
-            Err(_) => return 0,
-        };
+                Err(_) => return 0,
+            };
 
         if level == crypto::Level::ZeroRTT {
             space.crypto_0rtt_open = Some(open);
+
+            #[cfg(feature = "qlog")]
+            conn.qlog_key_updated(level, false);
+
             return 1;
         }
 
         space.crypto_open = Some(open);
+
+        #[cfg(feature = "qlog")]
+        conn.qlog_key_updated(level, false);
     }
 
     1
@@ -748,7 +1126,7 @@ extern fn set_write_secret(
     secret: *const u8, secret_len: usize,
 ) -> c_int {
     let conn =
-        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+        match get_ex_data_from_ptr::<Connection>(ssl, quiche_ex_data_index()) {
             Some(v) => v,
 
             None => return 0,
@@ -776,13 +1154,18 @@ extern fn set_write_secret(
     if level != crypto::Level::ZeroRTT || !conn.is_server {
         let secret = unsafe { slice::from_raw_parts(secret, secret_len) };
 
-        let seal = match crypto::Seal::from_secret(aead, secret) {
-            Ok(v) => v,
+        let seal =
+            match crypto::Seal::from_secret(&*conn.crypto_provider, aead, secret)
+            {
+                Ok(v) => v,
 
-            Err(_) => return 0,
-        };
+                Err(_) => return 0,
+            };
 
         space.crypto_seal = Some(seal);
+
+        #[cfg(feature = "qlog")]
+        conn.qlog_key_updated(level, true);
     }
 
     1
@@ -792,7 +1175,7 @@ extern fn add_handshake_data(
     ssl: *mut SSL, level: crypto::Level, data: *const u8, len: usize,
 ) -> c_int {
     let conn =
-        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+        match get_ex_data_from_ptr::<Connection>(ssl, quiche_ex_data_index()) {
             Some(v) => v,
 
             None => return 0,
@@ -832,7 +1215,7 @@ extern fn flush_flight(_ssl: *mut SSL) -> c_int {
 
 extern fn send_alert(ssl: *mut SSL, level: crypto::Level, alert: u8) -> c_int {
     let conn =
-        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+        match get_ex_data_from_ptr::<Connection>(ssl, quiche_ex_data_index()) {
             Some(v) => v,
 
             None => return 0,
@@ -845,26 +1228,52 @@ extern fn send_alert(ssl: *mut SSL, level: crypto::Level, alert: u8) -> c_int {
         alert
     );
 
+    // Explain certificate verification failures, as the alert alone doesn't
+    // say what was wrong with the certificate.
+    let reason = match alert {
+        SSL_AD_CERTIFICATE_REQUIRED =>
+            b"peer did not send a certificate".to_vec(),
+
+        // bad_certificate to certificate_unknown, and unknown_ca.
+        42..=46 | 48 => {
+            let verify_result = unsafe { SSL_get_verify_result(ssl) };
+
+            if verify_result != X509_V_OK {
+                let s = unsafe {
+                    CStr::from_ptr(X509_verify_cert_error_string(verify_result))
+                };
+
+                s.to_bytes().to_vec()
+            } else {
+                Vec::new()
+            }
+        },
+
+        _ => Vec::new(),
+    };
+
     let error: u64 = TLS_ALERT_ERROR + u64::from(alert);
     conn.local_error = Some(ConnectionError {
         is_app: false,
         error_code: error,
-        reason: Vec::new(),
+        reason,
+        frame_type: None,
     });
 
     1
 }
 
+#[cfg(feature = "std")]
 extern fn keylog(ssl: *mut SSL, line: *const c_char) {
     let conn =
-        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+        match get_ex_data_from_ptr::<Connection>(ssl, quiche_ex_data_index()) {
             Some(v) => v,
 
             None => return,
         };
 
     if let Some(keylog) = &mut conn.keylog {
-        let data = unsafe { ffi::CStr::from_ptr(line).to_bytes() };
+        let data = unsafe { CStr::from_ptr(line).to_bytes() };
 
         let mut full_line = Vec::with_capacity(data.len() + 1);
         full_line.extend_from_slice(data);
@@ -879,26 +1288,77 @@ extern fn select_alpn(
     in_len: c_uint, _arg: *mut c_void,
 ) -> c_int {
     let conn =
-        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+        match get_ex_data_from_ptr::<Connection>(ssl, quiche_ex_data_index()) {
             Some(v) => v,
 
             None => return 3, // SSL_TLSEXT_ERR_NOACK
         };
 
+    let inp = unsafe { slice::from_raw_parts(inp, in_len as usize) };
+
+    conn.peer_application_protos = inp.to_vec();
+
+    if let Some(selector) = conn.alpn_selector.clone() {
+        let mut offered = Vec::new();
+
+        let mut protos = octets::Octets::with_slice(inp);
+
+        loop {
+            let off = protos.off();
+
+            if protos.get_bytes_with_u8_length().is_err() {
+                break;
+            }
+
+            offered.push(&inp[off + 1..protos.off()]);
+        }
+
+        let server_name = unsafe {
+            let ptr = SSL_get_servername(
+                ssl, 0, // TLSEXT_NAMETYPE_host_name
+            );
+
+            if ptr.is_null() {
+                None
+            } else {
+                CStr::from_ptr(ptr).to_str().ok()
+            }
+        };
+
+        let selected = match selector.select(server_name, &offered) {
+            Some(v) => v,
+
+            None => return 3, // SSL_TLSEXT_ERR_NOACK
+        };
+
+        // Only accept protocols the client actually offered, and point
+        // BoringSSL to the copy in the ClientHello, which outlives the call.
+        return match offered.iter().find(|proto| **proto == selected) {
+            Some(proto) => {
+                unsafe {
+                    *out = proto.as_ptr();
+                    *out_len = proto.len() as u8;
+                }
+
+                0 // SSL_TLSEXT_ERR_OK
+            },
+
+            None => 3, // SSL_TLSEXT_ERR_NOACK
+        };
+    }
+
     if conn.application_protos.is_empty() {
         return 3; // SSL_TLSEXT_ERR_NOACK
     }
 
-    let mut protos = octets::Octets::with_slice(unsafe {
-        slice::from_raw_parts(inp, in_len as usize)
-    });
+    let mut protos = octets::Octets::with_slice(inp);
 
     while let Ok(proto) = protos.get_bytes_with_u8_length() {
         let found = conn.application_protos.iter().any(|expected| {
             trace!(
                 "checking peer ALPN {:?} against {:?}",
-                std::str::from_utf8(proto.as_ref()),
-                std::str::from_utf8(expected.as_slice())
+                core::str::from_utf8(proto.as_ref()),
+                core::str::from_utf8(expected.as_slice())
             );
 
             if expected.len() == proto.len() &&
@@ -923,10 +1383,53 @@ extern fn select_alpn(
     3 // SSL_TLSEXT_ERR_NOACK
 }
 
+extern fn select_server_cert(
+    ssl: *mut SSL, _alert: *mut c_int, _arg: *mut c_void,
+) -> c_int {
+    let conn =
+        match get_ex_data_from_ptr::<Connection>(ssl, quiche_ex_data_index()) {
+            Some(v) => v,
+
+            None => return SSL_TLSEXT_ERR_ALERT_FATAL,
+        };
+
+    let name = unsafe {
+        let ptr = SSL_get_servername(
+            ssl, 0, // TLSEXT_NAMETYPE_host_name
+        );
+
+        if ptr.is_null() {
+            return SSL_TLSEXT_ERR_OK;
+        }
+
+        match CStr::from_ptr(ptr).to_str() {
+            Ok(v) => v.to_ascii_lowercase(),
+
+            Err(_) => return SSL_TLSEXT_ERR_OK,
+        }
+    };
+
+    let wildcard = name.find('.').map(|i| format!("*{}", &name[i..]));
+
+    let ctx = conn.server_certs.get(&name).or_else(|| {
+        wildcard.and_then(|wildcard| conn.server_certs.get(&wildcard))
+    });
+
+    if let Some(ctx) = ctx {
+        trace!("{} using certificate for {}", conn.trace_id, name);
+
+        unsafe {
+            SSL_set_SSL_CTX(ssl, ctx.0);
+        }
+    }
+
+    SSL_TLSEXT_ERR_OK
+}
+
 #[no_mangle]
 extern fn new_session(ssl: *mut SSL, session: *mut SSL_SESSION) -> c_int {
     let conn =
-        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+        match get_ex_data_from_ptr::<Connection>(ssl, quiche_ex_data_index()) {
             Some(v) => v,
 
             None => return 0,
@@ -937,14 +1440,14 @@ extern fn new_session(ssl: *mut SSL, session: *mut SSL_SESSION) -> c_int {
 
     // Serialize session object into buffer.
     let session_bytes = unsafe {
-        let mut out: *mut u8 = std::ptr::null_mut();
+        let mut out: *mut u8 = core::ptr::null_mut();
         let mut out_len: usize = 0;
 
         if SSL_SESSION_to_bytes(session, &mut out, &mut out_len) == 0 {
             return 0;
         }
 
-        let session_bytes = std::slice::from_raw_parts(out, out_len).to_vec();
+        let session_bytes = core::slice::from_raw_parts(out, out_len).to_vec();
         OPENSSL_free(out as *mut c_void);
 
         session_bytes
@@ -955,32 +1458,18 @@ extern fn new_session(ssl: *mut SSL, session: *mut SSL_SESSION) -> c_int {
 
     let session_bytes_len = session_bytes.len() as u64;
 
-    if buffer.write(&session_bytes_len.to_be_bytes()).is_err() {
-        std::mem::forget(handshake);
-        return 0;
-    }
-
-    if buffer.write(&session_bytes).is_err() {
-        std::mem::forget(handshake);
-        return 0;
-    }
+    buffer.extend_from_slice(&session_bytes_len.to_be_bytes());
+    buffer.extend_from_slice(&session_bytes);
 
     let peer_params_len = peer_params.len() as u64;
 
-    if buffer.write(&peer_params_len.to_be_bytes()).is_err() {
-        std::mem::forget(handshake);
-        return 0;
-    }
-
-    if buffer.write(peer_params).is_err() {
-        std::mem::forget(handshake);
-        return 0;
-    }
+    buffer.extend_from_slice(&peer_params_len.to_be_bytes());
+    buffer.extend_from_slice(peer_params);
 
     conn.session = Some(buffer);
 
     // Prevent handshake from being freed, as we still need it.
-    std::mem::forget(handshake);
+    core::mem::forget(handshake);
 
     0
 }
@@ -1067,7 +1556,7 @@ fn log_ssl_error() {
         ERR_error_string_n(e, err.as_ptr(), err.len());
     }
 
-    trace!("{}", std::str::from_utf8(&err).unwrap());
+    trace!("{}", core::str::from_utf8(&err).unwrap());
 }
 
 extern {
@@ -1098,6 +1587,9 @@ extern {
 
     fn SSL_CTX_set_verify(ctx: *mut SSL_CTX, mode: c_int, cb: *const c_void);
 
+    fn SSL_CTX_get_verify_mode(ctx: *const SSL_CTX) -> c_int;
+
+    #[cfg(feature = "std")]
     fn SSL_CTX_set_keylog_callback(
         ctx: *mut SSL_CTX, cb: extern fn(ssl: *mut SSL, line: *const c_char),
     );
@@ -1123,8 +1615,29 @@ extern {
         arg: *mut c_void,
     );
 
+    fn SSL_CTX_set_tlsext_servername_callback(
+        ctx: *mut SSL_CTX,
+        cb: extern fn(
+            ssl: *mut SSL,
+            alert: *mut c_int,
+            arg: *mut c_void,
+        ) -> c_int,
+    ) -> c_int;
+
     fn SSL_CTX_set_early_data_enabled(ctx: *mut SSL_CTX, enabled: i32);
 
+    fn SSL_CTX_set_ocsp_response(
+        ctx: *mut SSL_CTX, response: *const u8, response_len: usize,
+    ) -> c_int;
+
+    fn SSL_CTX_set_signed_cert_timestamp_list(
+        ctx: *mut SSL_CTX, list: *const u8, list_len: usize,
+    ) -> c_int;
+
+    fn SSL_CTX_enable_ocsp_stapling(ctx: *mut SSL_CTX);
+
+    fn SSL_CTX_enable_signed_cert_timestamps(ctx: *mut SSL_CTX);
+
     fn SSL_CTX_set_session_cache_mode(ctx: *mut SSL_CTX, mode: c_int) -> c_int;
 
     fn SSL_CTX_sess_set_new_cb(
@@ -1140,6 +1653,10 @@ extern {
 
     fn SSL_new(ctx: *mut SSL_CTX) -> *mut SSL;
 
+    fn SSL_set_SSL_CTX(ssl: *mut SSL, ctx: *mut SSL_CTX) -> *mut SSL_CTX;
+
+    fn SSL_get_verify_result(ssl: *const SSL) -> c_long;
+
     fn SSL_get_error(ssl: *const SSL, ret_code: c_int) -> c_int;
 
     fn SSL_set_accept_state(ssl: *mut SSL);
@@ -1214,10 +1731,71 @@ extern {
 
     fn SSL_session_reused(ssl: *const SSL) -> c_int;
 
+    fn SSL_export_keying_material(
+        ssl: *mut SSL, out: *mut u8, out_len: usize, label: *const c_char,
+        label_len: usize, context: *const u8, context_len: usize,
+        use_context: c_int,
+    ) -> c_int;
+
+    fn SSL_set1_ech_config_list(
+        ssl: *mut SSL, ech_config_list: *const u8, ech_config_list_len: usize,
+    ) -> c_int;
+
+    fn SSL_ech_accepted(ssl: *const SSL) -> c_int;
+
+    fn SSL_get0_ech_retry_configs(
+        ssl: *const SSL, out_retry_configs: *mut *const u8,
+        out_retry_configs_len: *mut usize,
+    );
+
+    fn SSL_add_application_settings(
+        ssl: *mut SSL, proto: *const u8, proto_len: usize, settings: *const u8,
+        settings_len: usize,
+    ) -> c_int;
+
+    fn SSL_has_application_settings(ssl: *const SSL) -> c_int;
+
+    fn SSL_get0_ocsp_response(
+        ssl: *const SSL, out: *mut *const u8, out_len: *mut usize,
+    );
+
+    fn SSL_get0_signed_cert_timestamp_list(
+        ssl: *const SSL, out: *mut *const u8, out_len: *mut usize,
+    );
+
+    fn SSL_get0_peer_application_settings(
+        ssl: *const SSL, out_data: *mut *const u8, out_len: *mut usize,
+    );
+
+    // SSL_ECH_KEYS
+    fn SSL_ECH_KEYS_new() -> *mut SSL_ECH_KEYS;
+    fn SSL_ECH_KEYS_free(keys: *mut SSL_ECH_KEYS);
+
+    fn SSL_ECH_KEYS_add(
+        keys: *mut SSL_ECH_KEYS, is_retry_config: c_int, ech_config: *const u8,
+        ech_config_len: usize, key: *const EVP_HPKE_KEY,
+    ) -> c_int;
+
+    fn SSL_CTX_set1_ech_keys(ctx: *mut SSL_CTX, keys: *mut SSL_ECH_KEYS)
+        -> c_int;
+
+    // EVP_HPKE
+    fn EVP_hpke_x25519_hkdf_sha256() -> *const EVP_HPKE_KEM;
+
+    fn EVP_HPKE_KEY_new() -> *mut EVP_HPKE_KEY;
+    fn EVP_HPKE_KEY_free(key: *mut EVP_HPKE_KEY);
+
+    fn EVP_HPKE_KEY_init(
+        key: *mut EVP_HPKE_KEY, kem: *const EVP_HPKE_KEM, priv_key: *const u8,
+        priv_key_len: usize,
+    ) -> c_int;
+
     fn SSL_in_init(ssl: *const SSL) -> c_int;
 
     fn SSL_in_early_data(ssl: *const SSL) -> c_int;
 
+    fn SSL_early_data_accepted(ssl: *const SSL) -> c_int;
+
     fn SSL_clear(ssl: *mut SSL) -> c_int;
 
     fn SSL_free(ssl: *mut SSL);
@@ -1225,6 +1803,8 @@ extern {
     // SSL_CIPHER
     fn SSL_CIPHER_get_id(cipher: *const SSL_CIPHER) -> c_uint;
 
+    fn SSL_CIPHER_standard_name(cipher: *const SSL_CIPHER) -> *const c_char;
+
     // SSL_SESSION
     fn SSL_SESSION_to_bytes(
         session: *const SSL_SESSION, out: *mut *mut u8, out_len: *mut usize,
@@ -1251,6 +1831,8 @@ extern {
     #[cfg(windows)]
     fn d2i_X509(px: *mut X509, input: *const *const u8, len: c_int) -> *mut X509;
 
+    fn X509_verify_cert_error_string(err: c_long) -> *const c_char;
+
     // STACK_OF
     fn sk_num(stack: *const STACK_OF) -> c_int;
     fn sk_value(stack: *const STACK_OF, idx: c_int) -> *mut c_void;
@@ -0,0 +1,1022 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! TLS backend based on rustls, used in place of BoringSSL when the `rustls`
+//! feature is enabled.
+//!
+//! Session resumption, 0-RTT and Encrypted Client Hello are not supported.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::net;
+use std::time;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use rustls::quic::ClientQuicExt;
+use rustls::quic::QuicExt;
+use rustls::quic::ServerQuicExt;
+
+use crate::Error;
+use crate::Result;
+
+use crate::Connection;
+use crate::ConnectionError;
+
+use crate::crypto;
+use crate::packet;
+
+const TLS_ALERT_ERROR: u64 = 0x100;
+
+/// Certificates keyed by the lowercase server name they are used for.
+pub type ServerCerts = HashMap<String, Arc<Context>>;
+
+pub struct Context {
+    roots: rustls::RootCertStore,
+
+    // Whether the default CA certificates were added to `roots` already.
+    has_default_roots: bool,
+
+    // As with BoringSSL's SSL_VERIFY_NONE default, certificate errors are not
+    // fatal unless verification is enabled.
+    verify: bool,
+
+    require_peer_cert: bool,
+
+    // Built on demand, and reset whenever the settings they depend on change.
+    server_verifier: Option<Arc<dyn rustls::client::ServerCertVerifier>>,
+    client_verifier: Option<Arc<dyn rustls::server::ClientCertVerifier>>,
+
+    cert_chain: Vec<rustls::Certificate>,
+
+    key: Option<rustls::PrivateKey>,
+
+    certified_key: Option<Arc<rustls::sign::CertifiedKey>>,
+
+    // Stapled to the server's certificate.
+    ocsp_response: Option<Vec<u8>>,
+    sct_list: Option<Vec<u8>>,
+
+    // Whether clients request signed certificate timestamps. OCSP responses
+    // are always requested by rustls.
+    request_scts: bool,
+
+    keylog: bool,
+}
+
+impl Context {
+    pub fn new() -> Result<Context> {
+        Ok(Context {
+            roots: rustls::RootCertStore::empty(),
+            has_default_roots: false,
+            verify: false,
+            require_peer_cert: false,
+            server_verifier: None,
+            client_verifier: None,
+            cert_chain: Vec::new(),
+            key: None,
+            certified_key: None,
+            ocsp_response: None,
+            sct_list: None,
+            request_scts: false,
+            keylog: false,
+        })
+    }
+
+    pub fn new_handshake(&mut self) -> Result<Handshake> {
+        let cert_status = Arc::new(Mutex::new(CertStatus::default()));
+
+        let server_verifier = Arc::new(CertStatusRecorder {
+            verifier: self.server_verifier()?,
+            request_scts: self.request_scts,
+            status: cert_status.clone(),
+        });
+
+        Ok(Handshake {
+            conn: std::ptr::null(),
+            is_server: false,
+            server_verifier,
+            client_verifier: self.client_verifier(),
+            cert_chain: self.cert_chain.clone(),
+            key: self.key.clone(),
+            certified_key: self.certified_key.clone(),
+            keylog: self.keylog,
+            quic_version: rustls::quic::Version::V1,
+            host_name: None,
+            transport_params: Vec::new(),
+            peer_alpn: Arc::new(Mutex::new(Vec::new())),
+            cert_status,
+            tls: None,
+            write_level: crypto::Level::Initial,
+            pending_open: None,
+        })
+    }
+
+    pub fn load_verify_locations_from_file(&mut self, file: &str) -> Result<()> {
+        for cert in load_certs(file)? {
+            self.roots.add(&cert).map_err(|_| Error::TlsFail)?;
+        }
+
+        self.reset_verifiers();
+
+        Ok(())
+    }
+
+    pub fn load_verify_locations_from_directory(
+        &mut self, path: &str,
+    ) -> Result<()> {
+        let dir = fs::read_dir(path).map_err(|_| Error::TlsFail)?;
+
+        // Unlike BoringSSL, every file in the directory is loaded, not just
+        // the ones named after the certificate's subject hash.
+        for entry in dir {
+            let path = entry.map_err(|_| Error::TlsFail)?.path();
+
+            if !path.is_file() {
+                continue;
+            }
+
+            let certs = match path.to_str().map(load_certs) {
+                Some(Ok(v)) => v,
+
+                _ => continue,
+            };
+
+            for cert in certs {
+                self.roots.add(&cert).ok();
+            }
+        }
+
+        self.reset_verifiers();
+
+        Ok(())
+    }
+
+    pub fn use_certificate_chain_file(&mut self, file: &str) -> Result<()> {
+        let certs = load_certs(file)?;
+
+        if certs.is_empty() {
+            return Err(Error::TlsFail);
+        }
+
+        self.cert_chain = certs;
+        self.update_certified_key();
+
+        Ok(())
+    }
+
+    pub fn use_privkey_file(&mut self, file: &str) -> Result<()> {
+        let file = fs::File::open(file).map_err(|_| Error::TlsFail)?;
+        let mut reader = io::BufReader::new(file);
+
+        let key = loop {
+            match rustls_pemfile::read_one(&mut reader) {
+                Ok(Some(rustls_pemfile::Item::PKCS8Key(key))) |
+                Ok(Some(rustls_pemfile::Item::RSAKey(key))) |
+                Ok(Some(rustls_pemfile::Item::ECKey(key))) => break key,
+
+                Ok(Some(_)) => continue,
+
+                _ => return Err(Error::TlsFail),
+            }
+        };
+
+        let key = rustls::PrivateKey(key);
+
+        // Make sure the key is actually usable.
+        rustls::sign::any_supported_type(&key).map_err(|_| Error::TlsFail)?;
+
+        self.key = Some(key);
+        self.update_certified_key();
+
+        Ok(())
+    }
+
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+        self.reset_verifiers();
+    }
+
+    pub fn set_require_peer_cert(&mut self, require: bool) {
+        self.require_peer_cert = require;
+        self.reset_verifiers();
+    }
+
+    pub fn enable_keylog(&mut self) {
+        self.keylog = true;
+    }
+
+    pub fn set_alpn(&mut self, _v: &[Vec<u8>]) -> Result<()> {
+        // The connection's application protocols are used directly when the
+        // handshake starts.
+        Ok(())
+    }
+
+    pub fn enable_server_name_selection(&mut self) {
+        // Server certificates are always selected based on the server name,
+        // see `ServerCertResolver`.
+    }
+
+    pub fn set_early_data_enabled(&mut self, _enabled: bool) {
+        // 0-RTT is not supported, so early data is never sent nor accepted.
+    }
+
+    pub fn set_ocsp_response(&mut self, response: &[u8]) -> Result<()> {
+        self.ocsp_response = Some(response.to_vec());
+        self.update_certified_key();
+
+        Ok(())
+    }
+
+    pub fn set_sct_list(&mut self, list: &[u8]) -> Result<()> {
+        self.sct_list = Some(list.to_vec());
+        self.update_certified_key();
+
+        Ok(())
+    }
+
+    pub fn enable_ocsp_stapling(&mut self) {
+        // OCSP responses are always requested.
+    }
+
+    pub fn enable_signed_cert_timestamps(&mut self) {
+        self.request_scts = true;
+    }
+
+    fn update_certified_key(&mut self) {
+        self.certified_key = match &self.key {
+            Some(key) if !self.cert_chain.is_empty() =>
+                rustls::sign::any_supported_type(key).ok().map(|key| {
+                    let chain = self.cert_chain.clone();
+
+                    let mut key = rustls::sign::CertifiedKey::new(chain, key);
+                    key.ocsp = self.ocsp_response.clone();
+                    key.sct_list = self.sct_list.clone();
+
+                    Arc::new(key)
+                }),
+
+            _ => None,
+        };
+    }
+
+    fn reset_verifiers(&mut self) {
+        self.server_verifier = None;
+        self.client_verifier = None;
+    }
+
+    fn server_verifier(
+        &mut self,
+    ) -> Result<Arc<dyn rustls::client::ServerCertVerifier>> {
+        if let Some(verifier) = &self.server_verifier {
+            return Ok(verifier.clone());
+        }
+
+        let verifier: Arc<dyn rustls::client::ServerCertVerifier> = if self.verify
+        {
+            self.load_default_roots();
+
+            Arc::new(rustls::client::WebPkiVerifier::new(
+                self.roots.clone(),
+                None,
+            ))
+        } else {
+            Arc::new(NoServerCertVerifier)
+        };
+
+        self.server_verifier = Some(verifier.clone());
+
+        Ok(verifier)
+    }
+
+    fn client_verifier(&mut self) -> Arc<dyn rustls::server::ClientCertVerifier> {
+        if let Some(verifier) = &self.client_verifier {
+            return verifier.clone();
+        }
+
+        let verifier = if self.require_peer_cert {
+            rustls::server::AllowAnyAuthenticatedClient::new(self.roots.clone())
+        } else if self.verify {
+            rustls::server::AllowAnyAnonymousOrAuthenticatedClient::new(
+                self.roots.clone(),
+            )
+        } else {
+            rustls::server::NoClientAuth::new()
+        };
+
+        self.client_verifier = Some(verifier.clone());
+
+        verifier
+    }
+
+    // The equivalent of BoringSSL's default verify paths. This is done lazily
+    // as parsing the system's certificates is expensive, and pointless for
+    // servers and clients that don't verify the peer.
+    fn load_default_roots(&mut self) {
+        if self.has_default_roots {
+            return;
+        }
+
+        if let Ok(certs) = rustls_native_certs::load_native_certs() {
+            let certs: Vec<Vec<u8>> = certs.into_iter().map(|c| c.0).collect();
+            self.roots.add_parsable_certificates(&certs);
+        }
+
+        self.has_default_roots = true;
+    }
+}
+
+pub struct Handshake {
+    conn: *const Connection,
+
+    is_server: bool,
+
+    server_verifier: Arc<dyn rustls::client::ServerCertVerifier>,
+
+    client_verifier: Arc<dyn rustls::server::ClientCertVerifier>,
+
+    cert_chain: Vec<rustls::Certificate>,
+
+    key: Option<rustls::PrivateKey>,
+
+    certified_key: Option<Arc<rustls::sign::CertifiedKey>>,
+
+    keylog: bool,
+
+    quic_version: rustls::quic::Version,
+
+    host_name: Option<String>,
+
+    transport_params: Vec<u8>,
+
+    // The ALPN protocols offered by the client in wire format, as seen by the
+    // server's certificate resolver.
+    peer_alpn: Arc<Mutex<Vec<u8>>>,
+
+    // The status information stapled to the server's certificate, as seen by
+    // the client's certificate verifier.
+    cert_status: Arc<Mutex<CertStatus>>,
+
+    // Created when the handshake starts, as the transport parameters and
+    // server name need to be known by then.
+    tls: Option<rustls::Connection>,
+
+    write_level: crypto::Level,
+
+    // rustls hands out the server's 1-RTT read keys together with the write
+    // ones, but they must not be used before the handshake completes.
+    pending_open: Option<crypto::Open>,
+}
+
+impl Handshake {
+    pub fn init(
+        &mut self, conn: *const Connection, is_server: bool,
+    ) -> Result<()> {
+        self.conn = conn;
+        self.is_server = is_server;
+
+        Ok(())
+    }
+
+    pub fn use_legacy_codepoint(&mut self, use_legacy: bool) {
+        self.quic_version = if use_legacy {
+            rustls::quic::Version::V1Draft
+        } else {
+            rustls::quic::Version::V1
+        };
+    }
+
+    pub fn set_host_name(&mut self, name: &str) -> Result<()> {
+        rustls::ServerName::try_from(name).map_err(|_| Error::TlsFail)?;
+
+        self.host_name = Some(name.to_string());
+
+        Ok(())
+    }
+
+    pub fn set_quic_transport_params(&mut self, buf: &[u8]) -> Result<()> {
+        self.transport_params = buf.to_vec();
+
+        Ok(())
+    }
+
+    pub fn quic_transport_params(&self) -> &[u8] {
+        self.tls
+            .as_ref()
+            .and_then(|tls| tls.quic_transport_parameters())
+            .unwrap_or(&[])
+    }
+
+    pub fn alpn_protocol(&self) -> &[u8] {
+        self.tls
+            .as_ref()
+            .and_then(|tls| tls.alpn_protocol())
+            .unwrap_or(&[])
+    }
+
+    pub fn server_name(&self) -> Option<&str> {
+        match &self.tls {
+            Some(rustls::Connection::Server(tls)) => tls.sni_hostname(),
+
+            _ => self.host_name.as_deref(),
+        }
+    }
+
+    pub fn provide_data(
+        &mut self, _level: crypto::Level, buf: &[u8],
+    ) -> Result<()> {
+        let conn = unsafe { &mut *(self.conn as *mut Connection) };
+
+        self.start(conn)?;
+
+        let tls = self.tls.as_mut().ok_or(Error::TlsFail)?;
+
+        if let Err(e) = tls.read_hs(buf) {
+            trace!("{} handshake failed: {}", conn.trace_id, e);
+
+            if let Some(alert) = tls.alert() {
+                conn.local_error = Some(ConnectionError {
+                    is_app: false,
+                    error_code: TLS_ALERT_ERROR + u64::from(alert.get_u8()),
+                    reason: e.to_string().into_bytes(),
+                    frame_type: None,
+                });
+            }
+
+            return Err(Error::TlsFail);
+        }
+
+        Ok(())
+    }
+
+    pub fn do_handshake(&mut self) -> Result<()> {
+        let conn = unsafe { &mut *(self.conn as *mut Connection) };
+
+        // The server's transport parameters are only final once the client's
+        // first packet is received, so don't start before that.
+        if self.is_server && self.tls.is_none() {
+            return Err(Error::Done);
+        }
+
+        self.start(conn)?;
+        self.write_handshake_data(conn)?;
+
+        if !self.is_completed() {
+            return Err(Error::Done);
+        }
+
+        Ok(())
+    }
+
+    pub fn process_post_handshake(&mut self) -> Result<()> {
+        let conn = unsafe { &mut *(self.conn as *mut Connection) };
+
+        self.write_handshake_data(conn)
+    }
+
+    pub fn write_level(&self) -> crypto::Level {
+        self.write_level
+    }
+
+    pub fn cipher(&self) -> Option<crypto::Algorithm> {
+        let suite = self.tls.as_ref()?.negotiated_cipher_suite()?;
+
+        let alg = match suite.suite() {
+            rustls::CipherSuite::TLS13_AES_128_GCM_SHA256 =>
+                crypto::Algorithm::AES128_GCM,
+
+            rustls::CipherSuite::TLS13_AES_256_GCM_SHA384 =>
+                crypto::Algorithm::AES256_GCM,
+
+            rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256 =>
+                crypto::Algorithm::ChaCha20_Poly1305,
+
+            _ => return None,
+        };
+
+        Some(alg)
+    }
+
+    pub fn cipher_name(&self) -> Option<String> {
+        let name = match self.cipher()? {
+            crypto::Algorithm::AES128_GCM => "TLS_AES_128_GCM_SHA256",
+
+            crypto::Algorithm::AES256_GCM => "TLS_AES_256_GCM_SHA384",
+
+            crypto::Algorithm::ChaCha20_Poly1305 =>
+                "TLS_CHACHA20_POLY1305_SHA256",
+        };
+
+        Some(name.to_string())
+    }
+
+    pub fn curve(&self) -> Option<String> {
+        // Not exposed by rustls.
+        None
+    }
+
+    pub fn sigalg(&self) -> Option<String> {
+        // Not exposed by rustls.
+        None
+    }
+
+    pub fn peer_cert(&self) -> Option<Vec<u8>> {
+        let certs = self.tls.as_ref()?.peer_certificates()?;
+
+        certs.first().map(|cert| cert.0.clone())
+    }
+
+    pub fn peer_cert_chain(&self) -> Option<Vec<Vec<u8>>> {
+        let certs = self.tls.as_ref()?.peer_certificates()?;
+
+        Some(certs.iter().map(|cert| cert.0.clone()).collect())
+    }
+
+    pub fn export_keying_material(
+        &self, label: &[u8], context: &[u8], out: &mut [u8],
+    ) -> Result<()> {
+        let tls = self.tls.as_ref().ok_or(Error::TlsFail)?;
+
+        let res = match tls {
+            rustls::Connection::Client(c) =>
+                c.export_keying_material(out, label, Some(context)),
+
+            rustls::Connection::Server(c) =>
+                c.export_keying_material(out, label, Some(context)),
+        };
+
+        res.map_err(|_| Error::TlsFail)
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.tls.as_ref().map_or(false, |tls| !tls.is_handshaking())
+    }
+
+    pub fn ocsp_response(&self) -> Option<Vec<u8>> {
+        self.cert_status.lock().unwrap().ocsp_response.clone()
+    }
+
+    pub fn sct_list(&self) -> Option<Vec<u8>> {
+        self.cert_status.lock().unwrap().sct_list.clone()
+    }
+
+    // Session resumption, 0-RTT, ECH and ALPS can't be enabled with this
+    // backend, so the following always describe a full handshake without
+    // them.
+
+    pub fn is_resumed(&self) -> bool {
+        false
+    }
+
+    pub fn is_ech_accepted(&self) -> bool {
+        false
+    }
+
+    pub fn peer_application_settings(&self) -> Option<&[u8]> {
+        None
+    }
+
+    pub fn ech_retry_configs(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    pub fn is_in_early_data(&self) -> bool {
+        false
+    }
+
+    pub fn is_early_data_accepted(&self) -> bool {
+        false
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.tls = None;
+        self.write_level = crypto::Level::Initial;
+        self.pending_open = None;
+
+        Ok(())
+    }
+
+    fn start(&mut self, conn: &Connection) -> Result<()> {
+        if self.tls.is_some() {
+            return Ok(());
+        }
+
+        let key_log: Arc<dyn rustls::KeyLog> = if self.keylog {
+            Arc::new(KeyLogger(self.conn))
+        } else {
+            Arc::new(rustls::NoKeyLog)
+        };
+
+        let params = self.transport_params.clone();
+
+        let tls = if self.is_server {
+            let resolver = ServerCertResolver {
+                default: self.certified_key.clone(),
+                server_certs: conn.server_certs.clone(),
+                peer_alpn: self.peer_alpn.clone(),
+            };
+
+            let mut config = rustls::ServerConfig::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .map_err(|_| Error::TlsFail)?
+                .with_client_cert_verifier(self.client_verifier.clone())
+                .with_cert_resolver(Arc::new(resolver));
+
+            config.alpn_protocols = conn.application_protos.clone();
+            config.key_log = key_log;
+
+            let tls = rustls::ServerConnection::new_quic(
+                Arc::new(config),
+                self.quic_version,
+                params,
+            )
+            .map_err(|_| Error::TlsFail)?;
+
+            rustls::Connection::Server(tls)
+        } else {
+            let builder = rustls::ClientConfig::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .map_err(|_| Error::TlsFail)?
+                .with_custom_certificate_verifier(self.server_verifier.clone());
+
+            let mut config = match &self.key {
+                Some(key) if !self.cert_chain.is_empty() => builder
+                    .with_single_cert(self.cert_chain.clone(), key.clone())
+                    .map_err(|_| Error::TlsFail)?,
+
+                _ => builder.with_no_client_auth(),
+            };
+
+            config.alpn_protocols = conn.application_protos.clone();
+            config.key_log = key_log;
+
+            // rustls always needs a name to verify the server's certificate
+            // against, so use a placeholder that is never sent when the
+            // application didn't provide one.
+            let name = match &self.host_name {
+                Some(v) => rustls::ServerName::try_from(v.as_str())
+                    .map_err(|_| Error::TlsFail)?,
+
+                None => {
+                    config.enable_sni = false;
+
+                    rustls::ServerName::IpAddress(
+                        net::Ipv4Addr::UNSPECIFIED.into(),
+                    )
+                },
+            };
+
+            let tls = rustls::ClientConnection::new_quic(
+                Arc::new(config),
+                self.quic_version,
+                name,
+                params,
+            )
+            .map_err(|_| Error::TlsFail)?;
+
+            rustls::Connection::Client(tls)
+        };
+
+        self.tls = Some(tls);
+
+        Ok(())
+    }
+
+    // Moves the handshake data generated by rustls to the crypto streams, and
+    // installs new packet protection keys as they become available.
+    fn write_handshake_data(&mut self, conn: &mut Connection) -> Result<()> {
+        let tls = match self.tls.as_mut() {
+            Some(v) => v,
+
+            None => return Ok(()),
+        };
+
+        if self.is_server && conn.peer_application_protos.is_empty() {
+            conn.peer_application_protos = self.peer_alpn.lock().unwrap().clone();
+        }
+
+        loop {
+            let mut buf = Vec::new();
+
+            let key_change = tls.write_hs(&mut buf);
+
+            if !buf.is_empty() {
+                trace!(
+                    "{} write message lvl={:?} len={}",
+                    conn.trace_id,
+                    self.write_level,
+                    buf.len()
+                );
+
+                let epoch = epoch_from_level(self.write_level);
+
+                let space = &mut conn.pkt_num_spaces[epoch];
+
+                space.crypto_stream.send.write(&buf, false)?;
+            }
+
+            let (level, keys) = match key_change {
+                Some(rustls::quic::KeyChange::Handshake { keys }) =>
+                    (crypto::Level::Handshake, keys),
+
+                Some(rustls::quic::KeyChange::OneRtt { keys, .. }) =>
+                    (crypto::Level::OneRTT, keys),
+
+                None => break,
+            };
+
+            let aead = match tls.negotiated_cipher_suite().map(|s| s.suite()) {
+                Some(rustls::CipherSuite::TLS13_AES_128_GCM_SHA256) =>
+                    crypto::Algorithm::AES128_GCM,
+
+                Some(rustls::CipherSuite::TLS13_AES_256_GCM_SHA384) =>
+                    crypto::Algorithm::AES256_GCM,
+
+                Some(rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256) =>
+                    crypto::Algorithm::ChaCha20_Poly1305,
+
+                _ => return Err(Error::TlsFail),
+            };
+
+            trace!("{} set secrets lvl={:?}", conn.trace_id, level);
+
+            let space = &mut conn.pkt_num_spaces[epoch_from_level(level)];
+
+            let open = crypto::Open::from_rustls(aead, keys.remote);
+
+            if self.is_server && level == crypto::Level::OneRTT {
+                self.pending_open = Some(open);
+            } else {
+                space.crypto_open = Some(open);
+
+                #[cfg(feature = "qlog")]
+                conn.qlog_key_updated(level, false);
+            }
+
+            let space = &mut conn.pkt_num_spaces[epoch_from_level(level)];
+
+            space.crypto_seal = Some(crypto::Seal::from_rustls(aead, keys.local));
+
+            #[cfg(feature = "qlog")]
+            conn.qlog_key_updated(level, true);
+
+            self.write_level = level;
+        }
+
+        if !tls.is_handshaking() {
+            if let Some(open) = self.pending_open.take() {
+                let space = &mut conn.pkt_num_spaces[packet::EPOCH_APPLICATION];
+
+                space.crypto_open = Some(open);
+
+                #[cfg(feature = "qlog")]
+                conn.qlog_key_updated(crypto::Level::OneRTT, false);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// NOTE: These traits are not automatically implemented for Handshake due to the
+// raw pointer to the connection it holds. However, the pointer is only used
+// while the connection itself is being accessed, in the same way as the
+// BoringSSL backend does through SSL ex_data.
+unsafe impl std::marker::Send for Handshake {}
+unsafe impl std::marker::Sync for Handshake {}
+
+fn epoch_from_level(level: crypto::Level) -> packet::Epoch {
+    match level {
+        crypto::Level::Initial => packet::EPOCH_INITIAL,
+        crypto::Level::ZeroRTT => packet::EPOCH_APPLICATION,
+        crypto::Level::Handshake => packet::EPOCH_HANDSHAKE,
+        crypto::Level::OneRTT => packet::EPOCH_APPLICATION,
+    }
+}
+
+fn load_certs(file: &str) -> Result<Vec<rustls::Certificate>> {
+    let file = fs::File::open(file).map_err(|_| Error::TlsFail)?;
+    let mut reader = io::BufReader::new(file);
+
+    let certs = rustls_pemfile::certs(&mut reader).map_err(|_| Error::TlsFail)?;
+
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+// Accepts any server certificate, used when peer verification is disabled.
+//
+// The handshake signature is not checked either, as that requires parsing the
+// certificate with webpki, which rejects some certificates BoringSSL accepts,
+// e.g. X.509 v1 ones.
+struct NoServerCertVerifier;
+
+impl rustls::client::ServerCertVerifier for NoServerCertVerifier {
+    fn verify_server_cert(
+        &self, _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>, _ocsp_response: &[u8],
+        _now: time::SystemTime,
+    ) -> std::result::Result<rustls::client::ServerCertVerified, rustls::Error>
+    {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self, _message: &[u8], _cert: &rustls::Certificate,
+        _dss: &rustls::DigitallySignedStruct,
+    ) -> std::result::Result<rustls::client::HandshakeSignatureValid, rustls::Error>
+    {
+        Ok(rustls::client::HandshakeSignatureValid::assertion())
+    }
+}
+
+#[derive(Default)]
+struct CertStatus {
+    ocsp_response: Option<Vec<u8>>,
+
+    // In the same SignedCertificateTimestampList encoding as BoringSSL.
+    sct_list: Option<Vec<u8>>,
+}
+
+// Records the status information stapled to the server's certificate, before
+// handing the certificate over to the actual verifier.
+struct CertStatusRecorder {
+    verifier: Arc<dyn rustls::client::ServerCertVerifier>,
+
+    request_scts: bool,
+
+    status: Arc<Mutex<CertStatus>>,
+}
+
+impl rustls::client::ServerCertVerifier for CertStatusRecorder {
+    fn verify_server_cert(
+        &self, end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate], server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>, ocsp_response: &[u8],
+        now: time::SystemTime,
+    ) -> std::result::Result<rustls::client::ServerCertVerified, rustls::Error>
+    {
+        let scts: Vec<&[u8]> = scts.collect();
+
+        let mut status = self.status.lock().unwrap();
+
+        if !ocsp_response.is_empty() {
+            status.ocsp_response = Some(ocsp_response.to_vec());
+        }
+
+        if !scts.is_empty() {
+            let mut list = Vec::new();
+
+            for sct in &scts {
+                list.extend_from_slice(&(sct.len() as u16).to_be_bytes());
+                list.extend_from_slice(sct);
+            }
+
+            let mut sct_list = (list.len() as u16).to_be_bytes().to_vec();
+            sct_list.extend_from_slice(&list);
+
+            status.sct_list = Some(sct_list);
+        }
+
+        self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            &mut scts.into_iter(),
+            ocsp_response,
+            now,
+        )
+    }
+
+    fn verify_tls12_signature(
+        &self, message: &[u8], cert: &rustls::Certificate,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::result::Result<rustls::client::HandshakeSignatureValid, rustls::Error>
+    {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self, message: &[u8], cert: &rustls::Certificate,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::result::Result<rustls::client::HandshakeSignatureValid, rustls::Error>
+    {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+
+    fn request_scts(&self) -> bool {
+        self.request_scts
+    }
+}
+
+// Picks the certificate added for the client's server name, either exactly or
+// through a wildcard, falling back to the default one.
+struct ServerCertResolver {
+    default: Option<Arc<rustls::sign::CertifiedKey>>,
+
+    server_certs: Arc<ServerCerts>,
+
+    peer_alpn: Arc<Mutex<Vec<u8>>>,
+}
+
+impl rustls::server::ResolvesServerCert for ServerCertResolver {
+    fn resolve(
+        &self, client_hello: rustls::server::ClientHello,
+    ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        if let Some(protos) = client_hello.alpn() {
+            let mut peer_alpn = self.peer_alpn.lock().unwrap();
+
+            for proto in protos {
+                peer_alpn.push(proto.len() as u8);
+                peer_alpn.extend_from_slice(proto);
+            }
+        }
+        let name = match client_hello.server_name() {
+            Some(v) => v.to_ascii_lowercase(),
+
+            None => return self.default.clone(),
+        };
+
+        let wildcard = name.find('.').map(|i| format!("*{}", &name[i..]));
+
+        let ctx = self.server_certs.get(&name).or_else(|| {
+            wildcard.and_then(|wildcard| self.server_certs.get(&wildcard))
+        });
+
+        match ctx.and_then(|ctx| ctx.certified_key.clone()) {
+            Some(v) => Some(v),
+
+            None => self.default.clone(),
+        }
+    }
+}
+
+// Writes secrets to the connection's keylog writer, in the same format as
+// BoringSSL.
+struct KeyLogger(*const Connection);
+
+// NOTE: rustls only calls into the key logger while the handshake is being
+// driven by the connection, see the note on Handshake.
+unsafe impl std::marker::Send for KeyLogger {}
+unsafe impl std::marker::Sync for KeyLogger {}
+
+impl rustls::KeyLog for KeyLogger {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let conn = unsafe { &mut *(self.0 as *mut Connection) };
+
+        if let Some(keylog) = &mut conn.keylog {
+            let mut line = format!("{} ", label);
+
+            for b in client_random {
+                line.push_str(&format!("{:02x}", b));
+            }
+
+            line.push(' ');
+
+            for b in secret {
+                line.push_str(&format!("{:02x}", b));
+            }
+
+            line.push('\n');
+
+            keylog.write_all(line.as_bytes()).ok();
+        }
+    }
+}